@@ -10,4 +10,23 @@ fn main() {
             println!("cargo:rustc-env={}={}", variable, val);
         }
     }
+
+    #[cfg(feature = "grpc")]
+    compile_command_protocol();
+}
+
+/// generates the messages and the service of the gRPC command API
+#[cfg(feature = "grpc")]
+fn compile_command_protocol() {
+    // a protoc installed on the system comes first
+    if env::var_os("PROTOC").is_none() {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("could not find protoc");
+        env::set_var("PROTOC", protoc);
+    }
+
+    tonic_build::configure()
+        .build_client(false)
+        .btree_map(["."])
+        .compile_protos(&["src/grpc/command.proto"], &["src/grpc"])
+        .expect("could not compile the gRPC command protocol");
 }
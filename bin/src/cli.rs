@@ -1,7 +1,13 @@
 use std::{collections::BTreeMap, net::SocketAddr};
 
 use clap::{Parser, Subcommand};
-use sozu_command_lib::proxy::{LoadBalancingAlgorithms, TlsVersion};
+use sozu_command_lib::proxy::{
+    is_deny_status, is_header_name, is_header_value, AclMode, BackendProtocol, Compression,
+    Destination, HashKey, HeaderOperation, HealthCheckKind, HostRewrite, Http2Settings, IpRange,
+    ListenerType, LoadBalancingAlgorithms, PathNormalization, RequestLimits, RequestRetries,
+    RetryCondition, SecurityHeaders, StatusRange, StickyMode, Timeouts, TlsProvider, TlsVersion,
+    TrailingSlash, WebSocketDrain, WeightedCluster, REDIRECT_CODES,
+};
 
 #[derive(Parser, PartialEq, Eq, Clone, Debug)]
 #[clap(author, version, about)]
@@ -20,6 +26,38 @@ pub struct Args {
         help = "Sets a custom timeout for commands (in milliseconds). 0 disables the timeout"
     )]
     pub timeout: Option<u64>,
+    #[clap(
+        long = "remote",
+        global = true,
+        requires = "token",
+        help = "sends the command to the remote command channel of a proxy, at this host:port"
+    )]
+    pub remote: Option<String>,
+    #[clap(
+        long = "token",
+        global = true,
+        help = "token of the remote command channel"
+    )]
+    pub token: Option<String>,
+    #[clap(
+        long = "remote-ca",
+        global = true,
+        help = "certificate authorities of the remote command channel, the web ones if not set"
+    )]
+    pub remote_ca: Option<String>,
+    #[clap(
+        long = "dry-run",
+        global = true,
+        help = "validates the configuration order and shows what it would change, without executing it"
+    )]
+    pub dry_run: bool,
+    #[clap(
+        long = "workers",
+        global = true,
+        help = "sends the configuration order to these workers only, an added listener is then handled by them only. Coma-separated list of worker ids",
+        use_value_delimiter = true
+    )]
+    pub workers: Vec<u32>,
     #[clap(subcommand)]
     pub cmd: SubCmd,
 }
@@ -35,29 +73,47 @@ impl paw::ParseArgs for Args {
 #[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
 pub enum SubCmd {
     #[clap(name = "start", about = "launch the main process")]
-    Start,
+    Start {
+        #[clap(
+            long = "pidfile",
+            help = "writes the pid of the main process to this file and locks it, instead of pid_file_path"
+        )]
+        pid_file: Option<String>,
+        #[clap(
+            long = "daemonize",
+            help = "runs the main process in the background, detached from the terminal"
+        )]
+        daemonize: bool,
+    },
     #[clap(
         name = "worker",
-        about = "start a worker (internal command, should not be used directly)"
+        about = "worker management, or start a worker without subcommand (internal command, should not be used directly)",
+        subcommand_negates_reqs = true,
+        args_conflicts_with_subcommands = true
     )]
     Worker {
-        #[clap(long = "id", help = "worker identifier")]
-        id: i32,
+        #[clap(subcommand)]
+        cmd: Option<WorkerCmd>,
+        #[clap(long = "id", required = true, help = "worker identifier")]
+        id: Option<i32>,
         #[clap(
             long = "fd",
+            required = true,
             help = "IPC file descriptor of the worker to main channel"
         )]
-        fd: i32,
+        fd: Option<i32>,
         #[clap(
             long = "scm",
+            required = true,
             help = "IPC SCM_RIGHTS file descriptor of the worker to main scm socket"
         )]
-        scm: i32,
+        scm: Option<i32>,
         #[clap(
             long = "configuration-state-fd",
+            required = true,
             help = "configuration data file descriptor"
         )]
-        configuration_state_fd: i32,
+        configuration_state_fd: Option<i32>,
         #[clap(
             long = "command-buffer-size",
             help = "Worker's channel buffer size",
@@ -69,6 +125,12 @@ pub enum SubCmd {
             help = "Worker's channel max buffer size"
         )]
         max_command_buffer_size: Option<usize>,
+        #[clap(
+            long = "cpu-cores",
+            value_delimiter = ',',
+            help = "CPU cores the worker pins itself to"
+        )]
+        cpu_cores: Vec<usize>,
     },
     #[clap(
         name = "main",
@@ -103,6 +165,12 @@ pub enum SubCmd {
             help = "shuts down the worker with this id"
         )]
         worker: Option<u32>,
+        #[clap(
+            long = "deadline",
+            conflicts_with = "hard",
+            help = "seconds the connections have to finish, before they are closed"
+        )]
+        deadline: Option<u32>,
     },
     #[clap(name = "upgrade", about = "upgrade the proxy")]
     Upgrade {
@@ -117,6 +185,39 @@ pub enum SubCmd {
             help = "Print the command result in JSON format"
         )]
         json: bool,
+        #[clap(
+            long = "watch",
+            help = "keeps the connection open and redraws the result when it changes, checked every N seconds, 2 by default",
+            num_args = 0..=1,
+            default_missing_value = "2"
+        )]
+        watch: Option<u64>,
+        #[clap(flatten)]
+        output: OutputArgs,
+    },
+    #[clap(
+        name = "healthcheck",
+        alias = "ping",
+        about = "exits with 0 only if the proxy answers and all its workers are running, for liveness probes"
+    )]
+    Healthcheck {
+        #[clap(
+            long = "listeners",
+            help = "also checks that the listeners of the configuration file are active"
+        )]
+        listeners: bool,
+        #[clap(
+            long = "deadline",
+            default_value = "500",
+            help = "the time in milliseconds the whole check must complete in"
+        )]
+        deadline: u64,
+        #[clap(
+            short = 'j',
+            long = "json",
+            help = "Print the command result in JSON format"
+        )]
+        json: bool,
     },
     #[clap(
         name = "metrics",
@@ -161,6 +262,23 @@ pub enum SubCmd {
         )]
         json: bool,
     },
+    #[clap(
+        name = "batch",
+        about = "Sends orders as one transaction: if one of them fails on a worker, the others are rolled back"
+    )]
+    Batch {
+        #[clap(
+            short = 'f',
+            long = "file",
+            help = "JSON array of the orders, in the format of the command socket"
+        )]
+        file: String,
+        #[clap(
+            long = "strict",
+            help = "refuse the batch if an HTTPS frontend has no certificate covering its hostname, instead of warning"
+        )]
+        strict: bool,
+    },
     #[clap(name = "cluster", about = "cluster management")]
     Cluster {
         #[clap(subcommand)]
@@ -186,6 +304,11 @@ pub enum SubCmd {
         #[clap(subcommand)]
         cmd: CertificateCmd,
     },
+    #[clap(name = "acl", about = "source IP access control lists management")]
+    Acl {
+        #[clap(subcommand)]
+        cmd: AclCmd,
+    },
     #[clap(name = "query", about = "configuration state verification")]
     Query {
         #[clap(
@@ -205,6 +328,80 @@ pub enum SubCmd {
     },
     #[clap(name = "events", about = "receive sozu events")]
     Events,
+    #[clap(
+        name = "top",
+        about = "live view of the request rates, server errors, latencies and health of the clusters and their backends"
+    )]
+    Top {
+        #[clap(
+            short = 'i',
+            long = "interval",
+            default_value_t = 2,
+            help = "seconds between two refreshes"
+        )]
+        interval: u64,
+    },
+    #[clap(name = "audit", about = "audit log of the orders changing the proxy")]
+    Audit {
+        #[clap(subcommand)]
+        cmd: AuditCmd,
+    },
+    #[clap(name = "logs", about = "access logs of the workers")]
+    Logs {
+        #[clap(subcommand)]
+        cmd: LogsCmd,
+    },
+    #[clap(
+        name = "shell",
+        about = "interactive prompt sending the commands over a single connection"
+    )]
+    Shell {
+        #[clap(
+            long = "history",
+            help = "file keeping the history of the prompt, ~/.sozu_history by default"
+        )]
+        history: Option<String>,
+    },
+    #[clap(
+        name = "completion",
+        about = "print the script completing the command line, with the clusters, backends and listeners of the running proxy"
+    )]
+    Completion {
+        #[clap(value_parser = parse_completion_shell, help = "bash, zsh or fish")]
+        shell: CompletionShell,
+    },
+    /// called by the completion scripts with the line being typed
+    #[clap(name = "__complete", hide = true)]
+    Complete {
+        #[clap(allow_hyphen_values = true)]
+        line: String,
+    },
+    #[cfg(feature = "grpc")]
+    #[clap(
+        name = "grpc",
+        about = "serve the command API over gRPC, next to a running proxy"
+    )]
+    Grpc {
+        #[clap(
+            short = 'a',
+            long = "address",
+            help = "address the gRPC API listens on",
+            default_value = "127.0.0.1:9090"
+        )]
+        address: SocketAddr,
+    },
+}
+
+#[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
+pub enum WorkerCmd {
+    #[clap(
+        name = "restart",
+        about = "replace a worker with a new one running the same binary, that takes over its listeners, then soft stop it"
+    )]
+    Restart {
+        #[clap(help = "id of the worker")]
+        id: u32,
+    },
 }
 
 #[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
@@ -221,10 +418,17 @@ pub enum MetricsCmd {
         list: bool,
         #[clap(short, long, help = "refresh metrics results (in seconds)")]
         refresh: Option<u32>,
+        #[clap(
+            long = "watch",
+            help = "keeps the connection open and redraws the result when it changes, checked every N seconds, 2 by default",
+            num_args = 0..=1,
+            default_missing_value = "2"
+        )]
+        watch: Option<u64>,
         #[clap(
             short = 'n',
             long = "names",
-            help = "Filter by metric names. Coma-separated list.",
+            help = "Filter by metric names, `tls.*` selects all the names starting with `tls.`. Coma-separated list.",
             use_value_delimiter = true
         )]
         names: Vec<String>,
@@ -289,164 +493,1177 @@ pub enum StateCmd {
         )]
         json: bool,
     },
-}
-
-#[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
-pub enum ClusterCmd {
-    #[clap(name = "remove", about = "Remove a cluster")]
-    Remove {
-        #[clap(short = 'i', long = "id", help = "cluster id")]
-        id: String,
-    },
-    #[clap(name = "add", about = "Add a cluster")]
-    Add {
-        #[clap(short = 'i', long = "id", help = "cluster id")]
-        id: String,
-        #[clap(short = 's', long = "sticky-session")]
-        sticky_session: bool,
-        #[clap(short = 'r', long = "https-redirect")]
-        https_redirect: bool,
-        #[clap(
-            long = "send-proxy",
-            help = "Enforces use of the PROXY protocol version 2 over any connection established to this server."
-        )]
-        send_proxy: bool,
+    #[clap(
+        name = "apply",
+        about = "Turn the current state into the one of that file, executing only the orders of their difference"
+    )]
+    Apply {
+        #[clap(short = 'f', long = "file", help = "state in JSON, as dumped")]
+        file: String,
         #[clap(
-            long = "expect-proxy",
-            help = "Configures the client-facing connection to receive a PROXY protocol header version 2"
+            short = 'j',
+            long = "json",
+            help = "Print the command result in JSON format"
         )]
-        expect_proxy: bool,
+        json: bool,
         #[clap(
-            long = "load-balancing-policy",
-            help = "Configures the load balancing policy. Possible values are 'roundrobin', 'random' or 'leastconnections'"
+            long = "strict",
+            help = "refuse the state if an HTTPS frontend has no certificate covering its hostname, instead of warning"
         )]
-        load_balancing_policy: LoadBalancingAlgorithms,
+        strict: bool,
     },
-}
-
-#[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
-pub enum BackendCmd {
-    #[clap(name = "remove", about = "Remove a backend")]
-    Remove {
-        #[clap(short = 'i', long = "id")]
-        id: String,
-        #[clap(long = "backend-id")]
-        backend_id: String,
+    #[clap(
+        name = "export-config",
+        about = "Write the current state as a configuration file, with the listeners and clusters added since the start"
+    )]
+    ExportConfig {
+        #[clap(short = 'f', long = "file", help = "configuration file to write")]
+        file: String,
         #[clap(
-            short = 'a',
-            long = "address",
-            help = "server address, format: IP:port"
+            short = 'd',
+            long = "directory",
+            help = "directory receiving the certificates, keys and custom answers, the one of the file by default"
         )]
-        address: SocketAddr,
+        directory: Option<String>,
     },
-    #[clap(name = "add", about = "Add a backend")]
-    Add {
-        #[clap(short = 'i', long = "id")]
-        id: String,
-        #[clap(long = "backend-id")]
-        backend_id: String,
+    #[clap(
+        name = "history",
+        about = "List the versions of the state kept by the main process"
+    )]
+    History {
         #[clap(
-            short = 'a',
-            long = "address",
-            help = "server address, format: IP:port"
+            short = 'j',
+            long = "json",
+            help = "Print the command result in JSON format"
         )]
-        address: SocketAddr,
+        json: bool,
+        #[clap(flatten)]
+        output: OutputArgs,
+    },
+    #[clap(
+        name = "rollback",
+        about = "Turn the current state back into a version of the history, as a new version"
+    )]
+    Rollback {
+        #[clap(long = "to", help = "version of the state, as listed by the history")]
+        to: u64,
         #[clap(
-            short = 's',
-            long = "sticky-id",
-            help = "value for the sticky session cookie"
+            short = 'j',
+            long = "json",
+            help = "Print the command result in JSON format"
         )]
-        sticky_id: Option<String>,
-        #[clap(short = 'b', long = "backup", help = "set backend as a backup backend")]
-        backup: Option<bool>,
+        json: bool,
+    },
+    #[clap(
+        name = "sync-peers",
+        about = "Replace the state of the peers with this one, to resolve a conflict"
+    )]
+    SyncPeers,
+    #[clap(
+        name = "edit-file",
+        about = "Work on a saved state file, without a running proxy"
+    )]
+    EditFile {
+        #[clap(subcommand)]
+        cmd: StateFileCmd,
     },
 }
 
 #[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
-pub enum FrontendCmd {
-    #[clap(name = "http", about = "HTTP frontend management")]
-    Http {
-        #[clap(subcommand)]
-        cmd: HttpFrontendCmd,
-    },
-    #[clap(name = "https", about = "HTTPS frontend management")]
-    Https {
-        #[clap(subcommand)]
-        cmd: HttpFrontendCmd,
+pub enum StateFileCmd {
+    #[clap(
+        name = "validate",
+        about = "Check that the file can be loaded, and that its frontends have their clusters and certificates"
+    )]
+    Validate {
+        #[clap(short = 'f', long = "file")]
+        file: String,
     },
-    #[clap(name = "tcp", about = "TCP frontend management")]
-    Tcp {
-        #[clap(subcommand)]
-        cmd: TcpFrontendCmd,
+    #[clap(name = "print", about = "Print the state of the file")]
+    Print {
+        #[clap(short = 'f', long = "file")]
+        file: String,
+        #[clap(
+            short = 'j',
+            long = "json",
+            help = "Print the state in JSON format, as dumped"
+        )]
+        json: bool,
     },
-    #[clap(name = "list", about = "List frontends using filters")]
-    List {
-        #[clap(long = "http", help = "filter for http frontends")]
-        http: bool,
-        #[clap(long = "https", help = "filter for https frontends")]
-        https: bool,
-        #[clap(long = "tcp", help = "filter for tcp frontends")]
-        tcp: bool,
+    #[clap(
+        name = "filter",
+        about = "Write the listeners and some of the clusters of the file, with their frontends, backends and certificates"
+    )]
+    Filter {
+        #[clap(short = 'f', long = "file")]
+        file: String,
         #[clap(
-            short = 'd',
-            long = "domain",
-            help = "filter by domain name (for http & https frontends)"
+            long = "cluster",
+            required = true,
+            help = "cluster to keep, several can be given"
         )]
-        domain: Option<String>,
+        clusters: Vec<String>,
+        #[clap(short = 'o', long = "output", help = "state file to write")]
+        output: String,
+    },
+    #[clap(
+        name = "merge",
+        about = "Write the state of the file with the one of another file added, its clusters replace the ones with the same ids"
+    )]
+    Merge {
+        #[clap(short = 'f', long = "file")]
+        file: String,
+        #[clap(short = 'w', long = "with", help = "state file to add")]
+        with: String,
+        #[clap(short = 'o', long = "output", help = "state file to write")]
+        output: String,
+    },
+    #[clap(
+        name = "strip-certificates",
+        about = "Write the state of the file without its certificates and keys"
+    )]
+    StripCertificates {
+        #[clap(short = 'f', long = "file")]
+        file: String,
+        #[clap(short = 'o', long = "output", help = "state file to write")]
+        output: String,
     },
 }
 
 #[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
-pub enum Route {
-    /// traffic will go to the backend servers with this cluster id
-    Id {
-        /// traffic will go to the backend servers with this cluster id
-        id: String,
+pub enum AuditCmd {
+    #[clap(name = "tail", about = "List the last entries of the audit log")]
+    Tail {
+        #[clap(
+            short = 'n',
+            long = "lines",
+            help = "number of entries",
+            default_value = "20"
+        )]
+        lines: usize,
+        #[clap(
+            short = 'j',
+            long = "json",
+            help = "Print the command result in JSON format"
+        )]
+        json: bool,
+        #[clap(flatten)]
+        output: OutputArgs,
     },
-    /// traffic to this frontend will be rejected with HTTP 401
-    Deny,
-}
-
-#[allow(clippy::from_over_into)]
-impl std::convert::Into<sozu_command_lib::proxy::Route> for Route {
-    fn into(self) -> sozu_command_lib::proxy::Route {
-        match self {
-            Route::Deny => sozu_command_lib::proxy::Route::Deny,
-            Route::Id { id } => sozu_command_lib::proxy::Route::ClusterId(id),
-        }
-    }
 }
 
 #[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
-pub enum HttpFrontendCmd {
-    #[clap(name = "add")]
-    Add {
+pub enum LogsCmd {
+    #[clap(
+        name = "tail",
+        about = "Print the access logs of the workers as the requests end, until interrupted"
+    )]
+    Tail {
+        #[clap(long = "cluster", help = "the requests of this cluster only")]
+        cluster_id: Option<String>,
         #[clap(
-            short = 'a',
-            long = "address",
-            help = "frontend address, format: IP:port"
+            long = "status",
+            value_parser = parse_status_range,
+            help = "the responses with these statuses only, like 502, 5xx or 400-499"
         )]
-        address: SocketAddr,
-        #[clap(subcommand, name = "route")]
-        route: Route,
-        #[clap(long = "hostname", aliases = &["host"])]
-        hostname: String,
-        #[clap(short = 'p', long = "path-prefix", help = "URL prefix of the frontend")]
-        path_prefix: Option<String>,
+        status: Option<StatusRange>,
         #[clap(
-            long = "path-regex",
-            help = "the frontend URL path should match this regex"
+            long = "rate",
+            help = "records printed a second at most, the others are dropped",
+            default_value = "100"
         )]
-        path_regex: Option<String>,
-        #[clap(
+        rate: u32,
+        #[clap(short = 'j', long = "json", help = "Print each record in JSON format")]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
+pub enum ClusterCmd {
+    #[clap(name = "remove", about = "Remove a cluster")]
+    Remove {
+        #[clap(short = 'i', long = "id", help = "cluster id")]
+        id: String,
+    },
+    #[clap(
+        name = "apply",
+        about = "Provisions the clusters of a YAML or JSON document with their frontends, backends and certificates, in one batch"
+    )]
+    Apply {
+        #[clap(
+            short = 'f',
+            long = "file",
+            help = "list of clusters, in the format of `cluster export`"
+        )]
+        file: String,
+        #[clap(
+            long = "strict",
+            help = "refuse the batch if an HTTPS frontend has no certificate covering its hostname, instead of warning"
+        )]
+        strict: bool,
+    },
+    #[clap(
+        name = "export",
+        about = "Prints clusters with their frontends, backends and certificates, as YAML"
+    )]
+    Export {
+        #[clap(
+            short = 'i',
+            long = "id",
+            help = "cluster id, can be repeated",
+            required = true
+        )]
+        ids: Vec<String>,
+        #[clap(
+            short = 'j',
+            long = "json",
+            help = "Print the command result in JSON format"
+        )]
+        json: bool,
+    },
+    #[clap(
+        name = "maintenance",
+        about = "Answer the requests of a cluster with a 503 page, without removing it"
+    )]
+    Maintenance {
+        #[clap(help = "cluster id")]
+        id: String,
+        #[clap(
+            long = "enable",
+            conflicts_with = "disable",
+            required_unless_present = "disable"
+        )]
+        enable: bool,
+        #[clap(long = "disable")]
+        disable: bool,
+        #[clap(
+            long = "answer-file",
+            help = "file containing the full HTTP answer, defaults to the cluster's 503 answer",
+            conflicts_with = "disable"
+        )]
+        answer_file: Option<String>,
+    },
+    #[clap(name = "add", about = "Add a cluster")]
+    Add(ClusterArgs),
+    #[clap(
+        name = "add-full",
+        about = "Add a cluster with its frontends, backends and certificate, in one batch"
+    )]
+    AddFull {
+        #[clap(flatten)]
+        cluster: ClusterArgs,
+        #[clap(
+            long = "hostname",
+            help = "hostname of the frontends, can be repeated",
+            required = true
+        )]
+        hostnames: Vec<String>,
+        #[clap(
+            short = 'p',
+            long = "path-prefix",
+            help = "URL prefix of the frontends"
+        )]
+        path_prefix: Option<String>,
+        #[clap(
+            long = "http",
+            help = "address of an HTTP listener receiving the frontends, can be repeated",
+            required_unless_present = "https"
+        )]
+        http: Vec<SocketAddr>,
+        #[clap(
+            long = "https",
+            help = "address of an HTTPS listener receiving the frontends, can be repeated"
+        )]
+        https: Vec<SocketAddr>,
+        #[clap(
+            long = "backend",
+            help = "address of a backend, can be repeated",
+            required = true
+        )]
+        backends: Vec<SocketAddr>,
+        #[clap(
+            long = "certificate",
+            help = "path to the certificate added to the HTTPS listeners, PEM encoded",
+            requires_all = &["https", "chain", "key"]
+        )]
+        certificate: Option<String>,
+        #[clap(
+            long = "certificate-chain",
+            help = "path to the certificate chain, PEM encoded",
+            requires = "certificate"
+        )]
+        chain: Option<String>,
+        #[clap(
+            long = "key",
+            help = "path to the key, PEM encoded",
+            requires = "certificate"
+        )]
+        key: Option<String>,
+        #[clap(
+            long = "key-passphrase",
+            help = "passphrase of an encrypted key: pass:<passphrase>, file:<path> or env:<variable>",
+            requires = "key"
+        )]
+        key_passphrase: Option<String>,
+        #[clap(
+            long = "strict",
+            help = "refuse the batch if an HTTPS frontend has no certificate covering its hostname, instead of warning"
+        )]
+        strict: bool,
+    },
+}
+
+#[derive(clap::Args, PartialEq, Eq, Clone, Debug)]
+pub struct ClusterArgs {
+    #[clap(short = 'i', long = "id", help = "cluster id")]
+    pub id: String,
+    #[clap(short = 's', long = "sticky-session")]
+    pub sticky_session: bool,
+    #[clap(
+        long = "sticky-mode",
+        help = "how a sticky session chooses its backend, format: cookie|source_ip|header=name",
+        default_value = "cookie",
+        requires = "sticky_session",
+        value_parser = parse_sticky_mode
+    )]
+    pub sticky_mode: StickyMode,
+    #[clap(short = 'r', long = "https-redirect")]
+    pub https_redirect: bool,
+    #[clap(
+        long = "send-proxy",
+        help = "Enforces use of the PROXY protocol version 2 over any connection established to this server."
+    )]
+    pub send_proxy: bool,
+    #[clap(
+        long = "expect-proxy",
+        help = "Configures the client-facing connection to receive a PROXY protocol header version 2"
+    )]
+    pub expect_proxy: bool,
+    #[clap(
+        long = "load-balancing-policy",
+        help = "Configures the load balancing policy. Possible values are 'roundrobin', 'random', 'consistenthash' or 'leastresponsetime'"
+    )]
+    pub load_balancing_policy: LoadBalancingAlgorithms,
+    #[clap(
+        long = "hash-key",
+        help = "key of the requests hashed by the consistenthash policy, format: url|source_ip|header=name|cookie=name",
+        default_value = "url",
+        value_parser = parse_hash_key
+    )]
+    pub hash_key: HashKey,
+    #[clap(
+        long = "request-header",
+        help = "edit a header sent to the backends, format: add|set|remove:name[=value]",
+        value_parser = parse_header_action
+    )]
+    pub request_header: Vec<(HeaderOperation, String, String)>,
+    #[clap(
+        long = "response-header",
+        help = "edit a header returned to the clients, format: add|set|remove:name[=value]",
+        value_parser = parse_header_action
+    )]
+    pub response_header: Vec<(HeaderOperation, String, String)>,
+    #[clap(
+        long = "host-rewrite",
+        help = "Host header sent to the backends, format: preserve|backend_address|fixed=hostname",
+        default_value = "preserve",
+        value_parser = parse_host_rewrite
+    )]
+    pub host_rewrite: HostRewrite,
+    #[clap(flatten)]
+    pub request_limits: RequestLimitsArgs,
+    #[clap(flatten)]
+    pub compression: CompressionArgs,
+    #[clap(flatten)]
+    pub security_headers: SecurityHeadersArgs,
+    #[clap(flatten)]
+    pub request_retries: RequestRetriesArgs,
+    #[clap(flatten)]
+    pub timeouts: TimeoutsArgs,
+    #[clap(flatten)]
+    pub tls: BackendTlsArgs,
+    #[clap(flatten)]
+    pub health_check: HealthCheckArgs,
+    #[clap(flatten)]
+    pub outlier_detection: OutlierDetectionArgs,
+    #[clap(
+        long = "queue-size",
+        help = "requests waiting for a free connection when all the backends are at their maximum connections"
+    )]
+    pub queue_size: Option<usize>,
+    #[clap(
+        long = "queue-timeout",
+        requires = "queue_size",
+        help = "seconds a request waits in the queue before being answered with a 503, defaults to 5"
+    )]
+    pub queue_timeout: Option<u32>,
+    #[clap(
+        long = "affinity-table-size",
+        help = "binds the sticky keys to their backends in a table shared by the workers, keeping this many keys"
+    )]
+    pub affinity_table_size: Option<usize>,
+    #[clap(
+        long = "max-sessions",
+        help = "client sessions routed to the cluster at most by a worker, the next ones get a 503 or are refused"
+    )]
+    pub max_sessions: Option<usize>,
+    #[clap(
+        long = "backend-protocol",
+        help = "protocol spoken to the backends: http1, http2 to multiplex the requests of HTTP/2 clients, or grpc for gRPC services",
+        default_value = "http1"
+    )]
+    pub backend_protocol: BackendProtocol,
+    #[clap(
+        long = "websocket-drain",
+        help = "what a stopping worker does with the WebSocket connections: close them with a close frame, or wait for them to end",
+        default_value = "close"
+    )]
+    pub websocket_drain: WebSocketDrain,
+    #[clap(
+        long = "streaming",
+        help = "forwards the responses as they arrive, without compression or back timeout once the body streams, for server-sent events and long polling"
+    )]
+    pub streaming: bool,
+    #[clap(
+        long = "upgrade-protocol",
+        help = "protocol the connections can switch to with an Upgrade header besides WebSocket, like h2c. Can be repeated"
+    )]
+    pub upgrade_protocols: Vec<String>,
+}
+
+#[derive(clap::Args, PartialEq, Eq, Clone, Debug)]
+pub struct OutputArgs {
+    #[clap(
+        short = 'o',
+        long = "output",
+        help = "format of the listing: table, json, yaml or csv",
+        default_value = "table",
+        value_parser = parse_output_format
+    )]
+    pub output: OutputFormat,
+    #[clap(
+        long = "columns",
+        help = "columns to print, in this order, format: column,other_column",
+        value_delimiter = ','
+    )]
+    pub columns: Vec<String>,
+    #[clap(
+        long = "sort",
+        help = "column sorting the rows, by value for the numbers"
+    )]
+    pub sort: Option<String>,
+    #[clap(long = "reverse", help = "sort in descending order", requires = "sort")]
+    pub reverse: bool,
+    #[clap(
+        long = "filter",
+        help = "only the rows whose column contains the value, format: column=value. Can be repeated",
+        value_parser = parse_filter
+    )]
+    pub filters: Vec<(String, String)>,
+}
+
+#[derive(clap::Args, PartialEq, Eq, Clone, Debug)]
+pub struct RequestLimitsArgs {
+    #[clap(
+        long = "max-header-size",
+        help = "maximum size of the request line and headers in bytes, larger requests are answered with a 431"
+    )]
+    pub max_header_size: Option<usize>,
+    #[clap(
+        long = "max-header-count",
+        help = "maximum number of request headers, requests with more are answered with a 431"
+    )]
+    pub max_header_count: Option<usize>,
+    #[clap(
+        long = "max-body-size",
+        help = "maximum size of the request body in bytes, larger requests are answered with a 413"
+    )]
+    pub max_body_size: Option<usize>,
+    #[clap(
+        long = "max-buffered-body",
+        help = "maximum size in bytes of the request body read ahead of the backend, the client is not read from above it"
+    )]
+    pub max_buffered_body: Option<usize>,
+    #[clap(
+        long = "buffer-chunked-body",
+        help = "send the chunked request bodies with a Content-Length, for backends that need it. They are read whole, up to the maximum buffered body"
+    )]
+    pub buffer_chunked_body: bool,
+}
+
+impl From<RequestLimitsArgs> for RequestLimits {
+    fn from(args: RequestLimitsArgs) -> Self {
+        RequestLimits {
+            max_header_size: args.max_header_size,
+            max_header_count: args.max_header_count,
+            max_body_size: args.max_body_size,
+            max_buffered_body: args.max_buffered_body,
+            buffer_chunked_body: args.buffer_chunked_body.then_some(true),
+        }
+    }
+}
+
+#[derive(clap::Args, PartialEq, Eq, Clone, Debug)]
+pub struct CompressionArgs {
+    #[clap(
+        long = "gzip",
+        help = "compress the responses with gzip for the clients accepting it"
+    )]
+    pub gzip: bool,
+    #[clap(
+        long = "brotli",
+        help = "compress the responses with brotli for the clients accepting it, preferred to gzip"
+    )]
+    pub brotli: bool,
+    #[clap(
+        long = "compression-min-size",
+        help = "size in bytes of the smallest response body compressed, defaults to 1024"
+    )]
+    pub compression_min_size: Option<usize>,
+}
+
+impl From<CompressionArgs> for Compression {
+    fn from(args: CompressionArgs) -> Self {
+        Compression {
+            gzip: args.gzip.then_some(true),
+            brotli: args.brotli.then_some(true),
+            min_size: args.compression_min_size,
+        }
+    }
+}
+
+#[derive(clap::Args, PartialEq, Eq, Clone, Debug)]
+pub struct SecurityHeadersArgs {
+    #[clap(
+        long = "strict-transport-security",
+        help = "value of the Strict-Transport-Security header added to the HTTPS responses, like max-age=31536000"
+    )]
+    pub strict_transport_security: Option<String>,
+    #[clap(
+        long = "content-type-options",
+        help = "value of the X-Content-Type-Options header added to the responses, like nosniff"
+    )]
+    pub content_type_options: Option<String>,
+    #[clap(
+        long = "frame-options",
+        help = "value of the X-Frame-Options header added to the responses, like DENY"
+    )]
+    pub frame_options: Option<String>,
+}
+
+impl From<SecurityHeadersArgs> for SecurityHeaders {
+    fn from(args: SecurityHeadersArgs) -> Self {
+        SecurityHeaders {
+            strict_transport_security: args.strict_transport_security,
+            content_type_options: args.content_type_options,
+            frame_options: args.frame_options,
+        }
+    }
+}
+
+#[derive(clap::Args, PartialEq, Eq, Clone, Debug)]
+pub struct PathNormalizationArgs {
+    #[clap(
+        long = "merge-slashes",
+        help = "collapse the consecutive slashes of the request paths before routing them"
+    )]
+    pub merge_slashes: bool,
+    #[clap(
+        long = "resolve-dot-segments",
+        help = "resolve the . and .. segments of the request paths before routing them"
+    )]
+    pub resolve_dot_segments: bool,
+    #[clap(
+        long = "trailing-slash",
+        help = "redirect the requests to add or remove the trailing slash of their path, format: keep|add|remove",
+        default_value = "keep",
+        value_parser = parse_trailing_slash
+    )]
+    pub trailing_slash: TrailingSlash,
+}
+
+impl From<PathNormalizationArgs> for PathNormalization {
+    fn from(args: PathNormalizationArgs) -> Self {
+        PathNormalization {
+            merge_slashes: args.merge_slashes,
+            resolve_dot_segments: args.resolve_dot_segments,
+            trailing_slash: args.trailing_slash,
+        }
+    }
+}
+
+#[derive(clap::Args, PartialEq, Eq, Clone, Debug)]
+pub struct ClientAuthArgs {
+    #[clap(
+        long = "client-ca",
+        help = "path to the bundle of certificate authorities verifying the client certificates, enables the client authentication"
+    )]
+    pub client_ca: Option<String>,
+    #[clap(
+        long = "client-cert-required",
+        requires = "client_ca",
+        help = "reject the clients without a certificate, otherwise it is only verified when sent"
+    )]
+    pub client_cert_required: bool,
+    #[clap(
+        long = "client-crl",
+        requires = "client_ca",
+        help = "path to the certificate revocation lists checked against the client certificates"
+    )]
+    pub client_crl: Option<String>,
+    #[clap(
+        long = "client-dn-header",
+        requires = "client_ca",
+        help = "request header receiving the subject of the client certificate, defaults to X-Client-DN"
+    )]
+    pub client_dn_header: Option<String>,
+}
+
+#[derive(clap::Args, PartialEq, Eq, Clone, Debug)]
+pub struct Http2Args {
+    #[clap(
+        long = "http2",
+        help = "offers HTTP/2 to the clients through ALPN, the streams are forwarded to the backends over HTTP/1.1"
+    )]
+    pub http2: bool,
+    #[clap(
+        long = "h2-max-concurrent-streams",
+        requires = "http2",
+        help = "streams a client can have open at the same time on a connection, defaults to 100"
+    )]
+    pub max_concurrent_streams: Option<u32>,
+    #[clap(
+        long = "h2-initial-window-size",
+        requires = "http2",
+        help = "flow control window of each stream in bytes, defaults to 65535"
+    )]
+    pub initial_window_size: Option<u32>,
+}
+
+impl From<Http2Args> for Http2Settings {
+    fn from(args: Http2Args) -> Self {
+        Http2Settings {
+            enabled: args.http2,
+            max_concurrent_streams: args.max_concurrent_streams,
+            initial_window_size: args.initial_window_size,
+        }
+    }
+}
+
+#[derive(clap::Args, PartialEq, Eq, Clone, Debug)]
+pub struct RequestRetriesArgs {
+    #[clap(
+        long = "retry-attempts",
+        help = "attempts of a request on the backends, including the first one, defaults to 3"
+    )]
+    pub retry_attempts: Option<u8>,
+    #[clap(
+        long = "retry-on",
+        help = "failure sending the request to another backend, format: connect_failure|http_502|http_503, defaults to connect_failure",
+        value_parser = parse_retry_condition
+    )]
+    pub retry_on: Vec<RetryCondition>,
+}
+
+impl From<RequestRetriesArgs> for RequestRetries {
+    fn from(args: RequestRetriesArgs) -> Self {
+        RequestRetries {
+            max_attempts: args.retry_attempts,
+            retry_on: args.retry_on,
+        }
+    }
+}
+
+#[derive(clap::Args, PartialEq, Eq, Clone, Debug)]
+pub struct TimeoutsArgs {
+    #[clap(
+        long = "front-timeout",
+        help = "overrides the front timeout of the listeners, in seconds"
+    )]
+    pub front_timeout: Option<u32>,
+    #[clap(
+        long = "back-timeout",
+        help = "overrides the back timeout of the listeners, in seconds"
+    )]
+    pub back_timeout: Option<u32>,
+    #[clap(
+        long = "connect-timeout",
+        help = "overrides the connect timeout of the listeners, in seconds"
+    )]
+    pub connect_timeout: Option<u32>,
+    #[clap(
+        long = "websocket-timeout",
+        help = "inactivity timeout of the WebSocket connections, in seconds, instead of the front and back timeouts"
+    )]
+    pub websocket_timeout: Option<u32>,
+}
+
+impl From<TimeoutsArgs> for Timeouts {
+    fn from(args: TimeoutsArgs) -> Self {
+        Timeouts {
+            front_timeout: args.front_timeout,
+            back_timeout: args.back_timeout,
+            connect_timeout: args.connect_timeout,
+            websocket_timeout: args.websocket_timeout,
+        }
+    }
+}
+
+#[derive(clap::Args, PartialEq, Eq, Clone, Debug)]
+pub struct BackendTlsArgs {
+    #[clap(long = "tls", help = "connect to the backends over TLS")]
+    pub tls: bool,
+    #[clap(
+        long = "tls-sni",
+        requires = "tls",
+        help = "server name sent to the backends and verified in their certificate"
+    )]
+    pub tls_sni: Option<String>,
+    #[clap(
+        long = "tls-skip-verification",
+        requires = "tls",
+        help = "accept any certificate from the backends"
+    )]
+    pub tls_skip_verification: bool,
+    #[clap(
+        long = "tls-ca",
+        requires = "tls",
+        help = "path to the certificate authorities verifying the backends, defaults to the web authorities"
+    )]
+    pub tls_ca: Option<String>,
+    #[clap(
+        long = "tls-client-certificate",
+        requires_all = &["tls", "tls_client_key"],
+        help = "path to the certificate presented to the backends"
+    )]
+    pub tls_client_certificate: Option<String>,
+    #[clap(
+        long = "tls-client-key",
+        requires_all = &["tls", "tls_client_certificate"],
+        help = "path to the key of the client certificate"
+    )]
+    pub tls_client_key: Option<String>,
+    #[clap(
+        long = "tls-alpn",
+        requires = "tls",
+        help = "protocols offered to the backends through ALPN, format: http/1.1",
+        value_delimiter = ','
+    )]
+    pub tls_alpn: Vec<String>,
+}
+
+#[derive(clap::Args, PartialEq, Eq, Clone, Debug)]
+pub struct HealthCheckArgs {
+    #[clap(
+        long = "health-check",
+        help = "checks the backends actively with a TCP connection, an HTTP GET request, or a call to the gRPC health checking service, format: tcp|http|grpc"
+    )]
+    pub health_check: Option<HealthCheckKind>,
+    #[clap(
+        long = "health-check-path",
+        requires = "health_check",
+        help = "path of the HTTP health check requests, defaults to /"
+    )]
+    pub health_check_path: Option<String>,
+    #[clap(
+        long = "health-check-status",
+        requires = "health_check",
+        help = "status of the HTTP health check responses, defaults to any 2xx or 3xx status"
+    )]
+    pub health_check_status: Option<u16>,
+    #[clap(
+        long = "health-check-body",
+        requires = "health_check",
+        help = "text the body of the HTTP health check responses must contain"
+    )]
+    pub health_check_body: Option<String>,
+    #[clap(
+        long = "health-check-service",
+        requires = "health_check",
+        help = "service name of the gRPC health checks, the whole server is checked by default"
+    )]
+    pub health_check_service: Option<String>,
+    #[clap(
+        long = "health-check-interval",
+        requires = "health_check",
+        help = "seconds between two checks of a backend, defaults to 10"
+    )]
+    pub health_check_interval: Option<u32>,
+    #[clap(
+        long = "health-check-timeout",
+        requires = "health_check",
+        help = "seconds after which a check fails, defaults to 5"
+    )]
+    pub health_check_timeout: Option<u32>,
+    #[clap(
+        long = "health-check-rise",
+        requires = "health_check",
+        help = "successful checks in a row bringing a backend back in the rotation, defaults to 2"
+    )]
+    pub health_check_rise: Option<u32>,
+    #[clap(
+        long = "health-check-fall",
+        requires = "health_check",
+        help = "failed checks in a row taking a backend out of the rotation, defaults to 3"
+    )]
+    pub health_check_fall: Option<u32>,
+}
+
+#[derive(clap::Args, PartialEq, Eq, Clone, Debug)]
+pub struct OutlierDetectionArgs {
+    #[clap(
+        long = "outlier-detection",
+        help = "ejects the backends failing too much of the traffic from the rotation for a while"
+    )]
+    pub outlier_detection: bool,
+    #[clap(
+        long = "outlier-consecutive-failures",
+        requires = "outlier_detection",
+        help = "connection failures and 5xx responses in a row ejecting a backend, defaults to 5"
+    )]
+    pub outlier_consecutive_failures: Option<u32>,
+    #[clap(
+        long = "outlier-error-rate",
+        requires = "outlier_detection",
+        help = "share of failures in percent ejecting a backend"
+    )]
+    pub outlier_error_rate: Option<u8>,
+    #[clap(
+        long = "outlier-minimum-requests",
+        requires = "outlier_detection",
+        help = "requests between two evaluations of the error rate, defaults to 20"
+    )]
+    pub outlier_minimum_requests: Option<u32>,
+    #[clap(
+        long = "outlier-ejection-time",
+        requires = "outlier_detection",
+        help = "seconds a backend stays out of the rotation, defaults to 30"
+    )]
+    pub outlier_ejection_time: Option<u32>,
+    #[clap(
+        long = "outlier-max-ejection-percent",
+        requires = "outlier_detection",
+        help = "share of the backends of the cluster that can be ejected at once, defaults to 50"
+    )]
+    pub outlier_max_ejection_percent: Option<u8>,
+}
+
+#[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
+pub enum BackendCmd {
+    #[clap(name = "remove", about = "Remove a backend")]
+    Remove {
+        #[clap(short = 'i', long = "id")]
+        id: String,
+        #[clap(long = "backend-id")]
+        backend_id: String,
+        #[clap(
+            short = 'a',
+            long = "address",
+            help = "server address, format: IP:port"
+        )]
+        address: SocketAddr,
+    },
+    #[clap(
+        name = "drain",
+        about = "Stop sending new sessions to a backend, the RemovedBackendHasNoConnections event is sent once its connections are closed"
+    )]
+    Drain {
+        #[clap(short = 'i', long = "id")]
+        id: String,
+        #[clap(long = "backend-id")]
+        backend_id: String,
+        #[clap(
+            short = 'a',
+            long = "address",
+            help = "server address, format: IP:port"
+        )]
+        address: SocketAddr,
+        #[clap(
+            long = "remove",
+            help = "remove the backend from the configuration, the drain goes on until its connections are closed"
+        )]
+        remove: bool,
+    },
+    #[clap(
+        name = "set-weight",
+        about = "Change the weight of a backend for the load balancing"
+    )]
+    SetWeight {
+        #[clap(short = 'i', long = "id")]
+        id: String,
+        #[clap(long = "backend-id")]
+        backend_id: String,
+        #[clap(
+            short = 'a',
+            long = "address",
+            help = "server address, format: IP:port"
+        )]
+        address: SocketAddr,
+        #[clap(
+            short = 'w',
+            long = "weight",
+            help = "a backend of weight 0 gets no traffic"
+        )]
+        weight: u8,
+    },
+    #[clap(name = "add", about = "Add a backend")]
+    Add {
+        #[clap(short = 'i', long = "id")]
+        id: String,
+        #[clap(long = "backend-id")]
+        backend_id: String,
+        #[clap(
+            short = 'a',
+            long = "address",
+            help = "server address, format: IP:port"
+        )]
+        address: SocketAddr,
+        #[clap(
+            short = 's',
+            long = "sticky-id",
+            help = "value for the sticky session cookie"
+        )]
+        sticky_id: Option<String>,
+        #[clap(short = 'b', long = "backup", help = "set backend as a backup backend")]
+        backup: Option<bool>,
+        #[clap(
+            short = 'w',
+            long = "weight",
+            help = "weight of the backend for the load balancing, 100 by default"
+        )]
+        weight: Option<u8>,
+        #[clap(
+            long = "max-connections",
+            help = "connections opened to the backend at most"
+        )]
+        max_connections: Option<usize>,
+        #[clap(flatten)]
+        timeouts: TimeoutsArgs,
+        #[clap(flatten)]
+        tls: BackendTlsArgs,
+    },
+    #[clap(
+        name = "health",
+        about = "Show the health of the backends, as the workers see it"
+    )]
+    Health {
+        #[clap(short = 'i', long = "id", help = "cluster identifier")]
+        id: Option<String>,
+        #[clap(long = "json", help = "Print the command result in JSON format")]
+        json: bool,
+        #[clap(flatten)]
+        output: OutputArgs,
+    },
+    #[clap(
+        name = "list",
+        about = "List the backends with their weight, health and connections, and the frontends of their cluster"
+    )]
+    List {
+        #[clap(short = 'i', long = "id", help = "cluster identifier")]
+        id: Option<String>,
+        #[clap(long = "json", help = "Print the command result in JSON format")]
+        json: bool,
+        #[clap(flatten)]
+        output: OutputArgs,
+    },
+}
+
+#[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
+pub enum FrontendCmd {
+    #[clap(name = "http", about = "HTTP frontend management")]
+    Http {
+        #[clap(subcommand)]
+        cmd: HttpFrontendCmd,
+    },
+    #[clap(name = "https", about = "HTTPS frontend management")]
+    Https {
+        #[clap(subcommand)]
+        cmd: HttpFrontendCmd,
+    },
+    #[clap(name = "tcp", about = "TCP frontend management")]
+    Tcp {
+        #[clap(subcommand)]
+        cmd: TcpFrontendCmd,
+    },
+    #[clap(
+        name = "sni",
+        about = "routing of the TLS connections of TCP listeners by server name"
+    )]
+    Sni {
+        #[clap(subcommand)]
+        cmd: SniFrontendCmd,
+    },
+    #[clap(name = "list", about = "List frontends using filters")]
+    List {
+        #[clap(long = "http", help = "filter for http frontends")]
+        http: bool,
+        #[clap(long = "https", help = "filter for https frontends")]
+        https: bool,
+        #[clap(long = "tcp", help = "filter for tcp and sni frontends")]
+        tcp: bool,
+        #[clap(
+            short = 'd',
+            long = "domain",
+            help = "filter by domain name (for http & https frontends)"
+        )]
+        domain: Option<String>,
+        #[clap(
+            long = "watch",
+            help = "keeps the connection open and redraws the result when it changes, checked every N seconds, 2 by default",
+            num_args = 0..=1,
+            default_missing_value = "2"
+        )]
+        watch: Option<u64>,
+        #[clap(flatten)]
+        output: OutputArgs,
+    },
+}
+
+#[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
+pub enum Route {
+    /// traffic will go to the backend servers with this cluster id
+    Id {
+        /// traffic will go to the backend servers with this cluster id
+        id: String,
+    },
+    /// traffic to this frontend will be rejected with an HTTP error
+    Deny {
+        #[clap(
+            long = "status",
+            help = "HTTP status code of the answer, between 400 and 599",
+            default_value = "401",
+            value_parser = parse_deny_status
+        )]
+        status: u16,
+        #[clap(
+            long = "body",
+            help = "path to a file containing the body of the answer"
+        )]
+        body_path: Option<String>,
+    },
+}
+
+#[allow(clippy::from_over_into)]
+impl std::convert::Into<sozu_command_lib::proxy::Route> for Route {
+    fn into(self) -> sozu_command_lib::proxy::Route {
+        match self {
+            Route::Deny { status, body_path } => {
+                sozu_command_lib::proxy::Route::Deny { status, body_path }
+            }
+            Route::Id { id } => sozu_command_lib::proxy::Route::ClusterId(id),
+        }
+    }
+}
+
+#[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
+pub enum HttpFrontendCmd {
+    #[clap(name = "add")]
+    Add {
+        #[clap(
+            short = 'a',
+            long = "address",
+            help = "frontend address, format: IP:port"
+        )]
+        address: SocketAddr,
+        #[clap(subcommand, name = "route")]
+        route: Option<Route>,
+        #[clap(
+            long = "route-weighted",
+            help = "split the traffic between clusters, format: cluster_id=weight,other_cluster_id=weight",
+            value_delimiter = ','
+        )]
+        route_weighted: Vec<WeightedCluster>,
+        #[clap(
+            long = "ab-test-cookie",
+            help = "turn the weighted route into an A/B test: users are assigned one of the clusters, stored in this cookie",
+            requires = "route_weighted"
+        )]
+        ab_test_cookie: Option<String>,
+        #[clap(
+            long = "redirect-to",
+            help = "answer with a redirection to this target, it can contain the {host} and {path} placeholders"
+        )]
+        redirect_to: Option<String>,
+        #[clap(
+            long = "redirect-code",
+            help = "HTTP status code of the redirection: 301, 302, 307 or 308 (default: 301)",
+            requires = "redirect_to",
+            value_parser = parse_redirect_code
+        )]
+        redirect_code: Option<u16>,
+        #[clap(
+            long = "hostname",
+            aliases = &["host"],
+            help = "hostname of the frontend, or a wildcard: *.example.com (one label), **.example.com (one or more labels), * (any hostname)"
+        )]
+        hostname: String,
+        #[clap(short = 'p', long = "path-prefix", help = "URL prefix of the frontend")]
+        path_prefix: Option<String>,
+        #[clap(
+            long = "path-regex",
+            help = "the frontend URL path should match this regex"
+        )]
+        path_regex: Option<String>,
+        #[clap(
             long = "path-equals",
             help = "the frontend URL path should equal this regex"
         )]
         path_equals: Option<String>,
         #[clap(short = 'm', long = "method", help = "HTTP method")]
         method: Option<String>,
+        #[clap(
+            long = "methods",
+            help = "the request method should be one of these, format: GET,HEAD,POST",
+            value_delimiter = ','
+        )]
+        methods: Vec<String>,
+        #[clap(
+            long = "reject-other-methods",
+            help = "answer 405 to the requests with another method, instead of trying the other frontends"
+        )]
+        reject_other_methods: bool,
+        #[clap(
+            long = "header",
+            help = "the request should have a header with this exact value, format: name=value",
+            value_parser = parse_header
+        )]
+        header: Vec<(String, String)>,
+        #[clap(
+            long = "header-prefix",
+            help = "the request should have a header starting with this value, format: name=value",
+            value_parser = parse_header
+        )]
+        header_prefix: Vec<(String, String)>,
+        #[clap(
+            long = "header-regex",
+            help = "the request should have a header matching this regex, format: name=regex",
+            value_parser = parse_header
+        )]
+        header_regex: Vec<(String, String)>,
+        #[clap(
+            long = "strip-prefix",
+            help = "remove the path prefix of the frontend from the request path before sending it to the backend"
+        )]
+        strip_prefix: bool,
+        #[clap(
+            long = "rewrite-path",
+            help = "replace the parts of the request path matching a regex before sending it to the backend",
+            num_args = 2,
+            value_names = &["REGEX", "REPLACEMENT"]
+        )]
+        rewrite_path: Vec<String>,
+        #[clap(
+            long = "mirror",
+            help = "send a copy of the requests to this cluster, discarding its responses"
+        )]
+        mirror_cluster_id: Option<String>,
         #[clap(long = "tags", help = "Specify tag (key-value pair) to apply on front-end (example: 'key=value, other-key=other-value')", value_parser = parse_tags)]
         tags: Option<BTreeMap<String, String>>,
+        #[clap(
+            long = "strict",
+            help = "HTTPS only: refuse the frontend if no certificate of the listener covers its hostname, instead of warning"
+        )]
+        strict: bool,
     },
     #[clap(name = "remove")]
     Remove {
@@ -457,8 +1674,30 @@ pub enum HttpFrontendCmd {
         )]
         address: SocketAddr,
         #[clap(subcommand, name = "route")]
-        route: Route,
-        #[clap(long = "hostname", aliases = &["host"])]
+        route: Option<Route>,
+        #[clap(
+            long = "route-weighted",
+            help = "split the traffic between clusters, format: cluster_id=weight,other_cluster_id=weight",
+            value_delimiter = ','
+        )]
+        route_weighted: Vec<WeightedCluster>,
+        #[clap(
+            long = "redirect-to",
+            help = "answer with a redirection to this target, it can contain the {host} and {path} placeholders"
+        )]
+        redirect_to: Option<String>,
+        #[clap(
+            long = "redirect-code",
+            help = "HTTP status code of the redirection: 301, 302, 307 or 308 (default: 301)",
+            requires = "redirect_to",
+            value_parser = parse_redirect_code
+        )]
+        redirect_code: Option<u16>,
+        #[clap(
+            long = "hostname",
+            aliases = &["host"],
+            help = "hostname of the frontend, or a wildcard: *.example.com (one label), **.example.com (one or more labels), * (any hostname)"
+        )]
         hostname: String,
         #[clap(short = 'p', long = "path-prefix", help = "URL prefix of the frontend")]
         path_prefix: Option<String>,
@@ -474,6 +1713,30 @@ pub enum HttpFrontendCmd {
         path_equals: Option<String>,
         #[clap(short = 'm', long = "method", help = "HTTP method")]
         method: Option<String>,
+        #[clap(
+            long = "methods",
+            help = "the request method should be one of these, format: GET,HEAD,POST",
+            value_delimiter = ','
+        )]
+        methods: Vec<String>,
+        #[clap(
+            long = "header",
+            help = "the request should have a header with this exact value, format: name=value",
+            value_parser = parse_header
+        )]
+        header: Vec<(String, String)>,
+        #[clap(
+            long = "header-prefix",
+            help = "the request should have a header starting with this value, format: name=value",
+            value_parser = parse_header
+        )]
+        header_prefix: Vec<(String, String)>,
+        #[clap(
+            long = "header-regex",
+            help = "the request should have a header matching this regex, format: name=regex",
+            value_parser = parse_header
+        )]
+        header_regex: Vec<(String, String)>,
     },
 }
 
@@ -517,6 +1780,52 @@ pub enum TcpFrontendCmd {
     },
 }
 
+#[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
+pub enum SniFrontendCmd {
+    #[clap(name = "add")]
+    Add {
+        #[clap(
+            short = 'i',
+            long = "id",
+            help = "the id of the cluster to which the frontend belongs"
+        )]
+        id: String,
+        #[clap(
+            short = 'a',
+            long = "address",
+            help = "address of the TCP listener, format: IP:port"
+        )]
+        address: SocketAddr,
+        #[clap(
+            long = "hostname",
+            aliases = &["host"],
+            help = "server name of the TLS connections, wildcards like *.example.com are accepted"
+        )]
+        hostname: String,
+    },
+    #[clap(name = "remove")]
+    Remove {
+        #[clap(
+            short = 'i',
+            long = "id",
+            help = "the id of the cluster to which the frontend belongs"
+        )]
+        id: String,
+        #[clap(
+            short = 'a',
+            long = "address",
+            help = "address of the TCP listener, format: IP:port"
+        )]
+        address: SocketAddr,
+        #[clap(
+            long = "hostname",
+            aliases = &["host"],
+            help = "server name of the TLS connections"
+        )]
+        hostname: String,
+    },
+}
+
 #[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
 pub enum ListenerCmd {
     #[clap(name = "http", about = "HTTP listener management")]
@@ -564,6 +1873,16 @@ pub enum HttpListenerCmd {
         expect_proxy: bool,
         #[clap(long = "sticky-name", help = "sticky session cookie name")]
         sticky_name: Option<String>,
+        #[clap(
+            long = "fallback-cluster",
+            help = "cluster receiving the requests matching no frontend, instead of answering a 404"
+        )]
+        fallback_cluster: Option<String>,
+        #[clap(
+            long = "max-sessions",
+            help = "client sessions open on the listener at most, the next ones are answered with a 503"
+        )]
+        max_sessions: Option<usize>,
         #[clap(long = "front-timeout", help = "Set front timeout")]
         front_timeout: Option<u32>,
         #[clap(long = "back-timeout", help = "Set back timeout")]
@@ -572,6 +1891,12 @@ pub enum HttpListenerCmd {
         request_timeout: Option<u32>,
         #[clap(long = "connect-timeout", help = "Set connect timeout")]
         connect_timeout: Option<u32>,
+        #[clap(flatten)]
+        request_limits: RequestLimitsArgs,
+        #[clap(flatten)]
+        compression: CompressionArgs,
+        #[clap(flatten)]
+        path_normalization: PathNormalizationArgs,
     },
     #[clap(name = "remove")]
     Remove {
@@ -623,6 +1948,12 @@ pub enum HttpsListenerCmd {
             help = "path to file of the 503 answer sent to the client when a cluster has no backends available"
         )]
         answer_503: Option<String>,
+        #[clap(
+            long = "tls-provider",
+            help = "implementation of TLS serving this listener (rustls|openssl), the one of the configuration by default",
+            value_parser = parse_tls_provider
+        )]
+        tls_provider: Option<TlsProvider>,
         #[clap(long = "tls-versions", help = "list of TLS versions to use")]
         tls_versions: Vec<TlsVersion>,
         #[clap(
@@ -652,6 +1983,16 @@ pub enum HttpsListenerCmd {
         expect_proxy: bool,
         #[clap(long = "sticky-name", help = "sticky session cookie name")]
         sticky_name: Option<String>,
+        #[clap(
+            long = "fallback-cluster",
+            help = "cluster receiving the requests matching no frontend, instead of answering a 404"
+        )]
+        fallback_cluster: Option<String>,
+        #[clap(
+            long = "max-sessions",
+            help = "client sessions open on the listener at most, the next ones are answered with a 503"
+        )]
+        max_sessions: Option<usize>,
         #[clap(long = "front-timeout", help = "Set front timeout")]
         front_timeout: Option<u32>,
         #[clap(long = "back-timeout", help = "Set back timeout")]
@@ -660,6 +2001,21 @@ pub enum HttpsListenerCmd {
         request_timeout: Option<u32>,
         #[clap(long = "connect-timeout", help = "Set connect timeout")]
         connect_timeout: Option<u32>,
+        #[clap(
+            long = "strict-sni",
+            help = "refuses the handshakes without a server name or with an unknown one, instead of presenting the default certificate"
+        )]
+        strict_sni: bool,
+        #[clap(flatten)]
+        request_limits: RequestLimitsArgs,
+        #[clap(flatten)]
+        compression: CompressionArgs,
+        #[clap(flatten)]
+        path_normalization: PathNormalizationArgs,
+        #[clap(flatten)]
+        client_auth: ClientAuthArgs,
+        #[clap(flatten)]
+        http2: Http2Args,
     },
     #[clap(name = "remove")]
     Remove {
@@ -710,6 +2066,23 @@ pub enum TcpListenerCmd {
             help = "Configures the client socket to receive a PROXY protocol header"
         )]
         expect_proxy: bool,
+        #[clap(
+            long = "forward-destination",
+            help = "turns the listener into a forward proxy reaching this destination, format: host:port, *.domain:port or IP range:port, the port can be *",
+            value_delimiter = ','
+        )]
+        forward_destinations: Vec<Destination>,
+        #[clap(
+            long = "socks5",
+            requires = "forward_destinations",
+            help = "the forward proxy accepts SOCKS5 handshakes besides the HTTP CONNECT requests"
+        )]
+        socks5: bool,
+        #[clap(
+            long = "max-sessions",
+            help = "client sessions open on the listener at most, the next connections are refused"
+        )]
+        max_sessions: Option<usize>,
     },
     #[clap(name = "remove")]
     Remove {
@@ -750,18 +2123,128 @@ pub enum CertificateCmd {
             help = "listener address, format: IP:port"
         )]
         address: SocketAddr,
-        #[clap(long = "certificate", help = "path to the certificate")]
+        #[clap(
+            long = "certificate",
+            help = "path to the certificate, PEM or DER encoded",
+            required_unless_present = "pkcs12",
+            conflicts_with = "pkcs12"
+        )]
+        certificate: Option<String>,
+        #[clap(
+            long = "certificate-chain",
+            help = "path to the certificate chain, PEM encoded or a single DER certificate",
+            required_unless_present = "pkcs12",
+            conflicts_with = "pkcs12"
+        )]
+        chain: Option<String>,
+        #[clap(
+            long = "key",
+            help = "path to the key, PEM or DER encoded",
+            required_unless_present = "pkcs12",
+            conflicts_with = "pkcs12"
+        )]
+        key: Option<String>,
+        #[clap(
+            long = "key-passphrase",
+            help = "passphrase of an encrypted key: pass:<passphrase>, file:<path> or env:<variable>",
+            requires = "key"
+        )]
+        key_passphrase: Option<String>,
+        #[clap(
+            long = "pkcs12",
+            help = "path to a PKCS#12 bundle with the certificate, its chain and its key"
+        )]
+        pkcs12: Option<String>,
+        #[clap(
+            long = "password-file",
+            help = "path to a file containing the password of the PKCS#12 bundle",
+            requires = "pkcs12"
+        )]
+        password_file: Option<String>,
+        #[clap(long = "tls-versions", help = "accepted TLS versions for this certificate",
+                value_parser = parse_tls_versions)]
+        tls_versions: Vec<TlsVersion>,
+        #[clap(
+            long = "ocsp-response",
+            help = "path to a DER encoded OCSP response, stapled with the certificate"
+        )]
+        ocsp_response: Option<String>,
+        #[clap(
+            long = "priority",
+            help = "preference over the other certificates providing the same names",
+            default_value = "0",
+            allow_hyphen_values = true
+        )]
+        priority: i32,
+    },
+    #[clap(name = "remove", about = "Remove a certificate")]
+    Remove {
+        #[clap(
+            short = 'a',
+            long = "address",
+            help = "listener address, format: IP:port"
+        )]
+        address: SocketAddr,
+        #[clap(aliases = &["cert"], long = "certificate", help = "path to the certificate")]
+        certificate: Option<String>,
+        #[clap(short = 'f', long = "fingerprint", help = "certificate fingerprint")]
+        fingerprint: Option<String>,
+    },
+    #[clap(name = "replace", about = "Replace an existing certificate")]
+    Replace {
+        #[clap(
+            short = 'a',
+            long = "address",
+            help = "listener address, format: IP:port"
+        )]
+        address: SocketAddr,
+        #[clap(long = "new-certificate", help = "path to the new certificate")]
         certificate: String,
-        #[clap(long = "certificate-chain", help = "path to the certificate chain")]
+        #[clap(
+            long = "new-certificate-chain",
+            help = "path to the new certificate chain"
+        )]
         chain: String,
-        #[clap(long = "key", help = "path to the key")]
+        #[clap(long = "new-key", help = "path to the new key")]
         key: String,
+        #[clap(
+            long = "new-key-passphrase",
+            help = "passphrase of the new key, if encrypted: pass:<passphrase>, file:<path> or env:<variable>"
+        )]
+        key_passphrase: Option<String>,
+        #[clap(
+            aliases = &["old-cert"],
+            long = "old-certificate",
+            help = "path to the old certificate"
+        )]
+        old_certificate: Option<String>,
+        #[clap(
+            short = 'f',
+            long = "fingerprint",
+            help = "old certificate fingerprint"
+        )]
+        old_fingerprint: Option<String>,
         #[clap(long = "tls-versions", help = "accepted TLS versions for this certificate",
                 value_parser = parse_tls_versions)]
         tls_versions: Vec<TlsVersion>,
+        #[clap(
+            long = "ocsp-response",
+            help = "path to a DER encoded OCSP response, stapled with the certificate"
+        )]
+        ocsp_response: Option<String>,
+        #[clap(
+            long = "priority",
+            help = "preference over the other certificates providing the same names",
+            default_value = "0",
+            allow_hyphen_values = true
+        )]
+        priority: i32,
     },
-    #[clap(name = "remove", about = "Remove a certificate")]
-    Remove {
+    #[clap(
+        name = "set-default",
+        about = "Set the certificate presented to the clients sending no server name or an unknown one, unset it without certificate or fingerprint"
+    )]
+    SetDefault {
         #[clap(
             short = 'a',
             long = "address",
@@ -773,38 +2256,93 @@ pub enum CertificateCmd {
         #[clap(short = 'f', long = "fingerprint", help = "certificate fingerprint")]
         fingerprint: Option<String>,
     },
-    #[clap(name = "replace", about = "Replace an existing certificate")]
-    Replace {
+    #[clap(
+        name = "list",
+        about = "List the certificates of the listeners, with their subject, names, expiration and the hostnames of the frontends they cover"
+    )]
+    List {
+        #[clap(
+            long = "expiring",
+            help = "only the certificates expiring within this duration, format: 30d, 12h, 45m, 3600s",
+            value_parser = parse_duration
+        )]
+        expiring: Option<u64>,
+        #[clap(
+            short = 'd',
+            long = "domain",
+            help = "only the certificates whose names contain this domain"
+        )]
+        domain: Option<String>,
+        #[clap(
+            short = 'a',
+            long = "address",
+            help = "only the certificates of the listener at this address, format: IP:port"
+        )]
+        address: Option<SocketAddr>,
+        #[clap(long = "json", help = "Print the command result in JSON format")]
+        json: bool,
+        #[clap(flatten)]
+        output: OutputArgs,
+    },
+}
+
+#[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
+pub enum AclCmd {
+    #[clap(
+        name = "add",
+        about = "Set the ACL of a listener, or of a hostname on this listener"
+    )]
+    Add {
         #[clap(
             short = 'a',
             long = "address",
             help = "listener address, format: IP:port"
         )]
         address: SocketAddr,
-        #[clap(long = "new-certificate", help = "path to the new certificate")]
-        certificate: String,
         #[clap(
-            long = "new-certificate-chain",
-            help = "path to the new certificate chain"
+            short = 'p',
+            long = "proxy",
+            help = "listener type: http, https or tcp",
+            value_parser = parse_listener_type
         )]
-        chain: String,
-        #[clap(long = "new-key", help = "path to the new key")]
-        key: String,
+        proxy: ListenerType,
         #[clap(
-            aliases = &["old-cert"],
-            long = "old-certificate",
-            help = "path to the old certificate"
+            long = "hostname",
+            help = "apply the ACL to requests for this hostname only, on all its frontends (HTTP and HTTPS)"
         )]
-        old_certificate: Option<String>,
+        hostname: Option<String>,
         #[clap(
-            short = 'f',
-            long = "fingerprint",
-            help = "old certificate fingerprint"
+            short = 'm',
+            long = "mode",
+            help = "allow only the listed ranges, or deny them: allow or deny",
+            value_parser = parse_acl_mode
         )]
-        old_fingerprint: Option<String>,
-        #[clap(long = "tls-versions", help = "accepted TLS versions for this certificate",
-                value_parser = parse_tls_versions)]
-        tls_versions: Vec<TlsVersion>,
+        mode: AclMode,
+        #[clap(
+            short = 'r',
+            long = "range",
+            help = "IP address or CIDR range, like 10.0.0.0/8",
+            required = true
+        )]
+        ranges: Vec<IpRange>,
+    },
+    #[clap(name = "remove", about = "Remove the ACL of a listener or hostname")]
+    Remove {
+        #[clap(
+            short = 'a',
+            long = "address",
+            help = "listener address, format: IP:port"
+        )]
+        address: SocketAddr,
+        #[clap(
+            short = 'p',
+            long = "proxy",
+            help = "listener type: http, https or tcp",
+            value_parser = parse_listener_type
+        )]
+        proxy: ListenerType,
+        #[clap(long = "hostname")]
+        hostname: Option<String>,
     },
 }
 
@@ -826,6 +2364,23 @@ pub enum QueryCmd {
         fingerprint: Option<String>,
         #[clap(short = 'd', long = "domain", help = "domain name")]
         domain: Option<String>,
+        #[clap(
+            short = 'a',
+            long = "address",
+            help = "listener address, shows the certificate it presents for the domain, or without a domain its default certificate",
+            conflicts_with = "fingerprint"
+        )]
+        address: Option<SocketAddr>,
+    },
+    #[clap(
+        name = "backend-health",
+        about = "Query the health of the backends, as the workers see it"
+    )]
+    BackendHealth {
+        #[clap(short = 'i', long = "id", help = "cluster identifier")]
+        id: Option<String>,
+        #[clap(flatten)]
+        output: OutputArgs,
     },
 }
 
@@ -833,6 +2388,24 @@ pub enum QueryCmd {
 pub enum ConfigCmd {
     #[clap(name = "check", about = "check configuration file syntax and exit")]
     Check {},
+    #[clap(
+        name = "diff",
+        about = "List the differences between the state built by the configuration file and the current one, without applying anything"
+    )]
+    Diff {
+        #[clap(
+            short = 'f',
+            long = "file",
+            help = "configuration file to compare, the current one by default"
+        )]
+        file: Option<String>,
+        #[clap(
+            short = 'j',
+            long = "json",
+            help = "Print the command result in JSON format"
+        )]
+        json: bool,
+    },
 }
 
 fn parse_tls_versions(i: &str) -> Result<TlsVersion, String> {
@@ -845,6 +2418,266 @@ fn parse_tls_versions(i: &str) -> Result<TlsVersion, String> {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+fn parse_completion_shell(i: &str) -> Result<CompletionShell, String> {
+    match i {
+        "bash" => Ok(CompletionShell::Bash),
+        "zsh" => Ok(CompletionShell::Zsh),
+        "fish" => Ok(CompletionShell::Fish),
+        s => Err(format!("unsupported shell: {}", s)),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Yaml,
+    Csv,
+}
+
+fn parse_output_format(i: &str) -> Result<OutputFormat, String> {
+    match i {
+        "table" => Ok(OutputFormat::Table),
+        "json" => Ok(OutputFormat::Json),
+        "yaml" => Ok(OutputFormat::Yaml),
+        "csv" => Ok(OutputFormat::Csv),
+        s => Err(format!(
+            "unrecognized output format '{}', expected: table|json|yaml|csv",
+            s
+        )),
+    }
+}
+
+fn parse_filter(string_to_parse: &str) -> Result<(String, String), String> {
+    match string_to_parse.split_once('=') {
+        Some((column, value)) if !column.trim().is_empty() => {
+            Ok((column.trim().to_owned(), value.to_owned()))
+        }
+        _ => Err(format!(
+            "could not parse the filter '{}', expected format: column=value",
+            string_to_parse
+        )),
+    }
+}
+
+fn parse_tls_provider(string_to_parse: &str) -> Result<TlsProvider, String> {
+    match string_to_parse {
+        "rustls" => Ok(TlsProvider::Rustls),
+        "openssl" => Ok(TlsProvider::Openssl),
+        s => Err(format!(
+            "unrecognized TLS provider '{}', expected: rustls|openssl",
+            s
+        )),
+    }
+}
+
+fn parse_header(string_to_parse: &str) -> Result<(String, String), String> {
+    match string_to_parse.split_once('=') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_owned(), value.trim().to_owned()))
+        }
+        _ => Err(format!(
+            "could not parse the header rule '{}', expected format: name=value",
+            string_to_parse
+        )),
+    }
+}
+
+fn parse_header_action(string_to_parse: &str) -> Result<(HeaderOperation, String, String), String> {
+    let error = || {
+        format!(
+            "could not parse the header action '{}', expected format: add|set|remove:name[=value]",
+            string_to_parse
+        )
+    };
+
+    let (operation, header) = string_to_parse.split_once(':').ok_or_else(error)?;
+    let operation = operation
+        .trim()
+        .parse::<HeaderOperation>()
+        .map_err(|_| error())?;
+    let (name, value) = header.split_once('=').unwrap_or((header, ""));
+    if !is_header_name(name.trim()) || !is_header_value(value) {
+        return Err(error());
+    }
+
+    Ok((operation, name.trim().to_owned(), value.trim().to_owned()))
+}
+
+fn parse_host_rewrite(string_to_parse: &str) -> Result<HostRewrite, String> {
+    let host_rewrite = match string_to_parse.split_once('=') {
+        None if string_to_parse == "preserve" => Some(HostRewrite::Preserve),
+        None if string_to_parse == "backend_address" => Some(HostRewrite::BackendAddress),
+        Some(("fixed", host)) => Some(HostRewrite::Fixed(host.trim().to_owned())),
+        _ => None,
+    };
+
+    match host_rewrite {
+        Some(host_rewrite) if host_rewrite.validate().is_ok() => Ok(host_rewrite),
+        _ => Err(format!(
+            "could not parse the host rewrite '{}', expected format: preserve|backend_address|fixed=hostname",
+            string_to_parse
+        )),
+    }
+}
+
+fn parse_hash_key(string_to_parse: &str) -> Result<HashKey, String> {
+    match string_to_parse.split_once('=') {
+        None if string_to_parse == "url" => Ok(HashKey::Url),
+        None if string_to_parse == "source_ip" => Ok(HashKey::SourceIp),
+        Some(("header", name)) if !name.trim().is_empty() => {
+            Ok(HashKey::Header(name.trim().to_owned()))
+        }
+        Some(("cookie", name)) if !name.trim().is_empty() => {
+            Ok(HashKey::Cookie(name.trim().to_owned()))
+        }
+        _ => Err(format!(
+            "could not parse the hash key '{}', expected format: url|source_ip|header=name|cookie=name",
+            string_to_parse
+        )),
+    }
+}
+
+fn parse_sticky_mode(string_to_parse: &str) -> Result<StickyMode, String> {
+    match string_to_parse.split_once('=') {
+        None if string_to_parse == "cookie" => Ok(StickyMode::Cookie),
+        None if string_to_parse == "source_ip" => Ok(StickyMode::SourceIp),
+        Some(("header", name)) if !name.trim().is_empty() => {
+            Ok(StickyMode::Header(name.trim().to_owned()))
+        }
+        _ => Err(format!(
+            "could not parse the sticky mode '{}', expected format: cookie|source_ip|header=name",
+            string_to_parse
+        )),
+    }
+}
+
+fn parse_retry_condition(string_to_parse: &str) -> Result<RetryCondition, String> {
+    match string_to_parse {
+        "connect_failure" => Ok(RetryCondition::ConnectFailure),
+        "http_502" => Ok(RetryCondition::Http502),
+        "http_503" => Ok(RetryCondition::Http503),
+        _ => Err(format!(
+            "could not parse the retry condition '{}', expected format: connect_failure|http_502|http_503",
+            string_to_parse
+        )),
+    }
+}
+
+fn parse_trailing_slash(string_to_parse: &str) -> Result<TrailingSlash, String> {
+    match string_to_parse {
+        "keep" => Ok(TrailingSlash::Keep),
+        "add" => Ok(TrailingSlash::Add),
+        "remove" => Ok(TrailingSlash::Remove),
+        _ => Err(format!(
+            "could not parse the trailing slash handling '{}', expected format: keep|add|remove",
+            string_to_parse
+        )),
+    }
+}
+
+fn parse_redirect_code(string_to_parse: &str) -> Result<u16, String> {
+    match string_to_parse.parse::<u16>() {
+        Ok(code) if REDIRECT_CODES.contains(&code) => Ok(code),
+        _ => Err(format!(
+            "invalid redirect code '{}', expected one of {:?}",
+            string_to_parse, REDIRECT_CODES
+        )),
+    }
+}
+
+fn parse_deny_status(string_to_parse: &str) -> Result<u16, String> {
+    match string_to_parse.parse::<u16>() {
+        Ok(status) if is_deny_status(status) => Ok(status),
+        _ => Err(format!(
+            "invalid deny status '{}', expected a code between 400 and 599",
+            string_to_parse
+        )),
+    }
+}
+
+fn parse_status_range(string_to_parse: &str) -> Result<StatusRange, String> {
+    let error = || {
+        format!(
+            "invalid status '{}', expected a code like 502, a class like 5xx or a range like 400-499",
+            string_to_parse
+        )
+    };
+    let status = |code: &str| match code.parse::<u16>() {
+        Ok(status) if (100..=599).contains(&status) => Ok(status),
+        _ => Err(error()),
+    };
+
+    if let Some(class) = string_to_parse.strip_suffix("xx") {
+        let from = status(&format!("{}00", class))?;
+        return Ok(StatusRange {
+            from,
+            to: from + 99,
+        });
+    }
+
+    let (from, to) = match string_to_parse.split_once('-') {
+        Some((from, to)) => (status(from)?, status(to)?),
+        None => (status(string_to_parse)?, status(string_to_parse)?),
+    };
+    if from > to {
+        return Err(error());
+    }
+    Ok(StatusRange { from, to })
+}
+
+fn parse_listener_type(string_to_parse: &str) -> Result<ListenerType, String> {
+    match string_to_parse {
+        "http" => Ok(ListenerType::HTTP),
+        "https" => Ok(ListenerType::HTTPS),
+        "tcp" => Ok(ListenerType::TCP),
+        s => Err(format!(
+            "unrecognized listener type '{}', expected http, https or tcp",
+            s
+        )),
+    }
+}
+
+fn parse_acl_mode(string_to_parse: &str) -> Result<AclMode, String> {
+    match string_to_parse {
+        "allow" => Ok(AclMode::Allow),
+        "deny" => Ok(AclMode::Deny),
+        s => Err(format!(
+            "unrecognized ACL mode '{}', expected allow or deny",
+            s
+        )),
+    }
+}
+
+/// parses a duration in seconds, with an optional d, h, m or s unit
+fn parse_duration(string_to_parse: &str) -> Result<u64, String> {
+    let (value, multiplier) = match string_to_parse.char_indices().last() {
+        Some((i, 'd')) => (&string_to_parse[..i], 24 * 3600),
+        Some((i, 'h')) => (&string_to_parse[..i], 3600),
+        Some((i, 'm')) => (&string_to_parse[..i], 60),
+        Some((i, 's')) => (&string_to_parse[..i], 1),
+        _ => (string_to_parse, 1),
+    };
+
+    value
+        .parse::<u64>()
+        .ok()
+        .and_then(|value| value.checked_mul(multiplier))
+        .ok_or_else(|| {
+            format!(
+                "could not parse the duration '{}', expected a number of d, h, m or s",
+                string_to_parse
+            )
+        })
+}
+
 fn parse_tags(string_to_parse: &str) -> Result<BTreeMap<String, String>, String> {
     let mut tags: BTreeMap<String, String> = BTreeMap::new();
 
@@ -883,4 +2716,291 @@ mod tests {
             parse_tags(tags_to_parse)
         );
     }
+
+    #[test]
+    fn parse_host_rewrite_from_string() {
+        use super::*;
+
+        assert_eq!(Ok(HostRewrite::Preserve), parse_host_rewrite("preserve"));
+        assert_eq!(
+            Ok(HostRewrite::BackendAddress),
+            parse_host_rewrite("backend_address")
+        );
+        assert_eq!(
+            Ok(HostRewrite::Fixed("app.platform.example".to_owned())),
+            parse_host_rewrite("fixed=app.platform.example")
+        );
+        assert!(parse_host_rewrite("fixed=").is_err());
+        assert!(parse_host_rewrite("backend").is_err());
+        assert!(parse_host_rewrite("fixed=app\r\nX-Evil: injected").is_err());
+    }
+
+    #[test]
+    fn parse_sticky_mode_from_string() {
+        use super::*;
+
+        assert_eq!(Ok(StickyMode::Cookie), parse_sticky_mode("cookie"));
+        assert_eq!(Ok(StickyMode::SourceIp), parse_sticky_mode("source_ip"));
+        assert_eq!(
+            Ok(StickyMode::Header("Authorization".to_owned())),
+            parse_sticky_mode("header=Authorization")
+        );
+        assert!(parse_sticky_mode("header=").is_err());
+        assert!(parse_sticky_mode("ip").is_err());
+    }
+
+    #[test]
+    fn parse_hash_key_from_string() {
+        use super::*;
+
+        assert_eq!(Ok(HashKey::Url), parse_hash_key("url"));
+        assert_eq!(Ok(HashKey::SourceIp), parse_hash_key("source_ip"));
+        assert_eq!(
+            Ok(HashKey::Cookie("session".to_owned())),
+            parse_hash_key("cookie=session")
+        );
+        assert_eq!(
+            Ok(HashKey::Header("X-Tenant".to_owned())),
+            parse_hash_key("header= X-Tenant")
+        );
+        assert!(parse_hash_key("cookie=").is_err());
+        assert!(parse_hash_key("path").is_err());
+    }
+
+    #[test]
+    fn parse_retry_condition_from_string() {
+        use super::*;
+
+        assert_eq!(
+            Ok(RetryCondition::ConnectFailure),
+            parse_retry_condition("connect_failure")
+        );
+        assert_eq!(
+            Ok(RetryCondition::Http502),
+            parse_retry_condition("http_502")
+        );
+        assert_eq!(
+            Ok(RetryCondition::Http503),
+            parse_retry_condition("http_503")
+        );
+        assert!(parse_retry_condition("http_500").is_err());
+    }
+
+    #[test]
+    fn parse_trailing_slash_from_string() {
+        use super::*;
+
+        assert_eq!(Ok(TrailingSlash::Keep), parse_trailing_slash("keep"));
+        assert_eq!(Ok(TrailingSlash::Add), parse_trailing_slash("add"));
+        assert_eq!(Ok(TrailingSlash::Remove), parse_trailing_slash("remove"));
+        assert!(parse_trailing_slash("strip").is_err());
+    }
+
+    #[test]
+    fn parse_acl_arguments() {
+        use super::*;
+
+        assert_eq!(Ok(ListenerType::HTTPS), parse_listener_type("https"));
+        assert!(parse_listener_type("udp").is_err());
+        assert_eq!(Ok(AclMode::Deny), parse_acl_mode("deny"));
+        assert!(parse_acl_mode("block").is_err());
+    }
+
+    #[test]
+    fn parse_header_from_string() {
+        use super::*;
+
+        assert_eq!(
+            Ok(("X-Tenant".to_owned(), "foo=bar".to_owned())),
+            parse_header("X-Tenant=foo=bar")
+        );
+        assert!(parse_header("X-Tenant").is_err());
+        assert!(parse_header("=foo").is_err());
+    }
+
+    #[test]
+    fn parse_header_action_from_string() {
+        use super::*;
+
+        assert_eq!(
+            Ok((
+                HeaderOperation::Set,
+                "X-Request-Start".to_owned(),
+                "t=1".to_owned()
+            )),
+            parse_header_action("set:X-Request-Start=t=1")
+        );
+        assert_eq!(
+            Ok((HeaderOperation::Remove, "Server".to_owned(), String::new())),
+            parse_header_action("remove:Server")
+        );
+        assert!(parse_header_action("Server").is_err());
+        assert!(parse_header_action("replace:Server").is_err());
+        assert!(parse_header_action("add:=foo").is_err());
+        assert!(parse_header_action("add:X Bad=foo").is_err());
+        assert!(parse_header_action("add:X-Foo=a\r\nEvil: injected").is_err());
+    }
+
+    #[test]
+    fn parse_deny_status_from_string() {
+        use super::*;
+
+        assert_eq!(Ok(429), parse_deny_status("429"));
+        assert!(parse_deny_status("302").is_err());
+        assert!(parse_deny_status("forbidden").is_err());
+    }
+
+    #[test]
+    fn parse_status_range_from_string() {
+        use super::*;
+
+        assert_eq!(
+            Ok(StatusRange { from: 500, to: 599 }),
+            parse_status_range("5xx")
+        );
+        assert_eq!(
+            Ok(StatusRange { from: 404, to: 404 }),
+            parse_status_range("404")
+        );
+        assert_eq!(
+            Ok(StatusRange { from: 400, to: 499 }),
+            parse_status_range("400-499")
+        );
+        assert!(parse_status_range("6xx").is_err());
+        assert!(parse_status_range("499-400").is_err());
+        assert!(parse_status_range("error").is_err());
+    }
+
+    #[test]
+    fn parse_redirect_code_from_string() {
+        use super::*;
+
+        assert_eq!(Ok(308), parse_redirect_code("308"));
+        assert!(parse_redirect_code("200").is_err());
+        assert!(parse_redirect_code("moved").is_err());
+    }
+
+    #[test]
+    fn parse_duration_from_string() {
+        use super::*;
+
+        assert_eq!(Ok(30 * 24 * 3600), parse_duration("30d"));
+        assert_eq!(Ok(12 * 3600), parse_duration("12h"));
+        assert_eq!(Ok(45 * 60), parse_duration("45m"));
+        assert_eq!(Ok(3600), parse_duration("3600s"));
+        assert_eq!(Ok(3600), parse_duration("3600"));
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("thirty days").is_err());
+    }
+
+    #[test]
+    fn parse_cluster_add_full() {
+        use super::*;
+
+        let args = Args::try_parse_from([
+            "sozu",
+            "cluster",
+            "add-full",
+            "--id",
+            "app",
+            "--load-balancing-policy",
+            "roundrobin",
+            "--hostname",
+            "example.com",
+            "--hostname",
+            "www.example.com",
+            "--http",
+            "0.0.0.0:80",
+            "--backend",
+            "10.0.0.1:8080",
+        ])
+        .unwrap();
+        match args.cmd {
+            SubCmd::Cluster {
+                cmd:
+                    ClusterCmd::AddFull {
+                        cluster,
+                        hostnames,
+                        http,
+                        https,
+                        backends,
+                        ..
+                    },
+            } => {
+                assert_eq!(cluster.id, "app");
+                assert_eq!(hostnames, vec!["example.com", "www.example.com"]);
+                assert_eq!(http, vec!["0.0.0.0:80".parse().unwrap()]);
+                assert!(https.is_empty());
+                assert_eq!(backends, vec!["10.0.0.1:8080".parse().unwrap()]);
+            }
+            cmd => panic!("unexpected command: {:?}", cmd),
+        }
+
+        // a frontend needs a listener, and a certificate an HTTPS one
+        let base = [
+            "sozu",
+            "cluster",
+            "add-full",
+            "--id",
+            "app",
+            "--load-balancing-policy",
+            "roundrobin",
+            "--hostname",
+            "example.com",
+            "--backend",
+            "10.0.0.1:8080",
+        ];
+        assert!(Args::try_parse_from(base).is_err());
+        assert!(Args::try_parse_from(base.iter().chain(&[
+            "--http",
+            "0.0.0.0:80",
+            "--certificate",
+            "cert.pem",
+            "--certificate-chain",
+            "chain.pem",
+            "--key",
+            "key.pem",
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn parse_worker_restart_and_internal_worker() {
+        use super::*;
+
+        let args = Args::try_parse_from(["sozu", "worker", "restart", "2"]).unwrap();
+        assert!(matches!(
+            args.cmd,
+            SubCmd::Worker {
+                cmd: Some(WorkerCmd::Restart { id: 2 }),
+                ..
+            }
+        ));
+
+        let args = Args::try_parse_from([
+            "sozu",
+            "worker",
+            "--id",
+            "0",
+            "--fd",
+            "3",
+            "--scm",
+            "4",
+            "--configuration-state-fd",
+            "5",
+        ])
+        .unwrap();
+        assert!(matches!(
+            args.cmd,
+            SubCmd::Worker {
+                cmd: None,
+                id: Some(0),
+                fd: Some(3),
+                ..
+            }
+        ));
+
+        // the internal command needs all its file descriptors
+        assert!(Args::try_parse_from(["sozu", "worker", "--id", "0"]).is_err());
+    }
 }
@@ -0,0 +1,164 @@
+//! authorizes the clients of the command socket to send some categories of
+//! orders, from the unix user and groups of their process
+use std::os::unix::io::RawFd;
+
+use nix::unistd::{geteuid, Gid, Group, Uid, User};
+
+use sozu_command_lib::{command::OrderCategory, config::CommandRole};
+
+const ALL_CATEGORIES: [OrderCategory; 4] = [
+    OrderCategory::Read,
+    OrderCategory::Mutation,
+    OrderCategory::Upgrade,
+    OrderCategory::Admin,
+];
+
+/// the user and primary group of the process at the other end of the socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: Uid,
+    pub gid: Gid,
+}
+
+#[cfg(target_os = "linux")]
+pub fn peer_credentials(fd: RawFd) -> nix::Result<PeerCredentials> {
+    use nix::sys::socket::{getsockopt, sockopt};
+
+    let credentials = getsockopt(fd, sockopt::PeerCredentials)?;
+    Ok(PeerCredentials {
+        uid: Uid::from_raw(credentials.uid()),
+        gid: Gid::from_raw(credentials.gid()),
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn peer_credentials(fd: RawFd) -> nix::Result<PeerCredentials> {
+    let (uid, gid) = nix::unistd::getpeereid(fd)?;
+    Ok(PeerCredentials { uid, gid })
+}
+
+/// the categories of orders the client can send. Without roles, only the owner
+/// of the socket can connect, so every order is allowed, as it is for root and
+/// the user running sozu. The other users get the orders of their roles
+pub fn allowed_categories(
+    roles: &[CommandRole],
+    peer: Option<PeerCredentials>,
+) -> Vec<OrderCategory> {
+    if roles.is_empty() {
+        return ALL_CATEGORIES.to_vec();
+    }
+
+    let peer = match peer {
+        Some(peer) => peer,
+        None => return Vec::new(),
+    };
+    if peer.uid.is_root() || peer.uid == geteuid() {
+        return ALL_CATEGORIES.to_vec();
+    }
+
+    let user = User::from_uid(peer.uid).ok().flatten();
+    let mut categories = Vec::new();
+    for role in roles {
+        let user_matches = role
+            .users
+            .iter()
+            .any(|name| user_matches(name, &peer, user.as_ref()));
+        let group_matches = role
+            .groups
+            .iter()
+            .any(|name| group_matches(name, &peer, user.as_ref()));
+
+        if user_matches || group_matches {
+            for category in &role.orders {
+                if !categories.contains(category) {
+                    categories.push(*category);
+                }
+            }
+        }
+    }
+    categories
+}
+
+/// the name of the user of the peer, or its uid if it has none
+pub fn user_name(peer: &PeerCredentials) -> String {
+    match User::from_uid(peer.uid) {
+        Ok(Some(user)) => user.name,
+        _ => peer.uid.to_string(),
+    }
+}
+
+fn user_matches(name: &str, peer: &PeerCredentials, user: Option<&User>) -> bool {
+    match name.parse::<u32>() {
+        Ok(uid) => uid == peer.uid.as_raw(),
+        Err(_) => user.map(|user| user.name == name).unwrap_or(false),
+    }
+}
+
+/// the peer is in the group if it is its primary group, or if its user is a member
+fn group_matches(name: &str, peer: &PeerCredentials, user: Option<&User>) -> bool {
+    let group = match name.parse::<u32>() {
+        Ok(gid) if gid == peer.gid.as_raw() => return true,
+        Ok(gid) => Group::from_gid(Gid::from_raw(gid)),
+        Err(_) => Group::from_name(name),
+    };
+
+    match group {
+        Ok(Some(group)) => {
+            group.gid == peer.gid
+                || user
+                    .map(|user| group.mem.contains(&user.name))
+                    .unwrap_or(false)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn role(users: &[&str], groups: &[&str], orders: &[OrderCategory]) -> CommandRole {
+        CommandRole {
+            users: users.iter().map(|user| user.to_string()).collect(),
+            groups: groups.iter().map(|group| group.to_string()).collect(),
+            orders: orders.to_vec(),
+        }
+    }
+
+    #[test]
+    fn roles_allow_categories() {
+        let roles = vec![
+            role(&["64001"], &[], &[OrderCategory::Read]),
+            role(
+                &[],
+                &["64100"],
+                &[OrderCategory::Read, OrderCategory::Mutation],
+            ),
+        ];
+        let peer = |uid, gid| {
+            Some(PeerCredentials {
+                uid: Uid::from_raw(uid),
+                gid: Gid::from_raw(gid),
+            })
+        };
+
+        assert_eq!(
+            allowed_categories(&[], peer(64002, 64002)),
+            ALL_CATEGORIES.to_vec()
+        );
+        assert_eq!(
+            allowed_categories(&roles, peer(0, 0)),
+            ALL_CATEGORIES.to_vec()
+        );
+        assert_eq!(
+            allowed_categories(&roles, peer(64001, 64001)),
+            vec![OrderCategory::Read]
+        );
+        assert_eq!(
+            allowed_categories(&roles, peer(64001, 64100)),
+            vec![OrderCategory::Read, OrderCategory::Mutation]
+        );
+        assert!(allowed_categories(&roles, peer(64002, 64002)).is_empty());
+        assert!(allowed_categories(&roles, None).is_empty());
+    }
+}
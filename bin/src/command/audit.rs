@@ -0,0 +1,184 @@
+//! the audit log of the orders changing the proxy: one JSON entry per line,
+//! appended to a file rotated once it reaches its maximum size
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+};
+
+use anyhow::Context;
+
+use sozu_command_lib::{command::AuditEntry, config::AuditLogConfig};
+
+pub struct AuditLog {
+    config: AuditLogConfig,
+    file: File,
+    /// size of the current file
+    size: u64,
+}
+
+impl AuditLog {
+    pub fn open(config: &AuditLogConfig) -> anyhow::Result<Self> {
+        let file = open_file(&config.path)?;
+        let size = file.metadata()?.len();
+        Ok(AuditLog {
+            config: config.clone(),
+            file,
+            size,
+        })
+    }
+
+    pub fn write(&mut self, entry: &AuditEntry) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_size {
+            self.rotate()?;
+        }
+
+        self.file
+            .write_all(&line)
+            .with_context(|| format!("could not write to the audit log {}", self.config.path))?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// the current file becomes path.1, the previous ones are shifted, and
+    /// the oldest one is dropped
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        let path = &self.config.path;
+        if self.config.max_files == 0 {
+            fs::remove_file(path)
+                .with_context(|| format!("could not remove the audit log {}", path))?;
+        } else {
+            for index in (1..self.config.max_files).rev() {
+                let rotated = rotated_path(path, index);
+                if fs::metadata(&rotated).is_ok() {
+                    fs::rename(&rotated, rotated_path(path, index + 1))
+                        .with_context(|| format!("could not rotate the audit log {}", rotated))?;
+                }
+            }
+            fs::rename(path, rotated_path(path, 1))
+                .with_context(|| format!("could not rotate the audit log {}", path))?;
+        }
+
+        self.file = open_file(path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    /// the last entries, from the current file and the rotated ones, the
+    /// oldest first
+    pub fn tail(&mut self, lines: usize) -> anyhow::Result<Vec<AuditEntry>> {
+        self.file.flush()?;
+
+        let mut entries = Vec::new();
+        let paths = std::iter::once(self.config.path.clone())
+            .chain((1..=self.config.max_files).map(|index| rotated_path(&self.config.path, index)));
+        for path in paths {
+            if entries.len() >= lines {
+                break;
+            }
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(_) => break,
+            };
+
+            let mut file_entries = Vec::new();
+            for line in BufReader::new(file).lines() {
+                let line =
+                    line.with_context(|| format!("could not read the audit log {}", path))?;
+                match serde_json::from_str::<AuditEntry>(&line) {
+                    Ok(entry) => file_entries.push(entry),
+                    Err(e) => {
+                        warn!("invalid entry in the audit log {}: {}", path, e);
+                    }
+                }
+            }
+            // the entries of this file are older than the ones already read
+            file_entries.append(&mut entries);
+            entries = file_entries;
+        }
+
+        let skipped = entries.len().saturating_sub(lines);
+        Ok(entries.split_off(skipped))
+    }
+}
+
+fn open_file(path: &str) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("could not open the audit log {}", path))
+}
+
+fn rotated_path(path: &str, index: usize) -> String {
+    format!("{}.{}", path, index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sozu_command_lib::command::{CommandRequestOrder, CommandStatus, OrderCategory};
+
+    fn entry(request_id: &str) -> AuditEntry {
+        AuditEntry {
+            timestamp: 0,
+            request_id: request_id.to_owned(),
+            client: String::from("CL-0"),
+            user: None,
+            uid: None,
+            category: OrderCategory::Mutation,
+            order: CommandRequestOrder::SyncPeers,
+            dry_run: false,
+            status: Some(CommandStatus::Ok),
+            message: String::new(),
+            version: None,
+        }
+    }
+
+    #[test]
+    fn audit_log_rotation() {
+        let directory = std::env::temp_dir().join(format!("sozu-audit-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("audit.log").to_str().unwrap().to_owned();
+
+        let line_size = serde_json::to_vec(&entry("ID-0")).unwrap().len() as u64 + 1;
+        let config = AuditLogConfig {
+            path: path.clone(),
+            // two entries per file
+            max_size: line_size * 2,
+            max_files: 2,
+        };
+
+        let mut audit_log = AuditLog::open(&config).unwrap();
+        for index in 0..7 {
+            audit_log.write(&entry(&format!("ID-{}", index))).unwrap();
+        }
+
+        // ID-0 and ID-1 were in the dropped file
+        assert!(fs::metadata(rotated_path(&path, 3)).is_err());
+        let ids = |entries: Vec<AuditEntry>| {
+            entries
+                .into_iter()
+                .map(|entry| entry.request_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ids(audit_log.tail(10).unwrap()),
+            vec!["ID-2", "ID-3", "ID-4", "ID-5", "ID-6"]
+        );
+        assert_eq!(
+            ids(audit_log.tail(3).unwrap()),
+            vec!["ID-4", "ID-5", "ID-6"]
+        );
+
+        // the entries are kept by the next main process
+        let mut reopened = AuditLog::open(&config).unwrap();
+        reopened.write(&entry("ID-7")).unwrap();
+        assert_eq!(ids(reopened.tail(2).unwrap()), vec!["ID-6", "ID-7"]);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
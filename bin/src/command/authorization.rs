@@ -0,0 +1,201 @@
+//! asks an external command or service whether a client can send an order
+//! changing the proxy, so that the policies of the operators are enforced by
+//! their own engines. Without a decision in time, the order is refused
+use std::{
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+
+use sozu_command_lib::{
+    command::{AuthorizationDecision, AuthorizationRequest},
+    config::CommandAuthorizationConfig,
+};
+
+/// duration between two checks of the end of the authorization command
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const MAX_DECISION_SIZE: usize = 65536;
+
+pub fn authorize(
+    config: &CommandAuthorizationConfig,
+    request: &AuthorizationRequest,
+) -> anyhow::Result<AuthorizationDecision> {
+    let request = serde_json::to_vec(request)?;
+    let timeout = Duration::from_secs(config.timeout);
+
+    match (&config.command, &config.socket) {
+        (Some(command), _) => run_command(command, &request, timeout),
+        (None, Some(socket)) => ask_service(socket, &request, timeout),
+        (None, None) => bail!("the command authorization has no command nor socket"),
+    }
+}
+
+/// the command reads the request on its standard input, and allows the order
+/// by exiting with the status 0
+fn run_command(
+    command: &[String],
+    request: &[u8],
+    timeout: Duration,
+) -> anyhow::Result<AuthorizationDecision> {
+    let (program, arguments) = command
+        .split_first()
+        .with_context(|| "the authorization command is empty")?;
+    let mut child = Command::new(program)
+        .args(arguments)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("could not run {}", program))?;
+
+    // written in a thread, for the timeout to apply to a command not reading
+    // it, and closed once written, for the command to see its end
+    if let Some(mut stdin) = child.stdin.take() {
+        let request = request.to_vec();
+        thread::spawn(move || {
+            let _ = stdin.write_all(&request);
+        });
+    }
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() > deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!(
+                "{} did not decide in {} seconds",
+                program,
+                timeout.as_secs()
+            );
+        }
+        thread::sleep(COMMAND_POLL_INTERVAL);
+    };
+
+    if status.success() {
+        return Ok(AuthorizationDecision {
+            allowed: true,
+            reason: None,
+        });
+    }
+
+    let mut output = String::new();
+    if let Some(stdout) = child.stdout.as_mut() {
+        let _ = stdout
+            .take(MAX_DECISION_SIZE as u64)
+            .read_to_string(&mut output);
+    }
+    let reason = match output.trim() {
+        "" => format!("{} refused the order with {}", program, status),
+        reason => reason.to_owned(),
+    };
+    Ok(AuthorizationDecision {
+        allowed: false,
+        reason: Some(reason),
+    })
+}
+
+/// the service reads the request ended by a 0 byte, and answers with its
+/// decision, ended by a 0 byte or by closing the connection
+fn ask_service(
+    socket: &str,
+    request: &[u8],
+    timeout: Duration,
+) -> anyhow::Result<AuthorizationDecision> {
+    let mut stream = UnixStream::connect(socket)
+        .with_context(|| format!("could not connect to the authorization service {}", socket))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    stream.write_all(request)?;
+    stream.write_all(&[0])?;
+    stream.flush()?;
+
+    let mut answer = Vec::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+        let size = stream
+            .read(&mut buffer)
+            .with_context(|| format!("no decision from the authorization service {}", socket))?;
+        if size == 0 {
+            break;
+        }
+        answer.extend_from_slice(&buffer[..size]);
+        if let Some(position) = answer.iter().position(|byte| *byte == 0) {
+            answer.truncate(position);
+            break;
+        }
+        if answer.len() > MAX_DECISION_SIZE {
+            bail!("the decision of the authorization service is too large");
+        }
+    }
+
+    serde_json::from_slice(&answer).with_context(|| {
+        format!(
+            "invalid decision from the authorization service: {}",
+            String::from_utf8_lossy(&answer)
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sozu_command_lib::command::{CommandRequest, CommandRequestOrder, OrderCategory};
+
+    fn request() -> AuthorizationRequest {
+        AuthorizationRequest {
+            client: String::from("CL-0"),
+            user: Some(String::from("root")),
+            uid: Some(0),
+            gid: Some(0),
+            category: OrderCategory::Mutation,
+            request: CommandRequest::new(
+                String::from("ID-TEST"),
+                CommandRequestOrder::SyncPeers,
+                None,
+            ),
+        }
+    }
+
+    fn shell(script: &str) -> CommandAuthorizationConfig {
+        CommandAuthorizationConfig {
+            command: Some(vec![
+                String::from("sh"),
+                String::from("-c"),
+                script.to_owned(),
+            ]),
+            socket: None,
+            timeout: 5,
+        }
+    }
+
+    #[test]
+    fn authorization_command() {
+        let allowed = authorize(&shell("grep -q SYNC_PEERS"), &request()).unwrap();
+        assert!(allowed.allowed);
+
+        let refused = authorize(
+            &shell("cat > /dev/null; echo read only; exit 1"),
+            &request(),
+        )
+        .unwrap();
+        assert_eq!(
+            refused,
+            AuthorizationDecision {
+                allowed: false,
+                reason: Some(String::from("read only")),
+            }
+        );
+
+        let mut slow = shell("sleep 5");
+        slow.timeout = 0;
+        assert!(authorize(&slow, &request()).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+//! watches the services of the Consul catalog, the instances passing their
+//! health checks become the backends of the mapped clusters
+use std::{
+    io::Read,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use sozu_command_lib::config::{ConsulConfig, ConsulService};
+
+/// added to the duration of the blocking query, Consul answers a bit later
+/// than asked to spread the answers
+const CONSUL_TIMEOUT_MARGIN: Duration = Duration::from_secs(10);
+const MAX_CONSUL_RESPONSE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// an instance of a service, the backend of a cluster
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredBackend {
+    /// `<node>/<service id>`, unique in a datacenter
+    pub backend_id: String,
+    pub address: SocketAddr,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ServiceEntry {
+    node: Node,
+    service: Service,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Node {
+    node: String,
+    address: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Service {
+    #[serde(rename = "ID")]
+    id: String,
+    #[serde(default)]
+    address: String,
+    port: u16,
+    #[serde(default)]
+    tags: Option<Vec<String>>,
+}
+
+/// waits for a change of the healthy instances of the service, with a blocking query
+/// starting at this index, and returns the new index with the instances
+pub fn watch_service(
+    consul: &ConsulConfig,
+    name: &str,
+    service: &ConsulService,
+    index: u64,
+) -> anyhow::Result<(u64, Vec<DiscoveredBackend>)> {
+    let url = format!(
+        "{}/v1/health/service/{}",
+        consul.address.trim_end_matches('/'),
+        name
+    );
+
+    let mut request = ureq::get(&url)
+        .timeout(Duration::from_secs(consul.wait as u64) + CONSUL_TIMEOUT_MARGIN)
+        .query("passing", "true")
+        .query("index", &index.to_string())
+        .query("wait", &format!("{}s", consul.wait));
+    if let Some(datacenter) = &consul.datacenter {
+        request = request.query("dc", datacenter);
+    }
+    if let Some(token) = &consul.token {
+        request = request.set("X-Consul-Token", token);
+    }
+
+    let response = request
+        .call()
+        .with_context(|| format!("could not query the service {} on Consul", name))?;
+
+    let new_index = response
+        .header("X-Consul-Index")
+        .and_then(|index| index.parse::<u64>().ok())
+        .with_context(|| "Consul did not answer with an index")?;
+
+    let mut body = Vec::new();
+    response
+        .into_reader()
+        .take(MAX_CONSUL_RESPONSE_SIZE)
+        .read_to_end(&mut body)
+        .with_context(|| format!("could not read the instances of the service {}", name))?;
+
+    let backends = parse_instances(&body, &service.tags)
+        .with_context(|| format!("could not parse the instances of the service {}", name))?;
+
+    Ok((new_index, backends))
+}
+
+/// the instances having all the tags, at the address of the service, or of
+/// its node if the service does not set one
+fn parse_instances(body: &[u8], tags: &[String]) -> anyhow::Result<Vec<DiscoveredBackend>> {
+    let entries: Vec<ServiceEntry> = serde_json::from_slice(body)?;
+
+    let mut backends = Vec::new();
+    for entry in entries {
+        let instance_tags = entry.service.tags.unwrap_or_default();
+        if !tags.iter().all(|tag| instance_tags.contains(tag)) {
+            continue;
+        }
+
+        let host = if entry.service.address.is_empty() {
+            &entry.node.address
+        } else {
+            &entry.service.address
+        };
+        let backend_id = format!("{}/{}", entry.node.node, entry.service.id);
+
+        match resolve(host, entry.service.port) {
+            Some(address) => backends.push(DiscoveredBackend {
+                backend_id,
+                address,
+            }),
+            None => {
+                warn!(
+                    "could not resolve the address {} of the Consul instance {}",
+                    host, backend_id
+                );
+            }
+        }
+    }
+
+    backends.sort_by(|a, b| a.backend_id.cmp(&b.backend_id));
+    Ok(backends)
+}
+
+fn resolve(host: &str, port: u16) -> Option<SocketAddr> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Some(SocketAddr::new(ip, port));
+    }
+
+    (host, port).to_socket_addrs().ok()?.next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INSTANCES: &str = r#"[
+        {
+            "Node": { "ID": "1b7c", "Node": "node-1", "Address": "10.0.0.1", "Datacenter": "dc1" },
+            "Service": { "ID": "api-1", "Service": "api", "Tags": ["v2", "public"], "Address": "", "Port": 8080 },
+            "Checks": []
+        },
+        {
+            "Node": { "ID": "4c2d", "Node": "node-2", "Address": "10.0.0.2", "Datacenter": "dc1" },
+            "Service": { "ID": "api-2", "Service": "api", "Tags": ["v2"], "Address": "192.168.1.2", "Port": 8081 },
+            "Checks": []
+        },
+        {
+            "Node": { "ID": "9e0f", "Node": "node-3", "Address": "10.0.0.3", "Datacenter": "dc1" },
+            "Service": { "ID": "api-3", "Service": "api", "Tags": null, "Address": "", "Port": 8082 },
+            "Checks": []
+        }
+    ]"#;
+
+    #[test]
+    fn instances_become_backends() {
+        let backends = parse_instances(INSTANCES.as_bytes(), &[]).unwrap();
+        assert_eq!(
+            backends,
+            vec![
+                DiscoveredBackend {
+                    backend_id: String::from("node-1/api-1"),
+                    address: "10.0.0.1:8080".parse().unwrap(),
+                },
+                DiscoveredBackend {
+                    backend_id: String::from("node-2/api-2"),
+                    address: "192.168.1.2:8081".parse().unwrap(),
+                },
+                DiscoveredBackend {
+                    backend_id: String::from("node-3/api-3"),
+                    address: "10.0.0.3:8082".parse().unwrap(),
+                },
+            ]
+        );
+
+        let tags = vec![String::from("v2"), String::from("public")];
+        let backends = parse_instances(INSTANCES.as_bytes(), &tags).unwrap();
+        assert_eq!(
+            backends,
+            vec![DiscoveredBackend {
+                backend_id: String::from("node-1/api-1"),
+                address: "10.0.0.1:8080".parse().unwrap(),
+            }]
+        );
+    }
+}
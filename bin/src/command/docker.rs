@@ -0,0 +1,371 @@
+//! watches the containers of a Docker or Podman daemon, the ones with a
+//! `sozu.hostname` label become clusters, with their frontends and backends
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{BufRead, BufReader, Read, Write},
+    net::{IpAddr, SocketAddr},
+    os::unix::net::UnixStream,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+use sozu_command_lib::{
+    config::DockerConfig,
+    proxy::{
+        Backend, Cluster, HttpFrontend, LoadBalancingParams, PathRule, ProxyRequestOrder, Route,
+        RulePosition, Timeouts,
+    },
+};
+
+/// the containers without this label are not routed
+const HOSTNAME_LABEL: &str = "sozu.hostname";
+/// port of the container receiving the requests, 80 if not set
+const PORT_LABEL: &str = "sozu.port";
+const PATH_PREFIX_LABEL: &str = "sozu.path_prefix";
+/// cluster of the container, its name if not set
+const CLUSTER_ID_LABEL: &str = "sozu.cluster_id";
+const DEFAULT_PORT: u16 = 80;
+
+const DOCKER_API_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_DOCKER_RESPONSE_SIZE: u64 = 16 * 1024 * 1024;
+/// the events of the containers only, `filters={"type":["container"]}`
+const EVENTS_PATH: &str = "/events?filters=%7B%22type%22%3A%5B%22container%22%5D%7D";
+
+/// a running container routed by sozu
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutedContainer {
+    pub name: String,
+    pub cluster_id: String,
+    pub hostname: String,
+    pub path_prefix: Option<String>,
+    pub address: SocketAddr,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Container {
+    names: Vec<String>,
+    #[serde(default)]
+    labels: Option<HashMap<String, String>>,
+    state: String,
+    network_settings: NetworkSettings,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct NetworkSettings {
+    #[serde(default)]
+    networks: Option<BTreeMap<String, Network>>,
+}
+
+#[derive(Deserialize)]
+struct Network {
+    #[serde(rename = "IPAddress", default)]
+    ip_address: String,
+}
+
+#[derive(Deserialize)]
+struct ContainerEvent {
+    #[serde(rename = "Action", default)]
+    action: String,
+}
+
+/// the stream of the container events of the daemon
+pub struct Events(BufReader<UnixStream>);
+
+impl Events {
+    pub fn open(socket: &str) -> anyhow::Result<Self> {
+        get(socket, EVENTS_PATH, None).map(Events)
+    }
+
+    /// waits for an event that can change the routed containers
+    pub fn wait(&mut self) -> anyhow::Result<()> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self
+                .0
+                .read_line(&mut line)
+                .with_context(|| "could not read the container events")?
+                == 0
+            {
+                bail!("the daemon closed the stream of the container events");
+            }
+
+            let event: ContainerEvent = match serde_json::from_str(line.trim()) {
+                Ok(event) => event,
+                Err(_) => continue,
+            };
+            // the health checks of the containers run in exec sessions
+            if !event.action.starts_with("exec_") {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// the running containers with a `sozu.hostname` label
+pub fn list_containers(docker: &DockerConfig) -> anyhow::Result<Vec<RoutedContainer>> {
+    let reader = get(&docker.socket, "/containers/json", Some(DOCKER_API_TIMEOUT))?;
+
+    let mut body = Vec::new();
+    reader
+        .take(MAX_DOCKER_RESPONSE_SIZE)
+        .read_to_end(&mut body)
+        .with_context(|| "could not read the list of the containers")?;
+
+    parse_containers(&body, docker.network.as_deref())
+        .with_context(|| "could not parse the list of the containers")
+}
+
+/// sends a GET request over the unix socket of the API, and returns the body
+/// of the answer. HTTP/1.0 answers are not chunked, they end with the connection
+fn get(
+    socket: &str,
+    path: &str,
+    timeout: Option<Duration>,
+) -> anyhow::Result<BufReader<UnixStream>> {
+    let mut stream = UnixStream::connect(socket)
+        .with_context(|| format!("could not connect to the Docker socket {}", socket))?;
+    stream.set_read_timeout(timeout)?;
+    write!(stream, "GET {} HTTP/1.0\r\nHost: docker\r\n\r\n", path)
+        .with_context(|| format!("could not send the request {} to the daemon", path))?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    if status_line.split_whitespace().nth(1) != Some("200") {
+        bail!(
+            "the daemon answered '{}' to the request {}",
+            status_line.trim(),
+            path
+        );
+    }
+
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if reader.read_line(&mut header)? == 0 {
+            bail!("the answer to the request {} was truncated", path);
+        }
+        if header.trim_end().is_empty() {
+            return Ok(reader);
+        }
+    }
+}
+
+fn parse_containers(body: &[u8], network: Option<&str>) -> anyhow::Result<Vec<RoutedContainer>> {
+    let containers: Vec<Container> = serde_json::from_slice(body)?;
+
+    let mut routed = Vec::new();
+    for container in containers {
+        let labels = container.labels.unwrap_or_default();
+        let hostname = match labels.get(HOSTNAME_LABEL) {
+            Some(hostname) if container.state == "running" => hostname.to_owned(),
+            _ => continue,
+        };
+        let name = match container.names.first() {
+            Some(name) => name.trim_start_matches('/').to_owned(),
+            None => continue,
+        };
+
+        let port = match labels.get(PORT_LABEL).map(|port| port.parse::<u16>()) {
+            None => DEFAULT_PORT,
+            Some(Ok(port)) => port,
+            Some(Err(_)) => {
+                warn!("invalid {} label on the container {}", PORT_LABEL, name);
+                continue;
+            }
+        };
+
+        let networks = container.network_settings.networks.unwrap_or_default();
+        let ip = match network {
+            Some(network) => networks.get(network),
+            None => networks.values().find(|n| !n.ip_address.is_empty()),
+        }
+        .and_then(|n| n.ip_address.parse::<IpAddr>().ok());
+        let ip = match ip {
+            Some(ip) => ip,
+            None => {
+                warn!("the container {} has no address to reach it", name);
+                continue;
+            }
+        };
+
+        routed.push(RoutedContainer {
+            cluster_id: labels
+                .get(CLUSTER_ID_LABEL)
+                .cloned()
+                .unwrap_or_else(|| name.clone()),
+            name,
+            hostname,
+            path_prefix: labels.get(PATH_PREFIX_LABEL).cloned(),
+            address: SocketAddr::new(ip, port),
+        });
+    }
+
+    routed.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(routed)
+}
+
+/// the orders creating the clusters of the containers, except the existing ones
+/// that they join, their frontends on the listeners, and their backends
+pub fn generate_orders(
+    containers: &[RoutedContainer],
+    http_listeners: &[SocketAddr],
+    https_listeners: &[SocketAddr],
+    existing_clusters: &HashSet<String>,
+) -> Vec<ProxyRequestOrder> {
+    let mut orders = Vec::new();
+    let mut clusters = HashSet::new();
+
+    for container in containers {
+        if !existing_clusters.contains(&container.cluster_id)
+            && clusters.insert(container.cluster_id.clone())
+        {
+            orders.push(ProxyRequestOrder::AddCluster(Cluster {
+                cluster_id: container.cluster_id.clone(),
+                ..Default::default()
+            }));
+        }
+
+        for address in http_listeners {
+            orders.push(ProxyRequestOrder::AddHttpFrontend(frontend(
+                container, *address,
+            )));
+        }
+        for address in https_listeners {
+            orders.push(ProxyRequestOrder::AddHttpsFrontend(frontend(
+                container, *address,
+            )));
+        }
+
+        orders.push(ProxyRequestOrder::AddBackend(Backend {
+            cluster_id: container.cluster_id.clone(),
+            backend_id: container.name.clone(),
+            address: container.address,
+            sticky_id: None,
+            load_balancing_parameters: Some(LoadBalancingParams { weight: 100 }),
+            backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
+        }));
+    }
+
+    orders
+}
+
+fn frontend(container: &RoutedContainer, address: SocketAddr) -> HttpFrontend {
+    HttpFrontend {
+        route: Route::ClusterId(container.cluster_id.clone()),
+        address,
+        hostname: container.hostname.clone(),
+        path: container
+            .path_prefix
+            .clone()
+            .map(PathRule::Prefix)
+            .unwrap_or_default(),
+        method: None,
+        methods: Vec::new(),
+        reject_other_methods: false,
+        headers: Vec::new(),
+        rewrite_path: None,
+        mirror_cluster_id: None,
+        position: RulePosition::Tree,
+        tags: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTAINERS: &str = r#"[
+        {
+            "Id": "8dfafdbc3a40",
+            "Names": ["/blog"],
+            "Labels": { "sozu.hostname": "blog.example.com", "sozu.port": "8080" },
+            "State": "running",
+            "NetworkSettings": { "Networks": { "bridge": { "IPAddress": "172.17.0.2" } } }
+        },
+        {
+            "Id": "9cd87474be90",
+            "Names": ["/api-2"],
+            "Labels": {
+                "sozu.hostname": "example.com",
+                "sozu.path_prefix": "/api",
+                "sozu.cluster_id": "api"
+            },
+            "State": "running",
+            "NetworkSettings": {
+                "Networks": {
+                    "backend": { "IPAddress": "10.0.1.3" },
+                    "bridge": { "IPAddress": "172.17.0.3" }
+                }
+            }
+        },
+        {
+            "Id": "3176a2479c92",
+            "Names": ["/database"],
+            "Labels": {},
+            "State": "running",
+            "NetworkSettings": { "Networks": { "bridge": { "IPAddress": "172.17.0.4" } } }
+        },
+        {
+            "Id": "4cb07b47f9fb",
+            "Names": ["/paused"],
+            "Labels": { "sozu.hostname": "paused.example.com" },
+            "State": "paused",
+            "NetworkSettings": { "Networks": { "bridge": { "IPAddress": "172.17.0.5" } } }
+        }
+    ]"#;
+
+    #[test]
+    fn labelled_containers_are_routed() {
+        let containers = parse_containers(CONTAINERS.as_bytes(), Some("bridge")).unwrap();
+        assert_eq!(
+            containers,
+            vec![
+                RoutedContainer {
+                    name: String::from("api-2"),
+                    cluster_id: String::from("api"),
+                    hostname: String::from("example.com"),
+                    path_prefix: Some(String::from("/api")),
+                    address: "172.17.0.3:80".parse().unwrap(),
+                },
+                RoutedContainer {
+                    name: String::from("blog"),
+                    cluster_id: String::from("blog"),
+                    hostname: String::from("blog.example.com"),
+                    path_prefix: None,
+                    address: "172.17.0.2:8080".parse().unwrap(),
+                },
+            ]
+        );
+
+        let containers = parse_containers(CONTAINERS.as_bytes(), None).unwrap();
+        assert_eq!(containers[0].address, "10.0.1.3:80".parse().unwrap());
+
+        let http_listener: SocketAddr = "0.0.0.0:80".parse().unwrap();
+        let existing_clusters = HashSet::from([String::from("api")]);
+        let orders = generate_orders(&containers, &[http_listener], &[], &existing_clusters);
+        assert_eq!(orders.len(), 5);
+        assert!(matches!(
+            &orders[0],
+            ProxyRequestOrder::AddHttpFrontend(front)
+                if front.path == PathRule::Prefix(String::from("/api"))
+        ));
+        assert!(matches!(
+            &orders[2],
+            ProxyRequestOrder::AddCluster(cluster) if cluster.cluster_id == "blog"
+        ));
+        assert!(matches!(
+            &orders[4],
+            ProxyRequestOrder::AddBackend(backend)
+                if backend.backend_id == "blog" && backend.address == containers[1].address
+        ));
+    }
+}
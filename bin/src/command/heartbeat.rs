@@ -0,0 +1,81 @@
+//! the heartbeats the main process sends to the workers, to find the ones
+//! that stopped answering their command channel
+use std::collections::HashMap;
+
+/// the request ids of the heartbeats start with this prefix
+const HEARTBEAT_PREFIX: &str = "HEARTBEAT-";
+
+#[derive(Debug, Default)]
+pub struct Heartbeats {
+    /// number of heartbeats sent, to identify them
+    sent: u64,
+    /// worker id -> the unanswered heartbeat, and the heartbeats missed in a row
+    workers: HashMap<u32, (Option<String>, u32)>,
+}
+
+impl Heartbeats {
+    /// a new heartbeat for a worker: returns its request id, and the number
+    /// of heartbeats the worker missed in a row, counting the previous one if
+    /// it is still unanswered
+    pub fn beat(&mut self, worker_id: u32) -> (String, u32) {
+        self.sent += 1;
+        let id = format!("{}{}-{}", HEARTBEAT_PREFIX, worker_id, self.sent);
+
+        let (pending, missed) = self.workers.entry(worker_id).or_default();
+        if pending.is_some() {
+            *missed += 1;
+        }
+        *pending = Some(id.clone());
+        (id, *missed)
+    }
+
+    /// records the answer of a worker, and returns true if it answered its
+    /// last heartbeat. The answers to older heartbeats are ignored
+    pub fn answered(&mut self, worker_id: u32, response_id: &str) -> bool {
+        match self.workers.get_mut(&worker_id) {
+            Some((pending, missed)) if pending.as_deref() == Some(response_id) => {
+                *pending = None;
+                *missed = 0;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// forgets a worker that stopped
+    pub fn remove(&mut self, worker_id: u32) {
+        self.workers.remove(&worker_id);
+    }
+
+    pub fn is_heartbeat(response_id: &str) -> bool {
+        response_id.starts_with(HEARTBEAT_PREFIX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missed_heartbeats_add_up_until_answered() {
+        let mut heartbeats = Heartbeats::default();
+
+        let (first, missed) = heartbeats.beat(0);
+        assert_eq!(missed, 0);
+        assert!(Heartbeats::is_heartbeat(&first));
+
+        let (second, missed) = heartbeats.beat(0);
+        assert_eq!(missed, 1);
+        let (third, missed) = heartbeats.beat(0);
+        assert_eq!(missed, 2);
+
+        // a late answer does not count
+        assert!(!heartbeats.answered(0, &second));
+        assert!(heartbeats.answered(0, &third));
+        assert_eq!(heartbeats.beat(0).1, 0);
+
+        // the other workers are counted apart
+        assert_eq!(heartbeats.beat(1).1, 0);
+        assert!(!heartbeats.answered(1, &first));
+    }
+}
@@ -0,0 +1,144 @@
+//! the versions of the state kept by the main process, to list the changes
+//! and roll back to one of them
+use std::{
+    collections::VecDeque,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use sozu_command_lib::{command::StateVersion, proxy::ProxyRequestOrder, state::ConfigState};
+
+/// a version of the state, with the change that created it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    pub version: StateVersion,
+    pub state: ConfigState,
+}
+
+/// the last versions of the state, the oldest first. The current version is
+/// always kept, to find the changes of the next one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateHistory {
+    snapshots: VecDeque<StateSnapshot>,
+    /// the version given to the next change, never reused
+    next_version: u64,
+    capacity: usize,
+}
+
+impl StateHistory {
+    pub fn new(capacity: usize) -> Self {
+        StateHistory {
+            snapshots: VecDeque::new(),
+            next_version: 1,
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// keeps the state as a new version if it differs from the last one,
+    /// dropping the oldest versions beyond the capacity. Returns the new
+    /// version, with the orders leading to it from the previous one
+    pub fn record(
+        &mut self,
+        request_id: &str,
+        state: &ConfigState,
+    ) -> Option<(u64, Vec<ProxyRequestOrder>)> {
+        let orders = match self.snapshots.back() {
+            Some(snapshot) if &snapshot.state == state => return None,
+            Some(snapshot) => snapshot.state.diff(state),
+            None => ConfigState::new().diff(state),
+        };
+
+        let version = self.next_version;
+        self.next_version += 1;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or(0);
+
+        self.snapshots.push_back(StateSnapshot {
+            version: StateVersion {
+                version,
+                request_id: request_id.to_owned(),
+                timestamp,
+            },
+            state: state.clone(),
+        });
+        while self.snapshots.len() > self.capacity {
+            self.snapshots.pop_front();
+        }
+        Some((version, orders))
+    }
+
+    pub fn versions(&self) -> Vec<StateVersion> {
+        self.snapshots
+            .iter()
+            .map(|snapshot| snapshot.version.clone())
+            .collect()
+    }
+
+    /// the version of the current state
+    pub fn current_version(&self) -> Option<u64> {
+        self.snapshots
+            .back()
+            .map(|snapshot| snapshot.version.version)
+    }
+
+    pub fn get(&self, version: u64) -> Option<&ConfigState> {
+        self.snapshots
+            .iter()
+            .find(|snapshot| snapshot.version.version == version)
+            .map(|snapshot| &snapshot.state)
+    }
+
+    /// the capacity comes from the configuration, that can change on upgrade
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        while self.snapshots.len() > self.capacity {
+            self.snapshots.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sozu_command_lib::proxy::Cluster;
+
+    #[test]
+    fn record_versions() {
+        let mut history = StateHistory::new(2);
+        let mut state = ConfigState::new();
+
+        assert_eq!(
+            history.record("INITIALIZATION", &state),
+            Some((1, Vec::new()))
+        );
+        assert_eq!(history.record("ID-A", &state), None);
+
+        let add = ProxyRequestOrder::AddCluster(Cluster {
+            cluster_id: String::from("cluster_1"),
+            ..Default::default()
+        });
+        state.handle_order(&add);
+        assert_eq!(history.record("ID-B", &state), Some((2, vec![add])));
+
+        let remove = ProxyRequestOrder::RemoveCluster {
+            cluster_id: String::from("cluster_1"),
+        };
+        state.handle_order(&remove);
+        assert_eq!(history.record("ID-C", &state), Some((3, vec![remove])));
+
+        let versions = history.versions();
+        assert_eq!(
+            versions
+                .iter()
+                .map(|version| (version.version, version.request_id.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(2, "ID-B"), (3, "ID-C")]
+        );
+        assert!(history.get(1).is_none());
+        assert!(history.get(2).unwrap().clusters.contains_key("cluster_1"));
+        assert!(history.get(3).unwrap().clusters.is_empty());
+    }
+}
@@ -1,17 +1,19 @@
 use std::{
-    collections::{HashMap, HashSet},
-    fs,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs, io,
+    net::ToSocketAddrs,
     os::unix::{
         fs::PermissionsExt,
         io::{AsRawFd, FromRawFd, IntoRawFd},
         net::{UnixListener, UnixStream},
     },
     path::PathBuf,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{bail, Context};
 use async_dup::Arc;
-use async_io::Async;
+use async_io::{Async, Timer};
 use futures::{
     channel::{mpsc::*, oneshot},
     {SinkExt, StreamExt},
@@ -21,40 +23,87 @@ use nix::{
     sys::signal::{kill, Signal},
     unistd::Pid,
 };
+use rand::{rngs::OsRng, RngCore};
 use serde::{Deserialize, Serialize};
+use signal_hook::{
+    consts::{SIGHUP, SIGQUIT, SIGTERM},
+    iterator::Signals,
+};
 
 use sozu_command_lib::{
     command::{
-        CommandRequest, CommandRequestOrder, CommandResponse, CommandResponseContent,
-        CommandStatus, Event, RunState,
+        AuditEntry, CommandRequest, CommandRequestOrder, CommandResponse, CommandResponseContent,
+        CommandStatus, ConfigurationChange, Event, OrderCategory, Progress, RunState,
     },
-    config::Config,
+    config::{Config, DockerConfig, NotAnsweringPolicy},
     proxy::{
-        MetricsConfiguration, ProxyRequest, ProxyRequestOrder, ProxyResponse, ProxyResponseContent,
-        ProxyResponseStatus,
+        AccessLogFilter, AccessLogRateLimit, AccessLogRecord, Affinity, Backend,
+        CertificateFingerprint, CrashReport, LoadBalancingParams, MetricsConfiguration,
+        NameResolution, ProxyRequest, ProxyRequestOrder, ProxyResponse, ProxyResponseContent,
+        ProxyResponseStatus, RemoveBackend, SetOcspResponse, SetTicketKeys, Timeouts,
+        TICKET_KEY_LENGTH,
     },
     scm_socket::{Listeners, ScmSocket},
     state::ConfigState,
 };
 
 use crate::{
-    get_executable_path,
+    get_executable_path, remote, systemd,
     upgrade::{SerializedWorker, UpgradeData},
     util,
-    worker::start_worker,
+    worker::{bind_listeners, start_worker},
 };
 
+mod access;
+mod audit;
+mod authorization;
+mod consul;
+mod docker;
+mod heartbeat;
+mod history;
+mod ocsp;
 mod orders;
+mod peering;
+mod supervisor;
 mod worker;
 
 pub use worker::*;
 
+use access::PeerCredentials;
+use audit::AuditLog;
+use consul::DiscoveredBackend;
+use docker::RoutedContainer;
+use heartbeat::Heartbeats;
+pub use history::StateHistory;
+use peering::{PeerAnswer, Peering};
+use supervisor::CrashSupervisor;
+
+/// duration between two checks of the certificate expirations
+const CERTIFICATE_EXPIRATION_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+/// how often the resident memory of the workers is compared to `worker_max_memory`
+const WORKER_MEMORY_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// how long the main process lets the clients get their last answers, once stopped
+const CLIENT_FLUSH_DELAY: Duration = Duration::from_millis(200);
+/// the client of the requests made by the main process itself, like the
+/// recycling of a worker, their answers are only logged
+const MAIN_PROCESS_CLIENT: &str = "MAIN";
+
+/// how long the main process resolves a name for a worker, before answering
+/// without addresses
+const NAME_RESOLUTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// duration before querying Consul again after an error
+const CONSUL_RETRY_DELAY: Duration = Duration::from_secs(5);
+/// duration before connecting to the Docker daemon again after an error
+const DOCKER_RETRY_DELAY: Duration = Duration::from_secs(5);
+
 // The CommandServer receives these CommandMessages, either from within Sōzu,
 // or from without, in which case they are ALWAYS of the ClientRequest variant.
 enum CommandMessage {
     ClientNew {
         client_id: String,
         sender: Sender<CommandResponse>, // to send things back to the client
+        peer: Option<PeerCredentials>,
     },
     ClientClose {
         client_id: String,
@@ -75,11 +124,82 @@ enum CommandMessage {
         response: Response,
     },
     MasterStop,
+    /// fetch the OCSP responses of the certificates
+    RefreshOcspResponses,
+    /// an OCSP response was fetched, to send to the workers
+    OcspResponse(SetOcspResponse),
+    /// a name a worker asked for was resolved, to send back to it
+    NameResolved {
+        worker_id: u32,
+        resolution: NameResolution,
+    },
+    /// update the certificate expiration metrics, and notify the ones expiring soon
+    CheckCertificateExpirations,
+    /// compare the resident memory of the workers to `worker_max_memory`
+    CheckWorkerMemory,
+    /// send a heartbeat to the workers, and count the ones they missed
+    CheckHeartbeats,
+    /// write the state to `state_file` if it changed since the last save
+    SaveStateFile,
+    /// SIGTERM or SIGQUIT: soft or hard stop of the workers and the main process
+    StopOnSignal {
+        hard: bool,
+    },
+    /// tell the systemd watchdog that the main loop is alive
+    WatchdogPing,
+    /// the new worker of an upgrade answered its checks, or failed them
+    WorkerUpgradeChecked {
+        request_identifier: RequestIdentifier,
+        check_id: String,
+        old_id: u32,
+        new_id: u32,
+        listeners: Option<Listeners>,
+        /// answer with a restart instead of an upgrade
+        restart: bool,
+        result: std::result::Result<(), String>,
+    },
+    /// the new main process of an upgrade confirmed, failed its self-test, or
+    /// did not answer in time
+    MainUpgradeChecked {
+        request_identifier: RequestIdentifier,
+        new_main_pid: i32,
+        confirmation: Option<bool>,
+    },
+    /// generate a new session ticket key and send the keys to the workers
+    RotateTicketKeys,
+    /// the instances of a cluster found in a service catalog, that replace its backends
+    DiscoveredBackends {
+        cluster_id: String,
+        backends: Vec<DiscoveredBackend>,
+    },
+    /// the running containers with routing labels, after a change
+    DockerContainers(Vec<RoutedContainer>),
+    /// a crashed worker to replace, once its restart delay is over
+    RestartWorker {
+        worker_id: u32,
+    },
+    /// a batch failed on every worker, these orders undo it in the state
+    RolledBackBatch(Vec<ProxyRequestOrder>),
+    /// the main process received SIGHUP, to reload the configuration file
+    ReloadConfiguration,
+    /// an event found outside of the main loop, for the subscribers
+    PublishEvent {
+        id: String,
+        event: Event,
+    },
+    /// a peer answered an order, or could not be reached
+    PeerAnswered {
+        address: String,
+        /// the request of the client that made the change
+        request: Option<RequestIdentifier>,
+        is_change: bool,
+        answer: PeerAnswer,
+    },
 }
 
 /// identifies a request only within the command server
 /// the request part does NOT get sent to a worker
-#[derive(PartialEq, Eq, Clone, Debug)]
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
 pub struct RequestIdentifier {
     /// the client who sent the request (ex: "CL-0")
     client: String,
@@ -102,7 +222,11 @@ impl RequestIdentifier {
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub enum Response {
     Error(String),
+    /// an error with the details of why the order was refused
+    Rejected(String, CommandResponseContent),
     Processing(String),
+    /// the answers of the workers so far to a long operation
+    Progress(Progress),
     Ok(Success),
 }
 
@@ -110,31 +234,63 @@ pub enum Response {
 // in which case Success caries the response data.
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub enum Success {
-    ClientClose(String),               // the client id
-    ClientNew(String),                 // the client id
-    DumpState(CommandResponseContent), // the cloned state
+    AppliedState(CommandResponseContent), // the executed orders
+    ClientClose(String),                  // the client id
+    ClientNew(String),                    // the client id
+    DumpState(CommandResponseContent),    // the cloned state
+    DryRun(CommandResponseContent),       // what the order would change
     HandledClientRequest,
-    ListFrontends(CommandResponseContent), // the list of frontends
+    CheckedCertificateExpirations(usize), // number of certificates expiring soon
+    CheckedWorkerMemory(usize),           // number of workers past the memory ceiling
+    CheckedHeartbeats(usize),             // number of workers not answering
+    CrashReported(u32),                   // the worker that panicked
+    PingedWatchdog,
+    ListCertificates(CommandResponseContent), // the list of certificates
+    ListFrontends(CommandResponseContent),    // the list of frontends
     ListWorkers(CommandResponseContent),
     LoadState(String, usize, usize), // state path, oks, errors
     Logging(String),                 // new logging level
     Metrics(MetricsConfiguration),   // enable / disable / clear metrics on the proxy
     MasterStop,
+    NameResolved(String), // the name resolved for a worker
     // this should contain CommandResponseData but the logic does not return anything
     // is this logic gone into sozu_command_lib::proxy::Query::Metrics(_) ?
     // Metrics,
     NotifiedClient(String), // client id
+    OcspResponse(String),   // certificate fingerprint
     PropagatedWorkerEvent,
+    PeerAnswered(String), // the address of the peer
     Query(CommandResponseContent),
+    RefreshOcspResponses(usize), // number of OCSP responses to fetch
     ReloadConfiguration(usize, usize), // ok, errors
-    SaveState(usize, String),          // amount of written commands, path of the saved state
-    Status(CommandResponseContent),    // Vec<WorkerInfo>
+    ReloadedOnSignal(usize),     // number of reloads on SIGHUP
+    ResolvingName(String),       // the name a worker asked for
+    RolledBackBatch(usize),      // number of orders undoing the batch
+    RotatedTicketKeys(usize),    // number of workers notified
+    SaveState(usize, String),    // amount of written commands, path of the saved state
+    SavedStateFile(usize),       // number of written orders, 0 if the state did not change
+    StoppingOnSignal(bool),      // hard stop
+    SharedAffinity(String),      // cluster id
+    SoftStop(CommandResponseContent), // the sessions drained and killed per worker
+    StateHistory(CommandResponseContent), // the versions of the state
+    AuditLog(CommandResponseContent), // the last entries of the audit log
+    SyncedBackends(String, usize, usize), // cluster id, added, removed
+    SyncedContainers(usize, usize), // routed containers, orders sent
+    SyncedPeers(usize, u64),     // number of peers, version of the state
+    Status(CommandResponseContent), // Vec<WorkerInfo>
     SubscribeEvent(String),
-    UpgradeMain(i32),         // pid of the new main process
-    UpgradeWorker(u32),       // worker id
-    WorkerKilled(u32),        // worker id
-    WorkerLaunched(u32),      // worker id
-    WorkerOrder(Option<u32>), // worker id
+    TailAccessLogs(String),  // client id
+    UpgradeMain(i32),        // pid of the new main process
+    WaitingForPeers(String), // request id
+    UpgradeWorker(u32),      // worker id
+    RestartWorker(u32, u32), // old worker id, new worker id
+    // the problems found in the certificate
+    ValidatedCertificate(CommandResponseContent),
+    WorkerKilled(u32),     // worker id
+    WorkerLaunched(u32),   // worker id
+    WorkerOrder(Vec<u32>), // worker ids, all of them if empty
+    // the problems found in the order
+    WorkerOrderWithWarnings(CommandResponseContent),
     WorkerResponse,
     WorkerRestarted(u32), // worker id
     WorkerStopped(u32),   // worker id
@@ -144,10 +300,37 @@ pub enum Success {
 impl std::fmt::Display for Success {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
+            Self::AppliedState(_) => write!(f, "Successfully applied the state"),
             Self::ClientClose(id) => write!(f, "Close client: {}", id),
             Self::ClientNew(id) => write!(f, "New client successfully added: {}", id),
             Self::DumpState(_) => write!(f, "Successfully gathered state from the main process"),
+            Self::DryRun(_) => write!(
+                f,
+                "Validated the order, nothing was sent to the workers (dry run)"
+            ),
             Self::HandledClientRequest => write!(f, "Successfully handled the client request"),
+            Self::CheckedCertificateExpirations(count) => {
+                write!(
+                    f,
+                    "Checked the certificate expirations, {} expire soon",
+                    count
+                )
+            }
+            Self::CheckedWorkerMemory(count) => write!(
+                f,
+                "Checked the memory of the workers, {} use too much",
+                count
+            ),
+            Self::CheckedHeartbeats(count) => write!(
+                f,
+                "Sent the heartbeats to the workers, {} are not answering",
+                count
+            ),
+            Self::CrashReported(id) => write!(f, "Received the crash report of worker {}", id),
+            Self::PingedWatchdog => write!(f, "Pinged the systemd watchdog"),
+            Self::ListCertificates(_) => {
+                write!(f, "Successfully gathered the list of certificates")
+            }
             Self::ListFrontends(_) => write!(f, "Successfully gathered the list of frontends"),
             Self::ListWorkers(_) => write!(f, "Successfully listed all workers"),
             Self::LoadState(path, ok, error) => write!(
@@ -164,6 +347,15 @@ impl std::fmt::Display for Success {
                 write!(f, "Successfully set the metrics to {:?}", metrics_cfg)
             }
             Self::MasterStop => write!(f, "stopping main process"),
+            Self::NameResolved(name) => {
+                write!(f, "Sent the addresses of {} to the worker", name)
+            }
+            Self::SavedStateFile(count) => write!(f, "Saved {} orders to the state file", count),
+            Self::StoppingOnSignal(hard) => write!(
+                f,
+                "{} stopping the workers on signal",
+                if *hard { "Hard" } else { "Soft" }
+            ),
             // Self::Metrics => write!(f, "Successfully fetched the metrics"),
             Self::NotifiedClient(id) => {
                 write!(f, "Successfully notified client {} of the advancement", id)
@@ -171,21 +363,80 @@ impl std::fmt::Display for Success {
             Self::PropagatedWorkerEvent => {
                 write!(f, "Sent worker response to all subscribing clients")
             }
+            Self::OcspResponse(fingerprint) => write!(
+                f,
+                "Sent the OCSP response of certificate {} to the workers",
+                fingerprint
+            ),
             Self::Query(_) => write!(f, "Ran the query successfully"),
+            Self::RefreshOcspResponses(count) => {
+                write!(f, "Fetching {} OCSP responses", count)
+            }
             Self::ReloadConfiguration(ok, error) => write!(
                 f,
                 "Successfully reloaded configuration, ok: {}, errors: {}",
                 ok, error
             ),
+            Self::ReloadedOnSignal(count) => {
+                write!(f, "Reloading the configuration on SIGHUP, reload {}", count)
+            }
+            Self::ResolvingName(name) => write!(f, "Resolving {} for a worker", name),
+            Self::RolledBackBatch(count) => write!(
+                f,
+                "Rolled back the failed batch in the state, with {} orders",
+                count
+            ),
+            Self::RotatedTicketKeys(count) => {
+                write!(
+                    f,
+                    "Sent the rotated session ticket keys to {} workers",
+                    count
+                )
+            }
             Self::SaveState(counter, path) => {
                 write!(f, "saved {} config messages to {}", counter, path)
             }
+            Self::SharedAffinity(cluster_id) => write!(
+                f,
+                "Sent a sticky key of cluster {} to the workers",
+                cluster_id
+            ),
+            Self::StateHistory(_) => write!(f, "Successfully listed the versions of the state"),
+            Self::AuditLog(_) => write!(f, "Successfully listed the entries of the audit log"),
             Self::Status(_) => {
                 write!(f, "Sent a status response to client")
             }
+            Self::SyncedBackends(cluster_id, added, removed) => write!(
+                f,
+                "Synced the discovered backends of cluster {}, {} added, {} removed",
+                cluster_id, added, removed
+            ),
+            Self::SyncedContainers(containers, orders) => write!(
+                f,
+                "Synced the configuration of {} containers, {} orders",
+                containers, orders
+            ),
+            Self::PeerAnswered(address) => write!(f, "The peer {} answered", address),
+            Self::WaitingForPeers(request) => {
+                write!(
+                    f,
+                    "The request {} waits for the answers of the peers",
+                    request
+                )
+            }
+            Self::SyncedPeers(peers, version) => write!(
+                f,
+                "Sent the version {} of the state to {} peers",
+                version, peers
+            ),
             Self::SubscribeEvent(client_id) => {
                 write!(f, "Successfully Added {} to subscribers", client_id)
             }
+            Self::TailAccessLogs(client_id) => write!(
+                f,
+                "Successfully added {} to the clients tailing the access logs",
+                client_id
+            ),
             Self::UpgradeMain(pid) => write!(
                 f,
                 "new main process launched with pid {}, closing the old one",
@@ -194,14 +445,46 @@ impl std::fmt::Display for Success {
             Self::UpgradeWorker(id) => {
                 write!(f, "Successfully upgraded worker with new id: {}", id)
             }
+            Self::RestartWorker(old_id, new_id) => write!(
+                f,
+                "Successfully restarted worker {} with new id: {}",
+                old_id, new_id
+            ),
             Self::WorkerKilled(id) => write!(f, "Successfully killed worker {}", id),
             Self::WorkerLaunched(id) => write!(f, "Successfully launched worker {}", id),
-            Self::WorkerOrder(worker) => match worker {
-                Some(worker_id) => {
+            Self::WorkerOrder(workers) => match workers.as_slice() {
+                [] => write!(f, "Successfully executed the order on all workers"),
+                [worker_id] => {
                     write!(f, "Successfully executed the order on worker {}", worker_id)
                 }
-                None => write!(f, "Successfully executed the order on all workers"),
+                worker_ids => write!(
+                    f,
+                    "Successfully executed the order on workers {}",
+                    join_ids(worker_ids)
+                ),
             },
+            Self::WorkerOrderWithWarnings(_) => {
+                write!(f, "Executed the order, with warnings")
+            }
+            Self::ValidatedCertificate(_) => {
+                write!(
+                    f,
+                    "Successfully sent the validated certificate to the workers"
+                )
+            }
+            Self::SoftStop(CommandResponseContent::SoftStop(reports)) => {
+                let reports: Vec<String> = reports
+                    .iter()
+                    .map(|(id, report)| {
+                        format!(
+                            "worker {}: {} sessions drained, {} killed",
+                            id, report.drained, report.killed
+                        )
+                    })
+                    .collect();
+                write!(f, "Soft stopped the workers ({})", reports.join(", "))
+            }
+            Self::SoftStop(_) => write!(f, "Soft stopped the workers"),
             Self::WorkerResponse => write!(f, "Successfully handled worker response"),
             Self::WorkerRestarted(id) => write!(f, "Successfully restarted worker {}", id),
             Self::WorkerStopped(id) => write!(f, "Successfully stopped worker {}", id),
@@ -224,6 +507,11 @@ pub struct CommandServer {
     command_rx: Receiver<CommandMessage>,
     /// All client loops. id -> cloned command_tx
     clients: HashMap<String, Sender<CommandResponse>>,
+    /// the categories of orders each client is allowed to send
+    client_categories: HashMap<String, Vec<OrderCategory>>,
+    /// the unix user and group of each client, for the configuration change
+    /// events and the external authorization
+    client_peers: HashMap<String, PeerCredentials>,
     /// handles to the workers as seen from the main process
     workers: Vec<Worker>,
     /// A map of requests sent to workers.
@@ -241,7 +529,41 @@ pub struct CommandServer {
         ),
     >,
     event_subscribers: HashSet<String>,
+    /// the clients tailing the access logs, with the records each of them
+    /// received in the current second
+    access_log_subscribers: HashMap<String, (AccessLogFilter, AccessLogRateLimit)>,
+    /// certificates for which an expiration event was already sent
+    expiring_certificates: HashSet<(std::net::SocketAddr, CertificateFingerprint)>,
+    /// hex encoded TLS session ticket keys shared by the workers, the newest first
+    ticket_keys: Vec<String>,
     state: ConfigState,
+    /// the part of the state created from the labels of the containers
+    docker_state: ConfigState,
+    /// number of syncs of the containers, to identify their orders
+    docker_syncs: usize,
+    /// number of reloads on SIGHUP, to identify their orders
+    signal_reloads: usize,
+    /// the last versions of the state, to roll back to
+    history: StateHistory,
+    /// the other main processes receiving the changes of this one
+    peering: Option<Peering>,
+    audit_log: Option<AuditLog>,
+    /// the orders changing the proxy, written to the audit log once answered
+    audit_pending: HashMap<RequestIdentifier, AuditEntry>,
+    /// delays the restarts of the crashed workers, and stops them if they crash too often
+    crash_supervisor: CrashSupervisor,
+    /// the workers past `worker_max_memory`, already notified
+    memory_exceeded: HashSet<u32>,
+    /// the new workers of upgrades, with the id of the orders checking them
+    upgrade_checks: HashMap<u32, String>,
+    /// pid of the new main process of an upgrade, until it confirmed or failed
+    main_upgrade: Option<i32>,
+    /// the reports of the workers that panicked, until their channel closes
+    crash_reports: HashMap<u32, CrashReport>,
+    /// the heartbeats sent to the workers, and the ones they missed
+    heartbeats: Heartbeats,
+    /// version of the state last written to `state_file`
+    saved_state_version: Option<u64>,
     config: Config,
     /// id of the next worker to be spawned
     next_worker_id: u32,
@@ -260,6 +582,7 @@ impl CommandServer {
         command_tx: Sender<CommandMessage>,
         command_rx: Receiver<CommandMessage>,
         mut workers: Vec<Worker>,
+        state: ConfigState,
         accept_cancel: oneshot::Sender<()>,
     ) -> anyhow::Result<Self> {
         //FIXME
@@ -273,8 +596,6 @@ impl CommandServer {
             });*/
         }
 
-        let state: ConfigState = Default::default();
-
         for worker in workers.iter_mut() {
             let main_to_worker_channel = worker
                 .worker_channel
@@ -302,6 +623,10 @@ impl CommandServer {
         let executable_path = unsafe { get_executable_path()? };
         let backends_count = state.count_backends();
         let frontends_count = state.count_frontends();
+        let history = StateHistory::new(config.state_history_size);
+        let peering = Peering::new(&config, 0, command_tx.clone());
+        let audit_log = config.audit_log.as_ref().map(AuditLog::open).transpose()?;
+        let crash_supervisor = CrashSupervisor::new(&config);
 
         Ok(CommandServer {
             unix_listener_fd: fd,
@@ -310,8 +635,27 @@ impl CommandServer {
             command_tx,
             command_rx,
             clients: HashMap::new(),
+            client_categories: HashMap::new(),
+            client_peers: HashMap::new(),
             workers,
             event_subscribers: HashSet::new(),
+            access_log_subscribers: HashMap::new(),
+            expiring_certificates: HashSet::new(),
+            ticket_keys: Vec::new(),
+            docker_state: ConfigState::default(),
+            docker_syncs: 0,
+            signal_reloads: 0,
+            history,
+            peering,
+            audit_log,
+            audit_pending: HashMap::new(),
+            crash_supervisor,
+            memory_exceeded: HashSet::new(),
+            upgrade_checks: HashMap::new(),
+            main_upgrade: None,
+            crash_reports: HashMap::new(),
+            heartbeats: Heartbeats::default(),
+            saved_state_version: None,
             in_flight: HashMap::new(),
             next_worker_id: next_id,
             executable_path,
@@ -324,16 +668,33 @@ impl CommandServer {
     pub async fn run(&mut self) {
         while let Some(command) = self.command_rx.next().await {
             let result: anyhow::Result<Success> = match command {
-                CommandMessage::ClientNew { client_id, sender } => {
+                CommandMessage::ClientNew {
+                    client_id,
+                    sender,
+                    peer,
+                } => {
                     // this appears twice, which is weird
-                    debug!("adding new client {}", client_id);
+                    debug!("adding new client {} {:?}", client_id, peer);
                     self.clients.insert(client_id.to_owned(), sender);
+                    self.client_categories.insert(
+                        client_id.to_owned(),
+                        access::allowed_categories(&self.config.command_roles, peer),
+                    );
+                    if let Some(peer) = peer {
+                        self.client_peers.insert(client_id.to_owned(), peer);
+                    }
                     Ok(Success::ClientNew(client_id))
                 }
                 CommandMessage::ClientClose { client_id } => {
                     debug!("removing client {}", client_id);
                     self.clients.remove(&client_id);
+                    self.client_categories.remove(&client_id);
                     self.event_subscribers.remove(&client_id);
+                    if self.access_log_subscribers.remove(&client_id).is_some() {
+                        self.send_access_log_filters(&client_id).await;
+                    }
+                    self.abandon_audit_entries(&client_id);
+                    self.client_peers.remove(&client_id);
                     Ok(Success::ClientClose(client_id))
                 }
                 CommandMessage::ClientRequest { client_id, request } => {
@@ -354,20 +715,130 @@ impl CommandServer {
                     request_identifier,
                     response,
                 } => {
-                    let success_result = self
-                        .notify_advancement_to_client(request_identifier, response.clone())
-                        .await;
-                    if let Response::Ok(Success::UpgradeMain(_)) = response {
-                        std::thread::sleep(std::time::Duration::from_secs(2));
-                        info!("shutting down old main");
-                        std::process::exit(0);
+                    // the final response to a change shared with the peers waits
+                    // for their answers
+                    let response = match self.peering.as_mut() {
+                        Some(peering) => peering.hold_response(&request_identifier, response),
+                        None => Some(response),
                     };
-                    success_result
+                    match response {
+                        None => {
+                            let request = request_identifier.request.clone();
+                            let processing = Response::Processing(String::from(
+                                "Waiting for the answers of the peers",
+                            ));
+                            self.notify_advancement_to_client(request_identifier, processing)
+                                .await
+                                .map(|_| Success::WaitingForPeers(request))
+                        }
+                        Some(response) => {
+                            let success_result = self
+                                .notify_advancement_to_client(request_identifier, response.clone())
+                                .await;
+                            if let Response::Ok(Success::UpgradeMain(new_main_pid)) = response {
+                                // systemd follows the new main process from now on
+                                systemd::notify(&format!("MAINPID={}", new_main_pid));
+                                std::thread::sleep(std::time::Duration::from_secs(2));
+                                info!("shutting down old main");
+                                std::process::exit(0);
+                            };
+                            success_result
+                        }
+                    }
                 }
                 CommandMessage::MasterStop => {
                     info!("stopping main process");
+                    systemd::notify("STOPPING=1");
+                    // keeps the changes made since the last periodic save
+                    if let Err(e) = self.save_state_file() {
+                        error!("could not save the state file: {:#}", e);
+                    }
                     Ok(Success::MasterStop)
                 }
+                CommandMessage::RefreshOcspResponses => Ok(self.refresh_ocsp_responses()),
+                CommandMessage::OcspResponse(set_ocsp_response) => {
+                    Ok(self.set_ocsp_response(set_ocsp_response).await)
+                }
+                CommandMessage::NameResolved {
+                    worker_id,
+                    resolution,
+                } => Ok(self.send_name_resolution(worker_id, resolution).await),
+                CommandMessage::CheckCertificateExpirations => self
+                    .check_certificate_expirations()
+                    .await
+                    .with_context(|| "Could not check the certificate expirations"),
+                CommandMessage::CheckWorkerMemory => Ok(self.check_worker_memory().await),
+                CommandMessage::CheckHeartbeats => Ok(self.check_heartbeats().await),
+                CommandMessage::SaveStateFile => self
+                    .save_state_file()
+                    .with_context(|| "Could not save the state file"),
+                CommandMessage::StopOnSignal { hard } => self
+                    .stop_on_signal(hard)
+                    .await
+                    .with_context(|| "Could not stop on signal"),
+                CommandMessage::WorkerUpgradeChecked {
+                    request_identifier,
+                    check_id,
+                    old_id,
+                    new_id,
+                    listeners,
+                    restart,
+                    result,
+                } => self
+                    .finish_worker_upgrade(
+                        request_identifier,
+                        check_id,
+                        old_id,
+                        new_id,
+                        listeners,
+                        restart,
+                        result,
+                    )
+                    .await
+                    .with_context(|| format!("Could not upgrade worker {}", old_id)),
+                CommandMessage::MainUpgradeChecked {
+                    request_identifier,
+                    new_main_pid,
+                    confirmation,
+                } => self
+                    .finish_main_upgrade(request_identifier, new_main_pid, confirmation)
+                    .await
+                    .with_context(|| "Could not upgrade the main process"),
+                CommandMessage::WatchdogPing => {
+                    systemd::notify("WATCHDOG=1");
+                    Ok(Success::PingedWatchdog)
+                }
+                CommandMessage::RotateTicketKeys => Ok(self.rotate_ticket_keys().await),
+                CommandMessage::DiscoveredBackends {
+                    cluster_id,
+                    backends,
+                } => Ok(self.sync_discovered_backends(cluster_id, backends).await),
+                CommandMessage::DockerContainers(containers) => {
+                    Ok(self.sync_docker_containers(containers).await)
+                }
+                CommandMessage::RolledBackBatch(orders) => Ok(self.roll_back_batch(orders).await),
+                CommandMessage::ReloadConfiguration => self
+                    .reload_on_signal()
+                    .await
+                    .with_context(|| "Could not reload the configuration on SIGHUP"),
+                CommandMessage::PeerAnswered {
+                    address,
+                    request,
+                    is_change,
+                    answer,
+                } => {
+                    self.peer_answered(address, request, is_change, answer)
+                        .await
+                }
+                CommandMessage::PublishEvent { id, event } => {
+                    self.publish_event(id, event).await;
+                    Ok(Success::PropagatedWorkerEvent)
+                }
+                CommandMessage::RestartWorker { worker_id } => self
+                    .restart_worker(worker_id, Signal::SIGKILL)
+                    .await
+                    .map(|()| Success::WorkerRestarted(worker_id))
+                    .with_context(|| format!("Could not restart worker {}", worker_id)),
             };
 
             match result {
@@ -376,6 +847,19 @@ impl CommandServer {
 
                     // perform shutdowns
                     if order_success == Success::MasterStop {
+                        // the answers are written by the tasks of the clients,
+                        // they end once their channel is closed and drained
+                        for client in self.clients.values_mut() {
+                            client.close_channel();
+                        }
+                        Timer::after(CLIENT_FLUSH_DELAY).await;
+                        // the next start must not find them
+                        if let Ok(path) = self.config.command_socket_path() {
+                            if let Err(e) = fs::remove_file(&path) {
+                                error!("could not remove the command socket {}: {}", path, e);
+                            }
+                        }
+                        util::remove_pid_file();
                         // breaking the loop brings run() to return and ends Sōzu
                         // shouldn't we have the same break for both shutdowns?
                         break;
@@ -404,6 +888,11 @@ impl CommandServer {
             workers,
             state,
             next_id: self.next_worker_id,
+            ticket_keys: self.ticket_keys.clone(),
+            docker_state: self.docker_state.clone(),
+            history: Some(self.history.clone()),
+            peer_version: self.peering.as_ref().map(|peering| peering.version),
+            activated_listeners: systemd::activated_listeners(),
             //token_count: self.token_count,
         }
     }
@@ -415,9 +904,15 @@ impl CommandServer {
             workers,
             state,
             next_id,
+            ticket_keys,
+            docker_state,
+            history,
+            peer_version,
+            activated_listeners,
         } = upgrade_data;
 
         debug!("listener is: {}", command);
+        systemd::restore_activated_listeners(activated_listeners);
         let listener = Async::new(unsafe { UnixListener::from_raw_fd(command) })?;
 
         let (accept_cancel_tx, accept_cancel_rx) = oneshot::channel();
@@ -452,26 +947,33 @@ impl CommandServer {
                 };
                 debug!("Accepted a client from upgraded");
 
+                let peer = client_peer_credentials(&stream);
                 let (client_tx, client_rx) = channel(10000);
                 let client_id = format!("CL-up-{}", counter);
-                smol::spawn(client_loop(
-                    client_id.clone(),
-                    stream,
-                    tx.clone(),
-                    client_rx,
-                ))
-                .detach();
+                // the client is known before its first request is authorized
                 tx.send(CommandMessage::ClientNew {
-                    client_id,
+                    client_id: client_id.clone(),
                     sender: client_tx,
+                    peer,
                 })
                 .await
                 .expect("Could not send ClientNew message");
+                smol::spawn(client_loop(client_id, stream, tx.clone(), client_rx)).detach();
                 counter += 1;
             }
         })
         .detach();
 
+        spawn_ticket_keys_rotation(config.ticket_keys_rotation_interval, command_tx.clone());
+        spawn_consul_watchers(&config, command_tx.clone());
+        spawn_docker_watcher(&config, command_tx.clone());
+        spawn_reload_on_sighup(command_tx.clone())?;
+        spawn_stop_on_signals(command_tx.clone())?;
+        spawn_systemd_watchdog(command_tx.clone());
+        spawn_worker_heartbeats(config.worker_heartbeat_interval, command_tx.clone());
+        spawn_state_file_saves(&config, command_tx.clone());
+        remote::spawn_server(&config)?;
+
         let tx = command_tx.clone();
 
         let workers: Vec<Worker> = workers
@@ -506,6 +1008,7 @@ impl CommandServer {
                     run_state: serialized.run_state,
                     queue: serialized.queue.clone().into(),
                     scm_socket: ScmSocket::new(serialized.scm),
+                    cpu_cores: serialized.cpu_cores.clone(),
                 })
             })
             .collect();
@@ -517,6 +1020,13 @@ impl CommandServer {
 
         let executable_path = unsafe { get_executable_path()? };
 
+        let mut history = history.unwrap_or_else(|| StateHistory::new(config.state_history_size));
+        history.set_capacity(config.state_history_size);
+        history.record("UPGRADE", &state);
+        let peering = Peering::new(&config, peer_version.unwrap_or(0), command_tx.clone());
+        let audit_log = config.audit_log.as_ref().map(AuditLog::open).transpose()?;
+        let crash_supervisor = CrashSupervisor::new(&config);
+
         Ok(CommandServer {
             unix_listener_fd: command,
             config,
@@ -524,8 +1034,27 @@ impl CommandServer {
             command_tx,
             command_rx,
             clients: HashMap::new(),
+            client_categories: HashMap::new(),
+            client_peers: HashMap::new(),
             workers,
             event_subscribers: HashSet::new(),
+            access_log_subscribers: HashMap::new(),
+            expiring_certificates: HashSet::new(),
+            ticket_keys,
+            docker_state,
+            docker_syncs: 0,
+            signal_reloads: 0,
+            history,
+            peering,
+            audit_log,
+            audit_pending: HashMap::new(),
+            crash_supervisor,
+            memory_exceeded: HashSet::new(),
+            upgrade_checks: HashMap::new(),
+            main_upgrade: None,
+            crash_reports: HashMap::new(),
+            heartbeats: Heartbeats::default(),
+            saved_state_version: None,
             in_flight: HashMap::new(),
             next_worker_id: next_id,
             executable_path,
@@ -551,6 +1080,14 @@ impl CommandServer {
             self.unix_listener_fd
         );
         util::disable_close_on_exec(self.unix_listener_fd)?;
+        for (address, fd) in systemd::activated_listeners() {
+            util::disable_close_on_exec(fd).with_context(|| {
+                format!(
+                    "could not hand over the socket passed by systemd for {}",
+                    address
+                )
+            })?;
+        }
         Ok(())
     }
 
@@ -566,6 +1103,9 @@ impl CommandServer {
             }
         }
         util::enable_close_on_exec(self.unix_listener_fd)?;
+        for (_, fd) in systemd::activated_listeners() {
+            util::enable_close_on_exec(fd)?;
+        }
         Ok(())
     }
 
@@ -577,7 +1117,10 @@ impl CommandServer {
         //FIXME: too many loops, this could be cleaner
         for message in self.config.generate_config_messages() {
             if let CommandRequestOrder::Proxy(order) = message.order {
-                self.state.handle_order(&order);
+                // the workers got the preloaded state when they were launched
+                if !self.state.handle_order(&order) {
+                    continue;
+                }
 
                 if let &ProxyRequestOrder::AddCertificate(_) = &*order {
                     debug!("config generated AddCertificate( ... )");
@@ -648,169 +1191,1081 @@ impl CommandServer {
         .detach();
     }
 
-    /// in case a worker has crashed while Running and automatic_worker_restart is set to true
-    pub async fn restart_worker(&mut self, worker_id: u32) -> anyhow::Result<()> {
-        let worker_to_upgrade = &mut (self
-            .workers
-            .get_mut(worker_id as usize)
-            .with_context(|| "there should be a worker at that token")?);
+    /// fetches in the background the OCSP responses of the certificates that have a chain,
+    /// each response comes back as a CommandMessage::OcspResponse
+    pub fn refresh_ocsp_responses(&mut self) -> Success {
+        let mut count = 0usize;
 
-        match kill(Pid::from_raw(worker_to_upgrade.pid), None) {
-            Ok(_) => {
-                error!(
-                    "worker process {} (PID = {}) is alive but the worker must have crashed. Killing and replacing",
-                    worker_to_upgrade.id, worker_to_upgrade.pid
-                );
-            }
-            Err(_) => {
-                error!(
-                    "worker process {} (PID = {}) not answering, killing and replacing",
-                    worker_to_upgrade.id, worker_to_upgrade.pid
-                );
+        for (address, certificates) in self.state.certificates.iter() {
+            for (fingerprint, (certificate_and_key, _)) in certificates.iter() {
+                if certificate_and_key.certificate_chain.is_empty() {
+                    continue;
+                }
+
+                let address = *address;
+                let fingerprint = fingerprint.clone();
+                let certificate_and_key = certificate_and_key.clone();
+                let mut command_tx = self.command_tx.clone();
+                count += 1;
+
+                smol::spawn(async move {
+                    let ocsp_response =
+                        smol::unblock(move || ocsp::fetch_ocsp_response(&certificate_and_key))
+                            .await;
+
+                    match ocsp_response {
+                        Ok(ocsp_response) => {
+                            let set_ocsp_response = SetOcspResponse {
+                                address,
+                                fingerprint,
+                                ocsp_response: Some(ocsp_response),
+                            };
+                            if let Err(e) = command_tx
+                                .send(CommandMessage::OcspResponse(set_ocsp_response))
+                                .await
+                            {
+                                error!("could not send the OCSP response: {}", e);
+                            }
+                        }
+                        Err(e) => {
+                            incr!("ocsp.fetch.error");
+                            error!(
+                                "could not fetch the OCSP response of certificate {}: {:#}",
+                                fingerprint, e
+                            );
+                        }
+                    }
+                })
+                .detach();
             }
         }
 
-        kill(Pid::from_raw(worker_to_upgrade.pid), Signal::SIGKILL)
-            .with_context(|| "failed to kill the worker process")?;
+        Success::RefreshOcspResponses(count)
+    }
 
-        worker_to_upgrade.run_state = RunState::Stopped;
+    /// stores a fetched OCSP response in the state and sends it to the workers
+    pub async fn set_ocsp_response(&mut self, set_ocsp_response: SetOcspResponse) -> Success {
+        let fingerprint = set_ocsp_response.fingerprint.to_string();
+        let order = ProxyRequestOrder::SetOcspResponse(set_ocsp_response);
 
-        incr!("worker_restart");
+        // the workers already staple this response
+        if !self.state.handle_order(&order) {
+            return Success::OcspResponse(fingerprint);
+        }
 
-        let new_worker_id = self.next_worker_id;
-        let listeners = Some(Listeners {
-            http: Vec::new(),
-            tls: Vec::new(),
-            tcp: Vec::new(),
-        });
+        let id = format!("OCSP-{}", fingerprint);
+        if self.send_to_workers(id, order).await == 0 {
+            error!("no worker found to send the OCSP response");
+        }
 
-        let mut new_worker = start_worker(
-            new_worker_id,
-            &self.config,
-            self.executable_path.clone(),
-            &self.state,
-            listeners,
-        )
-        .with_context(|| format!("Could not start new worker {}", new_worker_id))?;
+        Success::OcspResponse(fingerprint)
+    }
 
-        info!("created new worker: {}", new_worker_id);
-        self.next_worker_id += 1;
+    /// resolves in the background a name a worker asked for, the event loop of
+    /// the worker never waits on the system resolver. The addresses come back
+    /// as a CommandMessage::NameResolved, none if the resolution failed or took
+    /// longer than NAME_RESOLUTION_TIMEOUT
+    fn resolve_name(&mut self, worker_id: u32, mut resolution: NameResolution) -> Success {
+        let name = resolution.name.clone();
+        let mut command_tx = self.command_tx.clone();
 
-        let sock = new_worker
-            .worker_channel
-            .take()
-            .with_context(|| {
-                format!(
-                    "the new worker with id {} does not have a channel",
-                    new_worker.id
-                )
-            })? // this used to crash with unwrap(), do we still want to crash?
-            .sock;
-        let (worker_tx, worker_rx) = channel(10_000);
-        new_worker.sender = Some(worker_tx);
+        smol::spawn(async move {
+            let (host, port) = (resolution.name.clone(), resolution.port);
+            let lookup = smol::unblock(move || {
+                (host.as_str(), port)
+                    .to_socket_addrs()
+                    .map(|addresses| addresses.collect())
+            });
+            let timeout = async {
+                Timer::after(NAME_RESOLUTION_TIMEOUT).await;
+                Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+            };
 
-        let stream = Async::new(unsafe {
-            let fd = sock.into_raw_fd();
-            UnixStream::from_raw_fd(fd)
-        })?;
+            match future::or(lookup, timeout).await {
+                Ok(addresses) => resolution.addresses = addresses,
+                Err(e) => {
+                    incr!("forward.resolution_error");
+                    debug!("could not resolve {}: {}", resolution.name, e);
+                }
+            }
 
-        let new_worker_id = new_worker.id;
-        let command_tx = self.command_tx.clone();
-        smol::spawn(async move {
-            worker_loop(new_worker_id, stream, command_tx, worker_rx).await;
+            if let Err(e) = command_tx
+                .send(CommandMessage::NameResolved {
+                    worker_id,
+                    resolution,
+                })
+                .await
+            {
+                error!("could not send the resolved name: {}", e);
+            }
         })
         .detach();
 
-        let mut orders = self.state.generate_activate_orders();
-        for (count, order) in orders.drain(..).enumerate() {
-            new_worker
-                .send(
-                    format!("RESTART-{}-ACTIVATE-{}", new_worker_id, count),
-                    order,
-                )
-                .await;
+        Success::ResolvingName(name)
+    }
+
+    /// sends the addresses of a name to the worker that asked for them, it does
+    /// not answer
+    async fn send_name_resolution(
+        &mut self,
+        worker_id: u32,
+        resolution: NameResolution,
+    ) -> Success {
+        let name = resolution.name.clone();
+        let id = format!("RESOLVE-{}", resolution.session);
+
+        match self
+            .workers
+            .iter_mut()
+            .find(|worker| worker.id == worker_id)
+        {
+            Some(worker) => {
+                worker
+                    .send(id, ProxyRequestOrder::NameResolved(resolution))
+                    .await
+            }
+            None => {
+                debug!("worker {} closed before {} was resolved", worker_id, name);
+            }
         }
 
-        new_worker
-            .send(
-                format!("RESTART-{}-STATUS", new_worker_id),
-                ProxyRequestOrder::Status,
-            )
-            .await;
+        Success::NameResolved(name)
+    }
 
-        self.workers.push(new_worker);
+    /// stores a sticky key bound to a backend by a worker in the state, and sends
+    /// it to the workers
+    pub async fn share_affinity(&mut self, affinity: Affinity) -> Success {
+        let cluster_id = affinity.cluster_id.clone();
+        let id = format!("AFFINITY-{}-{}", cluster_id, affinity.key);
+        let order = ProxyRequestOrder::SetAffinity(affinity);
 
-        Ok(())
+        // the workers already know it
+        if !self.state.handle_order(&order) {
+            return Success::SharedAffinity(cluster_id);
+        }
+
+        self.send_to_workers(id, order).await;
+        Success::SharedAffinity(cluster_id)
     }
 
-    async fn handle_worker_close(&mut self, id: u32) -> anyhow::Result<Success> {
-        info!("removing worker {}", id);
+    /// replaces the backends of a cluster with the instances found in a service
+    /// catalog. The backends already known at the address of an instance are kept
+    /// as they are, with their settings
+    pub async fn sync_discovered_backends(
+        &mut self,
+        cluster_id: String,
+        discovered: Vec<DiscoveredBackend>,
+    ) -> Success {
+        let known = self
+            .state
+            .backends
+            .get(&cluster_id)
+            .cloned()
+            .unwrap_or_default();
+
+        let removed: Vec<(String, ProxyRequestOrder)> = known
+            .iter()
+            .filter(|backend| !discovered.iter().any(|d| d.address == backend.address))
+            .map(|backend| {
+                let id = format!("DISCOVERY-REMOVE-{}-{}", cluster_id, backend.address);
+                let order = ProxyRequestOrder::RemoveBackend(RemoveBackend {
+                    cluster_id: cluster_id.clone(),
+                    backend_id: backend.backend_id.clone(),
+                    address: backend.address,
+                });
+                (id, order)
+            })
+            .collect();
 
-        if let Some(worker) = self.workers.iter_mut().find(|w| w.id == id) {
-            // In case a worker crashes and should be restarted
-            if self.config.worker_automatic_restart && worker.run_state == RunState::Running {
-                info!("Automatically restarting worker {}", id);
-                match self.restart_worker(id).await {
-                    Ok(()) => info!("Worker {} has automatically restarted!", id),
-                    Err(e) => error!("Could not restart worker {}: {}", id, e),
-                }
-                return Ok(Success::WorkerRestarted(id));
-            }
+        let added: Vec<(String, ProxyRequestOrder)> = discovered
+            .iter()
+            .filter(|d| !known.iter().any(|backend| backend.address == d.address))
+            .map(|d| {
+                let id = format!("DISCOVERY-ADD-{}-{}", cluster_id, d.address);
+                let order = ProxyRequestOrder::AddBackend(Backend {
+                    cluster_id: cluster_id.clone(),
+                    backend_id: d.backend_id.clone(),
+                    address: d.address,
+                    sticky_id: None,
+                    load_balancing_parameters: Some(LoadBalancingParams { weight: 100 }),
+                    backup: None,
+                    max_connections: None,
+                    timeouts: Timeouts::default(),
+                    tls: None,
+                });
+                (id, order)
+            })
+            .collect();
 
-            info!("Closing the worker {}.", worker.id);
-            if !worker.the_pid_is_alive() {
-                info!("Worker {} is dead, setting to Stopped.", worker.id);
-                worker.run_state = RunState::Stopped;
-                return Ok(Success::WorkerStopped(id));
+        let (added_count, removed_count) = (added.len(), removed.len());
+        for (id, order) in removed.into_iter().chain(added) {
+            if self.state.handle_order(&order) {
+                self.send_to_workers(id, order).await;
             }
+        }
 
+        if added_count + removed_count > 0 {
             info!(
-                "Worker {} is not dead but should be. Let's kill it.",
-                worker.id
+                "cluster {}: {} discovered backends added, {} removed",
+                cluster_id, added_count, removed_count
             );
-
-            match kill(Pid::from_raw(worker.pid), Signal::SIGKILL) {
-                Ok(()) => {
-                    info!("Worker {} was successfuly killed", id);
-                    worker.run_state = RunState::Stopped;
-                    return Ok(Success::WorkerKilled(id));
-                }
-                Err(e) => {
-                    return Err(e).with_context(|| "failed to kill the worker process");
-                }
-            }
+            self.backends_count = self.state.count_backends();
+            gauge!("configuration.backends", self.backends_count);
+            self.record_state_version(&format!("DISCOVERY-{}", cluster_id), None)
+                .await;
         }
-        bail!(format!("Could not find worker {}", id))
+
+        Success::SyncedBackends(cluster_id, added_count, removed_count)
     }
 
-    async fn handle_worker_response(
-        &mut self,
-        worker_id: u32,
-        response: ProxyResponse,
-    ) -> anyhow::Result<Success> {
-        // Notify the client with Processing in case of a proxy event
-        if let Some(ProxyResponseContent::Event(proxy_event)) = response.content {
-            let event: Event = proxy_event.into();
-            for client_id in self.event_subscribers.iter() {
-                if let Some(client_tx) = self.clients.get_mut(client_id) {
-                    let event = CommandResponse::new(
-                        response.id.to_string(),
-                        CommandStatus::Processing,
-                        format!("{}", worker_id),
-                        Some(CommandResponseContent::Event(event.clone())),
-                    );
-                    client_tx.send(event).await.with_context(|| {
-                        format!("could not send message to client {}", client_id)
-                    })?
-                }
+    /// applies the clusters, frontends and backends of the routed containers,
+    /// and removes the ones of the containers that are gone
+    pub async fn sync_docker_containers(&mut self, containers: Vec<RoutedContainer>) -> Success {
+        let listeners = self
+            .config
+            .discovery
+            .as_ref()
+            .and_then(|discovery| discovery.docker.as_ref())
+            .map(|docker| docker.listeners.clone())
+            .unwrap_or_default();
+        let selected =
+            |address: &&std::net::SocketAddr| listeners.is_empty() || listeners.contains(*address);
+        let http_listeners: Vec<std::net::SocketAddr> = self
+            .state
+            .http_listeners
+            .keys()
+            .filter(selected)
+            .copied()
+            .collect();
+        let https_listeners: Vec<std::net::SocketAddr> = self
+            .state
+            .https_listeners
+            .keys()
+            .filter(selected)
+            .copied()
+            .collect();
+
+        // the containers join the clusters that do not come from them, and keep
+        // their settings
+        let existing_clusters: HashSet<String> = self
+            .state
+            .clusters
+            .keys()
+            .filter(|cluster_id| !self.docker_state.clusters.contains_key(*cluster_id))
+            .cloned()
+            .collect();
+
+        let mut docker_state = ConfigState::default();
+        for order in docker::generate_orders(
+            &containers,
+            &http_listeners,
+            &https_listeners,
+            &existing_clusters,
+        ) {
+            docker_state.handle_order(&order);
+        }
+
+        let orders = self.docker_state.diff(&docker_state);
+        self.docker_state = docker_state;
+        self.docker_syncs += 1;
+
+        let count = orders.len();
+        for (index, order) in orders.into_iter().enumerate() {
+            if self.state.handle_order(&order) {
+                let id = format!("DOCKER-{}-{}", self.docker_syncs, index);
+                self.send_to_workers(id, order).await;
             }
-            return Ok(Success::PropagatedWorkerEvent);
         }
 
-        // Notify the function that sent the request to which the worker responded.
-        // The in_flight map contains the id of each sent request, together with a sender
-        // we use to send the response to.
-        match self.in_flight.remove(&response.id) {
+        if count > 0 {
+            info!(
+                "synced the configuration of {} containers, {} orders",
+                containers.len(),
+                count
+            );
+            self.backends_count = self.state.count_backends();
+            self.frontends_count = self.state.count_frontends();
+            gauge!("configuration.clusters", self.state.clusters.len());
+            gauge!("configuration.backends", self.backends_count);
+            gauge!("configuration.frontends", self.frontends_count);
+            self.record_state_version(&format!("DOCKER-{}", self.docker_syncs), None)
+                .await;
+        }
+
+        Success::SyncedContainers(containers.len(), count)
+    }
+
+    /// reloads the configuration file, like `sozu reload` without a path, for
+    /// the init systems and the tools sending SIGHUP
+    pub async fn reload_on_signal(&mut self) -> anyhow::Result<Success> {
+        self.signal_reloads += 1;
+        info!("received SIGHUP, reloading the configuration");
+
+        let request_id = format!("SIGHUP-{}", self.signal_reloads);
+        let result = self
+            .reload_configuration(None, request_id.clone(), None)
+            .await;
+        self.record_state_version(&request_id, None).await;
+
+        result.map(|_| Success::ReloadedOnSignal(self.signal_reloads))
+    }
+
+    /// the workers already rolled the batch back, only the state changes
+    pub async fn roll_back_batch(&mut self, orders: Vec<ProxyRequestOrder>) -> Success {
+        for order in orders.iter() {
+            self.state.handle_order(order);
+        }
+
+        self.backends_count = self.state.count_backends();
+        self.frontends_count = self.state.count_frontends();
+        gauge!("configuration.clusters", self.state.clusters.len());
+        gauge!("configuration.backends", self.backends_count);
+        gauge!("configuration.frontends", self.frontends_count);
+        self.record_state_version("BATCH-ROLLBACK", None).await;
+
+        Success::RolledBackBatch(orders.len())
+    }
+
+    /// keeps the state as a new version of the history, if it changed since the
+    /// last one, and tells the subscribers. The changes that are not recorded,
+    /// like shared affinities or OCSP responses, go in the next version.
+    /// Returns the orders leading to the new version
+    pub async fn record_state_version(
+        &mut self,
+        request_id: &str,
+        client_id: Option<&str>,
+    ) -> Vec<ProxyRequestOrder> {
+        let (version, orders) = match self.history.record(request_id, &self.state) {
+            Some(recorded) => recorded,
+            None => return Vec::new(),
+        };
+        debug!("state version {} created by {}", version, request_id);
+
+        let id = format!("VERSION-{}", version);
+        let change = ConfigurationChange {
+            version,
+            request_id: request_id.to_owned(),
+            client: client_id.map(ToOwned::to_owned),
+            user: client_id
+                .and_then(|client_id| self.client_peers.get(client_id))
+                .map(access::user_name),
+            orders: count_order_types(&orders),
+        };
+        self.publish_event(id.clone(), Event::ConfigurationChanged(change))
+            .await;
+
+        for order in orders.iter() {
+            let event = match order {
+                ProxyRequestOrder::ActivateListener(activate) => {
+                    Event::ListenerActivated(activate.address, activate.proxy.clone())
+                }
+                ProxyRequestOrder::DeactivateListener(deactivate) => {
+                    Event::ListenerDeactivated(deactivate.address, deactivate.proxy.clone())
+                }
+                _ => continue,
+            };
+            self.publish_event(id.clone(), event).await;
+        }
+        orders
+    }
+
+    /// keeps an order changing the proxy, written to the audit log with its
+    /// answer
+    pub fn start_audit_entry(&mut self, client_id: &str, request: &CommandRequest) {
+        if self.audit_log.is_none() {
+            return;
+        }
+        let peer = self.client_peers.get(client_id);
+        let entry = AuditEntry {
+            timestamp: 0,
+            request_id: request.id.clone(),
+            client: client_id.to_owned(),
+            user: peer.map(access::user_name),
+            uid: peer.map(|peer| peer.uid.as_raw()),
+            category: request.order.category(),
+            order: request.order.clone(),
+            dry_run: request.dry_run,
+            status: None,
+            message: String::new(),
+            version: None,
+        };
+        self.audit_pending.insert(
+            RequestIdentifier {
+                client: client_id.to_owned(),
+                request: request.id.clone(),
+            },
+            entry,
+        );
+    }
+
+    /// writes the entry of an order to the audit log, once it is answered
+    pub fn finish_audit_entry(
+        &mut self,
+        request_identifier: &RequestIdentifier,
+        status: Option<CommandStatus>,
+        message: &str,
+    ) {
+        let (audit_log, mut entry) = match (
+            self.audit_log.as_mut(),
+            self.audit_pending.remove(request_identifier),
+        ) {
+            (Some(audit_log), Some(entry)) => (audit_log, entry),
+            _ => return,
+        };
+
+        entry.timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or(0);
+        entry.status = status;
+        entry.message = message.to_owned();
+        entry.version = self.history.current_version();
+
+        if let Err(e) = audit_log.write(&entry) {
+            incr!("command.audit.error");
+            error!("could not audit the order {}: {:#}", entry.request_id, e);
+        }
+    }
+
+    /// the orders of a client that left are written without their result
+    fn abandon_audit_entries(&mut self, client_id: &str) {
+        let identifiers: Vec<RequestIdentifier> = self
+            .audit_pending
+            .keys()
+            .filter(|identifier| identifier.client == client_id)
+            .cloned()
+            .collect();
+        for identifier in identifiers {
+            self.finish_audit_entry(&identifier, None, "the client left before the answer");
+        }
+    }
+
+    /// generates a new session ticket key, keeps the previous one to decrypt
+    /// the tickets it issued, and sends both to the workers
+    pub async fn rotate_ticket_keys(&mut self) -> Success {
+        let mut key = [0u8; TICKET_KEY_LENGTH];
+        OsRng.fill_bytes(&mut key);
+
+        self.ticket_keys.insert(0, hex::encode(key));
+        self.ticket_keys.truncate(2);
+
+        let order = match self.ticket_keys_order() {
+            Some(order) => order,
+            None => return Success::RotatedTicketKeys(0),
+        };
+
+        let count = self
+            .send_to_workers(String::from("TICKET-KEYS"), order)
+            .await;
+        Success::RotatedTicketKeys(count)
+    }
+
+    /// the order setting the current ticket keys, to send to new workers
+    fn ticket_keys_order(&self) -> Option<ProxyRequestOrder> {
+        if self.ticket_keys.is_empty() {
+            return None;
+        }
+
+        Some(ProxyRequestOrder::SetTicketKeys(SetTicketKeys {
+            keys: self.ticket_keys.clone(),
+            lifetime: self.config.ticket_keys_rotation_interval,
+        }))
+    }
+
+    /// the order asking the workers for the access logs the clients tail, to
+    /// send to new workers
+    fn access_log_filters_order(&self) -> Option<ProxyRequestOrder> {
+        if self.access_log_subscribers.is_empty() {
+            return None;
+        }
+
+        Some(ProxyRequestOrder::SetAccessLogFilters(
+            self.access_log_subscribers
+                .values()
+                .map(|(filter, _)| filter.clone())
+                .collect(),
+        ))
+    }
+
+    /// sends the filters of the clients tailing the access logs to the workers,
+    /// after the client with this id subscribed or left
+    async fn send_access_log_filters(&mut self, client_id: &str) -> usize {
+        let order = self
+            .access_log_filters_order()
+            .unwrap_or(ProxyRequestOrder::SetAccessLogFilters(Vec::new()));
+        self.send_to_workers(format!("ACCESS-LOG-FILTERS-{}", client_id), order)
+            .await
+    }
+
+    /// sends an order to the running workers and logs their errors,
+    /// returns the number of workers the order was sent to
+    async fn send_to_workers(&mut self, id: String, order: ProxyRequestOrder) -> usize {
+        let (tx, mut rx) = futures::channel::mpsc::channel(self.workers.len() * 2);
+
+        let mut count = 0usize;
+        for ref mut worker in self.workers.iter_mut().filter(|worker| {
+            worker.run_state != RunState::Stopping && worker.run_state != RunState::Stopped
+        }) {
+            let order = match self.state.worker_scopes.order_for(worker.id, &order) {
+                Some(order) => order,
+                None => continue,
+            };
+            let order = worker.with_listener(order);
+            worker.send(id.clone(), order).await;
+            count += 1;
+        }
+
+        if count == 0 {
+            return count;
+        }
+
+        self.in_flight.insert(id, (tx, count));
+
+        smol::spawn(async move {
+            let mut i = 0;
+            while let Some((proxy_response, worker_id)) = rx.next().await {
+                match proxy_response.status {
+                    ProxyResponseStatus::Ok => {}
+                    ProxyResponseStatus::Processing => continue,
+                    ProxyResponseStatus::Error(e) => {
+                        error!(
+                            "worker {} could not handle the order {}: {}",
+                            worker_id, proxy_response.id, e
+                        );
+                    }
+                };
+
+                i += 1;
+                if i == count {
+                    break;
+                }
+            }
+        })
+        .detach();
+
+        count
+    }
+
+    /// sets the `certificate_ttl_days` gauge of each certificate, labelled with its fingerprint,
+    /// and sends an event to the subscribers for the ones expiring before the threshold
+    pub async fn check_certificate_expirations(&mut self) -> anyhow::Result<Success> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .with_context(|| "the system time is before the unix epoch")?
+            .as_secs() as i64;
+        let threshold = i64::from(self.config.certificate_expiration_threshold) * 24 * 3600;
+
+        let mut expiring_certificates = HashSet::new();
+        for certificate in self.state.list_certificates() {
+            let fingerprint = certificate.fingerprint.to_string();
+            let ttl = certificate.expiration - now;
+            gauge!(
+                "certificate_ttl_days",
+                (ttl.max(0) / (24 * 3600)) as usize,
+                Some(&fingerprint),
+                None
+            );
+
+            if ttl >= threshold {
+                continue;
+            }
+
+            warn!(
+                "certificate {} of listener {} expires in {} days",
+                fingerprint,
+                certificate.address,
+                ttl / (24 * 3600)
+            );
+
+            let key = (certificate.address, certificate.fingerprint);
+            if !self.expiring_certificates.contains(&key) {
+                self.notify_event_subscribers(
+                    format!("EXPIRATION-{}", fingerprint),
+                    String::from("main"),
+                    Event::CertificateWillExpire(
+                        fingerprint,
+                        certificate.address,
+                        certificate.expiration,
+                    ),
+                )
+                .await?;
+            }
+            expiring_certificates.insert(key);
+        }
+
+        // the replaced or removed certificates are forgotten
+        let count = expiring_certificates.len();
+        self.expiring_certificates = expiring_certificates;
+
+        Ok(Success::CheckedCertificateExpirations(count))
+    }
+
+    /// sends an event for the workers using more memory than `worker_max_memory`,
+    /// and replaces them if `worker_memory_recycle` is set
+    pub async fn check_worker_memory(&mut self) -> Success {
+        let max_memory = match self.config.worker_max_memory {
+            Some(max_memory) => max_memory * 1024 * 1024,
+            None => return Success::CheckedWorkerMemory(0),
+        };
+
+        let exceeding = self
+            .workers
+            .iter()
+            .filter(|worker| worker.run_state == RunState::Running)
+            .filter_map(|worker| {
+                worker
+                    .resident_memory()
+                    .filter(|memory| *memory > max_memory)
+                    .map(|memory| (worker.id, memory))
+            })
+            .collect::<Vec<_>>();
+
+        let mut memory_exceeded = HashSet::new();
+        for (id, memory) in exceeding.iter() {
+            memory_exceeded.insert(*id);
+            if self.memory_exceeded.contains(id) {
+                continue;
+            }
+
+            warn!(
+                "worker {} uses {} MB of memory, more than the {} MB allowed",
+                id,
+                memory / (1024 * 1024),
+                max_memory / (1024 * 1024)
+            );
+            incr!("worker_memory_exceeded");
+            self.publish_event(
+                format!("WORKER-{}", id),
+                Event::WorkerMemoryExceeded(*id, *memory),
+            )
+            .await;
+
+            if self.config.worker_memory_recycle {
+                info!("recycling worker {}", id);
+                let request_identifier = RequestIdentifier::new(
+                    MAIN_PROCESS_CLIENT.to_string(),
+                    format!("RECYCLE-{}", id),
+                );
+                if let Err(e) = self.upgrade_worker(request_identifier, *id, true).await {
+                    error!("could not recycle worker {}: {:#}", id, e);
+                }
+            }
+        }
+        // the workers back under the ceiling will be notified again
+        self.memory_exceeded = memory_exceeded;
+
+        Success::CheckedWorkerMemory(exceeding.len())
+    }
+
+    /// sends a heartbeat to the workers, marks the ones that missed
+    /// `worker_heartbeat_misses` in a row as `NotAnswering`, and applies
+    /// `worker_not_answering_policy` to them
+    pub async fn check_heartbeats(&mut self) -> Success {
+        let misses = self.config.worker_heartbeat_misses;
+        let mut not_answering = Vec::new();
+        for worker in self.workers.iter_mut().filter(|worker| {
+            worker.run_state == RunState::Running || worker.run_state == RunState::NotAnswering
+        }) {
+            let (id, missed) = self.heartbeats.beat(worker.id);
+            if missed >= misses && worker.run_state == RunState::Running {
+                worker.run_state = RunState::NotAnswering;
+                not_answering.push((worker.id, missed));
+            }
+            worker.send(id, ProxyRequestOrder::Status).await;
+        }
+
+        for (id, missed) in not_answering {
+            warn!("worker {} missed {} heartbeats in a row", id, missed);
+            incr!("worker_not_answering");
+            self.publish_event(format!("WORKER-{}", id), Event::WorkerNotAnswering(id))
+                .await;
+
+            let signal = match self.config.worker_not_answering_policy {
+                NotAnsweringPolicy::None => continue,
+                NotAnsweringPolicy::Terminate => Signal::SIGTERM,
+                NotAnsweringPolicy::Kill => Signal::SIGKILL,
+            };
+            info!("replacing worker {} with {}", id, signal);
+            self.heartbeats.remove(id);
+            if let Err(e) = self.restart_worker(id, signal).await {
+                error!("could not replace worker {}: {:#}", id, e);
+            }
+        }
+
+        Success::CheckedHeartbeats(
+            self.workers
+                .iter()
+                .filter(|worker| worker.run_state == RunState::NotAnswering)
+                .count(),
+        )
+    }
+
+    /// records the answer of a worker to its heartbeat. A worker marked
+    /// `NotAnswering` is running again once it answers
+    fn heartbeat_answered(&mut self, worker_id: u32, response_id: &str) -> Success {
+        if !self.heartbeats.answered(worker_id, response_id) {
+            return Success::WorkerResponse;
+        }
+        if let Some(worker) = self
+            .workers
+            .iter_mut()
+            .find(|worker| worker.id == worker_id && worker.run_state == RunState::NotAnswering)
+        {
+            info!("worker {} answers its heartbeats again", worker_id);
+            worker.run_state = RunState::Running;
+        }
+        Success::WorkerResponse
+    }
+
+    /// logs the panic of a worker, and keeps its report for the event sent
+    /// once the worker is closed
+    fn crash_reported(&mut self, worker_id: u32, report: CrashReport) -> Success {
+        error!(
+            "worker {} panicked at {}: {}, with {} sessions, after the order {}",
+            worker_id,
+            report.location.as_deref().unwrap_or("an unknown location"),
+            report.message,
+            report.sessions,
+            report.last_order.as_deref().unwrap_or("none")
+        );
+        error!("backtrace of worker {}:\n{}", worker_id, report.backtrace);
+        incr!("worker_panic");
+        self.crash_reports.insert(worker_id, report);
+        Success::CrashReported(worker_id)
+    }
+
+    /// sends an event of the main process to the subscribers, the failures
+    /// are only logged
+    async fn publish_event(&mut self, id: String, event: Event) {
+        if let Err(e) = self
+            .notify_event_subscribers(id, String::from("main"), event)
+            .await
+        {
+            error!("could not send the event to the subscribers: {:#}", e);
+        }
+    }
+
+    /// sends an event to the clients subscribed to the events
+    async fn notify_event_subscribers(
+        &mut self,
+        id: String,
+        message: String,
+        event: Event,
+    ) -> anyhow::Result<()> {
+        for client_id in self.event_subscribers.iter() {
+            if let Some(client_tx) = self.clients.get_mut(client_id) {
+                let event = CommandResponse::new(
+                    id.clone(),
+                    CommandStatus::Processing,
+                    message.clone(),
+                    Some(CommandResponseContent::Event(event.clone())),
+                );
+                client_tx
+                    .send(event)
+                    .await
+                    .with_context(|| format!("could not send message to client {}", client_id))?
+            }
+        }
+
+        Ok(())
+    }
+
+    /// sends an access log record of a worker to the clients tailing the
+    /// access logs, within their filter and rate
+    async fn notify_access_log_subscribers(
+        &mut self,
+        id: String,
+        worker_id: u32,
+        record: AccessLogRecord,
+    ) -> anyhow::Result<()> {
+        let now = Instant::now();
+        for (client_id, (filter, limit)) in self.access_log_subscribers.iter_mut() {
+            if !filter.matches(record.cluster_id.as_deref(), record.status)
+                || !limit.allow(filter.max_per_second, now)
+            {
+                continue;
+            }
+
+            if let Some(client_tx) = self.clients.get_mut(client_id) {
+                let response = CommandResponse::new(
+                    id.clone(),
+                    CommandStatus::Processing,
+                    format!("{}", worker_id),
+                    Some(CommandResponseContent::AccessLog(record.clone())),
+                );
+                client_tx
+                    .send(response)
+                    .await
+                    .with_context(|| format!("could not send message to client {}", client_id))?
+            }
+        }
+
+        Ok(())
+    }
+
+    /// in case a worker has crashed while Running and automatic_worker_restart is set to true
+    /// stops a worker with this signal, and starts a new one in its place
+    pub async fn restart_worker(&mut self, worker_id: u32, signal: Signal) -> anyhow::Result<()> {
+        let worker_to_upgrade = self
+            .workers
+            .iter_mut()
+            .find(|worker| worker.id == worker_id)
+            .with_context(|| "there should be a worker with that id")?;
+
+        match kill(Pid::from_raw(worker_to_upgrade.pid), None) {
+            Ok(_) => {
+                error!(
+                    "worker process {} (PID = {}) is alive but the worker must have crashed. Killing and replacing",
+                    worker_to_upgrade.id, worker_to_upgrade.pid
+                );
+            }
+            Err(_) => {
+                error!(
+                    "worker process {} (PID = {}) not answering, killing and replacing",
+                    worker_to_upgrade.id, worker_to_upgrade.pid
+                );
+            }
+        }
+
+        kill(Pid::from_raw(worker_to_upgrade.pid), signal)
+            .with_context(|| "failed to kill the worker process")?;
+
+        worker_to_upgrade.run_state = RunState::Stopped;
+        let cpu_cores = worker_to_upgrade.cpu_cores.clone();
+
+        incr!("worker_restart");
+
+        let new_worker_id = self.next_worker_id;
+
+        self.state
+            .worker_scopes
+            .replace_worker(worker_id, new_worker_id);
+        let state = self.state.scoped_to(new_worker_id);
+        let listeners = Some(bind_listeners(&self.config, &state));
+        let mut new_worker = start_worker(
+            new_worker_id,
+            &self.config,
+            self.executable_path.clone(),
+            &state,
+            listeners,
+            cpu_cores,
+        )
+        .with_context(|| format!("Could not start new worker {}", new_worker_id))?;
+
+        info!("created new worker: {}", new_worker_id);
+        self.next_worker_id += 1;
+
+        let sock = new_worker
+            .worker_channel
+            .take()
+            .with_context(|| {
+                format!(
+                    "the new worker with id {} does not have a channel",
+                    new_worker.id
+                )
+            })? // this used to crash with unwrap(), do we still want to crash?
+            .sock;
+        let (worker_tx, worker_rx) = channel(10_000);
+        new_worker.sender = Some(worker_tx);
+
+        let stream = Async::new(unsafe {
+            let fd = sock.into_raw_fd();
+            UnixStream::from_raw_fd(fd)
+        })?;
+
+        let new_worker_id = new_worker.id;
+        let command_tx = self.command_tx.clone();
+        smol::spawn(async move {
+            worker_loop(new_worker_id, stream, command_tx, worker_rx).await;
+        })
+        .detach();
+
+        let mut orders = state.generate_activate_orders();
+        for (count, order) in orders.drain(..).enumerate() {
+            new_worker
+                .send(
+                    format!("RESTART-{}-ACTIVATE-{}", new_worker_id, count),
+                    order,
+                )
+                .await;
+        }
+
+        if let Some(order) = self.ticket_keys_order() {
+            new_worker
+                .send(format!("RESTART-{}-TICKET-KEYS", new_worker_id), order)
+                .await;
+        }
+
+        if let Some(order) = self.access_log_filters_order() {
+            new_worker
+                .send(
+                    format!("RESTART-{}-ACCESS-LOG-FILTERS", new_worker_id),
+                    order,
+                )
+                .await;
+        }
+
+        new_worker
+            .send(
+                format!("RESTART-{}-STATUS", new_worker_id),
+                ProxyRequestOrder::Status,
+            )
+            .await;
+
+        let pid = new_worker.pid;
+        self.workers.push(new_worker);
+        self.publish_event(
+            format!("WORKER-{}", new_worker_id),
+            Event::WorkerLaunched(new_worker_id, pid),
+        )
+        .await;
+
+        Ok(())
+    }
+
+    /// replaces the crashed worker after the delay of the crash supervisor, or
+    /// publishes an event if it crashed too often to be restarted
+    async fn schedule_restart(&mut self, id: u32) {
+        let delay = match self.crash_supervisor.record_crash(Instant::now()) {
+            Some(delay) => delay,
+            None => {
+                let crashes = self.crash_supervisor.crashes();
+                error!(
+                    "Worker {} is not restarted, {} workers crashed in the last {} seconds",
+                    id, crashes, self.config.worker_restart_window
+                );
+                incr!("worker_restart_limit");
+                self.publish_event(
+                    format!("WORKER-{}", id),
+                    Event::WorkerRestartLimit(id, crashes),
+                )
+                .await;
+                return;
+            }
+        };
+
+        info!(
+            "Automatically restarting worker {} in {}ms",
+            id,
+            delay.as_millis()
+        );
+        let mut command_tx = self.command_tx.clone();
+        smol::spawn(async move {
+            Timer::after(delay).await;
+            if let Err(e) = command_tx
+                .send(CommandMessage::RestartWorker { worker_id: id })
+                .await
+            {
+                error!("could not send the restart of worker {}: {:?}", id, e);
+            }
+        })
+        .detach();
+    }
+
+    async fn handle_worker_close(&mut self, id: u32) -> anyhow::Result<Success> {
+        info!("removing worker {}", id);
+        self.heartbeats.remove(id);
+
+        // the workers asked to stop are not running anymore
+        let crashed_pid = self
+            .workers
+            .iter()
+            .find(|worker| {
+                worker.id == id
+                    && (worker.run_state == RunState::Running
+                        || worker.run_state == RunState::NotAnswering)
+            })
+            .map(|worker| worker.pid);
+        let crash_report = self.crash_reports.remove(&id);
+        if let Some(pid) = crashed_pid {
+            self.publish_event(
+                format!("WORKER-{}", id),
+                Event::WorkerCrashed(id, pid, crash_report),
+            )
+            .await;
+        }
+
+        // the new worker of an upgrade is not restarted, dropping the sender
+        // of its checks rolls the upgrade back right away
+        let upgrading = match self.upgrade_checks.remove(&id) {
+            Some(check_id) => self.in_flight.remove(&check_id).is_some(),
+            None => false,
+        };
+
+        // In case a worker crashes and should be restarted
+        if self.config.worker_automatic_restart && crashed_pid.is_some() && !upgrading {
+            self.schedule_restart(id).await;
+        }
+
+        if let Some(worker) = self.workers.iter_mut().find(|w| w.id == id) {
+            info!("Closing the worker {}.", worker.id);
+            if !worker.the_pid_is_alive() {
+                info!("Worker {} is dead, setting to Stopped.", worker.id);
+                worker.run_state = RunState::Stopped;
+                return Ok(Success::WorkerStopped(id));
+            }
+
+            info!(
+                "Worker {} is not dead but should be. Let's kill it.",
+                worker.id
+            );
+
+            match kill(Pid::from_raw(worker.pid), Signal::SIGKILL) {
+                Ok(()) => {
+                    info!("Worker {} was successfuly killed", id);
+                    worker.run_state = RunState::Stopped;
+                    return Ok(Success::WorkerKilled(id));
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| "failed to kill the worker process");
+                }
+            }
+        }
+        bail!(format!("Could not find worker {}", id))
+    }
+
+    async fn handle_worker_response(
+        &mut self,
+        worker_id: u32,
+        response: ProxyResponse,
+    ) -> anyhow::Result<Success> {
+        if let Some(ProxyResponseContent::Affinity(affinity)) = response.content {
+            return Ok(self.share_affinity(affinity).await);
+        }
+
+        if let Some(ProxyResponseContent::Crash(report)) = response.content {
+            return Ok(self.crash_reported(worker_id, report));
+        }
+
+        if let Some(ProxyResponseContent::ResolveName(resolution)) = response.content {
+            return Ok(self.resolve_name(worker_id, resolution));
+        }
+
+        if let Some(ProxyResponseContent::AccessLog(record)) = response.content {
+            self.notify_access_log_subscribers(response.id, worker_id, record)
+                .await?;
+            return Ok(Success::PropagatedWorkerEvent);
+        }
+
+        if Heartbeats::is_heartbeat(&response.id) {
+            return Ok(self.heartbeat_answered(worker_id, &response.id));
+        }
+
+        // Notify the client with Processing in case of a proxy event
+        if let Some(ProxyResponseContent::Event(proxy_event)) = response.content {
+            self.notify_event_subscribers(
+                response.id.to_string(),
+                format!("{}", worker_id),
+                proxy_event.into(),
+            )
+            .await?;
+            return Ok(Success::PropagatedWorkerEvent);
+        }
+
+        // Notify the function that sent the request to which the worker responded.
+        // The in_flight map contains the id of each sent request, together with a sender
+        // we use to send the response to.
+        match self.in_flight.remove(&response.id) {
             None => {
                 // FIXME: this messsage happens a lot at startup because AddCluster
                 // messages receive responses from each of the HTTP, HTTPS and TCP
@@ -852,6 +2307,7 @@ pub fn start_server(
     config: Config,
     command_socket_path: String,
     workers: Vec<Worker>,
+    state: ConfigState,
 ) -> anyhow::Result<()> {
     let path = PathBuf::from(&command_socket_path);
 
@@ -876,7 +2332,13 @@ pub fn start_server(
         }
     };
 
-    if let Err(e) = fs::set_permissions(&path, fs::Permissions::from_mode(0o600)) {
+    // with roles, the other users can connect, to send the orders of their roles
+    let mode = if config.command_roles.is_empty() {
+        0o600
+    } else {
+        0o666
+    };
+    if let Err(e) = fs::set_permissions(&path, fs::Permissions::from_mode(mode)) {
         error!("could not set the unix socket permissions: {:?}", e);
         let _ = fs::remove_file(&path).map_err(|e2| {
             error!("could not remove the unix socket: {:?}", e2);
@@ -922,22 +2384,25 @@ pub fn start_server(
                         }
                     };
 
+                let peer = client_peer_credentials(&stream);
                 let (client_tx, client_rx) = channel(10000);
                 let client_id = format!("CL-{}", counter);
-                smol::spawn(client_loop(
-                    client_id.clone(),
-                    stream,
-                    cloned_command_tx.clone(),
-                    client_rx,
-                ))
-                .detach();
+                // the client is known before its first request is authorized
                 cloned_command_tx
                     .send(CommandMessage::ClientNew {
-                        client_id,
+                        client_id: client_id.clone(),
                         sender: client_tx,
+                        peer,
                     })
                     .await
                     .expect("Failed at sending ClientNew message");
+                smol::spawn(client_loop(
+                    client_id,
+                    stream,
+                    cloned_command_tx.clone(),
+                    client_rx,
+                ))
+                .detach();
                 counter += 1;
             }
         })
@@ -945,12 +2410,75 @@ pub fn start_server(
 
         let saved_state_path = config.saved_state.clone();
 
+        if let Some(interval) = config.ocsp_refresh_interval {
+            let mut command_tx = command_tx.clone();
+            smol::spawn(async move {
+                loop {
+                    // fetch the responses right after the configuration is loaded
+                    if command_tx
+                        .send(CommandMessage::RefreshOcspResponses)
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                    Timer::after(Duration::from_secs(interval as u64)).await;
+                }
+            })
+            .detach();
+        }
+
+        spawn_ticket_keys_rotation(config.ticket_keys_rotation_interval, command_tx.clone());
+        spawn_consul_watchers(&config, command_tx.clone());
+        spawn_docker_watcher(&config, command_tx.clone());
+        spawn_reload_on_sighup(command_tx.clone())?;
+        spawn_stop_on_signals(command_tx.clone())?;
+        spawn_systemd_watchdog(command_tx.clone());
+        spawn_worker_heartbeats(config.worker_heartbeat_interval, command_tx.clone());
+        spawn_state_file_saves(&config, command_tx.clone());
+        remote::spawn_server(&config)?;
+
+        if config.worker_max_memory.is_some() {
+            let mut command_tx = command_tx.clone();
+            smol::spawn(async move {
+                loop {
+                    Timer::after(WORKER_MEMORY_CHECK_INTERVAL).await;
+                    if command_tx
+                        .send(CommandMessage::CheckWorkerMemory)
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            })
+            .detach();
+        }
+
+        {
+            let mut command_tx = command_tx.clone();
+            smol::spawn(async move {
+                loop {
+                    if command_tx
+                        .send(CommandMessage::CheckCertificateExpirations)
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                    Timer::after(CERTIFICATE_EXPIRATION_CHECK_INTERVAL).await;
+                }
+            })
+            .detach();
+        }
+
         let mut server = CommandServer::new(
             listener_fd,
             config,
             command_tx,
             command_rx,
             workers,
+            state,
             accept_cancel_tx,
         )?;
         server.load_static_cluster_configuration().await;
@@ -961,10 +2489,13 @@ pub fn start_server(
                 .await
                 .with_context(|| format!("Loading {:?} failed", &path))?;
         }
+        server.record_state_version("INITIALIZATION", None).await;
         gauge!("configuration.clusters", server.state.clusters.len());
         gauge!("configuration.backends", server.backends_count);
         gauge!("configuration.frontends", server.frontends_count);
 
+        // the workers are up and configured
+        systemd::notify("READY=1");
         info!("waiting for configuration client connections");
         server.run().await;
         info!("main process stopped");
@@ -975,6 +2506,34 @@ pub fn start_server(
 // The client loop does two things:
 // - write everything destined to the client onto the unix stream
 // - parse CommandRequests from the unix stream and send them to the command server
+/// the number of orders of each type, named as in the command protocol
+fn count_order_types(orders: &[ProxyRequestOrder]) -> BTreeMap<String, usize> {
+    let mut types = BTreeMap::new();
+    for order in orders {
+        let name = serde_json::to_value(order)
+            .ok()
+            .and_then(|value| value.get("type")?.as_str().map(ToOwned::to_owned))
+            .unwrap_or_else(|| String::from("UNKNOWN"));
+        *types.entry(name).or_insert(0) += 1;
+    }
+    types
+}
+
+fn join_ids(ids: &[u32]) -> String {
+    ids.iter()
+        .map(ToString::to_string)
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// the credentials of the client process, none if the system does not give them,
+/// which denies every order when roles are configured
+fn client_peer_credentials(stream: &Async<UnixStream>) -> Option<PeerCredentials> {
+    access::peer_credentials(stream.get_ref().as_raw_fd())
+        .map_err(|e| error!("could not get the credentials of the client: {}", e))
+        .ok()
+}
+
 async fn client_loop(
     client_id: String,
     stream: Async<UnixStream>,
@@ -1046,6 +2605,234 @@ async fn client_loop(
     }
 }
 
+/// generates the first session ticket keys right away, then rotates them at each interval
+fn spawn_ticket_keys_rotation(interval: u32, mut command_tx: Sender<CommandMessage>) {
+    smol::spawn(async move {
+        loop {
+            if command_tx
+                .send(CommandMessage::RotateTicketKeys)
+                .await
+                .is_err()
+            {
+                break;
+            }
+            Timer::after(Duration::from_secs(interval as u64)).await;
+        }
+    })
+    .detach();
+}
+
+/// starts a watcher for each service of the Consul configuration, sending its
+/// healthy instances after each answer, even unchanged ones, so that the backends
+/// changed by other orders come back to the catalog
+fn spawn_consul_watchers(config: &Config, command_tx: Sender<CommandMessage>) {
+    let consul = match config
+        .discovery
+        .as_ref()
+        .and_then(|discovery| discovery.consul.as_ref())
+    {
+        Some(consul) => consul,
+        None => return,
+    };
+
+    for (cluster_id, service) in consul.services.iter() {
+        let consul = consul.clone();
+        let cluster_id = cluster_id.clone();
+        let service = service.clone();
+        let name = service.name.clone().unwrap_or_else(|| cluster_id.clone());
+        let mut command_tx = command_tx.clone();
+
+        info!(
+            "watching the Consul service {} for the backends of cluster {}",
+            name, cluster_id
+        );
+        smol::spawn(async move {
+            let mut index = 0u64;
+            loop {
+                let (consul, name, service) = (consul.clone(), name.clone(), service.clone());
+                let answer =
+                    smol::unblock(move || consul::watch_service(&consul, &name, &service, index))
+                        .await;
+
+                match answer {
+                    Ok((new_index, backends)) => {
+                        // the index went backwards, Consul asks to start over
+                        index = if new_index < index { 0 } else { new_index };
+                        if command_tx
+                            .send(CommandMessage::DiscoveredBackends {
+                                cluster_id: cluster_id.clone(),
+                                backends,
+                            })
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        incr!("consul.error");
+                        error!(
+                            "could not watch the backends of cluster {}: {:#}",
+                            cluster_id, e
+                        );
+                        Timer::after(CONSUL_RETRY_DELAY).await;
+                    }
+                }
+            }
+        })
+        .detach();
+    }
+}
+
+/// saves the state to `state_file` from the main loop
+fn spawn_state_file_saves(config: &Config, mut command_tx: Sender<CommandMessage>) {
+    let interval = config.save_state_interval;
+    if config.state_file.is_none() || interval == 0 {
+        return;
+    }
+
+    smol::spawn(async move {
+        loop {
+            Timer::after(Duration::from_secs(interval)).await;
+            if command_tx
+                .send(CommandMessage::SaveStateFile)
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    })
+    .detach();
+}
+
+/// sends the heartbeats to the workers from the main loop
+fn spawn_worker_heartbeats(interval: u64, mut command_tx: Sender<CommandMessage>) {
+    if interval == 0 {
+        return;
+    }
+
+    smol::spawn(async move {
+        loop {
+            Timer::after(Duration::from_secs(interval)).await;
+            if command_tx
+                .send(CommandMessage::CheckHeartbeats)
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    })
+    .detach();
+}
+
+/// pings the watchdog from the main loop, so that systemd restarts sozu if
+/// the loop is stuck
+fn spawn_systemd_watchdog(mut command_tx: Sender<CommandMessage>) {
+    let interval = match systemd::watchdog_interval() {
+        Some(interval) => interval,
+        None => return,
+    };
+
+    info!("pinging the systemd watchdog every {:?}", interval);
+    smol::spawn(async move {
+        loop {
+            if command_tx.send(CommandMessage::WatchdogPing).await.is_err() {
+                break;
+            }
+            Timer::after(interval).await;
+        }
+    })
+    .detach();
+}
+
+/// turns the SIGHUP signals into reloads of the configuration, in a thread
+fn spawn_reload_on_sighup(mut command_tx: Sender<CommandMessage>) -> anyhow::Result<()> {
+    let mut signals =
+        Signals::new([SIGHUP]).with_context(|| "could not handle the SIGHUP signal")?;
+
+    std::thread::Builder::new()
+        .name(String::from("sighup"))
+        .spawn(move || {
+            for _ in signals.forever() {
+                if future::block_on(command_tx.send(CommandMessage::ReloadConfiguration)).is_err() {
+                    break;
+                }
+            }
+        })
+        .with_context(|| "could not start the SIGHUP handler")?;
+    Ok(())
+}
+
+/// stops the workers and the main process on SIGTERM, softly, and on SIGQUIT,
+/// right away, for the init scripts
+fn spawn_stop_on_signals(mut command_tx: Sender<CommandMessage>) -> anyhow::Result<()> {
+    let mut signals = Signals::new([SIGTERM, SIGQUIT])
+        .with_context(|| "could not handle the SIGTERM and SIGQUIT signals")?;
+
+    std::thread::Builder::new()
+        .name(String::from("stop-signals"))
+        .spawn(move || {
+            for signal in signals.forever() {
+                let hard = signal == SIGQUIT;
+                if future::block_on(command_tx.send(CommandMessage::StopOnSignal { hard })).is_err()
+                {
+                    break;
+                }
+            }
+        })
+        .with_context(|| "could not start the stop signals handler")?;
+    Ok(())
+}
+
+/// follows the container events of the Docker daemon, and sends the routed
+/// containers after each change
+fn spawn_docker_watcher(config: &Config, mut command_tx: Sender<CommandMessage>) {
+    let docker = match config
+        .discovery
+        .as_ref()
+        .and_then(|discovery| discovery.docker.clone())
+    {
+        Some(docker) => docker,
+        None => return,
+    };
+
+    info!("watching the containers of {}", docker.socket);
+    smol::spawn(async move {
+        loop {
+            if let Err(e) = watch_docker(&docker, &mut command_tx).await {
+                incr!("docker.error");
+                error!(
+                    "could not watch the containers of {}: {:#}",
+                    docker.socket, e
+                );
+            }
+            Timer::after(DOCKER_RETRY_DELAY).await;
+        }
+    })
+    .detach();
+}
+
+async fn watch_docker(
+    docker: &DockerConfig,
+    command_tx: &mut Sender<CommandMessage>,
+) -> anyhow::Result<()> {
+    // the events are followed before listing the containers, to miss none
+    let socket = docker.socket.clone();
+    let mut events = smol::unblock(move || docker::Events::open(&socket)).await?;
+
+    loop {
+        let config = docker.clone();
+        let containers = smol::unblock(move || docker::list_containers(&config)).await?;
+        command_tx
+            .send(CommandMessage::DockerContainers(containers))
+            .await?;
+
+        events = smol::unblock(move || events.wait().map(|()| events)).await?;
+    }
+}
+
 // the worker loop does two things:
 // - write everything destined to the worker onto the unix stream
 // - parse ProxyResponses from the unix stream and send them to the CommandServer
@@ -0,0 +1,199 @@
+//! fetches the OCSP responses of the certificates, the workers staple them
+//! during the TLS handshakes
+use std::{io::Read, time::Duration};
+
+use anyhow::{bail, Context};
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use x509_parser::{
+    certificate::X509Certificate,
+    der_parser::parse_der,
+    extensions::{GeneralName, ParsedExtension},
+    oid_registry::OID_PKIX_ACCESS_DESCRIPTOR_OCSP,
+    pem::{parse_x509_pem, Pem},
+};
+
+use sozu_command_lib::proxy::CertificateAndKey;
+
+/// DER encoded AlgorithmIdentifier of SHA-1, the hash algorithm supported by all responders
+const SHA1_ALGORITHM_IDENTIFIER: &[u8] = &[
+    0x30, 0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a, 0x05, 0x00,
+];
+
+const OCSP_RESPONDER_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_OCSP_RESPONSE_SIZE: u64 = 64 * 1024;
+
+/// asks the OCSP responder of the certificate for its status, and returns
+/// the hex encoded response. The issuer is the first certificate of the chain
+pub fn fetch_ocsp_response(certificate_and_key: &CertificateAndKey) -> anyhow::Result<String> {
+    let certificate_pem = parse_pem(&certificate_and_key.certificate)?;
+    let certificate = parse_certificate(&certificate_pem)?;
+
+    let issuer_pem = match certificate_and_key.certificate_chain.first() {
+        Some(issuer) => parse_pem(issuer)?,
+        None => bail!("the certificate has no chain, its issuer is unknown"),
+    };
+    let issuer = parse_certificate(&issuer_pem)?;
+
+    let responder = ocsp_responder(&certificate)
+        .with_context(|| "the certificate does not provide an OCSP responder")?;
+    let request = ocsp_request(&certificate, &issuer);
+
+    let response = ureq::post(&responder)
+        .timeout(OCSP_RESPONDER_TIMEOUT)
+        .set("Content-Type", "application/ocsp-request")
+        .send_bytes(&request)
+        .with_context(|| format!("could not query the OCSP responder {}", responder))?;
+
+    let mut ocsp_response = Vec::new();
+    response
+        .into_reader()
+        .take(MAX_OCSP_RESPONSE_SIZE)
+        .read_to_end(&mut ocsp_response)
+        .with_context(|| {
+            format!(
+                "could not read the answer of the OCSP responder {}",
+                responder
+            )
+        })?;
+
+    check_ocsp_response(&ocsp_response)?;
+
+    Ok(hex::encode(ocsp_response))
+}
+
+fn parse_pem(pem: &str) -> anyhow::Result<Pem> {
+    let (_, pem) =
+        parse_x509_pem(pem.as_bytes()).with_context(|| "could not parse the certificate")?;
+    Ok(pem)
+}
+
+fn parse_certificate(pem: &Pem) -> anyhow::Result<X509Certificate<'_>> {
+    pem.parse_x509()
+        .with_context(|| "could not parse the certificate")
+}
+
+/// looks for the OCSP responder in the authority information access extension
+fn ocsp_responder(certificate: &X509Certificate) -> Option<String> {
+    certificate
+        .extensions()
+        .iter()
+        .find_map(|extension| match extension.parsed_extension() {
+            ParsedExtension::AuthorityInfoAccess(access) => {
+                access.accessdescs.iter().find_map(|description| {
+                    match description.access_location {
+                        GeneralName::URI(uri)
+                            if description.access_method == OID_PKIX_ACCESS_DESCRIPTOR_OCSP =>
+                        {
+                            Some(uri.to_string())
+                        }
+                        _ => None,
+                    }
+                })
+            }
+            _ => None,
+        })
+}
+
+/// DER encoded OCSPRequest (RFC 6960) with a single request for the certificate
+fn ocsp_request(certificate: &X509Certificate, issuer: &X509Certificate) -> Vec<u8> {
+    let issuer_name_hash = digest(&SHA1_FOR_LEGACY_USE_ONLY, issuer.subject().as_raw());
+    let issuer_key_hash = digest(
+        &SHA1_FOR_LEGACY_USE_ONLY,
+        &issuer.public_key().subject_public_key.data,
+    );
+
+    let cert_id = der_encode(
+        0x30,
+        &[
+            SHA1_ALGORITHM_IDENTIFIER,
+            &der_encode(0x04, issuer_name_hash.as_ref()),
+            &der_encode(0x04, issuer_key_hash.as_ref()),
+            &der_encode(0x02, certificate.raw_serial()),
+        ]
+        .concat(),
+    );
+
+    // OCSPRequest { TBSRequest { requestList { Request { CertID } } } }
+    let request = der_encode(0x30, &cert_id);
+    let request_list = der_encode(0x30, &request);
+    let tbs_request = der_encode(0x30, &request_list);
+    der_encode(0x30, &tbs_request)
+}
+
+fn der_encode(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+
+    if content.len() < 0x80 {
+        encoded.push(content.len() as u8);
+    } else {
+        let length = content.len().to_be_bytes();
+        let length: Vec<u8> = length
+            .iter()
+            .copied()
+            .skip_while(|byte| *byte == 0)
+            .collect();
+        encoded.push(0x80 | length.len() as u8);
+        encoded.extend_from_slice(&length);
+    }
+
+    encoded.extend_from_slice(content);
+    encoded
+}
+
+/// verifies that the responder answered with a successful OCSPResponse
+fn check_ocsp_response(ocsp_response: &[u8]) -> anyhow::Result<()> {
+    let (_, ocsp_response) =
+        parse_der(ocsp_response).with_context(|| "could not parse the OCSP response")?;
+    let fields = ocsp_response
+        .as_sequence()
+        .with_context(|| "the OCSP response is not a sequence")?;
+
+    // the response bytes are only present if the status is successful (0)
+    match fields.first().map(|status| status.as_u64()) {
+        Some(Ok(0)) if fields.len() == 2 => Ok(()),
+        Some(Ok(status)) => bail!("the OCSP responder answered with the status {}", status),
+        _ => bail!("the OCSP response has no status"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ocsp_request_encoding() {
+        let certificate_pem = parse_pem(include_str!(
+            "../../../lib/assets/tests/client-certificate.pem"
+        ))
+        .unwrap();
+        let certificate = parse_certificate(&certificate_pem).unwrap();
+        let issuer_pem =
+            parse_pem(include_str!("../../../lib/assets/tests/client-ca.pem")).unwrap();
+        let issuer = parse_certificate(&issuer_pem).unwrap();
+
+        let request = ocsp_request(&certificate, &issuer);
+        let (rest, request) = parse_der(&request).unwrap();
+        assert!(rest.is_empty());
+
+        let tbs_request = &request.as_sequence().unwrap()[0];
+        let request_list = &tbs_request.as_sequence().unwrap()[0];
+        let request = &request_list.as_sequence().unwrap()[0];
+        let cert_id = request.as_sequence().unwrap()[0].as_sequence().unwrap();
+
+        assert_eq!(cert_id.len(), 4);
+        assert_eq!(
+            cert_id[1].as_slice().unwrap(),
+            digest(&SHA1_FOR_LEGACY_USE_ONLY, issuer.subject().as_raw()).as_ref()
+        );
+        assert_eq!(cert_id[3].as_u64().unwrap(), 4242);
+    }
+
+    #[test]
+    fn ocsp_response_status() {
+        // OCSPResponse with the unauthorized status (6)
+        assert!(check_ocsp_response(&[0x30, 0x03, 0x0a, 0x01, 0x06]).is_err());
+        assert!(check_ocsp_response(&[0x30, 0x03, 0x0a, 0x01]).is_err());
+        // successful status, with empty response bytes
+        assert!(check_ocsp_response(&[0x30, 0x05, 0x0a, 0x01, 0x00, 0xa0, 0x00]).is_ok());
+    }
+}
@@ -1,41 +1,58 @@
 use std::{
     collections::{BTreeMap, HashSet},
-    fs::File,
+    fs::{self, File, OpenOptions},
     io::{Read, Write},
+    os::unix::fs::OpenOptionsExt,
     os::unix::io::{FromRawFd, IntoRawFd},
     os::unix::net::UnixStream,
-    time::{Duration, Instant},
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{bail, Context};
-use async_io::Async;
+use async_io::{Async, Timer};
 use futures::{channel::mpsc::*, SinkExt, StreamExt};
+use futures_lite::future;
+use nix::{
+    sys::{
+        signal::{kill, Signal},
+        wait::waitpid,
+    },
+    unistd::Pid,
+};
 use nom::{Err, HexDisplay, Offset};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
 
 use sozu_command_lib::{
     buffer::fixed::Buffer,
     command::{
-        CommandRequest, CommandRequestOrder, CommandResponse, CommandResponseContent,
-        CommandStatus, FrontendFilters, ListedFrontends, RunState, WorkerInfo, PROTOCOL_VERSION,
+        AuthorizationRequest, CertificateFilters, CommandRequest, CommandRequestOrder,
+        CommandResponse, CommandResponseContent, CommandStatus, DryRun, Event, FrontendFilters,
+        ListedFrontends, OrderCategory, PeerChange, PeerState, Progress, RunState, WorkerInfo,
+        PROTOCOL_VERSION,
     },
-    config::Config,
+    config::{CommandAuthorizationConfig, Config},
     logging,
     parser::parse_several_commands,
     proxy::{
-        AggregatedMetricsData, MetricsConfiguration, ProxyRequest, ProxyRequestOrder,
-        ProxyResponseContent, ProxyResponseStatus, Query, QueryAnswer, QueryClusterType, Route,
-        TcpFrontend,
+        AccessLogRateLimit, AggregatedMetricsData, ListenerType, MetricsConfiguration,
+        ProxyRequest, ProxyRequestOrder, ProxyResponse, ProxyResponseContent, ProxyResponseStatus,
+        Query, QueryAnswer, QueryClusterType, Route, SniFrontend, TcpFrontend,
     },
     scm_socket::Listeners,
-    state::get_cluster_ids_by_domain,
+    state::{get_cluster_ids_by_domain, ConfigState},
 };
 
-use sozu::metrics::METRICS;
+use sozu::{metrics::METRICS, tls::validate_certificate};
 
 use crate::{
-    command::{CommandMessage, CommandServer, RequestIdentifier, Response, Success, Worker},
+    command::{
+        access, authorization, join_ids, peering::PeerAnswer, CommandMessage, CommandServer,
+        RequestIdentifier, Response, Success, Worker, MAIN_PROCESS_CLIENT,
+    },
     upgrade::fork_main_into_new_main,
-    worker::start_worker,
+    util,
+    worker::{bind_listeners, start_worker, worker_cpu_cores},
 };
 
 impl CommandServer {
@@ -51,11 +68,111 @@ impl CommandServer {
         };
         let cloned_identifier = request_identifier.clone();
 
+        let category = request.order.category();
+        // the refused orders are audited as well
+        if category != OrderCategory::Read {
+            self.start_audit_entry(&client_id, &request);
+        }
+
+        let allowed = self
+            .client_categories
+            .get(&client_id)
+            .map(|categories| categories.contains(&category))
+            .unwrap_or(false);
+        if !allowed {
+            let message = format!(
+                "the client {} is not allowed to send {} orders",
+                client_id, category
+            );
+            error!("{}", message);
+            return_error(self.command_tx.clone(), request_identifier, message).await;
+            return Ok(Success::HandledClientRequest);
+        }
+
+        // the new main process of an upgrade runs with the state passed to it
+        if category != OrderCategory::Read {
+            if let Some(new_main_pid) = self.main_upgrade {
+                let message = format!(
+                    "the main process is being upgraded to {}, the order {} is refused",
+                    new_main_pid, request.id
+                );
+                error!("{}", message);
+                return_error(self.command_tx.clone(), request_identifier, message).await;
+                return Ok(Success::HandledClientRequest);
+            }
+        }
+
+        if category != OrderCategory::Read {
+            if let Some(config) = self.config.command_authorization.clone() {
+                if let Err(e) = self.authorize(config, &client_id, category, &request).await {
+                    let message = format!(
+                        "the client {} is not authorized to send the order {}: {:#}",
+                        client_id, request.id, e
+                    );
+                    error!("{}", message);
+                    return_error(self.command_tx.clone(), request_identifier, message).await;
+                    return Ok(Success::HandledClientRequest);
+                }
+            }
+        }
+
+        // only the orders changing the state can be validated against it
+        let dry_run_supported = match &request.order {
+            CommandRequestOrder::ApplyState { .. } | CommandRequestOrder::RollbackState { .. } => {
+                true
+            }
+            CommandRequestOrder::Proxy(order) => {
+                category == OrderCategory::Mutation
+                    && !matches!(
+                        **order,
+                        ProxyRequestOrder::ConfigureMetrics(_) | ProxyRequestOrder::Logging(_)
+                    )
+            }
+            _ => false,
+        };
+        if request.dry_run && !dry_run_supported {
+            let message = format!("the order {:?} cannot be dry run", request.order);
+            error!("{}", message);
+            return_error(self.command_tx.clone(), request_identifier, message).await;
+            return Ok(Success::HandledClientRequest);
+        }
+
+        // the main process sets them from the clients tailing the access logs
+        if let CommandRequestOrder::Proxy(order) = &request.order {
+            if let ProxyRequestOrder::SetAccessLogFilters(_) = **order {
+                let message = String::from(
+                    "the access log filters cannot be set, tail the access logs instead",
+                );
+                error!("{}", message);
+                return_error(self.command_tx.clone(), request_identifier, message).await;
+                return Ok(Success::HandledClientRequest);
+            }
+            // the main process answers the workers with the names they asked for
+            if let ProxyRequestOrder::NameResolved(_) = **order {
+                let message = String::from("the resolved names are only sent by the main process");
+                error!("{}", message);
+                return_error(self.command_tx.clone(), request_identifier, message).await;
+                return Ok(Success::HandledClientRequest);
+            }
+        }
+
+        let from_peer = matches!(
+            request.order,
+            CommandRequestOrder::PeerChange(_) | CommandRequestOrder::PeerState(_)
+        );
         let result: anyhow::Result<Option<Success>> = match request.order {
             CommandRequestOrder::SaveState { path } => self.save_state(&path).await,
-            CommandRequestOrder::DumpState => self.dump_state().await,
+            CommandRequestOrder::DumpState => {
+                let with_private_keys = self
+                    .client_categories
+                    .get(&client_id)
+                    .map(|categories| categories.contains(&OrderCategory::Admin))
+                    .unwrap_or(false);
+                self.dump_state(with_private_keys).await
+            }
             CommandRequestOrder::ListWorkers => self.list_workers().await,
             CommandRequestOrder::ListFrontends(filters) => self.list_frontends(filters).await,
+            CommandRequestOrder::ListCertificates(filters) => self.list_certificates(filters).await,
             CommandRequestOrder::LoadState { path } => {
                 self.load_state(
                     Some(request_identifier.client),
@@ -69,7 +186,12 @@ impl CommandServer {
             }
             CommandRequestOrder::UpgradeMain => self.upgrade_main(request_identifier).await,
             CommandRequestOrder::UpgradeWorker(worker_id) => {
-                self.upgrade_worker(request_identifier, worker_id).await
+                self.upgrade_worker(request_identifier, worker_id, false)
+                    .await
+            }
+            CommandRequestOrder::RestartWorker(worker_id) => {
+                self.upgrade_worker(request_identifier, worker_id, true)
+                    .await
             }
             CommandRequestOrder::Proxy(proxy_request_order) => match *proxy_request_order {
                 ProxyRequestOrder::ConfigureMetrics(config) => {
@@ -84,20 +206,68 @@ impl CommandServer {
                 // ProxyRequestOrder::HardStop => self.do_nothing_and_return_early(),
                 // but it goes in there instead:
                 order => {
-                    self.worker_order(request_identifier, order, request.worker_id)
-                        .await
+                    self.worker_order(
+                        request_identifier,
+                        order,
+                        request.worker_id,
+                        request.worker_ids,
+                        request.strict,
+                        request.dry_run,
+                    )
+                    .await
                 }
             },
             CommandRequestOrder::SubscribeEvents => {
                 self.event_subscribers.insert(client_id.clone());
                 Ok(Some(Success::SubscribeEvent(client_id.clone())))
             }
+            CommandRequestOrder::TailAccessLogs(filter) => {
+                self.access_log_subscribers
+                    .insert(client_id.clone(), (filter, AccessLogRateLimit::default()));
+                self.send_access_log_filters(&client_id).await;
+                Ok(Some(Success::TailAccessLogs(client_id.clone())))
+            }
             CommandRequestOrder::ReloadConfiguration { path } => {
-                self.reload_configuration(request_identifier, path).await
+                self.reload_configuration(
+                    Some(request_identifier.client),
+                    request_identifier.request,
+                    path,
+                )
+                .await
             }
             CommandRequestOrder::Status => self.status(request_identifier).await,
+            CommandRequestOrder::ApplyState { state } => {
+                self.apply_state(request_identifier, *state, request.strict, request.dry_run)
+                    .await
+            }
+            CommandRequestOrder::StateHistory => self.state_history(),
+            CommandRequestOrder::RollbackState { version } => {
+                self.rollback_state(request_identifier, version, request.dry_run)
+                    .await
+            }
+            CommandRequestOrder::PeerChange(change) => {
+                self.apply_peer_change(request_identifier, change).await
+            }
+            CommandRequestOrder::PeerState(state) => {
+                self.apply_peer_state(request_identifier, state).await
+            }
+            CommandRequestOrder::SyncPeers => self.sync_peers(),
+            CommandRequestOrder::AuditLog { lines } => self.audit_log_tail(lines),
         };
 
+        // the orders sent to the workers already changed the state
+        if category == OrderCategory::Mutation {
+            let orders = self
+                .record_state_version(&cloned_identifier.request, Some(&client_id))
+                .await;
+            // the changes of the peers are not sent back to them
+            if let (Some(peering), false) = (self.peering.as_mut(), from_peer) {
+                if !orders.is_empty() {
+                    peering.share_change(orders, cloned_identifier.clone());
+                }
+            }
+        }
+
         // Notify the command server by sending using his command_tx
         match result {
             Ok(Some(success)) => {
@@ -117,6 +287,40 @@ impl CommandServer {
         Ok(Success::HandledClientRequest)
     }
 
+    /// asks the external command or service to authorize the order, the
+    /// other orders wait for its decision
+    async fn authorize(
+        &self,
+        config: CommandAuthorizationConfig,
+        client_id: &str,
+        category: OrderCategory,
+        request: &CommandRequest,
+    ) -> anyhow::Result<()> {
+        let peer = self.client_peers.get(client_id);
+        let authorization_request = AuthorizationRequest {
+            client: client_id.to_owned(),
+            user: peer.map(access::user_name),
+            uid: peer.map(|peer| peer.uid.as_raw()),
+            gid: peer.map(|peer| peer.gid.as_raw()),
+            category,
+            request: request.clone(),
+        };
+
+        let decision =
+            smol::unblock(move || authorization::authorize(&config, &authorization_request))
+                .await?;
+        if !decision.allowed {
+            incr!("command.authorization.refused");
+            bail!(
+                "{}",
+                decision
+                    .reason
+                    .unwrap_or_else(|| String::from("refused by the authorization policy"))
+            );
+        }
+        Ok(())
+    }
+
     pub async fn save_state(&mut self, path: &str) -> anyhow::Result<Option<Success>> {
         let mut file = File::create(&path)
             .with_context(|| format!("could not open file at path: {}", &path))?;
@@ -130,17 +334,82 @@ impl CommandServer {
         Ok(Some(Success::SaveState(counter, path.into())))
     }
 
+    /// writes the state to `state_file` if it changed since the last save,
+    /// through a temporary file renamed over it, so that the file is never
+    /// left half written
+    pub fn save_state_file(&mut self) -> anyhow::Result<Success> {
+        let path = match self.config.state_file.clone() {
+            Some(path) => path,
+            None => return Ok(Success::SavedStateFile(0)),
+        };
+        let version = self.history.current_version();
+        if version == self.saved_state_version {
+            return Ok(Success::SavedStateFile(0));
+        }
+
+        // the state contains the private keys, the file is only readable by its
+        // owner. A unique name keeps concurrent saves from writing the same file
+        let suffix: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(8)
+            .map(char::from)
+            .collect();
+        let temporary_path = format!("{}.{}.tmp", path, suffix);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temporary_path)
+            .with_context(|| format!("could not create the file {}", temporary_path))?;
+
+        let written = self
+            .save_state_to_file(&mut file)
+            .and_then(|counter| {
+                file.sync_all()?;
+                Ok(counter)
+            })
+            .with_context(|| format!("failed writing state to {}", temporary_path))
+            .and_then(|counter| {
+                fs::rename(&temporary_path, &path).with_context(|| {
+                    format!("could not replace {} with {}", path, temporary_path)
+                })?;
+                Ok(counter)
+            });
+        let counter = match written {
+            Ok(counter) => counter,
+            Err(e) => {
+                let _ = fs::remove_file(&temporary_path);
+                return Err(e);
+            }
+        };
+
+        // the rename is only durable once the directory is written
+        let directory = match Path::new(&path).parent() {
+            Some(parent) if parent != Path::new("") => parent,
+            _ => Path::new("."),
+        };
+        File::open(directory)
+            .and_then(|directory| directory.sync_all())
+            .with_context(|| format!("could not sync the directory of {}", path))?;
+
+        self.saved_state_version = version;
+        debug!("wrote {} commands to {}", counter, path);
+        Ok(Success::SavedStateFile(counter))
+    }
+
     pub fn save_state_to_file(&mut self, file: &mut File) -> anyhow::Result<usize> {
         let mut counter = 0usize;
         let orders = self.state.generate_orders();
+        let worker_scopes = self.state.worker_scopes.clone();
 
         let result: anyhow::Result<usize> = (move || {
             for command in orders {
-                let message = CommandRequest::new(
+                let mut message = CommandRequest::new(
                     format!("SAVE-{}", counter),
-                    CommandRequestOrder::Proxy(Box::new(command)),
+                    CommandRequestOrder::Proxy(Box::new(command.clone())),
                     None,
                 );
+                message.worker_ids = worker_scopes.listener_workers(&command);
 
                 file.write_all(
                     &serde_json::to_string(&message)
@@ -173,8 +442,13 @@ impl CommandServer {
         result.with_context(|| "Could not write the state onto the state file")
     }
 
-    pub async fn dump_state(&mut self) -> anyhow::Result<Option<Success>> {
-        let state = self.state.clone();
+    /// the private keys are only sent to the clients allowed to send admin orders
+    pub async fn dump_state(&mut self, with_private_keys: bool) -> anyhow::Result<Option<Success>> {
+        let state = if with_private_keys {
+            self.state.clone()
+        } else {
+            self.state.without_private_keys()
+        };
 
         Ok(Some(Success::DumpState(CommandResponseContent::State(
             Box::new(state),
@@ -196,8 +470,9 @@ impl CommandServer {
 
         let mut message_counter = 0usize;
         let mut diff_counter = 0usize;
+        let mut progress = Progress::default();
 
-        let (load_state_tx, mut load_state_rx) = futures::channel::mpsc::channel(10000);
+        let (load_state_tx, load_state_rx) = futures::channel::mpsc::channel(10000);
         loop {
             let previous = buffer.available_data();
             //FIXME: we should read in streaming here
@@ -241,6 +516,7 @@ impl CommandServer {
 
                             if self.state.handle_order(&order) {
                                 diff_counter += 1;
+                                self.state.worker_scopes.scope(&order, &request.worker_ids);
 
                                 let mut found = false;
                                 let id = format!("LOAD-STATE-{}-{}", request_id, diff_counter);
@@ -249,10 +525,18 @@ impl CommandServer {
                                     worker.run_state != RunState::Stopping
                                         && worker.run_state != RunState::Stopped
                                 }) {
+                                    let order =
+                                        match self.state.worker_scopes.order_for(worker.id, &order)
+                                        {
+                                            Some(order) => order,
+                                            None => continue,
+                                        };
+                                    let order = worker.with_listener(order);
                                     let worker_message_id = format!("{}-{}", id, worker.id);
-                                    worker.send(worker_message_id.clone(), *order.clone()).await;
+                                    worker.send(worker_message_id.clone(), order).await;
                                     self.in_flight
                                         .insert(worker_message_id, (load_state_tx.clone(), 1));
+                                    progress.expect(worker.id);
 
                                     found = true;
                                 }
@@ -295,24 +579,18 @@ impl CommandServer {
             let path = path.to_owned();
 
             smol::spawn(async move {
-                let mut ok = 0usize;
-                let mut error = 0usize;
-                while let Some((proxy_response, _)) = load_state_rx.next().await {
-                    match proxy_response.status {
-                        ProxyResponseStatus::Ok => {
-                            ok += 1;
-                        }
-                        ProxyResponseStatus::Processing => {}
-                        ProxyResponseStatus::Error(message) => {
-                            error!("{}", message);
-                            error += 1;
-                        }
-                    };
-                    debug!("ok:{}, error: {}", ok, error);
-                }
+                let request_identifier =
+                    client_id.map(|client_id| RequestIdentifier::new(client_id, request_id));
+                let (ok, error) = collect_worker_answers(
+                    load_state_rx,
+                    progress,
+                    command_tx.clone(),
+                    request_identifier.clone(),
+                )
+                .await;
 
-                let request_identifier = match client_id {
-                    Some(client_id) => RequestIdentifier::new(client_id, request_id),
+                let request_identifier = match request_identifier {
+                    Some(request_identifier) => request_identifier,
                     None => {
                         match error {
                             0 => info!("loading state: {} ok messages, 0 errors", ok),
@@ -414,22 +692,72 @@ impl CommandServer {
             }
         }
 
+        if filters.tcp || list_all {
+            for sni_frontend in self.state.sni_fronts.values().flat_map(|v| v.iter()) {
+                if filters
+                    .domain
+                    .as_ref()
+                    .map(|domain| sni_frontend.hostname.contains(domain.as_str()))
+                    .unwrap_or(true)
+                {
+                    listed_frontends.sni_frontends.push(sni_frontend.to_owned())
+                }
+            }
+        }
+
         Ok(Some(Success::ListFrontends(
             CommandResponseContent::FrontendList(listed_frontends),
         )))
     }
 
-    pub async fn list_workers(&mut self) -> anyhow::Result<Option<Success>> {
-        let workers: Vec<WorkerInfo> = self
-            .workers
-            .iter()
-            .map(|worker| WorkerInfo {
-                id: worker.id,
-                pid: worker.pid,
-                run_state: worker.run_state,
+    pub async fn list_certificates(
+        &mut self,
+        filters: CertificateFilters,
+    ) -> anyhow::Result<Option<Success>> {
+        info!(
+            "Received a request to list certificates, along these filters: {:?}",
+            filters
+        );
+
+        let expiration_limit = match filters.expiring_within {
+            Some(seconds) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .with_context(|| "the system time is before the unix epoch")?;
+                Some((now.as_secs() + seconds) as i64)
+            }
+            None => None,
+        };
+
+        let certificates = self
+            .state
+            .list_certificates()
+            .into_iter()
+            .filter(|certificate| match &filters.domain {
+                Some(domain) => certificate
+                    .names
+                    .iter()
+                    .any(|name| name.contains(domain.as_str())),
+                None => true,
+            })
+            .filter(|certificate| match expiration_limit {
+                Some(limit) => certificate.expiration < limit,
+                None => true,
+            })
+            .filter(|certificate| match filters.address {
+                Some(address) => certificate.address == address,
+                None => true,
             })
             .collect();
 
+        Ok(Some(Success::ListCertificates(
+            CommandResponseContent::CertificateList(certificates),
+        )))
+    }
+
+    pub async fn list_workers(&mut self) -> anyhow::Result<Option<Success>> {
+        let workers: Vec<WorkerInfo> = self.workers.iter().map(|worker| worker.info()).collect();
+
         debug!("workers: {:#?}", workers);
 
         Ok(Some(Success::ListWorkers(CommandResponseContent::Workers(
@@ -442,12 +770,21 @@ impl CommandServer {
         request_identifier: RequestIdentifier,
         _tag: &str,
     ) -> anyhow::Result<Option<Success>> {
+        let state = self.state.scoped_to(self.next_worker_id);
+        let slot = self
+            .workers
+            .iter()
+            .filter(|worker| {
+                worker.run_state != RunState::Stopping && worker.run_state != RunState::Stopped
+            })
+            .count();
         let mut worker = start_worker(
             self.next_worker_id,
             &self.config,
             self.executable_path.clone(),
-            &self.state,
+            &state,
             None,
+            worker_cpu_cores(&self.config, slot),
         )
         .with_context(|| format!("Failed at creating worker {}", self.next_worker_id))?;
 
@@ -483,23 +820,34 @@ impl CommandServer {
         })
         .detach();
 
+        let listeners = bind_listeners(&self.config, &state);
         info!(
             "sending listeners: to the new worker: {:?}",
-            worker.scm_socket.send_listeners(&Listeners {
-                http: Vec::new(),
-                tls: Vec::new(),
-                tcp: Vec::new(),
-            })
+            worker.scm_socket.send_listeners(&listeners)
         );
+        listeners.close();
 
-        let activate_orders = self.state.generate_activate_orders();
+        let activate_orders = state.generate_activate_orders();
         for (count, order) in activate_orders.into_iter().enumerate() {
             worker
                 .send(format!("{}-ACTIVATE-{}", id, count), order)
                 .await;
         }
 
+        if let Some(order) = self.ticket_keys_order() {
+            worker.send(format!("{}-TICKET-KEYS", id), order).await;
+        }
+
+        if let Some(order) = self.access_log_filters_order() {
+            worker
+                .send(format!("{}-ACCESS-LOG-FILTERS", id), order)
+                .await;
+        }
+
+        let pid = worker.pid;
         self.workers.push(worker);
+        self.publish_event(format!("WORKER-{}", id), Event::WorkerLaunched(id, pid))
+            .await;
 
         return_success(
             self.command_tx.clone(),
@@ -518,20 +866,82 @@ impl CommandServer {
 
         return_processing(
             self.command_tx.clone(),
-            request_identifier,
+            request_identifier.clone(),
             "The proxy is processing the upgrade command.",
         )
         .await;
 
         let upgrade_data = self.generate_upgrade_data();
+        // the new main process locks it
+        util::release_pid_file();
 
         let (new_main_pid, mut fork_confirmation_channel) =
-            fork_main_into_new_main(self.executable_path.clone(), upgrade_data)
-                .with_context(|| "Could not start a new main process")?;
+            match fork_main_into_new_main(self.executable_path.clone(), upgrade_data) {
+                Ok(forked) => forked,
+                Err(e) => {
+                    util::write_pid_file(&self.config)
+                        .with_context(|| "PID file is not writeable")?;
+                    return Err(e).with_context(|| "Could not start a new main process");
+                }
+            };
 
+        // the new main process confirms once its self-test passed. This one
+        // keeps serving the clients until then, without changing the state it
+        // passed to the new one
+        self.main_upgrade = Some(new_main_pid);
         fork_confirmation_channel.blocking();
-        let received_ok_from_new_process = fork_confirmation_channel.read_message();
-        debug!("upgrade channel sent {:?}", received_ok_from_new_process);
+        let timeout = Duration::from_secs(self.config.upgrade_timeout);
+        let mut command_tx = self.command_tx.clone();
+        smol::spawn(async move {
+            let confirmation = smol::unblock(move || {
+                fork_confirmation_channel.read_message_blocking_timeout(Some(timeout))
+            })
+            .await;
+            debug!("upgrade channel sent {:?}", confirmation);
+
+            if let Err(e) = command_tx
+                .send(CommandMessage::MainUpgradeChecked {
+                    request_identifier,
+                    new_main_pid,
+                    confirmation,
+                })
+                .await
+            {
+                error!(
+                    "could not send the confirmation of the new main process {}: {:?}",
+                    new_main_pid, e
+                );
+            }
+        })
+        .detach();
+
+        Ok(None)
+    }
+
+    /// stops accepting clients once the new main process confirmed, the main
+    /// loop exits after answering. Otherwise the new main process is killed and
+    /// this one resumes
+    pub async fn finish_main_upgrade(
+        &mut self,
+        request_identifier: RequestIdentifier,
+        new_main_pid: i32,
+        confirmation: Option<bool>,
+    ) -> anyhow::Result<Success> {
+        self.main_upgrade = None;
+
+        if confirmation != Some(true) {
+            let reason = match confirmation {
+                Some(_) => "failed its self-test",
+                None => "stopped or did not confirm in time",
+            };
+            self.roll_back_main_upgrade(new_main_pid)?;
+            let message = format!(
+                "the new main process {} {}, the upgrade was rolled back",
+                new_main_pid, reason
+            );
+            return_error(self.command_tx.clone(), request_identifier, &message).await;
+            bail!(message);
+        }
 
         // signaling the accept loop that it should stop
         if let Err(e) = self
@@ -543,23 +953,67 @@ impl CommandServer {
             error!("could not close the accept loop: {:?}", e);
         }
 
-        match received_ok_from_new_process {
-            Some(true) => {
-                info!("wrote final message, closing");
-                Ok(Some(Success::UpgradeMain(new_main_pid)))
-            }
-            _ => bail!("could not upgrade main process"),
+        info!("wrote final message, closing");
+        return_success(
+            self.command_tx.clone(),
+            request_identifier,
+            Success::UpgradeMain(new_main_pid),
+        )
+        .await;
+        Ok(Success::UpgradeMain(new_main_pid))
+    }
+
+    /// stops the workers like `sozu shutdown`, the main process stops once
+    /// they answered
+    pub async fn stop_on_signal(&mut self, hard: bool) -> anyhow::Result<Success> {
+        info!(
+            "received {}, {} stopping",
+            if hard { "SIGQUIT" } else { "SIGTERM" },
+            if hard { "hard" } else { "soft" }
+        );
+        let order = if hard {
+            ProxyRequestOrder::HardStop
+        } else {
+            ProxyRequestOrder::SoftStop(None)
+        };
+        let request_identifier =
+            RequestIdentifier::new(MAIN_PROCESS_CLIENT.to_string(), "SIGNAL-STOP".to_string());
+        self.worker_order(request_identifier, order, None, Vec::new(), false, false)
+            .await?;
+        Ok(Success::StoppingOnSignal(hard))
+    }
+
+    /// stops the new main process of a failed upgrade, this one takes over again
+    fn roll_back_main_upgrade(&mut self, new_main_pid: i32) -> anyhow::Result<()> {
+        error!("rolling back the upgrade to main process {}", new_main_pid);
+        incr!("upgrade_rollback");
+
+        if let Err(e) = kill(Pid::from_raw(new_main_pid), Signal::SIGKILL) {
+            error!("could not kill the new main process: {}", e);
         }
+        let _ = waitpid(Pid::from_raw(new_main_pid), None);
+
+        self.enable_cloexec_after_upgrade()?;
+        // the new main process may have written its own
+        util::write_pid_file(&self.config).with_context(|| "PID file is not writeable")?;
+        Ok(())
     }
 
+    /// replaces a worker with a new one, that takes over its listeners and
+    /// state. A restart is an upgrade answered as a restart: both run the
+    /// binary at the executable path
     pub async fn upgrade_worker(
         &mut self,
         request_identifier: RequestIdentifier,
         id: u32,
+        restart: bool,
     ) -> anyhow::Result<Option<Success>> {
         info!(
-            "client[{}] msg {} wants to upgrade worker {}",
-            request_identifier.client, request_identifier.request, id
+            "client[{}] msg {} wants to {} worker {}",
+            request_identifier.client,
+            request_identifier.request,
+            if restart { "restart" } else { "upgrade" },
+            id
         );
 
         if !self.workers.iter().any(|worker| {
@@ -575,14 +1029,25 @@ impl CommandServer {
             ));
         }
 
-        // same as launch_worker
+        // same as launch_worker, the new worker takes over the listeners of
+        // the old one
         let next_id = self.next_worker_id;
+        self.state.worker_scopes.replace_worker(id, next_id);
+        let state = self.state.scoped_to(next_id);
+        // the new worker runs on the cores of the one it replaces
+        let cpu_cores = self
+            .workers
+            .iter()
+            .find(|worker| worker.id == id)
+            .map(|worker| worker.cpu_cores.clone())
+            .unwrap_or_default();
         let mut new_worker = start_worker(
             next_id,
             &self.config,
             self.executable_path.clone(),
-            &self.state,
+            &state,
             None,
+            cpu_cores,
         )
         .with_context(|| "failed at creating worker")?;
 
@@ -605,12 +1070,22 @@ impl CommandServer {
         let (worker_tx, worker_rx) = channel(10000);
         new_worker.sender = Some(worker_tx);
 
+        // the new worker answers its activation orders and a status request
+        // before the old one is stopped
+        let check_id = format!("{}-UPGRADE-{}-CHECK", request_identifier.client, id);
+        let activate_orders = state.generate_activate_orders();
+        let expected_answers = activate_orders.len() + 1;
+        let (check_tx, check_rx) = futures::channel::mpsc::channel(10);
+        self.in_flight
+            .insert(check_id.clone(), (check_tx, expected_answers));
+        self.upgrade_checks.insert(next_id, check_id.clone());
+
         new_worker
             .sender
             .as_mut()
             .with_context(|| "No sender on new worker".to_string())?
             .send(ProxyRequest {
-                id: format!("UPGRADE-{}-STATUS", id),
+                id: check_id.clone(),
                 order: ProxyRequestOrder::Status,
             })
             .await
@@ -689,61 +1164,16 @@ impl CommandServer {
                 }
             }
             info!("got scm sockets");
-            old_worker.run_state = RunState::Stopping;
-
-            let (softstop_tx, mut softstop_rx) = futures::channel::mpsc::channel(10);
-            let id = format!("{}-softstop", request_identifier.client);
-            self.in_flight.insert(id.clone(), (softstop_tx, 1));
-            old_worker
-                .send(id.clone(), ProxyRequestOrder::SoftStop)
-                .await;
-
-            let mut command_tx = self.command_tx.clone();
-            let cloned_request_identifier = request_identifier.clone();
-            let worker_id = old_worker.id;
-            smol::spawn(async move {
-                while let Some((proxy_response, _)) = softstop_rx.next().await {
-                    match proxy_response.status {
-                        // should we send all this to the command server?
-                        ProxyResponseStatus::Ok => {
-                            info!("softstop OK"); // this doesn't display :-(
-                            if let Err(e) = command_tx
-                                .send(CommandMessage::WorkerClose { worker_id })
-                                .await
-                            {
-                                error!(
-                                    "could not send worker close message to {}: {:?}",
-                                    worker_id, e
-                                );
-                            }
-                            break;
-                        }
-                        ProxyResponseStatus::Processing => {
-                            info!("softstop processing");
-                        }
-                        ProxyResponseStatus::Error(message) => {
-                            info!("softstop error: {:?}", message);
-                            break;
-                        }
-                    };
-                }
-                return_processing(
-                    command_tx.clone(),
-                    cloned_request_identifier,
-                    "Processing softstop responses from the workers...",
-                )
-                .await;
-            })
-            .detach();
         }
 
+        // the main process keeps the listeners until the new worker is checked,
+        // to give them back to the old one if needed
         match listeners {
-            Some(l) => {
+            Some(ref l) => {
                 info!(
                     "sending listeners: to the new worker: {:?}",
-                    new_worker.scm_socket.send_listeners(&l)
+                    new_worker.scm_socket.send_listeners(l)
                 );
-                l.close();
             }
             None => error!("could not get the list of listeners from the previous worker"),
         };
@@ -753,6 +1183,7 @@ impl CommandServer {
             UnixStream::from_raw_fd(fd)
         })?;
 
+        let old_id = id;
         let id = new_worker.id;
         let command_tx = self.command_tx.clone();
         smol::spawn(async move {
@@ -760,68 +1191,286 @@ impl CommandServer {
         })
         .detach();
 
-        let activate_orders = self.state.generate_activate_orders();
-        for (count, order) in activate_orders.into_iter().enumerate() {
+        for order in activate_orders {
+            new_worker.send(check_id.clone(), order).await;
+        }
+
+        if let Some(order) = self.ticket_keys_order() {
+            new_worker
+                .send(format!("{}-TICKET-KEYS", request_identifier.client), order)
+                .await;
+        }
+
+        if let Some(order) = self.access_log_filters_order() {
             new_worker
                 .send(
-                    format!("{}-ACTIVATE-{}", request_identifier.client, count),
+                    format!("{}-ACCESS-LOG-FILTERS", request_identifier.client),
                     order,
                 )
                 .await;
         }
 
         info!("sent config messages to the new worker");
+        let pid = new_worker.pid;
         self.workers.push(new_worker);
+        self.publish_event(format!("WORKER-{}", id), Event::WorkerLaunched(id, pid))
+            .await;
 
-        info!("finished upgrade");
-        Ok(Some(Success::UpgradeWorker(id)))
+        return_processing(
+            self.command_tx.clone(),
+            request_identifier.clone(),
+            format!("Checking the listeners of the new worker {}", id),
+        )
+        .await;
+
+        let timeout = Duration::from_secs(self.config.upgrade_timeout);
+        let mut command_tx = self.command_tx.clone();
+        let new_id = id;
+        smol::spawn(async move {
+            let result = future::or(check_new_worker(check_rx, expected_answers), async {
+                Timer::after(timeout).await;
+                Err(format!("did not answer in {:?}", timeout))
+            })
+            .await;
+
+            if let Err(e) = command_tx
+                .send(CommandMessage::WorkerUpgradeChecked {
+                    request_identifier,
+                    check_id,
+                    old_id,
+                    new_id,
+                    listeners,
+                    restart,
+                    result,
+                })
+                .await
+            {
+                error!("could not send the check of worker {}: {:?}", new_id, e);
+            }
+        })
+        .detach();
+
+        Ok(None)
     }
 
-    pub async fn reload_configuration(
+    /// soft stops the old worker once the new one answered its checks, or stops
+    /// the new one and gives the listeners back to the old one
+    #[allow(clippy::too_many_arguments)]
+    pub async fn finish_worker_upgrade(
         &mut self,
         request_identifier: RequestIdentifier,
-        config_path: Option<String>,
-    ) -> anyhow::Result<Option<Success>> {
-        // check that this works
-        let path = config_path.as_deref().unwrap_or(&self.config.config_path);
-        let new_config = Config::load_from_path(path)
-            .with_context(|| format!("cannot load configuration from '{}'", path))?;
+        check_id: String,
+        old_id: u32,
+        new_id: u32,
+        listeners: Option<Listeners>,
+        restart: bool,
+        result: Result<(), String>,
+    ) -> anyhow::Result<Success> {
+        self.in_flight.remove(&check_id);
+        self.upgrade_checks.remove(&new_id);
 
-        let mut diff_counter = 0usize;
+        if let Err(reason) = result {
+            incr!("upgrade_rollback");
+            self.roll_back_worker_upgrade(old_id, new_id, listeners)
+                .await;
+            let message = format!(
+                "the new worker {} {}, the upgrade was rolled back to worker {}",
+                new_id, reason, old_id
+            );
+            return_error(self.command_tx.clone(), request_identifier, &message).await;
+            bail!(message);
+        }
+
+        if let Some(listeners) = listeners {
+            listeners.close();
+        }
 
-        let (load_state_tx, mut load_state_rx) = futures::channel::mpsc::channel(10000);
+        let old_worker = self
+            .workers
+            .iter_mut()
+            .find(|worker| worker.id == old_id)
+            .with_context(|| format!("the upgraded worker {} is gone", old_id))?;
+        old_worker.run_state = RunState::Stopping;
 
         return_processing(
             self.command_tx.clone(),
             request_identifier.clone(),
-            "Reloading configuration, sending config messages to workers...",
+            format!(
+                "The new worker {} answered, soft stopping worker {}",
+                new_id, old_id
+            ),
         )
         .await;
 
-        for message in new_config.generate_config_messages() {
-            if let CommandRequestOrder::Proxy(order) = message.order {
-                if self.state.handle_order(&order) {
-                    diff_counter += 1;
-
-                    let mut found = false;
-                    let id = format!(
-                        "LOAD-STATE-{}-{}",
-                        &request_identifier.request, diff_counter
-                    );
-
-                    for ref mut worker in self.workers.iter_mut().filter(|worker| {
-                        worker.run_state != RunState::Stopping
-                            && worker.run_state != RunState::Stopped
-                    }) {
-                        let worker_message_id = format!("{}-{}", id, worker.id);
-                        worker.send(worker_message_id.clone(), *order.clone()).await;
-                        self.in_flight
-                            .insert(worker_message_id, (load_state_tx.clone(), 1));
-
-                        found = true;
-                    }
+        let (softstop_tx, mut softstop_rx) = futures::channel::mpsc::channel(10);
+        let id = format!("{}-softstop", request_identifier.client);
+        self.in_flight.insert(id.clone(), (softstop_tx, 1));
+        old_worker
+            .send(id.clone(), ProxyRequestOrder::SoftStop(None))
+            .await;
 
-                    if !found {
+        let mut command_tx = self.command_tx.clone();
+        let cloned_request_identifier = request_identifier.clone();
+        let worker_id = old_worker.id;
+        smol::spawn(async move {
+            while let Some((proxy_response, _)) = softstop_rx.next().await {
+                match proxy_response.status {
+                    // should we send all this to the command server?
+                    ProxyResponseStatus::Ok => {
+                        info!("softstop OK"); // this doesn't display :-(
+                        if let Err(e) = command_tx
+                            .send(CommandMessage::WorkerClose { worker_id })
+                            .await
+                        {
+                            error!(
+                                "could not send worker close message to {}: {:?}",
+                                worker_id, e
+                            );
+                        }
+                        break;
+                    }
+                    ProxyResponseStatus::Processing => {
+                        info!("softstop processing");
+                    }
+                    ProxyResponseStatus::Error(message) => {
+                        info!("softstop error: {:?}", message);
+                        break;
+                    }
+                };
+            }
+            return_processing(
+                command_tx.clone(),
+                cloned_request_identifier,
+                "Processing softstop responses from the workers...",
+            )
+            .await;
+        })
+        .detach();
+
+        self.publish_event(
+            format!("WORKER-{}", new_id),
+            Event::WorkerUpgraded(old_id, new_id),
+        )
+        .await;
+
+        info!("finished upgrade");
+        let success = if restart {
+            Success::RestartWorker(old_id, new_id)
+        } else {
+            Success::UpgradeWorker(new_id)
+        };
+        return_success(self.command_tx.clone(), request_identifier, success.clone()).await;
+        Ok(success)
+    }
+
+    /// the old worker activates again the listeners it gave to the new one
+    async fn roll_back_worker_upgrade(
+        &mut self,
+        old_id: u32,
+        new_id: u32,
+        listeners: Option<Listeners>,
+    ) {
+        error!(
+            "rolling back the upgrade of worker {} to {}",
+            old_id, new_id
+        );
+
+        // stopped, so that its exit is not taken for a crash
+        if let Some(new_worker) = self.workers.iter_mut().find(|worker| worker.id == new_id) {
+            if let Err(e) = kill(Pid::from_raw(new_worker.pid), Signal::SIGKILL) {
+                error!("could not kill the new worker {}: {}", new_id, e);
+            }
+            new_worker.run_state = RunState::Stopped;
+        }
+        self.state.worker_scopes.replace_worker(new_id, old_id);
+
+        let mut activate_orders = self.state.scoped_to(old_id).generate_activate_orders();
+        let old_worker = match self.workers.iter_mut().find(|worker| worker.id == old_id) {
+            Some(worker) => worker,
+            None => {
+                error!("the upgraded worker {} is gone", old_id);
+                return;
+            }
+        };
+
+        // each socket is sent right before the order activating it
+        if let Some(mut listeners) = listeners {
+            for order in activate_orders.iter_mut() {
+                if let ProxyRequestOrder::ActivateListener(activate) = order {
+                    let fd = match activate.proxy {
+                        ListenerType::HTTP => listeners.get_http(&activate.address),
+                        ListenerType::HTTPS => listeners.get_https(&activate.address),
+                        ListenerType::TCP => listeners.get_tcp(&activate.address),
+                    };
+                    if let Some(fd) = fd {
+                        old_worker.hand_over_listener(activate, fd);
+                    }
+                }
+            }
+            listeners.close();
+        }
+
+        for (count, order) in activate_orders.into_iter().enumerate() {
+            old_worker
+                .send(format!("ROLLBACK-{}-ACTIVATE-{}", old_id, count), order)
+                .await;
+        }
+    }
+
+    /// loads the configuration file again and sends its new orders to the
+    /// workers. Without a client, like on SIGHUP, the result is only logged
+    pub async fn reload_configuration(
+        &mut self,
+        client_id: Option<String>,
+        request_id: String,
+        config_path: Option<String>,
+    ) -> anyhow::Result<Option<Success>> {
+        // check that this works
+        let path = config_path.as_deref().unwrap_or(&self.config.config_path);
+        let new_config = Config::load_from_path(path)
+            .with_context(|| format!("cannot load configuration from '{}'", path))?;
+
+        let mut diff_counter = 0usize;
+        let mut progress = Progress::default();
+
+        let (load_state_tx, load_state_rx) = futures::channel::mpsc::channel(10000);
+
+        if let Some(client_id) = &client_id {
+            return_processing(
+                self.command_tx.clone(),
+                RequestIdentifier::new(client_id, &request_id),
+                "Reloading configuration, sending config messages to workers...",
+            )
+            .await;
+        }
+
+        for message in new_config.generate_config_messages() {
+            if let CommandRequestOrder::Proxy(order) = message.order {
+                if self.state.handle_order(&order) {
+                    diff_counter += 1;
+
+                    let mut found = false;
+                    let id = format!("LOAD-STATE-{}-{}", &request_id, diff_counter);
+
+                    for ref mut worker in self.workers.iter_mut().filter(|worker| {
+                        worker.run_state != RunState::Stopping
+                            && worker.run_state != RunState::Stopped
+                    }) {
+                        let order = match self.state.worker_scopes.order_for(worker.id, &order) {
+                            Some(order) => order,
+                            None => continue,
+                        };
+                        let order = worker.with_listener(order);
+                        let worker_message_id = format!("{}-{}", id, worker.id);
+                        worker.send(worker_message_id.clone(), order).await;
+                        self.in_flight
+                            .insert(worker_message_id, (load_state_tx.clone(), 1));
+                        progress.expect(worker.id);
+
+                        found = true;
+                    }
+
+                    if !found {
                         // FIXME: should send back error here
                         error!("no worker found");
                     }
@@ -831,7 +1480,6 @@ impl CommandServer {
 
         // clone everything we will need in the detached thread
         let command_tx = self.command_tx.clone();
-        let cloned_identifier = request_identifier.clone();
 
         if diff_counter > 0 {
             info!(
@@ -839,33 +1487,41 @@ impl CommandServer {
                 new_config.config_path, diff_counter
             );
             smol::spawn(async move {
-                let mut ok = 0usize;
-                let mut error = 0usize;
-                while let Some((proxy_response, _)) = load_state_rx.next().await {
-                    match proxy_response.status {
-                        ProxyResponseStatus::Ok => {
-                            ok += 1;
-                        }
-                        ProxyResponseStatus::Processing => {}
-                        ProxyResponseStatus::Error(message) => {
-                            error!("{}", message);
-                            error += 1;
+                let request_identifier =
+                    client_id.map(|client_id| RequestIdentifier::new(client_id, request_id));
+                let (ok, error) = collect_worker_answers(
+                    load_state_rx,
+                    progress,
+                    command_tx.clone(),
+                    request_identifier.clone(),
+                )
+                .await;
+
+                let request_identifier = match request_identifier {
+                    Some(request_identifier) => request_identifier,
+                    None => {
+                        match error {
+                            0 => info!("reloading configuration: {} ok messages, 0 errors", ok),
+                            _ => error!(
+                                "reloading configuration: {} ok messages, {} errors",
+                                ok, error
+                            ),
                         }
-                    };
-                    debug!("ok:{}, error: {}", ok, error);
-                }
+                        return;
+                    }
+                };
 
                 if error == 0 {
                     return_success(
                         command_tx,
-                        cloned_identifier,
+                        request_identifier,
                         Success::ReloadConfiguration(ok, error),
                     )
                     .await;
                 } else {
                     return_error(
                         command_tx,
-                        cloned_identifier,
+                        request_identifier,
                         format!(
                             "Reloading configuration failed. ok: {} messages, error: {}",
                             ok, error
@@ -887,9 +1543,368 @@ impl CommandServer {
 
         self.config = new_config;
 
+        // no answer will come from the workers
+        if diff_counter == 0 {
+            return Ok(Some(Success::ReloadConfiguration(0, 0)));
+        }
         Ok(None)
     }
 
+    /// executes the orders turning the current state into the desired one. They
+    /// are all checked before the first one is sent, a refused state changes nothing
+    pub async fn apply_state(
+        &mut self,
+        request_identifier: RequestIdentifier,
+        state: ConfigState,
+        strict: bool,
+        dry_run: bool,
+    ) -> anyhow::Result<Option<Success>> {
+        // rebuilt from its orders, for its indexes to match the ones of the current state
+        let mut desired = ConfigState::new();
+        for order in state.generate_orders() {
+            desired.handle_order(&order);
+        }
+        let orders = self.state.diff(&desired);
+
+        let mut certificate_issues = Vec::new();
+        let mut certificate_warning = None;
+        let mut warnings = Vec::new();
+        for order in orders.iter() {
+            match order {
+                ProxyRequestOrder::AddCertificate(add) => {
+                    certificate_issues.extend(validate_certificate(&add.certificate))
+                }
+                ProxyRequestOrder::AddHttpsFrontend(front)
+                    if front.hostname != "*"
+                        && !desired.certificate_covers(&front.address, &front.hostname) =>
+                {
+                    warnings.push(format!(
+                        "no certificate of the listener {} covers the hostname {}",
+                        front.address, front.hostname
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        if !certificate_issues.is_empty() {
+            let message = format!(
+                "invalid certificates: {}",
+                certificate_issues
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            );
+            if self.config.strict_certificate_validation {
+                return_rejection(
+                    self.command_tx.clone(),
+                    request_identifier,
+                    message,
+                    CommandResponseContent::CertificateValidation(certificate_issues),
+                )
+                .await;
+                return Ok(None);
+            }
+            warn!("{}", message);
+            certificate_warning = Some(message);
+        }
+        if !warnings.is_empty() {
+            if strict {
+                return_rejection(
+                    self.command_tx.clone(),
+                    request_identifier,
+                    warnings.join(", "),
+                    CommandResponseContent::Warnings(warnings),
+                )
+                .await;
+                return Ok(None);
+            }
+            warn!("{}", warnings.join(", "));
+        }
+
+        if dry_run {
+            warnings.extend(certificate_warning);
+            warnings.extend(
+                orders
+                    .iter()
+                    .flat_map(|order| unknown_clusters(&desired, order)),
+            );
+            return Ok(Some(Success::DryRun(CommandResponseContent::DryRun(
+                DryRun { orders, warnings },
+            ))));
+        }
+
+        if orders.is_empty() {
+            info!("the applied state is the current one");
+            return Ok(Some(Success::AppliedState(
+                CommandResponseContent::StateDiff(orders),
+            )));
+        }
+
+        return_processing(
+            self.command_tx.clone(),
+            request_identifier.clone(),
+            format!(
+                "Applying the state, sending {} orders to the workers",
+                orders.len()
+            ),
+        )
+        .await;
+
+        // the listeners keep their scope, the new ones take the one of the
+        // applied state, the removed ones still need theirs to be removed
+        let mut scopes = state.worker_scopes;
+        scopes.0.extend(self.state.worker_scopes.0.clone());
+        self.state.worker_scopes = scopes.clone();
+
+        let (apply_state_tx, mut apply_state_rx) = futures::channel::mpsc::channel(10000);
+        for (index, order) in orders.iter().enumerate() {
+            self.state.handle_order(order);
+
+            let id = format!("APPLY-STATE-{}-{}", request_identifier.request, index);
+            for worker in self.workers.iter_mut().filter(|worker| {
+                worker.run_state != RunState::Stopping && worker.run_state != RunState::Stopped
+            }) {
+                let order = match scopes.order_for(worker.id, order) {
+                    Some(order) => order,
+                    None => continue,
+                };
+                let order = worker.with_listener(order);
+                let worker_message_id = format!("{}-{}", id, worker.id);
+                worker.send(worker_message_id.clone(), order).await;
+                self.in_flight
+                    .insert(worker_message_id, (apply_state_tx.clone(), 1));
+            }
+        }
+        // the answers end when the last order in flight is answered
+        drop(apply_state_tx);
+
+        let command_tx = self.command_tx.clone();
+        smol::spawn(async move {
+            let mut ok = 0usize;
+            let mut error = 0usize;
+            while let Some((proxy_response, _)) = apply_state_rx.next().await {
+                match proxy_response.status {
+                    ProxyResponseStatus::Ok => ok += 1,
+                    ProxyResponseStatus::Processing => {}
+                    ProxyResponseStatus::Error(message) => {
+                        error!("{}", message);
+                        error += 1;
+                    }
+                };
+            }
+
+            if error == 0 {
+                return_success(
+                    command_tx,
+                    request_identifier,
+                    Success::AppliedState(CommandResponseContent::StateDiff(orders)),
+                )
+                .await;
+            } else {
+                return_error(
+                    command_tx,
+                    request_identifier,
+                    format!(
+                        "Applying the state failed on the workers, ok: {} messages, error: {}",
+                        ok, error
+                    ),
+                )
+                .await;
+            }
+        })
+        .detach();
+
+        self.backends_count = self.state.count_backends();
+        self.frontends_count = self.state.count_frontends();
+        gauge!("configuration.clusters", self.state.clusters.len());
+        gauge!("configuration.backends", self.backends_count);
+        gauge!("configuration.frontends", self.frontends_count);
+
+        Ok(None)
+    }
+
+    pub fn state_history(&self) -> anyhow::Result<Option<Success>> {
+        Ok(Some(Success::StateHistory(
+            CommandResponseContent::StateHistory(self.history.versions()),
+        )))
+    }
+
+    pub fn audit_log_tail(&mut self, lines: usize) -> anyhow::Result<Option<Success>> {
+        let audit_log = self
+            .audit_log
+            .as_mut()
+            .with_context(|| "the audit log is not configured")?;
+        let entries = audit_log
+            .tail(lines)
+            .with_context(|| "could not read the audit log")?;
+        Ok(Some(Success::AuditLog(CommandResponseContent::AuditLog(
+            entries,
+        ))))
+    }
+
+    /// applies a version of the history, the orders of its diff with the current
+    /// state make a new version
+    pub async fn rollback_state(
+        &mut self,
+        request_identifier: RequestIdentifier,
+        version: u64,
+        dry_run: bool,
+    ) -> anyhow::Result<Option<Success>> {
+        let state = match self.history.get(version) {
+            Some(state) => state.clone(),
+            None => bail!("the version {} of the state is not in the history", version),
+        };
+
+        info!("rolling the state back to version {}", version);
+        self.apply_state(request_identifier, state, false, dry_run)
+            .await
+    }
+
+    /// applies the change of a peer, if it follows the version of the group
+    /// state of this instance
+    pub async fn apply_peer_change(
+        &mut self,
+        request_identifier: RequestIdentifier,
+        change: PeerChange,
+    ) -> anyhow::Result<Option<Success>> {
+        let version = match &self.peering {
+            Some(peering) => peering.version,
+            None => bail!(
+                "peering is not configured, refusing the change of {}",
+                change.origin
+            ),
+        };
+        if change.base_version != version {
+            incr!("peering.conflict");
+            bail!(
+                "conflict with {}: its change applies to the version {} of the group state, this instance is at the version {}",
+                change.origin,
+                change.base_version,
+                version
+            );
+        }
+
+        let mut state = self.state.clone();
+        for order in change.orders.iter() {
+            state.handle_order(order);
+        }
+        info!(
+            "applying the change of {} as the version {} of the group state",
+            change.origin,
+            version + 1
+        );
+        self.apply_group_state(request_identifier, state, version + 1)
+            .await
+    }
+
+    /// replaces the state with the one of a peer, if it is forced or if it
+    /// wins over the state of this instance. Otherwise, this instance sends
+    /// its state to the peers
+    pub async fn apply_peer_state(
+        &mut self,
+        request_identifier: RequestIdentifier,
+        peer_state: PeerState,
+    ) -> anyhow::Result<Option<Success>> {
+        let peering = match self.peering.as_mut() {
+            Some(peering) => peering,
+            None => bail!(
+                "peering is not configured, refusing the state of {}",
+                peer_state.origin
+            ),
+        };
+        if !peer_state.forced && peering.wins_over(&peer_state) {
+            incr!("peering.conflict");
+            let version = peering.version;
+            peering.share_state(&self.state, false);
+            bail!(
+                "conflict with {}: its state is at the version {} of the group state, this instance keeps its own at the version {} and sends it",
+                peer_state.origin,
+                peer_state.version,
+                version
+            );
+        }
+
+        info!(
+            "applying the state of {}, at the version {} of the group state",
+            peer_state.origin, peer_state.version
+        );
+        self.apply_group_state(request_identifier, *peer_state.state, peer_state.version)
+            .await
+    }
+
+    /// applies the state, that becomes this version of the group state once
+    /// the workers have it
+    async fn apply_group_state(
+        &mut self,
+        request_identifier: RequestIdentifier,
+        state: ConfigState,
+        version: u64,
+    ) -> anyhow::Result<Option<Success>> {
+        // rebuilt from its orders, to be compared with the current state
+        let mut desired = ConfigState::new();
+        for order in state.generate_orders() {
+            desired.handle_order(&order);
+        }
+
+        let success = self
+            .apply_state(request_identifier, desired.clone(), false, false)
+            .await?;
+        // the state is refused if its certificates are invalid
+        if self.state.diff(&desired).is_empty() {
+            if let Some(peering) = self.peering.as_mut() {
+                peering.version = version;
+            }
+        }
+        Ok(success)
+    }
+
+    /// sends the whole state to the peers, to resolve a conflict
+    pub fn sync_peers(&mut self) -> anyhow::Result<Option<Success>> {
+        let peering = self
+            .peering
+            .as_mut()
+            .with_context(|| "peering is not configured")?;
+
+        let peers = peering.share_state(&self.state, true);
+        Ok(Some(Success::SyncedPeers(peers, peering.version)))
+    }
+
+    /// a peer answered an order. A refused change is resolved by sending the
+    /// state of this instance to the peer, and the client that made the change
+    /// gets its final response once all the peers answered
+    pub async fn peer_answered(
+        &mut self,
+        address: String,
+        request: Option<RequestIdentifier>,
+        is_change: bool,
+        answer: PeerAnswer,
+    ) -> anyhow::Result<Success> {
+        let peering = match self.peering.as_mut() {
+            Some(peering) => peering,
+            None => return Ok(Success::PeerAnswered(address)),
+        };
+
+        if let (PeerAnswer::Refused(_), true) = (&answer, is_change) {
+            info!(
+                "sending the version {} of the state to the peer {}, that refused the change",
+                peering.version, address
+            );
+            peering.share_state_with(&address, &self.state);
+        }
+
+        let response = request.and_then(|request| {
+            peering
+                .answered(&request, &address, &answer)
+                .map(|response| (request, response))
+        });
+        match response {
+            Some((request, response)) => self.notify_advancement_to_client(request, response).await,
+            None => Ok(Success::PeerAnswered(address)),
+        }
+    }
+
     pub async fn status(
         &mut self,
         request_identifier: RequestIdentifier,
@@ -937,7 +1952,7 @@ impl CommandServer {
         smol::spawn(async move {
             let mut i = 0;
 
-            while let Some((proxy_response, _)) = status_rx.next().await {
+            while let Some((proxy_response, worker_id)) = status_rx.next().await {
                 info!(
                     "received response with id {}: {:?}",
                     proxy_response.id, proxy_response
@@ -945,7 +1960,16 @@ impl CommandServer {
                 let new_run_state = match proxy_response.status {
                     ProxyResponseStatus::Ok => RunState::Running,
                     ProxyResponseStatus::Processing => continue,
-                    ProxyResponseStatus::Error(_) => RunState::NotAnswering,
+                    ProxyResponseStatus::Error(_) => {
+                        let event = CommandMessage::PublishEvent {
+                            id: format!("WORKER-{}", worker_id),
+                            event: Event::WorkerNotAnswering(worker_id),
+                        };
+                        if let Err(e) = command_tx.clone().send(event).await {
+                            error!("could not send the worker event: {:?}", e);
+                        }
+                        RunState::NotAnswering
+                    }
                 };
                 worker_info_map
                     .entry(proxy_response.id)
@@ -1107,6 +2131,8 @@ impl CommandServer {
             }
             Query::Certificates(_) => {}
             Query::Metrics(_) => {}
+            // the main process does not check the backends, nor connects to them
+            Query::BackendHealth(_) | Query::Backends(_) => {}
         };
 
         // all theses are passed to the thread
@@ -1157,6 +2183,9 @@ impl CommandServer {
                     proxy_responses_map.insert(String::from("main"), main);
                     Success::Query(CommandResponseContent::Query(proxy_responses_map))
                 }
+                &Query::BackendHealth(_) | &Query::Backends(_) => {
+                    Success::Query(CommandResponseContent::Query(proxy_responses_map))
+                }
                 &Query::Certificates(_) => {
                     info!(
                         "certificates query answer received: {:?}",
@@ -1198,56 +2227,192 @@ impl CommandServer {
         Ok(Some(Success::Logging(logging_filter)))
     }
 
+    /// the state after the orders of the batch. It is refused if one of them cannot
+    /// be rolled back by the workers, or removes something missing
+    fn batch_state(
+        &self,
+        orders: &[ProxyRequestOrder],
+        targeted: bool,
+    ) -> anyhow::Result<ConfigState> {
+        if orders.is_empty() {
+            bail!("the batch has no orders");
+        }
+
+        let mut state = self.state.clone();
+        for (index, order) in orders.iter().enumerate() {
+            if !order.is_reversible() {
+                bail!("order {} of the batch cannot be rolled back", index);
+            }
+            if !state.handle_order(order) && !targeted {
+                if let Some(message) = missing_target(order) {
+                    bail!("order {} of the batch: {}", index, message);
+                }
+            }
+        }
+        Ok(state)
+    }
+
     pub async fn worker_order(
         &mut self,
         request_identifier: RequestIdentifier,
         order: ProxyRequestOrder,
         worker_id: Option<u32>,
+        worker_ids: Vec<u32>,
+        strict: bool,
+        dry_run: bool,
     ) -> anyhow::Result<Option<Success>> {
+        // the workers the order is sent to, all of them if empty
+        let targets: Vec<u32> = worker_id.into_iter().chain(worker_ids).collect();
+
         if let &ProxyRequestOrder::AddCertificate(_) = &order {
             debug!("workerconfig client order AddCertificate()");
+        } else if let ProxyRequestOrder::Batch(orders) = &order {
+            debug!("workerconfig client order Batch({} orders)", orders.len());
         } else {
             debug!("workerconfig client order {:?}", order);
         }
+        // the clusters writing invalid headers in the messages are refused
+        let orders = match &order {
+            ProxyRequestOrder::Batch(orders) => orders.iter().collect(),
+            order => vec![order],
+        };
+        for order in orders.iter() {
+            if let ProxyRequestOrder::AddCluster(cluster) = order {
+                if let Err(e) = cluster.validate() {
+                    bail!(format!("invalid cluster {}: {}", cluster.cluster_id, e));
+                }
+            }
+        }
+        // a batch is checked as a whole, against the state it leads to
+        let batch_state = match &order {
+            ProxyRequestOrder::Batch(orders) => {
+                Some(self.batch_state(orders, !targets.is_empty())?)
+            }
+            _ => None,
+        };
 
-        if !self.state.handle_order(&order) {
-            // Check if the backend or frontend exist before deleting it
-            if worker_id.is_none() {
-                match order {
-                    ProxyRequestOrder::RemoveBackend(ref backend) => {
-                        bail!(format!(
-                            "cannot remove backend: cluster {} has no backends {} at {}",
-                            backend.cluster_id, backend.backend_id, backend.address,
-                        ));
-                    }
-                    ProxyRequestOrder::RemoveHttpFrontend(h)
-                    | ProxyRequestOrder::RemoveHttpsFrontend(h) => {
-                        let msg = match h.route {
-                            Route::ClusterId(cluster_id) => format!(
-                                "No such frontend at {} for the cluster {}",
-                                h.address, cluster_id
-                            ),
-                            Route::Deny => format!("No such frontend at {}", h.address),
-                        };
-                        bail!(msg);
+        let mut certificate_issues = None;
+        let mut certificate_warning = None;
+        for order in orders.iter() {
+            let issues = match order {
+                ProxyRequestOrder::AddCertificate(add) => validate_certificate(&add.certificate),
+                ProxyRequestOrder::ReplaceCertificate(replace) => {
+                    validate_certificate(&replace.new_certificate)
+                }
+                _ => continue,
+            };
+            certificate_issues
+                .get_or_insert_with(Vec::new)
+                .extend(issues);
+        }
+        if let Some(issues) = certificate_issues.as_ref().filter(|i| !i.is_empty()) {
+            let message = format!(
+                "invalid certificate: {}",
+                issues
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            );
+            if self.config.strict_certificate_validation {
+                return_rejection(
+                    self.command_tx.clone(),
+                    request_identifier,
+                    message,
+                    CommandResponseContent::CertificateValidation(issues.clone()),
+                )
+                .await;
+                return Ok(None);
+            }
+            warn!("{}", message);
+            certificate_warning = Some(message);
+        }
+        let covering_state = batch_state.as_ref().unwrap_or(&self.state);
+        let mut warnings = Vec::new();
+        for order in orders.iter() {
+            if let ProxyRequestOrder::AddHttpsFrontend(front) = order {
+                if front.hostname != "*"
+                    && !covering_state.certificate_covers(&front.address, &front.hostname)
+                {
+                    warnings.push(format!(
+                        "no certificate of the listener {} covers the hostname {}",
+                        front.address, front.hostname
+                    ));
+                }
+            }
+        }
+        if !warnings.is_empty() {
+            if strict {
+                return_rejection(
+                    self.command_tx.clone(),
+                    request_identifier,
+                    warnings.join(", "),
+                    CommandResponseContent::Warnings(warnings),
+                )
+                .await;
+                return Ok(None);
+            }
+            warn!("{}", warnings.join(", "));
+        }
+        if dry_run {
+            warnings.extend(certificate_warning);
+            let mut next = self.state.clone();
+            for order in orders.iter() {
+                if next.handle_order(order) {
+                    continue;
+                }
+                match existing_target(order) {
+                    Some(message) => warnings.push(message),
+                    None if targets.is_empty() => {
+                        if let Some(message) = missing_target(order) {
+                            bail!(message);
+                        }
                     }
-                    ProxyRequestOrder::RemoveTcpFrontend(TcpFrontend {
-                        ref cluster_id,
-                        ref address,
-                        ref tags,
-                    }) => {
-                        bail!(format!(
-                            "cannot remove TCP frontend: cluster {} has no frontends at {} (custom tags: {:?})",
-                            cluster_id, address, tags
-                        ));
+                    None => {}
+                }
+            }
+            warnings.extend(
+                orders
+                    .iter()
+                    .flat_map(|order| unknown_clusters(&next, order)),
+            );
+            return Ok(Some(Success::DryRun(CommandResponseContent::DryRun(
+                DryRun {
+                    orders: self.state.diff(&next),
+                    warnings,
+                },
+            ))));
+        }
+
+        // the orders undoing the batch in the state, if every worker rolls it back
+        let rollback = batch_state.as_ref().map(|state| state.diff(&self.state));
+        // the scopes of the removed listeners are still needed to send the order
+        let mut scopes = self.state.worker_scopes.clone();
+        let changed = match batch_state {
+            Some(state) => {
+                self.state = state;
+                true
+            }
+            None => {
+                let changed = self.state.handle_order(&order);
+                // Check if the backend or frontend exist before deleting it
+                if !changed && targets.is_empty() {
+                    if let Some(message) = missing_target(&order) {
+                        bail!(message);
                     }
-                    _ => {}
-                };
+                }
+                changed
             }
+        };
+        // the listeners added for some workers are handled by them only
+        if changed {
+            self.state.worker_scopes.scope(&order, &targets);
+            scopes.0.extend(self.state.worker_scopes.0.clone());
         }
 
         if self.config.automatic_state_save
-            & (order != ProxyRequestOrder::SoftStop || order != ProxyRequestOrder::HardStop)
+            & (!matches!(order, ProxyRequestOrder::SoftStop(_))
+                || order != ProxyRequestOrder::HardStop)
         {
             if let Some(path) = self.config.saved_state.clone() {
                 return_processing(
@@ -1267,9 +2432,10 @@ impl CommandServer {
         return_processing(
             self.command_tx.clone(),
             request_identifier.clone(),
-            match worker_id {
-                Some(id) => format!("Sending the order to worker {}", id),
-                None => "Sending the order to all workers".to_owned(),
+            match targets.as_slice() {
+                [] => "Sending the order to all workers".to_owned(),
+                [id] => format!("Sending the order to worker {}", id),
+                ids => format!("Sending the order to workers {}", join_ids(ids)),
             },
         )
         .await;
@@ -1279,18 +2445,30 @@ impl CommandServer {
         let mut found = false;
         let mut stopping_workers = HashSet::new();
         let mut worker_count = 0usize;
+        // the workers the order is sent to, if not all of them
+        let mut sent_to = Vec::new();
+        let mut skipped = false;
         for ref mut worker in self.workers.iter_mut().filter(|worker| {
             worker.run_state != RunState::Stopping && worker.run_state != RunState::Stopped
         }) {
-            // sort out the specificly targeted worker, if provided
-            if let Some(id) = worker_id {
-                if id != worker.id {
+            // sort out the specificly targeted workers, if provided
+            if !targets.is_empty() && !targets.contains(&worker.id) {
+                skipped = true;
+                continue;
+            }
+            // and the ones not handling the listener of the order
+            let worker_order = match scopes.order_for(worker.id, &order) {
+                Some(worker_order) => worker_order,
+                None => {
+                    skipped = true;
                     continue;
                 }
-            }
+            };
 
-            let should_stop_worker =
-                order == ProxyRequestOrder::SoftStop || order == ProxyRequestOrder::HardStop;
+            let should_stop_worker = matches!(
+                order,
+                ProxyRequestOrder::SoftStop(_) | ProxyRequestOrder::HardStop
+            );
             if should_stop_worker {
                 worker.run_state = RunState::Stopping;
                 stopping_workers.insert(worker.id);
@@ -1299,16 +2477,22 @@ impl CommandServer {
             // TODO:
             // let request_id = request_identifier.to_worker_request_id();
             let req_id = format!("{}-worker-{}", request_identifier.client, worker.id);
-            worker.send(req_id.clone(), order.clone()).await;
+            let worker_order = worker.with_listener(worker_order);
+            worker.send(req_id.clone(), worker_order).await;
             self.in_flight.insert(req_id, (worker_order_tx.clone(), 1));
 
             found = true;
             worker_count += 1;
+            sent_to.push(worker.id);
+        }
+        if !skipped {
+            sent_to.clear();
         }
 
-        let should_stop_main = (order == ProxyRequestOrder::SoftStop
-            || order == ProxyRequestOrder::HardStop)
-            && worker_id.is_none();
+        let should_stop_main = matches!(
+            order,
+            ProxyRequestOrder::SoftStop(_) | ProxyRequestOrder::HardStop
+        ) && targets.is_empty();
 
         let mut command_tx = self.command_tx.clone();
         let thread_request_identifier = request_identifier.clone();
@@ -1348,15 +2532,10 @@ impl CommandServer {
                 }
             }
 
-            // send the order to kill the main process only after all workers responded
-            if should_stop_main {
-                if let Err(e) = command_tx.send(CommandMessage::MasterStop).await {
-                    error!("could not send main stop message: {:?}", e);
-                }
-            }
-
             let mut messages = vec![];
             let mut has_error = false;
+            // the sessions drained and killed by the workers on a soft stop
+            let mut soft_stop_reports = BTreeMap::new();
             for response in responses.iter() {
                 match response.1.status {
                     ProxyResponseStatus::Error(ref e) => {
@@ -1365,17 +2544,52 @@ impl CommandServer {
                     }
                     _ => messages.push(format!("{}: OK", response.0)),
                 }
+                if let Some(ProxyResponseContent::SoftStop(report)) = response.1.content {
+                    soft_stop_reports.insert(response.0, report);
+                }
+            }
+
+            let rolled_back = responses
+                .iter()
+                .all(|(_, response)| matches!(response.status, ProxyResponseStatus::Error(_)));
+            if let Some(rollback) = rollback.filter(|_| rolled_back) {
+                if let Err(e) = command_tx
+                    .send(CommandMessage::RolledBackBatch(rollback))
+                    .await
+                {
+                    error!("could not send the rollback of the batch: {:?}", e);
+                }
             }
 
             if has_error {
-                return_error(command_tx, thread_request_identifier, messages.join(", ")).await;
-            } else {
-                return_success(
-                    command_tx,
+                return_error(
+                    command_tx.clone(),
                     thread_request_identifier,
-                    Success::WorkerOrder(worker_id),
+                    messages.join(", "),
                 )
                 .await;
+            } else {
+                let success = match certificate_issues {
+                    Some(issues) => Success::ValidatedCertificate(
+                        CommandResponseContent::CertificateValidation(issues),
+                    ),
+                    None if !warnings.is_empty() => {
+                        Success::WorkerOrderWithWarnings(CommandResponseContent::Warnings(warnings))
+                    }
+                    None if !soft_stop_reports.is_empty() => {
+                        Success::SoftStop(CommandResponseContent::SoftStop(soft_stop_reports))
+                    }
+                    None => Success::WorkerOrder(sent_to),
+                };
+                return_success(command_tx.clone(), thread_request_identifier, success).await;
+            }
+
+            // send the order to kill the main process only after all workers responded,
+            // and after their answer, for the client to get it
+            if should_stop_main {
+                if let Err(e) = command_tx.send(CommandMessage::MasterStop).await {
+                    error!("could not send main stop message: {:?}", e);
+                }
             }
         })
         .detach();
@@ -1387,17 +2601,25 @@ impl CommandServer {
         }
 
         match order {
-            ProxyRequestOrder::AddBackend(_) | ProxyRequestOrder::RemoveBackend(_) => {
+            ProxyRequestOrder::AddBackend(_)
+            | ProxyRequestOrder::RemoveBackend(_)
+            | ProxyRequestOrder::DrainBackend(_) => {
                 self.backends_count = self.state.count_backends()
             }
             ProxyRequestOrder::AddHttpFrontend(_)
             | ProxyRequestOrder::AddHttpsFrontend(_)
             | ProxyRequestOrder::AddTcpFrontend(_)
+            | ProxyRequestOrder::AddSniFrontend(_)
             | ProxyRequestOrder::RemoveHttpFrontend(_)
             | ProxyRequestOrder::RemoveHttpsFrontend(_)
-            | ProxyRequestOrder::RemoveTcpFrontend(_) => {
+            | ProxyRequestOrder::RemoveTcpFrontend(_)
+            | ProxyRequestOrder::RemoveSniFrontend(_) => {
                 self.frontends_count = self.state.count_frontends()
             }
+            ProxyRequestOrder::Batch(_) => {
+                self.backends_count = self.state.count_backends();
+                self.frontends_count = self.state.count_frontends();
+            }
             _ => {}
         };
 
@@ -1425,10 +2647,18 @@ impl CommandServer {
                 let command_response_data = match success {
                     // should list Success::Metrics(crd) as well
                     Success::DumpState(crd)
+                    | Success::ListCertificates(crd)
                     | Success::ListFrontends(crd)
                     | Success::ListWorkers(crd)
                     | Success::Query(crd)
-                    | Success::Status(crd) => Some(crd),
+                    | Success::Status(crd)
+                    | Success::AppliedState(crd)
+                    | Success::StateHistory(crd)
+                    | Success::AuditLog(crd)
+                    | Success::DryRun(crd)
+                    | Success::ValidatedCertificate(crd)
+                    | Success::SoftStop(crd)
+                    | Success::WorkerOrderWithWarnings(crd) => Some(crd),
                     _ => None,
                 };
 
@@ -1445,12 +2675,24 @@ impl CommandServer {
                 processing_message,
                 None,
             ),
+            Response::Progress(progress) => CommandResponse::new(
+                request_id.clone(),
+                CommandStatus::Processing,
+                progress.to_string(),
+                Some(CommandResponseContent::Progress(progress)),
+            ),
             Response::Error(error_message) => CommandResponse::new(
                 request_id.clone(),
                 CommandStatus::Error,
                 error_message,
                 None,
             ),
+            Response::Rejected(error_message, content) => CommandResponse::new(
+                request_id.clone(),
+                CommandStatus::Error,
+                error_message,
+                Some(content),
+            ),
         };
 
         trace!(
@@ -1460,6 +2702,14 @@ impl CommandServer {
             command_response
         );
 
+        if command_response.status != CommandStatus::Processing {
+            self.finish_audit_entry(
+                &request_identifier,
+                Some(command_response.status.clone()),
+                &command_response.message,
+            );
+        }
+
         match self.clients.get_mut(&client_id) {
             Some(client_tx) => {
                 trace!("sending from main process to client loop");
@@ -1470,6 +2720,12 @@ impl CommandServer {
                     )
                 })?;
             }
+            None if client_id == MAIN_PROCESS_CLIENT => {
+                debug!(
+                    "request {} of the main process: {:?}",
+                    request_id, command_response
+                );
+            }
             None => bail!(format!("Could not find client {}", client_id)),
         }
 
@@ -1477,6 +2733,111 @@ impl CommandServer {
     }
 }
 
+/// the warning of an order adding something the state already has
+fn existing_target(order: &ProxyRequestOrder) -> Option<String> {
+    match order {
+        ProxyRequestOrder::AddHttpFrontend(front) | ProxyRequestOrder::AddHttpsFrontend(front) => {
+            Some(format!(
+                "a frontend already exists at {} for the hostname {} and the path {}",
+                front.address, front.hostname, front.path
+            ))
+        }
+        ProxyRequestOrder::AddTcpFrontend(front) => Some(format!(
+            "cluster {} already has a TCP frontend at {}",
+            front.cluster_id, front.address
+        )),
+        ProxyRequestOrder::AddSniFrontend(front) => Some(format!(
+            "cluster {} already has a SNI frontend for {} at {}",
+            front.cluster_id, front.hostname, front.address
+        )),
+        ProxyRequestOrder::AddCertificate(add) => Some(format!(
+            "the certificate is already on the listener {}",
+            add.address
+        )),
+        ProxyRequestOrder::AddHttpListener(listener) => {
+            Some(format!("a listener already exists at {}", listener.address))
+        }
+        ProxyRequestOrder::AddHttpsListener(listener) => {
+            Some(format!("a listener already exists at {}", listener.address))
+        }
+        ProxyRequestOrder::AddTcpListener(listener) => {
+            Some(format!("a listener already exists at {}", listener.address))
+        }
+        _ => None,
+    }
+}
+
+/// the warnings of an order routing to, or adding backends to, clusters
+/// missing from the state
+fn unknown_clusters(state: &ConfigState, order: &ProxyRequestOrder) -> Vec<String> {
+    let cluster_ids = match order {
+        ProxyRequestOrder::AddHttpFrontend(front) | ProxyRequestOrder::AddHttpsFrontend(front) => {
+            front.route.cluster_ids()
+        }
+        ProxyRequestOrder::AddTcpFrontend(front) => vec![front.cluster_id.as_str()],
+        ProxyRequestOrder::AddSniFrontend(front) => vec![front.cluster_id.as_str()],
+        ProxyRequestOrder::AddBackend(backend) => vec![backend.cluster_id.as_str()],
+        _ => Vec::new(),
+    };
+
+    cluster_ids
+        .into_iter()
+        .filter(|cluster_id| !state.clusters.contains_key(*cluster_id))
+        .map(|cluster_id| format!("unknown cluster {}", cluster_id))
+        .collect()
+}
+
+/// the error of an order removing or updating something missing from the state
+fn missing_target(order: &ProxyRequestOrder) -> Option<String> {
+    match order {
+        ProxyRequestOrder::RemoveBackend(backend) => Some(format!(
+            "cannot remove backend: cluster {} has no backends {} at {}",
+            backend.cluster_id, backend.backend_id, backend.address,
+        )),
+        ProxyRequestOrder::DrainBackend(backend) => Some(format!(
+            "cannot drain backend: cluster {} has no backends {} at {}",
+            backend.cluster_id, backend.backend_id, backend.address,
+        )),
+        ProxyRequestOrder::UpdateBackendWeight(backend) => Some(format!(
+            "cannot set the backend weight: cluster {} has no backends {} at {}",
+            backend.cluster_id, backend.backend_id, backend.address,
+        )),
+        ProxyRequestOrder::RemoveHttpFrontend(h) | ProxyRequestOrder::RemoveHttpsFrontend(h) => {
+            let msg = match &h.route {
+                Route::ClusterId(cluster_id) => format!(
+                    "No such frontend at {} for the cluster {}",
+                    h.address, cluster_id
+                ),
+                Route::Deny { .. } | Route::Redirect { .. } => {
+                    format!("No such frontend at {}", h.address)
+                }
+                Route::Weighted(_) | Route::AbTest { .. } => format!(
+                    "No such frontend at {} for the clusters {}",
+                    h.address, h.route
+                ),
+            };
+            Some(msg)
+        }
+        ProxyRequestOrder::RemoveTcpFrontend(TcpFrontend {
+            cluster_id,
+            address,
+            tags,
+        }) => Some(format!(
+            "cannot remove TCP frontend: cluster {} has no frontends at {} (custom tags: {:?})",
+            cluster_id, address, tags
+        )),
+        ProxyRequestOrder::RemoveSniFrontend(SniFrontend {
+            cluster_id,
+            address,
+            hostname,
+        }) => Some(format!(
+            "cannot remove SNI frontend: cluster {} has no frontend for {} at {}",
+            cluster_id, hostname, address
+        )),
+        _ => None,
+    }
+}
+
 // Those return functions are meant to be called in detached threads
 // to notify the command server of an order's advancement.
 async fn return_error<T>(
@@ -1497,6 +2858,48 @@ async fn return_error<T>(
     }
 }
 
+async fn return_rejection<T>(
+    mut command_tx: Sender<CommandMessage>,
+    request_identifier: RequestIdentifier,
+    error_message: T,
+    content: CommandResponseContent,
+) where
+    T: ToString,
+{
+    let rejection_command_message = CommandMessage::Advancement {
+        request_identifier,
+        response: Response::Rejected(error_message.to_string(), content),
+    };
+
+    trace!("return_rejection: sending event to the command server");
+    if let Err(e) = command_tx.send(rejection_command_message).await {
+        error!(
+            "Error while returning rejection to the command server: {}",
+            e
+        )
+    }
+}
+
+/// waits for the answers of the new worker of an upgrade, any error fails it
+async fn check_new_worker(
+    mut check_rx: Receiver<(ProxyResponse, u32)>,
+    mut expected_answers: usize,
+) -> Result<(), String> {
+    while expected_answers > 0 {
+        match check_rx.next().await {
+            Some((response, _)) => match response.status {
+                ProxyResponseStatus::Ok => expected_answers -= 1,
+                ProxyResponseStatus::Processing => {}
+                ProxyResponseStatus::Error(message) => {
+                    return Err(format!("refused an order: {}", message));
+                }
+            },
+            None => return Err(String::from("stopped answering")),
+        }
+    }
+    Ok(())
+}
+
 async fn return_processing<T>(
     mut command_tx: Sender<CommandMessage>,
     request_identifier: RequestIdentifier,
@@ -1518,6 +2921,72 @@ async fn return_processing<T>(
     }
 }
 
+/// how often the progress of a long operation is sent to the client, on top
+/// of a message each time a worker answered all its orders
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// counts the answers of the workers to the orders of a long operation, and
+/// sends its progress to the client. Returns the number of ok and error answers
+async fn collect_worker_answers(
+    mut answers_rx: Receiver<(ProxyResponse, u32)>,
+    mut progress: Progress,
+    command_tx: Sender<CommandMessage>,
+    request_identifier: Option<RequestIdentifier>,
+) -> (usize, usize) {
+    let mut ok = 0usize;
+    let mut error = 0usize;
+    let mut last_progress = Instant::now();
+
+    while let Some((proxy_response, worker_id)) = answers_rx.next().await {
+        let worker_done = match proxy_response.status {
+            ProxyResponseStatus::Ok => {
+                ok += 1;
+                progress.record(worker_id, true)
+            }
+            ProxyResponseStatus::Processing => false,
+            ProxyResponseStatus::Error(message) => {
+                error!("{}", message);
+                error += 1;
+                progress.record(worker_id, false)
+            }
+        };
+        debug!("ok:{}, error: {}", ok, error);
+
+        if let Some(request_identifier) = &request_identifier {
+            if worker_done || last_progress.elapsed() >= PROGRESS_INTERVAL {
+                return_progress(
+                    command_tx.clone(),
+                    request_identifier.clone(),
+                    progress.clone(),
+                )
+                .await;
+                last_progress = Instant::now();
+            }
+        }
+    }
+
+    (ok, error)
+}
+
+async fn return_progress(
+    mut command_tx: Sender<CommandMessage>,
+    request_identifier: RequestIdentifier,
+    progress: Progress,
+) {
+    let progress_command_message = CommandMessage::Advancement {
+        request_identifier,
+        response: Response::Progress(progress),
+    };
+
+    trace!("return_progress: sending event to the command server");
+    if let Err(e) = command_tx.send(progress_command_message).await {
+        error!(
+            "Error while returning progress to the command server: {}",
+            e
+        )
+    }
+}
+
 async fn return_success(
     mut command_tx: Sender<CommandMessage>,
     request_identifier: RequestIdentifier,
@@ -1532,3 +3001,431 @@ async fn return_success(
         error!("Error while returning success to the command server: {}", e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::access::PeerCredentials;
+    use nix::unistd::{Gid, Uid};
+    use sozu_command_lib::{
+        command::ConfigurationChange,
+        proxy::{
+            ActivateListener, AddCertificate, CertificateAndKey, Cluster, CrashReport,
+            HttpFrontend, HttpListener, PathRule, RemoveBackend, RulePosition,
+        },
+        scm_socket::ScmSocket,
+    };
+
+    /// a command server with one worker, and the orders this worker receives
+    fn command_server(state: ConfigState) -> (CommandServer, Receiver<ProxyRequest>) {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(&config_path, "").unwrap();
+        let config = Config::load_from_path(&config_path.to_string_lossy()).unwrap();
+
+        let (command_tx, command_rx) = channel(10);
+        let (accept_cancel, _) = futures::channel::oneshot::channel();
+        let mut server = CommandServer::new(
+            -1,
+            config,
+            command_tx,
+            command_rx,
+            Vec::new(),
+            state,
+            accept_cancel,
+        )
+        .unwrap();
+
+        let (scm, _) = UnixStream::pair().unwrap();
+        let (worker_tx, worker_rx) = channel(10);
+        server.workers.push(Worker {
+            id: 0,
+            worker_channel: None,
+            worker_channel_fd: -1,
+            pid: std::process::id() as i32,
+            run_state: RunState::Running,
+            queue: Default::default(),
+            scm_socket: ScmSocket::new(scm.into_raw_fd()),
+            sender: Some(worker_tx),
+            cpu_cores: Vec::new(),
+        });
+        (server, worker_rx)
+    }
+
+    fn frontend(cluster_id: &str, address: &str) -> HttpFrontend {
+        HttpFrontend {
+            route: Route::ClusterId(String::from(cluster_id)),
+            hostname: String::from("lolcatho.st"),
+            path: PathRule::Prefix(String::from("/")),
+            method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
+            address: address.parse().unwrap(),
+            position: RulePosition::Tree,
+            tags: None,
+        }
+    }
+
+    fn state() -> ConfigState {
+        let mut state = ConfigState::new();
+        state.handle_order(&ProxyRequestOrder::AddCluster(Cluster {
+            cluster_id: String::from("app"),
+            ..Default::default()
+        }));
+        state.handle_order(&ProxyRequestOrder::AddHttpFrontend(frontend(
+            "app",
+            "127.0.0.1:8080",
+        )));
+        state
+    }
+
+    fn dry_run(server: &mut CommandServer, order: ProxyRequestOrder) -> anyhow::Result<DryRun> {
+        let result = future::block_on(server.worker_order(
+            RequestIdentifier::new("CL-0", "ID-0"),
+            order,
+            None,
+            Vec::new(),
+            false,
+            true,
+        ))?;
+        match result {
+            Some(Success::DryRun(CommandResponseContent::DryRun(dry_run))) => Ok(dry_run),
+            other => panic!("expected a dry run, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn dry_run_duplicate_frontend() {
+        let (mut server, _) = command_server(state());
+
+        let dry_run = dry_run(
+            &mut server,
+            ProxyRequestOrder::AddHttpFrontend(frontend("app", "127.0.0.1:8080")),
+        )
+        .unwrap();
+        assert!(dry_run.orders.is_empty());
+        assert_eq!(
+            dry_run.warnings,
+            vec!["a frontend already exists at 127.0.0.1:8080 for the hostname lolcatho.st and the path prefix '/'"]
+        );
+    }
+
+    #[test]
+    fn dry_run_unknown_cluster() {
+        let (mut server, _) = command_server(state());
+
+        let order = ProxyRequestOrder::AddHttpFrontend(frontend("unknown", "127.0.0.1:8081"));
+        let dry_run = dry_run(&mut server, order.clone()).unwrap();
+        assert_eq!(dry_run.orders, vec![order]);
+        assert_eq!(dry_run.warnings, vec!["unknown cluster unknown"]);
+    }
+
+    #[test]
+    fn dry_run_missing_target() {
+        let (mut server, _) = command_server(state());
+
+        let error = dry_run(
+            &mut server,
+            ProxyRequestOrder::RemoveBackend(RemoveBackend {
+                cluster_id: String::from("app"),
+                backend_id: String::from("app-0"),
+                address: "127.0.0.1:1026".parse().unwrap(),
+            }),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "cannot remove backend: cluster app has no backends app-0 at 127.0.0.1:1026"
+        );
+    }
+
+    #[test]
+    fn dry_run_certificate_mismatch() {
+        let (mut server, _) = command_server(state());
+
+        let order = ProxyRequestOrder::AddHttpsFrontend(frontend("app", "127.0.0.1:8443"));
+        let dry_run = dry_run(&mut server, order.clone()).unwrap();
+        assert_eq!(dry_run.orders, vec![order]);
+        assert_eq!(
+            dry_run.warnings,
+            vec!["no certificate of the listener 127.0.0.1:8443 covers the hostname lolcatho.st"]
+        );
+    }
+
+    #[test]
+    fn dry_run_changes_nothing() {
+        let (mut server, mut worker_rx) = command_server(state());
+
+        let add_cluster = ProxyRequestOrder::AddCluster(Cluster {
+            cluster_id: String::from("api"),
+            ..Default::default()
+        });
+        let dry_run = dry_run(&mut server, add_cluster.clone()).unwrap();
+        assert_eq!(dry_run.orders, vec![add_cluster]);
+
+        let mut desired = state();
+        desired.handle_order(&ProxyRequestOrder::AddHttpFrontend(frontend(
+            "unknown",
+            "127.0.0.1:8081",
+        )));
+        let applied = future::block_on(server.apply_state(
+            RequestIdentifier::new("CL-0", "ID-1"),
+            desired,
+            false,
+            true,
+        ))
+        .unwrap();
+        assert_eq!(
+            applied,
+            Some(Success::DryRun(CommandResponseContent::DryRun(DryRun {
+                orders: vec![ProxyRequestOrder::AddHttpFrontend(frontend(
+                    "unknown",
+                    "127.0.0.1:8081"
+                ))],
+                warnings: vec![String::from("unknown cluster unknown")],
+            })))
+        );
+
+        assert_eq!(server.state, state());
+        assert!(server.in_flight.is_empty());
+        assert!(worker_rx.try_next().is_err());
+    }
+
+    /// subscribes a client to the events, and returns what it receives
+    fn subscribe(server: &mut CommandServer) -> Receiver<CommandResponse> {
+        let (client_tx, client_rx) = channel(10);
+        server.clients.insert(String::from("CL-0"), client_tx);
+        server.event_subscribers.insert(String::from("CL-0"));
+        client_rx
+    }
+
+    fn events(client_rx: &mut Receiver<CommandResponse>) -> Vec<(String, Event)> {
+        let mut events = Vec::new();
+        while let Ok(Some(response)) = client_rx.try_next() {
+            match response.content {
+                Some(CommandResponseContent::Event(event)) => events.push((response.id, event)),
+                content => panic!("expected an event, got {:?}", content),
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn configuration_change_events() {
+        let (mut server, _) = command_server(ConfigState::new());
+        let mut client_rx = subscribe(&mut server);
+        future::block_on(server.record_state_version("INITIALIZATION", None));
+        events(&mut client_rx);
+
+        let peer = PeerCredentials {
+            uid: Uid::current(),
+            gid: Gid::current(),
+        };
+        server.client_peers.insert(String::from("CL-0"), peer);
+        let address = "127.0.0.1:8080".parse().unwrap();
+        server
+            .state
+            .handle_order(&ProxyRequestOrder::AddHttpListener(HttpListener {
+                address,
+                ..Default::default()
+            }));
+        server
+            .state
+            .handle_order(&ProxyRequestOrder::ActivateListener(ActivateListener {
+                address,
+                proxy: ListenerType::HTTP,
+                from_scm: false,
+            }));
+        server
+            .state
+            .handle_order(&ProxyRequestOrder::AddCluster(Cluster {
+                cluster_id: String::from("app"),
+                ..Default::default()
+            }));
+        future::block_on(server.record_state_version("ID-1", Some("CL-0")));
+
+        assert_eq!(
+            events(&mut client_rx),
+            vec![
+                (
+                    String::from("VERSION-2"),
+                    Event::ConfigurationChanged(ConfigurationChange {
+                        version: 2,
+                        request_id: String::from("ID-1"),
+                        client: Some(String::from("CL-0")),
+                        user: Some(access::user_name(&peer)),
+                        orders: BTreeMap::from([
+                            (String::from("ACTIVATE_LISTENER"), 1),
+                            (String::from("ADD_CLUSTER"), 1),
+                            (String::from("ADD_HTTP_LISTENER"), 1),
+                        ]),
+                    })
+                ),
+                (
+                    String::from("VERSION-2"),
+                    Event::ListenerActivated(address, ListenerType::HTTP)
+                ),
+            ]
+        );
+
+        // the same state is not a new version
+        future::block_on(server.record_state_version("ID-2", Some("CL-0")));
+        assert!(events(&mut client_rx).is_empty());
+    }
+
+    #[test]
+    fn worker_lifecycle_events() {
+        let (mut server, _worker_rx) = command_server(ConfigState::new());
+        let mut client_rx = subscribe(&mut server);
+
+        // the worker misses `worker_heartbeat_misses` heartbeats after the first one
+        for _ in 0..server.config.worker_heartbeat_misses {
+            future::block_on(server.check_heartbeats());
+        }
+        assert!(events(&mut client_rx).is_empty());
+        future::block_on(server.check_heartbeats());
+        assert_eq!(
+            events(&mut client_rx),
+            vec![(String::from("WORKER-0"), Event::WorkerNotAnswering(0))]
+        );
+
+        // the worker stops without being asked to, after a panic
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        let pid = child.id() as i32;
+        server.workers[0].pid = pid;
+        server.config.worker_automatic_restart = false;
+        let report = CrashReport {
+            message: String::from("attempt to subtract with overflow"),
+            location: Some(String::from("lib/src/buffer_queue.rs:42:9")),
+            backtrace: String::new(),
+            sessions: 3,
+            last_order: None,
+        };
+        server.crash_reports.insert(0, report.clone());
+        future::block_on(server.handle_worker_close(0)).unwrap();
+        assert_eq!(
+            events(&mut client_rx),
+            vec![(
+                String::from("WORKER-0"),
+                Event::WorkerCrashed(0, pid, Some(report))
+            )]
+        );
+
+        // a stopped worker did not crash
+        future::block_on(server.handle_worker_close(0)).unwrap();
+        assert!(events(&mut client_rx).is_empty());
+    }
+
+    #[test]
+    fn reload_on_sighup() {
+        let (mut server, mut worker_rx) = command_server(ConfigState::new());
+        let mut client_rx = subscribe(&mut server);
+        future::block_on(server.record_state_version("INITIALIZATION", None));
+        events(&mut client_rx);
+
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(
+            &config_path,
+            "[clusters.app]\nprotocol = \"tcp\"\nfrontends = []\nbackends = []\n",
+        )
+        .unwrap();
+        server.config.config_path = config_path.to_string_lossy().to_string();
+
+        let success = future::block_on(server.reload_on_signal()).unwrap();
+        assert_eq!(
+            success.to_string(),
+            "Reloading the configuration on SIGHUP, reload 1"
+        );
+        assert!(server.state.clusters.contains_key("app"));
+
+        let order = worker_rx.try_next().unwrap().unwrap();
+        assert_eq!(order.id, "LOAD-STATE-SIGHUP-1-1-0");
+        assert!(matches!(
+            order.order,
+            ProxyRequestOrder::AddCluster(Cluster { ref cluster_id, .. }) if cluster_id == "app"
+        ));
+        assert!(worker_rx.try_next().is_err());
+
+        // there is no client to answer, the change is only published
+        match &events(&mut client_rx)[..] {
+            [(_, Event::ConfigurationChanged(change))] => {
+                assert_eq!(change.request_id, "SIGHUP-1");
+                assert_eq!(change.client, None);
+            }
+            events => panic!("expected a configuration change, got {:?}", events),
+        }
+
+        // each reload identifies its own orders, an unchanged state is not a new version
+        let success = future::block_on(server.reload_on_signal()).unwrap();
+        assert_eq!(
+            success.to_string(),
+            "Reloading the configuration on SIGHUP, reload 2"
+        );
+        let order = worker_rx.try_next().unwrap().unwrap();
+        assert_eq!(order.id, "LOAD-STATE-SIGHUP-2-1-0");
+        assert!(events(&mut client_rx).is_empty());
+    }
+
+    #[test]
+    fn list_certificates_of_a_listener() {
+        let mut state = state();
+        for (address, names) in [
+            ("127.0.0.1:8443", vec![]),
+            ("127.0.0.1:8444", vec![String::from("other.example")]),
+        ] {
+            state.handle_order(&ProxyRequestOrder::AddCertificate(AddCertificate {
+                address: address.parse().unwrap(),
+                certificate: CertificateAndKey {
+                    certificate: String::from(include_str!(
+                        "../../../command/assets/certificate.pem"
+                    )),
+                    certificate_chain: vec![],
+                    key: String::from(include_str!("../../../command/assets/key.pem")),
+                    versions: vec![],
+                    ocsp_response: None,
+                    priority: 0,
+                },
+                names,
+                expired_at: None,
+            }));
+        }
+        state.handle_order(&ProxyRequestOrder::AddHttpsFrontend(frontend(
+            "app",
+            "127.0.0.1:8443",
+        )));
+        let (mut server, _) = command_server(state);
+
+        let mut list = |address: Option<&str>| match future::block_on(server.list_certificates(
+            CertificateFilters {
+                domain: None,
+                expiring_within: None,
+                address: address.map(|address| address.parse().unwrap()),
+            },
+        )) {
+            Ok(Some(Success::ListCertificates(CommandResponseContent::CertificateList(
+                certificates,
+            )))) => certificates,
+            other => panic!("expected a list of certificates, got {:?}", other),
+        };
+
+        assert_eq!(list(None).len(), 2);
+
+        let certificates = list(Some("127.0.0.1:8443"));
+        assert_eq!(certificates.len(), 1);
+        assert_eq!(certificates[0].address, "127.0.0.1:8443".parse().unwrap());
+        assert!(certificates[0].subject.contains("CN=lolcatho.st"));
+        assert_eq!(certificates[0].hostnames, vec![String::from("lolcatho.st")]);
+
+        // the frontend of the other listener is not covered by its certificate
+        let certificates = list(Some("127.0.0.1:8444"));
+        assert_eq!(certificates[0].names, vec![String::from("other.example")]);
+        assert!(certificates[0].hostnames.is_empty());
+
+        assert!(list(Some("127.0.0.1:8445")).is_empty());
+    }
+}
@@ -0,0 +1,447 @@
+//! the changes of the state shared with the other main processes of an
+//! active-active group.
+//!
+//! Each change made by a client is sent to the remote command channel of the
+//! peers, with the version of the group state it applies to. A peer at another
+//! version refuses it: it missed a change, or made one at the same time. The
+//! instance whose change was refused then sends its whole state to the peer,
+//! that keeps the most recent one, or the one of the first name on a tie. The
+//! client that made the change gets its answer once the peers answered.
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{bail, Context};
+use futures::{
+    channel::mpsc::{channel, Sender},
+    SinkExt, StreamExt,
+};
+use mio::net::UnixStream;
+
+use sozu_command_lib::{
+    channel::Channel,
+    command::{
+        CommandRequest, CommandRequestOrder, CommandResponse, CommandStatus, PeerChange, PeerState,
+    },
+    config::{Config, PeerConfig},
+    proxy::ProxyRequestOrder,
+    state::ConfigState,
+};
+
+use crate::{
+    command::{CommandMessage, RequestIdentifier, Response},
+    remote,
+};
+
+/// number of changes waiting for a peer before dropping the next ones
+const PEER_QUEUE_SIZE: usize = 1000;
+/// duration to wait for a peer to apply a change
+const PEER_ANSWER_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Peering {
+    /// the name of this instance, sent with its changes
+    pub name: String,
+    /// the version of the group state, increased by every change
+    pub version: u64,
+    /// the address of each peer, with the queue of its orders
+    peers: Vec<(String, Sender<PeerOrder>)>,
+    /// the changes of the clients waiting for the answers of the peers
+    shared_changes: HashMap<RequestIdentifier, SharedChange>,
+}
+
+/// an order for a peer, with the request of the client that made the change
+struct PeerOrder {
+    order: CommandRequestOrder,
+    request: Option<RequestIdentifier>,
+}
+
+/// what a peer did with an order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerAnswer {
+    Applied,
+    /// the peer refused the order, because of a conflict
+    Refused(String),
+    /// the order could not be sent, or the peer did not answer
+    Unreachable(String),
+}
+
+/// a change of a client sent to the peers, with the response to the client
+/// once the workers applied it, sent when all the peers answered
+#[derive(Default)]
+struct SharedChange {
+    waiting: usize,
+    failures: Vec<String>,
+    response: Option<Response>,
+}
+
+impl SharedChange {
+    /// the response to the client, an error if a peer did not apply the change
+    fn response(self, response: Response) -> Response {
+        if self.failures.is_empty() {
+            return response;
+        }
+        let failures = self.failures.join(", ");
+        match response {
+            Response::Ok(success) => Response::Error(format!(
+                "{}, but the peers did not all apply the change: {}",
+                success, failures
+            )),
+            Response::Error(message) => {
+                Response::Error(format!("{}, and the peers: {}", message, failures))
+            }
+            response => response,
+        }
+    }
+}
+
+impl Peering {
+    pub fn new(config: &Config, version: u64, command_tx: Sender<CommandMessage>) -> Option<Self> {
+        let peering = config.peering.as_ref()?;
+        let peers = peering
+            .peers
+            .iter()
+            .map(|peer| {
+                (
+                    peer.address.clone(),
+                    spawn_peer(
+                        peer.clone(),
+                        config.command_buffer_size,
+                        config.max_command_buffer_size,
+                        command_tx.clone(),
+                    ),
+                )
+            })
+            .collect();
+
+        Some(Peering {
+            name: peering.name.clone(),
+            version,
+            peers,
+            shared_changes: HashMap::new(),
+        })
+    }
+
+    /// sends the orders of a change made by a client of this instance to the
+    /// peers, as the next version of the group state
+    pub fn share_change(&mut self, orders: Vec<ProxyRequestOrder>, request: RequestIdentifier) {
+        let change = PeerChange {
+            origin: self.name.clone(),
+            base_version: self.version,
+            orders,
+        };
+        self.version += 1;
+
+        let mut shared_change = SharedChange::default();
+        for (address, peer) in self.peers.iter_mut() {
+            let order = PeerOrder {
+                order: CommandRequestOrder::PeerChange(change.clone()),
+                request: Some(request.clone()),
+            };
+            match peer.try_send(order) {
+                Ok(()) => shared_change.waiting += 1,
+                Err(e) => {
+                    incr!("peering.error");
+                    error!("could not queue the change for the peer {}: {}", address, e);
+                    shared_change
+                        .failures
+                        .push(format!("{}: the queue is full", address));
+                }
+            }
+        }
+        self.shared_changes.insert(request, shared_change);
+    }
+
+    /// sends the whole state to the peers. Forced, they replace theirs with
+    /// it, otherwise they keep the most recent one. Returns the number of peers
+    pub fn share_state(&mut self, state: &ConfigState, forced: bool) -> usize {
+        let state = self.peer_state(state, forced);
+        for (address, peer) in self.peers.iter_mut() {
+            queue_state(address, peer, state.clone());
+        }
+        self.peers.len()
+    }
+
+    /// sends the whole state to this peer, that keeps the most recent one
+    pub fn share_state_with(&mut self, address: &str, state: &ConfigState) {
+        let state = self.peer_state(state, false);
+        if let Some((address, peer)) = self.peers.iter_mut().find(|(a, _)| a == address) {
+            queue_state(address, peer, state);
+        }
+    }
+
+    fn peer_state(&self, state: &ConfigState, forced: bool) -> PeerState {
+        PeerState {
+            origin: self.name.clone(),
+            version: self.version,
+            state: Box::new(state.clone()),
+            forced,
+        }
+    }
+
+    /// true if the state of this instance is kept over the one of the peer:
+    /// it is more recent, or of the same version and of the first name
+    pub fn wins_over(&self, peer_state: &PeerState) -> bool {
+        (self.version, peer_state.origin.as_str()) > (peer_state.version, self.name.as_str())
+    }
+
+    /// the final response to a client, or None if it waits for the peers
+    pub fn hold_response(
+        &mut self,
+        request: &RequestIdentifier,
+        response: Response,
+    ) -> Option<Response> {
+        if matches!(response, Response::Processing(_) | Response::Progress(_)) {
+            return Some(response);
+        }
+        match self.shared_changes.get_mut(request) {
+            None => Some(response),
+            Some(shared_change) if shared_change.waiting > 0 => {
+                shared_change.response = Some(response);
+                None
+            }
+            Some(_) => self
+                .shared_changes
+                .remove(request)
+                .map(|shared_change| shared_change.response(response)),
+        }
+    }
+
+    /// counts the answer of a peer to a change, returns the final response to
+    /// the client if it was the last one awaited
+    pub fn answered(
+        &mut self,
+        request: &RequestIdentifier,
+        address: &str,
+        answer: &PeerAnswer,
+    ) -> Option<Response> {
+        let shared_change = self.shared_changes.get_mut(request)?;
+        shared_change.waiting = shared_change.waiting.saturating_sub(1);
+        match answer {
+            PeerAnswer::Applied => {}
+            PeerAnswer::Refused(message) | PeerAnswer::Unreachable(message) => shared_change
+                .failures
+                .push(format!("{}: {}", address, message)),
+        }
+
+        if shared_change.waiting > 0 || shared_change.response.is_none() {
+            return None;
+        }
+        let mut shared_change = self.shared_changes.remove(request)?;
+        let response = shared_change.response.take()?;
+        Some(shared_change.response(response))
+    }
+}
+
+fn queue_state(address: &str, peer: &mut Sender<PeerOrder>, state: PeerState) {
+    let order = PeerOrder {
+        order: CommandRequestOrder::PeerState(state),
+        request: None,
+    };
+    if let Err(e) = peer.try_send(order) {
+        incr!("peering.error");
+        error!("could not queue the state for the peer {}: {}", address, e);
+    }
+}
+
+/// sends the orders of the queue to the peer one by one, in a task, and gives
+/// its answers to the command server
+fn spawn_peer(
+    peer: PeerConfig,
+    buffer_size: usize,
+    max_buffer_size: usize,
+    mut command_tx: Sender<CommandMessage>,
+) -> Sender<PeerOrder> {
+    let (peer_tx, mut peer_rx) = channel::<PeerOrder>(PEER_QUEUE_SIZE);
+
+    smol::spawn(async move {
+        let mut connection = None;
+        while let Some(PeerOrder { order, request }) = peer_rx.next().await {
+            let config = peer.clone();
+            let is_change = matches!(order, CommandRequestOrder::PeerChange(_));
+            let (returned, result) = smol::unblock(move || {
+                let reused = connection.is_some();
+                let mut connection = connection;
+                let mut result = send_order(
+                    &mut connection,
+                    &config,
+                    order.clone(),
+                    buffer_size,
+                    max_buffer_size,
+                );
+                // the peer closes the connection when it restarts. Sending the
+                // change twice is safe, the second one would be a conflict
+                if result.is_err() && reused {
+                    connection = None;
+                    result = send_order(
+                        &mut connection,
+                        &config,
+                        order,
+                        buffer_size,
+                        max_buffer_size,
+                    );
+                }
+                (connection, result)
+            })
+            .await;
+
+            connection = returned;
+            let answer = match result {
+                Ok(answer) => answer,
+                Err(e) => {
+                    incr!("peering.error");
+                    error!(
+                        "could not share the change with the peer {}: {:#}",
+                        peer.address, e
+                    );
+                    // opened again for the next change
+                    connection = None;
+                    PeerAnswer::Unreachable(format!("{:#}", e))
+                }
+            };
+
+            let message = CommandMessage::PeerAnswered {
+                address: peer.address.clone(),
+                request,
+                is_change,
+                answer,
+            };
+            if let Err(e) = command_tx.send(message).await {
+                error!(
+                    "could not give the answer of the peer {}: {}",
+                    peer.address, e
+                );
+            }
+        }
+    })
+    .detach();
+
+    peer_tx
+}
+
+/// sends the order on the channel to the peer, opened if needed, and waits
+/// for its answer. The errors are the ones of the channel
+fn send_order(
+    connection: &mut Option<Channel<CommandRequest, CommandResponse>>,
+    peer: &PeerConfig,
+    order: CommandRequestOrder,
+    buffer_size: usize,
+    max_buffer_size: usize,
+) -> anyhow::Result<PeerAnswer> {
+    let channel = match connection {
+        Some(channel) => channel,
+        None => {
+            let stream = remote::connect(&peer.address, &peer.token, peer.ca.as_deref())?;
+            let mut channel =
+                Channel::new(UnixStream::from_std(stream), buffer_size, max_buffer_size);
+            channel.blocking();
+            connection.insert(channel)
+        }
+    };
+
+    let id = match &order {
+        CommandRequestOrder::PeerChange(change) => {
+            format!("PEER-{}-{}", change.origin, change.base_version + 1)
+        }
+        CommandRequestOrder::PeerState(state) => {
+            format!("PEER-STATE-{}-{}", state.origin, state.version)
+        }
+        _ => bail!("only the peering orders are sent to the peers"),
+    };
+    if !channel.write_message(&CommandRequest::new(id.clone(), order, None)) {
+        bail!("could not send the order {}", id);
+    }
+
+    loop {
+        let response = channel
+            .read_message_blocking_timeout(Some(PEER_ANSWER_TIMEOUT))
+            .with_context(|| format!("no answer to the order {}", id))?;
+        if response.id != id {
+            continue;
+        }
+        match response.status {
+            CommandStatus::Processing => {}
+            CommandStatus::Ok => {
+                debug!("the peer {} applied the order {}", peer.address, id);
+                return Ok(PeerAnswer::Applied);
+            }
+            CommandStatus::Error => {
+                incr!("peering.refused");
+                error!(
+                    "the peer {} refused the order {}: {}",
+                    peer.address, id, response.message
+                );
+                return Ok(PeerAnswer::Refused(response.message));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::Success;
+
+    fn peering(peers: &[&str]) -> (Peering, Vec<futures::channel::mpsc::Receiver<PeerOrder>>) {
+        let (peers, receivers) = peers
+            .iter()
+            .map(|address| {
+                let (sender, receiver) = channel(10);
+                ((address.to_string(), sender), receiver)
+            })
+            .unzip();
+        let peering = Peering {
+            name: String::from("sozu-b"),
+            version: 3,
+            peers,
+            shared_changes: HashMap::new(),
+        };
+        (peering, receivers)
+    }
+
+    #[test]
+    fn responses_wait_for_the_peers() {
+        let (mut peering, _receivers) = peering(&["sozu-a:4242", "sozu-c:4242"]);
+        let request = RequestIdentifier::new("CL-1", "ID-1");
+        let ok = || Response::Ok(Success::SavedStateFile(1));
+
+        peering.share_change(Vec::new(), request.clone());
+        assert_eq!(peering.version, 4);
+        assert_eq!(peering.hold_response(&request, ok()), None);
+        assert_eq!(
+            peering.answered(&request, "sozu-a:4242", &PeerAnswer::Applied),
+            None
+        );
+        assert_eq!(
+            peering.answered(&request, "sozu-c:4242", &PeerAnswer::Applied),
+            Some(ok())
+        );
+
+        // the peers can answer before the workers
+        peering.share_change(Vec::new(), request.clone());
+        let refused = PeerAnswer::Refused(String::from("conflict"));
+        assert_eq!(peering.answered(&request, "sozu-a:4242", &refused), None);
+        assert_eq!(
+            peering.answered(&request, "sozu-c:4242", &PeerAnswer::Applied),
+            None
+        );
+        assert!(matches!(
+            peering.hold_response(&request, ok()),
+            Some(Response::Error(message)) if message.contains("sozu-a:4242: conflict")
+        ));
+        assert_eq!(peering.hold_response(&request, ok()), Some(ok()));
+    }
+
+    #[test]
+    fn the_most_recent_state_wins() {
+        let (peering, _receivers) = peering(&[]);
+        let state = |origin: &str, version| PeerState {
+            origin: origin.to_owned(),
+            version,
+            state: Box::default(),
+            forced: false,
+        };
+
+        assert!(peering.wins_over(&state("sozu-a", 2)));
+        assert!(!peering.wins_over(&state("sozu-a", 4)));
+        assert!(!peering.wins_over(&state("sozu-a", 3)));
+        assert!(peering.wins_over(&state("sozu-c", 3)));
+    }
+}
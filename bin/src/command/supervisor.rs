@@ -0,0 +1,96 @@
+//! the restart policy of the crashed workers: the restarts are delayed by an
+//! exponential backoff, and stop past a number of crashes in a time window
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use sozu_command_lib::config::Config;
+
+/// the longest delay before restarting a crashed worker
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrashSupervisor {
+    /// the crashes in the window, the oldest first
+    crashes: VecDeque<Instant>,
+    delay: Duration,
+    max_restarts: u32,
+    window: Duration,
+}
+
+impl CrashSupervisor {
+    pub fn new(config: &Config) -> Self {
+        CrashSupervisor {
+            crashes: VecDeque::new(),
+            delay: Duration::from_millis(config.worker_restart_delay),
+            max_restarts: config.worker_max_restarts,
+            window: Duration::from_secs(config.worker_restart_window),
+        }
+    }
+
+    /// records a crash, and returns the delay before restarting the worker,
+    /// or none if there were too many crashes in the window
+    pub fn record_crash(&mut self, now: Instant) -> Option<Duration> {
+        while let Some(crash) = self.crashes.front() {
+            if now.saturating_duration_since(*crash) < self.window {
+                break;
+            }
+            self.crashes.pop_front();
+        }
+        self.crashes.push_back(now);
+
+        let crashes = self.crashes.len() as u32;
+        if crashes > self.max_restarts {
+            return None;
+        }
+
+        let factor = 2u32.saturating_pow(crashes - 1);
+        Some(self.delay.saturating_mul(factor).min(MAX_RESTART_DELAY))
+    }
+
+    /// number of crashes in the window
+    pub fn crashes(&self) -> u32 {
+        self.crashes.len() as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restarts_back_off_then_stop() {
+        let mut supervisor = CrashSupervisor {
+            crashes: VecDeque::new(),
+            delay: Duration::from_millis(500),
+            max_restarts: 3,
+            window: Duration::from_secs(60),
+        };
+        let start = Instant::now();
+
+        assert_eq!(
+            supervisor.record_crash(start),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            supervisor.record_crash(start + Duration::from_secs(1)),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(
+            supervisor.record_crash(start + Duration::from_secs(2)),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            supervisor.record_crash(start + Duration::from_secs(3)),
+            None
+        );
+        assert_eq!(supervisor.crashes(), 4);
+
+        // the first crashes left the window
+        assert_eq!(
+            supervisor.record_crash(start + Duration::from_secs(62)),
+            Some(Duration::from_secs(1))
+        );
+    }
+}
@@ -1,4 +1,8 @@
-use std::{collections::VecDeque, fmt, os::unix::io::AsRawFd};
+use std::{
+    collections::VecDeque,
+    fmt,
+    os::unix::io::{AsRawFd, RawFd},
+};
 
 use futures::SinkExt;
 use libc::pid_t;
@@ -8,10 +12,12 @@ use sozu_command_lib::{
     channel::Channel,
     command::{RunState, WorkerInfo},
     config::Config,
-    proxy::{ProxyRequest, ProxyRequestOrder, ProxyResponse},
-    scm_socket::ScmSocket,
+    proxy::{ActivateListener, ListenerType, ProxyRequest, ProxyRequestOrder, ProxyResponse},
+    scm_socket::{Listeners, ScmSocket},
 };
 
+use crate::worker::bind_listener;
+
 pub struct Worker {
     pub id: u32,
     /// for the worker to receive requests and respond to the main process
@@ -24,6 +30,8 @@ pub struct Worker {
     /// used to receive listeners
     pub scm_socket: ScmSocket,
     pub sender: Option<futures::channel::mpsc::Sender<ProxyRequest>>,
+    /// the CPU cores the worker is pinned to, all of them if empty
+    pub cpu_cores: Vec<usize>,
 }
 
 impl Worker {
@@ -43,6 +51,7 @@ impl Worker {
             run_state: RunState::Running,
             queue: VecDeque::new(),
             scm_socket,
+            cpu_cores: Vec::new(),
         }
     }
 
@@ -63,16 +72,70 @@ impl Worker {
         }
     }
 
+    /// the order for this worker. The listeners it activates get a socket
+    /// bound for this worker by the main process, the worker binds them
+    /// itself if that fails
+    pub fn with_listener(&self, order: ProxyRequestOrder) -> ProxyRequestOrder {
+        match order {
+            ProxyRequestOrder::ActivateListener(mut activate) if !activate.from_scm => {
+                if let Some(fd) = bind_listener(activate.address) {
+                    self.hand_over_listener(&mut activate, fd);
+                }
+                ProxyRequestOrder::ActivateListener(activate)
+            }
+            order => order,
+        }
+    }
+
+    /// sends the socket of a listener on the scm socket, for the order
+    /// activating it, that waits for it. The socket is closed here
+    pub fn hand_over_listener(&self, activate: &mut ActivateListener, fd: RawFd) {
+        let mut listeners = Listeners {
+            http: Vec::new(),
+            tls: Vec::new(),
+            tcp: Vec::new(),
+        };
+        match activate.proxy {
+            ListenerType::HTTP => listeners.http.push((activate.address, fd)),
+            ListenerType::HTTPS => listeners.tls.push((activate.address, fd)),
+            ListenerType::TCP => listeners.tcp.push((activate.address, fd)),
+        }
+
+        match self.scm_socket.send_listeners(&listeners) {
+            Ok(()) => activate.from_scm = true,
+            Err(e) => error!(
+                "could not send the listener {} to worker {}: {}",
+                activate.address, self.id, e
+            ),
+        }
+        listeners.close();
+    }
+
     pub fn the_pid_is_alive(&self) -> bool {
         // send a kill -0 to check on the pid, if it's dead it should be an error
         kill(Pid::from_raw(self.pid), None).is_ok()
     }
 
+    /// the resident memory of the worker process in bytes, from /proc
+    #[cfg(target_os = "linux")]
+    pub fn resident_memory(&self) -> Option<u64> {
+        let statm = std::fs::read_to_string(format!("/proc/{}/statm", self.pid)).ok()?;
+        let pages = statm.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        Some(pages * page_size.max(0) as u64)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn resident_memory(&self) -> Option<u64> {
+        None
+    }
+
     pub fn info(&self) -> WorkerInfo {
         WorkerInfo {
             id: self.id,
             pid: self.pid,
             run_state: self.run_state,
+            cpu_cores: self.cpu_cores.clone(),
         }
     }
 
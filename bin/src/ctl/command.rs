@@ -1,3 +1,9 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
 use anyhow::{self, bail, Context};
 use prettytable::Table;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
@@ -5,22 +11,27 @@ use serde::Serialize;
 
 use sozu_command_lib::{
     command::{
-        CommandRequest, CommandRequestOrder, CommandResponse, CommandResponseContent,
-        CommandStatus, FrontendFilters, RunState, WorkerInfo,
+        CertificateFilters, CommandRequest, CommandRequestOrder, CommandResponse,
+        CommandResponseContent, CommandStatus, FrontendFilters, Progress, RunState, WorkerInfo,
     },
+    config::{Config, FileConfig},
     proxy::{
-        MetricsConfiguration, ProxyRequestOrder, Query, QueryCertificateType, QueryClusterDomain,
-        QueryClusterType, QueryMetricsOptions,
+        AccessLogFilter, MetricsConfiguration, ProxyRequestOrder, Query, QueryCertificateResolve,
+        QueryCertificateType, QueryClusterDomain, QueryClusterType, QueryMetricsOptions,
     },
+    state::ConfigState,
 };
 
 use crate::{
-    cli::MetricsCmd,
+    cli::{MetricsCmd, OutputArgs, OutputFormat},
     ctl::{
         create_channel,
         display::{
-            print_available_metrics, print_certificates, print_frontend_list, print_json_response,
-            print_metrics, print_query_response_data, print_status,
+            print_access_log, print_audit_log, print_available_metrics, print_backend_health,
+            print_backend_list, print_certificate_issues, print_certificate_list,
+            print_certificates, print_dry_run, print_frontend_list, print_json_response,
+            print_metrics, print_progress, print_query_response_data, print_state_changes,
+            print_state_diff, print_state_history, print_status, print_warnings,
         },
         CommandManager,
     },
@@ -33,7 +44,7 @@ struct WorkerStatus<'a> {
     pub status: &'a String,
 }
 
-fn generate_id() -> String {
+pub fn generate_id() -> String {
     let s: String = thread_rng()
         .sample_iter(&Alphanumeric)
         .take(6)
@@ -52,12 +63,24 @@ fn generate_tagged_id(tag: &str) -> String {
 }
 
 impl CommandManager {
-    fn send_request(
+    pub(super) fn send_request(
+        &mut self,
+        id: &str,
+        command_request_order: CommandRequestOrder,
+    ) -> anyhow::Result<()> {
+        self.send_strict_request(id, command_request_order, false)
+    }
+
+    fn send_strict_request(
         &mut self,
         id: &str,
         command_request_order: CommandRequestOrder,
+        strict: bool,
     ) -> anyhow::Result<()> {
-        let command_request = CommandRequest::new(id.to_string(), command_request_order, None);
+        let mut command_request = CommandRequest::new(id.to_string(), command_request_order, None);
+        command_request.strict = strict;
+        command_request.dry_run = self.dry_run;
+        command_request.worker_ids = self.worker_ids.clone();
 
         if !self.channel.write_message(&command_request) {
             bail!("Could not write the request");
@@ -71,6 +94,144 @@ impl CommandManager {
             .with_context(|| "Command timeout. The proxy didn't send an answer")
     }
 
+    /// reads the answers to a long operation until the last one, drawing the
+    /// progress of the workers. The timeout applies to each answer rather than
+    /// to the whole operation, so the command waits as long as workers answer
+    fn read_with_progress(&mut self, id: &str, draw: bool) -> anyhow::Result<CommandResponse> {
+        let mut progress: Option<Progress> = None;
+
+        loop {
+            let response = match self
+                .channel
+                .read_message_blocking_timeout(Some(self.timeout))
+            {
+                Some(response) => response,
+                None => {
+                    if draw && progress.is_some() {
+                        println!();
+                    }
+                    match progress {
+                        Some(progress) => bail!(
+                            "Command timeout. No answer for {}ms, the workers {:?} did not answer all their orders ({})",
+                            self.timeout.as_millis(),
+                            progress.pending_workers(),
+                            progress
+                        ),
+                        None => bail!("Command timeout. The proxy didn't send an answer"),
+                    }
+                }
+            };
+
+            if id != response.id {
+                bail!("received message with invalid id: {:?}", response);
+            }
+
+            match (response.status, response.content) {
+                (CommandStatus::Processing, Some(CommandResponseContent::Progress(current))) => {
+                    if draw {
+                        print_progress(&current);
+                    }
+                    progress = Some(current);
+                }
+                (CommandStatus::Processing, _) => {
+                    if draw {
+                        println!("Proxy is processing: {}", response.message);
+                    }
+                }
+                (status, content) => {
+                    if draw && progress.is_some() {
+                        println!();
+                    }
+                    return Ok(CommandResponse {
+                        status,
+                        content,
+                        ..response
+                    });
+                }
+            }
+        }
+    }
+
+    /// sends the order and returns the content of its answer
+    pub fn request_content(
+        &mut self,
+        command_request_order: CommandRequestOrder,
+    ) -> anyhow::Result<CommandResponseContent> {
+        match self.request_answer(command_request_order)? {
+            Ok(content) => Ok(content),
+            Err(message) => bail!("{}", message),
+        }
+    }
+
+    /// sends the order and returns the content of its answer, or the error
+    /// message of the proxy
+    fn request_answer(
+        &mut self,
+        command_request_order: CommandRequestOrder,
+    ) -> anyhow::Result<Result<CommandResponseContent, String>> {
+        let id = generate_id();
+        self.send_request(&id, command_request_order)?;
+
+        loop {
+            let response = self.read_channel_message_with_timeout()?;
+
+            if id != response.id {
+                bail!("received message with invalid id: {:?}", response);
+            }
+            match response.status {
+                CommandStatus::Processing => {}
+                CommandStatus::Error => return Ok(Err(response.message)),
+                CommandStatus::Ok => {
+                    return response
+                        .content
+                        .map(Ok)
+                        .with_context(|| "No data in the response")
+                }
+            }
+        }
+    }
+
+    /// prints the answer of the order. With an interval, the order is sent again
+    /// over the same connection every interval seconds, and the screen redrawn
+    /// when the answer changed. A plain output, meant for other programs, is
+    /// printed again as is, without clearing the screen nor a header
+    fn watch<F>(
+        &mut self,
+        command_request_order: CommandRequestOrder,
+        interval: Option<u64>,
+        plain: bool,
+        print: F,
+    ) -> anyhow::Result<()>
+    where
+        F: Fn(Result<CommandResponseContent, String>) -> anyhow::Result<()>,
+    {
+        let interval = match interval {
+            None => return print(self.request_answer(command_request_order)?),
+            Some(interval) => interval,
+        };
+
+        let mut previous = None;
+        loop {
+            let content = self.request_answer(command_request_order.clone())?;
+            let serialized = serde_json::to_string(&content)?;
+
+            if previous.as_ref() != Some(&serialized) {
+                if !plain {
+                    print!("{}{}", termion::clear::All, termion::cursor::Goto(1, 1));
+                    println!(
+                        "Every {}s, changed at {}\n",
+                        interval,
+                        time::OffsetDateTime::now_utc()
+                    );
+                }
+                print(content)?;
+                previous = Some(serialized);
+            }
+
+            std::thread::sleep(Duration::from_secs(interval));
+        }
+    }
+
     pub fn save_state(&mut self, path: String) -> Result<(), anyhow::Error> {
         let id = generate_id();
 
@@ -104,6 +265,21 @@ impl CommandManager {
 
         self.send_request(&id, CommandRequestOrder::LoadState { path: path.clone() })?;
 
+        let response = self.read_with_progress(&id, true)?;
+        match response.status {
+            CommandStatus::Ok => {
+                println!("Proxy state loaded successfully from {}", path);
+                Ok(())
+            }
+            _ => bail!("could not load proxy state: {}", response.message),
+        }
+    }
+
+    pub fn sync_peers(&mut self) -> Result<(), anyhow::Error> {
+        let id = generate_id();
+
+        self.send_request(&id, CommandRequestOrder::SyncPeers)?;
+
         loop {
             let response = self.read_channel_message_with_timeout()?;
 
@@ -115,10 +291,13 @@ impl CommandManager {
                     println!("Proxy is processing: {}", response.message);
                 }
                 CommandStatus::Error => {
-                    bail!("could not load proxy state: {}", response.message)
+                    bail!(
+                        "could not send the state to the peers: {}",
+                        response.message
+                    )
                 }
                 CommandStatus::Ok => {
-                    println!("Proxy state loaded successfully from {}", path);
+                    println!("{}", response.message);
                     break;
                 }
             }
@@ -163,18 +342,302 @@ impl CommandManager {
         Ok(())
     }
 
-    pub fn soft_stop(&mut self, proxy_id: Option<u32>) -> Result<(), anyhow::Error> {
+    /// the state of the main process, without printing it
+    pub fn current_state(&mut self) -> anyhow::Result<ConfigState> {
+        let id = generate_id();
+
+        // the dump cannot be dry run, and is not sent to the workers
+        if !self.channel.write_message(&CommandRequest::new(
+            id.clone(),
+            CommandRequestOrder::DumpState,
+            None,
+        )) {
+            bail!("Could not write the request");
+        }
+
+        loop {
+            let response = self.read_channel_message_with_timeout()?;
+
+            if id != response.id {
+                bail!("received message with invalid id: {:?}", response);
+            }
+            match response.status {
+                CommandStatus::Processing => {}
+                CommandStatus::Error => {
+                    bail!("could not dump proxy state: {}", response.message);
+                }
+                CommandStatus::Ok => match response.content {
+                    Some(CommandResponseContent::State(state)) => return Ok(*state),
+                    _ => bail!("state dump was empty"),
+                },
+            }
+        }
+    }
+
+    pub fn config_diff(&mut self, file: Option<String>, json: bool) -> Result<(), anyhow::Error> {
+        let path = file.unwrap_or_else(|| self.config.config_path.clone());
+        let config = Config::load_from_path(&path)
+            .with_context(|| format!("could not load the configuration {}", path))?;
+
+        // what the orders changed since the start with this configuration
+        let changes = config.initial_state().changes(&self.current_state()?);
+        match json {
+            true => print_json_response(&changes),
+            false => {
+                print_state_changes(&changes, &path);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn export_config(
+        &mut self,
+        file: String,
+        directory: Option<String>,
+    ) -> Result<(), anyhow::Error> {
+        let directory = match directory {
+            Some(directory) => PathBuf::from(directory),
+            None => match Path::new(&file).parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+                _ => PathBuf::from("."),
+            },
+        };
+
+        // the options that are not part of the state come from the current configuration
+        let mut file_config = FileConfig::load_from_path(&self.config.config_path)
+            .with_context(|| format!("could not load {}", self.config.config_path))?;
+        let state = self.current_state()?;
+        let skipped = file_config.set_state(&state, &directory)?;
+
+        std::fs::write(&file, file_config.to_toml()?)
+            .with_context(|| format!("could not write the configuration to {}", file))?;
+        println!("The state was written to {}", file);
+        if !skipped.is_empty() {
+            println!("The configuration file cannot express:");
+            for item in skipped {
+                println!("\t{}", item);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply_state(
+        &mut self,
+        path: String,
+        json: bool,
+        strict: bool,
+    ) -> Result<(), anyhow::Error> {
+        let data = std::fs::read_to_string(&path)
+            .with_context(|| format!("could not read the state file {}", path))?;
+        let state: ConfigState = serde_json::from_str(&data)
+            .with_context(|| format!("could not parse the state file {}", path))?;
+
+        let id = generate_id();
+        self.send_strict_request(
+            &id,
+            CommandRequestOrder::ApplyState {
+                state: Box::new(state),
+            },
+            strict,
+        )?;
+
+        loop {
+            let response = self.read_channel_message_with_timeout()?;
+
+            if id != response.id {
+                bail!("received message with invalid id: {:?}", response);
+            }
+            match response.status {
+                CommandStatus::Processing => {
+                    println!("Proxy is processing: {}", response.message);
+                }
+                CommandStatus::Error => {
+                    if json {
+                        print_json_response(&response)?;
+                    } else {
+                        match response.content {
+                            Some(CommandResponseContent::CertificateValidation(issues)) => {
+                                print_certificate_issues(&issues)
+                            }
+                            Some(CommandResponseContent::Warnings(warnings)) => {
+                                print_warnings(&warnings)
+                            }
+                            _ => {}
+                        }
+                    }
+                    bail!("could not apply the state: {}", response.message);
+                }
+                CommandStatus::Ok => match response.content {
+                    Some(CommandResponseContent::StateDiff(orders)) => {
+                        match json {
+                            true => print_json_response(&orders)?,
+                            false => print_state_diff(&orders),
+                        }
+                        break;
+                    }
+                    Some(CommandResponseContent::DryRun(dry_run)) => {
+                        match json {
+                            true => print_json_response(&dry_run)?,
+                            false => print_dry_run(&dry_run),
+                        }
+                        break;
+                    }
+                    _ => bail!("the answer did not list the executed orders"),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    pub fn state_history(&mut self, json: bool, output: OutputArgs) -> Result<(), anyhow::Error> {
+        let id = generate_id();
+        self.send_request(&id, CommandRequestOrder::StateHistory)?;
+
+        loop {
+            let response = self.read_channel_message_with_timeout()?;
+
+            if id != response.id {
+                bail!("received message with invalid id: {:?}", response);
+            }
+            match response.status {
+                CommandStatus::Processing => {
+                    println!("Proxy is processing: {}", response.message);
+                }
+                CommandStatus::Error => {
+                    if json {
+                        print_json_response(&response.message)?;
+                    }
+                    bail!(
+                        "could not list the versions of the state: {}",
+                        response.message
+                    );
+                }
+                CommandStatus::Ok => match response.content {
+                    Some(CommandResponseContent::StateHistory(versions)) => {
+                        match json {
+                            true => print_json_response(&versions)?,
+                            false => print_state_history(&versions, &output)?,
+                        }
+                        break;
+                    }
+                    _ => bail!("the answer did not list the versions of the state"),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    pub fn audit_log_tail(
+        &mut self,
+        lines: usize,
+        json: bool,
+        output: OutputArgs,
+    ) -> Result<(), anyhow::Error> {
+        let id = generate_id();
+        self.send_request(&id, CommandRequestOrder::AuditLog { lines })?;
+
+        loop {
+            let response = self.read_channel_message_with_timeout()?;
+
+            if id != response.id {
+                bail!("received message with invalid id: {:?}", response);
+            }
+            match response.status {
+                CommandStatus::Processing => {
+                    println!("Proxy is processing: {}", response.message);
+                }
+                CommandStatus::Error => {
+                    if json {
+                        print_json_response(&response.message)?;
+                    }
+                    bail!("could not read the audit log: {}", response.message);
+                }
+                CommandStatus::Ok => match response.content {
+                    Some(CommandResponseContent::AuditLog(entries)) => {
+                        match json {
+                            true => print_json_response(&entries)?,
+                            false => print_audit_log(&entries, &output)?,
+                        }
+                        break;
+                    }
+                    _ => bail!("the answer did not list the entries of the audit log"),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    pub fn rollback_state(&mut self, version: u64, json: bool) -> Result<(), anyhow::Error> {
+        let id = generate_id();
+        self.send_request(&id, CommandRequestOrder::RollbackState { version })?;
+
+        loop {
+            let response = self.read_channel_message_with_timeout()?;
+
+            if id != response.id {
+                bail!("received message with invalid id: {:?}", response);
+            }
+            match response.status {
+                CommandStatus::Processing => {
+                    println!("Proxy is processing: {}", response.message);
+                }
+                CommandStatus::Error => {
+                    if json {
+                        print_json_response(&response)?;
+                    } else if let Some(CommandResponseContent::CertificateValidation(issues)) =
+                        response.content
+                    {
+                        print_certificate_issues(&issues)
+                    }
+                    bail!(
+                        "could not roll the state back to version {}: {}",
+                        version,
+                        response.message
+                    );
+                }
+                CommandStatus::Ok => match response.content {
+                    Some(CommandResponseContent::StateDiff(orders)) => {
+                        match json {
+                            true => print_json_response(&orders)?,
+                            false => print_state_diff(&orders),
+                        }
+                        break;
+                    }
+                    Some(CommandResponseContent::DryRun(dry_run)) => {
+                        match json {
+                            true => print_json_response(&dry_run)?,
+                            false => print_dry_run(&dry_run),
+                        }
+                        break;
+                    }
+                    _ => bail!("the answer did not list the executed orders"),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    pub fn soft_stop(
+        &mut self,
+        proxy_id: Option<u32>,
+        deadline: Option<u32>,
+    ) -> Result<(), anyhow::Error> {
         println!("shutting down proxy");
         let id = generate_id();
 
         self.channel.write_message(&CommandRequest::new(
             id.clone(),
-            CommandRequestOrder::Proxy(Box::new(ProxyRequestOrder::SoftStop)),
+            CommandRequestOrder::Proxy(Box::new(ProxyRequestOrder::SoftStop(deadline))),
             proxy_id,
         ));
 
+        // the workers answer once their sessions are drained or closed
+        let timeout = self.timeout + Duration::from_secs(deadline.unwrap_or(0).into());
         loop {
-            let response = self.read_channel_message_with_timeout()?;
+            let response = self
+                .channel
+                .read_message_blocking_timeout(Some(timeout))
+                .with_context(|| "Command timeout. The proxy didn't send an answer")?;
 
             if id != response.id {
                 bail!("received message with invalid id: {:?}", response);
@@ -343,7 +806,7 @@ impl CommandManager {
             let response = self.read_channel_message_with_timeout()?;
 
             match response.status {
-                CommandStatus::Processing => info!("Proxy is processing: {}", response.message),
+                CommandStatus::Processing => println!("  {}", response.message),
                 CommandStatus::Error => bail!(
                     "could not stop the worker {}: {}",
                     worker_id,
@@ -360,43 +823,60 @@ impl CommandManager {
         Ok(())
     }
 
-    pub fn status(&mut self, json: bool) -> anyhow::Result<()> {
-        let request_id = generate_id();
-
-        self.send_request(&request_id, CommandRequestOrder::Status)?;
+    pub fn restart_worker(&mut self, worker_id: u32) -> Result<(), anyhow::Error> {
+        println!("restarting worker {}", worker_id);
+        let id = generate_id();
+        self.send_request(&id, CommandRequestOrder::RestartWorker(worker_id))?;
 
         loop {
             let response = self.read_channel_message_with_timeout()?;
 
-            if request_id != response.id {
-                bail!("received message with invalid id: {:?}", response);
-            }
-
             match response.status {
-                CommandStatus::Processing => {
-                    println!("server is processing: {}", response.message);
-                }
-                CommandStatus::Error => {
-                    if json {
-                        print_json_response(&response.message)?;
+                CommandStatus::Processing => println!("  {}", response.message),
+                CommandStatus::Error => bail!(
+                    "could not restart the worker {}: {}",
+                    worker_id,
+                    response.message
+                ),
+                CommandStatus::Ok => {
+                    if id == response.id {
+                        println!("{}", response.message);
                     }
-                    bail!("could not get the worker list: {}", response.message);
+                    break;
                 }
-                CommandStatus::Ok => match response.content {
-                    Some(CommandResponseContent::Status(worker_info_vec)) => {
-                        print_status(worker_info_vec);
-                        break;
-                    }
-                    Some(_) => {
-                        bail!("Received the wrong kind of response data from the command server")
-                    }
-                    None => bail!("No data in the response"),
-                },
             }
         }
         Ok(())
     }
 
+    pub fn status(
+        &mut self,
+        json: bool,
+        watch: Option<u64>,
+        output: OutputArgs,
+    ) -> anyhow::Result<()> {
+        let plain = json || output.output != OutputFormat::Table;
+        self.watch(
+            CommandRequestOrder::Status,
+            watch,
+            plain,
+            |content| match content {
+                Ok(CommandResponseContent::Status(worker_info_vec)) => match json {
+                    true => print_json_response(&worker_info_vec),
+                    false => print_status(worker_info_vec, &output),
+                },
+                Ok(_) => bail!("Received the wrong kind of response data from the command server"),
+                Err(message) => bail!("{}", message),
+            },
+        )
+        .or_else(|e| {
+            if json {
+                print_json_response(&e.to_string())?;
+            }
+            Err(e).with_context(|| "could not get the worker list")
+        })
+    }
+
     pub fn configure_metrics(&mut self, cmd: MetricsCmd) -> Result<(), anyhow::Error> {
         let id = generate_id();
         //println!("will send message for metrics with id {}", id);
@@ -440,7 +920,7 @@ impl CommandManager {
         &mut self,
         json: bool,
         list: bool,
-        refresh: Option<u32>,
+        watch: Option<u64>,
         metric_names: Vec<String>,
         cluster_ids: Vec<String>,
         backend_ids: Vec<String>,
@@ -454,59 +934,25 @@ impl CommandManager {
             }),
         )));
 
-        // a loop to reperform the query every refresh time
-        loop {
-            let id = generate_id();
-            self.send_request(&id, command.clone())?;
-
-            print!("{}", termion::cursor::Save);
-
-            // a loop to process responses
-            loop {
-                let response = self.read_channel_message_with_timeout()?;
-
-                if id != response.id {
-                    bail!("received message with invalid id: {:?}", response);
+        self.watch(command, watch, json, |content| {
+            match content {
+                Ok(CommandResponseContent::Metrics(aggregated_metrics_data)) => {
+                    print_metrics(aggregated_metrics_data, json)?
                 }
-                match response.status {
-                    CommandStatus::Processing => {
-                        println!("Proxy is processing: {}", response.message);
-                    }
-                    CommandStatus::Error => {
-                        if json {
-                            return print_json_response(&response.message);
-                        } else {
-                            bail!("could not query proxy state: {}", response.message);
-                        }
-                    }
-                    CommandStatus::Ok => {
-                        match response.content {
-                            Some(CommandResponseContent::Metrics(aggregated_metrics_data)) => {
-                                print_metrics(aggregated_metrics_data, json)?
-                            }
-                            Some(CommandResponseContent::Query(lists_of_metrics)) => {
-                                print_available_metrics(&lists_of_metrics)?;
-                            }
-                            _ => println!("Wrong kind of response here"),
-                        }
-                        break;
-                    }
+                Ok(CommandResponseContent::Query(lists_of_metrics)) => {
+                    print_available_metrics(&lists_of_metrics)?;
                 }
+                Ok(_) => println!("Wrong kind of response here"),
+                Err(message) => bail!("{}", message),
             }
-
-            match refresh {
-                None => break,
-                Some(seconds) => std::thread::sleep(std::time::Duration::from_secs(seconds as u64)),
+            Ok(())
+        })
+        .or_else(|e| {
+            if json {
+                return print_json_response(&e.to_string());
             }
-
-            print!(
-                "{}{}",
-                termion::cursor::Restore,
-                termion::clear::BeforeCursor
-            );
-        }
-
-        Ok(())
+            Err(e).with_context(|| "could not query proxy state")
+        })
     }
 
     pub fn reload_configuration(
@@ -518,33 +964,20 @@ impl CommandManager {
 
         self.send_request(&id, CommandRequestOrder::ReloadConfiguration { path })?;
 
-        loop {
-            let response = self.read_channel_message_with_timeout()?;
-
-            if id != response.id {
-                bail!("received message with invalid id: {:?}", response);
-            }
-            match response.status {
-                CommandStatus::Processing => {
-                    println!("Proxy is processing: {}", response.message);
-                }
-                CommandStatus::Error => {
-                    if json {
-                        return print_json_response(&response.message);
-                    }
-                    bail!("could not get the worker list: {}", response.message);
-                }
-                CommandStatus::Ok => {
-                    match json {
-                        true => print_json_response(&response.message)?,
-                        false => println!("Reloaded configuration: {}", response.message),
-                    }
-                    break;
-                }
+        let response = match self.read_with_progress(&id, !json) {
+            Ok(response) => response,
+            Err(e) if json => return print_json_response(&e.to_string()),
+            Err(e) => return Err(e),
+        };
+        match (response.status, json) {
+            (CommandStatus::Ok, true) => print_json_response(&response.message),
+            (CommandStatus::Ok, false) => {
+                println!("Reloaded configuration: {}", response.message);
+                Ok(())
             }
+            (_, true) => print_json_response(&response.message),
+            (_, false) => bail!("could not reload the configuration: {}", response.message),
         }
-
-        Ok(())
     }
 
     pub fn list_frontends(
@@ -553,6 +986,8 @@ impl CommandManager {
         https: bool,
         tcp: bool,
         domain: Option<String>,
+        watch: Option<u64>,
+        output: OutputArgs,
     ) -> Result<(), anyhow::Error> {
         let command = CommandRequestOrder::ListFrontends(FrontendFilters {
             http,
@@ -561,6 +996,34 @@ impl CommandManager {
             domain,
         });
 
+        let plain = output.output != OutputFormat::Table;
+        self.watch(command, watch, plain, |content| {
+            match content {
+                Ok(CommandResponseContent::FrontendList(frontends)) => {
+                    print_frontend_list(frontends, &output)?
+                }
+                Ok(content) => println!("Received a response of the wrong kind: {:?}", content),
+                Err(message) => println!("could not query proxy state: {}", message),
+            }
+            Ok(())
+        })
+        .with_context(|| "could not query proxy state")
+    }
+
+    pub fn list_certificates(
+        &mut self,
+        expiring_within: Option<u64>,
+        domain: Option<String>,
+        address: Option<SocketAddr>,
+        json: bool,
+        output: OutputArgs,
+    ) -> Result<(), anyhow::Error> {
+        let command = CommandRequestOrder::ListCertificates(CertificateFilters {
+            domain,
+            expiring_within,
+            address,
+        });
+
         let id = generate_id();
         self.send_request(&id, command)?;
 
@@ -575,17 +1038,18 @@ impl CommandManager {
                     println!("Proxy is processing: {}", response.message);
                 }
                 CommandStatus::Error => {
-                    println!("could not query proxy state: {}", response.message)
-                }
-                CommandStatus::Ok => {
-                    match response.content {
-                        Some(CommandResponseContent::FrontendList(frontends)) => {
-                            print_frontend_list(frontends)
-                        }
-                        _ => println!("Received a response of the wrong kind: {:?}", response),
+                    if json {
+                        print_json_response(&response.message)?;
                     }
-                    break;
+                    bail!("could not list the certificates: {}", response.message);
                 }
+                CommandStatus::Ok => match response.content {
+                    Some(CommandResponseContent::CertificateList(certificates)) => {
+                        print_certificate_list(certificates, json, &output)?;
+                        break;
+                    }
+                    _ => bail!("Received a response of the wrong kind: {:?}", response),
+                },
             }
         }
 
@@ -663,17 +1127,21 @@ impl CommandManager {
         json: bool,
         fingerprint: Option<String>,
         domain: Option<String>,
+        address: Option<SocketAddr>,
     ) -> Result<(), anyhow::Error> {
-        let query = match (fingerprint, domain) {
-            (None, None) => QueryCertificateType::All,
-            (Some(f), None) => match hex::decode(f) {
+        let query = match (fingerprint, domain, address) {
+            (None, None, None) => QueryCertificateType::All,
+            (Some(f), None, None) => match hex::decode(f) {
                 Err(e) => {
                     bail!("invalid fingerprint: {:?}", e);
                 }
                 Ok(f) => QueryCertificateType::Fingerprint(f),
             },
-            (None, Some(d)) => QueryCertificateType::Domain(d),
-            (Some(_), Some(_)) => {
+            (None, Some(d), None) => QueryCertificateType::Domain(d),
+            (None, hostname, Some(address)) => {
+                QueryCertificateType::Resolve(QueryCertificateResolve { address, hostname })
+            }
+            (Some(_), _, _) => {
                 bail!("Error: Either request a fingerprint or a domain name");
             }
         };
@@ -718,13 +1186,89 @@ impl CommandManager {
         Ok(())
     }
 
+    pub fn query_backend_health(
+        &mut self,
+        json: bool,
+        cluster_id: Option<String>,
+        output: OutputArgs,
+    ) -> Result<(), anyhow::Error> {
+        let command = CommandRequestOrder::Proxy(Box::new(ProxyRequestOrder::Query(
+            Query::BackendHealth(cluster_id),
+        )));
+
+        let id = generate_id();
+        self.send_request(&id, command)?;
+
+        loop {
+            let response = self.read_channel_message_with_timeout()?;
+
+            if id != response.id {
+                bail!("received message with invalid id: {:?}", response);
+            }
+            match response.status {
+                CommandStatus::Processing => {
+                    println!("Proxy is processing: {}", response.message);
+                }
+                CommandStatus::Error => {
+                    if json {
+                        print_json_response(&response.message)?;
+                    }
+                    bail!("could not query the backend health: {}", response.message);
+                }
+                CommandStatus::Ok => {
+                    match response.content {
+                        Some(CommandResponseContent::Query(data)) => {
+                            print_backend_health(data, json, &output)?
+                        }
+                        _ => bail!("unexpected response: {:?}", response.content),
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn list_backends(
+        &mut self,
+        json: bool,
+        cluster_id: Option<String>,
+        output: OutputArgs,
+    ) -> Result<(), anyhow::Error> {
+        let command = CommandRequestOrder::Proxy(Box::new(ProxyRequestOrder::Query(
+            Query::Backends(cluster_id),
+        )));
+
+        let data = match self.request_content(command) {
+            Ok(CommandResponseContent::Query(data)) => data,
+            Ok(content) => bail!("unexpected response: {:?}", content),
+            Err(e) => {
+                if json {
+                    print_json_response(&e.to_string())?;
+                }
+                return Err(e).with_context(|| "could not list the backends");
+            }
+        };
+        if json {
+            return print_json_response(&data);
+        }
+
+        // the frontends of the clusters are the same on every worker
+        let state = self.current_state()?;
+        print_backend_list(data, &state, &output)
+    }
+
     pub fn events(&mut self) -> Result<(), anyhow::Error> {
         let id = generate_id();
 
         self.send_request(&id, CommandRequestOrder::SubscribeEvents)?;
 
         loop {
-            let response = self.read_channel_message_with_timeout()?;
+            // the events come whenever they happen, there is no timeout
+            let response = self
+                .channel
+                .read_message_blocking_timeout(None)
+                .with_context(|| "the connection to sozu was closed")?;
             match response.status {
                 CommandStatus::Processing => match response.content {
                     Some(CommandResponseContent::Event(event)) => {
@@ -737,21 +1281,64 @@ impl CommandManager {
                 CommandStatus::Error => {
                     bail!("could not get proxy events: {}", response.message);
                 }
+                // the confirmation of the subscription
+                CommandStatus::Ok => println!("{}", response.message),
+            }
+        }
+    }
+
+    pub fn tail_access_logs(
+        &mut self,
+        filter: AccessLogFilter,
+        json: bool,
+    ) -> Result<(), anyhow::Error> {
+        let id = generate_id();
+
+        self.send_request(&id, CommandRequestOrder::TailAccessLogs(filter))?;
+
+        loop {
+            // the records come whenever a request ends, there is no timeout
+            let response = self
+                .channel
+                .read_message_blocking_timeout(None)
+                .with_context(|| "the connection to sozu was closed")?;
+            match response.status {
+                CommandStatus::Processing => match response.content {
+                    Some(CommandResponseContent::AccessLog(record)) => match json {
+                        true => println!("{}", serde_json::to_string(&record)?),
+                        false => print_access_log(&response.message, &record),
+                    },
+                    _ => println!("Received an unexpected response: {:?}", response),
+                },
+                CommandStatus::Error => {
+                    bail!("could not tail the access logs: {}", response.message);
+                }
+                // the confirmation of the subscription
                 CommandStatus::Ok => {
-                    println!("{}", response.message);
-                    break;
+                    if !json {
+                        println!("{}", response.message)
+                    }
                 }
             }
         }
-        Ok(())
     }
 
     pub fn order_command(&mut self, order: ProxyRequestOrder) -> Result<(), anyhow::Error> {
+        self.strict_order_command(order, false)
+    }
+
+    /// with `strict`, the main process refuses the order if it finds problems
+    /// with it, instead of warning
+    pub fn strict_order_command(
+        &mut self,
+        order: ProxyRequestOrder,
+        strict: bool,
+    ) -> Result<(), anyhow::Error> {
         let id = generate_id();
 
         let request_order = CommandRequestOrder::Proxy(Box::new(order));
         println!("Sending request order: {:?}", request_order);
-        self.send_request(&id, request_order)?;
+        self.send_strict_request(&id, request_order, strict)?;
 
         loop {
             let response = self.read_channel_message_with_timeout()?;
@@ -761,9 +1348,30 @@ impl CommandManager {
             }
             match response.status {
                 CommandStatus::Processing => println!("Proxy is processing: {}", response.message),
-                CommandStatus::Error => bail!("could not execute order: {}", response.message),
+                CommandStatus::Error => {
+                    match response.content {
+                        Some(CommandResponseContent::CertificateValidation(issues)) => {
+                            print_certificate_issues(&issues)
+                        }
+                        Some(CommandResponseContent::Warnings(warnings)) => {
+                            print_warnings(&warnings)
+                        }
+                        _ => {}
+                    }
+                    bail!("could not execute order: {}", response.message);
+                }
                 CommandStatus::Ok => {
                     println!("Success: {}", response.message);
+                    match response.content {
+                        Some(CommandResponseContent::CertificateValidation(issues)) => {
+                            print_certificate_issues(&issues)
+                        }
+                        Some(CommandResponseContent::Warnings(warnings)) => {
+                            print_warnings(&warnings)
+                        }
+                        Some(CommandResponseContent::DryRun(dry_run)) => print_dry_run(&dry_run),
+                        _ => {}
+                    }
                     break;
                 }
             }
@@ -771,3 +1379,99 @@ impl CommandManager {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, fs};
+
+    use sozu_command_lib::channel::Channel;
+
+    use super::*;
+
+    /// a command manager, and a proxy answering each request with the next
+    /// status, then closing the connection
+    fn command_manager(statuses: Vec<Vec<WorkerInfo>>) -> CommandManager {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(&config_path, "").unwrap();
+        let config = Config::load_from_path(&config_path.to_string_lossy()).unwrap();
+
+        let (channel, mut proxy): (
+            Channel<CommandRequest, CommandResponse>,
+            Channel<CommandResponse, CommandRequest>,
+        ) = Channel::generate(1000, 10000).unwrap();
+        proxy.blocking();
+        std::thread::spawn(move || {
+            for workers in statuses {
+                let request = proxy.read_message().unwrap();
+                proxy.write_message(&CommandResponse::new(
+                    request.id,
+                    CommandStatus::Ok,
+                    String::new(),
+                    Some(CommandResponseContent::Status(workers)),
+                ));
+            }
+        });
+
+        CommandManager {
+            channel,
+            timeout: Duration::from_secs(1),
+            config,
+            dry_run: false,
+            worker_ids: Vec::new(),
+        }
+    }
+
+    fn worker(run_state: RunState) -> WorkerInfo {
+        WorkerInfo {
+            id: 0,
+            pid: 1000,
+            run_state,
+            cpu_cores: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn watch_prints_the_changed_answers() {
+        let mut command_manager = command_manager(vec![
+            vec![worker(RunState::Running)],
+            vec![worker(RunState::Running)],
+            vec![worker(RunState::NotAnswering)],
+            vec![worker(RunState::NotAnswering)],
+        ]);
+
+        let printed = RefCell::new(Vec::new());
+        let error = command_manager
+            .watch(CommandRequestOrder::Status, Some(0), true, |content| {
+                match content {
+                    Ok(CommandResponseContent::Status(workers)) => {
+                        printed.borrow_mut().push(workers[0].run_state)
+                    }
+                    other => panic!("expected a status, got {:?}", other),
+                }
+                Ok(())
+            })
+            .unwrap_err();
+
+        // the proxy closed the connection after its last answer
+        assert!(error.to_string().contains("Command timeout"));
+        assert_eq!(
+            printed.into_inner(),
+            vec![RunState::Running, RunState::NotAnswering]
+        );
+    }
+
+    #[test]
+    fn without_watch_the_answer_is_printed_once() {
+        let mut command_manager = command_manager(vec![vec![worker(RunState::Running)]]);
+
+        let printed = RefCell::new(0);
+        command_manager
+            .watch(CommandRequestOrder::Status, None, false, |_| {
+                *printed.borrow_mut() += 1;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(printed.into_inner(), 1);
+    }
+}
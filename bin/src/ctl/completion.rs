@@ -0,0 +1,152 @@
+//! `sozu completion`: the scripts completing the command line in bash, zsh and
+//! fish. They call `sozu __complete` with the line being typed, which is
+//! completed like the prompt of `sozu shell`, with the clusters, backends,
+//! hostnames and listeners of the running proxy
+use std::time::Duration;
+
+use sozu_command_lib::config::Config;
+
+use crate::{
+    cli::CompletionShell,
+    ctl::{
+        create_channel,
+        shell::{CompletionValues, ShellHelper},
+        CommandManager,
+    },
+};
+
+/// the completion must not hang when the proxy does not answer
+const STATE_TIMEOUT: Duration = Duration::from_secs(1);
+
+const BASH_SCRIPT: &str = r#"_sozu() {
+    local line="${COMP_LINE:0:$COMP_POINT}"
+    local cur="${line##*[[:space:]]}"
+    local IFS=$'\n'
+    COMPREPLY=($(sozu __complete "$line" 2>/dev/null))
+    # bash replaces the text after the last colon of the word, like in addresses
+    if [[ "$cur" == *:* && "$COMP_WORDBREAKS" == *:* ]]; then
+        local prefix="${cur%"${cur##*:}"}"
+        COMPREPLY=("${COMPREPLY[@]#"$prefix"}")
+    fi
+}
+complete -o default -F _sozu sozu
+"#;
+
+const ZSH_SCRIPT: &str = r#"#compdef sozu
+
+_sozu() {
+    local -a candidates
+    candidates=("${(@f)$(sozu __complete "${words[1,CURRENT-1]} $PREFIX" 2>/dev/null)}")
+    candidates=(${candidates:#})
+    if (( ${#candidates} )); then
+        compadd -Q -a candidates
+    else
+        _files
+    fi
+}
+
+compdef _sozu sozu
+"#;
+
+const FISH_SCRIPT: &str = r#"function __sozu_complete
+    sozu __complete (commandline -cp) 2>/dev/null
+end
+
+complete -c sozu -f -a '(__sozu_complete)'
+complete -c sozu -F -n 'test -z "$(__sozu_complete)"'
+"#;
+
+pub fn script(shell: CompletionShell) -> &'static str {
+    match shell {
+        CompletionShell::Bash => BASH_SCRIPT,
+        CompletionShell::Zsh => ZSH_SCRIPT,
+        CompletionShell::Fish => FISH_SCRIPT,
+    }
+}
+
+/// prints the candidates for the last word of the line, one per line. The
+/// errors are not printed, they would be mixed with the line being typed
+pub fn complete(line: &str) -> anyhow::Result<()> {
+    let start = line.rfind(char::is_whitespace).map_or(0, |index| index + 1);
+    let mut previous = match shell_words::split(&line[..start]) {
+        Ok(previous) => previous,
+        // an unterminated quote
+        Err(_) => return Ok(()),
+    };
+    if previous.is_empty() {
+        // the name of the program is being typed
+        return Ok(());
+    }
+    previous.remove(0);
+
+    let values = config_path(&previous)
+        .and_then(|path| Config::load_from_path(path).ok())
+        .and_then(|config| current_values(config).ok())
+        .unwrap_or_default();
+
+    for candidate in ShellHelper::new(values, false).candidates(&previous, &line[start..]) {
+        println!("{}", candidate);
+    }
+    Ok(())
+}
+
+/// the configuration given on the line, or the default one
+fn config_path(words: &[String]) -> Option<&str> {
+    let mut words = words.iter();
+    while let Some(word) = words.next() {
+        if word == "-c" || word == "--config" {
+            return words.next().map(String::as_str);
+        }
+        if let Some(path) = word.strip_prefix("--config=") {
+            return Some(path);
+        }
+    }
+    option_env!("SOZU_CONFIG")
+}
+
+fn current_values(config: Config) -> anyhow::Result<CompletionValues> {
+    let channel = create_channel(&config)?;
+    let mut command_manager = CommandManager {
+        channel,
+        timeout: STATE_TIMEOUT,
+        config,
+        dry_run: false,
+        worker_ids: Vec::new(),
+    };
+    let state = command_manager.current_state()?;
+    Ok(CompletionValues::from(&state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_completion() {
+        // unlike in the prompt, every command can be run from the command line
+        let helper = ShellHelper::new(CompletionValues::default(), false);
+        assert_eq!(
+            helper.candidates(&[], "sta"),
+            vec!["start", "status", "state"]
+        );
+        assert!(helper.candidates(&[], "ex").is_empty());
+    }
+
+    #[test]
+    fn completion_config_path() {
+        let words = |line: &str| shell_words::split(line).unwrap();
+
+        assert_eq!(
+            config_path(&words("-c /etc/sozu.toml cluster remove --id")),
+            Some("/etc/sozu.toml")
+        );
+        assert_eq!(
+            config_path(&words("cluster remove --config=sozu.toml --id")),
+            Some("sozu.toml")
+        );
+        assert_eq!(
+            config_path(&words("cluster remove --id")),
+            option_env!("SOZU_CONFIG")
+        );
+    }
+}
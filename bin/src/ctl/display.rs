@@ -1,93 +1,362 @@
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    io::Write,
+    net::SocketAddr,
     process::exit,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{self, bail, Context};
 use prettytable::{Row, Table};
 
 use sozu_command_lib::{
-    command::{CommandResponseContent, ListedFrontends, WorkerInfo},
+    command::{
+        AuditEntry, CertificateIssue, CommandRequestOrder, CommandResponseContent, DryRun,
+        ListedCertificate, ListedFrontends, Progress, StateVersion, WorkerInfo,
+    },
     proxy::{
-        AggregatedMetricsData, ClusterMetricsData, FilteredData, QueryAnswer,
+        AccessLogRecord, AggregatedMetricsData, BackendHealth, ClusterMetricsData, FilteredData,
+        HeaderRule, HealthCheckKind, PathRule, ProxyRequestOrder, QueryAnswer, QueryAnswerBackend,
         QueryAnswerCertificate, QueryAnswerMetrics, Route, WorkerMetrics,
     },
+    state::{ConfigState, StateChange},
 };
 
-pub fn print_status(worker_info_vec: Vec<WorkerInfo>) {
-    let mut table = Table::new();
-    table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
-    table.add_row(row!["worker id", "pid", "run state"]);
+use crate::{cli::OutputArgs, ctl::output::Listing};
 
+pub fn print_status(worker_info_vec: Vec<WorkerInfo>, output: &OutputArgs) -> anyhow::Result<()> {
+    let mut listing = Listing::new(&["id", "pid", "state"]);
     for worker_info in worker_info_vec {
-        let row = row!(worker_info.id, worker_info.pid, worker_info.run_state);
-        table.add_row(row);
+        listing.add_row(vec![
+            worker_info.id.to_string(),
+            worker_info.pid.to_string(),
+            worker_info.run_state.to_string(),
+        ]);
     }
-
-    table.printstd();
+    listing.print(output)
 }
 
-pub fn print_frontend_list(frontends: ListedFrontends) {
-    trace!(" We received this frontends to display {:#?}", frontends);
-    // HTTP frontends
-    if !frontends.http_frontends.is_empty() {
-        let mut table = Table::new();
-        table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
-        table.add_row(row!["HTTP frontends "]);
-        table.add_row(row![
-            "route", "address", "hostname", "path", "method", "position", "tags"
+pub fn print_certificate_list(
+    certificates: Vec<ListedCertificate>,
+    json: bool,
+    output: &OutputArgs,
+) -> Result<(), anyhow::Error> {
+    if json {
+        return print_json_response(&certificates);
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() as i64)
+        .unwrap_or(0);
+
+    let mut listing = Listing::new(&[
+        "address",
+        "fingerprint",
+        "subject",
+        "names",
+        "expiration",
+        "days_left",
+        "hostnames",
+    ]);
+
+    for certificate in certificates.iter() {
+        let expiration = match time::OffsetDateTime::from_unix_timestamp(certificate.expiration) {
+            Ok(date) => date.to_string(),
+            Err(_) => certificate.expiration.to_string(),
+        };
+
+        listing.add_row(vec![
+            certificate.address.to_string(),
+            certificate.fingerprint.to_string(),
+            certificate.subject.to_owned(),
+            certificate.names.join(", "),
+            expiration,
+            ((certificate.expiration - now) / (24 * 3600)).to_string(),
+            certificate.hostnames.join(", "),
         ]);
-        for http_frontend in frontends.http_frontends.iter() {
-            table.add_row(row!(
-                http_frontend.route,
-                http_frontend.address.to_string(),
-                http_frontend.hostname.to_string(),
-                format!("{:?}", http_frontend.path),
-                format!("{:?}", http_frontend.method),
-                format!("{:?}", http_frontend.position),
-                format_tags_to_string(http_frontend.tags.as_ref())
-            ));
+    }
+
+    listing.print(output)
+}
+
+pub fn print_certificate_issues(issues: &[CertificateIssue]) {
+    if issues.is_empty() {
+        println!("The certificate passed the validation");
+        return;
+    }
+
+    println!("The certificate failed the validation:");
+    for issue in issues {
+        println!("\t{}", issue);
+    }
+}
+
+pub fn print_warnings(warnings: &[String]) {
+    println!("Warnings:");
+    for warning in warnings {
+        println!("\t{}", warning);
+    }
+}
+
+pub fn print_state_diff(orders: &[ProxyRequestOrder]) {
+    if orders.is_empty() {
+        println!("The state was already applied, nothing changed");
+        return;
+    }
+
+    println!("Executed {} orders:", orders.len());
+    for order in orders {
+        println!("\t{:?}", order);
+    }
+}
+
+pub fn print_state_changes(changes: &[StateChange], config_path: &str) {
+    if changes.is_empty() {
+        println!("The state matches the configuration file {}", config_path);
+        return;
+    }
+
+    println!(
+        "The state differs from the configuration file {} by {} changes:",
+        config_path,
+        changes.len()
+    );
+    for change in changes {
+        println!("\t{}", change);
+    }
+}
+
+pub fn print_dry_run(dry_run: &DryRun) {
+    if dry_run.orders.is_empty() {
+        println!("Dry run: the order would change nothing");
+    } else {
+        println!(
+            "Dry run: the order would execute {} orders:",
+            dry_run.orders.len()
+        );
+        for order in dry_run.orders.iter() {
+            println!("\t{:?}", order);
         }
-        table.printstd();
     }
+    if !dry_run.warnings.is_empty() {
+        print_warnings(&dry_run.warnings);
+    }
+}
 
-    // HTTPS frontends
-    if !frontends.https_frontends.is_empty() {
-        let mut table = Table::new();
-        table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
-        table.add_row(row!["HTTPS frontends"]);
-        table.add_row(row![
-            "route", "address", "hostname", "path", "method", "position", "tags"
+pub fn print_state_history(versions: &[StateVersion], output: &OutputArgs) -> anyhow::Result<()> {
+    let mut listing = Listing::new(&["version", "request_id", "date"]);
+
+    for version in versions {
+        let date = match time::OffsetDateTime::from_unix_timestamp(version.timestamp) {
+            Ok(date) => date.to_string(),
+            Err(_) => version.timestamp.to_string(),
+        };
+        listing.add_row(vec![
+            version.version.to_string(),
+            version.request_id.to_owned(),
+            date,
         ]);
-        for https_frontend in frontends.https_frontends.iter() {
-            table.add_row(row!(
-                https_frontend.route,
-                https_frontend.address.to_string(),
-                https_frontend.hostname.to_string(),
-                format!("{:?}", https_frontend.path),
-                format!("{:?}", https_frontend.method),
-                format!("{:?}", https_frontend.position),
-                format_tags_to_string(https_frontend.tags.as_ref())
-            ));
+    }
+
+    listing.print(output)
+}
+
+/// redraws the progress of a long operation on the current line, with the
+/// answers of each worker
+pub fn print_progress(progress: &Progress) {
+    const WIDTH: usize = 20;
+    let expected = progress.expected().max(1);
+    let filled = progress.answered() * WIDTH / expected;
+
+    let workers = progress
+        .workers
+        .iter()
+        .map(|(id, worker)| {
+            let answered = worker.ok + worker.errors;
+            let state = if worker.errors > 0 {
+                format!("{}/{}, {} errors", answered, worker.expected, worker.errors)
+            } else if worker.is_done() {
+                String::from("done")
+            } else {
+                format!("{}/{}", answered, worker.expected)
+            };
+            format!("worker {}: {}", id, state)
+        })
+        .collect::<Vec<_>>()
+        .join(" | ");
+
+    print!(
+        "\r{}[{}{}] {}/{} | {}",
+        termion::clear::CurrentLine,
+        "#".repeat(filled),
+        " ".repeat(WIDTH - filled),
+        progress.answered(),
+        progress.expected(),
+        workers
+    );
+    let _ = std::io::stdout().flush();
+}
+
+/// a record of the access logs on one line, like the workers log it
+pub fn print_access_log(worker: &str, record: &AccessLogRecord) {
+    let date =
+        time::OffsetDateTime::from_unix_timestamp_nanos(i128::from(record.timestamp) * 1_000_000)
+            .map(|date| date.to_string())
+            .unwrap_or_else(|_| record.timestamp.to_string());
+    let address = |address: Option<SocketAddr>| {
+        address
+            .map(|address| address.to_string())
+            .unwrap_or_else(|| String::from("-"))
+    };
+    let error = match &record.error {
+        Some(error) => format!(" | {}", error),
+        None => String::new(),
+    };
+
+    println!(
+        "{} worker {}\t{} {}\t{} -> {}\t{}ms {}ms {} {}\t{} {} {} {} {}{}",
+        date,
+        worker,
+        record.cluster_id.as_deref().unwrap_or("-"),
+        record.backend_id.as_deref().unwrap_or("-"),
+        address(record.session_address),
+        address(record.backend_address),
+        record.response_time,
+        record.service_time,
+        record.bytes_in,
+        record.bytes_out,
+        record.protocol,
+        record.host.as_deref().unwrap_or("-"),
+        record
+            .status
+            .map(|status| status.to_string())
+            .unwrap_or_else(|| String::from("-")),
+        record.method.as_deref().unwrap_or("-"),
+        record.path.as_deref().unwrap_or("-"),
+        error
+    );
+}
+
+pub fn print_audit_log(entries: &[AuditEntry], output: &OutputArgs) -> anyhow::Result<()> {
+    let mut listing = Listing::new(&[
+        "date",
+        "request_id",
+        "client",
+        "user",
+        "order",
+        "status",
+        "version",
+        "message",
+    ]);
+
+    for entry in entries {
+        let date = match time::OffsetDateTime::from_unix_timestamp(entry.timestamp) {
+            Ok(date) => date.to_string(),
+            Err(_) => entry.timestamp.to_string(),
+        };
+        let mut order = order_type(&entry.order);
+        if entry.dry_run {
+            order.push_str(" (dry run)");
         }
-        table.printstd();
+        let status = match &entry.status {
+            Some(status) => format!("{:?}", status),
+            None => String::from("unknown"),
+        };
+        listing.add_row(vec![
+            date,
+            entry.request_id.to_owned(),
+            entry.client.to_string(),
+            entry.user.as_deref().unwrap_or("-").to_owned(),
+            order,
+            status,
+            entry
+                .version
+                .map(|version| version.to_string())
+                .unwrap_or_default(),
+            entry.message.to_owned(),
+        ]);
     }
 
-    // TCP frontends
-    if !frontends.tcp_frontends.is_empty() {
-        let mut table = Table::new();
-        table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
-        table.add_row(row!["TCP frontends  "]);
-        table.add_row(row!["Cluster ID", "address", "tags"]);
-        for tcp_frontend in frontends.tcp_frontends.iter() {
-            table.add_row(row!(
-                tcp_frontend.cluster_id,
-                tcp_frontend.address,
-                format_tags_to_string(tcp_frontend.tags.as_ref())
-            ));
+    listing.print(output)
+}
+
+/// the type of an order, as named in the JSON of the command socket
+fn order_type(order: &CommandRequestOrder) -> String {
+    let value = match serde_json::to_value(order) {
+        Ok(value) => value,
+        Err(_) => return String::from("-"),
+    };
+    let order_type = match value.get("type") {
+        Some(serde_json::Value::String(order_type)) if order_type == "PROXY" => {
+            value.get("data").and_then(|data| data.get("type"))
         }
-        table.printstd();
+        order_type => order_type,
+    };
+    order_type
+        .and_then(|order_type| order_type.as_str())
+        .unwrap_or("-")
+        .to_owned()
+}
+
+pub fn print_frontend_list(frontends: ListedFrontends, output: &OutputArgs) -> anyhow::Result<()> {
+    trace!(" We received this frontends to display {:#?}", frontends);
+    let mut listing = Listing::new(&[
+        "protocol", "route", "address", "hostname", "path", "method", "headers", "position", "tags",
+    ]);
+
+    let http_frontends = frontends
+        .http_frontends
+        .iter()
+        .map(|frontend| ("http", frontend));
+    let https_frontends = frontends
+        .https_frontends
+        .iter()
+        .map(|frontend| ("https", frontend));
+    for (protocol, http_frontend) in http_frontends.chain(https_frontends) {
+        listing.add_row(vec![
+            protocol.to_owned(),
+            http_frontend.route.to_string(),
+            http_frontend.address.to_string(),
+            http_frontend.hostname.to_string(),
+            format!("{:?}", http_frontend.path),
+            format!("{:?}", http_frontend.method),
+            format_header_rules_to_string(&http_frontend.headers),
+            format!("{:?}", http_frontend.position),
+            format_tags_to_string(http_frontend.tags.as_ref()),
+        ]);
+    }
+
+    // the TCP and SNI frontends route to a cluster, on every path
+    for tcp_frontend in frontends.tcp_frontends.iter() {
+        listing.add_row(vec![
+            String::from("tcp"),
+            tcp_frontend.cluster_id.to_owned(),
+            tcp_frontend.address.to_string(),
+            String::new(),
+            String::new(),
+            String::new(),
+            String::new(),
+            String::new(),
+            format_tags_to_string(tcp_frontend.tags.as_ref()),
+        ]);
     }
+
+    for sni_frontend in frontends.sni_frontends.iter() {
+        listing.add_row(vec![
+            String::from("sni"),
+            sni_frontend.cluster_id.to_owned(),
+            sni_frontend.address.to_string(),
+            sni_frontend.hostname.to_owned(),
+            String::new(),
+            String::new(),
+            String::new(),
+            String::new(),
+            String::new(),
+        ]);
+    }
+
+    listing.print(output)
 }
 
 pub fn print_metrics(
@@ -298,7 +567,7 @@ pub fn print_query_response_data(
                 return print_json_response(data);
             }
 
-            let cluster_headers = vec!["id", "sticky_session", "https_redirect"];
+            let cluster_headers = vec!["id", "sticky_session", "https_redirect", "health_check"];
             let mut cluster_table = create_queried_cluster_table(cluster_headers, data);
 
             let http_headers = vec!["id", "hostname", "path"];
@@ -310,7 +579,11 @@ pub fn print_query_response_data(
             let tcp_headers = vec!["id", "address"];
             let mut tcp_frontend_table = create_queried_cluster_table(tcp_headers, data);
 
+            let sni_headers = vec!["id", "address", "hostname"];
+            let mut sni_frontend_table = create_queried_cluster_table(sni_headers, data);
+
             let backend_headers = vec!["backend id", "IP address", "Backup"];
+            // the workers mark the backends failing their health checks as down
             let mut backend_table = create_queried_cluster_table(backend_headers, data);
 
             let keys: HashSet<&String> = data.keys().collect();
@@ -319,13 +592,18 @@ pub fn print_query_response_data(
             let mut frontend_data = HashMap::new();
             let mut https_frontend_data = HashMap::new();
             let mut tcp_frontend_data = HashMap::new();
+            let mut sni_frontend_data = HashMap::new();
             let mut backend_data = HashMap::new();
+            let mut unhealthy_backends = HashSet::new();
 
             for (key, metrics) in data.iter() {
                 //let m: u8 = metrics;
                 if let QueryAnswer::Clusters(clusters) = metrics {
                     for cluster in clusters.iter() {
-                        let entry = cluster_data.entry(cluster).or_insert(Vec::new());
+                        // the workers differ in the health of the backends only
+                        let entry = cluster_data
+                            .entry(&cluster.configuration)
+                            .or_insert(Vec::new());
                         entry.push(key.to_owned());
 
                         for frontend in cluster.http_frontends.iter() {
@@ -343,9 +621,18 @@ pub fn print_query_response_data(
                             entry.push(key.to_owned());
                         }
 
+                        for frontend in cluster.sni_frontends.iter() {
+                            let entry = sni_frontend_data.entry(frontend).or_insert(Vec::new());
+                            entry.push(key.to_owned());
+                        }
+
                         for backend in cluster.backends.iter() {
                             let entry = backend_data.entry(backend).or_insert(Vec::new());
                             entry.push(key.to_owned());
+
+                            if cluster.unhealthy_backends.contains(&backend.backend_id) {
+                                unhealthy_backends.insert((key, &backend.backend_id));
+                            }
                         }
                     }
                 }
@@ -356,20 +643,29 @@ pub fn print_query_response_data(
             for (key, values) in cluster_data.iter() {
                 let mut row = Vec::new();
                 row.push(cell!(key
-                    .configuration
                     .as_ref()
                     .map(|conf| conf.cluster_id.to_owned())
                     .unwrap_or_else(String::new)));
                 row.push(cell!(key
-                    .configuration
                     .as_ref()
                     .map(|conf| conf.sticky_session)
                     .unwrap_or_else(|| false)));
                 row.push(cell!(key
-                    .configuration
                     .as_ref()
                     .map(|conf| conf.https_redirect)
                     .unwrap_or_else(|| false)));
+                row.push(cell!(key
+                    .as_ref()
+                    .and_then(|conf| conf.health_check.as_ref())
+                    .map(|health_check| match health_check.kind {
+                        HealthCheckKind::Tcp => String::from("tcp"),
+                        HealthCheckKind::Http => format!("http {}", health_check.path),
+                        HealthCheckKind::Grpc => match &health_check.service {
+                            Some(service) => format!("grpc {}", service),
+                            None => String::from("grpc"),
+                        },
+                    })
+                    .unwrap_or_default()));
 
                 for val in values {
                     if keys.contains(val) {
@@ -390,7 +686,10 @@ pub fn print_query_response_data(
                 let mut row = Vec::new();
                 match &key.route {
                     Route::ClusterId(cluster_id) => row.push(cell!(cluster_id)),
-                    Route::Deny => row.push(cell!("-")),
+                    Route::Deny { .. } => row.push(cell!("-")),
+                    Route::Weighted(_) | Route::Redirect { .. } | Route::AbTest { .. } => {
+                        row.push(cell!(key.route.to_string()))
+                    }
                 }
                 row.push(cell!(key.hostname));
                 row.push(cell!(key.path));
@@ -414,7 +713,10 @@ pub fn print_query_response_data(
                 let mut row = Vec::new();
                 match &key.route {
                     Route::ClusterId(cluster_id) => row.push(cell!(cluster_id)),
-                    Route::Deny => row.push(cell!("-")),
+                    Route::Deny { .. } => row.push(cell!("-")),
+                    Route::Weighted(_) | Route::Redirect { .. } | Route::AbTest { .. } => {
+                        row.push(cell!(key.route.to_string()))
+                    }
                 }
                 row.push(cell!(key.hostname));
                 row.push(cell!(key.path));
@@ -450,6 +752,28 @@ pub fn print_query_response_data(
 
             tcp_frontend_table.printstd();
 
+            println!("\nSNI frontends configuration for {}:\n", needle);
+
+            for (key, values) in sni_frontend_data.iter() {
+                let mut row = vec![
+                    cell!(key.cluster_id),
+                    cell!(format!("{}", key.address)),
+                    cell!(key.hostname),
+                ];
+
+                for val in values.iter() {
+                    if keys.contains(val) {
+                        row.push(cell!(String::from("X")));
+                    } else {
+                        row.push(cell!(String::from("")));
+                    }
+                }
+
+                sni_frontend_table.add_row(Row::new(row));
+            }
+
+            sni_frontend_table.printstd();
+
             println!("\nbackends configuration for {}:\n", needle);
 
             for (key, values) in backend_data.iter() {
@@ -463,7 +787,9 @@ pub fn print_query_response_data(
                 ];
 
                 for val in values {
-                    if keys.contains(&val) {
+                    if unhealthy_backends.contains(&(val, &key.backend_id)) {
+                        row.push(cell!("down"));
+                    } else if keys.contains(&val) {
                         row.push(cell!("X"));
                     } else {
                         row.push(cell!(""));
@@ -566,12 +892,275 @@ pub fn print_certificates(data: BTreeMap<String, QueryAnswer>, json: bool) -> an
                     println!("\tnot found");
                 }
             }
+            QueryAnswerCertificate::Resolve(opt) => match opt {
+                Some(certificate) => {
+                    let expiration =
+                        match time::OffsetDateTime::from_unix_timestamp(certificate.expiration) {
+                            Ok(date) => date.to_string(),
+                            Err(_) => certificate.expiration.to_string(),
+                        };
+
+                    println!("\tfingerprint:\t{}", certificate.fingerprint);
+                    println!("\tnames:\t\t{}", certificate.names.join(", "));
+                    println!("\texpiration:\t{}", expiration);
+                    println!("\tTLS versions:\t{:?}", certificate.versions);
+                    if certificate.default {
+                        println!("\tthe domain matched no certificate, this is the default one");
+                    }
+                }
+                None => println!("\tno certificate would be presented"),
+            },
         }
         println!();
     }
     Ok(())
 }
 
+/// one row per backend, the health of the backend as the workers see it put together
+/// "down" on every worker, or on some of them
+fn share(label: &str, count: usize, worker_count: usize) -> String {
+    if count == worker_count {
+        label.to_owned()
+    } else {
+        format!("{} ({}/{} workers)", label, count, worker_count)
+    }
+}
+
+/// up, down or ejected, from the health of a backend on each worker
+pub fn backend_state(health: &[&BackendHealth], worker_count: usize) -> String {
+    let down = health.iter().filter(|backend| !backend.healthy).count();
+    let ejected = health.iter().filter(|backend| backend.ejected).count();
+    match (down, ejected) {
+        (0, 0) => String::from("up"),
+        (0, ejected) => share("ejected", ejected, worker_count),
+        (down, _) => share("down", down, worker_count),
+    }
+}
+
+pub fn print_backend_health(
+    data: BTreeMap<String, QueryAnswer>,
+    json: bool,
+    output: &OutputArgs,
+) -> anyhow::Result<()> {
+    if json {
+        print_json_response(&data)?;
+        return Ok(());
+    }
+
+    for (worker_id, answer) in data.iter() {
+        if !matches!(answer, QueryAnswer::BackendHealth(_)) {
+            eprintln!(
+                "unexpected backend health answer from worker {}: {:?}",
+                worker_id, answer
+            );
+            exit(1);
+        }
+    }
+
+    let mut listing = Listing::new(&[
+        "cluster",
+        "id",
+        "address",
+        "state",
+        "last_check",
+        "latency",
+        "failures_in_a_row",
+        "failures",
+    ]);
+    for row in backend_health_rows(&data) {
+        listing.add_row(row);
+    }
+
+    listing.print(output)
+}
+
+/// a row for each backend, with its health on the workers put together
+fn backend_health_rows(data: &BTreeMap<String, QueryAnswer>) -> Vec<Vec<String>> {
+    let worker_count = data.len();
+    let mut backends: BTreeMap<(String, String, String), Vec<&BackendHealth>> = BTreeMap::new();
+    for answer in data.values() {
+        let health = match answer {
+            QueryAnswer::BackendHealth(health) => health,
+            _ => continue,
+        };
+
+        for backend in health.iter() {
+            backends
+                .entry((
+                    backend.cluster_id.to_owned(),
+                    backend.backend_id.to_owned(),
+                    backend.address.to_string(),
+                ))
+                .or_default()
+                .push(backend);
+        }
+    }
+
+    let mut rows = Vec::new();
+    for ((cluster_id, backend_id, address), health) in backends.iter() {
+        let state = backend_state(health, worker_count);
+
+        let checked: Vec<bool> = health
+            .iter()
+            .filter_map(|backend| backend.last_check)
+            .collect();
+        let failed = checked.iter().filter(|success| !**success).count();
+        let last_check = match (checked.len(), failed) {
+            (0, _) => String::from("-"),
+            (_, 0) => String::from("ok"),
+            (_, failed) => share("failed", failed, worker_count),
+        };
+
+        let latency = health
+            .iter()
+            .filter_map(|backend| backend.latency)
+            .max()
+            .map(|latency| format!("{}ms", latency))
+            .unwrap_or_else(|| String::from("-"));
+
+        rows.push(vec![
+            cluster_id.to_owned(),
+            backend_id.to_owned(),
+            address.to_owned(),
+            state,
+            last_check,
+            latency,
+            health
+                .iter()
+                .map(|backend| backend.failures)
+                .max()
+                .unwrap_or(0)
+                .to_string(),
+            health
+                .iter()
+                .map(|backend| backend.total_failures)
+                .sum::<u64>()
+                .to_string(),
+        ]);
+    }
+
+    rows
+}
+
+/// the HTTP, HTTPS, TCP and SNI frontends routing to the cluster
+fn cluster_frontends(state: &ConfigState, cluster_id: &str) -> String {
+    let cluster = state.cluster_state(cluster_id);
+    let http_frontends = cluster
+        .http_frontends
+        .iter()
+        .map(|frontend| ("http", frontend));
+    let https_frontends = cluster
+        .https_frontends
+        .iter()
+        .map(|frontend| ("https", frontend));
+
+    let mut frontends = BTreeSet::new();
+    for (scheme, frontend) in http_frontends.chain(https_frontends) {
+        frontends.insert(match &frontend.path {
+            PathRule::Prefix(prefix) => format!("{}://{}{}", scheme, frontend.hostname, prefix),
+            path => format!("{}://{} ({})", scheme, frontend.hostname, path),
+        });
+    }
+    for frontend in cluster.tcp_frontends.iter() {
+        frontends.insert(format!("tcp://{}", frontend.address));
+    }
+    for frontend in cluster.sni_frontends.iter() {
+        frontends.insert(format!("sni://{}", frontend.hostname));
+    }
+    frontends.into_iter().collect::<Vec<_>>().join(", ")
+}
+
+/// one row per backend, the counts of the workers added up
+pub fn print_backend_list(
+    data: BTreeMap<String, QueryAnswer>,
+    state: &ConfigState,
+    output: &OutputArgs,
+) -> anyhow::Result<()> {
+    let worker_count = data.len();
+    let mut backends: BTreeMap<(&str, &str, SocketAddr), Vec<&QueryAnswerBackend>> =
+        BTreeMap::new();
+    for (worker_id, answer) in data.iter() {
+        let worker_backends = match answer {
+            QueryAnswer::Backends(backends) => backends,
+            answer => bail!(
+                "unexpected backend list answer from worker {}: {:?}",
+                worker_id,
+                answer
+            ),
+        };
+
+        for backend in worker_backends.iter() {
+            backends
+                .entry((&backend.cluster_id, &backend.backend_id, backend.address))
+                .or_default()
+                .push(backend);
+        }
+    }
+
+    let mut listing = Listing::new(&[
+        "cluster",
+        "id",
+        "address",
+        "weight",
+        "backup",
+        "sticky_id",
+        "state",
+        "connections",
+        "requests",
+        "failures",
+        "frontends",
+    ]);
+
+    let mut frontends = BTreeMap::new();
+    for ((cluster_id, backend_id, address), workers) in backends.iter() {
+        let backend = workers[0];
+        let draining = workers.iter().filter(|backend| backend.draining).count();
+        let down = workers.iter().filter(|backend| !backend.healthy).count();
+        let ejected = workers.iter().filter(|backend| backend.ejected).count();
+        let backend_state = match (draining, down, ejected) {
+            (0, 0, 0) => String::from("up"),
+            (0, 0, ejected) => share("ejected", ejected, worker_count),
+            (0, down, _) => share("down", down, worker_count),
+            (draining, _, _) => share("draining", draining, worker_count),
+        };
+
+        listing.add_row(vec![
+            cluster_id.to_string(),
+            backend_id.to_string(),
+            address.to_string(),
+            backend
+                .weight
+                .map(|weight| weight.to_string())
+                .unwrap_or_else(|| String::from("-")),
+            String::from(if backend.backup { "X" } else { "" }),
+            backend.sticky_id.clone().unwrap_or_default(),
+            backend_state,
+            workers
+                .iter()
+                .map(|backend| backend.active_connections)
+                .sum::<usize>()
+                .to_string(),
+            workers
+                .iter()
+                .map(|backend| backend.active_requests)
+                .sum::<usize>()
+                .to_string(),
+            workers
+                .iter()
+                .map(|backend| backend.failures)
+                .max()
+                .unwrap_or(0)
+                .to_string(),
+            frontends
+                .entry(*cluster_id)
+                .or_insert_with(|| cluster_frontends(state, cluster_id))
+                .to_owned(),
+        ]);
+    }
+
+    listing.print(output)
+}
+
 fn format_tags_to_string(tags: Option<&BTreeMap<String, String>>) -> String {
     tags.map(|tags| {
         tags.iter()
@@ -582,6 +1171,14 @@ fn format_tags_to_string(tags: Option<&BTreeMap<String, String>>) -> String {
     .unwrap_or_default()
 }
 
+fn format_header_rules_to_string(headers: &[HeaderRule]) -> String {
+    headers
+        .iter()
+        .map(|header| header.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 pub fn print_available_metrics(answers: &BTreeMap<String, QueryAnswer>) -> anyhow::Result<()> {
     let mut available_metrics: (HashSet<String>, HashSet<String>) =
         (HashSet::new(), HashSet::new());
@@ -626,3 +1223,75 @@ pub fn print_available_metrics(answers: &BTreeMap<String, QueryAnswer>) -> anyho
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health(backend_id: &str, healthy: bool, ejected: bool, failures: u32) -> BackendHealth {
+        BackendHealth {
+            cluster_id: String::from("app"),
+            backend_id: String::from(backend_id),
+            address: "127.0.0.1:1026".parse().unwrap(),
+            healthy,
+            ejected,
+            last_check: Some(healthy),
+            latency: Some(u64::from(failures) + 2),
+            successes: 0,
+            failures,
+            total_failures: u64::from(failures),
+        }
+    }
+
+    #[test]
+    fn backend_health_across_workers() {
+        let data = BTreeMap::from([
+            (
+                String::from("0"),
+                QueryAnswer::BackendHealth(vec![
+                    health("app-0", true, false, 0),
+                    health("app-1", false, false, 3),
+                    health("app-2", false, false, 5),
+                    health("app-3", true, true, 0),
+                ]),
+            ),
+            (
+                String::from("1"),
+                QueryAnswer::BackendHealth(vec![
+                    health("app-0", true, false, 0),
+                    health("app-1", true, false, 0),
+                    health("app-2", false, false, 4),
+                    health("app-3", true, false, 0),
+                ]),
+            ),
+        ]);
+
+        let rows = backend_health_rows(&data);
+        let states: Vec<&[String]> = rows.iter().map(|row| &row[1..]).collect();
+        assert_eq!(
+            states,
+            vec![
+                ["app-0", "127.0.0.1:1026", "up", "ok", "2ms", "0", "0"],
+                [
+                    "app-1",
+                    "127.0.0.1:1026",
+                    "down (1/2 workers)",
+                    "failed (1/2 workers)",
+                    "5ms",
+                    "3",
+                    "3"
+                ],
+                ["app-2", "127.0.0.1:1026", "down", "failed", "7ms", "5", "9"],
+                [
+                    "app-3",
+                    "127.0.0.1:1026",
+                    "ejected (1/2 workers)",
+                    "ok",
+                    "2ms",
+                    "0",
+                    "0"
+                ],
+            ]
+        );
+    }
+}
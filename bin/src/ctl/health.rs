@@ -0,0 +1,197 @@
+//! `sozu healthcheck`: exits with 0 only if the proxy answers within the
+//! deadline and its workers are running, for container and systemd probes
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use serde::Serialize;
+
+use sozu_command_lib::{
+    command::{CommandRequestOrder, CommandResponseContent, CommandStatus, RunState, WorkerInfo},
+    config::Config,
+    state::ConfigState,
+};
+
+use crate::ctl::{command::generate_id, display::print_json_response, CommandManager};
+
+#[derive(Serialize, Debug)]
+struct HealthReport {
+    healthy: bool,
+    elapsed_ms: u128,
+    problems: Vec<String>,
+}
+
+impl CommandManager {
+    pub fn healthcheck(
+        &mut self,
+        listeners: bool,
+        deadline: u64,
+        json: bool,
+    ) -> anyhow::Result<()> {
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(deadline);
+
+        let mut problems = match self.request_content_before(CommandRequestOrder::Status, deadline)
+        {
+            Ok(CommandResponseContent::Status(workers)) => {
+                worker_problems(&workers, self.config.worker_count)
+            }
+            Ok(_) => vec![String::from("wrong kind of answer to the status order")],
+            Err(e) => vec![e.to_string()],
+        };
+
+        if listeners && problems.is_empty() {
+            match self.request_content_before(CommandRequestOrder::DumpState, deadline) {
+                Ok(CommandResponseContent::State(state)) => {
+                    problems.extend(listener_problems(&self.config, &state))
+                }
+                Ok(_) => problems.push(String::from("wrong kind of answer to the dump order")),
+                Err(e) => problems.push(e.to_string()),
+            }
+        }
+
+        let report = HealthReport {
+            healthy: problems.is_empty(),
+            elapsed_ms: start.elapsed().as_millis(),
+            problems,
+        };
+
+        if json {
+            print_json_response(&report)?;
+            if !report.healthy {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+
+        if !report.healthy {
+            bail!("unhealthy: {}", report.problems.join(", "));
+        }
+        println!("healthy ({}ms)", report.elapsed_ms);
+        Ok(())
+    }
+
+    /// sends the order and returns the content of its answer, if it came
+    /// before the deadline
+    fn request_content_before(
+        &mut self,
+        order: CommandRequestOrder,
+        deadline: Instant,
+    ) -> anyhow::Result<CommandResponseContent> {
+        let id = generate_id();
+        self.send_request(&id, order)?;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                bail!("no answer of the proxy before the deadline");
+            }
+
+            let response = match self.channel.read_message_blocking_timeout(Some(remaining)) {
+                Some(response) => response,
+                None => bail!("no answer of the proxy before the deadline"),
+            };
+
+            if id != response.id {
+                bail!("received message with invalid id: {:?}", response);
+            }
+            match response.status {
+                CommandStatus::Processing => {}
+                CommandStatus::Error => bail!("{}", response.message),
+                CommandStatus::Ok => match response.content {
+                    Some(content) => return Ok(content),
+                    None => bail!("no data in the answer"),
+                },
+            }
+        }
+    }
+}
+
+/// the stopped workers were replaced by an upgrade or crashed, so only the
+/// running ones are counted
+fn worker_problems(workers: &[WorkerInfo], worker_count: u16) -> Vec<String> {
+    let mut problems = workers
+        .iter()
+        .filter(|worker| {
+            worker.run_state != RunState::Running
+                && worker.run_state != RunState::Stopping
+                && worker.run_state != RunState::Stopped
+        })
+        .map(|worker| format!("worker {} is {}", worker.id, worker.run_state))
+        .collect::<Vec<_>>();
+
+    let running = workers
+        .iter()
+        .filter(|worker| worker.run_state == RunState::Running)
+        .count();
+    if running < worker_count as usize {
+        problems.push(format!(
+            "{} workers running out of {}",
+            running, worker_count
+        ));
+    }
+    problems
+}
+
+/// the listeners of the configuration file that are missing or not active
+fn listener_problems(config: &Config, state: &ConfigState) -> Vec<String> {
+    let http = config.http_listeners.iter().map(|listener| {
+        let active = state.http_listeners.get(&listener.address);
+        (listener.address, active.map(|(_, active)| *active))
+    });
+    let https = config.https_listeners.iter().map(|listener| {
+        let active = state.https_listeners.get(&listener.address);
+        (listener.address, active.map(|(_, active)| *active))
+    });
+    let tcp = config.tcp_listeners.iter().map(|listener| {
+        let active = state.tcp_listeners.get(&listener.address);
+        (listener.address, active.map(|(_, active)| *active))
+    });
+
+    http.chain(https)
+        .chain(tcp)
+        .filter_map(|(address, active)| match active {
+            Some(true) => None,
+            Some(false) => Some(format!("listener {} is not active", address)),
+            None => Some(format!("listener {} is missing", address)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn worker(id: u32, run_state: RunState) -> WorkerInfo {
+        WorkerInfo {
+            id,
+            pid: 1000 + id as i32,
+            run_state,
+            cpu_cores: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn upgraded_workers_are_healthy() {
+        let workers = vec![
+            worker(0, RunState::Stopped),
+            worker(1, RunState::Running),
+            worker(2, RunState::Running),
+        ];
+        assert!(worker_problems(&workers, 2).is_empty());
+    }
+
+    #[test]
+    fn missing_and_stuck_workers_are_unhealthy() {
+        let workers = vec![
+            worker(0, RunState::NotAnswering),
+            worker(1, RunState::Running),
+        ];
+        assert_eq!(
+            worker_problems(&workers, 2),
+            vec![
+                String::from("worker 0 is NotAnswering"),
+                String::from("1 workers running out of 2"),
+            ]
+        );
+    }
+}
@@ -1,29 +1,57 @@
 mod command;
+mod completion;
 mod display;
+mod health;
+mod output;
 mod request_builder;
+mod shell;
+pub mod state_file;
+mod top;
 
 use std::time::Duration;
 
 use anyhow::Context;
+use mio::net::UnixStream;
 
 use sozu_command_lib::{
     channel::Channel,
     command::{CommandRequest, CommandResponse},
     config::Config,
+    proxy::AccessLogFilter,
 };
 
 use crate::{
     cli::{self, *},
-    get_config_file_path, load_configuration, util,
+    get_config_file_path, load_configuration, remote, util,
 };
 
+use self::request_builder::CertificateFiles;
+
 pub struct CommandManager {
     channel: Channel<CommandRequest, CommandResponse>,
     timeout: Duration,
     config: Config,
+    /// the orders are only validated by the main process
+    dry_run: bool,
+    /// the workers receiving the configuration orders, all of them if empty
+    worker_ids: Vec<u32>,
 }
 
 pub fn ctl(args: cli::Args) -> Result<(), anyhow::Error> {
+    // the completion works without a configuration, and without a running proxy
+    match &args.cmd {
+        SubCmd::Completion { shell } => {
+            print!("{}", completion::script(*shell));
+            return Ok(());
+        }
+        SubCmd::Complete { line } => return completion::complete(line),
+        // the state files are edited without a running proxy
+        SubCmd::State {
+            cmd: StateCmd::EditFile { cmd },
+        } => return state_file::edit_state_file(cmd),
+        _ => {}
+    }
+
     let config_file_path = get_config_file_path(&args)?;
     let config = load_configuration(config_file_path)?;
 
@@ -38,9 +66,15 @@ pub fn ctl(args: cli::Args) -> Result<(), anyhow::Error> {
         std::process::exit(0);
     }
 
-    let channel = create_channel(&config).with_context(|| {
-        "could not connect to the command unix socket. Are you sure the proxy is up?"
-    })?;
+    let channel = match (&args.remote, &args.token) {
+        (Some(address), Some(token)) => {
+            create_remote_channel(&config, address, token, args.remote_ca.as_deref())
+                .with_context(|| "could not connect to the remote command channel")?
+        }
+        _ => create_channel(&config).with_context(|| {
+            "could not connect to the command unix socket. Are you sure the proxy is up?"
+        })?,
+    };
 
     let timeout = Duration::from_millis(args.timeout.unwrap_or(config.ctl_command_timeout));
 
@@ -48,6 +82,8 @@ pub fn ctl(args: cli::Args) -> Result<(), anyhow::Error> {
         channel,
         timeout,
         config,
+        dry_run: args.dry_run,
+        worker_ids: args.workers,
     };
     command_manager.handle_command(args.cmd)
 }
@@ -55,16 +91,33 @@ pub fn ctl(args: cli::Args) -> Result<(), anyhow::Error> {
 impl CommandManager {
     fn handle_command(&mut self, command: SubCmd) -> anyhow::Result<()> {
         match command {
-            SubCmd::Shutdown { hard, worker } => {
+            SubCmd::Shutdown {
+                hard,
+                worker,
+                deadline,
+            } => {
                 if hard {
                     self.hard_stop(worker)
                 } else {
-                    self.soft_stop(worker)
+                    self.soft_stop(worker, deadline)
                 }
             }
             SubCmd::Upgrade { worker: None } => self.upgrade_main(),
             SubCmd::Upgrade { worker: Some(id) } => self.upgrade_worker(id),
-            SubCmd::Status { json } => self.status(json),
+            SubCmd::Worker {
+                cmd: Some(WorkerCmd::Restart { id }),
+                ..
+            } => self.restart_worker(id),
+            SubCmd::Status {
+                json,
+                watch,
+                output,
+            } => self.status(json, watch, output),
+            SubCmd::Healthcheck {
+                listeners,
+                deadline,
+                json,
+            } => self.healthcheck(listeners, deadline, json),
             SubCmd::Metrics { cmd, json } => match cmd {
                 MetricsCmd::Get {
                     list,
@@ -72,7 +125,15 @@ impl CommandManager {
                     names,
                     clusters,
                     backends,
-                } => self.get_metrics(json, list, refresh, names, clusters, backends),
+                    watch,
+                } => self.get_metrics(
+                    json,
+                    list,
+                    watch.or(refresh.map(u64::from)),
+                    names,
+                    clusters,
+                    backends,
+                ),
                 _ => self.configure_metrics(cmd),
             },
             SubCmd::Logging { level } => self.logging_filter(&level),
@@ -80,20 +141,30 @@ impl CommandManager {
                 StateCmd::Save { file } => self.save_state(file),
                 StateCmd::Load { file } => self.load_state(file),
                 StateCmd::Dump { json } => self.dump_state(json),
+                StateCmd::Apply { file, json, strict } => self.apply_state(file, json, strict),
+                StateCmd::ExportConfig { file, directory } => self.export_config(file, directory),
+                StateCmd::History { json, output } => self.state_history(json, output),
+                StateCmd::Rollback { to, json } => self.rollback_state(to, json),
+                StateCmd::SyncPeers => self.sync_peers(),
+                StateCmd::EditFile { cmd } => state_file::edit_state_file(&cmd),
             },
             SubCmd::Reload { file, json } => self.reload_configuration(file, json),
+            SubCmd::Batch { file, strict } => self.batch(&file, strict),
             SubCmd::Cluster { cmd } => self.cluster_command(cmd),
             SubCmd::Backend { cmd } => self.backend_command(cmd),
             SubCmd::Frontend { cmd } => match cmd {
                 FrontendCmd::Http { cmd } => self.http_frontend_command(cmd),
                 FrontendCmd::Https { cmd } => self.https_frontend_command(cmd),
                 FrontendCmd::Tcp { cmd } => self.tcp_frontend_command(cmd),
+                FrontendCmd::Sni { cmd } => self.sni_frontend_command(cmd),
                 FrontendCmd::List {
                     http,
                     https,
                     tcp,
                     domain,
-                } => self.list_frontends(http, https, tcp, domain),
+                    watch,
+                    output,
+                } => self.list_frontends(http, https, tcp, domain, watch, output),
             },
             SubCmd::Listener { cmd } => match cmd {
                 ListenerCmd::Http { cmd } => self.http_listener_command(cmd),
@@ -105,9 +176,27 @@ impl CommandManager {
                     certificate,
                     chain,
                     key,
+                    key_passphrase,
+                    pkcs12,
+                    password_file,
+                    address,
+                    tls_versions,
+                    ocsp_response,
+                    priority,
+                } => self.add_certificate(
                     address,
+                    CertificateFiles {
+                        certificate: certificate.as_deref(),
+                        certificate_chain: chain.as_deref(),
+                        key: key.as_deref(),
+                        key_passphrase: key_passphrase.as_deref(),
+                        pkcs12: pkcs12.as_deref(),
+                        password: password_file.as_deref(),
+                        ocsp_response: ocsp_response.as_deref(),
+                    },
                     tls_versions,
-                } => self.add_certificate(address, &certificate, &chain, &key, tls_versions),
+                    priority,
+                ),
                 CertificateCmd::Remove {
                     certificate,
                     address,
@@ -119,29 +208,90 @@ impl CommandManager {
                     certificate,
                     chain,
                     key,
+                    key_passphrase,
                     old_certificate,
                     address,
                     old_fingerprint,
                     tls_versions,
+                    ocsp_response,
+                    priority,
                 } => self.replace_certificate(
                     address,
-                    &certificate,
-                    &chain,
-                    &key,
+                    CertificateFiles {
+                        certificate: Some(&certificate),
+                        certificate_chain: Some(&chain),
+                        key: Some(&key),
+                        key_passphrase: key_passphrase.as_deref(),
+                        ocsp_response: ocsp_response.as_deref(),
+                        ..Default::default()
+                    },
                     old_certificate.as_deref(),
                     old_fingerprint.as_deref(),
                     tls_versions,
+                    priority,
                 ),
+                CertificateCmd::SetDefault {
+                    address,
+                    certificate,
+                    fingerprint,
+                } => self.set_default_certificate(
+                    address,
+                    certificate.as_deref(),
+                    fingerprint.as_deref(),
+                ),
+                CertificateCmd::List {
+                    expiring,
+                    domain,
+                    address,
+                    json,
+                    output,
+                } => self.list_certificates(expiring, domain, address, json, output),
             },
+            SubCmd::Acl { cmd } => self.acl_command(cmd),
             SubCmd::Query { cmd, json } => match cmd {
                 QueryCmd::Clusters { id, domain } => self.query_cluster(json, id, domain),
                 QueryCmd::Certificates {
                     fingerprint,
                     domain,
-                } => self.query_certificate(json, fingerprint, domain),
+                    address,
+                } => self.query_certificate(json, fingerprint, domain, address),
+                QueryCmd::BackendHealth { id, output } => {
+                    self.query_backend_health(json, id, output)
+                }
+            },
+            SubCmd::Config { cmd } => match cmd {
+                ConfigCmd::Check {} => Ok(()), // noop, handled at the beginning of the method
+                ConfigCmd::Diff { file, json } => self.config_diff(file, json),
             },
-            SubCmd::Config { cmd: _ } => Ok(()), // noop, handled at the beginning of the method
+            // noop, handled at the beginning of ctl
+            SubCmd::Completion { .. } | SubCmd::Complete { .. } => Ok(()),
             SubCmd::Events => self.events(),
+            SubCmd::Top { interval } => self.top(interval),
+            SubCmd::Audit {
+                cmd:
+                    AuditCmd::Tail {
+                        lines,
+                        json,
+                        output,
+                    },
+            } => self.audit_log_tail(lines, json, output),
+            SubCmd::Logs {
+                cmd:
+                    LogsCmd::Tail {
+                        cluster_id,
+                        status,
+                        rate,
+                        json,
+                    },
+            } => self.tail_access_logs(
+                AccessLogFilter {
+                    cluster_id,
+                    status,
+                    max_per_second: rate,
+                },
+                json,
+            ),
+            SubCmd::Shell { history } => self.shell(history),
             rest => {
                 panic!("that command should have been handled earlier: {:x?}", rest)
             }
@@ -161,3 +311,21 @@ pub fn create_channel(config: &Config) -> anyhow::Result<Channel<CommandRequest,
     channel.blocking();
     Ok(channel)
 }
+
+/// the command channel of a proxy on another host, relayed over TLS
+pub fn create_remote_channel(
+    config: &Config,
+    address: &str,
+    token: &str,
+    ca: Option<&str>,
+) -> anyhow::Result<Channel<CommandRequest, CommandResponse>> {
+    let stream = remote::connect(address, token, ca)?;
+    let mut channel = Channel::new(
+        UnixStream::from_std(stream),
+        config.command_buffer_size,
+        config.max_command_buffer_size,
+    );
+
+    channel.blocking();
+    Ok(channel)
+}
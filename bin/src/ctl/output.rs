@@ -0,0 +1,223 @@
+//! the listings of the command line: rows of named columns, printed as a
+//! table, JSON, YAML or CSV, filtered, sorted and reduced to some of their
+//! columns as asked with `--output`, `--filter`, `--sort` and `--columns`
+use std::cmp::Ordering;
+
+use anyhow::{bail, Context};
+use prettytable::{Cell, Row, Table};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+use crate::cli::{OutputArgs, OutputFormat};
+
+pub struct Listing {
+    columns: Vec<&'static str>,
+    rows: Vec<Vec<String>>,
+}
+
+/// a row serialized as a map, keeping the order of the columns
+struct Record<'a> {
+    columns: &'a [&'static str],
+    values: &'a [String],
+}
+
+impl Serialize for Record<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.columns.len()))?;
+        for (column, value) in self.columns.iter().zip(self.values) {
+            map.serialize_entry(column, value)?;
+        }
+        map.end()
+    }
+}
+
+impl Listing {
+    pub fn new(columns: &[&'static str]) -> Self {
+        Listing {
+            columns: columns.to_vec(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn add_row(&mut self, row: Vec<String>) {
+        debug_assert_eq!(row.len(), self.columns.len());
+        self.rows.push(row);
+    }
+
+    pub fn print(self, options: &OutputArgs) -> anyhow::Result<()> {
+        print!("{}", self.render(options)?);
+        Ok(())
+    }
+
+    pub fn render(mut self, options: &OutputArgs) -> anyhow::Result<String> {
+        for (column, value) in options.filters.iter() {
+            let index = self.column_index(column)?;
+            self.rows.retain(|row| row[index].contains(value.as_str()));
+        }
+
+        if let Some(column) = &options.sort {
+            let index = self.column_index(column)?;
+            self.rows
+                .sort_by(|a, b| compare_cells(&a[index], &b[index]));
+            if options.reverse {
+                self.rows.reverse();
+            }
+        }
+
+        let indexes = match options.columns.is_empty() {
+            true => (0..self.columns.len()).collect(),
+            false => options
+                .columns
+                .iter()
+                .map(|column| self.column_index(column))
+                .collect::<anyhow::Result<Vec<usize>>>()?,
+        };
+        let columns: Vec<&'static str> = indexes.iter().map(|i| self.columns[*i]).collect();
+        let rows: Vec<Vec<String>> = self
+            .rows
+            .into_iter()
+            .map(|row| indexes.iter().map(|i| row[*i].to_owned()).collect())
+            .collect();
+
+        let records = || {
+            rows.iter()
+                .map(|values| Record {
+                    columns: &columns,
+                    values,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        Ok(match options.output {
+            OutputFormat::Table => {
+                let mut table = Table::new();
+                table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+                table.add_row(Row::new(columns.iter().map(|c| Cell::new(c)).collect()));
+                for row in rows.iter() {
+                    table.add_row(Row::new(row.iter().map(|value| Cell::new(value)).collect()));
+                }
+                table.to_string()
+            }
+            OutputFormat::Json => {
+                let mut json = serde_json::to_string_pretty(&records())
+                    .with_context(|| "could not serialize the listing to JSON")?;
+                json.push('\n');
+                json
+            }
+            OutputFormat::Yaml => serde_yaml::to_string(&records())
+                .with_context(|| "could not serialize the listing to YAML")?,
+            OutputFormat::Csv => {
+                let mut csv = csv_line(columns.iter().copied());
+                for row in rows.iter() {
+                    csv.push_str(&csv_line(row.iter().map(String::as_str)));
+                }
+                csv
+            }
+        })
+    }
+
+    fn column_index(&self, column: &str) -> anyhow::Result<usize> {
+        match self.columns.iter().position(|c| *c == column) {
+            Some(index) => Ok(index),
+            None => bail!(
+                "unknown column '{}', expected one of: {}",
+                column,
+                self.columns.join(", ")
+            ),
+        }
+    }
+}
+
+/// numbers, like counts, latencies or durations, are sorted by value,
+/// the rest alphabetically
+fn compare_cells(a: &str, b: &str) -> Ordering {
+    match (leading_number(a), leading_number(b)) {
+        (Some(x), Some(y)) => x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+        _ => a.cmp(b),
+    }
+}
+
+/// the number a cell starts with, like 12 in "12ms"
+fn leading_number(cell: &str) -> Option<f64> {
+    let end = cell
+        .char_indices()
+        .find(|(i, c)| !(c.is_ascii_digit() || *c == '.' || (*i == 0 && *c == '-')))
+        .map(|(i, _)| i)
+        .unwrap_or(cell.len());
+    cell[..end].parse().ok()
+}
+
+fn csv_line<'a>(values: impl Iterator<Item = &'a str>) -> String {
+    let mut line = values
+        .map(|value| {
+            if value.contains([',', '"', '\n']) {
+                format!("\"{}\"", value.replace('"', "\"\""))
+            } else {
+                value.to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push('\n');
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing() -> Listing {
+        let mut listing = Listing::new(&["id", "address", "latency"]);
+        listing.add_row(vec!["b".into(), "10.0.0.2:80".into(), "120ms".into()]);
+        listing.add_row(vec!["a".into(), "10.0.0.1:80".into(), "9ms".into()]);
+        listing.add_row(vec!["c, d".into(), "10.0.1.1:80".into(), "-".into()]);
+        listing
+    }
+
+    fn options(output: OutputFormat) -> OutputArgs {
+        OutputArgs {
+            output,
+            columns: Vec::new(),
+            sort: None,
+            reverse: false,
+            filters: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn csv_with_columns_sorted_by_number() {
+        let options = OutputArgs {
+            columns: vec!["latency".into(), "id".into()],
+            sort: Some("latency".into()),
+            ..options(OutputFormat::Csv)
+        };
+        assert_eq!(
+            listing().render(&options).unwrap(),
+            "latency,id\n-,\"c, d\"\n9ms,a\n120ms,b\n"
+        );
+    }
+
+    #[test]
+    fn json_filtered_in_column_order() {
+        let options = OutputArgs {
+            filters: vec![("address".into(), "10.0.0.".into())],
+            sort: Some("id".into()),
+            reverse: true,
+            ..options(OutputFormat::Json)
+        };
+        let json = listing().render(&options).unwrap();
+        assert!(json.find("\"id\"").unwrap() < json.find("\"address\"").unwrap());
+        let rows: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["id"], "b");
+        assert_eq!(rows[1]["latency"], "9ms");
+    }
+
+    #[test]
+    fn unknown_column() {
+        let options = OutputArgs {
+            columns: vec!["state".into()],
+            ..options(OutputFormat::Table)
+        };
+        assert!(listing().render(&options).is_err());
+    }
+}
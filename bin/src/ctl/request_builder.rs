@@ -3,22 +3,30 @@ use std::net::SocketAddr;
 use anyhow::{bail, Context};
 
 use sozu_command_lib::{
-    certificate::{calculate_fingerprint, split_certificate_chain},
-    config::{Config, FileListenerProtocolConfig, Listener, ProxyProtocolConfig},
+    certificate::{calculate_fingerprint, PemBundle},
+    config::{
+        Config, FileBackendTlsConfig, FileClientAuthConfig, FileListenerProtocolConfig, Listener,
+        ProxyProtocolConfig,
+    },
     proxy::{
-        ActivateListener, AddCertificate, Backend, CertificateAndKey, CertificateFingerprint,
-        Cluster, DeactivateListener, HttpFrontend, ListenerType, LoadBalancingParams, PathRule,
-        ProxyRequestOrder, RemoveBackend, RemoveCertificate, RemoveListener, ReplaceCertificate,
-        RulePosition, TcpFrontend, TcpListener, TlsVersion,
+        self, Acl, ActivateListener, AddCertificate, AffinityTable, Backend, BackendTls,
+        CertificateAndKey, CertificateFingerprint, Cluster, ClusterMaintenance, DeactivateListener,
+        DrainBackend, ForwardProxy, HeaderAction, HeaderOperation, HeaderPosition, HeaderRule,
+        HealthCheck, HttpFrontend, ListenerType, LoadBalancingParams, OutlierDetection,
+        PathRewrite, PathRule, ProxyRequestOrder, RemoveAcl, RemoveBackend, RemoveCertificate,
+        RemoveListener, ReplaceCertificate, RequestQueue, RulePosition, SetDefaultCertificate,
+        SniFrontend, TcpFrontend, TcpListener, TlsVersion, UpdateBackendWeight, WeightedCluster,
     },
+    state::ClusterDocument,
 };
 
 use crate::{
     cli::{
-        BackendCmd, ClusterCmd, HttpFrontendCmd, HttpListenerCmd, HttpsListenerCmd, LoggingLevel,
-        TcpFrontendCmd, TcpListenerCmd,
+        AclCmd, BackendCmd, BackendTlsArgs, ClusterArgs, ClusterCmd, HealthCheckArgs,
+        HttpFrontendCmd, HttpListenerCmd, HttpsListenerCmd, LoggingLevel, OutlierDetectionArgs,
+        Route, SniFrontendCmd, TcpFrontendCmd, TcpListenerCmd,
     },
-    ctl::CommandManager,
+    ctl::{display::print_json_response, CommandManager},
 };
 
 impl CommandManager {
@@ -30,14 +38,25 @@ impl CommandManager {
                 address,
                 sticky_id,
                 backup,
+                weight,
+                max_connections,
+                timeouts,
+                tls,
             } => self.order_command(ProxyRequestOrder::AddBackend(Backend {
                 cluster_id: id,
                 address,
                 backend_id,
-                load_balancing_parameters: Some(LoadBalancingParams::default()),
+                load_balancing_parameters: Some(LoadBalancingParams {
+                    weight: weight.unwrap_or(100),
+                }),
                 sticky_id,
                 backup,
+                max_connections,
+                timeouts: timeouts.into(),
+                tls: backend_tls(tls)?.map(Box::new),
             })),
+            BackendCmd::Health { id, json, output } => self.query_backend_health(json, id, output),
+            BackendCmd::List { id, json, output } => self.list_backends(json, id, output),
             BackendCmd::Remove {
                 id,
                 backend_id,
@@ -47,39 +66,231 @@ impl CommandManager {
                 address,
                 backend_id,
             })),
+            BackendCmd::Drain {
+                id,
+                backend_id,
+                address,
+                remove,
+            } => self.order_command(ProxyRequestOrder::DrainBackend(DrainBackend {
+                cluster_id: id,
+                address,
+                backend_id,
+                remove,
+            })),
+            BackendCmd::SetWeight {
+                id,
+                backend_id,
+                address,
+                weight,
+            } => self.order_command(ProxyRequestOrder::UpdateBackendWeight(
+                UpdateBackendWeight {
+                    cluster_id: id,
+                    address,
+                    backend_id,
+                    weight,
+                },
+            )),
         }
     }
 
     pub fn cluster_command(&mut self, cmd: ClusterCmd) -> Result<(), anyhow::Error> {
         match cmd {
-            ClusterCmd::Add {
+            ClusterCmd::Add(args) => {
+                self.order_command(ProxyRequestOrder::AddCluster(cluster(args)?))
+            }
+            ClusterCmd::AddFull {
+                cluster: args,
+                hostnames,
+                path_prefix,
+                http,
+                https,
+                backends,
+                certificate,
+                chain,
+                key,
+                key_passphrase,
+                strict,
+            } => self.add_full_cluster(
+                cluster(args)?,
+                &hostnames,
+                path_prefix,
+                &http,
+                &https,
+                &backends,
+                certificate.as_deref(),
+                chain.as_deref(),
+                key.as_deref(),
+                key_passphrase.as_deref(),
+                strict,
+            ),
+            ClusterCmd::Remove { id } => {
+                self.order_command(ProxyRequestOrder::RemoveCluster { cluster_id: id })
+            }
+            ClusterCmd::Apply { file, strict } => self.apply_clusters(&file, strict),
+            ClusterCmd::Export { ids, json } => self.export_clusters(&ids, json),
+            ClusterCmd::Maintenance {
                 id,
-                sticky_session,
-                https_redirect,
-                send_proxy,
-                expect_proxy,
-                load_balancing_policy,
+                enable,
+                disable: _,
+                answer_file,
             } => {
-                let proxy_protocol = match (send_proxy, expect_proxy) {
-                    (true, true) => Some(ProxyProtocolConfig::RelayHeader),
-                    (true, false) => Some(ProxyProtocolConfig::SendHeader),
-                    (false, true) => Some(ProxyProtocolConfig::ExpectHeader),
-                    _ => None,
+                let answer = match answer_file {
+                    Some(path) => Some(Config::load_file(&path).with_context(|| {
+                        format!("could not load the maintenance answer at {}", path)
+                    })?),
+                    None => None,
                 };
-                self.order_command(ProxyRequestOrder::AddCluster(Cluster {
-                    cluster_id: id,
-                    sticky_session,
-                    https_redirect,
-                    proxy_protocol,
-                    load_balancing: load_balancing_policy,
-                    load_metric: None,
-                    answer_503: None,
-                }))
+
+                self.order_command(ProxyRequestOrder::SetClusterMaintenance(
+                    ClusterMaintenance {
+                        cluster_id: id,
+                        enabled: enable,
+                        answer,
+                    },
+                ))
             }
-            ClusterCmd::Remove { id } => {
-                self.order_command(ProxyRequestOrder::RemoveCluster { cluster_id: id })
+        }
+    }
+
+    /// sends the orders changing the current state into the one of the
+    /// documents, as one batch
+    fn apply_clusters(&mut self, path: &str, strict: bool) -> Result<(), anyhow::Error> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read the cluster file {}", path))?;
+        // a JSON document is also a YAML one
+        let documents: Vec<ClusterDocument> = serde_yaml::from_str(&data)
+            .with_context(|| format!("could not parse the clusters of the file {}", path))?;
+
+        let state = self.current_state()?;
+        let orders = state.diff(&state.with_clusters(&documents));
+        if orders.is_empty() {
+            println!("The clusters are already up to date");
+            return Ok(());
+        }
+
+        self.strict_order_command(ProxyRequestOrder::Batch(orders), strict)
+    }
+
+    /// sends the orders adding the cluster with its frontends on every
+    /// listener, its backends and its certificate, as one batch
+    #[allow(clippy::too_many_arguments)]
+    fn add_full_cluster(
+        &mut self,
+        cluster: Cluster,
+        hostnames: &[String],
+        path_prefix: Option<String>,
+        http_addresses: &[SocketAddr],
+        https_addresses: &[SocketAddr],
+        backend_addresses: &[SocketAddr],
+        certificate_path: Option<&str>,
+        certificate_chain_path: Option<&str>,
+        key_path: Option<&str>,
+        key_passphrase: Option<&str>,
+        strict: bool,
+    ) -> Result<(), anyhow::Error> {
+        let cluster_id = cluster.cluster_id.clone();
+        if self.current_state()?.clusters.contains_key(&cluster_id) {
+            bail!(
+                "the cluster {} already exists, change it with `cluster export` and `cluster apply`",
+                cluster_id
+            );
+        }
+
+        let certificate = match (certificate_path, certificate_chain_path, key_path) {
+            (Some(certificate_path), Some(certificate_chain_path), Some(key_path)) => {
+                let bundle = load_pem_bundle(
+                    certificate_path,
+                    certificate_chain_path,
+                    key_path,
+                    key_passphrase,
+                )?;
+                Some(
+                    load_full_certificate(bundle, Vec::new(), None, 0)
+                        .with_context(|| "Could not load the full certificate")?,
+                )
             }
+            _ => None,
+        };
+
+        let frontend = |address: &SocketAddr, hostname: &String| HttpFrontend {
+            route: proxy::Route::ClusterId(cluster_id.clone()),
+            address: *address,
+            hostname: hostname.to_owned(),
+            path: PathRule::from_cli_options(path_prefix.clone(), None, None),
+            method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
+            position: RulePosition::Tree,
+            tags: None,
+        };
+        let frontends = |addresses: &[SocketAddr]| -> Vec<HttpFrontend> {
+            addresses
+                .iter()
+                .flat_map(|address| {
+                    hostnames
+                        .iter()
+                        .map(move |hostname| frontend(address, hostname))
+                })
+                .collect()
+        };
+
+        let document = ClusterDocument {
+            http_frontends: frontends(http_addresses),
+            https_frontends: frontends(https_addresses),
+            tcp_frontends: Vec::new(),
+            sni_frontends: Vec::new(),
+            // named like the backends of the configuration file
+            backends: backend_addresses
+                .iter()
+                .enumerate()
+                .map(|(index, address)| Backend {
+                    cluster_id: cluster_id.clone(),
+                    backend_id: format!("{}-{}-{}", cluster_id, index, address),
+                    address: *address,
+                    load_balancing_parameters: Some(LoadBalancingParams { weight: 100 }),
+                    sticky_id: None,
+                    backup: None,
+                    max_connections: None,
+                    timeouts: Default::default(),
+                    tls: None,
+                })
+                .collect(),
+            certificates: certificate
+                .iter()
+                .flat_map(|certificate| {
+                    https_addresses.iter().map(|address| AddCertificate {
+                        address: *address,
+                        certificate: certificate.clone(),
+                        names: Vec::new(),
+                        expired_at: None,
+                    })
+                })
+                .collect(),
+            cluster,
+        };
+
+        self.strict_order_command(ProxyRequestOrder::Batch(document.generate_orders()), strict)
+    }
+
+    fn export_clusters(&mut self, cluster_ids: &[String], json: bool) -> Result<(), anyhow::Error> {
+        let state = self.current_state()?;
+        let documents = cluster_ids
+            .iter()
+            .map(|cluster_id| {
+                state
+                    .cluster_document(cluster_id)
+                    .with_context(|| format!("no cluster with the id {}", cluster_id))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        if json {
+            return print_json_response(&documents);
         }
+        print!("{}", serde_yaml::to_string(&documents)?);
+        Ok(())
     }
 
     pub fn tcp_frontend_command(&mut self, cmd: TcpFrontendCmd) -> Result<(), anyhow::Error> {
@@ -101,6 +312,29 @@ impl CommandManager {
         }
     }
 
+    pub fn sni_frontend_command(&mut self, cmd: SniFrontendCmd) -> Result<(), anyhow::Error> {
+        match cmd {
+            SniFrontendCmd::Add {
+                id,
+                address,
+                hostname,
+            } => self.order_command(ProxyRequestOrder::AddSniFrontend(SniFrontend {
+                cluster_id: id,
+                address,
+                hostname,
+            })),
+            SniFrontendCmd::Remove {
+                id,
+                address,
+                hostname,
+            } => self.order_command(ProxyRequestOrder::RemoveSniFrontend(SniFrontend {
+                cluster_id: id,
+                address,
+                hostname,
+            })),
+        }
+    }
+
     pub fn http_frontend_command(&mut self, cmd: HttpFrontendCmd) -> Result<(), anyhow::Error> {
         match cmd {
             HttpFrontendCmd::Add {
@@ -110,14 +344,38 @@ impl CommandManager {
                 path_equals,
                 address,
                 method,
+                methods,
+                reject_other_methods,
+                header,
+                header_prefix,
+                header_regex,
                 route,
+                route_weighted,
+                ab_test_cookie,
+                redirect_to,
+                redirect_code,
+                strip_prefix,
+                rewrite_path,
+                mirror_cluster_id,
                 tags,
+                strict: _,
             } => self.order_command(ProxyRequestOrder::AddHttpFrontend(HttpFrontend {
-                route: route.into(),
+                route: frontend_route(
+                    route,
+                    route_weighted,
+                    ab_test_cookie,
+                    redirect_to,
+                    redirect_code,
+                )?,
                 address,
                 hostname,
                 path: PathRule::from_cli_options(path_prefix, path_regex, path_equals),
                 method: method.map(String::from),
+                methods,
+                reject_other_methods,
+                headers: HeaderRule::from_cli_options(header, header_prefix, header_regex),
+                rewrite_path: frontend_rewrite_path(strip_prefix, rewrite_path)?,
+                mirror_cluster_id,
                 position: RulePosition::Tree,
                 tags,
             })),
@@ -129,13 +387,25 @@ impl CommandManager {
                 path_equals,
                 address,
                 method,
+                methods,
+                header,
+                header_prefix,
+                header_regex,
                 route,
+                route_weighted,
+                redirect_to,
+                redirect_code,
             } => self.order_command(ProxyRequestOrder::RemoveHttpFrontend(HttpFrontend {
-                route: route.into(),
+                route: frontend_route(route, route_weighted, None, redirect_to, redirect_code)?,
                 address,
                 hostname,
                 path: PathRule::from_cli_options(path_prefix, path_regex, path_equals),
                 method: method.map(String::from),
+                methods,
+                reject_other_methods: false,
+                headers: HeaderRule::from_cli_options(header, header_prefix, header_regex),
+                rewrite_path: None,
+                mirror_cluster_id: None,
                 position: RulePosition::Tree,
                 tags: None,
             })),
@@ -151,17 +421,44 @@ impl CommandManager {
                 path_equals,
                 address,
                 method,
+                methods,
+                reject_other_methods,
+                header,
+                header_prefix,
+                header_regex,
                 route,
+                route_weighted,
+                ab_test_cookie,
+                redirect_to,
+                redirect_code,
+                strip_prefix,
+                rewrite_path,
+                mirror_cluster_id,
                 tags,
-            } => self.order_command(ProxyRequestOrder::AddHttpsFrontend(HttpFrontend {
-                route: route.into(),
-                address,
-                hostname,
-                path: PathRule::from_cli_options(path_prefix, path_regex, path_equals),
-                method: method.map(String::from),
-                position: RulePosition::Tree,
-                tags,
-            })),
+                strict,
+            } => self.strict_order_command(
+                ProxyRequestOrder::AddHttpsFrontend(HttpFrontend {
+                    route: frontend_route(
+                        route,
+                        route_weighted,
+                        ab_test_cookie,
+                        redirect_to,
+                        redirect_code,
+                    )?,
+                    address,
+                    hostname,
+                    path: PathRule::from_cli_options(path_prefix, path_regex, path_equals),
+                    method: method.map(String::from),
+                    methods,
+                    reject_other_methods,
+                    headers: HeaderRule::from_cli_options(header, header_prefix, header_regex),
+                    rewrite_path: frontend_rewrite_path(strip_prefix, rewrite_path)?,
+                    mirror_cluster_id,
+                    position: RulePosition::Tree,
+                    tags,
+                }),
+                strict,
+            ),
             HttpFrontendCmd::Remove {
                 hostname,
                 path_prefix,
@@ -169,13 +466,25 @@ impl CommandManager {
                 path_equals,
                 address,
                 method,
+                methods,
+                header,
+                header_prefix,
+                header_regex,
                 route,
+                route_weighted,
+                redirect_to,
+                redirect_code,
             } => self.order_command(ProxyRequestOrder::RemoveHttpsFrontend(HttpFrontend {
-                route: route.into(),
+                route: frontend_route(route, route_weighted, None, redirect_to, redirect_code)?,
                 address,
                 hostname,
                 path: PathRule::from_cli_options(path_prefix, path_regex, path_equals),
                 method: method.map(String::from),
+                methods,
+                reject_other_methods: false,
+                headers: HeaderRule::from_cli_options(header, header_prefix, header_regex),
+                rewrite_path: None,
+                mirror_cluster_id: None,
                 position: RulePosition::Tree,
                 tags: None,
             })),
@@ -189,6 +498,7 @@ impl CommandManager {
                 public_address,
                 answer_404,
                 answer_503,
+                tls_provider,
                 tls_versions,
                 cipher_list,
                 cipher_suites,
@@ -196,10 +506,18 @@ impl CommandManager {
                 groups_list,
                 expect_proxy,
                 sticky_name,
+                fallback_cluster,
+                max_sessions,
                 front_timeout,
                 back_timeout,
                 request_timeout,
                 connect_timeout,
+                strict_sni,
+                request_limits,
+                compression,
+                path_normalization,
+                client_auth,
+                http2,
             } => {
                 let mut listener = Listener::new(address, FileListenerProtocolConfig::Https);
                 listener.public_address = public_address;
@@ -209,6 +527,7 @@ impl CommandManager {
                 if let Some(sticky_name) = sticky_name {
                     listener.sticky_name = sticky_name;
                 }
+                listener.tls_provider = tls_provider;
                 listener.cipher_list = cipher_list;
                 listener.tls_versions = if tls_versions.is_empty() {
                     None
@@ -218,12 +537,26 @@ impl CommandManager {
                 listener.cipher_suites = cipher_suites;
                 listener.signature_algorithms = signature_algorithms;
                 listener.groups_list = groups_list;
+                listener.request_limits = request_limits.into();
+                listener.compression = compression.into();
+                listener.path_normalization = path_normalization.into();
+                listener.fallback_cluster = fallback_cluster;
+                listener.max_sessions = max_sessions;
+                listener.strict_sni = Some(strict_sni);
+                listener.http2 = http2.into();
+                listener.client_auth = client_auth.client_ca.map(|ca| FileClientAuthConfig {
+                    required: client_auth.client_cert_required,
+                    ca,
+                    crl: client_auth.client_crl,
+                    dn_header: client_auth.client_dn_header,
+                });
                 let https_listener = listener
                     .to_tls(
                         front_timeout,
                         back_timeout,
                         connect_timeout,
                         request_timeout,
+                        None,
                     )
                     .with_context(|| "Error creating HTTPS listener")?;
                 self.order_command(ProxyRequestOrder::AddHttpsListener(https_listener))
@@ -249,10 +582,15 @@ impl CommandManager {
                 answer_503,
                 expect_proxy,
                 sticky_name,
+                fallback_cluster,
+                max_sessions,
                 front_timeout,
                 back_timeout,
                 request_timeout,
                 connect_timeout,
+                request_limits,
+                compression,
+                path_normalization,
             } => {
                 let mut listener = Listener::new(address, FileListenerProtocolConfig::Http);
                 listener.public_address = public_address;
@@ -262,6 +600,11 @@ impl CommandManager {
                 if let Some(sticky_name) = sticky_name {
                     listener.sticky_name = sticky_name;
                 }
+                listener.request_limits = request_limits.into();
+                listener.compression = compression.into();
+                listener.path_normalization = path_normalization.into();
+                listener.fallback_cluster = fallback_cluster;
+                listener.max_sessions = max_sessions;
 
                 let http_listener = listener
                     .to_http(
@@ -291,6 +634,9 @@ impl CommandManager {
                 address,
                 public_address,
                 expect_proxy,
+                forward_destinations,
+                socks5,
+                max_sessions,
             } => self.order_command(ProxyRequestOrder::AddTcpListener(TcpListener {
                 address,
                 public_address,
@@ -298,6 +644,15 @@ impl CommandManager {
                 front_timeout: 60,
                 back_timeout: 30,
                 connect_timeout: 3,
+                forward_proxy: if forward_destinations.is_empty() {
+                    None
+                } else {
+                    Some(ForwardProxy {
+                        allowed_destinations: forward_destinations,
+                        socks5,
+                    })
+                },
+                max_sessions,
             })),
             TcpListenerCmd::Remove { address } => self.remove_listener(address, ListenerType::TCP),
             TcpListenerCmd::Activate { address } => {
@@ -344,22 +699,65 @@ impl CommandManager {
         }))
     }
 
+    pub fn acl_command(&mut self, cmd: AclCmd) -> Result<(), anyhow::Error> {
+        match cmd {
+            AclCmd::Add {
+                address,
+                proxy,
+                hostname,
+                mode,
+                ranges,
+            } => {
+                if proxy == ListenerType::TCP && hostname.is_some() {
+                    bail!("TCP listeners do not support ACLs by hostname");
+                }
+
+                self.order_command(ProxyRequestOrder::AddAcl(Acl {
+                    address,
+                    proxy,
+                    hostname,
+                    mode,
+                    ranges,
+                }))
+            }
+            AclCmd::Remove {
+                address,
+                proxy,
+                hostname,
+            } => self.order_command(ProxyRequestOrder::RemoveAcl(RemoveAcl {
+                address,
+                proxy,
+                hostname,
+            })),
+        }
+    }
+
     pub fn logging_filter(&mut self, filter: &LoggingLevel) -> Result<(), anyhow::Error> {
         self.order_command(ProxyRequestOrder::Logging(
             filter.to_string().to_lowercase(),
         ))
     }
 
+    pub fn batch(&mut self, path: &str, strict: bool) -> Result<(), anyhow::Error> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read the batch file {}", path))?;
+        let orders: Vec<ProxyRequestOrder> = serde_json::from_str(&data)
+            .with_context(|| format!("could not parse the orders of the batch file {}", path))?;
+
+        self.strict_order_command(ProxyRequestOrder::Batch(orders), strict)
+    }
+
     pub fn add_certificate(
         &mut self,
         address: SocketAddr,
-        certificate_path: &str,
-        certificate_chain_path: &str,
-        key_path: &str,
+        files: CertificateFiles,
         versions: Vec<TlsVersion>,
+        priority: i32,
     ) -> Result<(), anyhow::Error> {
+        let bundle = files.load()?;
+
         let new_certificate =
-            load_full_certificate(certificate_path, certificate_chain_path, key_path, versions)
+            load_full_certificate(bundle, versions, files.ocsp_response, priority)
                 .with_context(|| "Could not load the full certificate")?;
 
         self.order_command(ProxyRequestOrder::AddCertificate(AddCertificate {
@@ -373,12 +771,11 @@ impl CommandManager {
     pub fn replace_certificate(
         &mut self,
         address: SocketAddr,
-        new_certificate_path: &str,
-        new_certificate_chain_path: &str,
-        new_key_path: &str,
+        new_files: CertificateFiles,
         old_certificate_path: Option<&str>,
         old_fingerprint: Option<&str>,
         versions: Vec<TlsVersion>,
+        priority: i32,
     ) -> Result<(), anyhow::Error> {
         let old_fingerprint = match (old_certificate_path, old_fingerprint) {
             (None, None) | (Some(_), Some(_)) => {
@@ -393,13 +790,11 @@ impl CommandManager {
                 .with_context(|| "Error decoding the given fingerprint")?,
         };
 
-        let new_certificate = load_full_certificate(
-            new_certificate_path,
-            new_certificate_chain_path,
-            new_key_path,
-            versions,
-        )
-        .with_context(|| "Could not load the full certificate")?;
+        let bundle = new_files.load()?;
+
+        let new_certificate =
+            load_full_certificate(bundle, versions, new_files.ocsp_response, priority)
+                .with_context(|| "Could not load the full certificate")?;
 
         self.order_command(ProxyRequestOrder::ReplaceCertificate(ReplaceCertificate {
             address,
@@ -436,19 +831,49 @@ impl CommandManager {
             fingerprint,
         }))
     }
+
+    pub fn set_default_certificate(
+        &mut self,
+        address: SocketAddr,
+        certificate_path: Option<&str>,
+        fingerprint: Option<&str>,
+    ) -> Result<(), anyhow::Error> {
+        let fingerprint = match (certificate_path, fingerprint) {
+            (None, None) => None,
+            (Some(_), Some(_)) => {
+                bail!("Error: Please provide either one, the path OR the fingerprint of the certificate")
+            }
+            (Some(certificate_path), None) => Some(
+                get_fingerprint_from_certificate_path(certificate_path).with_context(|| {
+                    "Could not retrieve the fingerprint from the given certificate path"
+                })?,
+            ),
+            (None, Some(fingerprint)) => Some(
+                decode_fingerprint(fingerprint)
+                    .with_context(|| "Error decoding the given fingerprint")?,
+            ),
+        };
+
+        self.order_command(ProxyRequestOrder::SetDefaultCertificate(
+            SetDefaultCertificate {
+                address,
+                fingerprint,
+            },
+        ))
+    }
 }
 
 fn get_fingerprint_from_certificate_path(
     certificate_path: &str,
 ) -> anyhow::Result<CertificateFingerprint> {
-    let bytes = Config::load_file_bytes(certificate_path).with_context(|| {
+    let certificate = Config::load_certificate(certificate_path).with_context(|| {
         format!(
             "could not load certificate file on path {}",
             certificate_path
         )
     })?;
 
-    let parsed_bytes = calculate_fingerprint(&bytes).with_context(|| {
+    let parsed_bytes = calculate_fingerprint(certificate.as_bytes()).with_context(|| {
         format!(
             "could not calculate fingerprint for the certificate at {}",
             certificate_path
@@ -464,35 +889,292 @@ fn decode_fingerprint(fingerprint: &str) -> anyhow::Result<CertificateFingerprin
     Ok(CertificateFingerprint(bytes))
 }
 
-fn load_full_certificate(
+/// the files of a certificate with its chain and key, PEM or DER
+/// encoded, or of a PKCS#12 bundle holding all of them, and the
+/// OCSP response stapled with it
+#[derive(Default)]
+pub struct CertificateFiles<'a> {
+    pub certificate: Option<&'a str>,
+    pub certificate_chain: Option<&'a str>,
+    pub key: Option<&'a str>,
+    pub key_passphrase: Option<&'a str>,
+    pub pkcs12: Option<&'a str>,
+    /// file containing the password of the PKCS#12 bundle
+    pub password: Option<&'a str>,
+    /// DER encoded OCSP response
+    pub ocsp_response: Option<&'a str>,
+}
+
+impl CertificateFiles<'_> {
+    fn load(&self) -> Result<PemBundle, anyhow::Error> {
+        match (self.pkcs12, self.certificate, self.certificate_chain, self.key) {
+            (Some(pkcs12_path), None, None, None) => Config::load_pkcs12(pkcs12_path, self.password)
+                .with_context(|| format!("Could not load PKCS#12 bundle on path {}", pkcs12_path)),
+            (None, Some(certificate_path), Some(certificate_chain_path), Some(key_path)) => {
+                load_pem_bundle(
+                    certificate_path,
+                    certificate_chain_path,
+                    key_path,
+                    self.key_passphrase,
+                )
+            }
+            _ => bail!(
+                "Error: Please provide either a PKCS#12 bundle, or a certificate, its chain and its key"
+            ),
+        }
+    }
+}
+
+fn load_pem_bundle(
     certificate_path: &str,
     certificate_chain_path: &str,
     key_path: &str,
-    versions: Vec<TlsVersion>,
-) -> Result<CertificateAndKey, anyhow::Error> {
-    let certificate = Config::load_file(certificate_path).with_context(|| {
+    key_passphrase: Option<&str>,
+) -> Result<PemBundle, anyhow::Error> {
+    let certificate = Config::load_certificate(certificate_path).with_context(|| {
         format!(
             "Could not load certificate file on path {}",
             certificate_path
         )
     })?;
 
-    let certificate_chain = Config::load_file(certificate_chain_path)
-        .map(split_certificate_chain)
-        .with_context(|| {
+    let certificate_chain =
+        Config::load_certificate_chain(certificate_chain_path).with_context(|| {
             format!(
                 "could not load certificate chain on path: {}",
                 certificate_chain_path
             )
         })?;
 
-    let key = Config::load_file(key_path)
+    let key = Config::load_key(key_path, key_passphrase)
         .with_context(|| format!("Could not load key file on path {}", key_path))?;
 
+    Ok(PemBundle {
+        certificate,
+        certificate_chain,
+        key,
+    })
+}
+
+fn load_full_certificate(
+    bundle: PemBundle,
+    versions: Vec<TlsVersion>,
+    ocsp_response_path: Option<&str>,
+    priority: i32,
+) -> Result<CertificateAndKey, anyhow::Error> {
+    let PemBundle {
+        certificate,
+        certificate_chain,
+        key,
+    } = bundle;
+
+    let ocsp_response = match ocsp_response_path {
+        Some(path) => Some(hex::encode(Config::load_file_bytes(path).with_context(
+            || format!("Could not load OCSP response file on path {}", path),
+        )?)),
+        None => None,
+    };
+
     Ok(CertificateAndKey {
         certificate,
         certificate_chain,
         key,
         versions,
+        ocsp_response,
+        priority,
+    })
+}
+
+fn cluster(args: ClusterArgs) -> anyhow::Result<Cluster> {
+    let ClusterArgs {
+        id,
+        sticky_session,
+        sticky_mode,
+        https_redirect,
+        send_proxy,
+        expect_proxy,
+        load_balancing_policy,
+        hash_key,
+        request_header,
+        response_header,
+        host_rewrite,
+        request_limits,
+        compression,
+        security_headers,
+        request_retries,
+        timeouts,
+        tls,
+        health_check,
+        outlier_detection,
+        queue_size,
+        queue_timeout,
+        affinity_table_size,
+        max_sessions,
+        backend_protocol,
+        websocket_drain,
+        streaming,
+        upgrade_protocols,
+    } = args;
+    let proxy_protocol = match (send_proxy, expect_proxy) {
+        (true, true) => Some(ProxyProtocolConfig::RelayHeader),
+        (true, false) => Some(ProxyProtocolConfig::SendHeader),
+        (false, true) => Some(ProxyProtocolConfig::ExpectHeader),
+        _ => None,
+    };
+    Ok(Cluster {
+        cluster_id: id,
+        sticky_session,
+        sticky_mode,
+        https_redirect,
+        proxy_protocol,
+        load_balancing: load_balancing_policy,
+        hash_key,
+        load_metric: None,
+        answer_503: None,
+        header_actions: header_actions(request_header, response_header),
+        host_rewrite,
+        request_limits: request_limits.into(),
+        compression: compression.into(),
+        security_headers: security_headers.into(),
+        request_retries: request_retries.into(),
+        timeouts: timeouts.into(),
+        backend_tls: backend_tls(tls)?.map(Box::new),
+        backend_protocol,
+        websocket_drain,
+        streaming,
+        upgrade_protocols,
+        health_check: health_check_config(health_check),
+        outlier_detection: outlier_detection_config(outlier_detection),
+        request_queue: queue_size.map(|size| RequestQueue {
+            size,
+            timeout: queue_timeout.unwrap_or(RequestQueue::default().timeout),
+        }),
+        affinity_table: affinity_table_size.map(|size| AffinityTable { size }),
+        max_sessions,
+    })
+}
+
+fn frontend_route(
+    route: Option<Route>,
+    route_weighted: Vec<WeightedCluster>,
+    ab_test_cookie: Option<String>,
+    redirect_to: Option<String>,
+    redirect_code: Option<u16>,
+) -> anyhow::Result<proxy::Route> {
+    match (route, route_weighted.is_empty(), redirect_to) {
+        (Some(route), true, None) => Ok(route.into()),
+        (None, false, None) => Ok(match ab_test_cookie {
+            Some(cookie) => proxy::Route::AbTest {
+                cookie,
+                variants: route_weighted,
+            },
+            None => proxy::Route::Weighted(route_weighted),
+        }),
+        (None, true, Some(to)) => Ok(proxy::Route::Redirect {
+            to,
+            code: redirect_code.unwrap_or(301),
+        }),
+        (None, true, None) => {
+            bail!("the frontend needs a route: id, deny, --route-weighted or --redirect-to")
+        }
+        _ => bail!("a frontend can only have one of: a route, a weighted route or a redirection"),
+    }
+}
+
+fn frontend_rewrite_path(
+    strip_prefix: bool,
+    rewrite_path: Vec<String>,
+) -> anyhow::Result<Option<PathRewrite>> {
+    match (strip_prefix, rewrite_path.as_slice()) {
+        (false, []) => Ok(None),
+        (true, []) => Ok(Some(PathRewrite::StripPrefix)),
+        (false, [pattern, replacement]) => Ok(Some(PathRewrite::Regex {
+            pattern: pattern.to_owned(),
+            replacement: replacement.to_owned(),
+        })),
+        (true, _) => bail!("a frontend cannot both strip the prefix and rewrite the path"),
+        (false, _) => bail!("--rewrite-path expects a regex and a replacement"),
+    }
+}
+
+fn header_actions(
+    request_header: Vec<(HeaderOperation, String, String)>,
+    response_header: Vec<(HeaderOperation, String, String)>,
+) -> Vec<HeaderAction> {
+    let request = request_header
+        .into_iter()
+        .map(|action| (HeaderPosition::Request, action));
+    let response = response_header
+        .into_iter()
+        .map(|action| (HeaderPosition::Response, action));
+
+    request
+        .chain(response)
+        .map(|(position, (operation, name, value))| HeaderAction {
+            position,
+            operation,
+            name,
+            value,
+        })
+        .collect()
+}
+
+/// the health check of a cluster, with the defaults of the unset options
+fn health_check_config(args: HealthCheckArgs) -> Option<HealthCheck> {
+    let kind = args.health_check?;
+    let default = HealthCheck::default();
+
+    Some(HealthCheck {
+        kind,
+        path: args.health_check_path.unwrap_or(default.path),
+        expected_status: args.health_check_status,
+        expected_body: args.health_check_body,
+        service: args.health_check_service,
+        interval: args.health_check_interval.unwrap_or(default.interval),
+        timeout: args.health_check_timeout.unwrap_or(default.timeout),
+        rise: args.health_check_rise.unwrap_or(default.rise),
+        fall: args.health_check_fall.unwrap_or(default.fall),
     })
 }
+
+/// the outlier detection of a cluster, with the defaults of the unset options
+fn outlier_detection_config(args: OutlierDetectionArgs) -> Option<OutlierDetection> {
+    if !args.outlier_detection {
+        return None;
+    }
+    let default = OutlierDetection::default();
+
+    Some(OutlierDetection {
+        consecutive_failures: args
+            .outlier_consecutive_failures
+            .unwrap_or(default.consecutive_failures),
+        error_rate: args.outlier_error_rate,
+        minimum_requests: args
+            .outlier_minimum_requests
+            .unwrap_or(default.minimum_requests),
+        ejection_time: args.outlier_ejection_time.unwrap_or(default.ejection_time),
+        max_ejection_percent: args
+            .outlier_max_ejection_percent
+            .unwrap_or(default.max_ejection_percent),
+    })
+}
+
+/// loads the files of the backend TLS settings, if TLS is enabled
+fn backend_tls(args: BackendTlsArgs) -> anyhow::Result<Option<BackendTls>> {
+    if !args.tls {
+        return Ok(None);
+    }
+
+    let tls = FileBackendTlsConfig {
+        sni: args.tls_sni,
+        skip_verification: args.tls_skip_verification,
+        ca_certificates: args.tls_ca,
+        client_certificate: args.tls_client_certificate,
+        client_key: args.tls_client_key,
+        alpn_protocols: args.tls_alpn,
+    }
+    .to_backend_tls()
+    .with_context(|| "could not load the backend TLS settings")?;
+
+    Ok(Some(tls))
+}
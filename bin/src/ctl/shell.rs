@@ -0,0 +1,371 @@
+//! `sozu shell`: an interactive prompt sending the commands over the connection
+//! of the command manager, completing the clusters, backends, hostnames and
+//! listeners from the state of the proxy
+use std::{collections::BTreeSet, env, mem};
+
+use anyhow::bail;
+use clap::{Arg, Command, CommandFactory, Parser};
+use rustyline::{
+    completion::{Completer, Pair},
+    error::ReadlineError,
+    highlight::Highlighter,
+    hint::Hinter,
+    validate::Validator,
+    Context, Editor, Helper,
+};
+
+use sozu_command_lib::state::ConfigState;
+
+use crate::{
+    cli::{Args, ConfigCmd, SubCmd},
+    ctl::CommandManager,
+};
+
+/// the commands starting processes, or never returning, cannot be run from the prompt
+const UNAVAILABLE_COMMANDS: [&str; 8] = [
+    "start",
+    "worker",
+    "main",
+    "events",
+    "top",
+    "shell",
+    "completion",
+    "grpc",
+];
+
+impl CommandManager {
+    pub fn shell(&mut self, history: Option<String>) -> anyhow::Result<()> {
+        let history = history.or_else(|| {
+            env::var("HOME")
+                .ok()
+                .map(|home| format!("{}/.sozu_history", home))
+        });
+
+        let mut editor = Editor::<ShellHelper>::new()?;
+        editor.set_helper(Some(ShellHelper::new(CompletionValues::default(), true)));
+        if let Some(path) = &history {
+            // there is no history on the first use
+            let _ = editor.load_history(path);
+        }
+        self.refresh_completion(&mut editor);
+
+        loop {
+            let line = match editor.readline("sozu> ") {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(e) => bail!("could not read the prompt: {}", e),
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            editor.add_history_entry(line);
+            if line == "exit" || line == "quit" {
+                break;
+            }
+
+            match self.shell_command(line) {
+                Ok(true) => self.refresh_completion(&mut editor),
+                Ok(false) => {}
+                Err(e) => eprintln!("{:#}", e),
+            }
+        }
+
+        if let Some(path) = &history {
+            if let Err(e) = editor.save_history(path) {
+                eprintln!("could not save the history to {}: {}", path, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// runs a line of the prompt, returns true if it may have changed the state
+    fn shell_command(&mut self, line: &str) -> anyhow::Result<bool> {
+        let words = shell_words::split(line)?;
+        let args = match Args::try_parse_from(std::iter::once("sozu".to_owned()).chain(words)) {
+            Ok(args) => args,
+            Err(e) => {
+                // the help and the usage errors
+                e.print()?;
+                return Ok(false);
+            }
+        };
+
+        if args.config.is_some() || args.remote.is_some() || args.timeout.is_some() {
+            bail!("the connection of the shell cannot be changed, start another one");
+        }
+        let changes_state = match &args.cmd {
+            SubCmd::Start { .. }
+            | SubCmd::Worker { cmd: None, .. }
+            | SubCmd::Main { .. }
+            | SubCmd::Config {
+                cmd: ConfigCmd::Check {},
+            }
+            | SubCmd::Events
+            | SubCmd::Top { .. }
+            | SubCmd::Shell { .. }
+            | SubCmd::Completion { .. }
+            | SubCmd::Complete { .. } => {
+                bail!("this command is not available in the shell")
+            }
+            #[cfg(feature = "grpc")]
+            SubCmd::Grpc { .. } => bail!("this command is not available in the shell"),
+            SubCmd::Cluster { .. }
+            | SubCmd::Backend { .. }
+            | SubCmd::Frontend { .. }
+            | SubCmd::Listener { .. }
+            | SubCmd::Certificate { .. }
+            | SubCmd::Acl { .. }
+            | SubCmd::State { .. }
+            | SubCmd::Reload { .. }
+            | SubCmd::Batch { .. } => !args.dry_run,
+            _ => false,
+        };
+
+        // the flags of the line only apply to its command
+        let dry_run = mem::replace(&mut self.dry_run, args.dry_run);
+        let worker_ids = mem::replace(&mut self.worker_ids, args.workers);
+        let result = self.handle_command(args.cmd);
+        self.dry_run = dry_run;
+        self.worker_ids = worker_ids;
+
+        result.map(|()| changes_state)
+    }
+
+    fn refresh_completion(&mut self, editor: &mut Editor<ShellHelper>) {
+        match self.current_state() {
+            Ok(state) => {
+                if let Some(helper) = editor.helper_mut() {
+                    helper.values = CompletionValues::from(&state);
+                }
+            }
+            Err(e) => eprintln!("could not get the state to complete the commands: {:#}", e),
+        }
+    }
+}
+
+/// the values of the state offered by the completion
+#[derive(Default)]
+pub(super) struct CompletionValues {
+    cluster_ids: BTreeSet<String>,
+    backend_ids: BTreeSet<String>,
+    hostnames: BTreeSet<String>,
+    addresses: BTreeSet<String>,
+}
+
+impl From<&ConfigState> for CompletionValues {
+    fn from(state: &ConfigState) -> Self {
+        let addresses = state
+            .http_listeners
+            .keys()
+            .chain(state.https_listeners.keys())
+            .chain(state.tcp_listeners.keys())
+            .map(|address| address.to_string())
+            .collect();
+
+        CompletionValues {
+            cluster_ids: state.clusters.keys().cloned().collect(),
+            backend_ids: state
+                .backends
+                .values()
+                .flatten()
+                .map(|backend| backend.backend_id.clone())
+                .collect(),
+            hostnames: state
+                .http_fronts
+                .values()
+                .chain(state.https_fronts.values())
+                .map(|front| front.hostname.clone())
+                .collect(),
+            addresses,
+        }
+    }
+}
+
+pub(super) struct ShellHelper {
+    /// the command line interface, with its global arguments propagated
+    command: Command,
+    values: CompletionValues,
+    /// completes the prompt of the shell rather than the command line
+    prompt: bool,
+}
+
+impl ShellHelper {
+    pub(super) fn new(values: CompletionValues, prompt: bool) -> Self {
+        let mut command = Args::command();
+        command.build();
+        ShellHelper {
+            command,
+            values,
+            prompt,
+        }
+    }
+
+    /// the candidates for the word being typed, after the previous ones
+    pub(super) fn candidates(&self, previous: &[String], current: &str) -> Vec<String> {
+        let mut command = &self.command;
+        // the argument whose value is expected next
+        let mut expecting: Option<&Arg> = None;
+        let mut positionals = 0;
+
+        for word in previous {
+            if expecting.take().is_some() {
+                continue;
+            }
+            if let Some(long) = word.strip_prefix("--") {
+                expecting = command
+                    .get_arguments()
+                    .find(|arg| arg.get_long() == Some(long))
+                    .filter(|arg| arg.get_action().takes_values());
+            } else if let Some(short) = word.strip_prefix('-').filter(|short| short.len() == 1) {
+                expecting = command
+                    .get_arguments()
+                    .find(|arg| arg.get_short().map(String::from).as_deref() == Some(short))
+                    .filter(|arg| arg.get_action().takes_values());
+            } else if let Some(subcommand) = command.find_subcommand(word) {
+                command = subcommand;
+                positionals = 0;
+            } else {
+                positionals += 1;
+            }
+        }
+
+        let candidates = if let Some(arg) = expecting {
+            self.values_of(arg)
+        } else if current.starts_with('-') {
+            command
+                .get_arguments()
+                .filter(|arg| !arg.is_hide_set())
+                .filter_map(|arg| arg.get_long())
+                .map(|long| format!("--{}", long))
+                .collect()
+        } else {
+            let mut candidates: Vec<String> = command
+                .get_subcommands()
+                .filter(|subcommand| !subcommand.is_hide_set())
+                .map(|subcommand| subcommand.get_name().to_owned())
+                .filter(|name| name != "help")
+                .collect();
+            if previous.is_empty() && self.prompt {
+                candidates.retain(|name| !UNAVAILABLE_COMMANDS.contains(&name.as_str()));
+                candidates.extend(["exit".to_owned(), "quit".to_owned()]);
+            }
+            if let Some(arg) = command.get_positionals().nth(positionals) {
+                candidates.extend(self.values_of(arg));
+            }
+            candidates
+        };
+
+        candidates
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(current))
+            .collect()
+    }
+
+    fn values_of(&self, arg: &Arg) -> Vec<String> {
+        let values = match arg.get_id().as_str() {
+            "id" | "ids" | "cluster_id" => &self.values.cluster_ids,
+            "backend_id" => &self.values.backend_ids,
+            "hostname" | "hostnames" => &self.values.hostnames,
+            "address" | "http" | "https" => &self.values.addresses,
+            _ => return Vec::new(),
+        };
+        values.iter().cloned().collect()
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _context: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map_or(0, |index| index + 1);
+        let previous = match shell_words::split(&line[..start]) {
+            Ok(previous) => previous,
+            // an unterminated quote
+            Err(_) => return Ok((pos, Vec::new())),
+        };
+
+        let candidates = self
+            .candidates(&previous, &line[start..])
+            .into_iter()
+            .map(|candidate| Pair {
+                display: candidate.clone(),
+                replacement: format!("{} ", candidate),
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn helper() -> ShellHelper {
+        ShellHelper::new(
+            CompletionValues {
+                cluster_ids: ["app".to_owned(), "api".to_owned()].into(),
+                backend_ids: ["app-0".to_owned()].into(),
+                hostnames: ["example.com".to_owned()].into(),
+                addresses: ["0.0.0.0:80".to_owned()].into(),
+            },
+            true,
+        )
+    }
+
+    fn words(line: &str) -> Vec<String> {
+        shell_words::split(line).unwrap()
+    }
+
+    #[test]
+    fn shell_completion() {
+        let helper = helper();
+
+        assert_eq!(helper.candidates(&[], "clu"), vec!["cluster"]);
+        assert!(helper.candidates(&[], "st").contains(&"status".to_owned()));
+        assert!(!helper.candidates(&[], "st").contains(&"start".to_owned()));
+        assert_eq!(
+            helper.candidates(&words("cluster remove"), "--i"),
+            vec!["--id"]
+        );
+        assert_eq!(
+            helper.candidates(&words("cluster remove --id"), "a"),
+            vec!["api", "app"]
+        );
+        assert_eq!(
+            helper.candidates(&words("backend remove --id app --backend-id"), ""),
+            vec!["app-0"]
+        );
+        assert_eq!(
+            helper.candidates(&words("frontend http add --hostname"), "ex"),
+            vec!["example.com"]
+        );
+        // the cluster of the route is a positional argument
+        assert_eq!(
+            helper.candidates(&words("frontend http add -a 0.0.0.0:80 id"), "ap"),
+            vec!["api", "app"]
+        );
+        // the global arguments are known by the subcommands
+        assert_eq!(
+            helper.candidates(&words("cluster remove"), "--dry-"),
+            vec!["--dry-run"]
+        );
+    }
+}
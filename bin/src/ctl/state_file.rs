@@ -0,0 +1,238 @@
+//! `sozu state edit-file`: validates, prints, filters, merges or strips the
+//! certificates of saved state files, without a running proxy
+use std::{
+    fs::{self, File},
+    io::Write,
+};
+
+use anyhow::{bail, Context};
+
+use sozu_command_lib::{
+    command::{CommandRequest, CommandRequestOrder, PROTOCOL_VERSION},
+    state::ConfigState,
+};
+
+use crate::{
+    cli::StateFileCmd,
+    ctl::display::{print_json_response, print_warnings},
+};
+
+/// a saved state read back, with the problems found in its messages
+pub struct StateFile {
+    pub state: ConfigState,
+    pub messages: usize,
+    pub warnings: Vec<String>,
+}
+
+pub fn edit_state_file(cmd: &StateFileCmd) -> anyhow::Result<()> {
+    match cmd {
+        StateFileCmd::Validate { file } => {
+            let state_file = read_state_file(file)?;
+            let state = &state_file.state;
+            println!(
+                "{}: {} messages, {} clusters, {} frontends, {} backends, {} certificates",
+                file,
+                state_file.messages,
+                state.clusters.len(),
+                state.count_frontends(),
+                state.count_backends(),
+                state.certificates.values().map(|c| c.len()).sum::<usize>()
+            );
+            if !state_file.warnings.is_empty() {
+                print_warnings(&state_file.warnings);
+                bail!("the state file has {} problems", state_file.warnings.len());
+            }
+            Ok(())
+        }
+        StateFileCmd::Print { file, json } => {
+            let state = read_state_file(file)?.state;
+            match json {
+                true => print_json_response(&state),
+                false => {
+                    println!("{:#?}", state);
+                    Ok(())
+                }
+            }
+        }
+        StateFileCmd::Filter {
+            file,
+            clusters,
+            output,
+        } => {
+            let state = read_state_file(file)?.state;
+            for cluster_id in clusters {
+                if !state.clusters.contains_key(cluster_id) {
+                    bail!("the cluster {} is not in {}", cluster_id, file);
+                }
+            }
+            write_state_file(&state.only_clusters(clusters), output)
+        }
+        StateFileCmd::Merge { file, with, output } => {
+            let state = read_state_file(file)?.state;
+            let other = read_state_file(with)?.state;
+            write_state_file(&state.merge(&other), output)
+        }
+        StateFileCmd::StripCertificates { file, output } => {
+            let state = read_state_file(file)?.state;
+            write_state_file(&state.without_certificates(), output)
+        }
+    }
+}
+
+/// executes the orders of the file like the main process does when loading it
+pub fn read_state_file(path: &str) -> anyhow::Result<StateFile> {
+    let data = fs::read(path).with_context(|| format!("Cannot read the state file {}", path))?;
+    let mut state = ConfigState::new();
+    let mut messages = 0usize;
+    let mut warnings = Vec::new();
+
+    let chunks = data
+        .split(|byte| *byte == 0)
+        .filter(|chunk| !chunk.iter().all(u8::is_ascii_whitespace));
+    for (index, chunk) in chunks.enumerate() {
+        let request: CommandRequest = serde_json::from_slice(chunk)
+            .with_context(|| format!("message {} of {} is not a valid request", index, path))?;
+        if request.version > PROTOCOL_VERSION {
+            bail!(
+                "message {} of {} uses the protocol version {}, Sōzu handles up to version {}",
+                index,
+                path,
+                request.version,
+                PROTOCOL_VERSION
+            );
+        }
+        messages += 1;
+
+        match request.order {
+            CommandRequestOrder::Proxy(order) => {
+                if state.handle_order(&order) {
+                    state.worker_scopes.scope(&order, &request.worker_ids);
+                } else {
+                    warnings.push(format!(
+                        "message {} ({}) changes nothing",
+                        index, request.id
+                    ));
+                }
+            }
+            _ => warnings.push(format!(
+                "message {} ({}) is not a proxy order, it is ignored",
+                index, request.id
+            )),
+        }
+    }
+
+    warnings.extend(state_problems(&state));
+
+    Ok(StateFile {
+        state,
+        messages,
+        warnings,
+    })
+}
+
+/// writes the state in the format of `state save`
+fn write_state_file(state: &ConfigState, path: &str) -> anyhow::Result<()> {
+    let mut file =
+        File::create(path).with_context(|| format!("could not create the file {}", path))?;
+
+    let orders = state.generate_orders();
+    let count = orders.len();
+    for (counter, order) in orders.into_iter().enumerate() {
+        let worker_ids = state.worker_scopes.listener_workers(&order);
+        let mut message = CommandRequest::new(
+            format!("SAVE-{}", counter),
+            CommandRequestOrder::Proxy(Box::new(order)),
+            None,
+        );
+        message.worker_ids = worker_ids;
+
+        file.write_all(serde_json::to_string(&message)?.as_bytes())
+            .and_then(|_| file.write_all(&b"\n\0"[..]))
+            .with_context(|| format!("could not write to the file {}", path))?;
+    }
+    file.sync_all()
+        .with_context(|| format!("could not sync the file {}", path))?;
+
+    println!("wrote {} orders to {}", count, path);
+    Ok(())
+}
+
+/// the frontends and backends of missing clusters, and the HTTPS frontends
+/// no certificate covers
+fn state_problems(state: &ConfigState) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    for front in state
+        .http_fronts
+        .values()
+        .chain(state.https_fronts.values())
+    {
+        for cluster_id in front.route.cluster_ids() {
+            if !state.clusters.contains_key(cluster_id) {
+                problems.push(format!(
+                    "the frontend {}{} routes to the missing cluster {}",
+                    front.address, front.hostname, cluster_id
+                ));
+            }
+        }
+    }
+    for front in state.https_fronts.values() {
+        if front.hostname != "*" && !state.certificate_covers(&front.address, &front.hostname) {
+            problems.push(format!(
+                "no certificate of the listener {} covers the hostname {}",
+                front.address, front.hostname
+            ));
+        }
+    }
+    for cluster_id in state.tcp_fronts.keys().chain(state.backends.keys()) {
+        if !state.clusters.contains_key(cluster_id) {
+            problems.push(format!(
+                "the frontends or backends of the missing cluster {}",
+                cluster_id
+            ));
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sozu_command_lib::proxy::{Backend, Cluster, ProxyRequestOrder};
+
+    #[test]
+    fn state_files_are_read_back() {
+        let mut state = ConfigState::new();
+        state.handle_order(&ProxyRequestOrder::AddCluster(Cluster {
+            cluster_id: String::from("app"),
+            ..Default::default()
+        }));
+        let backend: Backend = serde_json::from_str(
+            r#"{ "cluster_id": "app", "backend_id": "app-0", "address": "127.0.0.1:1026" }"#,
+        )
+        .unwrap();
+        state.handle_order(&ProxyRequestOrder::AddBackend(backend));
+
+        let path = std::env::temp_dir().join(format!("sozu-state-file-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_state_file(&state, path).unwrap();
+        let state_file = read_state_file(path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(state_file.messages, 2);
+        assert!(state_file.warnings.is_empty());
+        assert!(state.diff(&state_file.state).is_empty());
+
+        state.handle_order(&ProxyRequestOrder::RemoveCluster {
+            cluster_id: String::from("app"),
+        });
+        assert_eq!(
+            state_problems(&state),
+            vec![String::from(
+                "the frontends or backends of the missing cluster app"
+            )]
+        );
+    }
+}
@@ -0,0 +1,595 @@
+//! `sozu top`: the request rates, server errors, latencies and health of the
+//! clusters and their backends, redrawn in place, above the last events
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, Write},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use termion::{event::Key, input::TermRead, raw::IntoRawMode, screen::AlternateScreen};
+
+use sozu_command_lib::{
+    command::{CommandRequest, CommandRequestOrder, CommandResponseContent, Event},
+    proxy::{
+        AggregatedMetricsData, BackendHealth, FilteredData, Percentiles, ProxyRequestOrder, Query,
+        QueryAnswer, QueryAnswerMetrics, QueryMetricsOptions, WorkerMetrics,
+    },
+};
+
+use crate::ctl::{command::generate_id, create_channel, display::backend_state, CommandManager};
+
+/// the events kept under the clusters
+const EVENT_COUNT: usize = 8;
+/// how often the keys and the events are checked between two refreshes
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+impl CommandManager {
+    pub fn top(&mut self, interval: u64) -> anyhow::Result<()> {
+        let stdout = io::stdout()
+            .into_raw_mode()
+            .with_context(|| "sozu top needs a terminal")?;
+        let mut screen = AlternateScreen::from(stdout);
+        write!(screen, "{}", termion::cursor::Hide)?;
+
+        let result = self.top_loop(&mut screen, Duration::from_secs(interval.max(1)));
+
+        write!(screen, "{}", termion::cursor::Show)?;
+        screen.flush()?;
+        result
+    }
+
+    fn top_loop(&mut self, screen: &mut impl Write, interval: Duration) -> anyhow::Result<()> {
+        let events = self.subscribe_events();
+        let mut keys = termion::async_stdin().keys();
+        let mut last_events = VecDeque::new();
+        let mut previous: Option<Sample> = None;
+
+        loop {
+            let sample = self.sample()?;
+            let health = self.backend_health()?;
+            let rows = rows(previous.as_ref(), &sample, &health);
+            let header = format!(
+                "sozu top, every {}s, at {} UTC. q: quit",
+                interval.as_secs(),
+                clock()
+            );
+            draw(screen, &header, &rows, &last_events)?;
+            previous = Some(sample);
+
+            let next_refresh = Instant::now() + interval;
+            while Instant::now() < next_refresh {
+                for key in keys.by_ref() {
+                    if let Key::Char('q') | Key::Esc | Key::Ctrl('c') = key? {
+                        return Ok(());
+                    }
+                }
+
+                let mut new_events = false;
+                while let Ok(event) = events.try_recv() {
+                    last_events.push_front(format!("{} {}", clock(), event));
+                    last_events.truncate(EVENT_COUNT);
+                    new_events = true;
+                }
+                if new_events {
+                    draw(screen, &header, &rows, &last_events)?;
+                }
+
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+
+    fn sample(&mut self) -> anyhow::Result<Sample> {
+        let order = CommandRequestOrder::Proxy(Box::new(ProxyRequestOrder::Query(Query::Metrics(
+            QueryMetricsOptions {
+                list: false,
+                cluster_ids: Vec::new(),
+                backend_ids: Vec::new(),
+                metric_names: Vec::new(),
+            },
+        ))));
+        match self.request_content(order)? {
+            CommandResponseContent::Metrics(metrics) => Ok(Sample::new(&metrics, Instant::now())),
+            content => bail!("unexpected metrics answer: {:?}", content),
+        }
+    }
+
+    fn backend_health(&mut self) -> anyhow::Result<BTreeMap<String, QueryAnswer>> {
+        let order = CommandRequestOrder::Proxy(Box::new(ProxyRequestOrder::Query(
+            Query::BackendHealth(None),
+        )));
+        match self.request_content(order)? {
+            CommandResponseContent::Query(health) => Ok(health),
+            content => bail!("unexpected backend health answer: {:?}", content),
+        }
+    }
+
+    /// the events are read by a thread, on their own connection
+    fn subscribe_events(&self) -> mpsc::Receiver<String> {
+        let (sender, receiver) = mpsc::channel();
+
+        let mut channel = match create_channel(&self.config) {
+            Ok(channel) => channel,
+            Err(e) => {
+                let _ = sender.send(format!("could not subscribe to the events: {:#}", e));
+                return receiver;
+            }
+        };
+        let request =
+            CommandRequest::new(generate_id(), CommandRequestOrder::SubscribeEvents, None);
+        if !channel.write_message(&request) {
+            let _ = sender.send(String::from("could not subscribe to the events"));
+            return receiver;
+        }
+
+        thread::spawn(move || {
+            while let Some(response) = channel.read_message_blocking_timeout(None) {
+                if let Some(CommandResponseContent::Event(event)) = response.content {
+                    if sender.send(describe_event(&event)).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        receiver
+    }
+}
+
+/// the counters of a backend, summed over the workers, since their start
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct BackendSample {
+    requests: i64,
+    errors: i64,
+    /// the highest response time percentiles of the workers
+    latency: Option<Percentiles>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct ClusterSample {
+    /// the server errors sozu answered itself, without a backend
+    errors: i64,
+    backends: BTreeMap<String, BackendSample>,
+}
+
+/// the metrics of the clusters at a point in time
+struct Sample {
+    taken_at: Instant,
+    clusters: BTreeMap<String, ClusterSample>,
+}
+
+impl Sample {
+    fn new(metrics: &AggregatedMetricsData, taken_at: Instant) -> Self {
+        let mut clusters: BTreeMap<String, ClusterSample> = BTreeMap::new();
+
+        for answer in metrics.workers.values() {
+            let worker_clusters = match answer {
+                QueryAnswer::Metrics(QueryAnswerMetrics::All(WorkerMetrics {
+                    clusters: Some(clusters),
+                    ..
+                })) => clusters,
+                _ => continue,
+            };
+
+            for (cluster_id, data) in worker_clusters {
+                let cluster = clusters.entry(cluster_id.to_owned()).or_default();
+                if let Some(metrics) = &data.cluster {
+                    cluster.errors += count(metrics, "http.status.5xx");
+                }
+
+                for (backend_id, metrics) in data.backends.iter().flatten() {
+                    let backend = cluster.backends.entry(backend_id.to_owned()).or_default();
+                    backend.requests += count(metrics, "requests");
+                    backend.errors += count(metrics, "http.status.5xx");
+                    if let Some(FilteredData::Percentiles(latency)) =
+                        metrics.get("backend_response_time")
+                    {
+                        backend.latency = Some(highest(backend.latency.as_ref(), latency));
+                    }
+                }
+            }
+        }
+
+        Sample { taken_at, clusters }
+    }
+}
+
+fn count(metrics: &BTreeMap<String, FilteredData>, name: &str) -> i64 {
+    match metrics.get(name) {
+        Some(FilteredData::Count(count)) => *count,
+        _ => 0,
+    }
+}
+
+fn highest(previous: Option<&Percentiles>, latency: &Percentiles) -> Percentiles {
+    match previous {
+        None => latency.clone(),
+        Some(previous) => Percentiles {
+            samples: previous.samples + latency.samples,
+            p_50: previous.p_50.max(latency.p_50),
+            p_90: previous.p_90.max(latency.p_90),
+            p_99: previous.p_99.max(latency.p_99),
+            p_99_9: previous.p_99_9.max(latency.p_99_9),
+            p_99_99: previous.p_99_99.max(latency.p_99_99),
+            p_99_999: previous.p_99_999.max(latency.p_99_999),
+            p_100: previous.p_100.max(latency.p_100),
+        },
+    }
+}
+
+/// the requests and server errors per second since the previous sample
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Rates {
+    requests: f64,
+    errors: f64,
+}
+
+impl Rates {
+    /// none on the first sample, or if a worker restarted and its counters with it
+    fn new(previous: Option<(i64, i64)>, current: (i64, i64), seconds: f64) -> Option<Self> {
+        let (previous_requests, previous_errors) = previous.unwrap_or_default();
+        if previous.is_none()
+            || seconds <= 0.0
+            || current.0 < previous_requests
+            || current.1 < previous_errors
+        {
+            return None;
+        }
+        Some(Rates {
+            requests: (current.0 - previous_requests) as f64 / seconds,
+            errors: (current.1 - previous_errors) as f64 / seconds,
+        })
+    }
+}
+
+/// a line of the table, for a cluster or one of its backends
+#[derive(Debug, Clone, PartialEq)]
+struct Row {
+    name: String,
+    address: String,
+    state: String,
+    rates: Option<Rates>,
+    latency: Option<Percentiles>,
+}
+
+impl Row {
+    fn format(&self) -> String {
+        let (requests, errors, error_share) = match self.rates {
+            None => ("-".to_owned(), "-".to_owned(), "-".to_owned()),
+            Some(rates) => (
+                format!("{:.1}", rates.requests),
+                format!("{:.1}", rates.errors),
+                if rates.requests > 0.0 {
+                    format!("{:.1}%", 100.0 * rates.errors / rates.requests)
+                } else {
+                    "-".to_owned()
+                },
+            ),
+        };
+        let (p_50, p_90, p_99) = match &self.latency {
+            None => ("-".to_owned(), "-".to_owned(), "-".to_owned()),
+            Some(latency) => (
+                format!("{}ms", latency.p_50),
+                format!("{}ms", latency.p_90),
+                format!("{}ms", latency.p_99),
+            ),
+        };
+
+        format!(
+            "{:<28} {:<22} {:<20} {:>8} {:>8} {:>6} {:>7} {:>7} {:>7}",
+            self.name, self.address, self.state, requests, errors, error_share, p_50, p_90, p_99
+        )
+    }
+}
+
+const TABLE_HEADER: [&str; 9] = [
+    "CLUSTER/BACKEND",
+    "ADDRESS",
+    "STATE",
+    "REQ/S",
+    "5XX/S",
+    "ERR",
+    "P50",
+    "P90",
+    "P99",
+];
+
+/// a row for each cluster, followed by the ones of its backends
+fn rows(
+    previous: Option<&Sample>,
+    current: &Sample,
+    health: &BTreeMap<String, QueryAnswer>,
+) -> Vec<Row> {
+    let seconds = previous
+        .map(|previous| (current.taken_at - previous.taken_at).as_secs_f64())
+        .unwrap_or_default();
+
+    // the health of the backends on each worker, by cluster and backend id
+    let mut backend_health: BTreeMap<&str, BTreeMap<&str, Vec<&BackendHealth>>> = BTreeMap::new();
+    for answer in health.values() {
+        if let QueryAnswer::BackendHealth(backends) = answer {
+            for backend in backends {
+                backend_health
+                    .entry(backend.cluster_id.as_str())
+                    .or_default()
+                    .entry(backend.backend_id.as_str())
+                    .or_default()
+                    .push(backend);
+            }
+        }
+    }
+
+    let empty = ClusterSample::default();
+    let mut cluster_ids: Vec<&str> = current.clusters.keys().map(String::as_str).collect();
+    cluster_ids.extend(backend_health.keys());
+    cluster_ids.sort_unstable();
+    cluster_ids.dedup();
+
+    let mut rows = Vec::new();
+    for cluster_id in cluster_ids {
+        let cluster = current.clusters.get(cluster_id).unwrap_or(&empty);
+        let previous_cluster = previous.and_then(|previous| previous.clusters.get(cluster_id));
+        let cluster_health = backend_health.remove(cluster_id).unwrap_or_default();
+
+        let mut backend_ids: Vec<&str> = cluster.backends.keys().map(String::as_str).collect();
+        backend_ids.extend(cluster_health.keys());
+        backend_ids.sort_unstable();
+        backend_ids.dedup();
+
+        let mut backend_rows = Vec::new();
+        // the answers sozu gave itself are requests too
+        let mut totals = (cluster.errors, cluster.errors);
+        let mut previous_totals = previous_cluster.map(|cluster| (cluster.errors, cluster.errors));
+        let mut latency: Option<Percentiles> = None;
+        let mut up = 0;
+        for backend_id in backend_ids.iter() {
+            let backend = cluster
+                .backends
+                .get(*backend_id)
+                .cloned()
+                .unwrap_or_default();
+            let previous_backend = previous_cluster.map(|cluster| {
+                cluster
+                    .backends
+                    .get(*backend_id)
+                    .cloned()
+                    .unwrap_or_default()
+            });
+            let health = cluster_health
+                .get(backend_id)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+
+            totals.0 += backend.requests;
+            totals.1 += backend.errors;
+            if let (Some(totals), Some(backend)) = (previous_totals.as_mut(), &previous_backend) {
+                totals.0 += backend.requests;
+                totals.1 += backend.errors;
+            }
+            if let Some(backend_latency) = &backend.latency {
+                latency = Some(highest(latency.as_ref(), backend_latency));
+            }
+
+            let state = match health.len() {
+                0 => "-".to_owned(),
+                worker_count => backend_state(health, worker_count),
+            };
+            if state == "up" {
+                up += 1;
+            }
+
+            backend_rows.push(Row {
+                name: format!("  {}", backend_id),
+                address: health
+                    .first()
+                    .map(|backend| backend.address.to_string())
+                    .unwrap_or_default(),
+                state,
+                rates: Rates::new(
+                    previous_backend.map(|backend| (backend.requests, backend.errors)),
+                    (backend.requests, backend.errors),
+                    seconds,
+                ),
+                latency: backend.latency,
+            });
+        }
+
+        rows.push(Row {
+            name: cluster_id.to_owned(),
+            address: String::new(),
+            state: format!("{}/{} up", up, backend_ids.len()),
+            rates: Rates::new(previous_totals, totals, seconds),
+            latency,
+        });
+        rows.extend(backend_rows);
+    }
+    rows
+}
+
+fn draw(
+    screen: &mut impl Write,
+    header: &str,
+    rows: &[Row],
+    events: &VecDeque<String>,
+) -> anyhow::Result<()> {
+    let (width, height) = termion::terminal_size().unwrap_or((80, 24));
+
+    let mut lines = vec![header.to_owned(), String::new()];
+    lines.push(format!(
+        "{:<28} {:<22} {:<20} {:>8} {:>8} {:>6} {:>7} {:>7} {:>7}",
+        TABLE_HEADER[0],
+        TABLE_HEADER[1],
+        TABLE_HEADER[2],
+        TABLE_HEADER[3],
+        TABLE_HEADER[4],
+        TABLE_HEADER[5],
+        TABLE_HEADER[6],
+        TABLE_HEADER[7],
+        TABLE_HEADER[8]
+    ));
+    if rows.is_empty() {
+        lines.push(String::from(
+            "no cluster metrics, they are collected after `sozu metrics enable`",
+        ));
+    }
+    // the events stay visible below the clusters
+    let table_height = (height as usize).saturating_sub(lines.len() + 2 + events.len());
+    lines.extend(rows.iter().take(table_height).map(Row::format));
+
+    lines.push(String::new());
+    lines.push(String::from("Events:"));
+    lines.extend(events.iter().cloned());
+
+    write!(
+        screen,
+        "{}{}",
+        termion::clear::All,
+        termion::cursor::Goto(1, 1)
+    )?;
+    for line in lines.iter().take(height as usize) {
+        let line: String = line.chars().take(width as usize).collect();
+        // the terminal is in raw mode
+        write!(screen, "{}\r\n", line)?;
+    }
+    screen.flush()?;
+    Ok(())
+}
+
+fn clock() -> String {
+    let now = time::OffsetDateTime::now_utc();
+    format!("{:02}:{:02}:{:02}", now.hour(), now.minute(), now.second())
+}
+
+fn describe_event(event: &Event) -> String {
+    match event {
+        Event::BackendDown(backend_id, address) => {
+            format!("backend {} at {} is down", backend_id, address)
+        }
+        Event::BackendUp(backend_id, address) => {
+            format!("backend {} at {} is up", backend_id, address)
+        }
+        Event::NoAvailableBackends(cluster_id) => {
+            format!("no backend available for the cluster {}", cluster_id)
+        }
+        Event::WorkerCrashed(id, pid, None) => format!("worker {} (pid {}) crashed", id, pid),
+        Event::WorkerCrashed(id, pid, Some(report)) => {
+            format!("worker {} (pid {}) panicked: {}", id, pid, report.message)
+        }
+        Event::WorkerRestartLimit(id, crashes) => {
+            format!("worker {} is not restarted after {} crashes", id, crashes)
+        }
+        event => format!("{:?}", event),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sozu_command_lib::proxy::ClusterMetricsData;
+
+    fn metrics(requests: i64, errors: i64, p_99: u64) -> AggregatedMetricsData {
+        let backend = BTreeMap::from([
+            (String::from("requests"), FilteredData::Count(requests)),
+            (String::from("http.status.5xx"), FilteredData::Count(errors)),
+            (
+                String::from("backend_response_time"),
+                FilteredData::Percentiles(Percentiles {
+                    p_99,
+                    ..Default::default()
+                }),
+            ),
+        ]);
+        let worker = WorkerMetrics {
+            proxy: None,
+            clusters: Some(BTreeMap::from([(
+                String::from("app"),
+                ClusterMetricsData {
+                    cluster: Some(BTreeMap::from([(
+                        String::from("http.status.5xx"),
+                        FilteredData::Count(1),
+                    )])),
+                    backends: Some(BTreeMap::from([(String::from("app-0"), backend)])),
+                },
+            )])),
+        };
+
+        AggregatedMetricsData {
+            main: BTreeMap::new(),
+            workers: BTreeMap::from([
+                (
+                    String::from("0"),
+                    QueryAnswer::Metrics(QueryAnswerMetrics::All(worker.clone())),
+                ),
+                (
+                    String::from("1"),
+                    QueryAnswer::Metrics(QueryAnswerMetrics::All(worker)),
+                ),
+            ]),
+        }
+    }
+
+    #[test]
+    fn top_rows() {
+        let start = Instant::now();
+        let previous = Sample::new(&metrics(10, 0, 5), start);
+        let current = Sample::new(&metrics(20, 2, 8), start + Duration::from_secs(2));
+        let health = BTreeMap::from([(
+            String::from("0"),
+            QueryAnswer::BackendHealth(vec![BackendHealth {
+                cluster_id: String::from("app"),
+                backend_id: String::from("app-0"),
+                address: "127.0.0.1:1026".parse().unwrap(),
+                healthy: true,
+                ejected: false,
+                last_check: None,
+                latency: None,
+                successes: 0,
+                failures: 0,
+                total_failures: 0,
+            }]),
+        )]);
+
+        let first = rows(None, &previous, &health);
+        assert_eq!(first.len(), 2);
+        assert_eq!(first[0].rates, None);
+
+        let rows = rows(Some(&previous), &current, &health);
+        assert_eq!(rows[0].name, "app");
+        assert_eq!(rows[0].state, "1/1 up");
+        // the counters of both workers are summed
+        assert_eq!(
+            rows[0].rates,
+            Some(Rates {
+                requests: 10.0,
+                errors: 2.0
+            })
+        );
+        assert_eq!(
+            rows[0].latency.as_ref().map(|latency| latency.p_99),
+            Some(8)
+        );
+        assert_eq!(rows[1].name, "  app-0");
+        assert_eq!(rows[1].address, "127.0.0.1:1026");
+        assert_eq!(rows[1].state, "up");
+        assert_eq!(
+            rows[1].rates,
+            Some(Rates {
+                requests: 10.0,
+                errors: 2.0
+            })
+        );
+
+        // a worker restarted
+        assert_eq!(rows_after_restart(&current), None);
+    }
+
+    fn rows_after_restart(previous: &Sample) -> Option<Rates> {
+        let current = Sample::new(
+            &metrics(1, 0, 8),
+            previous.taken_at + Duration::from_secs(2),
+        );
+        rows(Some(previous), &current, &BTreeMap::new())[0].rates
+    }
+}
@@ -0,0 +1,2815 @@
+//! conversions between the protobuf messages and the types of the command library.
+//! The requests are checked on the way in, the answers of the main process
+//! always fit in their messages
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+};
+
+use anyhow::{anyhow, Context};
+
+use sozu_command_lib::{
+    command::{
+        AuditEntry, CertificateFilters, CertificateIssue, CommandRequest, CommandRequestOrder,
+        CommandResponse, CommandResponseContent, CommandStatus, Event, FrontendFilters,
+        ListedCertificate, ListedFrontends, Progress, RunState, StateVersion, WorkerInfo,
+        WorkerProgress,
+    },
+    config::ProxyProtocolConfig,
+    proxy::{
+        default_deny_status, AccessLogFilter, AccessLogRecord, Acl, AclMode, ActivateListener,
+        AddCertificate, Affinity, AffinityTable, AggregatedMetricsData, Backend, BackendHealth,
+        BackendProtocol, BackendTls, CertificateAndKey, CertificateFingerprint, ClientAuth,
+        Cluster, ClusterMaintenance, ClusterMetricsData, Compression, CrashReport,
+        DeactivateListener, DrainBackend, FilteredData, ForwardProxy, HashKey, HeaderAction,
+        HeaderOperation, HeaderPosition, HeaderRule, HeaderValueRule, HealthCheck, HealthCheckKind,
+        HostRewrite, Http2Settings, HttpFrontend, HttpListener, HttpsListener, ListenerType,
+        LoadBalancingAlgorithms, LoadBalancingParams, LoadMetric, MetricsConfiguration,
+        NameResolution, OutlierDetection, PathNormalization, PathRewrite, PathRule,
+        ProxyRequestOrder, Query, QueryAnswer, QueryAnswerBackend, QueryAnswerCertificate,
+        QueryAnswerCluster, QueryAnswerMetrics, QueryCertificateResolve, QueryCertificateType,
+        QueryClusterDomain, QueryClusterType, QueryMetricsOptions, RemoveAcl, RemoveBackend,
+        RemoveCertificate, RemoveListener, ReplaceCertificate, RequestLimits, RequestQueue,
+        RequestRetries, RetryCondition, Route, RulePosition, SecurityHeaders,
+        SetDefaultCertificate, SetOcspResponse, SetTicketKeys, SniFrontend, SoftStopReport,
+        StatusRange, StickyMode, TcpFrontend, TcpListener, Timeouts, TlsProvider, TlsVersion,
+        TrailingSlash, UpdateBackendWeight, WebSocketDrain, WeightedCluster, WorkerMetrics,
+        DEFAULT_CLIENT_DN_HEADER,
+    },
+    state::ConfigState,
+};
+
+use super::proto;
+
+fn address(address: &str) -> anyhow::Result<SocketAddr> {
+    address
+        .parse()
+        .with_context(|| format!("invalid socket address '{}'", address))
+}
+
+fn required<T>(value: Option<T>, field: &str) -> anyhow::Result<T> {
+    value.with_context(|| format!("missing {}", field))
+}
+
+/// protobuf has no integer smaller than 32 bits
+fn narrow<T: TryFrom<u32>>(value: u32, field: &str) -> anyhow::Result<T> {
+    T::try_from(value).map_err(|_| anyhow!("{} is out of range: {}", field, value))
+}
+
+/// the enumerations are sent as integers
+fn enumeration<P: TryFrom<i32>, R: From<P>>(value: i32, field: &str) -> anyhow::Result<R> {
+    P::try_from(value)
+        .map(R::from)
+        .map_err(|_| anyhow!("invalid {}: {}", field, value))
+}
+
+fn enumerations<P: TryFrom<i32>, R: From<P>>(
+    values: Vec<i32>,
+    field: &str,
+) -> anyhow::Result<Vec<R>> {
+    values
+        .into_iter()
+        .map(|value| enumeration::<P, R>(value, field))
+        .collect()
+}
+
+fn convert_all<T, U: TryFrom<T, Error = anyhow::Error>>(values: Vec<T>) -> anyhow::Result<Vec<U>> {
+    values.into_iter().map(U::try_from).collect()
+}
+
+fn into_all<T, U: From<T>>(values: impl IntoIterator<Item = T>) -> Vec<U> {
+    values.into_iter().map(U::from).collect()
+}
+
+fn into_map<V, W: From<V>>(map: impl IntoIterator<Item = (String, V)>) -> BTreeMap<String, W> {
+    map.into_iter()
+        .map(|(key, value)| (key, W::from(value)))
+        .collect()
+}
+
+/// an empty map of tags is no tags
+fn tags(tags: BTreeMap<String, String>) -> Option<BTreeMap<String, String>> {
+    Some(tags).filter(|tags| !tags.is_empty())
+}
+
+/// the enumerations without data, with the same variants on both sides
+macro_rules! unit_enum {
+    ($rust:ident, $proto:ident, $($rust_variant:ident => $proto_variant:ident),+ $(,)?) => {
+        impl From<$rust> for proto::$proto {
+            fn from(value: $rust) -> Self {
+                match value {
+                    $($rust::$rust_variant => proto::$proto::$proto_variant,)+
+                }
+            }
+        }
+
+        impl From<proto::$proto> for $rust {
+            fn from(value: proto::$proto) -> Self {
+                match value {
+                    $(proto::$proto::$proto_variant => $rust::$rust_variant,)+
+                }
+            }
+        }
+    };
+}
+
+unit_enum!(CommandStatus, ResponseStatus, Ok => Ok, Processing => Processing, Error => Error);
+unit_enum!(
+    RunState,
+    RunState,
+    Running => Running,
+    Stopping => Stopping,
+    Stopped => Stopped,
+    NotAnswering => NotAnswering,
+);
+unit_enum!(
+    ProxyProtocolConfig,
+    ProxyProtocolConfig,
+    ExpectHeader => ExpectHeader,
+    SendHeader => SendHeader,
+    RelayHeader => RelayHeader,
+);
+unit_enum!(
+    LoadBalancingAlgorithms,
+    LoadBalancingAlgorithm,
+    RoundRobin => RoundRobin,
+    Random => Random,
+    LeastLoaded => LeastLoaded,
+    PowerOfTwo => PowerOfTwo,
+    ConsistentHash => ConsistentHash,
+    LeastResponseTime => LeastResponseTime,
+);
+unit_enum!(
+    LoadMetric,
+    LoadMetric,
+    Connections => Connections,
+    Requests => Requests,
+    ConnectionTime => ConnectionTime,
+);
+unit_enum!(BackendProtocol, BackendProtocol, Http1 => Http1, Http2 => Http2, Grpc => Grpc);
+unit_enum!(WebSocketDrain, WebSocketDrain, Close => Close, Wait => Wait);
+unit_enum!(HeaderPosition, HeaderPosition, Request => Request, Response => Response);
+unit_enum!(HeaderOperation, HeaderOperation, Add => Add, Set => Set, Remove => Remove);
+unit_enum!(
+    RetryCondition,
+    RetryCondition,
+    ConnectFailure => ConnectFailure,
+    Http502 => Http502,
+    Http503 => Http503,
+);
+unit_enum!(HealthCheckKind, HealthCheckKind, Tcp => Tcp, Http => Http, Grpc => Grpc);
+unit_enum!(RulePosition, RulePosition, Tree => Tree, Pre => Pre, Post => Post);
+unit_enum!(
+    TlsVersion,
+    TlsVersion,
+    SSLv2 => SslV2,
+    SSLv3 => SslV3,
+    TLSv1_0 => TlsV10,
+    TLSv1_1 => TlsV11,
+    TLSv1_2 => TlsV12,
+    TLSv1_3 => TlsV13,
+);
+unit_enum!(ListenerType, ListenerType, HTTP => Http, HTTPS => Https, TCP => Tcp);
+unit_enum!(TrailingSlash, TrailingSlash, Keep => Keep, Add => Add, Remove => Remove);
+unit_enum!(TlsProvider, TlsProvider, Rustls => Rustls, Openssl => Openssl);
+unit_enum!(AclMode, AclMode, Allow => Allow, Deny => Deny);
+
+/// the id of the request is left empty, for the caller to fill
+impl TryFrom<proto::Request> for CommandRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(request: proto::Request) -> anyhow::Result<Self> {
+        use proto::request::Order;
+
+        let proxy = |order| CommandRequestOrder::Proxy(Box::new(order));
+
+        let order = match required(request.order, "order")? {
+            Order::SaveState(path) => CommandRequestOrder::SaveState { path },
+            Order::LoadState(path) => CommandRequestOrder::LoadState { path },
+            Order::DumpState(_) => CommandRequestOrder::DumpState,
+            Order::ListWorkers(_) => CommandRequestOrder::ListWorkers,
+            Order::ListFrontends(filters) => CommandRequestOrder::ListFrontends(FrontendFilters {
+                http: filters.http,
+                https: filters.https,
+                tcp: filters.tcp,
+                domain: filters.domain,
+            }),
+            Order::ListCertificates(filters) => {
+                CommandRequestOrder::ListCertificates(CertificateFilters {
+                    domain: filters.domain,
+                    expiring_within: filters.expiring_within,
+                    address: filters.address.as_deref().map(address).transpose()?,
+                })
+            }
+            Order::LaunchWorker(tag) => CommandRequestOrder::LaunchWorker(tag),
+            Order::UpgradeMain(_) => CommandRequestOrder::UpgradeMain,
+            Order::UpgradeWorker(id) => CommandRequestOrder::UpgradeWorker(id),
+            Order::RestartWorker(id) => CommandRequestOrder::RestartWorker(id),
+            Order::ReloadConfiguration(reload) => {
+                CommandRequestOrder::ReloadConfiguration { path: reload.path }
+            }
+            Order::Status(_) => CommandRequestOrder::Status,
+            Order::ApplyState(state) => CommandRequestOrder::ApplyState {
+                state: Box::new(state.try_into()?),
+            },
+            Order::StateHistory(_) => CommandRequestOrder::StateHistory,
+            Order::RollbackState(version) => CommandRequestOrder::RollbackState { version },
+            Order::SyncPeers(_) => CommandRequestOrder::SyncPeers,
+            Order::AuditLog(lines) => CommandRequestOrder::AuditLog {
+                lines: lines as usize,
+            },
+
+            Order::AddCluster(cluster) => proxy(ProxyRequestOrder::AddCluster(cluster.try_into()?)),
+            Order::RemoveCluster(cluster_id) => {
+                proxy(ProxyRequestOrder::RemoveCluster { cluster_id })
+            }
+            Order::SetClusterMaintenance(maintenance) => {
+                proxy(ProxyRequestOrder::SetClusterMaintenance(maintenance.into()))
+            }
+            Order::AddHttpFrontend(front) => {
+                proxy(ProxyRequestOrder::AddHttpFrontend(front.try_into()?))
+            }
+            Order::RemoveHttpFrontend(front) => {
+                proxy(ProxyRequestOrder::RemoveHttpFrontend(front.try_into()?))
+            }
+            Order::AddHttpsFrontend(front) => {
+                proxy(ProxyRequestOrder::AddHttpsFrontend(front.try_into()?))
+            }
+            Order::RemoveHttpsFrontend(front) => {
+                proxy(ProxyRequestOrder::RemoveHttpsFrontend(front.try_into()?))
+            }
+            Order::AddCertificate(add) => {
+                proxy(ProxyRequestOrder::AddCertificate(AddCertificate {
+                    address: address(&add.address)?,
+                    certificate: required(add.certificate, "certificate")?.try_into()?,
+                    names: add.names,
+                    expired_at: add.expired_at,
+                }))
+            }
+            Order::ReplaceCertificate(replace) => {
+                proxy(ProxyRequestOrder::ReplaceCertificate(ReplaceCertificate {
+                    address: address(&replace.address)?,
+                    new_certificate: required(replace.new_certificate, "new_certificate")?
+                        .try_into()?,
+                    old_fingerprint: CertificateFingerprint(replace.old_fingerprint),
+                    new_names: replace.new_names,
+                    new_expired_at: replace.new_expired_at,
+                }))
+            }
+            Order::RemoveCertificate(remove) => {
+                proxy(ProxyRequestOrder::RemoveCertificate(RemoveCertificate {
+                    address: address(&remove.address)?,
+                    fingerprint: CertificateFingerprint(remove.fingerprint),
+                }))
+            }
+            Order::SetOcspResponse(set) => {
+                proxy(ProxyRequestOrder::SetOcspResponse(SetOcspResponse {
+                    address: address(&set.address)?,
+                    fingerprint: CertificateFingerprint(set.fingerprint),
+                    ocsp_response: set.ocsp_response,
+                }))
+            }
+            Order::SetDefaultCertificate(set) => proxy(ProxyRequestOrder::SetDefaultCertificate(
+                SetDefaultCertificate {
+                    address: address(&set.address)?,
+                    fingerprint: set.fingerprint.map(CertificateFingerprint),
+                },
+            )),
+            Order::SetTicketKeys(set) => proxy(ProxyRequestOrder::SetTicketKeys(SetTicketKeys {
+                keys: set.keys,
+                lifetime: set.lifetime,
+            })),
+            Order::AddTcpFrontend(front) => {
+                proxy(ProxyRequestOrder::AddTcpFrontend(front.try_into()?))
+            }
+            Order::RemoveTcpFrontend(front) => {
+                proxy(ProxyRequestOrder::RemoveTcpFrontend(front.try_into()?))
+            }
+            Order::AddSniFrontend(front) => {
+                proxy(ProxyRequestOrder::AddSniFrontend(front.try_into()?))
+            }
+            Order::RemoveSniFrontend(front) => {
+                proxy(ProxyRequestOrder::RemoveSniFrontend(front.try_into()?))
+            }
+            Order::AddBackend(backend) => proxy(ProxyRequestOrder::AddBackend(backend.try_into()?)),
+            Order::RemoveBackend(remove) => {
+                proxy(ProxyRequestOrder::RemoveBackend(RemoveBackend {
+                    cluster_id: remove.cluster_id,
+                    backend_id: remove.backend_id,
+                    address: address(&remove.address)?,
+                }))
+            }
+            Order::DrainBackend(drain) => proxy(ProxyRequestOrder::DrainBackend(DrainBackend {
+                cluster_id: drain.cluster_id,
+                backend_id: drain.backend_id,
+                address: address(&drain.address)?,
+                remove: drain.remove,
+            })),
+            Order::UpdateBackendWeight(update) => proxy(ProxyRequestOrder::UpdateBackendWeight(
+                UpdateBackendWeight {
+                    cluster_id: update.cluster_id,
+                    backend_id: update.backend_id,
+                    address: address(&update.address)?,
+                    weight: narrow(update.weight, "weight")?,
+                },
+            )),
+            Order::SetAffinity(affinity) => proxy(ProxyRequestOrder::SetAffinity(affinity.into())),
+            Order::AddHttpListener(listener) => {
+                proxy(ProxyRequestOrder::AddHttpListener(listener.try_into()?))
+            }
+            Order::AddHttpsListener(listener) => {
+                proxy(ProxyRequestOrder::AddHttpsListener(listener.try_into()?))
+            }
+            Order::AddTcpListener(listener) => {
+                proxy(ProxyRequestOrder::AddTcpListener(listener.try_into()?))
+            }
+            Order::RemoveListener(remove) => {
+                proxy(ProxyRequestOrder::RemoveListener(RemoveListener {
+                    address: address(&remove.address)?,
+                    proxy: enumeration::<proto::ListenerType, _>(remove.proxy, "proxy")?,
+                }))
+            }
+            Order::ActivateListener(activate) => {
+                proxy(ProxyRequestOrder::ActivateListener(ActivateListener {
+                    address: address(&activate.address)?,
+                    proxy: enumeration::<proto::ListenerType, _>(activate.proxy, "proxy")?,
+                    from_scm: activate.from_scm,
+                }))
+            }
+            Order::DeactivateListener(deactivate) => {
+                proxy(ProxyRequestOrder::DeactivateListener(DeactivateListener {
+                    address: address(&deactivate.address)?,
+                    proxy: enumeration::<proto::ListenerType, _>(deactivate.proxy, "proxy")?,
+                    to_scm: deactivate.to_scm,
+                }))
+            }
+            Order::AddAcl(acl) => proxy(ProxyRequestOrder::AddAcl(acl.try_into()?)),
+            Order::RemoveAcl(remove) => proxy(ProxyRequestOrder::RemoveAcl(RemoveAcl {
+                address: address(&remove.address)?,
+                proxy: enumeration::<proto::ListenerType, _>(remove.proxy, "proxy")?,
+                hostname: remove.hostname,
+            })),
+            Order::Query(query) => proxy(ProxyRequestOrder::Query(query.try_into()?)),
+            Order::SoftStop(soft_stop) => proxy(ProxyRequestOrder::SoftStop(soft_stop.deadline)),
+            Order::HardStop(_) => proxy(ProxyRequestOrder::HardStop),
+            Order::WorkerStatus(_) => proxy(ProxyRequestOrder::Status),
+            Order::ConfigureMetrics(configuration) => {
+                use proto::metrics_configuration::Configuration;
+
+                proxy(ProxyRequestOrder::ConfigureMetrics(
+                    match required(configuration.configuration, "configuration")? {
+                        Configuration::Enabled(enabled) => MetricsConfiguration::Enabled(enabled),
+                        Configuration::Clear(_) => MetricsConfiguration::Clear,
+                    },
+                ))
+            }
+            Order::Logging(filter) => proxy(ProxyRequestOrder::Logging(filter)),
+            Order::ReturnListenSockets(_) => proxy(ProxyRequestOrder::ReturnListenSockets),
+            Order::SetAccessLogFilters(set) => proxy(ProxyRequestOrder::SetAccessLogFilters(
+                convert_all(set.filters)?,
+            )),
+            Order::NameResolved(resolution) => {
+                proxy(ProxyRequestOrder::NameResolved(NameResolution {
+                    session: resolution.session as usize,
+                    name: resolution.name,
+                    port: u16::try_from(resolution.port).context("invalid port")?,
+                    addresses: resolution
+                        .addresses
+                        .iter()
+                        .map(|a| address(a))
+                        .collect::<anyhow::Result<_>>()?,
+                }))
+            }
+            Order::Batch(batch) => {
+                let mut orders = Vec::new();
+                for request in batch.orders {
+                    match CommandRequest::try_from(request)?.order {
+                        CommandRequestOrder::Proxy(order) => orders.push(*order),
+                        order => return Err(anyhow!("a batch cannot hold the order {:?}", order)),
+                    }
+                }
+                proxy(ProxyRequestOrder::Batch(orders))
+            }
+        };
+
+        let mut command_request = CommandRequest::new(String::new(), order, request.worker_id);
+        command_request.worker_ids = request.worker_ids;
+        command_request.strict = request.strict;
+        command_request.dry_run = request.dry_run;
+        Ok(command_request)
+    }
+}
+
+/// the orders of an applied state, as they were sent to the workers
+impl From<ProxyRequestOrder> for proto::request::Order {
+    fn from(order: ProxyRequestOrder) -> Self {
+        use proto::request::Order;
+
+        let listener_type = |proxy: ListenerType| proto::ListenerType::from(proxy) as i32;
+
+        match order {
+            ProxyRequestOrder::AddCluster(cluster) => Order::AddCluster(cluster.into()),
+            ProxyRequestOrder::RemoveCluster { cluster_id } => Order::RemoveCluster(cluster_id),
+            ProxyRequestOrder::SetClusterMaintenance(maintenance) => {
+                Order::SetClusterMaintenance(maintenance.into())
+            }
+            ProxyRequestOrder::AddHttpFrontend(front) => Order::AddHttpFrontend(front.into()),
+            ProxyRequestOrder::RemoveHttpFrontend(front) => Order::RemoveHttpFrontend(front.into()),
+            ProxyRequestOrder::AddHttpsFrontend(front) => Order::AddHttpsFrontend(front.into()),
+            ProxyRequestOrder::RemoveHttpsFrontend(front) => {
+                Order::RemoveHttpsFrontend(front.into())
+            }
+            ProxyRequestOrder::AddCertificate(add) => {
+                Order::AddCertificate(proto::AddCertificate {
+                    address: add.address.to_string(),
+                    certificate: Some(add.certificate.into()),
+                    names: add.names,
+                    expired_at: add.expired_at,
+                })
+            }
+            ProxyRequestOrder::ReplaceCertificate(replace) => {
+                Order::ReplaceCertificate(proto::ReplaceCertificate {
+                    address: replace.address.to_string(),
+                    new_certificate: Some(replace.new_certificate.into()),
+                    old_fingerprint: replace.old_fingerprint.0,
+                    new_names: replace.new_names,
+                    new_expired_at: replace.new_expired_at,
+                })
+            }
+            ProxyRequestOrder::RemoveCertificate(remove) => {
+                Order::RemoveCertificate(proto::RemoveCertificate {
+                    address: remove.address.to_string(),
+                    fingerprint: remove.fingerprint.0,
+                })
+            }
+            ProxyRequestOrder::SetOcspResponse(set) => {
+                Order::SetOcspResponse(proto::SetOcspResponse {
+                    address: set.address.to_string(),
+                    fingerprint: set.fingerprint.0,
+                    ocsp_response: set.ocsp_response,
+                })
+            }
+            ProxyRequestOrder::SetDefaultCertificate(set) => {
+                Order::SetDefaultCertificate(proto::SetDefaultCertificate {
+                    address: set.address.to_string(),
+                    fingerprint: set.fingerprint.map(|fingerprint| fingerprint.0),
+                })
+            }
+            ProxyRequestOrder::SetTicketKeys(set) => Order::SetTicketKeys(proto::SetTicketKeys {
+                keys: set.keys,
+                lifetime: set.lifetime,
+            }),
+            ProxyRequestOrder::AddTcpFrontend(front) => Order::AddTcpFrontend(front.into()),
+            ProxyRequestOrder::RemoveTcpFrontend(front) => Order::RemoveTcpFrontend(front.into()),
+            ProxyRequestOrder::AddSniFrontend(front) => Order::AddSniFrontend(front.into()),
+            ProxyRequestOrder::RemoveSniFrontend(front) => Order::RemoveSniFrontend(front.into()),
+            ProxyRequestOrder::AddBackend(backend) => Order::AddBackend(backend.into()),
+            ProxyRequestOrder::RemoveBackend(remove) => {
+                Order::RemoveBackend(proto::RemoveBackend {
+                    cluster_id: remove.cluster_id,
+                    backend_id: remove.backend_id,
+                    address: remove.address.to_string(),
+                })
+            }
+            ProxyRequestOrder::DrainBackend(drain) => Order::DrainBackend(proto::DrainBackend {
+                cluster_id: drain.cluster_id,
+                backend_id: drain.backend_id,
+                address: drain.address.to_string(),
+                remove: drain.remove,
+            }),
+            ProxyRequestOrder::UpdateBackendWeight(update) => {
+                Order::UpdateBackendWeight(proto::UpdateBackendWeight {
+                    cluster_id: update.cluster_id,
+                    backend_id: update.backend_id,
+                    address: update.address.to_string(),
+                    weight: update.weight.into(),
+                })
+            }
+            ProxyRequestOrder::SetAffinity(affinity) => Order::SetAffinity(affinity.into()),
+            ProxyRequestOrder::AddHttpListener(listener) => Order::AddHttpListener(listener.into()),
+            ProxyRequestOrder::AddHttpsListener(listener) => {
+                Order::AddHttpsListener(listener.into())
+            }
+            ProxyRequestOrder::AddTcpListener(listener) => Order::AddTcpListener(listener.into()),
+            ProxyRequestOrder::RemoveListener(remove) => {
+                Order::RemoveListener(proto::RemoveListener {
+                    address: remove.address.to_string(),
+                    proxy: listener_type(remove.proxy),
+                })
+            }
+            ProxyRequestOrder::ActivateListener(activate) => {
+                Order::ActivateListener(proto::ActivateListener {
+                    address: activate.address.to_string(),
+                    proxy: listener_type(activate.proxy),
+                    from_scm: activate.from_scm,
+                })
+            }
+            ProxyRequestOrder::DeactivateListener(deactivate) => {
+                Order::DeactivateListener(proto::DeactivateListener {
+                    address: deactivate.address.to_string(),
+                    proxy: listener_type(deactivate.proxy),
+                    to_scm: deactivate.to_scm,
+                })
+            }
+            ProxyRequestOrder::AddAcl(acl) => Order::AddAcl(acl.into()),
+            ProxyRequestOrder::RemoveAcl(remove) => Order::RemoveAcl(proto::RemoveAcl {
+                address: remove.address.to_string(),
+                proxy: listener_type(remove.proxy),
+                hostname: remove.hostname,
+            }),
+            ProxyRequestOrder::Query(query) => Order::Query(query.into()),
+            ProxyRequestOrder::SoftStop(deadline) => Order::SoftStop(proto::SoftStop { deadline }),
+            ProxyRequestOrder::HardStop => Order::HardStop(proto::Empty {}),
+            ProxyRequestOrder::Status => Order::WorkerStatus(proto::Empty {}),
+            ProxyRequestOrder::ConfigureMetrics(configuration) => {
+                use proto::metrics_configuration::Configuration;
+
+                Order::ConfigureMetrics(proto::MetricsConfiguration {
+                    configuration: Some(match configuration {
+                        MetricsConfiguration::Enabled(enabled) => Configuration::Enabled(enabled),
+                        MetricsConfiguration::Clear => Configuration::Clear(proto::Empty {}),
+                    }),
+                })
+            }
+            ProxyRequestOrder::Logging(filter) => Order::Logging(filter),
+            ProxyRequestOrder::ReturnListenSockets => Order::ReturnListenSockets(proto::Empty {}),
+            ProxyRequestOrder::SetAccessLogFilters(filters) => {
+                Order::SetAccessLogFilters(proto::AccessLogFilters {
+                    filters: into_all(filters),
+                })
+            }
+            ProxyRequestOrder::NameResolved(resolution) => {
+                Order::NameResolved(proto::NameResolution {
+                    session: resolution.session as u64,
+                    name: resolution.name,
+                    port: resolution.port.into(),
+                    addresses: resolution
+                        .addresses
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect(),
+                })
+            }
+            ProxyRequestOrder::Batch(orders) => Order::Batch(proto::Batch {
+                orders: orders
+                    .into_iter()
+                    .map(|order| proto::Request {
+                        order: Some(order.into()),
+                        worker_id: None,
+                        worker_ids: Vec::new(),
+                        strict: false,
+                        dry_run: false,
+                    })
+                    .collect(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<proto::Query> for Query {
+    type Error = anyhow::Error;
+
+    fn try_from(query: proto::Query) -> anyhow::Result<Self> {
+        use proto::{query::Query as Kind, query_certificates::Certificates};
+
+        Ok(match required(query.query, "query")? {
+            Kind::ClusterId(cluster_id) => Query::Clusters(QueryClusterType::ClusterId(cluster_id)),
+            Kind::ClusterDomain(domain) => {
+                Query::Clusters(QueryClusterType::Domain(QueryClusterDomain {
+                    hostname: domain.hostname,
+                    path: domain.path,
+                }))
+            }
+            Kind::Certificates(certificates) => {
+                Query::Certificates(match required(certificates.certificates, "certificates")? {
+                    Certificates::All(_) => QueryCertificateType::All,
+                    Certificates::Domain(domain) => QueryCertificateType::Domain(domain),
+                    Certificates::Fingerprint(fingerprint) => {
+                        QueryCertificateType::Fingerprint(fingerprint)
+                    }
+                    Certificates::Resolve(resolve) => {
+                        QueryCertificateType::Resolve(QueryCertificateResolve {
+                            address: address(&resolve.address)?,
+                            hostname: resolve.hostname,
+                        })
+                    }
+                })
+            }
+            Kind::Metrics(options) => Query::Metrics(QueryMetricsOptions {
+                list: options.list,
+                cluster_ids: options.cluster_ids,
+                backend_ids: options.backend_ids,
+                metric_names: options.metric_names,
+            }),
+            Kind::ClustersHashes(_) => Query::ClustersHashes,
+            Kind::BackendHealth(cluster_id) => {
+                Query::BackendHealth(Some(cluster_id).filter(|id| !id.is_empty()))
+            }
+            Kind::Backends(cluster_id) => {
+                Query::Backends(Some(cluster_id).filter(|id| !id.is_empty()))
+            }
+        })
+    }
+}
+
+impl From<Query> for proto::Query {
+    fn from(query: Query) -> Self {
+        use proto::{query::Query as Kind, query_certificates::Certificates};
+
+        let kind = match query {
+            Query::Clusters(QueryClusterType::ClusterId(cluster_id)) => Kind::ClusterId(cluster_id),
+            Query::Clusters(QueryClusterType::Domain(domain)) => {
+                Kind::ClusterDomain(proto::QueryClusterDomain {
+                    hostname: domain.hostname,
+                    path: domain.path,
+                })
+            }
+            Query::Certificates(certificates) => Kind::Certificates(proto::QueryCertificates {
+                certificates: Some(match certificates {
+                    QueryCertificateType::All => Certificates::All(proto::Empty {}),
+                    QueryCertificateType::Domain(domain) => Certificates::Domain(domain),
+                    QueryCertificateType::Fingerprint(fingerprint) => {
+                        Certificates::Fingerprint(fingerprint)
+                    }
+                    QueryCertificateType::Resolve(resolve) => {
+                        Certificates::Resolve(proto::QueryCertificateResolve {
+                            address: resolve.address.to_string(),
+                            hostname: resolve.hostname,
+                        })
+                    }
+                }),
+            }),
+            Query::Metrics(options) => Kind::Metrics(proto::QueryMetricsOptions {
+                list: options.list,
+                cluster_ids: options.cluster_ids,
+                backend_ids: options.backend_ids,
+                metric_names: options.metric_names,
+            }),
+            Query::ClustersHashes => Kind::ClustersHashes(proto::Empty {}),
+            Query::BackendHealth(cluster_id) => Kind::BackendHealth(cluster_id.unwrap_or_default()),
+            Query::Backends(cluster_id) => Kind::Backends(cluster_id.unwrap_or_default()),
+        };
+
+        proto::Query { query: Some(kind) }
+    }
+}
+
+// clusters
+
+impl TryFrom<proto::Cluster> for Cluster {
+    type Error = anyhow::Error;
+
+    fn try_from(cluster: proto::Cluster) -> anyhow::Result<Self> {
+        Ok(Cluster {
+            cluster_id: cluster.cluster_id,
+            sticky_session: cluster.sticky_session,
+            sticky_mode: cluster.sticky_mode.map(Into::into).unwrap_or_default(),
+            https_redirect: cluster.https_redirect,
+            proxy_protocol: cluster
+                .proxy_protocol
+                .map(|config| {
+                    enumeration::<proto::ProxyProtocolConfig, _>(config, "proxy_protocol")
+                })
+                .transpose()?,
+            load_balancing: enumeration::<proto::LoadBalancingAlgorithm, _>(
+                cluster.load_balancing,
+                "load_balancing",
+            )?,
+            hash_key: cluster.hash_key.map(Into::into).unwrap_or_default(),
+            answer_503: cluster.answer_503,
+            load_metric: cluster
+                .load_metric
+                .map(|metric| enumeration::<proto::LoadMetric, _>(metric, "load_metric"))
+                .transpose()?,
+            header_actions: convert_all(cluster.header_actions)?,
+            host_rewrite: cluster.host_rewrite.map(Into::into).unwrap_or_default(),
+            request_limits: cluster.request_limits.map(Into::into).unwrap_or_default(),
+            compression: cluster.compression.map(Into::into).unwrap_or_default(),
+            security_headers: cluster.security_headers.map(Into::into).unwrap_or_default(),
+            request_retries: cluster
+                .request_retries
+                .map(TryInto::try_into)
+                .transpose()?
+                .unwrap_or_default(),
+            timeouts: cluster.timeouts.map(Into::into).unwrap_or_default(),
+            backend_tls: cluster.backend_tls.map(|tls| Box::new(tls.into())),
+            backend_protocol: enumeration::<proto::BackendProtocol, _>(
+                cluster.backend_protocol,
+                "backend_protocol",
+            )?,
+            websocket_drain: enumeration::<proto::WebSocketDrain, _>(
+                cluster.websocket_drain,
+                "websocket_drain",
+            )?,
+            streaming: cluster.streaming,
+            upgrade_protocols: cluster.upgrade_protocols,
+            health_check: cluster.health_check.map(TryInto::try_into).transpose()?,
+            outlier_detection: cluster
+                .outlier_detection
+                .map(TryInto::try_into)
+                .transpose()?,
+            request_queue: cluster.request_queue.map(Into::into),
+            affinity_table: cluster.affinity_table.map(Into::into),
+            max_sessions: cluster.max_sessions.map(|sessions| sessions as usize),
+        })
+    }
+}
+
+impl From<Cluster> for proto::Cluster {
+    fn from(cluster: Cluster) -> Self {
+        proto::Cluster {
+            cluster_id: cluster.cluster_id,
+            sticky_session: cluster.sticky_session,
+            sticky_mode: Some(cluster.sticky_mode.into()),
+            https_redirect: cluster.https_redirect,
+            proxy_protocol: cluster
+                .proxy_protocol
+                .map(|config| proto::ProxyProtocolConfig::from(config) as i32),
+            load_balancing: proto::LoadBalancingAlgorithm::from(cluster.load_balancing) as i32,
+            hash_key: Some(cluster.hash_key.into()),
+            answer_503: cluster.answer_503,
+            load_metric: cluster
+                .load_metric
+                .map(|metric| proto::LoadMetric::from(metric) as i32),
+            header_actions: into_all(cluster.header_actions),
+            host_rewrite: Some(cluster.host_rewrite.into()),
+            request_limits: Some(cluster.request_limits.into()),
+            compression: Some(cluster.compression.into()),
+            security_headers: Some(cluster.security_headers.into()),
+            request_retries: Some(cluster.request_retries.into()),
+            timeouts: Some(cluster.timeouts.into()),
+            backend_tls: cluster.backend_tls.map(|tls| (*tls).into()),
+            backend_protocol: proto::BackendProtocol::from(cluster.backend_protocol) as i32,
+            websocket_drain: proto::WebSocketDrain::from(cluster.websocket_drain) as i32,
+            streaming: cluster.streaming,
+            upgrade_protocols: cluster.upgrade_protocols,
+            health_check: cluster.health_check.map(Into::into),
+            outlier_detection: cluster.outlier_detection.map(Into::into),
+            request_queue: cluster.request_queue.map(Into::into),
+            affinity_table: cluster.affinity_table.map(Into::into),
+            max_sessions: cluster.max_sessions.map(|sessions| sessions as u64),
+        }
+    }
+}
+
+impl From<proto::StickyMode> for StickyMode {
+    fn from(mode: proto::StickyMode) -> Self {
+        use proto::sticky_mode::Mode;
+
+        match mode.mode {
+            None | Some(Mode::Cookie(_)) => StickyMode::Cookie,
+            Some(Mode::SourceIp(_)) => StickyMode::SourceIp,
+            Some(Mode::Header(name)) => StickyMode::Header(name),
+        }
+    }
+}
+
+impl From<StickyMode> for proto::StickyMode {
+    fn from(mode: StickyMode) -> Self {
+        use proto::sticky_mode::Mode;
+
+        let mode = match mode {
+            StickyMode::Cookie => Mode::Cookie(proto::Empty {}),
+            StickyMode::SourceIp => Mode::SourceIp(proto::Empty {}),
+            StickyMode::Header(name) => Mode::Header(name),
+        };
+        proto::StickyMode { mode: Some(mode) }
+    }
+}
+
+impl From<proto::HashKey> for HashKey {
+    fn from(key: proto::HashKey) -> Self {
+        use proto::hash_key::Key;
+
+        match key.key {
+            None | Some(Key::Url(_)) => HashKey::Url,
+            Some(Key::SourceIp(_)) => HashKey::SourceIp,
+            Some(Key::Header(name)) => HashKey::Header(name),
+            Some(Key::Cookie(name)) => HashKey::Cookie(name),
+        }
+    }
+}
+
+impl From<HashKey> for proto::HashKey {
+    fn from(key: HashKey) -> Self {
+        use proto::hash_key::Key;
+
+        let key = match key {
+            HashKey::Url => Key::Url(proto::Empty {}),
+            HashKey::SourceIp => Key::SourceIp(proto::Empty {}),
+            HashKey::Header(name) => Key::Header(name),
+            HashKey::Cookie(name) => Key::Cookie(name),
+        };
+        proto::HashKey { key: Some(key) }
+    }
+}
+
+impl From<proto::HostRewrite> for HostRewrite {
+    fn from(rewrite: proto::HostRewrite) -> Self {
+        use proto::host_rewrite::Rewrite;
+
+        match rewrite.rewrite {
+            None | Some(Rewrite::Preserve(_)) => HostRewrite::Preserve,
+            Some(Rewrite::BackendAddress(_)) => HostRewrite::BackendAddress,
+            Some(Rewrite::Fixed(host)) => HostRewrite::Fixed(host),
+        }
+    }
+}
+
+impl From<HostRewrite> for proto::HostRewrite {
+    fn from(rewrite: HostRewrite) -> Self {
+        use proto::host_rewrite::Rewrite;
+
+        let rewrite = match rewrite {
+            HostRewrite::Preserve => Rewrite::Preserve(proto::Empty {}),
+            HostRewrite::BackendAddress => Rewrite::BackendAddress(proto::Empty {}),
+            HostRewrite::Fixed(host) => Rewrite::Fixed(host),
+        };
+        proto::HostRewrite {
+            rewrite: Some(rewrite),
+        }
+    }
+}
+
+impl TryFrom<proto::HeaderAction> for HeaderAction {
+    type Error = anyhow::Error;
+
+    fn try_from(action: proto::HeaderAction) -> anyhow::Result<Self> {
+        Ok(HeaderAction {
+            position: enumeration::<proto::HeaderPosition, _>(action.position, "position")?,
+            operation: enumeration::<proto::HeaderOperation, _>(action.operation, "operation")?,
+            name: action.name,
+            value: action.value,
+        })
+    }
+}
+
+impl From<HeaderAction> for proto::HeaderAction {
+    fn from(action: HeaderAction) -> Self {
+        proto::HeaderAction {
+            position: proto::HeaderPosition::from(action.position) as i32,
+            operation: proto::HeaderOperation::from(action.operation) as i32,
+            name: action.name,
+            value: action.value,
+        }
+    }
+}
+
+impl From<proto::RequestLimits> for RequestLimits {
+    fn from(limits: proto::RequestLimits) -> Self {
+        RequestLimits {
+            max_header_size: limits.max_header_size.map(|size| size as usize),
+            max_header_count: limits.max_header_count.map(|count| count as usize),
+            max_body_size: limits.max_body_size.map(|size| size as usize),
+            max_buffered_body: limits.max_buffered_body.map(|size| size as usize),
+            buffer_chunked_body: limits.buffer_chunked_body,
+        }
+    }
+}
+
+impl From<RequestLimits> for proto::RequestLimits {
+    fn from(limits: RequestLimits) -> Self {
+        proto::RequestLimits {
+            max_header_size: limits.max_header_size.map(|size| size as u64),
+            max_header_count: limits.max_header_count.map(|count| count as u64),
+            max_body_size: limits.max_body_size.map(|size| size as u64),
+            max_buffered_body: limits.max_buffered_body.map(|size| size as u64),
+            buffer_chunked_body: limits.buffer_chunked_body,
+        }
+    }
+}
+
+impl From<proto::Compression> for Compression {
+    fn from(compression: proto::Compression) -> Self {
+        Compression {
+            gzip: compression.gzip,
+            brotli: compression.brotli,
+            min_size: compression.min_size.map(|size| size as usize),
+        }
+    }
+}
+
+impl From<Compression> for proto::Compression {
+    fn from(compression: Compression) -> Self {
+        proto::Compression {
+            gzip: compression.gzip,
+            brotli: compression.brotli,
+            min_size: compression.min_size.map(|size| size as u64),
+        }
+    }
+}
+
+impl From<proto::SecurityHeaders> for SecurityHeaders {
+    fn from(headers: proto::SecurityHeaders) -> Self {
+        SecurityHeaders {
+            strict_transport_security: headers.strict_transport_security,
+            content_type_options: headers.content_type_options,
+            frame_options: headers.frame_options,
+        }
+    }
+}
+
+impl From<SecurityHeaders> for proto::SecurityHeaders {
+    fn from(headers: SecurityHeaders) -> Self {
+        proto::SecurityHeaders {
+            strict_transport_security: headers.strict_transport_security,
+            content_type_options: headers.content_type_options,
+            frame_options: headers.frame_options,
+        }
+    }
+}
+
+impl TryFrom<proto::RequestRetries> for RequestRetries {
+    type Error = anyhow::Error;
+
+    fn try_from(retries: proto::RequestRetries) -> anyhow::Result<Self> {
+        Ok(RequestRetries {
+            max_attempts: retries
+                .max_attempts
+                .map(|attempts| narrow(attempts, "max_attempts"))
+                .transpose()?,
+            retry_on: enumerations::<proto::RetryCondition, _>(retries.retry_on, "retry_on")?,
+        })
+    }
+}
+
+impl From<RequestRetries> for proto::RequestRetries {
+    fn from(retries: RequestRetries) -> Self {
+        proto::RequestRetries {
+            max_attempts: retries.max_attempts.map(u32::from),
+            retry_on: retries
+                .retry_on
+                .into_iter()
+                .map(|condition| proto::RetryCondition::from(condition) as i32)
+                .collect(),
+        }
+    }
+}
+
+impl From<proto::Timeouts> for Timeouts {
+    fn from(timeouts: proto::Timeouts) -> Self {
+        Timeouts {
+            front_timeout: timeouts.front_timeout,
+            back_timeout: timeouts.back_timeout,
+            connect_timeout: timeouts.connect_timeout,
+            websocket_timeout: timeouts.websocket_timeout,
+        }
+    }
+}
+
+impl From<Timeouts> for proto::Timeouts {
+    fn from(timeouts: Timeouts) -> Self {
+        proto::Timeouts {
+            front_timeout: timeouts.front_timeout,
+            back_timeout: timeouts.back_timeout,
+            connect_timeout: timeouts.connect_timeout,
+            websocket_timeout: timeouts.websocket_timeout,
+        }
+    }
+}
+
+impl From<proto::BackendTls> for BackendTls {
+    fn from(tls: proto::BackendTls) -> Self {
+        BackendTls {
+            sni: tls.sni,
+            skip_verification: tls.skip_verification,
+            ca_certificates: tls.ca_certificates,
+            client_certificate: tls.client_certificate,
+            client_key: tls.client_key,
+            alpn_protocols: tls.alpn_protocols,
+        }
+    }
+}
+
+impl From<BackendTls> for proto::BackendTls {
+    fn from(tls: BackendTls) -> Self {
+        proto::BackendTls {
+            sni: tls.sni,
+            skip_verification: tls.skip_verification,
+            ca_certificates: tls.ca_certificates,
+            client_certificate: tls.client_certificate,
+            client_key: tls.client_key,
+            alpn_protocols: tls.alpn_protocols,
+        }
+    }
+}
+
+impl TryFrom<proto::HealthCheck> for HealthCheck {
+    type Error = anyhow::Error;
+
+    fn try_from(check: proto::HealthCheck) -> anyhow::Result<Self> {
+        let default = HealthCheck::default();
+
+        Ok(HealthCheck {
+            kind: enumeration::<proto::HealthCheckKind, _>(check.kind, "kind")?,
+            path: check.path.unwrap_or(default.path),
+            expected_status: check
+                .expected_status
+                .map(|status| narrow(status, "expected_status"))
+                .transpose()?,
+            expected_body: check.expected_body,
+            service: check.service,
+            interval: check.interval.unwrap_or(default.interval),
+            timeout: check.timeout.unwrap_or(default.timeout),
+            rise: check.rise.unwrap_or(default.rise),
+            fall: check.fall.unwrap_or(default.fall),
+        })
+    }
+}
+
+impl From<HealthCheck> for proto::HealthCheck {
+    fn from(check: HealthCheck) -> Self {
+        proto::HealthCheck {
+            kind: proto::HealthCheckKind::from(check.kind) as i32,
+            path: Some(check.path),
+            expected_status: check.expected_status.map(u32::from),
+            expected_body: check.expected_body,
+            service: check.service,
+            interval: Some(check.interval),
+            timeout: Some(check.timeout),
+            rise: Some(check.rise),
+            fall: Some(check.fall),
+        }
+    }
+}
+
+impl TryFrom<proto::OutlierDetection> for OutlierDetection {
+    type Error = anyhow::Error;
+
+    fn try_from(detection: proto::OutlierDetection) -> anyhow::Result<Self> {
+        let default = OutlierDetection::default();
+
+        Ok(OutlierDetection {
+            consecutive_failures: detection
+                .consecutive_failures
+                .unwrap_or(default.consecutive_failures),
+            error_rate: detection
+                .error_rate
+                .map(|rate| narrow(rate, "error_rate"))
+                .transpose()?,
+            minimum_requests: detection
+                .minimum_requests
+                .unwrap_or(default.minimum_requests),
+            ejection_time: detection.ejection_time.unwrap_or(default.ejection_time),
+            max_ejection_percent: match detection.max_ejection_percent {
+                Some(percent) => narrow(percent, "max_ejection_percent")?,
+                None => default.max_ejection_percent,
+            },
+        })
+    }
+}
+
+impl From<OutlierDetection> for proto::OutlierDetection {
+    fn from(detection: OutlierDetection) -> Self {
+        proto::OutlierDetection {
+            consecutive_failures: Some(detection.consecutive_failures),
+            error_rate: detection.error_rate.map(u32::from),
+            minimum_requests: Some(detection.minimum_requests),
+            ejection_time: Some(detection.ejection_time),
+            max_ejection_percent: Some(u32::from(detection.max_ejection_percent)),
+        }
+    }
+}
+
+impl From<proto::RequestQueue> for RequestQueue {
+    fn from(queue: proto::RequestQueue) -> Self {
+        let default = RequestQueue::default();
+
+        RequestQueue {
+            size: queue.size.map(|size| size as usize).unwrap_or(default.size),
+            timeout: queue.timeout.unwrap_or(default.timeout),
+        }
+    }
+}
+
+impl From<RequestQueue> for proto::RequestQueue {
+    fn from(queue: RequestQueue) -> Self {
+        proto::RequestQueue {
+            size: Some(queue.size as u64),
+            timeout: Some(queue.timeout),
+        }
+    }
+}
+
+impl From<proto::AffinityTable> for AffinityTable {
+    fn from(table: proto::AffinityTable) -> Self {
+        AffinityTable {
+            size: table
+                .size
+                .map(|size| size as usize)
+                .unwrap_or(AffinityTable::default().size),
+        }
+    }
+}
+
+impl From<AffinityTable> for proto::AffinityTable {
+    fn from(table: AffinityTable) -> Self {
+        proto::AffinityTable {
+            size: Some(table.size as u64),
+        }
+    }
+}
+
+impl From<proto::ClusterMaintenance> for ClusterMaintenance {
+    fn from(maintenance: proto::ClusterMaintenance) -> Self {
+        ClusterMaintenance {
+            cluster_id: maintenance.cluster_id,
+            enabled: maintenance.enabled,
+            answer: maintenance.answer,
+        }
+    }
+}
+
+impl From<ClusterMaintenance> for proto::ClusterMaintenance {
+    fn from(maintenance: ClusterMaintenance) -> Self {
+        proto::ClusterMaintenance {
+            cluster_id: maintenance.cluster_id,
+            enabled: maintenance.enabled,
+            answer: maintenance.answer,
+        }
+    }
+}
+
+impl From<proto::Affinity> for Affinity {
+    fn from(affinity: proto::Affinity) -> Self {
+        Affinity {
+            cluster_id: affinity.cluster_id,
+            key: affinity.key,
+            backend_id: affinity.backend_id,
+        }
+    }
+}
+
+impl From<Affinity> for proto::Affinity {
+    fn from(affinity: Affinity) -> Self {
+        proto::Affinity {
+            cluster_id: affinity.cluster_id,
+            key: affinity.key,
+            backend_id: affinity.backend_id,
+        }
+    }
+}
+
+// frontends
+
+impl TryFrom<proto::HttpFrontend> for HttpFrontend {
+    type Error = anyhow::Error;
+
+    fn try_from(front: proto::HttpFrontend) -> anyhow::Result<Self> {
+        Ok(HttpFrontend {
+            route: required(front.route, "route")?.try_into()?,
+            address: address(&front.address)?,
+            hostname: front.hostname,
+            path: front.path.map(Into::into).unwrap_or_default(),
+            method: front.method,
+            methods: front.methods,
+            reject_other_methods: front.reject_other_methods,
+            headers: convert_all(front.headers)?,
+            rewrite_path: front.rewrite_path.map(TryInto::try_into).transpose()?,
+            mirror_cluster_id: front.mirror_cluster_id,
+            position: enumeration::<proto::RulePosition, _>(front.position, "position")?,
+            tags: tags(front.tags),
+        })
+    }
+}
+
+impl From<HttpFrontend> for proto::HttpFrontend {
+    fn from(front: HttpFrontend) -> Self {
+        proto::HttpFrontend {
+            route: Some(front.route.into()),
+            address: front.address.to_string(),
+            hostname: front.hostname,
+            path: Some(front.path.into()),
+            method: front.method,
+            methods: front.methods,
+            reject_other_methods: front.reject_other_methods,
+            headers: into_all(front.headers),
+            rewrite_path: front.rewrite_path.map(Into::into),
+            mirror_cluster_id: front.mirror_cluster_id,
+            position: proto::RulePosition::from(front.position) as i32,
+            tags: front.tags.unwrap_or_default(),
+        }
+    }
+}
+
+impl TryFrom<proto::Route> for Route {
+    type Error = anyhow::Error;
+
+    fn try_from(route: proto::Route) -> anyhow::Result<Self> {
+        use proto::route::Route as Kind;
+
+        Ok(match required(route.route, "route")? {
+            Kind::ClusterId(cluster_id) => Route::ClusterId(cluster_id),
+            Kind::Deny(deny) => Route::Deny {
+                status: match deny.status {
+                    Some(status) => narrow(status, "status")?,
+                    None => default_deny_status(),
+                },
+                body_path: deny.body_path,
+            },
+            Kind::Weighted(weighted) => Route::Weighted(convert_all(weighted.clusters)?),
+            Kind::Redirect(redirect) => Route::Redirect {
+                to: redirect.to,
+                code: narrow(redirect.code, "code")?,
+            },
+            Kind::AbTest(test) => Route::AbTest {
+                cookie: test.cookie,
+                variants: convert_all(test.variants)?,
+            },
+        })
+    }
+}
+
+impl From<Route> for proto::Route {
+    fn from(route: Route) -> Self {
+        use proto::route::Route as Kind;
+
+        let route = match route {
+            Route::ClusterId(cluster_id) => Kind::ClusterId(cluster_id),
+            Route::Deny { status, body_path } => Kind::Deny(proto::Deny {
+                status: Some(u32::from(status)),
+                body_path,
+            }),
+            Route::Weighted(clusters) => Kind::Weighted(proto::WeightedClusters {
+                clusters: into_all(clusters),
+            }),
+            Route::Redirect { to, code } => Kind::Redirect(proto::Redirect {
+                to,
+                code: u32::from(code),
+            }),
+            Route::AbTest { cookie, variants } => Kind::AbTest(proto::AbTest {
+                cookie,
+                variants: into_all(variants),
+            }),
+        };
+        proto::Route { route: Some(route) }
+    }
+}
+
+impl TryFrom<proto::WeightedCluster> for WeightedCluster {
+    type Error = anyhow::Error;
+
+    fn try_from(cluster: proto::WeightedCluster) -> anyhow::Result<Self> {
+        Ok(WeightedCluster {
+            cluster_id: cluster.cluster_id,
+            weight: narrow(cluster.weight, "weight")?,
+        })
+    }
+}
+
+impl From<WeightedCluster> for proto::WeightedCluster {
+    fn from(cluster: WeightedCluster) -> Self {
+        proto::WeightedCluster {
+            cluster_id: cluster.cluster_id,
+            weight: u32::from(cluster.weight),
+        }
+    }
+}
+
+impl From<proto::PathRule> for PathRule {
+    fn from(rule: proto::PathRule) -> Self {
+        use proto::path_rule::Rule;
+
+        match rule.rule {
+            None => PathRule::default(),
+            Some(Rule::Prefix(prefix)) => PathRule::Prefix(prefix),
+            Some(Rule::Regex(regex)) => PathRule::Regex(regex),
+            Some(Rule::Equals(path)) => PathRule::Equals(path),
+        }
+    }
+}
+
+impl From<PathRule> for proto::PathRule {
+    fn from(rule: PathRule) -> Self {
+        use proto::path_rule::Rule;
+
+        let rule = match rule {
+            PathRule::Prefix(prefix) => Rule::Prefix(prefix),
+            PathRule::Regex(regex) => Rule::Regex(regex),
+            PathRule::Equals(path) => Rule::Equals(path),
+        };
+        proto::PathRule { rule: Some(rule) }
+    }
+}
+
+impl TryFrom<proto::HeaderRule> for HeaderRule {
+    type Error = anyhow::Error;
+
+    fn try_from(rule: proto::HeaderRule) -> anyhow::Result<Self> {
+        use proto::header_rule::Value;
+
+        Ok(HeaderRule {
+            name: rule.name,
+            value: match required(rule.value, "value")? {
+                Value::Prefix(prefix) => HeaderValueRule::Prefix(prefix),
+                Value::Regex(regex) => HeaderValueRule::Regex(regex),
+                Value::Equals(value) => HeaderValueRule::Equals(value),
+            },
+        })
+    }
+}
+
+impl From<HeaderRule> for proto::HeaderRule {
+    fn from(rule: HeaderRule) -> Self {
+        use proto::header_rule::Value;
+
+        proto::HeaderRule {
+            name: rule.name,
+            value: Some(match rule.value {
+                HeaderValueRule::Prefix(prefix) => Value::Prefix(prefix),
+                HeaderValueRule::Regex(regex) => Value::Regex(regex),
+                HeaderValueRule::Equals(value) => Value::Equals(value),
+            }),
+        }
+    }
+}
+
+impl TryFrom<proto::PathRewrite> for PathRewrite {
+    type Error = anyhow::Error;
+
+    fn try_from(rewrite: proto::PathRewrite) -> anyhow::Result<Self> {
+        use proto::path_rewrite::Rewrite;
+
+        Ok(match required(rewrite.rewrite, "rewrite")? {
+            Rewrite::StripPrefix(_) => PathRewrite::StripPrefix,
+            Rewrite::Regex(regex) => PathRewrite::Regex {
+                pattern: regex.pattern,
+                replacement: regex.replacement,
+            },
+        })
+    }
+}
+
+impl From<PathRewrite> for proto::PathRewrite {
+    fn from(rewrite: PathRewrite) -> Self {
+        use proto::path_rewrite::Rewrite;
+
+        let rewrite = match rewrite {
+            PathRewrite::StripPrefix => Rewrite::StripPrefix(proto::Empty {}),
+            PathRewrite::Regex {
+                pattern,
+                replacement,
+            } => Rewrite::Regex(proto::RegexRewrite {
+                pattern,
+                replacement,
+            }),
+        };
+        proto::PathRewrite {
+            rewrite: Some(rewrite),
+        }
+    }
+}
+
+impl TryFrom<proto::TcpFrontend> for TcpFrontend {
+    type Error = anyhow::Error;
+
+    fn try_from(front: proto::TcpFrontend) -> anyhow::Result<Self> {
+        Ok(TcpFrontend {
+            cluster_id: front.cluster_id,
+            address: address(&front.address)?,
+            tags: tags(front.tags),
+        })
+    }
+}
+
+impl From<TcpFrontend> for proto::TcpFrontend {
+    fn from(front: TcpFrontend) -> Self {
+        proto::TcpFrontend {
+            cluster_id: front.cluster_id,
+            address: front.address.to_string(),
+            tags: front.tags.unwrap_or_default(),
+        }
+    }
+}
+
+impl TryFrom<proto::SniFrontend> for SniFrontend {
+    type Error = anyhow::Error;
+
+    fn try_from(front: proto::SniFrontend) -> anyhow::Result<Self> {
+        Ok(SniFrontend {
+            cluster_id: front.cluster_id,
+            address: address(&front.address)?,
+            hostname: front.hostname,
+        })
+    }
+}
+
+impl From<SniFrontend> for proto::SniFrontend {
+    fn from(front: SniFrontend) -> Self {
+        proto::SniFrontend {
+            cluster_id: front.cluster_id,
+            address: front.address.to_string(),
+            hostname: front.hostname,
+        }
+    }
+}
+
+impl From<ListedFrontends> for proto::ListedFrontends {
+    fn from(frontends: ListedFrontends) -> Self {
+        proto::ListedFrontends {
+            http_frontends: into_all(frontends.http_frontends),
+            https_frontends: into_all(frontends.https_frontends),
+            tcp_frontends: into_all(frontends.tcp_frontends),
+            sni_frontends: into_all(frontends.sni_frontends),
+        }
+    }
+}
+
+// backends
+
+impl TryFrom<proto::Backend> for Backend {
+    type Error = anyhow::Error;
+
+    fn try_from(backend: proto::Backend) -> anyhow::Result<Self> {
+        Ok(Backend {
+            cluster_id: backend.cluster_id,
+            backend_id: backend.backend_id,
+            address: address(&backend.address)?,
+            sticky_id: backend.sticky_id,
+            load_balancing_parameters: backend
+                .weight
+                .map(|weight| narrow(weight, "weight"))
+                .transpose()?
+                .map(|weight| LoadBalancingParams { weight }),
+            backup: backend.backup,
+            max_connections: backend.max_connections.map(|count| count as usize),
+            timeouts: backend.timeouts.map(Into::into).unwrap_or_default(),
+            tls: backend.tls.map(|tls| Box::new(tls.into())),
+        })
+    }
+}
+
+impl From<Backend> for proto::Backend {
+    fn from(backend: Backend) -> Self {
+        proto::Backend {
+            cluster_id: backend.cluster_id,
+            backend_id: backend.backend_id,
+            address: backend.address.to_string(),
+            sticky_id: backend.sticky_id,
+            weight: backend
+                .load_balancing_parameters
+                .map(|parameters| u32::from(parameters.weight)),
+            backup: backend.backup,
+            max_connections: backend.max_connections.map(|count| count as u64),
+            timeouts: Some(backend.timeouts.into()),
+            tls: backend.tls.map(|tls| (*tls).into()),
+        }
+    }
+}
+
+impl From<BackendHealth> for proto::BackendHealth {
+    fn from(health: BackendHealth) -> Self {
+        proto::BackendHealth {
+            cluster_id: health.cluster_id,
+            backend_id: health.backend_id,
+            address: health.address.to_string(),
+            healthy: health.healthy,
+            ejected: health.ejected,
+            last_check: health.last_check,
+            latency: health.latency,
+            successes: health.successes,
+            failures: health.failures,
+            total_failures: health.total_failures,
+        }
+    }
+}
+
+impl From<QueryAnswerBackend> for proto::QueryAnswerBackend {
+    fn from(backend: QueryAnswerBackend) -> Self {
+        proto::QueryAnswerBackend {
+            cluster_id: backend.cluster_id,
+            backend_id: backend.backend_id,
+            address: backend.address.to_string(),
+            sticky_id: backend.sticky_id,
+            weight: backend.weight.map(u32::from),
+            backup: backend.backup,
+            draining: backend.draining,
+            healthy: backend.healthy,
+            ejected: backend.ejected,
+            active_connections: backend.active_connections as u64,
+            active_requests: backend.active_requests as u64,
+            failures: backend.failures as u64,
+        }
+    }
+}
+
+// certificates
+
+impl TryFrom<proto::CertificateAndKey> for CertificateAndKey {
+    type Error = anyhow::Error;
+
+    fn try_from(certificate: proto::CertificateAndKey) -> anyhow::Result<Self> {
+        Ok(CertificateAndKey {
+            certificate: certificate.certificate,
+            certificate_chain: certificate.certificate_chain,
+            key: certificate.key,
+            versions: enumerations::<proto::TlsVersion, _>(certificate.versions, "versions")?,
+            ocsp_response: certificate.ocsp_response,
+            priority: certificate.priority,
+        })
+    }
+}
+
+impl From<CertificateAndKey> for proto::CertificateAndKey {
+    fn from(certificate: CertificateAndKey) -> Self {
+        proto::CertificateAndKey {
+            certificate: certificate.certificate,
+            certificate_chain: certificate.certificate_chain,
+            key: certificate.key,
+            versions: tls_versions(certificate.versions),
+            ocsp_response: certificate.ocsp_response,
+            priority: certificate.priority,
+        }
+    }
+}
+
+fn tls_versions(versions: Vec<TlsVersion>) -> Vec<i32> {
+    versions
+        .into_iter()
+        .map(|version| proto::TlsVersion::from(version) as i32)
+        .collect()
+}
+
+impl From<ListedCertificate> for proto::ListedCertificate {
+    fn from(certificate: ListedCertificate) -> Self {
+        proto::ListedCertificate {
+            address: certificate.address.to_string(),
+            fingerprint: certificate.fingerprint.0,
+            names: certificate.names,
+            expiration: certificate.expiration,
+            subject: certificate.subject,
+            hostnames: certificate.hostnames,
+        }
+    }
+}
+
+impl From<CertificateIssue> for proto::CertificateIssue {
+    fn from(issue: CertificateIssue) -> Self {
+        use proto::certificate_issue::Issue;
+
+        let issue = match issue {
+            CertificateIssue::InvalidCertificate(error) => Issue::InvalidCertificate(error),
+            CertificateIssue::InvalidKey => Issue::InvalidKey(proto::Empty {}),
+            CertificateIssue::KeyMismatch => Issue::KeyMismatch(proto::Empty {}),
+            CertificateIssue::UnorderedChain(index) => Issue::UnorderedChain(index as u64),
+            CertificateIssue::IncompleteChain => Issue::IncompleteChain(proto::Empty {}),
+            CertificateIssue::UntrustedChain(error) => Issue::UntrustedChain(error),
+            CertificateIssue::Expired => Issue::Expired(proto::Empty {}),
+            CertificateIssue::NotYetValid => Issue::NotYetValid(proto::Empty {}),
+        };
+        proto::CertificateIssue { issue: Some(issue) }
+    }
+}
+
+// listeners
+
+impl From<proto::PathNormalization> for PathNormalization {
+    fn from(normalization: proto::PathNormalization) -> Self {
+        PathNormalization {
+            merge_slashes: normalization.merge_slashes,
+            resolve_dot_segments: normalization.resolve_dot_segments,
+            trailing_slash: proto::TrailingSlash::try_from(normalization.trailing_slash)
+                .map(Into::into)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl From<PathNormalization> for proto::PathNormalization {
+    fn from(normalization: PathNormalization) -> Self {
+        proto::PathNormalization {
+            merge_slashes: normalization.merge_slashes,
+            resolve_dot_segments: normalization.resolve_dot_segments,
+            trailing_slash: proto::TrailingSlash::from(normalization.trailing_slash) as i32,
+        }
+    }
+}
+
+impl TryFrom<proto::HttpListener> for HttpListener {
+    type Error = anyhow::Error;
+
+    fn try_from(listener: proto::HttpListener) -> anyhow::Result<Self> {
+        let default = HttpListener::default();
+
+        Ok(HttpListener {
+            address: address(&listener.address)?,
+            public_address: listener
+                .public_address
+                .as_deref()
+                .map(address)
+                .transpose()?,
+            answer_404: listener.answer_404.unwrap_or(default.answer_404),
+            answer_503: listener.answer_503.unwrap_or(default.answer_503),
+            expect_proxy: listener.expect_proxy,
+            sticky_name: listener.sticky_name.unwrap_or(default.sticky_name),
+            front_timeout: listener.front_timeout.unwrap_or(default.front_timeout),
+            back_timeout: listener.back_timeout.unwrap_or(default.back_timeout),
+            connect_timeout: listener.connect_timeout.unwrap_or(default.connect_timeout),
+            request_timeout: listener.request_timeout.unwrap_or(default.request_timeout),
+            request_limits: listener.request_limits.map(Into::into).unwrap_or_default(),
+            compression: listener.compression.map(Into::into).unwrap_or_default(),
+            path_normalization: listener
+                .path_normalization
+                .map(Into::into)
+                .unwrap_or_default(),
+            fallback_cluster: listener.fallback_cluster,
+            max_sessions: listener.max_sessions.map(|sessions| sessions as usize),
+        })
+    }
+}
+
+impl From<HttpListener> for proto::HttpListener {
+    fn from(listener: HttpListener) -> Self {
+        proto::HttpListener {
+            address: listener.address.to_string(),
+            public_address: listener.public_address.map(|address| address.to_string()),
+            answer_404: Some(listener.answer_404),
+            answer_503: Some(listener.answer_503),
+            expect_proxy: listener.expect_proxy,
+            sticky_name: Some(listener.sticky_name),
+            front_timeout: Some(listener.front_timeout),
+            back_timeout: Some(listener.back_timeout),
+            connect_timeout: Some(listener.connect_timeout),
+            request_timeout: Some(listener.request_timeout),
+            request_limits: Some(listener.request_limits.into()),
+            compression: Some(listener.compression.into()),
+            path_normalization: Some(listener.path_normalization.into()),
+            fallback_cluster: listener.fallback_cluster,
+            max_sessions: listener.max_sessions.map(|sessions| sessions as u64),
+        }
+    }
+}
+
+impl From<proto::ClientAuth> for ClientAuth {
+    fn from(auth: proto::ClientAuth) -> Self {
+        ClientAuth {
+            required: auth.required,
+            ca_certificates: auth.ca_certificates,
+            revocation_lists: auth.revocation_lists,
+            dn_header: auth
+                .dn_header
+                .unwrap_or_else(|| DEFAULT_CLIENT_DN_HEADER.to_string()),
+        }
+    }
+}
+
+impl From<ClientAuth> for proto::ClientAuth {
+    fn from(auth: ClientAuth) -> Self {
+        proto::ClientAuth {
+            required: auth.required,
+            ca_certificates: auth.ca_certificates,
+            revocation_lists: auth.revocation_lists,
+            dn_header: Some(auth.dn_header),
+        }
+    }
+}
+
+impl From<proto::Http2Settings> for Http2Settings {
+    fn from(settings: proto::Http2Settings) -> Self {
+        Http2Settings {
+            enabled: settings.enabled,
+            max_concurrent_streams: settings.max_concurrent_streams,
+            initial_window_size: settings.initial_window_size,
+        }
+    }
+}
+
+impl From<Http2Settings> for proto::Http2Settings {
+    fn from(settings: Http2Settings) -> Self {
+        proto::Http2Settings {
+            enabled: settings.enabled,
+            max_concurrent_streams: settings.max_concurrent_streams,
+            initial_window_size: settings.initial_window_size,
+        }
+    }
+}
+
+/// the empty lists of the TLS settings take the defaults
+fn or_default<T>(values: Vec<T>, default: Vec<T>) -> Vec<T> {
+    if values.is_empty() {
+        default
+    } else {
+        values
+    }
+}
+
+impl TryFrom<proto::HttpsListener> for HttpsListener {
+    type Error = anyhow::Error;
+
+    fn try_from(listener: proto::HttpsListener) -> anyhow::Result<Self> {
+        let default = HttpsListener::default();
+
+        Ok(HttpsListener {
+            address: address(&listener.address)?,
+            public_address: listener
+                .public_address
+                .as_deref()
+                .map(address)
+                .transpose()?,
+            answer_404: listener.answer_404.unwrap_or(default.answer_404),
+            answer_503: listener.answer_503.unwrap_or(default.answer_503),
+            versions: or_default(
+                enumerations::<proto::TlsVersion, _>(listener.versions, "versions")?,
+                default.versions,
+            ),
+            cipher_list: or_default(listener.cipher_list, default.cipher_list),
+            cipher_suites: or_default(listener.cipher_suites, default.cipher_suites),
+            signature_algorithms: or_default(
+                listener.signature_algorithms,
+                default.signature_algorithms,
+            ),
+            groups_list: or_default(listener.groups_list, default.groups_list),
+            tls_provider: enumeration::<proto::TlsProvider, _>(
+                listener.tls_provider,
+                "tls_provider",
+            )?,
+            expect_proxy: listener.expect_proxy,
+            sticky_name: listener.sticky_name.unwrap_or(default.sticky_name),
+            certificate: listener.certificate,
+            certificate_chain: listener.certificate_chain,
+            key: listener.key,
+            front_timeout: listener.front_timeout.unwrap_or(default.front_timeout),
+            back_timeout: listener.back_timeout.unwrap_or(default.back_timeout),
+            connect_timeout: listener.connect_timeout.unwrap_or(default.connect_timeout),
+            request_timeout: listener.request_timeout.unwrap_or(default.request_timeout),
+            request_limits: listener.request_limits.map(Into::into).unwrap_or_default(),
+            compression: listener.compression.map(Into::into).unwrap_or_default(),
+            path_normalization: listener
+                .path_normalization
+                .map(Into::into)
+                .unwrap_or_default(),
+            fallback_cluster: listener.fallback_cluster,
+            client_auth: listener.client_auth.map(|auth| Box::new(auth.into())),
+            default_certificate: listener.default_certificate.map(CertificateFingerprint),
+            strict_sni: listener.strict_sni,
+            handshake_timeout: listener.handshake_timeout,
+            security_headers: Box::new(
+                listener
+                    .security_headers
+                    .map(Into::into)
+                    .unwrap_or_default(),
+            ),
+            http2: listener.http2.map(Into::into).unwrap_or_default(),
+            max_sessions: listener.max_sessions.map(|sessions| sessions as usize),
+        })
+    }
+}
+
+impl From<HttpsListener> for proto::HttpsListener {
+    fn from(listener: HttpsListener) -> Self {
+        proto::HttpsListener {
+            address: listener.address.to_string(),
+            public_address: listener.public_address.map(|address| address.to_string()),
+            answer_404: Some(listener.answer_404),
+            answer_503: Some(listener.answer_503),
+            versions: tls_versions(listener.versions),
+            cipher_list: listener.cipher_list,
+            cipher_suites: listener.cipher_suites,
+            signature_algorithms: listener.signature_algorithms,
+            groups_list: listener.groups_list,
+            tls_provider: proto::TlsProvider::from(listener.tls_provider) as i32,
+            expect_proxy: listener.expect_proxy,
+            sticky_name: Some(listener.sticky_name),
+            certificate: listener.certificate,
+            certificate_chain: listener.certificate_chain,
+            key: listener.key,
+            front_timeout: Some(listener.front_timeout),
+            back_timeout: Some(listener.back_timeout),
+            connect_timeout: Some(listener.connect_timeout),
+            request_timeout: Some(listener.request_timeout),
+            request_limits: Some(listener.request_limits.into()),
+            compression: Some(listener.compression.into()),
+            path_normalization: Some(listener.path_normalization.into()),
+            fallback_cluster: listener.fallback_cluster,
+            client_auth: listener.client_auth.map(|auth| (*auth).into()),
+            default_certificate: listener
+                .default_certificate
+                .map(|fingerprint| fingerprint.0),
+            strict_sni: listener.strict_sni,
+            handshake_timeout: listener.handshake_timeout,
+            security_headers: Some((*listener.security_headers).into()),
+            http2: Some(listener.http2.into()),
+            max_sessions: listener.max_sessions.map(|sessions| sessions as u64),
+        }
+    }
+}
+
+impl TryFrom<proto::TcpListener> for TcpListener {
+    type Error = anyhow::Error;
+
+    fn try_from(listener: proto::TcpListener) -> anyhow::Result<Self> {
+        // the TCP listeners have the timeouts of the HTTP ones by default
+        let default = HttpListener::default();
+
+        Ok(TcpListener {
+            address: address(&listener.address)?,
+            public_address: listener
+                .public_address
+                .as_deref()
+                .map(address)
+                .transpose()?,
+            expect_proxy: listener.expect_proxy,
+            front_timeout: listener.front_timeout.unwrap_or(default.front_timeout),
+            back_timeout: listener.back_timeout.unwrap_or(default.back_timeout),
+            connect_timeout: listener.connect_timeout.unwrap_or(default.connect_timeout),
+            forward_proxy: listener
+                .forward_proxy
+                .map(|proxy| -> anyhow::Result<ForwardProxy> {
+                    Ok(ForwardProxy {
+                        allowed_destinations: proxy
+                            .allowed_destinations
+                            .iter()
+                            .map(|destination| destination.parse().map_err(anyhow::Error::msg))
+                            .collect::<anyhow::Result<_>>()?,
+                        socks5: proxy.socks5,
+                    })
+                })
+                .transpose()?,
+            max_sessions: listener.max_sessions.map(|sessions| sessions as usize),
+        })
+    }
+}
+
+impl From<TcpListener> for proto::TcpListener {
+    fn from(listener: TcpListener) -> Self {
+        proto::TcpListener {
+            address: listener.address.to_string(),
+            public_address: listener.public_address.map(|address| address.to_string()),
+            expect_proxy: listener.expect_proxy,
+            front_timeout: Some(listener.front_timeout),
+            back_timeout: Some(listener.back_timeout),
+            connect_timeout: Some(listener.connect_timeout),
+            forward_proxy: listener.forward_proxy.map(|proxy| proto::ForwardProxy {
+                allowed_destinations: into_all(proxy.allowed_destinations),
+                socks5: proxy.socks5,
+            }),
+            max_sessions: listener.max_sessions.map(|sessions| sessions as u64),
+        }
+    }
+}
+
+impl TryFrom<proto::Acl> for Acl {
+    type Error = anyhow::Error;
+
+    fn try_from(acl: proto::Acl) -> anyhow::Result<Self> {
+        Ok(Acl {
+            address: address(&acl.address)?,
+            proxy: enumeration::<proto::ListenerType, _>(acl.proxy, "proxy")?,
+            hostname: acl.hostname,
+            mode: enumeration::<proto::AclMode, _>(acl.mode, "mode")?,
+            ranges: acl
+                .ranges
+                .iter()
+                .map(|range| range.parse().map_err(anyhow::Error::msg))
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+}
+
+impl From<Acl> for proto::Acl {
+    fn from(acl: Acl) -> Self {
+        proto::Acl {
+            address: acl.address.to_string(),
+            proxy: proto::ListenerType::from(acl.proxy) as i32,
+            hostname: acl.hostname,
+            mode: proto::AclMode::from(acl.mode) as i32,
+            ranges: into_all(acl.ranges),
+        }
+    }
+}
+
+impl TryFrom<proto::AccessLogFilter> for AccessLogFilter {
+    type Error = anyhow::Error;
+
+    fn try_from(filter: proto::AccessLogFilter) -> anyhow::Result<Self> {
+        let status = match filter.status {
+            Some(status) => Some(StatusRange {
+                from: narrow(status.from, "status.from")?,
+                to: narrow(status.to, "status.to")?,
+            }),
+            None => None,
+        };
+
+        Ok(AccessLogFilter {
+            cluster_id: filter.cluster_id,
+            status,
+            max_per_second: filter.max_per_second,
+        })
+    }
+}
+
+impl From<AccessLogFilter> for proto::AccessLogFilter {
+    fn from(filter: AccessLogFilter) -> Self {
+        proto::AccessLogFilter {
+            cluster_id: filter.cluster_id,
+            status: filter.status.map(|status| proto::StatusRange {
+                from: status.from.into(),
+                to: status.to.into(),
+            }),
+            max_per_second: filter.max_per_second,
+        }
+    }
+}
+
+// answers
+
+impl From<CommandResponse> for proto::Response {
+    fn from(response: CommandResponse) -> Self {
+        use proto::response::Content;
+
+        // the orders of a state diff, as requests
+        let requests = |orders: Vec<ProxyRequestOrder>| -> Vec<proto::Request> {
+            orders
+                .into_iter()
+                .map(|order| proto::Request {
+                    order: Some(order.into()),
+                    worker_id: None,
+                    worker_ids: Vec::new(),
+                    strict: false,
+                    dry_run: false,
+                })
+                .collect()
+        };
+
+        let content = response.content.map(|content| match content {
+            CommandResponseContent::Workers(workers) => Content::Workers(proto::Workers {
+                workers: into_all(workers),
+            }),
+            CommandResponseContent::Metrics(metrics) => Content::Metrics(metrics.into()),
+            CommandResponseContent::Query(answers) => Content::Query(proto::WorkerQueryAnswers {
+                answers: into_map(answers),
+            }),
+            CommandResponseContent::State(state) => Content::State((*state).into()),
+            CommandResponseContent::Event(event) => Content::Event(event.into()),
+            CommandResponseContent::FrontendList(frontends) => {
+                Content::FrontendList(frontends.into())
+            }
+            CommandResponseContent::CertificateList(certificates) => {
+                Content::CertificateList(proto::ListedCertificates {
+                    certificates: into_all(certificates),
+                })
+            }
+            CommandResponseContent::CertificateValidation(issues) => {
+                Content::CertificateValidation(proto::CertificateIssues {
+                    issues: into_all(issues),
+                })
+            }
+            CommandResponseContent::Warnings(warnings) => {
+                Content::Warnings(proto::Warnings { warnings })
+            }
+            CommandResponseContent::Status(workers) => Content::WorkerStatus(proto::Workers {
+                workers: into_all(workers),
+            }),
+            CommandResponseContent::StateDiff(orders) => Content::StateDiff(proto::StateDiff {
+                orders: requests(orders),
+            }),
+            CommandResponseContent::StateHistory(versions) => {
+                Content::StateHistory(proto::StateHistory {
+                    versions: into_all(versions),
+                })
+            }
+            CommandResponseContent::DryRun(dry_run) => Content::DryRun(proto::DryRun {
+                orders: requests(dry_run.orders),
+                warnings: dry_run.warnings,
+            }),
+            CommandResponseContent::AuditLog(entries) => Content::AuditLog(proto::AuditLog {
+                entries: into_all(entries),
+            }),
+            CommandResponseContent::AccessLog(record) => Content::AccessLog(record.into()),
+            CommandResponseContent::Progress(progress) => Content::Progress(progress.into()),
+            CommandResponseContent::SoftStop(reports) => {
+                Content::SoftStop(proto::SoftStopReports {
+                    workers: reports
+                        .into_iter()
+                        .map(|(id, report)| (id, report.into()))
+                        .collect(),
+                })
+            }
+        });
+
+        proto::Response {
+            status: proto::ResponseStatus::from(response.status) as i32,
+            message: response.message,
+            content,
+        }
+    }
+}
+
+impl From<AuditEntry> for proto::AuditEntry {
+    fn from(entry: AuditEntry) -> Self {
+        proto::AuditEntry {
+            timestamp: entry.timestamp,
+            request_id: entry.request_id,
+            client: entry.client,
+            user: entry.user,
+            uid: entry.uid,
+            category: entry.category.to_string(),
+            order: serde_json::to_string(&entry.order).unwrap_or_default(),
+            dry_run: entry.dry_run,
+            status: entry
+                .status
+                .map(|status| proto::ResponseStatus::from(status) as i32),
+            message: entry.message,
+            version: entry.version,
+        }
+    }
+}
+
+impl From<AccessLogRecord> for proto::AccessLogRecord {
+    fn from(record: AccessLogRecord) -> Self {
+        proto::AccessLogRecord {
+            timestamp: record.timestamp,
+            cluster_id: record.cluster_id,
+            backend_id: record.backend_id,
+            session_address: record.session_address.map(|address| address.to_string()),
+            backend_address: record.backend_address.map(|address| address.to_string()),
+            protocol: record.protocol,
+            host: record.host,
+            method: record.method,
+            path: record.path,
+            status: record.status.map(u32::from),
+            response_time: record.response_time,
+            service_time: record.service_time,
+            bytes_in: record.bytes_in as u64,
+            bytes_out: record.bytes_out as u64,
+            error: record.error,
+        }
+    }
+}
+
+impl From<Progress> for proto::Progress {
+    fn from(progress: Progress) -> Self {
+        proto::Progress {
+            workers: progress
+                .workers
+                .into_iter()
+                .map(|(id, worker)| (id, worker.into()))
+                .collect(),
+        }
+    }
+}
+
+impl From<WorkerProgress> for proto::WorkerProgress {
+    fn from(worker: WorkerProgress) -> Self {
+        proto::WorkerProgress {
+            expected: worker.expected as u64,
+            ok: worker.ok as u64,
+            errors: worker.errors as u64,
+        }
+    }
+}
+
+impl From<SoftStopReport> for proto::SoftStopReport {
+    fn from(report: SoftStopReport) -> Self {
+        proto::SoftStopReport {
+            drained: report.drained as u64,
+            killed: report.killed as u64,
+        }
+    }
+}
+
+impl From<CrashReport> for proto::CrashReport {
+    fn from(report: CrashReport) -> Self {
+        proto::CrashReport {
+            message: report.message,
+            location: report.location,
+            backtrace: report.backtrace,
+            sessions: report.sessions as u64,
+            last_order: report.last_order,
+        }
+    }
+}
+
+impl From<StateVersion> for proto::StateVersion {
+    fn from(version: StateVersion) -> Self {
+        proto::StateVersion {
+            version: version.version,
+            request_id: version.request_id,
+            timestamp: version.timestamp,
+        }
+    }
+}
+
+impl From<WorkerInfo> for proto::WorkerInfo {
+    fn from(worker: WorkerInfo) -> Self {
+        proto::WorkerInfo {
+            id: worker.id,
+            pid: worker.pid,
+            run_state: proto::RunState::from(worker.run_state) as i32,
+            cpu_cores: worker
+                .cpu_cores
+                .into_iter()
+                .map(|core| core as u64)
+                .collect(),
+        }
+    }
+}
+
+impl From<Event> for proto::Event {
+    fn from(event: Event) -> Self {
+        use proto::event::Kind;
+
+        let backend = |backend_id, address: SocketAddr| proto::BackendEvent {
+            backend_id,
+            address: address.to_string(),
+        };
+        let listener = |address: SocketAddr, proxy: ListenerType| proto::ListenerEvent {
+            address: address.to_string(),
+            proxy: proto::ListenerType::from(proxy) as i32,
+        };
+
+        let kind = match event {
+            Event::BackendDown(backend_id, address) => {
+                Kind::BackendDown(backend(backend_id, address))
+            }
+            Event::BackendUp(backend_id, address) => Kind::BackendUp(backend(backend_id, address)),
+            Event::NoAvailableBackends(cluster_id) => Kind::NoAvailableBackends(cluster_id),
+            Event::RemovedBackendHasNoConnections(backend_id, address) => {
+                Kind::RemovedBackendHasNoConnections(backend(backend_id, address))
+            }
+            Event::CertificateWillExpire(fingerprint, address, expiration) => {
+                Kind::CertificateWillExpire(proto::CertificateWillExpire {
+                    fingerprint,
+                    address: address.to_string(),
+                    expiration,
+                })
+            }
+            Event::ConfigurationChanged(change) => {
+                Kind::ConfigurationChanged(proto::ConfigurationChange {
+                    version: change.version,
+                    request_id: change.request_id,
+                    client: change.client,
+                    user: change.user,
+                    orders: change
+                        .orders
+                        .into_iter()
+                        .map(|(order_type, count)| (order_type, count as u64))
+                        .collect(),
+                })
+            }
+            Event::WorkerLaunched(id, pid) => Kind::WorkerLaunched(proto::WorkerEvent {
+                id,
+                pid,
+                crash_report: None,
+            }),
+            Event::WorkerUpgraded(old_id, new_id) => {
+                Kind::WorkerUpgraded(proto::WorkerUpgraded { old_id, new_id })
+            }
+            Event::WorkerCrashed(id, pid, crash_report) => {
+                Kind::WorkerCrashed(proto::WorkerEvent {
+                    id,
+                    pid,
+                    crash_report: crash_report.map(Into::into),
+                })
+            }
+            Event::WorkerNotAnswering(id) => Kind::WorkerNotAnswering(id),
+            Event::WorkerRestartLimit(id, crashes) => {
+                Kind::WorkerRestartLimit(proto::WorkerRestartLimit { id, crashes })
+            }
+            Event::WorkerMemoryExceeded(id, resident_memory) => {
+                Kind::WorkerMemoryExceeded(proto::WorkerMemoryExceeded {
+                    id,
+                    resident_memory,
+                })
+            }
+            Event::ListenerActivated(address, proxy) => {
+                Kind::ListenerActivated(listener(address, proxy))
+            }
+            Event::ListenerDeactivated(address, proxy) => {
+                Kind::ListenerDeactivated(listener(address, proxy))
+            }
+        };
+        proto::Event { kind: Some(kind) }
+    }
+}
+
+impl From<AggregatedMetricsData> for proto::AggregatedMetrics {
+    fn from(metrics: AggregatedMetricsData) -> Self {
+        proto::AggregatedMetrics {
+            main: into_map(metrics.main),
+            workers: into_map(metrics.workers),
+        }
+    }
+}
+
+impl From<WorkerMetrics> for proto::WorkerMetrics {
+    fn from(metrics: WorkerMetrics) -> Self {
+        proto::WorkerMetrics {
+            proxy: into_map(metrics.proxy.unwrap_or_default()),
+            clusters: into_map(metrics.clusters.unwrap_or_default()),
+        }
+    }
+}
+
+impl From<ClusterMetricsData> for proto::ClusterMetrics {
+    fn from(metrics: ClusterMetricsData) -> Self {
+        proto::ClusterMetrics {
+            cluster: into_map(metrics.cluster.unwrap_or_default()),
+            backends: metrics
+                .backends
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(backend_id, metrics)| {
+                    (
+                        backend_id,
+                        proto::BackendMetrics {
+                            metrics: into_map(metrics),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<FilteredData> for proto::FilteredData {
+    fn from(data: FilteredData) -> Self {
+        use proto::filtered_data::Data;
+
+        let data = match data {
+            FilteredData::Gauge(value) => Data::Gauge(value as u64),
+            FilteredData::Count(value) => Data::Count(value),
+            FilteredData::Time(value) => Data::Time(value as u64),
+            FilteredData::Percentiles(percentiles) => Data::Percentiles(proto::Percentiles {
+                samples: percentiles.samples,
+                p_50: percentiles.p_50,
+                p_90: percentiles.p_90,
+                p_99: percentiles.p_99,
+                p_99_9: percentiles.p_99_9,
+                p_99_99: percentiles.p_99_99,
+                p_99_999: percentiles.p_99_999,
+                p_100: percentiles.p_100,
+            }),
+            FilteredData::TimeSerie(serie) => Data::TimeSerie(proto::FilteredTimeSerie {
+                last_second: serie.last_second,
+                last_minute: serie.last_minute,
+                last_hour: serie.last_hour,
+            }),
+        };
+        proto::FilteredData { data: Some(data) }
+    }
+}
+
+impl From<QueryAnswer> for proto::QueryAnswer {
+    fn from(answer: QueryAnswer) -> Self {
+        use proto::query_answer::Answer;
+
+        let answer = match answer {
+            QueryAnswer::Clusters(clusters) => Answer::Clusters(proto::QueryAnswerClusters {
+                clusters: into_all(clusters),
+            }),
+            QueryAnswer::ClustersHashes(hashes) => {
+                Answer::ClustersHashes(proto::QueryAnswerClustersHashes { hashes })
+            }
+            QueryAnswer::Certificates(certificates) => Answer::Certificates(certificates.into()),
+            QueryAnswer::Metrics(metrics) => Answer::Metrics(metrics.into()),
+            QueryAnswer::BackendHealth(backends) => {
+                Answer::BackendHealth(proto::QueryAnswerBackendHealth {
+                    backends: into_all(backends),
+                })
+            }
+            QueryAnswer::Backends(backends) => Answer::Backends(proto::QueryAnswerBackends {
+                backends: into_all(backends),
+            }),
+        };
+        proto::QueryAnswer {
+            answer: Some(answer),
+        }
+    }
+}
+
+impl From<QueryAnswerCluster> for proto::QueryAnswerCluster {
+    fn from(answer: QueryAnswerCluster) -> Self {
+        proto::QueryAnswerCluster {
+            configuration: answer.configuration.map(Into::into),
+            http_frontends: into_all(answer.http_frontends),
+            https_frontends: into_all(answer.https_frontends),
+            tcp_frontends: into_all(answer.tcp_frontends),
+            sni_frontends: into_all(answer.sni_frontends),
+            backends: into_all(answer.backends),
+            unhealthy_backends: answer.unhealthy_backends,
+        }
+    }
+}
+
+impl From<QueryAnswerCertificate> for proto::QueryAnswerCertificate {
+    fn from(answer: QueryAnswerCertificate) -> Self {
+        use proto::query_answer_certificate::Answer;
+
+        let answer = match answer {
+            QueryAnswerCertificate::All(listeners) => Answer::All(proto::AllCertificates {
+                listeners: listeners
+                    .into_iter()
+                    .map(|(address, domains)| {
+                        (
+                            address.to_string(),
+                            proto::CertificatesByDomain {
+                                domains: domains.into_iter().collect(),
+                            },
+                        )
+                    })
+                    .collect(),
+            }),
+            QueryAnswerCertificate::Domain(listeners) => {
+                Answer::Domain(proto::DomainCertificates {
+                    listeners: listeners
+                        .into_iter()
+                        .filter_map(|(address, certificate)| {
+                            certificate.map(|(domain, fingerprint)| {
+                                (
+                                    address.to_string(),
+                                    proto::DomainCertificate {
+                                        domain,
+                                        fingerprint,
+                                    },
+                                )
+                            })
+                        })
+                        .collect(),
+                })
+            }
+            QueryAnswerCertificate::Fingerprint(certificate) => {
+                let (certificate, frontends) = certificate.unwrap_or_default();
+                Answer::Fingerprint(proto::CertificateWithFrontends {
+                    certificate,
+                    frontends,
+                })
+            }
+            QueryAnswerCertificate::Resolve(resolved) => {
+                Answer::Resolve(resolved.map_or_else(Default::default, |resolved| {
+                    proto::ResolvedCertificate {
+                        fingerprint: resolved.fingerprint.0,
+                        names: resolved.names,
+                        expiration: resolved.expiration,
+                        versions: tls_versions(resolved.versions),
+                        default: resolved.default,
+                    }
+                }))
+            }
+        };
+        proto::QueryAnswerCertificate {
+            answer: Some(answer),
+        }
+    }
+}
+
+impl From<QueryAnswerMetrics> for proto::QueryAnswerMetrics {
+    fn from(answer: QueryAnswerMetrics) -> Self {
+        use proto::query_answer_metrics::Answer;
+
+        let answer = match answer {
+            QueryAnswerMetrics::List((proxy_metrics, cluster_metrics)) => {
+                Answer::List(proto::MetricNames {
+                    proxy_metrics,
+                    cluster_metrics,
+                })
+            }
+            QueryAnswerMetrics::All(metrics) => Answer::All(metrics.into()),
+            QueryAnswerMetrics::Error(error) => Answer::Error(error),
+        };
+        proto::QueryAnswerMetrics {
+            answer: Some(answer),
+        }
+    }
+}
+
+impl From<ConfigState> for proto::State {
+    fn from(state: ConfigState) -> Self {
+        use proto::listener_state::Listener;
+
+        // the hash maps are sorted, for the same state to give the same message
+        fn sorted<K: Ord, V>(map: HashMap<K, V>) -> impl Iterator<Item = V> {
+            map.into_iter().collect::<BTreeMap<K, V>>().into_values()
+        }
+
+        let listener = |listener, activated| proto::ListenerState {
+            listener: Some(listener),
+            activated,
+        };
+
+        proto::State {
+            clusters: into_all(state.clusters.into_values()),
+            backends: into_all(state.backends.into_values().flatten()),
+            http_listeners: sorted(state.http_listeners)
+                .map(|(http, activated)| listener(Listener::Http(http.into()), activated))
+                .collect(),
+            https_listeners: sorted(state.https_listeners)
+                .map(|(https, activated)| listener(Listener::Https(https.into()), activated))
+                .collect(),
+            tcp_listeners: sorted(state.tcp_listeners)
+                .map(|(tcp, activated)| listener(Listener::Tcp(tcp.into()), activated))
+                .collect(),
+            http_frontends: into_all(state.http_fronts.into_values()),
+            https_frontends: into_all(state.https_fronts.into_values()),
+            tcp_frontends: into_all(sorted(state.tcp_fronts).flatten()),
+            sni_frontends: into_all(sorted(state.sni_fronts).flatten()),
+            certificates: state
+                .certificates
+                .into_iter()
+                .flat_map(|(address, certificates)| {
+                    certificates
+                        .into_iter()
+                        .map(move |(fingerprint, (certificate, names))| {
+                            let certificate = proto::CertificateState {
+                                address: address.to_string(),
+                                fingerprint: fingerprint.0,
+                                certificate: Some(certificate.into()),
+                                names,
+                            };
+                            ((address, certificate.fingerprint.clone()), certificate)
+                        })
+                })
+                .collect::<BTreeMap<_, _>>()
+                .into_values()
+                .collect(),
+            acls: into_all(state.acls.into_values().flatten()),
+            maintenance: into_all(state.maintenance.into_values()),
+            affinities: state
+                .affinities
+                .into_iter()
+                .flat_map(|(cluster_id, affinities)| {
+                    affinities
+                        .into_iter()
+                        .map(move |(key, backend_id)| proto::Affinity {
+                            cluster_id: cluster_id.clone(),
+                            key,
+                            backend_id,
+                        })
+                })
+                .collect(),
+        }
+    }
+}
+
+/// the state is rebuilt with the orders creating it, for its indexes to be
+/// the ones the main process computes
+impl TryFrom<proto::State> for ConfigState {
+    type Error = anyhow::Error;
+
+    fn try_from(state: proto::State) -> anyhow::Result<Self> {
+        use proto::listener_state::Listener;
+
+        let mut orders = Vec::new();
+        for cluster in state.clusters {
+            orders.push(ProxyRequestOrder::AddCluster(cluster.try_into()?));
+        }
+        for backend in state.backends {
+            orders.push(ProxyRequestOrder::AddBackend(backend.try_into()?));
+        }
+
+        for listener in state
+            .http_listeners
+            .into_iter()
+            .chain(state.https_listeners)
+            .chain(state.tcp_listeners)
+        {
+            let (address, proxy, order) = match required(listener.listener, "listener")? {
+                Listener::Http(http) => {
+                    let http = HttpListener::try_from(http)?;
+                    (
+                        http.address,
+                        ListenerType::HTTP,
+                        ProxyRequestOrder::AddHttpListener(http),
+                    )
+                }
+                Listener::Https(https) => {
+                    let https = HttpsListener::try_from(https)?;
+                    (
+                        https.address,
+                        ListenerType::HTTPS,
+                        ProxyRequestOrder::AddHttpsListener(https),
+                    )
+                }
+                Listener::Tcp(tcp) => {
+                    let tcp = TcpListener::try_from(tcp)?;
+                    (
+                        tcp.address,
+                        ListenerType::TCP,
+                        ProxyRequestOrder::AddTcpListener(tcp),
+                    )
+                }
+            };
+            orders.push(order);
+            if listener.activated {
+                orders.push(ProxyRequestOrder::ActivateListener(ActivateListener {
+                    address,
+                    proxy,
+                    from_scm: false,
+                }));
+            }
+        }
+
+        for front in state.http_frontends {
+            orders.push(ProxyRequestOrder::AddHttpFrontend(front.try_into()?));
+        }
+        for front in state.https_frontends {
+            orders.push(ProxyRequestOrder::AddHttpsFrontend(front.try_into()?));
+        }
+        for front in state.tcp_frontends {
+            orders.push(ProxyRequestOrder::AddTcpFrontend(front.try_into()?));
+        }
+        for front in state.sni_frontends {
+            orders.push(ProxyRequestOrder::AddSniFrontend(front.try_into()?));
+        }
+        for certificate in state.certificates {
+            orders.push(ProxyRequestOrder::AddCertificate(AddCertificate {
+                address: address(&certificate.address)?,
+                certificate: required(certificate.certificate, "certificate")?.try_into()?,
+                names: certificate.names,
+                expired_at: None,
+            }));
+        }
+        for acl in state.acls {
+            orders.push(ProxyRequestOrder::AddAcl(acl.try_into()?));
+        }
+        for maintenance in state.maintenance {
+            orders.push(ProxyRequestOrder::SetClusterMaintenance(maintenance.into()));
+        }
+        for affinity in state.affinities {
+            orders.push(ProxyRequestOrder::SetAffinity(affinity.into()));
+        }
+
+        let mut config_state = ConfigState::new();
+        for order in orders.iter() {
+            config_state.handle_order(order);
+        }
+        Ok(config_state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clusters_and_frontends_round_trip() {
+        let cluster = Cluster {
+            cluster_id: String::from("app"),
+            sticky_session: true,
+            sticky_mode: Default::default(),
+            https_redirect: true,
+            proxy_protocol: None,
+            load_balancing: Default::default(),
+            hash_key: Default::default(),
+            answer_503: None,
+            load_metric: None,
+            header_actions: Vec::new(),
+            host_rewrite: Default::default(),
+            request_limits: Default::default(),
+            compression: Default::default(),
+            security_headers: Default::default(),
+            request_retries: Default::default(),
+            timeouts: Default::default(),
+            backend_tls: None,
+            backend_protocol: Default::default(),
+            websocket_drain: Default::default(),
+            streaming: false,
+            upgrade_protocols: Vec::new(),
+            health_check: Some(HealthCheck::default()),
+            outlier_detection: None,
+            request_queue: None,
+            affinity_table: None,
+            max_sessions: Some(100),
+        };
+        let message = proto::Cluster::from(cluster.clone());
+        assert_eq!(Cluster::try_from(message).unwrap(), cluster);
+
+        let front = HttpFrontend {
+            route: Route::Deny {
+                status: 403,
+                body_path: None,
+            },
+            address: "127.0.0.1:8080".parse().unwrap(),
+            hostname: String::from("example.com"),
+            path: Default::default(),
+            method: Some(String::from("GET")),
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
+            position: RulePosition::Pre,
+            tags: Some([(String::from("owner"), String::from("me"))].into()),
+        };
+        let message = proto::HttpFrontend::from(front.clone());
+        assert_eq!(HttpFrontend::try_from(message).unwrap(), front);
+
+        let listener = HttpsListener {
+            versions: vec![TlsVersion::TLSv1_2, TlsVersion::TLSv1_3],
+            ..Default::default()
+        };
+        let message = proto::HttpsListener::from(listener.clone());
+        assert_eq!(HttpsListener::try_from(message).unwrap(), listener);
+    }
+
+    #[test]
+    fn requests_are_checked() {
+        use proto::request::Order;
+
+        let request = |order| proto::Request {
+            order: Some(order),
+            worker_id: Some(1),
+            worker_ids: vec![1, 2],
+            strict: true,
+            dry_run: false,
+        };
+
+        let backend = proto::Backend {
+            cluster_id: String::from("app"),
+            backend_id: String::from("app-0"),
+            address: String::from("127.0.0.1:1026"),
+            weight: Some(10),
+            ..Default::default()
+        };
+        let command =
+            CommandRequest::try_from(request(Order::AddBackend(backend.clone()))).unwrap();
+        assert_eq!(command.worker_id, Some(1));
+        assert_eq!(command.worker_ids, vec![1, 2]);
+        assert!(command.strict);
+        assert_eq!(
+            command.order,
+            CommandRequestOrder::Proxy(Box::new(ProxyRequestOrder::AddBackend(Backend {
+                cluster_id: String::from("app"),
+                backend_id: String::from("app-0"),
+                address: "127.0.0.1:1026".parse().unwrap(),
+                sticky_id: None,
+                load_balancing_parameters: Some(LoadBalancingParams { weight: 10 }),
+                backup: None,
+                max_connections: None,
+                timeouts: Default::default(),
+                tls: None,
+            })))
+        );
+
+        // the weights are bytes
+        let heavy = proto::Backend {
+            weight: Some(1000),
+            ..backend.clone()
+        };
+        assert!(CommandRequest::try_from(request(Order::AddBackend(heavy))).is_err());
+
+        let misplaced = proto::Backend {
+            address: String::from("localhost"),
+            ..backend
+        };
+        assert!(CommandRequest::try_from(request(Order::AddBackend(misplaced))).is_err());
+
+        assert!(CommandRequest::try_from(proto::Request::default()).is_err());
+
+        let health = proto::Query {
+            query: Some(proto::query::Query::BackendHealth(String::new())),
+        };
+        assert_eq!(
+            CommandRequest::try_from(request(Order::Query(health)))
+                .unwrap()
+                .order,
+            CommandRequestOrder::Proxy(Box::new(ProxyRequestOrder::Query(Query::BackendHealth(
+                None
+            ))))
+        );
+
+        let backends = proto::Query {
+            query: Some(proto::query::Query::Backends(String::from("app"))),
+        };
+        assert_eq!(
+            CommandRequest::try_from(request(Order::Query(backends)))
+                .unwrap()
+                .order,
+            CommandRequestOrder::Proxy(Box::new(ProxyRequestOrder::Query(Query::Backends(Some(
+                String::from("app")
+            )))))
+        );
+
+        // the statuses are 16 bits
+        let filter = proto::AccessLogFilter {
+            cluster_id: Some(String::from("app")),
+            status: Some(proto::StatusRange { from: 500, to: 599 }),
+            max_per_second: 10,
+        };
+        assert_eq!(
+            AccessLogFilter::try_from(filter.clone()).unwrap(),
+            AccessLogFilter {
+                cluster_id: Some(String::from("app")),
+                status: Some(StatusRange { from: 500, to: 599 }),
+                max_per_second: 10,
+            }
+        );
+        let wide = proto::AccessLogFilter {
+            status: Some(proto::StatusRange {
+                from: 500,
+                to: 100_000,
+            }),
+            ..filter
+        };
+        assert!(AccessLogFilter::try_from(wide).is_err());
+    }
+
+    #[test]
+    fn states_round_trip() {
+        let address: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let orders = [
+            ProxyRequestOrder::AddCluster(Cluster {
+                cluster_id: String::from("app"),
+                ..Default::default()
+            }),
+            ProxyRequestOrder::AddBackend(Backend {
+                cluster_id: String::from("app"),
+                backend_id: String::from("app-0"),
+                address: "127.0.0.1:1026".parse().unwrap(),
+                sticky_id: None,
+                load_balancing_parameters: Some(LoadBalancingParams { weight: 100 }),
+                backup: None,
+                max_connections: None,
+                timeouts: Timeouts::default(),
+                tls: None,
+            }),
+            ProxyRequestOrder::AddHttpListener(HttpListener {
+                address,
+                ..Default::default()
+            }),
+            ProxyRequestOrder::ActivateListener(ActivateListener {
+                address,
+                proxy: ListenerType::HTTP,
+                from_scm: false,
+            }),
+        ];
+        let mut state = ConfigState::new();
+        for order in orders.iter() {
+            state.handle_order(order);
+        }
+
+        let message = proto::State::from(state.clone());
+        assert_eq!(ConfigState::try_from(message).unwrap(), state);
+
+        let diff = ConfigState::new().diff(&state);
+        let response = proto::Response::from(CommandResponse::new(
+            String::from("ID"),
+            CommandStatus::Ok,
+            String::new(),
+            Some(CommandResponseContent::StateDiff(diff.clone())),
+        ));
+        let requests = match response.content {
+            Some(proto::response::Content::StateDiff(state_diff)) => state_diff.orders,
+            content => panic!("unexpected content {:?}", content),
+        };
+        let orders = requests
+            .into_iter()
+            .map(
+                |request| *match CommandRequest::try_from(request).unwrap().order {
+                    CommandRequestOrder::Proxy(order) => order,
+                    order => panic!("unexpected order {:?}", order),
+                },
+            )
+            .collect::<Vec<_>>();
+        assert_eq!(orders, diff);
+    }
+
+    #[test]
+    fn answers() {
+        let response = CommandResponse::new(
+            String::from("ID"),
+            CommandStatus::Error,
+            String::from("could not add the backend"),
+            Some(CommandResponseContent::Warnings(vec![String::from(
+                "unknown cluster app",
+            )])),
+        );
+        assert_eq!(
+            proto::Response::from(response),
+            proto::Response {
+                status: proto::ResponseStatus::Error as i32,
+                message: String::from("could not add the backend"),
+                content: Some(proto::response::Content::Warnings(proto::Warnings {
+                    warnings: vec![String::from("unknown cluster app")],
+                })),
+            }
+        );
+    }
+
+    #[test]
+    fn crash_events() {
+        let report = CrashReport {
+            message: String::from("the message should be valid"),
+            location: Some(String::from("lib/src/server.rs:743")),
+            backtrace: String::new(),
+            sessions: 12,
+            last_order: Some(String::from("ID (AddCluster)")),
+        };
+        assert_eq!(
+            proto::Event::from(Event::WorkerCrashed(1, 4242, Some(report))),
+            proto::Event {
+                kind: Some(proto::event::Kind::WorkerCrashed(proto::WorkerEvent {
+                    id: 1,
+                    pid: 4242,
+                    crash_report: Some(proto::CrashReport {
+                        message: String::from("the message should be valid"),
+                        location: Some(String::from("lib/src/server.rs:743")),
+                        backtrace: String::new(),
+                        sessions: 12,
+                        last_order: Some(String::from("ID (AddCluster)")),
+                    }),
+                })),
+            }
+        );
+    }
+}
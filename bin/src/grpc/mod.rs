@@ -0,0 +1,225 @@
+//! The command protocol served over gRPC, with the messages of `command.proto`.
+//!
+//! Every call opens its own connection to the command socket of the main process,
+//! like sozuctl does, so the gRPC server runs next to the proxy and needs nothing
+//! more than the path of the socket from the configuration.
+// the calls fail with a `tonic::Status`, large as it is
+#![allow(clippy::result_large_err)]
+
+mod convert;
+
+#[allow(clippy::all)]
+mod proto {
+    tonic::include_proto!("sozu.command");
+}
+
+use std::{net::SocketAddr, sync::Arc, thread, time::Duration};
+
+use anyhow::Context;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{transport::Server, Request, Response, Status};
+
+use sozu_command_lib::{
+    channel::Channel,
+    command::{
+        CommandRequest, CommandRequestOrder, CommandResponse, CommandResponseContent, CommandStatus,
+    },
+    config::Config,
+    proxy::AccessLogFilter,
+};
+
+use crate::ctl::create_channel;
+
+use proto::command_server::{Command, CommandServer};
+
+/// events or access logs waiting for a slow client, the subscription waits
+/// after that
+const EVENT_BUFFER: usize = 64;
+
+fn generate_id() -> String {
+    let s: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(6)
+        .map(|c| c as char)
+        .collect();
+    format!("GRPC-{}", s)
+}
+
+/// serves the command API on this address until the process is stopped
+pub fn serve(config: Config, address: SocketAddr) -> anyhow::Result<()> {
+    // the logger lives on this thread, so the calls run on it too
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .with_context(|| "could not create the gRPC runtime")?;
+
+    let service = CommandService {
+        config: Arc::new(config),
+    };
+
+    info!("serving the command API over gRPC on {}", address);
+    runtime
+        .block_on(
+            Server::builder()
+                .add_service(CommandServer::new(service))
+                .serve(address),
+        )
+        .with_context(|| format!("could not serve the command API on {}", address))
+}
+
+struct CommandService {
+    config: Arc<Config>,
+}
+
+fn connect(config: &Config) -> Result<Channel<CommandRequest, CommandResponse>, Status> {
+    create_channel(config).map_err(|error| {
+        Status::unavailable(format!(
+            "could not connect to the command socket of sozu: {:#}",
+            error
+        ))
+    })
+}
+
+fn write_request(
+    channel: &mut Channel<CommandRequest, CommandResponse>,
+    request: &CommandRequest,
+) -> Result<(), Status> {
+    if !channel.write_message(request) {
+        return Err(Status::unavailable("could not write the request to sozu"));
+    }
+    Ok(())
+}
+
+/// sends the request to the main process, and waits for its final answer
+fn send(config: &Config, request: CommandRequest) -> Result<CommandResponse, Status> {
+    let mut channel = connect(config)?;
+    write_request(&mut channel, &request)?;
+
+    let timeout = Duration::from_millis(config.ctl_command_timeout);
+    loop {
+        let response = channel
+            .read_message_blocking_timeout(Some(timeout))
+            .ok_or_else(|| Status::deadline_exceeded("sozu did not answer in time"))?;
+
+        if response.id != request.id {
+            return Err(Status::internal(format!(
+                "received an answer with the wrong id: {}",
+                response.id
+            )));
+        }
+        if !matches!(response.status, CommandStatus::Processing) {
+            return Ok(response);
+        }
+    }
+}
+
+/// sends the order of a subscription, and streams the answers of the main
+/// process converted by `convert` from a thread reading them
+fn subscribe<T: Send + 'static>(
+    config: &Config,
+    order: CommandRequestOrder,
+    convert: fn(CommandResponseContent) -> Option<T>,
+) -> Result<ReceiverStream<Result<T, Status>>, Status> {
+    let mut channel = connect(config)?;
+    let request = CommandRequest::new(generate_id(), order, None);
+    write_request(&mut channel, &request)?;
+    debug!("gRPC subscription {}: {:?}", request.id, request.order);
+
+    let (sender, receiver) = mpsc::channel(EVENT_BUFFER);
+    thread::Builder::new()
+        .name(request.id)
+        .spawn(move || forward(channel, sender, convert))
+        .map_err(|error| Status::internal(error.to_string()))?;
+
+    Ok(ReceiverStream::new(receiver))
+}
+
+/// forwards the answers of the subscription, until the connection to the main
+/// process closes or the client is gone, which is noticed on the next answer
+fn forward<T>(
+    mut channel: Channel<CommandRequest, CommandResponse>,
+    sender: mpsc::Sender<Result<T, Status>>,
+    convert: fn(CommandResponseContent) -> Option<T>,
+) {
+    loop {
+        let response = match channel.read_message_blocking_timeout(None) {
+            Some(response) => response,
+            None => {
+                let _ = sender.blocking_send(Err(Status::unavailable(
+                    "the connection to sozu was closed",
+                )));
+                return;
+            }
+        };
+
+        let item = match (response.status, response.content.and_then(convert)) {
+            (CommandStatus::Processing, Some(item)) => Ok(item),
+            (CommandStatus::Error, _) => Err(Status::internal(response.message)),
+            // the confirmation of the subscription
+            _ => continue,
+        };
+
+        let failed = item.is_err();
+        if sender.blocking_send(item).is_err() || failed {
+            return;
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Command for CommandService {
+    async fn execute(
+        &self,
+        request: Request<proto::Request>,
+    ) -> Result<Response<proto::Response>, Status> {
+        let mut request = CommandRequest::try_from(request.into_inner())
+            .map_err(|error| Status::invalid_argument(format!("{:#}", error)))?;
+        request.id = generate_id();
+        debug!("gRPC request {}: {:?}", request.id, request.order);
+
+        let config = self.config.clone();
+        let response = tokio::task::spawn_blocking(move || send(&config, request))
+            .await
+            .map_err(|error| Status::internal(error.to_string()))??;
+
+        Ok(Response::new(response.into()))
+    }
+
+    type SubscribeEventsStream = ReceiverStream<Result<proto::Event, Status>>;
+
+    async fn subscribe_events(
+        &self,
+        _request: Request<proto::SubscribeEventsRequest>,
+    ) -> Result<Response<Self::SubscribeEventsStream>, Status> {
+        let events = subscribe(
+            &self.config,
+            CommandRequestOrder::SubscribeEvents,
+            |content| match content {
+                CommandResponseContent::Event(event) => Some(event.into()),
+                _ => None,
+            },
+        )?;
+        Ok(Response::new(events))
+    }
+
+    type TailAccessLogsStream = ReceiverStream<Result<proto::AccessLogRecord, Status>>;
+
+    async fn tail_access_logs(
+        &self,
+        request: Request<proto::AccessLogFilter>,
+    ) -> Result<Response<Self::TailAccessLogsStream>, Status> {
+        let filter = AccessLogFilter::try_from(request.into_inner())
+            .map_err(|error| Status::invalid_argument(format!("{:#}", error)))?;
+        let records = subscribe(
+            &self.config,
+            CommandRequestOrder::TailAccessLogs(filter),
+            |content| match content {
+                CommandResponseContent::AccessLog(record) => Some(record.into()),
+                _ => None,
+            },
+        )?;
+        Ok(Response::new(records))
+    }
+}
@@ -28,9 +28,10 @@ macro_rules! log {
           target: module_path!(),
       };
       {
+        // the tag is initialized from the logger, before it is locked here
+        let tag = &*$crate::logging::TAG;
         let mut logger = $crate::logging::MAIN_LOGGER.lock().unwrap();
         let pid = (*logger).pid;
-        let tag = &*$crate::logging::TAG;
 
         let (now, precise_time) = $crate::sozu_command_lib::logging::now();
         (*logger).log(
@@ -60,7 +61,7 @@ pub fn init(
     let directives = crate::sozu_command_lib::logging::parse_logging_spec(spec);
     let mut logger = MAIN_LOGGER.lock().unwrap();
     if !logger.initialized {
-        println!("initializing logger");
+        eprintln!("initializing logger");
         logger.set_directives(directives);
         logger.backend = backend;
         logger.access_backend = access_backend;
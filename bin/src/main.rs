@@ -27,6 +27,15 @@ mod cli;
 mod command;
 /// The command line logic
 mod ctl;
+/// The command API over gRPC
+#[cfg(feature = "grpc")]
+mod grpc;
+/// The command channel over TCP and TLS, for the clients of other hosts
+mod remote;
+/// Seccomp and Landlock confinement of the workers
+mod sandbox;
+/// Readiness and watchdog notifications, and socket activation, under systemd
+mod systemd;
 /// Forking & restarting the main process
 mod upgrade;
 /// Some unix helper functions
@@ -34,14 +43,14 @@ mod util;
 /// Start and restart the worker UNIX processes
 mod worker;
 
-use std::panic;
+use std::{panic, path::Path};
 
 use anyhow::{bail, Context};
 #[cfg(target_os = "linux")]
 use libc::{cpu_set_t, pid_t};
 
 use sozu::metrics::METRICS;
-use sozu_command_lib::config::Config;
+use sozu_command_lib::{config::Config, state::ConfigState};
 
 use crate::{
     command::Worker,
@@ -53,19 +62,24 @@ fn main(args: Args) -> anyhow::Result<()> {
     register_panic_hook();
 
     match args.cmd {
-        cli::SubCmd::Start => {
-            start(&args)?;
+        cli::SubCmd::Start {
+            ref pid_file,
+            daemonize,
+        } => {
+            start(&args, pid_file.as_deref(), daemonize)?;
             info!("main process stopped");
             Ok(())
         }
         // this is used only by the CLI when upgrading
         cli::SubCmd::Worker {
-            fd,
-            scm,
-            configuration_state_fd,
-            id,
+            cmd: None,
+            fd: Some(fd),
+            scm: Some(scm),
+            configuration_state_fd: Some(configuration_state_fd),
+            id: Some(id),
             command_buffer_size,
             max_command_buffer_size,
+            cpu_cores,
         } => {
             let max_command_buffer_size =
                 max_command_buffer_size.unwrap_or(command_buffer_size * 2);
@@ -76,6 +90,7 @@ fn main(args: Args) -> anyhow::Result<()> {
                 id,
                 command_buffer_size,
                 max_command_buffer_size,
+                cpu_cores,
             )
         }
         // this is used only by the CLI when upgrading
@@ -94,38 +109,94 @@ fn main(args: Args) -> anyhow::Result<()> {
                 max_command_buffer_size,
             )
         }
+        #[cfg(feature = "grpc")]
+        cli::SubCmd::Grpc { address } => {
+            let config = load_configuration(get_config_file_path(&args)?)?;
+            util::setup_logging(&config);
+            grpc::serve(config, address)
+        }
         _ => ctl::ctl(args),
     }
 }
 
-fn start(args: &cli::Args) -> Result<(), anyhow::Error> {
+fn start(args: &cli::Args, pid_file: Option<&str>, daemonize: bool) -> Result<(), anyhow::Error> {
     let config_file_path = get_config_file_path(args)?;
-    let config = load_configuration(config_file_path)?;
+    let mut config = load_configuration(config_file_path)?;
+    if let Some(pid_file) = pid_file {
+        config.pid_file_path = Some(pid_file.to_owned());
+    }
+
+    // locked before detaching, so that a running sozu is reported on the terminal
+    util::write_pid_file(&config).with_context(|| "PID file is not writeable")?;
+    if daemonize {
+        util::daemonize()?;
+        util::write_pid_file(&config).with_context(|| "PID file is not writeable")?;
+    }
 
     util::setup_logging(&config);
     info!("Starting up");
     util::setup_metrics(&config).with_context(|| "Could not setup metrics")?;
-    util::write_pid_file(&config).with_context(|| "PID file is not writeable")?;
 
     update_process_limits(&config)?;
 
-    let workers = init_workers(&config)?;
-
-    if config.handle_process_affinity {
+    systemd::take_activated_listeners()
+        .with_context(|| "Could not use the sockets passed by systemd")?;
+    let listener_addresses: Vec<_> = config
+        .http_listeners
+        .iter()
+        .map(|listener| listener.address)
+        .chain(
+            config
+                .https_listeners
+                .iter()
+                .map(|listener| listener.address),
+        )
+        .chain(config.tcp_listeners.iter().map(|listener| listener.address))
+        .collect();
+    systemd::warn_unused_listeners(&listener_addresses);
+
+    let state = preload_state(&config)?;
+    let workers = init_workers(&config, &state)?;
+
+    // the workers pin themselves with worker_cpu_affinity
+    if config.handle_process_affinity && config.worker_cpu_affinity.is_none() {
         set_workers_affinity(&workers);
     }
 
     let command_socket_path = config.command_socket_path()?;
 
-    command::start_server(config, command_socket_path, workers)
+    command::start_server(config, command_socket_path, workers, state)
         .with_context(|| "could not start Sozu")?;
 
     Ok(())
 }
 
-fn init_workers(config: &Config) -> Result<Vec<Worker>, anyhow::Error> {
+/// the state of `state_file`, that the workers get when they are launched
+fn preload_state(config: &Config) -> Result<ConfigState, anyhow::Error> {
+    let path = match &config.state_file {
+        Some(path) if Path::new(path).exists() => path,
+        Some(path) => {
+            info!("no state file at {} yet, starting without it", path);
+            return Ok(ConfigState::new());
+        }
+        None => return Ok(ConfigState::new()),
+    };
+
+    let state_file = ctl::state_file::read_state_file(path)
+        .with_context(|| format!("Could not preload the state file {}", path))?;
+    for warning in state_file.warnings.iter() {
+        warn!("state file {}: {}", path, warning);
+    }
+    info!(
+        "preloaded {} messages from the state file {}",
+        state_file.messages, path
+    );
+    Ok(state_file.state)
+}
+
+fn init_workers(config: &Config, state: &ConfigState) -> Result<Vec<Worker>, anyhow::Error> {
     let path = unsafe { get_executable_path().with_context(|| "Could not get executable path")? };
-    start_workers(path, config).with_context(|| "Failed at spawning workers")
+    start_workers(path, config, state).with_context(|| "Failed at spawning workers")
 }
 
 pub fn get_config_file_path(args: &cli::Args) -> Result<&str, anyhow::Error> {
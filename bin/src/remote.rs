@@ -0,0 +1,588 @@
+//! The command channel over TCP and TLS, to manage the proxy from other hosts.
+//!
+//! Once the TLS session is established, the client sends the shared token, ended
+//! by a 0 byte like the command messages, and the server answers `OK`. From then
+//! on, the plaintext is relayed to the command socket of the main process, so the
+//! remote clients speak the same protocol as the local ones. The requests are
+//! checked against the orders of the token before being relayed, the main process
+//! sees the remote clients as its own user.
+use std::{
+    fs::File,
+    io::{self, BufReader, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::net::UnixStream,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use mio::{Events, Interest, Poll, Token};
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, Connection, OwnedTrustAnchor, PrivateKey,
+    RootCertStore, ServerConfig, ServerConnection, ServerName, StreamOwned,
+};
+use rustls_pemfile::Item;
+
+use sozu_command_lib::{
+    command::{CommandRequest, CommandResponse, CommandStatus, OrderCategory},
+    config::{Config, RemoteCommandConfig},
+};
+
+/// the answer of the server to a valid token
+const TOKEN_ACCEPTED: &[u8] = b"OK";
+const MAX_HANDSHAKE_MESSAGE_SIZE: usize = 4096;
+/// duration of the TLS handshake and of the token exchange, at most
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// duration before trying to listen again, the previous main process keeps the
+/// address for a while after an upgrade
+const BIND_RETRY_DELAY: Duration = Duration::from_secs(5);
+const RELAY_BUFFER_SIZE: usize = 16384;
+
+const TCP: Token = Token(0);
+const UNIX: Token = Token(1);
+
+/// serves the remote command channel in a thread, if it is configured
+pub fn spawn_server(config: &Config) -> anyhow::Result<()> {
+    let remote = match &config.command_remote {
+        Some(remote) => remote.clone(),
+        None => return Ok(()),
+    };
+    let tls_config = server_config(&remote)
+        .with_context(|| "could not load the certificate of the remote command channel")?;
+    let socket_path = config.command_socket_path()?;
+    let max_request_size = config.max_command_buffer_size;
+
+    thread::Builder::new()
+        .name(String::from("remote-command"))
+        .spawn(move || accept_clients(remote, tls_config, socket_path, max_request_size))
+        .with_context(|| "could not start the remote command channel")?;
+    Ok(())
+}
+
+fn accept_clients(
+    remote: RemoteCommandConfig,
+    tls_config: Arc<ServerConfig>,
+    socket_path: String,
+    max_request_size: usize,
+) {
+    let listener = loop {
+        match TcpListener::bind(remote.address) {
+            Ok(listener) => break listener,
+            Err(e) => {
+                error!(
+                    "could not listen on {} for the remote command clients: {}",
+                    remote.address, e
+                );
+                thread::sleep(BIND_RETRY_DELAY);
+            }
+        }
+    };
+    info!("serving the remote command channel on {}", remote.address);
+
+    let max_clients = remote.max_clients;
+    let remote = Arc::new(remote);
+    let clients = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let tcp = match stream {
+            Ok(tcp) => tcp,
+            Err(e) => {
+                error!("could not accept a remote command client: {}", e);
+                continue;
+            }
+        };
+        let peer = tcp
+            .peer_addr()
+            .map(|address| address.to_string())
+            .unwrap_or_default();
+
+        if clients.fetch_add(1, Ordering::SeqCst) >= max_clients {
+            clients.fetch_sub(1, Ordering::SeqCst);
+            error!(
+                "refusing the remote command client {}, {} clients are already served",
+                peer, max_clients
+            );
+            continue;
+        }
+
+        let tls_config = tls_config.clone();
+        let remote = remote.clone();
+        let served = clients.clone();
+        let socket_path = socket_path.clone();
+        let spawned = thread::Builder::new()
+            .name(String::from("remote-client"))
+            .spawn(move || {
+                if let Err(e) =
+                    serve_client(tls_config, &remote, &socket_path, tcp, max_request_size)
+                {
+                    error!("remote command client {}: {:#}", peer, e);
+                }
+                served.fetch_sub(1, Ordering::SeqCst);
+            });
+        if let Err(e) = spawned {
+            error!("could not serve a remote command client: {}", e);
+            clients.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// the orders allowed to the clients sending this token, if it is known
+fn token_orders<'a>(
+    remote: &'a RemoteCommandConfig,
+    received: &[u8],
+) -> Option<&'a [OrderCategory]> {
+    let matches = |token: &str| {
+        ring::constant_time::verify_slices_are_equal(received, token.as_bytes()).is_ok()
+    };
+
+    // every token is compared, to take the same time whichever matches
+    let mut orders = None;
+    if matches(&remote.token) {
+        orders = Some(remote.orders.as_slice());
+    }
+    for role in &remote.roles {
+        if matches(&role.token) && orders.is_none() {
+            orders = Some(role.orders.as_slice());
+        }
+    }
+    orders
+}
+
+fn serve_client(
+    tls_config: Arc<ServerConfig>,
+    remote: &RemoteCommandConfig,
+    socket_path: &str,
+    tcp: TcpStream,
+    max_request_size: usize,
+) -> anyhow::Result<()> {
+    tcp.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut stream = StreamOwned::new(ServerConnection::new(tls_config)?, tcp);
+
+    let (received, pending) = read_message(&mut stream)?;
+    let orders = match token_orders(remote, &received) {
+        Some(orders) => orders.to_vec(),
+        None => {
+            stream.write_all(b"invalid token\0")?;
+            stream.conn.send_close_notify();
+            stream.flush()?;
+            bail!("invalid token");
+        }
+    };
+
+    let unix = UnixStream::connect(socket_path)
+        .with_context(|| format!("could not connect to the command socket {}", socket_path))?;
+    stream.write_all(TOKEN_ACCEPTED)?;
+    stream.write_all(&[0])?;
+    stream.flush()?;
+    info!(
+        "remote command client {} authenticated",
+        stream.sock.peer_addr()?
+    );
+
+    let filter = RequestFilter {
+        orders,
+        max_request_size,
+        incoming: Vec::new(),
+    };
+    relay(stream.conn.into(), stream.sock, unix, pending, Some(filter))
+}
+
+/// checks the requests of a remote client against the orders of its token
+struct RequestFilter {
+    orders: Vec<OrderCategory>,
+    max_request_size: usize,
+    /// the beginning of a request not received entirely
+    incoming: Vec<u8>,
+}
+
+impl RequestFilter {
+    /// moves the complete requests that the client can send to `to_unix`, and
+    /// answers the other ones with an error in `to_client`. Returns the number
+    /// of refused requests
+    fn filter(
+        &mut self,
+        received: &[u8],
+        to_unix: &mut Vec<u8>,
+        to_client: &mut Vec<u8>,
+    ) -> anyhow::Result<usize> {
+        let mut refused = 0;
+        self.incoming.extend_from_slice(received);
+        while let Some(position) = self.incoming.iter().position(|byte| *byte == 0) {
+            let message: Vec<u8> = self.incoming.drain(..=position).collect();
+            let request = &message[..message.len() - 1];
+
+            // the main process answers to the requests it cannot parse
+            let category = match serde_json::from_slice::<CommandRequest>(request) {
+                Ok(request) if !self.orders.contains(&request.order.category()) => {
+                    Some((request.id, request.order.category()))
+                }
+                _ => None,
+            };
+            match category {
+                None => to_unix.extend_from_slice(&message),
+                Some((id, category)) => {
+                    let message = format!(
+                        "the remote client is not allowed to send {} orders",
+                        category
+                    );
+                    refused += 1;
+                    let response = CommandResponse::new(id, CommandStatus::Error, message, None);
+                    to_client.extend_from_slice(serde_json::to_string(&response)?.as_bytes());
+                    to_client.push(0);
+                }
+            }
+        }
+
+        if self.incoming.len() > self.max_request_size {
+            bail!("the request is larger than {} bytes", self.max_request_size);
+        }
+        Ok(refused)
+    }
+}
+
+/// opens the command channel of the proxy at this address, and relays it on a
+/// unix stream, to be used like the local command socket
+pub fn connect(address: &str, token: &str, ca: Option<&str>) -> anyhow::Result<UnixStream> {
+    let tcp =
+        TcpStream::connect(address).with_context(|| format!("could not connect to {}", address))?;
+    tcp.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let connection = ClientConnection::new(client_config(ca)?, server_name(address)?)?;
+    let mut stream = StreamOwned::new(connection, tcp);
+
+    stream.write_all(token.as_bytes())?;
+    stream.write_all(&[0])?;
+    stream.flush()?;
+    let (answer, pending) = read_message(&mut stream)
+        .with_context(|| format!("could not open the command channel of {}", address))?;
+    if answer != TOKEN_ACCEPTED {
+        bail!(
+            "{} refused the connection: {}",
+            address,
+            String::from_utf8_lossy(&answer)
+        );
+    }
+
+    let (local, relayed) = UnixStream::pair()?;
+    thread::spawn(move || relay(stream.conn.into(), stream.sock, relayed, pending, None));
+    Ok(local)
+}
+
+/// reads the stream until the 0 byte ending a message, returns the message and
+/// the bytes received after it
+fn read_message<S: Read>(stream: &mut S) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let mut received = Vec::new();
+    let mut buffer = [0u8; 1024];
+    loop {
+        if let Some(position) = received.iter().position(|byte| *byte == 0) {
+            let pending = received.split_off(position + 1);
+            received.pop();
+            return Ok((received, pending));
+        }
+        if received.len() > MAX_HANDSHAKE_MESSAGE_SIZE {
+            bail!("the handshake message is too large");
+        }
+
+        let size = stream.read(&mut buffer)?;
+        if size == 0 {
+            bail!("the connection was closed during the handshake");
+        }
+        received.extend_from_slice(&buffer[..size]);
+    }
+}
+
+/// forwards the plaintext of the TLS session to the unix stream and back, until
+/// one of them is closed. `received` holds plaintext already received. With a
+/// filter, only the allowed requests reach the unix stream
+fn relay(
+    mut tls: Connection,
+    tcp: TcpStream,
+    unix: UnixStream,
+    mut received: Vec<u8>,
+    mut filter: Option<RequestFilter>,
+) -> anyhow::Result<()> {
+    tcp.set_nonblocking(true)?;
+    unix.set_nonblocking(true)?;
+    let mut tcp = mio::net::TcpStream::from_std(tcp);
+    let mut unix = mio::net::UnixStream::from_std(unix);
+
+    let mut poll = Poll::new()?;
+    poll.registry()
+        .register(&mut tcp, TCP, Interest::READABLE | Interest::WRITABLE)?;
+    poll.registry()
+        .register(&mut unix, UNIX, Interest::READABLE | Interest::WRITABLE)?;
+    // the answers can be larger than the default limit, like the state dumps
+    tls.set_buffer_limit(None);
+
+    let mut events = Events::with_capacity(4);
+    let mut buffer = vec![0u8; RELAY_BUFFER_SIZE];
+    let mut to_unix = Vec::new();
+    let mut refusals = Vec::new();
+    let mut between_answers = true;
+    loop {
+        // the sockets are edge triggered, so both are drained after each event
+        let mut tls_closed = false;
+        loop {
+            match tls.read_tls(&mut tcp) {
+                Ok(0) => {
+                    tls_closed = true;
+                    break;
+                }
+                Ok(_) => {
+                    if let Err(e) = tls.process_new_packets() {
+                        // sends the alert to the peer
+                        let _ = tls.write_tls(&mut tcp);
+                        return Err(e.into());
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        loop {
+            match tls.reader().read(&mut buffer) {
+                Ok(0) => {
+                    tls_closed = true;
+                    break;
+                }
+                Ok(size) => received.extend_from_slice(&buffer[..size]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    tls_closed = true;
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        match filter.as_mut() {
+            Some(filter) => {
+                let refused = filter.filter(&received, &mut to_unix, &mut refusals)?;
+                if refused > 0 {
+                    error!(
+                        "refused {} requests of a remote client, not allowed by its token",
+                        refused
+                    );
+                }
+            }
+            None => to_unix.extend_from_slice(&received),
+        }
+        received.clear();
+        write_pending(&mut unix, &mut to_unix)?;
+        if tls_closed {
+            return Ok(());
+        }
+
+        let mut unix_closed = false;
+        loop {
+            match unix.read(&mut buffer) {
+                Ok(0) => {
+                    unix_closed = true;
+                    break;
+                }
+                Ok(size) => {
+                    tls.writer().write_all(&buffer[..size])?;
+                    between_answers = buffer[size - 1] == 0;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        // the refusals are only sent between the answers of the main process
+        if !refusals.is_empty() && between_answers {
+            tls.writer().write_all(&refusals)?;
+            refusals.clear();
+        }
+        if unix_closed {
+            tls.send_close_notify();
+        }
+        while tls.wants_write() {
+            match tls.write_tls(&mut tcp) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if unix_closed {
+            return Ok(());
+        }
+
+        if let Err(e) = poll.poll(&mut events, None) {
+            if e.kind() != ErrorKind::Interrupted {
+                return Err(e.into());
+            }
+        }
+    }
+}
+
+/// writes as much of the pending bytes as the stream accepts without blocking
+fn write_pending<W: Write>(stream: &mut W, pending: &mut Vec<u8>) -> io::Result<()> {
+    while !pending.is_empty() {
+        match stream.write(pending) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(size) => {
+                pending.drain(..size);
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn server_config(remote: &RemoteCommandConfig) -> anyhow::Result<Arc<ServerConfig>> {
+    let certificates = load_certificates(&remote.certificate)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certificates, load_private_key(&remote.key)?)?;
+    Ok(Arc::new(config))
+}
+
+/// trusts the certificate authorities of this file, or the web ones
+fn client_config(ca: Option<&str>) -> anyhow::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(path) => {
+            let (added, _ignored) = roots.add_parsable_certificates(&load_certificates(path)?);
+            if added == 0 {
+                bail!("no certificate authority found in {}", path);
+            }
+        }
+        None => {
+            roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    anchor.subject,
+                    anchor.spki,
+                    anchor.name_constraints,
+                )
+            }))
+        }
+    }
+
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// the host of a `host:port` address, that the certificate of the server must name
+fn server_name(address: &str) -> anyhow::Result<ServerName> {
+    let host = match address.rsplit_once(':') {
+        Some((host, _port)) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => bail!("the remote address {} has no port", address),
+    };
+    ServerName::try_from(host).with_context(|| format!("invalid host name {}", host))
+}
+
+fn load_certificates(path: &str) -> anyhow::Result<Vec<Vec<u8>>> {
+    let file = File::open(path).with_context(|| format!("could not open {}", path))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("could not parse the certificates of {}", path))?;
+    if certificates.is_empty() {
+        bail!("no certificate found in {}", path);
+    }
+    Ok(certificates)
+}
+
+/// the first private key of the file, in the PKCS#1, PKCS#8 or SEC1 format
+fn load_private_key(path: &str) -> anyhow::Result<PrivateKey> {
+    let file = File::open(path).with_context(|| format!("could not open {}", path))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("could not parse the private key of {}", path))?;
+
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .with_context(|| format!("no private key found in {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_messages() {
+        let mut stream: &[u8] = b"secret\0{\"id\":\"ID-1\"}";
+        let (message, pending) = read_message(&mut stream).unwrap();
+        assert_eq!(message, b"secret");
+        assert_eq!(pending, b"{\"id\":\"ID-1\"}");
+
+        let mut truncated: &[u8] = b"secret";
+        assert!(read_message(&mut truncated).is_err());
+
+        assert!(matches!(
+            server_name("sozu.example.com:4242").unwrap(),
+            ServerName::DnsName(name) if name.as_ref() == "sozu.example.com"
+        ));
+        assert!(server_name("sozu.example.com").is_err());
+    }
+
+    #[test]
+    fn remote_roles() {
+        use sozu_command_lib::{command::CommandRequestOrder, config::RemoteCommandRole};
+
+        let remote = RemoteCommandConfig {
+            address: "127.0.0.1:4242".parse().unwrap(),
+            certificate: String::new(),
+            key: String::new(),
+            token: String::from("secret"),
+            orders: vec![OrderCategory::Read, OrderCategory::Mutation],
+            roles: vec![RemoteCommandRole {
+                token: String::from("monitoring"),
+                orders: vec![OrderCategory::Read],
+            }],
+            max_clients: 1,
+        };
+        assert_eq!(
+            token_orders(&remote, b"secret"),
+            Some(&[OrderCategory::Read, OrderCategory::Mutation][..])
+        );
+        assert_eq!(
+            token_orders(&remote, b"monitoring"),
+            Some(&[OrderCategory::Read][..])
+        );
+        assert_eq!(token_orders(&remote, b"monitor"), None);
+
+        let mut filter = RequestFilter {
+            orders: vec![OrderCategory::Read],
+            max_request_size: 1000,
+            incoming: Vec::new(),
+        };
+        let mut requests = Vec::new();
+        for (id, order) in [
+            ("ID-1", CommandRequestOrder::Status),
+            ("ID-2", CommandRequestOrder::UpgradeMain),
+        ] {
+            let request = CommandRequest::new(id.to_owned(), order, None);
+            requests.extend_from_slice(serde_json::to_string(&request).unwrap().as_bytes());
+            requests.push(0);
+        }
+
+        // the requests can be split anywhere
+        let (to_unix, to_client) = (&mut Vec::new(), &mut Vec::new());
+        let (first, second) = requests.split_at(10);
+        assert_eq!(filter.filter(first, to_unix, to_client).unwrap(), 0);
+        assert!(to_unix.is_empty());
+        assert_eq!(filter.filter(second, to_unix, to_client).unwrap(), 1);
+
+        let relayed: CommandRequest =
+            serde_json::from_slice(&to_unix[..to_unix.len() - 1]).unwrap();
+        assert_eq!(relayed.id, "ID-1");
+        let refusal: CommandResponse =
+            serde_json::from_slice(&to_client[..to_client.len() - 1]).unwrap();
+        assert_eq!(refusal.id, "ID-2");
+        assert_eq!(refusal.status, CommandStatus::Error);
+
+        assert!(filter.filter(&[b'{'; 1001], to_unix, to_client).is_err());
+    }
+}
@@ -0,0 +1,410 @@
+//! confines a worker once it is set up: Landlock forbids any access to the
+//! filesystem but the reading of the executable, for the backtraces of the crash
+//! reports, and a seccomp filter only allows the system calls of the event loop,
+//! the others fail with EPERM. The worker resolves no name, the main process does
+//! it for the forward proxy listeners
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub use self::linux::sandbox_worker;
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+pub fn sandbox_worker() -> anyhow::Result<()> {
+    warn!("the workers can only be sandboxed on Linux, on x86_64 and aarch64");
+    Ok(())
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod linux {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt, path::Path};
+
+    use anyhow::bail;
+
+    /// denies the filesystem and the system calls the event loop does not need.
+    /// Landlock only restricts the calling thread, so the worker must not run
+    /// any other one: it is sandboxed before spawning one, and cannot spawn any
+    /// afterwards. The seccomp filter applies to every thread anyway
+    pub fn sandbox_worker() -> anyhow::Result<()> {
+        let threads = std::fs::read_dir("/proc/self/task")
+            .map(|tasks| tasks.count())
+            .unwrap_or(1);
+        if threads > 1 {
+            bail!(
+                "the worker runs {} threads, Landlock would only restrict the calling one",
+                threads
+            );
+        }
+
+        forbid_new_privileges()?;
+
+        match restrict_filesystem() {
+            Ok(abi) => {
+                info!("filesystem access forbidden by Landlock (ABI {})", abi);
+            }
+            // older kernels, or Landlock is not enabled
+            Err(e) => {
+                warn!(
+                    "filesystem access not restricted, Landlock is unavailable: {}",
+                    e
+                );
+            }
+        }
+
+        apply_seccomp_filter()?;
+        info!("system calls restricted by seccomp");
+        Ok(())
+    }
+
+    /// needed to apply a seccomp filter or a Landlock ruleset without privileges
+    fn forbid_new_privileges() -> anyhow::Result<()> {
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            bail!(
+                "could not forbid new privileges: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(())
+    }
+
+    /// the filter is synchronized to every thread of the process
+    fn apply_seccomp_filter() -> anyhow::Result<()> {
+        let filter = seccomp_filter();
+        let program = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_ptr() as *mut libc::sock_filter,
+        };
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &program as *const libc::sock_fprog,
+            )
+        };
+        if result != 0 {
+            bail!(
+                "could not apply the seccomp filter: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(())
+    }
+
+    #[repr(C)]
+    struct LandlockRulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct LandlockPathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1;
+    const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+    const LANDLOCK_ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    const LANDLOCK_ACCESS_FS_READ_DIR: u64 = 1 << 3;
+
+    /// the files a backtrace is symbolized from: the mappings of the process
+    /// give the path of the executable, its debug info can be apart
+    const BACKTRACE_FILES: &[&str] = &["/proc/self/maps", "/usr/lib/debug"];
+
+    /// a ruleset handling every filesystem access, with rules only allowing the
+    /// reading of the files of the backtraces. Returns the Landlock ABI version
+    /// of the kernel
+    fn restrict_filesystem() -> std::io::Result<libc::c_long> {
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<LandlockRulesetAttr>(),
+                0usize,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        // the 13 rights of the first ABI, then REFER and TRUNCATE
+        let handled_access_fs = match abi {
+            1 => (1 << 13) - 1,
+            2 => (1 << 14) - 1,
+            _ => (1 << 15) - 1,
+        };
+        let attr = LandlockRulesetAttr { handled_access_fs };
+        let ruleset = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const LandlockRulesetAttr,
+                std::mem::size_of::<LandlockRulesetAttr>(),
+                0,
+            )
+        };
+        if ruleset < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let executable = std::env::current_exe().ok();
+        let paths = executable
+            .iter()
+            .map(|path| path.as_path())
+            .chain(BACKTRACE_FILES.iter().map(Path::new));
+        for path in paths {
+            // a missing file needs no rule
+            if let Err(e) = allow_reading(ruleset as libc::c_int, path) {
+                debug!("could not allow reading {}: {}", path.display(), e);
+            }
+        }
+
+        let result = unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0) };
+        let error = std::io::Error::last_os_error();
+        unsafe { libc::close(ruleset as libc::c_int) };
+        if result < 0 {
+            return Err(error);
+        }
+        Ok(abi)
+    }
+
+    /// allows reading the file, or the directory and the files beneath it
+    fn allow_reading(ruleset: libc::c_int, path: &Path) -> std::io::Result<()> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        let allowed_access = if unsafe { libc::fstat(fd, &mut stat) } == 0
+            && stat.st_mode & libc::S_IFMT == libc::S_IFDIR
+        {
+            LANDLOCK_ACCESS_FS_READ_FILE | LANDLOCK_ACCESS_FS_READ_DIR
+        } else {
+            LANDLOCK_ACCESS_FS_READ_FILE
+        };
+        let attr = LandlockPathBeneathAttr {
+            allowed_access,
+            parent_fd: fd,
+        };
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset,
+                LANDLOCK_RULE_PATH_BENEATH,
+                &attr as *const LandlockPathBeneathAttr,
+                0,
+            )
+        };
+        let error = std::io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        if result < 0 {
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// offsets of the architecture and system call number in seccomp_data
+    const SECCOMP_DATA_NR: u32 = 0;
+    const SECCOMP_DATA_ARCH: u32 = 4;
+
+    /// the system calls of the event loop: sockets, memory, time and signals
+    const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_readv,
+        libc::SYS_writev,
+        libc::SYS_close,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_statx,
+        libc::SYS_lseek,
+        libc::SYS_openat,
+        libc::SYS_readlinkat,
+        libc::SYS_fcntl,
+        libc::SYS_ioctl,
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        libc::SYS_brk,
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_sigaltstack,
+        libc::SYS_futex,
+        libc::SYS_sched_yield,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_nanosleep,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_clock_gettime,
+        libc::SYS_gettimeofday,
+        libc::SYS_getpid,
+        libc::SYS_gettid,
+        libc::SYS_getrandom,
+        libc::SYS_getrusage,
+        libc::SYS_prlimit64,
+        libc::SYS_uname,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+        libc::SYS_tgkill,
+        libc::SYS_restart_syscall,
+        libc::SYS_epoll_ctl,
+        libc::SYS_epoll_pwait,
+        libc::SYS_eventfd2,
+        libc::SYS_ppoll,
+        libc::SYS_socket,
+        libc::SYS_connect,
+        libc::SYS_accept4,
+        libc::SYS_bind,
+        libc::SYS_listen,
+        libc::SYS_shutdown,
+        libc::SYS_setsockopt,
+        libc::SYS_getsockopt,
+        libc::SYS_getsockname,
+        libc::SYS_getpeername,
+        libc::SYS_sendto,
+        libc::SYS_recvfrom,
+        libc::SYS_sendmsg,
+        libc::SYS_recvmsg,
+        libc::SYS_dup,
+        libc::SYS_dup3,
+        libc::SYS_pipe2,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_epoll_wait,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_poll,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_accept,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_dup2,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_pipe,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_getrlimit,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_arch_prctl,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_readlink,
+    ];
+
+    fn statement(code: u32, k: u32) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    /// kills the process if the system call is from another architecture, allows
+    /// the ones of ALLOWED_SYSCALLS, and fails the others with EPERM
+    fn seccomp_filter() -> Vec<libc::sock_filter> {
+        let mut filter = vec![
+            statement(
+                libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+                SECCOMP_DATA_ARCH,
+            ),
+            jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                AUDIT_ARCH,
+                1,
+                0,
+            ),
+            statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+            statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SECCOMP_DATA_NR),
+        ];
+        for syscall in ALLOWED_SYSCALLS {
+            filter.push(jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                *syscall as u32,
+                0,
+                1,
+            ));
+            filter.push(statement(
+                libc::BPF_RET | libc::BPF_K,
+                libc::SECCOMP_RET_ALLOW,
+            ));
+        }
+        filter.push(statement(
+            libc::BPF_RET | libc::BPF_K,
+            libc::SECCOMP_RET_ERRNO | (libc::EPERM as u32 & libc::SECCOMP_RET_DATA),
+        ));
+        filter
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn sandbox_needs_a_single_thread() {
+            // the test harness runs this test in a thread of its own
+            assert!(sandbox_worker().is_err());
+        }
+
+        #[test]
+        fn sandboxed_worker() {
+            // the seccomp filter applies to every thread of the process, the
+            // checks run in a process of their own
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "sandbox::linux::tests::sandboxed_checks"])
+                .args(["--nocapture", "--test-threads=1"])
+                .env("SOZU_SANDBOX_CHECKS", "1")
+                .output()
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stdout)
+            );
+        }
+
+        /// run by `sandboxed_worker` only. The test harness runs another thread,
+        /// that Landlock does not restrict
+        #[test]
+        fn sandboxed_checks() {
+            if std::env::var("SOZU_SANDBOX_CHECKS").is_err() {
+                return;
+            }
+
+            forbid_new_privileges().unwrap();
+            let landlock = restrict_filesystem().is_ok();
+            apply_seccomp_filter().unwrap();
+
+            let result = unsafe { libc::syscall(libc::SYS_execve, 0, 0, 0) };
+            let error = std::io::Error::last_os_error().raw_os_error();
+            assert_eq!(result, -1);
+            assert_eq!(error, Some(libc::EPERM));
+
+            if landlock {
+                assert!(std::fs::read("/etc/hosts").is_err());
+            }
+
+            // the crash reports symbolize their backtraces in the worker
+            let backtrace = std::backtrace::Backtrace::force_capture().to_string();
+            assert!(backtrace.contains("sandboxed_checks"), "{}", backtrace);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! integration with systemd: the readiness and watchdog notifications of the
+//! `Type=notify` units, and the listener sockets passed by socket activation
+use std::{
+    env,
+    net::{SocketAddr, TcpListener},
+    os::unix::{
+        io::{FromRawFd, IntoRawFd, RawFd},
+        net::UnixDatagram,
+    },
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+
+use crate::util;
+
+/// the first file descriptor passed by socket activation
+const LISTEN_FDS_START: RawFd = 3;
+
+lazy_static! {
+    /// the sockets passed by systemd, with their address. They are kept open
+    /// for the whole life of the main process, the workers get copies
+    static ref ACTIVATED_LISTENERS: Mutex<Vec<(SocketAddr, RawFd)>> = Mutex::new(Vec::new());
+}
+
+/// sends a state to the notification socket of systemd, like `READY=1`.
+/// Does nothing if sozu was not started by a `Type=notify` unit
+pub fn notify(state: &str) {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    if let Err(e) = send_notification(&path.to_string_lossy(), state) {
+        error!("could not notify systemd of {}: {:#}", state, e);
+    }
+}
+
+fn send_notification(path: &str, state: &str) -> anyhow::Result<()> {
+    let socket = UnixDatagram::unbound().with_context(|| "could not create a socket")?;
+
+    // a leading '@' designates a socket of the abstract namespace
+    if let Some(name) = path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            let address = SocketAddr::from_abstract_name(name.as_bytes())?;
+            socket.send_to_addr(state.as_bytes(), &address)?;
+            return Ok(());
+        }
+        #[cfg(not(target_os = "linux"))]
+        bail!("abstract socket {} is only available on Linux", name);
+    }
+
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+/// the interval between two watchdog pings: half of the timeout of the unit,
+/// or none if the watchdog is disabled or set for another process
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    parse_watchdog_interval(&env::var("WATCHDOG_USEC").ok()?)
+}
+
+fn parse_watchdog_interval(usec: &str) -> Option<Duration> {
+    match usec.parse::<u64>() {
+        Ok(usec) if usec > 0 => Some(Duration::from_micros(usec / 2)),
+        _ => None,
+    }
+}
+
+/// takes the listener sockets passed by systemd socket activation, if they
+/// are meant for this process. They are not inherited by the workers or
+/// other child processes
+pub fn take_activated_listeners() -> anyhow::Result<()> {
+    let pid = match env::var("LISTEN_PID") {
+        Ok(pid) => pid,
+        Err(_) => return Ok(()),
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(());
+    }
+    let count = env::var("LISTEN_FDS")
+        .with_context(|| "LISTEN_PID is set without LISTEN_FDS")?
+        .parse::<RawFd>()
+        .with_context(|| "invalid LISTEN_FDS")?;
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let mut activated = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        util::enable_close_on_exec(fd)
+            .with_context(|| format!("could not set close on exec on socket {}", fd))?;
+
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        let address = match listener.local_addr() {
+            Ok(address) => address,
+            Err(e) => {
+                // not a TCP socket, it stays open and unused
+                let _ = listener.into_raw_fd();
+                bail!("socket {} from systemd is not a TCP listener: {}", fd, e);
+            }
+        };
+        info!("systemd passed a listener for {}", address);
+        activated.push((address, listener.into_raw_fd()));
+    }
+
+    restore_activated_listeners(activated);
+    Ok(())
+}
+
+/// the activated listeners, to hand them over to a new main process
+pub fn activated_listeners() -> Vec<(SocketAddr, RawFd)> {
+    ACTIVATED_LISTENERS
+        .lock()
+        .map(|listeners| listeners.clone())
+        .unwrap_or_default()
+}
+
+/// the activated listeners handed over by the previous main process
+pub fn restore_activated_listeners(listeners: Vec<(SocketAddr, RawFd)>) {
+    if let Ok(mut activated) = ACTIVATED_LISTENERS.lock() {
+        *activated = listeners;
+    }
+}
+
+/// the activated socket for this address, if any
+pub fn activated_listener(address: &SocketAddr) -> Option<RawFd> {
+    ACTIVATED_LISTENERS
+        .lock()
+        .ok()?
+        .iter()
+        .find(|(activated, _)| activated == address)
+        .map(|(_, fd)| *fd)
+}
+
+/// warns about the sockets passed by systemd that match no listener address
+pub fn warn_unused_listeners(addresses: &[SocketAddr]) {
+    for (address, _) in activated_listeners() {
+        if !addresses.contains(&address) {
+            warn!(
+                "systemd passed a socket for {}, but no listener is configured on it",
+                address
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watchdog_pings_twice_per_timeout() {
+        assert_eq!(
+            parse_watchdog_interval("30000000"),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(parse_watchdog_interval("0"), None);
+        assert_eq!(parse_watchdog_interval("thirty"), None);
+    }
+
+    #[test]
+    fn notifications_reach_the_socket() {
+        let dir = env::temp_dir().join(format!("sozu-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&dir);
+        let receiver = UnixDatagram::bind(&dir).unwrap();
+
+        send_notification(&dir.to_string_lossy(), "READY=1").unwrap();
+
+        let mut buffer = [0u8; 64];
+        let size = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"READY=1");
+        std::fs::remove_file(&dir).unwrap();
+    }
+}
@@ -1,16 +1,22 @@
 use std::{
     fs::File,
-    io::{Seek, SeekFrom},
+    io::{self, Read, Seek, SeekFrom, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream},
     os::unix::io::{AsRawFd, FromRawFd},
     os::unix::process::CommandExt,
     process::Command,
+    time::{Duration, Instant},
 };
 
 use anyhow::{bail, Context};
 use futures_lite::future;
 use libc::{self, pid_t};
 use mio::net::UnixStream;
-use nix::unistd::*;
+use nix::{
+    fcntl::{fcntl, FcntlArg},
+    sys::signal::kill,
+    unistd::*,
+};
 use serde::{Deserialize, Serialize};
 
 use tempfile::tempfile;
@@ -20,7 +26,7 @@ use sozu_command_lib::{
 };
 
 use crate::{
-    command::{CommandServer, Worker},
+    command::{CommandServer, StateHistory, Worker},
     util,
 };
 
@@ -32,6 +38,8 @@ pub struct SerializedWorker {
     pub run_state: RunState,
     pub queue: Vec<ProxyRequest>,
     pub scm: i32,
+    #[serde(default)]
+    pub cpu_cores: Vec<usize>,
 }
 
 impl SerializedWorker {
@@ -44,6 +52,7 @@ impl SerializedWorker {
             //token:      worker.token.clone().map(|Token(t)| t),
             queue: worker.queue.clone().into(),
             scm: worker.scm_socket.raw_fd(),
+            cpu_cores: worker.cpu_cores.clone(),
         }
     }
 }
@@ -59,6 +68,21 @@ pub struct UpgradeData {
     pub workers: Vec<SerializedWorker>,
     pub state: ConfigState,
     pub next_id: u32,
+    /// hex encoded TLS session ticket keys, the newest first
+    #[serde(default)]
+    pub ticket_keys: Vec<String>,
+    /// the part of the state created from the labels of the containers
+    #[serde(default)]
+    pub docker_state: ConfigState,
+    /// the last versions of the state
+    #[serde(default)]
+    pub history: Option<StateHistory>,
+    /// the version of the state shared with the peers
+    #[serde(default)]
+    pub peer_version: Option<u64>,
+    /// the listener sockets passed by systemd, with their address
+    #[serde(default)]
+    pub activated_listeners: Vec<(SocketAddr, i32)>,
     //pub token_count: usize,
 }
 
@@ -111,6 +135,8 @@ pub fn fork_main_into_new_main(
                 .arg(upgrade_data.config.command_buffer_size.to_string())
                 .arg("--max-command-buffer-size")
                 .arg(upgrade_data.config.max_command_buffer_size.to_string())
+                // the watchdog of the old main process applies to the new one
+                .env_remove("WATCHDOG_PID")
                 .exec();
 
             error!("exec call failed: {:?}", res);
@@ -142,8 +168,35 @@ pub fn begin_new_main_process(
     util::setup_metrics(&config).with_context(|| "Could not setup metrics")?;
     //info!("new main got upgrade data: {:?}", upgrade_data);
 
+    let state = upgrade_data.state.clone();
+    let workers = upgrade_data
+        .workers
+        .iter()
+        .filter(|worker| worker.run_state == RunState::Running)
+        .map(|worker| (worker.id, worker.pid, worker.fd))
+        .collect::<Vec<_>>();
+    // half of the upgrade timeout, the previous main process waits for the other
+    let self_test_timeout = Duration::from_secs(config.upgrade_timeout) / 2;
     let mut server = CommandServer::from_upgrade_data(upgrade_data)?;
     server.enable_cloexec_after_upgrade()?;
+
+    // the previous main process resumes if the workers are not usable
+    match self_test(&workers, &state, self_test_timeout) {
+        Ok(skipped) => {
+            for reason in skipped {
+                warn!("the self-test skipped {}", reason);
+            }
+        }
+        Err(e) => {
+            fork_confirmation_channel.write_message(&false);
+            error!(
+                "Couldn't upgrade main process, the self-test failed: {:#}",
+                e
+            );
+            bail!("begin_new_main_process() failed");
+        }
+    }
+
     info!("starting new main loop");
     match util::write_pid_file(&config) {
         Ok(()) => {
@@ -162,3 +215,168 @@ pub fn begin_new_main_process(
         }
     }
 }
+
+/// how long each listener has at most to answer the self-test
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// checks, before the previous main process stops, that the running workers
+/// it passed, with their id, pid and channel, are still there. The listeners
+/// are served by these workers: the active ones are probed within `timeout`,
+/// and the ones this process cannot reach, like the ones denying the loopback
+/// address, are skipped and returned. A listener answering something else
+/// than HTTP where HTTP is expected fails the test
+fn self_test(
+    workers: &[(u32, i32, i32)],
+    state: &ConfigState,
+    timeout: Duration,
+) -> anyhow::Result<Vec<String>> {
+    for (id, pid, fd) in workers {
+        kill(Pid::from_raw(*pid), None)
+            .with_context(|| format!("the worker {} (pid {}) is gone", id, pid))?;
+        fcntl(*fd, FcntlArg::F_GETFD)
+            .with_context(|| format!("the channel of the worker {} was not passed", id))?;
+    }
+
+    // the listeners expecting the proxy protocol would wait for its header
+    let listeners = state
+        .http_listeners
+        .iter()
+        .filter(|(_, (_, active))| *active)
+        .map(|(address, (listener, _))| (*address, !listener.expect_proxy))
+        .chain(
+            state
+                .https_listeners
+                .iter()
+                .filter(|(_, (_, active))| *active)
+                .map(|(address, _)| (*address, false)),
+        )
+        .chain(
+            state
+                .tcp_listeners
+                .iter()
+                .filter(|(_, (_, active))| *active)
+                .map(|(address, _)| (*address, false)),
+        );
+
+    let mut skipped = Vec::new();
+    let deadline = Instant::now() + timeout;
+    for (address, http) in listeners {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            skipped.push(format!("the listener {}: out of time", address));
+            continue;
+        }
+
+        let stream = match connect(address, remaining.min(SELF_TEST_TIMEOUT)) {
+            Ok(stream) => stream,
+            Err(e) => {
+                skipped.push(format!("the listener {}: {}", address, e));
+                continue;
+            }
+        };
+        if !http {
+            continue;
+        }
+        match request_http(stream) {
+            Ok(status_line) if status_line.starts_with(b"HTTP/1.") => {}
+            Ok(status_line) => bail!(
+                "the listener {} answered {:?}",
+                address,
+                String::from_utf8_lossy(&status_line)
+            ),
+            Err(e) => skipped.push(format!("the listener {}: {}", address, e)),
+        }
+    }
+    Ok(skipped)
+}
+
+fn connect(address: SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+    let mut target = address;
+    // the listeners bound to every interface are reached on the loopback
+    if target.ip().is_unspecified() {
+        target.set_ip(match target {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+
+    let stream = TcpStream::connect_timeout(&target, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+/// returns the beginning of the status line, any HTTP answer will do since
+/// the host of the request matches no frontend
+fn request_http(mut stream: TcpStream) -> io::Result<[u8; 12]> {
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: sozu-self-test\r\nConnection: close\r\n\r\n")?;
+
+    let mut status_line = [0u8; 12];
+    stream.read_exact(&mut status_line)?;
+    Ok(status_line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sozu_command_lib::proxy::HttpListener;
+    use std::net::TcpListener;
+
+    #[test]
+    fn self_test_needs_an_http_answer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            for answer in [
+                &b"HTTP/1.1 404 Not Found\r\n\r\n"[..],
+                &b"SSH-2.0-OpenSSH\r\n"[..],
+            ] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0u8; 16];
+                stream.read_exact(&mut request).unwrap();
+                stream.write_all(answer).unwrap();
+            }
+        });
+
+        let mut state = ConfigState::default();
+        state.http_listeners.insert(
+            address,
+            (
+                HttpListener {
+                    address,
+                    ..Default::default()
+                },
+                true,
+            ),
+        );
+        let timeout = Duration::from_secs(5);
+        assert_eq!(self_test(&[], &state, timeout).unwrap().len(), 0);
+        assert!(self_test(&[], &state, timeout).is_err());
+        server.join().unwrap();
+
+        // nothing listens there anymore, the listener is skipped
+        assert!(connect(address, timeout).is_err());
+        assert_eq!(self_test(&[], &state, timeout).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn self_test_needs_the_workers() {
+        let state = ConfigState::default();
+        let timeout = Duration::from_secs(5);
+        let channel = TcpListener::bind("127.0.0.1:0").unwrap();
+        let pid = std::process::id() as i32;
+
+        let worker = (0, pid, channel.as_raw_fd());
+        assert!(self_test(&[worker], &state, timeout).is_ok());
+
+        // the channel was not passed
+        let worker = (0, pid, 1_000_000);
+        assert!(self_test(&[worker], &state, timeout).is_err());
+
+        // the worker is gone
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        let worker = (0, child.id() as i32, channel.as_raw_fd());
+        assert!(self_test(&[worker], &state, timeout).is_err());
+    }
+}
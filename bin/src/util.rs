@@ -1,8 +1,16 @@
-use std::{fs::File, io::Write, os::unix::io::RawFd};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    os::unix::io::{AsRawFd, RawFd},
+    sync::Mutex,
+};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 
-use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::{
+    fcntl::{fcntl, flock, FcntlArg, FdFlag, FlockArg},
+    unistd,
+};
 
 use sozu::metrics;
 use sozu_command_lib::config::Config;
@@ -58,19 +66,105 @@ pub fn setup_metrics(config: &Config) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// the PID file of the main process, with its path, kept open to hold its lock
+static PID_FILE: Mutex<Option<(String, File)>> = Mutex::new(None);
+
+/// writes the pid of the main process to `pid_file_path`, and locks the file
+/// so that a second main process cannot take it
 pub fn write_pid_file(config: &Config) -> Result<(), anyhow::Error> {
-    let pid_file_path: Option<&str> = config
-        .pid_file_path
-        .as_ref()
-        .map(|pid_file_path| pid_file_path.as_ref());
+    let pid_file_path = match config.pid_file_path.as_ref() {
+        Some(pid_file_path) => pid_file_path,
+        None => return Ok(()),
+    };
+
+    let mut pid_file = PID_FILE.lock().unwrap_or_else(|e| e.into_inner());
+    let mut file = match pid_file.take() {
+        Some((path, file)) if path == *pid_file_path => file,
+        _ => lock_pid_file(pid_file_path)?,
+    };
+
+    let pid = unsafe { libc::getpid() };
+    file.set_len(0)?;
+    file.write_all(format!("{}", pid).as_bytes())?;
+    file.sync_all()?;
+
+    *pid_file = Some((pid_file_path.to_owned(), file));
+    Ok(())
+}
 
-    if let Some(pid_file_path) = pid_file_path {
-        let mut file = File::create(pid_file_path)?;
+fn lock_pid_file(path: &str) -> Result<File, anyhow::Error> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)
+        .with_context(|| format!("could not open the PID file {}", path))?;
+
+    if flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock).is_err() {
+        let mut pid = String::new();
+        let _ = file.read_to_string(&mut pid);
+        bail!(
+            "the PID file {} is locked, sozu already runs with pid {}",
+            path,
+            pid.trim()
+        );
+    }
+    Ok(file)
+}
 
-        let pid = unsafe { libc::getpid() };
+/// unlocks the PID file, for the new main process of an upgrade
+pub fn release_pid_file() {
+    *PID_FILE.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
 
-        file.write_all(format!("{}", pid).as_bytes())?;
-        file.sync_all()?;
+/// removes the PID file once the main process stopped
+pub fn remove_pid_file() {
+    if let Some((path, _file)) = PID_FILE.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        if let Err(e) = fs::remove_file(&path) {
+            error!("could not remove the PID file {}: {}", path, e);
+        }
+    }
+}
+
+/// detaches the main process from the terminal: the parent exits, and the
+/// child runs in a new session with its standard streams on /dev/null. The
+/// working directory is kept, for the relative paths of the configuration
+pub fn daemonize() -> Result<(), anyhow::Error> {
+    unistd::daemon(true, false).with_context(|| "could not run sozu in the background")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pid_file_is_locked() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file_path = dir.path().join("sozu.pid");
+        let config_path = dir.path().join("config.toml");
+        fs::write(
+            &config_path,
+            format!("pid_file_path = \"{}\"", pid_file_path.display()),
+        )
+        .unwrap();
+        let config = Config::load_from_path(&config_path.to_string_lossy()).unwrap();
+        let pid_file_path = pid_file_path.to_string_lossy();
+
+        write_pid_file(&config).unwrap();
+        let pid = unsafe { libc::getpid() }.to_string();
+        assert_eq!(fs::read_to_string(&*pid_file_path).unwrap(), pid);
+
+        // writing again from the same process keeps the lock
+        write_pid_file(&config).unwrap();
+
+        let error = lock_pid_file(&pid_file_path).unwrap_err().to_string();
+        assert!(error.contains(&format!("sozu already runs with pid {}", pid)));
+
+        release_pid_file();
+        assert!(lock_pid_file(&pid_file_path).is_ok());
+
+        write_pid_file(&config).unwrap();
+        remove_pid_file();
+        assert!(!std::path::Path::new(&*pid_file_path).exists());
     }
-    Ok(())
 }
@@ -11,11 +11,16 @@ use std::mem::size_of;
 #[cfg(target_os = "macos")]
 use std::ptr::null_mut;
 use std::{
+    backtrace::Backtrace,
+    cell::RefCell,
+    collections::BTreeSet,
     fs::File,
     io::{Seek, SeekFrom},
-    os::unix::io::{AsRawFd, FromRawFd, IntoRawFd},
+    net::SocketAddr,
+    os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd},
     os::unix::process::CommandExt,
-    process::Command,
+    panic::{self, AssertUnwindSafe},
+    process::{self, Command},
 };
 
 use anyhow::{bail, Context};
@@ -25,41 +30,53 @@ use libc::{c_char, PATH_MAX};
 #[cfg(target_os = "freebsd")]
 use libc::{sysctl, CTL_KERN, KERN_PROC, KERN_PROC_PATHNAME, PATH_MAX};
 use mio::net::UnixStream;
-use nix::{self, unistd::*};
+use nix::{
+    self,
+    fcntl::{fcntl, FcntlArg},
+    unistd::*,
+};
 
 use tempfile::tempfile;
 
-use sozu::{metrics, server::Server};
+use sozu::{
+    metrics,
+    server::{crash_report, Server},
+    socket::server_bind,
+    tls::DelegatedCertificateResolvers,
+};
 use sozu_command_lib::{
     channel::Channel,
     config::Config,
     logging::target_to_backend,
-    proxy::{ProxyRequest, ProxyRequestOrder, ProxyResponse},
+    proxy::{CrashReport, ProxyRequest, ProxyRequestOrder, ProxyResponse},
     ready::Ready,
     scm_socket::{Listeners, ScmSocket},
     state::ConfigState,
 };
 
-use crate::{command::Worker, logging, util};
+use crate::{command::Worker, logging, sandbox::sandbox_worker, systemd, util};
 
-pub fn start_workers(executable_path: String, config: &Config) -> anyhow::Result<Vec<Worker>> {
-    let state = ConfigState::new();
+pub fn start_workers(
+    executable_path: String,
+    config: &Config,
+    state: &ConfigState,
+) -> anyhow::Result<Vec<Worker>> {
     let mut workers = Vec::new();
     for index in 0..config.worker_count {
-        let listeners = Some(Listeners {
-            http: Vec::new(),
-            tls: Vec::new(),
-            tcp: Vec::new(),
-        });
+        let state = state.scoped_to(index as u32);
+        let listeners = Some(bind_listeners(config, &state));
 
+        let cpu_cores = worker_cpu_cores(config, index as usize);
         let (pid, command_channel, scm_socket) = fork_main_into_worker(
             &index.to_string(),
             config,
             executable_path.clone(),
             &state,
             listeners,
+            &cpu_cores,
         )?;
         let mut worker = Worker::new(index as u32, pid, command_channel, scm_socket, config);
+        worker.cpu_cores = cpu_cores;
 
         // the new worker expects a status message at startup
         if let Some(command_channel) = worker.worker_channel.as_mut() {
@@ -85,17 +102,116 @@ pub fn start_worker(
     executable_path: String,
     state: &ConfigState,
     listeners: Option<Listeners>,
+    cpu_cores: Vec<usize>,
 ) -> anyhow::Result<Worker> {
-    let (worker_pid, main_to_worker_channel, main_to_worker_scm) =
-        fork_main_into_worker(&id.to_string(), config, executable_path, state, listeners)?;
+    let (worker_pid, main_to_worker_channel, main_to_worker_scm) = fork_main_into_worker(
+        &id.to_string(),
+        config,
+        executable_path,
+        state,
+        listeners,
+        &cpu_cores,
+    )?;
 
-    Ok(Worker::new(
+    let mut worker = Worker::new(
         id,
         worker_pid,
         main_to_worker_channel,
         main_to_worker_scm,
         config,
-    ))
+    );
+    worker.cpu_cores = cpu_cores;
+    Ok(worker)
+}
+
+/// the main process binds the listeners of the configuration and the state for
+/// each worker, with SO_REUSEPORT: every worker gets its own sockets, and the
+/// kernel spreads the new connections over them. The workers switching to an
+/// unprivileged user could not bind the privileged ports either
+pub fn bind_listeners(config: &Config, state: &ConfigState) -> Listeners {
+    let mut listeners = Listeners {
+        http: Vec::new(),
+        tls: Vec::new(),
+        tcp: Vec::new(),
+    };
+
+    listeners.http = bind_addresses(
+        config
+            .http_listeners
+            .iter()
+            .map(|listener| listener.address)
+            .chain(state.http_listeners.keys().copied())
+            .collect(),
+    );
+    listeners.tls = bind_addresses(
+        config
+            .https_listeners
+            .iter()
+            .map(|listener| listener.address)
+            .chain(state.https_listeners.keys().copied())
+            .collect(),
+    );
+    listeners.tcp = bind_addresses(
+        config
+            .tcp_listeners
+            .iter()
+            .map(|listener| listener.address)
+            .chain(state.tcp_listeners.keys().copied())
+            .collect(),
+    );
+    listeners
+}
+
+/// the addresses that cannot be bound are left to the worker
+fn bind_addresses(addresses: BTreeSet<SocketAddr>) -> Vec<(SocketAddr, RawFd)> {
+    addresses
+        .into_iter()
+        .filter_map(|address| Some((address, bind_listener(address)?)))
+        .collect()
+}
+
+/// a new socket for a worker, or a copy of the socket passed by systemd for
+/// this address: all the workers share it
+pub fn bind_listener(address: SocketAddr) -> Option<RawFd> {
+    if let Some(fd) = systemd::activated_listener(&address) {
+        return match fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(0)) {
+            Ok(copy) => Some(copy),
+            Err(e) => {
+                error!(
+                    "could not copy the socket passed by systemd for {}: {}",
+                    address, e
+                );
+                None
+            }
+        };
+    }
+    match server_bind(address) {
+        Ok(listener) => Some(listener.into_raw_fd()),
+        Err(e) => {
+            error!(
+                "could not bind the listener {} for a worker: {}",
+                address, e
+            );
+            None
+        }
+    }
+}
+
+/// the CPU cores of the worker started at this position, from
+/// `worker_cpu_affinity`. Empty if the workers are not pinned
+#[cfg(target_os = "linux")]
+pub fn worker_cpu_cores(config: &Config, slot: usize) -> Vec<usize> {
+    config
+        .worker_cpu_affinity
+        .as_ref()
+        .map(|affinity| affinity.cores(slot, num_cpus::get()))
+        .unwrap_or_default()
+}
+
+/// the workers are only pinned on Linux
+#[cfg(not(target_os = "linux"))]
+pub fn worker_cpu_cores(_config: &Config, _slot: usize) -> Vec<usize> {
+    Vec::new()
 }
 
 /// called within a worker process, this starts the actual proxy
@@ -106,7 +222,10 @@ pub fn begin_worker_process(
     id: i32,
     command_buffer_size: usize,
     max_command_buffer_size: usize,
+    cpu_cores: Vec<usize>,
 ) -> Result<(), anyhow::Error> {
+    register_crash_report_hook();
+
     let mut worker_to_main_channel: Channel<ProxyResponse, Config> = Channel::new(
         unsafe { UnixStream::from_raw_fd(worker_to_main_channel_fd) },
         command_buffer_size,
@@ -147,6 +266,10 @@ pub fn begin_worker_process(
     );
     info!("worker {} starting...", id);
 
+    if !cpu_cores.is_empty() {
+        pin_to_cpu_cores(&cpu_cores);
+    }
+
     worker_to_main_channel.nonblocking();
     let mut worker_to_main_channel: Channel<ProxyResponse, ProxyRequest> =
         worker_to_main_channel.into();
@@ -162,27 +285,83 @@ pub fn begin_worker_process(
         .with_context(|| "Could not setup metrics")?;
     }
 
+    let (user, group, chroot) = (
+        worker_config.user.clone(),
+        worker_config.group.clone(),
+        worker_config.chroot.clone(),
+    );
+    let sandbox = worker_config.worker_sandbox;
     let mut server = Server::try_new_from_config(
         worker_to_main_channel,
         ScmSocket::new(worker_to_main_scm_fd),
         worker_config,
         config_state,
         true,
+        DelegatedCertificateResolvers::new(),
     )
     .with_context(|| "Could not create server from config")?;
 
+    // the listeners sent by the main process were received with the server
+    if let Some(user) = user {
+        drop_privileges(&user, group.as_deref(), chroot.as_deref())?;
+    }
+    if sandbox {
+        sandbox_worker().with_context(|| "Could not sandbox the worker")?;
+    }
+
     info!("starting event loop");
-    server.run();
+    // the crash report is sent on the channel after the message that was being
+    // written, so that the main process can still read both
+    if panic::catch_unwind(AssertUnwindSafe(|| server.run())).is_err() {
+        if let Some(report) = CRASH_REPORT.with(|report| report.borrow_mut().take()) {
+            server.send_crash_report(report);
+        }
+        process::abort();
+    }
     info!("ending event loop");
     Ok(())
 }
 
+thread_local! {
+  /// the report of the last panic, sent once the event loop is unwound
+  static CRASH_REPORT: RefCell<Option<CrashReport>> = const { RefCell::new(None) };
+}
+
+/// on a panic, keeps a crash report for the main process, before the original
+/// hook runs. The report is built here, while the sessions are still open
+fn register_crash_report_hook() {
+    let original_panic_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |panic_info| {
+        let payload = panic_info.payload();
+        let message = match (
+            payload.downcast_ref::<&str>(),
+            payload.downcast_ref::<String>(),
+        ) {
+            (Some(message), _) => message.to_string(),
+            (_, Some(message)) => message.to_owned(),
+            _ => String::from("unknown panic"),
+        };
+        let location = panic_info
+            .location()
+            .map(|location| format!("{}:{}", location.file(), location.line()));
+        let report = crash_report(message, location, Backtrace::force_capture().to_string());
+        let _ = CRASH_REPORT.try_with(|crash_report| {
+            if let Ok(mut crash_report) = crash_report.try_borrow_mut() {
+                *crash_report = Some(report);
+            }
+        });
+
+        (*original_panic_hook)(panic_info);
+    }));
+}
+
 pub fn fork_main_into_worker(
     worker_id: &str,
     config: &Config,
     executable_path: String,
     state: &ConfigState,
     listeners: Option<Listeners>,
+    cpu_cores: &[usize],
 ) -> anyhow::Result<(pid_t, Channel<ProxyRequest, ProxyResponse>, ScmSocket)> {
     trace!("parent({})", unsafe { libc::getpid() });
 
@@ -236,7 +415,11 @@ pub fn fork_main_into_worker(
         }
         Ok(ForkResult::Child) => {
             trace!("child({}):\twill spawn a child", unsafe { libc::getpid() });
-            Command::new(executable_path)
+            if let Some(max_open_files) = config.worker_max_open_files {
+                limit_open_files(max_open_files);
+            }
+            let mut command = Command::new(executable_path);
+            command
                 .arg("worker")
                 .arg("--id")
                 .arg(worker_id)
@@ -249,8 +432,15 @@ pub fn fork_main_into_worker(
                 .arg("--command-buffer-size")
                 .arg(config.command_buffer_size.to_string())
                 .arg("--max-command-buffer-size")
-                .arg(config.max_command_buffer_size.to_string())
-                .exec();
+                .arg(config.max_command_buffer_size.to_string());
+            if !cpu_cores.is_empty() {
+                let cpu_cores = cpu_cores
+                    .iter()
+                    .map(|core| core.to_string())
+                    .collect::<Vec<_>>();
+                command.arg("--cpu-cores").arg(cpu_cores.join(","));
+            }
+            command.exec();
 
             unreachable!();
         }
@@ -261,6 +451,85 @@ pub fn fork_main_into_worker(
     }
 }
 
+/// confines the worker to the chroot directory, then switches to the user and
+/// group, the group of the user by default
+fn drop_privileges(user: &str, group: Option<&str>, chroot: Option<&str>) -> anyhow::Result<()> {
+    let user = User::from_name(user)
+        .with_context(|| format!("could not look up the user {}", user))?
+        .with_context(|| format!("the user {} does not exist", user))?;
+    let gid = match group {
+        Some(group) => {
+            Group::from_name(group)
+                .with_context(|| format!("could not look up the group {}", group))?
+                .with_context(|| format!("the group {} does not exist", group))?
+                .gid
+        }
+        None => user.gid,
+    };
+
+    if let Some(directory) = chroot {
+        nix::unistd::chroot(directory)
+            .with_context(|| format!("could not chroot the worker to {}", directory))?;
+        chdir("/").with_context(|| "could not change to the root of the chroot")?;
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    setgroups(&[gid]).with_context(|| "could not drop the supplementary groups")?;
+    setgid(gid).with_context(|| format!("could not switch to the group {}", gid))?;
+    setuid(user.uid).with_context(|| format!("could not switch to the user {}", user.name))?;
+
+    info!(
+        "switched to the user {} ({}) and the group {}",
+        user.name, user.uid, gid
+    );
+    Ok(())
+}
+
+/// sets the soft and hard limits of open file descriptors of the current
+/// process, inherited by the worker executed next
+fn limit_open_files(max_open_files: u64) {
+    let limits = libc::rlimit {
+        rlim_cur: max_open_files as libc::rlim_t,
+        rlim_max: max_open_files as libc::rlim_t,
+    };
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limits) } != 0 {
+        error!(
+            "could not limit the worker to {} open files: {}",
+            max_open_files,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// binds the current process to these CPU cores, see man sched_setaffinity
+#[cfg(target_os = "linux")]
+fn pin_to_cpu_cores(cpu_cores: &[usize]) {
+    let result = unsafe {
+        let mut cpu_set: libc::cpu_set_t = std::mem::zeroed();
+        for core in cpu_cores {
+            libc::CPU_SET(*core, &mut cpu_set);
+        }
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &cpu_set)
+    };
+
+    match result {
+        0 => info!("pinned to the CPU cores {:?}", cpu_cores),
+        _ => error!(
+            "could not pin the worker to the CPU cores {:?}: {}",
+            cpu_cores,
+            std::io::Error::last_os_error()
+        ),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_to_cpu_cores(cpu_cores: &[usize]) {
+    warn!(
+        "the workers can only be pinned to CPU cores on Linux, ignoring {:?}",
+        cpu_cores
+    );
+}
+
 #[cfg(target_os = "linux")]
 pub unsafe fn get_executable_path() -> anyhow::Result<String> {
     use std::fs;
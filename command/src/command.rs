@@ -2,15 +2,16 @@ use std::{collections::BTreeMap, fmt, net::SocketAddr};
 
 use crate::{
     proxy::{
-        AggregatedMetricsData, HttpFrontend, ProxyEvent, ProxyRequestOrder, QueryAnswer,
-        TcpFrontend,
+        is_false, AccessLogFilter, AccessLogRecord, AggregatedMetricsData, CertificateFingerprint,
+        CrashReport, HttpFrontend, ListenerType, ProxyEvent, ProxyRequestOrder, QueryAnswer,
+        SniFrontend, SoftStopReport, TcpFrontend,
     },
     state::ConfigState,
 };
 
 pub const PROTOCOL_VERSION: u8 = 0;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(tag = "type", content = "data", rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum CommandRequestOrder {
     Proxy(Box<ProxyRequestOrder>),
@@ -19,12 +20,120 @@ pub enum CommandRequestOrder {
     DumpState,
     ListWorkers,
     ListFrontends(FrontendFilters),
+    ListCertificates(CertificateFilters),
     LaunchWorker(String),
     UpgradeMain,
     UpgradeWorker(u32),
+    // replaces a worker with a new one running the same binary, that takes over
+    // its listeners and state, then soft stops it
+    RestartWorker(u32),
     SubscribeEvents,
     ReloadConfiguration { path: Option<String> },
     Status,
+    // executes the orders turning the current state into this one
+    ApplyState { state: Box<ConfigState> },
+    // lists the versions of the state kept by the main process
+    StateHistory,
+    // applies a previous version of the state, as a new version
+    RollbackState { version: u64 },
+    // a change made on another main process of the peering group
+    PeerChange(PeerChange),
+    // the whole state of another main process of the peering group, replacing this one
+    PeerState(PeerState),
+    // sends the whole state to the peers, to resolve a conflict
+    SyncPeers,
+    // lists the last entries of the audit log
+    AuditLog { lines: usize },
+    // streams the access logs of the workers matching the filter, until the
+    // client disconnects
+    TailAccessLogs(AccessLogFilter),
+}
+
+impl CommandRequestOrder {
+    /// what the order does to the proxy, to authorize the clients of the command socket
+    pub fn category(&self) -> OrderCategory {
+        match self {
+            CommandRequestOrder::DumpState
+            | CommandRequestOrder::ListWorkers
+            | CommandRequestOrder::ListFrontends(_)
+            | CommandRequestOrder::ListCertificates(_)
+            | CommandRequestOrder::SubscribeEvents
+            | CommandRequestOrder::Status
+            | CommandRequestOrder::StateHistory
+            | CommandRequestOrder::AuditLog { .. }
+            | CommandRequestOrder::TailAccessLogs(_) => OrderCategory::Read,
+            CommandRequestOrder::LaunchWorker(_)
+            | CommandRequestOrder::UpgradeMain
+            | CommandRequestOrder::UpgradeWorker(_)
+            | CommandRequestOrder::RestartWorker(_) => OrderCategory::Upgrade,
+            CommandRequestOrder::SaveState { .. } | CommandRequestOrder::LoadState { .. } => {
+                OrderCategory::Admin
+            }
+            CommandRequestOrder::ReloadConfiguration { .. }
+            | CommandRequestOrder::ApplyState { .. }
+            | CommandRequestOrder::RollbackState { .. }
+            | CommandRequestOrder::PeerChange(_)
+            | CommandRequestOrder::PeerState(_)
+            | CommandRequestOrder::SyncPeers => OrderCategory::Mutation,
+            CommandRequestOrder::Proxy(order) => match **order {
+                ProxyRequestOrder::Query(_) | ProxyRequestOrder::Status => OrderCategory::Read,
+                ProxyRequestOrder::SoftStop(_)
+                | ProxyRequestOrder::HardStop
+                | ProxyRequestOrder::ReturnListenSockets => OrderCategory::Upgrade,
+                _ => OrderCategory::Mutation,
+            },
+        }
+    }
+}
+
+/// the kinds of orders a client of the command socket can be allowed to send
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderCategory {
+    /// queries, listings, state dumps and events, that change nothing. The
+    /// state dumps do not contain the private keys
+    Read,
+    /// changes of the configuration of the proxy
+    Mutation,
+    /// upgrades, launches and stops of the processes
+    Upgrade,
+    /// the state files read and written by the main process, and the private
+    /// keys in the state dumps
+    Admin,
+}
+
+impl fmt::Display for OrderCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderCategory::Read => write!(f, "read"),
+            OrderCategory::Mutation => write!(f, "mutation"),
+            OrderCategory::Upgrade => write!(f, "upgrade"),
+            OrderCategory::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// the orders of a change, shared with the peers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerChange {
+    /// the name of the main process that made the change
+    pub origin: String,
+    /// the version of the group state the change applies to, the next one
+    /// follows it
+    pub base_version: u64,
+    pub orders: Vec<ProxyRequestOrder>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerState {
+    /// the name of the main process that sent its state
+    pub origin: String,
+    /// the version of the group state, taken by the peers
+    pub version: u64,
+    pub state: Box<ConfigState>,
+    /// replaces the state of the peers, even if theirs is more recent
+    #[serde(default)]
+    pub forced: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -36,13 +145,44 @@ pub struct FrontendFilters {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CertificateFilters {
+    /// keeps the certificates providing a name containing this domain
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    /// keeps the certificates expiring in less than this duration, in seconds
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expiring_within: Option<u64>,
+    /// keeps the certificates of the listener at this address
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct CommandRequest {
     pub id: String,
     pub version: u8,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub worker_id: Option<u32>,
+    /// sends the order to these workers only. An added listener is then
+    /// handled by them only, with its frontends and certificates
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub worker_ids: Vec<u32>,
     #[serde(flatten)]
     pub order: CommandRequestOrder,
+    /// refuse the order, instead of answering with warnings, when the main
+    /// process finds problems with it
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_false")]
+    pub strict: bool,
+    /// validate the order against the state and answer with what it would
+    /// change, without sending anything to the workers
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_false")]
+    pub dry_run: bool,
 }
 
 impl CommandRequest {
@@ -52,10 +192,61 @@ impl CommandRequest {
             id,
             order,
             worker_id,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
         }
     }
 }
 
+/// sent to the external authorization command or service, for each order
+/// changing the proxy
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthorizationRequest {
+    /// the id of the client in the main process
+    pub client: String,
+    /// the unix user and primary group of the client process. The remote
+    /// clients are relayed by the main process, and have its user
+    pub user: Option<String>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub category: OrderCategory,
+    pub request: CommandRequest,
+}
+
+/// the answer of the external authorization service
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct AuthorizationDecision {
+    pub allowed: bool,
+    /// why the order is refused, given to the client
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// an order changing the proxy received by the main process, with its result,
+/// written as a line of the audit log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// when the order was answered, as a unix timestamp
+    pub timestamp: i64,
+    pub request_id: String,
+    /// the id of the client in the main process, and its unix user. The remote
+    /// clients are relayed by the main process, and have its user
+    pub client: String,
+    pub user: Option<String>,
+    pub uid: Option<u32>,
+    pub category: OrderCategory,
+    pub order: CommandRequestOrder,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub dry_run: bool,
+    /// the final status of the order, none if the client left before it
+    pub status: Option<CommandStatus>,
+    pub message: String,
+    /// the version of the state once the order was answered
+    pub version: Option<u64>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum CommandStatus {
@@ -75,8 +266,124 @@ pub enum CommandResponseContent {
     State(Box<ConfigState>),
     Event(Event),
     FrontendList(ListedFrontends),
+    CertificateList(Vec<ListedCertificate>),
+    /// the problems found in a certificate sent to the workers
+    CertificateValidation(Vec<CertificateIssue>),
+    /// problems found in an order that was executed anyway
+    Warnings(Vec<String>),
     // this is new
     Status(Vec<WorkerInfo>),
+    /// the orders executed to reach an applied state
+    StateDiff(Vec<ProxyRequestOrder>),
+    /// the versions of the state kept by the main process, the oldest first
+    StateHistory(Vec<StateVersion>),
+    /// what a dry run order would change
+    DryRun(DryRun),
+    /// the last entries of the audit log, the oldest first
+    AuditLog(Vec<AuditEntry>),
+    /// a request logged by a worker, sent to the clients tailing the access logs
+    AccessLog(AccessLogRecord),
+    /// how far the workers are in a long operation, sent with `Processing`
+    Progress(Progress),
+    /// worker_id -> the sessions it let finish or closed on a soft stop
+    SoftStop(BTreeMap<u32, SoftStopReport>),
+}
+
+/// the answer to an order validated without being executed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct DryRun {
+    /// the orders turning the current state into the one the order leads to
+    pub orders: Vec<ProxyRequestOrder>,
+    /// the problems found in the order, that would not refuse it
+    pub warnings: Vec<String>,
+}
+
+/// the answers of the workers to the orders of a long operation, like
+/// loading a state or reloading the configuration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct Progress {
+    /// worker id -> answers of that worker
+    pub workers: BTreeMap<u32, WorkerProgress>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct WorkerProgress {
+    /// the number of orders sent to the worker
+    pub expected: usize,
+    pub ok: usize,
+    pub errors: usize,
+}
+
+impl WorkerProgress {
+    pub fn is_done(&self) -> bool {
+        self.ok + self.errors >= self.expected
+    }
+}
+
+impl Progress {
+    /// counts an order sent to the worker
+    pub fn expect(&mut self, worker_id: u32) {
+        self.workers.entry(worker_id).or_default().expected += 1;
+    }
+
+    /// counts an answer of the worker, returns true if it was the last one expected
+    pub fn record(&mut self, worker_id: u32, ok: bool) -> bool {
+        let worker = self.workers.entry(worker_id).or_default();
+        match ok {
+            true => worker.ok += 1,
+            false => worker.errors += 1,
+        }
+        worker.ok + worker.errors == worker.expected
+    }
+
+    pub fn expected(&self) -> usize {
+        self.workers.values().map(|worker| worker.expected).sum()
+    }
+
+    pub fn answered(&self) -> usize {
+        self.workers
+            .values()
+            .map(|worker| worker.ok + worker.errors)
+            .sum()
+    }
+
+    /// the workers that did not answer all their orders yet
+    pub fn pending_workers(&self) -> Vec<u32> {
+        self.workers
+            .iter()
+            .filter(|(_, worker)| !worker.is_done())
+            .map(|(id, _)| *id)
+            .collect()
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{} answers", self.answered(), self.expected())?;
+        for (id, worker) in self.workers.iter() {
+            write!(
+                f,
+                ", worker {}: {}/{}",
+                id,
+                worker.ok + worker.errors,
+                worker.expected
+            )?;
+            if worker.errors > 0 {
+                write!(f, " ({} errors)", worker.errors)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// a change of the state recorded by the main process
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateVersion {
+    pub version: u64,
+    /// the id of the request that changed the state
+    pub request_id: String,
+    /// when the state changed, as a unix timestamp
+    pub timestamp: i64,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
@@ -84,6 +391,70 @@ pub struct ListedFrontends {
     pub http_frontends: Vec<HttpFrontend>,
     pub https_frontends: Vec<HttpFrontend>,
     pub tcp_frontends: Vec<TcpFrontend>,
+    #[serde(default)]
+    pub sni_frontends: Vec<SniFrontend>,
+}
+
+/// a certificate of the state, with the names and expiration parsed from it
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ListedCertificate {
+    pub address: SocketAddr,
+    pub fingerprint: CertificateFingerprint,
+    #[serde(default)]
+    pub subject: String,
+    pub names: Vec<String>,
+    /// the `not_after` field of the certificate, as a unix timestamp
+    pub expiration: i64,
+    /// the hostnames of the HTTPS frontends of the listener the certificate covers
+    #[serde(default)]
+    pub hostnames: Vec<String>,
+}
+
+/// a problem found in a certificate, its chain or its key when it is added
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum CertificateIssue {
+    /// a certificate of the chain cannot be parsed
+    InvalidCertificate(String),
+    /// the private key cannot be parsed
+    InvalidKey,
+    /// the private key does not match the certificate
+    KeyMismatch,
+    /// the certificate at this position of the chain did not issue the previous one
+    UnorderedChain(usize),
+    /// the chain does not lead to a known root certificate, or to a self-signed one
+    IncompleteChain,
+    /// the chain was rejected by the verification
+    UntrustedChain(String),
+    Expired,
+    NotYetValid,
+}
+
+impl fmt::Display for CertificateIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CertificateIssue::InvalidCertificate(error) => {
+                write!(f, "invalid certificate: {}", error)
+            }
+            CertificateIssue::InvalidKey => write!(f, "invalid private key"),
+            CertificateIssue::KeyMismatch => {
+                write!(f, "the private key does not match the certificate")
+            }
+            CertificateIssue::UnorderedChain(position) => write!(
+                f,
+                "the certificate {} of the chain did not issue the previous one",
+                position
+            ),
+            CertificateIssue::IncompleteChain => {
+                write!(f, "the chain does not lead to a known root certificate")
+            }
+            CertificateIssue::UntrustedChain(error) => {
+                write!(f, "the chain is not trusted: {}", error)
+            }
+            CertificateIssue::Expired => write!(f, "the certificate is expired"),
+            CertificateIssue::NotYetValid => write!(f, "the certificate is not valid yet"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -132,6 +503,10 @@ pub struct WorkerInfo {
     pub id: u32,
     pub pid: i32,
     pub run_state: RunState,
+    /// the CPU cores the worker is pinned to, all of them if empty
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub cpu_cores: Vec<usize>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -143,6 +518,43 @@ pub enum Event {
     /// indicates a backend that was removed from configuration has no lingering connections
     /// so it can be safely stopped
     RemovedBackendHasNoConnections(String, SocketAddr),
+    /// the certificate with this fingerprint, on this listener, expires at this unix
+    /// timestamp, sooner than the configured threshold
+    CertificateWillExpire(String, SocketAddr, i64),
+    /// a new version of the state
+    ConfigurationChanged(ConfigurationChange),
+    /// the worker with this id and pid started
+    WorkerLaunched(u32, i32),
+    /// the worker with the first id was replaced by the one with the second id
+    WorkerUpgraded(u32, u32),
+    /// the worker with this id and pid stopped without being asked to, with
+    /// the report it sent if it panicked
+    WorkerCrashed(u32, i32, Option<CrashReport>),
+    /// the worker with this id answered the status order with an error
+    WorkerNotAnswering(u32),
+    /// the worker with this id crashed, and was not restarted because of this
+    /// number of crashes in the restart window
+    WorkerRestartLimit(u32, u32),
+    /// the worker with this id uses this resident memory in bytes, more than
+    /// `worker_max_memory`
+    WorkerMemoryExceeded(u32, u64),
+    ListenerActivated(SocketAddr, ListenerType),
+    ListenerDeactivated(SocketAddr, ListenerType),
+}
+
+/// a change of the state, for the subscribers of the events
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ConfigurationChange {
+    /// the version of the state, as listed by its history
+    pub version: u64,
+    /// the id of the request that changed the state
+    pub request_id: String,
+    /// the client that sent the request, none for the changes made by the main process
+    pub client: Option<String>,
+    /// the unix user of the client
+    pub user: Option<String>,
+    /// number of orders from the previous version to this one, by type
+    pub orders: BTreeMap<String, usize>,
 }
 
 impl From<ProxyEvent> for Event {
@@ -169,10 +581,12 @@ mod tests {
     use crate::certificate::split_certificate_chain;
     use crate::config::ProxyProtocolConfig;
     use crate::proxy::{
-        AddCertificate, Backend, CertificateAndKey, CertificateFingerprint, Cluster,
-        ClusterMetricsData, FilteredData, HttpFrontend, LoadBalancingAlgorithms,
-        LoadBalancingParams, PathRule, Percentiles, ProxyRequestOrder, QueryAnswerMetrics,
-        RemoveBackend, RemoveCertificate, Route, RulePosition, TlsVersion, WorkerMetrics,
+        AddCertificate, Backend, BackendProtocol, CertificateAndKey, CertificateFingerprint,
+        Cluster, ClusterMetricsData, Compression, FilteredData, HashKey, HostRewrite, HttpFrontend,
+        LoadBalancingAlgorithms, LoadBalancingParams, PathRule, Percentiles, ProxyRequestOrder,
+        QueryAnswerMetrics, RemoveBackend, RemoveCertificate, RequestLimits, RequestRetries, Route,
+        RulePosition, SecurityHeaders, StatusRange, StickyMode, Timeouts, TlsVersion,
+        WebSocketDrain, WorkerMetrics,
     };
     use hex::FromHex;
     use serde_json;
@@ -190,6 +604,11 @@ mod tests {
                     hostname: String::from("yyy"),
                     path: PathRule::Prefix(String::from("xxx")),
                     method: None,
+                    methods: Vec::new(),
+                    reject_other_methods: false,
+                    headers: Vec::new(),
+                    rewrite_path: None,
+                    mirror_cluster_id: None,
                     address: "0.0.0.0:8080".parse().unwrap(),
                     position: RulePosition::Tree,
                     tags: None,
@@ -241,13 +660,35 @@ mod tests {
             order: CommandRequestOrder::Proxy(Box::new(ProxyRequestOrder::AddCluster(Cluster {
                 cluster_id: String::from("xxx"),
                 sticky_session: true,
+                sticky_mode: StickyMode::Cookie,
                 https_redirect: true,
                 proxy_protocol: Some(ProxyProtocolConfig::ExpectHeader),
                 load_balancing: LoadBalancingAlgorithms::RoundRobin,
+                hash_key: HashKey::Url,
+                health_check: None,
+                outlier_detection: None,
+                request_queue: None,
+                affinity_table: None,
+                max_sessions: None,
                 load_metric: None,
                 answer_503: None,
+                header_actions: Vec::new(),
+                host_rewrite: HostRewrite::Preserve,
+                request_limits: RequestLimits::default(),
+                compression: Compression::default(),
+                security_headers: SecurityHeaders::default(),
+                request_retries: RequestRetries::default(),
+                timeouts: Timeouts::default(),
+                backend_tls: None,
+                backend_protocol: BackendProtocol::Http1,
+                websocket_drain: WebSocketDrain::Close,
+                streaming: false,
+                upgrade_protocols: Vec::new(),
             }))),
-            worker_id: None
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
         }
     );
 
@@ -260,7 +701,10 @@ mod tests {
             order: CommandRequestOrder::Proxy(Box::new(ProxyRequestOrder::RemoveCluster {
                 cluster_id: String::from("xxx")
             })),
-            worker_id: None
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
         }
     );
 
@@ -276,12 +720,20 @@ mod tests {
                     hostname: String::from("yyy"),
                     path: PathRule::Prefix(String::from("xxx")),
                     method: None,
+                    methods: Vec::new(),
+                    reject_other_methods: false,
+                    headers: Vec::new(),
+                    rewrite_path: None,
+                    mirror_cluster_id: None,
                     address: "0.0.0.0:8080".parse().unwrap(),
                     position: RulePosition::Tree,
                     tags: None,
                 }
             ))),
-            worker_id: None
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
         }
     );
 
@@ -297,6 +749,11 @@ mod tests {
                     hostname: String::from("yyy"),
                     path: PathRule::Prefix(String::from("xxx")),
                     method: None,
+                    methods: Vec::new(),
+                    reject_other_methods: false,
+                    headers: Vec::new(),
+                    rewrite_path: None,
+                    mirror_cluster_id: None,
                     address: "0.0.0.0:8080".parse().unwrap(),
                     position: RulePosition::Tree,
                     tags: Some(BTreeMap::from([
@@ -308,7 +765,10 @@ mod tests {
                     ]))
                 }
             ))),
-            worker_id: None
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
         }
     );
 
@@ -324,12 +784,20 @@ mod tests {
                     hostname: String::from("yyy"),
                     path: PathRule::Prefix(String::from("xxx")),
                     method: None,
+                    methods: Vec::new(),
+                    reject_other_methods: false,
+                    headers: Vec::new(),
+                    rewrite_path: None,
+                    mirror_cluster_id: None,
                     address: "0.0.0.0:8443".parse().unwrap(),
                     position: RulePosition::Tree,
                     tags: None,
                 }
             ))),
-            worker_id: None
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
         }
     );
 
@@ -345,6 +813,11 @@ mod tests {
                     hostname: String::from("yyy"),
                     path: PathRule::Prefix(String::from("xxx")),
                     method: None,
+                    methods: Vec::new(),
+                    reject_other_methods: false,
+                    headers: Vec::new(),
+                    rewrite_path: None,
+                    mirror_cluster_id: None,
                     address: "0.0.0.0:8443".parse().unwrap(),
                     position: RulePosition::Tree,
                     tags: Some(BTreeMap::from([
@@ -356,7 +829,10 @@ mod tests {
                     ]))
                 }
             ))),
-            worker_id: None
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
         }
     );
 
@@ -378,12 +854,17 @@ mod tests {
                         certificate_chain: split_certificate_chain(String::from(CHAIN)),
                         key: String::from(KEY),
                         versions: vec![TlsVersion::TLSv1_2, TlsVersion::TLSv1_3],
+                        ocsp_response: None,
+                        priority: 0,
                     },
                     names: vec![],
                     expired_at: None,
                 }
             ))),
-            worker_id: None
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
         }
     );
 
@@ -404,7 +885,10 @@ mod tests {
                     ),
                 }
             ))),
-            worker_id: None
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
         }
     );
 
@@ -421,8 +905,14 @@ mod tests {
                 load_balancing_parameters: Some(LoadBalancingParams { weight: 0 }),
                 sticky_id: Some(String::from("xxx-0")),
                 backup: Some(false),
+                max_connections: None,
+                timeouts: Timeouts::default(),
+                tls: None,
             }))),
-            worker_id: None
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
         }
     );
 
@@ -439,7 +929,30 @@ mod tests {
                     address: "127.0.0.1:8080".parse().unwrap(),
                 }
             ))),
-            worker_id: None
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
+        }
+    );
+
+    test_message!(
+        remove_backend_dry_run,
+        "../assets/remove_backend_dry_run.json",
+        CommandRequest {
+            id: "ID_TEST".to_string(),
+            version: 0,
+            order: CommandRequestOrder::Proxy(Box::new(ProxyRequestOrder::RemoveBackend(
+                RemoveBackend {
+                    cluster_id: String::from("xxx"),
+                    backend_id: String::from("xxx-0"),
+                    address: "127.0.0.1:8080".parse().unwrap(),
+                }
+            ))),
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: true,
         }
     );
 
@@ -449,8 +962,11 @@ mod tests {
         CommandRequest {
             id: "ID_TEST".to_string(),
             version: 0,
-            order: CommandRequestOrder::Proxy(Box::new(ProxyRequestOrder::SoftStop)),
+            order: CommandRequestOrder::Proxy(Box::new(ProxyRequestOrder::SoftStop(Some(30)))),
             worker_id: Some(0),
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
         }
     );
 
@@ -462,6 +978,9 @@ mod tests {
             version: 0,
             order: CommandRequestOrder::Proxy(Box::new(ProxyRequestOrder::HardStop)),
             worker_id: Some(0),
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
         }
     );
 
@@ -473,6 +992,9 @@ mod tests {
             version: 0,
             order: CommandRequestOrder::Proxy(Box::new(ProxyRequestOrder::Status)),
             worker_id: Some(0),
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
         }
     );
 
@@ -485,7 +1007,10 @@ mod tests {
             order: CommandRequestOrder::LoadState {
                 path: String::from("./config_dump.json")
             },
-            worker_id: None
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
         }
     );
 
@@ -498,7 +1023,10 @@ mod tests {
             order: CommandRequestOrder::SaveState {
                 path: String::from("./config_dump.json")
             },
-            worker_id: None
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
         }
     );
 
@@ -509,7 +1037,98 @@ mod tests {
             id: "ID_TEST".to_string(),
             version: 0,
             order: CommandRequestOrder::DumpState,
-            worker_id: None
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
+        }
+    );
+
+    test_message!(
+        apply_state,
+        "../assets/apply_state.json",
+        CommandRequest {
+            id: "ID_TEST".to_string(),
+            version: 0,
+            order: CommandRequestOrder::ApplyState {
+                state: Box::new({
+                    let mut state = ConfigState::new();
+                    state.handle_order(&ProxyRequestOrder::AddCluster(Cluster {
+                        cluster_id: String::from("xxx"),
+                        ..Default::default()
+                    }));
+                    state.handle_order(&ProxyRequestOrder::AddBackend(Backend {
+                        cluster_id: String::from("xxx"),
+                        backend_id: String::from("xxx-0"),
+                        address: "127.0.0.1:8080".parse().unwrap(),
+                        sticky_id: None,
+                        load_balancing_parameters: Some(LoadBalancingParams { weight: 100 }),
+                        backup: None,
+                        max_connections: None,
+                        timeouts: Timeouts::default(),
+                        tls: None,
+                    }));
+                    state
+                }),
+            },
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
+        }
+    );
+
+    test_message!(
+        rollback_state,
+        "../assets/rollback_state.json",
+        CommandRequest {
+            id: "ID_TEST".to_string(),
+            version: 0,
+            order: CommandRequestOrder::RollbackState { version: 3 },
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
+        }
+    );
+
+    test_message!(
+        peer_change,
+        "../assets/peer_change.json",
+        CommandRequest {
+            id: "PEER-sozu-a-4".to_string(),
+            version: 0,
+            order: CommandRequestOrder::PeerChange(PeerChange {
+                origin: String::from("sozu-a"),
+                base_version: 3,
+                orders: vec![ProxyRequestOrder::RemoveBackend(RemoveBackend {
+                    cluster_id: String::from("xxx"),
+                    backend_id: String::from("xxx-0"),
+                    address: "127.0.0.1:8080".parse().unwrap(),
+                })],
+            }),
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
+        }
+    );
+
+    test_message!(
+        tail_access_logs,
+        "../assets/tail_access_logs.json",
+        CommandRequest {
+            id: "ID_TEST".to_string(),
+            version: 0,
+            order: CommandRequestOrder::TailAccessLogs(AccessLogFilter {
+                cluster_id: Some(String::from("xxx")),
+                status: Some(StatusRange { from: 500, to: 599 }),
+                max_per_second: 100,
+            }),
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
         }
     );
 
@@ -520,7 +1139,10 @@ mod tests {
             id: "ID_TEST".to_string(),
             version: 0,
             order: CommandRequestOrder::ListWorkers,
-            worker_id: None
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
         }
     );
 
@@ -531,7 +1153,10 @@ mod tests {
             id: "ID_TEST".to_string(),
             version: 0,
             order: CommandRequestOrder::UpgradeMain,
-            worker_id: None
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
         }
     );
 
@@ -542,7 +1167,24 @@ mod tests {
             id: "ID_TEST".to_string(),
             version: 0,
             order: CommandRequestOrder::UpgradeWorker(0),
-            worker_id: None
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
+        }
+    );
+
+    test_message!(
+        restart_worker,
+        "../assets/restart_worker.json",
+        CommandRequest {
+            id: "ID_TEST".to_string(),
+            version: 0,
+            order: CommandRequestOrder::RestartWorker(0),
+            worker_id: None,
+            worker_ids: Vec::new(),
+            strict: false,
+            dry_run: false,
         }
     );
 
@@ -559,11 +1201,13 @@ mod tests {
                     id: 1,
                     pid: 5678,
                     run_state: RunState::Running,
+                    cpu_cores: vec![2, 3],
                 },
                 WorkerInfo {
                     id: 0,
                     pid: 1234,
                     run_state: RunState::Stopping,
+                    cpu_cores: Vec::new(),
                 },
             ))),
         }
@@ -669,4 +1313,164 @@ mod tests {
             }))
         }
     );
+
+    test_message_answer!(
+        answer_configuration_changed,
+        "../assets/answer_configuration_changed.json",
+        CommandResponse {
+            id: "VERSION-2".to_string(),
+            version: 0,
+            status: CommandStatus::Processing,
+            message: String::from("main"),
+            content: Some(CommandResponseContent::Event(Event::ConfigurationChanged(
+                ConfigurationChange {
+                    version: 2,
+                    request_id: String::from("ID_TEST"),
+                    client: Some(String::from("CL-0")),
+                    user: Some(String::from("sozu")),
+                    orders: [
+                        (String::from("ADD_BACKEND"), 2),
+                        (String::from("ADD_CLUSTER"), 1),
+                    ]
+                    .iter()
+                    .cloned()
+                    .collect(),
+                }
+            ))),
+        }
+    );
+
+    test_message_answer!(
+        answer_worker_crashed,
+        "../assets/answer_worker_crashed.json",
+        CommandResponse {
+            id: "WORKER-1".to_string(),
+            version: 0,
+            status: CommandStatus::Processing,
+            message: String::from("main"),
+            content: Some(CommandResponseContent::Event(Event::WorkerCrashed(
+                1,
+                4242,
+                Some(CrashReport {
+                    message: String::from("the message should be valid"),
+                    location: Some(String::from("lib/src/server.rs:743")),
+                    backtrace: String::from("   0: sozu::server::Server::run\n"),
+                    sessions: 12,
+                    last_order: Some(String::from("ID_TEST (AddCluster)")),
+                })
+            ))),
+        }
+    );
+
+    test_message_answer!(
+        answer_audit_log,
+        "../assets/answer_audit_log.json",
+        CommandResponse {
+            id: "ID_TEST".to_string(),
+            version: 0,
+            status: CommandStatus::Ok,
+            message: String::from("Successfully listed the entries of the audit log"),
+            content: Some(CommandResponseContent::AuditLog(vec![AuditEntry {
+                timestamp: 1700000000,
+                request_id: String::from("ID-REMOVE"),
+                client: String::from("CL-3"),
+                user: Some(String::from("sozu")),
+                uid: Some(1000),
+                category: OrderCategory::Mutation,
+                order: CommandRequestOrder::Proxy(Box::new(ProxyRequestOrder::RemoveCluster {
+                    cluster_id: String::from("xxx"),
+                })),
+                dry_run: false,
+                status: Some(CommandStatus::Ok),
+                message: String::from("Successfully executed the order on all workers"),
+                version: Some(7),
+            }])),
+        }
+    );
+
+    test_message_answer!(
+        answer_progress,
+        "../assets/answer_progress.json",
+        CommandResponse {
+            id: "ID_TEST".to_string(),
+            version: 0,
+            status: CommandStatus::Processing,
+            message: String::from("3/4 answers, worker 0: 2/2, worker 1: 1/2 (1 errors)"),
+            content: Some(CommandResponseContent::Progress(Progress {
+                workers: [
+                    (
+                        0,
+                        WorkerProgress {
+                            expected: 2,
+                            ok: 2,
+                            errors: 0,
+                        }
+                    ),
+                    (
+                        1,
+                        WorkerProgress {
+                            expected: 2,
+                            ok: 0,
+                            errors: 1,
+                        }
+                    ),
+                ]
+                .iter()
+                .cloned()
+                .collect(),
+            })),
+        }
+    );
+
+    #[test]
+    fn lifecycle_event_payloads() {
+        let address: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let payload = |event: Event| serde_json::to_value(event).unwrap();
+
+        assert_eq!(
+            payload(Event::WorkerLaunched(1, 4242)),
+            serde_json::json!({"type": "WORKER_LAUNCHED", "data": [1, 4242]})
+        );
+        assert_eq!(
+            payload(Event::WorkerUpgraded(1, 2)),
+            serde_json::json!({"type": "WORKER_UPGRADED", "data": [1, 2]})
+        );
+        assert_eq!(
+            payload(Event::WorkerCrashed(1, 4242, None)),
+            serde_json::json!({"type": "WORKER_CRASHED", "data": [1, 4242, null]})
+        );
+        assert_eq!(
+            payload(Event::WorkerNotAnswering(1)),
+            serde_json::json!({"type": "WORKER_NOT_ANSWERING", "data": 1})
+        );
+        assert_eq!(
+            payload(Event::ListenerActivated(address, ListenerType::HTTPS)),
+            serde_json::json!({"type": "LISTENER_ACTIVATED", "data": ["127.0.0.1:8080", "https"]})
+        );
+        assert_eq!(
+            payload(Event::ListenerDeactivated(address, ListenerType::TCP)),
+            serde_json::json!({"type": "LISTENER_DEACTIVATED", "data": ["127.0.0.1:8080", "tcp"]})
+        );
+    }
+
+    #[test]
+    fn progress_test() {
+        let mut progress = Progress::default();
+        progress.expect(0);
+        progress.expect(1);
+        progress.expect(1);
+
+        assert!(progress.record(0, true));
+        assert!(!progress.record(1, false));
+        assert_eq!(progress.answered(), 2);
+        assert_eq!(progress.expected(), 3);
+        assert_eq!(progress.pending_workers(), vec![1]);
+        assert_eq!(
+            progress.to_string(),
+            "2/3 answers, worker 0: 1/1, worker 1: 1/2 (1 errors)"
+        );
+
+        assert!(progress.record(1, true));
+        assert!(progress.pending_workers().is_empty());
+    }
 }
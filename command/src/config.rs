@@ -2,24 +2,33 @@
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
     env,
-    fs::File,
-    io::{self, Error, ErrorKind, Read},
+    fs::{self, File},
+    io::{self, Error, ErrorKind, Read, Write},
     net::SocketAddr,
-    path::PathBuf,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
 };
 
 use anyhow::{bail, Context};
 use toml;
 
 use crate::{
-    certificate::split_certificate_chain,
-    command::{CommandRequest, CommandRequestOrder, PROTOCOL_VERSION},
+    certificate::{
+        calculate_fingerprint, decrypt_key, der_certificate_to_pem, der_key_to_pem,
+        is_encrypted_key, is_pem, pkcs12_to_pem, split_certificate_chain, Passphrase, PemBundle,
+    },
+    command::{CommandRequest, CommandRequestOrder, OrderCategory, PROTOCOL_VERSION},
     proxy::{
-        ActivateListener, AddCertificate, Backend, CertificateAndKey, Cluster, HttpFrontend,
-        HttpListener, HttpsListener, ListenerType, LoadBalancingAlgorithms, LoadBalancingParams,
-        LoadMetric, PathRule, ProxyRequestOrder, Route, RulePosition, TcpFrontend, TcpListener,
-        TlsProvider, TlsVersion,
+        ActivateListener, AddCertificate, AffinityTable, Backend, BackendProtocol, BackendTls,
+        CertificateAndKey, CertificateFingerprint, ClientAuth, Cluster, Compression, Destination,
+        ForwardProxy, HashKey, HeaderAction, HeaderRule, HealthCheck, HostRewrite, Http2Settings,
+        HttpFrontend, HttpListener, HttpsListener, ListenerType, LoadBalancingAlgorithms,
+        LoadBalancingParams, LoadMetric, OutlierDetection, PathNormalization, PathRewrite,
+        PathRule, ProxyRequestOrder, RequestLimits, RequestQueue, RequestRetries, Route,
+        RulePosition, SecurityHeaders, SniFrontend, StickyMode, TcpFrontend, TcpListener, Timeouts,
+        TlsProvider, TlsVersion, WebSocketDrain, DEFAULT_CLIENT_DN_HEADER,
     },
+    state::ConfigState,
 };
 
 // -------------------------------------------------------------------------------------------------
@@ -92,8 +101,8 @@ pub struct Listener {
     pub public_address: Option<SocketAddr>,
     pub answer_404: Option<String>,
     pub answer_503: Option<String>,
-    #[serde(default)]
-    pub tls_provider: TlsProvider,
+    /// implementation of TLS of an HTTPS listener, the global `tls_provider` by default
+    pub tls_provider: Option<TlsProvider>,
     pub tls_versions: Option<Vec<TlsVersion>>,
     pub cipher_list: Option<Vec<String>>,
     pub cipher_suites: Option<Vec<String>>,
@@ -105,16 +114,95 @@ pub struct Listener {
     pub certificate: Option<String>,
     pub certificate_chain: Option<String>,
     pub key: Option<String>,
+    /// source of the passphrase of an encrypted `key`:
+    /// `pass:<passphrase>`, `file:<path>` or `env:<variable>`
+    pub key_passphrase: Option<String>,
     pub front_timeout: Option<u32>,
     pub back_timeout: Option<u32>,
     pub connect_timeout: Option<u32>,
     pub request_timeout: Option<u32>,
+    /// limits on the size of the HTTP requests
+    #[serde(default)]
+    pub request_limits: RequestLimits,
+    /// compression of the HTTP responses
+    #[serde(default)]
+    pub compression: Compression,
+    /// normalization of the request paths before the routing
+    #[serde(default)]
+    pub path_normalization: PathNormalization,
+    /// cluster receiving the requests matching no frontend, instead of answering a 404
+    pub fallback_cluster: Option<String>,
+    /// verification of the client certificates on an HTTPS listener
+    pub client_auth: Option<FileClientAuthConfig>,
+    /// path to the certificate presented when the client sends no server name, or
+    /// a name matching no certificate. It has to be added by a frontend too
+    pub default_certificate: Option<String>,
+    /// refuses the TLS handshakes without a server name or with an unknown one,
+    /// instead of presenting the default certificate
+    pub strict_sni: Option<bool>,
+    /// max time to complete the TLS handshake, in seconds
+    pub handshake_timeout: Option<u32>,
+    /// security headers added to the responses of an HTTPS listener
+    #[serde(default)]
+    pub security_headers: SecurityHeaders,
+    /// HTTP/2 offered to the clients of an HTTPS listener
+    #[serde(default)]
+    pub http2: Http2Settings,
+    /// destinations that the clients of a forward proxy listener can reach,
+    /// as `<host>:<port>`
+    pub allowed_destinations: Option<Vec<Destination>>,
+    /// a forward proxy listener accepts SOCKS5 handshakes besides the HTTP
+    /// `CONNECT` requests
+    pub socks5: Option<bool>,
+    /// client sessions open on the listener at most, the next ones are answered
+    /// with a 503, or refused by TCP listeners
+    pub max_sessions: Option<usize>,
 }
 
 fn default_sticky_name() -> String {
     String::from("SOZUBALANCEID")
 }
 
+/// client certificate authentication of an HTTPS listener, with paths to the PEM files
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileClientAuthConfig {
+    /// reject the clients without a certificate
+    #[serde(default)]
+    pub required: bool,
+    /// bundle of the certificate authorities the client certificates are verified against
+    pub ca: String,
+    /// certificate revocation lists
+    pub crl: Option<String>,
+    /// request header receiving the subject DN of the client certificate
+    pub dn_header: Option<String>,
+}
+
+impl FileClientAuthConfig {
+    pub fn to_client_auth(&self) -> anyhow::Result<ClientAuth> {
+        let ca_certificates = Config::load_file(&self.ca)
+            .with_context(|| format!("cannot load client CA bundle at path '{}'", self.ca))?;
+
+        let revocation_lists = match &self.crl {
+            Some(path) => Some(
+                Config::load_file(path)
+                    .with_context(|| format!("cannot load client CRL at path '{}'", path))?,
+            ),
+            None => None,
+        };
+
+        Ok(ClientAuth {
+            required: self.required,
+            ca_certificates,
+            revocation_lists,
+            dn_header: self
+                .dn_header
+                .clone()
+                .unwrap_or_else(|| DEFAULT_CLIENT_DN_HEADER.to_string()),
+        })
+    }
+}
+
 impl Listener {
     pub fn new(address: SocketAddr, protocol: FileListenerProtocolConfig) -> Listener {
         Listener {
@@ -123,7 +211,7 @@ impl Listener {
             public_address: None,
             answer_404: None,
             answer_503: None,
-            tls_provider: TlsProvider::default(),
+            tls_provider: None,
             tls_versions: None,
             cipher_list: None,
             cipher_suites: None,
@@ -134,10 +222,24 @@ impl Listener {
             certificate: None,
             certificate_chain: None,
             key: None,
+            key_passphrase: None,
             front_timeout: None,
             back_timeout: None,
             connect_timeout: None,
             request_timeout: None,
+            request_limits: RequestLimits::default(),
+            compression: Compression::default(),
+            path_normalization: PathNormalization::default(),
+            fallback_cluster: None,
+            client_auth: None,
+            default_certificate: None,
+            strict_sni: None,
+            handshake_timeout: None,
+            security_headers: SecurityHeaders::default(),
+            http2: Http2Settings::default(),
+            allowed_destinations: None,
+            socks5: None,
+            max_sessions: None,
         }
     }
 
@@ -176,6 +278,11 @@ impl Listener {
             back_timeout: self.back_timeout.or(back_timeout).unwrap_or(30),
             connect_timeout: self.connect_timeout.or(connect_timeout).unwrap_or(3),
             request_timeout: self.request_timeout.or(request_timeout).unwrap_or(10),
+            request_limits: self.request_limits,
+            compression: self.compression,
+            path_normalization: self.path_normalization,
+            fallback_cluster: self.fallback_cluster.clone(),
+            max_sessions: self.max_sessions,
             ..Default::default()
         };
 
@@ -214,12 +321,14 @@ impl Listener {
         back_timeout: Option<u32>,
         connect_timeout: Option<u32>,
         request_timeout: Option<u32>,
+        tls_provider: Option<TlsProvider>,
     ) -> anyhow::Result<HttpsListener> {
         if self.protocol != FileListenerProtocolConfig::Https {
             bail!("cannot convert listener to HTTPS");
         }
 
-        let default_cipher_list = match self.tls_provider {
+        let tls_provider = self.tls_provider.or(tls_provider).unwrap_or_default();
+        let default_cipher_list = match tls_provider {
             TlsProvider::Rustls => DEFAULT_RUSTLS_CIPHER_LIST
                 .into_iter()
                 .map(String::from)
@@ -259,7 +368,7 @@ impl Listener {
         };
 
         let key = self.key.as_ref().and_then(|path| {
-            Config::load_file(path)
+            Config::load_key(path, self.key_passphrase.as_deref())
                 .map_err(|e| {
                     error!("cannot load key at path '{}': {:?}", path, e);
                     e
@@ -267,7 +376,7 @@ impl Listener {
                 .ok()
         });
         let certificate = self.certificate.as_ref().and_then(|path| {
-            Config::load_file(path)
+            Config::load_certificate(path)
                 .map_err(|e| {
                     error!("cannot load certificate at path '{}': {:?}", path, e);
                     e
@@ -278,23 +387,38 @@ impl Listener {
             .certificate_chain
             .as_ref()
             .and_then(|path| {
-                Config::load_file(path)
+                Config::load_certificate_chain(path)
                     .map_err(|e| {
                         error!("cannot load certificate chain at path '{}': {:?}", path, e);
                         e
                     })
                     .ok()
             })
-            .map(split_certificate_chain)
             .unwrap_or_else(Vec::new);
 
         let expect_proxy = self.expect_proxy.unwrap_or(false);
 
+        let client_auth = match &self.client_auth {
+            Some(client_auth) => Some(Box::new(client_auth.to_client_auth()?)),
+            None => None,
+        };
+
+        let default_certificate = match &self.default_certificate {
+            Some(path) => {
+                let certificate = Config::load_certificate(path)
+                    .with_context(|| format!("cannot load default certificate at path {}", path))?;
+                Some(CertificateFingerprint(calculate_fingerprint(
+                    certificate.as_bytes(),
+                )?))
+            }
+            None => None,
+        };
+
         let mut configuration = HttpsListener {
             address: self.address,
             sticky_name: self.sticky_name.clone(),
             public_address: self.public_address,
-            tls_provider: self.tls_provider.clone(),
+            tls_provider,
             cipher_list,
             cipher_suites,
             signature_algorithms,
@@ -308,6 +432,17 @@ impl Listener {
             back_timeout: self.back_timeout.or(back_timeout).unwrap_or(30),
             connect_timeout: self.connect_timeout.or(connect_timeout).unwrap_or(3),
             request_timeout: self.request_timeout.or(request_timeout).unwrap_or(10),
+            request_limits: self.request_limits,
+            compression: self.compression,
+            security_headers: Box::new(self.security_headers.clone()),
+            path_normalization: self.path_normalization,
+            fallback_cluster: self.fallback_cluster.clone(),
+            client_auth,
+            default_certificate,
+            strict_sni: self.strict_sni.unwrap_or(false),
+            handshake_timeout: self.handshake_timeout,
+            http2: self.http2,
+            max_sessions: self.max_sessions,
             ..Default::default()
         };
 
@@ -366,6 +501,21 @@ impl Listener {
         let addr = addr_parsed;
         */
 
+        let forward_proxy = match self.protocol {
+            FileListenerProtocolConfig::Forward => Some(ForwardProxy {
+                allowed_destinations: self.allowed_destinations.clone().with_context(|| {
+                    "a forward proxy listener needs the allowed_destinations field"
+                })?,
+                socks5: self.socks5.unwrap_or(false),
+            }),
+            _ => {
+                if self.allowed_destinations.is_some() || self.socks5.is_some() {
+                    bail!("only the forward proxy listeners have allowed destinations");
+                }
+                None
+            }
+        };
+
         Ok(TcpListener {
             address: self.address,
             public_address: self.public_address,
@@ -373,6 +523,8 @@ impl Listener {
             front_timeout: self.front_timeout.or(front_timeout).unwrap_or(60),
             back_timeout: self.back_timeout.or(back_timeout).unwrap_or(30),
             connect_timeout: self.connect_timeout.or(connect_timeout).unwrap_or(3),
+            forward_proxy,
+            max_sessions: self.max_sessions,
         })
     }
 }
@@ -387,6 +539,255 @@ pub struct MetricsConfig {
     pub prefix: Option<String>,
 }
 
+/// sources of backends watched by the main process
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    #[serde(default)]
+    pub consul: Option<ConsulConfig>,
+    #[serde(default)]
+    pub docker: Option<DockerConfig>,
+}
+
+/// the Consul agent whose catalog replaces the backends of the mapped clusters
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConsulConfig {
+    #[serde(default = "default_consul_address")]
+    pub address: String,
+    /// ACL token, sent in the `X-Consul-Token` header
+    #[serde(default)]
+    pub token: Option<String>,
+    /// datacenter of the services, the one of the agent if not set
+    #[serde(default)]
+    pub datacenter: Option<String>,
+    /// duration of the blocking queries, in seconds
+    #[serde(default = "default_consul_wait")]
+    pub wait: u32,
+    /// services of each cluster, by cluster id
+    #[serde(default)]
+    pub services: BTreeMap<String, ConsulService>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConsulService {
+    /// name of the service in Consul, the cluster id if not set
+    #[serde(default)]
+    pub name: Option<String>,
+    /// only the instances having all these tags become backends
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// the Docker or Podman daemon whose labelled containers become clusters, with
+/// their frontends and backends
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DockerConfig {
+    /// path to the unix socket of the API
+    #[serde(default = "default_docker_socket")]
+    pub socket: String,
+    /// network of the containers where the backends are reached, the first one if not set
+    #[serde(default)]
+    pub network: Option<String>,
+    /// listeners receiving the frontends, all the HTTP and HTTPS ones if empty
+    #[serde(default)]
+    pub listeners: Vec<SocketAddr>,
+}
+
+/// the orders that the clients of the command socket running as these users,
+/// or in these groups, can send
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandRole {
+    /// names or uids of the users
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// names or gids of the groups, primary or supplementary
+    #[serde(default)]
+    pub groups: Vec<String>,
+    pub orders: Vec<OrderCategory>,
+}
+
+/// the command channel exposed on a TCP address, over TLS, to manage the proxy
+/// from other hosts
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteCommandConfig {
+    pub address: SocketAddr,
+    /// path to the certificate of the channel, followed by its chain
+    pub certificate: String,
+    /// path to the private key of the certificate
+    pub key: String,
+    /// shared secret that the clients send before their orders
+    pub token: String,
+    /// the orders that the clients of `token` can send, all of them by default.
+    /// The peers need the mutations
+    #[serde(default = "default_remote_orders")]
+    pub orders: Vec<OrderCategory>,
+    /// other secrets, each allowing some orders
+    #[serde(default)]
+    pub roles: Vec<RemoteCommandRole>,
+    /// number of remote clients served at the same time, the others are refused
+    #[serde(default = "default_remote_max_clients")]
+    pub max_clients: usize,
+}
+
+/// the orders that the remote clients sending this token can send
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteCommandRole {
+    pub token: String,
+    pub orders: Vec<OrderCategory>,
+}
+
+fn default_remote_orders() -> Vec<OrderCategory> {
+    vec![
+        OrderCategory::Read,
+        OrderCategory::Mutation,
+        OrderCategory::Upgrade,
+        OrderCategory::Admin,
+    ]
+}
+
+fn default_remote_max_clients() -> usize {
+    16
+}
+
+/// the external program asked to authorize each order changing the proxy, with
+/// the identity of the client. It receives an `AuthorizationRequest` in JSON
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandAuthorizationConfig {
+    /// executable and its arguments, reading the request on its standard input.
+    /// The order is allowed if it exits with the status 0, its output gives the
+    /// reason of a refusal
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+    /// path to the unix socket of a service reading the request, followed by a
+    /// 0 byte, and answering with an `AuthorizationDecision` in JSON
+    #[serde(default)]
+    pub socket: Option<String>,
+    /// seconds to wait for the decision, the order is refused after it
+    #[serde(default = "default_authorization_timeout")]
+    pub timeout: u64,
+}
+
+fn default_authorization_timeout() -> u64 {
+    5
+}
+
+/// the append-only log of the orders changing the proxy, one JSON `AuditEntry`
+/// per line, separate from the logs of the main process
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditLogConfig {
+    pub path: String,
+    /// size in bytes of the file before it is rotated
+    #[serde(default = "default_audit_log_max_size")]
+    pub max_size: u64,
+    /// number of rotated files kept, as path.1, path.2 and so on, the most
+    /// recent first
+    #[serde(default = "default_audit_log_max_files")]
+    pub max_files: usize,
+}
+
+fn default_audit_log_max_size() -> u64 {
+    10_000_000
+}
+
+fn default_audit_log_max_files() -> usize {
+    5
+}
+
+/// the CPU cores the workers are pinned to, with `sched_setaffinity`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum WorkerCpuAffinity {
+    /// `"auto"`: each worker on its own core, after the first one
+    Spread(AffinitySpread),
+    /// the cores of each worker, the first list for the first worker
+    Cores(Vec<Vec<usize>>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AffinitySpread {
+    Auto,
+}
+
+/// what the main process does with a worker missing its heartbeats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum NotAnsweringPolicy {
+    /// only sends the event
+    #[default]
+    None,
+    /// sends SIGTERM to the worker and starts a new one
+    Terminate,
+    /// sends SIGKILL to the worker and starts a new one
+    Kill,
+}
+
+impl WorkerCpuAffinity {
+    /// the cores of the worker started at this position. The workers
+    /// replacing a worker keep its cores
+    pub fn cores(&self, slot: usize, cpu_count: usize) -> Vec<usize> {
+        match self {
+            WorkerCpuAffinity::Spread(AffinitySpread::Auto) => {
+                vec![(slot + 1) % cpu_count.max(1)]
+            }
+            WorkerCpuAffinity::Cores(cores) if cores.is_empty() => Vec::new(),
+            WorkerCpuAffinity::Cores(cores) => cores[slot % cores.len()].clone(),
+        }
+    }
+}
+
+/// the number of CPU cores a worker can be pinned to, numbered from 0. A CPU
+/// set cannot hold more than `CPU_SETSIZE` of them
+fn core_count() -> usize {
+    let cpu_count = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) }.max(1) as usize;
+    #[cfg(target_os = "linux")]
+    let cpu_count = cpu_count.min(libc::CPU_SETSIZE as usize);
+    cpu_count
+}
+
+/// the main processes of an active-active group, sharing the changes of their
+/// state through their remote command channels
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeeringConfig {
+    /// name of this instance, sent to the peers with its changes
+    pub name: String,
+    #[serde(default)]
+    pub peers: Vec<PeerConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeerConfig {
+    /// host and port of the remote command channel of the peer
+    pub address: String,
+    /// token of the remote command channel of the peer
+    pub token: String,
+    /// path to the certificate authority of the peer, the web ones by default
+    #[serde(default)]
+    pub ca: Option<String>,
+}
+
+fn default_docker_socket() -> String {
+    String::from("/var/run/docker.sock")
+}
+
+fn default_consul_address() -> String {
+    String::from("http://127.0.0.1:8500")
+}
+
+fn default_consul_wait() -> u32 {
+    60
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 #[serde(deny_unknown_fields)]
@@ -415,9 +816,34 @@ pub struct FileClusterFrontendConfig {
     /// declares wether the path rule is Prefix (default), Regex, or Equals
     pub path_type: Option<PathRuleType>,
     pub method: Option<String>,
+    /// the frontend matches any of these methods, in addition to `method`
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// answers 405 to requests with another method, if no other frontend accepts them
+    #[serde(default)]
+    pub reject_other_methods: bool,
+    /// header rules that the request has to match
+    #[serde(default)]
+    pub headers: Vec<HeaderRule>,
+    /// rewrites the request path before sending it to the backend
+    pub rewrite_path: Option<PathRewrite>,
+    /// cluster receiving a copy of the requests, its responses are discarded
+    pub mirror_cluster_id: Option<String>,
     pub certificate: Option<String>,
     pub key: Option<String>,
     pub certificate_chain: Option<String>,
+    /// source of the passphrase of an encrypted `key`:
+    /// `pass:<passphrase>`, `file:<path>` or `env:<variable>`
+    pub key_passphrase: Option<String>,
+    /// path to a PKCS#12 bundle holding the certificate, its chain and its key,
+    /// instead of the `certificate`, `certificate_chain` and `key` fields
+    pub pkcs12: Option<String>,
+    /// path to a file containing the password of the PKCS#12 bundle
+    pub pkcs12_password_file: Option<String>,
+    /// path to a DER encoded OCSP response stapled with the certificate
+    pub ocsp_response: Option<String>,
+    /// preference of the certificate over the other ones providing the same name
+    pub certificate_priority: Option<i32>,
     #[serde(default)]
     pub tls_versions: Vec<TlsVersion>,
     #[serde(default)]
@@ -426,25 +852,61 @@ pub struct FileClusterFrontendConfig {
 }
 
 impl FileClusterFrontendConfig {
-    pub fn to_tcp_front(&self) -> anyhow::Result<TcpFrontendConfig> {
-        if self.hostname.is_some() {
-            bail!("invalid 'hostname' field for TCP frontend");
+    pub fn new(address: SocketAddr) -> FileClusterFrontendConfig {
+        FileClusterFrontendConfig {
+            address,
+            hostname: None,
+            path: None,
+            path_type: None,
+            method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
+            certificate: None,
+            key: None,
+            certificate_chain: None,
+            key_passphrase: None,
+            pkcs12: None,
+            pkcs12_password_file: None,
+            ocsp_response: None,
+            certificate_priority: None,
+            tls_versions: Vec::new(),
+            position: RulePosition::default(),
+            tags: None,
         }
+    }
+
+    pub fn to_tcp_front(&self) -> anyhow::Result<TcpFrontendConfig> {
         if self.path.is_some() {
             bail!("invalid 'path_prefix' field for TCP frontend");
         }
         if self.certificate.is_some() {
             bail!("invalid 'certificate' field for TCP frontend");
         }
-        if self.hostname.is_some() {
+        if self.key.is_some() {
             bail!("invalid 'key' field for TCP frontend");
         }
         if self.certificate_chain.is_some() {
             bail!("invalid 'certificate_chain' field for TCP frontend",);
         }
+        if self.key_passphrase.is_some() {
+            bail!("invalid 'key_passphrase' field for TCP frontend",);
+        }
+        if self.pkcs12.is_some() {
+            bail!("invalid 'pkcs12' field for TCP frontend",);
+        }
+        if self.ocsp_response.is_some() {
+            bail!("invalid 'ocsp_response' field for TCP frontend",);
+        }
+        if self.certificate_priority.is_some() {
+            bail!("invalid 'certificate_priority' field for TCP frontend",);
+        }
 
         Ok(TcpFrontendConfig {
             address: self.address,
+            hostname: self.hostname.clone(),
             tags: self.tags.clone(),
         })
     }
@@ -456,30 +918,64 @@ impl FileClusterFrontendConfig {
             None => bail!("HTTP frontend should have a 'hostname' field"),
         };
 
-        let key_opt = match self.key.as_ref() {
-            None => None,
+        let (certificate_opt, chain_opt, key_opt) = match self.pkcs12.as_ref() {
             Some(path) => {
-                let key = Config::load_file(path)
-                    .with_context(|| format!("cannot load key at path '{}'", path))?;
-                Some(key)
+                if self.certificate.is_some()
+                    || self.certificate_chain.is_some()
+                    || self.key.is_some()
+                {
+                    bail!("the 'pkcs12' field replaces the 'certificate', 'certificate_chain' and 'key' fields");
+                }
+
+                let bundle = Config::load_pkcs12(path, self.pkcs12_password_file.as_deref())
+                    .with_context(|| format!("cannot load PKCS#12 bundle at path '{}'", path))?;
+                (
+                    Some(bundle.certificate),
+                    Some(bundle.certificate_chain),
+                    Some(bundle.key),
+                )
             }
-        };
+            None => {
+                let key_opt = match self.key.as_ref() {
+                    None => None,
+                    Some(path) => {
+                        let key = Config::load_key(path, self.key_passphrase.as_deref())
+                            .with_context(|| format!("cannot load key at path '{}'", path))?;
+                        Some(key)
+                    }
+                };
 
-        let certificate_opt = match self.certificate.as_ref() {
-            None => None,
-            Some(path) => {
-                let certificate = Config::load_file(path)
-                    .with_context(|| format!("cannot load certificate at path '{}'", path))?;
-                Some(certificate)
+                let certificate_opt = match self.certificate.as_ref() {
+                    None => None,
+                    Some(path) => {
+                        let certificate = Config::load_certificate(path).with_context(|| {
+                            format!("cannot load certificate at path '{}'", path)
+                        })?;
+                        Some(certificate)
+                    }
+                };
+
+                let chain_opt = match self.certificate_chain.as_ref() {
+                    None => None,
+                    Some(path) => {
+                        let certificate_chain =
+                            Config::load_certificate_chain(path).with_context(|| {
+                                format!("cannot load certificate chain at path {}", path)
+                            })?;
+                        Some(certificate_chain)
+                    }
+                };
+
+                (certificate_opt, chain_opt, key_opt)
             }
         };
 
-        let chain_opt = match self.certificate_chain.as_ref() {
+        let ocsp_response_opt = match self.ocsp_response.as_ref() {
             None => None,
             Some(path) => {
-                let certificate_chain = Config::load_file(path)
-                    .with_context(|| format!("cannot load certificate chain at path {}", path))?;
-                Some(split_certificate_chain(certificate_chain))
+                let ocsp_response = Config::load_file_bytes(path)
+                    .with_context(|| format!("cannot load OCSP response at path {}", path))?;
+                Some(hex::encode(ocsp_response))
             }
         };
 
@@ -497,10 +993,17 @@ impl FileClusterFrontendConfig {
             certificate: certificate_opt,
             key: key_opt,
             certificate_chain: chain_opt,
+            ocsp_response: ocsp_response_opt,
+            certificate_priority: self.certificate_priority.unwrap_or_default(),
             tls_versions: self.tls_versions.clone(),
             position: self.position,
             path,
             method: self.method.clone(),
+            methods: self.methods.clone(),
+            reject_other_methods: self.reject_other_methods,
+            headers: self.headers.clone(),
+            rewrite_path: self.rewrite_path.clone(),
+            mirror_cluster_id: self.mirror_cluster_id.clone(),
             tags: self.tags.clone(),
         })
     }
@@ -512,6 +1015,9 @@ pub enum FileListenerProtocolConfig {
     Http,
     Https,
     Tcp,
+    /// TCP listener tunneling the connections to the destinations requested
+    /// by the clients
+    Forward,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -525,17 +1031,171 @@ pub enum FileClusterProtocolConfig {
 #[serde(deny_unknown_fields)]
 pub struct FileClusterConfig {
     pub frontends: Vec<FileClusterFrontendConfig>,
-    pub backends: Vec<BackendConfig>,
+    pub backends: Vec<FileBackendConfig>,
     pub protocol: FileClusterProtocolConfig,
     pub sticky_session: Option<bool>,
+    /// how requests stick to a backend when sticky_session is set, for HTTP clusters
+    #[serde(default)]
+    pub sticky_mode: StickyMode,
     pub https_redirect: Option<bool>,
     #[serde(default)]
     pub send_proxy: Option<bool>,
     #[serde(default)]
     pub load_balancing: LoadBalancingAlgorithms,
+    /// key of the requests hashed by the consistent_hash load balancing, for HTTP clusters
+    #[serde(default)]
+    pub hash_key: HashKey,
+    /// active checks of the backends
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+    /// passive checks of the backends
+    #[serde(default)]
+    pub outlier_detection: Option<OutlierDetection>,
+    /// requests waiting for a free connection to the backends, for HTTP clusters
+    #[serde(default)]
+    pub request_queue: Option<RequestQueue>,
+    /// backends bound to the sticky keys, shared by the workers
+    #[serde(default)]
+    pub affinity_table: Option<AffinityTable>,
+    /// client sessions routed to the cluster at most by a worker
+    #[serde(default)]
+    pub max_sessions: Option<usize>,
     pub answer_503: Option<String>,
     #[serde(default)]
     pub load_metric: Option<LoadMetric>,
+    /// modifications of the request and response headers, for HTTP clusters
+    #[serde(default)]
+    pub header_actions: Vec<HeaderAction>,
+    /// how the Host header is sent to the backends, for HTTP clusters
+    #[serde(default)]
+    pub host_rewrite: HostRewrite,
+    /// limits on the size of the requests, for HTTP clusters
+    #[serde(default)]
+    pub request_limits: RequestLimits,
+    /// compression of the responses, for HTTP clusters
+    #[serde(default)]
+    pub compression: Compression,
+    /// security headers added to the responses, for HTTP clusters
+    #[serde(default)]
+    pub security_headers: SecurityHeaders,
+    /// retries of the failed requests on other backends, for HTTP clusters
+    #[serde(default)]
+    pub request_retries: RequestRetries,
+    /// timeouts overriding those of the listeners, for HTTP clusters
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    /// connects to the backends over TLS, for HTTP clusters
+    pub backend_tls: Option<FileBackendTlsConfig>,
+    /// protocol spoken to the backends, for HTTP clusters
+    #[serde(default)]
+    pub backend_protocol: BackendProtocol,
+    /// what happens to the WebSocket connections when the worker stops, for HTTP clusters
+    #[serde(default)]
+    pub websocket_drain: WebSocketDrain,
+    /// responses forwarded as they arrive, for server-sent events and long polling
+    #[serde(default)]
+    pub streaming: bool,
+    /// protocols the connections can be upgraded to, besides WebSocket
+    #[serde(default)]
+    pub upgrade_protocols: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileBackendConfig {
+    pub address: SocketAddr,
+    pub weight: Option<u8>,
+    pub sticky_id: Option<String>,
+    pub backup: Option<bool>,
+    pub backend_id: Option<String>,
+    /// connections opened to this backend at most
+    pub max_connections: Option<usize>,
+    /// timeouts of the connections to this backend, for HTTP clusters
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    /// TLS settings replacing those of the cluster, for HTTP clusters
+    pub tls: Option<FileBackendTlsConfig>,
+}
+
+impl FileBackendConfig {
+    pub fn to_backend_config(self) -> anyhow::Result<BackendConfig> {
+        let tls = match self.tls {
+            None => None,
+            Some(tls) => Some(tls.to_backend_tls()?),
+        };
+
+        Ok(BackendConfig {
+            address: self.address,
+            weight: self.weight,
+            sticky_id: self.sticky_id,
+            backup: self.backup,
+            backend_id: self.backend_id,
+            max_connections: self.max_connections,
+            timeouts: self.timeouts,
+            tls,
+        })
+    }
+}
+
+/// TLS connection to the backends, the certificates are paths to PEM files
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileBackendTlsConfig {
+    pub sni: Option<String>,
+    #[serde(default)]
+    pub skip_verification: bool,
+    pub ca_certificates: Option<String>,
+    pub client_certificate: Option<String>,
+    pub client_key: Option<String>,
+    #[serde(default)]
+    pub alpn_protocols: Vec<String>,
+}
+
+impl FileBackendTlsConfig {
+    pub fn to_backend_tls(&self) -> anyhow::Result<BackendTls> {
+        if self.client_certificate.is_some() != self.client_key.is_some() {
+            bail!("the backend client certificate and key should be set together");
+        }
+
+        let ca_certificates = match self.ca_certificates.as_ref() {
+            None => None,
+            Some(path) => {
+                let ca_certificates = Config::load_file(path).with_context(|| {
+                    format!("cannot load backend CA certificates at path '{}'", path)
+                })?;
+                Some(ca_certificates)
+            }
+        };
+
+        let client_certificate = match self.client_certificate.as_ref() {
+            None => None,
+            Some(path) => {
+                let client_certificate = Config::load_file(path).with_context(|| {
+                    format!("cannot load backend client certificate at path '{}'", path)
+                })?;
+                Some(client_certificate)
+            }
+        };
+
+        let client_key = match self.client_key.as_ref() {
+            None => None,
+            Some(path) => {
+                let client_key = Config::load_file(path).with_context(|| {
+                    format!("cannot load backend client key at path '{}'", path)
+                })?;
+                Some(client_key)
+            }
+        };
+
+        Ok(BackendTls {
+            sni: self.sni.clone(),
+            skip_verification: self.skip_verification,
+            ca_certificates,
+            client_certificate,
+            client_key,
+            alpn_protocols: self.alpn_protocols.clone(),
+        })
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -546,6 +1206,12 @@ pub struct BackendConfig {
     pub sticky_id: Option<String>,
     pub backup: Option<bool>,
     pub backend_id: Option<String>,
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub tls: Option<BackendTls>,
 }
 
 impl FileClusterConfig {
@@ -554,6 +1220,16 @@ impl FileClusterConfig {
         cluster_id: &str,
         expect_proxy: &HashSet<SocketAddr>,
     ) -> anyhow::Result<ClusterConfig> {
+        let mut backends = Vec::new();
+        for backend in self.backends {
+            backends.push(backend.to_backend_config()?);
+        }
+
+        let backend_tls = match self.backend_tls {
+            None => None,
+            Some(tls) => Some(tls.to_backend_tls()?),
+        };
+
         match self.protocol {
             FileClusterProtocolConfig::Tcp => {
                 let mut has_expect_proxy = None;
@@ -591,16 +1267,35 @@ impl FileClusterConfig {
                     _ => None,
                 };
 
-                Ok(ClusterConfig::Tcp(TcpClusterConfig {
+                Ok(ClusterConfig::Tcp(Box::new(TcpClusterConfig {
                     cluster_id: cluster_id.to_string(),
                     frontends,
-                    backends: self.backends,
+                    backends,
                     proxy_protocol,
                     load_balancing: self.load_balancing,
                     load_metric: self.load_metric,
-                }))
+                    health_check: self.health_check,
+                    outlier_detection: self.outlier_detection,
+                    affinity_table: self.affinity_table,
+                    max_sessions: self.max_sessions,
+                })))
             }
             FileClusterProtocolConfig::Http => {
+                for action in &self.header_actions {
+                    if let Err(e) = action.validate() {
+                        bail!(format!(
+                            "invalid header action for cluster {}: {}",
+                            cluster_id, e
+                        ));
+                    }
+                }
+                if let Err(e) = self.host_rewrite.validate() {
+                    bail!(format!(
+                        "invalid host rewrite for cluster {}: {}",
+                        cluster_id, e
+                    ));
+                }
+
                 let mut frontends = Vec::new();
                 for frontend in self.frontends {
                     let http_frontend = frontend.to_http_front(cluster_id)?;
@@ -616,16 +1311,35 @@ impl FileClusterConfig {
                         .ok()
                 });
 
-                Ok(ClusterConfig::Http(HttpClusterConfig {
+                Ok(ClusterConfig::Http(Box::new(HttpClusterConfig {
                     cluster_id: cluster_id.to_string(),
                     frontends,
-                    backends: self.backends,
+                    backends,
                     sticky_session: self.sticky_session.unwrap_or(false),
+                    sticky_mode: self.sticky_mode,
                     https_redirect: self.https_redirect.unwrap_or(false),
                     load_balancing: self.load_balancing,
+                    hash_key: self.hash_key,
+                    health_check: self.health_check,
+                    outlier_detection: self.outlier_detection,
+                    request_queue: self.request_queue,
+                    affinity_table: self.affinity_table,
+                    max_sessions: self.max_sessions,
                     load_metric: self.load_metric,
                     answer_503,
-                }))
+                    header_actions: self.header_actions,
+                    host_rewrite: self.host_rewrite,
+                    request_limits: self.request_limits,
+                    compression: self.compression,
+                    security_headers: self.security_headers,
+                    request_retries: self.request_retries,
+                    timeouts: self.timeouts,
+                    backend_tls,
+                    backend_protocol: self.backend_protocol,
+                    websocket_drain: self.websocket_drain,
+                    streaming: self.streaming,
+                    upgrade_protocols: self.upgrade_protocols,
+                })))
             }
         }
     }
@@ -638,9 +1352,20 @@ pub struct HttpFrontendConfig {
     pub hostname: String,
     pub path: PathRule,
     pub method: Option<String>,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub reject_other_methods: bool,
+    #[serde(default)]
+    pub headers: Vec<HeaderRule>,
+    pub rewrite_path: Option<PathRewrite>,
+    pub mirror_cluster_id: Option<String>,
     pub certificate: Option<String>,
     pub key: Option<String>,
     pub certificate_chain: Option<Vec<String>>,
+    pub ocsp_response: Option<String>,
+    #[serde(default)]
+    pub certificate_priority: i32,
     #[serde(default)]
     pub tls_versions: Vec<TlsVersion>,
     #[serde(default)]
@@ -660,6 +1385,8 @@ impl HttpFrontendConfig {
                     certificate: self.certificate.clone().unwrap(),
                     certificate_chain: self.certificate_chain.clone().unwrap_or_default(),
                     versions: self.tls_versions.clone(),
+                    ocsp_response: self.ocsp_response.clone(),
+                    priority: self.certificate_priority,
                 },
                 names: vec![self.hostname.clone()],
                 expired_at: None,
@@ -671,6 +1398,11 @@ impl HttpFrontendConfig {
                 hostname: self.hostname.clone(),
                 path: self.path.clone(),
                 method: self.method.clone(),
+                methods: self.methods.clone(),
+                reject_other_methods: self.reject_other_methods,
+                headers: self.headers.clone(),
+                rewrite_path: self.rewrite_path.clone(),
+                mirror_cluster_id: self.mirror_cluster_id.clone(),
                 position: self.position,
                 tags: self.tags.clone(),
             }));
@@ -682,6 +1414,11 @@ impl HttpFrontendConfig {
                 hostname: self.hostname.clone(),
                 path: self.path.clone(),
                 method: self.method.clone(),
+                methods: self.methods.clone(),
+                reject_other_methods: self.reject_other_methods,
+                headers: self.headers.clone(),
+                rewrite_path: self.rewrite_path.clone(),
+                mirror_cluster_id: self.mirror_cluster_id.clone(),
                 position: self.position,
                 tags: self.tags.clone(),
             }));
@@ -698,22 +1435,79 @@ pub struct HttpClusterConfig {
     pub frontends: Vec<HttpFrontendConfig>,
     pub backends: Vec<BackendConfig>,
     pub sticky_session: bool,
+    #[serde(default)]
+    pub sticky_mode: StickyMode,
     pub https_redirect: bool,
     pub load_balancing: LoadBalancingAlgorithms,
+    #[serde(default)]
+    pub hash_key: HashKey,
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+    #[serde(default)]
+    pub outlier_detection: Option<OutlierDetection>,
+    #[serde(default)]
+    pub request_queue: Option<RequestQueue>,
+    #[serde(default)]
+    pub affinity_table: Option<AffinityTable>,
+    #[serde(default)]
+    pub max_sessions: Option<usize>,
     pub load_metric: Option<LoadMetric>,
     pub answer_503: Option<String>,
-}
-
-impl HttpClusterConfig {
-    pub fn generate_orders(&self) -> Vec<ProxyRequestOrder> {
-        let mut v = vec![ProxyRequestOrder::AddCluster(Cluster {
+    #[serde(default)]
+    pub header_actions: Vec<HeaderAction>,
+    #[serde(default)]
+    pub host_rewrite: HostRewrite,
+    #[serde(default)]
+    pub request_limits: RequestLimits,
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default)]
+    pub security_headers: SecurityHeaders,
+    #[serde(default)]
+    pub request_retries: RequestRetries,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub backend_tls: Option<BackendTls>,
+    #[serde(default)]
+    pub backend_protocol: BackendProtocol,
+    #[serde(default)]
+    pub websocket_drain: WebSocketDrain,
+    #[serde(default)]
+    pub streaming: bool,
+    #[serde(default)]
+    pub upgrade_protocols: Vec<String>,
+}
+
+impl HttpClusterConfig {
+    pub fn generate_orders(&self) -> Vec<ProxyRequestOrder> {
+        let mut v = vec![ProxyRequestOrder::AddCluster(Cluster {
             cluster_id: self.cluster_id.clone(),
             sticky_session: self.sticky_session,
+            sticky_mode: self.sticky_mode.clone(),
             https_redirect: self.https_redirect,
             proxy_protocol: None,
             load_balancing: self.load_balancing,
+            hash_key: self.hash_key.clone(),
+            health_check: self.health_check.clone(),
+            outlier_detection: self.outlier_detection,
+            request_queue: self.request_queue,
+            affinity_table: self.affinity_table,
+            max_sessions: self.max_sessions,
             answer_503: self.answer_503.clone(),
             load_metric: self.load_metric,
+            header_actions: self.header_actions.clone(),
+            host_rewrite: self.host_rewrite.clone(),
+            request_limits: self.request_limits,
+            compression: self.compression,
+            security_headers: self.security_headers.clone(),
+            request_retries: self.request_retries.clone(),
+            timeouts: self.timeouts,
+            backend_tls: self.backend_tls.clone().map(Box::new),
+            backend_protocol: self.backend_protocol,
+            websocket_drain: self.websocket_drain,
+            streaming: self.streaming,
+            upgrade_protocols: self.upgrade_protocols.clone(),
         })];
 
         for frontend in &self.frontends {
@@ -735,6 +1529,9 @@ impl HttpClusterConfig {
                 load_balancing_parameters,
                 sticky_id: backend.sticky_id.clone(),
                 backup: backend.backup,
+                max_connections: backend.max_connections,
+                timeouts: backend.timeouts,
+                tls: backend.tls.clone().map(Box::new),
             }));
         }
 
@@ -745,6 +1542,8 @@ impl HttpClusterConfig {
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct TcpFrontendConfig {
     pub address: SocketAddr,
+    /// routes the TLS connections with this server name, without terminating TLS
+    pub hostname: Option<String>,
     pub tags: Option<BTreeMap<String, String>>,
 }
 
@@ -757,6 +1556,14 @@ pub struct TcpClusterConfig {
     pub proxy_protocol: Option<ProxyProtocolConfig>,
     pub load_balancing: LoadBalancingAlgorithms,
     pub load_metric: Option<LoadMetric>,
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+    #[serde(default)]
+    pub outlier_detection: Option<OutlierDetection>,
+    #[serde(default)]
+    pub affinity_table: Option<AffinityTable>,
+    #[serde(default)]
+    pub max_sessions: Option<usize>,
 }
 
 impl TcpClusterConfig {
@@ -764,19 +1571,45 @@ impl TcpClusterConfig {
         let mut v = vec![ProxyRequestOrder::AddCluster(Cluster {
             cluster_id: self.cluster_id.clone(),
             sticky_session: false,
+            sticky_mode: StickyMode::Cookie,
             https_redirect: false,
             proxy_protocol: self.proxy_protocol.clone(),
             load_balancing: self.load_balancing,
+            hash_key: HashKey::SourceIp,
+            health_check: self.health_check.clone(),
+            outlier_detection: self.outlier_detection,
+            request_queue: None,
+            affinity_table: self.affinity_table,
+            max_sessions: self.max_sessions,
             load_metric: self.load_metric,
             answer_503: None,
+            header_actions: Vec::new(),
+            host_rewrite: HostRewrite::Preserve,
+            request_limits: RequestLimits::default(),
+            compression: Compression::default(),
+            security_headers: SecurityHeaders::default(),
+            request_retries: RequestRetries::default(),
+            timeouts: Timeouts::default(),
+            backend_tls: None,
+            backend_protocol: BackendProtocol::Http1,
+            websocket_drain: WebSocketDrain::default(),
+            streaming: false,
+            upgrade_protocols: Vec::new(),
         })];
 
         for frontend in &self.frontends {
-            v.push(ProxyRequestOrder::AddTcpFrontend(TcpFrontend {
-                cluster_id: self.cluster_id.clone(),
-                address: frontend.address,
-                tags: frontend.tags.clone(),
-            }));
+            match &frontend.hostname {
+                Some(hostname) => v.push(ProxyRequestOrder::AddSniFrontend(SniFrontend {
+                    cluster_id: self.cluster_id.clone(),
+                    address: frontend.address,
+                    hostname: hostname.to_owned(),
+                })),
+                None => v.push(ProxyRequestOrder::AddTcpFrontend(TcpFrontend {
+                    cluster_id: self.cluster_id.clone(),
+                    address: frontend.address,
+                    tags: frontend.tags.clone(),
+                })),
+            }
         }
 
         for (backend_count, backend) in self.backends.iter().enumerate() {
@@ -793,6 +1626,9 @@ impl TcpClusterConfig {
                 load_balancing_parameters,
                 sticky_id: backend.sticky_id.clone(),
                 backup: backend.backup,
+                max_connections: backend.max_connections,
+                timeouts: backend.timeouts,
+                tls: backend.tls.clone().map(Box::new),
             }));
         }
 
@@ -802,8 +1638,8 @@ impl TcpClusterConfig {
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum ClusterConfig {
-    Http(HttpClusterConfig),
-    Tcp(TcpClusterConfig),
+    Http(Box<HttpClusterConfig>),
+    Tcp(Box<TcpClusterConfig>),
 }
 
 impl ClusterConfig {
@@ -821,18 +1657,30 @@ pub struct FileConfig {
     pub command_buffer_size: Option<usize>,
     pub max_command_buffer_size: Option<usize>,
     pub max_connections: Option<usize>,
+    #[serde(default)]
+    pub max_sessions: Option<usize>,
     pub min_buffers: Option<usize>,
     pub max_buffers: Option<usize>,
     pub buffer_size: Option<usize>,
     pub saved_state: Option<String>,
     #[serde(default)]
     pub automatic_state_save: Option<bool>,
+    #[serde(default)]
+    pub state_file: Option<String>,
+    #[serde(default)]
+    pub save_state_interval: Option<u64>,
     pub log_level: Option<String>,
     pub log_target: Option<String>,
     #[serde(default)]
     pub log_access_target: Option<String>,
     pub worker_count: Option<u16>,
     pub worker_automatic_restart: Option<bool>,
+    #[serde(default)]
+    pub worker_restart_delay: Option<u64>,
+    #[serde(default)]
+    pub worker_max_restarts: Option<u32>,
+    #[serde(default)]
+    pub worker_restart_window: Option<u64>,
     pub metrics: Option<MetricsConfig>,
     pub listeners: Option<Vec<Listener>>,
     pub clusters: Option<HashMap<String, FileClusterConfig>>,
@@ -851,9 +1699,55 @@ pub struct FileConfig {
     #[serde(default)]
     pub zombie_check_interval: Option<u32>,
     #[serde(default)]
+    pub ocsp_refresh_interval: Option<u32>,
+    #[serde(default)]
+    pub certificate_expiration_threshold: Option<u32>,
+    #[serde(default)]
+    pub strict_certificate_validation: Option<bool>,
+    #[serde(default)]
+    pub ticket_keys_rotation_interval: Option<u32>,
+    #[serde(default)]
     pub accept_queue_timeout: Option<u32>,
     #[serde(default)]
     pub request_timeout: Option<u32>,
+    #[serde(default)]
+    pub discovery: Option<DiscoveryConfig>,
+    #[serde(default)]
+    pub command_roles: Option<Vec<CommandRole>>,
+    #[serde(default)]
+    pub command_remote: Option<RemoteCommandConfig>,
+    #[serde(default)]
+    pub command_authorization: Option<CommandAuthorizationConfig>,
+    #[serde(default)]
+    pub audit_log: Option<AuditLogConfig>,
+    #[serde(default)]
+    pub state_history_size: Option<usize>,
+    #[serde(default)]
+    pub peering: Option<PeeringConfig>,
+    #[serde(default)]
+    pub worker_cpu_affinity: Option<WorkerCpuAffinity>,
+    #[serde(default)]
+    pub worker_max_open_files: Option<u64>,
+    #[serde(default)]
+    pub worker_max_memory: Option<u64>,
+    #[serde(default)]
+    pub worker_memory_recycle: Option<bool>,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub chroot: Option<String>,
+    #[serde(default)]
+    pub worker_sandbox: Option<bool>,
+    #[serde(default)]
+    pub upgrade_timeout: Option<u64>,
+    #[serde(default)]
+    pub worker_heartbeat_interval: Option<u64>,
+    #[serde(default)]
+    pub worker_heartbeat_misses: Option<u32>,
+    #[serde(default)]
+    pub worker_not_answering_policy: Option<NotAnsweringPolicy>,
 }
 
 impl FileConfig {
@@ -955,6 +1849,7 @@ impl FileConfig {
                                 self.back_timeout,
                                 self.connect_timeout,
                                 self.request_timeout,
+                                self.tls_provider,
                             )
                             .with_context(|| "invalid listener")?;
                         https_listeners.push(listener);
@@ -970,7 +1865,7 @@ impl FileConfig {
                             .with_context(|| "invalid listener")?;
                         http_listeners.push(listener);
                     }
-                    FileListenerProtocolConfig::Tcp => {
+                    FileListenerProtocolConfig::Tcp | FileListenerProtocolConfig::Forward => {
                         let listener = listener
                             .to_tcp(self.front_timeout, self.back_timeout, self.connect_timeout)
                             .with_context(|| "invalid listener")?;
@@ -992,7 +1887,8 @@ impl FileConfig {
                     ClusterConfig::Http(ref http) => {
                         for frontend in http.frontends.iter() {
                             match known_addresses.get(&frontend.address) {
-                                Some(FileListenerProtocolConfig::Tcp) => {
+                                Some(FileListenerProtocolConfig::Tcp)
+                                | Some(FileListenerProtocolConfig::Forward) => {
                                     bail!(
                                         "cannot set up a HTTP or HTTPS frontend on a TCP listener"
                                     );
@@ -1023,6 +1919,7 @@ impl FileConfig {
                                                     self.back_timeout,
                                                     self.connect_timeout,
                                                     self.request_timeout,
+                                                    self.tls_provider,
                                                 )
                                                 .with_context(|| {
                                                     "Cannot convert listener to TLS"
@@ -1065,6 +1962,11 @@ impl FileConfig {
                                     bail!("cannot set up a TCP frontend on a HTTP listener");
                                 }
                                 Some(FileListenerProtocolConfig::Tcp) => {}
+                                Some(FileListenerProtocolConfig::Forward) => {
+                                    bail!(
+                                        "cannot set up a TCP frontend on a forward proxy listener"
+                                    );
+                                }
                                 None => {
                                     // create a default listener for that front
                                     let listener = Listener::new(
@@ -1104,6 +2006,101 @@ impl FileConfig {
         if let (None, Some(true)) = (&self.saved_state, &self.automatic_state_save) {
             bail!("cannot activate automatic state save if the 'saved_state` option is not set");
         }
+        if self.state_file.is_some() && self.saved_state.is_some() {
+            bail!("the 'state_file' and 'saved_state' options cannot be set together");
+        }
+        if let (None, Some(1..)) = (&self.state_file, self.save_state_interval) {
+            bail!("cannot save the state periodically if the 'state_file' option is not set");
+        }
+
+        if let Some(remote) = &self.command_remote {
+            if remote.token.is_empty() || remote.roles.iter().any(|role| role.token.is_empty()) {
+                bail!("the tokens of the remote command channel cannot be empty");
+            }
+            if remote.max_clients == 0 {
+                bail!("the remote command channel needs at least one client");
+            }
+        }
+
+        if let Some(authorization) = &self.command_authorization {
+            match (&authorization.command, &authorization.socket) {
+                (Some(command), None) if command.is_empty() => {
+                    bail!("the command of the command authorization cannot be empty")
+                }
+                (Some(_), None) | (None, Some(_)) => {}
+                _ => bail!("the command authorization needs either a command or a socket"),
+            }
+        }
+
+        if let Some(audit_log) = &self.audit_log {
+            if audit_log.path.is_empty() {
+                bail!("the path of the audit log cannot be empty");
+            }
+            if audit_log.max_size == 0 {
+                bail!("the maximum size of the audit log cannot be 0");
+            }
+        }
+
+        if let Some(WorkerCpuAffinity::Cores(cores)) = &self.worker_cpu_affinity {
+            if cores.is_empty() || cores.iter().any(|cores| cores.is_empty()) {
+                bail!("the CPU cores of the workers cannot be empty");
+            }
+            let core_count = core_count();
+            if let Some(core) = cores.iter().flatten().find(|core| **core >= core_count) {
+                bail!(
+                    "the CPU core {} of the workers does not exist, there are {} cores",
+                    core,
+                    core_count
+                );
+            }
+        }
+
+        if let Some(max_open_files) = self.worker_max_open_files {
+            let max_connections = self.max_connections.unwrap_or(10000) as u64;
+            if max_open_files < max_connections * 2 {
+                bail!(
+                    "worker_max_open_files is {}, the workers need two file descriptors for each of the {} connections",
+                    max_open_files,
+                    max_connections
+                );
+            }
+        }
+        if self.worker_max_memory == Some(0) {
+            bail!("worker_max_memory cannot be 0");
+        }
+        if self.worker_memory_recycle == Some(true) && self.worker_max_memory.is_none() {
+            bail!("worker_memory_recycle needs worker_max_memory");
+        }
+
+        if self.user.is_none() && (self.group.is_some() || self.chroot.is_some()) {
+            bail!("the group and chroot of the workers need a user to switch to");
+        }
+        if let Some(chroot) = &self.chroot {
+            if !Path::new(chroot).is_absolute() {
+                bail!("the chroot directory {} is not an absolute path", chroot);
+            }
+        }
+
+        if let Some(peering) = &self.peering {
+            if self.command_remote.is_none() {
+                bail!(
+                    "peering needs the remote command channel, to receive the changes of the peers"
+                );
+            }
+            if peering.name.is_empty() {
+                bail!("the name of this instance in the peering group cannot be empty");
+            }
+            if let Some(peer) = peering.peers.iter().find(|peer| peer.token.is_empty()) {
+                bail!("the token of the peer {} cannot be empty", peer.address);
+            }
+        }
+
+        if self.upgrade_timeout == Some(0) {
+            bail!("upgrade_timeout cannot be 0");
+        }
+        if self.worker_heartbeat_misses == Some(0) {
+            bail!("worker_heartbeat_misses cannot be 0");
+        }
 
         Ok(Config {
             config_path: config_path.to_string(),
@@ -1113,6 +2110,7 @@ impl FileConfig {
                 .max_command_buffer_size
                 .unwrap_or(self.command_buffer_size.unwrap_or(1_000_000) * 2),
             max_connections: self.max_connections.unwrap_or(10000),
+            max_sessions: self.max_sessions,
             min_buffers: std::cmp::min(
                 self.min_buffers.unwrap_or(1),
                 self.max_buffers.unwrap_or(1000),
@@ -1121,11 +2119,16 @@ impl FileConfig {
             buffer_size: self.buffer_size.unwrap_or(16393),
             saved_state: self.saved_state,
             automatic_state_save: self.automatic_state_save.unwrap_or(false),
+            state_file: self.state_file,
+            save_state_interval: self.save_state_interval.unwrap_or(60),
             log_level: self.log_level.unwrap_or_else(|| String::from("info")),
             log_target: self.log_target.unwrap_or_else(|| String::from("stdout")),
             log_access_target: self.log_access_target,
             worker_count: self.worker_count.unwrap_or(2),
             worker_automatic_restart: self.worker_automatic_restart.unwrap_or(true),
+            worker_restart_delay: self.worker_restart_delay.unwrap_or(500),
+            worker_max_restarts: self.worker_max_restarts.unwrap_or(5),
+            worker_restart_window: self.worker_restart_window.unwrap_or(300),
             metrics: self.metrics,
             http_listeners,
             https_listeners,
@@ -1134,18 +2137,540 @@ impl FileConfig {
             handle_process_affinity: self.handle_process_affinity.unwrap_or(false),
             ctl_command_timeout: self.ctl_command_timeout.unwrap_or(1_000),
             pid_file_path: self.pid_file_path,
-            tls_provider: self.tls_provider.clone().unwrap_or_default(),
+            tls_provider: self.tls_provider.unwrap_or_default(),
             activate_listeners: self.activate_listeners.unwrap_or(true),
             front_timeout: self.front_timeout.unwrap_or(60),
             back_timeout: self.front_timeout.unwrap_or(30),
             connect_timeout: self.front_timeout.unwrap_or(3),
             //defaults to 30mn
             zombie_check_interval: self.zombie_check_interval.unwrap_or(30 * 60),
+            ocsp_refresh_interval: self.ocsp_refresh_interval,
+            //defaults to 30 days
+            certificate_expiration_threshold: self.certificate_expiration_threshold.unwrap_or(30),
+            strict_certificate_validation: self.strict_certificate_validation.unwrap_or(false),
+            //defaults to 1 hour
+            ticket_keys_rotation_interval: self.ticket_keys_rotation_interval.unwrap_or(3600),
             accept_queue_timeout: self.accept_queue_timeout.unwrap_or(60),
+            discovery: self.discovery,
+            command_roles: self.command_roles.unwrap_or_default(),
+            command_remote: self.command_remote,
+            command_authorization: self.command_authorization,
+            audit_log: self.audit_log,
+            state_history_size: self.state_history_size.unwrap_or(20),
+            peering: self.peering,
+            worker_cpu_affinity: self.worker_cpu_affinity,
+            worker_max_open_files: self.worker_max_open_files,
+            worker_max_memory: self.worker_max_memory,
+            worker_memory_recycle: self.worker_memory_recycle.unwrap_or(false),
+            user: self.user,
+            group: self.group,
+            chroot: self.chroot,
+            worker_sandbox: self.worker_sandbox.unwrap_or(false),
+            upgrade_timeout: self.upgrade_timeout.unwrap_or(30),
+            worker_heartbeat_interval: self.worker_heartbeat_interval.unwrap_or(10),
+            worker_heartbeat_misses: self.worker_heartbeat_misses.unwrap_or(3),
+            worker_not_answering_policy: self.worker_not_answering_policy.unwrap_or_default(),
         })
     }
 }
 
+impl FileConfig {
+    /// replaces the listeners and clusters of this configuration with the ones of
+    /// the state. The certificates, keys and custom answers are written to files
+    /// in `directory`, and referenced by their paths. Returns what the state holds
+    /// that a configuration file cannot express
+    pub fn set_state(
+        &mut self,
+        state: &ConfigState,
+        directory: &Path,
+    ) -> anyhow::Result<Vec<String>> {
+        fs::create_dir_all(directory)
+            .with_context(|| format!("could not create the directory {}", directory.display()))?;
+        let files = ExportDirectory {
+            path: directory
+                .canonicalize()
+                .with_context(|| format!("invalid directory {}", directory.display()))?,
+        };
+        let mut skipped = Vec::new();
+
+        let mut listeners = Vec::new();
+        for (listener, _) in state.http_listeners.values() {
+            listeners.push(files.http_listener(listener)?);
+        }
+        for (listener, _) in state.https_listeners.values() {
+            listeners.push(files.https_listener(listener, state)?);
+        }
+        for (listener, _) in state.tcp_listeners.values() {
+            listeners.push(files.tcp_listener(listener));
+        }
+        listeners.sort_by_key(|listener| listener.address);
+
+        let mut clusters = HashMap::new();
+        // certificates referenced by the configuration, by listener
+        let mut exported_certificates = HashSet::new();
+        for (cluster_id, cluster) in state.clusters.iter() {
+            let cluster_config =
+                files.cluster(cluster, state, &mut exported_certificates, &mut skipped)?;
+            clusters.insert(cluster_id.clone(), cluster_config);
+        }
+
+        for front in state
+            .http_fronts
+            .values()
+            .chain(state.https_fronts.values())
+        {
+            if !matches!(front.route, Route::ClusterId(_)) {
+                skipped.push(format!(
+                    "the frontend {}{:?} on {} routed to {:?}: only frontends of a cluster can be configured",
+                    front.hostname, front.path, front.address, front.route
+                ));
+            }
+        }
+        for (address, certificates) in state.certificates.iter() {
+            for (fingerprint, _) in certificates.iter() {
+                let default_certificate = state
+                    .https_listeners
+                    .get(address)
+                    .and_then(|(listener, _)| listener.default_certificate.as_ref())
+                    == Some(fingerprint);
+                if !default_certificate
+                    && !exported_certificates.contains(&(*address, fingerprint.clone()))
+                {
+                    skipped.push(format!(
+                        "the certificate {} on {}: it covers no HTTPS frontend",
+                        fingerprint, address
+                    ));
+                }
+            }
+        }
+        if !state.acls.is_empty() {
+            skipped.push(String::from("the source IP access control lists"));
+        }
+        for cluster_id in state.maintenance.keys() {
+            skipped.push(format!("the maintenance of the cluster {}", cluster_id));
+        }
+        if !state.worker_scopes.is_empty() {
+            skipped.push(String::from(
+                "the listeners handled by some of the workers only, the configuration gives them to every worker",
+            ));
+        }
+
+        self.listeners = Some(listeners);
+        self.clusters = Some(clusters);
+        Ok(skipped)
+    }
+
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        // serializing through a value places the tables after the other fields
+        let value =
+            toml::Value::try_from(self).with_context(|| "could not serialize the configuration")?;
+        toml::to_string(&value).with_context(|| "could not serialize the configuration")
+    }
+}
+
+/// the directory receiving the certificates, keys and answers of a state
+/// exported as a configuration file
+struct ExportDirectory {
+    path: PathBuf,
+}
+
+impl ExportDirectory {
+    /// writes the file, returns its path
+    fn write(&self, name: &str, content: &[u8], private: bool) -> anyhow::Result<String> {
+        let name: String = name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let path = self.path.join(name);
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        if private {
+            options.mode(0o600);
+        }
+        options
+            .open(&path)
+            .and_then(|mut file| file.write_all(content))
+            .with_context(|| format!("could not write {}", path.display()))?;
+
+        Ok(path.to_string_lossy().into_owned())
+    }
+
+    /// the answers of the listeners are only written if they are not the default ones
+    fn answer(&self, answer: &str, default: &str, name: &str) -> anyhow::Result<Option<String>> {
+        if answer == default {
+            return Ok(None);
+        }
+        self.write(name, answer.as_bytes(), false).map(Some)
+    }
+
+    fn certificate(
+        &self,
+        certificate_and_key: &CertificateAndKey,
+    ) -> anyhow::Result<ExportedCertificate> {
+        let fingerprint = CertificateFingerprint(calculate_fingerprint(
+            certificate_and_key.certificate.as_bytes(),
+        )?);
+
+        let certificate = self.write(
+            &format!("{}.pem", fingerprint),
+            certificate_and_key.certificate.as_bytes(),
+            false,
+        )?;
+        let key = self.write(
+            &format!("{}.key.pem", fingerprint),
+            certificate_and_key.key.as_bytes(),
+            true,
+        )?;
+        let certificate_chain = match certificate_and_key.certificate_chain.is_empty() {
+            true => None,
+            false => Some(self.write(
+                &format!("{}.chain.pem", fingerprint),
+                certificate_and_key.certificate_chain.join("\n").as_bytes(),
+                false,
+            )?),
+        };
+        let ocsp_response = match &certificate_and_key.ocsp_response {
+            None => None,
+            Some(ocsp_response) => Some(self.write(
+                &format!("{}.ocsp.der", fingerprint),
+                &hex::decode(ocsp_response).with_context(|| "invalid OCSP response")?,
+                false,
+            )?),
+        };
+
+        Ok(ExportedCertificate {
+            fingerprint,
+            certificate,
+            key,
+            certificate_chain,
+            ocsp_response,
+        })
+    }
+
+    fn http_listener(&self, listener: &HttpListener) -> anyhow::Result<Listener> {
+        let name = listener.address.to_string();
+        let mut config = Listener::new(listener.address, FileListenerProtocolConfig::Http);
+        config.public_address = listener.public_address;
+        config.answer_404 = self.answer(
+            &listener.answer_404,
+            include_str!("../assets/404.html"),
+            &format!("{}.404.html", name),
+        )?;
+        config.answer_503 = self.answer(
+            &listener.answer_503,
+            include_str!("../assets/503.html"),
+            &format!("{}.503.html", name),
+        )?;
+        config.expect_proxy = Some(listener.expect_proxy);
+        config.sticky_name = listener.sticky_name.clone();
+        config.front_timeout = Some(listener.front_timeout);
+        config.back_timeout = Some(listener.back_timeout);
+        config.connect_timeout = Some(listener.connect_timeout);
+        config.request_timeout = Some(listener.request_timeout);
+        config.request_limits = listener.request_limits;
+        config.compression = listener.compression;
+        config.path_normalization = listener.path_normalization;
+        config.fallback_cluster = listener.fallback_cluster.clone();
+        config.max_sessions = listener.max_sessions;
+        Ok(config)
+    }
+
+    fn https_listener(
+        &self,
+        listener: &HttpsListener,
+        state: &ConfigState,
+    ) -> anyhow::Result<Listener> {
+        let name = listener.address.to_string();
+        let mut config = Listener::new(listener.address, FileListenerProtocolConfig::Https);
+        config.public_address = listener.public_address;
+        config.answer_404 = self.answer(
+            &listener.answer_404,
+            include_str!("../assets/404.html"),
+            &format!("{}.404.html", name),
+        )?;
+        config.answer_503 = self.answer(
+            &listener.answer_503,
+            include_str!("../assets/503.html"),
+            &format!("{}.503.html", name),
+        )?;
+        config.tls_provider = Some(listener.tls_provider);
+        config.tls_versions = Some(listener.versions.clone());
+        config.cipher_list = Some(listener.cipher_list.clone());
+        config.cipher_suites = Some(listener.cipher_suites.clone());
+        config.signature_algorithms = Some(listener.signature_algorithms.clone());
+        config.groups_list = Some(listener.groups_list.clone());
+        config.expect_proxy = Some(listener.expect_proxy);
+        config.sticky_name = listener.sticky_name.clone();
+        config.certificate = match &listener.certificate {
+            Some(certificate) => Some(self.write(
+                &format!("{}.certificate.pem", name),
+                certificate.as_bytes(),
+                false,
+            )?),
+            None => None,
+        };
+        if !listener.certificate_chain.is_empty() {
+            config.certificate_chain = Some(self.write(
+                &format!("{}.chain.pem", name),
+                listener.certificate_chain.join("\n").as_bytes(),
+                false,
+            )?);
+        }
+        config.key = match &listener.key {
+            Some(key) => Some(self.write(&format!("{}.key.pem", name), key.as_bytes(), true)?),
+            None => None,
+        };
+        config.front_timeout = Some(listener.front_timeout);
+        config.back_timeout = Some(listener.back_timeout);
+        config.connect_timeout = Some(listener.connect_timeout);
+        config.request_timeout = Some(listener.request_timeout);
+        config.request_limits = listener.request_limits;
+        config.compression = listener.compression;
+        config.path_normalization = listener.path_normalization;
+        config.fallback_cluster = listener.fallback_cluster.clone();
+        config.client_auth = match &listener.client_auth {
+            Some(client_auth) => Some(FileClientAuthConfig {
+                required: client_auth.required,
+                ca: self.write(
+                    &format!("{}.client-ca.pem", name),
+                    client_auth.ca_certificates.as_bytes(),
+                    false,
+                )?,
+                crl: match &client_auth.revocation_lists {
+                    Some(revocation_lists) => Some(self.write(
+                        &format!("{}.client-crl.pem", name),
+                        revocation_lists.as_bytes(),
+                        false,
+                    )?),
+                    None => None,
+                },
+                dn_header: Some(client_auth.dn_header.clone()),
+            }),
+            None => None,
+        };
+        config.default_certificate =
+            match listener
+                .default_certificate
+                .as_ref()
+                .and_then(|fingerprint| {
+                    state
+                        .certificates
+                        .get(&listener.address)
+                        .and_then(|certificates| certificates.get(fingerprint))
+                }) {
+                Some((certificate_and_key, _)) => {
+                    Some(self.certificate(certificate_and_key)?.certificate)
+                }
+                None => None,
+            };
+        config.strict_sni = Some(listener.strict_sni);
+        config.handshake_timeout = listener.handshake_timeout;
+        config.security_headers = (*listener.security_headers).clone();
+        config.http2 = listener.http2;
+        config.max_sessions = listener.max_sessions;
+        Ok(config)
+    }
+
+    fn tcp_listener(&self, listener: &TcpListener) -> Listener {
+        let protocol = match listener.forward_proxy {
+            Some(_) => FileListenerProtocolConfig::Forward,
+            None => FileListenerProtocolConfig::Tcp,
+        };
+        let mut config = Listener::new(listener.address, protocol);
+        config.public_address = listener.public_address;
+        config.expect_proxy = Some(listener.expect_proxy);
+        config.front_timeout = Some(listener.front_timeout);
+        config.back_timeout = Some(listener.back_timeout);
+        config.connect_timeout = Some(listener.connect_timeout);
+        if let Some(forward_proxy) = &listener.forward_proxy {
+            config.allowed_destinations = Some(forward_proxy.allowed_destinations.clone());
+            config.socks5 = Some(forward_proxy.socks5);
+        }
+        config.max_sessions = listener.max_sessions;
+        config
+    }
+
+    fn backend_tls(&self, tls: &BackendTls, name: &str) -> anyhow::Result<FileBackendTlsConfig> {
+        let write = |suffix: &str, content: &Option<String>, private: bool| match content {
+            Some(content) => self
+                .write(&format!("{}.{}", name, suffix), content.as_bytes(), private)
+                .map(Some),
+            None => Ok(None),
+        };
+
+        Ok(FileBackendTlsConfig {
+            sni: tls.sni.clone(),
+            skip_verification: tls.skip_verification,
+            ca_certificates: write("backend-ca.pem", &tls.ca_certificates, false)?,
+            client_certificate: write("backend-certificate.pem", &tls.client_certificate, false)?,
+            client_key: write("backend-key.pem", &tls.client_key, true)?,
+            alpn_protocols: tls.alpn_protocols.clone(),
+        })
+    }
+
+    fn cluster(
+        &self,
+        cluster: &Cluster,
+        state: &ConfigState,
+        exported_certificates: &mut HashSet<(SocketAddr, CertificateFingerprint)>,
+        skipped: &mut Vec<String>,
+    ) -> anyhow::Result<FileClusterConfig> {
+        let cluster_id = &cluster.cluster_id;
+        let cluster_state = state.cluster_state(cluster_id);
+
+        let mut frontends = Vec::new();
+        for front in cluster_state.http_frontends.iter() {
+            frontends.push(frontend_config(front));
+        }
+        for front in cluster_state.https_frontends.iter() {
+            let certificate_and_key =
+                match state.covering_certificate(&front.address, &front.hostname) {
+                    Some(certificate_and_key) => certificate_and_key,
+                    None => {
+                        skipped.push(format!(
+                        "the HTTPS frontend {} on {} of the cluster {}: no certificate covers it",
+                        front.hostname, front.address, cluster_id
+                    ));
+                        continue;
+                    }
+                };
+            let certificate = self.certificate(certificate_and_key)?;
+            exported_certificates.insert((front.address, certificate.fingerprint));
+
+            let mut frontend = frontend_config(front);
+            frontend.certificate = Some(certificate.certificate);
+            frontend.key = Some(certificate.key);
+            frontend.certificate_chain = certificate.certificate_chain;
+            frontend.ocsp_response = certificate.ocsp_response;
+            frontend.certificate_priority = Some(certificate_and_key.priority);
+            frontend.tls_versions = certificate_and_key.versions.clone();
+            frontends.push(frontend);
+        }
+        for front in cluster_state.tcp_frontends.iter() {
+            let mut frontend = FileClusterFrontendConfig::new(front.address);
+            frontend.tags = front.tags.clone();
+            frontends.push(frontend);
+        }
+        for front in cluster_state.sni_frontends.iter() {
+            let mut frontend = FileClusterFrontendConfig::new(front.address);
+            frontend.hostname = Some(front.hostname.clone());
+            frontends.push(frontend);
+        }
+
+        let mut backends = Vec::new();
+        for backend in cluster_state.backends.iter() {
+            backends.push(FileBackendConfig {
+                address: backend.address,
+                weight: backend
+                    .load_balancing_parameters
+                    .as_ref()
+                    .map(|parameters| parameters.weight),
+                sticky_id: backend.sticky_id.clone(),
+                backup: backend.backup,
+                backend_id: Some(backend.backend_id.clone()),
+                max_connections: backend.max_connections,
+                timeouts: backend.timeouts,
+                tls: match &backend.tls {
+                    Some(tls) => Some(
+                        self.backend_tls(tls, &format!("{}.{}", cluster_id, backend.backend_id))?,
+                    ),
+                    None => None,
+                },
+            });
+        }
+
+        let protocol = match cluster_state.tcp_frontends.is_empty()
+            && cluster_state.sni_frontends.is_empty()
+        {
+            true => FileClusterProtocolConfig::Http,
+            false => FileClusterProtocolConfig::Tcp,
+        };
+
+        Ok(FileClusterConfig {
+            frontends,
+            backends,
+            protocol,
+            sticky_session: Some(cluster.sticky_session),
+            sticky_mode: cluster.sticky_mode.clone(),
+            https_redirect: Some(cluster.https_redirect),
+            send_proxy: Some(matches!(
+                cluster.proxy_protocol,
+                Some(ProxyProtocolConfig::SendHeader) | Some(ProxyProtocolConfig::RelayHeader)
+            )),
+            load_balancing: cluster.load_balancing,
+            hash_key: cluster.hash_key.clone(),
+            health_check: cluster.health_check.clone(),
+            outlier_detection: cluster.outlier_detection,
+            request_queue: cluster.request_queue,
+            affinity_table: cluster.affinity_table,
+            max_sessions: cluster.max_sessions,
+            answer_503: match &cluster.answer_503 {
+                Some(answer) => Some(self.write(
+                    &format!("{}.503.html", cluster_id),
+                    answer.as_bytes(),
+                    false,
+                )?),
+                None => None,
+            },
+            load_metric: cluster.load_metric,
+            header_actions: cluster.header_actions.clone(),
+            host_rewrite: cluster.host_rewrite.clone(),
+            request_limits: cluster.request_limits,
+            compression: cluster.compression,
+            security_headers: cluster.security_headers.clone(),
+            request_retries: cluster.request_retries.clone(),
+            timeouts: cluster.timeouts,
+            backend_tls: match &cluster.backend_tls {
+                Some(tls) => Some(self.backend_tls(tls, cluster_id)?),
+                None => None,
+            },
+            backend_protocol: cluster.backend_protocol,
+            websocket_drain: cluster.websocket_drain,
+            streaming: cluster.streaming,
+            upgrade_protocols: cluster.upgrade_protocols.clone(),
+        })
+    }
+}
+
+/// paths to the files of an exported certificate
+struct ExportedCertificate {
+    fingerprint: CertificateFingerprint,
+    certificate: String,
+    key: String,
+    certificate_chain: Option<String>,
+    ocsp_response: Option<String>,
+}
+
+fn frontend_config(front: &HttpFrontend) -> FileClusterFrontendConfig {
+    let (path, path_type) = match &front.path {
+        PathRule::Prefix(prefix) if prefix.is_empty() => (None, None),
+        PathRule::Prefix(prefix) => (Some(prefix.clone()), Some(PathRuleType::Prefix)),
+        PathRule::Regex(regex) => (Some(regex.clone()), Some(PathRuleType::Regex)),
+        PathRule::Equals(path) => (Some(path.clone()), Some(PathRuleType::Equals)),
+    };
+
+    let mut frontend = FileClusterFrontendConfig::new(front.address);
+    frontend.hostname = Some(front.hostname.clone());
+    frontend.path = path;
+    frontend.path_type = path_type;
+    frontend.method = front.method.clone();
+    frontend.methods = front.methods.clone();
+    frontend.reject_other_methods = front.reject_other_methods;
+    frontend.headers = front.headers.clone();
+    frontend.rewrite_path = front.rewrite_path.clone();
+    frontend.mirror_cluster_id = front.mirror_cluster_id.clone();
+    frontend.position = front.position;
+    frontend.tags = front.tags.clone();
+    frontend
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Config {
     pub config_path: String,
@@ -1153,18 +2678,40 @@ pub struct Config {
     pub command_buffer_size: usize,
     pub max_command_buffer_size: usize,
     pub max_connections: usize,
+    /// client sessions of a worker at most, the next ones are answered with a
+    /// 503 or refused, instead of waiting in the accept queue
+    #[serde(default)]
+    pub max_sessions: Option<usize>,
     pub min_buffers: usize,
     pub max_buffers: usize,
     pub buffer_size: usize,
     pub saved_state: Option<String>,
     #[serde(default)]
     pub automatic_state_save: bool,
+    /// state loaded before the workers are launched, and saved every
+    /// `save_state_interval` seconds. It does not have to exist at startup
+    #[serde(default)]
+    pub state_file: Option<String>,
+    /// duration between two saves of `state_file`, in seconds, disabled if 0
+    #[serde(default = "default_save_state_interval")]
+    pub save_state_interval: u64,
     pub log_level: String,
     pub log_target: String,
     #[serde(default)]
     pub log_access_target: Option<String>,
     pub worker_count: u16,
     pub worker_automatic_restart: bool,
+    /// delay before restarting a crashed worker, in milliseconds, doubled for
+    /// each other crash in the restart window
+    #[serde(default = "default_worker_restart_delay")]
+    pub worker_restart_delay: u64,
+    /// crashed workers are not restarted anymore past this number of crashes
+    /// in the restart window
+    #[serde(default = "default_worker_max_restarts")]
+    pub worker_max_restarts: u32,
+    /// duration in which the crashes are counted, in seconds
+    #[serde(default = "default_worker_restart_window")]
+    pub worker_restart_window: u64,
     pub metrics: Option<MetricsConfig>,
     pub http_listeners: Vec<HttpListener>,
     pub https_listeners: Vec<HttpsListener>,
@@ -1184,8 +2731,83 @@ pub struct Config {
     pub connect_timeout: u32,
     #[serde(default = "default_zombie_check_interval")]
     pub zombie_check_interval: u32,
+    /// duration between two fetches of the OCSP responses, in seconds, disabled if not set
+    #[serde(default)]
+    pub ocsp_refresh_interval: Option<u32>,
+    /// the certificates expiring in less than this number of days trigger an event
+    #[serde(default = "default_certificate_expiration_threshold")]
+    pub certificate_expiration_threshold: u32,
+    /// the certificates with an incomplete chain, or a key that does not match, are rejected
+    /// instead of being added with a warning
+    #[serde(default)]
+    pub strict_certificate_validation: bool,
+    /// duration between two rotations of the TLS session ticket keys, in seconds
+    #[serde(default = "default_ticket_keys_rotation_interval")]
+    pub ticket_keys_rotation_interval: u32,
     #[serde(default = "default_accept_queue_timeout")]
     pub accept_queue_timeout: u32,
+    /// backends discovered from service catalogs
+    #[serde(default)]
+    pub discovery: Option<DiscoveryConfig>,
+    /// the orders allowed to the other users of the command socket, only its
+    /// owner can connect to it if empty
+    #[serde(default)]
+    pub command_roles: Vec<CommandRole>,
+    /// the command channel for the clients of other hosts
+    #[serde(default)]
+    pub command_remote: Option<RemoteCommandConfig>,
+    /// the external policy deciding if the clients can send the orders changing
+    /// the proxy
+    #[serde(default)]
+    pub command_authorization: Option<CommandAuthorizationConfig>,
+    /// the log of the orders changing the proxy, with their clients and results
+    #[serde(default)]
+    pub audit_log: Option<AuditLogConfig>,
+    /// number of versions of the state kept by the main process, to roll back to
+    #[serde(default = "default_state_history_size")]
+    pub state_history_size: usize,
+    /// the other main processes sharing the changes of the state
+    #[serde(default)]
+    pub peering: Option<PeeringConfig>,
+    /// the CPU cores of each worker, replaces `handle_process_affinity`
+    #[serde(default)]
+    pub worker_cpu_affinity: Option<WorkerCpuAffinity>,
+    /// the limit of open file descriptors of each worker, set before it starts
+    #[serde(default)]
+    pub worker_max_open_files: Option<u64>,
+    /// the resident memory of a worker, in megabytes, past which an event is sent
+    #[serde(default)]
+    pub worker_max_memory: Option<u64>,
+    /// replaces the workers going past `worker_max_memory`
+    #[serde(default)]
+    pub worker_memory_recycle: bool,
+    /// the user the workers switch to once they received their listeners, the
+    /// main process binds the listeners for them
+    #[serde(default)]
+    pub user: Option<String>,
+    /// the group of the workers, the group of `user` if not set
+    #[serde(default)]
+    pub group: Option<String>,
+    /// the directory the workers are confined to before switching to `user`
+    #[serde(default)]
+    pub chroot: Option<String>,
+    /// the workers forbid themselves the filesystem and the system calls the
+    /// event loop does not need, once they are set up
+    #[serde(default)]
+    pub worker_sandbox: bool,
+    /// how long the new main process or worker of an upgrade has to check its
+    /// listeners, in seconds, before the upgrade is rolled back
+    #[serde(default = "default_upgrade_timeout")]
+    pub upgrade_timeout: u64,
+    /// duration between two heartbeats sent to each worker, in seconds, disabled if 0
+    #[serde(default = "default_worker_heartbeat_interval")]
+    pub worker_heartbeat_interval: u64,
+    /// the heartbeats a worker can miss in a row before it is marked `NotAnswering`
+    #[serde(default = "default_worker_heartbeat_misses")]
+    pub worker_heartbeat_misses: u32,
+    /// what is done with the workers marked `NotAnswering`
+    #[serde(default)]
+    pub worker_not_answering_policy: NotAnsweringPolicy,
 }
 
 fn default_front_timeout() -> u32 {
@@ -1209,6 +2831,46 @@ fn default_accept_queue_timeout() -> u32 {
     60
 }
 
+fn default_certificate_expiration_threshold() -> u32 {
+    30
+}
+
+fn default_ticket_keys_rotation_interval() -> u32 {
+    3600
+}
+
+fn default_worker_restart_delay() -> u64 {
+    500
+}
+
+fn default_worker_max_restarts() -> u32 {
+    5
+}
+
+fn default_worker_restart_window() -> u64 {
+    300
+}
+
+fn default_upgrade_timeout() -> u64 {
+    30
+}
+
+fn default_worker_heartbeat_interval() -> u64 {
+    10
+}
+
+fn default_worker_heartbeat_misses() -> u32 {
+    3
+}
+
+fn default_save_state_interval() -> u64 {
+    60
+}
+
+fn default_state_history_size() -> usize {
+    20
+}
+
 impl Config {
     pub fn load_from_path(path: &str) -> anyhow::Result<Config> {
         let file_config =
@@ -1222,6 +2884,7 @@ impl Config {
         config.saved_state = config
             .saved_state_path()
             .with_context(|| "Invalid saved_state in the config. Check your config file")?;
+        config.state_file = config.state_file_path()?;
 
         Ok(config)
     }
@@ -1235,6 +2898,9 @@ impl Config {
                 id: format!("CONFIG-{}", count),
                 version: PROTOCOL_VERSION,
                 worker_id: None,
+                worker_ids: Vec::new(),
+                strict: false,
+                dry_run: false,
                 order: CommandRequestOrder::Proxy(Box::new(ProxyRequestOrder::AddHttpListener(
                     listener.clone(),
                 ))),
@@ -1247,6 +2913,9 @@ impl Config {
                 id: format!("CONFIG-{}", count),
                 version: PROTOCOL_VERSION,
                 worker_id: None,
+                worker_ids: Vec::new(),
+                strict: false,
+                dry_run: false,
                 order: CommandRequestOrder::Proxy(Box::new(ProxyRequestOrder::AddHttpsListener(
                     listener.clone(),
                 ))),
@@ -1259,6 +2928,9 @@ impl Config {
                 id: format!("CONFIG-{}", count),
                 version: PROTOCOL_VERSION,
                 worker_id: None,
+                worker_ids: Vec::new(),
+                strict: false,
+                dry_run: false,
                 order: CommandRequestOrder::Proxy(Box::new(ProxyRequestOrder::AddTcpListener(
                     listener.clone(),
                 ))),
@@ -1273,6 +2945,9 @@ impl Config {
                     id: format!("CONFIG-{}", count),
                     version: PROTOCOL_VERSION,
                     worker_id: None,
+                    worker_ids: Vec::new(),
+                    strict: false,
+                    dry_run: false,
                     order: CommandRequestOrder::Proxy(Box::new(order)),
                 });
                 count += 1;
@@ -1285,6 +2960,9 @@ impl Config {
                     id: format!("CONFIG-{}", count),
                     version: PROTOCOL_VERSION,
                     worker_id: None,
+                    worker_ids: Vec::new(),
+                    strict: false,
+                    dry_run: false,
                     order: CommandRequestOrder::Proxy(Box::new(
                         ProxyRequestOrder::ActivateListener(ActivateListener {
                             address: listener.address,
@@ -1301,6 +2979,9 @@ impl Config {
                     id: format!("CONFIG-{}", count),
                     version: PROTOCOL_VERSION,
                     worker_id: None,
+                    worker_ids: Vec::new(),
+                    strict: false,
+                    dry_run: false,
                     order: CommandRequestOrder::Proxy(Box::new(
                         ProxyRequestOrder::ActivateListener(ActivateListener {
                             address: listener.address,
@@ -1317,6 +2998,9 @@ impl Config {
                     id: format!("CONFIG-{}", count),
                     version: PROTOCOL_VERSION,
                     worker_id: None,
+                    worker_ids: Vec::new(),
+                    strict: false,
+                    dry_run: false,
                     order: CommandRequestOrder::Proxy(Box::new(
                         ProxyRequestOrder::ActivateListener(ActivateListener {
                             address: listener.address,
@@ -1332,6 +3016,17 @@ impl Config {
         v
     }
 
+    /// the state of a proxy started with this configuration
+    pub fn initial_state(&self) -> ConfigState {
+        let mut state = ConfigState::new();
+        for request in self.generate_config_messages() {
+            if let CommandRequestOrder::Proxy(order) = request.order {
+                state.handle_order(&order);
+            }
+        }
+        state
+    }
+
     pub fn command_socket_path(&self) -> anyhow::Result<String> {
         let config_path_buf = PathBuf::from(self.config_path.clone());
         let mut config_folder = match config_path_buf.parent() {
@@ -1403,6 +3098,25 @@ impl Config {
         Ok(Some(stringified_path))
     }
 
+    /// the state file relative to the folder of the configuration file, it
+    /// is created by the first save if missing
+    fn state_file_path(&self) -> anyhow::Result<Option<String>> {
+        let path = match self.state_file.as_ref() {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let config_folder = Path::new(&self.config_path)
+            .parent()
+            .with_context(|| "could not get parent folder of configuration file")?;
+
+        config_folder
+            .join(path)
+            .to_str()
+            .map(|path| Some(path.to_owned()))
+            .with_context(|| format!("invalid state_file path {:?}, expected UTF8", path))
+    }
+
     pub fn load_file(path: &str) -> io::Result<String> {
         std::fs::read_to_string(path)
     }
@@ -1410,6 +3124,60 @@ impl Config {
     pub fn load_file_bytes(path: &str) -> io::Result<Vec<u8>> {
         std::fs::read(path)
     }
+
+    /// loads a PEM or DER encoded certificate, converted to PEM
+    pub fn load_certificate(path: &str) -> anyhow::Result<String> {
+        let certificate = Config::load_file_bytes(path)?;
+        if is_pem(&certificate) {
+            return Ok(String::from_utf8(certificate)?);
+        }
+        der_certificate_to_pem(&certificate)
+    }
+
+    /// loads a PEM certificate chain, or a single DER encoded certificate
+    pub fn load_certificate_chain(path: &str) -> anyhow::Result<Vec<String>> {
+        let certificate_chain = Config::load_file_bytes(path)?;
+        if is_pem(&certificate_chain) {
+            return Ok(split_certificate_chain(String::from_utf8(
+                certificate_chain,
+            )?));
+        }
+        Ok(vec![der_certificate_to_pem(&certificate_chain)?])
+    }
+
+    /// loads a PEM or DER encoded private key, converted to PEM. An encrypted key
+    /// is decrypted with the passphrase from the source, written `pass:<passphrase>`,
+    /// `file:<path>` or `env:<variable>`
+    pub fn load_key(path: &str, passphrase: Option<&str>) -> anyhow::Result<String> {
+        let key = Config::load_file_bytes(path)?;
+        if !is_pem(&key) {
+            return der_key_to_pem(&key);
+        }
+
+        let key = String::from_utf8(key)?;
+        if !is_encrypted_key(&key) {
+            return Ok(key);
+        }
+
+        let passphrase = match passphrase {
+            Some(source) => source.parse::<Passphrase>()?.load()?,
+            None => bail!("the key is encrypted, its passphrase is missing"),
+        };
+        decrypt_key(&key, &passphrase)
+    }
+
+    /// loads the certificate, chain and key of a PKCS#12 bundle, decrypted
+    /// with the password stored in a file, or an empty password
+    pub fn load_pkcs12(path: &str, password_path: Option<&str>) -> anyhow::Result<PemBundle> {
+        let bundle = Config::load_file_bytes(path)?;
+        let password = match password_path {
+            Some(password_path) => Config::load_file(password_path)
+                .with_context(|| format!("cannot load password at path '{}'", password_path))?,
+            None => String::new(),
+        };
+
+        pkcs12_to_pem(&bundle, password.trim_end_matches(&['\r', '\n'][..]))
+    }
 }
 
 pub fn display_toml_error(file: &str, error: &toml::de::Error) {
@@ -1434,7 +3202,7 @@ mod tests {
             answer_404: Some(String::from("404.html")),
             answer_503: None,
             public_address: None,
-            tls_provider: TlsProvider::default(),
+            tls_provider: None,
             tls_versions: None,
             cipher_list: None,
             cipher_suites: None,
@@ -1445,10 +3213,24 @@ mod tests {
             certificate: None,
             certificate_chain: None,
             key: None,
+            key_passphrase: None,
             front_timeout: None,
             back_timeout: None,
             connect_timeout: None,
             request_timeout: None,
+            request_limits: RequestLimits::default(),
+            compression: Compression::default(),
+            path_normalization: PathNormalization::default(),
+            fallback_cluster: None,
+            client_auth: None,
+            default_certificate: None,
+            strict_sni: None,
+            handshake_timeout: None,
+            security_headers: SecurityHeaders::default(),
+            http2: Http2Settings::default(),
+            allowed_destinations: None,
+            socks5: None,
+            max_sessions: None,
         };
         println!("http: {:?}", to_string(&http));
         let https = Listener {
@@ -1457,7 +3239,7 @@ mod tests {
             answer_404: Some(String::from("404.html")),
             answer_503: None,
             public_address: None,
-            tls_provider: TlsProvider::default(),
+            tls_provider: None,
             tls_versions: None,
             cipher_list: None,
             cipher_suites: None,
@@ -1468,10 +3250,24 @@ mod tests {
             certificate: None,
             certificate_chain: None,
             key: None,
+            key_passphrase: None,
             front_timeout: None,
             back_timeout: None,
             connect_timeout: None,
             request_timeout: None,
+            request_limits: RequestLimits::default(),
+            compression: Compression::default(),
+            path_normalization: PathNormalization::default(),
+            fallback_cluster: None,
+            client_auth: None,
+            default_certificate: None,
+            strict_sni: None,
+            handshake_timeout: None,
+            security_headers: SecurityHeaders::default(),
+            http2: Http2Settings::default(),
+            allowed_destinations: None,
+            socks5: None,
+            max_sessions: None,
         };
         println!("https: {:?}", to_string(&https));
 
@@ -1480,11 +3276,17 @@ mod tests {
             command_socket: Some(String::from("./command_folder/sock")),
             saved_state: None,
             automatic_state_save: None,
+            state_file: None,
+            save_state_interval: None,
             worker_count: Some(2),
             worker_automatic_restart: Some(true),
+            worker_restart_delay: None,
+            worker_max_restarts: None,
+            worker_restart_window: None,
             handle_process_affinity: None,
             command_buffer_size: None,
             max_connections: Some(500),
+            max_sessions: None,
             min_buffers: Some(1),
             max_buffers: Some(500),
             buffer_size: Some(16393),
@@ -1507,8 +3309,31 @@ mod tests {
             back_timeout: None,
             connect_timeout: None,
             zombie_check_interval: None,
+            ocsp_refresh_interval: None,
+            certificate_expiration_threshold: None,
+            strict_certificate_validation: None,
+            ticket_keys_rotation_interval: None,
             accept_queue_timeout: None,
             request_timeout: None,
+            discovery: None,
+            command_roles: None,
+            command_remote: None,
+            command_authorization: None,
+            audit_log: None,
+            state_history_size: None,
+            peering: None,
+            worker_cpu_affinity: None,
+            worker_max_open_files: None,
+            worker_max_memory: None,
+            worker_memory_recycle: None,
+            user: None,
+            group: None,
+            chroot: None,
+            worker_sandbox: None,
+            upgrade_timeout: None,
+            worker_heartbeat_interval: None,
+            worker_heartbeat_misses: None,
+            worker_not_answering_policy: None,
         };
 
         println!("config: {:?}", to_string(&config));
@@ -1524,4 +3349,263 @@ mod tests {
         println!("config: {:#?}", config);
         //panic!();
     }
+
+    #[test]
+    fn listener_tls_provider() {
+        let mut listener = Listener::new(
+            "127.0.0.1:8443".parse().unwrap(),
+            FileListenerProtocolConfig::Https,
+        );
+        let https_listener = listener
+            .to_tls(None, None, None, None, Some(TlsProvider::Openssl))
+            .unwrap();
+        assert_eq!(https_listener.tls_provider, TlsProvider::Openssl);
+
+        listener.tls_provider = Some(TlsProvider::Rustls);
+        let https_listener = listener
+            .to_tls(None, None, None, None, Some(TlsProvider::Openssl))
+            .unwrap();
+        assert_eq!(https_listener.tls_provider, TlsProvider::Rustls);
+    }
+
+    #[test]
+    fn forward_proxy_listener() {
+        let mut listener: Listener = toml::from_str(
+            r#"
+            address = "127.0.0.1:3128"
+            protocol = "forward"
+            allowed_destinations = ["*.example.com:443", "10.0.0.0/8:*"]
+            socks5 = true
+            "#,
+        )
+        .unwrap();
+        let forward_proxy = listener.to_tcp(None, None, None).unwrap().forward_proxy;
+        assert_eq!(
+            forward_proxy,
+            Some(ForwardProxy {
+                allowed_destinations: vec![
+                    "*.example.com:443".parse().unwrap(),
+                    "10.0.0.0/8:*".parse().unwrap(),
+                ],
+                socks5: true,
+            })
+        );
+
+        listener.protocol = FileListenerProtocolConfig::Tcp;
+        assert!(listener.to_tcp(None, None, None).is_err());
+
+        listener.protocol = FileListenerProtocolConfig::Forward;
+        listener.allowed_destinations = None;
+        assert!(listener.to_tcp(None, None, None).is_err());
+    }
+    #[test]
+    fn consul_discovery() {
+        let discovery: DiscoveryConfig = toml::from_str(
+            r#"
+            [consul]
+            token = "secret"
+
+            [consul.services.api]
+            tags = ["production"]
+
+            [consul.services.admin]
+            name = "admin-web"
+            "#,
+        )
+        .unwrap();
+        let consul = discovery.consul.unwrap();
+        assert_eq!(consul.address, "http://127.0.0.1:8500");
+        assert_eq!(consul.token.as_deref(), Some("secret"));
+        assert_eq!(consul.wait, 60);
+        assert_eq!(
+            consul.services.get("api"),
+            Some(&ConsulService {
+                name: None,
+                tags: vec![String::from("production")],
+            })
+        );
+        assert_eq!(
+            consul.services.get("admin"),
+            Some(&ConsulService {
+                name: Some(String::from("admin-web")),
+                tags: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn command_role() {
+        let role: CommandRole = toml::from_str(
+            r#"
+            groups = ["monitoring", "1001"]
+            orders = ["read"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            role,
+            CommandRole {
+                users: vec![],
+                groups: vec![String::from("monitoring"), String::from("1001")],
+                orders: vec![OrderCategory::Read],
+            }
+        );
+
+        assert!(toml::from_str::<CommandRole>(r#"orders = ["write"]"#).is_err());
+    }
+
+    #[test]
+    fn remote_command_roles() {
+        let remote: RemoteCommandConfig = toml::from_str(
+            r#"
+            address = "0.0.0.0:4242"
+            certificate = "/etc/sozu/remote.pem"
+            key = "/etc/sozu/remote-key.pem"
+            token = "secret"
+
+            [[roles]]
+            token = "monitoring"
+            orders = ["read"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(remote.orders, default_remote_orders());
+        assert_eq!(remote.max_clients, 16);
+        assert_eq!(
+            remote.roles,
+            vec![RemoteCommandRole {
+                token: String::from("monitoring"),
+                orders: vec![OrderCategory::Read],
+            }]
+        );
+    }
+
+    #[test]
+    fn peering() {
+        let peering: PeeringConfig = toml::from_str(
+            r#"
+            name = "sozu-a"
+
+            [[peers]]
+            address = "sozu-b.example.com:4242"
+            token = "secret"
+            ca = "/etc/sozu/ca.pem"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            peering,
+            PeeringConfig {
+                name: String::from("sozu-a"),
+                peers: vec![PeerConfig {
+                    address: String::from("sozu-b.example.com:4242"),
+                    token: String::from("secret"),
+                    ca: Some(String::from("/etc/sozu/ca.pem")),
+                }],
+            }
+        );
+
+        assert!(toml::from_str::<PeeringConfig>(r#"peers = []"#).is_err());
+    }
+
+    #[test]
+    fn worker_cpu_affinity() {
+        #[derive(Deserialize)]
+        struct Affinity {
+            worker_cpu_affinity: WorkerCpuAffinity,
+        }
+
+        let spread: Affinity = toml::from_str(r#"worker_cpu_affinity = "auto""#).unwrap();
+        assert_eq!(spread.worker_cpu_affinity.cores(0, 4), vec![1]);
+        assert_eq!(spread.worker_cpu_affinity.cores(3, 4), vec![0]);
+
+        let cores: Affinity = toml::from_str(r#"worker_cpu_affinity = [[2, 3], [4, 5]]"#).unwrap();
+        assert_eq!(cores.worker_cpu_affinity.cores(0, 8), vec![2, 3]);
+        assert_eq!(cores.worker_cpu_affinity.cores(1, 8), vec![4, 5]);
+        assert_eq!(cores.worker_cpu_affinity.cores(2, 8), vec![2, 3]);
+
+        assert!(toml::from_str::<Affinity>(r#"worker_cpu_affinity = "all""#).is_err());
+    }
+
+    #[test]
+    fn worker_resource_limits() {
+        let limits = |toml: &str| {
+            toml::from_str::<FileConfig>(toml)
+                .unwrap()
+                .into("config.toml")
+        };
+
+        let config = limits("max_connections = 1000\nworker_max_open_files = 4000").unwrap();
+        assert_eq!(config.worker_max_open_files, Some(4000));
+        assert!(!config.worker_memory_recycle);
+
+        assert!(limits("max_connections = 1000\nworker_max_open_files = 1500").is_err());
+        assert!(limits("worker_max_memory = 0").is_err());
+        assert!(limits("worker_memory_recycle = true").is_err());
+        assert!(limits("worker_max_memory = 512\nworker_memory_recycle = true").is_ok());
+
+        let config = limits("worker_not_answering_policy = \"kill\"").unwrap();
+        assert_eq!(config.worker_heartbeat_interval, 10);
+        assert_eq!(config.worker_not_answering_policy, NotAnsweringPolicy::Kill);
+        assert!(limits("worker_heartbeat_misses = 0").is_err());
+
+        assert!(limits("worker_cpu_affinity = [[0]]").is_ok());
+        assert!(limits("worker_cpu_affinity = [[0], [100000]]").is_err());
+    }
+
+    #[test]
+    fn state_file_persistence() {
+        let persistence = |toml: &str| {
+            toml::from_str::<FileConfig>(toml)
+                .unwrap()
+                .into("config.toml")
+        };
+
+        let config = persistence("state_file = \"state.json\"").unwrap();
+        assert_eq!(config.state_file.as_deref(), Some("state.json"));
+        assert_eq!(config.save_state_interval, 60);
+
+        assert!(persistence("save_state_interval = 10").is_err());
+        assert!(persistence("save_state_interval = 0").is_ok());
+        assert!(persistence("state_file = \"a.json\"\nsaved_state = \"b.json\"").is_err());
+    }
+
+    #[test]
+    fn worker_privileges() {
+        let privileges = |toml: &str| {
+            toml::from_str::<FileConfig>(toml)
+                .unwrap()
+                .into("config.toml")
+        };
+
+        let config = privileges("user = \"sozu\"\nchroot = \"/var/empty\"").unwrap();
+        assert_eq!(config.user.as_deref(), Some("sozu"));
+        assert_eq!(config.group, None);
+
+        assert!(privileges("group = \"sozu\"").is_err());
+        assert!(privileges("user = \"sozu\"\nchroot = \"var/empty\"").is_err());
+    }
+
+    #[test]
+    fn export_state() {
+        let path = "assets/config.toml";
+        let config = Config::load_from_path(path).expect("could not load the config");
+        let state = config.initial_state();
+
+        let directory = env::temp_dir().join(format!("sozu-export-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let mut file_config = FileConfig::load_from_path(path).unwrap();
+        // relative to the directory of the configuration, it is not in the export directory
+        file_config.saved_state = None;
+        let skipped = file_config.set_state(&state, &directory).unwrap();
+        assert!(skipped.is_empty(), "skipped: {:?}", skipped);
+
+        let exported = directory.join("config.toml");
+        fs::write(&exported, file_config.to_toml().unwrap()).unwrap();
+        let reloaded = Config::load_from_path(exported.to_str().unwrap())
+            .expect("could not load the exported config");
+        assert_eq!(state.diff(&reloaded.initial_state()), Vec::new());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
 }
@@ -4,8 +4,9 @@ use std::{
     convert::From,
     default::Default,
     error, fmt,
-    net::SocketAddr,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
     str::FromStr,
+    time::{Duration, Instant},
 };
 
 use hex::{self, FromHex};
@@ -98,6 +99,59 @@ pub enum ProxyResponseContent {
     Metrics(WorkerMetrics),
     Query(QueryAnswer),
     Event(ProxyEvent),
+    /// a worker bound a sticky key to a backend
+    Affinity(Affinity),
+    /// a request logged by a worker, matching the filters of a client tailing
+    /// the access logs
+    AccessLog(AccessLogRecord),
+    /// the sessions a worker let finish or closed on a soft stop
+    SoftStop(SoftStopReport),
+    /// what a worker was doing when it panicked, sent before it aborts
+    Crash(CrashReport),
+    /// a name a worker needs the addresses of, its event loop never waits on
+    /// the system resolver
+    ResolveName(NameResolution),
+}
+
+/// the outcome of a soft stop for a worker
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SoftStopReport {
+    /// sessions that finished before the deadline
+    pub drained: usize,
+    /// sessions closed at the deadline
+    pub killed: usize,
+}
+
+/// the panic of a worker, with what it was doing then
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CrashReport {
+    /// the message of the panic
+    pub message: String,
+    /// the file and line of the panic
+    #[serde(default)]
+    pub location: Option<String>,
+    #[serde(default)]
+    pub backtrace: String,
+    /// client sessions opened when the worker panicked
+    #[serde(default)]
+    pub sessions: usize,
+    /// the id and the type of the last order the worker received, as
+    /// `ID (TYPE)`
+    #[serde(default)]
+    pub last_order: Option<String>,
+}
+
+/// a name to resolve for a session of a worker. The worker asks the main
+/// process without addresses, the main process answers with the ones it
+/// found, none if the resolution failed or timed out
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct NameResolution {
+    /// token of the session in the worker
+    pub session: usize,
+    pub name: String,
+    pub port: u16,
+    #[serde(default)]
+    pub addresses: Vec<SocketAddr>,
 }
 
 /// Aggregated metrics of main process & workers, for the CLI
@@ -200,7 +254,10 @@ impl fmt::Display for ProxyRequest {
 #[serde(tag = "type", content = "data", rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum ProxyRequestOrder {
     AddCluster(Cluster),
-    RemoveCluster { cluster_id: String },
+    RemoveCluster {
+        cluster_id: String,
+    },
+    SetClusterMaintenance(ClusterMaintenance),
 
     AddHttpFrontend(HttpFrontend),
     RemoveHttpFrontend(HttpFrontend),
@@ -211,12 +268,21 @@ pub enum ProxyRequestOrder {
     AddCertificate(AddCertificate),
     ReplaceCertificate(ReplaceCertificate),
     RemoveCertificate(RemoveCertificate),
+    SetOcspResponse(SetOcspResponse),
+    SetDefaultCertificate(SetDefaultCertificate),
+    SetTicketKeys(SetTicketKeys),
 
     AddTcpFrontend(TcpFrontend),
     RemoveTcpFrontend(TcpFrontend),
 
+    AddSniFrontend(SniFrontend),
+    RemoveSniFrontend(SniFrontend),
+
     AddBackend(Backend),
     RemoveBackend(RemoveBackend),
+    DrainBackend(DrainBackend),
+    UpdateBackendWeight(UpdateBackendWeight),
+    SetAffinity(Affinity),
 
     AddHttpListener(HttpListener),
     AddHttpsListener(HttpsListener),
@@ -227,16 +293,30 @@ pub enum ProxyRequestOrder {
     ActivateListener(ActivateListener),
     DeactivateListener(DeactivateListener),
 
+    AddAcl(Acl),
+    RemoveAcl(RemoveAcl),
+
     Query(Query),
 
-    SoftStop,
+    /// stops accepting new connections and lets the sessions finish. With a
+    /// deadline in seconds, the sessions left past it are closed
+    SoftStop(#[serde(default)] Option<u32>),
     HardStop,
 
     Status,
     ConfigureMetrics(MetricsConfiguration),
     Logging(String),
+    /// the access logs the workers send to the main process, for the clients
+    /// tailing them. No filter stops the sending
+    SetAccessLogFilters(Vec<AccessLogFilter>),
+    /// the addresses of a name a worker asked the main process to resolve
+    NameResolved(NameResolution),
 
     ReturnListenSockets,
+
+    // orders applied as one transaction by the workers: if one of them fails,
+    // the ones applied before it are rolled back
+    Batch(Vec<ProxyRequestOrder>),
 }
 
 //FIXME: make fixed size depending on hash algorithm
@@ -283,36 +363,923 @@ impl<'de> Visitor<'de> for CertificateFingerprintVisitor {
     }
 }
 
-impl<'de> serde::Deserialize<'de> for CertificateFingerprint {
-    fn deserialize<D>(deserializer: D) -> Result<CertificateFingerprint, D::Error>
-    where
-        D: serde::de::Deserializer<'de>,
-    {
-        deserializer.deserialize_str(CertificateFingerprintVisitor {})
-    }
+impl<'de> serde::Deserialize<'de> for CertificateFingerprint {
+    fn deserialize<D>(deserializer: D) -> Result<CertificateFingerprint, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        deserializer.deserialize_str(CertificateFingerprintVisitor {})
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Cluster {
+    pub cluster_id: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_false")]
+    pub sticky_session: bool,
+    /// how requests stick to a backend when `sticky_session` is set
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub sticky_mode: StickyMode,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_false")]
+    pub https_redirect: bool,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub proxy_protocol: Option<ProxyProtocolConfig>,
+    #[serde(rename = "load_balancing")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub load_balancing: LoadBalancingAlgorithms,
+    /// what the `consistent_hash` load balancing hashes to choose the backend
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub hash_key: HashKey,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub answer_503: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub load_metric: Option<LoadMetric>,
+    /// modifications of the request and response headers, applied in order
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub header_actions: Vec<HeaderAction>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub host_rewrite: HostRewrite,
+    /// overrides the request limits of the listener
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub request_limits: RequestLimits,
+    /// overrides the response compression settings of the listener
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub compression: Compression,
+    /// overrides the security headers added by the listener to the responses
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub security_headers: SecurityHeaders,
+    /// when and how many times a failed request is sent to another backend
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub request_retries: RequestRetries,
+    /// overrides the timeouts of the listener
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub timeouts: Timeouts,
+    /// connects to the backends over TLS, for HTTP clusters
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backend_tls: Option<Box<BackendTls>>,
+    /// protocol spoken to the backends, for HTTP clusters
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub backend_protocol: BackendProtocol,
+    /// what happens to the WebSocket connections when the worker stops, for HTTP clusters
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub websocket_drain: WebSocketDrain,
+    /// the responses are forwarded as they arrive, without compression, and
+    /// are not cut by the back timeout once their body streams, for the
+    /// server-sent events and long polling endpoints of HTTP clusters
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_false")]
+    pub streaming: bool,
+    /// protocols the connections can switch to with an `Upgrade` header, besides
+    /// WebSocket, for HTTP clusters. Once the backend answers with a 101, the
+    /// connection is forwarded as is. The other protocols are not offered to the backends
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub upgrade_protocols: Vec<String>,
+    /// active checks of the backends, which leave the rotation while they fail
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheck>,
+    /// passive checks of the backends, which leave the rotation for a while when
+    /// they fail too much of the traffic
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outlier_detection: Option<OutlierDetection>,
+    /// requests waiting for a connection slot when all the backends are at their
+    /// maximum connections, for HTTP clusters
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_queue: Option<RequestQueue>,
+    /// backends bound to the sticky keys, shared by the workers
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub affinity_table: Option<AffinityTable>,
+    /// client sessions routed to the cluster at most by a worker, the next ones
+    /// are answered with a 503, or refused in TCP clusters
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_sessions: Option<usize>,
+}
+
+impl Cluster {
+    /// checks the parts of the cluster written as is in the messages sent to
+    /// the backends or clients, an invalid cluster is refused
+    pub fn validate(&self) -> Result<(), String> {
+        for action in &self.header_actions {
+            action.validate()?;
+        }
+        self.host_rewrite.validate()
+    }
+}
+
+/// limits on the requests of a listener or cluster: requests whose headers
+/// are too large are answered with a 431, those with a body too large with a 413.
+/// The limits of a cluster take precedence over those of the listener
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct RequestLimits {
+    /// size of the request line and headers, in bytes
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_header_size: Option<usize>,
+    /// number of request headers
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_header_count: Option<usize>,
+    /// size of the request body, in bytes
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_body_size: Option<usize>,
+    /// body data read from the client and not yet written to the backend, in bytes.
+    /// The client is not read from while this much is waiting for the backend
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_buffered_body: Option<usize>,
+    /// chunked bodies are read whole, up to `max_buffered_body` or the buffer
+    /// size, and sent with a `Content-Length`, for the backends that need it.
+    /// Larger bodies are answered with a 413
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buffer_chunked_body: Option<bool>,
+}
+
+impl RequestLimits {
+    /// uses the limits of `other` for those not set here
+    pub fn or(&self, other: &RequestLimits) -> RequestLimits {
+        RequestLimits {
+            max_header_size: self.max_header_size.or(other.max_header_size),
+            max_header_count: self.max_header_count.or(other.max_header_count),
+            max_body_size: self.max_body_size.or(other.max_body_size),
+            max_buffered_body: self.max_buffered_body.or(other.max_buffered_body),
+            buffer_chunked_body: self.buffer_chunked_body.or(other.buffer_chunked_body),
+        }
+    }
+}
+
+/// responses smaller than this are not compressed, unless `min_size` is set
+pub const DEFAULT_COMPRESSION_MIN_SIZE: usize = 1024;
+
+/// on-the-fly compression of the responses, for the clients sending a matching
+/// `Accept-Encoding` header. The settings of a cluster take precedence over those of the listener
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct Compression {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gzip: Option<bool>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub brotli: Option<bool>,
+    /// size of the smallest response body compressed, in bytes
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_size: Option<usize>,
+}
+
+impl Compression {
+    /// uses the settings of `other` for those not set here
+    pub fn or(&self, other: &Compression) -> Compression {
+        Compression {
+            gzip: self.gzip.or(other.gzip),
+            brotli: self.brotli.or(other.brotli),
+            min_size: self.min_size.or(other.min_size),
+        }
+    }
+}
+
+/// default limit of the streams a client can have open at the same time on an HTTP/2 connection
+pub const DEFAULT_H2_MAX_CONCURRENT_STREAMS: u32 = 100;
+/// default flow control window of the HTTP/2 streams, in bytes
+pub const DEFAULT_H2_INITIAL_WINDOW_SIZE: u32 = 65535;
+
+/// HTTP/2 on an HTTPS listener. When enabled, `h2` is offered to the clients
+/// through ALPN next to `http/1.1`, and the streams are forwarded to the backends over HTTP/1.1
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct Http2Settings {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_false")]
+    pub enabled: bool,
+    /// streams a client can have open at the same time on a connection,
+    /// the next ones are refused
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_streams: Option<u32>,
+    /// flow control window of each stream, in bytes. It bounds the request
+    /// data buffered for a stream while its backend does not read it
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initial_window_size: Option<u32>,
+}
+
+impl Http2Settings {
+    pub fn max_concurrent_streams(&self) -> u32 {
+        self.max_concurrent_streams
+            .unwrap_or(DEFAULT_H2_MAX_CONCURRENT_STREAMS)
+    }
+
+    pub fn initial_window_size(&self) -> u32 {
+        self.initial_window_size
+            .unwrap_or(DEFAULT_H2_INITIAL_WINDOW_SIZE)
+    }
+}
+
+/// security headers added to the responses, replacing those sent by the backends.
+/// The values of a cluster take precedence over those of the listener
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SecurityHeaders {
+    /// value of the `Strict-Transport-Security` header, like `max-age=31536000`
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strict_transport_security: Option<String>,
+    /// value of the `X-Content-Type-Options` header, like `nosniff`
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type_options: Option<String>,
+    /// value of the `X-Frame-Options` header, like `DENY`
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame_options: Option<String>,
+}
+
+impl SecurityHeaders {
+    /// uses the values of `other` for those not set here
+    pub fn or(&self, other: &SecurityHeaders) -> SecurityHeaders {
+        SecurityHeaders {
+            strict_transport_security: self
+                .strict_transport_security
+                .clone()
+                .or_else(|| other.strict_transport_security.clone()),
+            content_type_options: self
+                .content_type_options
+                .clone()
+                .or_else(|| other.content_type_options.clone()),
+            frame_options: self
+                .frame_options
+                .clone()
+                .or_else(|| other.frame_options.clone()),
+        }
+    }
+}
+
+/// normalization of the request paths of a listener, applied before the routing.
+/// The request is sent to the backend with the normalized path
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct PathNormalization {
+    /// collapses consecutive slashes: `/a//b` becomes `/a/b`
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_false")]
+    pub merge_slashes: bool,
+    /// resolves the `.` and `..` segments, even percent-encoded: `/a/./b/../c` becomes `/a/c`
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_false")]
+    pub resolve_dot_segments: bool,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub trailing_slash: TrailingSlash,
+}
+
+/// how a listener handles the trailing slash of the request paths
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum TrailingSlash {
+    /// the path is left as is
+    #[default]
+    Keep,
+    /// the requests without a trailing slash are redirected with a 308 to the path ending with one
+    Add,
+    /// the requests with a trailing slash are redirected with a 308 to the path without it
+    Remove,
+}
+
+/// header carrying the subject of the client certificate to the backends, unless `dn_header` is set
+pub const DEFAULT_CLIENT_DN_HEADER: &str = "X-Client-DN";
+
+fn default_client_dn_header() -> String {
+    String::from(DEFAULT_CLIENT_DN_HEADER)
+}
+
+/// authentication of the clients of an HTTPS listener with a certificate
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientAuth {
+    /// the handshake fails without a client certificate. Otherwise the certificate
+    /// is optional, but still verified when the client sends one
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_false")]
+    pub required: bool,
+    /// PEM bundle of the certificate authorities the client certificates are verified against
+    pub ca_certificates: String,
+    /// PEM certificate revocation lists, checked against the whole chain of the client.
+    /// They come from the configuration, so their signatures are not verified
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revocation_lists: Option<String>,
+    /// request header set to the subject DN of the verified client certificate.
+    /// The header sent by the client is always removed
+    #[serde(default = "default_client_dn_header")]
+    pub dn_header: String,
+}
+
+/// number of attempts to send a request to the backends of a cluster, unless `max_attempts` is set
+pub const DEFAULT_REQUEST_ATTEMPTS: u8 = 3;
+
+/// failures after which a request is sent again to another backend of the cluster
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryCondition {
+    /// the connection to the backend could not be established
+    ConnectFailure,
+    /// the backend answered with a 502 before anything was sent to the client
+    #[serde(rename = "http_502")]
+    Http502,
+    /// the backend answered with a 503 before anything was sent to the client
+    #[serde(rename = "http_503")]
+    Http503,
+}
+
+/// retry policy of the requests of a cluster. Without any condition,
+/// only connection failures are retried
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestRetries {
+    /// attempts of a request, including the first one
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_attempts: Option<u8>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub retry_on: Vec<RetryCondition>,
+}
+
+impl RequestRetries {
+    pub fn max_attempts(&self) -> u8 {
+        self.max_attempts.unwrap_or(DEFAULT_REQUEST_ATTEMPTS).max(1)
+    }
+
+    pub fn retries_on(&self, condition: RetryCondition) -> bool {
+        if self.retry_on.is_empty() {
+            condition == RetryCondition::ConnectFailure
+        } else {
+            self.retry_on.contains(&condition)
+        }
+    }
+
+    /// requests are kept to be sent again only if a response status can trigger a retry
+    pub fn retries_on_status(&self) -> bool {
+        self.retries_on(RetryCondition::Http502) || self.retries_on(RetryCondition::Http503)
+    }
+}
+
+/// how the backends of a cluster are checked
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheckKind {
+    /// a TCP connection to the backend
+    #[default]
+    Tcp,
+    /// an HTTP GET request, the backends connected with TLS get a TCP check
+    Http,
+    /// a call to the `grpc.health.v1.Health/Check` method over HTTP/2 in
+    /// cleartext, for gRPC services. The backends connected with TLS get a TCP check
+    Grpc,
+}
+
+/// active health checks of the backends of a cluster, run by every worker.
+/// A backend leaves the rotation after `fall` failed checks in a row, and comes
+/// back after `rise` successful ones
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheck {
+    #[serde(default)]
+    pub kind: HealthCheckKind,
+    /// path of the HTTP request
+    #[serde(default = "default_health_check_path")]
+    pub path: String,
+    /// status of the HTTP response, any 2xx or 3xx status if unset
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_status: Option<u16>,
+    /// text the body of the HTTP response must contain
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_body: Option<String>,
+    /// service name of the gRPC health check, the whole server is checked if unset
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service: Option<String>,
+    /// seconds between two checks of a backend
+    #[serde(default = "default_health_check_interval")]
+    pub interval: u32,
+    /// seconds after which a check fails
+    #[serde(default = "default_health_check_timeout")]
+    pub timeout: u32,
+    #[serde(default = "default_health_check_rise")]
+    pub rise: u32,
+    #[serde(default = "default_health_check_fall")]
+    pub fall: u32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            kind: HealthCheckKind::default(),
+            path: default_health_check_path(),
+            expected_status: None,
+            expected_body: None,
+            service: None,
+            interval: default_health_check_interval(),
+            timeout: default_health_check_timeout(),
+            rise: default_health_check_rise(),
+            fall: default_health_check_fall(),
+        }
+    }
+}
+
+impl std::str::FromStr for HealthCheckKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "tcp" => Ok(HealthCheckKind::Tcp),
+            "http" => Ok(HealthCheckKind::Http),
+            "grpc" => Ok(HealthCheckKind::Grpc),
+            _ => Err(format!(
+                "invalid health check '{}', expected tcp, http or grpc",
+                s
+            )),
+        }
+    }
+}
+
+fn default_health_check_path() -> String {
+    String::from("/")
+}
+
+fn default_health_check_interval() -> u32 {
+    10
+}
+
+fn default_health_check_timeout() -> u32 {
+    5
+}
+
+fn default_health_check_rise() -> u32 {
+    2
+}
+
+fn default_health_check_fall() -> u32 {
+    3
+}
+
+/// passive health checks of the backends of a cluster, from the results of the
+/// connections and requests sent to them. Connection failures and 5xx responses
+/// count as failures. An outlier backend is ejected from the rotation for
+/// `ejection_time` seconds, unless `max_ejection_percent` of the backends of the
+/// cluster are already ejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutlierDetection {
+    /// failures in a row ejecting a backend
+    #[serde(default = "default_outlier_consecutive_failures")]
+    pub consecutive_failures: u32,
+    /// share of failures, in percent, ejecting a backend, checked every `minimum_requests`
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_rate: Option<u8>,
+    #[serde(default = "default_outlier_minimum_requests")]
+    pub minimum_requests: u32,
+    #[serde(default = "default_outlier_ejection_time")]
+    pub ejection_time: u32,
+    #[serde(default = "default_outlier_max_ejection_percent")]
+    pub max_ejection_percent: u8,
+}
+
+impl Default for OutlierDetection {
+    fn default() -> Self {
+        OutlierDetection {
+            consecutive_failures: default_outlier_consecutive_failures(),
+            error_rate: None,
+            minimum_requests: default_outlier_minimum_requests(),
+            ejection_time: default_outlier_ejection_time(),
+            max_ejection_percent: default_outlier_max_ejection_percent(),
+        }
+    }
+}
+
+fn default_outlier_consecutive_failures() -> u32 {
+    5
+}
+
+fn default_outlier_minimum_requests() -> u32 {
+    20
+}
+
+fn default_outlier_ejection_time() -> u32 {
+    30
+}
+
+fn default_outlier_max_ejection_percent() -> u8 {
+    50
+}
+
+/// bounded wait queue of a cluster. The requests arriving while every backend is
+/// at its maximum connections wait for a free connection, in order. They are
+/// answered with a 503 if the queue is full, or once they waited `timeout` seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestQueue {
+    /// requests waiting at most
+    #[serde(default = "default_request_queue_size")]
+    pub size: usize,
+    #[serde(default = "default_request_queue_timeout")]
+    pub timeout: u32,
+}
+
+impl Default for RequestQueue {
+    fn default() -> Self {
+        RequestQueue {
+            size: default_request_queue_size(),
+            timeout: default_request_queue_timeout(),
+        }
+    }
+}
+
+fn default_request_queue_size() -> usize {
+    100
+}
+
+fn default_request_queue_timeout() -> u32 {
+    5
+}
+
+/// the backend chosen for a sticky key is kept in a table shared by the workers
+/// through the main process, so that the key goes to it while it is available,
+/// even after a worker restart. The keys are the `source_ip` or `header` sticky
+/// keys of the HTTP clusters, and the client IP for the TCP clusters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AffinityTable {
+    /// keys kept at most, the oldest ones are forgotten first
+    #[serde(default = "default_affinity_table_size")]
+    pub size: usize,
+}
+
+impl Default for AffinityTable {
+    fn default() -> Self {
+        AffinityTable {
+            size: default_affinity_table_size(),
+        }
+    }
+}
+
+fn default_affinity_table_size() -> usize {
+    10000
+}
+
+/// a sticky key of a cluster bound to a backend
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Affinity {
+    pub cluster_id: String,
+    pub key: String,
+    pub backend_id: String,
+}
+
+/// timeouts of a cluster or backend in seconds, overriding those of the listener.
+/// The settings of a backend take precedence over those of its cluster. The request
+/// timeout of the listener still applies, since it runs before the request is routed
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct Timeouts {
+    /// inactivity of the client once the request is routed, and between requests
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub front_timeout: Option<u32>,
+    /// inactivity of the backend, like a response taking long to start
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub back_timeout: Option<u32>,
+    /// establishment of the connection to the backend
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<u32>,
+    /// inactivity of a connection upgraded to WebSocket, in both directions.
+    /// The front and back timeouts apply if not set
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub websocket_timeout: Option<u32>,
+}
+
+impl Timeouts {
+    /// uses the timeouts of `other` for those not set here
+    pub fn or(&self, other: &Timeouts) -> Timeouts {
+        Timeouts {
+            front_timeout: self.front_timeout.or(other.front_timeout),
+            back_timeout: self.back_timeout.or(other.back_timeout),
+            connect_timeout: self.connect_timeout.or(other.connect_timeout),
+            websocket_timeout: self.websocket_timeout.or(other.websocket_timeout),
+        }
+    }
+}
+
+/// TLS connection to the backends of a cluster, or to a single backend.
+/// The settings of a backend replace those of its cluster
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackendTls {
+    /// server name sent to the backend and verified in its certificate,
+    /// the backend is verified by IP address if not set
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sni: Option<String>,
+    /// accepts any certificate from the backend
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_false")]
+    pub skip_verification: bool,
+    /// PEM encoded authorities verifying the backend certificate,
+    /// the usual web authorities are used if not set
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_certificates: Option<String>,
+    /// PEM encoded certificate presented to the backends
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_certificate: Option<String>,
+    /// PEM encoded key of the client certificate
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<String>,
+    /// protocols offered to the backend through ALPN, like `http/1.1`
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub alpn_protocols: Vec<String>,
+}
+
+/// how the Host header of the requests is sent to the backends of a cluster
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HostRewrite {
+    /// keep the Host header sent by the client
+    #[default]
+    Preserve,
+    /// replace it with the address of the backend, like `10.0.0.1:8080`
+    BackendAddress,
+    /// replace it with a fixed value
+    Fixed(String),
+}
+
+impl HostRewrite {
+    /// a fixed host must be a hostname or an IP address, with an optional port
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            HostRewrite::Fixed(host) if !is_host(host) => {
+                Err(format!("invalid host '{}'", host.escape_default()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// `host[:port]`, the host being a hostname, an IPv4 address, or an IPv6 address in brackets
+fn is_host(host: &str) -> bool {
+    let (valid_host, port) = match host.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']') {
+            Some((ip, port)) => (ip.parse::<Ipv6Addr>().is_ok(), port),
+            None => return false,
+        },
+        None => {
+            let (name, port) = host.split_at(host.find(':').unwrap_or(host.len()));
+            let valid_name = !name.is_empty()
+                && name
+                    .bytes()
+                    .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'.' || c == b'_');
+            (valid_name, port)
+        }
+    };
+
+    valid_host
+        && (port.is_empty()
+            || port
+                .strip_prefix(':')
+                .is_some_and(|port| port.parse::<u16>().is_ok()))
+}
+
+/// protocol of the connections to the backends of a cluster
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendProtocol {
+    /// one request at a time on each connection
+    #[default]
+    Http1,
+    /// the requests of the HTTP/2 clients are multiplexed on a few connections,
+    /// in cleartext (h2c) or over the `backend_tls` of the cluster.
+    /// HTTP/1.1 clients are still forwarded in HTTP/1.1
+    Http2,
+    /// HTTP/2 as well, for gRPC services: the errors of the proxy become gRPC
+    /// statuses, the health checks are answered when no backend is available,
+    /// and the status of the calls is counted
+    Grpc,
+}
+
+impl std::str::FromStr for BackendProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "http1" => Ok(BackendProtocol::Http1),
+            "http2" => Ok(BackendProtocol::Http2),
+            "grpc" => Ok(BackendProtocol::Grpc),
+            _ => Err(format!(
+                "invalid backend protocol '{}', expected http1, http2 or grpc",
+                s
+            )),
+        }
+    }
+}
+
+/// what a stopping worker does with the WebSocket connections of a cluster
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum WebSocketDrain {
+    /// a close frame is sent to both sides once the frames in flight are
+    /// forwarded, then the connection is closed
+    #[default]
+    Close,
+    /// the worker waits for the connections to close, or to reach their timeout
+    Wait,
+}
+
+impl std::str::FromStr for WebSocketDrain {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "close" => Ok(WebSocketDrain::Close),
+            "wait" => Ok(WebSocketDrain::Wait),
+            _ => Err(format!(
+                "invalid WebSocket drain mode '{}', expected close or wait",
+                s
+            )),
+        }
+    }
+}
+
+/// how the requests of a sticky cluster are sent to the same backend
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StickyMode {
+    /// a cookie set on the first response designates the backend
+    #[default]
+    Cookie,
+    /// consistent hash of the client IP address
+    SourceIp,
+    /// consistent hash of the value of this request header, like `Authorization`
+    Header(String),
+}
+
+/// the key of a request hashed by the consistent hashing load balancing, the
+/// requests with the same key go to the same backend while it is available
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKey {
+    /// host and path of the request, with the query string
+    #[default]
+    Url,
+    /// client IP address, the only key of the TCP clusters
+    SourceIp,
+    /// value of this request header
+    Header(String),
+    /// value of this request cookie
+    Cookie(String),
 }
 
+/// While a cluster is in maintenance, the workers answer its requests
+/// with a 503 page, without touching its frontends and backends
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct Cluster {
+pub struct ClusterMaintenance {
     pub cluster_id: String,
+    pub enabled: bool,
+    /// full HTTP answer, defaults to the 503 answer of the cluster or listener
     #[serde(default)]
-    #[serde(skip_serializing_if = "is_false")]
-    pub sticky_session: bool,
-    #[serde(default)]
-    #[serde(skip_serializing_if = "is_false")]
-    pub https_redirect: bool,
-    #[serde(default)]
-    #[serde(skip_serializing_if = "is_default")]
-    pub proxy_protocol: Option<ProxyProtocolConfig>,
-    #[serde(rename = "load_balancing")]
-    #[serde(default)]
-    #[serde(skip_serializing_if = "is_default")]
-    pub load_balancing: LoadBalancingAlgorithms,
-    #[serde(default)]
-    #[serde(skip_serializing_if = "is_default")]
-    pub answer_503: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub load_metric: Option<LoadMetric>,
+    pub answer: Option<String>,
+}
+
+/// Modifies the headers of the requests sent to the backends of a cluster,
+/// or of the responses sent back to the clients
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct HeaderAction {
+    pub position: HeaderPosition,
+    pub operation: HeaderOperation,
+    pub name: String,
+    /// unused when removing a header
+    #[serde(default)]
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub value: String,
+}
+
+impl HeaderAction {
+    /// the name must be a token and the value cannot end the header line,
+    /// otherwise the action would inject headers in the message
+    pub fn validate(&self) -> Result<(), String> {
+        if !is_header_name(&self.name) {
+            return Err(format!(
+                "invalid header name '{}'",
+                self.name.escape_default()
+            ));
+        }
+        if !is_header_value(&self.value) {
+            return Err(format!(
+                "invalid value '{}' for the header {}",
+                self.value.escape_default(),
+                self.name
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// a header name is a token, as defined in RFC 7230
+pub fn is_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c))
+}
+
+/// a header value cannot contain line breaks or NUL
+pub fn is_header_value(value: &str) -> bool {
+    !value.bytes().any(|c| c == b'\r' || c == b'\n' || c == 0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum HeaderPosition {
+    Request,
+    Response,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum HeaderOperation {
+    /// adds the header, even if the message already has one with the same name
+    Add,
+    /// replaces the headers with the same name
+    Set,
+    /// removes the headers with this name
+    Remove,
+}
+
+impl std::str::FromStr for HeaderOperation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "add" => Ok(HeaderOperation::Add),
+            "set" => Ok(HeaderOperation::Set),
+            "remove" => Ok(HeaderOperation::Remove),
+            _ => Err(format!(
+                "invalid header operation '{}', expected add, set or remove",
+                s
+            )),
+        }
+    }
 }
 
 fn socketaddr_cmp(a: &SocketAddr, b: &SocketAddr) -> Ordering {
@@ -384,16 +1351,230 @@ impl std::fmt::Display for PathRule {
     }
 }
 
+/// A filter on a header of incoming requests
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct HeaderRule {
+    /// name of the header, compared case insensitively
+    pub name: String,
+    pub value: HeaderValueRule,
+}
+
+/// A filter on the value of a request header
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum HeaderValueRule {
+    /// filters values that start with a pattern
+    Prefix(String),
+    /// filters values that match a regex pattern
+    Regex(String),
+    /// filters values that exactly match a pattern
+    Equals(String),
+}
+
+impl HeaderRule {
+    /// builds the header rules from `name=value` pairs given on the command line
+    pub fn from_cli_options(
+        headers_equals: Vec<(String, String)>,
+        headers_prefix: Vec<(String, String)>,
+        headers_regex: Vec<(String, String)>,
+    ) -> Vec<Self> {
+        let equals = headers_equals
+            .into_iter()
+            .map(|(name, value)| (name, HeaderValueRule::Equals(value)));
+        let prefix = headers_prefix
+            .into_iter()
+            .map(|(name, value)| (name, HeaderValueRule::Prefix(value)));
+        let regex = headers_regex
+            .into_iter()
+            .map(|(name, value)| (name, HeaderValueRule::Regex(value)));
+
+        equals
+            .chain(prefix)
+            .chain(regex)
+            .map(|(name, value)| HeaderRule { name, value })
+            .collect()
+    }
+}
+
+impl std::fmt::Display for HeaderRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.value {
+            HeaderValueRule::Prefix(s) => write!(f, "{} prefix '{}'", self.name, s),
+            HeaderValueRule::Regex(r) => write!(f, "{} regexp '{}'", self.name, r),
+            HeaderValueRule::Equals(s) => write!(f, "{} equals '{}'", self.name, s),
+        }
+    }
+}
+
+/// How the request path is modified before being sent to the backend
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum PathRewrite {
+    /// removes the part of the path matched by the frontend's path prefix
+    StripPrefix,
+    /// replaces the parts of the path matching the regex.
+    /// The replacement can refer to capture groups with `$1` or `${name}`
+    Regex {
+        pattern: String,
+        replacement: String,
+    },
+}
+
+impl std::fmt::Display for PathRewrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathRewrite::StripPrefix => write!(f, "strip prefix"),
+            PathRewrite::Regex {
+                pattern,
+                replacement,
+            } => write!(f, "'{}' -> '{}'", pattern, replacement),
+        }
+    }
+}
+
 /// The cluster to which the traffic will be redirected
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(remote = "Self")]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum Route {
-    /// send a 401 default answer
-    Deny,
+    /// answers directly with an error, without contacting a backend.
+    /// `body_path` is a file containing the body of the answer
+    Deny {
+        #[serde(default = "default_deny_status")]
+        status: u16,
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        body_path: Option<String>,
+    },
     /// Routes to a cluster.
     // TODO: create a custom type `ClusterId`
     /// the cluster to which the frontend belongs
     ClusterId(String),
+    /// splits the traffic between several clusters, proportionally to their weight
+    Weighted(Vec<WeightedCluster>),
+    /// answers directly with a redirection, without contacting a backend.
+    /// `to` is the redirection target, it can contain the `{host}` and `{path}`
+    /// placeholders. If it has neither a path nor a `{path}` placeholder,
+    /// the path of the request is appended
+    Redirect { to: String, code: u16 },
+    /// A/B testing: a new user is assigned one of the clusters, proportionally to
+    /// their weight, and stored in the `cookie` cookie, so that the following
+    /// requests of this user go to the same cluster
+    AbTest {
+        cookie: String,
+        variants: Vec<WeightedCluster>,
+    },
+}
+
+impl serde::Serialize for Route {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        Route::serialize(self, serializer)
+    }
+}
+
+/// deny routes used to be written as the bare `"DENY"` string, state files
+/// and clients still sending it get a deny route with the default status
+impl<'de> serde::Deserialize<'de> for Route {
+    fn deserialize<D>(deserializer: D) -> Result<Route, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(RouteVisitor)
+    }
+}
+
+struct RouteVisitor;
+
+impl<'de> Visitor<'de> for RouteVisitor {
+    type Value = Route;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a route")
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Route, E>
+    where
+        E: de::Error,
+    {
+        match value {
+            "DENY" => Ok(Route::Deny {
+                status: default_deny_status(),
+                body_path: None,
+            }),
+            other => Err(E::unknown_variant(other, &["DENY"])),
+        }
+    }
+
+    fn visit_map<A>(self, map: A) -> Result<Route, A::Error>
+    where
+        A: de::MapAccess<'de>,
+    {
+        Route::deserialize(de::value::MapAccessDeserializer::new(map))
+    }
+}
+
+impl Route {
+    /// `cluster_ids` lists the clusters that can receive traffic from this route
+    pub fn cluster_ids(&self) -> Vec<&str> {
+        match self {
+            Route::Deny { .. } | Route::Redirect { .. } => Vec::new(),
+            Route::ClusterId(cluster_id) => vec![cluster_id.as_str()],
+            Route::Weighted(clusters)
+            | Route::AbTest {
+                variants: clusters, ..
+            } => clusters
+                .iter()
+                .map(|cluster| cluster.cluster_id.as_str())
+                .collect(),
+        }
+    }
+}
+
+/// HTTP status codes accepted for a redirect route
+pub const REDIRECT_CODES: [u16; 4] = [301, 302, 307, 308];
+
+/// a deny route answers with a client or server error
+pub fn is_deny_status(status: u16) -> bool {
+    (400..600).contains(&status)
+}
+
+/// status of the deny routes that do not set one
+pub fn default_deny_status() -> u16 {
+    401
+}
+
+/// A cluster receiving a share of the traffic of a weighted route
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct WeightedCluster {
+    pub cluster_id: String,
+    pub weight: u8,
+}
+
+impl std::str::FromStr for WeightedCluster {
+    type Err = String;
+
+    /// parses the `cluster_id=weight` format
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (cluster_id, weight) = s.trim().split_once('=').ok_or_else(|| {
+            format!(
+                "invalid weighted cluster '{}', expected cluster_id=weight",
+                s
+            )
+        })?;
+
+        let weight = weight
+            .trim()
+            .parse::<u8>()
+            .map_err(|e| format!("invalid weight for cluster '{}': {}", cluster_id, e))?;
+
+        Ok(WeightedCluster {
+            cluster_id: cluster_id.trim().to_string(),
+            weight,
+        })
+    }
 }
 
 #[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -407,6 +1588,28 @@ pub struct HttpFrontend {
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub method: Option<String>,
+    /// the frontend matches any of these methods, in addition to `method`
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub methods: Vec<String>,
+    /// requests matching the frontend except for their method get
+    /// a 405 answer listing the allowed methods in an `Allow` header,
+    /// if no other frontend accepts them
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_false")]
+    pub reject_other_methods: bool,
+    /// every header rule has to match for the frontend to be selected
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<HeaderRule>,
+    /// modifies the request path before it is sent to the backend
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rewrite_path: Option<PathRewrite>,
+    /// a copy of every request is sent to this cluster, and its responses are discarded
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirror_cluster_id: Option<String>,
     #[serde(default)]
     pub position: RulePosition,
     pub tags: Option<BTreeMap<String, String>>,
@@ -415,13 +1618,34 @@ pub struct HttpFrontend {
 impl HttpFrontend {
     /// `is_cluster_id` chech if the frontend is dedicated to the given cluster_id
     pub fn is_cluster_id(&self, cluster_id: &str) -> bool {
-        matches!(&self.route, Route::ClusterId(id) if id == cluster_id)
+        self.route.cluster_ids().contains(&cluster_id)
     }
 
     /// `route_key` returns a representation of the frontend as a route key
     pub fn route_key(self) -> RouteKey {
         self.into()
     }
+
+    /// `allowed_methods` lists the methods accepted by the frontend, empty if it accepts any
+    pub fn allowed_methods(&self) -> Vec<&str> {
+        let mut methods: Vec<&str> = Vec::new();
+        for method in self.method.iter().chain(self.methods.iter()) {
+            if !methods.iter().any(|m| m.eq_ignore_ascii_case(method)) {
+                methods.push(method);
+            }
+        }
+        methods
+    }
+
+    /// `method_key` identifies the methods of the frontend in its route key
+    pub fn method_key(&self) -> Option<String> {
+        let methods = self.allowed_methods();
+        if methods.is_empty() {
+            None
+        } else {
+            Some(methods.join(","))
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -432,6 +1656,15 @@ pub struct CertificateAndKey {
     #[serde(default)]
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub versions: Vec<TlsVersion>,
+    /// hex encoded DER OCSP response, stapled during the TLS handshakes
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ocsp_response: Option<String>,
+    /// among the certificates providing the same name, the one with the
+    /// highest priority is presented, then the one expiring last
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub priority: i32,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -463,6 +1696,52 @@ pub struct ReplaceCertificate {
     pub new_expired_at: Option<i64>,
 }
 
+/// selects the certificate presented by a HTTPS listener when the client sends
+/// no server name or a name matching no certificate, or unsets it
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SetDefaultCertificate {
+    pub address: SocketAddr,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<CertificateFingerprint>,
+}
+
+/// replaces the OCSP response stapled with a certificate, or stops stapling it
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SetOcspResponse {
+    pub address: SocketAddr,
+    pub fingerprint: CertificateFingerprint,
+    /// hex encoded DER OCSP response
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ocsp_response: Option<String>,
+}
+
+/// keys encrypting the TLS session tickets, generated by the main process and shared
+/// by the workers, so that a session can be resumed on any of them
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SetTicketKeys {
+    /// hex encoded keys of TICKET_KEY_LENGTH bytes: a 16 bytes name, then the secrets.
+    /// The first key encrypts the new tickets, all of them decrypt the tickets
+    pub keys: Vec<String>,
+    /// lifetime of the tickets, in seconds
+    pub lifetime: u32,
+}
+
+/// length of a session ticket key: 16 bytes of name, 32 bytes of HMAC secret
+/// and 32 bytes of encryption secret, the layout expected by OpenSSL
+pub const TICKET_KEY_LENGTH: usize = 80;
+
+// the keys are secrets, they should not end up in the logs
+impl fmt::Debug for SetTicketKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SetTicketKeys")
+            .field("keys", &self.keys.len())
+            .field("lifetime", &self.lifetime)
+            .finish()
+    }
+}
+
 #[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct TcpFrontend {
     pub cluster_id: String,
@@ -470,6 +1749,17 @@ pub struct TcpFrontend {
     pub tags: Option<BTreeMap<String, String>>,
 }
 
+/// routes the TLS connections of a TCP listener to a cluster by the server name
+/// of their ClientHello, without terminating TLS. The listener falls back to its
+/// TCP frontend for the unknown server names
+#[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SniFrontend {
+    pub cluster_id: String,
+    pub address: SocketAddr,
+    /// exact server name, or wildcard like `*.example.com`
+    pub hostname: String,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Backend {
     pub cluster_id: String,
@@ -484,6 +1774,19 @@ pub struct Backend {
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub backup: Option<bool>,
+    /// connections opened to the backend at most, the other requests wait in the
+    /// queue of the cluster, or go to another backend
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+    /// overrides the timeouts of the cluster and listener, for HTTP backends
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub timeouts: Timeouts,
+    /// replaces the TLS settings of the cluster, for HTTP backends
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<Box<BackendTls>>,
 }
 
 impl Ord for Backend {
@@ -497,6 +1800,9 @@ impl Ord for Backend {
                     .cmp(&o.load_balancing_parameters),
             )
             .then(self.backup.cmp(&o.backup))
+            .then(self.max_connections.cmp(&o.max_connections))
+            .then(self.timeouts.cmp(&o.timeouts))
+            .then(self.tls.cmp(&o.tls))
             .then(socketaddr_cmp(&self.address, &o.address))
     }
 }
@@ -514,6 +1820,27 @@ pub struct RemoveBackend {
     pub address: SocketAddr,
 }
 
+/// stops sending new sessions to a backend. The workers send
+/// `RemovedBackendHasNoConnections` once its connections are closed
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DrainBackend {
+    pub cluster_id: String,
+    pub backend_id: String,
+    pub address: SocketAddr,
+    /// the backend is removed from the configuration too
+    #[serde(default)]
+    pub remove: bool,
+}
+
+/// changes the weight of a backend for the load balancing of its cluster
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct UpdateBackendWeight {
+    pub cluster_id: String,
+    pub backend_id: String,
+    pub address: SocketAddr,
+    pub weight: u8,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum LoadBalancingAlgorithms {
@@ -521,6 +1848,12 @@ pub enum LoadBalancingAlgorithms {
     Random,
     LeastLoaded,
     PowerOfTwo,
+    /// the backends are placed on a hash ring, a request goes to the backend
+    /// following the hash of its key on the ring
+    ConsistentHash,
+    /// the backends answering the fastest get the requests, their response
+    /// times decay over time so slow backends get traffic again
+    LeastResponseTime,
 }
 
 impl Default for LoadBalancingAlgorithms {
@@ -555,6 +1888,8 @@ impl FromStr for LoadBalancingAlgorithms {
         match s {
             "roundrobin" => Ok(LoadBalancingAlgorithms::RoundRobin),
             "random" => Ok(LoadBalancingAlgorithms::Random),
+            "consistenthash" => Ok(LoadBalancingAlgorithms::ConsistentHash),
+            "leastresponsetime" => Ok(LoadBalancingAlgorithms::LeastResponseTime),
             _ => Err(ParseErrorLoadBalancing {}),
         }
     }
@@ -588,6 +1923,17 @@ pub enum ListenerType {
     TCP,
 }
 
+impl ListenerType {
+    /// the topic of the proxy handling this kind of listener
+    pub fn topic(&self) -> Topic {
+        match self {
+            ListenerType::HTTP => Topic::HttpProxyConfig,
+            ListenerType::HTTPS => Topic::HttpsProxyConfig,
+            ListenerType::TCP => Topic::TcpProxyConfig,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct RemoveListener {
     pub address: SocketAddr,
@@ -601,11 +1947,130 @@ pub struct ActivateListener {
     pub from_scm: bool,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct DeactivateListener {
-    pub address: SocketAddr,
-    pub proxy: ListenerType,
-    pub to_scm: bool,
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DeactivateListener {
+    pub address: SocketAddr,
+    pub proxy: ListenerType,
+    pub to_scm: bool,
+}
+
+/// source IP access control list of a listener. Without a hostname, it is
+/// checked when accepting connections, with a hostname it is checked when
+/// routing requests for this hostname (HTTP and HTTPS listeners only), for
+/// all the frontends of the hostname on the listener
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Acl {
+    pub address: SocketAddr,
+    pub proxy: ListenerType,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    pub mode: AclMode,
+    pub ranges: Vec<IpRange>,
+}
+
+impl Acl {
+    /// an allow list lets in only the listed ranges, a deny list blocks them
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let listed = self.ranges.iter().any(|range| range.contains(ip));
+        match self.mode {
+            AclMode::Allow => listed,
+            AclMode::Deny => !listed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RemoveAcl {
+    pub address: SocketAddr,
+    pub proxy: ListenerType,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AclMode {
+    Allow,
+    Deny,
+}
+
+/// a range of IP addresses in CIDR notation, like `10.0.0.0/8`.
+/// A single address without prefix length is a range of one address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpRange {
+    pub address: IpAddr,
+    pub prefix_length: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual stack listener appear as IPv4-mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+
+        let (range, ip, bits) = match (self.address, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                (u32::from(range) as u128, u32::from(ip) as u128, 32)
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => (u128::from(range), u128::from(ip), 128),
+            _ => return false,
+        };
+
+        let shift = bits - u32::from(self.prefix_length);
+        range.checked_shr(shift) == ip.checked_shr(shift)
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_length) = match s.split_once('/') {
+            Some((address, prefix_length)) => (address, Some(prefix_length)),
+            None => (s, None),
+        };
+
+        let address = IpAddr::from_str(address)
+            .map_err(|e| format!("invalid IP address in range {}: {}", s, e))?;
+        let max_length = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_length = match prefix_length {
+            None => max_length,
+            Some(length) => match length.parse::<u8>() {
+                Ok(length) if length <= max_length => length,
+                _ => return Err(format!("invalid prefix length in range {}", s)),
+            },
+        };
+
+        Ok(IpRange {
+            address,
+            prefix_length,
+        })
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<IpRange> for String {
+    fn from(range: IpRange) -> Self {
+        range.to_string()
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_length)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -628,6 +2093,24 @@ pub struct HttpListener {
     pub connect_timeout: u32,
     /// max time to send a complete request
     pub request_timeout: u32,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub request_limits: RequestLimits,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub compression: Compression,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub path_normalization: PathNormalization,
+    /// cluster receiving the requests matching no frontend, instead of answering a 404
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback_cluster: Option<String>,
+    /// client sessions open on the listener at most, the next ones are answered
+    /// with a 503
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_sessions: Option<usize>,
 }
 
 impl Default for HttpListener {
@@ -643,6 +2126,11 @@ impl Default for HttpListener {
               back_timeout:    30,
               connect_timeout: 3,
               request_timeout: 10,
+              request_limits:  RequestLimits::default(),
+              compression:  Compression::default(),
+              path_normalization: PathNormalization::default(),
+              fallback_cluster: None,
+              max_sessions: None,
         }
     }
 }
@@ -718,7 +2206,9 @@ pub struct HttpsListener {
     pub signature_algorithms: Vec<String>,
     #[serde(default)]
     pub groups_list: Vec<String>,
-    #[serde(skip, default)]
+    /// implementation of TLS serving this listener
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
     pub tls_provider: TlsProvider,
     #[serde(default)]
     pub expect_proxy: bool,
@@ -735,6 +2225,50 @@ pub struct HttpsListener {
     pub connect_timeout: u32,
     /// max time to send a complete request
     pub request_timeout: u32,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub request_limits: RequestLimits,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub compression: Compression,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub path_normalization: PathNormalization,
+    /// cluster receiving the requests matching no frontend, instead of answering a 404
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback_cluster: Option<String>,
+    /// verification of the client certificates, they are not requested without it
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_auth: Option<Box<ClientAuth>>,
+    /// certificate presented when the client sends no server name, or a name
+    /// matching no certificate
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_certificate: Option<CertificateFingerprint>,
+    /// refuses the handshakes without a server name, or with a name matching no
+    /// certificate, instead of presenting the default certificate
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_false")]
+    pub strict_sni: bool,
+    /// max time to complete the TLS handshake, the request timeout by default
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub handshake_timeout: Option<u32>,
+    /// security headers added to the responses
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub security_headers: Box<SecurityHeaders>,
+    /// HTTP/2 offered to the clients, only HTTP/1.1 without it
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub http2: Http2Settings,
+    /// client sessions open on the listener at most, the next ones are answered
+    /// with a 503
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_sessions: Option<usize>,
 }
 
 impl Default for HttpsListener {
@@ -767,6 +2301,17 @@ impl Default for HttpsListener {
       back_timeout:    30,
       connect_timeout: 3,
       request_timeout: 10,
+      request_limits:  RequestLimits::default(),
+      compression:  Compression::default(),
+      path_normalization: PathNormalization::default(),
+      fallback_cluster: None,
+      client_auth: None,
+      default_certificate: None,
+      strict_sni: false,
+      handshake_timeout: None,
+      security_headers: Box::default(),
+      http2: Http2Settings::default(),
+      max_sessions: None,
     }
     }
 }
@@ -783,6 +2328,162 @@ pub struct TcpListener {
     pub front_timeout: u32,
     pub back_timeout: u32,
     pub connect_timeout: u32,
+    /// the listener tunnels its connections to the destinations requested by
+    /// the clients, instead of routing them to clusters
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forward_proxy: Option<ForwardProxy>,
+    /// client sessions open on the listener at most, the next connections are
+    /// refused
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_sessions: Option<usize>,
+}
+
+/// forward proxy mode of a TCP listener: the clients ask for a destination
+/// with an HTTP `CONNECT` request, or a SOCKS5 handshake if enabled
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ForwardProxy {
+    /// the destinations that can be reached, any other one is refused
+    pub allowed_destinations: Vec<Destination>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_false")]
+    pub socks5: bool,
+}
+
+impl ForwardProxy {
+    /// a destination requested by name, like `api.example.com`
+    pub fn allows_name(&self, name: &str, port: u16) -> bool {
+        self.allowed_destinations
+            .iter()
+            .any(|destination| destination.allows_name(name, port))
+    }
+
+    /// a destination requested by address, or a name resolved to that address
+    pub fn allows_address(&self, address: SocketAddr) -> bool {
+        self.allowed_destinations
+            .iter()
+            .any(|destination| destination.allows_address(address))
+    }
+}
+
+/// a destination allowed by a forward proxy, written `<host>:<port>`. The
+/// host is a name, a wildcard name like `*.example.com`, or an IP range like
+/// `10.0.0.0/8`, between brackets for IPv6. The port is a number or `*`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Destination {
+    pub host: DestinationHost,
+    /// any port if none
+    pub port: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DestinationHost {
+    /// lowercase, starting with `*.` for the subdomains of a domain
+    Name(String),
+    Range(IpRange),
+}
+
+impl Destination {
+    fn allows_port(&self, port: u16) -> bool {
+        self.port.map(|p| p == port).unwrap_or(true)
+    }
+
+    pub fn allows_name(&self, name: &str, port: u16) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let matches = match &self.host {
+            DestinationHost::Name(pattern) => match pattern.strip_prefix("*.") {
+                Some(domain) => name
+                    .split_once('.')
+                    .map(|(_, parent)| parent == domain)
+                    .unwrap_or(false),
+                None => *pattern == name,
+            },
+            DestinationHost::Range(_) => false,
+        };
+
+        matches && self.allows_port(port)
+    }
+
+    pub fn allows_address(&self, address: SocketAddr) -> bool {
+        match &self.host {
+            DestinationHost::Range(range) => {
+                range.contains(address.ip()) && self.allows_port(address.port())
+            }
+            DestinationHost::Name(_) => false,
+        }
+    }
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("missing port in destination {}", s))?;
+
+        let port = match port {
+            "*" => None,
+            port => Some(
+                port.parse::<u16>()
+                    .map_err(|e| format!("invalid port in destination {}: {}", s, e))?,
+            ),
+        };
+
+        let host = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+            Some(range) => DestinationHost::Range(range.parse()?),
+            None if host.parse::<IpRange>().is_ok() => DestinationHost::Range(host.parse()?),
+            None => {
+                let name = host.to_ascii_lowercase();
+                let labels = name.strip_prefix("*.").unwrap_or(&name);
+                if labels.is_empty()
+                    || !labels
+                        .split('.')
+                        .all(|label| !label.is_empty() && label.bytes().all(is_name_byte))
+                {
+                    return Err(format!("invalid host in destination {}", s));
+                }
+                DestinationHost::Name(name)
+            }
+        };
+
+        Ok(Destination { host, port })
+    }
+}
+
+fn is_name_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_'
+}
+
+impl TryFrom<String> for Destination {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Destination> for String {
+    fn from(destination: Destination) -> Self {
+        destination.to_string()
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.host {
+            DestinationHost::Name(name) => write!(f, "{}", name)?,
+            DestinationHost::Range(range) if range.address.is_ipv6() => write!(f, "[{}]", range)?,
+            DestinationHost::Range(range) => write!(f, "{}", range)?,
+        }
+
+        match self.port {
+            Some(port) => write!(f, ":{}", port),
+            None => write!(f, ":*"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -791,6 +2492,114 @@ pub enum MetricsConfiguration {
     Clear,
 }
 
+/// the access logs a client tails
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct AccessLogFilter {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cluster_id: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<StatusRange>,
+    /// the records over this number in a second are dropped
+    pub max_per_second: u32,
+}
+
+impl AccessLogFilter {
+    pub fn matches(&self, cluster_id: Option<&str>, status: Option<u16>) -> bool {
+        if self.cluster_id.is_some() && self.cluster_id.as_deref() != cluster_id {
+            return false;
+        }
+
+        match (&self.status, status) {
+            (None, _) => true,
+            (Some(range), Some(status)) => range.contains(status),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// HTTP statuses from `from` to `to`, both included, like 500 to 599 for 5xx
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct StatusRange {
+    pub from: u16,
+    pub to: u16,
+}
+
+impl StatusRange {
+    pub fn contains(&self, status: u16) -> bool {
+        self.from <= status && status <= self.to
+    }
+}
+
+impl fmt::Display for StatusRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.from == self.to {
+            write!(f, "{}", self.from)
+        } else if self.from.is_multiple_of(100) && self.to == self.from + 99 {
+            write!(f, "{}xx", self.from / 100)
+        } else {
+            write!(f, "{}-{}", self.from, self.to)
+        }
+    }
+}
+
+/// counts the access logs sent in the current second, to drop the ones over
+/// the maximum
+#[derive(Debug, Clone, Default)]
+pub struct AccessLogRateLimit {
+    second: Option<Instant>,
+    count: u32,
+    /// the records dropped since the creation of the limit
+    pub dropped: u64,
+}
+
+impl AccessLogRateLimit {
+    /// counts a record if it can be sent now, with at most `max_per_second`
+    /// records a second
+    pub fn allow(&mut self, max_per_second: u32, now: Instant) -> bool {
+        match self.second {
+            Some(second) if now.duration_since(second) < Duration::from_secs(1) => {}
+            _ => {
+                self.second = Some(now);
+                self.count = 0;
+            }
+        }
+
+        if self.count >= max_per_second {
+            self.dropped += 1;
+            return false;
+        }
+        self.count += 1;
+        true
+    }
+}
+
+/// a request handled by a worker, as written to the access logs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessLogRecord {
+    /// when the request ended, as a unix timestamp in milliseconds
+    pub timestamp: i64,
+    pub cluster_id: Option<String>,
+    pub backend_id: Option<String>,
+    pub session_address: Option<SocketAddr>,
+    pub backend_address: Option<SocketAddr>,
+    /// like HTTP, HTTPS-TLS1.3 or TCP
+    pub protocol: String,
+    pub host: Option<String>,
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub status: Option<u16>,
+    /// in milliseconds
+    pub response_time: u64,
+    /// in milliseconds
+    pub service_time: u64,
+    pub bytes_in: usize,
+    pub bytes_out: usize,
+    /// why the request failed
+    pub error: Option<String>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(tag = "type", content = "data", rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum Query {
@@ -798,6 +2607,10 @@ pub enum Query {
     Certificates(QueryCertificateType),
     Metrics(QueryMetricsOptions),
     ClustersHashes,
+    /// health of the backends, of every cluster or of this one
+    BackendHealth(Option<String>),
+    /// backends with their connections, of every cluster or of this one
+    Backends(Option<String>),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -819,6 +2632,15 @@ pub enum QueryCertificateType {
     All,
     Domain(String),
     Fingerprint(Vec<u8>),
+    /// the certificate a listener presents to a TLS client sending this server name
+    Resolve(QueryCertificateResolve),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct QueryCertificateResolve {
+    pub address: SocketAddr,
+    /// no server name resolves to the default certificate of the listener
+    pub hostname: Option<String>,
 }
 
 /// Options originating from the command line
@@ -839,6 +2661,8 @@ pub enum QueryAnswer {
     ClustersHashes(BTreeMap<String, u64>),
     Certificates(QueryAnswerCertificate),
     Metrics(QueryAnswerMetrics),
+    BackendHealth(Vec<BackendHealth>),
+    Backends(Vec<QueryAnswerBackend>),
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -847,7 +2671,56 @@ pub struct QueryAnswerCluster {
     pub http_frontends: Vec<HttpFrontend>,
     pub https_frontends: Vec<HttpFrontend>,
     pub tcp_frontends: Vec<TcpFrontend>,
+    #[serde(default)]
+    pub sni_frontends: Vec<SniFrontend>,
     pub backends: Vec<Backend>,
+    /// ids of the backends out of the rotation because of their health checks
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unhealthy_backends: Vec<String>,
+}
+
+/// health of a backend, as a worker sees it
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct BackendHealth {
+    pub cluster_id: String,
+    pub backend_id: String,
+    pub address: SocketAddr,
+    /// false if the backend left the rotation because of its health checks
+    pub healthy: bool,
+    /// the backend is ejected from the rotation by the outlier detection
+    pub ejected: bool,
+    /// result of the last health check, none if the backend was not checked yet
+    pub last_check: Option<bool>,
+    /// duration of the last health check, in milliseconds
+    pub latency: Option<u64>,
+    /// health checks succeeding in a row
+    pub successes: u32,
+    /// health checks failing in a row
+    pub failures: u32,
+    /// health checks failed since the backend is checked
+    pub total_failures: u64,
+}
+
+/// a backend with its connections, as a worker sees it
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct QueryAnswerBackend {
+    pub cluster_id: String,
+    pub backend_id: String,
+    pub address: SocketAddr,
+    pub sticky_id: Option<String>,
+    pub weight: Option<u8>,
+    pub backup: bool,
+    /// the backend takes no new sessions, its connections are waited for
+    pub draining: bool,
+    /// false if the backend left the rotation because of its health checks
+    pub healthy: bool,
+    /// the backend is ejected from the rotation by the outlier detection
+    pub ejected: bool,
+    pub active_connections: usize,
+    pub active_requests: usize,
+    /// connections to the backend failing in a row
+    pub failures: usize,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -858,6 +2731,20 @@ pub enum QueryAnswerCertificate {
     Domain(HashMap<SocketAddr, Option<(String, Vec<u8>)>>),
     /// returns the certificate
     Fingerprint(Option<(String, Vec<String>)>),
+    /// returns the certificate picked by the resolver of the listener
+    Resolve(Option<ResolvedCertificate>),
+}
+
+/// the certificate presented for a server name, as the resolver of a listener picks it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResolvedCertificate {
+    pub fingerprint: CertificateFingerprint,
+    pub names: Vec<String>,
+    /// the `not_after` field of the certificate, as a unix timestamp
+    pub expiration: i64,
+    pub versions: Vec<TlsVersion>,
+    /// true if the server name matched no certificate, and the default one was picked
+    pub default: bool,
 }
 
 /// Returned by the local drain
@@ -890,6 +2777,12 @@ impl ProxyRequestOrder {
             .iter()
             .cloned()
             .collect(),
+            ProxyRequestOrder::SetClusterMaintenance(_) => {
+                [Topic::HttpProxyConfig, Topic::HttpsProxyConfig]
+                    .iter()
+                    .cloned()
+                    .collect()
+            }
             ProxyRequestOrder::AddHttpFrontend(_) => {
                 [Topic::HttpProxyConfig].iter().cloned().collect()
             }
@@ -911,12 +2804,27 @@ impl ProxyRequestOrder {
             ProxyRequestOrder::RemoveCertificate(_) => {
                 [Topic::HttpsProxyConfig].iter().cloned().collect()
             }
+            ProxyRequestOrder::SetOcspResponse(_) => {
+                [Topic::HttpsProxyConfig].iter().cloned().collect()
+            }
+            ProxyRequestOrder::SetDefaultCertificate(_) => {
+                [Topic::HttpsProxyConfig].iter().cloned().collect()
+            }
+            ProxyRequestOrder::SetTicketKeys(_) => {
+                [Topic::HttpsProxyConfig].iter().cloned().collect()
+            }
             ProxyRequestOrder::AddTcpFrontend(_) => {
                 [Topic::TcpProxyConfig].iter().cloned().collect()
             }
             ProxyRequestOrder::RemoveTcpFrontend(_) => {
                 [Topic::TcpProxyConfig].iter().cloned().collect()
             }
+            ProxyRequestOrder::AddSniFrontend(_) => {
+                [Topic::TcpProxyConfig].iter().cloned().collect()
+            }
+            ProxyRequestOrder::RemoveSniFrontend(_) => {
+                [Topic::TcpProxyConfig].iter().cloned().collect()
+            }
             ProxyRequestOrder::AddBackend(_) => [
                 Topic::HttpProxyConfig,
                 Topic::HttpsProxyConfig,
@@ -933,6 +2841,30 @@ impl ProxyRequestOrder {
             .iter()
             .cloned()
             .collect(),
+            ProxyRequestOrder::DrainBackend(_) => [
+                Topic::HttpProxyConfig,
+                Topic::HttpsProxyConfig,
+                Topic::TcpProxyConfig,
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+            ProxyRequestOrder::UpdateBackendWeight(_) => [
+                Topic::HttpProxyConfig,
+                Topic::HttpsProxyConfig,
+                Topic::TcpProxyConfig,
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+            ProxyRequestOrder::SetAffinity(_) => [
+                Topic::HttpProxyConfig,
+                Topic::HttpsProxyConfig,
+                Topic::TcpProxyConfig,
+            ]
+            .iter()
+            .cloned()
+            .collect(),
             ProxyRequestOrder::AddHttpListener(_) => {
                 [Topic::HttpProxyConfig].iter().cloned().collect()
             }
@@ -966,8 +2898,12 @@ impl ProxyRequestOrder {
             .iter()
             .cloned()
             .collect(),
+            ProxyRequestOrder::AddAcl(ref acl) => [acl.proxy.topic()].iter().cloned().collect(),
+            ProxyRequestOrder::RemoveAcl(ref remove) => {
+                [remove.proxy.topic()].iter().cloned().collect()
+            }
             ProxyRequestOrder::Query(_) => [Topic::HttpsProxyConfig].iter().cloned().collect(),
-            ProxyRequestOrder::SoftStop => [
+            ProxyRequestOrder::SoftStop(_) => [
                 Topic::HttpProxyConfig,
                 Topic::HttpsProxyConfig,
                 Topic::TcpProxyConfig,
@@ -992,6 +2928,8 @@ impl ProxyRequestOrder {
             .cloned()
             .collect(),
             ProxyRequestOrder::ConfigureMetrics(_) => HashSet::new(),
+            ProxyRequestOrder::SetAccessLogFilters(_) => HashSet::new(),
+            ProxyRequestOrder::NameResolved(_) => HashSet::new(),
             ProxyRequestOrder::Logging(_) => [
                 Topic::HttpsProxyConfig,
                 Topic::HttpProxyConfig,
@@ -1001,8 +2939,75 @@ impl ProxyRequestOrder {
             .cloned()
             .collect(),
             ProxyRequestOrder::ReturnListenSockets => HashSet::new(),
+            ProxyRequestOrder::Batch(ref orders) => {
+                orders.iter().flat_map(|order| order.get_topics()).collect()
+            }
+        }
+    }
+
+    /// the address of the listener the order applies to, for the orders of a
+    /// listener, its frontends, certificates and ACLs
+    pub fn listener_address(&self) -> Option<SocketAddr> {
+        match self {
+            ProxyRequestOrder::AddHttpFrontend(front)
+            | ProxyRequestOrder::RemoveHttpFrontend(front)
+            | ProxyRequestOrder::AddHttpsFrontend(front)
+            | ProxyRequestOrder::RemoveHttpsFrontend(front) => Some(front.address),
+            ProxyRequestOrder::AddTcpFrontend(front)
+            | ProxyRequestOrder::RemoveTcpFrontend(front) => Some(front.address),
+            ProxyRequestOrder::AddSniFrontend(front)
+            | ProxyRequestOrder::RemoveSniFrontend(front) => Some(front.address),
+            ProxyRequestOrder::AddCertificate(add) => Some(add.address),
+            ProxyRequestOrder::ReplaceCertificate(replace) => Some(replace.address),
+            ProxyRequestOrder::RemoveCertificate(remove) => Some(remove.address),
+            ProxyRequestOrder::SetOcspResponse(set) => Some(set.address),
+            ProxyRequestOrder::SetDefaultCertificate(set) => Some(set.address),
+            ProxyRequestOrder::AddHttpListener(listener) => Some(listener.address),
+            ProxyRequestOrder::AddHttpsListener(listener) => Some(listener.address),
+            ProxyRequestOrder::AddTcpListener(listener) => Some(listener.address),
+            ProxyRequestOrder::RemoveListener(remove) => Some(remove.address),
+            ProxyRequestOrder::ActivateListener(activate) => Some(activate.address),
+            ProxyRequestOrder::DeactivateListener(deactivate) => Some(deactivate.address),
+            ProxyRequestOrder::AddAcl(acl) => Some(acl.address),
+            ProxyRequestOrder::RemoveAcl(remove) => Some(remove.address),
+            _ => None,
         }
     }
+
+    /// the orders changing the configuration kept in the state, that a diff of
+    /// the states before and after undoes. Only those can be batched
+    pub fn is_reversible(&self) -> bool {
+        matches!(
+            self,
+            ProxyRequestOrder::AddCluster(_)
+                | ProxyRequestOrder::RemoveCluster { .. }
+                | ProxyRequestOrder::SetClusterMaintenance(_)
+                | ProxyRequestOrder::AddHttpFrontend(_)
+                | ProxyRequestOrder::RemoveHttpFrontend(_)
+                | ProxyRequestOrder::AddHttpsFrontend(_)
+                | ProxyRequestOrder::RemoveHttpsFrontend(_)
+                | ProxyRequestOrder::AddCertificate(_)
+                | ProxyRequestOrder::ReplaceCertificate(_)
+                | ProxyRequestOrder::RemoveCertificate(_)
+                | ProxyRequestOrder::SetOcspResponse(_)
+                | ProxyRequestOrder::SetDefaultCertificate(_)
+                | ProxyRequestOrder::AddTcpFrontend(_)
+                | ProxyRequestOrder::RemoveTcpFrontend(_)
+                | ProxyRequestOrder::AddSniFrontend(_)
+                | ProxyRequestOrder::RemoveSniFrontend(_)
+                | ProxyRequestOrder::AddBackend(_)
+                | ProxyRequestOrder::RemoveBackend(_)
+                | ProxyRequestOrder::UpdateBackendWeight(_)
+                | ProxyRequestOrder::AddHttpListener(_)
+                | ProxyRequestOrder::AddHttpsListener(_)
+                | ProxyRequestOrder::AddTcpListener(_)
+                | ProxyRequestOrder::RemoveListener(_)
+                | ProxyRequestOrder::ActivateListener(_)
+                | ProxyRequestOrder::DeactivateListener(_)
+                | ProxyRequestOrder::AddAcl(_)
+                | ProxyRequestOrder::RemoveAcl(_)
+        )
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -1017,7 +3022,7 @@ fn is_true(b: &bool) -> bool {
     *b
 }*/
 
-fn is_false(b: &bool) -> bool {
+pub(crate) fn is_false(b: &bool) -> bool {
     !*b
 }
 
@@ -1028,8 +3033,26 @@ fn is_default<T: Default + PartialEq>(t: &T) -> bool {
 impl std::fmt::Display for Route {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Route::Deny => write!(f, "deny"),
+            Route::Deny { status, body_path } => match body_path {
+                Some(body_path) => write!(f, "deny {} {}", status, body_path),
+                None => write!(f, "deny {}", status),
+            },
             Route::ClusterId(string) => write!(f, "{}", string),
+            Route::Weighted(clusters) => {
+                let clusters = clusters
+                    .iter()
+                    .map(|cluster| format!("{}={}", cluster.cluster_id, cluster.weight))
+                    .collect::<Vec<_>>();
+                write!(f, "{}", clusters.join(","))
+            }
+            Route::Redirect { to, code } => write!(f, "redirect {} {}", code, to),
+            Route::AbTest { cookie, variants } => {
+                let variants = variants
+                    .iter()
+                    .map(|cluster| format!("{}={}", cluster.cluster_id, cluster.weight))
+                    .collect::<Vec<_>>();
+                write!(f, "ab test {} {}", cookie, variants.join(","))
+            }
         }
     }
 }
@@ -1039,6 +3062,92 @@ mod tests {
     use super::*;
     use serde_json;
 
+    #[test]
+    fn ip_range_test() {
+        let range: IpRange = "10.1.0.0/16".parse().unwrap();
+        assert!(range.contains("10.1.2.3".parse().unwrap()));
+        assert!(!range.contains("10.2.0.1".parse().unwrap()));
+        assert!(range.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert_eq!(range.to_string(), "10.1.0.0/16");
+
+        let single: IpRange = "2001:db8::1".parse().unwrap();
+        assert_eq!(single.prefix_length, 128);
+        assert!(single.contains("2001:db8::1".parse().unwrap()));
+        assert!(!single.contains("2001:db8::2".parse().unwrap()));
+
+        let everything: IpRange = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains("203.0.113.7".parse().unwrap()));
+        assert!(!everything.contains("2001:db8::1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("10.0.0/8".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn destination_test() {
+        let proxy = ForwardProxy {
+            allowed_destinations: vec![
+                "api.example.com:443".parse().unwrap(),
+                "*.internal.example.com:*".parse().unwrap(),
+                "10.1.0.0/16:22".parse().unwrap(),
+                "[2001:db8::/32]:443".parse().unwrap(),
+            ],
+            socks5: true,
+        };
+
+        assert!(proxy.allows_name("API.example.com", 443));
+        assert!(!proxy.allows_name("api.example.com", 80));
+        assert!(proxy.allows_name("db.internal.example.com", 5432));
+        assert!(!proxy.allows_name("internal.example.com", 5432));
+        assert!(!proxy.allows_name("www.example.com", 443));
+
+        assert!(proxy.allows_address("10.1.2.3:22".parse().unwrap()));
+        assert!(!proxy.allows_address("10.1.2.3:23".parse().unwrap()));
+        assert!(proxy.allows_address("[2001:db8::1]:443".parse().unwrap()));
+        assert!(!proxy.allows_address("203.0.113.7:443".parse().unwrap()));
+
+        assert_eq!(
+            proxy.allowed_destinations[3].to_string(),
+            "[2001:db8::/32]:443"
+        );
+        assert_eq!(
+            proxy.allowed_destinations[1].to_string(),
+            "*.internal.example.com:*"
+        );
+
+        assert!("api.example.com".parse::<Destination>().is_err());
+        assert!("api.example.com:http".parse::<Destination>().is_err());
+        assert!("api..example.com:443".parse::<Destination>().is_err());
+        assert!("[10.0.0.0/33]:443".parse::<Destination>().is_err());
+    }
+
+    #[test]
+    fn acl_test() {
+        let raw_json = r#"{"type": "ADD_ACL", "data": {"address": "0.0.0.0:8080", "proxy": "http", "mode": "DENY", "ranges": ["192.168.0.0/16", "10.0.0.1"]}}"#;
+        let command: ProxyRequestOrder =
+            serde_json::from_str(raw_json).expect("could not parse json");
+        let acl = match command {
+            ProxyRequestOrder::AddAcl(acl) => acl,
+            _ => panic!("expected an ACL, got {:?}", command),
+        };
+
+        assert!(!acl.allows("192.168.1.1".parse().unwrap()));
+        assert!(!acl.allows("10.0.0.1".parse().unwrap()));
+        assert!(acl.allows("10.0.0.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn soft_stop_without_deadline_test() {
+        let command: ProxyRequestOrder =
+            serde_json::from_str(r#"{"type": "SOFT_STOP"}"#).expect("could not parse json");
+        assert_eq!(command, ProxyRequestOrder::SoftStop(None));
+
+        let command: ProxyRequestOrder =
+            serde_json::from_str(r#"{"type": "SOFT_STOP", "data": 10}"#)
+                .expect("could not parse json");
+        assert_eq!(command, ProxyRequestOrder::SoftStop(Some(10)));
+    }
+
     #[test]
     fn add_front_test() {
         let raw_json = r#"{"type": "ADD_HTTP_FRONTEND", "data": {"route": { "CLUSTER_ID": "xxx"}, "hostname": "yyy", "path": {"PREFIX": "xxx"}, "address": "127.0.0.1:4242", "sticky_session": false}}"#;
@@ -1052,6 +3161,11 @@ mod tests {
                     hostname: String::from("yyy"),
                     path: PathRule::Prefix(String::from("xxx")),
                     method: None,
+                    methods: Vec::new(),
+                    reject_other_methods: false,
+                    headers: Vec::new(),
+                    rewrite_path: None,
+                    mirror_cluster_id: None,
                     address: "127.0.0.1:4242".parse().unwrap(),
                     position: RulePosition::Tree,
                     tags: None,
@@ -1072,6 +3186,11 @@ mod tests {
                     hostname: String::from("yyy"),
                     path: PathRule::Prefix(String::from("xxx")),
                     method: None,
+                    methods: Vec::new(),
+                    reject_other_methods: false,
+                    headers: Vec::new(),
+                    rewrite_path: None,
+                    mirror_cluster_id: None,
                     address: "127.0.0.1:4242".parse().unwrap(),
                     position: RulePosition::Tree,
                     tags: Some(BTreeMap::from([
@@ -1097,6 +3216,9 @@ mod tests {
                     sticky_id: None,
                     load_balancing_parameters: Some(LoadBalancingParams { weight: 0 }),
                     backup: None,
+                    max_connections: None,
+                    timeouts: Timeouts::default(),
+                    tls: None,
                 })
         );
     }
@@ -1130,6 +3252,11 @@ mod tests {
                     hostname: String::from("cltdl.fr"),
                     path: PathRule::Prefix(String::from("")),
                     method: None,
+                    methods: Vec::new(),
+                    reject_other_methods: false,
+                    headers: Vec::new(),
+                    rewrite_path: None,
+                    mirror_cluster_id: None,
                     address: "127.0.0.1:4242".parse().unwrap(),
                     position: RulePosition::Tree,
                     tags: Some(BTreeMap::from([
@@ -1152,10 +3279,159 @@ mod tests {
                     hostname: String::from("cltdl.fr"),
                     path: PathRule::Prefix(String::from("")),
                     method: None,
+                    methods: Vec::new(),
+                    reject_other_methods: false,
+                    headers: Vec::new(),
+                    rewrite_path: None,
+                    mirror_cluster_id: None,
                     address: "127.0.0.1:4242".parse().unwrap(),
                     position: RulePosition::Tree,
                     tags: None,
                 }
         );
     }
+
+    #[test]
+    fn weighted_front_test() {
+        let raw_json = r#"{"route": {"WEIGHTED": [{"cluster_id": "stable", "weight": 95}, {"cluster_id": "canary", "weight": 5}]}, "hostname": "cltdl.fr", "path": {"PREFIX": ""}, "address": "127.0.0.1:4242" }"#;
+        let front: HttpFrontend = serde_json::from_str(raw_json).expect("could not parse json");
+        assert_eq!(front.hostname, "cltdl.fr");
+        assert_eq!(front.path, PathRule::Prefix(String::new()));
+        assert_eq!(front.address, "127.0.0.1:4242".parse().unwrap());
+        assert_eq!(
+            front.route,
+            Route::Weighted(vec![
+                "stable=95".parse().unwrap(),
+                "canary=5".parse().unwrap(),
+            ])
+        );
+        assert!(front.is_cluster_id("canary"));
+        assert_eq!(front.route.to_string(), "stable=95,canary=5");
+    }
+
+    #[test]
+    fn deny_front_test() {
+        let raw_json = r#"{"route": "DENY", "hostname": "cltdl.fr", "address": "127.0.0.1:4242" }"#;
+        let front: HttpFrontend = serde_json::from_str(raw_json).expect("could not parse json");
+        assert_eq!(
+            front.route,
+            Route::Deny {
+                status: 401,
+                body_path: None
+            }
+        );
+
+        let serialized = serde_json::to_string(&front).expect("could not serialize");
+        let reparsed: HttpFrontend =
+            serde_json::from_str(&serialized).expect("could not parse json");
+        assert_eq!(reparsed, front);
+
+        let raw_json = r#"{"route": {"DENY": {"status": 403, "body_path": "/tmp/403.html"}}, "hostname": "cltdl.fr", "address": "127.0.0.1:4242" }"#;
+        let front: HttpFrontend = serde_json::from_str(raw_json).expect("could not parse json");
+        assert_eq!(
+            front.route,
+            Route::Deny {
+                status: 403,
+                body_path: Some("/tmp/403.html".to_string())
+            }
+        );
+
+        assert!(serde_json::from_str::<Route>(r#""CLUSTER_ID""#).is_err());
+    }
+
+    #[test]
+    fn ab_test_front_test() {
+        let raw_json = r#"{"route": {"AB_TEST": {"cookie": "variant", "variants": [{"cluster_id": "checkout-a", "weight": 50}, {"cluster_id": "checkout-b", "weight": 50}]}}, "hostname": "cltdl.fr", "address": "127.0.0.1:4242" }"#;
+        let front: HttpFrontend = serde_json::from_str(raw_json).expect("could not parse json");
+        assert_eq!(
+            front.route,
+            Route::AbTest {
+                cookie: "variant".to_string(),
+                variants: vec![
+                    "checkout-a=50".parse().unwrap(),
+                    "checkout-b=50".parse().unwrap(),
+                ]
+            }
+        );
+        assert!(front.is_cluster_id("checkout-b"));
+        assert_eq!(
+            front.route.to_string(),
+            "ab test variant checkout-a=50,checkout-b=50"
+        );
+    }
+
+    #[test]
+    fn header_action_validation_test() {
+        let action = |name: &str, value: &str| HeaderAction {
+            position: HeaderPosition::Request,
+            operation: HeaderOperation::Set,
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+
+        assert!(action("X-Forwarded-Prefix", "/api").validate().is_ok());
+        assert!(action("X-Empty", "").validate().is_ok());
+        assert!(action("", "value").validate().is_err());
+        assert!(action("X Bad", "value").validate().is_err());
+        assert!(action("X-Bad:", "value").validate().is_err());
+        assert!(action("X-Bad\r\nEvil", "value").validate().is_err());
+        assert!(action("X-Value", "a\r\nEvil: injected").validate().is_err());
+        assert!(action("X-Value", "a\nb").validate().is_err());
+        assert!(action("X-Value", "a\0b").validate().is_err());
+
+        let cluster = Cluster {
+            cluster_id: "api".to_string(),
+            header_actions: vec![action("X-Value", "a\r\nEvil: injected")],
+            ..Default::default()
+        };
+        assert!(cluster.validate().is_err());
+    }
+
+    #[test]
+    fn host_rewrite_validation_test() {
+        let fixed = |host: &str| HostRewrite::Fixed(host.to_string()).validate();
+
+        assert!(fixed("app.platform.example").is_ok());
+        assert!(fixed("app.platform.example:8080").is_ok());
+        assert!(fixed("10.0.0.1:8080").is_ok());
+        assert!(fixed("[2001:db8::1]:443").is_ok());
+        assert!(fixed("[2001:db8::1]").is_ok());
+        assert!(fixed("").is_err());
+        assert!(fixed("app.example\r\nX-Evil: injected").is_err());
+        assert!(fixed("app example").is_err());
+        assert!(fixed("app.example:http").is_err());
+        assert!(fixed("app.example:8080:1").is_err());
+        assert!(fixed("2001:db8::1").is_err());
+        assert!(fixed("[app.example]").is_err());
+        assert!(HostRewrite::BackendAddress.validate().is_ok());
+
+        let cluster = Cluster {
+            cluster_id: "api".to_string(),
+            host_rewrite: HostRewrite::Fixed("app\nexample".to_string()),
+            ..Default::default()
+        };
+        assert!(cluster.validate().is_err());
+    }
+
+    #[test]
+    fn access_log_filter_test() {
+        let filter = AccessLogFilter {
+            cluster_id: Some("api".to_string()),
+            status: Some(StatusRange { from: 500, to: 599 }),
+            max_per_second: 2,
+        };
+        assert!(filter.matches(Some("api"), Some(502)));
+        assert!(!filter.matches(Some("api"), Some(404)));
+        assert!(!filter.matches(Some("api"), None));
+        assert!(!filter.matches(Some("web"), Some(502)));
+        assert_eq!(filter.status.unwrap().to_string(), "5xx");
+
+        let now = Instant::now();
+        let mut limit = AccessLogRateLimit::default();
+        assert!(limit.allow(filter.max_per_second, now));
+        assert!(limit.allow(filter.max_per_second, now));
+        assert!(!limit.allow(filter.max_per_second, now));
+        assert!(limit.allow(filter.max_per_second, now + Duration::from_secs(1)));
+        assert_eq!(limit.dropped, 1);
+    }
 }
@@ -9,12 +9,17 @@ use std::{
 use serde::de::{self, Visitor};
 
 use crate::{
-    certificate::calculate_fingerprint,
+    certificate::{
+        calculate_fingerprint, certificate_name_matches, get_expiration_and_names, get_subject,
+    },
+    command::ListedCertificate,
     proxy::{
-        ActivateListener, AddCertificate, Backend, CertificateAndKey, CertificateFingerprint,
-        Cluster, DeactivateListener, HttpFrontend, HttpListener, HttpsListener, ListenerType,
-        PathRule, ProxyRequestOrder, QueryAnswerCluster, RemoveBackend, RemoveCertificate,
-        RemoveListener, Route, TcpFrontend, TcpListener,
+        Acl, ActivateListener, AddCertificate, Affinity, Backend, CertificateAndKey,
+        CertificateFingerprint, Cluster, ClusterMaintenance, DeactivateListener, HeaderRule,
+        HeaderValueRule, HttpFrontend, HttpListener, HttpsListener, ListenerType,
+        LoadBalancingParams, PathRule, ProxyRequestOrder, QueryAnswerCluster, RemoveAcl,
+        RemoveBackend, RemoveCertificate, RemoveListener, SetDefaultCertificate, SetOcspResponse,
+        SniFrontend, TcpFrontend, TcpListener,
     },
 };
 
@@ -48,12 +53,29 @@ pub struct ConfigState {
     /// indexed by (address, hostname, path)
     pub https_fronts: BTreeMap<RouteKey, HttpFrontend>,
     pub tcp_fronts: HashMap<ClusterId, Vec<TcpFrontend>>,
+    /// server names routed by the TCP listeners
+    #[serde(default)]
+    pub sni_fronts: HashMap<ClusterId, Vec<SniFrontend>>,
     /// certificate and names
     pub certificates:
         HashMap<SocketAddr, HashMap<CertificateFingerprint, (CertificateAndKey, Vec<String>)>>,
     //ip, port
     pub http_addresses: Vec<SocketAddr>,
     pub https_addresses: Vec<SocketAddr>,
+    /// source IP access control lists, indexed by listener address
+    #[serde(default)]
+    pub acls: BTreeMap<SocketAddr, Vec<Acl>>,
+    /// clusters in maintenance
+    #[serde(default)]
+    pub maintenance: BTreeMap<ClusterId, ClusterMaintenance>,
+    /// sticky keys and their backend ids, oldest first, for the clusters with an
+    /// affinity table. They are learnt from the traffic, not from the configuration
+    #[serde(default)]
+    pub affinities: BTreeMap<ClusterId, Vec<(String, String)>>,
+    /// the listeners handled by some of the workers only
+    #[serde(default)]
+    #[serde(skip_serializing_if = "WorkerScopes::is_empty")]
+    pub worker_scopes: WorkerScopes,
     //tcp:
 }
 
@@ -62,6 +84,23 @@ impl ConfigState {
         Self::default()
     }
 
+    /// a copy of the state without the private keys of the certificates and of
+    /// the backend client certificates
+    pub fn without_private_keys(&self) -> ConfigState {
+        let mut state = self.clone();
+        for certificates in state.certificates.values_mut() {
+            for (certificate_and_key, _) in certificates.values_mut() {
+                certificate_and_key.key.clear();
+            }
+        }
+        for cluster in state.clusters.values_mut() {
+            if let Some(backend_tls) = cluster.backend_tls.as_mut() {
+                backend_tls.client_key = None;
+            }
+        }
+        state
+    }
+
     pub fn add_http_address(&mut self, address: SocketAddr) {
         self.http_addresses.push(address)
     }
@@ -74,13 +113,66 @@ impl ConfigState {
     pub fn handle_order(&mut self, order: &ProxyRequestOrder) -> bool {
         match order {
             &ProxyRequestOrder::AddCluster(ref cluster) => {
+                if let Err(e) = cluster.validate() {
+                    error!("invalid cluster {}: {}", cluster.cluster_id, e);
+                    return false;
+                }
                 let cluster = cluster.clone();
+                match cluster.affinity_table {
+                    Some(table) => {
+                        if let Some(affinities) = self.affinities.get_mut(&cluster.cluster_id) {
+                            let len = affinities.len();
+                            affinities.drain(..len.saturating_sub(table.size));
+                        }
+                    }
+                    None => {
+                        self.affinities.remove(&cluster.cluster_id);
+                    }
+                }
                 self.clusters.insert(cluster.cluster_id.clone(), cluster);
                 true
             }
             &ProxyRequestOrder::RemoveCluster { ref cluster_id } => {
+                self.maintenance.remove(cluster_id);
+                self.affinities.remove(cluster_id);
                 self.clusters.remove(cluster_id).is_some()
             }
+            ProxyRequestOrder::SetAffinity(affinity) => {
+                let size = match self
+                    .clusters
+                    .get(&affinity.cluster_id)
+                    .and_then(|cluster| cluster.affinity_table)
+                {
+                    Some(table) => table.size,
+                    None => return false,
+                };
+
+                let affinities = self
+                    .affinities
+                    .entry(affinity.cluster_id.clone())
+                    .or_default();
+                if affinities.iter().any(|(key, backend_id)| {
+                    *key == affinity.key && *backend_id == affinity.backend_id
+                }) {
+                    return false;
+                }
+
+                affinities.retain(|(key, _)| *key != affinity.key);
+                affinities.push((affinity.key.clone(), affinity.backend_id.clone()));
+                let len = affinities.len();
+                affinities.drain(..len.saturating_sub(size));
+                true
+            }
+            ProxyRequestOrder::SetClusterMaintenance(maintenance) => {
+                if maintenance.enabled {
+                    self.maintenance
+                        .insert(maintenance.cluster_id.clone(), maintenance.clone())
+                        .as_ref()
+                        != Some(maintenance)
+                } else {
+                    self.maintenance.remove(&maintenance.cluster_id).is_some()
+                }
+            }
             &ProxyRequestOrder::AddHttpListener(ref listener) => {
                 if let std::collections::hash_map::Entry::Vacant(e) =
                     self.http_listeners.entry(listener.address)
@@ -111,11 +203,16 @@ impl ConfigState {
                     false
                 }
             }
-            &ProxyRequestOrder::RemoveListener(ref remove) => match remove.proxy {
-                ListenerType::HTTP => self.http_listeners.remove(&remove.address).is_some(),
-                ListenerType::HTTPS => self.https_listeners.remove(&remove.address).is_some(),
-                ListenerType::TCP => self.tcp_listeners.remove(&remove.address).is_some(),
-            },
+            &ProxyRequestOrder::RemoveListener(ref remove) => {
+                // the ACLs and the scope go away with their listener
+                self.acls.remove(&remove.address);
+                self.worker_scopes.0.remove(&remove.address);
+                match remove.proxy {
+                    ListenerType::HTTP => self.http_listeners.remove(&remove.address).is_some(),
+                    ListenerType::HTTPS => self.https_listeners.remove(&remove.address).is_some(),
+                    ListenerType::TCP => self.tcp_listeners.remove(&remove.address).is_some(),
+                }
+            }
             &ProxyRequestOrder::ActivateListener(ref activate) => match activate.proxy {
                 ListenerType::HTTP => self
                     .http_listeners
@@ -156,7 +253,8 @@ impl ConfigState {
                         front.address,
                         front.hostname.to_string(),
                         front.path.clone(),
-                        front.method.clone(),
+                        front.method_key(),
+                        front.headers.clone(),
                     ))
                 {
                     e.insert(front.clone());
@@ -171,7 +269,8 @@ impl ConfigState {
                     front.address,
                     front.hostname.to_string(),
                     front.path.clone(),
-                    front.method.clone(),
+                    front.method_key(),
+                    front.headers.clone(),
                 ))
                 .is_some(),
             &ProxyRequestOrder::AddCertificate(ref add) => {
@@ -210,6 +309,30 @@ impl ConfigState {
                 .get_mut(&remove.address)
                 .and_then(|certs| certs.remove(&remove.fingerprint))
                 .is_some(),
+            &ProxyRequestOrder::SetOcspResponse(ref ocsp) => {
+                match self
+                    .certificates
+                    .get_mut(&ocsp.address)
+                    .and_then(|certs| certs.get_mut(&ocsp.fingerprint))
+                {
+                    Some((certificate_and_key, _))
+                        if certificate_and_key.ocsp_response != ocsp.ocsp_response =>
+                    {
+                        certificate_and_key.ocsp_response = ocsp.ocsp_response.clone();
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            ProxyRequestOrder::SetDefaultCertificate(default) => {
+                match self.https_listeners.get_mut(&default.address) {
+                    Some((listener, _)) if listener.default_certificate != default.fingerprint => {
+                        listener.default_certificate = default.fingerprint.clone();
+                        true
+                    }
+                    _ => false,
+                }
+            }
             &ProxyRequestOrder::ReplaceCertificate(ref replace) => {
                 let changed = self
                     .certificates
@@ -253,7 +376,8 @@ impl ConfigState {
                         front.address,
                         front.hostname.to_string(),
                         front.path.clone(),
-                        front.method.clone(),
+                        front.method_key(),
+                        front.headers.clone(),
                     ))
                 {
                     e.insert(front.clone());
@@ -268,7 +392,8 @@ impl ConfigState {
                     front.address,
                     front.hostname.to_string(),
                     front.path.clone(),
-                    front.method.clone(),
+                    front.method_key(),
+                    front.headers.clone(),
                 ))
                 .is_some(),
             &ProxyRequestOrder::AddTcpFrontend(ref front) => {
@@ -292,6 +417,25 @@ impl ConfigState {
                     false
                 }
             }
+            ProxyRequestOrder::AddSniFrontend(front) => {
+                let front_vec = self.sni_fronts.entry(front.cluster_id.clone()).or_default();
+                if !front_vec.contains(front) {
+                    front_vec.push(front.clone());
+                    true
+                } else {
+                    false
+                }
+            }
+            ProxyRequestOrder::RemoveSniFrontend(front) => {
+                if let Some(front_list) = self.sni_fronts.get_mut(&front.cluster_id) {
+                    let len = front_list.len();
+                    front_list
+                        .retain(|el| el.address != front.address || el.hostname != front.hostname);
+                    front_list.len() != len
+                } else {
+                    false
+                }
+            }
             &ProxyRequestOrder::AddBackend(ref backend) => {
                 let backend_vec = self
                     .backends
@@ -307,6 +451,10 @@ impl ConfigState {
                 true
             }
             &ProxyRequestOrder::RemoveBackend(ref backend) => {
+                if let Some(affinities) = self.affinities.get_mut(&backend.cluster_id) {
+                    affinities.retain(|(_, backend_id)| *backend_id != backend.backend_id);
+                }
+
                 if let Some(backend_list) = self.backends.get_mut(&backend.cluster_id) {
                     let len = backend_list.len();
                     backend_list.retain(|b| {
@@ -318,11 +466,86 @@ impl ConfigState {
                     false
                 }
             }
+            // the drain itself is only known by the workers
+            ProxyRequestOrder::DrainBackend(backend) => {
+                match self.backends.get_mut(&backend.cluster_id) {
+                    Some(backend_list) => {
+                        let found = backend_list.iter().any(|b| {
+                            b.backend_id == backend.backend_id && b.address == backend.address
+                        });
+                        if backend.remove {
+                            if let Some(affinities) = self.affinities.get_mut(&backend.cluster_id) {
+                                affinities
+                                    .retain(|(_, backend_id)| *backend_id != backend.backend_id);
+                            }
+                            backend_list.retain(|b| {
+                                b.backend_id != backend.backend_id || b.address != backend.address
+                            });
+                        }
+                        found
+                    }
+                    None => false,
+                }
+            }
+            ProxyRequestOrder::UpdateBackendWeight(update) => {
+                let backend = self
+                    .backends
+                    .get_mut(&update.cluster_id)
+                    .and_then(|backends| {
+                        backends.iter_mut().find(|b| {
+                            b.backend_id == update.backend_id && b.address == update.address
+                        })
+                    });
+
+                match backend {
+                    Some(backend) => {
+                        backend.load_balancing_parameters = Some(LoadBalancingParams {
+                            weight: update.weight,
+                        });
+                        true
+                    }
+                    None => false,
+                }
+            }
+            ProxyRequestOrder::AddAcl(acl) => {
+                let acls = self.acls.entry(acl.address).or_default();
+                if acls.contains(acl) {
+                    return false;
+                }
+
+                // a listener has at most one ACL per hostname
+                acls.retain(|a| a.hostname != acl.hostname);
+                acls.push(acl.clone());
+                true
+            }
+            ProxyRequestOrder::RemoveAcl(remove) => {
+                if let Some(acls) = self.acls.get_mut(&remove.address) {
+                    let len = acls.len();
+                    acls.retain(|a| a.hostname != remove.hostname);
+                    let changed = acls.len() != len;
+                    if acls.is_empty() {
+                        self.acls.remove(&remove.address);
+                    }
+                    changed
+                } else {
+                    false
+                }
+            }
+            ProxyRequestOrder::Batch(orders) => {
+                let mut changed = false;
+                for order in orders {
+                    changed |= self.handle_order(order);
+                }
+                changed
+            }
             // This is to avoid the error message
             &ProxyRequestOrder::Logging(_)
             | &ProxyRequestOrder::Status
             | &ProxyRequestOrder::Query(_)
-            | &ProxyRequestOrder::SoftStop
+            | &ProxyRequestOrder::SetTicketKeys(_)
+            | &ProxyRequestOrder::SetAccessLogFilters(_)
+            | &ProxyRequestOrder::NameResolved(_)
+            | &ProxyRequestOrder::SoftStop(_)
             | &ProxyRequestOrder::HardStop => false,
             o => {
                 error!("state cannot handle order message: {:#?}", o);
@@ -367,10 +590,22 @@ impl ConfigState {
             }
         }
 
+        for acls in self.acls.values() {
+            for acl in acls {
+                v.push(ProxyRequestOrder::AddAcl(acl.clone()));
+            }
+        }
+
         for cluster in self.clusters.values() {
             v.push(ProxyRequestOrder::AddCluster(cluster.clone()));
         }
 
+        for maintenance in self.maintenance.values() {
+            v.push(ProxyRequestOrder::SetClusterMaintenance(
+                maintenance.clone(),
+            ));
+        }
+
         for front in self.http_fronts.values() {
             v.push(ProxyRequestOrder::AddHttpFrontend(front.clone()));
         }
@@ -396,12 +631,28 @@ impl ConfigState {
             }
         }
 
+        for front_list in self.sni_fronts.values() {
+            for front in front_list {
+                v.push(ProxyRequestOrder::AddSniFrontend(front.clone()));
+            }
+        }
+
         for backend_list in self.backends.values() {
             for backend in backend_list {
                 v.push(ProxyRequestOrder::AddBackend(backend.clone()));
             }
         }
 
+        for (cluster_id, affinities) in self.affinities.iter() {
+            for (key, backend_id) in affinities {
+                v.push(ProxyRequestOrder::SetAffinity(Affinity {
+                    cluster_id: cluster_id.clone(),
+                    key: key.clone(),
+                    backend_id: backend_id.clone(),
+                }));
+            }
+        }
+
         v
     }
 
@@ -448,6 +699,34 @@ impl ConfigState {
         v
     }
 
+    /// the part of the state handled by a worker, without the listeners
+    /// scoped to other workers, their frontends, certificates and ACLs
+    pub fn scoped_to(&self, worker_id: u32) -> ConfigState {
+        let mut state = self.clone();
+        if self.worker_scopes.is_empty() {
+            return state;
+        }
+
+        let scopes = &self.worker_scopes;
+        let handled = |address: &SocketAddr| scopes.handles(worker_id, address);
+        state.http_listeners.retain(|address, _| handled(address));
+        state.https_listeners.retain(|address, _| handled(address));
+        state.tcp_listeners.retain(|address, _| handled(address));
+        state.http_fronts.retain(|key, _| handled(&key.0));
+        state.https_fronts.retain(|key, _| handled(&key.0));
+        for fronts in state.tcp_fronts.values_mut() {
+            fronts.retain(|front| handled(&front.address));
+        }
+        for fronts in state.sni_fronts.values_mut() {
+            fronts.retain(|front| handled(&front.address));
+        }
+        state.certificates.retain(|address, _| handled(address));
+        state.http_addresses.retain(handled);
+        state.https_addresses.retain(handled);
+        state.acls.retain(|address, _| handled(address));
+        state
+    }
+
     pub fn diff(&self, other: &ConfigState) -> Vec<ProxyRequestOrder> {
         //pub tcp_listeners:   HashMap<SocketAddr, (TcpListener, bool)>,
         let my_tcp_listeners: HashSet<&SocketAddr> = self.tcp_listeners.keys().collect();
@@ -618,7 +897,22 @@ impl ConfigState {
             let (my_listener, my_active) = &self.https_listeners[addr];
             let (their_listener, their_active) = &other.https_listeners[addr];
 
-            if my_listener != their_listener {
+            // the default certificate can be changed without recreating the listener
+            let only_default_changed = my_listener.default_certificate
+                != their_listener.default_certificate
+                && HttpsListener {
+                    default_certificate: their_listener.default_certificate.clone(),
+                    ..my_listener.clone()
+                } == *their_listener;
+
+            if only_default_changed {
+                v.push(ProxyRequestOrder::SetDefaultCertificate(
+                    SetDefaultCertificate {
+                        address: **addr,
+                        fingerprint: their_listener.default_certificate.clone(),
+                    },
+                ));
+            } else if my_listener != their_listener {
                 v.push(ProxyRequestOrder::RemoveListener(RemoveListener {
                     address: **addr,
                     proxy: ListenerType::HTTPS,
@@ -655,6 +949,21 @@ impl ConfigState {
             }
         }
 
+        for (cluster_id, res) in diff_map(self.maintenance.iter(), other.maintenance.iter()) {
+            match res {
+                DiffResult::Added | DiffResult::Changed => v.push(
+                    ProxyRequestOrder::SetClusterMaintenance(other.maintenance[cluster_id].clone()),
+                ),
+                DiffResult::Removed => v.push(ProxyRequestOrder::SetClusterMaintenance(
+                    ClusterMaintenance {
+                        cluster_id: cluster_id.to_string(),
+                        enabled: false,
+                        answer: None,
+                    },
+                )),
+            }
+        }
+
         for ((cluster_id, backend_id), res) in diff_map(
             self.backends.iter().flat_map(|(cluster_id, v)| {
                 v.iter()
@@ -773,6 +1082,17 @@ impl ConfigState {
             v.push(ProxyRequestOrder::AddTcpFrontend(front.clone()));
         }
 
+        let my_sni_fronts: HashSet<&SniFrontend> = self.sni_fronts.values().flatten().collect();
+        let their_sni_fronts: HashSet<&SniFrontend> = other.sni_fronts.values().flatten().collect();
+
+        for front in my_sni_fronts.difference(&their_sni_fronts) {
+            v.push(ProxyRequestOrder::RemoveSniFrontend((*front).clone()));
+        }
+
+        for front in their_sni_fronts.difference(&my_sni_fronts) {
+            v.push(ProxyRequestOrder::AddSniFrontend((*front).clone()));
+        }
+
         //pub certificates:    HashMap<SocketAddr, HashMap<CertificateFingerprint, (CertificateAndKey, Vec<String>)>>,
         let my_certificates: HashSet<(SocketAddr, &CertificateFingerprint)> = HashSet::from_iter(
             self.certificates
@@ -811,6 +1131,55 @@ impl ConfigState {
             }
         }
 
+        for (address, fingerprint) in my_certificates.intersection(&their_certificates) {
+            let my_ocsp_response = self
+                .certificates
+                .get(address)
+                .and_then(|certs| certs.get(fingerprint))
+                .and_then(|(certificate_and_key, _)| certificate_and_key.ocsp_response.as_ref());
+            let their_ocsp_response = other
+                .certificates
+                .get(address)
+                .and_then(|certs| certs.get(fingerprint))
+                .and_then(|(certificate_and_key, _)| certificate_and_key.ocsp_response.as_ref());
+
+            if my_ocsp_response != their_ocsp_response {
+                v.push(ProxyRequestOrder::SetOcspResponse(SetOcspResponse {
+                    address: *address,
+                    fingerprint: (*fingerprint).clone(),
+                    ocsp_response: their_ocsp_response.cloned(),
+                }));
+            }
+        }
+
+        for acl in self.acls.values().flatten() {
+            let kept = other
+                .acls
+                .get(&acl.address)
+                .into_iter()
+                .flatten()
+                .any(|a| a.hostname == acl.hostname && a.proxy == acl.proxy);
+            if !kept {
+                v.push(ProxyRequestOrder::RemoveAcl(RemoveAcl {
+                    address: acl.address,
+                    proxy: acl.proxy.clone(),
+                    hostname: acl.hostname.clone(),
+                }));
+            }
+        }
+
+        for acl in other.acls.values().flatten() {
+            let unchanged = self
+                .acls
+                .get(&acl.address)
+                .into_iter()
+                .flatten()
+                .any(|a| a == acl);
+            if !unchanged {
+                v.push(ProxyRequestOrder::AddAcl(acl.clone()));
+            }
+        }
+
         for address in added_tcp_listeners {
             let listener = &other.tcp_listeners[address];
             if listener.1 {
@@ -825,6 +1194,148 @@ impl ConfigState {
         v
     }
 
+    /// the listeners, clusters, frontends, backends and certificates that differ
+    /// from the other state, to be read by people. `diff` gives the orders
+    pub fn changes(&self, other: &ConfigState) -> Vec<StateChange> {
+        let mut changes = Vec::new();
+
+        push_changes(
+            &mut changes,
+            StateObject::Listener,
+            listeners(&self.http_listeners).into_iter(),
+            listeners(&other.http_listeners).into_iter(),
+            |address| format!("HTTP {}", address),
+        );
+        push_changes(
+            &mut changes,
+            StateObject::Listener,
+            listeners(&self.https_listeners).into_iter(),
+            listeners(&other.https_listeners).into_iter(),
+            |address| format!("HTTPS {}", address),
+        );
+        push_changes(
+            &mut changes,
+            StateObject::Listener,
+            listeners(&self.tcp_listeners).into_iter(),
+            listeners(&other.tcp_listeners).into_iter(),
+            |address| format!("TCP {}", address),
+        );
+
+        push_changes(
+            &mut changes,
+            StateObject::Cluster,
+            self.clusters.iter(),
+            other.clusters.iter(),
+            |cluster_id| cluster_id.to_string(),
+        );
+
+        let describe_route = |protocol: &str, key: &&RouteKey| {
+            let RouteKey(address, hostname, path, method, headers) = key;
+            let mut description =
+                format!("{} {} on {}, path {}", protocol, hostname, address, path);
+            if let Some(method) = method {
+                description.push_str(&format!(", method {}", method));
+            }
+            for header in headers {
+                description.push_str(&format!(", header {}", header));
+            }
+            description
+        };
+        push_changes(
+            &mut changes,
+            StateObject::Frontend,
+            self.http_fronts.iter(),
+            other.http_fronts.iter(),
+            |key| describe_route("HTTP", key),
+        );
+        push_changes(
+            &mut changes,
+            StateObject::Frontend,
+            self.https_fronts.iter(),
+            other.https_fronts.iter(),
+            |key| describe_route("HTTPS", key),
+        );
+        fn tcp_fronts(state: &ConfigState) -> BTreeMap<(String, SocketAddr), &TcpFrontend> {
+            state
+                .tcp_fronts
+                .values()
+                .flatten()
+                .map(|front| ((front.cluster_id.clone(), front.address), front))
+                .collect()
+        }
+        push_changes(
+            &mut changes,
+            StateObject::Frontend,
+            tcp_fronts(self).into_iter(),
+            tcp_fronts(other).into_iter(),
+            |(cluster_id, address)| format!("TCP {} of {}", address, cluster_id),
+        );
+        fn sni_fronts(state: &ConfigState) -> BTreeMap<&SniFrontend, &SniFrontend> {
+            state
+                .sni_fronts
+                .values()
+                .flatten()
+                .map(|front| (front, front))
+                .collect()
+        }
+        push_changes(
+            &mut changes,
+            StateObject::Frontend,
+            sni_fronts(self).into_iter(),
+            sni_fronts(other).into_iter(),
+            |front| {
+                format!(
+                    "SNI {} on {} of {}",
+                    front.hostname, front.address, front.cluster_id
+                )
+            },
+        );
+
+        fn backends(state: &ConfigState) -> BTreeMap<(String, String), &Backend> {
+            state
+                .backends
+                .values()
+                .flatten()
+                .map(|backend| {
+                    (
+                        (backend.cluster_id.clone(), backend.backend_id.clone()),
+                        backend,
+                    )
+                })
+                .collect()
+        }
+        push_changes(
+            &mut changes,
+            StateObject::Backend,
+            backends(self).into_iter(),
+            backends(other).into_iter(),
+            |(cluster_id, backend_id)| format!("{} of {}", backend_id, cluster_id),
+        );
+
+        fn certificates(
+            state: &ConfigState,
+        ) -> BTreeMap<(SocketAddr, String), &(CertificateAndKey, Vec<String>)> {
+            state
+                .certificates
+                .iter()
+                .flat_map(|(address, certificates)| {
+                    certificates.iter().map(move |(fingerprint, certificate)| {
+                        ((*address, fingerprint.to_string()), certificate)
+                    })
+                })
+                .collect()
+        }
+        push_changes(
+            &mut changes,
+            StateObject::Certificate,
+            certificates(self).into_iter(),
+            certificates(other).into_iter(),
+            |(address, fingerprint)| format!("{} on {}", fingerprint, address),
+        );
+
+        changes
+    }
+
     // FIXME: what about deny rules?
     pub fn hash_state(&self) -> BTreeMap<ClusterId, u64> {
         let mut h: HashMap<_, _> = self
@@ -839,12 +1350,15 @@ impl ConfigState {
                 if let Some(v) = self.tcp_fronts.get(cluster_id) {
                     v.iter().collect::<BTreeSet<_>>().hash(&mut s)
                 }
+                if let Some(v) = self.sni_fronts.get(cluster_id) {
+                    v.iter().collect::<BTreeSet<_>>().hash(&mut s)
+                }
                 (cluster_id.to_string(), s)
             })
             .collect();
 
         for front in self.http_fronts.values() {
-            if let Route::ClusterId(cluster_id) = &front.route {
+            for cluster_id in front.route.cluster_ids() {
                 if let Some(s) = h.get_mut(cluster_id) {
                     front.hash(s);
                 }
@@ -852,7 +1366,7 @@ impl ConfigState {
         }
 
         for front in self.https_fronts.values() {
-            if let Route::ClusterId(cluster_id) = &front.route {
+            for cluster_id in front.route.cluster_ids() {
                 if let Some(s) = h.get_mut(cluster_id) {
                     front.hash(s);
                 }
@@ -869,36 +1383,21 @@ impl ConfigState {
             configuration: self.clusters.get(cluster_id).cloned(),
             http_frontends: self
                 .http_fronts
-                .iter()
-                .filter_map(|(_k, v)| match &v.route {
-                    Route::Deny => None,
-                    Route::ClusterId(id) => {
-                        if id == cluster_id {
-                            Some(v)
-                        } else {
-                            None
-                        }
-                    }
-                })
+                .values()
+                .filter(|front| front.is_cluster_id(cluster_id))
                 .cloned()
                 .collect(),
             https_frontends: self
                 .https_fronts
-                .iter()
-                .filter_map(|(_k, v)| match &v.route {
-                    Route::Deny => None,
-                    Route::ClusterId(id) => {
-                        if id == cluster_id {
-                            Some(v)
-                        } else {
-                            None
-                        }
-                    }
-                })
+                .values()
+                .filter(|front| front.is_cluster_id(cluster_id))
                 .cloned()
                 .collect(),
             tcp_frontends: self.tcp_fronts.get(cluster_id).cloned().unwrap_or_default(),
+            sni_frontends: self.sni_fronts.get(cluster_id).cloned().unwrap_or_default(),
             backends: self.backends.get(cluster_id).cloned().unwrap_or_default(),
+            // only the workers check the backends
+            unhealthy_backends: Vec::new(),
         }
     }
 
@@ -910,6 +1409,202 @@ impl ConfigState {
         self.http_fronts.values().count()
             + self.https_fronts.values().count()
             + self.tcp_fronts.values().fold(0, |acc, v| acc + v.len())
+            + self.sni_fronts.values().fold(0, |acc, v| acc + v.len())
+    }
+
+    /// true if a certificate of the listener provides a name covering the hostname,
+    /// the names given when adding a certificate replace the ones it provides
+    pub fn certificate_covers(&self, address: &SocketAddr, hostname: &str) -> bool {
+        self.covering_certificate(address, hostname).is_some()
+    }
+
+    /// a certificate of the listener covering the hostname, the one with the
+    /// highest priority if there are several
+    pub fn covering_certificate(
+        &self,
+        address: &SocketAddr,
+        hostname: &str,
+    ) -> Option<&CertificateAndKey> {
+        self.certificates
+            .get(address)?
+            .values()
+            .filter(|(certificate_and_key, names)| covers(certificate_and_key, names, hostname))
+            .map(|(certificate_and_key, _)| certificate_and_key)
+            .max_by_key(|certificate_and_key| certificate_and_key.priority)
+    }
+
+    /// the cluster with its frontends, backends, and the certificates covering
+    /// its HTTPS frontends
+    pub fn cluster_document(&self, cluster_id: &str) -> Option<ClusterDocument> {
+        let cluster_state = self.cluster_state(cluster_id);
+        let cluster = cluster_state.configuration?;
+
+        let mut certificates: Vec<AddCertificate> = Vec::new();
+        for front in cluster_state.https_frontends.iter() {
+            let listener_certificates = match self.certificates.get(&front.address) {
+                Some(listener_certificates) => listener_certificates,
+                None => continue,
+            };
+            for (certificate_and_key, names) in listener_certificates.values() {
+                let known = certificates.iter().any(|certificate| {
+                    certificate.address == front.address
+                        && certificate.certificate == *certificate_and_key
+                });
+                if !known && covers(certificate_and_key, names, &front.hostname) {
+                    certificates.push(AddCertificate {
+                        address: front.address,
+                        certificate: certificate_and_key.clone(),
+                        names: names.clone(),
+                        expired_at: None,
+                    });
+                }
+            }
+        }
+
+        Some(ClusterDocument {
+            cluster,
+            http_frontends: cluster_state.http_frontends,
+            https_frontends: cluster_state.https_frontends,
+            tcp_frontends: cluster_state.tcp_frontends,
+            sni_frontends: cluster_state.sni_frontends,
+            backends: cluster_state.backends,
+            certificates,
+        })
+    }
+
+    /// this state where the clusters of the documents replace the ones with the
+    /// same ids, with all their frontends and backends. The certificates of the
+    /// documents are added, the other ones are kept since they may be shared
+    pub fn with_clusters(&self, documents: &[ClusterDocument]) -> ConfigState {
+        let mut state = self.clone();
+
+        for document in documents {
+            let cluster_id = &document.cluster.cluster_id;
+            state
+                .http_fronts
+                .retain(|_, front| !front.is_cluster_id(cluster_id));
+            state
+                .https_fronts
+                .retain(|_, front| !front.is_cluster_id(cluster_id));
+            state.tcp_fronts.remove(cluster_id);
+            state.sni_fronts.remove(cluster_id);
+            state.backends.remove(cluster_id);
+
+            for order in document.generate_orders() {
+                state.handle_order(&order);
+            }
+        }
+
+        state
+    }
+
+    /// this state with its listeners and only the clusters with these ids, with
+    /// their frontends, backends and the certificates covering their HTTPS frontends
+    pub fn only_clusters(&self, cluster_ids: &[String]) -> ConfigState {
+        let documents = cluster_ids
+            .iter()
+            .filter_map(|cluster_id| self.cluster_document(cluster_id))
+            .collect::<Vec<_>>();
+
+        let mut state = self.clone();
+        state.clusters.clear();
+        state.backends.clear();
+        state.http_fronts.clear();
+        state.https_fronts.clear();
+        state.tcp_fronts.clear();
+        state.sni_fronts.clear();
+        state.certificates.clear();
+        state
+            .maintenance
+            .retain(|cluster_id, _| cluster_ids.contains(cluster_id));
+        state
+            .affinities
+            .retain(|cluster_id, _| cluster_ids.contains(cluster_id));
+
+        state.with_clusters(&documents)
+    }
+
+    /// this state without the certificates and keys, the ones of the HTTPS
+    /// listeners included
+    pub fn without_certificates(&self) -> ConfigState {
+        let mut state = self.clone();
+        state.certificates.clear();
+        for (listener, _) in state.https_listeners.values_mut() {
+            listener.certificate = None;
+            listener.certificate_chain.clear();
+            listener.key = None;
+        }
+        state
+    }
+
+    /// this state with the orders of the other one executed over it: its
+    /// clusters replace the ones with the same ids, its listeners, frontends,
+    /// backends and certificates are added, the listeners of this state are kept
+    pub fn merge(&self, other: &ConfigState) -> ConfigState {
+        let mut state = self.clone();
+        for order in other.generate_orders() {
+            state.handle_order(&order);
+        }
+        for (address, workers) in other.worker_scopes.0.iter() {
+            state.worker_scopes.0.insert(*address, workers.clone());
+        }
+        state
+    }
+
+    /// parses the expiration, subject and names of the certificates, the names
+    /// given when adding a certificate replace the ones it provides, and finds
+    /// the hostnames of the HTTPS frontends they cover
+    pub fn list_certificates(&self) -> Vec<ListedCertificate> {
+        let mut listed_certificates = Vec::new();
+
+        for (address, certificates) in self.certificates.iter() {
+            for (fingerprint, (certificate_and_key, names)) in certificates.iter() {
+                let (expiration, certificate_names) =
+                    match get_expiration_and_names(certificate_and_key.certificate.as_bytes()) {
+                        Ok(expiration_and_names) => expiration_and_names,
+                        Err(e) => {
+                            error!("cannot parse the certificate {}: {:#}", fingerprint, e);
+                            continue;
+                        }
+                    };
+
+                let names: Vec<String> = if names.is_empty() {
+                    certificate_names.into_iter().collect()
+                } else {
+                    names.clone()
+                };
+                let hostnames: BTreeSet<String> = self
+                    .https_fronts
+                    .values()
+                    .filter(|front| front.address == *address)
+                    .filter(|front| {
+                        names
+                            .iter()
+                            .any(|name| certificate_name_matches(name, &front.hostname))
+                    })
+                    .map(|front| front.hostname.clone())
+                    .collect();
+
+                listed_certificates.push(ListedCertificate {
+                    address: *address,
+                    fingerprint: fingerprint.clone(),
+                    subject: get_subject(certificate_and_key.certificate.as_bytes())
+                        .unwrap_or_default(),
+                    names,
+                    expiration,
+                    hostnames: hostnames.into_iter().collect(),
+                });
+            }
+        }
+
+        listed_certificates.sort_by(|a, b| {
+            (a.expiration, a.address, &a.fingerprint).cmp(&(
+                b.expiration,
+                b.address,
+                &b.fingerprint,
+            ))
+        });
+        listed_certificates
     }
 }
 
@@ -939,7 +1634,7 @@ pub fn get_cluster_ids_by_domain(
 
     state.http_fronts.values().for_each(|front| {
         if domain_check(&front.hostname, &front.path, &hostname, &path) {
-            if let Route::ClusterId(id) = &front.route {
+            for id in front.route.cluster_ids() {
                 cluster_ids.insert(id.to_string());
             }
         }
@@ -947,7 +1642,7 @@ pub fn get_cluster_ids_by_domain(
 
     state.https_fronts.values().for_each(|front| {
         if domain_check(&front.hostname, &front.path, &hostname, &path) {
-            if let Route::ClusterId(id) = &front.route {
+            for id in front.route.cluster_ids() {
                 cluster_ids.insert(id.to_string());
             }
         }
@@ -965,6 +1660,243 @@ pub fn get_certificate(state: &ConfigState, fingerprint: &[u8]) -> Option<(Strin
         .next()
 }
 
+/// true if the certificate provides a name covering the hostname, the names given
+/// when adding a certificate replace the ones it provides
+fn covers(certificate_and_key: &CertificateAndKey, names: &[String], hostname: &str) -> bool {
+    let names = if names.is_empty() {
+        match get_expiration_and_names(certificate_and_key.certificate.as_bytes()) {
+            Ok((_, certificate_names)) => certificate_names.into_iter().collect(),
+            Err(_) => return false,
+        }
+    } else {
+        names.to_vec()
+    };
+
+    names
+        .iter()
+        .any(|name| certificate_name_matches(name, hostname))
+}
+
+/// a cluster with its frontends, backends and certificates, as exported by
+/// `sozu cluster export` and provisioned in one batch by `sozu cluster apply`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClusterDocument {
+    pub cluster: Cluster,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub http_frontends: Vec<HttpFrontend>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub https_frontends: Vec<HttpFrontend>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tcp_frontends: Vec<TcpFrontend>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sni_frontends: Vec<SniFrontend>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub backends: Vec<Backend>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub certificates: Vec<AddCertificate>,
+}
+
+impl ClusterDocument {
+    /// the orders adding the cluster, in the order of `ConfigState::generate_orders`
+    pub fn generate_orders(&self) -> Vec<ProxyRequestOrder> {
+        let mut v = vec![ProxyRequestOrder::AddCluster(self.cluster.clone())];
+
+        for front in self.http_frontends.iter() {
+            v.push(ProxyRequestOrder::AddHttpFrontend(front.clone()));
+        }
+        for certificate in self.certificates.iter() {
+            v.push(ProxyRequestOrder::AddCertificate(certificate.clone()));
+        }
+        for front in self.https_frontends.iter() {
+            v.push(ProxyRequestOrder::AddHttpsFrontend(front.clone()));
+        }
+        for front in self.tcp_frontends.iter() {
+            v.push(ProxyRequestOrder::AddTcpFrontend(front.clone()));
+        }
+        for front in self.sni_frontends.iter() {
+            v.push(ProxyRequestOrder::AddSniFrontend(front.clone()));
+        }
+        for backend in self.backends.iter() {
+            v.push(ProxyRequestOrder::AddBackend(backend.clone()));
+        }
+
+        v
+    }
+}
+
+/// a listener, cluster, frontend, backend or certificate differing between
+/// two states, as listed by `sozu config diff`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateChange {
+    pub kind: ChangeKind,
+    pub object: StateObject,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StateObject {
+    Listener,
+    Cluster,
+    Frontend,
+    Backend,
+    Certificate,
+}
+
+impl fmt::Display for StateChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = match self.kind {
+            ChangeKind::Added => '+',
+            ChangeKind::Removed => '-',
+            ChangeKind::Changed => '~',
+        };
+        let object = match self.object {
+            StateObject::Listener => "listener",
+            StateObject::Cluster => "cluster",
+            StateObject::Frontend => "frontend",
+            StateObject::Backend => "backend",
+            StateObject::Certificate => "certificate",
+        };
+        write!(f, "{} {} {}", sign, object, self.description)
+    }
+}
+
+/// the listeners by address, without their activation, which is not part of
+/// their configuration
+fn listeners<L>(listeners: &HashMap<SocketAddr, (L, bool)>) -> BTreeMap<SocketAddr, &L> {
+    listeners
+        .iter()
+        .map(|(address, (listener, _))| (*address, listener))
+        .collect()
+}
+
+/// the iterators must be sorted by key, like the ones of `diff_map`
+fn push_changes<'a, K: Ord, V: PartialEq + 'a>(
+    changes: &mut Vec<StateChange>,
+    object: StateObject,
+    my: impl Iterator<Item = (K, &'a V)>,
+    other: impl Iterator<Item = (K, &'a V)>,
+    describe: impl Fn(&K) -> String,
+) {
+    for (key, result) in diff_map(my, other) {
+        changes.push(StateChange {
+            kind: match result {
+                DiffResult::Added => ChangeKind::Added,
+                DiffResult::Removed => ChangeKind::Removed,
+                DiffResult::Changed => ChangeKind::Changed,
+            },
+            object,
+            description: describe(&key),
+        });
+    }
+}
+
+/// the workers handling a listener, with its frontends, certificates and
+/// ACLs. The listeners absent from it are handled by every worker
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct WorkerScopes(pub BTreeMap<SocketAddr, BTreeSet<u32>>);
+
+impl WorkerScopes {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn handles(&self, worker_id: u32, address: &SocketAddr) -> bool {
+        match self.0.get(address) {
+            Some(workers) => workers.contains(&worker_id),
+            None => true,
+        }
+    }
+
+    /// the order to send to a worker, if it handles it. A batch keeps the
+    /// orders the worker handles
+    pub fn order_for(
+        &self,
+        worker_id: u32,
+        order: &ProxyRequestOrder,
+    ) -> Option<ProxyRequestOrder> {
+        match order {
+            ProxyRequestOrder::Batch(orders) => {
+                let orders: Vec<ProxyRequestOrder> = orders
+                    .iter()
+                    .filter_map(|order| self.order_for(worker_id, order))
+                    .collect();
+                if orders.is_empty() {
+                    None
+                } else {
+                    Some(ProxyRequestOrder::Batch(orders))
+                }
+            }
+            order => match order.listener_address() {
+                Some(address) if !self.handles(worker_id, &address) => None,
+                _ => Some(order.clone()),
+            },
+        }
+    }
+
+    /// the listeners added by the order are handled by these workers only, by
+    /// every worker if there are none
+    pub fn scope(&mut self, order: &ProxyRequestOrder, workers: &[u32]) {
+        match order {
+            ProxyRequestOrder::Batch(orders) => {
+                for order in orders {
+                    self.scope(order, workers);
+                }
+            }
+            ProxyRequestOrder::AddHttpListener(_)
+            | ProxyRequestOrder::AddHttpsListener(_)
+            | ProxyRequestOrder::AddTcpListener(_)
+                if !workers.is_empty() =>
+            {
+                if let Some(address) = order.listener_address() {
+                    self.0.insert(address, workers.iter().cloned().collect());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// the workers of the listener added by the order, empty if it is
+    /// handled by all of them
+    pub fn listener_workers(&self, order: &ProxyRequestOrder) -> Vec<u32> {
+        match order {
+            ProxyRequestOrder::AddHttpListener(_)
+            | ProxyRequestOrder::AddHttpsListener(_)
+            | ProxyRequestOrder::AddTcpListener(_) => order
+                .listener_address()
+                .and_then(|address| self.0.get(&address))
+                .map(|workers| workers.iter().cloned().collect())
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// the worker replacing another one, on upgrade or restart, takes over
+    /// its listeners
+    pub fn replace_worker(&mut self, old_id: u32, new_id: u32) {
+        for workers in self.0.values_mut() {
+            if workers.remove(&old_id) {
+                workers.insert(new_id);
+            }
+        }
+    }
+}
+
 struct DiffMap<'a, K: Ord, V, I1, I2> {
     my_it: I1,
     other_it: I2,
@@ -1051,8 +1983,11 @@ impl<
 mod tests {
     use super::*;
     use crate::proxy::{
-        Backend, HttpFrontend, LoadBalancingAlgorithms, LoadBalancingParams, PathRule,
-        ProxyRequestOrder, Route, RulePosition, TlsProvider,
+        Acl, AclMode, AffinityTable, Backend, BackendProtocol, BackendTls, ClusterMaintenance,
+        Compression, DrainBackend, HashKey, HostRewrite, Http2Settings, HttpFrontend,
+        LoadBalancingAlgorithms, LoadBalancingParams, PathNormalization, PathRule,
+        ProxyRequestOrder, RemoveAcl, RequestLimits, RequestRetries, Route, RulePosition,
+        SecurityHeaders, StickyMode, Timeouts, TlsProvider, UpdateBackendWeight, WebSocketDrain,
     };
 
     #[test]
@@ -1063,6 +1998,11 @@ mod tests {
             hostname: String::from("lolcatho.st:8080"),
             path: PathRule::Prefix(String::from("/")),
             method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
             address: "0.0.0.0:8080".parse().unwrap(),
             position: RulePosition::Tree,
             tags: None,
@@ -1072,6 +2012,11 @@ mod tests {
             hostname: String::from("test.local"),
             path: PathRule::Prefix(String::from("/abc")),
             method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
             address: "0.0.0.0:8080".parse().unwrap(),
             position: RulePosition::Pre,
             tags: None,
@@ -1083,6 +2028,9 @@ mod tests {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: None,
             backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
         }));
         state.handle_order(&ProxyRequestOrder::AddBackend(Backend {
             cluster_id: String::from("cluster_1"),
@@ -1091,6 +2039,9 @@ mod tests {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: None,
             backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
         }));
         state.handle_order(&ProxyRequestOrder::AddBackend(Backend {
             cluster_id: String::from("cluster_2"),
@@ -1099,6 +2050,9 @@ mod tests {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: None,
             backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
         }));
         state.handle_order(&ProxyRequestOrder::AddBackend(Backend {
             cluster_id: String::from("cluster_1"),
@@ -1107,6 +2061,9 @@ mod tests {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: None,
             backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
         }));
         state.handle_order(&ProxyRequestOrder::RemoveBackend(RemoveBackend {
             cluster_id: String::from("cluster_1"),
@@ -1133,6 +2090,11 @@ mod tests {
             hostname: String::from("lolcatho.st:8080"),
             path: PathRule::Prefix(String::from("/")),
             method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
             address: "0.0.0.0:8080".parse().unwrap(),
             position: RulePosition::Post,
             tags: None,
@@ -1142,6 +2104,11 @@ mod tests {
             hostname: String::from("test.local"),
             path: PathRule::Prefix(String::from("/abc")),
             method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
             address: "0.0.0.0:8080".parse().unwrap(),
             position: RulePosition::Tree,
             tags: None,
@@ -1153,6 +2120,9 @@ mod tests {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: None,
             backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
         }));
         state.handle_order(&ProxyRequestOrder::AddBackend(Backend {
             cluster_id: String::from("cluster_1"),
@@ -1161,6 +2131,9 @@ mod tests {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: None,
             backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
         }));
         state.handle_order(&ProxyRequestOrder::AddBackend(Backend {
             cluster_id: String::from("cluster_2"),
@@ -1169,15 +2142,37 @@ mod tests {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: None,
             backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
         }));
         state.handle_order(&ProxyRequestOrder::AddCluster(Cluster {
             cluster_id: String::from("cluster_2"),
             sticky_session: true,
+            sticky_mode: StickyMode::Cookie,
             https_redirect: true,
             proxy_protocol: None,
             load_balancing: LoadBalancingAlgorithms::RoundRobin,
+            hash_key: HashKey::Url,
+            health_check: None,
+            outlier_detection: None,
+            request_queue: None,
+            affinity_table: None,
+            max_sessions: None,
             load_metric: None,
             answer_503: None,
+            header_actions: Vec::new(),
+            host_rewrite: HostRewrite::Preserve,
+            request_limits: RequestLimits::default(),
+            compression: Compression::default(),
+            security_headers: SecurityHeaders::default(),
+            request_retries: RequestRetries::default(),
+            timeouts: Timeouts::default(),
+            backend_tls: None,
+            backend_protocol: BackendProtocol::Http1,
+            websocket_drain: WebSocketDrain::Close,
+            streaming: false,
+            upgrade_protocols: Vec::new(),
         }));
 
         let mut state2: ConfigState = Default::default();
@@ -1187,6 +2182,11 @@ mod tests {
             path: PathRule::Prefix(String::from("/")),
             address: "0.0.0.0:8080".parse().unwrap(),
             method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
             position: RulePosition::Post,
             tags: None,
         }));
@@ -1197,6 +2197,9 @@ mod tests {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: None,
             backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
         }));
         state2.handle_order(&ProxyRequestOrder::AddBackend(Backend {
             cluster_id: String::from("cluster_1"),
@@ -1205,6 +2208,9 @@ mod tests {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: None,
             backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
         }));
         state2.handle_order(&ProxyRequestOrder::AddBackend(Backend {
             cluster_id: String::from("cluster_1"),
@@ -1213,15 +2219,37 @@ mod tests {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: None,
             backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
         }));
         state2.handle_order(&ProxyRequestOrder::AddCluster(Cluster {
             cluster_id: String::from("cluster_3"),
             sticky_session: false,
+            sticky_mode: StickyMode::Cookie,
             https_redirect: false,
             proxy_protocol: None,
             load_balancing: LoadBalancingAlgorithms::RoundRobin,
+            hash_key: HashKey::Url,
+            health_check: None,
+            outlier_detection: None,
+            request_queue: None,
+            affinity_table: None,
+            max_sessions: None,
             load_metric: None,
             answer_503: None,
+            header_actions: Vec::new(),
+            host_rewrite: HostRewrite::Preserve,
+            request_limits: RequestLimits::default(),
+            compression: Compression::default(),
+            security_headers: SecurityHeaders::default(),
+            request_retries: RequestRetries::default(),
+            timeouts: Timeouts::default(),
+            backend_tls: None,
+            backend_protocol: BackendProtocol::Http1,
+            websocket_drain: WebSocketDrain::Close,
+            streaming: false,
+            upgrade_protocols: Vec::new(),
         }));
 
         let e = vec![
@@ -1230,6 +2258,11 @@ mod tests {
                 hostname: String::from("test.local"),
                 path: PathRule::Prefix(String::from("/abc")),
                 method: None,
+                methods: Vec::new(),
+                reject_other_methods: false,
+                headers: Vec::new(),
+                rewrite_path: None,
+                mirror_cluster_id: None,
                 address: "0.0.0.0:8080".parse().unwrap(),
                 position: RulePosition::Tree,
                 tags: None,
@@ -1246,6 +2279,9 @@ mod tests {
                 load_balancing_parameters: Some(LoadBalancingParams::default()),
                 sticky_id: None,
                 backup: None,
+                max_connections: None,
+                timeouts: Timeouts::default(),
+                tls: None,
             }),
             ProxyRequestOrder::RemoveCluster {
                 cluster_id: String::from("cluster_2"),
@@ -1253,11 +2289,30 @@ mod tests {
             ProxyRequestOrder::AddCluster(Cluster {
                 cluster_id: String::from("cluster_3"),
                 sticky_session: false,
+                sticky_mode: StickyMode::Cookie,
                 https_redirect: false,
                 proxy_protocol: None,
                 load_balancing: LoadBalancingAlgorithms::RoundRobin,
+                hash_key: HashKey::Url,
+                health_check: None,
+                outlier_detection: None,
+                request_queue: None,
+                affinity_table: None,
+                max_sessions: None,
                 load_metric: None,
                 answer_503: None,
+                header_actions: Vec::new(),
+                host_rewrite: HostRewrite::Preserve,
+                request_limits: RequestLimits::default(),
+                compression: Compression::default(),
+                security_headers: SecurityHeaders::default(),
+                request_retries: RequestRetries::default(),
+                timeouts: Timeouts::default(),
+                backend_tls: None,
+                backend_protocol: BackendProtocol::Http1,
+                websocket_drain: WebSocketDrain::Close,
+                streaming: false,
+                upgrade_protocols: Vec::new(),
             }),
         ];
         let expected_diff: HashSet<&ProxyRequestOrder> = HashSet::from_iter(e.iter());
@@ -1277,6 +2332,9 @@ mod tests {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: None,
             backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
         }));
         let hash3 = state3.hash_state();
         println!("state 1 hashes: {:#?}", hash1);
@@ -1284,6 +2342,48 @@ mod tests {
         println!("state 3 hashes: {:#?}", hash3);
 
         assert_eq!(diff, expected_diff);
+
+        let change = |kind, object, description: &str| StateChange {
+            kind,
+            object,
+            description: description.to_owned(),
+        };
+        assert_eq!(
+            state.changes(&state2),
+            vec![
+                change(ChangeKind::Removed, StateObject::Cluster, "cluster_2"),
+                change(ChangeKind::Added, StateObject::Cluster, "cluster_3"),
+                change(
+                    ChangeKind::Removed,
+                    StateObject::Frontend,
+                    "HTTP test.local on 0.0.0.0:8080, path prefix '/abc'"
+                ),
+                change(
+                    ChangeKind::Added,
+                    StateObject::Backend,
+                    "cluster_1-2 of cluster_1"
+                ),
+                change(
+                    ChangeKind::Removed,
+                    StateObject::Backend,
+                    "cluster_2-0 of cluster_2"
+                ),
+            ]
+        );
+
+        let mut cluster = state.clusters["cluster_2"].clone();
+        cluster.sticky_session = false;
+        let mut state4 = state.clone();
+        state4.handle_order(&ProxyRequestOrder::AddCluster(cluster));
+        assert_eq!(
+            state.changes(&state4),
+            vec![change(
+                ChangeKind::Changed,
+                StateObject::Cluster,
+                "cluster_2"
+            )]
+        );
+        assert!(state.changes(&state).is_empty());
     }
 
     #[test]
@@ -1294,6 +2394,11 @@ mod tests {
             hostname: String::from("lolcatho.st"),
             path: PathRule::Prefix(String::from("")),
             method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
             address: "0.0.0.0:8080".parse().unwrap(),
             position: RulePosition::Tree,
             tags: None,
@@ -1304,6 +2409,11 @@ mod tests {
             hostname: String::from("lolcatho.st"),
             path: PathRule::Prefix(String::from("")),
             method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
             address: "0.0.0.0:8443".parse().unwrap(),
             position: RulePosition::Tree,
             tags: None,
@@ -1314,6 +2424,11 @@ mod tests {
             hostname: String::from("lolcatho.st"),
             path: PathRule::Prefix(String::from("/api")),
             method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
             address: "0.0.0.0:8080".parse().unwrap(),
             position: RulePosition::Tree,
             tags: None,
@@ -1324,6 +2439,11 @@ mod tests {
             hostname: String::from("lolcatho.st"),
             path: PathRule::Prefix(String::from("/api")),
             method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
             address: "0.0.0.0:8443".parse().unwrap(),
             position: RulePosition::Tree,
             tags: None,
@@ -1384,6 +2504,9 @@ mod tests {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: None,
             backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
         }));
 
         let b = Backend {
@@ -1393,6 +2516,9 @@ mod tests {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: Some("sticky".to_string()),
             backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
         };
 
         state.handle_order(&ProxyRequestOrder::AddBackend(b.clone()));
@@ -1400,6 +2526,71 @@ mod tests {
         assert_eq!(state.backends.get("cluster_1").unwrap(), &vec![b]);
     }
 
+    #[test]
+    fn header_rules_in_route_key() {
+        let mut state: ConfigState = Default::default();
+        let front = HttpFrontend {
+            route: Route::ClusterId(String::from("cluster_1")),
+            hostname: String::from("lolcatho.st"),
+            path: PathRule::Prefix(String::from("/")),
+            method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
+            address: "0.0.0.0:8080".parse().unwrap(),
+            position: RulePosition::Tree,
+            tags: None,
+        };
+        let tenant_front = HttpFrontend {
+            route: Route::ClusterId(String::from("cluster_2")),
+            headers: vec![HeaderRule {
+                name: String::from("X-Tenant"),
+                value: HeaderValueRule::Equals(String::from("foo")),
+            }],
+            ..front.clone()
+        };
+
+        assert!(state.handle_order(&ProxyRequestOrder::AddHttpFrontend(front)));
+        assert!(state.handle_order(&ProxyRequestOrder::AddHttpFrontend(tenant_front.clone())));
+        assert_eq!(state.http_fronts.len(), 2);
+
+        let key = tenant_front.clone().route_key();
+        let serialized = serde_json::to_string(&key).unwrap();
+        assert_eq!(serialized, "\"0.0.0.0:8080;lolcatho.st;P/;@X-Tenant:=foo\"");
+        let deserialized: RouteKey = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, key);
+
+        let accept_front = HttpFrontend {
+            headers: vec![
+                HeaderRule {
+                    name: String::from("Accept"),
+                    value: HeaderValueRule::Equals(String::from("text/html;q=1")),
+                },
+                HeaderRule {
+                    name: String::from("X-Version"),
+                    value: HeaderValueRule::Regex(String::from("^v%2[;:]3$")),
+                },
+            ],
+            ..tenant_front
+        };
+        let key = accept_front.route_key();
+        let serialized = serde_json::to_string(&key).unwrap();
+        assert_eq!(
+            serialized,
+            "\"0.0.0.0:8080;lolcatho.st;P/;@Accept:=text/html%3Bq=1;@X-Version:R^v%252[%3B%3A]3$\""
+        );
+        let deserialized: RouteKey = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, key);
+        assert_eq!(deserialized.3, None);
+
+        assert!(
+            serde_json::from_str::<RouteKey>("\"0.0.0.0:8080;lolcatho.st;P/;@Accept:=a%3\"")
+                .is_err()
+        );
+    }
+
     #[test]
     fn listener_diff() {
         let mut state: ConfigState = Default::default();
@@ -1410,6 +2601,8 @@ mod tests {
             front_timeout: 60,
             back_timeout: 30,
             connect_timeout: 3,
+            forward_proxy: None,
+            max_sessions: None,
         }));
         state.handle_order(&ProxyRequestOrder::ActivateListener(ActivateListener {
             address: "0.0.0.0:1234".parse().unwrap(),
@@ -1425,8 +2618,13 @@ mod tests {
             sticky_name: String::new(),
             front_timeout: 60,
             request_timeout: 10,
+            request_limits: RequestLimits::default(),
+            compression: Compression::default(),
+            path_normalization: PathNormalization::default(),
+            fallback_cluster: None,
             back_timeout: 30,
             connect_timeout: 3,
+            max_sessions: None,
         }));
         state.handle_order(&ProxyRequestOrder::AddHttpsListener(HttpsListener {
             address: "0.0.0.0:8443".parse().unwrap(),
@@ -1446,8 +2644,19 @@ mod tests {
             key: None,
             front_timeout: 60,
             request_timeout: 10,
+            request_limits: RequestLimits::default(),
+            compression: Compression::default(),
+            path_normalization: PathNormalization::default(),
+            fallback_cluster: None,
+            client_auth: None,
+            default_certificate: None,
+            strict_sni: false,
+            handshake_timeout: None,
+            security_headers: Box::default(),
+            http2: Http2Settings::default(),
             back_timeout: 30,
             connect_timeout: 3,
+            max_sessions: None,
         }));
         state.handle_order(&ProxyRequestOrder::ActivateListener(ActivateListener {
             address: "0.0.0.0:8443".parse().unwrap(),
@@ -1463,6 +2672,8 @@ mod tests {
             front_timeout: 60,
             back_timeout: 30,
             connect_timeout: 3,
+            forward_proxy: None,
+            max_sessions: None,
         }));
         state2.handle_order(&ProxyRequestOrder::AddHttpListener(HttpListener {
             address: "0.0.0.0:8080".parse().unwrap(),
@@ -1473,8 +2684,13 @@ mod tests {
             sticky_name: String::new(),
             front_timeout: 60,
             request_timeout: 10,
+            request_limits: RequestLimits::default(),
+            compression: Compression::default(),
+            path_normalization: PathNormalization::default(),
+            fallback_cluster: None,
             back_timeout: 30,
             connect_timeout: 3,
+            max_sessions: None,
         }));
         state2.handle_order(&ProxyRequestOrder::ActivateListener(ActivateListener {
             address: "0.0.0.0:8080".parse().unwrap(),
@@ -1499,8 +2715,19 @@ mod tests {
             key: None,
             front_timeout: 60,
             request_timeout: 10,
+            request_limits: RequestLimits::default(),
+            compression: Compression::default(),
+            path_normalization: PathNormalization::default(),
+            fallback_cluster: None,
+            client_auth: None,
+            default_certificate: None,
+            strict_sni: false,
+            handshake_timeout: None,
+            security_headers: Box::default(),
+            http2: Http2Settings::default(),
             back_timeout: 30,
             connect_timeout: 3,
+            max_sessions: None,
         }));
         state2.handle_order(&ProxyRequestOrder::ActivateListener(ActivateListener {
             address: "0.0.0.0:8443".parse().unwrap(),
@@ -1520,6 +2747,8 @@ mod tests {
                 front_timeout: 60,
                 back_timeout: 30,
                 connect_timeout: 3,
+                forward_proxy: None,
+                max_sessions: None,
             }),
             ProxyRequestOrder::DeactivateListener(DeactivateListener {
                 address: "0.0.0.0:1234".parse().unwrap(),
@@ -1539,8 +2768,13 @@ mod tests {
                 sticky_name: String::new(),
                 front_timeout: 60,
                 request_timeout: 10,
+                request_limits: RequestLimits::default(),
+                compression: Compression::default(),
+                path_normalization: PathNormalization::default(),
+                fallback_cluster: None,
                 back_timeout: 30,
                 connect_timeout: 3,
+                max_sessions: None,
             }),
             ProxyRequestOrder::ActivateListener(ActivateListener {
                 address: "0.0.0.0:8080".parse().unwrap(),
@@ -1569,8 +2803,19 @@ mod tests {
                 key: None,
                 front_timeout: 60,
                 request_timeout: 10,
+                request_limits: RequestLimits::default(),
+                compression: Compression::default(),
+                path_normalization: PathNormalization::default(),
+                fallback_cluster: None,
+                client_auth: None,
+                default_certificate: None,
+                strict_sni: false,
+                handshake_timeout: None,
+                security_headers: Box::default(),
+                http2: Http2Settings::default(),
                 back_timeout: 30,
                 connect_timeout: 3,
+                max_sessions: None,
             }),
         ];
 
@@ -1584,13 +2829,571 @@ mod tests {
 
         assert_eq!(diff, e);
     }
+
+    #[test]
+    fn cluster_maintenance() {
+        let mut state: ConfigState = Default::default();
+        let maintenance = ClusterMaintenance {
+            cluster_id: String::from("cluster_1"),
+            enabled: true,
+            answer: Some(String::from(
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n",
+            )),
+        };
+        assert!(
+            state.handle_order(&ProxyRequestOrder::SetClusterMaintenance(
+                maintenance.clone()
+            ))
+        );
+        assert!(
+            !state.handle_order(&ProxyRequestOrder::SetClusterMaintenance(
+                maintenance.clone()
+            ))
+        );
+        assert!(state
+            .generate_orders()
+            .contains(&ProxyRequestOrder::SetClusterMaintenance(maintenance)));
+
+        let disable = ClusterMaintenance {
+            cluster_id: String::from("cluster_1"),
+            enabled: false,
+            answer: None,
+        };
+        let state2: ConfigState = Default::default();
+        assert_eq!(
+            state.diff(&state2),
+            vec![ProxyRequestOrder::SetClusterMaintenance(disable.clone())]
+        );
+
+        assert!(state.handle_order(&ProxyRequestOrder::SetClusterMaintenance(disable)));
+        assert!(state.maintenance.is_empty());
+    }
+
+    #[test]
+    fn drain_backend() {
+        let mut state: ConfigState = Default::default();
+        state.handle_order(&ProxyRequestOrder::AddBackend(Backend {
+            cluster_id: String::from("cluster_1"),
+            backend_id: String::from("cluster_1-0"),
+            address: "127.0.0.1:1026".parse().unwrap(),
+            load_balancing_parameters: Some(LoadBalancingParams::default()),
+            sticky_id: None,
+            backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
+        }));
+
+        let mut drain = DrainBackend {
+            cluster_id: String::from("cluster_1"),
+            backend_id: String::from("cluster_1-0"),
+            address: "127.0.0.1:1026".parse().unwrap(),
+            remove: false,
+        };
+        assert!(state.handle_order(&ProxyRequestOrder::DrainBackend(drain.clone())));
+        assert_eq!(state.count_backends(), 1);
+
+        assert!(state.handle_order(&ProxyRequestOrder::UpdateBackendWeight(
+            UpdateBackendWeight {
+                cluster_id: String::from("cluster_1"),
+                backend_id: String::from("cluster_1-0"),
+                address: "127.0.0.1:1026".parse().unwrap(),
+                weight: 20,
+            }
+        )));
+        assert_eq!(
+            state.backends["cluster_1"][0].load_balancing_parameters,
+            Some(LoadBalancingParams { weight: 20 })
+        );
+
+        drain.remove = true;
+        assert!(state.handle_order(&ProxyRequestOrder::DrainBackend(drain.clone())));
+        assert_eq!(state.count_backends(), 0);
+        assert!(!state.handle_order(&ProxyRequestOrder::DrainBackend(drain)));
+    }
+
+    #[test]
+    fn affinity_table() {
+        let mut state: ConfigState = Default::default();
+        let mut cluster = Cluster {
+            cluster_id: String::from("cluster_1"),
+            sticky_session: true,
+            sticky_mode: StickyMode::SourceIp,
+            https_redirect: false,
+            proxy_protocol: None,
+            load_balancing: LoadBalancingAlgorithms::RoundRobin,
+            hash_key: HashKey::SourceIp,
+            health_check: None,
+            outlier_detection: None,
+            request_queue: None,
+            affinity_table: Some(AffinityTable { size: 2 }),
+            load_metric: None,
+            answer_503: None,
+            header_actions: Vec::new(),
+            host_rewrite: HostRewrite::Preserve,
+            request_limits: RequestLimits::default(),
+            compression: Compression::default(),
+            security_headers: SecurityHeaders::default(),
+            request_retries: RequestRetries::default(),
+            timeouts: Timeouts::default(),
+            backend_tls: None,
+            backend_protocol: BackendProtocol::Http1,
+            websocket_drain: WebSocketDrain::Close,
+            streaming: false,
+            upgrade_protocols: Vec::new(),
+            max_sessions: None,
+        };
+        state.handle_order(&ProxyRequestOrder::AddCluster(cluster.clone()));
+
+        let affinity = |key: &str, backend_id: &str| {
+            ProxyRequestOrder::SetAffinity(Affinity {
+                cluster_id: String::from("cluster_1"),
+                key: key.to_string(),
+                backend_id: backend_id.to_string(),
+            })
+        };
+        assert!(state.handle_order(&affinity("10.0.0.1", "b0")));
+        assert!(!state.handle_order(&affinity("10.0.0.1", "b0")));
+        assert!(state.handle_order(&affinity("10.0.0.2", "b1")));
+        assert!(state.handle_order(&affinity("10.0.0.1", "b1")));
+        // the oldest key is forgotten
+        assert!(state.handle_order(&affinity("10.0.0.3", "b0")));
+        assert_eq!(
+            state.affinities["cluster_1"],
+            vec![
+                (String::from("10.0.0.1"), String::from("b1")),
+                (String::from("10.0.0.3"), String::from("b0")),
+            ]
+        );
+        assert!(state
+            .generate_orders()
+            .ends_with(&[affinity("10.0.0.1", "b1"), affinity("10.0.0.3", "b0")]));
+
+        state.handle_order(&ProxyRequestOrder::RemoveBackend(RemoveBackend {
+            cluster_id: String::from("cluster_1"),
+            backend_id: String::from("b1"),
+            address: "127.0.0.1:1026".parse().unwrap(),
+        }));
+        assert_eq!(state.affinities["cluster_1"].len(), 1);
+
+        cluster.affinity_table = None;
+        state.handle_order(&ProxyRequestOrder::AddCluster(cluster));
+        assert!(state.affinities.is_empty());
+        assert!(!state.handle_order(&affinity("10.0.0.1", "b0")));
+    }
+
+    #[test]
+    fn certificate_covers_hostname() {
+        let address: SocketAddr = "0.0.0.0:8443".parse().unwrap();
+        let certificate = CertificateAndKey {
+            certificate: String::from(include_str!("../assets/certificate.pem")),
+            certificate_chain: vec![],
+            key: String::from(include_str!("../assets/key.pem")),
+            versions: vec![],
+            ocsp_response: None,
+            priority: 0,
+        };
+
+        let mut state: ConfigState = Default::default();
+        assert!(!state.certificate_covers(&address, "lolcatho.st"));
+
+        state.handle_order(&ProxyRequestOrder::AddCertificate(AddCertificate {
+            address,
+            certificate: certificate.clone(),
+            names: vec![],
+            expired_at: None,
+        }));
+        assert!(state.certificate_covers(&address, "lolcatho.st"));
+        assert!(!state.certificate_covers(&address, "www.lolcatho.st"));
+        assert!(!state.certificate_covers(&"0.0.0.0:443".parse().unwrap(), "lolcatho.st"));
+
+        // the names given with the certificate replace the ones it provides
+        let other_address: SocketAddr = "0.0.0.0:9443".parse().unwrap();
+        state.handle_order(&ProxyRequestOrder::AddCertificate(AddCertificate {
+            address: other_address,
+            certificate,
+            names: vec![String::from("*.example.com")],
+            expired_at: None,
+        }));
+        assert!(state.certificate_covers(&other_address, "www.example.com"));
+        assert!(!state.certificate_covers(&other_address, "lolcatho.st"));
+
+        let front = |hostname: &str| {
+            ProxyRequestOrder::AddHttpsFrontend(HttpFrontend {
+                route: Route::ClusterId(String::from("app")),
+                hostname: String::from(hostname),
+                path: PathRule::Prefix(String::from("/")),
+                method: None,
+                methods: Vec::new(),
+                reject_other_methods: false,
+                headers: Vec::new(),
+                rewrite_path: None,
+                mirror_cluster_id: None,
+                address: other_address,
+                position: RulePosition::Tree,
+                tags: None,
+            })
+        };
+        state.handle_order(&front("www.example.com"));
+        state.handle_order(&front("api.example.com"));
+        state.handle_order(&front("lolcatho.st"));
+        let listed = state
+            .list_certificates()
+            .into_iter()
+            .find(|certificate| certificate.address == other_address)
+            .unwrap();
+        assert!(listed.subject.contains("CN=lolcatho.st"));
+        assert_eq!(listed.names, vec![String::from("*.example.com")]);
+        assert_eq!(
+            listed.hostnames,
+            vec![
+                String::from("api.example.com"),
+                String::from("www.example.com")
+            ]
+        );
+    }
+
+    #[test]
+    fn cluster_documents() {
+        let https_address: SocketAddr = "0.0.0.0:8443".parse().unwrap();
+        let mut document: ClusterDocument = serde_json::from_str(
+            r#"{
+                "cluster": { "cluster_id": "app" },
+                "http_frontends": [
+                    { "route": { "CLUSTER_ID": "app" }, "address": "0.0.0.0:8080", "hostname": "example.com" }
+                ],
+                "https_frontends": [
+                    { "route": { "CLUSTER_ID": "app" }, "address": "0.0.0.0:8443", "hostname": "lolcatho.st" }
+                ],
+                "backends": [
+                    { "cluster_id": "app", "backend_id": "app-0", "address": "127.0.0.1:1026" },
+                    { "cluster_id": "app", "backend_id": "app-1", "address": "127.0.0.1:1027" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        document.certificates.push(AddCertificate {
+            address: https_address,
+            certificate: CertificateAndKey {
+                certificate: String::from(include_str!("../assets/certificate.pem")),
+                certificate_chain: vec![],
+                key: String::from(include_str!("../assets/key.pem")),
+                versions: vec![],
+                ocsp_response: None,
+                priority: 0,
+            },
+            names: vec![],
+            expired_at: None,
+        });
+
+        let state = ConfigState::new().with_clusters(&[document.clone()]);
+        assert_eq!(state.count_frontends(), 2);
+        assert_eq!(state.count_backends(), 2);
+        assert_eq!(state.cluster_document("app"), Some(document.clone()));
+        assert_eq!(state.cluster_document("other"), None);
+
+        // applying the exported document changes nothing
+        assert!(state
+            .diff(&state.with_clusters(&[document.clone()]))
+            .is_empty());
+
+        // the frontends and backends missing from the document are removed
+        document.backends.pop();
+        document.http_frontends.clear();
+        let orders = state.diff(&state.with_clusters(&[document]));
+        assert_eq!(orders.len(), 2);
+        assert!(orders.iter().any(|order| matches!(
+            order,
+            ProxyRequestOrder::RemoveBackend(backend) if backend.backend_id == "app-1"
+        )));
+        assert!(orders
+            .iter()
+            .any(|order| matches!(order, ProxyRequestOrder::RemoveHttpFrontend(_))));
+    }
+
+    #[test]
+    fn edit_saved_states() {
+        let documents: Vec<ClusterDocument> = serde_json::from_str(
+            r#"[{
+                "cluster": { "cluster_id": "app" },
+                "https_frontends": [
+                    { "route": { "CLUSTER_ID": "app" }, "address": "0.0.0.0:8443", "hostname": "lolcatho.st" }
+                ],
+                "backends": [
+                    { "cluster_id": "app", "backend_id": "app-0", "address": "127.0.0.1:1026" }
+                ]
+            }, {
+                "cluster": { "cluster_id": "other" },
+                "http_frontends": [
+                    { "route": { "CLUSTER_ID": "other" }, "address": "0.0.0.0:8080", "hostname": "example.com" }
+                ],
+                "backends": [
+                    { "cluster_id": "other", "backend_id": "other-0", "address": "127.0.0.1:1027" }
+                ]
+            }]"#,
+        )
+        .unwrap();
+        let mut state = ConfigState::new().with_clusters(&documents);
+        state.handle_order(&ProxyRequestOrder::AddCertificate(AddCertificate {
+            address: "0.0.0.0:8443".parse().unwrap(),
+            certificate: CertificateAndKey {
+                certificate: String::from(include_str!("../assets/certificate.pem")),
+                certificate_chain: vec![],
+                key: String::from(include_str!("../assets/key.pem")),
+                versions: vec![],
+                ocsp_response: None,
+                priority: 0,
+            },
+            names: vec![],
+            expired_at: None,
+        }));
+
+        let app = state.only_clusters(&[String::from("app")]);
+        assert_eq!(app.clusters.keys().collect::<Vec<_>>(), vec!["app"]);
+        assert_eq!(app.count_frontends(), 1);
+        assert_eq!(app.count_backends(), 1);
+        assert_eq!(app.certificates.values().map(|c| c.len()).sum::<usize>(), 1);
+
+        let other = state.only_clusters(&[String::from("other")]);
+        assert_eq!(other.clusters.keys().collect::<Vec<_>>(), vec!["other"]);
+        assert!(other.certificates.values().all(|c| c.is_empty()));
+
+        assert!(state.without_certificates().list_certificates().is_empty());
+        assert!(state.diff(&app.merge(&other)).is_empty());
+    }
+
+    #[test]
+    fn dump_without_private_keys() {
+        let mut state = ConfigState::new();
+        state.handle_order(&ProxyRequestOrder::AddCluster(Cluster {
+            cluster_id: String::from("app"),
+            backend_tls: Some(Box::new(BackendTls {
+                client_certificate: Some(String::from(include_str!("../assets/certificate.pem"))),
+                client_key: Some(String::from(include_str!("../assets/key.pem"))),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }));
+        state.handle_order(&ProxyRequestOrder::AddCertificate(AddCertificate {
+            address: "0.0.0.0:8443".parse().unwrap(),
+            certificate: CertificateAndKey {
+                certificate: String::from(include_str!("../assets/certificate.pem")),
+                certificate_chain: vec![],
+                key: String::from(include_str!("../assets/key.pem")),
+                versions: vec![],
+                ocsp_response: None,
+                priority: 0,
+            },
+            names: vec![],
+            expired_at: None,
+        }));
+
+        let dump = state.without_private_keys();
+        let certificates = dump
+            .certificates
+            .values()
+            .flat_map(|certificates| certificates.values())
+            .collect::<Vec<_>>();
+        assert_eq!(certificates.len(), 1);
+        assert!(certificates[0].0.key.is_empty());
+        assert!(!certificates[0].0.certificate.is_empty());
+
+        let backend_tls = dump.clusters["app"].backend_tls.as_ref().unwrap();
+        assert!(backend_tls.client_key.is_none());
+        assert!(backend_tls.client_certificate.is_some());
+
+        // the state itself keeps them
+        assert!(state.certificates.values().all(|certificates| certificates
+            .values()
+            .all(|(certificate, _)| !certificate.key.is_empty())));
+    }
+
+    #[test]
+    fn acl_diff() {
+        let address: SocketAddr = "0.0.0.0:8080".parse().unwrap();
+        let deny_list = Acl {
+            address,
+            proxy: ListenerType::HTTP,
+            hostname: None,
+            mode: AclMode::Deny,
+            ranges: vec!["192.168.1.0/24".parse().unwrap()],
+        };
+        let admin_list = Acl {
+            address,
+            proxy: ListenerType::HTTP,
+            hostname: Some(String::from("admin.example.com")),
+            mode: AclMode::Allow,
+            ranges: vec!["10.0.0.0/8".parse().unwrap()],
+        };
+
+        let mut state: ConfigState = Default::default();
+        assert!(state.handle_order(&ProxyRequestOrder::AddAcl(deny_list.clone())));
+        assert!(state.handle_order(&ProxyRequestOrder::AddAcl(admin_list.clone())));
+        assert!(!state.handle_order(&ProxyRequestOrder::AddAcl(admin_list.clone())));
+
+        let mut state2 = state.clone();
+        let new_deny_list = Acl {
+            ranges: vec!["192.168.2.0/24".parse().unwrap()],
+            ..deny_list
+        };
+        // replaces the ACL without hostname
+        assert!(state2.handle_order(&ProxyRequestOrder::AddAcl(new_deny_list.clone())));
+        assert!(
+            state2.handle_order(&ProxyRequestOrder::RemoveAcl(RemoveAcl {
+                address,
+                proxy: ListenerType::HTTP,
+                hostname: admin_list.hostname.clone(),
+            }))
+        );
+        assert_eq!(state2.acls[&address], vec![new_deny_list.clone()]);
+
+        let e = vec![
+            ProxyRequestOrder::RemoveAcl(RemoveAcl {
+                address,
+                proxy: ListenerType::HTTP,
+                hostname: Some(String::from("admin.example.com")),
+            }),
+            ProxyRequestOrder::AddAcl(new_deny_list),
+        ];
+        assert_eq!(state.diff(&state2), e);
+    }
+
+    #[test]
+    fn batch_rollback() {
+        let backend = Backend {
+            cluster_id: String::from("cluster_1"),
+            backend_id: String::from("cluster_1-0"),
+            address: "127.0.0.1:1026".parse().unwrap(),
+            load_balancing_parameters: Some(LoadBalancingParams::default()),
+            sticky_id: None,
+            backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
+        };
+        let batch = ProxyRequestOrder::Batch(vec![
+            ProxyRequestOrder::AddCluster(Cluster {
+                cluster_id: String::from("cluster_1"),
+                ..Default::default()
+            }),
+            ProxyRequestOrder::AddBackend(backend.clone()),
+        ]);
+
+        let state_before: ConfigState = Default::default();
+        let mut state = state_before.clone();
+        assert!(state.handle_order(&batch));
+        assert_eq!(state.clusters.len(), 1);
+        assert_eq!(state.count_backends(), 1);
+
+        // the orders undoing the batch
+        let rollback = state.diff(&state_before);
+        assert_eq!(
+            rollback,
+            vec![
+                ProxyRequestOrder::RemoveCluster {
+                    cluster_id: String::from("cluster_1")
+                },
+                ProxyRequestOrder::RemoveBackend(RemoveBackend {
+                    cluster_id: backend.cluster_id,
+                    backend_id: backend.backend_id,
+                    address: backend.address,
+                }),
+            ]
+        );
+        assert!(rollback.iter().all(ProxyRequestOrder::is_reversible));
+        for order in rollback.iter() {
+            state.handle_order(order);
+        }
+        assert!(state.diff(&state_before).is_empty());
+
+        assert!(!batch.is_reversible());
+        assert!(!ProxyRequestOrder::SoftStop(None).is_reversible());
+    }
+
+    #[test]
+    fn worker_scopes() {
+        let pinned: SocketAddr = "0.0.0.0:1234".parse().unwrap();
+        let shared: SocketAddr = "0.0.0.0:5678".parse().unwrap();
+        let listener = |address| {
+            ProxyRequestOrder::AddTcpListener(TcpListener {
+                address,
+                public_address: None,
+                expect_proxy: false,
+                front_timeout: 60,
+                back_timeout: 30,
+                connect_timeout: 3,
+                forward_proxy: None,
+                max_sessions: None,
+            })
+        };
+        let front = |address| {
+            ProxyRequestOrder::AddTcpFrontend(TcpFrontend {
+                cluster_id: String::from("cluster_1"),
+                address,
+                tags: None,
+            })
+        };
+
+        let mut state: ConfigState = Default::default();
+        for (order, workers) in [
+            (listener(pinned), vec![1]),
+            (listener(shared), vec![]),
+            (front(pinned), vec![]),
+            (front(shared), vec![]),
+        ] {
+            assert!(state.handle_order(&order));
+            state.worker_scopes.scope(&order, &workers);
+        }
+        assert_eq!(
+            state.worker_scopes.listener_workers(&listener(pinned)),
+            vec![1]
+        );
+
+        // the frontends follow their listener
+        let scopes = &state.worker_scopes;
+        assert_eq!(scopes.order_for(0, &front(pinned)), None);
+        assert_eq!(scopes.order_for(1, &front(pinned)), Some(front(pinned)));
+        assert_eq!(
+            scopes.order_for(
+                0,
+                &ProxyRequestOrder::Batch(vec![front(pinned), front(shared)])
+            ),
+            Some(ProxyRequestOrder::Batch(vec![front(shared)]))
+        );
+
+        let worker_0 = state.scoped_to(0);
+        assert_eq!(worker_0.tcp_listeners.len(), 1);
+        assert!(worker_0.tcp_listeners.contains_key(&shared));
+        assert_eq!(worker_0.tcp_fronts["cluster_1"].len(), 1);
+        assert_eq!(state.scoped_to(1), state);
+
+        // the worker replacing the pinned one takes its listener
+        state.worker_scopes.replace_worker(1, 2);
+        assert_eq!(state.scoped_to(2).tcp_listeners.len(), 2);
+        assert_eq!(state.scoped_to(1).tcp_listeners.len(), 1);
+
+        assert!(
+            state.handle_order(&ProxyRequestOrder::RemoveListener(RemoveListener {
+                address: pinned,
+                proxy: ListenerType::TCP,
+            }))
+        );
+        assert!(state.worker_scopes.is_empty());
+    }
 }
 
 /// `RouteKey` is a the routing key built from the following tuple.
-/// The tuple is made of (socket address, hostname, path, method).
+/// The tuple is made of (socket address, hostname, path, methods, header rules),
+/// several methods being separated by commas.
 // TODO: Create a custom type for the hostname and use a common type for the method.
 #[derive(PartialOrd, Ord, Debug, Clone, PartialEq, Eq, Hash)]
-pub struct RouteKey(pub SocketAddr, pub String, pub PathRule, pub Option<String>);
+pub struct RouteKey(
+    pub SocketAddr,
+    pub String,
+    pub PathRule,
+    pub Option<String>,
+    pub Vec<HeaderRule>,
+);
 
 impl serde::Serialize for RouteKey {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -1607,17 +3410,68 @@ impl serde::Serialize for RouteKey {
             s = format!("{};{}", s, method);
         }
 
+        // '@' is not a valid token character, so a header rule
+        // cannot be confused with a method
+        for header in &self.4 {
+            let name = escape_key_part(&header.name);
+            s = match &header.value {
+                HeaderValueRule::Prefix(prefix) => {
+                    format!("{};@{}:P{}", s, name, escape_key_part(prefix))
+                }
+                HeaderValueRule::Regex(regex) => {
+                    format!("{};@{}:R{}", s, name, escape_key_part(regex))
+                }
+                HeaderValueRule::Equals(value) => {
+                    format!("{};@{}:={}", s, name, escape_key_part(value))
+                }
+            };
+        }
+
         serializer.serialize_str(&s)
     }
 }
 
+/// header rules can contain ';' and ':', which separate the parts of a route key,
+/// so they are percent-encoded
+fn escape_key_part(part: &str) -> String {
+    let mut escaped = String::with_capacity(part.len());
+    for c in part.chars() {
+        match c {
+            '%' => escaped.push_str("%25"),
+            ';' => escaped.push_str("%3B"),
+            ':' => escaped.push_str("%3A"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape_key_part(part: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(part.len());
+    let mut it = part.split('%');
+    unescaped.push_str(it.next()?);
+    for chunk in it {
+        let c = match chunk.get(..2)? {
+            "25" => '%',
+            "3B" => ';',
+            "3A" => ':',
+            _ => return None,
+        };
+        unescaped.push(c);
+        unescaped.push_str(&chunk[2..]);
+    }
+    Some(unescaped)
+}
+
 impl From<HttpFrontend> for RouteKey {
     fn from(frontend: HttpFrontend) -> Self {
+        let method = frontend.method_key();
         Self(
             frontend.address,
             frontend.hostname,
             frontend.path,
-            frontend.method,
+            method,
+            frontend.headers,
         )
     }
 }
@@ -1659,9 +3513,45 @@ impl<'de> Visitor<'de> for RouteKeyVisitor {
             _ => return Err(E::custom("invalid path rule".to_string())),
         };
 
-        let method = it.next().map(String::from);
+        let mut method = None;
+        let mut headers = Vec::new();
+        for part in it {
+            let header_rule_str = match part.strip_prefix('@') {
+                Some(h) => h,
+                None => {
+                    method = Some(String::from(part));
+                    continue;
+                }
+            };
+
+            let (name, value) = header_rule_str
+                .split_once(':')
+                .ok_or_else(|| E::custom("invalid header rule".to_string()))?;
+
+            let unescape = |part: &str| {
+                unescape_key_part(part).ok_or_else(|| E::custom("invalid header rule".to_string()))
+            };
+
+            let value = match value.chars().next() {
+                Some('R') => HeaderValueRule::Regex(unescape(&value[1..])?),
+                Some('P') => HeaderValueRule::Prefix(unescape(&value[1..])?),
+                Some('=') => HeaderValueRule::Equals(unescape(&value[1..])?),
+                _ => return Err(E::custom("invalid header rule".to_string())),
+            };
+
+            headers.push(HeaderRule {
+                name: unescape(name)?,
+                value,
+            });
+        }
 
-        Ok(RouteKey(address, hostname.to_string(), path_rule, method))
+        Ok(RouteKey(
+            address,
+            hostname.to_string(),
+            path_rule,
+            method,
+            headers,
+        ))
     }
 }
 
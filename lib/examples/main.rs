@@ -69,6 +69,11 @@ fn main() -> anyhow::Result<()> {
         hostname: String::from("lolcatho.st"),
         path: PathRule::Prefix(String::from("/")),
         method: None,
+        methods: Vec::new(),
+        reject_other_methods: false,
+        headers: Vec::new(),
+        rewrite_path: None,
+        mirror_cluster_id: None,
         position: RulePosition::Tree,
         tags: None,
     };
@@ -82,6 +87,9 @@ fn main() -> anyhow::Result<()> {
             .with_context(|| "Could not parse backend address")?,
         load_balancing_parameters: Some(LoadBalancingParams::default()),
         backup: None,
+        max_connections: None,
+        timeouts: proxy::Timeouts::default(),
+        tls: None,
     };
 
     command.write_message(&proxy::ProxyRequest {
@@ -124,6 +132,8 @@ fn main() -> anyhow::Result<()> {
         key: String::from(key1),
         certificate_chain: vec![],
         versions: vec![],
+        ocsp_response: None,
+        priority: 0,
     };
     command2.write_message(&proxy::ProxyRequest {
         id: String::from("ID_IJKL1"),
@@ -145,6 +155,11 @@ fn main() -> anyhow::Result<()> {
         hostname: String::from("lolcatho.st"),
         path: PathRule::Prefix(String::from("/")),
         method: None,
+        methods: Vec::new(),
+        reject_other_methods: false,
+        headers: Vec::new(),
+        rewrite_path: None,
+        mirror_cluster_id: None,
         position: RulePosition::Tree,
         tags: None,
     };
@@ -162,6 +177,9 @@ fn main() -> anyhow::Result<()> {
             .with_context(|| "Could not parse backend address")?,
         load_balancing_parameters: Some(LoadBalancingParams::default()),
         backup: None,
+        max_connections: None,
+        timeouts: proxy::Timeouts::default(),
+        tls: None,
     };
 
     command2.write_message(&proxy::ProxyRequest {
@@ -177,6 +195,8 @@ fn main() -> anyhow::Result<()> {
         key: String::from(key2),
         certificate_chain: vec![],
         versions: vec![],
+        ocsp_response: None,
+        priority: 0,
     };
 
     command2.write_message(&proxy::ProxyRequest {
@@ -199,6 +219,11 @@ fn main() -> anyhow::Result<()> {
         hostname: String::from("test.local"),
         path: PathRule::Prefix(String::from("/")),
         method: None,
+        methods: Vec::new(),
+        reject_other_methods: false,
+        headers: Vec::new(),
+        rewrite_path: None,
+        mirror_cluster_id: None,
         position: RulePosition::Tree,
         tags: None,
     };
@@ -217,6 +242,9 @@ fn main() -> anyhow::Result<()> {
             .with_context(|| "Could not parse backend address")?,
         load_balancing_parameters: Some(LoadBalancingParams::default()),
         backup: None,
+        max_connections: None,
+        timeouts: proxy::Timeouts::default(),
+        tls: None,
     };
 
     command2.write_message(&proxy::ProxyRequest {
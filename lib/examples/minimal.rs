@@ -58,6 +58,11 @@ fn main() -> anyhow::Result<()> {
         hostname: String::from("example.com"),
         path: PathRule::Prefix(String::from("/")),
         method: None,
+        methods: Vec::new(),
+        reject_other_methods: false,
+        headers: Vec::new(),
+        rewrite_path: None,
+        mirror_cluster_id: None,
         position: RulePosition::Pre,
         tags: Some(BTreeMap::from([
             ("owner".to_owned(), "John".to_owned()),
@@ -73,6 +78,9 @@ fn main() -> anyhow::Result<()> {
         load_balancing_parameters: Some(LoadBalancingParams::default()),
         sticky_id: None,
         backup: None,
+        max_connections: None,
+        timeouts: proxy::Timeouts::default(),
+        tls: None,
     };
 
     command.write_message(&proxy::ProxyRequest {
@@ -45,6 +45,8 @@ fn main() -> anyhow::Result<()> {
             front_timeout: 60,
             back_timeout: 30,
             connect_timeout: 3,
+            forward_proxy: None,
+            max_sessions: None,
         };
         Logger::init(
             "TCP".to_string(),
@@ -71,6 +73,9 @@ fn main() -> anyhow::Result<()> {
         load_balancing_parameters: Some(LoadBalancingParams::default()),
         sticky_id: None,
         backup: None,
+        max_connections: None,
+        timeouts: proxy::Timeouts::default(),
+        tls: None,
     };
 
     command.write_message(&proxy::ProxyRequest {
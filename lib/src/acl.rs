@@ -0,0 +1,106 @@
+use std::{io::ErrorKind, net::IpAddr};
+
+use mio::net::{TcpListener, TcpStream};
+
+use crate::{sozu_command::proxy::Acl, AcceptError};
+
+/// source IP access control lists of a listener: the one without hostname
+/// filters connections on accept, the others filter requests by hostname
+#[derive(Debug, Default)]
+pub struct AccessLists {
+    acls: Vec<Acl>,
+}
+
+impl AccessLists {
+    /// adds an ACL, replacing the one for the same hostname
+    pub fn add(&mut self, acl: Acl) {
+        self.acls.retain(|a| a.hostname != acl.hostname);
+        self.acls.push(acl);
+    }
+
+    pub fn remove(&mut self, hostname: Option<&str>) -> bool {
+        let len = self.acls.len();
+        self.acls.retain(|a| a.hostname.as_deref() != hostname);
+        self.acls.len() != len
+    }
+
+    pub fn allows_connection(&self, ip: IpAddr) -> bool {
+        self.acls
+            .iter()
+            .filter(|acl| acl.hostname.is_none())
+            .all(|acl| acl.allows(ip))
+    }
+
+    pub fn allows_request(&self, ip: IpAddr, hostname: &str) -> bool {
+        self.acls
+            .iter()
+            .filter(|acl| {
+                acl.hostname
+                    .as_deref()
+                    .is_some_and(|acl_hostname| acl_hostname.eq_ignore_ascii_case(hostname))
+            })
+            .all(|acl| acl.allows(ip))
+    }
+
+    /// accepts the next connection allowed by the ACLs, closing the denied ones
+    pub fn accept(&self, listener: &TcpListener) -> Result<TcpStream, AcceptError> {
+        loop {
+            let (sock, address) = listener.accept().map_err(|e| match e.kind() {
+                ErrorKind::WouldBlock => AcceptError::WouldBlock,
+                _ => {
+                    error!("accept() IO error: {:?}", e);
+                    AcceptError::IoError
+                }
+            })?;
+
+            if self.allows_connection(address.ip()) {
+                return Ok(sock);
+            }
+
+            debug!("connection from {} denied by ACL", address);
+            incr!("acl.denied");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sozu_command::proxy::{AclMode, ListenerType};
+
+    #[test]
+    fn connection_and_request_acls() {
+        let mut acls = AccessLists::default();
+        acls.add(Acl {
+            address: "0.0.0.0:8080".parse().unwrap(),
+            proxy: ListenerType::HTTP,
+            hostname: None,
+            mode: AclMode::Deny,
+            ranges: vec!["192.168.0.0/16".parse().unwrap()],
+        });
+        acls.add(Acl {
+            address: "0.0.0.0:8080".parse().unwrap(),
+            proxy: ListenerType::HTTP,
+            hostname: Some(String::from("admin.example.com")),
+            mode: AclMode::Allow,
+            ranges: vec!["10.0.0.0/8".parse().unwrap()],
+        });
+
+        let office = "10.1.2.3".parse().unwrap();
+        let home = "203.0.113.7".parse().unwrap();
+        let blocked = "192.168.1.1".parse().unwrap();
+
+        assert!(acls.allows_connection(office));
+        assert!(acls.allows_connection(home));
+        assert!(!acls.allows_connection(blocked));
+
+        assert!(acls.allows_request(office, "admin.example.com"));
+        assert!(!acls.allows_request(home, "admin.example.com"));
+        assert!(acls.allows_request(home, "www.example.com"));
+        assert!(!acls.allows_request(home, "ADMIN.example.com"));
+
+        assert!(acls.remove(Some("admin.example.com")));
+        assert!(!acls.remove(Some("admin.example.com")));
+        assert!(acls.allows_request(home, "admin.example.com"));
+    }
+}
@@ -1,13 +1,23 @@
-use std::{cell::RefCell, collections::HashMap, net::SocketAddr, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    rc::Rc,
+};
 
-use mio::net::TcpStream;
+use mio::{net::TcpStream, Token};
+use time::{Duration, Instant};
 
 use crate::{
-    server::push_event,
+    outlier_detection::OutlierDetector,
+    server::{push_affinity, push_event},
+    socket::{BackRustls, BackendSocket},
     sozu_command::proxy::{self, LoadBalancingAlgorithms},
+    tls::BackendTlsConfig,
 };
 
-use super::{load_balancing::*, Backend, ClusterId, ConnectionError};
+use super::{load_balancing::*, Backend, BackendStatus, ClusterId, ConnectionError};
 
 #[derive(Debug)]
 pub struct BackendMap {
@@ -62,6 +72,51 @@ impl BackendMap {
         }
     }
 
+    /// stops sending new sessions to the backend, and removes it from the cluster
+    /// if asked. Returns false if the cluster has no such backend
+    pub fn drain_backend(
+        &mut self,
+        cluster_id: &str,
+        backend_address: &SocketAddr,
+        remove: bool,
+    ) -> bool {
+        let backends = match self.backends.get_mut(cluster_id) {
+            Some(backends) => backends,
+            None => return false,
+        };
+
+        match backends.find_backend(backend_address) {
+            Some(backend) => backend.borrow_mut().set_draining(),
+            None => return false,
+        }
+
+        if remove {
+            backends.remove_backend(backend_address);
+        }
+        true
+    }
+
+    /// returns false if the cluster has no such backend
+    pub fn set_backend_weight(
+        &mut self,
+        cluster_id: &str,
+        backend_address: &SocketAddr,
+        weight: u8,
+    ) -> bool {
+        match self
+            .backends
+            .get_mut(cluster_id)
+            .and_then(|backends| backends.find_backend(backend_address))
+        {
+            Some(backend) => {
+                backend.borrow_mut().load_balancing_parameters =
+                    Some(proxy::LoadBalancingParams { weight });
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn close_backend_connection(&mut self, cluster_id: &str, addr: &SocketAddr) {
         if let Some(cluster_backends) = self.backends.get_mut(cluster_id) {
             if let Some(ref mut backend) = cluster_backends.find_backend(addr) {
@@ -80,6 +135,16 @@ impl BackendMap {
     pub fn backend_from_cluster_id(
         &mut self,
         cluster_id: &str,
+    ) -> Result<(Rc<RefCell<Backend>>, TcpStream), ConnectionError> {
+        self.backend_for_key(cluster_id, None)
+    }
+
+    /// connects to a backend chosen by the load balancing of the cluster, which
+    /// hashes the key if it is a consistent hashing
+    pub fn backend_for_key(
+        &mut self,
+        cluster_id: &str,
+        key: Option<&[u8]>,
     ) -> Result<(Rc<RefCell<Backend>>, TcpStream), ConnectionError> {
         if let Some(ref mut cluster_backends) = self.backends.get_mut(cluster_id) {
             if cluster_backends.backends.is_empty() {
@@ -87,7 +152,7 @@ impl BackendMap {
                 return Err(ConnectionError::NoBackendAvailable);
             }
 
-            if let Some(ref mut b) = cluster_backends.next_available_backend() {
+            if let Some(ref mut b) = cluster_backends.next_available_backend(key) {
                 let mut backend = b.borrow_mut();
 
                 debug!(
@@ -139,6 +204,8 @@ impl BackendMap {
             .backends
             .get_mut(cluster_id)
             .and_then(|cluster_backends| cluster_backends.find_sticky(sticky_session))
+            // the sessions of a draining backend go to the other ones
+            .filter(|b| b.borrow().status == BackendStatus::Normal)
             .map(|b| {
                 let mut backend = b.borrow_mut();
                 let conn = backend.try_connect();
@@ -163,6 +230,227 @@ impl BackendMap {
         }
     }
 
+    /// connects to the backend designated by a consistent hash of the key,
+    /// like the client IP or a request header value
+    pub fn backend_from_sticky_key(
+        &mut self,
+        cluster_id: &str,
+        key: &[u8],
+    ) -> Result<(Rc<RefCell<Backend>>, TcpStream), ConnectionError> {
+        self.backend_with_affinity(cluster_id, Some(key), |backend_map| {
+            backend_map.backend_from_hashed_sticky_key(cluster_id, key)
+        })
+    }
+
+    fn backend_from_hashed_sticky_key(
+        &mut self,
+        cluster_id: &str,
+        key: &[u8],
+    ) -> Result<(Rc<RefCell<Backend>>, TcpStream), ConnectionError> {
+        let sticky_conn: Option<Result<(Rc<RefCell<Backend>>, TcpStream), ConnectionError>> = self
+            .backends
+            .get_mut(cluster_id)
+            .and_then(|cluster_backends| cluster_backends.find_by_hash(key))
+            .map(|b| {
+                let mut backend = b.borrow_mut();
+                let conn = backend.try_connect();
+
+                conn.map(|c| (b.clone(), c)).map_err(|e| {
+                    error!(
+                        "could not connect {} to {:?} using a sticky key ({} failures)",
+                        cluster_id, backend.address, backend.failures
+                    );
+                    e
+                })
+            });
+
+        if let Some(res) = sticky_conn {
+            res
+        } else {
+            debug!(
+                "Couldn't find a backend for a sticky key in cluster {}",
+                cluster_id
+            );
+            self.backend_from_cluster_id(cluster_id)
+        }
+    }
+
+    /// connects to the backend bound to the key in the affinity table of the cluster.
+    /// Otherwise the backend is chosen as usual, then bound to the key and shared
+    /// with the other workers
+    pub fn backend_with_affinity<F>(
+        &mut self,
+        cluster_id: &str,
+        key: Option<&[u8]>,
+        choose: F,
+    ) -> Result<(Rc<RefCell<Backend>>, TcpStream), ConnectionError>
+    where
+        F: FnOnce(&mut BackendMap) -> Result<(Rc<RefCell<Backend>>, TcpStream), ConnectionError>,
+    {
+        let has_table = self
+            .backends
+            .get(cluster_id)
+            .map(|backends| backends.affinity.is_some())
+            .unwrap_or(false);
+        let key = match key {
+            Some(key) if has_table => String::from_utf8_lossy(key).into_owned(),
+            _ => return choose(self),
+        };
+
+        let bound = self
+            .backends
+            .get(cluster_id)
+            .and_then(|backends| backends.find_affinity(&key));
+        if let Some(backend) = bound {
+            let conn = backend.borrow_mut().try_connect();
+            match conn {
+                Ok(conn) => return Ok((backend, conn)),
+                Err(_) => error!(
+                    "could not connect {} to the backend bound to a sticky key",
+                    cluster_id
+                ),
+            }
+        }
+
+        let (backend, conn) = choose(self)?;
+        let backend_id = backend.borrow().backend_id.clone();
+        let table = self
+            .backends
+            .get_mut(cluster_id)
+            .and_then(|backends| backends.affinity.as_mut());
+        if let Some(table) = table {
+            if table.insert(key.clone(), backend_id.clone()) {
+                push_affinity(proxy::Affinity {
+                    cluster_id: cluster_id.to_string(),
+                    key,
+                    backend_id,
+                });
+            }
+        }
+
+        Ok((backend, conn))
+    }
+
+    pub fn set_affinity_table_for_cluster(
+        &mut self,
+        cluster_id: &str,
+        affinity_table: Option<proxy::AffinityTable>,
+    ) {
+        let cluster_backends = self.get_or_create_backend_list_for_cluster(cluster_id);
+        match (affinity_table, cluster_backends.affinity.as_mut()) {
+            (Some(settings), Some(table)) => table.resize(settings.size),
+            (Some(settings), None) => {
+                cluster_backends.affinity = Some(AffinityTable::new(settings.size))
+            }
+            (None, _) => cluster_backends.affinity = None,
+        }
+    }
+
+    /// binds a key to a backend, as learnt by another worker
+    pub fn set_affinity(&mut self, affinity: &proxy::Affinity) {
+        let table = self
+            .backends
+            .get_mut(&affinity.cluster_id)
+            .and_then(|backends| backends.affinity.as_mut());
+        if let Some(table) = table {
+            table.insert(affinity.key.clone(), affinity.backend_id.clone());
+        }
+    }
+
+    /// connects to a backend the request was not tried on yet, or to any
+    /// available backend once all of them were tried
+    pub fn backend_for_retry(
+        &mut self,
+        cluster_id: &str,
+        tried_backends: &[String],
+    ) -> Result<(Rc<RefCell<Backend>>, TcpStream), ConnectionError> {
+        let retry_conn: Option<Result<(Rc<RefCell<Backend>>, TcpStream), ConnectionError>> = self
+            .backends
+            .get_mut(cluster_id)
+            .and_then(|cluster_backends| cluster_backends.next_untried_backend(tried_backends))
+            .map(|b| {
+                let mut backend = b.borrow_mut();
+                let conn = backend.try_connect();
+
+                conn.map(|c| (b.clone(), c)).map_err(|e| {
+                    error!(
+                        "could not connect {} to {:?} to retry a request ({} failures)",
+                        cluster_id, backend.address, backend.failures
+                    );
+                    e
+                })
+            });
+
+        if let Some(res) = retry_conn {
+            res
+        } else {
+            debug!(
+                "all the backends of cluster {} were tried for this request",
+                cluster_id
+            );
+            self.backend_from_cluster_id(cluster_id)
+        }
+    }
+
+    /// ids of the backends of the cluster that fail their health checks
+    pub fn unhealthy_backends(&self, cluster_id: &str) -> Vec<String> {
+        self.backends
+            .get(cluster_id)
+            .map(|backend_list| {
+                backend_list
+                    .backends
+                    .iter()
+                    .filter(|backend| !backend.borrow().healthy)
+                    .map(|backend| backend.borrow().backend_id.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// the backends of every cluster, or of one of them, with their connections
+    pub fn query_backends(
+        &self,
+        cluster_id: Option<&str>,
+        now: Instant,
+    ) -> Vec<proxy::QueryAnswerBackend> {
+        let mut answer = Vec::new();
+        for (id, backend_list) in self.backends.iter() {
+            if cluster_id.filter(|cluster_id| cluster_id != id).is_some() {
+                continue;
+            }
+
+            for backend in backend_list.backends.iter() {
+                let backend = backend.borrow();
+                answer.push(proxy::QueryAnswerBackend {
+                    cluster_id: id.to_owned(),
+                    backend_id: backend.backend_id.to_owned(),
+                    address: backend.address,
+                    sticky_id: backend.sticky_id.clone(),
+                    weight: backend
+                        .load_balancing_parameters
+                        .as_ref()
+                        .map(|parameters| parameters.weight),
+                    backup: backend.backup,
+                    draining: backend.status != BackendStatus::Normal,
+                    healthy: backend.healthy,
+                    ejected: backend.outlier_stats.is_ejected(now),
+                    active_connections: backend.active_connections,
+                    active_requests: backend.active_requests,
+                    failures: backend.failures,
+                });
+            }
+        }
+
+        answer.sort_by(|a, b| {
+            (&a.cluster_id, &a.backend_id, a.address).cmp(&(
+                &b.cluster_id,
+                &b.backend_id,
+                b.address,
+            ))
+        });
+        answer
+    }
+
     pub fn set_load_balancing_policy_for_cluster(
         &mut self,
         cluster_id: &str,
@@ -175,6 +463,192 @@ impl BackendMap {
         cluster_backends.set_load_balancing_policy(lb_algo, metric);
     }
 
+    /// checks the backends of the cluster passively, or stops checking them
+    /// lets the requests of the cluster wait for a free connection when its backends
+    /// are saturated
+    pub fn set_request_queue_for_cluster(
+        &mut self,
+        cluster_id: &str,
+        request_queue: Option<proxy::RequestQueue>,
+    ) {
+        let cluster_backends = self.get_or_create_backend_list_for_cluster(cluster_id);
+        cluster_backends.request_queue = request_queue;
+    }
+
+    /// puts the request of a session in the wait queue of the cluster, if the cluster
+    /// has one and some of its backends are saturated. A request coming back to the
+    /// queue keeps its place at the front, until it waited too long. Returns the time
+    /// the request entered the queue
+    pub fn queue_request(
+        &mut self,
+        cluster_id: &str,
+        token: Token,
+        queued_since: Option<Instant>,
+    ) -> Option<Instant> {
+        let cluster_backends = self.backends.get_mut(cluster_id)?;
+        let request_queue = cluster_backends.request_queue?;
+
+        if !cluster_backends
+            .backends
+            .iter()
+            .any(|backend| backend.borrow().is_saturated())
+        {
+            return None;
+        }
+
+        cluster_backends
+            .waiting
+            .retain(|queued| queued.token != token);
+
+        let now = Instant::now();
+        let queued = match queued_since {
+            Some(since) if since + Duration::seconds(request_queue.timeout as i64) > now => {
+                let queued = QueuedRequest {
+                    token,
+                    since,
+                    deadline: since + Duration::seconds(request_queue.timeout as i64),
+                };
+                cluster_backends.waiting.push_front(queued);
+                since
+            }
+            Some(_) => return None,
+            None if cluster_backends.waiting.len() < request_queue.size => {
+                cluster_backends.waiting.push_back(QueuedRequest {
+                    token,
+                    since: now,
+                    deadline: now + Duration::seconds(request_queue.timeout as i64),
+                });
+                now
+            }
+            None => {
+                incr!("queue.full", Some(cluster_id), None);
+                return None;
+            }
+        };
+
+        gauge!(
+            "queue.depth",
+            cluster_backends.waiting.len(),
+            Some(cluster_id),
+            None
+        );
+        Some(queued)
+    }
+
+    /// removes a session from the wait queue of the cluster
+    pub fn remove_queued_request(&mut self, cluster_id: &str, token: Token) {
+        if let Some(cluster_backends) = self.backends.get_mut(cluster_id) {
+            cluster_backends
+                .waiting
+                .retain(|queued| queued.token != token);
+        }
+    }
+
+    /// takes out of the wait queues the sessions that can connect to a backend now, and
+    /// those that waited too long to be answered. Returns them with the next deadline
+    pub fn dequeue_requests(&mut self, now: Instant) -> (Vec<Token>, Option<Instant>) {
+        let mut tokens = Vec::new();
+        let mut next_deadline: Option<Instant> = None;
+
+        for (cluster_id, cluster_backends) in self.backends.iter_mut() {
+            if cluster_backends.waiting.is_empty() {
+                continue;
+            }
+
+            let mut free_connections = cluster_backends.free_connections();
+            let waiting = std::mem::take(&mut cluster_backends.waiting);
+            for queued in waiting {
+                if queued.deadline <= now {
+                    tokens.push(queued.token);
+                } else if free_connections > 0 {
+                    free_connections -= 1;
+                    time!(
+                        "queue.wait_time",
+                        cluster_id.as_str(),
+                        (now - queued.since).whole_milliseconds()
+                    );
+                    tokens.push(queued.token);
+                } else {
+                    next_deadline = Some(match next_deadline {
+                        Some(deadline) => deadline.min(queued.deadline),
+                        None => queued.deadline,
+                    });
+                    cluster_backends.waiting.push_back(queued);
+                }
+            }
+
+            gauge!(
+                "queue.depth",
+                cluster_backends.waiting.len(),
+                Some(cluster_id.as_str()),
+                None
+            );
+        }
+
+        (tokens, next_deadline)
+    }
+
+    pub fn set_outlier_detection_for_cluster(
+        &mut self,
+        cluster_id: &str,
+        outlier_detection: Option<proxy::OutlierDetection>,
+    ) {
+        let cluster_backends = self.get_or_create_backend_list_for_cluster(cluster_id);
+        cluster_backends.set_outlier_detection(outlier_detection);
+    }
+
+    /// connects to the backends of the cluster over TLS, or in clear text if
+    /// there is no configuration. An invalid configuration is logged and ignored
+    pub fn set_tls_for_cluster(&mut self, cluster_id: &str, tls: Option<&proxy::BackendTls>) {
+        let tls = match tls.map(BackendTlsConfig::new) {
+            Some(Ok(tls)) => Some(Rc::new(tls)),
+            Some(Err(e)) => {
+                error!(
+                    "invalid backend TLS configuration for cluster {}: {}",
+                    cluster_id, e
+                );
+                None
+            }
+            None => None,
+        };
+
+        let cluster_backends = self.get_or_create_backend_list_for_cluster(cluster_id);
+        cluster_backends.tls = tls;
+    }
+
+    /// starts a TLS session over the connection to the backend if it, or
+    /// its cluster, uses TLS
+    pub fn backend_socket(
+        &self,
+        cluster_id: &str,
+        backend: &Backend,
+        socket: TcpStream,
+        protocol: proxy::BackendProtocol,
+    ) -> Result<BackendSocket, ConnectionError> {
+        let tls = backend.tls.as_ref().or_else(|| {
+            self.backends
+                .get(cluster_id)
+                .and_then(|cluster_backends| cluster_backends.tls.as_ref())
+        });
+
+        match tls {
+            None => Ok(BackendSocket::Tcp(socket)),
+            Some(tls) => match tls.connect(backend.address, protocol) {
+                Ok(session) => Ok(BackendSocket::Rustls(Box::new(BackRustls {
+                    stream: socket,
+                    session,
+                }))),
+                Err(e) => {
+                    error!(
+                        "could not start a TLS session with backend {} of cluster {}: {}",
+                        backend.backend_id, cluster_id, e
+                    );
+                    Err(ConnectionError::NoBackendAvailable)
+                }
+            },
+        }
+    }
+
     pub fn get_or_create_backend_list_for_cluster(&mut self, cluster_id: &str) -> &mut BackendList {
         self.backends
             .entry(cluster_id.to_string())
@@ -182,11 +656,69 @@ impl BackendMap {
     }
 }
 
+/// a session waiting for a free connection to the backends of a cluster
+#[derive(Debug)]
+pub struct QueuedRequest {
+    pub token: Token,
+    pub since: Instant,
+    pub deadline: Instant,
+}
+
+/// backend ids bound to the sticky keys of a cluster
+#[derive(Debug, Default)]
+pub struct AffinityTable {
+    size: usize,
+    backends: HashMap<String, String>,
+    /// keys by age, the oldest ones are forgotten first
+    keys: VecDeque<String>,
+}
+
+impl AffinityTable {
+    pub fn new(size: usize) -> AffinityTable {
+        AffinityTable {
+            size,
+            ..Default::default()
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.backends.get(key).map(|backend_id| backend_id.as_str())
+    }
+
+    /// returns false if the key was already bound to this backend
+    pub fn insert(&mut self, key: String, backend_id: String) -> bool {
+        match self.backends.insert(key.clone(), backend_id.clone()) {
+            Some(previous) => previous != backend_id,
+            None => {
+                self.keys.push_back(key);
+                self.resize(self.size);
+                true
+            }
+        }
+    }
+
+    pub fn resize(&mut self, size: usize) {
+        self.size = size;
+        while self.keys.len() > size {
+            if let Some(key) = self.keys.pop_front() {
+                self.backends.remove(&key);
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct BackendList {
     pub backends: Vec<Rc<RefCell<Backend>>>,
     pub next_id: u32,
     pub load_balancing: Box<dyn LoadBalancingAlgorithm>,
+    /// TLS configuration of the backends that do not have their own
+    pub tls: Option<Rc<BackendTlsConfig>>,
+    pub outlier_detector: Option<Rc<OutlierDetector>>,
+    pub request_queue: Option<proxy::RequestQueue>,
+    /// sessions waiting for a free connection, in order
+    pub waiting: VecDeque<QueuedRequest>,
+    pub affinity: Option<AffinityTable>,
 }
 
 impl Default for BackendList {
@@ -201,20 +733,36 @@ impl BackendList {
             backends: Vec::new(),
             next_id: 0,
             load_balancing: Box::new(Random),
+            tls: None,
+            outlier_detector: None,
+            request_queue: None,
+            waiting: VecDeque::new(),
+            affinity: None,
         }
     }
 
     pub fn import_configuration_state(backend_vec: &[proxy::Backend]) -> BackendList {
         let mut list = BackendList::new();
         for backend in backend_vec {
-            let backend = Backend::new(
+            let mut new_backend = Backend::new(
                 &backend.backend_id,
                 backend.address,
                 backend.sticky_id.clone(),
                 backend.load_balancing_parameters.clone(),
                 backend.backup,
             );
-            list.add_backend(backend);
+            new_backend.max_connections = backend.max_connections;
+            new_backend.timeouts = backend.timeouts;
+            if let Some(tls) = &backend.tls {
+                match BackendTlsConfig::new(tls) {
+                    Ok(tls) => new_backend.tls = Some(Rc::new(tls)),
+                    Err(e) => error!(
+                        "invalid TLS configuration for backend {}: {}",
+                        backend.backend_id, e
+                    ),
+                }
+            }
+            list.add_backend(new_backend);
         }
 
         list
@@ -226,9 +774,11 @@ impl BackendList {
                 && (*b.borrow()).backend_id == backend.backend_id
         }) {
             None => {
-                let backend = Rc::new(RefCell::new(backend));
-                self.backends.push(backend);
+                let mut backend = backend;
+                backend.outlier_detector = self.outlier_detector.clone();
+                self.backends.push(Rc::new(RefCell::new(backend)));
                 self.next_id += 1;
+                self.count_outlier_backends();
             }
             // the backend already exists, update the configuration while
             // keeping connection retry state
@@ -237,6 +787,11 @@ impl BackendList {
                 b.sticky_id = backend.sticky_id.clone();
                 b.load_balancing_parameters = backend.load_balancing_parameters.clone();
                 b.backup = backend.backup;
+                b.max_connections = backend.max_connections;
+                b.timeouts = backend.timeouts;
+                b.tls = backend.tls.clone();
+                // adding a drained backend again puts it back in the rotation
+                b.status = BackendStatus::Normal;
             }
         }
     }
@@ -244,6 +799,48 @@ impl BackendList {
     pub fn remove_backend(&mut self, backend_address: &SocketAddr) {
         self.backends
             .retain(|backend| &(*backend.borrow()).address != backend_address);
+        self.count_outlier_backends();
+    }
+
+    /// shares a new outlier detection with the backends, their results and ejections
+    /// are forgotten
+    pub fn set_outlier_detection(&mut self, outlier_detection: Option<proxy::OutlierDetection>) {
+        if self
+            .outlier_detector
+            .as_ref()
+            .map(|detector| detector.settings)
+            == outlier_detection
+        {
+            return;
+        }
+
+        self.outlier_detector = outlier_detection
+            .map(|settings| Rc::new(OutlierDetector::new(settings, self.backends.len())));
+        for backend in self.backends.iter() {
+            let mut backend = backend.borrow_mut();
+            backend.outlier_detector = self.outlier_detector.clone();
+            backend.outlier_stats = Default::default();
+        }
+    }
+
+    /// connections the backends can still open, the backends without a maximum
+    /// accept any number of them
+    fn free_connections(&self) -> usize {
+        self.backends
+            .iter()
+            .map(|backend| backend.borrow())
+            .filter(|backend| backend.can_open())
+            .map(|backend| match backend.max_connections {
+                Some(max) => max.saturating_sub(backend.active_connections),
+                None => usize::MAX,
+            })
+            .fold(0, usize::saturating_add)
+    }
+
+    fn count_outlier_backends(&self) {
+        if let Some(detector) = &self.outlier_detector {
+            detector.set_backend_count(self.backends.len());
+        }
     }
 
     pub fn has_backend(&self, backend_address: &SocketAddr) -> bool {
@@ -261,6 +858,18 @@ impl BackendList {
             .find(|backend| backend.borrow().address == *backend_address)
     }
 
+    /// the available backend bound to the key
+    pub fn find_affinity(&self, key: &str) -> Option<Rc<RefCell<Backend>>> {
+        let backend_id = self.affinity.as_ref()?.get(key)?;
+        self.backends
+            .iter()
+            .find(|backend| {
+                let backend = backend.borrow();
+                backend.backend_id == backend_id && backend.can_open()
+            })
+            .cloned()
+    }
+
     pub fn find_sticky(&mut self, sticky_session: &str) -> Option<&mut Rc<RefCell<Backend>>> {
         self.backends
             .iter_mut()
@@ -268,6 +877,23 @@ impl BackendList {
             .and_then(|b| if b.borrow().can_open() { Some(b) } else { None })
     }
 
+    /// rendezvous hashing: every available backend gets a score from the key and its id,
+    /// the highest one wins. Adding or removing a backend only moves the keys it wins
+    pub fn find_by_hash(&mut self, key: &[u8]) -> Option<Rc<RefCell<Backend>>> {
+        let mut backends = self.available_backends(false);
+
+        if backends.is_empty() {
+            backends = self.available_backends(true);
+        }
+
+        backends.into_iter().max_by_key(|b| {
+            let mut hasher = DefaultHasher::new();
+            b.borrow().backend_id.hash(&mut hasher);
+            key.hash(&mut hasher);
+            hasher.finish()
+        })
+    }
+
     pub fn available_backends(&mut self, backup: bool) -> Vec<Rc<RefCell<Backend>>> {
         self.backends
             .iter()
@@ -280,7 +906,7 @@ impl BackendList {
             .collect()
     }
 
-    pub fn next_available_backend(&mut self) -> Option<Rc<RefCell<Backend>>> {
+    pub fn next_available_backend(&mut self, key: Option<&[u8]>) -> Option<Rc<RefCell<Backend>>> {
         let mut backends = self.available_backends(false);
 
         if backends.is_empty() {
@@ -291,6 +917,34 @@ impl BackendList {
             return None;
         }
 
+        match key {
+            Some(key) => self.load_balancing.next_backend_for_key(&mut backends, key),
+            None => self.load_balancing.next_available_backend(&mut backends),
+        }
+    }
+
+    /// like `next_available_backend`, skipping the backends whose id is in `tried_backends`
+    pub fn next_untried_backend(
+        &mut self,
+        tried_backends: &[String],
+    ) -> Option<Rc<RefCell<Backend>>> {
+        let untried = |backends: Vec<Rc<RefCell<Backend>>>| -> Vec<Rc<RefCell<Backend>>> {
+            backends
+                .into_iter()
+                .filter(|b| !tried_backends.contains(&b.borrow().backend_id))
+                .collect()
+        };
+
+        let mut backends = untried(self.available_backends(false));
+
+        if backends.is_empty() {
+            backends = untried(self.available_backends(true));
+        }
+
+        if backends.is_empty() {
+            return None;
+        }
+
         self.load_balancing.next_available_backend(&mut backends)
     }
 
@@ -314,6 +968,12 @@ impl BackendList {
                     metric: metric.unwrap_or(proxy::LoadMetric::Connections),
                 })
             }
+            LoadBalancingAlgorithms::ConsistentHash => {
+                self.load_balancing = Box::new(ConsistentHash::new())
+            }
+            LoadBalancingAlgorithms::LeastResponseTime => {
+                self.load_balancing = Box::new(LeastResponseTime)
+            }
         }
     }
 }
@@ -456,6 +1116,71 @@ mod backends_test {
             .is_err());
     }
 
+    #[test]
+    fn it_should_always_find_the_same_backend_for_a_sticky_key() {
+        let mut backends_list = BackendList::new();
+        for i in 0..4 {
+            backends_list.add_backend(Backend::new(
+                &format!("back-{}", i),
+                format!("127.0.0.1:{}", 9100 + i).parse().unwrap(),
+                None,
+                None,
+                None,
+            ));
+        }
+
+        let key = b"192.168.1.1";
+        let chosen = backends_list.find_by_hash(key).unwrap();
+        for _ in 0..10 {
+            assert!(Rc::ptr_eq(
+                &chosen,
+                &backends_list.find_by_hash(key).unwrap()
+            ));
+        }
+
+        // removing another backend does not move the key
+        let other = backends_list
+            .backends
+            .iter()
+            .find(|b| !Rc::ptr_eq(b, &chosen))
+            .map(|b| b.borrow().address)
+            .unwrap();
+        backends_list.remove_backend(&other);
+        assert!(Rc::ptr_eq(
+            &chosen,
+            &backends_list.find_by_hash(key).unwrap()
+        ));
+
+        assert!(BackendList::new().find_by_hash(key).is_none());
+    }
+
+    #[test]
+    fn it_should_not_retry_a_request_on_a_backend_already_tried() {
+        let mut backends_list = BackendList::new();
+        for i in 0..3 {
+            backends_list.add_backend(Backend::new(
+                &format!("back-{}", i),
+                format!("127.0.0.1:{}", 9200 + i).parse().unwrap(),
+                None,
+                None,
+                None,
+            ));
+        }
+
+        let tried = vec!["back-0".to_string(), "back-2".to_string()];
+        for _ in 0..10 {
+            let backend = backends_list.next_untried_backend(&tried).unwrap();
+            assert_eq!("back-1", backend.borrow().backend_id);
+        }
+
+        let tried = vec![
+            "back-0".to_string(),
+            "back-1".to_string(),
+            "back-2".to_string(),
+        ];
+        assert!(backends_list.next_untried_backend(&tried).is_none());
+    }
+
     #[test]
     fn it_should_add_a_backend_when_he_doesnt_already_exist() {
         let backend_id = "myback";
@@ -494,4 +1219,191 @@ mod backends_test {
 
         assert_eq!(1, backends_list.backends.len());
     }
+
+    #[test]
+    fn it_should_not_open_new_connections_to_a_draining_backend() {
+        let mut backend_map = BackendMap::new();
+        let address = "127.0.0.1:80".parse().unwrap();
+        let mut backend = Backend::new("back", address, None, None, None);
+        backend.active_connections = 2;
+        backend_map.add_backend("cluster", backend);
+
+        assert!(backend_map.drain_backend("cluster", &address, false));
+        assert!(!backend_map.drain_backend("cluster", &"127.0.0.1:81".parse().unwrap(), false));
+        let backend = backend_map.backends["cluster"].backends[0].clone();
+        assert!(!backend.borrow().can_open());
+        assert_eq!(backend.borrow_mut().inc_connections(), None);
+
+        assert_eq!(backend.borrow_mut().dec_connections(), Some(1));
+        assert_eq!(backend.borrow().status, BackendStatus::Draining);
+        assert_eq!(backend.borrow_mut().dec_connections(), None);
+        assert_eq!(backend.borrow().status, BackendStatus::Closed);
+
+        // the backend comes back when it is added again
+        backend_map.add_backend("cluster", Backend::new("back", address, None, None, None));
+        assert!(backend.borrow().can_open());
+
+        assert!(backend_map.drain_backend("cluster", &address, true));
+        assert!(backend_map.backends["cluster"].backends.is_empty());
+    }
+
+    #[test]
+    fn it_should_keep_the_backend_bound_to_a_sticky_key() {
+        let mut backend_map = BackendMap::new();
+        for (id, port) in [("b0", 1024), ("b1", 1025)] {
+            let address = format!("127.0.0.1:{}", port).parse().unwrap();
+            backend_map.add_backend("cluster", Backend::new(id, address, None, None, None));
+        }
+        backend_map.set_load_balancing_policy_for_cluster(
+            "cluster",
+            LoadBalancingAlgorithms::RoundRobin,
+            None,
+        );
+        backend_map
+            .set_affinity_table_for_cluster("cluster", Some(proxy::AffinityTable { size: 10 }));
+
+        let mut backend_for = |key: &str| {
+            backend_map
+                .backend_with_affinity("cluster", Some(key.as_bytes()), |backends| {
+                    backends.backend_from_cluster_id("cluster")
+                })
+                .unwrap()
+                .0
+                .borrow()
+                .backend_id
+                .clone()
+        };
+        assert_eq!(backend_for("10.0.0.1"), "b0");
+        assert_eq!(backend_for("10.0.0.1"), "b0");
+        assert_eq!(backend_for("10.0.0.2"), "b1");
+        assert_eq!(backend_for("10.0.0.1"), "b0");
+
+        // learnt by another worker
+        backend_map.set_affinity(&proxy::Affinity {
+            cluster_id: String::from("cluster"),
+            key: String::from("10.0.0.1"),
+            backend_id: String::from("b1"),
+        });
+        let backends = &backend_map.backends["cluster"];
+        assert_eq!(
+            backends
+                .find_affinity("10.0.0.1")
+                .map(|backend| backend.borrow().backend_id.clone()),
+            Some(String::from("b1"))
+        );
+
+        let mut table = AffinityTable::new(2);
+        assert!(table.insert(String::from("a"), String::from("b0")));
+        assert!(!table.insert(String::from("a"), String::from("b0")));
+        assert!(table.insert(String::from("b"), String::from("b0")));
+        assert!(table.insert(String::from("c"), String::from("b1")));
+        assert_eq!(table.get("a"), None);
+        assert_eq!(table.get("c"), Some("b1"));
+    }
+
+    #[test]
+    fn it_should_queue_the_requests_while_the_backends_are_saturated() {
+        let mut backend_map = BackendMap::new();
+        let mut backend = Backend::new("back", "127.0.0.1:80".parse().unwrap(), None, None, None);
+        backend.max_connections = Some(1);
+        backend.active_connections = 1;
+        backend_map.add_backend("cluster", backend);
+        backend_map.set_request_queue_for_cluster(
+            "cluster",
+            Some(proxy::RequestQueue {
+                size: 2,
+                timeout: 5,
+            }),
+        );
+
+        let since = backend_map
+            .queue_request("cluster", Token(1), None)
+            .unwrap();
+        assert!(backend_map
+            .queue_request("cluster", Token(2), None)
+            .is_some());
+        assert_eq!(backend_map.queue_request("cluster", Token(3), None), None);
+        // a request coming back keeps its place
+        assert_eq!(
+            backend_map.queue_request("cluster", Token(1), Some(since)),
+            Some(since)
+        );
+
+        let (tokens, deadline) = backend_map.dequeue_requests(Instant::now());
+        assert!(tokens.is_empty());
+        assert_eq!(deadline, Some(since + Duration::seconds(5)));
+
+        // one connection is free
+        backend_map.backends["cluster"].backends[0]
+            .borrow_mut()
+            .active_connections = 0;
+        let (tokens, _) = backend_map.dequeue_requests(Instant::now());
+        assert_eq!(tokens, vec![Token(1)]);
+
+        // the request waited too long
+        let (tokens, deadline) = backend_map.dequeue_requests(since + Duration::seconds(6));
+        assert_eq!((tokens, deadline), (vec![Token(2)], None));
+    }
+
+    #[test]
+    fn it_should_list_the_backends_with_their_connections() {
+        let mut backend_map = BackendMap::new();
+        let address = "127.0.0.1:1024".parse().unwrap();
+        let mut backend = Backend::new("back-1", address, Some("sticky".to_owned()), None, None);
+        backend.active_connections = 3;
+        backend.active_requests = 2;
+        backend.failures = 1;
+        backend_map.add_backend("cluster-b", backend);
+        let other = "127.0.0.1:1025".parse().unwrap();
+        backend_map.add_backend("cluster-b", Backend::new("back-0", other, None, None, None));
+        backend_map.add_backend(
+            "cluster-a",
+            Backend::new(
+                "back-2",
+                "127.0.0.1:1026".parse().unwrap(),
+                None,
+                None,
+                None,
+            ),
+        );
+        assert!(backend_map.drain_backend("cluster-b", &address, false));
+
+        let backends = backend_map.query_backends(None, Instant::now());
+        assert_eq!(
+            backends
+                .iter()
+                .map(|backend| (backend.cluster_id.as_str(), backend.backend_id.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("cluster-a", "back-2"),
+                ("cluster-b", "back-0"),
+                ("cluster-b", "back-1"),
+            ]
+        );
+        assert_eq!(
+            backends[2],
+            proxy::QueryAnswerBackend {
+                cluster_id: String::from("cluster-b"),
+                backend_id: String::from("back-1"),
+                address,
+                sticky_id: Some(String::from("sticky")),
+                weight: None,
+                backup: false,
+                draining: true,
+                healthy: true,
+                ejected: false,
+                active_connections: 3,
+                active_requests: 2,
+                failures: 1,
+            }
+        );
+        assert!(!backends[1].draining);
+
+        let backends = backend_map.query_backends(Some("cluster-a"), Instant::now());
+        assert_eq!(backends.len(), 1);
+        assert_eq!(backends[0].backend_id, "back-2");
+        assert!(backend_map
+            .query_backends(Some("unknown"), Instant::now())
+            .is_empty());
+    }
 }
@@ -160,6 +160,13 @@ impl BufferQueue {
         acc
     }
 
+    /// data at the start of the buffer that was already parsed, ahead of the input
+    pub fn parsed_data_size(&self) -> usize {
+        self.buffer
+            .available_data()
+            .saturating_sub(self.input_data_size())
+    }
+
     pub fn unparsed_data(&self) -> &[u8] {
         let largest_size = self.merge_input_slices();
         //println!("buffer: {}, parsed: {}", self.buffer_position, self.parsed_position);
@@ -235,6 +242,70 @@ impl BufferQueue {
         self.output_queue.push(OutputElement::Insert(v));
     }
 
+    /// replaces `length` bytes of the output, starting at `offset` in the buffer,
+    /// with `data`. Returns false if the range is not contained in one output slice
+    pub fn replace_output_range(&mut self, offset: usize, length: usize, data: Vec<u8>) -> bool {
+        let mut position = 0;
+
+        for (index, element) in self.output_queue.iter().enumerate() {
+            match *element {
+                OutputElement::Slice(size) => {
+                    if position <= offset && offset + length <= position + size {
+                        let before = offset - position;
+                        let after = size - before - length;
+
+                        let mut elements = Vec::new();
+                        if before > 0 {
+                            elements.push(OutputElement::Slice(before));
+                        }
+                        if length > 0 {
+                            elements.push(OutputElement::Delete(length));
+                        }
+                        if !data.is_empty() {
+                            elements.push(OutputElement::Insert(data));
+                        }
+                        if after > 0 {
+                            elements.push(OutputElement::Slice(after));
+                        }
+
+                        self.output_queue.splice(index..index + 1, elements);
+                        return true;
+                    }
+                    position += size;
+                }
+                OutputElement::Delete(size) => position += size,
+                OutputElement::Insert(_) => {}
+                OutputElement::Splice(_) => return false,
+            }
+
+            if position > offset {
+                return false;
+            }
+        }
+
+        false
+    }
+
+    /// replaces the first occurrence of `old` in the data inserted in the output,
+    /// like an edit of a request written again to another backend.
+    /// Returns false if no inserted data contains it
+    pub fn replace_inserted(&mut self, old: &[u8], new: &[u8]) -> bool {
+        if old.is_empty() {
+            return false;
+        }
+
+        for element in self.output_queue.iter_mut() {
+            if let OutputElement::Insert(data) = element {
+                if let Some(position) = data.windows(old.len()).position(|window| window == old) {
+                    data.splice(position..position + old.len(), new.iter().copied());
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
     pub fn has_output_data(&self) -> bool {
         !self.output_queue.is_empty()
     }
@@ -453,6 +524,19 @@ impl BufferQueue {
         self.invariant();
     }
 
+    /// skips the deleted data at the start of the output, if it is in the buffer,
+    /// for output that ends with deleted data
+    pub fn consume_deleted_output(&mut self) {
+        while let Some(&OutputElement::Delete(sz)) = self.output_queue.first() {
+            if sz > self.buffer.available_data() {
+                break;
+            }
+            self.buffer_position += sz;
+            self.buffer.consume(sz);
+            self.output_queue.remove(0);
+        }
+    }
+
     pub fn print_unparsed(&self) {
         println!("{:?}", str::from_utf8(self.unparsed_data()));
     }
@@ -608,4 +692,63 @@ mod tests {
 
         b.write(&b"KLMNOP"[..]).unwrap();
     }
+
+    #[test]
+    fn replace_output_range() {
+        let (_pool, mut b) = buf_with_capacity(17);
+        b.buffer.write_all(&b"GET /a/b HTTP/1.1"[..]).unwrap();
+        b.buffer.fill(17);
+        b.input_queue.push(InputElement::Slice(17));
+        b.consume_parsed_data(17);
+        b.slice_output(17);
+
+        assert!(!b.replace_output_range(4, 14, Vec::from(&b"/b"[..])));
+        assert!(b.replace_output_range(4, 4, Vec::from(&b"/b"[..])));
+        assert_eq!(
+            b.output_queue,
+            vec!(
+                OutputElement::Slice(4),
+                OutputElement::Delete(4),
+                OutputElement::Insert(Vec::from(&b"/b"[..])),
+                OutputElement::Slice(9)
+            )
+        );
+
+        // inserting at the start of the last slice
+        assert!(b.replace_output_range(8, 0, Vec::from(&b"?c"[..])));
+        assert!(!b.replace_output_range(6, 1, Vec::new()));
+
+        let output = b
+            .as_ioslice()
+            .iter()
+            .flat_map(|slice| slice.iter().copied())
+            .collect::<Vec<u8>>();
+        assert_eq!(&output[..], &b"GET /b?c HTTP/1.1"[..]);
+    }
+
+    #[test]
+    fn replace_inserted() {
+        let (_pool, mut b) = buf_with_capacity(17);
+        b.buffer.write_all(&b"GET /a/b HTTP/1.1"[..]).unwrap();
+        b.buffer.fill(17);
+        b.input_queue.push(InputElement::Slice(17));
+        b.consume_parsed_data(17);
+        b.slice_output(17);
+
+        assert!(!b.replace_inserted(&b"/a"[..], &b"/c"[..]));
+        assert!(b.replace_output_range(4, 4, Vec::from(&b"/b"[..])));
+        assert!(b.replace_inserted(&b"/b"[..], &b"/c/d"[..]));
+        // a written request queued again is one inserted element
+        b.output_queue
+            .insert(0, OutputElement::Insert(Vec::from(&b"Host: a\r\n"[..])));
+        assert!(b.replace_inserted(&b"Host: a\r\n"[..], &b"Host: bc\r\n"[..]));
+        assert!(!b.replace_inserted(&b""[..], &b"x"[..]));
+
+        let output = b
+            .as_ioslice()
+            .iter()
+            .flat_map(|slice| slice.iter().copied())
+            .collect::<Vec<u8>>();
+        assert_eq!(&output[..], &b"Host: bc\r\nGET /c/d HTTP/1.1"[..]);
+    }
 }
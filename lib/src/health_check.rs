@@ -0,0 +1,709 @@
+//! active health checks of the backends. Every worker checks the backends of
+//! the clusters that have a health check from its event loop: the checks use non
+//! blocking sockets that are not registered in the event loop, their progress is
+//! looked at every time the checks run, which happens every `PROBE_POLL_INTERVAL`
+//! while some of them are in progress
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Read, Write},
+    net::SocketAddr,
+    str,
+};
+
+use mio::net::TcpStream;
+use time::{Duration, Instant};
+
+use crate::{
+    backends::BackendMap,
+    protocol::{
+        h2::{client::ClientState, grpc},
+        http::parser::HeaderEdits,
+    },
+    server::push_event,
+    sozu_command::proxy::{BackendHealth, HealthCheck, HealthCheckKind, ProxyEvent},
+    Backend, ClusterId,
+};
+
+/// delay between two looks at the checks in progress
+const PROBE_POLL_INTERVAL: Duration = Duration::milliseconds(100);
+
+/// the end of longer responses is not read by the HTTP checks
+const MAX_RESPONSE_SIZE: usize = 16384;
+
+/// what a check does once connected
+enum Check {
+    /// nothing, the connection is enough
+    Tcp,
+    /// part of the HTTP request still to be written
+    Http(Vec<u8>),
+    Grpc(Box<GrpcCall>),
+}
+
+/// a check in progress
+struct Probe {
+    stream: TcpStream,
+    started: Instant,
+    connected: bool,
+    check: Check,
+    /// the HTTP response, or the HTTP/2 frames not parsed yet
+    response: Vec<u8>,
+}
+
+impl Probe {
+    fn start(address: SocketAddr, check: Check, now: Instant) -> std::io::Result<Probe> {
+        Ok(Probe {
+            stream: TcpStream::connect(address)?,
+            started: now,
+            connected: false,
+            check,
+            response: Vec::new(),
+        })
+    }
+
+    /// moves the check forward, returns its result once it is over
+    fn progress(&mut self, health_check: &HealthCheck) -> Option<bool> {
+        if !self.connected {
+            if !matches!(self.stream.take_error(), Ok(None)) {
+                return Some(false);
+            }
+            match self.stream.peer_addr() {
+                Ok(_) => self.connected = true,
+                Err(e) if e.kind() == ErrorKind::NotConnected => return None,
+                Err(_) => return Some(false),
+            }
+        }
+
+        let request = match &mut self.check {
+            Check::Tcp => return Some(true),
+            Check::Grpc(call) => return call.progress(&mut self.stream, &mut self.response),
+            Check::Http(request) => request,
+        };
+
+        while !request.is_empty() {
+            match self.stream.write(request) {
+                Ok(0) => return Some(false),
+                Ok(sz) => {
+                    request.drain(..sz);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return None,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return Some(false),
+            }
+        }
+
+        let mut buffer = [0; 4096];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return check_response(&self.response, health_check, true),
+                Ok(sz) => {
+                    self.response.extend_from_slice(&buffer[..sz]);
+                    if self.response.len() >= MAX_RESPONSE_SIZE {
+                        return check_response(&self.response, health_check, true);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    return check_response(&self.response, health_check, false)
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return Some(false),
+            }
+        }
+    }
+}
+
+/// the HTTP/2 connection of a gRPC check, carrying a single call
+struct GrpcCall {
+    state: ClientState,
+    stream_id: u32,
+}
+
+impl GrpcCall {
+    fn new(address: SocketAddr, service: &str) -> GrpcCall {
+        let (headers, mut message) = grpc::health_check_call(address, service);
+        let mut state = ClientState::new();
+        let stream_id = state.open_stream(0, &headers, false, HeaderEdits::default());
+        state.send_request_data(stream_id, &mut message, true, &[]);
+
+        GrpcCall { state, stream_id }
+    }
+
+    /// writes the frames of the client, parses those of the backend, and
+    /// returns the result once the call is over
+    fn progress(&mut self, stream: &mut TcpStream, input: &mut Vec<u8>) -> Option<bool> {
+        let mut buffer = [0; 4096];
+        loop {
+            while !self.state.output.is_empty() {
+                match stream.write(&self.state.output) {
+                    Ok(0) => return Some(false),
+                    Ok(sz) => {
+                        self.state.output.drain(..sz);
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(_) => return Some(false),
+                }
+            }
+
+            match stream.read(&mut buffer) {
+                Ok(0) => return Some(false),
+                Ok(sz) => {
+                    input.extend_from_slice(&buffer[..sz]);
+                    match self.state.parse(input) {
+                        Ok(consumed) => {
+                            input.drain(..consumed);
+                        }
+                        Err(_) => return Some(false),
+                    }
+
+                    let call = self.state.streams.get(&self.stream_id)?;
+                    if call.reset.is_some() {
+                        return Some(false);
+                    }
+                    if call.response_ended {
+                        return Some(call.response.as_ref().is_some_and(|response| {
+                            grpc::is_serving(response, &call.body, &call.trailers)
+                        }));
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return None,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return Some(false),
+            }
+        }
+    }
+}
+
+/// checks of a backend
+struct BackendCheck {
+    successes: u32,
+    failures: u32,
+    total_failures: u64,
+    last_result: Option<bool>,
+    /// duration of the last check, none if it could not start
+    last_latency: Option<Duration>,
+    next_check: Instant,
+    probe: Option<Probe>,
+}
+
+/// the health checks of the backends of a worker
+#[derive(Default)]
+pub struct HealthChecker {
+    /// health checks of the clusters
+    clusters: HashMap<ClusterId, HealthCheck>,
+    /// checks by cluster, backend id and address
+    checks: HashMap<(ClusterId, String, SocketAddr), BackendCheck>,
+    /// the checks have nothing to do before that date
+    next_run: Option<Instant>,
+}
+
+impl HealthChecker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// sets or removes the health check of a cluster. The backends of a
+    /// cluster that is not checked anymore come back in the rotation
+    pub fn set_cluster(
+        &mut self,
+        cluster_id: &str,
+        health_check: Option<HealthCheck>,
+        backends: &mut BackendMap,
+    ) {
+        match health_check {
+            Some(health_check) => {
+                self.clusters.insert(cluster_id.to_owned(), health_check);
+            }
+            None => {
+                if self.clusters.remove(cluster_id).is_none() {
+                    return;
+                }
+                self.checks.retain(|(id, _, _), _| id != cluster_id);
+                if let Some(backend_list) = backends.backends.get(cluster_id) {
+                    for backend in backend_list.backends.iter() {
+                        backend.borrow_mut().healthy = true;
+                    }
+                }
+            }
+        }
+        self.next_run = None;
+    }
+
+    /// starts the checks that are due and looks at those in progress.
+    /// Returns when the checks should run again
+    pub fn run(&mut self, backends: &mut BackendMap) -> Option<Instant> {
+        if self.clusters.is_empty() {
+            return None;
+        }
+
+        let now = Instant::now();
+        if let Some(next_run) = self.next_run.filter(|next_run| *next_run > now) {
+            return Some(next_run);
+        }
+
+        let mut next_run = now + Duration::seconds(1);
+        let mut checked = Vec::new();
+        for (cluster_id, health_check) in self.clusters.iter() {
+            let backend_list = match backends.backends.get(cluster_id) {
+                Some(backend_list) => backend_list,
+                None => continue,
+            };
+
+            for backend in backend_list.backends.iter() {
+                let mut backend = backend.borrow_mut();
+                let key = (
+                    cluster_id.to_owned(),
+                    backend.backend_id.to_owned(),
+                    backend.address,
+                );
+                let check = self.checks.entry(key.clone()).or_insert(BackendCheck {
+                    successes: 0,
+                    failures: 0,
+                    total_failures: 0,
+                    last_result: None,
+                    last_latency: None,
+                    next_check: now,
+                    probe: None,
+                });
+                checked.push(key);
+
+                let result = match check.probe.as_mut() {
+                    Some(probe) => probe.progress(health_check).or_else(|| {
+                        (now - probe.started >= Duration::seconds(health_check.timeout.into()))
+                            .then_some(false)
+                    }),
+                    None if check.next_check <= now => {
+                        // the backends connected with TLS only get a TCP check
+                        let tls = backend.tls.is_some() || backend_list.tls.is_some();
+                        let request = match health_check.kind {
+                            HealthCheckKind::Http if !tls => {
+                                Check::Http(health_check_request(health_check, backend.address))
+                            }
+                            HealthCheckKind::Grpc if !tls => Check::Grpc(Box::new(GrpcCall::new(
+                                backend.address,
+                                health_check.service.as_deref().unwrap_or_default(),
+                            ))),
+                            _ => Check::Tcp,
+                        };
+
+                        match Probe::start(backend.address, request, now) {
+                            Ok(probe) => {
+                                check.probe = Some(probe);
+                                None
+                            }
+                            Err(e) => {
+                                debug!(
+                                    "could not check backend {} at {}: {}",
+                                    backend.backend_id, backend.address, e
+                                );
+                                Some(false)
+                            }
+                        }
+                    }
+                    None => None,
+                };
+
+                if let Some(success) = result {
+                    check.last_latency = check.probe.take().map(|probe| now - probe.started);
+                    check.next_check = now + Duration::seconds(health_check.interval.max(1).into());
+                    record_result(cluster_id, &mut backend, check, health_check, success);
+                }
+
+                next_run = next_run.min(if check.probe.is_some() {
+                    now + PROBE_POLL_INTERVAL
+                } else {
+                    check.next_check
+                });
+            }
+        }
+
+        // forget the backends that were removed
+        if checked.len() != self.checks.len() {
+            self.checks.retain(|key, _| checked.contains(key));
+        }
+
+        self.next_run = Some(next_run);
+        Some(next_run)
+    }
+
+    /// health of the backends of every cluster, or of one of them
+    pub fn backend_health(
+        &self,
+        cluster_id: Option<&str>,
+        backends: &BackendMap,
+    ) -> Vec<BackendHealth> {
+        let now = Instant::now();
+        let mut health = Vec::new();
+        for (id, backend_list) in backends.backends.iter() {
+            if cluster_id.filter(|cluster_id| cluster_id != id).is_some() {
+                continue;
+            }
+
+            for backend in backend_list.backends.iter() {
+                let backend = backend.borrow();
+                let check = self.checks.get(&(
+                    id.to_owned(),
+                    backend.backend_id.to_owned(),
+                    backend.address,
+                ));
+
+                health.push(BackendHealth {
+                    cluster_id: id.to_owned(),
+                    backend_id: backend.backend_id.to_owned(),
+                    address: backend.address,
+                    healthy: backend.healthy,
+                    ejected: backend.outlier_stats.is_ejected(now),
+                    last_check: check.and_then(|check| check.last_result),
+                    latency: check
+                        .and_then(|check| check.last_latency)
+                        .map(|latency| latency.whole_milliseconds() as u64),
+                    successes: check.map(|check| check.successes).unwrap_or(0),
+                    failures: check.map(|check| check.failures).unwrap_or(0),
+                    total_failures: check.map(|check| check.total_failures).unwrap_or(0),
+                });
+            }
+        }
+
+        health.sort_by(|a, b| {
+            (&a.cluster_id, &a.backend_id, a.address).cmp(&(
+                &b.cluster_id,
+                &b.backend_id,
+                b.address,
+            ))
+        });
+        health
+    }
+}
+
+/// takes the backend out of the rotation, or brings it back, once enough checks
+/// failed or succeeded in a row
+fn record_result(
+    cluster_id: &str,
+    backend: &mut Backend,
+    check: &mut BackendCheck,
+    health_check: &HealthCheck,
+    success: bool,
+) {
+    check.last_result = Some(success);
+    if success {
+        check.successes += 1;
+        check.failures = 0;
+
+        if !backend.healthy && check.successes >= health_check.rise {
+            backend.healthy = true;
+            info!(
+                "backend server {} at {} passes its health checks, it is back in the rotation",
+                backend.backend_id, backend.address
+            );
+            incr!(
+                "health_check.up",
+                Some(cluster_id),
+                Some(backend.backend_id.as_str())
+            );
+            push_event(ProxyEvent::BackendUp(
+                backend.backend_id.clone(),
+                backend.address,
+            ));
+        }
+    } else {
+        check.failures += 1;
+        check.total_failures += 1;
+        check.successes = 0;
+        incr!(
+            "health_check.failures",
+            Some(cluster_id),
+            Some(backend.backend_id.as_str())
+        );
+
+        if backend.healthy && check.failures >= health_check.fall {
+            backend.healthy = false;
+            error!(
+                "backend server {} at {} fails its health checks, it leaves the rotation",
+                backend.backend_id, backend.address
+            );
+            incr!(
+                "health_check.down",
+                Some(cluster_id),
+                Some(backend.backend_id.as_str())
+            );
+            push_event(ProxyEvent::BackendDown(
+                backend.backend_id.clone(),
+                backend.address,
+            ));
+        }
+    }
+}
+
+fn health_check_request(health_check: &HealthCheck, address: SocketAddr) -> Vec<u8> {
+    format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: sozu-health-check\r\nConnection: close\r\n\r\n",
+        health_check.path, address
+    )
+    .into_bytes()
+}
+
+/// whether the response shows a healthy backend, None if more of it is needed
+fn check_response(response: &[u8], health_check: &HealthCheck, complete: bool) -> Option<bool> {
+    let status_line = match response.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => &response[..end],
+        None => return if complete { Some(false) } else { None },
+    };
+
+    let status = str::from_utf8(status_line).ok().and_then(|line| {
+        let mut parts = line.split(' ');
+        parts
+            .next()
+            .filter(|version| version.starts_with("HTTP/1."))
+            .and(parts.next())
+            .and_then(|status| status.parse::<u16>().ok())
+    });
+    let expected_status = match (status, health_check.expected_status) {
+        (Some(status), Some(expected)) => status == expected,
+        (Some(status), None) => (200..400).contains(&status),
+        (None, _) => false,
+    };
+    if !expected_status {
+        return Some(false);
+    }
+
+    let expected_body = match &health_check.expected_body {
+        Some(expected_body) => expected_body.as_bytes(),
+        None => return Some(true),
+    };
+    let found = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|end| {
+            response[end + 4..]
+                .windows(expected_body.len().max(1))
+                .any(|w| w == expected_body)
+        })
+        .unwrap_or(false);
+
+    if found {
+        Some(true)
+    } else if complete {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        net::TcpListener,
+        thread::{self, sleep},
+    };
+
+    use hpack::Encoder;
+
+    use crate::protocol::h2::serializer::{gen_data, gen_headers, gen_settings};
+
+    #[test]
+    fn responses() {
+        let health_check = HealthCheck {
+            kind: HealthCheckKind::Http,
+            expected_body: Some(String::from("ok")),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            check_response(b"HTTP/1.1 200 OK\r\n\r\nstatus: ok", &health_check, false),
+            Some(true)
+        );
+        assert_eq!(
+            check_response(b"HTTP/1.1 200 OK\r\n\r\nstatus: o", &health_check, false),
+            None
+        );
+        assert_eq!(
+            check_response(b"HTTP/1.1 200 OK\r\n\r\nstatus: o", &health_check, true),
+            Some(false)
+        );
+        assert_eq!(
+            check_response(
+                b"HTTP/1.1 503 Service Unavailable\r\n",
+                &health_check,
+                false
+            ),
+            Some(false)
+        );
+        assert_eq!(check_response(b"HTTP/1.1 2", &health_check, false), None);
+        assert_eq!(
+            check_response(b"SSH-2.0\r\n", &health_check, false),
+            Some(false)
+        );
+
+        let health_check = HealthCheck {
+            kind: HealthCheckKind::Http,
+            expected_status: Some(204),
+            ..Default::default()
+        };
+        assert_eq!(
+            check_response(b"HTTP/1.1 204 No Content\r\n", &health_check, false),
+            Some(true)
+        );
+        assert_eq!(
+            check_response(b"HTTP/1.1 200 OK\r\n", &health_check, false),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn backends_leave_and_come_back() {
+        // the backend answers the first request, then fails until it is stopped
+        let listener = TcpListener::bind("127.0.0.1:0").expect("could not bind");
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for (index, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request);
+                let status = if index == 0 { "200 OK" } else { "500 Error" };
+                let _ = stream.write_all(
+                    format!("HTTP/1.1 {}\r\nContent-Length: 2\r\n\r\nok", status).as_bytes(),
+                );
+            }
+        });
+
+        let mut backends = BackendMap::new();
+        backends.add_backend(
+            "cluster_1",
+            Backend::new("cluster_1-0", address, None, None, None),
+        );
+        let mut health_checker = HealthChecker::new();
+        health_checker.set_cluster(
+            "cluster_1",
+            Some(HealthCheck {
+                kind: HealthCheckKind::Http,
+                path: String::from("/health"),
+                expected_body: Some(String::from("ok")),
+                interval: 1,
+                rise: 1,
+                fall: 2,
+                ..Default::default()
+            }),
+            &mut backends,
+        );
+
+        let healthy =
+            |backends: &BackendMap| backends.backends["cluster_1"].backends[0].borrow().healthy;
+        let run_until =
+            |health_checker: &mut HealthChecker, backends: &mut BackendMap, failures: u32| {
+                for _ in 0..100 {
+                    health_checker.run(backends);
+                    let check = health_checker.checks.values().next().unwrap();
+                    if check.probe.is_none() && check.failures == failures {
+                        return;
+                    }
+                    sleep(std::time::Duration::from_millis(50));
+                }
+                panic!("the checks did not end");
+            };
+
+        run_until(&mut health_checker, &mut backends, 0);
+        assert!(healthy(&backends));
+
+        // one failure is not enough
+        run_until(&mut health_checker, &mut backends, 1);
+        assert!(healthy(&backends));
+        run_until(&mut health_checker, &mut backends, 2);
+        assert!(!healthy(&backends));
+
+        let health = health_checker.backend_health(Some("cluster_1"), &backends);
+        assert_eq!(health.len(), 1);
+        assert_eq!(
+            (
+                health[0].healthy,
+                health[0].last_check,
+                health[0].failures,
+                health[0].total_failures
+            ),
+            (false, Some(false), 2, 2)
+        );
+        assert!(health[0].latency.is_some());
+        assert!(health_checker
+            .backend_health(Some("cluster_2"), &backends)
+            .is_empty());
+        assert!(backends
+            .backends
+            .get_mut("cluster_1")
+            .unwrap()
+            .next_available_backend(None)
+            .is_none());
+
+        // without health check, the backend is back in the rotation
+        health_checker.set_cluster("cluster_1", None, &mut backends);
+        assert!(healthy(&backends));
+        assert_eq!(health_checker.run(&mut backends), None);
+    }
+
+    #[test]
+    fn grpc_checks() {
+        // the service is serving for the first call, then stops serving
+        let listener = TcpListener::bind("127.0.0.1:0").expect("could not bind");
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for (index, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request);
+
+                let serving_status = if index == 0 { 1 } else { 2 };
+                let mut encoder = Encoder::new();
+                let mut response = Vec::new();
+                gen_settings(&mut response, &[]);
+                let headers = encoder.encode([(&b":status"[..], &b"200"[..])]);
+                gen_headers(&mut response, 1, &headers, false, 16384);
+                gen_data(
+                    &mut response,
+                    1,
+                    &[0, 0, 0, 0, 2, 0x08, serving_status],
+                    false,
+                );
+                let trailers = encoder.encode([(&b"grpc-status"[..], &b"0"[..])]);
+                gen_headers(&mut response, 1, &trailers, true, 16384);
+                let _ = stream.write_all(&response);
+
+                // until the check closes the connection
+                while matches!(stream.read(&mut request), Ok(sz) if sz > 0) {}
+            }
+        });
+
+        let mut backends = BackendMap::new();
+        backends.add_backend(
+            "cluster_1",
+            Backend::new("cluster_1-0", address, None, None, None),
+        );
+        let mut health_checker = HealthChecker::new();
+        health_checker.set_cluster(
+            "cluster_1",
+            Some(HealthCheck {
+                kind: HealthCheckKind::Grpc,
+                service: Some(String::from("echo.Echo")),
+                interval: 1,
+                rise: 1,
+                fall: 1,
+                ..Default::default()
+            }),
+            &mut backends,
+        );
+
+        let run_until =
+            |health_checker: &mut HealthChecker, backends: &mut BackendMap, last_check: bool| {
+                for _ in 0..100 {
+                    health_checker.run(backends);
+                    let health = health_checker.backend_health(None, backends);
+                    if health[0].last_check == Some(last_check) {
+                        return health[0].healthy;
+                    }
+                    sleep(std::time::Duration::from_millis(50));
+                }
+                panic!("the checks did not end");
+            };
+
+        assert!(run_until(&mut health_checker, &mut backends, true));
+        assert!(!run_until(&mut health_checker, &mut backends, false));
+    }
+}
@@ -15,12 +15,14 @@ use slab::Slab;
 use time::{Duration, Instant};
 
 use crate::{
-    router::Router,
+    router::{pick_ab_test_variant, pick_weighted_cluster, RouteResult, Router},
     sozu_command::{
         logging,
         proxy::{
-            Cluster, HttpFrontend, HttpListener, ProxyEvent, ProxyRequest, ProxyRequestOrder,
-            ProxyResponse, Route,
+            BackendProtocol, Cluster, ClusterMaintenance, Compression, HashKey, HeaderPosition,
+            HttpFrontend, HttpListener, LoadBalancingAlgorithms, PathNormalization, ProxyEvent,
+            ProxyRequest, ProxyRequestOrder, ProxyResponse, RequestLimits, RetryCondition, Route,
+            StickyMode,
         },
         ready::Ready,
         scm_socket::{Listeners, ScmSocket},
@@ -31,22 +33,26 @@ use crate::{
 };
 
 use super::{
+    acl::AccessLists,
     backends::BackendMap,
+    buffer_queue::BufferQueue,
     pool::Pool,
     protocol::{
         http::{
-            answers::HttpAnswers,
-            parser::{hostname_and_port, Method, RequestState},
+            answers::{method_not_allowed_answer, redirect_answer, HttpAnswers},
+            normalization::{normalize_uri, NormalizedUri},
+            parser::{
+                hostname_and_port, normalized_hostname, Header, HeaderEdits, Method, RequestState,
+            },
+            session::{HttpProxy, HttpSession, RequestRouting},
             DefaultAnswerStatus,
         },
         proxy_protocol::expect::ExpectProxyProtocol,
         {Http, Pipe, ProtocolResult, StickySession},
     },
     retry::RetryPolicy,
-    server::{
-        push_event, ListenSession, ListenToken, ProxyChannel, Server, SessionManager, CONN_RETRIES,
-    },
-    socket::server_bind,
+    server::{push_event, ListenSession, ListenToken, ProxyChannel, Server, SessionManager},
+    socket::{server_bind, BackendSocket, SocketHandler},
     AcceptError, Backend, BackendConnectAction, BackendConnectionStatus, ClusterId,
     ConnectionError, Protocol, ProxyConfiguration, ProxySession, Readiness, SessionMetrics,
     SessionResult,
@@ -78,7 +84,7 @@ pub struct Session {
     pub cluster_id: Option<String>,
     sticky_name: String,
     pub listener_token: Token,
-    connection_attempt: u8,
+    routing: RequestRouting,
     answers: Rc<RefCell<HttpAnswers>>,
     last_event: Instant,
     front_timeout: TimeoutContainer,
@@ -147,7 +153,7 @@ impl Session {
             last_event: Instant::now(),
             front_timeout,
             listener_token: listener_token,
-            connection_attempt: 0,
+            routing: RequestRouting::default(),
             answers,
             frontend_timeout_duration,
             backend_timeout_duration,
@@ -168,6 +174,16 @@ impl Session {
                 let front_token = self.frontend_token;
                 let back_token = unwrap_msg!(http.back_token());
                 let ws_context = http.websocket_context();
+                let websocket = http.is_websocket_upgrade();
+                // the buffers can still hold the head of the request or response
+                let front_head = http
+                    .front_buf
+                    .as_ref()
+                    .map_or(0, BufferQueue::parsed_data_size);
+                let back_head = http
+                    .back_buf
+                    .as_ref()
+                    .map_or(0, BufferQueue::parsed_data_size);
 
                 let front_buf = match http.front_buf {
                     Some(buf) => buf.buffer,
@@ -224,14 +240,21 @@ impl Session {
 
                 pipe.front_readiness.event = http.front_readiness.event;
                 pipe.back_readiness.event = http.back_readiness.event;
-                http.front_timeout
-                    .set_duration(self.frontend_timeout_duration);
-                http.back_timeout
-                    .set_duration(self.backend_timeout_duration);
+                let websocket_timeout = self.websocket_timeout_duration().filter(|_| websocket);
+                http.front_timeout.set_duration(
+                    websocket_timeout.unwrap_or_else(|| self.front_timeout_duration()),
+                );
+                http.back_timeout.set_duration(
+                    websocket_timeout.unwrap_or_else(|| self.back_timeout_duration()),
+                );
                 pipe.front_timeout = Some(http.front_timeout);
                 pipe.back_timeout = Some(http.back_timeout);
                 pipe.set_back_token(back_token);
                 //pipe.set_cluster_id(self.cluster_id.clone());
+                // the frames of the other protocols are not known
+                if websocket {
+                    pipe.track_websocket(front_head, back_head, self.websocket_drain());
+                }
 
                 self.protocol = Some(State::WebSocket(pipe));
                 true
@@ -430,7 +453,7 @@ impl Session {
         }
     }
 
-    fn set_back_socket(&mut self, socket: TcpStream) {
+    fn set_back_socket(&mut self, socket: BackendSocket) {
         match *unwrap_msg!(self.protocol.as_mut()) {
             State::Http(ref mut http) => http.set_back_socket(socket, self.backend.clone()),
             // not passing it here since we should already have a connection available
@@ -466,7 +489,7 @@ impl Session {
 
             // the back timeout was of connect_timeout duration before,
             // now that we're connected, move to backend_timeout duration
-            let t = self.backend_timeout_duration;
+            let t = self.back_timeout_duration();
             if let Some(h) = self.http_mut() {
                 h.set_back_timeout(t);
                 h.cancel_backend_timeout();
@@ -547,6 +570,7 @@ impl Session {
 
             let already_unavailable = backend.retry_policy.is_down();
             backend.retry_policy.fail();
+            backend.record_outcome(self.cluster_id.as_deref(), true);
             incr!(
                 "connections.error",
                 self.cluster_id.as_deref(),
@@ -572,9 +596,11 @@ impl Session {
     }
 
     fn reset_connection_attempt(&mut self) {
-        self.connection_attempt = 0;
+        self.routing.connection_attempt = 0;
+        self.routing.tried_backends.clear();
     }
 
+    /// timeouts of the current backend, then of its cluster
     fn cancel_timeouts(&mut self) {
         self.front_timeout.cancel();
 
@@ -589,6 +615,18 @@ impl Session {
         let mut counter = 0;
         let max_loop_iterations = 100000;
 
+        // the request waiting in the queue of its cluster may have a free connection now
+        if self.routing.queued_since.is_some()
+            && self.back_connected == BackendConnectionStatus::NotConnected
+        {
+            match self.connect_to_backend(session.clone()) {
+                // reuse connection or send a default answer, we can continue
+                Ok(BackendConnectAction::Reuse) | Err(_) => {}
+                // New or Replace: stop here, we must wait for an event
+                _ => return SessionResult::Continue,
+            }
+        }
+
         if self.back_connected().is_connecting()
             && self
                 .back_readiness()
@@ -614,22 +652,34 @@ impl Session {
                     self.log_context()
                 );
 
-                self.connection_attempt += 1;
+                self.record_failed_attempt();
                 self.fail_backend_connection();
 
                 self.back_connected = BackendConnectionStatus::Connecting(Instant::now());
 
                 // trigger a backend reconnection
                 self.close_backend();
-                match self.connect_to_backend(session.clone()) {
-                    // reuse connection or send a default answer, we can continue
-                    Ok(BackendConnectAction::Reuse) | Err(_) => {}
-                    // New or Replace: stop here, we must wait for an event
-                    _ => return SessionResult::Continue,
+                if !self.can_retry(RetryCondition::ConnectFailure) {
+                    error!(
+                        "{} could not connect to a backend, not retrying",
+                        self.log_context()
+                    );
+                    self.set_answer(DefaultAnswerStatus::Answer503, None);
+                } else {
+                    incr!(
+                        "retries",
+                        self.cluster_id.as_deref(),
+                        self.metrics.backend_id.as_deref()
+                    );
+                    match self.connect_to_backend(session.clone()) {
+                        // reuse connection or send a default answer, we can continue
+                        Ok(BackendConnectAction::Reuse) | Err(_) => {}
+                        // New or Replace: stop here, we must wait for an event
+                        _ => return SessionResult::Continue,
+                    }
                 }
             } else {
                 self.metrics().backend_connected();
-                self.reset_connection_attempt();
                 self.set_back_connected(BackendConnectionStatus::Connected);
                 // we might get an early response from the backend, so we want to look
                 // at readable events
@@ -688,6 +738,8 @@ impl Session {
 
                 match order {
                     SessionResult::ConnectBackend => {
+                        // a new request gets a new retry budget
+                        self.reset_connection_attempt();
                         match self.connect_to_backend(session.clone()) {
                             // reuse connection or send a default answer, we can continue
                             Ok(BackendConnectAction::Reuse) | Err(_) => {}
@@ -712,6 +764,10 @@ impl Session {
                 if order != SessionResult::Continue {
                     return order;
                 }
+
+                if self.retry_request(session.clone()) {
+                    return SessionResult::Continue;
+                }
             }
 
             if front_interest.is_writable() {
@@ -846,7 +902,7 @@ impl Session {
     }
 
     fn check_circuit_breaker(&mut self) -> Result<(), ConnectionError> {
-        if self.connection_attempt == CONN_RETRIES {
+        if self.routing.connection_attempt >= self.request_retries().max_attempts() {
             error!("{} max connection attempt reached", self.log_context());
             self.set_answer(DefaultAnswerStatus::Answer503, None);
             return Err(ConnectionError::NoBackendAvailable);
@@ -924,6 +980,11 @@ impl Session {
     }
 
     fn cluster_id_from_request(&mut self) -> Result<String, ConnectionError> {
+        if self.routing.counted_listener.is_none() {
+            self.set_answer(DefaultAnswerStatus::Answer503, None);
+            return Err(ConnectionError::TooManySessions);
+        }
+
         let (host, uri, method) = match self.extract_route() {
             Ok((h, u, m)) => (h, u, m),
             Err(e) => {
@@ -931,27 +992,128 @@ impl Session {
                 return Err(e);
             }
         };
+        // the ACLs and the frontends match the hostname without its port, in lowercase
+        let hostname = match normalized_hostname(host) {
+            Some(hostname) => hostname,
+            None => {
+                self.set_answer(DefaultAnswerStatus::Answer400, None);
+                return Err(ConnectionError::InvalidHost);
+            }
+        };
+        let acl_allows = match self.http().and_then(|http| http.session_address) {
+            Some(address) => self
+                .proxy
+                .borrow()
+                .listeners
+                .get(&self.listener_token)
+                .map(|l| l.borrow().acls.allows_request(address.ip(), &hostname))
+                .unwrap_or(true),
+            None => true,
+        };
+        if !acl_allows {
+            let (status, answer) = self.answers.borrow().deny_answer(403, None);
+            self.set_answer(status, Some(answer));
+            return Err(ConnectionError::Unauthorized);
+        }
+
+        let path_normalization = self
+            .proxy
+            .borrow()
+            .listeners
+            .get(&self.listener_token)
+            .map(|l| l.borrow().get_path_normalization())
+            .unwrap_or_default();
+        let normalized_uri = match normalize_uri(uri, &path_normalization) {
+            NormalizedUri::Unchanged => None,
+            NormalizedUri::Rewritten(normalized) => Some(normalized),
+            NormalizedUri::Redirect(location) => {
+                match redirect_answer(308, "{path}", host, &location) {
+                    Some((status, answer)) => self.set_answer(status, Some(answer)),
+                    None => self.set_answer(DefaultAnswerStatus::Answer503, None),
+                }
+                return Err(ConnectionError::Redirect);
+            }
+        };
+        let uri = normalized_uri.as_deref().unwrap_or(uri);
 
         let cluster_id_res = self
             .proxy
             .borrow()
             .listeners
             .get(&self.listener_token)
-            .as_ref()
-            .and_then(|listener| listener.borrow().frontend_from_request(host, uri, method));
-
-        let cluster_id = match cluster_id_res {
-            Some(Route::ClusterId(cluster_id)) => cluster_id,
-            Some(Route::Deny) => {
-                self.set_answer(DefaultAnswerStatus::Answer401, None);
-                return Err(ConnectionError::Unauthorized);
-            }
+            .and_then(|listener| {
+                let listener = listener.borrow();
+                // parsing the headers again is only needed by the frontends matching on them
+                let headers = if listener.has_header_rules() {
+                    self.http()
+                        .map(|http| http.get_request_headers())
+                        .unwrap_or_default()
+                } else {
+                    Vec::new()
+                };
+                listener.frontend_from_request(&hostname, uri, method, &headers)
+            });
+
+        let RouteResult {
+            route,
+            rewritten_path,
+            mirror_cluster_id,
+            allowed_methods,
+        } = match cluster_id_res {
+            Some(route_result) => route_result,
             None => {
                 self.set_answer(DefaultAnswerStatus::Answer404, None);
                 return Err(ConnectionError::HostNotFound);
             }
         };
 
+        let mut ab_test_cookie = None;
+        let cluster_id = match route {
+            Route::ClusterId(cluster_id) => cluster_id,
+            Route::Weighted(clusters) => match pick_weighted_cluster(&clusters) {
+                Some(cluster_id) => cluster_id.to_string(),
+                None => {
+                    self.set_answer(DefaultAnswerStatus::Answer503, None);
+                    return Err(ConnectionError::NoBackendAvailable);
+                }
+            },
+            Route::AbTest { cookie, variants } => {
+                let assigned = self
+                    .http()
+                    .and_then(|http| http.get_request_cookie(&cookie));
+                match pick_ab_test_variant(&variants, assigned.as_deref()) {
+                    Some((cluster_id, newly_assigned)) => {
+                        if newly_assigned {
+                            ab_test_cookie = Some((cookie.clone(), cluster_id.to_string()));
+                        }
+                        cluster_id.to_string()
+                    }
+                    None => {
+                        self.set_answer(DefaultAnswerStatus::Answer503, None);
+                        return Err(ConnectionError::NoBackendAvailable);
+                    }
+                }
+            }
+            Route::Deny { status, body_path } => {
+                let (status, answer) = if allowed_methods.is_empty() {
+                    self.answers
+                        .borrow()
+                        .deny_answer(status, body_path.as_deref())
+                } else {
+                    method_not_allowed_answer(&allowed_methods)
+                };
+                self.set_answer(status, Some(answer));
+                return Err(ConnectionError::Unauthorized);
+            }
+            Route::Redirect { to, code } => {
+                match redirect_answer(code, &to, host, uri) {
+                    Some((status, answer)) => self.set_answer(status, Some(answer)),
+                    None => self.set_answer(DefaultAnswerStatus::Answer503, None),
+                }
+                return Err(ConnectionError::Redirect);
+            }
+        };
+
         let front_should_redirect_https = self
             .proxy
             .borrow()
@@ -969,21 +1131,168 @@ impl Session {
             return Err(ConnectionError::HttpsRedirect);
         }
 
+        let maintenance = self.proxy.borrow().maintenance.get(&cluster_id).cloned();
+        if let Some(answer) = maintenance {
+            let answer = answer.unwrap_or_else(|| {
+                self.answers
+                    .borrow()
+                    .get(DefaultAnswerStatus::Answer503, Some(&cluster_id))
+            });
+            self.set_answer(DefaultAnswerStatus::Answer503, Some(answer));
+            return Err(ConnectionError::Maintenance);
+        }
+
+        if !self.enter_cluster(&cluster_id) {
+            self.set_answer(DefaultAnswerStatus::Answer503, None);
+            return Err(ConnectionError::TooManySessions);
+        }
+
+        let request_limits = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .map(|cluster| cluster.request_limits)
+            .unwrap_or_default();
+        let within_limits = self
+            .http_mut()
+            .map(|http| http.set_cluster_request_limits(&request_limits))
+            .unwrap_or(true);
+        if !within_limits {
+            return Err(ConnectionError::RequestTooLarge);
+        }
+
+        let compression = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .map(|cluster| cluster.compression)
+            .unwrap_or_default();
+        if let Some(http) = self.http_mut() {
+            http.set_cluster_compression(&compression);
+        }
+
+        let streaming = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .map(|cluster| cluster.streaming)
+            .unwrap_or(false);
+        if let Some(http) = self.http_mut() {
+            http.set_cluster_streaming(streaming);
+        }
+
+        let upgrade_protocols = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .map(|cluster| cluster.upgrade_protocols.clone())
+            .unwrap_or_default();
+        if let Some(http) = self.http_mut() {
+            http.set_cluster_upgrades(&upgrade_protocols);
+        }
+
+        let request_retries = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .map(|cluster| cluster.request_retries.clone())
+            .unwrap_or_default();
+        if let Some(http) = self.http_mut() {
+            http.set_cluster_retries(&request_retries);
+        }
+
+        self.routing.mirror_cluster_id = mirror_cluster_id;
+
+        // the request is sent with the normalized path, unless the frontend rewrites it
+        if let Some(path) = rewritten_path.or(normalized_uri) {
+            let rewritten = self
+                .http_mut()
+                .map(|http| http.set_request_path(&path))
+                .unwrap_or(false);
+            if !rewritten {
+                return Err(ConnectionError::PathNotRewritten);
+            }
+        }
+
+        let header_edits = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .filter(|cluster| !cluster.header_actions.is_empty())
+            .map(|cluster| {
+                (
+                    HeaderEdits::new(&cluster.header_actions, HeaderPosition::Request),
+                    HeaderEdits::new(&cluster.header_actions, HeaderPosition::Response),
+                )
+            });
+        if let Some((request_edits, response_edits)) = header_edits {
+            if let Some(http) = self.http_mut() {
+                http.edit_request_headers(&request_edits);
+                http.response_header_edits.extend(response_edits);
+            }
+        }
+
+        let security_headers = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .map(|cluster| cluster.security_headers.clone())
+            .unwrap_or_default();
+        if let Some(http) = self.http_mut() {
+            http.set_cluster_security_headers(&security_headers);
+        }
+
+        // set after the header edits of the cluster, which replace those of the response
+        if let Some((name, value)) = ab_test_cookie {
+            if let Some(http) = self.http_mut() {
+                http.add_response_cookie(&name, &value);
+            }
+        }
+
         Ok(cluster_id)
     }
 
     pub fn backend_from_request(
         &mut self,
         cluster_id: &str,
-        front_should_stick: bool,
-    ) -> Result<TcpStream, ConnectionError> {
+        sticky_mode: Option<StickyMode>,
+        hash_key: Option<HashKey>,
+    ) -> Result<BackendSocket, ConnectionError> {
+        let front_should_stick = sticky_mode == Some(StickyMode::Cookie);
+        let sticky_key = self
+            .http()
+            .zip(sticky_mode.as_ref())
+            .and_then(|(http, sticky_mode)| http.get_sticky_key(sticky_mode));
+        let hash_key = self
+            .http()
+            .zip(hash_key.as_ref())
+            .and_then(|(http, hash_key)| http.get_hash_key(hash_key));
         let sticky_session = self
             .http()
             .and_then(|http| http.request_state.as_ref())
             .and_then(|request_state| request_state.get_sticky_session());
 
-        let result = match (front_should_stick, sticky_session) {
-            (true, Some(sticky_session)) => self
+        let result = match (sticky_key, front_should_stick, sticky_session) {
+            _ if !self.routing.tried_backends.is_empty() => self
+                .proxy
+                .borrow()
+                .backends
+                .borrow_mut()
+                .backend_for_retry(cluster_id, &self.routing.tried_backends),
+            (Some(sticky_key), _, _) => self
+                .proxy
+                .borrow()
+                .backends
+                .borrow_mut()
+                .backend_from_sticky_key(cluster_id, &sticky_key),
+            (None, true, Some(sticky_session)) => self
                 .proxy
                 .borrow()
                 .backends
@@ -1001,11 +1310,28 @@ impl Session {
                 .borrow()
                 .backends
                 .borrow_mut()
-                .backend_from_cluster_id(cluster_id),
+                .backend_for_key(cluster_id, hash_key.as_deref()),
         };
 
         let (backend, conn) = match result {
             Ok((b, c)) => (b, c),
+            Err(e) => {
+                if !self.queue_request(cluster_id) {
+                    self.set_answer(DefaultAnswerStatus::Answer503, None);
+                }
+                return Err(e);
+            }
+        };
+        self.leave_queue();
+
+        let socket = self.proxy.borrow().backends.borrow().backend_socket(
+            cluster_id,
+            &backend.borrow(),
+            conn,
+            BackendProtocol::Http1,
+        );
+        let conn = match socket {
+            Ok(socket) => socket,
             Err(e) => {
                 self.set_answer(DefaultAnswerStatus::Answer503, None);
                 return Err(e);
@@ -1054,7 +1380,19 @@ impl Session {
 
         self.check_circuit_breaker()?;
 
-        let cluster_id = self.cluster_id_from_request()?;
+        // a retried or queued request stays in the cluster it was routed to
+        let cluster_id = match self.cluster_id.clone() {
+            Some(cluster_id)
+                if self.routing.connection_attempt > 0 || self.routing.queued_since.is_some() =>
+            {
+                cluster_id
+            }
+            _ => {
+                let cluster_id = self.cluster_id_from_request()?;
+                self.connect_mirror(session_rc.clone());
+                cluster_id
+            }
+        };
 
         // check if we can reuse the backend connection
         if (self.http().and_then(|h| h.cluster_id.as_ref()) == Some(&cluster_id))
@@ -1074,6 +1412,7 @@ impl Session {
                 .unwrap_or(false);
 
             if has_backend && self.check_backend_connection() {
+                self.rewrite_host(&cluster_id);
                 return Ok(BackendConnectAction::Reuse);
             } else if let Some(token) = self.back_token() {
                 self.close_backend();
@@ -1100,19 +1439,28 @@ impl Session {
             http.cluster_id = Some(cluster_id.clone());
         }
 
-        let front_should_stick = self
+        let sticky_mode = self
             .proxy
             .borrow()
             .clusters
             .get(&cluster_id)
-            .map(|cluster| cluster.sticky_session)
-            .unwrap_or(false);
+            .filter(|cluster| cluster.sticky_session)
+            .map(|cluster| cluster.sticky_mode.clone());
+        let hash_key = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .filter(|cluster| cluster.load_balancing == LoadBalancingAlgorithms::ConsistentHash)
+            .map(|cluster| cluster.hash_key.clone());
 
-        let mut socket = self.backend_from_request(&cluster_id, front_should_stick)?;
-        if let Err(e) = socket.set_nodelay(true) {
+        let mut socket = self.backend_from_request(&cluster_id, sticky_mode, hash_key)?;
+        self.rewrite_host(&cluster_id);
+        if let Err(e) = socket.socket_ref().set_nodelay(true) {
             error!(
                 "error setting nodelay on back socket({:?}): {:?}",
-                socket, e
+                socket.socket_ref(),
+                e
             );
         }
 
@@ -1121,14 +1469,20 @@ impl Session {
         }
 
         let connect_timeout = time::Duration::seconds(i64::from(
-            self.proxy
-                .borrow()
-                .listeners
-                .get(&self.listener_token)
-                .as_ref()
-                .map(|listener| listener.borrow().config.connect_timeout)
-                .unwrap(),
+            self.timeouts().connect_timeout.unwrap_or_else(|| {
+                self.proxy
+                    .borrow()
+                    .listeners
+                    .get(&self.listener_token)
+                    .as_ref()
+                    .map(|listener| listener.borrow().config.connect_timeout)
+                    .unwrap()
+            }),
         ));
+        let front_timeout = self.front_timeout_duration();
+        if let Some(http) = self.http_mut() {
+            http.frontend_timeout_duration = front_timeout;
+        }
 
         self.back_connected = BackendConnectionStatus::Connecting(Instant::now());
 
@@ -1136,11 +1490,15 @@ impl Session {
             Some(back_token) => {
                 self.set_back_token(back_token);
                 if let Err(e) = self.proxy.borrow().registry.register(
-                    &mut socket,
+                    socket.socket_mut(),
                     back_token,
                     Interest::READABLE | Interest::WRITABLE,
                 ) {
-                    error!("error registering back socket({:?}): {:?}", socket, e);
+                    error!(
+                        "error registering back socket({:?}): {:?}",
+                        socket.socket_ref(),
+                        e
+                    );
                 }
 
                 self.set_back_socket(socket);
@@ -1174,11 +1532,15 @@ impl Session {
                 };
 
                 if let Err(e) = self.proxy.borrow().registry.register(
-                    &mut socket,
+                    socket.socket_mut(),
                     back_token,
                     Interest::READABLE | Interest::WRITABLE,
                 ) {
-                    error!("error registering back socket({:?}): {:?}", socket, e);
+                    error!(
+                        "error registering back socket({:?}): {:?}",
+                        socket.socket_ref(),
+                        e
+                    );
                 }
 
                 self.set_back_socket(socket);
@@ -1193,10 +1555,77 @@ impl Session {
     }
 }
 
+impl HttpSession for Session {
+    type Front = TcpStream;
+    type Listener = Listener;
+    type Proxy = Proxy;
+
+    fn proxy(&self) -> &Rc<RefCell<Proxy>> {
+        &self.proxy
+    }
+
+    fn http(&self) -> Option<&Http<TcpStream, Listener>> {
+        Session::http(self)
+    }
+
+    fn http_mut(&mut self) -> Option<&mut Http<TcpStream, Listener>> {
+        Session::http_mut(self)
+    }
+
+    fn frontend_token(&self) -> Token {
+        self.frontend_token
+    }
+
+    fn cluster_id(&self) -> Option<&str> {
+        self.cluster_id.as_deref()
+    }
+
+    fn backend(&self) -> Option<&Rc<RefCell<Backend>>> {
+        self.backend.as_ref()
+    }
+
+    fn metrics(&self) -> &SessionMetrics {
+        &self.metrics
+    }
+
+    fn routing(&self) -> &RequestRouting {
+        &self.routing
+    }
+
+    fn routing_mut(&mut self) -> &mut RequestRouting {
+        &mut self.routing
+    }
+
+    fn frontend_timeout_duration(&self) -> Duration {
+        self.frontend_timeout_duration
+    }
+
+    fn backend_timeout_duration(&self) -> Duration {
+        self.backend_timeout_duration
+    }
+
+    fn log_context(&self) -> String {
+        Session::log_context(self)
+    }
+
+    fn close_backend(&mut self) {
+        Session::close_backend(self)
+    }
+
+    fn connect_to_backend(
+        &mut self,
+        session_rc: Rc<RefCell<dyn ProxySession>>,
+    ) -> Result<BackendConnectAction, ConnectionError> {
+        Session::connect_to_backend(self, session_rc)
+    }
+}
+
 impl ProxySession for Session {
     fn close(&mut self) {
         self.metrics.service_stop();
         self.cancel_timeouts();
+        self.leave_queue();
+        self.leave_session_limits();
         if let Err(e) = self.front_socket().shutdown(Shutdown::Both) {
             if e.kind() != ErrorKind::NotConnected {
                 error!(
@@ -1208,6 +1637,7 @@ impl ProxySession for Session {
         }
 
         self.close_backend();
+        self.close_mirror();
 
         if let Some(State::Http(ref mut http)) = self.protocol {
             //if the state was initial, the connection was already reset
@@ -1285,13 +1715,23 @@ impl ProxySession for Session {
             if let Some(readiness) = self.back_readiness() {
                 readiness.event |= events;
             }
+        } else if let Some(mirror) = self
+            .routing
+            .mirror
+            .as_mut()
+            .filter(|mirror| mirror.token == token)
+        {
+            mirror.readiness.event |= events;
         }
     }
 
     fn ready(&mut self, session: Rc<RefCell<dyn ProxySession>>) {
         self.metrics().service_start();
 
-        match self.ready_inner(session) {
+        let result = self.ready_inner(session);
+        self.mirror_ready();
+
+        match result {
             SessionResult::CloseSession => self.close(),
             SessionResult::CloseBackend => self.close_backend(),
             _ => (),
@@ -1303,6 +1743,7 @@ impl ProxySession for Session {
     fn shutting_down(&mut self) {
         let res = match &mut self.protocol {
             Some(State::Http(h)) => h.shutting_down(),
+            Some(State::WebSocket(pipe)) => pipe.shutting_down(&mut self.metrics),
             _ => SessionResult::CloseSession,
         };
 
@@ -1349,6 +1790,9 @@ impl ProxySession for Session {
         if let Some(tk) = self.back_token() {
             v.push(tk)
         }
+        if let Some(mirror) = &self.routing.mirror {
+            v.push(mirror.token)
+        }
 
         v
     }
@@ -1365,6 +1809,7 @@ pub struct Listener {
     pub token: Token,
     pub active: bool,
     tags: BTreeMap<String, BTreeMap<String, String>>,
+    pub acls: AccessLists,
 }
 
 impl ListenerHandler for Listener {
@@ -1382,17 +1827,48 @@ impl ListenerHandler for Listener {
             None => self.tags.remove(&key),
         };
     }
+
+    fn get_request_limits(&self) -> RequestLimits {
+        self.config.request_limits
+    }
+
+    fn get_compression(&self) -> Compression {
+        self.config.compression
+    }
+
+    fn get_path_normalization(&self) -> PathNormalization {
+        self.config.path_normalization
+    }
 }
 
 pub struct Proxy {
     listeners: HashMap<Token, Rc<RefCell<Listener>>>,
     backends: Rc<RefCell<BackendMap>>,
     clusters: HashMap<ClusterId, Cluster>,
+    maintenance: HashMap<ClusterId, Option<Rc<Vec<u8>>>>,
     pool: Rc<RefCell<Pool>>,
     registry: Registry,
     sessions: Rc<RefCell<SessionManager>>,
 }
 
+impl HttpProxy for Proxy {
+    fn clusters(&self) -> &HashMap<ClusterId, Cluster> {
+        &self.clusters
+    }
+
+    fn backends(&self) -> &Rc<RefCell<BackendMap>> {
+        &self.backends
+    }
+
+    fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    fn sessions(&self) -> &Rc<RefCell<SessionManager>> {
+        &self.sessions
+    }
+}
+
 impl Proxy {
     pub fn new(
         registry: Registry,
@@ -1403,6 +1879,7 @@ impl Proxy {
         Proxy {
             listeners: HashMap::new(),
             clusters: HashMap::new(),
+            maintenance: HashMap::new(),
             backends,
             pool,
             registry,
@@ -1448,6 +1925,7 @@ impl Proxy {
             .filter_map(|(_, listener)| {
                 let mut owned = listener.borrow_mut();
                 if let Some(listener) = owned.listener.take() {
+                    owned.active = false;
                     return Some((owned.address, listener));
                 }
 
@@ -1463,10 +1941,10 @@ impl Proxy {
             .and_then(|listener| {
                 let mut owned = listener.borrow_mut();
 
-                owned
-                    .listener
-                    .take()
-                    .map(|listener| (owned.token, listener))
+                let listener = owned.listener.take()?;
+                // the listener can be activated again, with another socket
+                owned.active = false;
+                Some((owned.token, listener))
             })
     }
 
@@ -1486,6 +1964,7 @@ impl Proxy {
 
     pub fn remove_cluster(&mut self, cluster_id: &str) {
         self.clusters.remove(cluster_id);
+        self.maintenance.remove(cluster_id);
 
         for listener in self.listeners.values() {
             listener
@@ -1495,6 +1974,17 @@ impl Proxy {
                 .remove_custom_answer(cluster_id);
         }
     }
+
+    pub fn set_cluster_maintenance(&mut self, maintenance: ClusterMaintenance) {
+        if maintenance.enabled {
+            let answer = maintenance
+                .answer
+                .map(|answer| Rc::new(answer.into_bytes()));
+            self.maintenance.insert(maintenance.cluster_id, answer);
+        } else {
+            self.maintenance.remove(&maintenance.cluster_id);
+        }
+    }
 }
 
 impl Listener {
@@ -1511,6 +2001,7 @@ impl Listener {
             token,
             active: false,
             tags: BTreeMap::new(),
+            acls: AccessLists::default(),
         }
     }
 
@@ -1549,6 +2040,16 @@ impl Listener {
 
     pub fn add_http_front(&mut self, http_front: HttpFrontend) -> Result<(), String> {
         //FIXME: proper error reporting
+        if let Route::Deny {
+            body_path: Some(body_path),
+            ..
+        } = &http_front.route
+        {
+            if !self.answers.borrow_mut().load_deny_body(body_path) {
+                return Err(format!("could not read the deny answer body {}", body_path));
+            }
+        }
+
         if self.fronts.add_http_front(http_front) {
             Ok(())
         } else {
@@ -1565,7 +2066,17 @@ impl Listener {
         Ok(())
     }
 
-    pub fn frontend_from_request(&self, host: &str, uri: &str, method: &Method) -> Option<Route> {
+    pub fn has_header_rules(&self) -> bool {
+        self.fronts.has_header_rules()
+    }
+
+    pub fn frontend_from_request(
+        &self,
+        host: &str,
+        uri: &str,
+        method: &Method,
+        headers: &[Header],
+    ) -> Option<RouteResult> {
         // redundant
         // already called once in extract_route
         let host: &str = if let Ok((i, (hostname, _))) = hostname_and_port(host.as_bytes()) {
@@ -1592,20 +2103,19 @@ impl Listener {
             return None;
         };
 
-        self.fronts.lookup(host.as_bytes(), uri.as_bytes(), method)
+        self.fronts
+            .lookup_frontend(host.as_bytes(), uri.as_bytes(), method, headers)
+            .or_else(|| {
+                self.config
+                    .fallback_cluster
+                    .as_deref()
+                    .map(RouteResult::for_cluster)
+            })
     }
 
     fn accept(&mut self) -> Result<TcpStream, AcceptError> {
         if let Some(ref sock) = self.listener {
-            sock.accept()
-                .map_err(|e| match e.kind() {
-                    ErrorKind::WouldBlock => AcceptError::WouldBlock,
-                    _ => {
-                        error!("accept() IO error: {:?}", e);
-                        AcceptError::IoError
-                    }
-                })
-                .map(|(sock, _)| sock)
+            self.acls.accept(sock)
         } else {
             error!("cannot accept connections, no listening socket available");
             Err(AcceptError::IoError)
@@ -1628,6 +2138,11 @@ impl ProxyConfiguration<Session> for Proxy {
                 self.remove_cluster(&cluster_id);
                 ProxyResponse::ok(message.id)
             }
+            ProxyRequestOrder::SetClusterMaintenance(maintenance) => {
+                debug!("{} set cluster maintenance {:?}", message.id, maintenance);
+                self.set_cluster_maintenance(maintenance);
+                ProxyResponse::ok(message.id)
+            }
             ProxyRequestOrder::AddHttpFrontend(front) => {
                 debug!("{} add front {:?}", message.id, front);
                 if let Some(listener) = self
@@ -1683,18 +2198,56 @@ impl ProxyConfiguration<Session> for Proxy {
                     )
                 }
             }
-            ProxyRequestOrder::RemoveListener(remove) => {
-                debug!("removing HTTP listener at address {:?}", remove.address);
-                if !self.remove_listener(remove.address) {
-                    ProxyResponse::error(
+            ProxyRequestOrder::AddAcl(acl) => {
+                debug!("{} add ACL {:?}", message.id, acl);
+                match self
+                    .listeners
+                    .values()
+                    .find(|l| l.borrow().address == acl.address)
+                {
+                    Some(listener) => {
+                        listener.borrow_mut().acls.add(acl);
+                        ProxyResponse::ok(message.id)
+                    }
+                    None => ProxyResponse::error(
                         message.id,
-                        format!("no HTTP listener to remove at address {:?}", remove.address),
-                    )
-                } else {
+                        format!("no HTTP listener found for ACL: {:?}", acl),
+                    ),
+                }
+            }
+            ProxyRequestOrder::RemoveAcl(remove) => {
+                debug!("{} remove ACL {:?}", message.id, remove);
+                match self
+                    .listeners
+                    .values()
+                    .find(|l| l.borrow().address == remove.address)
+                {
+                    Some(listener)
+                        if listener
+                            .borrow_mut()
+                            .acls
+                            .remove(remove.hostname.as_deref()) =>
+                    {
+                        ProxyResponse::ok(message.id)
+                    }
+                    _ => ProxyResponse::error(
+                        message.id,
+                        format!("no ACL to remove on HTTP listener: {:?}", remove),
+                    ),
+                }
+            }
+            ProxyRequestOrder::RemoveListener(remove) => {
+                debug!("removing HTTP listener at address {:?}", remove.address);
+                if !self.remove_listener(remove.address) {
+                    ProxyResponse::error(
+                        message.id,
+                        format!("no HTTP listener to remove at address {:?}", remove.address),
+                    )
+                } else {
                     ProxyResponse::ok(message.id)
                 }
             }
-            ProxyRequestOrder::SoftStop => {
+            ProxyRequestOrder::SoftStop(_) => {
                 info!("{} processing soft shutdown", message.id);
                 let listeners: HashMap<_, _> = self.listeners.drain().collect();
                 for (_, l) in listeners.iter() {
@@ -1805,9 +2358,12 @@ impl ProxyConfiguration<Session> for Proxy {
         );
 
         let session = Rc::new(RefCell::new(session));
-        session_entry.insert(session);
+        session_entry.insert(session.clone());
 
         session_manager.incr();
+        if session_manager.open_listener_session(owned.token, owned.config.max_sessions) {
+            session.borrow_mut().routing.counted_listener = Some(owned.token);
+        }
         Ok(())
     }
 }
@@ -1914,8 +2470,10 @@ mod tests {
     use super::*;
     use crate::sozu_command::channel::Channel;
     use crate::sozu_command::proxy::{
-        Backend, HttpFrontend, HttpListener, LoadBalancingAlgorithms, LoadBalancingParams,
-        PathRule, ProxyRequest, ProxyRequestOrder, Route, RulePosition,
+        Acl, AclMode, Backend, BackendProtocol, HostRewrite, HttpFrontend, HttpListener,
+        ListenerType, LoadBalancingAlgorithms, LoadBalancingParams, PathRewrite, PathRule,
+        ProxyRequest, ProxyRequestOrder, RequestRetries, Route, RulePosition, SecurityHeaders,
+        Timeouts, WebSocketDrain,
     };
     use std::io::{Read, Write};
     use std::net::SocketAddr;
@@ -1965,6 +2523,11 @@ mod tests {
             hostname: String::from("localhost"),
             path: PathRule::Prefix(String::from("/")),
             method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
             position: RulePosition::Tree,
             tags: None,
         };
@@ -1979,6 +2542,9 @@ mod tests {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: None,
             backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
         };
         command.write_message(&ProxyRequest {
             id: String::from("ID_EFGH"),
@@ -2049,6 +2615,11 @@ mod tests {
             hostname: String::from("localhost"),
             path: PathRule::Prefix(String::from("/")),
             method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
             position: RulePosition::Tree,
             tags: None,
         };
@@ -2063,6 +2634,9 @@ mod tests {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: None,
             backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
         };
         command.write_message(&ProxyRequest {
             id: String::from("ID_EFGH"),
@@ -2155,11 +2729,30 @@ mod tests {
         let cluster = Cluster {
             cluster_id: String::from("cluster_1"),
             sticky_session: false,
+            sticky_mode: StickyMode::Cookie,
             https_redirect: true,
             proxy_protocol: None,
             load_balancing: LoadBalancingAlgorithms::default(),
+            hash_key: HashKey::Url,
+            health_check: None,
+            outlier_detection: None,
+            request_queue: None,
+            affinity_table: None,
             load_metric: None,
             answer_503: None,
+            header_actions: Vec::new(),
+            host_rewrite: HostRewrite::Preserve,
+            request_limits: RequestLimits::default(),
+            compression: Compression::default(),
+            security_headers: SecurityHeaders::default(),
+            request_retries: RequestRetries::default(),
+            timeouts: Timeouts::default(),
+            backend_tls: None,
+            backend_protocol: BackendProtocol::Http1,
+            websocket_drain: WebSocketDrain::Close,
+            streaming: false,
+            upgrade_protocols: Vec::new(),
+            max_sessions: None,
         };
         command.write_message(&ProxyRequest {
             id: String::from("ID_ABCD"),
@@ -2171,6 +2764,11 @@ mod tests {
             hostname: String::from("localhost"),
             path: PathRule::Prefix(String::from("/")),
             method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
             position: RulePosition::Tree,
             tags: None,
         };
@@ -2185,6 +2783,9 @@ mod tests {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: None,
             backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
         };
         command.write_message(&ProxyRequest {
             id: String::from("ID_IJKL"),
@@ -2228,119 +2829,1539 @@ mod tests {
         assert_eq!(answer, expected_answer);
     }
 
-    use self::tiny_http::{Response, Server};
+    #[test]
+    fn request_limits() {
+        setup_test_logger!();
+        let address: SocketAddr =
+            FromStr::from_str("127.0.0.1:1051").expect("could not parse address");
+        let config = HttpListener {
+            address,
+            request_limits: RequestLimits {
+                max_header_count: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
 
-    fn start_server(port: u16, barrier: Arc<Barrier>) {
-        thread::spawn(move || {
+        let (mut command, channel) =
+            Channel::generate(1000, 10000).expect("should create a channel");
+        let _jg = thread::spawn(move || {
             setup_test_logger!();
-            let server =
-                Server::http(&format!("127.0.0.1:{}", port)).expect("could not create server");
-            info!("starting web server in port {}", port);
-            barrier.wait();
+            start(config, channel, 10, 16384).expect("could not start the http server");
+        });
 
-            for request in server.incoming_requests() {
-                info!(
-                    "backend web server got request -> method: {:?}, url: {:?}, headers: {:?}",
-                    request.method(),
-                    request.url(),
-                    request.headers()
-                );
+        let cluster = Cluster {
+            cluster_id: String::from("cluster_1"),
+            sticky_session: false,
+            sticky_mode: StickyMode::Cookie,
+            https_redirect: false,
+            proxy_protocol: None,
+            load_balancing: LoadBalancingAlgorithms::default(),
+            hash_key: HashKey::Url,
+            health_check: None,
+            outlier_detection: None,
+            request_queue: None,
+            affinity_table: None,
+            load_metric: None,
+            answer_503: None,
+            header_actions: Vec::new(),
+            host_rewrite: HostRewrite::Preserve,
+            request_limits: RequestLimits {
+                max_body_size: Some(10),
+                ..Default::default()
+            },
+            compression: Compression::default(),
+            security_headers: SecurityHeaders::default(),
+            request_retries: RequestRetries::default(),
+            timeouts: Timeouts::default(),
+            backend_tls: None,
+            backend_protocol: BackendProtocol::Http1,
+            websocket_drain: WebSocketDrain::Close,
+            streaming: false,
+            upgrade_protocols: Vec::new(),
+            max_sessions: None,
+        };
+        command.write_message(&ProxyRequest {
+            id: String::from("ID_ABCD"),
+            order: ProxyRequestOrder::AddCluster(cluster),
+        });
+        let front = HttpFrontend {
+            route: Route::ClusterId(String::from("cluster_1")),
+            address: "127.0.0.1:1051".parse().unwrap(),
+            hostname: String::from("localhost"),
+            path: PathRule::Prefix(String::from("/")),
+            method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
+            position: RulePosition::Tree,
+            tags: None,
+        };
+        command.write_message(&ProxyRequest {
+            id: String::from("ID_EFGH"),
+            order: ProxyRequestOrder::AddHttpFrontend(front),
+        });
 
-                let response = Response::from_string("hello world");
-                request.respond(response).unwrap();
-                info!("backend web server sent response");
-                barrier.wait();
-                info!("server session stopped");
+        println!("test received: {:?}", command.read_message());
+        println!("test received: {:?}", command.read_message());
+
+        let requests: [(&[u8], &str); 2] = [
+            (
+                &b"GET / HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\nConnection: Close\r\n\r\n"[..],
+                "HTTP/1.1 431 Request Header Fields Too Large\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+            ),
+            (
+                &b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 20\r\n\r\n01234567890123456789"[..],
+                "HTTP/1.1 413 Payload Too Large\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+            ),
+        ];
+        for (request, expected_answer) in requests {
+            let mut client =
+                TcpStream::connect(("127.0.0.1", 1051)).expect("could not parse address");
+            // 5 seconds of timeout
+            client.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+            let w = client.write(request);
+            println!("http client write: {:?}", w);
+
+            let mut buffer = [0; 4096];
+            let mut index = 0;
+            while index < expected_answer.len() {
+                match client.read(&mut buffer[index..]) {
+                    Err(e) => panic!("client request should not fail. Error: {:?}", e),
+                    Ok(0) => break,
+                    Ok(sz) => index += sz,
+                }
             }
 
-            println!("server on port {} closed", port);
-        });
+            let answer =
+                str::from_utf8(&buffer[..index]).expect("could not make string from buffer");
+            println!("Response: {}", answer);
+            assert_eq!(answer, expected_answer);
+        }
     }
 
     #[test]
-    fn frontend_from_request_test() {
-        let cluster_id1 = "cluster_1".to_owned();
-        let cluster_id2 = "cluster_2".to_owned();
-        let cluster_id3 = "cluster_3".to_owned();
-        let uri1 = "/".to_owned();
-        let uri2 = "/yolo".to_owned();
-        let uri3 = "/yolo/swag".to_owned();
+    fn compressed_response() {
+        setup_test_logger!();
+        let body = "hello world! ".repeat(200);
+        let backend_response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let backend = std::net::TcpListener::bind("127.0.0.1:1053").expect("could not bind");
+        thread::spawn(move || {
+            for mut stream in backend.incoming().flatten() {
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request);
+                stream.write_all(backend_response.as_bytes()).unwrap();
+            }
+        });
 
-        let mut fronts = Router::new();
-        fronts.add_http_front(HttpFrontend {
-            route: Route::ClusterId(cluster_id1),
-            address: "0.0.0.0:80".parse().unwrap(),
-            hostname: "lolcatho.st".to_owned(),
-            path: PathRule::Prefix(uri1),
-            method: None,
-            position: RulePosition::Tree,
-            tags: None,
+        let address: SocketAddr =
+            FromStr::from_str("127.0.0.1:1052").expect("could not parse address");
+        let config = HttpListener {
+            address,
+            compression: Compression {
+                gzip: Some(true),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let (mut command, channel) =
+            Channel::generate(1000, 10000).expect("should create a channel");
+        let _jg = thread::spawn(move || {
+            setup_test_logger!();
+            start(config, channel, 10, 16384).expect("could not start the http server");
         });
-        fronts.add_http_front(HttpFrontend {
-            route: Route::ClusterId(cluster_id2),
-            address: "0.0.0.0:80".parse().unwrap(),
-            hostname: "lolcatho.st".to_owned(),
-            path: PathRule::Prefix(uri2),
+
+        let front = HttpFrontend {
+            route: Route::ClusterId(String::from("cluster_1")),
+            address,
+            hostname: String::from("localhost"),
+            path: PathRule::Prefix(String::from("/")),
             method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
             position: RulePosition::Tree,
             tags: None,
+        };
+        command.write_message(&ProxyRequest {
+            id: String::from("ID_ABCD"),
+            order: ProxyRequestOrder::AddHttpFrontend(front),
         });
-        fronts.add_http_front(HttpFrontend {
-            route: Route::ClusterId(cluster_id3),
-            address: "0.0.0.0:80".parse().unwrap(),
-            hostname: "lolcatho.st".to_owned(),
-            path: PathRule::Prefix(uri3),
-            method: None,
-            position: RulePosition::Tree,
-            tags: None,
+        let backend = Backend {
+            cluster_id: String::from("cluster_1"),
+            backend_id: String::from("cluster_1-0"),
+            address: "127.0.0.1:1053".parse().unwrap(),
+            load_balancing_parameters: Some(LoadBalancingParams::default()),
+            sticky_id: None,
+            backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
+        };
+        command.write_message(&ProxyRequest {
+            id: String::from("ID_EFGH"),
+            order: ProxyRequestOrder::AddBackend(backend),
         });
-        fronts.add_http_front(HttpFrontend {
-            route: Route::ClusterId("cluster_1".to_owned()),
-            address: "0.0.0.0:80".parse().unwrap(),
-            hostname: "other.domain".to_owned(),
-            path: PathRule::Prefix("/test".to_owned()),
+
+        println!("test received: {:?}", command.read_message());
+        println!("test received: {:?}", command.read_message());
+
+        let mut client = TcpStream::connect(("127.0.0.1", 1052)).expect("could not parse address");
+        client.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+        client
+            .write_all(
+                &b"GET / HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\nConnection: Close\r\n\r\n"[..],
+            )
+            .unwrap();
+
+        let mut response = Vec::new();
+        let mut buffer = [0; 4096];
+        while !response.ends_with(b"0\r\n\r\n") {
+            match client.read(&mut buffer) {
+                Err(e) => panic!("client request should not fail. Error: {:?}", e),
+                Ok(0) => break,
+                Ok(sz) => response.extend_from_slice(&buffer[..sz]),
+            }
+        }
+
+        let headers_end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .expect("the response should have headers")
+            + 4;
+        let headers = str::from_utf8(&response[..headers_end]).unwrap();
+        println!("Response headers: {}", headers);
+        assert!(headers.contains("Content-Encoding: gzip\r\n"));
+        assert!(headers.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!headers.contains("Content-Length"));
+
+        let mut compressed = Vec::new();
+        let mut chunks = &response[headers_end..];
+        loop {
+            let line_end = chunks.windows(2).position(|w| w == b"\r\n").unwrap();
+            let size =
+                usize::from_str_radix(str::from_utf8(&chunks[..line_end]).unwrap(), 16).unwrap();
+            if size == 0 {
+                break;
+            }
+            compressed.extend_from_slice(&chunks[line_end + 2..line_end + 2 + size]);
+            chunks = &chunks[line_end + 2 + size + 2..];
+        }
+
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+    }
+
+    #[test]
+    fn retried_response() {
+        setup_test_logger!();
+        // the first backend is overloaded, the second one answers
+        for (port, backend_response) in [
+            (
+                1055,
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n",
+            ),
+            (1056, "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello"),
+        ] {
+            let backend = std::net::TcpListener::bind(("127.0.0.1", port)).expect("could not bind");
+            thread::spawn(move || {
+                for mut stream in backend.incoming().flatten() {
+                    let mut request = [0; 4096];
+                    let _ = stream.read(&mut request);
+                    stream.write_all(backend_response.as_bytes()).unwrap();
+                }
+            });
+        }
+
+        let address: SocketAddr =
+            FromStr::from_str("127.0.0.1:1054").expect("could not parse address");
+        let config = HttpListener {
+            address,
+            ..Default::default()
+        };
+
+        let (mut command, channel) =
+            Channel::generate(1000, 10000).expect("should create a channel");
+        let _jg = thread::spawn(move || {
+            setup_test_logger!();
+            start(config, channel, 10, 16384).expect("could not start the http server");
+        });
+
+        let cluster = Cluster {
+            cluster_id: String::from("cluster_1"),
+            sticky_session: false,
+            sticky_mode: StickyMode::Cookie,
+            https_redirect: false,
+            proxy_protocol: None,
+            load_balancing: LoadBalancingAlgorithms::default(),
+            hash_key: HashKey::Url,
+            health_check: None,
+            outlier_detection: None,
+            request_queue: None,
+            affinity_table: None,
+            load_metric: None,
+            answer_503: None,
+            header_actions: Vec::new(),
+            host_rewrite: HostRewrite::Preserve,
+            request_limits: RequestLimits::default(),
+            compression: Compression::default(),
+            security_headers: SecurityHeaders::default(),
+            request_retries: RequestRetries {
+                max_attempts: Some(2),
+                retry_on: vec![RetryCondition::Http503],
+            },
+            timeouts: Timeouts::default(),
+            backend_tls: None,
+            backend_protocol: BackendProtocol::Http1,
+            websocket_drain: WebSocketDrain::Close,
+            streaming: false,
+            upgrade_protocols: Vec::new(),
+            max_sessions: None,
+        };
+        command.write_message(&ProxyRequest {
+            id: String::from("ID_ABCD"),
+            order: ProxyRequestOrder::AddCluster(cluster),
+        });
+        let front = HttpFrontend {
+            route: Route::ClusterId(String::from("cluster_1")),
+            address,
+            hostname: String::from("localhost"),
+            path: PathRule::Prefix(String::from("/")),
             method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
             position: RulePosition::Tree,
             tags: None,
+        };
+        command.write_message(&ProxyRequest {
+            id: String::from("ID_EFGH"),
+            order: ProxyRequestOrder::AddHttpFrontend(front),
+        });
+        for (index, port) in [1055, 1056].iter().enumerate() {
+            let backend = Backend {
+                cluster_id: String::from("cluster_1"),
+                backend_id: format!("cluster_1-{}", index),
+                address: format!("127.0.0.1:{}", port).parse().unwrap(),
+                load_balancing_parameters: Some(LoadBalancingParams::default()),
+                sticky_id: None,
+                backup: None,
+                max_connections: None,
+                timeouts: Timeouts::default(),
+                tls: None,
+            };
+            command.write_message(&ProxyRequest {
+                id: format!("ID_BACKEND_{}", index),
+                order: ProxyRequestOrder::AddBackend(backend),
+            });
+        }
+
+        for _ in 0..4 {
+            println!("test received: {:?}", command.read_message());
+        }
+
+        // whichever backend is chosen first, the client gets the answer of the second one
+        for _ in 0..4 {
+            let mut client =
+                TcpStream::connect(("127.0.0.1", 1054)).expect("could not parse address");
+            client.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+            client
+                .write_all(&b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Close\r\n\r\n"[..])
+                .unwrap();
+
+            let mut response = String::new();
+            client
+                .read_to_string(&mut response)
+                .expect("client request should not fail");
+            println!("Response: {}", response);
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(response.ends_with("hello"));
+        }
+    }
+
+    #[test]
+    fn streamed_bodies() {
+        setup_test_logger!();
+        // answers with the size of the request body, in several chunks
+        let backend = std::net::TcpListener::bind("127.0.0.1:1058").expect("could not bind");
+        thread::spawn(move || {
+            for mut stream in backend.incoming().flatten() {
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+                let headers_end = loop {
+                    if let Some(position) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break position + 4;
+                    }
+                    match stream.read(&mut buffer) {
+                        Ok(0) | Err(_) => return,
+                        Ok(sz) => request.extend_from_slice(&buffer[..sz]),
+                    }
+                };
+                let content_length = str::from_utf8(&request[..headers_end])
+                    .unwrap()
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .map(|length| length.parse::<usize>().unwrap())
+                    .unwrap_or(0);
+                while request.len() < headers_end + content_length {
+                    match stream.read(&mut buffer) {
+                        Ok(0) | Err(_) => return,
+                        Ok(sz) => request.extend_from_slice(&buffer[..sz]),
+                    }
+                }
+
+                let size = content_length.to_string();
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n")
+                    .unwrap();
+                stream.write_all(b"6\r\nbody: \r\n").unwrap();
+                thread::sleep(Duration::from_millis(50));
+                stream
+                    .write_all(format!("{:x}\r\n{}\r\n0\r\n\r\n", size.len(), size).as_bytes())
+                    .unwrap();
+            }
         });
 
         let address: SocketAddr =
-            FromStr::from_str("127.0.0.1:1030").expect("could not parse address");
-        let listener = Listener {
-            listener: None,
+            FromStr::from_str("127.0.0.1:1057").expect("could not parse address");
+        let config = HttpListener {
             address,
-            fronts,
-            answers: Rc::new(RefCell::new(HttpAnswers::new(
-                "HTTP/1.1 404 Not Found\r\n\r\n",
-                "HTTP/1.1 503 Service Unavailable\r\n\r\n",
-            ))),
-            config: Default::default(),
-            token: Token(0),
-            active: true,
-            tags: BTreeMap::new(),
+            request_limits: RequestLimits {
+                max_buffered_body: Some(1000),
+                ..Default::default()
+            },
+            ..Default::default()
         };
 
-        let frontend1 = listener.frontend_from_request("lolcatho.st", "/", &Method::Get);
-        let frontend2 = listener.frontend_from_request("lolcatho.st", "/test", &Method::Get);
-        let frontend3 = listener.frontend_from_request("lolcatho.st", "/yolo/test", &Method::Get);
-        let frontend4 = listener.frontend_from_request("lolcatho.st", "/yolo/swag", &Method::Get);
-        let frontend5 = listener.frontend_from_request("domain", "/", &Method::Get);
-        assert_eq!(
-            frontend1.expect("should find frontend"),
-            Route::ClusterId("cluster_1".to_string())
-        );
-        assert_eq!(
-            frontend2.expect("should find frontend"),
-            Route::ClusterId("cluster_1".to_string())
-        );
-        assert_eq!(
-            frontend3.expect("should find frontend"),
-            Route::ClusterId("cluster_2".to_string())
-        );
-        assert_eq!(
-            frontend4.expect("should find frontend"),
-            Route::ClusterId("cluster_3".to_string())
-        );
-        assert_eq!(frontend5, None);
+        let (mut command, channel) =
+            Channel::generate(1000, 10000).expect("should create a channel");
+        let _jg = thread::spawn(move || {
+            setup_test_logger!();
+            start(config, channel, 10, 16384).expect("could not start the http server");
+        });
+
+        let front = HttpFrontend {
+            route: Route::ClusterId(String::from("cluster_1")),
+            address,
+            hostname: String::from("localhost"),
+            path: PathRule::Prefix(String::from("/")),
+            method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
+            position: RulePosition::Tree,
+            tags: None,
+        };
+        command.write_message(&ProxyRequest {
+            id: String::from("ID_ABCD"),
+            order: ProxyRequestOrder::AddHttpFrontend(front),
+        });
+        let backend = Backend {
+            cluster_id: String::from("cluster_1"),
+            backend_id: String::from("cluster_1-0"),
+            address: "127.0.0.1:1058".parse().unwrap(),
+            load_balancing_parameters: Some(LoadBalancingParams::default()),
+            sticky_id: None,
+            backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
+        };
+        command.write_message(&ProxyRequest {
+            id: String::from("ID_EFGH"),
+            order: ProxyRequestOrder::AddBackend(backend),
+        });
+
+        println!("test received: {:?}", command.read_message());
+        println!("test received: {:?}", command.read_message());
+
+        // an upload larger than the buffers goes through
+        let body = vec![b'a'; 100000];
+        let mut client = TcpStream::connect(("127.0.0.1", 1057)).expect("could not parse address");
+        client.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+        client
+            .write_all(
+                format!(
+                    "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: Close\r\n\r\n",
+                    body.len()
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        client.write_all(&body).unwrap();
+
+        let mut response = String::new();
+        client
+            .read_to_string(&mut response)
+            .expect("client request should not fail");
+        println!("Response: {}", response);
+        assert!(response.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(response.ends_with("6\r\nbody: \r\n6\r\n100000\r\n0\r\n\r\n"));
+
+        // an HTTP/1.0 client gets the response without its chunks
+        let mut client = TcpStream::connect(("127.0.0.1", 1057)).expect("could not parse address");
+        client.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+        client
+            .write_all(&b"GET / HTTP/1.0\r\nHost: localhost\r\nConnection: keep-alive\r\n\r\n"[..])
+            .unwrap();
+
+        let mut response = String::new();
+        client
+            .read_to_string(&mut response)
+            .expect("client request should not fail");
+        println!("Response: {}", response);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(!response.contains("Transfer-Encoding"));
+        assert!(response.ends_with("\r\n\r\nbody: 0"));
+    }
+
+    #[test]
+    fn converted_bodies() {
+        setup_test_logger!();
+        // refuses the chunked requests, and answers until the end of the connection
+        let backend = std::net::TcpListener::bind("127.0.0.1:1063").expect("could not bind");
+        thread::spawn(move || {
+            for mut stream in backend.incoming().flatten() {
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+                let headers_end = loop {
+                    if let Some(position) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break position + 4;
+                    }
+                    match stream.read(&mut buffer) {
+                        Ok(0) | Err(_) => return,
+                        Ok(sz) => request.extend_from_slice(&buffer[..sz]),
+                    }
+                };
+                let headers = str::from_utf8(&request[..headers_end]).unwrap().to_owned();
+                if headers.contains("Transfer-Encoding") {
+                    stream
+                        .write_all(b"HTTP/1.1 411 Length Required\r\nContent-Length: 0\r\n\r\n")
+                        .unwrap();
+                    continue;
+                }
+                let content_length = headers
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .map(|length| length.parse::<usize>().unwrap())
+                    .unwrap_or(0);
+                while request.len() < headers_end + content_length {
+                    match stream.read(&mut buffer) {
+                        Ok(0) | Err(_) => return,
+                        Ok(sz) => request.extend_from_slice(&buffer[..sz]),
+                    }
+                }
+
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nreceived ")
+                    .unwrap();
+                thread::sleep(Duration::from_millis(50));
+                stream.write_all(&request[headers_end..]).unwrap();
+            }
+        });
+
+        let address: SocketAddr =
+            FromStr::from_str("127.0.0.1:1062").expect("could not parse address");
+        let config = HttpListener {
+            address,
+            request_limits: RequestLimits {
+                max_buffered_body: Some(1000),
+                buffer_chunked_body: Some(true),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let (mut command, channel) =
+            Channel::generate(1000, 10000).expect("should create a channel");
+        let _jg = thread::spawn(move || {
+            setup_test_logger!();
+            start(config, channel, 10, 16384).expect("could not start the http server");
+        });
+
+        let front = HttpFrontend {
+            route: Route::ClusterId(String::from("cluster_1")),
+            address,
+            hostname: String::from("localhost"),
+            path: PathRule::Prefix(String::from("/")),
+            method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
+            position: RulePosition::Tree,
+            tags: None,
+        };
+        command.write_message(&ProxyRequest {
+            id: String::from("ID_ABCD"),
+            order: ProxyRequestOrder::AddHttpFrontend(front),
+        });
+        let backend = Backend {
+            cluster_id: String::from("cluster_1"),
+            backend_id: String::from("cluster_1-0"),
+            address: "127.0.0.1:1063".parse().unwrap(),
+            load_balancing_parameters: Some(LoadBalancingParams::default()),
+            sticky_id: None,
+            backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
+        };
+        command.write_message(&ProxyRequest {
+            id: String::from("ID_EFGH"),
+            order: ProxyRequestOrder::AddBackend(backend),
+        });
+
+        println!("test received: {:?}", command.read_message());
+        println!("test received: {:?}", command.read_message());
+
+        let read_response = |client: &mut TcpStream| {
+            let mut response = Vec::new();
+            let mut buffer = [0; 4096];
+            while !response.ends_with(b"0\r\n\r\n") {
+                match client.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(sz) => response.extend_from_slice(&buffer[..sz]),
+                }
+            }
+            String::from_utf8(response).unwrap()
+        };
+
+        // the chunked request gets a length, the response gets chunks and
+        // the client connection stays open
+        let mut client = TcpStream::connect(("127.0.0.1", 1062)).expect("could not parse address");
+        client.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+        client
+            .write_all(b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n")
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+        client.write_all(b"6\r\n world\r\n0\r\n\r\n").unwrap();
+
+        let response = read_response(&mut client);
+        println!("Response: {}", response);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!response.contains("Connection: close"));
+        assert!(response.ends_with("\r\n\r\n9\r\nreceived \r\nb\r\nhello world\r\n0\r\n\r\n"));
+
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let response = read_response(&mut client);
+        println!("Response: {}", response);
+        assert!(response.ends_with("\r\n\r\n9\r\nreceived \r\n0\r\n\r\n"));
+
+        // a chunked body larger than the buffered body limit cannot get a length
+        let mut client = TcpStream::connect(("127.0.0.1", 1062)).expect("could not parse address");
+        client.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+        client
+            .write_all(
+                b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n800\r\n",
+            )
+            .unwrap();
+        let _ = client.write_all(&[b'a'; 0x800]);
+
+        let mut response = [0; 12];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(&response, b"HTTP/1.1 413");
+    }
+
+    #[test]
+    fn hostname_acls() {
+        setup_test_logger!();
+        let address: SocketAddr =
+            FromStr::from_str("127.0.0.1:1061").expect("could not parse address");
+        let config = HttpListener {
+            address,
+            ..Default::default()
+        };
+
+        let (mut command, channel) =
+            Channel::generate(1000, 10000).expect("should create a channel");
+        let _jg = thread::spawn(move || {
+            setup_test_logger!();
+            start(config, channel, 10, 16384).expect("could not start the http server");
+        });
+
+        let front = HttpFrontend {
+            route: Route::ClusterId(String::from("cluster_1")),
+            address,
+            hostname: String::from("admin.example.com"),
+            path: PathRule::Prefix(String::from("/")),
+            method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
+            position: RulePosition::Tree,
+            tags: None,
+        };
+        command.write_message(&ProxyRequest {
+            id: String::from("ID_ABCD"),
+            order: ProxyRequestOrder::AddHttpFrontend(front),
+        });
+        command.write_message(&ProxyRequest {
+            id: String::from("ID_EFGH"),
+            order: ProxyRequestOrder::AddAcl(Acl {
+                address,
+                proxy: ListenerType::HTTP,
+                hostname: Some(String::from("admin.example.com")),
+                mode: AclMode::Deny,
+                ranges: vec!["127.0.0.0/8".parse().unwrap()],
+            }),
+        });
+
+        println!("test received: {:?}", command.read_message());
+        println!("test received: {:?}", command.read_message());
+
+        // the port and the case of the Host header do not get around the ACL
+        for (host, status) in [
+            ("admin.example.com", "403"),
+            ("admin.example.com:8080", "403"),
+            ("ADMIN.example.com", "403"),
+            ("Admin.Example.com:80", "403"),
+            ("www.example.com", "404"),
+        ] {
+            let mut client =
+                TcpStream::connect(("127.0.0.1", 1061)).expect("could not parse address");
+            client.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+            client
+                .write_all(
+                    format!(
+                        "GET / HTTP/1.1\r\nHost: {}\r\nConnection: Close\r\n\r\n",
+                        host
+                    )
+                    .as_bytes(),
+                )
+                .unwrap();
+
+            let mut response = String::new();
+            let _ = client.read_to_string(&mut response);
+            println!("Response for {}: {}", host, response);
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {} ", status)),
+                "Host: {}",
+                host
+            );
+        }
+    }
+
+    #[test]
+    fn rewritten_paths() {
+        setup_test_logger!();
+        // answers with the request line it received
+        let backend = std::net::TcpListener::bind("127.0.0.1:1065").expect("could not bind");
+        thread::spawn(move || {
+            for mut stream in backend.incoming().flatten() {
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+                loop {
+                    while let Some(position) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        let head = String::from_utf8_lossy(&request[..position]).to_string();
+                        let line = head.lines().next().unwrap_or_default().to_string();
+                        request.drain(..position + 4);
+                        let answer = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                            line.len(),
+                            line
+                        );
+                        stream.write_all(answer.as_bytes()).unwrap();
+                    }
+                    match stream.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(sz) => request.extend_from_slice(&buffer[..sz]),
+                    }
+                }
+            }
+        });
+
+        let address: SocketAddr =
+            FromStr::from_str("127.0.0.1:1064").expect("could not parse address");
+        let config = HttpListener {
+            address,
+            ..Default::default()
+        };
+
+        let (mut command, channel) =
+            Channel::generate(1000, 10000).expect("should create a channel");
+        let _jg = thread::spawn(move || {
+            setup_test_logger!();
+            start(config, channel, 10, 16384).expect("could not start the http server");
+        });
+
+        let front = HttpFrontend {
+            route: Route::ClusterId(String::from("cluster_1")),
+            address,
+            hostname: String::from("localhost"),
+            path: PathRule::Prefix(String::from("/api")),
+            method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: Some(PathRewrite::StripPrefix),
+            mirror_cluster_id: None,
+            position: RulePosition::Tree,
+            tags: None,
+        };
+        command.write_message(&ProxyRequest {
+            id: String::from("ID_ABCD"),
+            order: ProxyRequestOrder::AddHttpFrontend(front),
+        });
+        let backend = Backend {
+            cluster_id: String::from("cluster_1"),
+            backend_id: String::from("cluster_1-0"),
+            address: "127.0.0.1:1065".parse().unwrap(),
+            load_balancing_parameters: Some(LoadBalancingParams::default()),
+            sticky_id: None,
+            backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
+        };
+        command.write_message(&ProxyRequest {
+            id: String::from("ID_EFGH"),
+            order: ProxyRequestOrder::AddBackend(backend),
+        });
+
+        println!("test received: {:?}", command.read_message());
+        println!("test received: {:?}", command.read_message());
+
+        let mut responses = Vec::new();
+        for request in [
+            &b"GET /api/users HTTP/1.1\r\nHost: localhost\r\n\r\nGET /api/items HTTP/1.1\r\nHost: localhost\r\nConnection: Close\r\n\r\n"[..],
+            &b"get /api/users HTTP/1.1\r\nHost: localhost\r\nConnection: Close\r\n\r\n"[..],
+        ] {
+            let mut client = TcpStream::connect(("127.0.0.1", 1064)).expect("could not parse address");
+            client.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+            client.write_all(request).unwrap();
+            let mut response = String::new();
+            let _ = client.read_to_string(&mut response);
+            println!("Response: {}", response);
+            responses.push(response);
+        }
+        assert!(responses[0].contains("\r\n\r\nGET /users HTTP/1.1HTTP/1.1 200 OK\r\n"));
+        assert!(responses[0].ends_with("\r\n\r\nGET /items HTTP/1.1"));
+        assert!(responses[1].ends_with("\r\n\r\nget /users HTTP/1.1"));
+    }
+
+    #[test]
+    fn unrewritable_path() {
+        setup_test_logger!();
+        let address: SocketAddr =
+            FromStr::from_str("127.0.0.1:1066").expect("could not parse address");
+        let front_listener = std::net::TcpListener::bind(address).expect("could not bind");
+        let mut client = TcpStream::connect(address).expect("could not connect");
+        let (front, _) = front_listener.accept().expect("could not accept");
+        front.set_nonblocking(true).unwrap();
+        client
+            .write_all(&b"GET /api/users HTTP/1.1\r\nHost: localhost\r\n\r\n"[..])
+            .unwrap();
+
+        let pool = Rc::new(RefCell::new(Pool::with_capacity(1, 2, 16384)));
+        let listener = Rc::new(RefCell::new(Listener::new(
+            HttpListener {
+                address,
+                ..Default::default()
+            },
+            Token(0),
+        )));
+        let answers = listener.borrow().answers.clone();
+        let mut http = Http::new(
+            mio::net::TcpStream::from_std(front),
+            Token(1),
+            Ulid::generate(),
+            Rc::downgrade(&pool),
+            address,
+            None,
+            String::from("SOZUBALANCEID"),
+            Protocol::HTTP,
+            answers,
+            TimeoutContainer::new_empty(time::Duration::seconds(10)),
+            time::Duration::seconds(10),
+            time::Duration::seconds(10),
+            listener,
+        );
+        http.front_readiness.event = Ready::readable();
+        let mut metrics = SessionMetrics::new(None);
+        http.readable(&mut metrics);
+        assert!(http.get_request_line().is_some());
+
+        assert!(http.set_request_path("/users"));
+        // the request line was already replaced, the request is not forwarded
+        assert!(!http.set_request_path("/items"));
+        assert!(matches!(
+            http.status,
+            crate::protocol::http::SessionStatus::DefaultAnswer(
+                DefaultAnswerStatus::Answer502,
+                _,
+                _
+            )
+        ));
+    }
+
+    #[test]
+    fn timeouts_override() {
+        struct TimeoutSession {
+            proxy: Rc<RefCell<Proxy>>,
+            cluster_id: Option<String>,
+            backend: Option<Rc<RefCell<crate::Backend>>>,
+            metrics: SessionMetrics,
+            routing: RequestRouting,
+        }
+
+        impl HttpSession for TimeoutSession {
+            type Front = mio::net::TcpStream;
+            type Listener = Listener;
+            type Proxy = Proxy;
+
+            fn proxy(&self) -> &Rc<RefCell<Proxy>> {
+                &self.proxy
+            }
+
+            fn http(&self) -> Option<&Http<mio::net::TcpStream, Listener>> {
+                None
+            }
+
+            fn http_mut(&mut self) -> Option<&mut Http<mio::net::TcpStream, Listener>> {
+                None
+            }
+
+            fn frontend_token(&self) -> Token {
+                Token(1)
+            }
+
+            fn cluster_id(&self) -> Option<&str> {
+                self.cluster_id.as_deref()
+            }
+
+            fn backend(&self) -> Option<&Rc<RefCell<crate::Backend>>> {
+                self.backend.as_ref()
+            }
+
+            fn metrics(&self) -> &SessionMetrics {
+                &self.metrics
+            }
+
+            fn routing(&self) -> &RequestRouting {
+                &self.routing
+            }
+
+            fn routing_mut(&mut self) -> &mut RequestRouting {
+                &mut self.routing
+            }
+
+            fn frontend_timeout_duration(&self) -> time::Duration {
+                time::Duration::seconds(60)
+            }
+
+            fn backend_timeout_duration(&self) -> time::Duration {
+                time::Duration::seconds(30)
+            }
+
+            fn log_context(&self) -> String {
+                String::new()
+            }
+
+            fn close_backend(&mut self) {}
+
+            fn connect_to_backend(
+                &mut self,
+                _session_rc: Rc<RefCell<dyn ProxySession>>,
+            ) -> Result<BackendConnectAction, ConnectionError> {
+                Err(ConnectionError::NoBackendAvailable)
+            }
+        }
+
+        let poll = Poll::new().expect("could not create event loop");
+        let mut proxy = Proxy::new(
+            poll.registry().try_clone().unwrap(),
+            SessionManager::new(Slab::with_capacity(1), 1),
+            Rc::new(RefCell::new(Pool::with_capacity(1, 2, 16384))),
+            Rc::new(RefCell::new(BackendMap::new())),
+        );
+        proxy.clusters.insert(
+            String::from("cluster_1"),
+            Cluster {
+                cluster_id: String::from("cluster_1"),
+                timeouts: Timeouts {
+                    back_timeout: Some(5),
+                    websocket_timeout: Some(600),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+
+        let mut session = TimeoutSession {
+            proxy: Rc::new(RefCell::new(proxy)),
+            cluster_id: None,
+            backend: None,
+            metrics: SessionMetrics::new(None),
+            routing: RequestRouting::default(),
+        };
+        // before routing, the timeouts of the listener apply
+        assert_eq!(session.back_timeout_duration(), time::Duration::seconds(30));
+        assert_eq!(session.websocket_timeout_duration(), None);
+
+        session.cluster_id = Some(String::from("cluster_1"));
+        assert_eq!(session.back_timeout_duration(), time::Duration::seconds(5));
+        assert_eq!(
+            session.front_timeout_duration(),
+            time::Duration::seconds(60)
+        );
+        assert_eq!(
+            session.websocket_timeout_duration(),
+            Some(time::Duration::seconds(600))
+        );
+
+        let mut backend = crate::Backend::new(
+            "cluster_1-0",
+            "127.0.0.1:1067".parse().unwrap(),
+            None,
+            None,
+            None,
+        );
+        backend.timeouts = Timeouts {
+            front_timeout: Some(120),
+            back_timeout: Some(2),
+            ..Default::default()
+        };
+        session.backend = Some(Rc::new(RefCell::new(backend)));
+        assert_eq!(session.back_timeout_duration(), time::Duration::seconds(2));
+        assert_eq!(
+            session.front_timeout_duration(),
+            time::Duration::seconds(120)
+        );
+        // the backend does not set it, the one of the cluster is kept
+        assert_eq!(
+            session.websocket_timeout_duration(),
+            Some(time::Duration::seconds(600))
+        );
+    }
+
+    #[test]
+    fn fallback_cluster() {
+        setup_test_logger!();
+        let backend = std::net::TcpListener::bind("127.0.0.1:1069").expect("could not bind");
+        thread::spawn(move || {
+            for mut stream in backend.incoming().flatten() {
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(sz) => request.extend_from_slice(&buffer[..sz]),
+                    }
+                }
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\nfallback");
+            }
+        });
+
+        let address: SocketAddr =
+            FromStr::from_str("127.0.0.1:1068").expect("could not parse address");
+        let config = HttpListener {
+            address,
+            fallback_cluster: Some(String::from("fallback")),
+            ..Default::default()
+        };
+
+        let (mut command, channel) =
+            Channel::generate(1000, 10000).expect("should create a channel");
+        let _jg = thread::spawn(move || {
+            setup_test_logger!();
+            start(config, channel, 10, 16384).expect("could not start the http server");
+        });
+
+        let front = HttpFrontend {
+            route: Route::ClusterId(String::from("cluster_1")),
+            address,
+            hostname: String::from("localhost"),
+            path: PathRule::Prefix(String::from("/api")),
+            method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
+            position: RulePosition::Tree,
+            tags: None,
+        };
+        command.write_message(&ProxyRequest {
+            id: String::from("ID_ABCD"),
+            order: ProxyRequestOrder::AddHttpFrontend(front),
+        });
+        let backend = Backend {
+            cluster_id: String::from("fallback"),
+            backend_id: String::from("fallback-0"),
+            address: "127.0.0.1:1069".parse().unwrap(),
+            load_balancing_parameters: Some(LoadBalancingParams::default()),
+            sticky_id: None,
+            backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
+        };
+        command.write_message(&ProxyRequest {
+            id: String::from("ID_EFGH"),
+            order: ProxyRequestOrder::AddBackend(backend),
+        });
+
+        println!("test received: {:?}", command.read_message());
+        println!("test received: {:?}", command.read_message());
+
+        // neither the hostname nor the path of these requests have a frontend
+        for request in [
+            &b"GET / HTTP/1.1\r\nHost: unknown.example.com\r\nConnection: Close\r\n\r\n"[..],
+            &b"GET /other HTTP/1.1\r\nHost: localhost\r\nConnection: Close\r\n\r\n"[..],
+        ] {
+            let mut client =
+                TcpStream::connect(("127.0.0.1", 1068)).expect("could not parse address");
+            client.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+            client.write_all(request).unwrap();
+            let mut response = String::new();
+            let _ = client.read_to_string(&mut response);
+            println!("Response: {}", response);
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(response.ends_with("\r\n\r\nfallback"));
+        }
+    }
+
+    #[test]
+    fn streaming_timeouts() {
+        setup_test_logger!();
+        // sends a chunk every 400ms for 2.4s, then nothing for 3s before the last one
+        let backend = std::net::TcpListener::bind("127.0.0.1:1071").expect("could not bind");
+        thread::spawn(move || {
+            for mut stream in backend.incoming().flatten() {
+                thread::spawn(move || {
+                    let mut request = Vec::new();
+                    let mut buffer = [0; 4096];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buffer) {
+                            Ok(0) | Err(_) => return,
+                            Ok(sz) => request.extend_from_slice(&buffer[..sz]),
+                        }
+                    }
+                    let _ =
+                        stream.write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n");
+                    for _ in 0..6 {
+                        thread::sleep(Duration::from_millis(400));
+                        if stream.write_all(b"5\r\ntick\n\r\n").is_err() {
+                            return;
+                        }
+                    }
+                    thread::sleep(Duration::from_millis(3000));
+                    let _ = stream.write_all(b"5\r\nlast\n\r\n0\r\n\r\n");
+                });
+            }
+        });
+
+        let address: SocketAddr =
+            FromStr::from_str("127.0.0.1:1070").expect("could not parse address");
+        let config = HttpListener {
+            address,
+            ..Default::default()
+        };
+
+        let (mut command, channel) =
+            Channel::generate(1000, 10000).expect("should create a channel");
+        let _jg = thread::spawn(move || {
+            setup_test_logger!();
+            start(config, channel, 10, 16384).expect("could not start the http server");
+        });
+
+        for (cluster_id, streaming) in [("streaming", true), ("plain", false)] {
+            command.write_message(&ProxyRequest {
+                id: format!("ID_CLUSTER_{}", cluster_id),
+                order: ProxyRequestOrder::AddCluster(Cluster {
+                    cluster_id: String::from(cluster_id),
+                    timeouts: Timeouts {
+                        front_timeout: Some(1),
+                        back_timeout: Some(1),
+                        ..Default::default()
+                    },
+                    streaming,
+                    ..Default::default()
+                }),
+            });
+            command.write_message(&ProxyRequest {
+                id: format!("ID_FRONT_{}", cluster_id),
+                order: ProxyRequestOrder::AddHttpFrontend(HttpFrontend {
+                    route: Route::ClusterId(String::from(cluster_id)),
+                    address,
+                    hostname: String::from("localhost"),
+                    path: PathRule::Prefix(format!("/{}", cluster_id)),
+                    method: None,
+                    methods: Vec::new(),
+                    reject_other_methods: false,
+                    headers: Vec::new(),
+                    rewrite_path: None,
+                    mirror_cluster_id: None,
+                    position: RulePosition::Tree,
+                    tags: None,
+                }),
+            });
+            command.write_message(&ProxyRequest {
+                id: format!("ID_BACKEND_{}", cluster_id),
+                order: ProxyRequestOrder::AddBackend(Backend {
+                    cluster_id: String::from(cluster_id),
+                    backend_id: format!("{}-0", cluster_id),
+                    address: "127.0.0.1:1071".parse().unwrap(),
+                    load_balancing_parameters: Some(LoadBalancingParams::default()),
+                    sticky_id: None,
+                    backup: None,
+                    max_connections: None,
+                    timeouts: Timeouts::default(),
+                    tls: None,
+                }),
+            });
+        }
+        while let Some(response) = command.read_message() {
+            println!("test received: {:?}", response);
+            if response.id == "ID_BACKEND_plain" {
+                break;
+            }
+        }
+
+        let get = |path: &str| {
+            let mut client =
+                TcpStream::connect(("127.0.0.1", 1070)).expect("could not parse address");
+            client.set_read_timeout(Some(Duration::new(10, 0))).unwrap();
+            client
+                .write_all(
+                    format!(
+                        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: Close\r\n\r\n",
+                        path
+                    )
+                    .as_bytes(),
+                )
+                .unwrap();
+            let mut response = String::new();
+            let _ = client.read_to_string(&mut response);
+            println!("Response: {}", response);
+            response
+        };
+
+        // the client sends nothing and the backend pauses longer than the
+        // timeouts, the streamed response still reaches its end
+        let response = get("/streaming");
+        assert_eq!(response.matches("tick").count(), 6);
+        assert!(response.ends_with("5\r\nlast\n\r\n0\r\n\r\n"));
+
+        // without streaming, the back timeout closes the session
+        let response = get("/plain");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!response.contains("last"));
+    }
+
+    #[test]
+    fn protocol_upgrades() {
+        setup_test_logger!();
+        // switches to h2c if it is the only protocol offered, then echoes
+        let backend = std::net::TcpListener::bind("127.0.0.1:1060").expect("could not bind");
+        thread::spawn(move || {
+            for mut stream in backend.incoming().flatten() {
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buffer) {
+                        Ok(0) | Err(_) => return,
+                        Ok(sz) => request.extend_from_slice(&buffer[..sz]),
+                    }
+                }
+                let request = String::from_utf8(request).unwrap();
+                if !request.contains("\r\nUpgrade: h2c\r\n") || request.contains("foo") {
+                    stream
+                        .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+                        .unwrap();
+                    continue;
+                }
+
+                stream
+                    .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: h2c\r\nConnection: Upgrade\r\n\r\n")
+                    .unwrap();
+                while let Ok(sz) = stream.read(&mut buffer) {
+                    if sz == 0 || stream.write_all(&buffer[..sz]).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        let address: SocketAddr =
+            FromStr::from_str("127.0.0.1:1059").expect("could not parse address");
+        let config = HttpListener {
+            address,
+            ..Default::default()
+        };
+
+        let (mut command, channel) =
+            Channel::generate(1000, 10000).expect("should create a channel");
+        let _jg = thread::spawn(move || {
+            setup_test_logger!();
+            start(config, channel, 10, 16384).expect("could not start the http server");
+        });
+
+        let cluster = Cluster {
+            cluster_id: String::from("cluster_1"),
+            sticky_session: false,
+            sticky_mode: StickyMode::Cookie,
+            https_redirect: false,
+            proxy_protocol: None,
+            load_balancing: LoadBalancingAlgorithms::default(),
+            hash_key: HashKey::Url,
+            health_check: None,
+            outlier_detection: None,
+            request_queue: None,
+            affinity_table: None,
+            load_metric: None,
+            answer_503: None,
+            header_actions: Vec::new(),
+            host_rewrite: HostRewrite::Preserve,
+            request_limits: RequestLimits::default(),
+            compression: Compression::default(),
+            security_headers: SecurityHeaders::default(),
+            request_retries: RequestRetries::default(),
+            timeouts: Timeouts::default(),
+            backend_tls: None,
+            backend_protocol: BackendProtocol::Http1,
+            websocket_drain: WebSocketDrain::Close,
+            streaming: false,
+            upgrade_protocols: vec![String::from("h2c")],
+            max_sessions: None,
+        };
+        command.write_message(&ProxyRequest {
+            id: String::from("ID_ABCD"),
+            order: ProxyRequestOrder::AddCluster(cluster),
+        });
+        let front = HttpFrontend {
+            route: Route::ClusterId(String::from("cluster_1")),
+            address,
+            hostname: String::from("localhost"),
+            path: PathRule::Prefix(String::from("/")),
+            method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
+            position: RulePosition::Tree,
+            tags: None,
+        };
+        command.write_message(&ProxyRequest {
+            id: String::from("ID_EFGH"),
+            order: ProxyRequestOrder::AddHttpFrontend(front),
+        });
+        let backend = Backend {
+            cluster_id: String::from("cluster_1"),
+            backend_id: String::from("cluster_1-0"),
+            address: "127.0.0.1:1060".parse().unwrap(),
+            load_balancing_parameters: Some(LoadBalancingParams::default()),
+            sticky_id: None,
+            backup: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
+        };
+        command.write_message(&ProxyRequest {
+            id: String::from("ID_IJKL"),
+            order: ProxyRequestOrder::AddBackend(backend),
+        });
+
+        for _ in 0..3 {
+            println!("test received: {:?}", command.read_message());
+        }
+
+        // the protocol the cluster does not allow is not offered to the backend
+        let mut client = TcpStream::connect(("127.0.0.1", 1059)).expect("could not parse address");
+        client.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+        client
+            .write_all(
+                &b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: h2c, foo/1\r\nConnection: Upgrade\r\n\r\n"[..],
+            )
+            .unwrap();
+
+        let mut response = Vec::new();
+        let mut buffer = [0; 4096];
+        while !response.windows(4).any(|w| w == b"\r\n\r\n") {
+            let sz = client
+                .read(&mut buffer)
+                .expect("client request should not fail");
+            assert!(sz > 0);
+            response.extend_from_slice(&buffer[..sz]);
+        }
+        let response = String::from_utf8(response).unwrap();
+        println!("Response: {}", response);
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+
+        // then the connection is a pipe
+        client.write_all(b"PING").unwrap();
+        let sz = client.read(&mut buffer).expect("the pipe should answer");
+        assert_eq!(&buffer[..sz], &b"PING"[..]);
+    }
+
+    use self::tiny_http::{Response, Server};
+
+    fn start_server(port: u16, barrier: Arc<Barrier>) {
+        thread::spawn(move || {
+            setup_test_logger!();
+            let server =
+                Server::http(&format!("127.0.0.1:{}", port)).expect("could not create server");
+            info!("starting web server in port {}", port);
+            barrier.wait();
+
+            for request in server.incoming_requests() {
+                info!(
+                    "backend web server got request -> method: {:?}, url: {:?}, headers: {:?}",
+                    request.method(),
+                    request.url(),
+                    request.headers()
+                );
+
+                let response = Response::from_string("hello world");
+                request.respond(response).unwrap();
+                info!("backend web server sent response");
+                barrier.wait();
+                info!("server session stopped");
+            }
+
+            println!("server on port {} closed", port);
+        });
+    }
+
+    #[test]
+    fn frontend_from_request_test() {
+        let cluster_id1 = "cluster_1".to_owned();
+        let cluster_id2 = "cluster_2".to_owned();
+        let cluster_id3 = "cluster_3".to_owned();
+        let uri1 = "/".to_owned();
+        let uri2 = "/yolo".to_owned();
+        let uri3 = "/yolo/swag".to_owned();
+
+        let mut fronts = Router::new();
+        fronts.add_http_front(HttpFrontend {
+            route: Route::ClusterId(cluster_id1),
+            address: "0.0.0.0:80".parse().unwrap(),
+            hostname: "lolcatho.st".to_owned(),
+            path: PathRule::Prefix(uri1),
+            method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
+            position: RulePosition::Tree,
+            tags: None,
+        });
+        fronts.add_http_front(HttpFrontend {
+            route: Route::ClusterId(cluster_id2),
+            address: "0.0.0.0:80".parse().unwrap(),
+            hostname: "lolcatho.st".to_owned(),
+            path: PathRule::Prefix(uri2),
+            method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
+            position: RulePosition::Tree,
+            tags: None,
+        });
+        fronts.add_http_front(HttpFrontend {
+            route: Route::ClusterId(cluster_id3),
+            address: "0.0.0.0:80".parse().unwrap(),
+            hostname: "lolcatho.st".to_owned(),
+            path: PathRule::Prefix(uri3),
+            method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
+            position: RulePosition::Tree,
+            tags: None,
+        });
+        fronts.add_http_front(HttpFrontend {
+            route: Route::ClusterId("cluster_1".to_owned()),
+            address: "0.0.0.0:80".parse().unwrap(),
+            hostname: "other.domain".to_owned(),
+            path: PathRule::Prefix("/test".to_owned()),
+            method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
+            position: RulePosition::Tree,
+            tags: None,
+        });
+
+        let address: SocketAddr =
+            FromStr::from_str("127.0.0.1:1030").expect("could not parse address");
+        let mut listener = Listener {
+            listener: None,
+            address,
+            fronts,
+            answers: Rc::new(RefCell::new(HttpAnswers::new(
+                "HTTP/1.1 404 Not Found\r\n\r\n",
+                "HTTP/1.1 503 Service Unavailable\r\n\r\n",
+            ))),
+            config: Default::default(),
+            token: Token(0),
+            active: true,
+            tags: BTreeMap::new(),
+            acls: AccessLists::default(),
+        };
+
+        let frontend1 = listener.frontend_from_request("lolcatho.st", "/", &Method::Get, &[]);
+        let frontend2 = listener.frontend_from_request("lolcatho.st", "/test", &Method::Get, &[]);
+        let frontend3 =
+            listener.frontend_from_request("lolcatho.st", "/yolo/test", &Method::Get, &[]);
+        let frontend4 =
+            listener.frontend_from_request("lolcatho.st", "/yolo/swag", &Method::Get, &[]);
+        let frontend5 = listener.frontend_from_request("domain", "/", &Method::Get, &[]);
+        assert_eq!(
+            frontend1.expect("should find frontend").route,
+            Route::ClusterId("cluster_1".to_string())
+        );
+        assert_eq!(
+            frontend2.expect("should find frontend").route,
+            Route::ClusterId("cluster_1".to_string())
+        );
+        assert_eq!(
+            frontend3.expect("should find frontend").route,
+            Route::ClusterId("cluster_2".to_string())
+        );
+        assert_eq!(
+            frontend4.expect("should find frontend").route,
+            Route::ClusterId("cluster_3".to_string())
+        );
+        assert_eq!(frontend5, None);
+
+        listener.config.fallback_cluster = Some("fallback".to_owned());
+        let frontend6 = listener.frontend_from_request("domain", "/", &Method::Get, &[]);
+        assert_eq!(
+            frontend6.expect("should use the fallback cluster").route,
+            Route::ClusterId("fallback".to_string())
+        );
     }
 }
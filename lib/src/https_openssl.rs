@@ -18,12 +18,13 @@ use nom::HexDisplay;
 use openssl::{
     dh::Dh,
     error::ErrorStack,
+    hash::MessageDigest,
     nid,
     pkey::{PKey, Private},
     ssl::{
         self, select_next_proto, AlpnError, NameType, SniError, Ssl, SslAlert, SslContext,
         SslContextBuilder, SslMethod, SslOptions, SslRef, SslSessionCacheMode, SslStream,
-        SslVersion,
+        SslVerifyMode, SslVersion,
     },
     x509::X509,
 };
@@ -32,13 +33,19 @@ use slab::Slab;
 use time::{Duration, Instant};
 
 use crate::{
+    acl::AccessLists,
     backends::BackendMap,
+    buffer_queue::BufferQueue,
     pool::Pool,
     protocol::{
-        h2::Http2,
         http::{
-            answers::HttpAnswers,
-            parser::{hostname_and_port, Method, RequestLine, RequestState},
+            answers::{method_not_allowed_answer, redirect_answer, HttpAnswers},
+            normalization::{normalize_uri, NormalizedUri},
+            parser::{
+                hostname_and_port, normalized_hostname, Header, HeaderEdits, Method, RequestLine,
+                RequestState,
+            },
+            session::{HttpProxy, HttpSession, RequestRouting},
             DefaultAnswerStatus,
         },
         openssl::TlsHandshake,
@@ -46,26 +53,30 @@ use crate::{
         Http, Pipe, ProtocolResult, StickySession,
     },
     retry::RetryPolicy,
-    router::Router,
+    router::{pick_ab_test_variant, pick_weighted_cluster, RouteResult, Router},
     server::{
         push_event, ListenSession, ListenToken, ProxyChannel, Server, SessionManager, SessionToken,
-        CONN_RETRIES,
     },
-    socket::server_bind,
+    socket::{server_bind, BackendSocket, SocketHandler},
     sozu_command::{
         logging,
         proxy::{
-            CertificateFingerprint, Cluster, HttpFrontend, HttpsListener, ProxyEvent, ProxyRequest,
+            BackendProtocol, CertificateFingerprint, ClientAuth, Cluster, ClusterMaintenance,
+            Compression, HashKey, HeaderPosition, HostRewrite, HttpFrontend, HttpsListener,
+            LoadBalancingAlgorithms, PathNormalization, ProxyEvent, ProxyRequest,
             ProxyRequestOrder, ProxyResponse, ProxyResponseContent, ProxyResponseStatus, Query,
-            QueryAnswer, QueryAnswerCertificate, QueryCertificateType, Route, TlsVersion,
+            QueryAnswer, QueryAnswerCertificate, QueryCertificateType, RequestLimits,
+            RequestRetries, RetryCondition, Route, SecurityHeaders, SetDefaultCertificate,
+            SetOcspResponse, SetTicketKeys, StickyMode, Timeouts, TlsProvider, TlsVersion,
+            WebSocketDrain, TICKET_KEY_LENGTH,
         },
         ready::Ready,
         scm_socket::ScmSocket,
     },
     timer::TimeoutContainer,
     tls::{
-        CertificateResolver, GenericCertificateResolver, GenericCertificateResolverError,
-        ParsedCertificateAndKey,
+        certificate_subject, CertificateResolver, GenericCertificateResolver,
+        GenericCertificateResolverError, ParsedCertificateAndKey, RevocationList,
     },
     util::UnwrapLog,
     AcceptError, Backend, BackendConnectAction, BackendConnectionStatus, ClusterId,
@@ -89,7 +100,6 @@ pub enum State {
     Handshake(TlsHandshake),
     Http(Http<SslStream<TcpStream>, Listener>),
     WebSocket(Pipe<SslStream<TcpStream>, Listener>),
-    Http2(Http2<SslStream<TcpStream>>),
 }
 
 pub enum AlpnProtocols {
@@ -110,7 +120,7 @@ pub struct Session {
     pub cluster_id: Option<String>,
     last_event: Instant,
     pub listener_token: Token,
-    connection_attempt: u8,
+    routing: RequestRouting,
     peer_address: Option<SocketAddr>,
     answers: Rc<RefCell<HttpAnswers>>,
     front_timeout: TimeoutContainer,
@@ -134,7 +144,7 @@ impl Session {
         wait_time: Duration,
         frontend_timeout_duration: Duration,
         backend_timeout_duration: Duration,
-        request_timeout_duration: Duration,
+        handshake_timeout_duration: Duration,
         listener: Rc<RefCell<Listener>>,
     ) -> Session {
         let peer_address = if expect_proxy {
@@ -145,7 +155,7 @@ impl Session {
         };
 
         let request_id = Ulid::generate();
-        let front_timeout = TimeoutContainer::new(request_timeout_duration, token);
+        let front_timeout = TimeoutContainer::new(handshake_timeout_duration, token);
 
         let protocol = if expect_proxy {
             trace!("starting in expect proxy state");
@@ -178,7 +188,7 @@ impl Session {
             cluster_id: None,
             last_event: Instant::now(),
             listener_token,
-            connection_attempt: 0,
+            routing: RequestRouting::default(),
             peer_address,
             answers,
             front_timeout,
@@ -255,6 +265,10 @@ impl Session {
             let pool = self.pool.clone();
             let readiness = handshake.readiness.clone();
 
+            time!(
+                "tls.handshake.time",
+                (Instant::now() - handshake.started).whole_milliseconds()
+            );
             handshake.stream.as_ref().map(|s| {
                 let ssl = s.ssl();
                 if let Some(version) = ssl.version2() {
@@ -284,29 +298,16 @@ impl Session {
 
             match selected_protocol {
                 AlpnProtocols::H2 => {
-                    info!("got h2");
-                    let mut http = Http2::new(
-                        unwrap_msg!(handshake.stream),
-                        self.frontend_token,
-                        pool,
-                        Some(self.public_address),
-                        None,
-                        self.sticky_name.clone(),
-                        Protocol::HTTPS,
-                    );
-
-                    http.frontend.readiness = readiness;
-                    http.frontend.readiness.interest =
-                        Ready::readable() | Ready::hup() | Ready::error();
-
-                    gauge_add!("protocol.tls.handshake", -1);
-                    gauge_add!("protocol.http2s", 1);
-
-                    self.protocol = Some(State::Http2(http));
-                    true
+                    // not offered in the ALPN of the OpenSSL listeners
+                    error!("HTTP/2 is only available on rustls listeners");
+                    false
                 }
                 AlpnProtocols::Http11 => {
                     let backend_timeout_duration = self.backend_timeout_duration;
+                    // the request timeout applies once the handshake is done
+                    let request_timeout = self.listener.borrow().config.request_timeout;
+                    self.front_timeout
+                        .set_duration(Duration::seconds(request_timeout as i64));
                     let mut http = Http::new(
                         unwrap_msg!(handshake.stream),
                         self.frontend_token,
@@ -339,6 +340,16 @@ impl Session {
             let front_token = self.frontend_token;
             let back_token = unwrap_msg!(http.back_token());
             let ws_context = http.websocket_context();
+            let websocket = http.is_websocket_upgrade();
+            // the buffers can still hold the head of the request or response
+            let front_head = http
+                .front_buf
+                .as_ref()
+                .map_or(0, BufferQueue::parsed_data_size);
+            let back_head = http
+                .back_buf
+                .as_ref()
+                .map_or(0, BufferQueue::parsed_data_size);
 
             let front_buf = match http.front_buf {
                 Some(buf) => buf.buffer,
@@ -391,13 +402,18 @@ impl Session {
 
             pipe.front_readiness.event = http.front_readiness.event;
             pipe.back_readiness.event = http.back_readiness.event;
+            let websocket_timeout = self.websocket_timeout_duration().filter(|_| websocket);
             http.front_timeout
-                .set_duration(self.frontend_timeout_duration);
+                .set_duration(websocket_timeout.unwrap_or_else(|| self.front_timeout_duration()));
             http.back_timeout
-                .set_duration(self.backend_timeout_duration);
+                .set_duration(websocket_timeout.unwrap_or_else(|| self.back_timeout_duration()));
             pipe.front_timeout = Some(http.front_timeout);
             pipe.back_timeout = Some(http.back_timeout);
             pipe.set_back_token(back_token);
+            // the frames of the other protocols are not known
+            if websocket {
+                pipe.track_websocket(front_head, back_head, self.websocket_drain());
+            }
 
             self.protocol = Some(State::WebSocket(pipe));
             true
@@ -411,7 +427,6 @@ impl Session {
         match *unwrap_msg!(self.protocol.as_mut()) {
             State::Http(ref mut http) => http.front_hup(),
             State::WebSocket(ref mut pipe) => pipe.front_hup(&mut self.metrics),
-            State::Http2(ref mut http) => http.front_hup(),
             State::Handshake(_) => SessionResult::CloseSession,
             State::Expect(_, _) => SessionResult::CloseSession,
         }
@@ -421,7 +436,6 @@ impl Session {
         match *unwrap_msg!(self.protocol.as_mut()) {
             State::Http(ref mut http) => http.back_hup(),
             State::WebSocket(ref mut pipe) => pipe.back_hup(&mut self.metrics),
-            State::Http2(ref mut http) => http.back_hup(),
             State::Handshake(_) => {
                 error!("why a backend HUP event while still in frontend handshake?");
                 SessionResult::CloseSession
@@ -460,9 +474,6 @@ impl Session {
             State::Http(ref mut http) => {
                 (ProtocolResult::Continue, http.readable(&mut self.metrics))
             }
-            State::Http2(ref mut http) => {
-                (ProtocolResult::Continue, http.readable(&mut self.metrics))
-            }
             State::WebSocket(ref mut pipe) => {
                 (ProtocolResult::Continue, pipe.readable(&mut self.metrics))
             }
@@ -473,7 +484,6 @@ impl Session {
         } else if self.upgrade() {
             match *unwrap_msg!(self.protocol.as_mut()) {
                 State::Http(ref mut http) => http.readable(&mut self.metrics),
-                State::Http2(ref mut http) => http.readable(&mut self.metrics),
                 _ => result,
             }
         } else {
@@ -486,7 +496,6 @@ impl Session {
             State::Expect(_, _) => SessionResult::CloseSession,
             State::Handshake(_) => SessionResult::CloseSession,
             State::Http(ref mut http) => http.writable(&mut self.metrics),
-            State::Http2(ref mut http) => http.writable(&mut self.metrics),
             State::WebSocket(ref mut pipe) => pipe.writable(&mut self.metrics),
         }
     }
@@ -495,10 +504,6 @@ impl Session {
         let (upgrade, result) = match *unwrap_msg!(self.protocol.as_mut()) {
             State::Expect(_, _) => (ProtocolResult::Continue, SessionResult::CloseSession),
             State::Http(ref mut http) => http.back_readable(&mut self.metrics),
-            State::Http2(ref mut http) => (
-                ProtocolResult::Continue,
-                http.back_readable(&mut self.metrics),
-            ),
             State::Handshake(_) => (ProtocolResult::Continue, SessionResult::CloseSession),
             State::WebSocket(ref mut pipe) => (
                 ProtocolResult::Continue,
@@ -523,7 +528,6 @@ impl Session {
             State::Expect(_, _) => SessionResult::CloseSession,
             State::Handshake(_) => SessionResult::CloseSession,
             State::Http(ref mut http) => http.back_writable(&mut self.metrics),
-            State::Http2(ref mut http) => http.back_writable(&mut self.metrics),
             State::WebSocket(ref mut pipe) => pipe.back_writable(&mut self.metrics),
         }
     }
@@ -533,7 +537,6 @@ impl Session {
             State::Expect(ref mut expect, _) => Some(expect.front_socket_mut()),
             State::Handshake(ref mut handshake) => handshake.socket_mut(),
             State::Http(ref mut http) => Some(http.front_socket_mut()),
-            State::Http2(ref mut http) => Some(http.front_socket_mut()),
             State::WebSocket(ref mut pipe) => Some(pipe.front_socket_mut()),
         }
     }
@@ -543,7 +546,6 @@ impl Session {
             State::Expect(_, _) => None,
             State::Handshake(_) => None,
             State::Http(ref mut http) => http.back_socket_mut(),
-            State::Http2(ref mut http) => http.back_socket_mut(),
             State::WebSocket(ref mut pipe) => pipe.back_socket_mut(),
         }
     }
@@ -553,12 +555,11 @@ impl Session {
             &State::Expect(_, _) => None,
             &State::Handshake(_) => None,
             &State::Http(ref http) => http.back_token(),
-            &State::Http2(ref http) => http.back_token(),
             &State::WebSocket(ref pipe) => pipe.back_token(),
         }
     }
 
-    fn set_back_socket(&mut self, sock: TcpStream) {
+    fn set_back_socket(&mut self, sock: BackendSocket) {
         let backend = self.backend.clone();
         unwrap_msg!(self.http_mut()).set_back_socket(sock, backend)
     }
@@ -566,7 +567,6 @@ impl Session {
     fn set_back_token(&mut self, token: Token) {
         match *unwrap_msg!(self.protocol.as_mut()) {
             State::Http(ref mut http) => http.set_back_token(token),
-            State::Http2(ref mut http) => http.set_back_token(token),
             State::WebSocket(ref mut pipe) => pipe.set_back_token(token),
             _ => {}
         }
@@ -591,7 +591,7 @@ impl Session {
 
             // the back timeout was of connect_timeout duration before,
             // now that we're connected, move to backend_timeout duration
-            let t = self.backend_timeout_duration;
+            let t = self.back_timeout_duration();
             self.http_mut().map(|h| {
                 h.set_back_timeout(t);
                 h.cancel_backend_timeout();
@@ -648,7 +648,6 @@ impl Session {
             State::Expect(ref mut expect, _) => &mut expect.readiness,
             State::Handshake(ref mut handshake) => &mut handshake.readiness,
             State::Http(ref mut http) => http.front_readiness(),
-            State::Http2(ref mut http) => http.front_readiness(),
             State::WebSocket(ref mut pipe) => &mut pipe.front_readiness,
         };
         //info!("current readiness: {:?}", r);
@@ -658,7 +657,6 @@ impl Session {
     fn back_readiness(&mut self) -> Option<&mut Readiness> {
         let r = match *unwrap_msg!(self.protocol.as_mut()) {
             State::Http(ref mut http) => Some(http.back_readiness()),
-            State::Http2(ref mut http) => Some(http.back_readiness()),
             State::WebSocket(ref mut pipe) => Some(&mut pipe.back_readiness),
             _ => None,
         };
@@ -673,6 +671,7 @@ impl Session {
 
             let already_unavailable = backend.retry_policy.is_down();
             backend.retry_policy.fail();
+            backend.record_outcome(self.cluster_id.as_deref(), true);
             incr!(
                 "connections.error",
                 self.cluster_id.as_deref(),
@@ -698,9 +697,11 @@ impl Session {
     }
 
     fn reset_connection_attempt(&mut self) {
-        self.connection_attempt = 0;
+        self.routing.connection_attempt = 0;
+        self.routing.tried_backends.clear();
     }
 
+    /// timeouts of the current backend, then of its cluster
     fn cancel_timeouts(&mut self) {
         self.front_timeout.cancel();
 
@@ -715,6 +716,18 @@ impl Session {
         let mut counter = 0;
         let max_loop_iterations = 100000;
 
+        // the request waiting in the queue of its cluster may have a free connection now
+        if self.routing.queued_since.is_some()
+            && self.back_connected == BackendConnectionStatus::NotConnected
+        {
+            match self.connect_to_backend(session.clone()) {
+                // reuse connection or send a default answer, we can continue
+                Ok(BackendConnectAction::Reuse) | Err(_) => {}
+                // New or Replace: stop here, we must wait for an event
+                _ => return SessionResult::Continue,
+            }
+        }
+
         if self.back_connected().is_connecting()
             && self
                 .back_readiness()
@@ -739,22 +752,34 @@ impl Session {
                     "{} error connecting to backend, trying again",
                     self.log_context()
                 );
-                self.connection_attempt += 1;
+                self.record_failed_attempt();
                 self.fail_backend_connection();
 
                 self.back_connected = BackendConnectionStatus::Connecting(Instant::now());
 
                 // trigger a backend reconnection
                 self.close_backend();
-                match self.connect_to_backend(session.clone()) {
-                    // reuse connection or send a default answer, we can continue
-                    Ok(BackendConnectAction::Reuse) | Err(_) => {}
-                    // New or Replace: stop here, we must wait for an event
-                    _ => return SessionResult::Continue,
+                if !self.can_retry(RetryCondition::ConnectFailure) {
+                    error!(
+                        "{} could not connect to a backend, not retrying",
+                        self.log_context()
+                    );
+                    self.set_answer(DefaultAnswerStatus::Answer503, None);
+                } else {
+                    incr!(
+                        "retries",
+                        self.cluster_id.as_deref(),
+                        self.metrics.backend_id.as_deref()
+                    );
+                    match self.connect_to_backend(session.clone()) {
+                        // reuse connection or send a default answer, we can continue
+                        Ok(BackendConnectAction::Reuse) | Err(_) => {}
+                        // New or Replace: stop here, we must wait for an event
+                        _ => return SessionResult::Continue,
+                    }
                 }
             } else {
                 self.metrics().backend_connected();
-                self.reset_connection_attempt();
                 self.set_back_connected(BackendConnectionStatus::Connected);
             }
         }
@@ -808,6 +833,8 @@ impl Session {
 
                 match order {
                     SessionResult::ConnectBackend => {
+                        // a new request gets a new retry budget
+                        self.reset_connection_attempt();
                         match self.connect_to_backend(session.clone()) {
                             // reuse connection or send a default answer, we can continue
                             Ok(BackendConnectAction::Reuse) | Err(_) => {}
@@ -832,6 +859,10 @@ impl Session {
                 if order != SessionResult::Continue {
                     return order;
                 }
+
+                if self.retry_request(session.clone()) {
+                    return SessionResult::Continue;
+                }
             }
 
             if front_interest.is_writable() {
@@ -957,7 +988,7 @@ impl Session {
     }
 
     fn check_circuit_breaker(&mut self) -> Result<(), ConnectionError> {
-        if self.connection_attempt == CONN_RETRIES {
+        if self.routing.connection_attempt >= self.request_retries().max_attempts() {
             error!("{} max connection attempt reached", self.log_context());
             self.set_answer(DefaultAnswerStatus::Answer503, None);
             Err(ConnectionError::NoBackendAvailable)
@@ -1050,15 +1081,37 @@ impl Session {
     pub fn backend_from_request(
         &mut self,
         cluster_id: &str,
-        front_should_stick: bool,
-    ) -> Result<TcpStream, ConnectionError> {
+        sticky_mode: Option<StickyMode>,
+        hash_key: Option<HashKey>,
+    ) -> Result<BackendSocket, ConnectionError> {
+        let front_should_stick = sticky_mode == Some(StickyMode::Cookie);
+        let sticky_key = self
+            .http()
+            .zip(sticky_mode.as_ref())
+            .and_then(|(http, sticky_mode)| http.get_sticky_key(sticky_mode));
+        let hash_key = self
+            .http()
+            .zip(hash_key.as_ref())
+            .and_then(|(http, hash_key)| http.get_hash_key(hash_key));
         let sticky_session = self
             .http()
             .and_then(|http| http.request_state.as_ref())
             .and_then(|r| r.get_sticky_session());
 
-        let res = match (front_should_stick, sticky_session) {
-            (true, Some(sticky_session)) => self
+        let res = match (sticky_key, front_should_stick, sticky_session) {
+            _ if !self.routing.tried_backends.is_empty() => self
+                .proxy
+                .borrow()
+                .backends
+                .borrow_mut()
+                .backend_for_retry(cluster_id, &self.routing.tried_backends),
+            (Some(sticky_key), _, _) => self
+                .proxy
+                .borrow()
+                .backends
+                .borrow_mut()
+                .backend_from_sticky_key(cluster_id, &sticky_key),
+            (None, true, Some(sticky_session)) => self
                 .proxy
                 .borrow()
                 .backends
@@ -1076,15 +1129,32 @@ impl Session {
                 .borrow()
                 .backends
                 .borrow_mut()
-                .backend_from_cluster_id(cluster_id),
+                .backend_for_key(cluster_id, hash_key.as_deref()),
         };
 
         match res {
             Err(e) => {
-                self.set_answer(DefaultAnswerStatus::Answer503, None);
+                if !self.queue_request(cluster_id) {
+                    self.set_answer(DefaultAnswerStatus::Answer503, None);
+                }
                 Err(e)
             }
             Ok((backend, conn)) => {
+                self.leave_queue();
+                let socket = self.proxy.borrow().backends.borrow().backend_socket(
+                    cluster_id,
+                    &backend.borrow(),
+                    conn,
+                    BackendProtocol::Http1,
+                );
+                let conn = match socket {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        self.set_answer(DefaultAnswerStatus::Answer503, None);
+                        return Err(e);
+                    }
+                };
+
                 if front_should_stick {
                     let sticky_name = self.proxy.borrow().listeners[&self.listener_token]
                         .borrow()
@@ -1115,6 +1185,11 @@ impl Session {
     }
 
     fn cluster_id_from_request(&mut self) -> Result<String, ConnectionError> {
+        if self.routing.counted_listener.is_none() {
+            self.set_answer(DefaultAnswerStatus::Answer503, None);
+            return Err(ConnectionError::TooManySessions);
+        }
+
         let (host, uri, method) = match self.extract_route() {
             Ok(t) => t,
             Err(e) => {
@@ -1122,25 +1197,273 @@ impl Session {
                 return Err(e);
             }
         };
+        // the ACLs and the frontends match the hostname without its port, in lowercase
+        let hostname = match normalized_hostname(host) {
+            Some(hostname) => hostname,
+            None => {
+                self.set_answer(DefaultAnswerStatus::Answer400, None);
+                return Err(ConnectionError::InvalidHost);
+            }
+        };
+        let acl_allows = match self.http().and_then(|http| http.session_address) {
+            Some(address) => self
+                .proxy
+                .borrow()
+                .listeners
+                .get(&self.listener_token)
+                .map(|l| l.borrow().acls.allows_request(address.ip(), &hostname))
+                .unwrap_or(true),
+            None => true,
+        };
+        if !acl_allows {
+            let (status, answer) = self.answers.borrow().deny_answer(403, None);
+            self.set_answer(status, Some(answer));
+            return Err(ConnectionError::Unauthorized);
+        }
 
-        let route_res = self
+        let path_normalization = self
             .proxy
             .borrow()
             .listeners
             .get(&self.listener_token)
-            .as_ref()
-            .and_then(|l| l.borrow().frontend_from_request(host, uri, method));
-        match route_res {
-            Some(Route::ClusterId(cluster_id)) => Ok(cluster_id),
-            Some(Route::Deny) => {
-                self.set_answer(DefaultAnswerStatus::Answer401, None);
-                Err(ConnectionError::Unauthorized)
+            .map(|l| l.borrow().get_path_normalization())
+            .unwrap_or_default();
+        let normalized_uri = match normalize_uri(uri, &path_normalization) {
+            NormalizedUri::Unchanged => None,
+            NormalizedUri::Rewritten(normalized) => Some(normalized),
+            NormalizedUri::Redirect(location) => {
+                match redirect_answer(308, "{path}", host, &location) {
+                    Some((status, answer)) => self.set_answer(status, Some(answer)),
+                    None => self.set_answer(DefaultAnswerStatus::Answer503, None),
+                }
+                return Err(ConnectionError::Redirect);
             }
+        };
+        let uri = normalized_uri.as_deref().unwrap_or(uri);
+
+        let route_res = self
+            .proxy
+            .borrow()
+            .listeners
+            .get(&self.listener_token)
+            .and_then(|l| {
+                let l = l.borrow();
+                // parsing the headers again is only needed by the frontends matching on them
+                let headers = if l.has_header_rules() {
+                    self.http()
+                        .map(|http| http.get_request_headers())
+                        .unwrap_or_default()
+                } else {
+                    Vec::new()
+                };
+                l.frontend_from_request(&hostname, uri, method, &headers)
+            });
+        let RouteResult {
+            route,
+            rewritten_path,
+            mirror_cluster_id,
+            allowed_methods,
+        } = match route_res {
+            Some(route_result) => route_result,
             None => {
                 self.set_answer(DefaultAnswerStatus::Answer404, None);
-                Err(ConnectionError::HostNotFound)
+                return Err(ConnectionError::HostNotFound);
+            }
+        };
+
+        let mut ab_test_cookie = None;
+        let cluster_id = match route {
+            Route::ClusterId(cluster_id) => cluster_id,
+            Route::Weighted(clusters) => match pick_weighted_cluster(&clusters) {
+                Some(cluster_id) => cluster_id.to_string(),
+                None => {
+                    self.set_answer(DefaultAnswerStatus::Answer503, None);
+                    return Err(ConnectionError::NoBackendAvailable);
+                }
+            },
+            Route::AbTest { cookie, variants } => {
+                let assigned = self
+                    .http()
+                    .and_then(|http| http.get_request_cookie(&cookie));
+                match pick_ab_test_variant(&variants, assigned.as_deref()) {
+                    Some((cluster_id, newly_assigned)) => {
+                        if newly_assigned {
+                            ab_test_cookie = Some((cookie.clone(), cluster_id.to_string()));
+                        }
+                        cluster_id.to_string()
+                    }
+                    None => {
+                        self.set_answer(DefaultAnswerStatus::Answer503, None);
+                        return Err(ConnectionError::NoBackendAvailable);
+                    }
+                }
+            }
+            Route::Deny { status, body_path } => {
+                let (status, answer) = if allowed_methods.is_empty() {
+                    self.answers
+                        .borrow()
+                        .deny_answer(status, body_path.as_deref())
+                } else {
+                    method_not_allowed_answer(&allowed_methods)
+                };
+                self.set_answer(status, Some(answer));
+                return Err(ConnectionError::Unauthorized);
+            }
+            Route::Redirect { to, code } => {
+                match redirect_answer(code, &to, host, uri) {
+                    Some((status, answer)) => self.set_answer(status, Some(answer)),
+                    None => self.set_answer(DefaultAnswerStatus::Answer503, None),
+                }
+                return Err(ConnectionError::Redirect);
             }
+        };
+
+        let maintenance = self.proxy.borrow().maintenance.get(&cluster_id).cloned();
+        if let Some(answer) = maintenance {
+            let answer = answer.unwrap_or_else(|| {
+                self.answers
+                    .borrow()
+                    .get(DefaultAnswerStatus::Answer503, Some(&cluster_id))
+            });
+            self.set_answer(DefaultAnswerStatus::Answer503, Some(answer));
+            return Err(ConnectionError::Maintenance);
         }
+
+        if !self.enter_cluster(&cluster_id) {
+            self.set_answer(DefaultAnswerStatus::Answer503, None);
+            return Err(ConnectionError::TooManySessions);
+        }
+
+        let request_limits = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .map(|cluster| cluster.request_limits)
+            .unwrap_or_default();
+        let within_limits = self
+            .http_mut()
+            .map(|http| http.set_cluster_request_limits(&request_limits))
+            .unwrap_or(true);
+        if !within_limits {
+            return Err(ConnectionError::RequestTooLarge);
+        }
+
+        let compression = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .map(|cluster| cluster.compression)
+            .unwrap_or_default();
+        if let Some(http) = self.http_mut() {
+            http.set_cluster_compression(&compression);
+        }
+
+        let streaming = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .map(|cluster| cluster.streaming)
+            .unwrap_or(false);
+        if let Some(http) = self.http_mut() {
+            http.set_cluster_streaming(streaming);
+        }
+
+        let upgrade_protocols = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .map(|cluster| cluster.upgrade_protocols.clone())
+            .unwrap_or_default();
+        if let Some(http) = self.http_mut() {
+            http.set_cluster_upgrades(&upgrade_protocols);
+        }
+
+        let request_retries = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .map(|cluster| cluster.request_retries.clone())
+            .unwrap_or_default();
+        if let Some(http) = self.http_mut() {
+            http.set_cluster_retries(&request_retries);
+        }
+
+        self.routing.mirror_cluster_id = mirror_cluster_id;
+
+        // the request is sent with the normalized path, unless the frontend rewrites it
+        if let Some(path) = rewritten_path.or(normalized_uri) {
+            let rewritten = self
+                .http_mut()
+                .map(|http| http.set_request_path(&path))
+                .unwrap_or(false);
+            if !rewritten {
+                return Err(ConnectionError::PathNotRewritten);
+            }
+        }
+
+        let header_edits = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .filter(|cluster| !cluster.header_actions.is_empty())
+            .map(|cluster| {
+                (
+                    HeaderEdits::new(&cluster.header_actions, HeaderPosition::Request),
+                    HeaderEdits::new(&cluster.header_actions, HeaderPosition::Response),
+                )
+            });
+        if let Some((request_edits, response_edits)) = header_edits {
+            if let Some(http) = self.http_mut() {
+                http.edit_request_headers(&request_edits);
+                http.response_header_edits.extend(response_edits);
+            }
+        }
+
+        let security_headers = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .map(|cluster| cluster.security_headers.clone())
+            .unwrap_or_default();
+        if let Some(http) = self.http_mut() {
+            http.set_cluster_security_headers(&security_headers);
+        }
+
+        // the client cannot send the header carrying the subject of its certificate
+        let client_dn_header = self
+            .listener
+            .borrow()
+            .config
+            .client_auth
+            .as_ref()
+            .map(|client_auth| client_auth.dn_header.clone());
+        if let Some(name) = client_dn_header {
+            if let Some(http) = self.http_mut() {
+                let subject = http
+                    .frontend
+                    .ssl()
+                    .peer_certificate()
+                    .and_then(|certificate| certificate.to_der().ok())
+                    .and_then(|certificate| certificate_subject(&certificate));
+                http.edit_request_headers(&HeaderEdits::client_dn(&name, subject.as_deref()));
+            }
+        }
+
+        // set after the header edits of the cluster, which replace those of the response
+        if let Some((name, value)) = ab_test_cookie {
+            if let Some(http) = self.http_mut() {
+                http.add_response_cookie(&name, &value);
+            }
+        }
+
+        Ok(cluster_id)
     }
 
     fn connect_to_backend(
@@ -1152,7 +1475,19 @@ impl Session {
 
         self.check_circuit_breaker()?;
 
-        let cluster_id = self.cluster_id_from_request()?;
+        // a retried or queued request stays in the cluster it was routed to
+        let cluster_id = match self.cluster_id.clone() {
+            Some(cluster_id)
+                if self.routing.connection_attempt > 0 || self.routing.queued_since.is_some() =>
+            {
+                cluster_id
+            }
+            _ => {
+                let cluster_id = self.cluster_id_from_request()?;
+                self.connect_mirror(session_rc.clone());
+                cluster_id
+            }
+        };
 
         if (self.http().and_then(|h| h.cluster_id.as_ref()) == Some(&cluster_id))
             && self.back_connected == BackendConnectionStatus::Connected
@@ -1171,6 +1506,7 @@ impl Session {
                 .unwrap_or(false);
 
             if has_backend && self.check_backend_connection() {
+                self.rewrite_host(&cluster_id);
                 return Ok(BackendConnectAction::Reuse);
             } else if let Some(token) = self.back_token() {
                 self.close_backend();
@@ -1197,19 +1533,28 @@ impl Session {
             http.cluster_id = Some(cluster_id.clone());
         }
 
-        let front_should_stick = self
+        let sticky_mode = self
             .proxy
             .borrow()
             .clusters
             .get(&cluster_id)
-            .map(|cluster| cluster.sticky_session)
-            .unwrap_or(false);
-        let mut socket = self.backend_from_request(&cluster_id, front_should_stick)?;
+            .filter(|cluster| cluster.sticky_session)
+            .map(|cluster| cluster.sticky_mode.clone());
+        let hash_key = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .filter(|cluster| cluster.load_balancing == LoadBalancingAlgorithms::ConsistentHash)
+            .map(|cluster| cluster.hash_key.clone());
+        let mut socket = self.backend_from_request(&cluster_id, sticky_mode, hash_key)?;
+        self.rewrite_host(&cluster_id);
 
-        if let Err(e) = socket.set_nodelay(true) {
+        if let Err(e) = socket.socket_ref().set_nodelay(true) {
             error!(
                 "error setting nodelay on back socket({:?}): {:?}",
-                socket, e
+                socket.socket_ref(),
+                e
             );
         }
         if let Some(r) = self.back_readiness() {
@@ -1217,24 +1562,34 @@ impl Session {
         }
 
         let connect_timeout = Duration::seconds(i64::from(
-            self.proxy
-                .borrow()
-                .listeners
-                .get(&self.listener_token)
-                .as_ref()
-                .map(|l| l.borrow().config.connect_timeout)
-                .unwrap(),
+            self.timeouts().connect_timeout.unwrap_or_else(|| {
+                self.proxy
+                    .borrow()
+                    .listeners
+                    .get(&self.listener_token)
+                    .as_ref()
+                    .map(|l| l.borrow().config.connect_timeout)
+                    .unwrap()
+            }),
         ));
+        let front_timeout = self.front_timeout_duration();
+        if let Some(http) = self.http_mut() {
+            http.frontend_timeout_duration = front_timeout;
+        }
 
         self.back_connected = BackendConnectionStatus::Connecting(Instant::now());
         if let Some(back_token) = old_back_token {
             self.set_back_token(back_token);
             if let Err(e) = self.proxy.borrow().registry.register(
-                &mut socket,
+                socket.socket_mut(),
                 back_token,
                 Interest::READABLE | Interest::WRITABLE,
             ) {
-                error!("error registering back socket({:?}): {:?}", socket, e);
+                error!(
+                    "error registering back socket({:?}): {:?}",
+                    socket.socket_ref(),
+                    e
+                );
             }
 
             self.set_back_socket(socket);
@@ -1261,11 +1616,15 @@ impl Session {
             };
 
             if let Err(e) = self.proxy.borrow().registry.register(
-                &mut socket,
+                socket.socket_mut(),
                 back_token,
                 Interest::READABLE | Interest::WRITABLE,
             ) {
-                error!("error registering back socket({:?}): {:?}", socket, e);
+                error!(
+                    "error registering back socket({:?}): {:?}",
+                    socket.socket_ref(),
+                    e
+                );
             }
 
             self.set_back_socket(socket);
@@ -1278,6 +1637,71 @@ impl Session {
     }
 }
 
+impl HttpSession for Session {
+    type Front = SslStream<TcpStream>;
+    type Listener = Listener;
+    type Proxy = Proxy;
+
+    fn proxy(&self) -> &Rc<RefCell<Proxy>> {
+        &self.proxy
+    }
+
+    fn http(&self) -> Option<&Http<SslStream<TcpStream>, Listener>> {
+        Session::http(self)
+    }
+
+    fn http_mut(&mut self) -> Option<&mut Http<SslStream<TcpStream>, Listener>> {
+        Session::http_mut(self)
+    }
+
+    fn frontend_token(&self) -> Token {
+        self.frontend_token
+    }
+
+    fn cluster_id(&self) -> Option<&str> {
+        self.cluster_id.as_deref()
+    }
+
+    fn backend(&self) -> Option<&Rc<RefCell<Backend>>> {
+        self.backend.as_ref()
+    }
+
+    fn metrics(&self) -> &SessionMetrics {
+        &self.metrics
+    }
+
+    fn routing(&self) -> &RequestRouting {
+        &self.routing
+    }
+
+    fn routing_mut(&mut self) -> &mut RequestRouting {
+        &mut self.routing
+    }
+
+    fn frontend_timeout_duration(&self) -> Duration {
+        self.frontend_timeout_duration
+    }
+
+    fn backend_timeout_duration(&self) -> Duration {
+        self.backend_timeout_duration
+    }
+
+    fn log_context(&self) -> String {
+        Session::log_context(self)
+    }
+
+    fn close_backend(&mut self) {
+        Session::close_backend(self)
+    }
+
+    fn connect_to_backend(
+        &mut self,
+        session_rc: Rc<RefCell<dyn ProxySession>>,
+    ) -> Result<BackendConnectAction, ConnectionError> {
+        Session::connect_to_backend(self, session_rc)
+    }
+}
+
 impl ProxySession for Session {
     fn close(&mut self) {
         //println!("TLS closing[{:?}] temp->front: {:?}, temp->back: {:?}", self.frontend_token, *self.temp.front_buf, *self.temp.back_buf);
@@ -1286,6 +1710,8 @@ impl ProxySession for Session {
         }
         self.metrics.service_stop();
         self.cancel_timeouts();
+        self.leave_queue();
+        self.leave_session_limits();
         if let Some(front_socket) = self.front_socket_mut() {
             if let Err(e) = front_socket.shutdown(Shutdown::Both) {
                 if e.kind() != ErrorKind::NotConnected {
@@ -1295,6 +1721,7 @@ impl ProxySession for Session {
         }
 
         self.close_backend();
+        self.close_mirror();
 
         if let Some(State::Http(ref mut http)) = self.protocol {
             //if the state was initial, the connection was already reset
@@ -1319,7 +1746,6 @@ impl ProxySession for Session {
             Some(State::Expect(_, _)) => gauge_add!("protocol.proxy.expect", -1),
             Some(State::Handshake(_)) => gauge_add!("protocol.tls.handshake", -1),
             Some(State::Http(_)) => gauge_add!("protocol.https", -1),
-            Some(State::Http2(_)) => gauge_add!("protocol.http2s", -1),
             Some(State::WebSocket(_)) => gauge_add!("protocol.wss", -1),
             None => {}
         }
@@ -1349,8 +1775,6 @@ impl ProxySession for Session {
                 SessionResult::CloseSession
             }
             State::WebSocket(ref mut pipe) => pipe.timeout(token, &mut self.metrics),
-            //FIXME: not implemented yet
-            State::Http2(_) => SessionResult::CloseSession,
             State::Http(ref mut http) => http.timeout(token, &mut self.metrics),
         };
 
@@ -1376,12 +1800,20 @@ impl ProxySession for Session {
             self.front_readiness().event = self.front_readiness().event | events;
         } else if self.back_token() == Some(token) {
             self.back_readiness().map(|r| r.event |= events);
+        } else if let Some(mirror) = self
+            .routing
+            .mirror
+            .as_mut()
+            .filter(|mirror| mirror.token == token)
+        {
+            mirror.readiness.event |= events;
         }
     }
 
     fn ready(&mut self, session: Rc<RefCell<dyn ProxySession>>) {
         self.metrics().service_start();
         let res = self.ready_inner(session);
+        self.mirror_ready();
 
         if res == SessionResult::CloseSession {
             self.close();
@@ -1395,6 +1827,7 @@ impl ProxySession for Session {
     fn shutting_down(&mut self) {
         let res = match &mut self.protocol {
             Some(State::Http(h)) => h.shutting_down(),
+            Some(State::WebSocket(pipe)) => pipe.shutting_down(&mut self.metrics),
             Some(State::Handshake(_)) => SessionResult::Continue,
             _ => SessionResult::CloseSession,
         };
@@ -1419,7 +1852,6 @@ impl ProxySession for Session {
             Some(State::Expect(_, _)) => String::from("Expect"),
             Some(State::Handshake(_)) => String::from("Handshake"),
             Some(State::Http(h)) => h.print_state("HTTPS"),
-            Some(State::Http2(h)) => format!("HTTP2S: {:?}", h.state),
             Some(State::WebSocket(_)) => String::from("WSS"),
             None => String::from("None"),
         };
@@ -1428,12 +1860,10 @@ impl ProxySession for Session {
             State::Expect(ref expect, _) => &expect.readiness,
             State::Handshake(ref handshake) => &handshake.readiness,
             State::Http(ref http) => &http.front_readiness,
-            State::Http2(ref http) => &http.frontend.readiness,
             State::WebSocket(ref pipe) => &pipe.front_readiness,
         };
         let rb = match *unwrap_msg!(self.protocol.as_ref()) {
             State::Http(ref http) => Some(&http.back_readiness),
-            State::Http2(ref http) => Some(&http.back_readiness),
             State::WebSocket(ref pipe) => Some(&pipe.back_readiness),
             _ => None,
         };
@@ -1447,6 +1877,9 @@ impl ProxySession for Session {
         if let Some(tk) = self.back_token() {
             v.push(tk)
         }
+        if let Some(mirror) = &self.routing.mirror {
+            v.push(mirror.token)
+        }
 
         v
     }
@@ -1465,6 +1898,8 @@ pub enum ListenerError {
     PemParseError(String),
     #[error("failed to build openssl context, {0}")]
     BuildOpenSslError(String),
+    #[error("failed to set the session ticket keys, {0}")]
+    TicketKeyError(String),
 }
 
 pub struct Listener {
@@ -1480,6 +1915,7 @@ pub struct Listener {
     pub token: Token,
     active: bool,
     tags: BTreeMap<String, BTreeMap<String, String>>,
+    pub acls: AccessLists,
 }
 
 impl ListenerHandler for Listener {
@@ -1497,6 +1933,22 @@ impl ListenerHandler for Listener {
             None => self.tags.remove(&key),
         };
     }
+
+    fn get_request_limits(&self) -> RequestLimits {
+        self.config.request_limits
+    }
+
+    fn get_compression(&self) -> Compression {
+        self.config.compression
+    }
+
+    fn get_security_headers(&self) -> SecurityHeaders {
+        (*self.config.security_headers).clone()
+    }
+
+    fn get_path_normalization(&self) -> PathNormalization {
+        self.config.path_normalization
+    }
 }
 
 impl CertificateResolver for Listener {
@@ -1558,6 +2010,7 @@ impl CertificateResolver for Listener {
             &certificate,
             &key,
             &chain,
+            self.config.client_auth.as_deref(),
             self.resolver.to_owned(),
             self.contexts.to_owned(),
         )?;
@@ -1596,11 +2049,37 @@ impl CertificateResolver for Listener {
 
         Ok(())
     }
+
+    fn set_ocsp_response(&mut self, opts: &SetOcspResponse) -> Result<(), Self::Error> {
+        let mut resolver = self
+            .resolver
+            .lock()
+            .map_err(|err| ListenerError::LockError(err.to_string()))?;
+
+        resolver
+            .set_ocsp_response(opts)
+            .map_err(ListenerError::ResolverError)
+    }
+
+    fn set_default_certificate(&mut self, opts: &SetDefaultCertificate) -> Result<(), Self::Error> {
+        let mut resolver = self
+            .resolver
+            .lock()
+            .map_err(|err| ListenerError::LockError(err.to_string()))?;
+
+        resolver
+            .set_default_certificate(opts)
+            .map_err(ListenerError::ResolverError)
+    }
 }
 
 impl Listener {
     pub fn try_new(config: HttpsListener, token: Token) -> Result<Listener, ListenerError> {
-        let resolver = Arc::new(Mutex::new(GenericCertificateResolver::new()));
+        let mut generic_resolver = GenericCertificateResolver::new();
+        // the certificates are added after the listener, the default one is not checked here
+        generic_resolver.default_certificate = config.default_certificate.to_owned();
+        generic_resolver.strict_sni = config.strict_sni;
+        let resolver = Arc::new(Mutex::new(generic_resolver));
         let contexts = Arc::new(Mutex::new(HashMap::new()));
 
         let (default_context, ssl_options): (SslContext, SslOptions) =
@@ -1622,9 +2101,23 @@ impl Listener {
             _ssl_options: ssl_options,
             token,
             tags: BTreeMap::new(),
+            acls: AccessLists::default(),
         })
     }
 
+    /// the session tickets are encrypted by the context the connections start with,
+    /// even after the server name callback switched to the context of a certificate
+    pub fn set_ticket_key(&self, key: &[u8]) -> Result<(), ListenerError> {
+        if set_ticket_key(&self.default_context, key) {
+            Ok(())
+        } else {
+            Err(ListenerError::TicketKeyError(format!(
+                "OpenSSL rejected the key on listener {}",
+                self.address
+            )))
+        }
+    }
+
     pub fn activate(
         &mut self,
         registry: &Registry,
@@ -1698,6 +2191,7 @@ impl Listener {
             &certificate,
             &key,
             &chain[..],
+            config.client_auth.as_deref(),
             resolver,
             contexts,
         )
@@ -1712,6 +2206,7 @@ impl Listener {
         cert: &X509,
         key: &PKey<Private>,
         cert_chain: &[X509],
+        client_auth: Option<&ClientAuth>,
         resolver: Arc<Mutex<GenericCertificateResolver>>,
         contexts: Arc<Mutex<HashMap<CertificateFingerprint, SslContext>>>,
     ) -> Result<(SslContext, SslOptions), ListenerError> {
@@ -1830,12 +2325,101 @@ impl Listener {
             }
         }
 
+        if let Some(client_auth) = client_auth {
+            Self::setup_client_auth(&mut context, client_auth)?;
+        }
+
+        if let Err(e) =
+            context.set_status_callback(Self::create_status_callback(resolver.to_owned()))
+        {
+            error!("could not set OCSP status callback: {:?}", e);
+            return Err(ListenerError::BuildOpenSslError(e.to_string()));
+        }
+
         context.set_client_hello_callback(Self::create_client_hello_callback(resolver, contexts));
 
         Ok((context.build(), ssl_options))
     }
 
+    /// requests the client certificates, and verifies them against the certificate
+    /// authorities and the revocation lists of the listener
+    fn setup_client_auth(
+        context: &mut SslContextBuilder,
+        client_auth: &ClientAuth,
+    ) -> Result<(), ListenerError> {
+        let authorities = X509::stack_from_pem(client_auth.ca_certificates.as_bytes())
+            .map_err(|err| ListenerError::PemParseError(err.to_string()))?;
+        if authorities.is_empty() {
+            return Err(ListenerError::BuildOpenSslError(String::from(
+                "no certificate authority found to verify the client certificates",
+            )));
+        }
+
+        for authority in authorities {
+            context
+                .cert_store_mut()
+                .add_cert(authority)
+                .map_err(|err| ListenerError::BuildOpenSslError(err.to_string()))?;
+        }
+
+        let revocation_list = match &client_auth.revocation_lists {
+            Some(pem) => RevocationList::from_pem(pem)
+                .map_err(|err| ListenerError::PemParseError(err.to_string()))?,
+            None => RevocationList::default(),
+        };
+
+        let mut mode = SslVerifyMode::PEER;
+        if client_auth.required {
+            mode |= SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+        }
+
+        // called for each certificate of the chain, after its verification by openssl
+        context.set_verify_callback(mode, move |verified, store| {
+            if !verified {
+                return false;
+            }
+
+            match store
+                .current_cert()
+                .and_then(|certificate| certificate.to_der().ok())
+            {
+                Some(certificate) if revocation_list.is_revoked(&certificate) => {
+                    incr!("tls.client_certificate.revoked");
+                    false
+                }
+                Some(_) => true,
+                None => false,
+            }
+        });
+
+        Ok(())
+    }
+
     #[allow(dead_code)]
+    /// staples the OCSP response of the certificate selected for the handshake
+    fn create_status_callback(
+        resolver: Arc<Mutex<GenericCertificateResolver>>,
+    ) -> impl Fn(&mut SslRef) -> Result<bool, ErrorStack> + 'static + Sync + Send {
+        move |ssl: &mut SslRef| {
+            let fingerprint = match ssl.certificate() {
+                Some(certificate) => certificate.digest(MessageDigest::sha256())?,
+                None => return Ok(false),
+            };
+
+            let ocsp_response = unwrap_msg!(resolver.lock())
+                .get_certificate(&CertificateFingerprint(fingerprint.to_vec()))
+                .and_then(|certificate_and_key| certificate_and_key.ocsp_response);
+
+            match ocsp_response {
+                Some(ocsp_response) => {
+                    ssl.set_ocsp_status(&ocsp_response)?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        }
+    }
+
     fn create_servername_callback(
         resolver: Arc<Mutex<GenericCertificateResolver>>,
         contexts: Arc<Mutex<HashMap<CertificateFingerprint, SslContext>>>,
@@ -1845,34 +2429,36 @@ impl Listener {
             let contexts = unwrap_msg!(contexts.lock());
 
             trace!("ref: {:?}", ssl);
-            if let Some(servername) = ssl.servername(NameType::HOST_NAME).map(|s| s.to_string()) {
-                debug!("looking for fingerprint for {:?}", servername);
-                if let Some(kv) = resolver.domain_lookup(servername.as_bytes(), true) {
-                    debug!(
-                        "looking for context for {:?} with fingerprint {:?}",
-                        servername, kv.1
-                    );
-                    if let Some(context) = contexts.get(&kv.1) {
-                        debug!("found context for {:?}", servername);
+            let servername = ssl.servername(NameType::HOST_NAME).map(|s| s.to_string());
+            debug!("looking for fingerprint for {:?}", servername);
+            match servername
+                .as_deref()
+                .and_then(|name| resolver.domain_lookup(name.as_bytes(), true))
+            {
+                Some(_) => incr!("tls.sni.hit"),
+                None => incr!("tls.sni.miss"),
+            }
+            if let Some(fingerprint) = resolver.resolve(servername.as_deref().map(str::as_bytes)) {
+                debug!(
+                    "looking for context for {:?} with fingerprint {:?}",
+                    servername, fingerprint
+                );
+                if let Some(context) = contexts.get(fingerprint) {
+                    debug!("found context for {:?}", servername);
 
-                        if let Ok(()) = ssl.set_ssl_context(context) {
-                            debug!(
-                                "servername is now {:?}",
-                                ssl.servername(NameType::HOST_NAME)
-                            );
+                    if let Ok(()) = ssl.set_ssl_context(context) {
+                        debug!(
+                            "servername is now {:?}",
+                            ssl.servername(NameType::HOST_NAME)
+                        );
 
-                            return Ok(());
-                        } else {
-                            error!("could not set context for {:?}", servername);
-                        }
+                        return Ok(());
                     } else {
-                        error!("no context found for {:?}", servername);
+                        error!("could not set context for {:?}", servername);
                     }
                 } else {
-                    //error!("unrecognized server name: {}", servername);
+                    error!("no context found for {:?}", servername);
                 }
-            } else {
-                //error!("no server name information found");
             }
 
             incr!("openssl.sni.error");
@@ -1894,16 +2480,24 @@ impl Listener {
                 ssl.version_str()
             );
 
+            let resolver = unwrap_msg!(resolver.lock());
+            let contexts = unwrap_msg!(contexts.lock());
+
             let server_name_opt = get_server_name(ssl).and_then(parse_sni_name_list);
-            // no SNI extension, use the default context
+            // no SNI extension, use the default certificate, or the default context
             if server_name_opt.is_none() {
+                incr!("tls.sni.miss");
+                if resolver.strict_sni {
+                    incr!("tls.strict_sni.rejected");
+                    *alert = SslAlert::UNRECOGNIZED_NAME;
+                    return Err(ErrorStack::get());
+                }
+                Self::set_default_certificate_context(ssl, &resolver, &contexts);
                 *alert = SslAlert::UNRECOGNIZED_NAME;
                 return Ok(ssl::ClientHelloResponse::SUCCESS);
             }
 
             let mut sni_names = server_name_opt.unwrap();
-            let resolver = unwrap_msg!(resolver.lock());
-            let contexts = unwrap_msg!(contexts.lock());
 
             while !sni_names.is_empty() {
                 debug!("name list:\n{}", sni_names.to_hex(16));
@@ -1924,6 +2518,7 @@ impl Listener {
                                 if let Ok(()) = ssl.set_ssl_context(context) {
                                     ssl_set_options(ssl, context);
 
+                                    incr!("tls.sni.hit");
                                     return Ok(ssl::ClientHelloResponse::SUCCESS);
                                 } else {
                                     error!("could not set context for {:?}", servername);
@@ -1941,12 +2536,48 @@ impl Listener {
                 }
             }
 
+            incr!("tls.sni.miss");
             *alert = SslAlert::UNRECOGNIZED_NAME;
+            if resolver.strict_sni {
+                incr!("tls.strict_sni.rejected");
+                return Err(ErrorStack::get());
+            }
+            Self::set_default_certificate_context(ssl, &resolver, &contexts);
             Ok::<ssl::ClientHelloResponse, ErrorStack>(ssl::ClientHelloResponse::SUCCESS)
         }
     }
 
+    /// switches the handshake to the context of the default certificate of the listener,
+    /// if there is one
+    fn set_default_certificate_context(
+        ssl: &mut SslRef,
+        resolver: &GenericCertificateResolver,
+        contexts: &HashMap<CertificateFingerprint, SslContext>,
+    ) {
+        if let Some(context) = resolver
+            .default_certificate
+            .as_ref()
+            .and_then(|fingerprint| contexts.get(fingerprint))
+        {
+            if let Ok(()) = ssl.set_ssl_context(context) {
+                ssl_set_options(ssl, context);
+            } else {
+                error!("could not set the context of the default certificate");
+            }
+        }
+    }
+
     pub fn add_https_front(&mut self, tls_front: HttpFrontend) -> bool {
+        if let Route::Deny {
+            body_path: Some(body_path),
+            ..
+        } = &tls_front.route
+        {
+            if !self.answers.borrow_mut().load_deny_body(body_path) {
+                return false;
+            }
+        }
+
         self.fronts.add_http_front(tls_front)
     }
 
@@ -1956,7 +2587,17 @@ impl Listener {
     }
 
     // ToDo factor out with http.rs
-    pub fn frontend_from_request(&self, host: &str, uri: &str, method: &Method) -> Option<Route> {
+    pub fn has_header_rules(&self) -> bool {
+        self.fronts.has_header_rules()
+    }
+
+    pub fn frontend_from_request(
+        &self,
+        host: &str,
+        uri: &str,
+        method: &Method,
+        headers: &[Header],
+    ) -> Option<RouteResult> {
         let host: &str = if let Ok((i, (hostname, _))) = hostname_and_port(host.as_bytes()) {
             if i != &b""[..] {
                 error!(
@@ -1975,20 +2616,19 @@ impl Listener {
             return None;
         };
 
-        self.fronts.lookup(host.as_bytes(), uri.as_bytes(), method)
+        self.fronts
+            .lookup_frontend(host.as_bytes(), uri.as_bytes(), method, headers)
+            .or_else(|| {
+                self.config
+                    .fallback_cluster
+                    .as_deref()
+                    .map(RouteResult::for_cluster)
+            })
     }
 
     fn accept(&mut self) -> Result<TcpStream, AcceptError> {
         if let Some(ref sock) = self.listener {
-            sock.accept()
-                .map_err(|e| match e.kind() {
-                    ErrorKind::WouldBlock => AcceptError::WouldBlock,
-                    _ => {
-                        error!("accept() IO error: {:?}", e);
-                        AcceptError::IoError
-                    }
-                })
-                .map(|(sock, _)| sock)
+            self.acls.accept(sock)
         } else {
             error!("cannot accept connections, no listening socket available");
             Err(AcceptError::IoError)
@@ -1999,10 +2639,32 @@ impl Listener {
 pub struct Proxy {
     listeners: HashMap<Token, Rc<RefCell<Listener>>>,
     clusters: HashMap<ClusterId, Cluster>,
+    maintenance: HashMap<ClusterId, Option<Rc<Vec<u8>>>>,
     backends: Rc<RefCell<BackendMap>>,
     pool: Rc<RefCell<Pool>>,
     registry: Registry,
     sessions: Rc<RefCell<SessionManager>>,
+    /// session ticket key shared by the workers. OpenSSL takes a single key,
+    /// the tickets encrypted with the previous keys are not accepted anymore
+    ticket_key: Option<Vec<u8>>,
+}
+
+impl HttpProxy for Proxy {
+    fn clusters(&self) -> &HashMap<ClusterId, Cluster> {
+        &self.clusters
+    }
+
+    fn backends(&self) -> &Rc<RefCell<BackendMap>> {
+        &self.backends
+    }
+
+    fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    fn sessions(&self) -> &Rc<RefCell<SessionManager>> {
+        &self.sessions
+    }
 }
 
 impl Proxy {
@@ -2015,25 +2677,56 @@ impl Proxy {
         Proxy {
             listeners: HashMap::new(),
             clusters: HashMap::new(),
+            maintenance: HashMap::new(),
             backends,
             pool,
             registry,
             sessions,
+            ticket_key: None,
         }
     }
 
     pub fn add_listener(&mut self, config: HttpsListener, token: Token) -> Option<Token> {
         match self.listeners.entry(token) {
             Entry::Vacant(entry) => {
-                entry.insert(Rc::new(RefCell::new(
-                    Listener::try_new(config, token).ok()?,
-                )));
+                let listener = Listener::try_new(config, token).ok()?;
+                if let Some(key) = &self.ticket_key {
+                    if let Err(e) = listener.set_ticket_key(key) {
+                        error!("{}", e);
+                    }
+                }
+                entry.insert(Rc::new(RefCell::new(listener)));
                 Some(token)
             }
             _ => None,
         }
     }
 
+    pub fn set_ticket_keys(
+        &mut self,
+        set_ticket_keys: &SetTicketKeys,
+    ) -> Result<(), ListenerError> {
+        let key = match set_ticket_keys.keys.first() {
+            Some(key) => {
+                hex::decode(key).map_err(|err| ListenerError::TicketKeyError(err.to_string()))?
+            }
+            None => return Ok(()),
+        };
+        if key.len() != TICKET_KEY_LENGTH {
+            return Err(ListenerError::TicketKeyError(format!(
+                "expected {} bytes, got {}",
+                TICKET_KEY_LENGTH,
+                key.len()
+            )));
+        }
+
+        for listener in self.listeners.values() {
+            listener.borrow().set_ticket_key(&key)?;
+        }
+        self.ticket_key = Some(key);
+        Ok(())
+    }
+
     pub fn remove_listener(&mut self, address: SocketAddr) -> bool {
         let len = self.listeners.len();
 
@@ -2058,6 +2751,7 @@ impl Proxy {
             .filter_map(|listener| {
                 let mut owned = listener.borrow_mut();
                 if let Some(listener) = owned.listener.take() {
+                    owned.active = false;
                     return Some((owned.address, listener));
                 }
 
@@ -2073,10 +2767,10 @@ impl Proxy {
             .and_then(|listener| {
                 let mut owned = listener.borrow_mut();
 
-                owned
-                    .listener
-                    .take()
-                    .map(|listener| (owned.token, listener))
+                let listener = owned.listener.take()?;
+                // the listener can be activated again, with another socket
+                owned.active = false;
+                Some((owned.token, listener))
             })
     }
 
@@ -2096,6 +2790,7 @@ impl Proxy {
 
     pub fn remove_cluster(&mut self, cluster_id: &str) {
         self.clusters.remove(cluster_id);
+        self.maintenance.remove(cluster_id);
         for listener in self.listeners.values() {
             listener
                 .borrow()
@@ -2104,6 +2799,17 @@ impl Proxy {
                 .remove_custom_answer(cluster_id);
         }
     }
+
+    pub fn set_cluster_maintenance(&mut self, maintenance: ClusterMaintenance) {
+        if maintenance.enabled {
+            let answer = maintenance
+                .answer
+                .map(|answer| Rc::new(answer.into_bytes()));
+            self.maintenance.insert(maintenance.cluster_id, answer);
+        } else {
+            self.maintenance.remove(&maintenance.cluster_id);
+        }
+    }
 }
 
 impl ProxyConfiguration<Session> for Proxy {
@@ -2171,12 +2877,20 @@ impl ProxyConfiguration<Session> for Proxy {
             wait_time,
             Duration::seconds(owned.config.front_timeout as i64),
             Duration::seconds(owned.config.back_timeout as i64),
-            Duration::seconds(owned.config.request_timeout as i64),
+            Duration::seconds(
+                owned
+                    .config
+                    .handshake_timeout
+                    .unwrap_or(owned.config.request_timeout) as i64,
+            ),
             listener.clone(),
         )));
-        entry.insert(session);
+        entry.insert(session.clone());
 
         session_manager.incr();
+        if session_manager.open_listener_session(Token(token.0), owned.config.max_sessions) {
+            session.borrow_mut().routing.counted_listener = Some(Token(token.0));
+        }
         Ok(())
     }
 
@@ -2193,6 +2907,11 @@ impl ProxyConfiguration<Session> for Proxy {
                 self.remove_cluster(&cluster_id);
                 ProxyResponse::ok(message.id)
             }
+            ProxyRequestOrder::SetClusterMaintenance(maintenance) => {
+                debug!("{} set cluster maintenance {:?}", message.id, maintenance);
+                self.set_cluster_maintenance(maintenance);
+                ProxyResponse::ok(message.id)
+            }
             ProxyRequestOrder::AddHttpsFrontend(front) => {
                 //info!("HTTPS\t{} add front {:?}", id, front);
                 if let Some(listener) = self
@@ -2263,6 +2982,46 @@ impl ProxyConfiguration<Session> for Proxy {
                     ProxyResponse::error(message.id, "unsupported message")
                 }
             }
+            ProxyRequestOrder::SetOcspResponse(set_ocsp_response) => {
+                if let Some(listener) = self
+                    .listeners
+                    .values()
+                    .find(|l| l.borrow().address == set_ocsp_response.address)
+                {
+                    match listener.borrow_mut().set_ocsp_response(&set_ocsp_response) {
+                        Ok(_) => ProxyResponse::ok(message.id),
+                        Err(err) => ProxyResponse::error(message.id, err),
+                    }
+                } else {
+                    error!("setting OCSP response to unknown listener");
+                    ProxyResponse::error(message.id, "unsupported message")
+                }
+            }
+            ProxyRequestOrder::SetTicketKeys(set_ticket_keys) => {
+                debug!("{} set ticket keys {:?}", message.id, set_ticket_keys);
+                match self.set_ticket_keys(&set_ticket_keys) {
+                    Ok(_) => ProxyResponse::ok(message.id),
+                    Err(err) => ProxyResponse::error(message.id, err),
+                }
+            }
+            ProxyRequestOrder::SetDefaultCertificate(set_default_certificate) => {
+                if let Some(listener) = self
+                    .listeners
+                    .values()
+                    .find(|l| l.borrow().address == set_default_certificate.address)
+                {
+                    match listener
+                        .borrow_mut()
+                        .set_default_certificate(&set_default_certificate)
+                    {
+                        Ok(_) => ProxyResponse::ok(message.id),
+                        Err(err) => ProxyResponse::error(message.id, err),
+                    }
+                } else {
+                    error!("setting default certificate to unknown listener");
+                    ProxyResponse::error(message.id, "unsupported message")
+                }
+            }
             ProxyRequestOrder::ReplaceCertificate(replace) => {
                 if let Some(listener) = self
                     .listeners
@@ -2278,6 +3037,44 @@ impl ProxyConfiguration<Session> for Proxy {
                     ProxyResponse::error(message.id, "unsupported message")
                 }
             }
+            ProxyRequestOrder::AddAcl(acl) => {
+                debug!("{} add ACL {:?}", message.id, acl);
+                match self
+                    .listeners
+                    .values()
+                    .find(|l| l.borrow().address == acl.address)
+                {
+                    Some(listener) => {
+                        listener.borrow_mut().acls.add(acl);
+                        ProxyResponse::ok(message.id)
+                    }
+                    None => ProxyResponse::error(
+                        message.id,
+                        format!("no HTTPS listener found for ACL: {:?}", acl),
+                    ),
+                }
+            }
+            ProxyRequestOrder::RemoveAcl(remove) => {
+                debug!("{} remove ACL {:?}", message.id, remove);
+                match self
+                    .listeners
+                    .values()
+                    .find(|l| l.borrow().address == remove.address)
+                {
+                    Some(listener)
+                        if listener
+                            .borrow_mut()
+                            .acls
+                            .remove(remove.hostname.as_deref()) =>
+                    {
+                        ProxyResponse::ok(message.id)
+                    }
+                    _ => ProxyResponse::error(
+                        message.id,
+                        format!("no ACL to remove on HTTPS listener: {:?}", remove),
+                    ),
+                }
+            }
             ProxyRequestOrder::RemoveListener(remove) => {
                 debug!("removing HTTPS listener at address {:?}", remove.address);
                 if !self.remove_listener(remove.address) {
@@ -2292,7 +3089,7 @@ impl ProxyConfiguration<Session> for Proxy {
                     ProxyResponse::ok(message.id)
                 }
             }
-            ProxyRequestOrder::SoftStop => {
+            ProxyRequestOrder::SoftStop(_) => {
                 info!("{} processing soft shutdown", message.id);
                 let listeners: HashMap<_, _> = self.listeners.drain().collect();
                 for (_, listener) in listeners.iter() {
@@ -2388,6 +3185,28 @@ impl ProxyConfiguration<Session> for Proxy {
                     ))),
                 }
             }
+            ProxyRequestOrder::Query(Query::Certificates(QueryCertificateType::Resolve(
+                resolve,
+            ))) => {
+                let res = self
+                    .listeners
+                    .values()
+                    .find(|listener| listener.borrow().address == resolve.address)
+                    .and_then(|listener| {
+                        let owned = listener.borrow();
+                        let resolver = unwrap_msg!(owned.resolver.lock());
+                        resolver
+                            .resolved_certificate(resolve.hostname.as_deref().map(str::as_bytes))
+                    });
+
+                ProxyResponse {
+                    id: message.id,
+                    status: ProxyResponseStatus::Ok,
+                    content: Some(ProxyResponseContent::Query(QueryAnswer::Certificates(
+                        QueryAnswerCertificate::Resolve(res),
+                    ))),
+                }
+            }
             command => {
                 error!(
                     "{} unsupported message for OpenSSL proxy, ignoring {:?}",
@@ -2494,10 +3313,14 @@ pub fn start(
         .registry()
         .try_clone()
         .with_context(|| "Failed at creating a registry")?;
-    let mut configuration = Proxy::new(registry, sessions.clone(), pool.clone(), backends.clone());
+    let mut https = HttpsProvider::new(registry, sessions.clone(), pool.clone(), backends.clone());
     let address = config.address;
-    if configuration.add_listener(config, token).is_some()
-        && configuration.activate_listener(&address, None).is_some()
+    let config = HttpsListener {
+        tls_provider: TlsProvider::Openssl,
+        ..config
+    };
+    if https.add_listener(config, token).is_some()
+        && https.activate_listener(&address, None).is_some()
     {
         let (scm_server, _scm_client) =
             UnixStream::pair().with_context(|| "Failed at creating scm stream sockets")?;
@@ -2511,7 +3334,7 @@ pub fn start(
             pool,
             backends,
             None,
-            Some(HttpsProvider::Openssl(Rc::new(RefCell::new(configuration)))),
+            Some(https),
             None,
             server_config,
             None,
@@ -2530,7 +3353,9 @@ pub fn start(
 mod tests {
     extern crate tiny_http;
     use super::*;
-    use crate::router::{trie::TrieNode, MethodRule, PathRule, Router};
+    use crate::router::{
+        trie::TrieNode, FrontendActions, HeaderRules, MethodRule, PathRule, Router,
+    };
     use crate::sozu_command::proxy::Route;
     use openssl::ssl::{SslContext, SslMethod};
     use std::collections::HashMap;
@@ -2570,24 +3395,32 @@ mod tests {
             "lolcatho.st".as_bytes(),
             PathRule::Prefix(uri1),
             MethodRule::new(None),
+            HeaderRules::default(),
+            FrontendActions::default(),
             Route::ClusterId(cluster_id1.clone())
         ));
         assert!(fronts.add_tree_rule(
             "lolcatho.st".as_bytes(),
             PathRule::Prefix(uri2),
             MethodRule::new(None),
+            HeaderRules::default(),
+            FrontendActions::default(),
             Route::ClusterId(cluster_id2)
         ));
         assert!(fronts.add_tree_rule(
             "lolcatho.st".as_bytes(),
             PathRule::Prefix(uri3),
             MethodRule::new(None),
+            HeaderRules::default(),
+            FrontendActions::default(),
             Route::ClusterId(cluster_id3)
         ));
         assert!(fronts.add_tree_rule(
             "other.domain".as_bytes(),
             PathRule::Prefix("test".to_string()),
             MethodRule::new(None),
+            HeaderRules::default(),
+            FrontendActions::default(),
             Route::ClusterId(cluster_id1)
         ));
 
@@ -2618,34 +3451,37 @@ mod tests {
             token: Token(0),
             active: true,
             tags: BTreeMap::new(),
+            acls: AccessLists::default(),
         };
 
         println!("TEST {}", line!());
-        let frontend1 = listener.frontend_from_request("lolcatho.st", "/", &Method::Get);
+        let frontend1 = listener.frontend_from_request("lolcatho.st", "/", &Method::Get, &[]);
         assert_eq!(
-            frontend1.expect("should find a frontend"),
+            frontend1.expect("should find a frontend").route,
             Route::ClusterId("cluster_1".to_string())
         );
         println!("TEST {}", line!());
-        let frontend2 = listener.frontend_from_request("lolcatho.st", "/test", &Method::Get);
+        let frontend2 = listener.frontend_from_request("lolcatho.st", "/test", &Method::Get, &[]);
         assert_eq!(
-            frontend2.expect("should find a frontend"),
+            frontend2.expect("should find a frontend").route,
             Route::ClusterId("cluster_1".to_string())
         );
         println!("TEST {}", line!());
-        let frontend3 = listener.frontend_from_request("lolcatho.st", "/yolo/test", &Method::Get);
+        let frontend3 =
+            listener.frontend_from_request("lolcatho.st", "/yolo/test", &Method::Get, &[]);
         assert_eq!(
-            frontend3.expect("should find a frontend"),
+            frontend3.expect("should find a frontend").route,
             Route::ClusterId("cluster_2".to_string())
         );
         println!("TEST {}", line!());
-        let frontend4 = listener.frontend_from_request("lolcatho.st", "/yolo/swag", &Method::Get);
+        let frontend4 =
+            listener.frontend_from_request("lolcatho.st", "/yolo/swag", &Method::Get, &[]);
         assert_eq!(
-            frontend4.expect("should find a frontend"),
+            frontend4.expect("should find a frontend").route,
             Route::ClusterId("cluster_3".to_string())
         );
         println!("TEST {}", line!());
-        let frontend5 = listener.frontend_from_request("domain", "/", &Method::Get);
+        let frontend5 = listener.frontend_from_request("domain", "/", &Method::Get, &[]);
         assert_eq!(frontend5, None);
         // assert!(false);
     }
@@ -2756,6 +3592,21 @@ fn get_server_name<'a, 'b>(ssl: &'a SslRef) -> Option<&'b [u8]> {
     }
 }
 
+/// from ssl.h, the key is 16 bytes of name, then 32 bytes of HMAC secret
+/// and 32 bytes of AES secret since OpenSSL 1.1.0
+const SSL_CTRL_SET_TLSEXT_TICKET_KEYS: libc::c_int = 59;
+
+fn set_ticket_key(context: &SslContext, key: &[u8]) -> bool {
+    unsafe {
+        openssl_sys::SSL_CTX_ctrl(
+            context.as_ptr(),
+            SSL_CTRL_SET_TLSEXT_TICKET_KEYS,
+            key.len() as libc::c_long,
+            key.as_ptr() as *mut libc::c_void,
+        ) == 1
+    }
+}
+
 fn ssl_set_options(ssl: &mut SslRef, context: &SslContext) {
     unsafe {
         let options = openssl_sys::SSL_CTX_get_options(context.as_ptr());
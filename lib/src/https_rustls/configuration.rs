@@ -1,7 +1,6 @@
 use std::{
     cell::RefCell,
     collections::{hash_map::Entry, BTreeMap, HashMap},
-    io::ErrorKind,
     net::SocketAddr,
     os::unix::io::AsRawFd,
     rc::Rc,
@@ -16,13 +15,18 @@ use slab::Slab;
 use time::Duration;
 
 use crate::{
+    acl::AccessLists,
     backends::BackendMap,
     pool::Pool,
-    protocol::http::{
-        answers::HttpAnswers,
-        parser::{hostname_and_port, Method},
+    protocol::{
+        h2::ALPN_H2,
+        http::{
+            answers::HttpAnswers,
+            parser::{hostname_and_port, Header, Method},
+            session::HttpProxy,
+        },
     },
-    router::Router,
+    router::{RouteResult, Router},
     server::{
         self, HttpsProvider, ListenSession, ListenToken, ProxyChannel, Server, SessionManager,
         SessionToken,
@@ -31,16 +35,20 @@ use crate::{
     sozu_command::{
         logging,
         proxy::{
-            AddCertificate, CertificateFingerprint, Cluster, HttpFrontend, HttpsListener,
-            ProxyRequest, ProxyRequestOrder, ProxyResponse, ProxyResponseContent,
-            ProxyResponseStatus, Query, QueryAnswer, QueryAnswerCertificate, QueryCertificateType,
-            RemoveCertificate, Route, TlsVersion,
+            AddCertificate, CertificateFingerprint, Cluster, ClusterMaintenance, Compression,
+            Http2Settings, HttpFrontend, HttpsListener, PathNormalization, ProxyRequest,
+            ProxyRequestOrder, ProxyResponse, ProxyResponseContent, ProxyResponseStatus, Query,
+            QueryAnswer, QueryAnswerCertificate, QueryCertificateType, RemoveCertificate,
+            RequestLimits, Route, SecurityHeaders, SetDefaultCertificate, SetOcspResponse,
+            SetTicketKeys, TlsVersion,
         },
         scm_socket::ScmSocket,
     },
     tls::{
-        CertificateResolver, GenericCertificateResolverError, MutexWrappedCertificateResolver,
-        ParsedCertificateAndKey,
+        CertificateResolver, ClientCertificateVerifier, DelegatedCertificateResolver,
+        DelegatedCertificateResolvers, DelegatingCertificateResolver,
+        GenericCertificateResolverError, MutexWrappedCertificateResolver, ParsedCertificateAndKey,
+        SharedTicketer, TicketKeyError,
     },
     util::UnwrapLog,
     ListenerHandler, {AcceptError, ClusterId, Protocol, ProxyConfiguration, ProxySession},
@@ -77,6 +85,7 @@ pub struct Listener {
     pub token: Token,
     active: bool,
     tags: BTreeMap<String, BTreeMap<String, String>>,
+    pub acls: AccessLists,
 }
 
 impl ListenerHandler for Listener {
@@ -94,6 +103,26 @@ impl ListenerHandler for Listener {
             None => self.tags.remove(&key),
         };
     }
+
+    fn get_request_limits(&self) -> RequestLimits {
+        self.config.request_limits
+    }
+
+    fn get_compression(&self) -> Compression {
+        self.config.compression
+    }
+
+    fn get_security_headers(&self) -> SecurityHeaders {
+        (*self.config.security_headers).clone()
+    }
+
+    fn get_path_normalization(&self) -> PathNormalization {
+        self.config.path_normalization
+    }
+
+    fn get_http2_settings(&self) -> Http2Settings {
+        self.config.http2
+    }
 }
 
 impl CertificateResolver for Listener {
@@ -139,10 +168,39 @@ impl CertificateResolver for Listener {
             .remove_certificate(opts)
             .map_err(ListenerError::ResolverError)
     }
+
+    fn set_ocsp_response(&mut self, opts: &SetOcspResponse) -> Result<(), Self::Error> {
+        let mut resolver = self
+            .resolver
+            .0
+            .lock()
+            .map_err(|err| ListenerError::LockError(err.to_string()))?;
+
+        resolver
+            .set_ocsp_response(opts)
+            .map_err(ListenerError::ResolverError)
+    }
+
+    fn set_default_certificate(&mut self, opts: &SetDefaultCertificate) -> Result<(), Self::Error> {
+        let mut resolver = self
+            .resolver
+            .0
+            .lock()
+            .map_err(|err| ListenerError::LockError(err.to_string()))?;
+
+        resolver
+            .set_default_certificate(opts)
+            .map_err(ListenerError::ResolverError)
+    }
 }
 
 impl Listener {
-    pub fn new(config: HttpsListener, token: Token) -> Result<Listener, rustls::Error> {
+    pub fn new(
+        config: HttpsListener,
+        token: Token,
+        ticketer: Arc<SharedTicketer>,
+        delegated_resolver: Option<Arc<dyn DelegatedCertificateResolver>>,
+    ) -> Result<Listener, rustls::Error> {
         let server_config = ServerConfig::builder();
         let server_config = if !config.cipher_list.is_empty() {
             let mut ciphers = Vec::new();
@@ -188,9 +246,30 @@ impl Listener {
             }
         }
         let server_config = server_config.with_protocol_versions(&versions[..])?;
-        let server_config = server_config.with_no_client_auth();
+        let server_config = match &config.client_auth {
+            Some(client_auth) => {
+                let verifier = ClientCertificateVerifier::new(client_auth)
+                    .map_err(|err| rustls::Error::General(err.to_string()))?;
+                server_config.with_client_cert_verifier(Arc::new(verifier))
+            }
+            None => server_config.with_no_client_auth(),
+        };
         let resolver = Arc::new(MutexWrappedCertificateResolver::new());
-        let server_config = server_config.with_cert_resolver(resolver.clone());
+        // the certificates are added after the listener, the default one is not checked here
+        if let Ok(mut generic_resolver) = resolver.0.lock() {
+            generic_resolver.default_certificate = config.default_certificate.to_owned();
+            generic_resolver.strict_sni = config.strict_sni;
+        }
+        let mut server_config = match delegated_resolver {
+            Some(delegated) => server_config.with_cert_resolver(Arc::new(
+                DelegatingCertificateResolver::new(delegated, resolver.clone()),
+            )),
+            None => server_config.with_cert_resolver(resolver.clone()),
+        };
+        server_config.ticketer = ticketer;
+        if config.http2.enabled {
+            server_config.alpn_protocols = vec![ALPN_H2.to_vec(), b"http/1.1".to_vec()];
+        }
 
         Ok(Listener {
             address: config.address,
@@ -206,6 +285,7 @@ impl Listener {
             token,
             active: false,
             tags: BTreeMap::new(),
+            acls: AccessLists::default(),
         })
     }
 
@@ -243,6 +323,16 @@ impl Listener {
     }
 
     pub fn add_https_front(&mut self, tls_front: HttpFrontend) -> bool {
+        if let Route::Deny {
+            body_path: Some(body_path),
+            ..
+        } = &tls_front.route
+        {
+            if !self.answers.borrow_mut().load_deny_body(body_path) {
+                return false;
+            }
+        }
+
         self.fronts.add_http_front(tls_front)
     }
 
@@ -253,23 +343,24 @@ impl Listener {
 
     fn accept(&mut self) -> Result<TcpStream, AcceptError> {
         if let Some(listener) = self.listener.as_ref() {
-            listener
-                .accept()
-                .map_err(|e| match e.kind() {
-                    ErrorKind::WouldBlock => AcceptError::WouldBlock,
-                    _ => {
-                        error!("accept() IO error: {:?}", e);
-                        AcceptError::IoError
-                    }
-                })
-                .map(|(frontend_sock, _)| frontend_sock)
+            self.acls.accept(listener)
         } else {
             Err(AcceptError::IoError)
         }
     }
 
     // ToDo factor out with http.rs
-    pub fn frontend_from_request(&self, host: &str, uri: &str, method: &Method) -> Option<Route> {
+    pub fn has_header_rules(&self) -> bool {
+        self.fronts.has_header_rules()
+    }
+
+    pub fn frontend_from_request(
+        &self,
+        host: &str,
+        uri: &str,
+        method: &Method,
+        headers: &[Header],
+    ) -> Option<RouteResult> {
         let host: &str = if let Ok((i, (hostname, _))) = hostname_and_port(host.as_bytes()) {
             if i != &b""[..] {
                 error!("invalid remaining chars after hostname");
@@ -285,17 +376,47 @@ impl Listener {
             return None;
         };
 
-        self.fronts.lookup(host.as_bytes(), uri.as_bytes(), method)
+        self.fronts
+            .lookup_frontend(host.as_bytes(), uri.as_bytes(), method, headers)
+            .or_else(|| {
+                self.config
+                    .fallback_cluster
+                    .as_deref()
+                    .map(RouteResult::for_cluster)
+            })
     }
 }
 
 pub struct Proxy {
     pub listeners: HashMap<Token, Rc<RefCell<Listener>>>,
     pub clusters: HashMap<ClusterId, Cluster>,
+    pub maintenance: HashMap<ClusterId, Option<Rc<Vec<u8>>>>,
     pub backends: Rc<RefCell<BackendMap>>,
     pool: Rc<RefCell<Pool>>,
     pub registry: Registry,
     pub sessions: Rc<RefCell<SessionManager>>,
+    /// session ticket keys shared by the listeners
+    ticketer: Arc<SharedTicketer>,
+    /// certificate resolvers supplied by the embedder, by listener address
+    pub delegated_resolvers: DelegatedCertificateResolvers,
+}
+
+impl HttpProxy for Proxy {
+    fn clusters(&self) -> &HashMap<ClusterId, Cluster> {
+        &self.clusters
+    }
+
+    fn backends(&self) -> &Rc<RefCell<BackendMap>> {
+        &self.backends
+    }
+
+    fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    fn sessions(&self) -> &Rc<RefCell<SessionManager>> {
+        &self.sessions
+    }
 }
 
 impl Proxy {
@@ -308,13 +429,26 @@ impl Proxy {
         Proxy {
             listeners: HashMap::new(),
             clusters: HashMap::new(),
+            maintenance: HashMap::new(),
             backends,
             pool,
             registry,
             sessions,
+            ticketer: Arc::new(SharedTicketer::default()),
+            delegated_resolvers: HashMap::new(),
         }
     }
 
+    /// the listener created on this address will present the certificates
+    /// of the delegated resolver
+    pub fn set_delegated_resolver(
+        &mut self,
+        address: SocketAddr,
+        resolver: Arc<dyn DelegatedCertificateResolver>,
+    ) {
+        self.delegated_resolvers.insert(address, resolver);
+    }
+
     pub fn add_listener(
         &mut self,
         config: HttpsListener,
@@ -322,7 +456,13 @@ impl Proxy {
     ) -> Result<Option<Token>, rustls::Error> {
         match self.listeners.entry(token) {
             Entry::Vacant(entry) => {
-                entry.insert(Rc::new(RefCell::new(Listener::new(config, token)?)));
+                let delegated_resolver = self.delegated_resolvers.get(&config.address).cloned();
+                entry.insert(Rc::new(RefCell::new(Listener::new(
+                    config,
+                    token,
+                    self.ticketer.clone(),
+                    delegated_resolver,
+                )?)));
                 Ok(Some(token))
             }
             _ => Ok(None),
@@ -353,6 +493,7 @@ impl Proxy {
             .filter_map(|listener| {
                 let mut owned = listener.borrow_mut();
                 if let Some(listener) = owned.listener.take() {
+                    owned.active = false;
                     return Some((owned.address, listener));
                 }
 
@@ -368,10 +509,10 @@ impl Proxy {
             .and_then(|listener| {
                 let mut owned = listener.borrow_mut();
 
-                owned
-                    .listener
-                    .take()
-                    .map(|listener| (owned.token, listener))
+                let listener = owned.listener.take()?;
+                // the listener can be activated again, with another socket
+                owned.active = false;
+                Some((owned.token, listener))
             })
     }
 
@@ -391,6 +532,7 @@ impl Proxy {
 
     pub fn remove_cluster(&mut self, cluster_id: &str) {
         self.clusters.remove(cluster_id);
+        self.maintenance.remove(cluster_id);
 
         for listener in self.listeners.values() {
             listener
@@ -400,6 +542,24 @@ impl Proxy {
                 .remove_custom_answer(cluster_id);
         }
     }
+
+    pub fn set_ticket_keys(
+        &mut self,
+        set_ticket_keys: &SetTicketKeys,
+    ) -> Result<(), TicketKeyError> {
+        self.ticketer.set_keys(set_ticket_keys)
+    }
+
+    pub fn set_cluster_maintenance(&mut self, maintenance: ClusterMaintenance) {
+        if maintenance.enabled {
+            let answer = maintenance
+                .answer
+                .map(|answer| Rc::new(answer.into_bytes()));
+            self.maintenance.insert(maintenance.cluster_id, answer);
+        } else {
+            self.maintenance.remove(&maintenance.cluster_id);
+        }
+    }
 }
 
 impl ProxyConfiguration<Session> for Proxy {
@@ -469,12 +629,20 @@ impl ProxyConfiguration<Session> for Proxy {
             wait_time,
             Duration::seconds(owned.config.front_timeout as i64),
             Duration::seconds(owned.config.back_timeout as i64),
-            Duration::seconds(owned.config.request_timeout as i64),
+            Duration::seconds(
+                owned
+                    .config
+                    .handshake_timeout
+                    .unwrap_or(owned.config.request_timeout) as i64,
+            ),
             listener.clone(),
         )));
-        entry.insert(session);
+        entry.insert(session.clone());
 
         session_manager.incr();
+        if session_manager.open_listener_session(Token(token.0), owned.config.max_sessions) {
+            session.borrow_mut().routing.counted_listener = Some(Token(token.0));
+        }
         Ok(())
     }
 
@@ -491,6 +659,11 @@ impl ProxyConfiguration<Session> for Proxy {
                 self.remove_cluster(&cluster_id);
                 ProxyResponse::ok(message.id)
             }
+            ProxyRequestOrder::SetClusterMaintenance(maintenance) => {
+                debug!("{} set cluster maintenance {:?}", message.id, maintenance);
+                self.set_cluster_maintenance(maintenance);
+                ProxyResponse::ok(message.id)
+            }
             ProxyRequestOrder::AddHttpsFrontend(front) => {
                 //info!("HTTPS\t{} add front {:?}", id, front);
                 if let Some(listener) = self
@@ -584,6 +757,84 @@ impl ProxyConfiguration<Session> for Proxy {
                     ProxyResponse::error(message.id, "unsupported message")
                 }
             }
+            ProxyRequestOrder::SetOcspResponse(set_ocsp_response) => {
+                if let Some(listener) = self
+                    .listeners
+                    .values()
+                    .find(|l| l.borrow().address == set_ocsp_response.address)
+                {
+                    match listener.borrow_mut().set_ocsp_response(&set_ocsp_response) {
+                        Ok(_) => ProxyResponse::ok(message.id),
+                        Err(err) => ProxyResponse::error(message.id, err),
+                    }
+                } else {
+                    error!("setting OCSP response to unknown listener");
+                    ProxyResponse::error(message.id, "unsupported message")
+                }
+            }
+            ProxyRequestOrder::SetDefaultCertificate(set_default_certificate) => {
+                if let Some(listener) = self
+                    .listeners
+                    .values()
+                    .find(|l| l.borrow().address == set_default_certificate.address)
+                {
+                    match listener
+                        .borrow_mut()
+                        .set_default_certificate(&set_default_certificate)
+                    {
+                        Ok(_) => ProxyResponse::ok(message.id),
+                        Err(err) => ProxyResponse::error(message.id, err),
+                    }
+                } else {
+                    error!("setting default certificate to unknown listener");
+                    ProxyResponse::error(message.id, "unsupported message")
+                }
+            }
+            ProxyRequestOrder::SetTicketKeys(set_ticket_keys) => {
+                debug!("{} set ticket keys {:?}", message.id, set_ticket_keys);
+                match self.set_ticket_keys(&set_ticket_keys) {
+                    Ok(_) => ProxyResponse::ok(message.id),
+                    Err(err) => ProxyResponse::error(message.id, err),
+                }
+            }
+            ProxyRequestOrder::AddAcl(acl) => {
+                debug!("{} add ACL {:?}", message.id, acl);
+                match self
+                    .listeners
+                    .values()
+                    .find(|l| l.borrow().address == acl.address)
+                {
+                    Some(listener) => {
+                        listener.borrow_mut().acls.add(acl);
+                        ProxyResponse::ok(message.id)
+                    }
+                    None => ProxyResponse::error(
+                        message.id,
+                        format!("no HTTPS listener found for ACL: {:?}", acl),
+                    ),
+                }
+            }
+            ProxyRequestOrder::RemoveAcl(remove) => {
+                debug!("{} remove ACL {:?}", message.id, remove);
+                match self
+                    .listeners
+                    .values()
+                    .find(|l| l.borrow().address == remove.address)
+                {
+                    Some(listener)
+                        if listener
+                            .borrow_mut()
+                            .acls
+                            .remove(remove.hostname.as_deref()) =>
+                    {
+                        ProxyResponse::ok(message.id)
+                    }
+                    _ => ProxyResponse::error(
+                        message.id,
+                        format!("no ACL to remove on HTTPS listener: {:?}", remove),
+                    ),
+                }
+            }
             ProxyRequestOrder::RemoveListener(remove) => {
                 debug!("removing HTTPS listener at address: {:?}", remove.address);
                 if !self.remove_listener(remove.address) {
@@ -598,7 +849,7 @@ impl ProxyConfiguration<Session> for Proxy {
                     ProxyResponse::ok(message.id)
                 }
             }
-            ProxyRequestOrder::SoftStop => {
+            ProxyRequestOrder::SoftStop(_) => {
                 info!("{} processing soft shutdown", message.id);
                 let listeners: HashMap<_, _> = self.listeners.drain().collect();
                 for (_, l) in listeners.iter() {
@@ -685,6 +936,28 @@ impl ProxyConfiguration<Session> for Proxy {
                     ))),
                 }
             }
+            ProxyRequestOrder::Query(Query::Certificates(QueryCertificateType::Resolve(
+                resolve,
+            ))) => {
+                let res = self
+                    .listeners
+                    .values()
+                    .find(|listener| listener.borrow().address == resolve.address)
+                    .and_then(|listener| {
+                        let owned = listener.borrow();
+                        let resolver = unwrap_msg!(owned.resolver.0.lock());
+                        resolver
+                            .resolved_certificate(resolve.hostname.as_deref().map(str::as_bytes))
+                    });
+
+                ProxyResponse {
+                    id: message.id,
+                    status: ProxyResponseStatus::Ok,
+                    content: Some(ProxyResponseContent::Query(QueryAnswer::Certificates(
+                        QueryAnswerCertificate::Resolve(res),
+                    ))),
+                }
+            }
             command => {
                 error!(
                     "{} unsupported message for rustls proxy, ignoring {:?}",
@@ -750,12 +1023,9 @@ pub fn start(
         .registry()
         .try_clone()
         .with_context(|| "Could not clone the mio registry")?;
-    let mut configuration = Proxy::new(registry, sessions.clone(), pool.clone(), backends.clone());
-    if configuration
-        .add_listener(config, token)
-        .with_context(|| "failed to create listener")?
-        .is_some()
-        && configuration.activate_listener(&address, None).is_some()
+    let mut https = HttpsProvider::new(registry, sessions.clone(), pool.clone(), backends.clone());
+    if https.add_listener(config, token).is_some()
+        && https.activate_listener(&address, None).is_some()
     {
         let (scm_server, _scm_client) = UnixStream::pair().unwrap();
         let server_config = server::ServerConfig {
@@ -771,7 +1041,7 @@ pub fn start(
             pool,
             backends,
             None,
-            Some(HttpsProvider::Rustls(Rc::new(RefCell::new(configuration)))),
+            Some(https),
             None,
             server_config,
             None,
@@ -17,9 +17,16 @@ use crate::{
     https_rustls::configuration::{Listener, Proxy},
     pool::Pool,
     protocol::{
+        h2::{BackendContext, Http2, Request, StreamRoute, ALPN_H2},
         http::{
-            answers::HttpAnswers,
-            parser::{hostname_and_port, Method, RequestLine, RequestState},
+            answers::{method_not_allowed_answer, redirect_answer, HttpAnswers},
+            cookies::find_request_cookie,
+            normalization::{normalize_uri, NormalizedUri},
+            parser::{
+                hostname_and_port, normalized_hostname, HeaderEdits, Method, RequestLine,
+                RequestState,
+            },
+            session::{HttpSession, RequestRouting},
             DefaultAnswerStatus,
         },
         proxy_protocol::expect::ExpectProxyProtocol,
@@ -27,17 +34,22 @@ use crate::{
         Http, Pipe, ProtocolResult, StickySession,
     },
     retry::RetryPolicy,
-    server::{push_event, CONN_RETRIES},
-    socket::FrontRustls,
+    router::{pick_ab_test_variant, pick_weighted_cluster, RouteResult},
+    server::push_event,
+    socket::{BackendSocket, FrontRustls, SocketHandler},
     sozu_command::{
-        proxy::{ProxyEvent, Route},
+        proxy::{
+            BackendProtocol, HashKey, HeaderPosition, LoadBalancingAlgorithms, ProxyEvent,
+            RetryCondition, Route, StickyMode,
+        },
         ready::Ready,
     },
     timer::TimeoutContainer,
+    tls::certificate_subject,
     util::UnwrapLog,
     {
-        Backend, BackendConnectAction, BackendConnectionStatus, ConnectionError, Protocol,
-        ProxySession, Readiness, SessionMetrics, SessionResult,
+        Backend, BackendConnectAction, BackendConnectionStatus, ConnectionError, ListenerHandler,
+        Protocol, ProxySession, Readiness, SessionMetrics, SessionResult,
     },
 };
 
@@ -45,6 +57,7 @@ pub enum State {
     Expect(ExpectProxyProtocol<TcpStream>, ServerConnection),
     Handshake(TlsHandshake),
     Http(Http<FrontRustls, Listener>),
+    Http2(Http2<FrontRustls, Listener>),
     WebSocket(Pipe<FrontRustls, Listener>),
 }
 
@@ -61,7 +74,7 @@ pub struct Session {
     sticky_name: String,
     last_event: Instant,
     pub listener_token: Token,
-    pub connection_attempt: u8,
+    pub routing: RequestRouting,
     peer_address: Option<SocketAddr>,
     answers: Rc<RefCell<HttpAnswers>>,
     front_timeout: TimeoutContainer,
@@ -85,7 +98,7 @@ impl Session {
         wait_time: Duration,
         frontend_timeout_duration: Duration,
         backend_timeout_duration: Duration,
-        request_timeout_duration: Duration,
+        handshake_timeout_duration: Duration,
         listener: Rc<RefCell<Listener>>,
     ) -> Session {
         let peer_address = if expect_proxy {
@@ -96,7 +109,7 @@ impl Session {
         };
 
         let request_id = Ulid::generate();
-        let front_timeout = TimeoutContainer::new(request_timeout_duration, token);
+        let front_timeout = TimeoutContainer::new(handshake_timeout_duration, token);
 
         let state = if expect_proxy {
             trace!("starting in expect proxy state");
@@ -125,7 +138,7 @@ impl Session {
             sticky_name,
             last_event: Instant::now(),
             listener_token,
-            connection_attempt: 0,
+            routing: RequestRouting::default(),
             peer_address,
             answers,
             front_timeout,
@@ -200,6 +213,11 @@ impl Session {
                 false
             }
             State::Handshake(handshake) => {
+                if handshake.session.alpn_protocol() == Some(ALPN_H2) {
+                    self.record_handshake(&handshake);
+                    return self.upgrade_http2(handshake);
+                }
+
                 let front_buf = self.pool.upgrade().and_then(|p| p.borrow_mut().checkout());
                 if front_buf.is_none() {
                     self.protocol = Some(State::Handshake(handshake));
@@ -207,18 +225,18 @@ impl Session {
                 }
 
                 let mut front_buf = front_buf.unwrap();
-                if let Some(version) = handshake.session.protocol_version() {
-                    incr!(version_str(version));
-                };
-                if let Some(cipher) = handshake.session.negotiated_cipher_suite() {
-                    incr!(ciphersuite_str(cipher));
-                };
+                self.record_handshake(&handshake);
 
                 let front_stream = FrontRustls {
                     stream: handshake.stream,
                     session: handshake.session,
                 };
 
+                // the request timeout applies once the handshake is done
+                let request_timeout = self.listener.borrow().config.request_timeout;
+                self.front_timeout
+                    .set_duration(Duration::seconds(request_timeout as i64));
+
                 let readiness = handshake.readiness.clone();
                 let mut http = Http::new(
                     front_stream,
@@ -267,6 +285,16 @@ impl Session {
                 let front_token = self.frontend_token;
                 let back_token = unwrap_msg!(http.back_token());
                 let ws_context = http.websocket_context();
+                let websocket = http.is_websocket_upgrade();
+                // the buffers can still hold the head of the request or response
+                let front_head = http
+                    .front_buf
+                    .as_ref()
+                    .map_or(0, BufferQueue::parsed_data_size);
+                let back_head = http
+                    .back_buf
+                    .as_ref()
+                    .map_or(0, BufferQueue::parsed_data_size);
 
                 let front_buf = match http.front_buf {
                     Some(buf) => buf.buffer,
@@ -316,14 +344,21 @@ impl Session {
 
                 pipe.front_readiness.event = http.front_readiness.event;
                 pipe.back_readiness.event = http.back_readiness.event;
-                http.front_timeout
-                    .set_duration(self.frontend_timeout_duration);
-                http.back_timeout
-                    .set_duration(self.backend_timeout_duration);
+                let websocket_timeout = self.websocket_timeout_duration().filter(|_| websocket);
+                http.front_timeout.set_duration(
+                    websocket_timeout.unwrap_or_else(|| self.front_timeout_duration()),
+                );
+                http.back_timeout.set_duration(
+                    websocket_timeout.unwrap_or_else(|| self.back_timeout_duration()),
+                );
                 pipe.front_timeout = Some(http.front_timeout);
                 pipe.back_timeout = Some(http.back_timeout);
                 pipe.set_back_token(back_token);
                 pipe.set_cluster_id(self.cluster_id.clone());
+                // the frames of the other protocols are not known
+                if websocket {
+                    pipe.track_websocket(front_head, back_head, self.websocket_drain());
+                }
 
                 gauge_add!("protocol.https", -1);
                 gauge_add!("protocol.wss", 1);
@@ -339,11 +374,126 @@ impl Session {
         }
     }
 
+    fn record_handshake(&self, handshake: &TlsHandshake) {
+        time!(
+            "tls.handshake.time",
+            (Instant::now() - handshake.started).whole_milliseconds()
+        );
+        if let Some(version) = handshake.session.protocol_version() {
+            incr!(version_str(version));
+        };
+        if let Some(cipher) = handshake.session.negotiated_cipher_suite() {
+            incr!(ciphersuite_str(cipher));
+        };
+    }
+
+    /// the client chose HTTP/2 in the handshake, its streams are forwarded
+    /// to the backends as HTTP/1.1 requests
+    fn upgrade_http2(&mut self, handshake: TlsHandshake) -> bool {
+        let readiness = handshake.readiness.clone();
+        let front_stream = FrontRustls {
+            stream: handshake.stream,
+            session: handshake.session,
+        };
+
+        // the connection is kept while it has streams, then closes after the front timeout
+        self.front_timeout
+            .set_duration(self.frontend_timeout_duration);
+
+        let mut h2 = Http2::new(
+            front_stream,
+            self.frontend_token,
+            self.public_address,
+            self.peer_address,
+            self.answers.clone(),
+            self.front_timeout.take(),
+            self.listener.clone(),
+        );
+
+        // the handshake may have decrypted the beginning of the connection already
+        let mut buffer = [0u8; 16384];
+        loop {
+            match h2.frontend.session.reader().read(&mut buffer) {
+                Ok(0) => break,
+                Ok(sz) => {
+                    h2.push_front_data(&buffer[..sz]);
+                    count!("bytes_in", sz as i64);
+                    self.metrics.bin += sz;
+                }
+                Err(e) => {
+                    if e.kind() != ErrorKind::WouldBlock {
+                        error!("read error: {:?}", e);
+                    }
+                    break;
+                }
+            }
+        }
+
+        gauge_add!("protocol.tls.handshake", -1);
+        gauge_add!("protocol.http2s", 1);
+        h2.front_readiness.event = readiness.event;
+        h2.front_readiness.event.insert(Ready::readable());
+
+        self.protocol = Some(State::Http2(h2));
+        true
+    }
+
+    /// runs the HTTP/2 connection, each new stream is routed like an HTTP/1.1 request
+    fn http2_ready(&mut self, session: Rc<RefCell<dyn ProxySession>>) -> SessionResult {
+        let mut h2 = match self.protocol.take() {
+            Some(State::Http2(h2)) => h2,
+            protocol => {
+                self.protocol = protocol;
+                return SessionResult::CloseSession;
+            }
+        };
+
+        let sni = h2.frontend.session.sni_hostname().map(|s| s.to_string());
+        let client_subject = h2
+            .frontend
+            .session
+            .peer_certificates()
+            .and_then(|certificates| certificates.first())
+            .and_then(|certificate| certificate_subject(&certificate.0));
+
+        let proxy = self.proxy.borrow();
+        let listener = self.listener.borrow();
+        let answers = self.answers.borrow();
+        let peer_address = self.peer_address;
+        let backend_timeout_duration = self.backend_timeout_duration;
+        let context = BackendContext {
+            backends: &proxy.backends,
+            registry: &proxy.registry,
+            sessions: &proxy.sessions,
+            session,
+        };
+
+        let result = h2.ready(&mut self.metrics, &context, |request| {
+            route_h2_request(
+                request,
+                &proxy,
+                &listener,
+                &answers,
+                sni.as_deref(),
+                peer_address,
+                client_subject.as_deref(),
+                backend_timeout_duration,
+            )
+        });
+        drop(context);
+        drop(answers);
+        drop(listener);
+        drop(proxy);
+
+        self.protocol = Some(State::Http2(h2));
+        result
+    }
+
     fn front_hup(&mut self) -> SessionResult {
         match *unwrap_msg!(self.protocol.as_mut()) {
             State::Http(ref mut http) => http.front_hup(),
             State::WebSocket(ref mut pipe) => pipe.front_hup(&mut self.metrics),
-            State::Handshake(_) => SessionResult::CloseSession,
+            State::Http2(_) | State::Handshake(_) => SessionResult::CloseSession,
             State::Expect(_, _) => SessionResult::CloseSession,
         }
     }
@@ -352,6 +502,8 @@ impl Session {
         match *unwrap_msg!(self.protocol.as_mut()) {
             State::Http(ref mut http) => http.back_hup(),
             State::WebSocket(ref mut pipe) => pipe.back_hup(&mut self.metrics),
+            // the backend connections of the streams are handled by the HTTP/2 state
+            State::Http2(_) => SessionResult::CloseSession,
             State::Handshake(_) => {
                 error!("why a backend HUP event while still in frontend handshake?");
                 SessionResult::CloseSession
@@ -391,6 +543,7 @@ impl Session {
             State::WebSocket(ref mut pipe) => {
                 (ProtocolResult::Continue, pipe.readable(&mut self.metrics))
             }
+            State::Http2(_) => return SessionResult::CloseSession,
         };
 
         if upgrade == ProtocolResult::Continue {
@@ -404,7 +557,7 @@ impl Session {
 
     fn writable(&mut self) -> SessionResult {
         let (upgrade, result) = match *unwrap_msg!(self.protocol.as_mut()) {
-            State::Expect(_, _) => return SessionResult::CloseSession,
+            State::Expect(_, _) | State::Http2(_) => return SessionResult::CloseSession,
             State::Handshake(ref mut handshake) => handshake.writable(),
             State::Http(ref mut http) => {
                 (ProtocolResult::Continue, http.writable(&mut self.metrics))
@@ -429,7 +582,7 @@ impl Session {
 
     fn back_readable(&mut self) -> SessionResult {
         let (upgrade, result) = match *unwrap_msg!(self.protocol.as_mut()) {
-            State::Expect(_, _) => return SessionResult::CloseSession,
+            State::Expect(_, _) | State::Http2(_) => return SessionResult::CloseSession,
             State::Http(ref mut http) => http.back_readable(&mut self.metrics),
             State::Handshake(_) => (ProtocolResult::Continue, SessionResult::CloseSession),
             State::WebSocket(ref mut pipe) => (
@@ -454,6 +607,7 @@ impl Session {
         match *unwrap_msg!(self.protocol.as_mut()) {
             State::Expect(_, _) => SessionResult::CloseSession,
             State::Handshake(_) => SessionResult::CloseSession,
+            State::Http2(_) => SessionResult::CloseSession,
             State::Http(ref mut http) => http.back_writable(&mut self.metrics),
             State::WebSocket(ref mut pipe) => pipe.back_writable(&mut self.metrics),
         }
@@ -464,6 +618,7 @@ impl Session {
             State::Expect(ref expect, _) => expect.front_socket(),
             State::Handshake(ref handshake) => &handshake.stream,
             State::Http(ref http) => http.front_socket(),
+            State::Http2(ref h2) => h2.front_socket(),
             State::WebSocket(ref pipe) => pipe.front_socket(),
         }
     }
@@ -473,6 +628,7 @@ impl Session {
             State::Expect(ref mut expect, _) => expect.front_socket_mut(),
             State::Handshake(ref mut handshake) => &mut handshake.stream,
             State::Http(ref mut http) => http.front_socket_mut(),
+            State::Http2(ref mut h2) => h2.front_socket_mut(),
             State::WebSocket(ref mut pipe) => pipe.front_socket_mut(),
         }
     }
@@ -481,6 +637,7 @@ impl Session {
         match unwrap_msg!(self.protocol.as_ref()) {
             State::Expect(_, _) => None,
             State::Handshake(_) => None,
+            State::Http2(_) => None,
             State::Http(ref http) => http.back_socket(),
             State::WebSocket(ref pipe) => pipe.back_socket(),
         }
@@ -490,6 +647,7 @@ impl Session {
         match unwrap_msg!(self.protocol.as_mut()) {
             State::Expect(_, _) => None,
             State::Handshake(_) => None,
+            State::Http2(_) => None,
             State::Http(ref mut http) => http.back_socket_mut(),
             State::WebSocket(ref mut pipe) => pipe.back_socket_mut(),
         }
@@ -499,12 +657,13 @@ impl Session {
         match unwrap_msg!(self.protocol.as_ref()) {
             State::Expect(_, _) => None,
             State::Handshake(_) => None,
+            State::Http2(_) => None,
             State::Http(ref http) => http.back_token(),
             State::WebSocket(ref pipe) => pipe.back_token(),
         }
     }
 
-    pub fn set_back_socket(&mut self, sock: TcpStream) {
+    pub fn set_back_socket(&mut self, sock: BackendSocket) {
         if let State::Http(ref mut http) = unwrap_msg!(self.protocol.as_mut()) {
             http.set_back_socket(sock, self.backend.clone())
         }
@@ -537,7 +696,7 @@ impl Session {
 
             // the back timeout was of connect_timeout duration before,
             // now that we're connected, move to backend_timeout duration
-            let t = self.backend_timeout_duration;
+            let t = self.back_timeout_duration();
             if let Some(h) = self.http_mut() {
                 h.set_back_timeout(t);
                 h.cancel_backend_timeout();
@@ -593,6 +752,7 @@ impl Session {
             State::Expect(ref mut expect, _) => &mut expect.readiness,
             State::Handshake(ref mut handshake) => &mut handshake.readiness,
             State::Http(ref mut http) => http.front_readiness(),
+            State::Http2(ref mut h2) => &mut h2.front_readiness,
             State::WebSocket(ref mut pipe) => &mut pipe.front_readiness,
         }
     }
@@ -612,6 +772,7 @@ impl Session {
 
             let already_unavailable = backend.retry_policy.is_down();
             backend.retry_policy.fail();
+            backend.record_outcome(self.cluster_id.as_deref(), true);
             incr!(
                 "connections.error",
                 self.cluster_id.as_deref(),
@@ -637,14 +798,19 @@ impl Session {
     }
 
     fn reset_connection_attempt(&mut self) {
-        self.connection_attempt = 0;
+        self.routing.connection_attempt = 0;
+        self.routing.tried_backends.clear();
     }
 
+    /// timeouts of the current backend, then of its cluster
     fn cancel_timeouts(&mut self) {
         self.front_timeout.cancel();
 
         match *unwrap_msg!(self.protocol.as_mut()) {
             State::Http(ref mut http) => http.cancel_timeouts(),
+            State::Http2(ref mut h2) => {
+                h2.front_timeout.cancel();
+            }
             State::WebSocket(ref mut pipe) => pipe.cancel_timeouts(),
             _ => {}
         }
@@ -654,6 +820,18 @@ impl Session {
         let mut counter = 0;
         let max_loop_iterations = 100000;
 
+        // the request waiting in the queue of its cluster may have a free connection now
+        if self.routing.queued_since.is_some()
+            && self.back_connected == BackendConnectionStatus::NotConnected
+        {
+            match self.connect_to_backend(session.clone()) {
+                // reuse connection or send a default answer, we can continue
+                Ok(BackendConnectAction::Reuse) | Err(_) => {}
+                // New or Replace: stop here, we must wait for an event
+                _ => return SessionResult::Continue,
+            }
+        }
+
         if self.back_connected().is_connecting()
             && self
                 .back_readiness()
@@ -678,22 +856,34 @@ impl Session {
                     "{} error connecting to backend, trying again",
                     self.log_context()
                 );
-                self.connection_attempt += 1;
+                self.record_failed_attempt();
                 self.fail_backend_connection();
 
                 self.back_connected = BackendConnectionStatus::Connecting(Instant::now());
 
                 // trigger a backend reconnection
                 self.close_backend();
-                match self.connect_to_backend(session.clone()) {
-                    // reuse connection or send a default answer, we can continue
-                    Ok(BackendConnectAction::Reuse) | Err(_) => {}
-                    // New or Replace: stop here, we must wait for an event
-                    _ => return SessionResult::Continue,
+                if !self.can_retry(RetryCondition::ConnectFailure) {
+                    error!(
+                        "{} could not connect to a backend, not retrying",
+                        self.log_context()
+                    );
+                    self.set_answer(DefaultAnswerStatus::Answer503, None);
+                } else {
+                    incr!(
+                        "retries",
+                        self.cluster_id.as_deref(),
+                        self.metrics.backend_id.as_deref()
+                    );
+                    match self.connect_to_backend(session.clone()) {
+                        // reuse connection or send a default answer, we can continue
+                        Ok(BackendConnectAction::Reuse) | Err(_) => {}
+                        // New or Replace: stop here, we must wait for an event
+                        _ => return SessionResult::Continue,
+                    }
                 }
             } else {
                 self.metrics().backend_connected();
-                self.reset_connection_attempt();
                 self.set_back_connected(BackendConnectionStatus::Connected);
             }
         }
@@ -747,6 +937,8 @@ impl Session {
 
                 match order {
                     SessionResult::ConnectBackend => {
+                        // a new request gets a new retry budget
+                        self.reset_connection_attempt();
                         match self.connect_to_backend(session.clone()) {
                             // reuse connection or send a default answer, we can continue
                             Ok(BackendConnectAction::Reuse) | Err(_) => {}
@@ -771,6 +963,10 @@ impl Session {
                 if order != SessionResult::Continue {
                     return order;
                 }
+
+                if self.retry_request(session.clone()) {
+                    return SessionResult::Continue;
+                }
             }
 
             if front_interest.is_writable() {
@@ -899,7 +1095,7 @@ impl Session {
     }
 
     pub fn check_circuit_breaker(&mut self) -> Result<(), ConnectionError> {
-        if self.connection_attempt == CONN_RETRIES {
+        if self.routing.connection_attempt >= self.request_retries().max_attempts() {
             error!("{} max connection attempt reached", self.log_context());
             self.set_answer(DefaultAnswerStatus::Answer503, None);
             Err(ConnectionError::NoBackendAvailable)
@@ -1013,15 +1209,37 @@ impl Session {
     pub fn backend_from_request(
         &mut self,
         cluster_id: &str,
-        front_should_stick: bool,
-    ) -> Result<TcpStream, ConnectionError> {
+        sticky_mode: Option<StickyMode>,
+        hash_key: Option<HashKey>,
+    ) -> Result<BackendSocket, ConnectionError> {
+        let front_should_stick = sticky_mode == Some(StickyMode::Cookie);
+        let sticky_key = self
+            .http()
+            .zip(sticky_mode.as_ref())
+            .and_then(|(http, sticky_mode)| http.get_sticky_key(sticky_mode));
+        let hash_key = self
+            .http()
+            .zip(hash_key.as_ref())
+            .and_then(|(http, hash_key)| http.get_hash_key(hash_key));
         let sticky_session = self
             .http()
             .and_then(|http| http.request_state.as_ref())
             .and_then(|r| r.get_sticky_session());
 
-        let res = match (front_should_stick, sticky_session) {
-            (true, Some(sticky_session)) => self
+        let res = match (sticky_key, front_should_stick, sticky_session) {
+            _ if !self.routing.tried_backends.is_empty() => self
+                .proxy
+                .borrow()
+                .backends
+                .borrow_mut()
+                .backend_for_retry(cluster_id, &self.routing.tried_backends),
+            (Some(sticky_key), _, _) => self
+                .proxy
+                .borrow()
+                .backends
+                .borrow_mut()
+                .backend_from_sticky_key(cluster_id, &sticky_key),
+            (None, true, Some(sticky_session)) => self
                 .proxy
                 .borrow()
                 .backends
@@ -1039,15 +1257,32 @@ impl Session {
                 .borrow()
                 .backends
                 .borrow_mut()
-                .backend_from_cluster_id(cluster_id),
+                .backend_for_key(cluster_id, hash_key.as_deref()),
         };
 
         match res {
             Err(e) => {
-                self.set_answer(DefaultAnswerStatus::Answer503, None);
+                if !self.queue_request(cluster_id) {
+                    self.set_answer(DefaultAnswerStatus::Answer503, None);
+                }
                 Err(e)
             }
             Ok((backend, conn)) => {
+                self.leave_queue();
+                let socket = self.proxy.borrow().backends.borrow().backend_socket(
+                    cluster_id,
+                    &backend.borrow(),
+                    conn,
+                    BackendProtocol::Http1,
+                );
+                let conn = match socket {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        self.set_answer(DefaultAnswerStatus::Answer503, None);
+                        return Err(e);
+                    }
+                };
+
                 if front_should_stick {
                     let sticky_name = self.proxy.borrow().listeners[&self.listener_token]
                         .borrow()
@@ -1078,6 +1313,11 @@ impl Session {
     }
 
     fn cluster_id_from_request(&mut self) -> Result<String, ConnectionError> {
+        if self.routing.counted_listener.is_none() {
+            self.set_answer(DefaultAnswerStatus::Answer503, None);
+            return Err(ConnectionError::TooManySessions);
+        }
+
         let listener_token = self.listener_token;
         let (host, uri, method) = match self.extract_route() {
             Ok(t) => t,
@@ -1086,25 +1326,274 @@ impl Session {
                 return Err(e);
             }
         };
-        let route_res = self
+        // the ACLs and the frontends match the hostname without its port, in lowercase
+        let hostname = match normalized_hostname(host) {
+            Some(hostname) => hostname,
+            None => {
+                self.set_answer(DefaultAnswerStatus::Answer400, None);
+                return Err(ConnectionError::InvalidHost);
+            }
+        };
+        let acl_allows = match self.http().and_then(|http| http.session_address) {
+            Some(address) => self
+                .proxy
+                .borrow()
+                .listeners
+                .get(&listener_token)
+                .map(|l| l.borrow().acls.allows_request(address.ip(), &hostname))
+                .unwrap_or(true),
+            None => true,
+        };
+        if !acl_allows {
+            let (status, answer) = self.answers.borrow().deny_answer(403, None);
+            self.set_answer(status, Some(answer));
+            return Err(ConnectionError::Unauthorized);
+        }
+
+        let path_normalization = self
             .proxy
             .borrow()
             .listeners
             .get(&listener_token)
-            .as_ref()
-            .and_then(|l| l.borrow().frontend_from_request(host, uri, method));
-
-        match route_res {
-            Some(Route::ClusterId(cluster_id)) => Ok(cluster_id),
-            Some(Route::Deny) => {
-                self.set_answer(DefaultAnswerStatus::Answer401, None);
-                Err(ConnectionError::Unauthorized)
+            .map(|l| l.borrow().get_path_normalization())
+            .unwrap_or_default();
+        let normalized_uri = match normalize_uri(uri, &path_normalization) {
+            NormalizedUri::Unchanged => None,
+            NormalizedUri::Rewritten(normalized) => Some(normalized),
+            NormalizedUri::Redirect(location) => {
+                match redirect_answer(308, "{path}", host, &location) {
+                    Some((status, answer)) => self.set_answer(status, Some(answer)),
+                    None => self.set_answer(DefaultAnswerStatus::Answer503, None),
+                }
+                return Err(ConnectionError::Redirect);
             }
+        };
+        let uri = normalized_uri.as_deref().unwrap_or(uri);
+
+        let route_res = self
+            .proxy
+            .borrow()
+            .listeners
+            .get(&listener_token)
+            .and_then(|l| {
+                let l = l.borrow();
+                // parsing the headers again is only needed by the frontends matching on them
+                let headers = if l.has_header_rules() {
+                    self.http()
+                        .map(|http| http.get_request_headers())
+                        .unwrap_or_default()
+                } else {
+                    Vec::new()
+                };
+                l.frontend_from_request(&hostname, uri, method, &headers)
+            });
+
+        let RouteResult {
+            route,
+            rewritten_path,
+            mirror_cluster_id,
+            allowed_methods,
+        } = match route_res {
+            Some(route_result) => route_result,
             None => {
                 self.set_answer(DefaultAnswerStatus::Answer404, None);
-                Err(ConnectionError::HostNotFound)
+                return Err(ConnectionError::HostNotFound);
+            }
+        };
+
+        let mut ab_test_cookie = None;
+        let cluster_id = match route {
+            Route::ClusterId(cluster_id) => cluster_id,
+            Route::Weighted(clusters) => match pick_weighted_cluster(&clusters) {
+                Some(cluster_id) => cluster_id.to_string(),
+                None => {
+                    self.set_answer(DefaultAnswerStatus::Answer503, None);
+                    return Err(ConnectionError::NoBackendAvailable);
+                }
+            },
+            Route::AbTest { cookie, variants } => {
+                let assigned = self
+                    .http()
+                    .and_then(|http| http.get_request_cookie(&cookie));
+                match pick_ab_test_variant(&variants, assigned.as_deref()) {
+                    Some((cluster_id, newly_assigned)) => {
+                        if newly_assigned {
+                            ab_test_cookie = Some((cookie.clone(), cluster_id.to_string()));
+                        }
+                        cluster_id.to_string()
+                    }
+                    None => {
+                        self.set_answer(DefaultAnswerStatus::Answer503, None);
+                        return Err(ConnectionError::NoBackendAvailable);
+                    }
+                }
             }
+            Route::Deny { status, body_path } => {
+                let (status, answer) = if allowed_methods.is_empty() {
+                    self.answers
+                        .borrow()
+                        .deny_answer(status, body_path.as_deref())
+                } else {
+                    method_not_allowed_answer(&allowed_methods)
+                };
+                self.set_answer(status, Some(answer));
+                return Err(ConnectionError::Unauthorized);
+            }
+            Route::Redirect { to, code } => {
+                match redirect_answer(code, &to, host, uri) {
+                    Some((status, answer)) => self.set_answer(status, Some(answer)),
+                    None => self.set_answer(DefaultAnswerStatus::Answer503, None),
+                }
+                return Err(ConnectionError::Redirect);
+            }
+        };
+
+        let maintenance = self.proxy.borrow().maintenance.get(&cluster_id).cloned();
+        if let Some(answer) = maintenance {
+            let answer = answer.unwrap_or_else(|| {
+                self.answers
+                    .borrow()
+                    .get(DefaultAnswerStatus::Answer503, Some(&cluster_id))
+            });
+            self.set_answer(DefaultAnswerStatus::Answer503, Some(answer));
+            return Err(ConnectionError::Maintenance);
+        }
+
+        if !self.enter_cluster(&cluster_id) {
+            self.set_answer(DefaultAnswerStatus::Answer503, None);
+            return Err(ConnectionError::TooManySessions);
         }
+
+        let request_limits = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .map(|cluster| cluster.request_limits)
+            .unwrap_or_default();
+        let within_limits = self
+            .http_mut()
+            .map(|http| http.set_cluster_request_limits(&request_limits))
+            .unwrap_or(true);
+        if !within_limits {
+            return Err(ConnectionError::RequestTooLarge);
+        }
+
+        let compression = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .map(|cluster| cluster.compression)
+            .unwrap_or_default();
+        if let Some(http) = self.http_mut() {
+            http.set_cluster_compression(&compression);
+        }
+
+        let streaming = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .map(|cluster| cluster.streaming)
+            .unwrap_or(false);
+        if let Some(http) = self.http_mut() {
+            http.set_cluster_streaming(streaming);
+        }
+
+        let upgrade_protocols = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .map(|cluster| cluster.upgrade_protocols.clone())
+            .unwrap_or_default();
+        if let Some(http) = self.http_mut() {
+            http.set_cluster_upgrades(&upgrade_protocols);
+        }
+
+        let request_retries = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .map(|cluster| cluster.request_retries.clone())
+            .unwrap_or_default();
+        if let Some(http) = self.http_mut() {
+            http.set_cluster_retries(&request_retries);
+        }
+
+        self.routing.mirror_cluster_id = mirror_cluster_id;
+
+        // the request is sent with the normalized path, unless the frontend rewrites it
+        if let Some(path) = rewritten_path.or(normalized_uri) {
+            let rewritten = self
+                .http_mut()
+                .map(|http| http.set_request_path(&path))
+                .unwrap_or(false);
+            if !rewritten {
+                return Err(ConnectionError::PathNotRewritten);
+            }
+        }
+
+        let header_edits = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .filter(|cluster| !cluster.header_actions.is_empty())
+            .map(|cluster| {
+                (
+                    HeaderEdits::new(&cluster.header_actions, HeaderPosition::Request),
+                    HeaderEdits::new(&cluster.header_actions, HeaderPosition::Response),
+                )
+            });
+        if let Some((request_edits, response_edits)) = header_edits {
+            if let Some(http) = self.http_mut() {
+                http.edit_request_headers(&request_edits);
+                http.response_header_edits.extend(response_edits);
+            }
+        }
+
+        let security_headers = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .map(|cluster| cluster.security_headers.clone())
+            .unwrap_or_default();
+        if let Some(http) = self.http_mut() {
+            http.set_cluster_security_headers(&security_headers);
+        }
+
+        // the client cannot send the header carrying the subject of its certificate
+        let client_dn_header = self
+            .listener
+            .borrow()
+            .config
+            .client_auth
+            .as_ref()
+            .map(|client_auth| client_auth.dn_header.clone());
+        if let Some(name) = client_dn_header {
+            if let Some(http) = self.http_mut() {
+                let subject = http
+                    .frontend
+                    .session
+                    .peer_certificates()
+                    .and_then(|certificates| certificates.first())
+                    .and_then(|certificate| certificate_subject(&certificate.0));
+                http.edit_request_headers(&HeaderEdits::client_dn(&name, subject.as_deref()));
+            }
+        }
+
+        // set after the header edits of the cluster, which replace those of the response
+        if let Some((name, value)) = ab_test_cookie {
+            if let Some(http) = self.http_mut() {
+                http.add_response_cookie(&name, &value);
+            }
+        }
+
+        Ok(cluster_id)
     }
 
     fn connect_to_backend(
@@ -1116,7 +1605,19 @@ impl Session {
 
         self.check_circuit_breaker()?;
 
-        let cluster_id = self.cluster_id_from_request()?;
+        // a retried or queued request stays in the cluster it was routed to
+        let cluster_id = match self.cluster_id.clone() {
+            Some(cluster_id)
+                if self.routing.connection_attempt > 0 || self.routing.queued_since.is_some() =>
+            {
+                cluster_id
+            }
+            _ => {
+                let cluster_id = self.cluster_id_from_request()?;
+                self.connect_mirror(session_rc.clone());
+                cluster_id
+            }
+        };
 
         if (self.http().and_then(|h| h.cluster_id.as_ref()) == Some(&cluster_id))
             && self.back_connected == BackendConnectionStatus::Connected
@@ -1135,6 +1636,7 @@ impl Session {
                 .unwrap_or(false);
 
             if has_backend && self.check_backend_connection() {
+                self.rewrite_host(&cluster_id);
                 return Ok(BackendConnectAction::Reuse);
             } else if let Some(token) = self.back_token() {
                 self.close_backend();
@@ -1160,17 +1662,25 @@ impl Session {
             http.cluster_id = Some(cluster_id.clone());
         };
 
-        let front_should_stick = self
+        let sticky_mode = self
             .proxy
             .borrow()
             .clusters
             .get(&cluster_id)
-            .map(|cluster| cluster.sticky_session)
-            .unwrap_or(false);
-        let mut socket = self.backend_from_request(&cluster_id, front_should_stick)?;
+            .filter(|cluster| cluster.sticky_session)
+            .map(|cluster| cluster.sticky_mode.clone());
+        let hash_key = self
+            .proxy
+            .borrow()
+            .clusters
+            .get(&cluster_id)
+            .filter(|cluster| cluster.load_balancing == LoadBalancingAlgorithms::ConsistentHash)
+            .map(|cluster| cluster.hash_key.clone());
+        let mut socket = self.backend_from_request(&cluster_id, sticky_mode, hash_key)?;
+        self.rewrite_host(&cluster_id);
 
         // we still want to use the new socket
-        if let Err(e) = socket.set_nodelay(true) {
+        if let Err(e) = socket.socket_ref().set_nodelay(true) {
             error!("error setting nodelay on back socket: {:?}", e);
         }
         if let Some(r) = self.back_readiness() {
@@ -1178,20 +1688,26 @@ impl Session {
         }
 
         let connect_timeout = time::Duration::seconds(i64::from(
-            self.proxy
-                .borrow()
-                .listeners
-                .get(&self.listener_token)
-                .as_ref()
-                .map(|l| l.borrow().config.connect_timeout)
-                .unwrap(),
+            self.timeouts().connect_timeout.unwrap_or_else(|| {
+                self.proxy
+                    .borrow()
+                    .listeners
+                    .get(&self.listener_token)
+                    .as_ref()
+                    .map(|l| l.borrow().config.connect_timeout)
+                    .unwrap()
+            }),
         ));
+        let front_timeout = self.front_timeout_duration();
+        if let Some(http) = self.http_mut() {
+            http.frontend_timeout_duration = front_timeout;
+        }
 
         self.back_connected = BackendConnectionStatus::Connecting(Instant::now());
         if let Some(back_token) = old_back_token {
             self.set_back_token(back_token);
             if let Err(e) = self.proxy.borrow().registry.register(
-                &mut socket,
+                socket.socket_mut(),
                 back_token,
                 Interest::READABLE | Interest::WRITABLE,
             ) {
@@ -1222,7 +1738,7 @@ impl Session {
             };
 
             if let Err(e) = self.proxy.borrow().registry.register(
-                &mut socket,
+                socket.socket_mut(),
                 back_token,
                 Interest::READABLE | Interest::WRITABLE,
             ) {
@@ -1239,6 +1755,71 @@ impl Session {
     }
 }
 
+impl HttpSession for Session {
+    type Front = FrontRustls;
+    type Listener = Listener;
+    type Proxy = Proxy;
+
+    fn proxy(&self) -> &Rc<RefCell<Proxy>> {
+        &self.proxy
+    }
+
+    fn http(&self) -> Option<&Http<FrontRustls, Listener>> {
+        Session::http(self)
+    }
+
+    fn http_mut(&mut self) -> Option<&mut Http<FrontRustls, Listener>> {
+        Session::http_mut(self)
+    }
+
+    fn frontend_token(&self) -> Token {
+        self.frontend_token
+    }
+
+    fn cluster_id(&self) -> Option<&str> {
+        self.cluster_id.as_deref()
+    }
+
+    fn backend(&self) -> Option<&Rc<RefCell<Backend>>> {
+        self.backend.as_ref()
+    }
+
+    fn metrics(&self) -> &SessionMetrics {
+        &self.metrics
+    }
+
+    fn routing(&self) -> &RequestRouting {
+        &self.routing
+    }
+
+    fn routing_mut(&mut self) -> &mut RequestRouting {
+        &mut self.routing
+    }
+
+    fn frontend_timeout_duration(&self) -> Duration {
+        self.frontend_timeout_duration
+    }
+
+    fn backend_timeout_duration(&self) -> Duration {
+        self.backend_timeout_duration
+    }
+
+    fn log_context(&self) -> String {
+        Session::log_context(self)
+    }
+
+    fn close_backend(&mut self) {
+        Session::close_backend(self)
+    }
+
+    fn connect_to_backend(
+        &mut self,
+        session_rc: Rc<RefCell<dyn ProxySession>>,
+    ) -> Result<BackendConnectAction, ConnectionError> {
+        Session::connect_to_backend(self, session_rc)
+    }
+}
+
 impl ProxySession for Session {
     fn close(&mut self) {
         //println!("TLS closing[{:?}] temp->front: {:?}, temp->back: {:?}", self.token, *self.temp.front_buf, *self.temp.back_buf);
@@ -1248,6 +1829,8 @@ impl ProxySession for Session {
 
         self.metrics.service_stop();
         self.cancel_timeouts();
+        self.leave_queue();
+        self.leave_session_limits();
         if let Err(e) = self.front_socket().shutdown(Shutdown::Both) {
             if e.kind() != ErrorKind::NotConnected {
                 error!("error closing front socket: {:?}", e);
@@ -1255,6 +1838,12 @@ impl ProxySession for Session {
         }
 
         self.close_backend();
+        self.close_mirror();
+
+        if let Some(State::Http2(ref mut h2)) = self.protocol {
+            let proxy = self.proxy.borrow();
+            h2.close(&proxy.registry, &proxy.sessions);
+        }
 
         if let Some(State::Http(ref mut http)) = self.protocol {
             //if the state was initial, the connection was already reset
@@ -1278,6 +1867,7 @@ impl ProxySession for Session {
             Some(State::Expect(_, _)) => gauge_add!("protocol.proxy.expect", -1),
             Some(State::Handshake(_)) => gauge_add!("protocol.tls.handshake", -1),
             Some(State::Http(_)) => gauge_add!("protocol.https", -1),
+            Some(State::Http2(_)) => gauge_add!("protocol.http2s", -1),
             Some(State::WebSocket(_)) => gauge_add!("protocol.wss", -1),
             None => {}
         }
@@ -1292,6 +1882,27 @@ impl ProxySession for Session {
     fn timeout(&mut self, token: Token) {
         let res = match *unwrap_msg!(self.protocol.as_mut()) {
             State::Http(ref mut http) => http.timeout(token, &mut self.metrics),
+            State::Http2(ref mut h2) => {
+                let proxy = self.proxy.borrow();
+                let session = proxy
+                    .sessions
+                    .borrow()
+                    .slab
+                    .get(self.frontend_token.0)
+                    .cloned();
+                match session {
+                    Some(session) => {
+                        let context = BackendContext {
+                            backends: &proxy.backends,
+                            registry: &proxy.registry,
+                            sessions: &proxy.sessions,
+                            session,
+                        };
+                        h2.timeout(token, &mut self.metrics, &context)
+                    }
+                    None => SessionResult::CloseSession,
+                }
+            }
             State::WebSocket(ref mut pipe) => pipe.timeout(token, &mut self.metrics),
             _ => {
                 if token == self.frontend_token {
@@ -1326,12 +1937,29 @@ impl ProxySession for Session {
             if let Some(r) = self.back_readiness() {
                 r.event |= events;
             }
+        } else if let Some(mirror) = self
+            .routing
+            .mirror
+            .as_mut()
+            .filter(|mirror| mirror.token == token)
+        {
+            mirror.readiness.event |= events;
+        } else if let Some(State::Http2(ref mut h2)) = self.protocol {
+            if let Some(readiness) = h2.backend_readiness(token) {
+                readiness.event |= events;
+            }
         }
     }
 
     fn ready(&mut self, session: Rc<RefCell<dyn ProxySession>>) {
         self.metrics().service_start();
-        let res = self.ready_inner(session);
+        let res = if let Some(State::Http2(_)) = self.protocol {
+            self.http2_ready(session)
+        } else {
+            self.ready_inner(session)
+        };
+        self.mirror_ready();
+
         if res == SessionResult::CloseSession {
             self.close();
         } else if let SessionResult::CloseBackend = res {
@@ -1344,6 +1972,8 @@ impl ProxySession for Session {
     fn shutting_down(&mut self) {
         let res = match &mut self.protocol {
             Some(State::Http(h)) => h.shutting_down(),
+            Some(State::WebSocket(pipe)) => pipe.shutting_down(&mut self.metrics),
+            Some(State::Http2(h2)) => h2.shutting_down(&mut self.metrics),
             Some(State::Handshake(_)) => SessionResult::Continue,
             _ => SessionResult::CloseSession,
         };
@@ -1368,6 +1998,7 @@ impl ProxySession for Session {
             Some(State::Expect(_, _)) => String::from("Expect"),
             Some(State::Handshake(_)) => String::from("Handshake"),
             Some(State::Http(h)) => h.print_state("HTTPS"),
+            Some(State::Http2(h2)) => h2.print_state(),
             Some(State::WebSocket(_)) => String::from("WSS"),
             None => String::from("None"),
         };
@@ -1376,6 +2007,7 @@ impl ProxySession for Session {
             State::Expect(ref expect, _) => &expect.readiness,
             State::Handshake(ref handshake) => &handshake.readiness,
             State::Http(ref http) => &http.front_readiness,
+            State::Http2(ref h2) => &h2.front_readiness,
             State::WebSocket(ref pipe) => &pipe.front_readiness,
         };
 
@@ -1384,15 +2016,211 @@ impl ProxySession for Session {
     }
 
     fn tokens(&self) -> Vec<Token> {
+        if let Some(State::Http2(ref h2)) = self.protocol {
+            return h2.tokens();
+        }
+
         let mut v = vec![self.frontend_token];
         if let Some(tk) = self.back_token() {
             v.push(tk)
         }
+        if let Some(mirror) = &self.routing.mirror {
+            v.push(mirror.token)
+        }
 
         v
     }
 }
 
+/// routes a request received on an HTTP/2 stream like the HTTP/1.1 requests of the
+/// listener, the error is the answer of the stream. Sticky sessions, retries, mirroring
+/// and compression do not apply to the streams
+#[allow(clippy::too_many_arguments)]
+fn route_h2_request(
+    request: &Request,
+    proxy: &Proxy,
+    listener: &Listener,
+    answers: &HttpAnswers,
+    sni: Option<&str>,
+    peer_address: Option<SocketAddr>,
+    client_subject: Option<&str>,
+    backend_timeout_duration: Duration,
+) -> Result<StreamRoute, Rc<Vec<u8>>> {
+    let host = request
+        .host()
+        .ok_or_else(|| answers.get(DefaultAnswerStatus::Answer400, None))?;
+    let hostname = match hostname_and_port(host.as_bytes()) {
+        Ok((&[], (hostname, _))) => {
+            // only ascii characters were parsed in the hostname
+            unsafe { from_utf8_unchecked(hostname) }
+        }
+        _ => {
+            error!("hostname parsing failed");
+            return Err(answers.get(DefaultAnswerStatus::Answer400, None));
+        }
+    };
+    if sni != Some(hostname) {
+        error!(
+            "TLS SNI hostname '{:?}' and Host header '{}' don't match",
+            sni, hostname
+        );
+        return Err(answers.get(DefaultAnswerStatus::Answer400, None));
+    }
+    let host = if host.ends_with(":443") {
+        hostname
+    } else {
+        host
+    };
+
+    // the ACLs and the frontends match the hostname without its port, in lowercase
+    let route_hostname = normalized_hostname(host)
+        .ok_or_else(|| answers.get(DefaultAnswerStatus::Answer400, None))?;
+
+    if let Some(address) = peer_address {
+        if !listener.acls.allows_request(address.ip(), &route_hostname) {
+            return Err(answers.deny_answer(403, None).1);
+        }
+    }
+
+    let uri = request.path.as_str();
+    let normalized_uri = match normalize_uri(uri, &listener.get_path_normalization()) {
+        NormalizedUri::Unchanged => None,
+        NormalizedUri::Rewritten(normalized) => Some(normalized),
+        NormalizedUri::Redirect(location) => {
+            return Err(match redirect_answer(308, "{path}", host, &location) {
+                Some((_, answer)) => answer,
+                None => answers.get(DefaultAnswerStatus::Answer503, None),
+            });
+        }
+    };
+    let uri = normalized_uri.as_deref().unwrap_or(uri);
+
+    let headers = request.routing_headers();
+    let RouteResult {
+        route,
+        rewritten_path,
+        allowed_methods,
+        ..
+    } = listener
+        .frontend_from_request(&route_hostname, uri, &request.method, &headers)
+        .ok_or_else(|| answers.get(DefaultAnswerStatus::Answer404, None))?;
+
+    let mut ab_test_cookie = None;
+    let cluster_id = match route {
+        Route::ClusterId(cluster_id) => cluster_id,
+        Route::Weighted(clusters) => pick_weighted_cluster(&clusters)
+            .map(|cluster_id| cluster_id.to_string())
+            .ok_or_else(|| answers.get(DefaultAnswerStatus::Answer503, None))?,
+        Route::AbTest { cookie, variants } => {
+            let assigned = find_request_cookie(&headers, &cookie);
+            match pick_ab_test_variant(&variants, assigned.as_deref()) {
+                Some((cluster_id, newly_assigned)) => {
+                    if newly_assigned {
+                        ab_test_cookie = Some((cookie.clone(), cluster_id.to_string()));
+                    }
+                    cluster_id.to_string()
+                }
+                None => return Err(answers.get(DefaultAnswerStatus::Answer503, None)),
+            }
+        }
+        Route::Deny { status, body_path } => {
+            return Err(if allowed_methods.is_empty() {
+                answers.deny_answer(status, body_path.as_deref()).1
+            } else {
+                method_not_allowed_answer(&allowed_methods).1
+            });
+        }
+        Route::Redirect { to, code } => {
+            return Err(match redirect_answer(code, &to, host, uri) {
+                Some((_, answer)) => answer,
+                None => answers.get(DefaultAnswerStatus::Answer503, None),
+            });
+        }
+    };
+
+    if let Some(answer) = proxy.maintenance.get(&cluster_id) {
+        return Err(answer
+            .clone()
+            .unwrap_or_else(|| answers.get(DefaultAnswerStatus::Answer503, Some(&cluster_id))));
+    }
+
+    let cluster = proxy.clusters.get(&cluster_id);
+    let request_limits = cluster
+        .map(|cluster| cluster.request_limits)
+        .unwrap_or_default()
+        .or(&listener.get_request_limits());
+    let header_size = request
+        .headers
+        .iter()
+        .map(|(name, value)| name.len() + value.len() + 4)
+        .sum::<usize>();
+    if matches!(request_limits.max_header_size, Some(max) if header_size > max)
+        || matches!(request_limits.max_header_count, Some(max) if request.headers.len() > max)
+    {
+        return Err(answers.get(DefaultAnswerStatus::Answer431, Some(&cluster_id)));
+    }
+    if matches!((request_limits.max_body_size, request.content_length), (Some(max), Some(length)) if length > max)
+    {
+        return Err(answers.get(DefaultAnswerStatus::Answer413, Some(&cluster_id)));
+    }
+
+    let (mut request_edits, mut response_edits) = cluster
+        .filter(|cluster| !cluster.header_actions.is_empty())
+        .map(|cluster| {
+            (
+                HeaderEdits::new(&cluster.header_actions, HeaderPosition::Request),
+                HeaderEdits::new(&cluster.header_actions, HeaderPosition::Response),
+            )
+        })
+        .unwrap_or_default();
+
+    let security_headers = cluster
+        .map(|cluster| cluster.security_headers.clone())
+        .unwrap_or_default()
+        .or(&listener.get_security_headers());
+    response_edits.extend(HeaderEdits::security_headers(&security_headers));
+
+    // the client cannot send the header carrying the subject of its certificate
+    if let Some(client_auth) = listener.config.client_auth.as_ref() {
+        request_edits.extend(HeaderEdits::client_dn(
+            &client_auth.dn_header,
+            client_subject,
+        ));
+    }
+
+    if let Some((name, value)) = ab_test_cookie {
+        response_edits
+            .added
+            .extend(format!("Set-Cookie: {}={}; Path=/\r\n", name, value).as_bytes());
+    }
+
+    let timeouts = cluster.map(|cluster| cluster.timeouts).unwrap_or_default();
+    let connect_timeout = Duration::seconds(i64::from(
+        timeouts
+            .connect_timeout
+            .unwrap_or(listener.config.connect_timeout),
+    ));
+    let back_timeout = timeouts
+        .back_timeout
+        .map(|timeout| Duration::seconds(i64::from(timeout)))
+        .unwrap_or(backend_timeout_duration);
+
+    Ok(StreamRoute {
+        host_rewrite: cluster
+            .map(|cluster| cluster.host_rewrite.clone())
+            .unwrap_or_default(),
+        cluster_id,
+        path: rewritten_path.or(normalized_uri),
+        request_edits,
+        response_edits,
+        connect_timeout,
+        back_timeout,
+        backend_protocol: cluster
+            .map(|cluster| cluster.backend_protocol)
+            .unwrap_or_default(),
+    })
+}
+
 fn version_str(version: ProtocolVersion) -> &'static str {
     match version {
         ProtocolVersion::SSLv2 => "tls.version.SSLv2",
@@ -184,11 +184,14 @@ pub mod util;
 #[macro_use]
 pub mod metrics;
 
+pub mod acl;
 pub mod backends;
 pub mod buffer_queue;
 pub mod features;
+pub mod health_check;
 pub mod http;
 pub mod load_balancing;
+pub mod outlier_detection;
 pub mod pool;
 pub mod protocol;
 pub mod retry;
@@ -214,11 +217,14 @@ use mio::{net::TcpStream, Token};
 use time::{Duration, Instant};
 
 use crate::sozu_command::{
-    proxy::{LoadBalancingParams, ProxyEvent, ProxyRequest, ProxyResponse},
+    proxy::{
+        Compression, Http2Settings, LoadBalancingParams, NameResolution, PathNormalization,
+        ProxyEvent, ProxyRequest, ProxyResponse, RequestLimits, SecurityHeaders, Timeouts,
+    },
     ready::Ready,
 };
 
-use self::retry::RetryPolicy;
+use self::{retry::RetryPolicy, tls::BackendTlsConfig};
 
 pub type ClusterId = String;
 
@@ -266,6 +272,14 @@ pub trait ProxySession {
     /// if the session handles HTTP requests, it will not close until the response
     /// is completely sent back to the client
     fn shutting_down(&mut self);
+    /// the main process resolved a name the session asked for. Only the forward
+    /// proxy sessions ask for names
+    fn name_resolved(
+        &mut self,
+        _resolution: NameResolution,
+        _session: Rc<RefCell<dyn ProxySession>>,
+    ) {
+    }
 }
 
 pub trait ListenerHandler {
@@ -274,6 +288,31 @@ pub trait ListenerHandler {
     fn get_tags(&self, key: &str) -> Option<&BTreeMap<String, String>>;
 
     fn set_tags(&mut self, key: String, tags: Option<BTreeMap<String, String>>);
+
+    /// limits on the size of the HTTP requests received by this listener
+    fn get_request_limits(&self) -> RequestLimits {
+        RequestLimits::default()
+    }
+
+    /// compression of the HTTP responses sent by this listener
+    fn get_compression(&self) -> Compression {
+        Compression::default()
+    }
+
+    /// security headers added to the HTTP responses sent by this listener
+    fn get_security_headers(&self) -> SecurityHeaders {
+        SecurityHeaders::default()
+    }
+
+    /// normalization of the request paths received by this listener
+    fn get_path_normalization(&self) -> PathNormalization {
+        PathNormalization::default()
+    }
+
+    /// HTTP/2 settings announced to the clients of this listener
+    fn get_http2_settings(&self) -> Http2Settings {
+        Http2Settings::default()
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -414,8 +453,15 @@ pub enum ConnectionError {
     NoBackendAvailable,
     ToBeDefined,
     HttpsRedirect,
+    Redirect,
     Unauthorized,
+    Maintenance,
+    RequestTooLarge,
     TooManyConnections,
+    /// the worker, listener or cluster holds as many sessions as it allows
+    TooManySessions,
+    /// the path of the request could not be rewritten for the backend
+    PathNotRewritten,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -427,7 +473,9 @@ pub enum SocketType {
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum BackendStatus {
     Normal,
-    Closing,
+    /// the backend does not accept new sessions, its connections are waited for
+    Draining,
+    /// the backend was drained, it has no connections left
     Closed,
 }
 
@@ -443,7 +491,20 @@ pub struct Backend {
     pub failures: usize,
     pub load_balancing_parameters: Option<LoadBalancingParams>,
     pub backup: bool,
+    /// connections opened to the backend at most
+    pub max_connections: Option<usize>,
     pub connection_time: PeakEWMA,
+    /// time to answer the requests, for the least response time algorithm
+    pub response_time: PeakEWMA,
+    /// overrides the timeouts of the cluster and listener
+    pub timeouts: Timeouts,
+    /// replaces the TLS configuration of the cluster
+    pub tls: Option<Rc<BackendTlsConfig>>,
+    /// false while the backend fails the health checks of its cluster
+    pub healthy: bool,
+    /// passive health checks of the cluster
+    pub outlier_detector: Option<Rc<outlier_detection::OutlierDetector>>,
+    pub outlier_stats: outlier_detection::OutlierStats,
 }
 
 impl Backend {
@@ -467,11 +528,39 @@ impl Backend {
             load_balancing_parameters,
             backup: backup.unwrap_or(false),
             connection_time: PeakEWMA::new(),
+            response_time: PeakEWMA::new(),
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
+            healthy: true,
+            outlier_detector: None,
+            outlier_stats: outlier_detection::OutlierStats::default(),
         }
     }
 
-    pub fn set_closing(&mut self) {
-        self.status = BackendStatus::Closing;
+    /// takes the backend out of the rotation while its connections complete.
+    /// `RemovedBackendHasNoConnections` is sent once the last one is closed
+    pub fn set_draining(&mut self) {
+        if self.status != BackendStatus::Normal {
+            return;
+        }
+
+        self.status = BackendStatus::Draining;
+        if self.active_connections == 0 {
+            self.drained();
+        }
+    }
+
+    fn drained(&mut self) {
+        self.status = BackendStatus::Closed;
+        info!(
+            "backend server {} at {} is drained",
+            self.backend_id, self.address
+        );
+        server::push_event(ProxyEvent::RemovedBackendHasNoConnections(
+            self.backend_id.clone(),
+            self.address,
+        ));
     }
 
     pub fn retry_policy(&mut self) -> &mut retry::RetryPolicyWrapper {
@@ -480,12 +569,23 @@ impl Backend {
 
     pub fn can_open(&self) -> bool {
         if let Some(action) = self.retry_policy.can_try() {
-            self.status == BackendStatus::Normal && action == retry::RetryAction::OKAY
+            self.status == BackendStatus::Normal
+                && self.healthy
+                && !self.is_saturated()
+                && !self.outlier_stats.is_ejected(Instant::now())
+                && action == retry::RetryAction::OKAY
         } else {
             false
         }
     }
 
+    /// the backend has as many connections as it accepts
+    pub fn is_saturated(&self) -> bool {
+        self.max_connections
+            .map(|max| self.active_connections >= max)
+            .unwrap_or(false)
+    }
+
     pub fn inc_connections(&mut self) -> Option<usize> {
         if self.status == BackendStatus::Normal {
             self.active_connections += 1;
@@ -504,12 +604,12 @@ impl Backend {
                 Some(self.active_connections)
             }
             BackendStatus::Closed => None,
-            BackendStatus::Closing => {
+            BackendStatus::Draining => {
                 if self.active_connections > 0 {
                     self.active_connections -= 1;
                 }
                 if self.active_connections == 0 {
-                    self.status = BackendStatus::Closed;
+                    self.drained();
                     None
                 } else {
                     Some(self.active_connections)
@@ -526,6 +626,14 @@ impl Backend {
         self.connection_time.get(self.active_connections)
     }
 
+    pub fn set_response_time(&mut self, dur: Duration) {
+        self.response_time.observe(dur.whole_nanoseconds() as f64);
+    }
+
+    pub fn peak_ewma_response(&mut self) -> f64 {
+        self.response_time.get(self.active_requests)
+    }
+
     pub fn try_connect(&mut self) -> Result<mio::net::TcpStream, ConnectionError> {
         if self.status != BackendStatus::Normal {
             return Err(ConnectionError::NoBackendAvailable);
@@ -548,9 +656,13 @@ impl Backend {
 
 // when a backend has been removed from configuration and the last connection to
 // it has stopped, it will be dropped, so we can notify that the backend server
-// can be safely stopped
+// can be safely stopped. A drained backend already notified it
 impl std::ops::Drop for Backend {
     fn drop(&mut self) {
+        if self.status == BackendStatus::Closed {
+            return;
+        }
+
         server::push_event(ProxyEvent::RemovedBackendHasNoConnections(
             self.backend_id.clone(),
             self.address,
@@ -1,5 +1,11 @@
 use std::fmt::Debug;
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    rc::Rc,
+};
 
 use rand::{
     distributions::{Distribution, WeightedIndex},
@@ -14,11 +20,55 @@ pub trait LoadBalancingAlgorithm: Debug {
         &mut self,
         backends: &mut Vec<Rc<RefCell<Backend>>>,
     ) -> Option<Rc<RefCell<Backend>>>;
+
+    /// chooses the backend of a request designated by a key, like its URL or
+    /// the client IP. The algorithms that do not hash a key ignore it
+    fn next_backend_for_key(
+        &mut self,
+        backends: &mut Vec<Rc<RefCell<Backend>>>,
+        _key: &[u8],
+    ) -> Option<Rc<RefCell<Backend>>> {
+        self.next_available_backend(backends)
+    }
 }
 
-#[derive(Debug)]
+/// weights of the backends. A backend of weight 0 gets no traffic, unless none
+/// of them has a weight: they are all equals then
+fn weights(backends: &[Rc<RefCell<Backend>>]) -> Vec<u32> {
+    let weights: Vec<u32> = backends
+        .iter()
+        .map(|b| {
+            b.borrow()
+                .load_balancing_parameters
+                .as_ref()
+                .map(|p| p.weight as u32)
+                .unwrap_or(100)
+        })
+        .collect();
+
+    if weights.iter().all(|weight| *weight == 0) {
+        vec![1; weights.len()]
+    } else {
+        weights
+    }
+}
+
+/// load of a backend for its weight, the backends of weight 0 come last
+fn weighted_load(load: f64, weight: u32) -> f64 {
+    if weight == 0 {
+        f64::INFINITY
+    } else {
+        load / weight as f64
+    }
+}
+
+/// smooth weighted round robin: every backend accumulates its weight at each
+/// request, the one with the most goes next and loses the total of the weights.
+/// A backend of weight 5 and two of weight 1 are chosen as `a a b a c a a`
+#[derive(Debug, Default)]
 pub struct RoundRobin {
-    pub next_backend: u32,
+    /// accumulated weights of the backends, by address
+    current_weights: HashMap<SocketAddr, i64>,
 }
 
 impl LoadBalancingAlgorithm for RoundRobin {
@@ -26,24 +76,40 @@ impl LoadBalancingAlgorithm for RoundRobin {
         &mut self,
         backends: &mut Vec<Rc<RefCell<Backend>>>,
     ) -> Option<Rc<RefCell<Backend>>> {
-        let res = backends
-            .get(self.next_backend as usize % backends.len())
-            .map(|backend| (*backend).clone());
+        let weights = weights(backends);
+        let total: i64 = weights.iter().map(|weight| *weight as i64).sum();
+
+        let mut next: Option<(usize, i64)> = None;
+        for (index, (backend, weight)) in backends.iter().zip(weights).enumerate() {
+            let current = self
+                .current_weights
+                .entry(backend.borrow().address)
+                .or_insert(0);
+            *current += weight as i64;
+
+            if next.map(|(_, max)| *current > max).unwrap_or(true) {
+                next = Some((index, *current));
+            }
+        }
 
-        self.next_backend = (self.next_backend + 1) % backends.len() as u32;
-        res
-    }
-}
+        let backend = backends.get(next?.0)?.clone();
+        if let Some(current) = self.current_weights.get_mut(&backend.borrow().address) {
+            *current -= total;
+        }
+
+        // forget the backends that were removed
+        if self.current_weights.len() > backends.len() {
+            self.current_weights
+                .retain(|address, _| backends.iter().any(|b| b.borrow().address == *address));
+        }
 
-impl Default for RoundRobin {
-    fn default() -> Self {
-        Self::new()
+        Some(backend)
     }
 }
 
 impl RoundRobin {
     pub fn new() -> Self {
-        Self { next_backend: 0 }
+        Self::default()
     }
 }
 
@@ -56,18 +122,7 @@ impl LoadBalancingAlgorithm for Random {
         backends: &mut Vec<Rc<RefCell<Backend>>>,
     ) -> Option<Rc<RefCell<Backend>>> {
         let mut rng = thread_rng();
-        let weights: Vec<u8> = backends
-            .iter()
-            .map(|b| {
-                b.borrow()
-                    .load_balancing_parameters
-                    .as_ref()
-                    .map(|p| p.weight)
-                    .unwrap_or(100)
-            })
-            .collect();
-
-        if let Ok(dist) = WeightedIndex::new(&weights) {
+        if let Ok(dist) = WeightedIndex::new(weights(backends)) {
             let index = dist.sample(&mut rng);
             backends.get(index).cloned()
         } else {
@@ -88,34 +143,23 @@ impl LoadBalancingAlgorithm for LeastLoaded {
         &mut self,
         backends: &mut Vec<Rc<RefCell<Backend>>>,
     ) -> Option<Rc<RefCell<Backend>>> {
-        let opt_b = match self.metric {
-            LoadMetric::Connections => backends
-                .iter_mut()
-                .min_by_key(|backend| backend.borrow().active_connections),
-            LoadMetric::Requests => backends
-                .iter_mut()
-                .min_by_key(|backend| backend.borrow().active_requests),
-            LoadMetric::ConnectionTime => {
-                let mut b = None;
-                for backend in backends.iter_mut() {
-                    let cost2 = backend.borrow_mut().peak_ewma_connection();
-
-                    match b.take() {
-                        None => b = Some((cost2, backend)),
-                        Some((cost1, back1)) => {
-                            if cost1 <= cost2 {
-                                b = Some((cost1, back1));
-                            } else {
-                                b = Some((cost2, backend));
-                            }
-                        }
-                    }
-                }
+        let weights = weights(backends);
+        let mut b: Option<(f64, &Rc<RefCell<Backend>>)> = None;
+        for (backend, weight) in backends.iter().zip(weights) {
+            let load = match self.metric {
+                LoadMetric::Connections => backend.borrow().active_connections as f64,
+                LoadMetric::Requests => backend.borrow().active_requests as f64,
+                LoadMetric::ConnectionTime => backend.borrow_mut().peak_ewma_connection(),
+            };
+            let cost2 = weighted_load(load, weight);
 
-                b.map(|(_cost, backend)| backend)
+            match b {
+                Some((cost1, _)) if cost1 <= cost2 => {}
+                _ => b = Some((cost2, backend)),
             }
-        };
-        opt_b.map(|backend| (*backend).clone())
+        }
+
+        b.map(|(_cost, backend)| backend.clone())
     }
 }
 
@@ -132,12 +176,14 @@ impl LoadBalancingAlgorithm for PowerOfTwo {
         let mut first = None;
         let mut second = None;
 
-        for backend in backends.iter_mut() {
-            let measure = match self.metric {
+        let weights = weights(backends);
+        for (backend, weight) in backends.iter_mut().zip(weights) {
+            let load = match self.metric {
                 LoadMetric::Connections => backend.borrow().active_connections as f64,
                 LoadMetric::Requests => backend.borrow().active_requests as f64,
                 LoadMetric::ConnectionTime => backend.borrow_mut().peak_ewma_connection(),
             };
+            let measure = weighted_load(load, weight);
 
             if first.is_none() {
                 first = Some((measure, backend));
@@ -173,13 +219,136 @@ impl LoadBalancingAlgorithm for PowerOfTwo {
     }
 }
 
+/// the backend answering the fastest for its weight gets the request. The
+/// response times are a peak EWMA, multiplied by the requests in flight: a
+/// slow answer raises the cost of a backend right away, then it decays while
+/// the backend gets no traffic, so a backend that recovered is tried again.
+///
+/// The response time percentiles of the metrics are not used: they are only
+/// recorded once the metrics are enabled, and they do not decay
+#[derive(Debug)]
+pub struct LeastResponseTime;
+
+impl LoadBalancingAlgorithm for LeastResponseTime {
+    fn next_available_backend(
+        &mut self,
+        backends: &mut Vec<Rc<RefCell<Backend>>>,
+    ) -> Option<Rc<RefCell<Backend>>> {
+        let weights = weights(backends);
+        let mut b: Option<(f64, &Rc<RefCell<Backend>>)> = None;
+        for (backend, weight) in backends.iter().zip(weights) {
+            let cost2 = weighted_load(backend.borrow_mut().peak_ewma_response(), weight);
+
+            match b {
+                Some((cost1, _)) if cost1 <= cost2 => {}
+                _ => b = Some((cost2, backend)),
+            }
+        }
+
+        b.map(|(_cost, backend)| backend.clone())
+    }
+}
+
+/// points of a backend of weight 100 on the hash ring
+const RING_POINTS: usize = 160;
+
+/// consistent hashing on a ring: every backend gets points on the ring, derived
+/// from its id, in proportion to its weight. A key goes to the backend of the
+/// first point following its hash, so adding or removing a backend only moves
+/// the keys of the ring segments it gains or loses
+#[derive(Debug, Default)]
+pub struct ConsistentHash {
+    /// ids and weights of the backends the ring was built from
+    members: Vec<(String, u8)>,
+    /// points of the ring, sorted, with the id of their backend
+    ring: Vec<(u64, String)>,
+}
+
+impl ConsistentHash {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// builds the ring again if the available backends changed
+    fn update_ring(&mut self, backends: &[Rc<RefCell<Backend>>]) {
+        let mut members: Vec<(String, u8)> = backends
+            .iter()
+            .map(|b| {
+                let backend = b.borrow();
+                let weight = backend
+                    .load_balancing_parameters
+                    .as_ref()
+                    .map(|p| p.weight)
+                    .unwrap_or(100);
+                (backend.backend_id.clone(), weight)
+            })
+            .collect();
+        members.sort();
+
+        if members == self.members {
+            return;
+        }
+
+        // like the random algorithm, backends without weights are equals
+        let unweighted = members.iter().all(|(_, weight)| *weight == 0);
+        self.ring.clear();
+        for (backend_id, weight) in members.iter() {
+            let weight = if unweighted { 100 } else { *weight as usize };
+            for point in 0..RING_POINTS * weight / 100 {
+                self.ring
+                    .push((hash_of(&(backend_id, point)), backend_id.clone()));
+            }
+        }
+        self.ring.sort();
+        self.members = members;
+    }
+}
+
+fn hash_of<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl LoadBalancingAlgorithm for ConsistentHash {
+    /// the requests without a key are spread randomly
+    fn next_available_backend(
+        &mut self,
+        backends: &mut Vec<Rc<RefCell<Backend>>>,
+    ) -> Option<Rc<RefCell<Backend>>> {
+        Random.next_available_backend(backends)
+    }
+
+    fn next_backend_for_key(
+        &mut self,
+        backends: &mut Vec<Rc<RefCell<Backend>>>,
+        key: &[u8],
+    ) -> Option<Rc<RefCell<Backend>>> {
+        self.update_ring(backends);
+        if self.ring.is_empty() {
+            return None;
+        }
+
+        let hash = hash_of(&key);
+        let index = self.ring.partition_point(|(point, _)| *point < hash) % self.ring.len();
+        let backend_id = &self.ring[index].1;
+
+        backends
+            .iter()
+            .find(|b| b.borrow().backend_id == *backend_id)
+            .cloned()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::outlier_detection::OutlierStats;
     use crate::retry::{ExponentialBackoffPolicy, RetryPolicyWrapper};
-    use crate::sozu_command::proxy::LoadMetric;
+    use crate::sozu_command::proxy::{LoadBalancingParams, LoadMetric, Timeouts};
     use crate::{BackendStatus, PeakEWMA};
     use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use time::Duration;
 
     fn create_backend(id: String, connections: Option<usize>) -> Backend {
         Backend {
@@ -194,9 +363,23 @@ mod test {
             load_balancing_parameters: None,
             backup: false,
             connection_time: PeakEWMA::new(),
+            response_time: PeakEWMA::new(),
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            tls: None,
+            healthy: true,
+            outlier_detector: None,
+            outlier_stats: OutlierStats::default(),
         }
     }
 
+    fn weighted_backend(id: &str, port: u16, weight: Option<u8>) -> Backend {
+        let mut backend = create_backend(id.to_string(), None);
+        backend.address.set_port(port);
+        backend.load_balancing_parameters = weight.map(|weight| LoadBalancingParams { weight });
+        backend
+    }
+
     #[test]
     fn it_should_find_the_backend_with_least_connections() {
         let backend_with_least_connection =
@@ -234,19 +417,195 @@ mod test {
 
     #[test]
     fn it_should_find_backend_with_roundrobin_when_some_backends_were_removed() {
-        let mut backends = vec![
-            Rc::new(RefCell::new(create_backend("toto".to_string(), None))),
-            Rc::new(RefCell::new(create_backend("voto".to_string(), None))),
-            Rc::new(RefCell::new(create_backend("yoto".to_string(), None))),
-        ];
+        let mut backends: Vec<Rc<RefCell<Backend>>> = ["toto", "voto", "yoto"]
+            .iter()
+            .enumerate()
+            .map(|(i, id)| Rc::new(RefCell::new(weighted_backend(id, 8080 + i as u16, None))))
+            .collect();
 
-        let mut roundrobin = RoundRobin { next_backend: 1 };
+        let mut roundrobin = RoundRobin::new();
+        let backend = roundrobin.next_available_backend(&mut backends);
+        assert_eq!(backend.as_ref(), backends.first());
         let backend = roundrobin.next_available_backend(&mut backends);
         assert_eq!(backend.as_ref(), backends.get(1));
 
         backends.remove(1);
 
         let backend2 = roundrobin.next_available_backend(&mut backends);
-        assert_eq!(backend2.as_ref(), backends.get(0));
+        assert_eq!(backend2.as_ref(), backends.get(1));
+    }
+
+    #[test]
+    fn it_should_spread_the_requests_smoothly_by_weight() {
+        let mut backends: Vec<Rc<RefCell<Backend>>> = [("a", 5), ("b", 1), ("c", 1)]
+            .iter()
+            .enumerate()
+            .map(|(i, (id, weight))| {
+                Rc::new(RefCell::new(weighted_backend(
+                    id,
+                    8080 + i as u16,
+                    Some(*weight),
+                )))
+            })
+            .collect();
+
+        let mut roundrobin = RoundRobin::new();
+        let chosen: Vec<String> = (0..14)
+            .map(|_| {
+                roundrobin
+                    .next_available_backend(&mut backends)
+                    .unwrap()
+                    .borrow()
+                    .backend_id
+                    .clone()
+            })
+            .collect();
+        assert_eq!(chosen.concat(), "aabacaaaabacaa");
+
+        // the least connections for the weight
+        backends[0].borrow_mut().active_connections = 9;
+        backends[1].borrow_mut().active_connections = 2;
+        backends[2].borrow_mut().active_connections = 1;
+        let mut least_loaded = LeastLoaded {
+            metric: LoadMetric::Connections,
+        };
+        let backend = least_loaded.next_available_backend(&mut backends);
+        assert_eq!(backend.as_ref(), backends.get(2));
+        backends[2].borrow_mut().active_connections = 2;
+        let backend = least_loaded.next_available_backend(&mut backends);
+        assert_eq!(backend.as_ref(), backends.first());
+
+        // a backend of weight 0 gets no traffic
+        backends[0]
+            .borrow_mut()
+            .load_balancing_parameters
+            .as_mut()
+            .unwrap()
+            .weight = 0;
+        for _ in 0..10 {
+            let backend = Random.next_available_backend(&mut backends).unwrap();
+            assert_ne!(backend.borrow().backend_id, "a");
+            let backend = roundrobin.next_available_backend(&mut backends).unwrap();
+            assert_ne!(backend.borrow().backend_id, "a");
+        }
+    }
+
+    #[test]
+    fn it_should_prefer_the_backends_answering_the_fastest() {
+        let mut backends: Vec<Rc<RefCell<Backend>>> = ["slow", "fast"]
+            .iter()
+            .enumerate()
+            .map(|(i, id)| Rc::new(RefCell::new(weighted_backend(id, 8080 + i as u16, None))))
+            .collect();
+        backends[0]
+            .borrow_mut()
+            .set_response_time(Duration::milliseconds(500));
+        backends[1]
+            .borrow_mut()
+            .set_response_time(Duration::milliseconds(20));
+
+        let backend = LeastResponseTime.next_available_backend(&mut backends);
+        assert_eq!(backend.as_ref(), backends.get(1));
+
+        // the requests in flight add up
+        backends[1].borrow_mut().active_requests = 30;
+        let backend = LeastResponseTime.next_available_backend(&mut backends);
+        assert_eq!(backend.as_ref(), backends.first());
+        backends[1].borrow_mut().active_requests = 0;
+
+        // the slow backend got no traffic for a while, its response time decayed
+        backends[0].borrow_mut().response_time.last_event -= Duration::seconds(10);
+        let backend = LeastResponseTime.next_available_backend(&mut backends);
+        assert_eq!(backend.as_ref(), backends.first());
+    }
+
+    #[test]
+    fn it_should_send_fewer_requests_to_the_slower_backends() {
+        let mut backends: Vec<Rc<RefCell<Backend>>> = ["slow", "fast"]
+            .iter()
+            .enumerate()
+            .map(|(i, id)| Rc::new(RefCell::new(weighted_backend(id, 8080 + i as u16, None))))
+            .collect();
+        let latencies = [Duration::milliseconds(100), Duration::milliseconds(20)];
+
+        // batches of 10 requests in flight, answered before the next batch
+        let mut counts = [0usize; 2];
+        for _ in 0..10 {
+            let mut picked = Vec::new();
+            for _ in 0..10 {
+                let backend = LeastResponseTime
+                    .next_available_backend(&mut backends)
+                    .unwrap();
+                let index = backends
+                    .iter()
+                    .position(|b| Rc::ptr_eq(b, &backend))
+                    .unwrap();
+                backend.borrow_mut().active_requests += 1;
+                counts[index] += 1;
+                picked.push(index);
+            }
+            for index in picked {
+                let mut backend = backends[index].borrow_mut();
+                backend.active_requests -= 1;
+                backend.set_response_time(latencies[index]);
+            }
+        }
+
+        assert!(counts[0] > 0, "the slow backend still gets requests");
+        assert!(
+            counts[0] * 2 < counts[1],
+            "the slow backend got {} requests, the fast one {}",
+            counts[0],
+            counts[1]
+        );
+    }
+
+    #[test]
+    fn it_should_move_few_keys_when_a_backend_is_added_to_the_hash_ring() {
+        let mut backends: Vec<Rc<RefCell<Backend>>> = (0..4)
+            .map(|i| Rc::new(RefCell::new(create_backend(format!("backend-{}", i), None))))
+            .collect();
+        let mut consistent_hash = ConsistentHash::new();
+
+        let mut choose = |backends: &mut Vec<Rc<RefCell<Backend>>>| -> Vec<String> {
+            (0..1000)
+                .map(|key| {
+                    consistent_hash
+                        .next_backend_for_key(backends, format!("/cache/{}", key).as_bytes())
+                        .unwrap()
+                        .borrow()
+                        .backend_id
+                        .clone()
+                })
+                .collect()
+        };
+
+        let before = choose(&mut backends);
+        assert_eq!(before, choose(&mut backends));
+        for i in 0..4 {
+            let count = before
+                .iter()
+                .filter(|id| **id == format!("backend-{}", i))
+                .count();
+            assert!(count > 100, "backend-{} got {} keys", i, count);
+        }
+
+        // the new backend takes about a fifth of the keys, from all the others
+        backends.push(Rc::new(RefCell::new(create_backend(
+            "backend-4".to_string(),
+            None,
+        ))));
+        let after = choose(&mut backends);
+        let moved: Vec<usize> = (0..1000).filter(|i| before[*i] != after[*i]).collect();
+        assert!(
+            moved.len() > 100 && moved.len() < 300,
+            "{} keys moved",
+            moved.len()
+        );
+        assert!(moved.iter().all(|i| after[*i] == "backend-4"));
+
+        // removing it brings the keys back
+        backends.pop();
+        assert_eq!(before, choose(&mut backends));
     }
 }
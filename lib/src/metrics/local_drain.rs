@@ -1,7 +1,7 @@
 #![allow(dead_code)]
 use std::{collections::BTreeMap, str, time::Instant};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 use hdrhistogram::Histogram;
 
 use crate::sozu_command::proxy::{
@@ -87,6 +87,19 @@ pub fn histogram_to_percentiles(hist: &Histogram<u32>) -> Percentiles {
     }
 }
 
+/// a metric is selected by its name, or by a name ending with `*` that matches
+/// all the metrics starting with it, like `tls.*`. All metrics are selected if
+/// there are no names.
+fn is_selected(metric_names: &[String], key: &str) -> bool {
+    metric_names.is_empty()
+        || metric_names
+            .iter()
+            .any(|name| match name.strip_suffix('*') {
+                Some(prefix) => key.starts_with(prefix),
+                None => name == key,
+            })
+}
+
 #[derive(Clone, Debug)]
 pub struct BackendMetrics {
     pub cluster_id: String,
@@ -152,16 +165,18 @@ impl LocalDrain {
         self.cluster_metrics.clear();
     }
 
+    /// the clusters with metrics of their own, or metrics of their backends
     fn get_cluster_ids(&self) -> Vec<String> {
-        self.cluster_metrics
-            .iter()
-            .filter_map(
-                |entry| match self.backend_to_cluster.contains_key(entry.0) {
-                    true => Some(entry.0.to_owned()),
-                    false => None,
-                },
-            )
-            .collect()
+        let mut cluster_ids: Vec<String> = self
+            .cluster_metrics
+            .keys()
+            .filter(|id| !self.backend_to_cluster.contains_key(*id))
+            .chain(self.backend_to_cluster.values())
+            .cloned()
+            .collect();
+        cluster_ids.sort_unstable();
+        cluster_ids.dedup();
+        cluster_ids
     }
 
     fn get_backend_ids(&self, cluster_id: &str) -> Vec<String> {
@@ -219,10 +234,7 @@ impl LocalDrain {
         QueryAnswerMetrics::List((proxy_metrics_names, cluster_metrics_names))
     }
 
-    pub fn dump_all_metrics(
-        &mut self,
-        metric_names: &Vec<String>,
-    ) -> anyhow::Result<WorkerMetrics> {
+    pub fn dump_all_metrics(&mut self, metric_names: &[String]) -> anyhow::Result<WorkerMetrics> {
         Ok(WorkerMetrics {
             proxy: Some(self.dump_proxy_metrics(metric_names)),
             clusters: Some(self.dump_cluster_metrics(metric_names)?),
@@ -231,24 +243,18 @@ impl LocalDrain {
 
     pub fn dump_proxy_metrics(
         &mut self,
-        metric_names: &Vec<String>,
+        metric_names: &[String],
     ) -> BTreeMap<String, FilteredData> {
         self.proxy_metrics
             .iter()
-            .filter(|(key, _)| {
-                if metric_names.is_empty() {
-                    true
-                } else {
-                    metric_names.contains(key)
-                }
-            })
+            .filter(|(key, _)| is_selected(metric_names, key))
             .map(|(key, value)| (key.to_string(), value.to_filtered()))
             .collect()
     }
 
     pub fn dump_cluster_metrics(
         &mut self,
-        metric_names: &Vec<String>,
+        metric_names: &[String],
     ) -> anyhow::Result<BTreeMap<String, ClusterMetricsData>> {
         let mut cluster_data = BTreeMap::new();
 
@@ -268,27 +274,23 @@ impl LocalDrain {
     fn metrics_of_one_cluster(
         &self,
         cluster_id: &str,
-        metric_names: &Vec<String>,
+        metric_names: &[String],
     ) -> anyhow::Result<ClusterMetricsData> {
-        let raw_metrics = self.cluster_metrics.get(cluster_id).context(format!(
-            "No metrics found for cluster with id {}",
-            cluster_id
-        ))?;
+        let backend_ids = self.get_backend_ids(cluster_id);
+        // a cluster can have metrics of its backends only
+        let raw_metrics = match self.cluster_metrics.get(cluster_id) {
+            Some(raw_metrics) => raw_metrics.iter(),
+            None if !backend_ids.is_empty() => Default::default(),
+            None => bail!("No metrics found for cluster with id {}", cluster_id),
+        };
 
         let cluster: BTreeMap<String, FilteredData> = raw_metrics
-            .iter()
-            .filter(|entry| {
-                if metric_names.is_empty() {
-                    true
-                } else {
-                    metric_names.contains(entry.0)
-                }
-            })
+            .filter(|entry| is_selected(metric_names, entry.0))
             .map(|entry| (entry.0.to_owned(), entry.1.to_filtered()))
             .collect::<BTreeMap<String, FilteredData>>();
 
         let mut backends = BTreeMap::new();
-        for backend_id in self.get_backend_ids(cluster_id) {
+        for backend_id in backend_ids {
             let backend_metrics = self
                 .metrics_of_one_backend(&backend_id, metric_names)
                 .context(format!(
@@ -306,7 +308,7 @@ impl LocalDrain {
     fn metrics_of_one_backend(
         &self,
         backend_id: &str,
-        metric_names: &Vec<String>,
+        metric_names: &[String],
     ) -> anyhow::Result<BTreeMap<String, FilteredData>> {
         let backend_metrics = self.cluster_metrics.get(backend_id).context(format!(
             "No metrics found for backend with id {}",
@@ -315,13 +317,7 @@ impl LocalDrain {
 
         let filtered_backend_metrics = backend_metrics
             .iter()
-            .filter(|entry| {
-                if metric_names.is_empty() {
-                    true
-                } else {
-                    metric_names.contains(entry.0)
-                }
-            })
+            .filter(|entry| is_selected(metric_names, entry.0))
             .map(|entry| (entry.0.to_owned(), entry.1.to_filtered()))
             .collect::<BTreeMap<String, FilteredData>>();
 
@@ -331,7 +327,7 @@ impl LocalDrain {
     fn query_clusters(
         &mut self,
         cluster_ids: &Vec<String>,
-        metric_names: &Vec<String>,
+        metric_names: &[String],
     ) -> anyhow::Result<WorkerMetrics> {
         debug!("Querying cluster with ids: {:?}", cluster_ids);
         let mut clusters: BTreeMap<String, ClusterMetricsData> = BTreeMap::new();
@@ -353,7 +349,7 @@ impl LocalDrain {
     fn query_backends(
         &mut self,
         backend_ids: &Vec<String>,
-        metric_names: &Vec<String>,
+        metric_names: &[String],
     ) -> anyhow::Result<WorkerMetrics> {
         let mut clusters: BTreeMap<String, ClusterMetricsData> = BTreeMap::new();
 
@@ -476,3 +472,20 @@ impl Subscriber for LocalDrain {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_selected_by_name_or_prefix() {
+        assert!(is_selected(&[], "tls.sni.hit"));
+
+        let names = vec!["tls.*".to_string(), "accept_queue.connections".to_string()];
+        assert!(is_selected(&names, "tls.sni.hit"));
+        assert!(is_selected(&names, "tls.handshake.failed.client_alert"));
+        assert!(is_selected(&names, "accept_queue.connections"));
+        assert!(!is_selected(&names, "accept_queue.connections.timeout"));
+        assert!(!is_selected(&names, "openssl.sni.error"));
+    }
+}
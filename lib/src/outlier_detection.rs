@@ -0,0 +1,226 @@
+//! passive health checks of the backends. The results of the connections and
+//! requests sent to a backend during the normal traffic are counted, and the
+//! backends failing too much of it are ejected from the rotation for a while.
+//! The ejection time runs out on its own, the next result sent by the backend
+//! after it brings it back
+use std::cell::{Cell, RefCell};
+
+use time::{Duration, Instant};
+
+use crate::{
+    server::push_event,
+    sozu_command::proxy::{OutlierDetection, ProxyEvent},
+    Backend,
+};
+
+/// outlier detection of a cluster, shared by its backends
+#[derive(Debug, PartialEq)]
+pub struct OutlierDetector {
+    pub settings: OutlierDetection,
+    /// number of backends in the cluster
+    backends: Cell<usize>,
+    /// end of the ejections in progress
+    ejections: RefCell<Vec<Instant>>,
+}
+
+impl OutlierDetector {
+    pub fn new(settings: OutlierDetection, backends: usize) -> OutlierDetector {
+        OutlierDetector {
+            settings,
+            backends: Cell::new(backends),
+            ejections: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn set_backend_count(&self, backends: usize) {
+        self.backends.set(backends);
+    }
+
+    /// returns the end of the ejection if the share of ejected backends stays under
+    /// the maximum
+    fn eject(&self, now: Instant) -> Option<Instant> {
+        let mut ejections = self.ejections.borrow_mut();
+        ejections.retain(|end| *end > now);
+
+        if ejections.len() * 100
+            >= self.backends.get() * self.settings.max_ejection_percent as usize
+        {
+            return None;
+        }
+
+        let end = now + Duration::seconds(self.settings.ejection_time as i64);
+        ejections.push(end);
+        Some(end)
+    }
+}
+
+/// results of the traffic sent to a backend
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct OutlierStats {
+    consecutive_failures: u32,
+    /// results counted for the error rate, reset every `minimum_requests`
+    results: u32,
+    failures: u32,
+    ejected_until: Option<Instant>,
+}
+
+impl OutlierStats {
+    pub fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.map(|end| end > now).unwrap_or(false)
+    }
+
+    /// counts a result, returns true if the backend is an outlier
+    fn record(&mut self, settings: &OutlierDetection, failed: bool) -> bool {
+        self.results += 1;
+        if failed {
+            self.consecutive_failures += 1;
+            self.failures += 1;
+        } else {
+            self.consecutive_failures = 0;
+        }
+
+        let mut outlier = settings.consecutive_failures > 0
+            && self.consecutive_failures >= settings.consecutive_failures;
+
+        if self.results >= settings.minimum_requests {
+            if let Some(error_rate) = settings.error_rate {
+                outlier |= self.failures * 100 >= self.results * error_rate as u32;
+            }
+            self.results = 0;
+            self.failures = 0;
+        }
+
+        outlier
+    }
+}
+
+impl Backend {
+    /// counts the result of a connection or request sent to the backend, and ejects
+    /// it from the rotation if it is an outlier of its cluster
+    pub fn record_outcome(&mut self, cluster_id: Option<&str>, failed: bool) {
+        let detector = match &self.outlier_detector {
+            Some(detector) => detector.clone(),
+            None => return,
+        };
+        let now = Instant::now();
+
+        if let Some(end) = self.outlier_stats.ejected_until {
+            // the traffic sent before the ejection
+            if end > now {
+                return;
+            }
+
+            self.outlier_stats.ejected_until = None;
+            info!(
+                "backend server {} at {} is back from its ejection",
+                self.backend_id, self.address
+            );
+            incr!(
+                "outlier.returned",
+                cluster_id,
+                Some(self.backend_id.as_str())
+            );
+            push_event(ProxyEvent::BackendUp(self.backend_id.clone(), self.address));
+        }
+
+        if !self.outlier_stats.record(&detector.settings, failed) {
+            return;
+        }
+
+        match detector.eject(now) {
+            Some(end) => {
+                self.outlier_stats = OutlierStats {
+                    ejected_until: Some(end),
+                    ..Default::default()
+                };
+                error!(
+                    "backend server {} at {} fails too much of the traffic, it is ejected for {} seconds",
+                    self.backend_id, self.address, detector.settings.ejection_time
+                );
+                incr!(
+                    "outlier.ejected",
+                    cluster_id,
+                    Some(self.backend_id.as_str())
+                );
+                push_event(ProxyEvent::BackendDown(
+                    self.backend_id.clone(),
+                    self.address,
+                ));
+            }
+            None => {
+                warn!(
+                    "backend server {} at {} is an outlier, but too many backends are ejected",
+                    self.backend_id, self.address
+                );
+                incr!(
+                    "outlier.ejection_skipped",
+                    cluster_id,
+                    Some(self.backend_id.as_str())
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::rc::Rc;
+
+    #[test]
+    fn outliers_are_ejected() {
+        let settings = OutlierDetection {
+            consecutive_failures: 3,
+            error_rate: Some(50),
+            minimum_requests: 10,
+            ejection_time: 30,
+            max_ejection_percent: 50,
+        };
+
+        let mut stats = OutlierStats::default();
+        assert!(!stats.record(&settings, true));
+        assert!(!stats.record(&settings, true));
+        assert!(!stats.record(&settings, false));
+        assert!(!stats.record(&settings, true));
+        assert!(!stats.record(&settings, true));
+        assert!(stats.record(&settings, true));
+
+        // every other request fails
+        let mut stats = OutlierStats::default();
+        for i in 0..9 {
+            assert!(!stats.record(&settings, i % 2 == 0));
+        }
+        assert!(stats.record(&settings, false));
+
+        let detector = Rc::new(OutlierDetector::new(settings, 4));
+        let backend = |id: &str, port: u16| {
+            let mut backend = Backend::new(
+                id,
+                format!("127.0.0.1:{}", port).parse().unwrap(),
+                None,
+                None,
+                None,
+            );
+            backend.outlier_detector = Some(detector.clone());
+            backend
+        };
+        let mut backends = [
+            backend("b1", 1024),
+            backend("b2", 1025),
+            backend("b3", 1026),
+        ];
+
+        for backend in backends.iter_mut() {
+            for _ in 0..3 {
+                backend.record_outcome(Some("cluster"), true);
+            }
+        }
+
+        // half of the 4 backends of the cluster at most
+        let now = Instant::now();
+        assert!(!backends[0].can_open() && backends[0].outlier_stats.is_ejected(now));
+        assert!(!backends[1].can_open());
+        assert!(backends[2].can_open());
+    }
+}
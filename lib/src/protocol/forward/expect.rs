@@ -0,0 +1,321 @@
+use std::{cell::RefCell, rc::Rc, str::from_utf8};
+
+use mio::{net::TcpStream, *};
+use nom::{Err, HexDisplay};
+use rusty_ulid::Ulid;
+
+use crate::{
+    pool::Checkout,
+    protocol::pipe::Pipe,
+    protocol::ProtocolResult,
+    socket::{SocketHandler, SocketResult},
+    sozu_command::ready::Ready,
+    tcp::Listener,
+    Protocol, Readiness, SessionMetrics, SessionResult,
+};
+
+use super::{
+    parser::{
+        parse_greeting, parse_request_head, parse_socks_request, SOCKS_CONNECT,
+        SOCKS_NO_ACCEPTABLE_METHOD, SOCKS_NO_AUTHENTICATION, SOCKS_VERSION,
+    },
+    refusal_answer, Handshake, Refusal, Target,
+};
+
+/// a complete message of the client
+enum Message {
+    /// SOCKS5 greeting, offering to proceed without authentication or not
+    Greeting(bool),
+    Request(Result<Target, Refusal>),
+}
+
+/// reads the `CONNECT` request or the SOCKS5 handshake of a forward proxy
+/// session, the data sent after it stays in the buffer for the destination
+pub struct ExpectForward<Front: SocketHandler> {
+    pub frontend: Front,
+    pub frontend_token: Token,
+    pub request_id: Ulid,
+    pub front_buf: Checkout,
+    pub readiness: Readiness,
+    /// SOCKS5 handshakes are accepted besides the `CONNECT` requests
+    socks5: bool,
+    pub handshake: Option<Handshake>,
+    /// the SOCKS5 greeting was answered, the request comes next
+    greeted: bool,
+    pub target: Option<Target>,
+    /// answer still to be written to the client
+    answer: Vec<u8>,
+    /// the connection is closed once the answer is written
+    refused: bool,
+}
+
+impl<Front: SocketHandler> ExpectForward<Front> {
+    pub fn new(
+        frontend: Front,
+        frontend_token: Token,
+        request_id: Ulid,
+        front_buf: Checkout,
+        socks5: bool,
+    ) -> Self {
+        ExpectForward {
+            frontend,
+            frontend_token,
+            request_id,
+            front_buf,
+            readiness: Readiness {
+                interest: Ready::readable(),
+                event: Ready::empty(),
+            },
+            socks5,
+            handshake: None,
+            greeted: false,
+            target: None,
+            answer: Vec::new(),
+            refused: false,
+        }
+    }
+
+    pub fn readable(&mut self, metrics: &mut SessionMetrics) -> (ProtocolResult, SessionResult) {
+        let (sz, res) = self.frontend.socket_read(self.front_buf.space());
+        trace!(
+            "FRONT FORWARD [{:?}]: read {} bytes and res={:?}",
+            self.frontend_token,
+            sz,
+            res
+        );
+
+        if sz > 0 {
+            self.front_buf.fill(sz);
+
+            count!("bytes_in", sz as i64);
+            metrics.bin += sz;
+        } else {
+            self.readiness.event.remove(Ready::readable());
+        }
+
+        if res == SocketResult::Error {
+            error!(
+                "[{:?}] (expect forward) front socket error, closing the connection(read {}, wrote {})",
+                self.frontend_token, metrics.bin, metrics.bout
+            );
+            metrics.service_stop();
+            incr!("forward.errors");
+            self.readiness.reset();
+            return (ProtocolResult::Continue, SessionResult::CloseSession);
+        }
+
+        if res == SocketResult::WouldBlock {
+            self.readiness.event.remove(Ready::readable());
+        }
+
+        loop {
+            let data = self.front_buf.data();
+            let handshake = match (self.handshake, data.first()) {
+                (Some(handshake), _) => handshake,
+                (None, Some(version)) if self.socks5 && *version == SOCKS_VERSION => {
+                    Handshake::Socks5
+                }
+                (None, Some(_)) => Handshake::Connect,
+                (None, None) => {
+                    if res == SocketResult::Closed {
+                        metrics.service_stop();
+                        self.readiness.reset();
+                        return (ProtocolResult::Continue, SessionResult::CloseSession);
+                    }
+                    return (ProtocolResult::Continue, SessionResult::Continue);
+                }
+            };
+            self.handshake = Some(handshake);
+
+            let parsed = match handshake {
+                Handshake::Connect => {
+                    parse_request_head(data).map(|(rest, (method, authority))| {
+                        let request = if method == b"CONNECT" {
+                            from_utf8(authority)
+                                .ok()
+                                .and_then(Target::from_authority)
+                                .ok_or(Refusal::Invalid)
+                        } else {
+                            Err(Refusal::Unsupported)
+                        };
+                        (data.len() - rest.len(), Message::Request(request))
+                    })
+                }
+                Handshake::Socks5 if !self.greeted => {
+                    parse_greeting(data).map(|(rest, methods)| {
+                        (
+                            data.len() - rest.len(),
+                            Message::Greeting(methods.contains(&SOCKS_NO_AUTHENTICATION)),
+                        )
+                    })
+                }
+                Handshake::Socks5 => parse_socks_request(data).map(|(rest, (command, target))| {
+                    let request = if command == SOCKS_CONNECT {
+                        Ok(target)
+                    } else {
+                        Err(Refusal::Unsupported)
+                    };
+                    (data.len() - rest.len(), Message::Request(request))
+                }),
+            };
+
+            match parsed {
+                Ok((consumed, message)) => {
+                    self.front_buf.consume(consumed);
+                    match message {
+                        Message::Greeting(true) => {
+                            self.greeted = true;
+                            let res = self
+                                .send_answer(vec![SOCKS_VERSION, SOCKS_NO_AUTHENTICATION], metrics);
+                            if res != SessionResult::Continue {
+                                return (ProtocolResult::Continue, res);
+                            }
+                        }
+                        Message::Greeting(false) => {
+                            debug!(
+                                "[{:?}] the SOCKS5 client requires an authentication",
+                                self.frontend_token
+                            );
+                            incr!("forward.unsupported");
+                            self.refused = true;
+                            self.readiness.interest.remove(Ready::readable());
+                            let res = self.send_answer(
+                                vec![SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHOD],
+                                metrics,
+                            );
+                            return (ProtocolResult::Continue, res);
+                        }
+                        Message::Request(Ok(target)) => {
+                            trace!("got forward request to {}", target);
+                            self.target = Some(target);
+                            return (ProtocolResult::Upgrade, SessionResult::Continue);
+                        }
+                        Message::Request(Err(refusal)) => {
+                            return (ProtocolResult::Continue, self.refuse(refusal, metrics));
+                        }
+                    }
+                }
+                Err(Err::Incomplete(_)) if self.front_buf.available_space() > 0 => {
+                    if res == SocketResult::Closed {
+                        metrics.service_stop();
+                        self.readiness.reset();
+                        return (ProtocolResult::Continue, SessionResult::CloseSession);
+                    }
+                    return (ProtocolResult::Continue, SessionResult::Continue);
+                }
+                Err(Err::Incomplete(_)) => {
+                    error!(
+                        "[{:?}] the forward request does not fit in the buffer, closing the connection",
+                        self.frontend_token
+                    );
+                    return (
+                        ProtocolResult::Continue,
+                        self.refuse(Refusal::Invalid, metrics),
+                    );
+                }
+                Err(Err::Error(e)) | Err(Err::Failure(e)) => {
+                    error!(
+                        "[{:?}] expect forward front socket parse error, closing the connection:\n{}",
+                        self.frontend_token,
+                        e.input.to_hex(16)
+                    );
+                    return (
+                        ProtocolResult::Continue,
+                        self.refuse(Refusal::Invalid, metrics),
+                    );
+                }
+            }
+        }
+    }
+
+    /// answers the client with the refusal, then closes the connection
+    pub fn refuse(&mut self, refusal: Refusal, metrics: &mut SessionMetrics) -> SessionResult {
+        match refusal {
+            Refusal::Invalid => incr!("forward.errors"),
+            Refusal::Unsupported => incr!("forward.unsupported"),
+            Refusal::Forbidden => incr!("forward.denied"),
+            Refusal::Unreachable => incr!("forward.unreachable"),
+        }
+
+        self.refused = true;
+        self.readiness.interest.remove(Ready::readable());
+        let answer = refusal_answer(self.handshake.unwrap_or(Handshake::Connect), refusal);
+        self.send_answer(answer, metrics)
+    }
+
+    fn send_answer(&mut self, answer: Vec<u8>, metrics: &mut SessionMetrics) -> SessionResult {
+        self.answer.extend(answer);
+        self.writable(metrics)
+    }
+
+    /// writes the pending answer
+    pub fn writable(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
+        while !self.answer.is_empty() {
+            let (sz, res) = self.frontend.socket_write(&self.answer);
+            self.answer.drain(..sz);
+            count!("bytes_out", sz as i64);
+            metrics.bout += sz;
+
+            match res {
+                SocketResult::Error | SocketResult::Closed => {
+                    metrics.service_stop();
+                    self.readiness.reset();
+                    return SessionResult::CloseSession;
+                }
+                SocketResult::WouldBlock => {
+                    self.readiness.event.remove(Ready::writable());
+                    self.readiness.interest.insert(Ready::writable());
+                    return SessionResult::Continue;
+                }
+                SocketResult::Continue if sz == 0 => {
+                    self.readiness.interest.insert(Ready::writable());
+                    return SessionResult::Continue;
+                }
+                SocketResult::Continue => {}
+            }
+        }
+
+        self.readiness.interest.remove(Ready::writable());
+        if self.refused {
+            metrics.service_stop();
+            self.readiness.reset();
+            return SessionResult::CloseSession;
+        }
+        SessionResult::Continue
+    }
+
+    pub fn front_socket(&self) -> &TcpStream {
+        self.frontend.socket_ref()
+    }
+
+    pub fn readiness(&mut self) -> &mut Readiness {
+        &mut self.readiness
+    }
+
+    pub fn into_pipe(
+        self,
+        back_buf: Checkout,
+        listener: Rc<RefCell<Listener>>,
+    ) -> Pipe<Front, Listener> {
+        let addr = self.front_socket().peer_addr().ok();
+
+        let mut pipe = Pipe::new(
+            self.frontend,
+            self.frontend_token,
+            self.request_id,
+            None,
+            self.target.as_ref().map(|target| target.to_string()),
+            None,
+            None,
+            self.front_buf,
+            back_buf,
+            addr,
+            Protocol::TCP,
+            listener,
+        );
+
+        pipe.front_readiness.event = self.readiness.event;
+
+        pipe
+    }
+}
@@ -0,0 +1,153 @@
+//! handshakes of the forward proxy listeners: the clients ask for a
+//! destination with an HTTP `CONNECT` request or a SOCKS5 request, then the
+//! connection is tunneled to that destination
+use std::{fmt, net::IpAddr};
+
+pub mod expect;
+pub mod parser;
+
+/// SOCKS5 reply codes
+pub const SOCKS_SUCCEEDED: u8 = 0x00;
+pub const SOCKS_GENERAL_FAILURE: u8 = 0x01;
+pub const SOCKS_NOT_ALLOWED: u8 = 0x02;
+pub const SOCKS_HOST_UNREACHABLE: u8 = 0x04;
+pub const SOCKS_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+
+/// how the client asked for its destination, the answers follow the same protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handshake {
+    Connect,
+    Socks5,
+}
+
+/// why the proxy does not tunnel a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    /// the request could not be parsed
+    Invalid,
+    /// something other than a `CONNECT` request or a SOCKS5 `CONNECT` command
+    Unsupported,
+    /// the destination is not in the allowed destinations
+    Forbidden,
+    /// the name of the destination could not be resolved
+    Unreachable,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Host {
+    Name(String),
+    Address(IpAddr),
+}
+
+/// the destination requested by a client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub host: Host,
+    pub port: u16,
+}
+
+impl Target {
+    /// parses the `host:port` authority of a `CONNECT` request
+    pub fn from_authority(authority: &str) -> Option<Target> {
+        let (host, port) = authority.rsplit_once(':')?;
+        let port = port.parse().ok()?;
+
+        let host = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+            Some(address) => Host::Address(address.parse().ok()?),
+            None => match host.parse::<IpAddr>() {
+                Ok(address) => Host::Address(address),
+                Err(_) => Host::Name(parser::hostname(host.as_bytes())?),
+            },
+        };
+
+        Some(Target { host, port })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.host {
+            Host::Name(name) => write!(f, "{}:{}", name, self.port),
+            Host::Address(IpAddr::V6(address)) => write!(f, "[{}]:{}", address, self.port),
+            Host::Address(address) => write!(f, "{}:{}", address, self.port),
+        }
+    }
+}
+
+/// the answer telling the client that the tunnel is open
+pub fn success_answer(handshake: Handshake) -> Vec<u8> {
+    match handshake {
+        Handshake::Connect => b"HTTP/1.1 200 Connection Established\r\n\r\n".to_vec(),
+        Handshake::Socks5 => socks_reply(SOCKS_SUCCEEDED),
+    }
+}
+
+/// the answer sent before closing a connection that will not be tunneled
+pub fn refusal_answer(handshake: Handshake, refusal: Refusal) -> Vec<u8> {
+    match handshake {
+        Handshake::Connect => {
+            let status = match refusal {
+                Refusal::Invalid => "400 Bad Request",
+                Refusal::Unsupported => "405 Method Not Allowed",
+                Refusal::Forbidden => "403 Forbidden",
+                Refusal::Unreachable => "502 Bad Gateway",
+            };
+            format!(
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .into_bytes()
+        }
+        Handshake::Socks5 => socks_reply(match refusal {
+            Refusal::Invalid => SOCKS_GENERAL_FAILURE,
+            Refusal::Unsupported => SOCKS_COMMAND_NOT_SUPPORTED,
+            Refusal::Forbidden => SOCKS_NOT_ALLOWED,
+            Refusal::Unreachable => SOCKS_HOST_UNREACHABLE,
+        }),
+    }
+}
+
+/// a reply to a SOCKS5 request, without the bound address that the clients ignore
+fn socks_reply(code: u8) -> Vec<u8> {
+    vec![parser::SOCKS_VERSION, code, 0, 1, 0, 0, 0, 0, 0, 0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authorities() {
+        assert_eq!(
+            Target::from_authority("API.example.com:443"),
+            Some(Target {
+                host: Host::Name(String::from("api.example.com")),
+                port: 443
+            })
+        );
+        assert_eq!(
+            Target::from_authority("[2001:db8::1]:22").map(|t| t.to_string()),
+            Some(String::from("[2001:db8::1]:22"))
+        );
+        assert_eq!(
+            Target::from_authority("10.0.0.1:5432").map(|t| t.host),
+            Some(Host::Address("10.0.0.1".parse().unwrap()))
+        );
+        assert_eq!(Target::from_authority("example.com"), None);
+        assert_eq!(Target::from_authority("example.com:http"), None);
+        assert_eq!(Target::from_authority("exa mple.com:443"), None);
+    }
+
+    #[test]
+    fn answers() {
+        assert_eq!(
+            refusal_answer(Handshake::Connect, Refusal::Forbidden),
+            b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+        );
+        assert_eq!(
+            refusal_answer(Handshake::Socks5, Refusal::Forbidden),
+            vec![5, 2, 0, 1, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(success_answer(Handshake::Socks5)[1], SOCKS_SUCCEEDED);
+    }
+}
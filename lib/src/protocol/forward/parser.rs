@@ -0,0 +1,169 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use nom::{
+    bytes::streaming::{tag, take, take_while1, take_while_m_n},
+    character::is_digit,
+    combinator::map_opt,
+    error::{Error, ErrorKind, ParseError},
+    multi::{length_data, many_till},
+    number::streaming::{be_u16, be_u8},
+    sequence::terminated,
+    Err, IResult,
+};
+
+use super::{Host, Target};
+
+pub const SOCKS_VERSION: u8 = 0x05;
+pub const SOCKS_NO_AUTHENTICATION: u8 = 0x00;
+pub const SOCKS_NO_ACCEPTABLE_METHOD: u8 = 0xff;
+pub const SOCKS_CONNECT: u8 = 0x01;
+
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_DOMAIN_NAME: u8 = 0x03;
+const ADDRESS_IPV6: u8 = 0x04;
+
+fn is_token(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+fn is_name_byte(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'-' || c == b'_'
+}
+
+/// a lowercase host name, made of non empty labels
+pub fn hostname(name: &[u8]) -> Option<String> {
+    let name = name.strip_suffix(b".").unwrap_or(name);
+    if name.is_empty()
+        || !name
+            .split(|c| *c == b'.')
+            .all(|label| !label.is_empty() && label.iter().all(|c| is_name_byte(*c)))
+    {
+        return None;
+    }
+
+    std::str::from_utf8(name).ok().map(str::to_ascii_lowercase)
+}
+
+/// parses the head of an HTTP/1 request, and returns its method and target.
+/// The headers are not looked at
+pub fn parse_request_head(i: &[u8]) -> IResult<&[u8], (&[u8], &[u8])> {
+    let (i, method) = take_while1(is_token)(i)?;
+    let (i, _) = tag(" ")(i)?;
+    let (i, target) = take_while1(|c| c != b' ' && c != b'\r' && c != b'\n')(i)?;
+    let (i, _) = tag(" HTTP/1.")(i)?;
+    let (i, _) = take_while_m_n(1, 1, is_digit)(i)?;
+    let (i, _) = tag("\r\n")(i)?;
+    let (i, _) = many_till(
+        terminated(take_while1(|c| c != b'\r' && c != b'\n'), tag("\r\n")),
+        tag("\r\n"),
+    )(i)?;
+
+    Ok((i, (method, target)))
+}
+
+/// parses the first message of a SOCKS5 client, and returns the
+/// authentication methods it offers
+pub fn parse_greeting(i: &[u8]) -> IResult<&[u8], &[u8]> {
+    let (i, _) = tag(&[SOCKS_VERSION])(i)?;
+    length_data(be_u8)(i)
+}
+
+/// parses a SOCKS5 request, and returns its command and destination
+pub fn parse_socks_request(i: &[u8]) -> IResult<&[u8], (u8, Target)> {
+    let (i, _) = tag(&[SOCKS_VERSION])(i)?;
+    let (i, command) = be_u8(i)?;
+    let (i, _reserved) = tag(&[0x00])(i)?;
+    let (i, address_type) = be_u8(i)?;
+
+    let (i, host) = match address_type {
+        ADDRESS_IPV4 => {
+            let (i, octets) = take(4usize)(i)?;
+            let octets: [u8; 4] = octets.try_into().unwrap();
+            (i, Host::Address(IpAddr::V4(Ipv4Addr::from(octets))))
+        }
+        ADDRESS_IPV6 => {
+            let (i, octets) = take(16usize)(i)?;
+            let octets: [u8; 16] = octets.try_into().unwrap();
+            (i, Host::Address(IpAddr::V6(Ipv6Addr::from(octets))))
+        }
+        ADDRESS_DOMAIN_NAME => {
+            let (i, name) = map_opt(length_data(be_u8), hostname)(i)?;
+            (i, Host::Name(name))
+        }
+        _ => return Err(Err::Error(Error::from_error_kind(i, ErrorKind::Switch))),
+    };
+    let (i, port) = be_u16(i)?;
+
+    Ok((i, (command, Target { host, port })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_heads() {
+        let request = b"CONNECT api.example.com:443 HTTP/1.1\r\nHost: api.example.com:443\r\nProxy-Connection: keep-alive\r\n\r\n\x16\x03";
+        assert_eq!(
+            parse_request_head(&request[..]),
+            Ok((
+                &b"\x16\x03"[..],
+                (&b"CONNECT"[..], &b"api.example.com:443"[..])
+            ))
+        );
+        assert!(matches!(
+            parse_request_head(&request[..50]),
+            Err(Err::Incomplete(_))
+        ));
+        assert!(matches!(
+            parse_request_head(&b"GET / HTTP/2\r\n\r\n"[..]),
+            Err(Err::Error(_))
+        ));
+    }
+
+    #[test]
+    fn socks_messages() {
+        assert_eq!(
+            parse_greeting(&[5, 2, 0, 2][..]),
+            Ok((&[][..], &[0, 2][..]))
+        );
+        assert!(matches!(
+            parse_greeting(&[5, 2, 0][..]),
+            Err(Err::Incomplete(_))
+        ));
+        assert!(parse_greeting(&[4, 1, 0][..]).is_err());
+
+        let mut request = vec![5, 1, 0, 3, 11];
+        request.extend_from_slice(b"Example.COM");
+        request.extend_from_slice(&[0x01, 0xbb]);
+        assert_eq!(
+            parse_socks_request(&request),
+            Ok((
+                &[][..],
+                (
+                    SOCKS_CONNECT,
+                    Target {
+                        host: Host::Name(String::from("example.com")),
+                        port: 443
+                    }
+                )
+            ))
+        );
+
+        assert_eq!(
+            parse_socks_request(&[5, 1, 0, 1, 10, 0, 0, 1, 0, 22]).map(|(_, (_, t))| t),
+            Ok(Target {
+                host: Host::Address("10.0.0.1".parse().unwrap()),
+                port: 22
+            })
+        );
+        assert!(matches!(
+            parse_socks_request(&[5, 1, 0, 4, 0, 0]),
+            Err(Err::Incomplete(_))
+        ));
+        assert!(matches!(
+            parse_socks_request(&[5, 1, 0, 9, 0, 0]),
+            Err(Err::Error(_))
+        ));
+    }
+}
@@ -0,0 +1,760 @@
+//! client side of the HTTP/2 connections to the backends.
+//!
+//! Like `State` on the server side, it only handles bytes: the requests of
+//! the streams are queued as frames in `output`, and the responses parsed
+//! from the backend wait in their stream until the session hands them to
+//! the client
+use std::collections::BTreeMap;
+
+use hpack::Decoder;
+use nom::Err;
+
+use crate::protocol::http::parser::HeaderEdits;
+
+use super::{
+    convert::{H2Header, Response},
+    parser::{
+        frame, Continuation, Data, Frame, GoAway, H2Error, Headers, RstStream, Settings,
+        WindowUpdate, PREFACE, SETTINGS_ENABLE_PUSH, SETTINGS_HEADER_TABLE_SIZE,
+        SETTINGS_INITIAL_WINDOW_SIZE, SETTINGS_MAX_CONCURRENT_STREAMS, SETTINGS_MAX_FRAME_SIZE,
+        SETTINGS_MAX_HEADER_LIST_SIZE,
+    },
+    serializer::{
+        gen_data, gen_goaway, gen_headers, gen_ping_ack, gen_rst_stream, gen_settings,
+        gen_settings_ack, gen_window_update,
+    },
+    state::{
+        decode, HeaderEncoder, PendingHeaders, DEFAULT_WINDOW_SIZE, MAX_FRAME_SIZE,
+        MAX_HEADER_BLOCK_SIZE, MAX_HEADER_LIST_SIZE, MAX_PEER_FRAME_SIZE, MAX_WINDOW_SIZE,
+        OUTPUT_BUFFER_LIMIT,
+    },
+};
+
+/// streams opened before the backend gives its own limit
+const DEFAULT_MAX_CONCURRENT_STREAMS: usize = 100;
+/// the identifiers of the streams opened by a client are odd, up to this one
+const MAX_STREAM_ID: u32 = 0x7FFF_FFFF;
+/// response data the backend can send on the whole connection, for all the streams
+const CONNECTION_WINDOW_SIZE: i64 = 1 << 20;
+
+/// a request sent to the backend, and its response
+#[derive(Debug)]
+pub struct ClientStream {
+    pub id: u32,
+    /// the stream of the client carrying the request
+    pub front_stream_id: u32,
+    /// request data the backend is ready to receive
+    send_window: i64,
+    /// response data the backend can still send
+    recv_window: i64,
+    /// response data handed to the client, not yet given back to the window
+    recv_credit: i64,
+    /// response data received from the backend and not given back to its window
+    pub unreleased: i64,
+    /// the end of the request was sent
+    pub request_ended: bool,
+    /// the final response head was received
+    response_started: bool,
+    /// response head waiting to be handed to the client
+    pub response: Option<Response>,
+    pub body: Vec<u8>,
+    pub trailers: Vec<H2Header>,
+    pub response_ended: bool,
+    /// the stream was reset, by the backend or because of its response
+    pub reset: Option<H2Error>,
+    pub response_edits: HeaderEdits,
+}
+
+pub struct ClientState {
+    /// the first frame of the backend must be SETTINGS
+    settings_received: bool,
+    decoder: Decoder<'static>,
+    encoder: HeaderEncoder,
+    pub streams: BTreeMap<u32, ClientStream>,
+    next_stream_id: u32,
+    peer_max_concurrent_streams: usize,
+    peer_initial_window_size: i64,
+    peer_max_frame_size: usize,
+    /// request data the backend is ready to receive, on the whole connection
+    send_window: i64,
+    /// response data the backend can still send, on the whole connection
+    recv_window: i64,
+    /// response data handed to the clients, not yet given back to the connection window
+    recv_credit: i64,
+    pending_headers: Option<PendingHeaders>,
+    /// frames waiting to be written to the backend
+    pub output: Vec<u8>,
+    /// the backend sent GOAWAY or the connection failed, no stream can be opened
+    pub going_away: bool,
+    pub error: Option<H2Error>,
+}
+
+impl ClientState {
+    /// creates the connection state and queues the connection preface
+    pub fn new() -> ClientState {
+        let mut state = ClientState {
+            settings_received: false,
+            decoder: Decoder::new(),
+            encoder: HeaderEncoder::new(),
+            streams: BTreeMap::new(),
+            next_stream_id: 1,
+            peer_max_concurrent_streams: DEFAULT_MAX_CONCURRENT_STREAMS,
+            peer_initial_window_size: DEFAULT_WINDOW_SIZE,
+            peer_max_frame_size: MAX_FRAME_SIZE as usize,
+            send_window: DEFAULT_WINDOW_SIZE,
+            recv_window: CONNECTION_WINDOW_SIZE,
+            recv_credit: 0,
+            pending_headers: None,
+            output: PREFACE.to_vec(),
+            going_away: false,
+            error: None,
+        };
+
+        gen_settings(
+            &mut state.output,
+            &[
+                (SETTINGS_ENABLE_PUSH, 0),
+                (SETTINGS_MAX_HEADER_LIST_SIZE, MAX_HEADER_LIST_SIZE as u32),
+            ],
+        );
+        gen_window_update(
+            &mut state.output,
+            0,
+            (CONNECTION_WINDOW_SIZE - DEFAULT_WINDOW_SIZE) as u32,
+        );
+
+        state
+    }
+
+    /// a new stream fits in the limits of the backend
+    pub fn can_open_stream(&self) -> bool {
+        self.error.is_none()
+            && !self.going_away
+            && self.streams.len() < self.peer_max_concurrent_streams
+            && self.next_stream_id <= MAX_STREAM_ID
+    }
+
+    /// there is no stream in flight and no frame to write
+    pub fn is_idle(&self) -> bool {
+        self.streams.is_empty() && self.output.is_empty()
+    }
+
+    /// queues the headers of a request, returns the identifier of its stream
+    pub fn open_stream(
+        &mut self,
+        front_stream_id: u32,
+        headers: &[H2Header],
+        end_stream: bool,
+        response_edits: HeaderEdits,
+    ) -> u32 {
+        let id = self.next_stream_id;
+        self.next_stream_id += 2;
+
+        let block = self.encoder.encode(headers);
+        gen_headers(
+            &mut self.output,
+            id,
+            &block,
+            end_stream,
+            self.peer_max_frame_size,
+        );
+
+        self.streams.insert(
+            id,
+            ClientStream {
+                id,
+                front_stream_id,
+                send_window: self.peer_initial_window_size,
+                recv_window: DEFAULT_WINDOW_SIZE,
+                recv_credit: 0,
+                unreleased: 0,
+                request_ended: end_stream,
+                response_started: false,
+                response: None,
+                body: Vec::new(),
+                trailers: Vec::new(),
+                response_ended: false,
+                reset: None,
+                response_edits,
+            },
+        );
+        id
+    }
+
+    /// queues the request body of a stream as far as the flow control allows,
+    /// with its trailers once `ended` is set. Returns the size taken from `body`
+    pub fn send_request_data(
+        &mut self,
+        stream_id: u32,
+        body: &mut Vec<u8>,
+        ended: bool,
+        trailers: &[H2Header],
+    ) -> usize {
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) if !stream.request_ended && stream.reset.is_none() => stream,
+            _ => return 0,
+        };
+
+        let mut sent = 0;
+        while self.output.len() < OUTPUT_BUFFER_LIMIT {
+            let window = stream.send_window.min(self.send_window).max(0) as usize;
+            let size = (body.len() - sent)
+                .min(window)
+                .min(self.peer_max_frame_size);
+            let body_done = ended && sent + size == body.len();
+            if size == 0 && !body_done {
+                break;
+            }
+
+            let with_trailers = body_done && !trailers.is_empty();
+            if size > 0 || !with_trailers {
+                gen_data(
+                    &mut self.output,
+                    stream_id,
+                    &body[sent..sent + size],
+                    body_done && !with_trailers,
+                );
+                stream.send_window -= size as i64;
+                self.send_window -= size as i64;
+                sent += size;
+            }
+            if with_trailers {
+                let block = self.encoder.encode(trailers);
+                gen_headers(
+                    &mut self.output,
+                    stream_id,
+                    &block,
+                    true,
+                    self.peer_max_frame_size,
+                );
+            }
+            if body_done {
+                stream.request_ended = true;
+                break;
+            }
+        }
+
+        body.drain(..sent);
+        sent
+    }
+
+    /// `size` bytes of the response of a stream were handed to the client,
+    /// the backend can send as much again
+    pub fn release(&mut self, stream_id: u32, size: usize) {
+        if size == 0 {
+            return;
+        }
+        self.credit_connection(size as i64);
+
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.unreleased -= size as i64;
+            if stream.response_ended || stream.reset.is_some() {
+                return;
+            }
+            stream.recv_credit += size as i64;
+            if stream.recv_window <= DEFAULT_WINDOW_SIZE / 2 {
+                gen_window_update(&mut self.output, stream_id, stream.recv_credit as u32);
+                stream.recv_window += stream.recv_credit;
+                stream.recv_credit = 0;
+            }
+        }
+    }
+
+    fn credit_connection(&mut self, size: i64) {
+        if size <= 0 {
+            return;
+        }
+        self.recv_credit += size;
+        if self.recv_window <= CONNECTION_WINDOW_SIZE / 2 {
+            gen_window_update(&mut self.output, 0, self.recv_credit as u32);
+            self.recv_window += self.recv_credit;
+            self.recv_credit = 0;
+        }
+    }
+
+    /// forgets a stream, the backend is told to stop it if it is not over
+    pub fn remove_stream(&mut self, stream_id: u32) -> Option<ClientStream> {
+        let stream = self.streams.remove(&stream_id)?;
+        if stream.reset.is_none() && !(stream.request_ended && stream.response_ended) {
+            gen_rst_stream(&mut self.output, stream_id, H2Error::Cancel);
+        }
+        // the response data that will not reach the client is given back to the connection
+        self.credit_connection(stream.unreleased);
+        Some(stream)
+    }
+
+    /// no new stream is opened, the current ones can end
+    pub fn goaway(&mut self) {
+        if !self.going_away {
+            gen_goaway(&mut self.output, 0, H2Error::NoError);
+            self.going_away = true;
+        }
+    }
+
+    /// parses the frames read from the backend and returns the size consumed.
+    /// After a connection error, the connection must be closed
+    pub fn parse(&mut self, input: &[u8]) -> Result<usize, H2Error> {
+        let mut consumed = 0;
+
+        while self.error.is_none() {
+            let i = &input[consumed..];
+            match frame(i, MAX_FRAME_SIZE) {
+                Ok((remaining, f)) => {
+                    consumed += i.len() - remaining.len();
+                    if let Err(error) = self.handle_frame(f) {
+                        return Err(self.connection_error(error));
+                    }
+                }
+                Err(Err::Incomplete(_)) => break,
+                Err(Err::Error(e)) | Err(Err::Failure(e)) => {
+                    return Err(self.connection_error(e.h2_error()));
+                }
+            }
+        }
+
+        Ok(consumed)
+    }
+
+    fn connection_error(&mut self, error: H2Error) -> H2Error {
+        if self.error.is_none() {
+            debug!("HTTP/2 backend connection error: {:?}", error);
+            gen_goaway(&mut self.output, 0, error);
+            self.going_away = true;
+            self.error = Some(error);
+        }
+        error
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<(), H2Error> {
+        if !self.settings_received {
+            match &frame {
+                Frame::Settings(settings) if !settings.ack => self.settings_received = true,
+                _ => return Err(H2Error::ProtocolError),
+            }
+        }
+
+        // a header block is only followed by its CONTINUATION frames
+        if let Some(pending) = &self.pending_headers {
+            match &frame {
+                Frame::Continuation(c) if c.stream_id == pending.stream_id => {}
+                _ => return Err(H2Error::ProtocolError),
+            }
+        }
+
+        match frame {
+            Frame::Settings(settings) => self.handle_settings(settings),
+            Frame::Ping(ping) => {
+                if !ping.ack {
+                    gen_ping_ack(&mut self.output, &ping.payload);
+                }
+                Ok(())
+            }
+            Frame::GoAway(goaway) => {
+                self.handle_goaway(goaway);
+                Ok(())
+            }
+            Frame::WindowUpdate(window_update) => self.handle_window_update(window_update),
+            Frame::Headers(headers) => self.handle_headers(headers),
+            Frame::Continuation(continuation) => self.handle_continuation(continuation),
+            Frame::Data(data) => self.handle_data(data),
+            Frame::RstStream(rst_stream) => self.handle_rst_stream(rst_stream),
+            // the push was disabled in the settings
+            Frame::PushPromise(_) => Err(H2Error::ProtocolError),
+            Frame::Priority(_) | Frame::Unknown(_) => Ok(()),
+        }
+    }
+
+    fn handle_settings(&mut self, settings: Settings) -> Result<(), H2Error> {
+        if settings.ack {
+            return Ok(());
+        }
+
+        for setting in settings.settings {
+            match setting.identifier {
+                SETTINGS_HEADER_TABLE_SIZE => self.encoder.set_table_size(setting.value as usize),
+                SETTINGS_ENABLE_PUSH if setting.value != 0 => {
+                    return Err(H2Error::ProtocolError);
+                }
+                SETTINGS_MAX_CONCURRENT_STREAMS => {
+                    self.peer_max_concurrent_streams = setting.value as usize;
+                }
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = setting.value as i64;
+                    if value > MAX_WINDOW_SIZE {
+                        return Err(H2Error::FlowControlError);
+                    }
+                    let delta = value - self.peer_initial_window_size;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW_SIZE {
+                            return Err(H2Error::FlowControlError);
+                        }
+                    }
+                    self.peer_initial_window_size = value;
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(MAX_FRAME_SIZE..=MAX_PEER_FRAME_SIZE).contains(&setting.value) {
+                        return Err(H2Error::ProtocolError);
+                    }
+                    self.peer_max_frame_size = setting.value as usize;
+                }
+                _ => {}
+            }
+        }
+
+        gen_settings_ack(&mut self.output);
+        Ok(())
+    }
+
+    /// the streams the backend will not process are refused, the others can end
+    fn handle_goaway(&mut self, goaway: GoAway) {
+        if goaway.error_code != 0 {
+            error!(
+                "HTTP/2 backend sent GOAWAY, last stream {}, error code {}",
+                goaway.last_stream_id, goaway.error_code
+            );
+        }
+        self.going_away = true;
+        for stream in self.streams.values_mut() {
+            if stream.id > goaway.last_stream_id && stream.reset.is_none() {
+                stream.reset = Some(H2Error::RefusedStream);
+            }
+        }
+    }
+
+    /// the stream identifier was never used by this client
+    fn is_unknown_stream(&self, stream_id: u32) -> bool {
+        stream_id.is_multiple_of(2) || stream_id >= self.next_stream_id
+    }
+
+    fn handle_window_update(&mut self, window_update: WindowUpdate) -> Result<(), H2Error> {
+        let increment = window_update.increment as i64;
+
+        if window_update.stream_id == 0 {
+            if increment == 0 {
+                return Err(H2Error::ProtocolError);
+            }
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW_SIZE {
+                return Err(H2Error::FlowControlError);
+            }
+            return Ok(());
+        }
+
+        let unknown = self.is_unknown_stream(window_update.stream_id);
+        match self.streams.get_mut(&window_update.stream_id) {
+            Some(stream) => {
+                stream.send_window += increment;
+                if increment == 0 {
+                    self.reset_stream(window_update.stream_id, H2Error::ProtocolError);
+                } else if stream.send_window > MAX_WINDOW_SIZE {
+                    self.reset_stream(window_update.stream_id, H2Error::FlowControlError);
+                }
+                Ok(())
+            }
+            None if unknown => Err(H2Error::ProtocolError),
+            None => Ok(()),
+        }
+    }
+
+    fn handle_headers(&mut self, headers: Headers) -> Result<(), H2Error> {
+        if headers.end_headers {
+            return self.handle_header_block(
+                headers.stream_id,
+                headers.header_block_fragment,
+                headers.end_stream,
+            );
+        }
+
+        self.pending_headers = Some(PendingHeaders {
+            stream_id: headers.stream_id,
+            block: headers.header_block_fragment.to_vec(),
+            end_stream: headers.end_stream,
+        });
+        Ok(())
+    }
+
+    fn handle_continuation(&mut self, continuation: Continuation) -> Result<(), H2Error> {
+        let mut pending = match self.pending_headers.take() {
+            Some(pending) => pending,
+            None => return Err(H2Error::ProtocolError),
+        };
+
+        if pending.block.len() + continuation.header_block_fragment.len() > MAX_HEADER_BLOCK_SIZE {
+            return Err(H2Error::EnhanceYourCalm);
+        }
+        pending
+            .block
+            .extend_from_slice(continuation.header_block_fragment);
+
+        if continuation.end_headers {
+            self.handle_header_block(pending.stream_id, &pending.block, pending.end_stream)
+        } else {
+            self.pending_headers = Some(pending);
+            Ok(())
+        }
+    }
+
+    /// decodes a complete header block, the response head of a stream or its trailers
+    fn handle_header_block(
+        &mut self,
+        stream_id: u32,
+        block: &[u8],
+        end_stream: bool,
+    ) -> Result<(), H2Error> {
+        // always decoded, the HPACK tables must stay in sync with the backend
+        let headers = decode(&mut self.decoder, block)?;
+
+        let unknown = self.is_unknown_stream(stream_id);
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None if unknown => return Err(H2Error::ProtocolError),
+            None => return Ok(()),
+        };
+        if stream.response_ended || stream.reset.is_some() {
+            self.reset_stream(stream_id, H2Error::StreamClosed);
+            return Ok(());
+        }
+        let headers = match headers {
+            Some(headers) => headers,
+            None => {
+                self.reset_stream(stream_id, H2Error::InternalError);
+                return Ok(());
+            }
+        };
+
+        if !stream.response_started {
+            match Response::from_headers(headers) {
+                Ok(Some(response)) => {
+                    stream.response_started = true;
+                    stream.response = Some(response);
+                    stream.response_ended = end_stream;
+                }
+                // an interim response cannot end the stream
+                Ok(None) if !end_stream => {}
+                _ => self.reset_stream(stream_id, H2Error::ProtocolError),
+            }
+        } else if end_stream && headers.iter().all(|(name, _)| !name.starts_with(b":")) {
+            stream.trailers = headers;
+            stream.response_ended = true;
+        } else {
+            self.reset_stream(stream_id, H2Error::ProtocolError);
+        }
+        Ok(())
+    }
+
+    fn handle_data(&mut self, data: Data) -> Result<(), H2Error> {
+        let length = data.flow_controlled_len as i64;
+        if length > self.recv_window {
+            return Err(H2Error::FlowControlError);
+        }
+        self.recv_window -= length;
+
+        let unknown = self.is_unknown_stream(data.stream_id);
+        let stream = match self.streams.get_mut(&data.stream_id) {
+            Some(stream) => stream,
+            None if unknown => return Err(H2Error::ProtocolError),
+            None => {
+                self.credit_connection(length);
+                return Ok(());
+            }
+        };
+
+        let error = if !stream.response_started {
+            Some(H2Error::ProtocolError)
+        } else if stream.response_ended || stream.reset.is_some() {
+            Some(H2Error::StreamClosed)
+        } else if length > stream.recv_window {
+            Some(H2Error::FlowControlError)
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            self.credit_connection(length);
+            self.reset_stream(data.stream_id, error);
+            return Ok(());
+        }
+
+        stream.recv_window -= length;
+        stream.unreleased += length;
+        stream.body.extend_from_slice(data.payload);
+        stream.response_ended = data.end_stream;
+        // the padding is not kept, the backend gets it back right away
+        let padding = length as usize - data.payload.len();
+        self.release(data.stream_id, padding);
+        Ok(())
+    }
+
+    fn handle_rst_stream(&mut self, rst_stream: RstStream) -> Result<(), H2Error> {
+        let unknown = self.is_unknown_stream(rst_stream.stream_id);
+        match self.streams.get_mut(&rst_stream.stream_id) {
+            Some(stream) => {
+                let error = H2Error::from_code(rst_stream.error_code);
+                if stream.response_ended && error == H2Error::NoError {
+                    // the backend answered without reading the whole request
+                    stream.request_ended = true;
+                } else if stream.reset.is_none() {
+                    debug!(
+                        "HTTP/2 backend reset stream {} with error code {}",
+                        rst_stream.stream_id, rst_stream.error_code
+                    );
+                    stream.reset = Some(error);
+                }
+                Ok(())
+            }
+            None if unknown => Err(H2Error::ProtocolError),
+            None => Ok(()),
+        }
+    }
+
+    /// ends a stream with a stream error, it stays until the session removes it
+    fn reset_stream(&mut self, stream_id: u32, error: H2Error) {
+        gen_rst_stream(&mut self.output, stream_id, error);
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.reset = Some(error);
+        }
+    }
+}
+
+impl Default for ClientState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hpack::Encoder;
+
+    fn server_headers(stream_id: u32, headers: &[(&[u8], &[u8])], end_stream: bool) -> Vec<u8> {
+        let block = Encoder::new().encode(headers.iter().copied());
+        let mut output = Vec::new();
+        gen_headers(&mut output, stream_id, &block, end_stream, 16384);
+        output
+    }
+
+    fn connected_state() -> ClientState {
+        let mut state = ClientState::new();
+        assert!(state.output.starts_with(PREFACE));
+        let mut input = Vec::new();
+        gen_settings(&mut input, &[(SETTINGS_MAX_CONCURRENT_STREAMS, 2)]);
+        assert_eq!(state.parse(&input), Ok(input.len()));
+        state.output.clear();
+        state
+    }
+
+    fn request() -> Vec<H2Header> {
+        vec![
+            (b":method".to_vec(), b"POST".to_vec()),
+            (b":scheme".to_vec(), b"http".to_vec()),
+            (b":path".to_vec(), b"/echo.Echo/Say".to_vec()),
+        ]
+    }
+
+    #[test]
+    fn multiplexed_streams() {
+        let mut state = connected_state();
+
+        let first = state.open_stream(1, &request(), false, HeaderEdits::default());
+        let second = state.open_stream(3, &request(), true, HeaderEdits::default());
+        assert_eq!((first, second), (1, 3));
+        // the backend accepts two streams at once
+        assert!(!state.can_open_stream());
+
+        let mut body = b"hello".to_vec();
+        let trailers = vec![(b"x-checksum".to_vec(), b"1".to_vec())];
+        assert_eq!(state.send_request_data(1, &mut body, true, &trailers), 5);
+        assert!(body.is_empty());
+        assert!(state.streams[&1].request_ended);
+
+        let (i, _) = frame(&state.output, 16384).unwrap();
+        let (i, _) = frame(i, 16384).unwrap();
+        let (i, data) = frame(i, 16384).unwrap();
+        assert_eq!(
+            data,
+            Frame::Data(Data {
+                stream_id: 1,
+                payload: &b"hello"[..],
+                end_stream: false,
+                flow_controlled_len: 5,
+            })
+        );
+        let (i, trailers) = frame(i, 16384).unwrap();
+        assert!(matches!(
+            trailers,
+            Frame::Headers(Headers {
+                stream_id: 1,
+                end_stream: true,
+                ..
+            })
+        ));
+        assert!(i.is_empty());
+
+        // the responses arrive interleaved
+        let mut input = server_headers(3, &[(b":status", b"200")], false);
+        input.extend(server_headers(1, &[(b":status", b"100")], false));
+        input.extend(server_headers(1, &[(b":status", b"204")], true));
+        gen_data(&mut input, 3, b"world", true);
+        assert_eq!(state.parse(&input), Ok(input.len()));
+
+        let stream = &state.streams[&1];
+        assert_eq!(stream.response.as_ref().map(|r| r.status), Some(204));
+        assert!(stream.response_ended);
+        let stream = &state.streams[&3];
+        assert_eq!(stream.body, b"world");
+        assert_eq!(stream.unreleased, 5);
+        assert!(stream.response_ended);
+
+        state.output.clear();
+        assert!(state.remove_stream(1).is_some());
+        assert!(state.remove_stream(3).is_some());
+        // both streams ended on both sides, nothing is reset
+        assert!(state.output.is_empty());
+        assert!(state.can_open_stream());
+    }
+
+    #[test]
+    fn refused_and_reset_streams() {
+        let mut state = connected_state();
+        state.open_stream(1, &request(), true, HeaderEdits::default());
+        state.open_stream(3, &request(), true, HeaderEdits::default());
+
+        let mut input = Vec::new();
+        gen_rst_stream(&mut input, 1, H2Error::InternalError);
+        gen_goaway(&mut input, 1, H2Error::NoError);
+        assert_eq!(state.parse(&input), Ok(input.len()));
+        assert_eq!(state.streams[&1].reset, Some(H2Error::InternalError));
+        assert_eq!(state.streams[&3].reset, Some(H2Error::RefusedStream));
+        assert!(state.going_away);
+        assert!(!state.can_open_stream());
+
+        // DATA before the response head
+        let mut state = connected_state();
+        state.open_stream(1, &request(), true, HeaderEdits::default());
+        let mut input = Vec::new();
+        gen_data(&mut input, 1, b"data", true);
+        assert_eq!(state.parse(&input), Ok(input.len()));
+        assert_eq!(state.streams[&1].reset, Some(H2Error::ProtocolError));
+    }
+
+    #[test]
+    fn connection_errors() {
+        // the first frame must be SETTINGS
+        let mut state = ClientState::new();
+        let mut input = Vec::new();
+        gen_window_update(&mut input, 0, 10);
+        assert_eq!(state.parse(&input), Err(H2Error::ProtocolError));
+        assert!(!state.can_open_stream());
+
+        // streams are opened by the client only
+        let mut state = connected_state();
+        let input = server_headers(2, &[(b":status", b"200")], true);
+        assert_eq!(state.parse(&input), Err(H2Error::ProtocolError));
+
+        let mut state = connected_state();
+        let mut input = Vec::new();
+        gen_settings(&mut input, &[(SETTINGS_ENABLE_PUSH, 1)]);
+        assert_eq!(state.parse(&input), Err(H2Error::ProtocolError));
+    }
+}
@@ -0,0 +1,873 @@
+//! translation of the HTTP/2 streams to HTTP/1.1 requests, and of the HTTP/1.1
+//! responses of the backends to HTTP/2 headers and data. The streams sent to
+//! HTTP/2 backends only get their headers edited
+use std::str::from_utf8;
+
+use nom::Err;
+
+use crate::protocol::http::parser::{
+    compare_no_case, is_token, response_head, ForwardedHeaders, Header, HeaderEdits, Method,
+    StatusLine,
+};
+
+use super::parser::H2Error;
+
+/// a header as sent in HPACK blocks, the name in lowercase
+pub type H2Header = (Vec<u8>, Vec<u8>);
+
+/// headers specific to an HTTP/1.1 connection, they cannot appear in HTTP/2
+const CONNECTION_HEADERS: [&[u8]; 5] = [
+    b"connection",
+    b"keep-alive",
+    b"proxy-connection",
+    b"transfer-encoding",
+    b"upgrade",
+];
+
+/// a status line and headers larger than this are rejected
+pub const MAX_RESPONSE_HEAD_SIZE: usize = 65536;
+
+fn is_connection_header(name: &[u8]) -> bool {
+    CONNECTION_HEADERS
+        .iter()
+        .any(|header| compare_no_case(header, name))
+}
+
+fn trim(value: &[u8]) -> &[u8] {
+    let start = value
+        .iter()
+        .position(|c| !c.is_ascii_whitespace())
+        .unwrap_or(value.len());
+    let end = value
+        .iter()
+        .rposition(|c| !c.is_ascii_whitespace())
+        .map_or(start, |position| position + 1);
+    &value[start..end]
+}
+
+fn is_valid_value(value: &[u8]) -> bool {
+    !value.iter().any(|c| matches!(c, b'\0' | b'\r' | b'\n'))
+}
+
+/// headers formatted as `Name: value\r\n`, with the names in lowercase
+fn header_lines(lines: &[u8]) -> impl Iterator<Item = H2Header> + '_ {
+    lines.split(|c| *c == b'\n').filter_map(|line| {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let position = line.iter().position(|c| *c == b':')?;
+        Some((
+            line[..position].to_ascii_lowercase(),
+            trim(&line[position + 1..]).to_vec(),
+        ))
+    })
+}
+
+/// a request received on a stream, with its pseudo-headers set apart
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub method: Method,
+    pub authority: Option<String>,
+    pub path: String,
+    /// regular headers, in lowercase
+    pub headers: Vec<H2Header>,
+    pub content_length: Option<usize>,
+}
+
+impl Request {
+    /// checks a decoded header block as a request, the malformed requests are
+    /// stream errors
+    pub fn from_headers(headers: Vec<H2Header>) -> Result<Request, H2Error> {
+        let mut method = None;
+        let mut scheme = None;
+        let mut authority = None;
+        let mut path = None;
+        let mut regular_headers: Vec<H2Header> = Vec::with_capacity(headers.len());
+        let mut content_length = None;
+
+        for (name, value) in headers {
+            if !is_valid_value(&value) {
+                return Err(H2Error::ProtocolError);
+            }
+
+            if let Some(pseudo_header) = name.strip_prefix(b":") {
+                // pseudo-headers come first, once each
+                if !regular_headers.is_empty() {
+                    return Err(H2Error::ProtocolError);
+                }
+
+                let value = String::from_utf8(value).map_err(|_| H2Error::ProtocolError)?;
+                let field = match pseudo_header {
+                    b"method" => &mut method,
+                    b"scheme" => &mut scheme,
+                    b"authority" => &mut authority,
+                    b"path" => &mut path,
+                    _ => return Err(H2Error::ProtocolError),
+                };
+                if field.replace(value).is_some() {
+                    return Err(H2Error::ProtocolError);
+                }
+                continue;
+            }
+
+            if !is_token(&name) || name.iter().any(|c| c.is_ascii_uppercase()) {
+                return Err(H2Error::ProtocolError);
+            }
+            if is_connection_header(&name) || (name == b"te" && value != b"trailers") {
+                return Err(H2Error::ProtocolError);
+            }
+            if name == b"content-length" {
+                let length = from_utf8(&value)
+                    .ok()
+                    .and_then(|length| length.parse::<usize>().ok())
+                    .ok_or(H2Error::ProtocolError)?;
+                if matches!(content_length.replace(length), Some(l) if l != length) {
+                    return Err(H2Error::ProtocolError);
+                }
+                // added back once when converting the request
+                continue;
+            }
+
+            regular_headers.push((name, value));
+        }
+
+        let method = method.ok_or(H2Error::ProtocolError)?;
+        if !is_token(method.as_bytes()) {
+            return Err(H2Error::ProtocolError);
+        }
+        let method = Method::new(method.as_bytes());
+        // tunnels are not proxied over HTTP/2, the client can use HTTP/1.1 for them
+        if method == Method::Connect {
+            return Err(H2Error::HTTP11Required);
+        }
+
+        let path = path.ok_or(H2Error::ProtocolError)?;
+        let valid_path = path.starts_with('/') || (method == Method::Options && path == "*");
+        if scheme.is_none() || !valid_path || path.bytes().any(|c| c <= b' ' || c == 127) {
+            return Err(H2Error::ProtocolError);
+        }
+
+        if matches!(&authority, Some(authority) if authority.bytes().any(|c| c <= b' ' || c == 127))
+        {
+            return Err(H2Error::ProtocolError);
+        }
+
+        Ok(Request {
+            method,
+            authority,
+            path,
+            headers: regular_headers,
+            content_length,
+        })
+    }
+
+    /// the host of the request, the `:authority` pseudo-header takes precedence over the Host header
+    pub fn host(&self) -> Option<&str> {
+        self.authority.as_deref().or_else(|| {
+            self.headers
+                .iter()
+                .find(|(name, _)| name == b"host")
+                .and_then(|(_, value)| from_utf8(value).ok())
+        })
+    }
+
+    /// the regular headers as the HTTP/1.1 parser gives them, for the routing
+    pub fn routing_headers(&self) -> Vec<Header> {
+        self.headers
+            .iter()
+            .map(|(name, value)| Header {
+                name: name.clone(),
+                value: value.clone(),
+            })
+            .collect()
+    }
+
+    /// values of the forwarding headers sent by the client
+    pub fn forwarded_headers(&self) -> ForwardedHeaders {
+        let mut forwarded = ForwardedHeaders::default();
+        for (name, value) in self.headers.iter() {
+            let value = String::from_utf8_lossy(value).to_string();
+            match name.as_slice() {
+                b"x-forwarded-proto" => forwarded.x_proto = true,
+                b"x-forwarded-host" => forwarded.x_host = true,
+                b"x-forwarded-port" => forwarded.x_port = true,
+                b"x-forwarded-for" => {
+                    forwarded.x_for = Some(match forwarded.x_for.take() {
+                        Some(previous) => format!("{}, {}", previous, value),
+                        None => value,
+                    })
+                }
+                b"forwarded" => {
+                    forwarded.forwarded = Some(match forwarded.forwarded.take() {
+                        Some(previous) => format!("{}, {}", previous, value),
+                        None => value,
+                    })
+                }
+                _ => {}
+            }
+        }
+        forwarded
+    }
+
+    /// the request line and headers sent to the backend. The body is chunked
+    /// if the client did not give its length and may send one.
+    /// `forwarding_headers` are formatted as `Name: value\r\n`, they replace the
+    /// X-Forwarded-For and Forwarded headers of the client
+    pub fn to_http1(
+        &self,
+        edits: &HeaderEdits,
+        forwarding_headers: &str,
+        chunked: bool,
+    ) -> Vec<u8> {
+        let mut head = Vec::with_capacity(512);
+        head.extend_from_slice(format!("{} {} HTTP/1.1\r\n", self.method, self.path).as_bytes());
+
+        if let Some(authority) = &self.authority {
+            if !edits.should_remove(b"Host") {
+                head.extend_from_slice(b"Host: ");
+                head.extend_from_slice(authority.as_bytes());
+                head.extend_from_slice(b"\r\n");
+            }
+        }
+
+        let mut cookies: Vec<&[u8]> = Vec::new();
+        for (name, value) in self.headers.iter() {
+            if edits.should_remove(name)
+                || (name == b"host" && self.authority.is_some())
+                || name == b"x-forwarded-for"
+                || name == b"forwarded"
+            {
+                continue;
+            }
+
+            // the cookies may be split in several headers, HTTP/1.1 expects one
+            if name == b"cookie" {
+                cookies.push(value);
+                continue;
+            }
+
+            head.extend_from_slice(name);
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value);
+            head.extend_from_slice(b"\r\n");
+        }
+
+        if !cookies.is_empty() && !edits.should_remove(b"Cookie") {
+            head.extend_from_slice(b"cookie: ");
+            head.extend_from_slice(&cookies.join(&b"; "[..]));
+            head.extend_from_slice(b"\r\n");
+        }
+
+        match self.content_length {
+            Some(length) => {
+                head.extend_from_slice(format!("Content-Length: {}\r\n", length).as_bytes())
+            }
+            None if chunked => head.extend_from_slice(b"Transfer-Encoding: chunked\r\n"),
+            None => {}
+        }
+
+        head.extend_from_slice(&edits.added);
+        head.extend_from_slice(forwarding_headers.as_bytes());
+        head.extend_from_slice(b"\r\n");
+        head
+    }
+
+    /// the header block sent to an HTTP/2 backend, with the same edits as
+    /// `to_http1`. A Host header added by the edits becomes the `:authority`
+    pub fn to_h2(
+        &self,
+        edits: &HeaderEdits,
+        forwarding_headers: &str,
+        scheme: &str,
+    ) -> Vec<H2Header> {
+        let mut authority = if edits.should_remove(b"Host") {
+            None
+        } else {
+            self.host().map(|host| host.as_bytes().to_vec())
+        };
+
+        let mut added = Vec::new();
+        for (name, value) in
+            header_lines(&edits.added).chain(header_lines(forwarding_headers.as_bytes()))
+        {
+            if name == b"host" {
+                authority = Some(value);
+            } else if !is_connection_header(&name) {
+                added.push((name, value));
+            }
+        }
+
+        let mut headers = Vec::with_capacity(self.headers.len() + added.len() + 5);
+        headers.push((b":method".to_vec(), self.method.to_string().into_bytes()));
+        headers.push((b":scheme".to_vec(), scheme.as_bytes().to_vec()));
+        if let Some(authority) = authority {
+            headers.push((b":authority".to_vec(), authority));
+        }
+        headers.push((b":path".to_vec(), self.path.as_bytes().to_vec()));
+
+        for (name, value) in self.headers.iter() {
+            if edits.should_remove(name)
+                || name == b"host"
+                || name == b"x-forwarded-for"
+                || name == b"forwarded"
+            {
+                continue;
+            }
+            headers.push((name.clone(), value.clone()));
+        }
+
+        if let Some(length) = self.content_length {
+            headers.push((b"content-length".to_vec(), length.to_string().into_bytes()));
+        }
+        headers.extend(added);
+        headers
+    }
+}
+
+/// the status and headers of a response, ready to be encoded in a HEADERS frame
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<H2Header>,
+}
+
+impl Response {
+    /// checks a decoded header block as the response of a backend, the
+    /// interim responses give `None`
+    pub fn from_headers(headers: Vec<H2Header>) -> Result<Option<Response>, H2Error> {
+        let mut status = None;
+        let mut regular_headers: Vec<H2Header> = Vec::with_capacity(headers.len());
+
+        for (name, value) in headers {
+            if !is_valid_value(&value) {
+                return Err(H2Error::ProtocolError);
+            }
+
+            if name.starts_with(b":") {
+                if name != b":status" || !regular_headers.is_empty() || status.is_some() {
+                    return Err(H2Error::ProtocolError);
+                }
+                status = from_utf8(&value)
+                    .ok()
+                    .and_then(|status| status.parse::<u16>().ok())
+                    .filter(|status| (100..1000).contains(status));
+                if status.is_none() {
+                    return Err(H2Error::ProtocolError);
+                }
+                continue;
+            }
+
+            if !is_token(&name)
+                || name.iter().any(|c| c.is_ascii_uppercase())
+                || is_connection_header(&name)
+            {
+                return Err(H2Error::ProtocolError);
+            }
+            regular_headers.push((name, value));
+        }
+
+        match status {
+            None | Some(101) => Err(H2Error::ProtocolError),
+            Some(100..=199) => Ok(None),
+            Some(status) => Ok(Some(Response {
+                status,
+                headers: regular_headers,
+            })),
+        }
+    }
+
+    /// the headers with the `:status` pseudo-header first
+    pub fn h2_headers(&self) -> Vec<H2Header> {
+        let mut headers = Vec::with_capacity(self.headers.len() + 1);
+        headers.push((b":status".to_vec(), self.status.to_string().into_bytes()));
+        headers.extend(self.headers.iter().cloned());
+        headers
+    }
+
+    /// applies the header edits of the cluster and listener to the response
+    pub fn edit(&mut self, edits: &HeaderEdits) {
+        if edits.is_empty() {
+            return;
+        }
+
+        self.headers.retain(|(name, _)| !edits.should_remove(name));
+        self.headers.extend(header_lines(&edits.added));
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ChunkState {
+    Size,
+    Data(usize),
+    DataEnd,
+    Trailers,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ResponseState {
+    Head,
+    Length(usize),
+    Chunked(ChunkState),
+    UntilClose,
+    Done,
+}
+
+/// incremental parser of the HTTP/1.1 response of a backend
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResponseParser {
+    state: ResponseState,
+    /// the response to a HEAD request has no body
+    head_request: bool,
+    /// the backend connection can carry another request after this response
+    pub keep_alive: bool,
+    pub trailers: Vec<H2Header>,
+}
+
+impl ResponseParser {
+    pub fn new(head_request: bool) -> Self {
+        ResponseParser {
+            state: ResponseState::Head,
+            head_request,
+            keep_alive: true,
+            trailers: Vec::new(),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == ResponseState::Done
+    }
+
+    /// the backend closed the connection, returns true if this ends the response
+    pub fn close(&mut self) -> bool {
+        self.keep_alive = false;
+        match self.state {
+            ResponseState::UntilClose | ResponseState::Done => {
+                self.state = ResponseState::Done;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// parses the data read from the backend, copies the body to `body`, and returns
+    /// the size consumed from `input`, with the response head once it is parsed
+    pub fn parse(
+        &mut self,
+        input: &[u8],
+        body: &mut Vec<u8>,
+    ) -> Result<(usize, Option<Response>), ()> {
+        let mut consumed = 0;
+        let mut response = None;
+
+        loop {
+            let i = &input[consumed..];
+            match self.state {
+                ResponseState::Done => break,
+                ResponseState::Head => {
+                    if response.is_some() {
+                        // one head at a time, so the caller can send it before the body
+                        break;
+                    }
+
+                    match response_head(i) {
+                        Ok((remaining, (raw_status, headers))) => {
+                            consumed += i.len() - remaining.len();
+                            let status_line =
+                                StatusLine::from_raw_status_line(raw_status).ok_or(())?;
+                            if let Some(head) = self.handle_head(status_line, headers)? {
+                                response = Some(head);
+                            }
+                        }
+                        Err(Err::Incomplete(_)) if i.len() < MAX_RESPONSE_HEAD_SIZE => break,
+                        Err(_) => return Err(()),
+                    }
+                }
+                ResponseState::Length(remaining) => {
+                    if i.is_empty() {
+                        break;
+                    }
+                    let size = remaining.min(i.len());
+                    body.extend_from_slice(&i[..size]);
+                    consumed += size;
+                    self.state = if size == remaining {
+                        ResponseState::Done
+                    } else {
+                        ResponseState::Length(remaining - size)
+                    };
+                }
+                ResponseState::UntilClose => {
+                    body.extend_from_slice(i);
+                    consumed += i.len();
+                    break;
+                }
+                ResponseState::Chunked(chunk) => match self.parse_chunk(chunk, i, body)? {
+                    0 => break,
+                    size => consumed += size,
+                },
+            }
+        }
+
+        Ok((consumed, response))
+    }
+
+    fn handle_head(
+        &mut self,
+        status_line: StatusLine,
+        headers: Vec<crate::protocol::http::parser::Header>,
+    ) -> Result<Option<Response>, ()> {
+        let status = status_line.status;
+        // interim responses are not forwarded, and the protocol cannot be upgraded
+        if status == 101 {
+            return Err(());
+        }
+        if (100..200).contains(&status) {
+            return Ok(None);
+        }
+
+        let mut content_length = None;
+        let mut chunked = false;
+        let mut h2_headers = Vec::with_capacity(headers.len());
+        for header in headers {
+            if compare_no_case(&header.name, b"content-length") {
+                let length = from_utf8(&header.value)
+                    .ok()
+                    .and_then(|length| length.trim().parse::<usize>().ok())
+                    .ok_or(())?;
+                if matches!(content_length.replace(length), Some(l) if l != length) {
+                    return Err(());
+                }
+                // added back once the body kind is known
+                continue;
+            } else if compare_no_case(&header.name, b"transfer-encoding") {
+                chunked = header
+                    .value
+                    .split(|c| *c == b',')
+                    .any(|coding| compare_no_case(trim(coding), b"chunked"));
+            } else if compare_no_case(&header.name, b"connection")
+                && header
+                    .value
+                    .split(|c| *c == b',')
+                    .any(|option| compare_no_case(trim(option), b"close"))
+            {
+                self.keep_alive = false;
+            }
+
+            if !is_connection_header(&header.name) {
+                h2_headers.push((header.name.to_ascii_lowercase(), header.value));
+            }
+        }
+
+        if status_line.version == crate::protocol::http::parser::Version::V10 {
+            self.keep_alive = false;
+        }
+
+        let has_body = !(self.head_request || status == 204 || status == 304);
+        self.state = if !has_body {
+            ResponseState::Done
+        } else if chunked {
+            ResponseState::Chunked(ChunkState::Size)
+        } else {
+            match content_length {
+                Some(0) => ResponseState::Done,
+                Some(length) => ResponseState::Length(length),
+                None => {
+                    self.keep_alive = false;
+                    ResponseState::UntilClose
+                }
+            }
+        };
+
+        // the length is known from the DATA frames, but it helps the clients
+        if let (Some(length), false) = (content_length, chunked) {
+            h2_headers.push((b"content-length".to_vec(), length.to_string().into_bytes()));
+        }
+
+        Ok(Some(Response {
+            status,
+            headers: h2_headers,
+        }))
+    }
+
+    /// parses a step of a chunked body, returns the size consumed
+    fn parse_chunk(
+        &mut self,
+        chunk: ChunkState,
+        i: &[u8],
+        body: &mut Vec<u8>,
+    ) -> Result<usize, ()> {
+        let line_end = i.windows(2).position(|window| window == b"\r\n");
+
+        match chunk {
+            ChunkState::Size => {
+                let line_end = match line_end {
+                    Some(position) => position,
+                    None if i.len() < 1024 => return Ok(0),
+                    None => return Err(()),
+                };
+                let line = &i[..line_end];
+                // chunk extensions are ignored
+                let size = trim(line.split(|c| *c == b';').next().unwrap_or(line));
+                let size = from_utf8(size)
+                    .ok()
+                    .and_then(|size| usize::from_str_radix(size, 16).ok())
+                    .ok_or(())?;
+
+                self.state = ResponseState::Chunked(if size == 0 {
+                    ChunkState::Trailers
+                } else {
+                    ChunkState::Data(size)
+                });
+                Ok(line_end + 2)
+            }
+            ChunkState::Data(remaining) => {
+                let size = remaining.min(i.len());
+                body.extend_from_slice(&i[..size]);
+                self.state = ResponseState::Chunked(if size == remaining {
+                    ChunkState::DataEnd
+                } else {
+                    ChunkState::Data(remaining - size)
+                });
+                Ok(size)
+            }
+            ChunkState::DataEnd => match line_end {
+                Some(0) => {
+                    self.state = ResponseState::Chunked(ChunkState::Size);
+                    Ok(2)
+                }
+                None if i.len() < 2 => Ok(0),
+                _ => Err(()),
+            },
+            ChunkState::Trailers => match line_end {
+                Some(0) => {
+                    self.state = ResponseState::Done;
+                    Ok(2)
+                }
+                Some(position) => {
+                    let line = &i[..position];
+                    let separator = line.iter().position(|c| *c == b':').ok_or(())?;
+                    let name = line[..separator].to_ascii_lowercase();
+                    let value = trim(&line[separator + 1..]).to_vec();
+                    if self.trailers.len() >= 100 || !is_token(&name) {
+                        return Err(());
+                    }
+                    if !is_connection_header(&name) {
+                        self.trailers.push((name, value));
+                    }
+                    Ok(position + 2)
+                }
+                None if i.len() < 8192 => Ok(0),
+                None => Err(()),
+            },
+        }
+    }
+}
+
+/// frames a piece of request body as an HTTP/1.1 chunk
+pub fn chunk(data: &[u8], output: &mut Vec<u8>) {
+    if data.is_empty() {
+        return;
+    }
+    output.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+    output.extend_from_slice(data);
+    output.extend_from_slice(b"\r\n");
+}
+
+/// the end of a chunked request body, with the trailers of the client
+pub fn last_chunk(trailers: &[H2Header], output: &mut Vec<u8>) {
+    output.extend_from_slice(b"0\r\n");
+    for (name, value) in trailers {
+        output.extend_from_slice(name);
+        output.extend_from_slice(b": ");
+        output.extend_from_slice(value);
+        output.extend_from_slice(b"\r\n");
+    }
+    output.extend_from_slice(b"\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(list: &[(&str, &str)]) -> Vec<H2Header> {
+        list.iter()
+            .map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn request_to_http1() {
+        let request = Request::from_headers(headers(&[
+            (":method", "POST"),
+            (":scheme", "https"),
+            (":authority", "example.com"),
+            (":path", "/upload?id=1"),
+            ("cookie", "a=1"),
+            ("accept", "*/*"),
+            ("cookie", "b=2"),
+            ("x-forwarded-for", "10.0.0.1"),
+        ]))
+        .unwrap();
+        assert_eq!(request.host(), Some("example.com"));
+        assert_eq!(
+            request.forwarded_headers().x_for,
+            Some(String::from("10.0.0.1"))
+        );
+
+        let edits = HeaderEdits::client_dn("X-Client-DN", None);
+        let head = request.to_http1(&edits, "X-Forwarded-For: 10.0.0.1, 10.0.0.2\r\n", true);
+        assert_eq!(
+            from_utf8(&head).unwrap(),
+            "POST /upload?id=1 HTTP/1.1\r\nHost: example.com\r\naccept: */*\r\ncookie: a=1; b=2\r\n\
+             Transfer-Encoding: chunked\r\nX-Forwarded-For: 10.0.0.1, 10.0.0.2\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn request_to_h2() {
+        let request = Request::from_headers(headers(&[
+            (":method", "POST"),
+            (":scheme", "https"),
+            (":authority", "example.com"),
+            (":path", "/echo.Echo/Say"),
+            ("te", "trailers"),
+            ("content-type", "application/grpc"),
+            ("x-forwarded-for", "10.0.0.1"),
+        ]))
+        .unwrap();
+
+        let mut edits = HeaderEdits::host("backend.internal");
+        edits.extend(HeaderEdits::client_dn("X-Client-DN", Some("CN=client")));
+        let block = request.to_h2(
+            &edits,
+            "X-Forwarded-For: 10.0.0.1, 10.0.0.2\r\nConnection: close\r\n",
+            "http",
+        );
+        assert_eq!(
+            block,
+            headers(&[
+                (":method", "POST"),
+                (":scheme", "http"),
+                (":authority", "backend.internal"),
+                (":path", "/echo.Echo/Say"),
+                ("te", "trailers"),
+                ("content-type", "application/grpc"),
+                ("x-client-dn", "CN=client"),
+                ("x-forwarded-for", "10.0.0.1, 10.0.0.2"),
+            ])
+        );
+    }
+
+    #[test]
+    fn backend_responses() {
+        assert_eq!(
+            Response::from_headers(headers(&[(":status", "200"), ("grpc-status", "0")])),
+            Ok(Some(Response {
+                status: 200,
+                headers: headers(&[("grpc-status", "0")]),
+            }))
+        );
+        assert_eq!(
+            Response::from_headers(headers(&[(":status", "103"), ("link", "</a.css>")])),
+            Ok(None)
+        );
+
+        for invalid in [
+            headers(&[("server", "test")]),
+            headers(&[(":status", "20")]),
+            headers(&[(":status", "200"), ("connection", "close")]),
+            headers(&[(":status", "200"), ("server", "test"), (":status", "204")]),
+            headers(&[(":status", "200"), (":path", "/")]),
+        ] {
+            assert_eq!(
+                Response::from_headers(invalid.clone()),
+                Err(H2Error::ProtocolError),
+                "{:?} should be refused",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_requests() {
+        let base = [(":method", "GET"), (":scheme", "https"), (":path", "/")];
+
+        assert!(Request::from_headers(headers(&base)).is_ok());
+
+        for extra in [
+            ("connection", "close"),
+            ("Accept", "*/*"),
+            ("te", "gzip"),
+            ("x-smuggled", "a\r\nb: c"),
+            (":status", "200"),
+        ] {
+            let mut list = base.to_vec();
+            list.push(extra);
+            assert_eq!(
+                Request::from_headers(headers(&list)),
+                Err(H2Error::ProtocolError),
+                "{:?} should be refused",
+                extra
+            );
+        }
+
+        assert_eq!(
+            Request::from_headers(headers(&[
+                (":method", "CONNECT"),
+                (":authority", "example.com:443")
+            ])),
+            Err(H2Error::HTTP11Required)
+        );
+        assert_eq!(
+            Request::from_headers(headers(&[(":method", "GET"), (":scheme", "https")])),
+            Err(H2Error::ProtocolError)
+        );
+    }
+
+    #[test]
+    fn parse_responses() {
+        let mut parser = ResponseParser::new(false);
+        let mut body = Vec::new();
+        let input = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nabcd\r\n2;ext=1\r\nef\r\n0\r\ngrpc-status: 0\r\n\r\n";
+
+        let (mut consumed, response) = parser.parse(&input[..], &mut body).unwrap();
+        assert_eq!(
+            response,
+            Some(Response {
+                status: 200,
+                headers: headers(&[("content-type", "text/plain")]),
+            })
+        );
+        // the body arrives byte by byte
+        let mut end = consumed;
+        while end < input.len() {
+            end += 1;
+            let (size, response) = parser.parse(&input[consumed..end], &mut body).unwrap();
+            assert_eq!(response, None);
+            consumed += size;
+        }
+        assert_eq!(consumed, input.len());
+        assert!(parser.is_done());
+        assert!(parser.keep_alive);
+        assert_eq!(body, b"abcdef");
+        assert_eq!(parser.trailers, headers(&[("grpc-status", "0")]));
+
+        let mut parser = ResponseParser::new(false);
+        let mut body = Vec::new();
+        let (_, response) = parser
+            .parse(b"HTTP/1.0 200 OK\r\n\r\nhello", &mut body)
+            .unwrap();
+        assert_eq!(response.map(|r| r.status), Some(200));
+        assert!(!parser.is_done());
+        assert!(parser.close());
+        assert!(!parser.keep_alive);
+        assert_eq!(body, b"hello");
+
+        let mut parser = ResponseParser::new(true);
+        let (_, response) = parser
+            .parse(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n", &mut body)
+            .unwrap();
+        assert_eq!(
+            response.map(|r| r.headers),
+            Some(headers(&[("content-length", "10")]))
+        );
+        assert!(parser.is_done());
+    }
+}
@@ -0,0 +1,282 @@
+//! gRPC semantics of the streams sent to the clusters in gRPC mode. The calls
+//! that the proxy cannot forward end with a gRPC status instead of an HTML
+//! page, the health checks of a cluster without available backend are told
+//! the service is not serving, and the status of each call is counted.
+//! The active health checks of the backends call the same health checking
+//! service
+use std::{net::SocketAddr, str::from_utf8};
+
+use crate::protocol::http::parser::Method;
+
+use super::convert::{H2Header, Request, Response};
+
+/// the unary method of the standard health checking service
+pub const HEALTH_CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
+
+/// a `HealthCheckResponse` with the status `NOT_SERVING`, behind the
+/// compression flag and length prefix of a gRPC message
+const NOT_SERVING_MESSAGE: [u8; 7] = [0, 0, 0, 0, 2, 0x08, 0x02];
+
+/// the `SERVING` status of a `HealthCheckResponse`
+const SERVING: u64 = 1;
+
+/// the status of a call, from its trailers or the headers of a trailers-only response
+pub fn status(headers: &[H2Header]) -> Option<u32> {
+    headers
+        .iter()
+        .find(|(name, _)| name == b"grpc-status")
+        .and_then(|(_, value)| from_utf8(value).ok()?.parse().ok())
+}
+
+/// the gRPC status of an HTTP status, as mapped by the gRPC specification
+pub fn status_from_http(status: u16) -> u32 {
+    match status {
+        // INTERNAL
+        400 => 13,
+        // UNAUTHENTICATED
+        401 => 16,
+        // PERMISSION_DENIED
+        403 => 7,
+        // UNIMPLEMENTED
+        404 => 12,
+        // UNAVAILABLE
+        429 | 502 | 503 | 504 => 14,
+        // UNKNOWN
+        _ => 2,
+    }
+}
+
+/// the metric counting the calls that ended with this status
+pub fn status_metric(status: Option<u32>) -> &'static str {
+    match status {
+        Some(0) => "grpc.status.ok",
+        Some(1) => "grpc.status.cancelled",
+        Some(2) => "grpc.status.unknown",
+        Some(3) => "grpc.status.invalid_argument",
+        Some(4) => "grpc.status.deadline_exceeded",
+        Some(5) => "grpc.status.not_found",
+        Some(6) => "grpc.status.already_exists",
+        Some(7) => "grpc.status.permission_denied",
+        Some(8) => "grpc.status.resource_exhausted",
+        Some(9) => "grpc.status.failed_precondition",
+        Some(10) => "grpc.status.aborted",
+        Some(11) => "grpc.status.out_of_range",
+        Some(12) => "grpc.status.unimplemented",
+        Some(13) => "grpc.status.internal",
+        Some(14) => "grpc.status.unavailable",
+        Some(15) => "grpc.status.data_loss",
+        Some(16) => "grpc.status.unauthenticated",
+        Some(_) => "grpc.status.other",
+        // the call was reset, or the backend did not send its status
+        None => "grpc.status.missing",
+    }
+}
+
+/// a trailers-only response ending a call with the gRPC status matching the
+/// HTTP status that the proxy would have answered
+pub fn error_response(http_status: u16) -> Response {
+    Response {
+        status: 200,
+        headers: vec![
+            (b"content-type".to_vec(), b"application/grpc".to_vec()),
+            (
+                b"grpc-status".to_vec(),
+                status_from_http(http_status).to_string().into_bytes(),
+            ),
+            (
+                b"grpc-message".to_vec(),
+                format!("the proxy answered with the HTTP status {}", http_status).into_bytes(),
+            ),
+        ],
+    }
+}
+
+/// the request is a call to the `Check` method of the health checking service
+pub fn is_health_check(request: &Request) -> bool {
+    request.method == Method::Post && request.path == HEALTH_CHECK_PATH
+}
+
+/// the answer of a health check when the service has no available backend:
+/// the response headers, its message, and the trailers with the `OK` status
+pub fn not_serving() -> (Response, Vec<u8>, Vec<H2Header>) {
+    (
+        Response {
+            status: 200,
+            headers: vec![(b"content-type".to_vec(), b"application/grpc".to_vec())],
+        },
+        NOT_SERVING_MESSAGE.to_vec(),
+        vec![(b"grpc-status".to_vec(), b"0".to_vec())],
+    )
+}
+
+/// the headers and the message of a call to the health checking service of a
+/// backend, for this service name or the whole server if it is empty
+pub fn health_check_call(address: SocketAddr, service: &str) -> (Vec<H2Header>, Vec<u8>) {
+    let headers = vec![
+        (b":method".to_vec(), b"POST".to_vec()),
+        (b":scheme".to_vec(), b"http".to_vec()),
+        (b":authority".to_vec(), address.to_string().into_bytes()),
+        (b":path".to_vec(), HEALTH_CHECK_PATH.as_bytes().to_vec()),
+        (b"content-type".to_vec(), b"application/grpc".to_vec()),
+        (b"te".to_vec(), b"trailers".to_vec()),
+    ];
+
+    // a `HealthCheckRequest`, its field 1 is the service name
+    let mut payload = Vec::new();
+    if !service.is_empty() {
+        payload.push(0x0a);
+        push_varint(&mut payload, service.len() as u64);
+        payload.extend_from_slice(service.as_bytes());
+    }
+
+    let mut message = vec![0];
+    message.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    message.extend_from_slice(&payload);
+    (headers, message)
+}
+
+/// the response to a health check call tells the service is serving
+pub fn is_serving(response: &Response, message: &[u8], trailers: &[H2Header]) -> bool {
+    // a trailers-only response carries the status in its headers
+    let call_status = status(trailers).or_else(|| status(&response.headers));
+    response.status == 200 && call_status == Some(0) && serving_status(message) == Some(SERVING)
+}
+
+/// the status field of a `HealthCheckResponse`, `UNKNOWN` if it is absent
+fn serving_status(message: &[u8]) -> Option<u64> {
+    // the responses are not expected to be compressed
+    let (&compressed, rest) = message.split_first()?;
+    if compressed != 0 {
+        return None;
+    }
+    let length = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+    let mut payload = rest.get(4..4 + length)?;
+
+    let mut serving_status = 0;
+    while !payload.is_empty() {
+        let (key, rest) = varint(payload)?;
+        payload = match key & 0x7 {
+            0 => {
+                let (value, rest) = varint(rest)?;
+                if key >> 3 == 1 {
+                    serving_status = value;
+                }
+                rest
+            }
+            2 => {
+                let (length, rest) = varint(rest)?;
+                rest.get(length as usize..)?
+            }
+            _ => return None,
+        };
+    }
+    Some(serving_status)
+}
+
+fn varint(input: &[u8]) -> Option<(u64, &[u8])> {
+    let mut value = 0;
+    for (index, byte) in input.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Some((value, &input[index + 1..]));
+        }
+    }
+    None
+}
+
+fn push_varint(output: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        output.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(list: &[(&str, &str)]) -> Vec<H2Header> {
+        list.iter()
+            .map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn statuses() {
+        assert_eq!(
+            status(&headers(&[("grpc-message", "gone"), ("grpc-status", "5")])),
+            Some(5)
+        );
+        assert_eq!(status(&headers(&[("grpc-status", "five")])), None);
+        assert_eq!(
+            status(&headers(&[("content-type", "application/grpc")])),
+            None
+        );
+
+        assert_eq!(status_metric(Some(0)), "grpc.status.ok");
+        assert_eq!(status_metric(Some(14)), "grpc.status.unavailable");
+        assert_eq!(status_metric(Some(42)), "grpc.status.other");
+        assert_eq!(status_metric(None), "grpc.status.missing");
+
+        let response = error_response(503);
+        assert_eq!(response.status, 200);
+        assert_eq!(status(&response.headers), Some(14));
+        assert_eq!(status(&error_response(404).headers), Some(12));
+        assert_eq!(status(&error_response(500).headers), Some(2));
+    }
+
+    #[test]
+    fn health_checks() {
+        let mut request = Request::from_headers(headers(&[
+            (":method", "POST"),
+            (":scheme", "https"),
+            (":authority", "api.example.com"),
+            (":path", HEALTH_CHECK_PATH),
+            ("content-type", "application/grpc"),
+            ("te", "trailers"),
+        ]))
+        .unwrap();
+        assert!(is_health_check(&request));
+
+        request.path = String::from("/grpc.health.v1.Health/Watch");
+        assert!(!is_health_check(&request));
+
+        let (response, message, trailers) = not_serving();
+        assert_eq!(response.status, 200);
+        // a message of 2 bytes: the field 1 of the response with the value 2
+        assert_eq!(&message[..5], &[0, 0, 0, 0, 2]);
+        assert_eq!(&message[5..], &[0x08, 0x02]);
+        assert_eq!(status(&trailers), Some(0));
+        assert!(!is_serving(&response, &message, &trailers));
+    }
+
+    #[test]
+    fn health_check_calls() {
+        let (call_headers, message) = health_check_call("127.0.0.1:50051".parse().unwrap(), "echo");
+        let request = Request::from_headers(call_headers).unwrap();
+        assert!(is_health_check(&request));
+        assert_eq!(message, b"\0\0\0\0\x06\x0a\x04echo");
+
+        let (_, message) = health_check_call("127.0.0.1:50051".parse().unwrap(), "");
+        assert_eq!(message, [0, 0, 0, 0, 0]);
+
+        let response = Response {
+            status: 200,
+            headers: vec![(b"content-type".to_vec(), b"application/grpc".to_vec())],
+        };
+        let serving = [0, 0, 0, 0, 2, 0x08, 0x01];
+        let ok = headers(&[("grpc-status", "0")]);
+        assert!(is_serving(&response, &serving, &ok));
+        // the service is unknown to the backend
+        assert!(!is_serving(
+            &response,
+            &[],
+            &headers(&[("grpc-status", "5")])
+        ));
+        // the status field is absent: UNKNOWN
+        assert!(!is_serving(&response, &[0, 0, 0, 0, 0], &ok));
+        assert!(!is_serving(&response, &[1, 0, 0, 0, 2, 0x08, 0x01], &ok));
+        assert!(!is_serving(&response, &serving[..6], &ok));
+    }
+}
@@ -1,478 +1,1521 @@
-//FIXME: we disallow warnings for the HTTP/2 module temporarily
-#![allow(warnings)]
-use std::{cell::RefCell, net::SocketAddr, rc::Weak};
+//! HTTP/2 on the frontend connections.
+//!
+//! The streams of a client are sent to the backends as HTTP/1.1 requests:
+//! each request in flight gets its own backend connection, and the
+//! connections are kept to carry the next requests to the same cluster.
+//! The clusters whose backends speak HTTP/2 get the streams multiplexed on
+//! a connection, until the backend limit on concurrent streams is reached.
+//! The clusters in gRPC mode are reached the same way, with the gRPC
+//! semantics of the `grpc` module on top
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    net::{Shutdown, SocketAddr},
+    rc::Rc,
+};
 
-use mio::{net::TcpStream, *};
-use rusty_ulid::Ulid;
+use mio::{net::TcpStream, Interest, Registry, Token};
+use time::{Duration, Instant};
 
 use crate::{
-    pool::{Checkout, Pool},
-    socket::{SocketHandler, SocketResult},
-    sozu_command::buffer::fixed::Buffer,
-    sozu_command::ready::Ready,
-    {Protocol, Readiness, SessionMetrics, SessionResult},
+    backends::BackendMap,
+    protocol::http::{
+        answers::HttpAnswers,
+        parser::{HeaderEdits, Method},
+        AddedRequestHeader, DefaultAnswerStatus,
+    },
+    retry::RetryPolicy,
+    server::{push_event, tap_access_log, SessionManager},
+    socket::{BackendSocket, SocketHandler, SocketResult},
+    sozu_command::{
+        proxy::{AccessLogRecord, BackendProtocol, HostRewrite, ProxyEvent},
+        ready::Ready,
+    },
+    timer::TimeoutContainer,
+    Backend, ClusterId, ConnectionError, ListenerHandler, LogDuration, Protocol, ProxySession,
+    Readiness, SessionMetrics, SessionResult,
 };
 
-mod parser;
-mod serializer;
-mod state;
-mod stream;
+pub mod client;
+mod convert;
+pub mod grpc;
+pub mod parser;
+pub mod serializer;
+pub mod state;
+pub mod stream;
+
+pub use self::convert::Request;
+use self::{
+    client::ClientState,
+    convert::{chunk, last_chunk, ResponseParser},
+    parser::{H2Error, FRAME_HEADER_SIZE},
+    state::{State, MAX_FRAME_SIZE, OUTPUT_BUFFER_LIMIT},
+    stream::Stream,
+};
 
-type BackendToken = Token;
+/// the ALPN identifier of HTTP/2 over TLS
+pub const ALPN_H2: &[u8] = b"h2";
+
+/// data read from the client and not parsed yet, a frame at most
+const FRONT_BUFFER_LIMIT: usize = 2 * (FRAME_HEADER_SIZE + MAX_FRAME_SIZE as usize);
+/// request data queued for a backend before more is taken from the stream
+const BACKEND_OUTPUT_LIMIT: usize = 65536;
+/// response data waiting for the flow control of the client, the backend
+/// is not read anymore past this size
+const RESPONSE_BUFFER_LIMIT: usize = 65536;
+const READ_BUFFER_SIZE: usize = 16384;
+const MAX_LOOP_ITERATIONS: usize = 100000;
+
+/// where a stream goes, as decided by the routing of the listener
+pub struct StreamRoute {
+    pub cluster_id: ClusterId,
+    /// replaces the path of the request
+    pub path: Option<String>,
+    pub host_rewrite: HostRewrite,
+    pub request_edits: HeaderEdits,
+    pub response_edits: HeaderEdits,
+    pub connect_timeout: Duration,
+    pub back_timeout: Duration,
+    pub backend_protocol: BackendProtocol,
+}
 
-#[derive(PartialEq)]
-pub enum SessionStatus {
-    Normal,
-    DefaultAnswer,
+/// what a session gives to open connections to the backends
+pub struct BackendContext<'a> {
+    pub backends: &'a RefCell<BackendMap>,
+    pub registry: &'a Registry,
+    pub sessions: &'a RefCell<SessionManager>,
+    pub session: Rc<RefCell<dyn ProxySession>>,
 }
 
-pub struct Http2<Front: SocketHandler> {
-    pub frontend: Connection<Front>,
-    backend: Option<TcpStream>,
-    frontend_token: Token,
-    backend_token: Option<Token>,
-    back_buf: Option<Checkout>,
-    pub cluster_id: Option<String>,
-    pub request_id: Ulid,
-    pub back_readiness: Readiness,
-    pub log_ctx: String,
-    public_address: Option<SocketAddr>,
-    pub state: Option<state::State>,
-    pool: Weak<RefCell<Pool>>,
+enum BackendResult {
+    Continue,
+    /// the connection must be closed, the stream is not affected
+    Close,
+    /// the connection could not be established
+    ConnectionFailed,
+    /// the stream gets this answer, or is reset if its response started
+    Error(DefaultAnswerStatus),
 }
 
-impl<Front: SocketHandler> Http2<Front> {
-    pub fn new(
-        frontend: Front,
-        frontend_token: Token,
-        pool: Weak<RefCell<Pool>>,
-        public_address: Option<SocketAddr>,
-        client_address: Option<SocketAddr>,
-        sticky_name: String,
-        protocol: Protocol,
-    ) -> Http2<Front> {
-        let request_id = Ulid::generate();
-        let log_ctx = format!("{}\tunknown\t", &request_id);
-        let (read, write) = {
-            let p0 = pool.upgrade().unwrap();
-            let mut p = p0.borrow_mut();
-            let res = (p.checkout().unwrap(), p.checkout().unwrap());
-            res
+/// a connection to a backend, carrying the request of one stream at a time in
+/// HTTP/1.1, or multiplexing the streams in HTTP/2
+pub struct BackendConnection {
+    pub token: Token,
+    pub cluster_id: ClusterId,
+    pub readiness: Readiness,
+    socket: BackendSocket,
+    backend: Rc<RefCell<Backend>>,
+    connected: bool,
+    connect_start: Instant,
+    /// the stream whose request is sent on this connection, none while idle
+    stream_id: Option<u32>,
+    /// request head and body waiting to be written
+    output: Vec<u8>,
+    chunked: bool,
+    /// the whole request was queued in `output`
+    request_done: bool,
+    parser: ResponseParser,
+    input: Vec<u8>,
+    /// the backend closed its side of the connection
+    hup: bool,
+    response_edits: HeaderEdits,
+    timeout: TimeoutContainer,
+    back_timeout: Duration,
+    /// the streams of an HTTP/2 connection, none in HTTP/1.1
+    h2: Option<Box<ClientState>>,
+}
+
+impl BackendConnection {
+    /// connects to a backend of the cluster, under a new token pointing to the session
+    pub fn connect(
+        cluster_id: &str,
+        context: &BackendContext,
+        connect_timeout: Duration,
+        protocol: BackendProtocol,
+    ) -> Result<BackendConnection, ConnectionError> {
+        {
+            let sessions = context.sessions.borrow();
+            if sessions.slab.len() >= sessions.slab_capacity() {
+                return Err(ConnectionError::TooManyConnections);
+            }
+        }
+
+        let (backend, socket) = context
+            .backends
+            .borrow_mut()
+            .backend_from_cluster_id(cluster_id)?;
+        let mut socket = context.backends.borrow().backend_socket(
+            cluster_id,
+            &backend.borrow(),
+            socket,
+            protocol,
+        )?;
+        if let Err(e) = socket.socket_ref().set_nodelay(true) {
+            error!(
+                "error setting nodelay on back socket({:?}): {:?}",
+                socket.socket_ref(),
+                e
+            );
+        }
+
+        let token = {
+            let mut sessions = context.sessions.borrow_mut();
+            let entry = sessions.slab.vacant_entry();
+            let token = Token(entry.key());
+            entry.insert(context.session.clone());
+            token
         };
-        let session = Http2 {
-            frontend: Connection::new(frontend, read, write),
-            frontend_token,
-            backend: None,
-            backend_token: None,
-            back_buf: None,
-            cluster_id: None,
-            state: Some(state::State::new()),
-            request_id,
-            back_readiness: Readiness {
-                interest: Ready::readable() | Ready::writable() | Ready::hup() | Ready::error(),
-                event: Ready::empty(),
+
+        if let Err(e) = context.registry.register(
+            socket.socket_mut(),
+            token,
+            Interest::READABLE | Interest::WRITABLE,
+        ) {
+            error!(
+                "error registering back socket({:?}): {:?}",
+                socket.socket_ref(),
+                e
+            );
+        }
+
+        let mut readiness = Readiness::new();
+        readiness.interest = Ready::writable() | Ready::hup() | Ready::error();
+
+        Ok(BackendConnection {
+            token,
+            cluster_id: cluster_id.to_string(),
+            readiness,
+            socket,
+            backend,
+            connected: false,
+            connect_start: Instant::now(),
+            stream_id: None,
+            output: Vec::new(),
+            chunked: false,
+            request_done: false,
+            parser: ResponseParser::new(false),
+            input: Vec::new(),
+            hup: false,
+            response_edits: HeaderEdits::default(),
+            timeout: TimeoutContainer::new(connect_timeout, token),
+            back_timeout: connect_timeout,
+            h2: match protocol {
+                BackendProtocol::Http1 => None,
+                BackendProtocol::Http2 | BackendProtocol::Grpc => {
+                    Some(Box::new(ClientState::new()))
+                }
             },
-            log_ctx,
-            public_address,
-            pool,
+        })
+    }
+
+    /// the streams of the client whose requests are on this connection
+    fn stream_ids(&self) -> Vec<u32> {
+        match &self.h2 {
+            Some(client) => client
+                .streams
+                .values()
+                .map(|stream| stream.front_stream_id)
+                .collect(),
+            None => self.stream_id.into_iter().collect(),
+        }
+    }
+
+    /// forgets a stream of an HTTP/2 connection, the backend is told to stop
+    /// it if it is not over
+    fn remove_stream(&mut self, backend_stream_id: u32) {
+        let removed = match self.h2.as_mut() {
+            Some(client) => client.remove_stream(backend_stream_id).is_some(),
+            None => false,
         };
+        if removed {
+            let mut backend = self.backend.borrow_mut();
+            backend.active_requests = backend.active_requests.saturating_sub(1);
+        }
+    }
+
+    fn set_connected(&mut self) {
+        self.connected = true;
+        self.readiness.interest.insert(Ready::readable());
+        self.timeout.set_duration(self.back_timeout);
+
+        let mut backend = self.backend.borrow_mut();
+        gauge_add!("backend.connections", 1);
+        gauge_add!(
+            "connections_per_backend",
+            1,
+            Some(self.cluster_id.as_str()),
+            Some(backend.backend_id.as_str())
+        );
+
+        if backend.retry_policy.is_down() {
+            incr!(
+                "up",
+                Some(self.cluster_id.as_str()),
+                Some(backend.backend_id.as_str())
+            );
+            info!(
+                "backend server {} at {} is up",
+                backend.backend_id, backend.address
+            );
+            push_event(ProxyEvent::BackendUp(
+                backend.backend_id.clone(),
+                backend.address,
+            ));
+        }
 
-        trace!("created http2");
-        session
+        backend.set_connection_time(Instant::now() - self.connect_start);
+        backend.failures = 0;
+        backend.retry_policy.succeed();
     }
 
-    fn tokens(&self) -> Option<(Token, Token)> {
-        if let Some(back) = self.backend_token {
-            return Some((self.frontend_token, back));
+    fn connection_failed(&mut self) {
+        let backend = &mut *self.backend.borrow_mut();
+        backend.failures += 1;
+
+        let already_unavailable = backend.retry_policy.is_down();
+        backend.retry_policy.fail();
+        backend.record_outcome(Some(self.cluster_id.as_str()), true);
+        incr!(
+            "connections.error",
+            Some(self.cluster_id.as_str()),
+            Some(backend.backend_id.as_str())
+        );
+        if !already_unavailable && backend.retry_policy.is_down() {
+            error!(
+                "backend server {} at {} is down",
+                backend.backend_id, backend.address
+            );
+            incr!(
+                "down",
+                Some(self.cluster_id.as_str()),
+                Some(backend.backend_id.as_str())
+            );
+            push_event(ProxyEvent::BackendDown(
+                backend.backend_id.clone(),
+                backend.address,
+            ));
+        }
+    }
+
+    /// moves request data from the stream to the output, returns the size taken
+    fn fill_request(&mut self, stream: &mut Stream) -> usize {
+        if self.request_done {
+            return 0;
+        }
+
+        let size = stream
+            .request_body
+            .len()
+            .min(BACKEND_OUTPUT_LIMIT.saturating_sub(self.output.len()));
+        if size > 0 {
+            if self.chunked {
+                chunk(&stream.request_body[..size], &mut self.output);
+            } else {
+                self.output.extend_from_slice(&stream.request_body[..size]);
+            }
+            stream.request_body.drain(..size);
+        }
+
+        if stream.request_body.is_empty() && stream.request_ended() {
+            if self.chunked {
+                last_chunk(&stream.request_trailers, &mut self.output);
+            }
+            self.request_done = true;
+        }
+        size
+    }
+
+    /// the connection can carry another request
+    fn is_reusable(&self) -> bool {
+        self.h2.is_none()
+            && self.connected
+            && !self.hup
+            && self.request_done
+            && self.parser.is_done()
+            && self.parser.keep_alive
+            && self.output.is_empty()
+            && self.input.is_empty()
+    }
+
+    pub fn close(&mut self, registry: &Registry, sessions: &RefCell<SessionManager>) {
+        self.timeout.cancel();
+        if let Err(e) = registry.deregister(self.socket.socket_mut()) {
+            error!(
+                "error deregistering back socket({:?}): {:?}",
+                self.socket.socket_ref(),
+                e
+            );
+        }
+        sessions.borrow_mut().slab.try_remove(self.token.0);
+
+        if let Err(e) = self.socket.socket_ref().shutdown(Shutdown::Both) {
+            if e.kind() != std::io::ErrorKind::NotConnected {
+                error!(
+                    "error shutting down back socket({:?}): {:?}",
+                    self.socket.socket_ref(),
+                    e
+                );
+            }
+        }
+
+        let active_requests = self.stream_ids().len();
+        self.stream_id = None;
+        let mut backend = self.backend.borrow_mut();
+        backend.active_requests = backend.active_requests.saturating_sub(active_requests);
+        if self.connected {
+            gauge_add!("backend.connections", -1);
+            gauge_add!(
+                "connections_per_backend",
+                -1,
+                Some(self.cluster_id.as_str()),
+                Some(backend.backend_id.as_str())
+            );
+        }
+        backend.dec_connections();
+    }
+}
+
+pub struct Http2<Front: SocketHandler, L: ListenerHandler> {
+    pub frontend: Front,
+    pub frontend_token: Token,
+    pub front_readiness: Readiness,
+    /// data read from the client, up to an incomplete frame
+    front_buf: Vec<u8>,
+    pub state: State,
+    backends: HashMap<Token, BackendConnection>,
+    public_address: SocketAddr,
+    peer_address: Option<SocketAddr>,
+    answers: Rc<RefCell<HttpAnswers>>,
+    pub front_timeout: TimeoutContainer,
+    listener: Rc<RefCell<L>>,
+}
+
+impl<Front: SocketHandler, L: ListenerHandler> Http2<Front, L> {
+    pub fn new(
+        frontend: Front,
+        frontend_token: Token,
+        public_address: SocketAddr,
+        peer_address: Option<SocketAddr>,
+        answers: Rc<RefCell<HttpAnswers>>,
+        front_timeout: TimeoutContainer,
+        listener: Rc<RefCell<L>>,
+    ) -> Http2<Front, L> {
+        let settings = listener.borrow().get_http2_settings();
+        let mut front_readiness = Readiness::new();
+        // the SETTINGS frame of the server goes first
+        front_readiness.interest =
+            Ready::readable() | Ready::writable() | Ready::hup() | Ready::error();
+
+        Http2 {
+            frontend,
+            frontend_token,
+            front_readiness,
+            front_buf: Vec::new(),
+            state: State::new(&settings),
+            backends: HashMap::new(),
+            public_address,
+            peer_address,
+            answers,
+            front_timeout,
+            listener,
         }
-        None
     }
 
     pub fn front_socket(&self) -> &TcpStream {
-        self.frontend.socket.socket_ref()
+        self.frontend.socket_ref()
     }
 
     pub fn front_socket_mut(&mut self) -> &mut TcpStream {
-        self.frontend.socket.socket_mut()
+        self.frontend.socket_mut()
     }
 
-    pub fn back_socket(&self) -> Option<&TcpStream> {
-        self.backend.as_ref()
+    /// data read from the client before the connection switched to HTTP/2
+    pub fn push_front_data(&mut self, data: &[u8]) {
+        self.front_buf.extend_from_slice(data);
     }
 
-    pub fn back_socket_mut(&mut self) -> Option<&mut TcpStream> {
-        self.backend.as_mut()
+    /// the readiness of a backend connection, if the token belongs to one
+    pub fn backend_readiness(&mut self, token: Token) -> Option<&mut Readiness> {
+        self.backends
+            .get_mut(&token)
+            .map(|connection| &mut connection.readiness)
     }
 
-    pub fn set_back_socket(&mut self, socket: TcpStream) {
-        self.backend = Some(socket);
+    pub fn tokens(&self) -> Vec<Token> {
+        let mut tokens = vec![self.frontend_token];
+        tokens.extend(self.backends.keys());
+        tokens
     }
 
-    pub fn back_token(&self) -> Option<Token> {
-        self.backend_token
+    /// handles the events of the frontend and backend connections. New
+    /// streams go through `route` to know which cluster they are sent to,
+    /// or which answer they get
+    pub fn ready<F>(
+        &mut self,
+        metrics: &mut SessionMetrics,
+        context: &BackendContext,
+        mut route: F,
+    ) -> SessionResult
+    where
+        F: FnMut(&Request) -> Result<StreamRoute, Rc<Vec<u8>>>,
+    {
+        for _ in 0..MAX_LOOP_ITERATIONS {
+            let front_interest = self.front_readiness.filter_interest();
+            if front_interest.is_error() {
+                error!(
+                    "PROXY session {:?} front error, disconnecting",
+                    self.frontend_token
+                );
+                return SessionResult::CloseSession;
+            }
+
+            if front_interest.is_readable() {
+                let result = self.readable(metrics);
+                if result != SessionResult::Continue {
+                    return result;
+                }
+            }
+
+            for stream_id in std::mem::take(&mut self.state.new_streams) {
+                self.route_stream(stream_id, context, &mut route);
+            }
+
+            let tokens: Vec<Token> = self.backends.keys().copied().collect();
+            for token in tokens {
+                let result = self.backend_ready(token);
+                self.handle_backend_result(token, result, context);
+            }
+
+            self.state.send_data();
+            self.release_streams(context);
+            self.update_backend_interests();
+
+            if !self.state.output.is_empty() {
+                self.front_readiness.interest.insert(Ready::writable());
+            }
+            if self.front_readiness.filter_interest().is_writable() {
+                let result = self.writable(metrics);
+                if result != SessionResult::Continue {
+                    return result;
+                }
+            }
+
+            if self.state.output.is_empty() {
+                if self.state.error.is_some() {
+                    return SessionResult::CloseSession;
+                }
+                if self.state.going_away && self.state.is_idle() {
+                    return SessionResult::CloseSession;
+                }
+            }
+            if front_interest.is_hup() && !self.front_readiness.event.is_readable() {
+                return SessionResult::CloseSession;
+            }
+
+            if self.front_readiness.filter_interest().is_empty()
+                && self
+                    .backends
+                    .values()
+                    .all(|connection| connection.readiness.filter_interest().is_empty())
+            {
+                return SessionResult::Continue;
+            }
+        }
+
+        error!(
+            "PROXY\thandling HTTP/2 session {:?} went through {} iterations, there's a probable infinite loop bug, closing the connection",
+            self.frontend_token, MAX_LOOP_ITERATIONS
+        );
+        incr!("http2.infinite_loop.error");
+        SessionResult::CloseSession
     }
 
-    pub fn close(&mut self) {}
+    fn readable(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
+        if !self.front_timeout.reset() {
+            error!("could not reset front timeout");
+        }
 
-    pub fn log_context(&self) -> String {
-        if let Some(ref cluster_id) = self.cluster_id {
-            format!("{}\t{}\t", self.request_id, cluster_id)
-        } else {
-            format!("{}\tunknown\t", self.request_id)
+        let mut buffer = [0u8; READ_BUFFER_SIZE];
+        loop {
+            if self.front_buf.len() >= FRONT_BUFFER_LIMIT
+                || self.state.output.len() >= OUTPUT_BUFFER_LIMIT
+                || self.state.error.is_some()
+            {
+                self.front_readiness.interest.remove(Ready::readable());
+                break;
+            }
+
+            let (size, result) = self.frontend.socket_read(&mut buffer);
+            self.front_buf.extend_from_slice(&buffer[..size]);
+            count!("bytes_in", size as i64);
+            metrics.bin += size;
+
+            if self.parse_front().is_err() {
+                self.front_readiness.interest.remove(Ready::readable());
+                break;
+            }
+
+            match result {
+                SocketResult::Continue => {}
+                SocketResult::WouldBlock => {
+                    self.front_readiness.event.remove(Ready::readable());
+                    break;
+                }
+                SocketResult::Error | SocketResult::Closed => {
+                    return SessionResult::CloseSession;
+                }
+            }
         }
+
+        SessionResult::Continue
     }
 
-    pub fn set_back_token(&mut self, token: Token) {
-        self.backend_token = Some(token);
+    fn parse_front(&mut self) -> Result<(), H2Error> {
+        match self.state.parse(&self.front_buf) {
+            Ok(consumed) => {
+                self.front_buf.drain(..consumed);
+                Ok(())
+            }
+            Err(error) => {
+                incr!("http2.errors");
+                self.front_buf.clear();
+                Err(error)
+            }
+        }
     }
 
-    pub fn front_readiness(&mut self) -> &mut Readiness {
-        &mut self.frontend.readiness
+    fn writable(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
+        while !self.state.output.is_empty() {
+            let (size, result) = self.frontend.socket_write(&self.state.output);
+            self.state.output.drain(..size);
+            count!("bytes_out", size as i64);
+            metrics.bout += size;
+
+            match result {
+                SocketResult::Continue if size > 0 => {}
+                SocketResult::Continue | SocketResult::WouldBlock => {
+                    self.front_readiness.event.remove(Ready::writable());
+                    break;
+                }
+                SocketResult::Error | SocketResult::Closed => {
+                    return SessionResult::CloseSession;
+                }
+            }
+        }
+
+        if self.state.output.is_empty() {
+            self.front_readiness.interest.remove(Ready::writable());
+        }
+        if self.state.output.len() < OUTPUT_BUFFER_LIMIT && self.state.error.is_none() {
+            self.front_readiness.interest.insert(Ready::readable());
+        }
+
+        SessionResult::Continue
     }
 
-    pub fn back_readiness(&mut self) -> &mut Readiness {
-        &mut self.back_readiness
+    fn route_stream<F>(&mut self, stream_id: u32, context: &BackendContext, route: &mut F)
+    where
+        F: FnMut(&Request) -> Result<StreamRoute, Rc<Vec<u8>>>,
+    {
+        let result = match self.state.streams.get(&stream_id) {
+            Some(stream) => route(&stream.request),
+            None => return,
+        };
+
+        let stream_route = match result {
+            Ok(stream_route) => stream_route,
+            Err(answer) => return self.answer(stream_id, &answer),
+        };
+        if let Some(stream) = self.state.stream_mut(stream_id) {
+            stream.cluster_id = Some(stream_route.cluster_id.clone());
+            stream.grpc = stream_route.backend_protocol == BackendProtocol::Grpc;
+        }
+
+        let token = match stream_route.backend_protocol {
+            BackendProtocol::Http1 => self.idle_backend(&stream_route.cluster_id),
+            BackendProtocol::Http2 | BackendProtocol::Grpc => {
+                self.multiplexed_backend(&stream_route.cluster_id)
+            }
+        };
+        let token = match token {
+            Some(token) => token,
+            None => match BackendConnection::connect(
+                &stream_route.cluster_id,
+                context,
+                stream_route.connect_timeout,
+                stream_route.backend_protocol,
+            ) {
+                Ok(connection) => {
+                    let token = connection.token;
+                    self.backends.insert(token, connection);
+                    token
+                }
+                Err(e) => {
+                    error!(
+                        "could not connect HTTP/2 stream {} to cluster {}: {:?}",
+                        stream_id, stream_route.cluster_id, e
+                    );
+                    return self.answer_error(stream_id, DefaultAnswerStatus::Answer503);
+                }
+            },
+        };
+
+        self.start_request(stream_id, token, stream_route);
     }
 
-    pub fn front_hup(&mut self) -> SessionResult {
-        SessionResult::CloseSession
+    fn idle_backend(&self, cluster_id: &str) -> Option<Token> {
+        self.backends
+            .values()
+            .find(|connection| {
+                connection.stream_id.is_none()
+                    && connection.cluster_id == cluster_id
+                    && connection.is_reusable()
+            })
+            .map(|connection| connection.token)
     }
 
-    pub fn back_hup(&mut self) -> SessionResult {
-        error!("todo[{}:{}]: back_hup", file!(), line!());
-        SessionResult::CloseSession
-        /*
-        if self.back_buf.output_data_size() == 0 || self.back_buf.next_output_data().len() == 0 {
-          if self.back_readiness.event.is_readable() {
-            self.back_readiness().interest.insert(Ready::readable());
-            error!("Http2::back_hup: backend connection closed but the kernel still holds some data. readiness: {:?} -> {:?}", self.frontend.readiness, self.back_readiness);
-            SessionResult::Continue
-          } else {
-            SessionResult::CloseSession
-          }
-        } else {
-          self.frontend.readiness().interest.insert(Ready::writable());
-          if self.back_readiness.event.is_readable() {
-            self.back_readiness.interest.insert(Ready::readable());
-          }
-          SessionResult::Continue
-        }
-        */
+    /// an HTTP/2 connection to the cluster that can take another stream
+    fn multiplexed_backend(&self, cluster_id: &str) -> Option<Token> {
+        self.backends
+            .values()
+            .find(|connection| {
+                connection.cluster_id == cluster_id
+                    && matches!(&connection.h2, Some(client) if client.can_open_stream())
+            })
+            .map(|connection| connection.token)
     }
 
-    // Read content from the session
-    pub fn readable(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
-        trace!("http2 readable");
-        error!("todo[{}:{}]: readable", file!(), line!());
+    /// queues the request of a stream on a backend connection
+    fn start_request(&mut self, stream_id: u32, token: Token, stream_route: StreamRoute) {
+        let (connection, stream) = match (
+            self.backends.get_mut(&token),
+            self.state.streams.get_mut(&stream_id),
+        ) {
+            (Some(connection), Some(stream)) => (connection, stream),
+            _ => return,
+        };
 
-        /* do not handle buffer pooling for now
-        if self.front_buf.is_none() {
-          if let Some(p) = self.pool.upgrade() {
-            if let Some(buf) = p.borrow_mut().checkout() {
-              self.front_buf = Some(buf);
-            } else {
-              error!("cannot get front buffer from pool, closing");
-              return SessionResult::CloseSession;
+        if let Some(path) = stream_route.path {
+            stream.request.path = path;
+        }
+
+        let mut edits = stream_route.request_edits;
+        match stream_route.host_rewrite {
+            HostRewrite::Preserve => {}
+            HostRewrite::BackendAddress => {
+                let address = connection.backend.borrow().address.to_string();
+                edits.extend(HeaderEdits::host(&address));
             }
-          }
+            HostRewrite::Fixed(host) => edits.extend(HeaderEdits::host(&host)),
         }
-        */
 
-        if self.frontend.read_buffer.available_space() == 0 {
-            if self.backend_token == None {
-                //let answer_413 = "HTTP/1.1 413 Payload Too Large\r\nContent-Length: 0\r\n\r\n";
-                //self.set_answer(DefaultAnswerStatus::Answer413, Rc::new(Vec::from(answer_413.as_bytes())));
-                self.frontend.readiness.interest.remove(Ready::readable());
-                self.frontend.readiness.interest.insert(Ready::writable());
-            } else {
-                self.frontend.readiness.interest.remove(Ready::readable());
-                self.back_readiness.interest.insert(Ready::writable());
+        // a body received entirely gets its length, otherwise it is chunked
+        if stream.request_ended()
+            && stream.request.content_length.is_none()
+            && !stream.request_body.is_empty()
+        {
+            stream.request.content_length = Some(stream.request_body.len());
+        }
+        let chunked = stream.request.content_length.is_none() && !stream.request_ended();
+
+        let forwarding_headers = AddedRequestHeader {
+            request_id: stream.request_id,
+            public_address: self.public_address,
+            peer_address: self.peer_address,
+            protocol: Protocol::HTTPS,
+            closing: false,
+        }
+        .added_request_header(&stream.request.forwarded_headers());
+
+        connection.back_timeout = stream_route.back_timeout;
+        if connection.connected {
+            connection.timeout.set_duration(stream_route.back_timeout);
+        }
+
+        let scheme = match connection.socket {
+            BackendSocket::Tcp(_) => "http",
+            BackendSocket::Rustls(_) => "https",
+        };
+        if let Some(client) = connection.h2.as_mut() {
+            let headers = stream.request.to_h2(&edits, &forwarding_headers, scheme);
+            let end_stream = stream.request_ended()
+                && stream.request_body.is_empty()
+                && stream.request_trailers.is_empty();
+            stream.backend_stream_id = Some(client.open_stream(
+                stream_id,
+                &headers,
+                end_stream,
+                stream_route.response_edits,
+            ));
+            incr!("http2.backend_streams");
+            connection.readiness.interest.insert(Ready::writable());
+        } else {
+            connection.output = stream
+                .request
+                .to_http1(&edits, &forwarding_headers, chunked);
+            connection.chunked = chunked;
+            connection.request_done = false;
+            connection.stream_id = Some(stream_id);
+            connection.parser = ResponseParser::new(stream.request.method == Method::Head);
+            connection.response_edits = stream_route.response_edits;
+            connection.readiness.interest.insert(Ready::writable());
+            if connection.connected {
+                connection.readiness.interest.insert(Ready::readable());
             }
-            return SessionResult::Continue;
         }
 
-        let res = self.frontend.read(metrics);
+        let mut backend = connection.backend.borrow_mut();
+        backend.active_requests += 1;
+        stream.backend_token = Some(token);
+        stream.backend_started = Some(Instant::now());
+        stream.cluster_id = Some(stream_route.cluster_id);
+        stream.backend_id = Some(backend.backend_id.clone());
+        stream.backend_address = Some(backend.address);
+    }
 
-        match res {
-            SocketResult::Error => {
-                let front_readiness = self.frontend.readiness.clone();
-                let back_readiness = self.back_readiness.clone();
-                error!(
-                    "front socket error, closing the connection. Readiness: {:?} -> {:?}",
-                    front_readiness, back_readiness
-                );
-                return SessionResult::CloseSession;
+    /// sends a default answer, formatted as an HTTP/1.1 response, on a stream
+    pub fn answer(&mut self, stream_id: u32, answer: &[u8]) {
+        let stream = match self.state.stream_mut(stream_id) {
+            Some(stream) if !stream.response_started => stream,
+            _ => return,
+        };
+
+        let mut parser = ResponseParser::new(stream.request.method == Method::Head);
+        stream.response_body.clear();
+        let response = match parser.parse(answer, &mut stream.response_body) {
+            Ok((consumed, Some(response))) => {
+                if parser
+                    .parse(&answer[consumed..], &mut stream.response_body)
+                    .is_err()
+                {
+                    stream.response_body.clear();
+                }
+                response
             }
-            SocketResult::Closed => {
-                return SessionResult::CloseSession;
+            _ => {
+                error!("invalid default answer for HTTP/2 stream {}", stream_id);
+                return self.state.reset_stream(stream_id, H2Error::InternalError);
             }
-            SocketResult::WouldBlock => {}
-            SocketResult::Continue => {}
         };
 
-        self.readable_parse(metrics)
+        stream.response_ended = true;
+        incr!("http.errors");
+        self.state.send_response(stream_id, &response);
     }
 
-    pub fn readable_parse(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
-        let mut state = self.state.take().unwrap();
-        let (sz, cont) = { state.parse_and_handle(self.frontend.read_buffer.data()) };
-        self.frontend.read_buffer.consume(sz);
-        self.frontend.readiness.interest = state.interest;
-        self.state = Some(state);
-
-        match cont {
-            state::FrameResult::Close => SessionResult::CloseSession,
-            state::FrameResult::Continue => SessionResult::Continue,
-            state::FrameResult::ConnectBackend(id) => SessionResult::ConnectBackend,
+    /// answers a stream that could not get its response from its cluster. The
+    /// gRPC calls end with a gRPC status instead, except the health checks of a
+    /// cluster without available backend, told that the service is not serving
+    fn answer_error(&mut self, stream_id: u32, status: DefaultAnswerStatus) {
+        let stream = match self.state.stream_mut(stream_id) {
+            Some(stream) if !stream.response_started => stream,
+            _ => return,
+        };
+        if !stream.grpc {
+            let answer = self
+                .answers
+                .borrow()
+                .get(status, stream.cluster_id.as_deref());
+            return self.answer(stream_id, &answer);
         }
 
-        /*let is_initial = unwrap_msg!(self.state.as_ref()).request == Some(RequestState::Initial);
-        // if there's no host, continue parsing until we find it
-        let has_host = unwrap_msg!(self.state.as_ref()).has_host();
-        if !has_host {
-          self.state = Some(parse_request_until_stop(unwrap_msg!(self.state.take()), &self.request_id,
-            &mut self.front_buf.as_mut().unwrap(), &self.sticky_name));
-          if unwrap_msg!(self.state.as_ref()).is_front_error() {
-            self.log_request_error(metrics, "front parsing error, closing the connection");
-            incr!("http.front_parse_errors");
+        let response =
+            if status == DefaultAnswerStatus::Answer503 && grpc::is_health_check(&stream.request) {
+                let (response, message, trailers) = grpc::not_serving();
+                stream.response_body = message;
+                stream.response_trailers = trailers;
+                incr!("grpc.health_check.not_serving");
+                response
+            } else {
+                stream.response_body.clear();
+                incr!("http.errors");
+                grpc::error_response(status.into())
+            };
+        stream.grpc_status =
+            grpc::status(&stream.response_trailers).or_else(|| grpc::status(&response.headers));
+        stream.response_ended = true;
+        self.state.send_response(stream_id, &response);
+    }
 
-            // increment active requests here because it will be decremented right away
-            // when closing the connection. It's slightly easier than decrementing it
-            // at every place we return SessionResult::CloseSession
-            gauge_add!("http.active_requests", 1);
+    fn backend_ready(&mut self, token: Token) -> BackendResult {
+        let connection = match self.backends.get_mut(&token) {
+            Some(connection) => connection,
+            None => return BackendResult::Continue,
+        };
+        if connection.h2.is_some() {
+            return self.multiplexed_ready(token);
+        }
+        let interest = connection.readiness.filter_interest();
+        if interest.is_empty() {
+            return self.deliver_response(token);
+        }
 
-            return SessionResult::CloseSession;
-          }
+        if !connection.connected {
+            if interest.is_hup() || interest.is_error() {
+                return BackendResult::ConnectionFailed;
+            }
+            connection.set_connected();
         }
-        */
-    }
 
-    // Forward content to session
-    pub fn writable(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
-        trace!("http2 writable");
-        error!("todo[{}:{}]: writable", file!(), line!());
+        let stream_id = match connection.stream_id {
+            Some(stream_id) => stream_id,
+            None => {
+                // an idle connection has nothing to say, unless it is closing
+                if interest.is_readable() || interest.is_hup() || interest.is_error() {
+                    return BackendResult::Close;
+                }
+                connection.readiness.interest.remove(Ready::writable());
+                return BackendResult::Continue;
+            }
+        };
+
+        if interest.is_error() {
+            return BackendResult::Error(DefaultAnswerStatus::Answer502);
+        }
 
-        let mut state = self.state.take().unwrap();
-        //FIXME: do that in a loop until no more frames or WouldBlock
-        match state.gen(self.frontend.write_buffer.space()) {
-            Ok(sz) => {
-                self.frontend.write_buffer.fill(sz);
-                //FIXME: use real condition here to indicate there was nothing to write
-                if sz == 0 {}
+        if interest.is_writable() {
+            loop {
+                if connection.output.is_empty() {
+                    let size = match self.state.streams.get_mut(&stream_id) {
+                        Some(stream) => connection.fill_request(stream),
+                        None => return BackendResult::Close,
+                    };
+                    self.state.consumed(stream_id, size);
+                    if connection.output.is_empty() {
+                        connection.readiness.interest.remove(Ready::writable());
+                        break;
+                    }
+                }
+
+                let (size, result) = connection.socket.socket_write(&connection.output);
+                connection.output.drain(..size);
+                count!("back_bytes_out", size as i64);
+                match result {
+                    SocketResult::Continue if size > 0 => {}
+                    SocketResult::Continue | SocketResult::WouldBlock => {
+                        connection.readiness.event.remove(Ready::writable());
+                        break;
+                    }
+                    SocketResult::Error | SocketResult::Closed => {
+                        return BackendResult::Error(DefaultAnswerStatus::Answer502);
+                    }
+                }
             }
-            Err(e) => {
-                self.state = Some(state);
-                error!("error serializing to front write buffer: {:?}", e);
-                return SessionResult::CloseSession;
+            connection.timeout.reset();
+        }
+
+        if interest.is_hup() {
+            // the data still readable is handled before the end of the connection
+            connection.hup = true;
+            connection.readiness.interest.remove(Ready::hup());
+        }
+
+        let mut closed = false;
+        if interest.is_readable() {
+            let mut buffer = [0u8; READ_BUFFER_SIZE];
+            loop {
+                if connection.input.len() >= RESPONSE_BUFFER_LIMIT || connection.parser.is_done() {
+                    connection.readiness.interest.remove(Ready::readable());
+                    break;
+                }
+
+                let (size, result) = connection.socket.socket_read(&mut buffer);
+                connection.input.extend_from_slice(&buffer[..size]);
+                count!("back_bytes_in", size as i64);
+                match result {
+                    SocketResult::Continue => {}
+                    SocketResult::WouldBlock => {
+                        connection.readiness.event.remove(Ready::readable());
+                        break;
+                    }
+                    SocketResult::Error | SocketResult::Closed => {
+                        closed = true;
+                        connection.readiness.interest.remove(Ready::readable());
+                        break;
+                    }
+                }
             }
+            connection.timeout.reset();
         }
 
-        self.frontend.readiness.interest = state.interest;
+        let result = self.deliver_response(token);
+        if !closed {
+            return result;
+        }
 
-        let res = self.frontend.write(metrics);
-        match res {
-            SocketResult::Error | SocketResult::Closed => {
-                error!(
-                    "{}\t[{:?}] error writing to front socket, closing",
-                    self.log_ctx, self.frontend_token
-                );
-                incr!("http2.errors");
-                metrics.service_stop();
-                self.frontend.readiness.reset();
-                self.back_readiness.reset();
-                self.state = Some(state);
-                return SessionResult::CloseSession;
+        let connection = match self.backends.get_mut(&token) {
+            Some(connection) => connection,
+            None => return result,
+        };
+        connection.hup = true;
+        if !connection.parser.is_done() && connection.input.is_empty() && connection.parser.close()
+        {
+            // the response was delimited by the end of the connection
+            if let Some(stream) = self.state.streams.get_mut(&stream_id) {
+                stream.end_backend_response(&connection.backend);
             }
-            SocketResult::WouldBlock => {}
-            SocketResult::Continue => {}
+            return result;
+        }
+        if connection.parser.is_done() {
+            return result;
         }
+        match result {
+            BackendResult::Continue => BackendResult::Error(DefaultAnswerStatus::Answer502),
+            result => result,
+        }
+    }
 
-        self.state = Some(state);
-        SessionResult::Continue
+    /// parses the response read from a backend and hands it to its stream
+    fn deliver_response(&mut self, token: Token) -> BackendResult {
+        loop {
+            let connection = match self.backends.get_mut(&token) {
+                Some(connection) => connection,
+                None => return BackendResult::Continue,
+            };
+            let stream_id = match connection.stream_id {
+                Some(stream_id) => stream_id,
+                None => return BackendResult::Continue,
+            };
+            let stream = match self.state.streams.get_mut(&stream_id) {
+                Some(stream) => stream,
+                None => return BackendResult::Close,
+            };
+            if stream.response_body.len() >= RESPONSE_BUFFER_LIMIT || connection.parser.is_done() {
+                return BackendResult::Continue;
+            }
+
+            let (consumed, response) = match connection
+                .parser
+                .parse(&connection.input, &mut stream.response_body)
+            {
+                Ok(result) => result,
+                Err(()) => {
+                    error!("{}invalid response from the backend", stream.log_context());
+                    return BackendResult::Error(DefaultAnswerStatus::Answer502);
+                }
+            };
+            connection.input.drain(..consumed);
+
+            if connection.parser.is_done() {
+                stream.end_backend_response(&connection.backend);
+                stream.response_trailers = std::mem::take(&mut connection.parser.trailers);
+            }
+
+            match response {
+                Some(mut response) => {
+                    response.edit(&connection.response_edits);
+                    self.state.send_response(stream_id, &response);
+                }
+                None if consumed == 0 => return BackendResult::Continue,
+                None => {}
+            }
+        }
     }
 
-    // Forward content to cluster
-    pub fn back_writable(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
-        trace!("http2 back_writable");
-        error!("todo[{}:{}]: back_writable", file!(), line!());
-        SessionResult::CloseSession
-        /*
-        if self.front_buf.output_data_size() == 0 || self.front_buf.next_output_data().len() == 0 {
-          self.frontend.readiness.interest.insert(Ready::readable());
-          self.back_readiness.interest.remove(Ready::writable());
-          return SessionResult::Continue;
+    /// writes the frames queued for an HTTP/2 backend, and parses what it sent
+    fn multiplexed_ready(&mut self, token: Token) -> BackendResult {
+        let connection = match self.backends.get_mut(&token) {
+            Some(connection) => connection,
+            None => return BackendResult::Continue,
+        };
+        let interest = connection.readiness.filter_interest();
+        if !connection.connected && !interest.is_empty() {
+            if interest.is_hup() || interest.is_error() {
+                return BackendResult::ConnectionFailed;
+            }
+            connection.set_connected();
+        }
+        if interest.is_error() {
+            return BackendResult::Error(DefaultAnswerStatus::Answer502);
         }
 
-        let tokens = self.tokens().clone();
-        let output_size = self.front_buf.output_data_size();
+        let client = match connection.h2.as_mut() {
+            Some(client) => client,
+            None => return BackendResult::Continue,
+        };
 
-        let mut sz = 0usize;
-        let mut socket_res = SocketResult::Continue;
+        if interest.is_writable() {
+            while !client.output.is_empty() {
+                let (size, result) = connection.socket.socket_write(&client.output);
+                client.output.drain(..size);
+                count!("back_bytes_out", size as i64);
+                match result {
+                    SocketResult::Continue if size > 0 => {}
+                    SocketResult::Continue | SocketResult::WouldBlock => {
+                        connection.readiness.event.remove(Ready::writable());
+                        break;
+                    }
+                    SocketResult::Error | SocketResult::Closed => {
+                        return BackendResult::Error(DefaultAnswerStatus::Answer502);
+                    }
+                }
+            }
+            if client.output.is_empty() {
+                connection.readiness.interest.remove(Ready::writable());
+            }
+            connection.timeout.reset();
+        }
 
-        if let Some(ref mut backend) = self.backend {
-          while socket_res == SocketResult::Continue && self.front_buf.output_data_size() > 0 {
-            // no more data in buffer, stop here
-            if self.front_buf.next_output_data().len() == 0 {
-              self.frontend.readiness.interest.insert(Ready::readable());
-              self.back_readiness.interest.remove(Ready::writable());
-              return SessionResult::Continue;
+        let mut closed = interest.is_hup();
+        if interest.is_readable() {
+            let mut buffer = [0u8; READ_BUFFER_SIZE];
+            loop {
+                let (size, result) = connection.socket.socket_read(&mut buffer);
+                connection.input.extend_from_slice(&buffer[..size]);
+                count!("back_bytes_in", size as i64);
+
+                match client.parse(&connection.input) {
+                    Ok(consumed) => {
+                        connection.input.drain(..consumed);
+                    }
+                    Err(error) => {
+                        error!(
+                            "invalid HTTP/2 frames from a backend of cluster {}: {:?}",
+                            connection.cluster_id, error
+                        );
+                        incr!("http2.backend_errors");
+                        return BackendResult::Error(DefaultAnswerStatus::Answer502);
+                    }
+                }
+
+                match result {
+                    SocketResult::Continue => {}
+                    SocketResult::WouldBlock => {
+                        connection.readiness.event.remove(Ready::readable());
+                        break;
+                    }
+                    SocketResult::Error | SocketResult::Closed => {
+                        closed = true;
+                        break;
+                    }
+                }
             }
+            connection.timeout.reset();
+        }
+
+        self.deliver_streams(token);
 
-            let (current_sz, current_res) = backend.socket_write(self.front_buf.next_output_data());
-            socket_res = current_res;
-            self.front_buf.consume_output_data(current_sz);
-            self.front_buf_position += current_sz;
-            sz += current_sz;
-          }
+        if !closed {
+            return BackendResult::Continue;
+        }
+        // the streams still waiting for their response will not get it
+        match self.backends.get(&token) {
+            Some(connection) if !connection.stream_ids().is_empty() => {
+                BackendResult::Error(DefaultAnswerStatus::Answer502)
+            }
+            _ => BackendResult::Close,
         }
+    }
+
+    /// hands the responses parsed from an HTTP/2 backend to their streams
+    fn deliver_streams(&mut self, token: Token) {
+        let connection = match self.backends.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let client = match connection.h2.as_mut() {
+            Some(client) => client,
+            None => return,
+        };
 
-        metrics.backend_bout += sz;
+        let mut responses = Vec::new();
+        let mut failed = Vec::new();
+        let mut removed = Vec::new();
+        for backend_stream in client.streams.values_mut() {
+            let stream_id = backend_stream.front_stream_id;
+            let stream = match self.state.streams.get_mut(&stream_id) {
+                Some(stream) => stream,
+                None => {
+                    removed.push(backend_stream.id);
+                    continue;
+                }
+            };
+
+            if let Some(error) = backend_stream.reset {
+                removed.push(backend_stream.id);
+                if !stream.response_ended {
+                    failed.push((stream_id, error));
+                }
+                continue;
+            }
 
-        if let Some((front,back)) = tokens {
-          debug!("{}\tBACK [{}->{}]: wrote {} bytes of {}", self.log_ctx, front.0, back.0, sz, output_size);
+            stream.response_body.append(&mut backend_stream.body);
+            if backend_stream.response_ended && !stream.response_ended {
+                stream.end_backend_response(&connection.backend);
+                stream.response_trailers = std::mem::take(&mut backend_stream.trailers);
+                if stream.grpc {
+                    stream.grpc_status =
+                        grpc::status(&stream.response_trailers).or(stream.grpc_status);
+                }
+            }
+            if let Some(mut response) = backend_stream.response.take() {
+                if stream.grpc {
+                    // a trailers-only response carries the status in its headers
+                    stream.grpc_status = grpc::status(&response.headers).or(stream.grpc_status);
+                }
+                response.edit(&backend_stream.response_edits);
+                responses.push((stream_id, response));
+            }
         }
-        match socket_res {
-          SocketResult::Error | SocketResult::Closed => {
-            error!("{}\tback socket write error, closing connection", self.log_ctx);
-            metrics.service_stop();
-            incr!("http2.errors");
-            self.frontend.readiness.reset();
-            self.back_readiness.reset();
-            return SessionResult::CloseSession;
-          },
-          SocketResult::WouldBlock => {
-            self.back_readiness.event.remove(Ready::writable());
 
-          },
-          SocketResult::Continue => {}
+        for backend_stream_id in removed {
+            connection.remove_stream(backend_stream_id);
+        }
+        for (stream_id, response) in responses {
+            self.state.send_response(stream_id, &response);
+        }
+        for (stream_id, error) in failed {
+            let started = match self.state.streams.get(&stream_id) {
+                Some(stream) => stream.response_started,
+                None => continue,
+            };
+            if started {
+                self.state.reset_stream(stream_id, H2Error::InternalError);
+                continue;
+            }
+            // a refused stream was not processed by the backend
+            let status = if error == H2Error::RefusedStream {
+                DefaultAnswerStatus::Answer503
+            } else {
+                DefaultAnswerStatus::Answer502
+            };
+            self.answer_error(stream_id, status);
         }
-        SessionResult::Continue
-        */
     }
 
-    // Read content from cluster
-    pub fn back_readable(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
-        trace!("http2 back_readable");
-        error!("todo[{}:{}]: back_readable", file!(), line!());
-        SessionResult::CloseSession
-        /*
-        if self.back_buf.buffer.available_space() == 0 {
-          self.back_readiness.interest.remove(Ready::readable());
-          return SessionResult::Continue;
-        }
-
-        let tokens     = self.tokens().clone();
-
-        if let Some(ref mut backend) = self.backend {
-          let (sz, r) = backend.socket_read(&mut self.back_buf.buffer.space());
-          self.back_buf.buffer.fill(sz);
-          self.back_buf.sliced_input(sz);
-          self.back_buf.consume_parsed_data(sz);
-          self.back_buf.slice_output(sz);
-
-          if let Some((front,back)) = tokens {
-            debug!("{}\tBACK  [{}<-{}]: read {} bytes", self.log_ctx, front.0, back.0, sz);
-          }
-
-          if r != SocketResult::Continue || sz == 0 {
-            self.back_readiness.event.remove(Ready::readable());
-          }
-          if sz > 0 {
-            self.frontend.readiness.interest.insert(Ready::writable());
-            metrics.backend_bin += sz;
-          }
-
-          match r {
-            SocketResult::Error => {
-              error!("{}\tback socket read error, closing connection", self.log_ctx);
-              metrics.service_stop();
-              incr!("http2.errors");
-              self.frontend.readiness.reset();
-              self.back_readiness.reset();
-              return SessionResult::CloseSession;
-            },
-            SocketResult::Closed => {
-              metrics.service_stop();
-              self.frontend.readiness.reset();
-              self.back_readiness.reset();
-              return SessionResult::CloseSession;
-            },
-            SocketResult::WouldBlock => {
-              self.back_readiness.event.remove(Ready::readable());
-            },
-            SocketResult::Continue => {}
-          }
+    fn handle_backend_result(
+        &mut self,
+        token: Token,
+        result: BackendResult,
+        context: &BackendContext,
+    ) {
+        match result {
+            BackendResult::Continue => {}
+            BackendResult::Close => self.close_backend(token, context),
+            BackendResult::ConnectionFailed => {
+                if let Some(connection) = self.backends.get_mut(&token) {
+                    error!(
+                        "error connecting to backend of cluster {}",
+                        connection.cluster_id
+                    );
+                    connection.connection_failed();
+                }
+                self.backend_error(token, DefaultAnswerStatus::Answer503, context);
+            }
+            BackendResult::Error(status) => self.backend_error(token, status, context),
         }
-
-        SessionResult::Continue
-        */
     }
-}
 
-pub struct Connection<Socket: SocketHandler> {
-    pub socket: Socket,
-    pub readiness: Readiness,
-    pub read_buffer: Checkout,
-    pub write_buffer: Checkout,
-}
+    /// the streams of a backend connection get an error answer, or are reset if
+    /// their response started, then the connection is closed. The streams that
+    /// received their whole response are not affected
+    fn backend_error(
+        &mut self,
+        token: Token,
+        status: DefaultAnswerStatus,
+        context: &BackendContext,
+    ) {
+        let stream_ids = match self.backends.get(&token) {
+            Some(connection) => connection.stream_ids(),
+            None => return,
+        };
 
-impl<Socket: SocketHandler> Connection<Socket> {
-    pub fn new(socket: Socket, read_buffer: Checkout, write_buffer: Checkout) -> Self {
-        Connection {
-            socket,
-            readiness: Readiness {
-                interest: Ready::readable() | Ready::writable() | Ready::hup() | Ready::error(),
-                event: Ready::empty(),
-            },
-            //FIXME: capacity can be configured
-            read_buffer,
-            //FIXME: capacity can be configured
-            write_buffer,
+        for stream_id in stream_ids {
+            match self
+                .state
+                .streams
+                .get(&stream_id)
+                .map(|stream| (stream.response_started, stream.response_ended))
+            {
+                Some((false, _)) => self.answer_error(stream_id, status),
+                Some((true, false)) => self.state.reset_stream(stream_id, H2Error::InternalError),
+                _ => {}
+            }
         }
+
+        self.close_backend(token, context);
     }
 
-    pub fn read(&mut self, metrics: &mut SessionMetrics) -> SocketResult {
-        let (sz, res) = self.socket.socket_read(self.read_buffer.space());
+    fn close_backend(&mut self, token: Token, context: &BackendContext) {
+        if let Some(mut connection) = self.backends.remove(&token) {
+            connection.close(context.registry, context.sessions);
+        }
+    }
 
-        if sz > 0 {
-            count!("bytes_in", sz as i64);
-            metrics.bin += sz;
+    /// logs the streams that ended, and frees or closes their backend connections
+    fn release_streams(&mut self, context: &BackendContext) {
+        for stream in std::mem::take(&mut self.state.closed_streams) {
+            gauge_add!("http2.active_streams", -1);
+            self.log_stream(&stream);
+
+            let token = match stream.backend_token {
+                Some(token) => token,
+                None => continue,
+            };
+            let reusable = match self.backends.get_mut(&token) {
+                Some(connection) if connection.h2.is_some() => {
+                    if let Some(backend_stream_id) = stream.backend_stream_id {
+                        connection.remove_stream(backend_stream_id);
+                    }
+                    continue;
+                }
+                Some(connection) if connection.stream_id == Some(stream.id) => {
+                    if stream.reset || !connection.is_reusable() {
+                        false
+                    } else {
+                        connection.stream_id = None;
+                        let mut backend = connection.backend.borrow_mut();
+                        backend.active_requests = backend.active_requests.saturating_sub(1);
+                        true
+                    }
+                }
+                _ => continue,
+            };
+
+            if reusable {
+                if let Some(connection) = self.backends.get_mut(&token) {
+                    connection.readiness.interest =
+                        Ready::readable() | Ready::hup() | Ready::error();
+                    connection.timeout.reset();
+                }
+            } else {
+                self.close_backend(token, context);
+            }
+        }
 
-            self.read_buffer.fill(sz);
+        // the HTTP/2 connections that cannot take new streams close once their streams end
+        let finished: Vec<Token> = self
+            .backends
+            .values()
+            .filter(|connection| {
+                matches!(&connection.h2, Some(client) if client.going_away && client.is_idle())
+            })
+            .map(|connection| connection.token)
+            .collect();
+        for token in finished {
+            self.close_backend(token, context);
+        }
+    }
 
-            if self.read_buffer.available_space() == 0 {
-                self.readiness.interest.remove(Ready::readable());
+    /// reads or writes the backends again once their streams have room or data.
+    /// The HTTP/2 backends get the request data of their streams, and the
+    /// response data handed to the client back in their windows
+    fn update_backend_interests(&mut self) {
+        let mut consumed = Vec::new();
+        for connection in self.backends.values_mut() {
+            if let Some(client) = connection.h2.as_mut() {
+                let stream_ids: Vec<(u32, u32)> = client
+                    .streams
+                    .values()
+                    .map(|backend_stream| (backend_stream.id, backend_stream.front_stream_id))
+                    .collect();
+                for (backend_stream_id, stream_id) in stream_ids {
+                    let stream = match self.state.streams.get_mut(&stream_id) {
+                        Some(stream) => stream,
+                        None => continue,
+                    };
+
+                    let ended = stream.request_ended();
+                    let size = client.send_request_data(
+                        backend_stream_id,
+                        &mut stream.request_body,
+                        ended,
+                        &stream.request_trailers,
+                    );
+                    consumed.push((stream_id, size));
+
+                    let unreleased = client
+                        .streams
+                        .get(&backend_stream_id)
+                        .map(|backend_stream| backend_stream.unreleased)
+                        .unwrap_or(0);
+                    let released = unreleased - stream.response_body.len() as i64;
+                    if released > 0 {
+                        client.release(backend_stream_id, released as usize);
+                    }
+                }
+
+                if !client.output.is_empty() {
+                    connection.readiness.interest.insert(Ready::writable());
+                }
+                continue;
+            }
+
+            let stream = match connection.stream_id {
+                Some(stream_id) if connection.connected => self.state.streams.get(&stream_id),
+                _ => None,
+            };
+            if let Some(stream) = stream {
+                if !connection.request_done
+                    && (!stream.request_body.is_empty() || stream.request_ended())
+                {
+                    connection.readiness.interest.insert(Ready::writable());
+                }
+                if connection.input.len() < RESPONSE_BUFFER_LIMIT
+                    && stream.response_body.len() < RESPONSE_BUFFER_LIMIT
+                    && !connection.parser.is_done()
+                {
+                    connection.readiness.interest.insert(Ready::readable());
+                }
             }
-        } else {
-            self.readiness.event.remove(Ready::readable());
         }
 
-        if res == SocketResult::WouldBlock {
-            self.readiness.event.remove(Ready::readable());
+        for (stream_id, size) in consumed {
+            self.state.consumed(stream_id, size);
+        }
+    }
+
+    fn log_stream(&self, stream: &Stream) {
+        let response_time = Instant::now() - stream.started;
+        let cluster_id = stream.cluster_id.as_deref();
+        time!(
+            "response_time",
+            cluster_id.unwrap_or("-"),
+            response_time.whole_milliseconds()
+        );
+        time!("response_time", response_time.whole_milliseconds());
+
+        match stream.response_status {
+            Some(100..=199) => incr!("http.status.1xx"),
+            Some(200..=299) => incr!("http.status.2xx"),
+            Some(300..=399) => incr!("http.status.3xx"),
+            Some(400..=499) => incr!("http.status.4xx"),
+            Some(500..=599) => {
+                incr!("http.status.5xx");
+                if cluster_id.is_some() {
+                    incr!("http.status.5xx", cluster_id, stream.backend_id.as_deref());
+                }
+            }
+            _ => incr!("http.status.other"),
+        }
+        if stream.grpc {
+            incr!(grpc::status_metric(stream.grpc_status), cluster_id, None);
         }
 
-        res
+        let host = stream.request.host().unwrap_or("-");
+        let hostname = host.split_once(':').map(|(name, _)| name).unwrap_or(host);
+        let tags = self.listener.borrow().get_tags(hostname).map(|tags| {
+            tags.iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<_>>()
+                .join(", ")
+        });
+
+        info_access!(
+            "{}{} -> {}\t{} - {} {}\t{}\tHTTP2 {} {} {} {}{}",
+            stream.log_context(),
+            self.peer_address
+                .map(|address| address.to_string())
+                .unwrap_or_else(|| "X".to_string()),
+            stream
+                .backend_address
+                .map(|address| address.to_string())
+                .unwrap_or_else(|| "X".to_string()),
+            LogDuration(response_time),
+            stream.request_body_size,
+            stream.bytes_out,
+            tags.as_deref().unwrap_or("-"),
+            host,
+            stream
+                .response_status
+                .map(|status| status.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            stream.request.method,
+            stream.request.path,
+            if stream.reset { " (reset)" } else { "" }
+        );
+
+        tap_access_log(cluster_id, stream.response_status, || AccessLogRecord {
+            timestamp: 0,
+            cluster_id: stream.cluster_id.clone(),
+            backend_id: stream.backend_id.clone(),
+            session_address: self.peer_address,
+            backend_address: stream.backend_address,
+            protocol: String::from("HTTP2"),
+            host: stream.request.host().map(String::from),
+            method: Some(stream.request.method.to_string()),
+            path: Some(stream.request.path.clone()),
+            status: stream.response_status,
+            response_time: response_time.whole_milliseconds() as u64,
+            service_time: response_time.whole_milliseconds() as u64,
+            bytes_in: stream.request_body_size,
+            bytes_out: stream.bytes_out,
+            error: stream.reset.then(|| String::from("reset")),
+        });
     }
 
-    pub fn write(&mut self, metrics: &mut SessionMetrics) -> SocketResult {
-        let mut sz = 0usize;
-        let mut res = SocketResult::Continue;
-        while res == SocketResult::Continue && self.write_buffer.available_data() > 0 {
-            let (current_sz, current_res) = self.socket.socket_write(self.write_buffer.data());
-            res = current_res;
-            self.write_buffer.consume(current_sz);
-            sz += current_sz;
+    pub fn timeout(
+        &mut self,
+        token: Token,
+        metrics: &mut SessionMetrics,
+        context: &BackendContext,
+    ) -> SessionResult {
+        if token == self.frontend_token {
+            self.front_timeout.triggered();
+            // the connection stays up while the backends work on its streams
+            if self.state.streams.is_empty() {
+                return SessionResult::CloseSession;
+            }
+            self.front_timeout.reset();
+            return SessionResult::Continue;
         }
 
-        if sz > 0 {
-            count!("bytes_out", sz as i64);
-            metrics.bout += sz;
+        let connected = match self.backends.get_mut(&token) {
+            Some(connection) => {
+                connection.timeout.triggered();
+                if connection.stream_ids().is_empty() {
+                    self.close_backend(token, context);
+                    return SessionResult::Continue;
+                }
+                if !connection.connected {
+                    connection.connection_failed();
+                }
+                connection.connected
+            }
+            None => return SessionResult::Continue,
+        };
+
+        let status = if connected {
+            DefaultAnswerStatus::Answer504
+        } else {
+            DefaultAnswerStatus::Answer503
+        };
+        self.backend_error(token, status, context);
+        self.state.send_data();
+        self.release_streams(context);
+        self.writable(metrics)
+    }
+
+    /// stops accepting new streams, the session closes once the current ones end
+    pub fn shutting_down(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
+        self.state.goaway();
+        if self.writable(metrics) == SessionResult::CloseSession || self.state.is_idle() {
+            SessionResult::CloseSession
+        } else {
+            SessionResult::Continue
         }
+    }
 
-        if res == SocketResult::WouldBlock {
-            self.readiness.event.remove(Ready::writable());
+    pub fn close(&mut self, registry: &Registry, sessions: &RefCell<SessionManager>) {
+        self.front_timeout.cancel();
+        for (_, mut connection) in self.backends.drain() {
+            connection.close(registry, sessions);
         }
+        gauge_add!(
+            "http2.active_streams",
+            -((self.state.streams.len() + self.state.closed_streams.len()) as i64)
+        );
+        self.state.streams.clear();
+        self.state.closed_streams.clear();
+    }
 
-        res
+    pub fn print_state(&self) -> String {
+        format!(
+            "HTTP2: {} streams, {} backend connections, output {} bytes, error {:?}",
+            self.state.streams.len(),
+            self.backends.len(),
+            self.state.output.len(),
+            self.state.error
+        )
     }
 }
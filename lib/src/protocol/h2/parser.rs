@@ -2,14 +2,26 @@ use std::convert::From;
 
 use nom::{
     bytes::streaming::{tag, take},
-    combinator::{complete, map, map_opt},
     error::{ErrorKind, ParseError},
-    multi::many0,
-    number::streaming::{be_u16, be_u24, be_u32, be_u8},
-    sequence::tuple,
-    Err, HexDisplay, IResult, Offset,
+    number::streaming::{be_u24, be_u32, be_u8},
+    Err, IResult,
 };
 
+pub const FRAME_HEADER_SIZE: usize = 9;
+
+pub const FLAG_END_STREAM: u8 = 0x1;
+pub const FLAG_ACK: u8 = 0x1;
+pub const FLAG_END_HEADERS: u8 = 0x4;
+pub const FLAG_PADDED: u8 = 0x8;
+pub const FLAG_PRIORITY: u8 = 0x20;
+
+pub const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+pub const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+pub const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+pub const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct FrameHeader {
     pub payload_len: u32,
@@ -30,34 +42,13 @@ pub enum FrameType {
     GoAway,
     WindowUpdate,
     Continuation,
+    /// frames of unknown types must be ignored
+    Unknown(u8),
 }
 
-/*
-const NO_ERROR: u32 = 0x0;
-const PROTOCOL_ERROR: u32 = 0x1;
-const INTERNAL_ERROR: u32 = 0x2;
-const FLOW_CONTROL_ERROR: u32 = 0x3;
-const SETTINGS_TIMEOUT: u32 = 0x4;
-const STREAM_CLOSED: u32 = 0x5;
-const FRAME_SIZE_ERROR: u32 = 0x6;
-const REFUSED_STREAM: u32 = 0x7;
-const CANCEL: u32 = 0x8;
-const COMPRESSION_ERROR: u32 = 0x9;
-const CONNECT_ERROR: u32 = 0xa;
-const ENHANCE_YOUR_CALM: u32 = 0xb;
-const INADEQUATE_SECURITY: u32 = 0xc;
-const HTTP_1_1_REQUIRED: u32 = 0xd;
-*/
-
-#[derive(Clone, Debug, PartialEq)]
-pub struct Error<'a> {
-    pub input: &'a [u8],
-    pub error: InnerError,
-}
-
-#[derive(Clone, Debug, PartialEq)]
-pub enum InnerError {
-    Nom(ErrorKind),
+/// error codes of RST_STREAM and GOAWAY frames
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum H2Error {
     NoError,
     ProtocolError,
     InternalError,
@@ -74,9 +65,73 @@ pub enum InnerError {
     HTTP11Required,
 }
 
+impl H2Error {
+    /// the unknown codes are treated as internal errors
+    pub fn from_code(code: u32) -> H2Error {
+        match code {
+            0x0 => H2Error::NoError,
+            0x1 => H2Error::ProtocolError,
+            0x3 => H2Error::FlowControlError,
+            0x4 => H2Error::SettingsTimeout,
+            0x5 => H2Error::StreamClosed,
+            0x6 => H2Error::FrameSizeError,
+            0x7 => H2Error::RefusedStream,
+            0x8 => H2Error::Cancel,
+            0x9 => H2Error::CompressionError,
+            0xa => H2Error::ConnectError,
+            0xb => H2Error::EnhanceYourCalm,
+            0xc => H2Error::InadequateSecurity,
+            0xd => H2Error::HTTP11Required,
+            _ => H2Error::InternalError,
+        }
+    }
+
+    pub fn code(&self) -> u32 {
+        match self {
+            H2Error::NoError => 0x0,
+            H2Error::ProtocolError => 0x1,
+            H2Error::InternalError => 0x2,
+            H2Error::FlowControlError => 0x3,
+            H2Error::SettingsTimeout => 0x4,
+            H2Error::StreamClosed => 0x5,
+            H2Error::FrameSizeError => 0x6,
+            H2Error::RefusedStream => 0x7,
+            H2Error::Cancel => 0x8,
+            H2Error::CompressionError => 0x9,
+            H2Error::ConnectError => 0xa,
+            H2Error::EnhanceYourCalm => 0xb,
+            H2Error::InadequateSecurity => 0xc,
+            H2Error::HTTP11Required => 0xd,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Error<'a> {
+    pub input: &'a [u8],
+    pub error: InnerError,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum InnerError {
+    Nom(ErrorKind),
+    H2(H2Error),
+}
+
 impl<'a> Error<'a> {
-    pub fn new(input: &'a [u8], error: InnerError) -> Error<'a> {
-        Error { input, error }
+    pub fn new(input: &'a [u8], error: H2Error) -> Error<'a> {
+        Error {
+            input,
+            error: InnerError::H2(error),
+        }
+    }
+
+    /// the error code sent to the peer in a GOAWAY frame
+    pub fn h2_error(&self) -> H2Error {
+        match self.error {
+            InnerError::H2(error) => error,
+            InnerError::Nom(_) => H2Error::ProtocolError,
+        }
     }
 }
 
@@ -88,7 +143,7 @@ impl<'a> ParseError<&'a [u8]> for Error<'a> {
         }
     }
 
-    fn append(input: &'a [u8], kind: ErrorKind, other: Self) -> Self {
+    fn append(input: &'a [u8], kind: ErrorKind, _other: Self) -> Self {
         Error {
             input,
             error: InnerError::Nom(kind),
@@ -105,29 +160,16 @@ impl<'a> From<(&'a [u8], ErrorKind)> for Error<'a> {
     }
 }
 
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
 pub fn preface(i: &[u8]) -> IResult<&[u8], &[u8]> {
-    tag(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n")(i)
+    tag(PREFACE)(i)
 }
 
 // https://httpwg.org/specs/rfc7540.html#rfc.section.4.1
-/*named!(pub frame_header<FrameHeader>,
-  do_parse!(
-    payload_len: dbg_dmp!(be_u24) >>
-    frame_type: map_opt!(be_u8, convert_frame_type) >>
-    flags: dbg_dmp!(be_u8) >>
-    stream_id: dbg_dmp!(verify!(be_u32, |id| {
-      match frame_type {
-
-      }
-    }) >>
-    (FrameHeader { payload_len, frame_type, flags, stream_id })
-  )
-);
-  */
-
-pub fn frame_header(input: &[u8]) -> IResult<&[u8], FrameHeader, Error> {
+pub fn frame_header(input: &[u8]) -> IResult<&[u8], FrameHeader, Error<'_>> {
     let (i1, payload_len) = be_u24(input)?;
-    let (i2, frame_type) = map_opt(be_u8, convert_frame_type)(i1)?;
+    let (i2, frame_type) = be_u8(i1)?;
     let (i3, flags) = be_u8(i2)?;
     let (i4, stream_id) = be_u32(i3)?;
 
@@ -135,27 +177,27 @@ pub fn frame_header(input: &[u8]) -> IResult<&[u8], FrameHeader, Error> {
         i4,
         FrameHeader {
             payload_len,
-            frame_type,
+            frame_type: convert_frame_type(frame_type),
             flags,
-            stream_id,
+            // the reserved bit is ignored
+            stream_id: stream_id & 0x7FFF_FFFF,
         },
     ))
 }
 
-fn convert_frame_type(t: u8) -> Option<FrameType> {
-    info!("got frame type: {}", t);
+fn convert_frame_type(t: u8) -> FrameType {
     match t {
-        0 => Some(FrameType::Data),
-        1 => Some(FrameType::Headers),
-        2 => Some(FrameType::Priority),
-        3 => Some(FrameType::RstStream),
-        4 => Some(FrameType::Settings),
-        5 => Some(FrameType::PushPromise),
-        6 => Some(FrameType::Ping),
-        7 => Some(FrameType::GoAway),
-        8 => Some(FrameType::WindowUpdate),
-        9 => Some(FrameType::Continuation),
-        _ => None,
+        0 => FrameType::Data,
+        1 => FrameType::Headers,
+        2 => FrameType::Priority,
+        3 => FrameType::RstStream,
+        4 => FrameType::Settings,
+        5 => FrameType::PushPromise,
+        6 => FrameType::Ping,
+        7 => FrameType::GoAway,
+        8 => FrameType::WindowUpdate,
+        9 => FrameType::Continuation,
+        t => FrameType::Unknown(t),
     }
 }
 
@@ -163,51 +205,39 @@ fn convert_frame_type(t: u8) -> Option<FrameType> {
 pub enum Frame<'a> {
     Data(Data<'a>),
     Headers(Headers<'a>),
-    Priority,
+    Priority(Priority),
     RstStream(RstStream),
     Settings(Settings),
-    PushPromise,
+    PushPromise(PushPromise<'a>),
     Ping(Ping),
-    GoAway,
+    GoAway(GoAway<'a>),
     WindowUpdate(WindowUpdate),
-    Continuation,
+    Continuation(Continuation<'a>),
+    Unknown(u32),
 }
 
 impl<'a> Frame<'a> {
-    pub fn is_stream_specific(&self) -> bool {
-        match self {
-            Frame::Data(_)
-            | Frame::Headers(_)
-            | Frame::Priority
-            | Frame::RstStream(_)
-            | Frame::PushPromise
-            | Frame::Continuation => true,
-            Frame::Settings(_) | Frame::Ping(_) | Frame::GoAway => false,
-            Frame::WindowUpdate(w) => w.stream_id != 0,
-        }
-    }
-
     pub fn stream_id(&self) -> u32 {
         match self {
             Frame::Data(d) => d.stream_id,
             Frame::Headers(h) => h.stream_id,
-            Frame::Priority => unimplemented!(),
+            Frame::Priority(p) => p.stream_id,
             Frame::RstStream(r) => r.stream_id,
-            Frame::PushPromise => unimplemented!(),
-            Frame::Continuation => unimplemented!(),
-            Frame::Settings(_) | Frame::Ping(_) | Frame::GoAway => 0,
+            Frame::PushPromise(p) => p.stream_id,
+            Frame::Continuation(c) => c.stream_id,
+            Frame::Settings(_) | Frame::Ping(_) | Frame::GoAway(_) => 0,
             Frame::WindowUpdate(w) => w.stream_id,
+            Frame::Unknown(stream_id) => *stream_id,
         }
     }
 }
 
+/// parses a complete frame, the payload must not be larger than `max_frame_size`
 pub fn frame<'a>(input: &'a [u8], max_frame_size: u32) -> IResult<&'a [u8], Frame<'a>, Error<'a>> {
     let (i, header) = frame_header(input)?;
 
-    info!("got frame header: {:?}", header);
-
     if header.payload_len > max_frame_size {
-        return Err(Err::Failure(Error::new(input, InnerError::FrameSizeError)));
+        return Err(Err::Failure(Error::new(input, H2Error::FrameSizeError)));
     }
 
     let valid_stream_id = match header.frame_type {
@@ -218,58 +248,61 @@ pub fn frame<'a>(input: &'a [u8], max_frame_size: u32) -> IResult<&'a [u8], Fram
         | FrameType::PushPromise
         | FrameType::Continuation => header.stream_id != 0,
         FrameType::Settings | FrameType::Ping | FrameType::GoAway => header.stream_id == 0,
-        FrameType::WindowUpdate => true,
+        FrameType::WindowUpdate | FrameType::Unknown(_) => true,
     };
 
     if !valid_stream_id {
-        return Err(Err::Failure(Error::new(input, InnerError::ProtocolError)));
+        return Err(Err::Failure(Error::new(input, H2Error::ProtocolError)));
     }
 
-    let f = match header.frame_type {
-        FrameType::Data => data_frame(i, &header)?,
-        FrameType::Headers => headers_frame(i, &header)?,
-        FrameType::Priority => {
-            if header.payload_len != 5 {
-                return Err(Err::Failure(Error::new(input, InnerError::FrameSizeError)));
-            }
-            unimplemented!();
-        }
-        FrameType::RstStream => {
-            if header.payload_len != 4 {
-                return Err(Err::Failure(Error::new(input, InnerError::FrameSizeError)));
-            }
-            rst_stream_frame(i, &header)?
-        }
-        FrameType::PushPromise => {
-            unimplemented!();
-        }
-        FrameType::Continuation => {
-            unimplemented!();
-        }
-        FrameType::Settings => {
-            if header.payload_len % 6 != 0 {
-                return Err(Err::Failure(Error::new(input, InnerError::FrameSizeError)));
-            }
-            settings_frame(i, &header)?
-        }
-        FrameType::Ping => {
-            if header.payload_len != 8 {
-                return Err(Err::Failure(Error::new(input, InnerError::FrameSizeError)));
-            }
-            ping_frame(i, &header)?
-        }
-        FrameType::GoAway => {
-            unimplemented!();
-        }
-        FrameType::WindowUpdate => {
-            if header.payload_len != 4 {
-                return Err(Err::Failure(Error::new(input, InnerError::FrameSizeError)));
-            }
-            window_update_frame(i, &header)?
-        }
+    let (remaining, payload) = take(header.payload_len)(i)?;
+
+    let expected_size = match header.frame_type {
+        FrameType::Priority => Some(5),
+        FrameType::RstStream | FrameType::WindowUpdate => Some(4),
+        FrameType::Ping => Some(8),
+        _ => None,
+    };
+    if matches!(expected_size, Some(size) if size != header.payload_len) {
+        return Err(Err::Failure(Error::new(input, H2Error::FrameSizeError)));
+    }
+
+    let frame = match header.frame_type {
+        FrameType::Data => data_frame(payload, &header),
+        FrameType::Headers => headers_frame(payload, &header),
+        FrameType::Priority => priority_frame(payload, &header),
+        FrameType::RstStream => rst_stream_frame(payload, &header),
+        FrameType::Settings => settings_frame(payload, &header),
+        FrameType::PushPromise => push_promise_frame(payload, &header),
+        FrameType::Ping => ping_frame(payload, &header),
+        FrameType::GoAway => goaway_frame(payload, &header),
+        FrameType::WindowUpdate => window_update_frame(payload, &header),
+        FrameType::Continuation => Ok(Frame::Continuation(Continuation {
+            stream_id: header.stream_id,
+            header_block_fragment: payload,
+            end_headers: header.flags & FLAG_END_HEADERS != 0,
+        })),
+        FrameType::Unknown(_) => Ok(Frame::Unknown(header.stream_id)),
     };
 
-    Ok(f)
+    match frame {
+        Ok(frame) => Ok((remaining, frame)),
+        Err(error) => Err(Err::Failure(Error::new(input, error))),
+    }
+}
+
+/// removes the padding of a frame payload
+fn unpad<'a>(payload: &'a [u8], header: &FrameHeader) -> Result<&'a [u8], H2Error> {
+    if header.flags & FLAG_PADDED == 0 {
+        return Ok(payload);
+    }
+
+    match payload.split_first() {
+        Some((pad_length, rest)) if (*pad_length as usize) <= rest.len() => {
+            Ok(&rest[..rest.len() - *pad_length as usize])
+        }
+        _ => Err(H2Error::ProtocolError),
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -277,35 +310,17 @@ pub struct Data<'a> {
     pub stream_id: u32,
     pub payload: &'a [u8],
     pub end_stream: bool,
+    /// size counted by the flow control, including the padding
+    pub flow_controlled_len: u32,
 }
 
-pub fn data_frame<'a, 'b>(
-    input: &'a [u8],
-    header: &'b FrameHeader,
-) -> IResult<&'a [u8], Frame<'a>, Error<'a>> {
-    let (remaining, i) = take(header.payload_len)(input)?;
-
-    let (i1, pad_length) = if header.flags & 0x8 != 0 {
-        let (i, pad_length) = be_u8(i)?;
-        (i, Some(pad_length))
-    } else {
-        (i, None)
-    };
-
-    if pad_length.is_some() && i1.len() <= pad_length.unwrap() as usize {
-        return Err(Err::Failure(Error::new(input, InnerError::ProtocolError)));
-    }
-
-    let (_, payload) = take(i1.len() - pad_length.unwrap_or(0) as usize)(i1)?;
-
-    Ok((
-        remaining,
-        Frame::Data(Data {
-            stream_id: header.stream_id,
-            payload,
-            end_stream: header.flags & 0x1 != 0,
-        }),
-    ))
+pub fn data_frame<'a>(payload: &'a [u8], header: &FrameHeader) -> Result<Frame<'a>, H2Error> {
+    Ok(Frame::Data(Data {
+        stream_id: header.stream_id,
+        payload: unpad(payload, header)?,
+        end_stream: header.flags & FLAG_END_STREAM != 0,
+        flow_controlled_len: header.payload_len,
+    }))
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -325,54 +340,57 @@ pub struct StreamDependency {
     pub stream_id: u32,
 }
 
-pub fn headers_frame<'a, 'b>(
-    input: &'a [u8],
-    header: &'b FrameHeader,
-) -> IResult<&'a [u8], Frame<'a>, Error<'a>> {
-    let (remaining, i) = take(header.payload_len)(input)?;
-
-    let (i1, pad_length) = if header.flags & 0x8 != 0 {
-        let (i, pad_length) = be_u8(i)?;
-        (i, Some(pad_length))
-    } else {
-        (i, None)
-    };
+fn stream_dependency(i: &[u8]) -> Result<(StreamDependency, u8, &[u8]), H2Error> {
+    if i.len() < 5 {
+        return Err(H2Error::FrameSizeError);
+    }
+    let dependency = u32::from_be_bytes([i[0], i[1], i[2], i[3]]);
+    Ok((
+        StreamDependency {
+            exclusive: dependency & 0x8000_0000 != 0,
+            stream_id: dependency & 0x7FFF_FFFF,
+        },
+        i[4],
+        &i[5..],
+    ))
+}
 
-    let (i2, stream_dependency) = if header.flags & 0x20 != 0 {
-        let (i, stream) = map(be_u32, |i| StreamDependency {
-            exclusive: i & 0x8000 != 0,
-            stream_id: i & 0x7FFF,
-        })(i1)?;
-        (i, Some(stream))
-    } else {
-        (i1, None)
-    };
+pub fn headers_frame<'a>(payload: &'a [u8], header: &FrameHeader) -> Result<Frame<'a>, H2Error> {
+    let i = unpad(payload, header)?;
 
-    let (i3, weight) = if header.flags & 0x20 != 0 {
-        let (i, weight) = be_u8(i2)?;
-        (i, Some(weight))
+    let priority = header.flags & FLAG_PRIORITY != 0;
+    let (stream_dependency, weight, header_block_fragment) = if priority {
+        let (dependency, weight, i) = stream_dependency(i)?;
+        (Some(dependency), Some(weight), i)
     } else {
-        (i2, None)
+        (None, None, i)
     };
 
-    if pad_length.is_some() && i3.len() <= pad_length.unwrap() as usize {
-        return Err(Err::Failure(Error::new(input, InnerError::ProtocolError)));
-    }
+    Ok(Frame::Headers(Headers {
+        stream_id: header.stream_id,
+        stream_dependency,
+        weight,
+        header_block_fragment,
+        end_stream: header.flags & FLAG_END_STREAM != 0,
+        end_headers: header.flags & FLAG_END_HEADERS != 0,
+        priority,
+    }))
+}
 
-    let (_, header_block_fragment) = take(i3.len() - pad_length.unwrap_or(0) as usize)(i3)?;
+#[derive(Clone, Debug, PartialEq)]
+pub struct Priority {
+    pub stream_id: u32,
+    pub stream_dependency: StreamDependency,
+    pub weight: u8,
+}
 
-    Ok((
-        remaining,
-        Frame::Headers(Headers {
-            stream_id: header.stream_id,
-            stream_dependency,
-            weight,
-            header_block_fragment,
-            end_stream: header.flags & 0x1 != 0,
-            end_headers: header.flags & 0x4 != 0,
-            priority: header.flags & 0x20 != 0,
-        }),
-    ))
+pub fn priority_frame<'a>(payload: &'a [u8], header: &FrameHeader) -> Result<Frame<'a>, H2Error> {
+    let (stream_dependency, weight, _) = stream_dependency(payload)?;
+    Ok(Frame::Priority(Priority {
+        stream_id: header.stream_id,
+        stream_dependency,
+        weight,
+    }))
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -381,22 +399,16 @@ pub struct RstStream {
     pub error_code: u32,
 }
 
-pub fn rst_stream_frame<'a, 'b>(
-    input: &'a [u8],
-    header: &'b FrameHeader,
-) -> IResult<&'a [u8], Frame<'a>, Error<'a>> {
-    let (i, error_code) = be_u32(input)?;
-    Ok((
-        i,
-        Frame::RstStream(RstStream {
-            stream_id: header.stream_id,
-            error_code,
-        }),
-    ))
+pub fn rst_stream_frame<'a>(payload: &'a [u8], header: &FrameHeader) -> Result<Frame<'a>, H2Error> {
+    Ok(Frame::RstStream(RstStream {
+        stream_id: header.stream_id,
+        error_code: u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]),
+    }))
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Settings {
+    pub ack: bool,
     pub settings: Vec<Setting>,
 }
 
@@ -406,38 +418,82 @@ pub struct Setting {
     pub value: u32,
 }
 
-pub fn settings_frame<'a, 'b>(
-    input: &'a [u8],
-    header: &'b FrameHeader,
-) -> IResult<&'a [u8], Frame<'a>, Error<'a>> {
-    let (i, data) = take(header.payload_len)(input)?;
+pub fn settings_frame<'a>(payload: &'a [u8], header: &FrameHeader) -> Result<Frame<'a>, H2Error> {
+    let ack = header.flags & FLAG_ACK != 0;
+    if !payload.len().is_multiple_of(6) || (ack && !payload.is_empty()) {
+        return Err(H2Error::FrameSizeError);
+    }
+
+    let settings = payload
+        .chunks(6)
+        .map(|setting| Setting {
+            identifier: u16::from_be_bytes([setting[0], setting[1]]),
+            value: u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]),
+        })
+        .collect();
+
+    Ok(Frame::Settings(Settings { ack, settings }))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PushPromise<'a> {
+    pub stream_id: u32,
+    pub promised_stream_id: u32,
+    pub header_block_fragment: &'a [u8],
+    pub end_headers: bool,
+}
 
-    let (_, settings) = many0(map(
-        complete(tuple((be_u16, be_u32))),
-        |(identifier, value)| Setting { identifier, value },
-    ))(data)?;
+pub fn push_promise_frame<'a>(
+    payload: &'a [u8],
+    header: &FrameHeader,
+) -> Result<Frame<'a>, H2Error> {
+    let i = unpad(payload, header)?;
+    if i.len() < 4 {
+        return Err(H2Error::FrameSizeError);
+    }
 
-    Ok((i, Frame::Settings(Settings { settings })))
+    Ok(Frame::PushPromise(PushPromise {
+        stream_id: header.stream_id,
+        promised_stream_id: u32::from_be_bytes([i[0], i[1], i[2], i[3]]) & 0x7FFF_FFFF,
+        header_block_fragment: &i[4..],
+        end_headers: header.flags & FLAG_END_HEADERS != 0,
+    }))
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Ping {
+    pub ack: bool,
     pub payload: [u8; 8],
 }
 
-pub fn ping_frame<'a, 'b>(
-    input: &'a [u8],
-    header: &'b FrameHeader,
-) -> IResult<&'a [u8], Frame<'a>, Error<'a>> {
-    let (i, data) = take(8usize)(input)?;
+pub fn ping_frame<'a>(payload: &'a [u8], header: &FrameHeader) -> Result<Frame<'a>, H2Error> {
+    let mut p = Ping {
+        ack: header.flags & FLAG_ACK != 0,
+        payload: [0; 8],
+    };
+    p.payload.copy_from_slice(payload);
+
+    Ok(Frame::Ping(p))
+}
 
-    let mut p = Ping { payload: [0; 8] };
+#[derive(Clone, Debug, PartialEq)]
+pub struct GoAway<'a> {
+    pub last_stream_id: u32,
+    pub error_code: u32,
+    pub additional_debug_data: &'a [u8],
+}
 
-    for i in 0..8 {
-        p.payload[i] = data[i];
+pub fn goaway_frame<'a>(payload: &'a [u8], _header: &FrameHeader) -> Result<Frame<'a>, H2Error> {
+    if payload.len() < 8 {
+        return Err(H2Error::FrameSizeError);
     }
 
-    Ok((i, Frame::Ping(p)))
+    Ok(Frame::GoAway(GoAway {
+        last_stream_id: u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]])
+            & 0x7FFF_FFFF,
+        error_code: u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]),
+        additional_debug_data: &payload[8..],
+    }))
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -446,32 +502,98 @@ pub struct WindowUpdate {
     pub increment: u32,
 }
 
-pub fn window_update_frame<'a, 'b>(
-    input: &'a [u8],
-    header: &'b FrameHeader,
-) -> IResult<&'a [u8], Frame<'a>, Error<'a>> {
-    let (i, increment) = be_u32(input)?;
-    let increment = increment & 0x7FFF;
+pub fn window_update_frame<'a>(
+    payload: &'a [u8],
+    header: &FrameHeader,
+) -> Result<Frame<'a>, H2Error> {
+    let increment =
+        u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) & 0x7FFF_FFFF;
+
+    // a zero increment is a stream error on a stream, and a connection error otherwise,
+    // the caller tells them apart with the stream id
+    Ok(Frame::WindowUpdate(WindowUpdate {
+        stream_id: header.stream_id,
+        increment,
+    }))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Continuation<'a> {
+    pub stream_id: u32,
+    pub header_block_fragment: &'a [u8],
+    pub end_headers: bool,
+}
 
-    //FIXME: if stream id is 0, trat it as connection error?
-    if increment == 0 {
-        return Err(Err::Failure(Error::new(input, InnerError::ProtocolError)));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_frames() {
+        let input = [
+            0, 0, 6, 4, 0, 0, 0, 0, 0, // SETTINGS
+            0, 3, 0, 0, 0, 100, // SETTINGS_MAX_CONCURRENT_STREAMS = 100
+            0, 0, 4, 8, 0, 0, 0, 0, 1, // WINDOW_UPDATE on stream 1
+            0x80, 0, 0x10, 0, // increment of 4096 with the reserved bit set
+            0, 0, 7, 0, 0x9, 0, 0, 0, 3, // padded DATA with END_STREAM on stream 3
+            2, b'a', b'b', b'c', b'd', 0, 0,
+        ];
+
+        let (i, f) = frame(&input[..], 16384).unwrap();
+        assert_eq!(
+            f,
+            Frame::Settings(Settings {
+                ack: false,
+                settings: vec![Setting {
+                    identifier: SETTINGS_MAX_CONCURRENT_STREAMS,
+                    value: 100
+                }],
+            })
+        );
+
+        let (i, f) = frame(i, 16384).unwrap();
+        assert_eq!(
+            f,
+            Frame::WindowUpdate(WindowUpdate {
+                stream_id: 1,
+                increment: 4096
+            })
+        );
+
+        let (i, f) = frame(i, 16384).unwrap();
+        assert_eq!(
+            f,
+            Frame::Data(Data {
+                stream_id: 3,
+                payload: &b"abcd"[..],
+                end_stream: true,
+                flow_controlled_len: 7,
+            })
+        );
+        assert!(i.is_empty());
     }
 
-    Ok((
-        i,
-        Frame::WindowUpdate(WindowUpdate {
-            stream_id: header.stream_id,
-            increment,
-        }),
-    ))
+    #[test]
+    fn parse_invalid_frames() {
+        // incomplete payload
+        assert!(matches!(
+            frame(&[0, 0, 8, 6, 0, 0, 0, 0, 0, 1, 2][..], 16384),
+            Err(Err::Incomplete(_))
+        ));
+
+        // PING on a stream
+        let e = frame(
+            &[0, 0, 8, 6, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0][..],
+            16384,
+        );
+        assert!(matches!(e, Err(Err::Failure(e)) if e.h2_error() == H2Error::ProtocolError));
+
+        // frame larger than the maximum size
+        let e = frame(&[0, 0x40, 1, 0, 0, 0, 0, 0, 1][..], 16384);
+        assert!(matches!(e, Err(Err::Failure(e)) if e.h2_error() == H2Error::FrameSizeError));
+
+        // padding longer than the payload
+        let e = frame(&[0, 0, 2, 0, 0x8, 0, 0, 0, 1, 5, 0][..], 16384);
+        assert!(matches!(e, Err(Err::Failure(e)) if e.h2_error() == H2Error::ProtocolError));
+    }
 }
-
-#[macro_export]
-macro_rules! map_err(
-  (__impl $i:expr, $submac:ident!( $($args:tt)* ), $g:expr) => (
-    ($submac!($i, $($args)*)).map_err(|e| {
-      $g(e)
-    })
-  );
-);
@@ -5,11 +5,13 @@ use cookie_factory::{
     GenError,
 };
 
-use super::parser::{FrameHeader, FrameType};
+use super::parser::{
+    FrameHeader, FrameType, H2Error, FLAG_ACK, FLAG_END_HEADERS, FLAG_END_STREAM, FRAME_HEADER_SIZE,
+};
 
-pub fn gen_frame_header<'a, 'b>(
+pub fn gen_frame_header<'a>(
     x: (&'a mut [u8], usize),
-    frame: &'b FrameHeader,
+    frame: &FrameHeader,
 ) -> Result<(&'a mut [u8], usize), GenError> {
     let serializer = tuple((
         be_u24(frame.payload_len),
@@ -33,5 +35,153 @@ pub fn serialize_frame_type(f: &FrameType) -> u8 {
         FrameType::GoAway => 7,
         FrameType::WindowUpdate => 8,
         FrameType::Continuation => 9,
+        FrameType::Unknown(t) => t,
+    }
+}
+
+/// appends a frame to the output buffer
+pub fn gen_frame(
+    output: &mut Vec<u8>,
+    frame_type: FrameType,
+    flags: u8,
+    stream_id: u32,
+    payload: &[u8],
+) {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    let frame_header = FrameHeader {
+        payload_len: payload.len() as u32,
+        frame_type,
+        flags,
+        stream_id,
+    };
+    if let Err(e) = gen_frame_header((&mut header[..], 0), &frame_header) {
+        error!("could not serialize the HTTP/2 frame header: {:?}", e);
+        return;
+    }
+
+    output.extend_from_slice(&header);
+    output.extend_from_slice(payload);
+}
+
+pub fn gen_settings(output: &mut Vec<u8>, settings: &[(u16, u32)]) {
+    let mut payload = Vec::with_capacity(settings.len() * 6);
+    for (identifier, value) in settings {
+        payload.extend_from_slice(&identifier.to_be_bytes());
+        payload.extend_from_slice(&value.to_be_bytes());
+    }
+    gen_frame(output, FrameType::Settings, 0, 0, &payload);
+}
+
+pub fn gen_settings_ack(output: &mut Vec<u8>) {
+    gen_frame(output, FrameType::Settings, FLAG_ACK, 0, &[]);
+}
+
+pub fn gen_ping_ack(output: &mut Vec<u8>, payload: &[u8; 8]) {
+    gen_frame(output, FrameType::Ping, FLAG_ACK, 0, payload);
+}
+
+pub fn gen_window_update(output: &mut Vec<u8>, stream_id: u32, increment: u32) {
+    gen_frame(
+        output,
+        FrameType::WindowUpdate,
+        0,
+        stream_id,
+        &increment.to_be_bytes(),
+    );
+}
+
+pub fn gen_rst_stream(output: &mut Vec<u8>, stream_id: u32, error: H2Error) {
+    gen_frame(
+        output,
+        FrameType::RstStream,
+        0,
+        stream_id,
+        &error.code().to_be_bytes(),
+    );
+}
+
+pub fn gen_goaway(output: &mut Vec<u8>, last_stream_id: u32, error: H2Error) {
+    let mut payload = [0u8; 8];
+    payload[..4].copy_from_slice(&last_stream_id.to_be_bytes());
+    payload[4..].copy_from_slice(&error.code().to_be_bytes());
+    gen_frame(output, FrameType::GoAway, 0, 0, &payload);
+}
+
+/// appends a header block, split in a HEADERS frame and as many CONTINUATION
+/// frames as needed to respect the maximum frame size of the peer
+pub fn gen_headers(
+    output: &mut Vec<u8>,
+    stream_id: u32,
+    header_block: &[u8],
+    end_stream: bool,
+    max_frame_size: usize,
+) {
+    let mut fragments = header_block.chunks(max_frame_size.max(1)).peekable();
+    let first = fragments.next().unwrap_or(&[]);
+
+    let mut flags = if end_stream { FLAG_END_STREAM } else { 0 };
+    if fragments.peek().is_none() {
+        flags |= FLAG_END_HEADERS;
+    }
+    gen_frame(output, FrameType::Headers, flags, stream_id, first);
+
+    while let Some(fragment) = fragments.next() {
+        let flags = if fragments.peek().is_none() {
+            FLAG_END_HEADERS
+        } else {
+            0
+        };
+        gen_frame(output, FrameType::Continuation, flags, stream_id, fragment);
+    }
+}
+
+pub fn gen_data(output: &mut Vec<u8>, stream_id: u32, data: &[u8], end_stream: bool) {
+    let flags = if end_stream { FLAG_END_STREAM } else { 0 };
+    gen_frame(output, FrameType::Data, flags, stream_id, data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::parser::{frame, Continuation, Frame, Headers};
+    use super::*;
+
+    #[test]
+    fn split_header_block() {
+        let block = (0..10u8).collect::<Vec<_>>();
+        let mut output = Vec::new();
+        gen_headers(&mut output, 5, &block, true, 4);
+
+        let (i, f) = frame(&output, 16384).unwrap();
+        assert_eq!(
+            f,
+            Frame::Headers(Headers {
+                stream_id: 5,
+                stream_dependency: None,
+                weight: None,
+                header_block_fragment: &[0, 1, 2, 3][..],
+                end_stream: true,
+                end_headers: false,
+                priority: false,
+            })
+        );
+        let (i, f) = frame(i, 16384).unwrap();
+        assert_eq!(
+            f,
+            Frame::Continuation(Continuation {
+                stream_id: 5,
+                header_block_fragment: &[4, 5, 6, 7][..],
+                end_headers: false,
+            })
+        );
+        let (i, f) = frame(i, 16384).unwrap();
+        assert_eq!(
+            f,
+            Frame::Continuation(Continuation {
+                stream_id: 5,
+                header_block_fragment: &[8, 9][..],
+                end_headers: true,
+            })
+        );
+        assert!(i.is_empty());
     }
 }
@@ -1,156 +1,992 @@
-use std::collections::{HashMap, VecDeque};
+//! server side of an HTTP/2 connection: frames, HPACK, flow control and streams.
+//!
+//! The state only handles bytes: it parses what the client sent, and queues
+//! the frames to write in `output`. Requests and responses are exchanged
+//! through the streams
+use std::collections::BTreeMap;
 
-use nom::Offset;
+use hpack::{encoder::encode_integer, Decoder, Encoder};
+use nom::Err;
 
-use crate::Ready;
+use crate::sozu_command::proxy::Http2Settings;
 
-use super::{parser, serializer, stream};
+use super::{
+    convert::{H2Header, Request, Response},
+    parser::{
+        frame, Continuation, Data, Frame, H2Error, Headers, RstStream, Settings, WindowUpdate,
+        PREFACE, SETTINGS_ENABLE_PUSH, SETTINGS_HEADER_TABLE_SIZE, SETTINGS_INITIAL_WINDOW_SIZE,
+        SETTINGS_MAX_CONCURRENT_STREAMS, SETTINGS_MAX_FRAME_SIZE, SETTINGS_MAX_HEADER_LIST_SIZE,
+    },
+    serializer::{
+        gen_data, gen_goaway, gen_headers, gen_ping_ack, gen_rst_stream, gen_settings,
+        gen_settings_ack, gen_window_update,
+    },
+    stream::{Stream, StreamState},
+};
 
-#[derive(Clone, Debug, PartialEq)]
-pub struct OutputFrame {
-    header: parser::FrameHeader,
-    payload: Option<Vec<u8>>,
+pub const DEFAULT_WINDOW_SIZE: i64 = 65535;
+pub const MAX_WINDOW_SIZE: i64 = 0x7FFF_FFFF;
+/// largest frame payload accepted from the client, the default of the protocol
+pub const MAX_FRAME_SIZE: u32 = 16384;
+pub const MAX_PEER_FRAME_SIZE: u32 = 16_777_215;
+/// size of the HPACK dynamic tables, the default of the protocol
+pub const HEADER_TABLE_SIZE: usize = 4096;
+/// a header block, in HEADERS and CONTINUATION frames, cannot be larger
+pub const MAX_HEADER_BLOCK_SIZE: usize = 65536;
+/// decoded size of the headers of a request, as computed in SETTINGS_MAX_HEADER_LIST_SIZE
+pub const MAX_HEADER_LIST_SIZE: usize = 65536;
+/// frames are not generated past this amount of data waiting for the client
+pub const OUTPUT_BUFFER_LIMIT: usize = 65536;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    /// waiting for the connection preface
+    Preface,
+    /// the first frame of the client must be SETTINGS
+    Settings,
+    Frames,
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum FrameResult {
-    Close,
-    Continue,
-    //parameter is the stream id
-    ConnectBackend(u32),
+/// a header block spread over a HEADERS frame and CONTINUATION frames
+pub(super) struct PendingHeaders {
+    pub(super) stream_id: u32,
+    pub(super) block: Vec<u8>,
+    pub(super) end_stream: bool,
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum St {
-    Init,
-    ClientPrefaceReceived,
-    ServerPrefaceSent,
+/// encodes the header blocks sent to the peer
+pub(super) struct HeaderEncoder {
+    encoder: Encoder<'static>,
+    /// the client shrank its dynamic table under the default size, which the
+    /// encoder cannot follow: the headers are then sent as literals, without indexing
+    literal: bool,
+    /// the next block must start with a dynamic table size update
+    size_update: bool,
+}
+
+impl HeaderEncoder {
+    pub(super) fn new() -> HeaderEncoder {
+        HeaderEncoder {
+            encoder: Encoder::new(),
+            literal: false,
+            size_update: false,
+        }
+    }
+
+    /// the peer set the size of its dynamic table
+    pub(super) fn set_table_size(&mut self, size: usize) {
+        if size < HEADER_TABLE_SIZE && !self.literal {
+            self.literal = true;
+            self.size_update = true;
+        }
+    }
+
+    pub(super) fn encode(&mut self, headers: &[H2Header]) -> Vec<u8> {
+        if !self.literal {
+            return self
+                .encoder
+                .encode(headers.iter().map(|(name, value)| (&name[..], &value[..])));
+        }
+
+        let mut block = Vec::new();
+        if self.size_update {
+            // empties the dynamic table of the client, nothing is indexed anymore
+            block.push(0x20);
+            self.size_update = false;
+        }
+        for (name, value) in headers {
+            block.push(0);
+            block.extend(encode_integer(name.len(), 7));
+            block.extend_from_slice(name);
+            block.extend(encode_integer(value.len(), 7));
+            block.extend_from_slice(value);
+        }
+        block
+    }
 }
 
-#[derive(Clone, Debug, PartialEq)]
 pub struct State {
-    pub output: VecDeque<OutputFrame>,
-    pub state: St,
-    pub interest: Ready,
-    //FIXME: make it configurable,
-    pub max_frame_size: u32,
-    pub streams: HashMap<u32, stream::Stream>,
+    phase: Phase,
+    decoder: Decoder<'static>,
+    encoder: HeaderEncoder,
+    pub streams: BTreeMap<u32, Stream>,
+    max_concurrent_streams: usize,
+    /// window of the new streams, for the request data
+    initial_window_size: i64,
+    /// window of the new streams, for the response data
+    peer_initial_window_size: i64,
+    peer_max_frame_size: usize,
+    /// response data the client is ready to receive, on the whole connection
+    send_window: i64,
+    /// request data the client can still send, on the whole connection
+    recv_window: i64,
+    recv_window_size: i64,
+    /// request data handed to the backends, not yet given back to the connection window
+    recv_credit: i64,
+    /// highest stream identifier opened by the client
+    pub last_stream_id: u32,
+    pending_headers: Option<PendingHeaders>,
+    /// frames waiting to be written to the client
+    pub output: Vec<u8>,
+    /// a GOAWAY frame was queued, the new streams are ignored
+    pub going_away: bool,
+    /// the connection failed, it closes once the GOAWAY frame is written
+    pub error: Option<H2Error>,
+    /// streams with a new request
+    pub new_streams: Vec<u32>,
+    /// streams that ended or were reset, removed from `streams`
+    pub closed_streams: Vec<Stream>,
 }
 
 impl State {
-    pub fn new() -> State {
-        State {
-            output: VecDeque::new(),
-            state: St::Init,
-            interest: Ready::readable() | Ready::hup() | Ready::error(),
-            max_frame_size: 16384,
-            streams: HashMap::new(),
+    /// creates the connection state and queues the SETTINGS frame of the server
+    pub fn new(settings: &Http2Settings) -> State {
+        let initial_window_size = (settings.initial_window_size() as i64).clamp(0, MAX_WINDOW_SIZE);
+        // the connection window leaves room for a few streams sending at full speed
+        let recv_window_size =
+            (initial_window_size * 4).clamp(DEFAULT_WINDOW_SIZE, MAX_WINDOW_SIZE);
+
+        let mut state = State {
+            phase: Phase::Preface,
+            decoder: Decoder::new(),
+            encoder: HeaderEncoder::new(),
+            streams: BTreeMap::new(),
+            max_concurrent_streams: settings.max_concurrent_streams() as usize,
+            initial_window_size,
+            peer_initial_window_size: DEFAULT_WINDOW_SIZE,
+            peer_max_frame_size: MAX_FRAME_SIZE as usize,
+            send_window: DEFAULT_WINDOW_SIZE,
+            recv_window: recv_window_size,
+            recv_window_size,
+            recv_credit: 0,
+            last_stream_id: 0,
+            pending_headers: None,
+            output: Vec::new(),
+            going_away: false,
+            error: None,
+            new_streams: Vec::new(),
+            closed_streams: Vec::new(),
+        };
+
+        gen_settings(
+            &mut state.output,
+            &[
+                (SETTINGS_ENABLE_PUSH, 0),
+                (
+                    SETTINGS_MAX_CONCURRENT_STREAMS,
+                    settings.max_concurrent_streams(),
+                ),
+                (SETTINGS_INITIAL_WINDOW_SIZE, initial_window_size as u32),
+                (SETTINGS_MAX_HEADER_LIST_SIZE, MAX_HEADER_LIST_SIZE as u32),
+            ],
+        );
+        if recv_window_size > DEFAULT_WINDOW_SIZE {
+            gen_window_update(
+                &mut state.output,
+                0,
+                (recv_window_size - DEFAULT_WINDOW_SIZE) as u32,
+            );
         }
+
+        state
     }
 
-    pub fn parse<'a>(&mut self, mut input: &'a [u8]) -> (usize, Result<parser::Frame<'a>, ()>) {
-        let mut consumed = 0usize;
+    /// parses the frames read from the client and returns the size consumed.
+    /// A connection error queues a GOAWAY frame, the connection must be
+    /// closed once it is written
+    pub fn parse(&mut self, input: &[u8]) -> Result<usize, H2Error> {
+        let mut consumed = 0;
+
+        if self.phase == Phase::Preface {
+            if input.len() < PREFACE.len() {
+                if !PREFACE.starts_with(input) {
+                    return Err(self.connection_error(H2Error::ProtocolError));
+                }
+                return Ok(0);
+            }
+            if &input[..PREFACE.len()] != PREFACE {
+                return Err(self.connection_error(H2Error::ProtocolError));
+            }
+            consumed = PREFACE.len();
+            self.phase = Phase::Settings;
+        }
 
-        if self.state == St::Init {
-            match parser::preface(input) {
-                Err(e) => {
-                    error!("parser::preface error: {:?}", e);
-                    return (0, Err(()));
+        while self.error.is_none() {
+            let i = &input[consumed..];
+            match frame(i, MAX_FRAME_SIZE) {
+                Ok((remaining, f)) => {
+                    consumed += i.len() - remaining.len();
+                    if let Err(error) = self.handle_frame(f) {
+                        return Err(self.connection_error(error));
+                    }
                 }
-                Ok((i, _)) => {
-                    consumed += input.offset(i);
-                    self.state = St::ClientPrefaceReceived;
-                    input = i;
+                Err(Err::Incomplete(_)) => break,
+                Err(Err::Error(e)) | Err(Err::Failure(e)) => {
+                    return Err(self.connection_error(e.h2_error()));
                 }
             }
         }
 
-        match parser::frame(input, self.max_frame_size) {
-            Err(e) => {
-                error!("parser::frame error: {:?}", e);
-                return (consumed, Err(()));
+        Ok(consumed)
+    }
+
+    fn connection_error(&mut self, error: H2Error) -> H2Error {
+        if self.error.is_none() {
+            debug!("HTTP/2 connection error: {:?}", error);
+            gen_goaway(&mut self.output, self.last_stream_id, error);
+            self.going_away = true;
+            self.error = Some(error);
+        }
+        error
+    }
+
+    /// refuses the new streams, the current ones can end
+    pub fn goaway(&mut self) {
+        if !self.going_away {
+            gen_goaway(&mut self.output, self.last_stream_id, H2Error::NoError);
+            self.going_away = true;
+        }
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<(), H2Error> {
+        if self.phase == Phase::Settings {
+            match &frame {
+                Frame::Settings(settings) if !settings.ack => self.phase = Phase::Frames,
+                _ => return Err(H2Error::ProtocolError),
+            }
+        }
+
+        // a header block is only followed by its CONTINUATION frames
+        if let Some(pending) = &self.pending_headers {
+            match &frame {
+                Frame::Continuation(c) if c.stream_id == pending.stream_id => {}
+                _ => return Err(H2Error::ProtocolError),
+            }
+        }
+
+        match frame {
+            Frame::Settings(settings) => self.handle_settings(settings),
+            Frame::Ping(ping) => {
+                if !ping.ack {
+                    gen_ping_ack(&mut self.output, &ping.payload);
+                }
+                Ok(())
             }
-            Ok((i, frame)) => {
-                consumed += input.offset(i);
-                (consumed, Ok(frame))
+            Frame::GoAway(goaway) => {
+                debug!(
+                    "HTTP/2 client sent GOAWAY, last stream {}, error code {}",
+                    goaway.last_stream_id, goaway.error_code
+                );
+                self.goaway();
+                Ok(())
             }
+            Frame::WindowUpdate(window_update) => self.handle_window_update(window_update),
+            Frame::Headers(headers) => self.handle_headers(headers),
+            Frame::Continuation(continuation) => self.handle_continuation(continuation),
+            Frame::Data(data) => self.handle_data(data),
+            Frame::RstStream(rst_stream) => self.handle_rst_stream(rst_stream),
+            Frame::Priority(priority) => {
+                if priority.stream_dependency.stream_id == priority.stream_id {
+                    self.reset_stream(priority.stream_id, H2Error::ProtocolError);
+                }
+                Ok(())
+            }
+            Frame::PushPromise(_) => Err(H2Error::ProtocolError),
+            Frame::Unknown(_) => Ok(()),
         }
     }
 
-    pub fn handle(&mut self, frame: &parser::Frame) -> FrameResult {
-        let stream_id = frame.stream_id();
-        if stream_id != 0 {
-            return self.stream_handle(stream_id, frame);
+    fn handle_settings(&mut self, settings: Settings) -> Result<(), H2Error> {
+        if settings.ack {
+            return Ok(());
         }
 
-        match self.state {
-            St::Init => FrameResult::Continue,
-            St::ClientPrefaceReceived => {
-                match frame {
-                    parser::Frame::Settings(s) => {
-                        let server_settings = OutputFrame {
-                            header: parser::FrameHeader {
-                                payload_len: 0,
-                                frame_type: parser::FrameType::Settings,
-                                //FIXME: setting 1 for ACK?
-                                flags: 1,
-                                stream_id: 0,
-                            },
-                            payload: None,
-                        };
-
-                        self.output.push_back(server_settings);
-                        self.state = St::ServerPrefaceSent;
-                        self.interest.insert(Ready::writable());
-                        FrameResult::Continue
+        for setting in settings.settings {
+            match setting.identifier {
+                SETTINGS_HEADER_TABLE_SIZE => self.encoder.set_table_size(setting.value as usize),
+                SETTINGS_ENABLE_PUSH if setting.value > 1 => {
+                    return Err(H2Error::ProtocolError);
+                }
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = setting.value as i64;
+                    if value > MAX_WINDOW_SIZE {
+                        return Err(H2Error::FlowControlError);
+                    }
+                    let delta = value - self.peer_initial_window_size;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW_SIZE {
+                            return Err(H2Error::FlowControlError);
+                        }
                     }
-                    f => {
-                        unimplemented!("invalid frame: {:?}, should send back an error", f);
+                    self.peer_initial_window_size = value;
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(MAX_FRAME_SIZE..=MAX_PEER_FRAME_SIZE).contains(&setting.value) {
+                        return Err(H2Error::ProtocolError);
                     }
+                    self.peer_max_frame_size = setting.value as usize;
                 }
+                _ => {}
+            }
+        }
+
+        gen_settings_ack(&mut self.output);
+        Ok(())
+    }
+
+    fn handle_window_update(&mut self, window_update: WindowUpdate) -> Result<(), H2Error> {
+        let increment = window_update.increment as i64;
+
+        if window_update.stream_id == 0 {
+            if increment == 0 {
+                return Err(H2Error::ProtocolError);
             }
-            St::ServerPrefaceSent => match frame {
-                frame => {
-                    panic!("unknown frame for now: {:?}", frame);
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW_SIZE {
+                return Err(H2Error::FlowControlError);
+            }
+            return Ok(());
+        }
+
+        match self.streams.get_mut(&window_update.stream_id) {
+            Some(stream) => {
+                stream.send_window += increment;
+                if increment == 0 {
+                    self.reset_stream(window_update.stream_id, H2Error::ProtocolError);
+                } else if stream.send_window > MAX_WINDOW_SIZE {
+                    self.reset_stream(window_update.stream_id, H2Error::FlowControlError);
                 }
-            },
+                Ok(())
+            }
+            None if window_update.stream_id > self.last_stream_id => Err(H2Error::ProtocolError),
+            None => Ok(()),
         }
     }
 
-    pub fn parse_and_handle<'a>(&mut self, mut input: &'a [u8]) -> (usize, FrameResult) {
-        let (sz, res) = self.parse(input);
-        match res {
-            Err(e) => {
-                error!("error parsing frame: {:?}", e);
-                (sz, FrameResult::Close)
+    fn handle_headers(&mut self, headers: Headers) -> Result<(), H2Error> {
+        if matches!(headers.stream_dependency, Some(dependency) if dependency.stream_id == headers.stream_id)
+        {
+            return Err(H2Error::ProtocolError);
+        }
+
+        if headers.end_headers {
+            return self.handle_header_block(
+                headers.stream_id,
+                headers.header_block_fragment,
+                headers.end_stream,
+            );
+        }
+
+        self.pending_headers = Some(PendingHeaders {
+            stream_id: headers.stream_id,
+            block: headers.header_block_fragment.to_vec(),
+            end_stream: headers.end_stream,
+        });
+        Ok(())
+    }
+
+    fn handle_continuation(&mut self, continuation: Continuation) -> Result<(), H2Error> {
+        let mut pending = match self.pending_headers.take() {
+            Some(pending) => pending,
+            None => return Err(H2Error::ProtocolError),
+        };
+
+        if pending.block.len() + continuation.header_block_fragment.len() > MAX_HEADER_BLOCK_SIZE {
+            return Err(H2Error::EnhanceYourCalm);
+        }
+        pending
+            .block
+            .extend_from_slice(continuation.header_block_fragment);
+
+        if continuation.end_headers {
+            self.handle_header_block(pending.stream_id, &pending.block, pending.end_stream)
+        } else {
+            self.pending_headers = Some(pending);
+            Ok(())
+        }
+    }
+
+    /// decodes a complete header block, the request of a new stream or trailers
+    fn handle_header_block(
+        &mut self,
+        stream_id: u32,
+        block: &[u8],
+        end_stream: bool,
+    ) -> Result<(), H2Error> {
+        // always decoded, the HPACK tables must stay in sync with the client
+        let headers = decode(&mut self.decoder, block)?;
+
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            if stream.request_ended() {
+                self.reset_stream(stream_id, H2Error::StreamClosed);
+                return Ok(());
             }
-            Ok(frame) => {
-                info!("parsed frame: {:?}", frame);
-                (sz, self.handle(&frame))
+
+            // the trailers end the request and carry no pseudo-header
+            let valid = end_stream
+                && matches!(&headers, Some(headers) if headers.iter().all(|(name, _)| !name.starts_with(b":")))
+                && !matches!(stream.request.content_length, Some(length) if length != stream.request_body_size);
+            match headers {
+                Some(headers) if valid => {
+                    stream.request_trailers = headers;
+                    stream.end_request();
+                }
+                _ => self.reset_stream(stream_id, H2Error::ProtocolError),
             }
+            return Ok(());
+        }
+
+        if stream_id.is_multiple_of(2) {
+            return Err(H2Error::ProtocolError);
+        }
+        if stream_id <= self.last_stream_id {
+            // the stream was closed, the frame is ignored
+            return Ok(());
         }
+        self.last_stream_id = stream_id;
+
+        if self.going_away {
+            return Ok(());
+        }
+
+        if self.streams.len() >= self.max_concurrent_streams {
+            incr!("http2.refused_streams");
+            gen_rst_stream(&mut self.output, stream_id, H2Error::RefusedStream);
+            return Ok(());
+        }
+
+        let headers = match headers {
+            Some(headers) => headers,
+            None => {
+                gen_rst_stream(&mut self.output, stream_id, H2Error::RefusedStream);
+                return Ok(());
+            }
+        };
+
+        match Request::from_headers(headers) {
+            Ok(request) => {
+                if end_stream && matches!(request.content_length, Some(length) if length != 0) {
+                    gen_rst_stream(&mut self.output, stream_id, H2Error::ProtocolError);
+                    return Ok(());
+                }
+
+                let stream = Stream::new(
+                    stream_id,
+                    request,
+                    end_stream,
+                    self.peer_initial_window_size,
+                    self.initial_window_size,
+                );
+                self.streams.insert(stream_id, stream);
+                self.new_streams.push(stream_id);
+                incr!("http2.streams");
+                gauge_add!("http2.active_streams", 1);
+            }
+            Err(error) => {
+                debug!(
+                    "invalid HTTP/2 request on stream {}: {:?}",
+                    stream_id, error
+                );
+                incr!("http2.invalid_requests");
+                gen_rst_stream(&mut self.output, stream_id, error);
+            }
+        }
+
+        Ok(())
     }
 
-    pub fn gen(&mut self, mut output: &mut [u8]) -> Result<usize, ()> {
-        if let Some(frame) = self.output.pop_front() {
-            match serializer::gen_frame_header((output, 0), &frame.header) {
-                Err(e) => {
-                    panic!("error serializing: {:?}", e);
+    fn handle_data(&mut self, data: Data) -> Result<(), H2Error> {
+        let length = data.flow_controlled_len as i64;
+        if length > self.recv_window {
+            return Err(H2Error::FlowControlError);
+        }
+        self.recv_window -= length;
+        // the padding is not kept, the client gets it back right away
+        let padding = length - data.payload.len() as i64;
+
+        let stream = match self.streams.get_mut(&data.stream_id) {
+            Some(stream) => stream,
+            None if data.stream_id > self.last_stream_id => return Err(H2Error::ProtocolError),
+            None => {
+                self.credit_connection(length);
+                return Ok(());
+            }
+        };
+
+        if stream.request_ended() {
+            self.credit_connection(length);
+            self.reset_stream(data.stream_id, H2Error::StreamClosed);
+            return Ok(());
+        }
+        if length > stream.recv_window {
+            self.credit_connection(length);
+            self.reset_stream(data.stream_id, H2Error::FlowControlError);
+            return Ok(());
+        }
+
+        stream.recv_window -= length;
+        stream.request_body.extend_from_slice(data.payload);
+        stream.request_body_size += data.payload.len();
+
+        let length_error = match stream.request.content_length {
+            Some(content_length) => {
+                stream.request_body_size > content_length
+                    || (data.end_stream && stream.request_body_size != content_length)
+            }
+            None => false,
+        };
+        if length_error {
+            self.credit_connection(length);
+            self.reset_stream(data.stream_id, H2Error::ProtocolError);
+            return Ok(());
+        }
+
+        if data.end_stream {
+            stream.end_request();
+        }
+        self.consumed(data.stream_id, padding as usize);
+        Ok(())
+    }
+
+    fn handle_rst_stream(&mut self, rst_stream: RstStream) -> Result<(), H2Error> {
+        if rst_stream.stream_id > self.last_stream_id {
+            return Err(H2Error::ProtocolError);
+        }
+
+        if let Some(mut stream) = self.streams.remove(&rst_stream.stream_id) {
+            debug!(
+                "HTTP/2 client reset stream {} with error code {}",
+                rst_stream.stream_id, rst_stream.error_code
+            );
+            self.close_stream(&mut stream, true);
+            self.closed_streams.push(stream);
+        }
+        Ok(())
+    }
+
+    /// ends a stream with a stream error
+    pub fn reset_stream(&mut self, stream_id: u32, error: H2Error) {
+        gen_rst_stream(&mut self.output, stream_id, error);
+        if let Some(mut stream) = self.streams.remove(&stream_id) {
+            self.close_stream(&mut stream, true);
+            self.closed_streams.push(stream);
+        }
+    }
+
+    fn close_stream(&mut self, stream: &mut Stream, reset: bool) {
+        stream.state = StreamState::Closed;
+        stream.reset = reset;
+        // the request data that will not reach the backend is given back to the connection
+        let unsent = stream.request_body.len() as i64;
+        stream.request_body.clear();
+        self.credit_connection(unsent);
+    }
+
+    /// `size` bytes of the request body of a stream were handed to the backend,
+    /// the client can send as much again
+    pub fn consumed(&mut self, stream_id: u32, size: usize) {
+        if size == 0 {
+            return;
+        }
+        self.credit_connection(size as i64);
+
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            if stream.request_ended() {
+                return;
+            }
+            stream.recv_credit += size as i64;
+            // sent once half of the window is used, the rest of the window lets the client go on
+            if stream.recv_window <= self.initial_window_size / 2 {
+                gen_window_update(&mut self.output, stream_id, stream.recv_credit as u32);
+                stream.recv_window += stream.recv_credit;
+                stream.recv_credit = 0;
+            }
+        }
+    }
+
+    fn credit_connection(&mut self, size: i64) {
+        if size == 0 {
+            return;
+        }
+        self.recv_credit += size;
+        if self.recv_window <= self.recv_window_size / 2 {
+            gen_window_update(&mut self.output, 0, self.recv_credit as u32);
+            self.recv_window += self.recv_credit;
+            self.recv_credit = 0;
+        }
+    }
+
+    /// queues the headers of the response, its body and trailers are sent by `send_data`
+    pub fn send_response(&mut self, stream_id: u32, response: &Response) {
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) if !stream.response_started => stream,
+            _ => return,
+        };
+
+        let block = self.encoder.encode(&response.h2_headers());
+        let end_stream = stream.response_ended
+            && stream.response_body.is_empty()
+            && stream.response_trailers.is_empty();
+        gen_headers(
+            &mut self.output,
+            stream_id,
+            &block,
+            end_stream,
+            self.peer_max_frame_size,
+        );
+        stream.response_started = true;
+        stream.response_status = Some(response.status);
+
+        if end_stream {
+            end_response(&mut self.output, stream);
+            self.collect_closed();
+        }
+    }
+
+    /// writes the response bodies of the streams as far as the flow control allows,
+    /// one frame per stream in turn
+    pub fn send_data(&mut self) {
+        loop {
+            let mut progress = false;
+
+            for (stream_id, stream) in self.streams.iter_mut() {
+                if self.output.len() >= OUTPUT_BUFFER_LIMIT {
+                    break;
+                }
+                if !stream.response_started || stream.response_sent() {
+                    continue;
+                }
+
+                let window = stream.send_window.min(self.send_window).max(0) as usize;
+                let size = stream
+                    .response_body
+                    .len()
+                    .min(window)
+                    .min(self.peer_max_frame_size);
+                let body_done = stream.response_ended && size == stream.response_body.len();
+                if size == 0 && !body_done {
+                    continue;
+                }
+
+                let trailers = body_done && !stream.response_trailers.is_empty();
+                if size > 0 || !trailers {
+                    gen_data(
+                        &mut self.output,
+                        *stream_id,
+                        &stream.response_body[..size],
+                        body_done && !trailers,
+                    );
+                    stream.response_body.drain(..size);
+                    stream.send_window -= size as i64;
+                    self.send_window -= size as i64;
+                    stream.bytes_out += size;
                 }
-                Ok((sl, index)) => Ok(index),
+                if trailers {
+                    let block = self.encoder.encode(&stream.response_trailers);
+                    gen_headers(
+                        &mut self.output,
+                        *stream_id,
+                        &block,
+                        true,
+                        self.peer_max_frame_size,
+                    );
+                }
+                if body_done {
+                    end_response(&mut self.output, stream);
+                }
+                progress = true;
+            }
+
+            if !progress || self.output.len() >= OUTPUT_BUFFER_LIMIT {
+                break;
+            }
+        }
+
+        self.collect_closed();
+    }
+
+    /// moves the streams that ended to `closed_streams`
+    fn collect_closed(&mut self) {
+        let closed: Vec<u32> = self
+            .streams
+            .iter()
+            .filter(|(_, stream)| stream.state == StreamState::Closed)
+            .map(|(stream_id, _)| *stream_id)
+            .collect();
+
+        for stream_id in closed {
+            if let Some(mut stream) = self.streams.remove(&stream_id) {
+                self.close_stream(&mut stream, false);
+                self.closed_streams.push(stream);
+            }
+        }
+    }
+
+    pub fn stream_mut(&mut self, stream_id: u32) -> Option<&mut Stream> {
+        self.streams.get_mut(&stream_id)
+    }
+
+    /// there is no frame to write and no open stream
+    pub fn is_idle(&self) -> bool {
+        self.streams.is_empty() && self.output.is_empty()
+    }
+}
+
+/// the last frame of the response was queued. If the client is still sending
+/// its request, it is told to stop
+fn end_response(output: &mut Vec<u8>, stream: &mut Stream) {
+    stream.end_response();
+    if stream.state == StreamState::HalfClosedLocal {
+        gen_rst_stream(output, stream.id, H2Error::NoError);
+        stream.state = StreamState::Closed;
+    }
+}
+
+/// decodes a header block, returns `None` if the headers are larger than the limit
+pub(super) fn decode(
+    decoder: &mut Decoder<'static>,
+    block: &[u8],
+) -> Result<Option<Vec<H2Header>>, H2Error> {
+    // the decoder panics on some malformed blocks, they are caught before
+    check_header_block(block)?;
+
+    let mut headers = Vec::new();
+    let mut size = 0;
+    decoder
+        .decode_with_cb(block, |name, value| {
+            size += name.len() + value.len() + 32;
+            if size <= MAX_HEADER_LIST_SIZE {
+                headers.push((name.into_owned(), value.into_owned()));
             }
+        })
+        .map_err(|e| {
+            debug!("could not decode an HPACK block: {:?}", e);
+            H2Error::CompressionError
+        })?;
+
+    if size > MAX_HEADER_LIST_SIZE {
+        return Ok(None);
+    }
+    Ok(Some(headers))
+}
+
+/// decodes an HPACK integer with a prefix of `prefix_size` bits
+fn decode_integer(block: &[u8], prefix_size: u8) -> Result<(usize, usize), H2Error> {
+    let mask = (1u16 << prefix_size) as u8 - 1;
+    let first = block.first().ok_or(H2Error::CompressionError)?;
+    let mut value = (first & mask) as usize;
+    if value < mask as usize {
+        return Ok((value, 1));
+    }
+
+    for (index, byte) in block.iter().enumerate().skip(1).take(4) {
+        value += ((byte & 0x7F) as usize) << (7 * (index - 1));
+        if byte & 0x80 == 0 {
+            return Ok((value, index + 1));
+        }
+    }
+    Err(H2Error::CompressionError)
+}
+
+/// skips an HPACK string literal
+fn skip_string(block: &[u8]) -> Result<usize, H2Error> {
+    let (length, consumed) = decode_integer(block, 7)?;
+    if consumed + length > block.len() {
+        return Err(H2Error::CompressionError);
+    }
+    Ok(consumed + length)
+}
+
+/// walks through the representations of a header block to check their
+/// encoding. The dynamic table size updates must come first, and stay
+/// under the size of the table
+fn check_header_block(block: &[u8]) -> Result<(), H2Error> {
+    let mut index = 0;
+    let mut first_representation = true;
+
+    while index < block.len() {
+        let i = &block[index..];
+        let byte = i[0];
+
+        index += if byte & 0x80 != 0 {
+            // indexed header field
+            decode_integer(i, 7)?.1
+        } else if byte & 0xE0 == 0x20 {
+            let (size, consumed) = decode_integer(i, 5)?;
+            if !first_representation || size > HEADER_TABLE_SIZE {
+                return Err(H2Error::CompressionError);
+            }
+            consumed
         } else {
-            self.interest.remove(Ready::writable());
-            Ok(0)
+            // literal header field, with an indexed or literal name
+            let prefix_size = if byte & 0x40 != 0 { 6 } else { 4 };
+            let (name_index, mut consumed) = decode_integer(i, prefix_size)?;
+            if name_index == 0 {
+                consumed += skip_string(&i[consumed..])?;
+            }
+            consumed + skip_string(&i[consumed..])?
+        };
+
+        if byte & 0xE0 != 0x20 {
+            first_representation = false;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::h2::parser::FrameType;
+    use crate::protocol::h2::serializer::gen_frame;
+
+    fn client_headers(stream_id: u32, headers: &[(&[u8], &[u8])], end_stream: bool) -> Vec<u8> {
+        let block = Encoder::new().encode(headers.iter().copied());
+        let mut output = Vec::new();
+        gen_headers(&mut output, stream_id, &block, end_stream, 16384);
+        output
+    }
+
+    fn connected_state() -> State {
+        let mut state = State::new(&Http2Settings {
+            enabled: true,
+            max_concurrent_streams: Some(1),
+            initial_window_size: None,
+        });
+        let mut input = PREFACE.to_vec();
+        gen_settings(&mut input, &[]);
+        assert_eq!(state.parse(&input), Ok(input.len()));
+        state.output.clear();
+        state
+    }
+
+    const GET: [(&[u8], &[u8]); 4] = [
+        (b":method", b"GET"),
+        (b":scheme", b"https"),
+        (b":authority", b"example.com"),
+        (b":path", b"/"),
+    ];
+
+    #[test]
+    fn open_and_answer_stream() {
+        let mut state = connected_state();
+
+        let input = client_headers(1, &GET, true);
+        assert_eq!(state.parse(&input), Ok(input.len()));
+        assert_eq!(state.new_streams, vec![1]);
+        assert!(state.streams[&1].request_ended());
+
+        // the concurrency limit refuses the second stream
+        let input = client_headers(3, &GET, true);
+        assert_eq!(state.parse(&input), Ok(input.len()));
+        let mut expected = Vec::new();
+        gen_rst_stream(&mut expected, 3, H2Error::RefusedStream);
+        assert_eq!(state.output, expected);
+        state.output.clear();
+
+        {
+            let stream = state.stream_mut(1).unwrap();
+            stream.response_body.extend_from_slice(b"hello");
+            stream.response_ended = true;
         }
+        state.send_response(
+            1,
+            &Response {
+                status: 200,
+                headers: vec![],
+            },
+        );
+        state.send_data();
+
+        let (i, headers) = frame(&state.output, 16384).unwrap();
+        assert!(matches!(
+            headers,
+            Frame::Headers(Headers {
+                stream_id: 1,
+                end_stream: false,
+                ..
+            })
+        ));
+        let (i, data) = frame(i, 16384).unwrap();
+        assert_eq!(
+            data,
+            Frame::Data(Data {
+                stream_id: 1,
+                payload: &b"hello"[..],
+                end_stream: true,
+                flow_controlled_len: 5,
+            })
+        );
+        assert!(i.is_empty());
+        assert!(state.streams.is_empty());
+        assert_eq!(state.closed_streams.len(), 1);
+        assert!(!state.closed_streams[0].reset);
+    }
+
+    #[test]
+    fn connection_errors() {
+        let mut state = State::new(&Http2Settings::default());
+        assert_eq!(
+            state.parse(b"GET / HTTP/1.1\r\n"),
+            Err(H2Error::ProtocolError)
+        );
+
+        // the first frame must be SETTINGS
+        let mut state = State::new(&Http2Settings::default());
+        let mut input = PREFACE.to_vec();
+        gen_frame(&mut input, FrameType::Ping, 0, 0, &[0; 8]);
+        assert_eq!(state.parse(&input), Err(H2Error::ProtocolError));
+
+        // DATA on a stream that was never opened
+        let mut state = connected_state();
+        let mut input = Vec::new();
+        gen_data(&mut input, 5, b"data", false);
+        assert_eq!(state.parse(&input), Err(H2Error::ProtocolError));
+        assert!(state.going_away);
     }
 
-    pub fn stream_handle(&mut self, stream_id: u32, frame: &parser::Frame) -> FrameResult {
-        assert!(stream_id != 0);
+    #[test]
+    fn malformed_header_blocks() {
+        assert_eq!(check_header_block(&[0x82, 0x86]), Ok(()));
+        // dynamic table size update after a header field
+        assert_eq!(
+            check_header_block(&[0x82, 0x20]),
+            Err(H2Error::CompressionError)
+        );
+        // table larger than the advertised size
+        assert_eq!(
+            check_header_block(&[0x3F, 0xE1, 0x3F]),
+            Err(H2Error::CompressionError)
+        );
+        // truncated integer and string
+        assert_eq!(check_header_block(&[0x3F]), Err(H2Error::CompressionError));
+        assert_eq!(
+            check_header_block(&[0x40, 0x05, b'a']),
+            Err(H2Error::CompressionError)
+        );
+    }
+
+    #[test]
+    fn backend_response_feeds_the_response_time() {
+        let mut state = connected_state();
+        let input = client_headers(1, &GET, true);
+        assert_eq!(state.parse(&input), Ok(input.len()));
+
+        let backend = std::cell::RefCell::new(crate::Backend::new(
+            "cluster_1-0",
+            "127.0.0.1:1026".parse().unwrap(),
+            None,
+            None,
+            None,
+        ));
+        let stream = state.stream_mut(1).unwrap();
+        stream.backend_started = Some(time::Instant::now() - time::Duration::seconds(1));
+        stream.end_backend_response(&backend);
 
-        self.streams
-            .entry(stream_id)
-            .or_insert(stream::Stream::new(stream_id))
-            .handle(frame)
+        assert!(stream.response_ended);
+        assert!(stream.backend_started.is_none());
+        assert!(backend.borrow().response_time.rtt >= 1_000_000_000f64);
     }
 }
@@ -1,102 +1,160 @@
-use std::{
-    collections::{HashMap, VecDeque},
-    str::from_utf8,
-};
+use std::{cell::RefCell, net::SocketAddr};
 
-use hpack::Decoder;
+use mio::Token;
+use rusty_ulid::Ulid;
+use time::Instant;
 
-use super::{
-    parser,
-    state::{FrameResult, OutputFrame},
-};
+use super::convert::{H2Header, Request};
+use crate::{protocol::http::LogContext, Backend};
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum St {
-    Init,
-    ClientPrefaceReceived,
-    ServerPrefaceSent,
+/// states of a stream opened by the client, the server does not push
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamState {
+    /// both sides can send frames
+    Open,
+    /// the client sent the end of its request
+    HalfClosedRemote,
+    /// the end of the response was sent, the client is still sending its request
+    HalfClosedLocal,
+    Closed,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// a request and its response, from the client side
+#[derive(Debug)]
 pub struct Stream {
     pub id: u32,
     pub state: StreamState,
-    pub output: VecDeque<OutputFrame>,
-    pub inbound_headers: HashMap<Vec<u8>, Vec<u8>>,
-}
-
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub enum StreamState {
-    Idle,
-    ReservedLocal,
-    ReservedRemote,
-    Open,
-    HalfClosedLocal,
-    HalfClosedRemote,
-    Closed,
+    pub request_id: Ulid,
+    pub request: Request,
+    /// response data the client is ready to receive
+    pub send_window: i64,
+    /// request data the client can still send
+    pub recv_window: i64,
+    /// request data handed to the backend, not yet given back to the client's window
+    pub recv_credit: i64,
+    /// request data received from the client, waiting for the backend
+    pub request_body: Vec<u8>,
+    /// size of the request body received, checked against its Content-Length
+    pub request_body_size: usize,
+    pub request_trailers: Vec<H2Header>,
+    /// the response headers were sent to the client
+    pub response_started: bool,
+    pub response_status: Option<u16>,
+    /// response data received from the backend, waiting for the flow control
+    pub response_body: Vec<u8>,
+    /// the backend sent the whole response, it ends with the data and trailers
+    pub response_ended: bool,
+    pub response_trailers: Vec<H2Header>,
+    /// connection to the backend answering the request
+    pub backend_token: Option<Token>,
+    /// stream carrying the request on an HTTP/2 backend connection
+    pub backend_stream_id: Option<u32>,
+    pub cluster_id: Option<String>,
+    /// the cluster of the stream is in gRPC mode
+    pub grpc: bool,
+    /// status of the gRPC call, from the response trailers
+    pub grpc_status: Option<u32>,
+    pub backend_id: Option<String>,
+    pub backend_address: Option<SocketAddr>,
+    pub started: Instant,
+    /// the request was handed to a backend connection
+    pub backend_started: Option<Instant>,
+    /// response data sent to the client
+    pub bytes_out: usize,
+    /// the stream was reset before the end of the response
+    pub reset: bool,
 }
 
 impl Stream {
-    pub fn new(id: u32) -> Stream {
-        info!("new stream with id {}", id);
-
+    pub fn new(
+        id: u32,
+        request: Request,
+        end_stream: bool,
+        send_window: i64,
+        recv_window: i64,
+    ) -> Stream {
         Stream {
             id,
-            state: StreamState::Idle,
-            output: VecDeque::new(),
-            inbound_headers: HashMap::new(),
+            state: if end_stream {
+                StreamState::HalfClosedRemote
+            } else {
+                StreamState::Open
+            },
+            request_id: Ulid::generate(),
+            request,
+            send_window,
+            recv_window,
+            recv_credit: 0,
+            request_body: Vec::new(),
+            request_body_size: 0,
+            request_trailers: Vec::new(),
+            response_started: false,
+            response_status: None,
+            response_body: Vec::new(),
+            response_ended: false,
+            response_trailers: Vec::new(),
+            backend_token: None,
+            backend_stream_id: None,
+            cluster_id: None,
+            grpc: false,
+            grpc_status: None,
+            backend_id: None,
+            backend_address: None,
+            started: Instant::now(),
+            backend_started: None,
+            bytes_out: 0,
+            reset: false,
         }
     }
 
-    pub fn handle(&mut self, frame: &parser::Frame) -> FrameResult {
-        match self.state {
-            StreamState::Idle => match frame {
-                parser::Frame::Headers(h) => {
-                    let mut decoder = Decoder::new();
-                    match decoder.decode(h.header_block_fragment) {
-                        Err(e) => {
-                            error!("error decoding headers: {:?}", e);
-                            FrameResult::Close
-                        }
-                        Ok(mut h) => {
-                            let mut has_authority = false;
-                            let mut has_path = false;
+    /// the client sent the whole request
+    pub fn request_ended(&self) -> bool {
+        matches!(
+            self.state,
+            StreamState::HalfClosedRemote | StreamState::Closed
+        )
+    }
 
-                            self.inbound_headers.extend(h.drain(..).map(|(k, v)| {
-                                if &k == b"authority" {
-                                    has_authority = true;
-                                }
+    /// the whole response was sent to the client
+    pub fn response_sent(&self) -> bool {
+        matches!(
+            self.state,
+            StreamState::HalfClosedLocal | StreamState::Closed
+        )
+    }
 
-                                if &k == b"path" {
-                                    has_path = true;
-                                }
+    /// the client stops sending data
+    pub fn end_request(&mut self) {
+        self.state = match self.state {
+            StreamState::Open => StreamState::HalfClosedRemote,
+            _ => StreamState::Closed,
+        };
+    }
 
-                                info!("{} -> {}", from_utf8(&k).unwrap(), from_utf8(&v).unwrap());
-                                (k, v)
-                            }));
+    /// the last frame of the response was sent
+    pub fn end_response(&mut self) {
+        self.state = match self.state {
+            StreamState::Open => StreamState::HalfClosedLocal,
+            _ => StreamState::Closed,
+        };
+    }
 
-                            self.state = StreamState::Open;
-                            info!("stream[{}] state is now {:?}", self.id, self.state);
-                            info!("headers: {:?}", self.inbound_headers);
+    /// the backend sent the whole response, the time it took feeds the least
+    /// response time load balancing
+    pub fn end_backend_response(&mut self, backend: &RefCell<Backend>) {
+        self.response_ended = true;
+        if let Some(started) = self.backend_started.take() {
+            backend
+                .borrow_mut()
+                .set_response_time(Instant::now() - started);
+        }
+    }
 
-                            if self.inbound_headers.contains_key(&b":authority"[..])
-                                && self.inbound_headers.contains_key(&b":path"[..])
-                            {
-                                info!("will send connect_to_backend");
-                                FrameResult::ConnectBackend(self.id)
-                            } else {
-                                FrameResult::Continue
-                            }
-                        }
-                    }
-                }
-                frame => {
-                    panic!("unknown frame for now: {:?}", frame);
-                }
-            },
-            s => {
-                unimplemented!("stream[{}] state {:?} not implemented", self.id, self.state);
-            }
+    pub fn log_context(&self) -> LogContext<'_> {
+        LogContext {
+            request_id: self.request_id,
+            cluster_id: self.cluster_id.as_deref(),
+            backend_id: self.backend_id.as_deref(),
         }
     }
 }
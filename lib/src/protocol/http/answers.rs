@@ -1,5 +1,5 @@
 use crate::ClusterId;
-use std::{collections::HashMap, rc::Rc};
+use std::{collections::HashMap, fs, rc::Rc};
 
 use super::DefaultAnswerStatus;
 
@@ -15,6 +15,8 @@ pub struct DefaultAnswers {
     pub RequestTimeout: Rc<Vec<u8>>,
     /// 413
     pub PayloadTooLarge: Rc<Vec<u8>>,
+    /// 431
+    pub RequestHeaderFieldsTooLarge: Rc<Vec<u8>>,
     /// 502
     pub BadGateway: Rc<Vec<u8>>,
     /// 503
@@ -31,6 +33,8 @@ pub struct CustomAnswers {
 pub struct HttpAnswers {
     pub default: DefaultAnswers,
     pub custom: HashMap<ClusterId, CustomAnswers>,
+    /// bodies of the deny routes, by file path
+    pub deny_bodies: HashMap<String, Rc<Vec<u8>>>,
 }
 
 impl HttpAnswers {
@@ -50,6 +54,9 @@ impl HttpAnswers {
         PayloadTooLarge: Rc::new(Vec::from(
           &b"HTTP/1.1 413 Payload Too Large\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"[..]
         )),
+        RequestHeaderFieldsTooLarge: Rc::new(Vec::from(
+          &b"HTTP/1.1 431 Request Header Fields Too Large\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"[..]
+        )),
         BadGateway: Rc::new(Vec::from(
           &b"HTTP/1.1 502 Bad Gateway\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"[..]
         )),
@@ -59,6 +66,7 @@ impl HttpAnswers {
         )),
       },
       custom: HashMap::new(),
+      deny_bodies: HashMap::new(),
     }
     }
 
@@ -76,14 +84,65 @@ impl HttpAnswers {
         self.custom.remove(cluster_id);
     }
 
+    /// reads the body of a deny route, so it is not read for every request
+    pub fn load_deny_body(&mut self, body_path: &str) -> bool {
+        match fs::read(body_path) {
+            Ok(body) => {
+                self.deny_bodies
+                    .insert(body_path.to_string(), Rc::new(body));
+                true
+            }
+            Err(e) => {
+                error!("could not read the deny answer body {}: {:?}", body_path, e);
+                false
+            }
+        }
+    }
+
+    /// generates the answer of a deny route
+    pub fn deny_answer(
+        &self,
+        status: u16,
+        body_path: Option<&str>,
+    ) -> (DefaultAnswerStatus, Rc<Vec<u8>>) {
+        let body = body_path.and_then(|path| self.deny_bodies.get(path));
+        if status == 401 && body.is_none() {
+            return (
+                DefaultAnswerStatus::Answer401,
+                self.default.Unauthorized.clone(),
+            );
+        }
+
+        let body = body.map(|body| &body[..]).unwrap_or_default();
+        let mut answer = format!(
+            "HTTP/1.1 {} {}\r\nCache-Control: no-cache\r\nConnection: close\r\nContent-Length: {}\r\n\r\n",
+            status,
+            reason_phrase(status),
+            body.len()
+        )
+        .into_bytes();
+        answer.extend_from_slice(body);
+
+        (DefaultAnswerStatus::AnswerDeny(status), Rc::new(answer))
+    }
+
     pub fn get(&self, answer: DefaultAnswerStatus, cluster_id: Option<&str>) -> Rc<Vec<u8>> {
         match answer {
-            DefaultAnswerStatus::Answer301 => panic!("the 301 answer is generated dynamically"),
+            DefaultAnswerStatus::Answer301
+            | DefaultAnswerStatus::Answer302
+            | DefaultAnswerStatus::Answer307
+            | DefaultAnswerStatus::Answer308 => {
+                panic!("the redirection answers are generated dynamically")
+            }
+            DefaultAnswerStatus::AnswerDeny(_) => {
+                panic!("the deny answers are generated dynamically")
+            }
             DefaultAnswerStatus::Answer400 => self.default.BadRequest.clone(),
             DefaultAnswerStatus::Answer401 => self.default.Unauthorized.clone(),
             DefaultAnswerStatus::Answer404 => self.default.NotFound.clone(),
             DefaultAnswerStatus::Answer408 => self.default.RequestTimeout.clone(),
             DefaultAnswerStatus::Answer413 => self.default.PayloadTooLarge.clone(),
+            DefaultAnswerStatus::Answer431 => self.default.RequestHeaderFieldsTooLarge.clone(),
             DefaultAnswerStatus::Answer502 => self.default.BadGateway.clone(),
             DefaultAnswerStatus::Answer503 => cluster_id
                 .and_then(|id: &str| self.custom.get(id))
@@ -93,3 +152,135 @@ impl HttpAnswers {
         }
     }
 }
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        410 => "Gone",
+        429 => "Too Many Requests",
+        451 => "Unavailable For Legal Reasons",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+/// generates the answer of a redirect route. `to` can contain the `{host}` and `{path}`
+/// placeholders, if it has neither a path nor a `{path}` placeholder, the path
+/// of the request is appended
+pub fn redirect_answer(
+    code: u16,
+    to: &str,
+    host: &str,
+    path: &str,
+) -> Option<(DefaultAnswerStatus, Rc<Vec<u8>>)> {
+    let (status, reason) = match code {
+        301 => (DefaultAnswerStatus::Answer301, "Moved Permanently"),
+        302 => (DefaultAnswerStatus::Answer302, "Found"),
+        307 => (DefaultAnswerStatus::Answer307, "Temporary Redirect"),
+        308 => (DefaultAnswerStatus::Answer308, "Permanent Redirect"),
+        _ => return None,
+    };
+
+    let mut location = to.replace("{host}", host);
+    if location.contains("{path}") {
+        location = location.replace("{path}", path);
+    } else {
+        let authority_and_path = location
+            .split_once("://")
+            .map(|(_, rest)| rest)
+            .unwrap_or(&location);
+        if !authority_and_path.contains('/') {
+            location.push_str(path);
+        }
+    }
+
+    let answer = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nLocation: {}\r\n\r\n",
+        code, reason, location
+    );
+    Some((status, Rc::new(answer.into_bytes())))
+}
+
+/// generates the 405 answer to a request whose method no frontend accepts,
+/// `allowed_methods` are listed in the `Allow` header
+pub fn method_not_allowed_answer(allowed_methods: &[String]) -> (DefaultAnswerStatus, Rc<Vec<u8>>) {
+    let answer = format!(
+        "HTTP/1.1 405 Method Not Allowed\r\nAllow: {}\r\nCache-Control: no-cache\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+        allowed_methods.join(", ")
+    );
+    (
+        DefaultAnswerStatus::AnswerDeny(405),
+        Rc::new(answer.into_bytes()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirect_location() {
+        let (status, answer) =
+            redirect_answer(308, "https://new.example.com", "example.com", "/a?b=c")
+                .expect("308 is a redirect code");
+        assert_eq!(status, DefaultAnswerStatus::Answer308);
+        assert_eq!(
+            &answer[..],
+            &b"HTTP/1.1 308 Permanent Redirect\r\nContent-Length: 0\r\nLocation: https://new.example.com/a?b=c\r\n\r\n"[..]
+        );
+
+        let (_, answer) = redirect_answer(302, "https://{host}/new{path}", "example.com", "/a")
+            .expect("302 is a redirect code");
+        assert_eq!(
+            &answer[..],
+            &b"HTTP/1.1 302 Found\r\nContent-Length: 0\r\nLocation: https://example.com/new/a\r\n\r\n"[..]
+        );
+
+        let (_, answer) = redirect_answer(
+            301,
+            "https://new.example.com/maintenance",
+            "example.com",
+            "/a",
+        )
+        .expect("301 is a redirect code");
+        assert_eq!(
+            &answer[..],
+            &b"HTTP/1.1 301 Moved Permanently\r\nContent-Length: 0\r\nLocation: https://new.example.com/maintenance\r\n\r\n"[..]
+        );
+
+        assert!(redirect_answer(200, "https://new.example.com", "example.com", "/").is_none());
+    }
+
+    #[test]
+    fn deny_with_status_and_body() {
+        let mut answers = HttpAnswers::new("", "");
+        answers.deny_bodies.insert(
+            "/etc/sozu/429.html".to_string(),
+            Rc::new(b"slow down".to_vec()),
+        );
+
+        let (status, answer) = answers.deny_answer(429, Some("/etc/sozu/429.html"));
+        assert_eq!(status, DefaultAnswerStatus::AnswerDeny(429));
+        assert_eq!(
+            &answer[..],
+            &b"HTTP/1.1 429 Too Many Requests\r\nCache-Control: no-cache\r\nConnection: close\r\nContent-Length: 9\r\n\r\nslow down"[..]
+        );
+
+        let (status, answer) = answers.deny_answer(403, None);
+        assert_eq!(status, DefaultAnswerStatus::AnswerDeny(403));
+        assert_eq!(
+            &answer[..],
+            &b"HTTP/1.1 403 Forbidden\r\nCache-Control: no-cache\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"[..]
+        );
+
+        let (status, _) = answers.deny_answer(401, None);
+        assert_eq!(status, DefaultAnswerStatus::Answer401);
+    }
+}
@@ -0,0 +1,427 @@
+//! conversions between the chunked transfer encoding and the other ways to
+//! delimit a body
+//!
+//! - the responses sent to HTTP/1.0 clients, which do not know the chunked
+//!   encoding, lose their `Transfer-Encoding: chunked` header and end when the
+//!   connection closes
+//! - the chunked requests sent to backends that need their length are buffered
+//!   whole, then sent with a `Content-Length` header
+//! - the responses that end when the backend closes the connection are chunked
+//!   for the HTTP/1.1 clients, so that they can keep theirs open
+//!
+//! The framing is removed from or added to the output of the buffer as the parser
+//! goes through the body, the body data is written as is.
+use std::cmp::min;
+
+use nom::Offset;
+
+use crate::buffer_queue::{BufferQueue, OutputElement};
+
+use super::parser::{
+    chunk_header, compare_no_case, parse_request_header_positions, parse_response_header_positions,
+    LocatedHeader,
+};
+
+/// removes the chunk framing of a body.
+///
+/// Positions are in the stream of the buffer, like its `buffer_position`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dechunker {
+    /// position of the next body byte to go through
+    position: usize,
+    /// data of the current chunk still to come
+    remaining: usize,
+    /// the data of the current chunk is followed by a CRLF
+    chunk_end: bool,
+    /// the last chunk was announced, a CRLF ends the body
+    last_chunk: bool,
+    /// size of the chunk data gone through
+    length: usize,
+    /// where the `Content-Length` header of a request goes, in the output
+    length_header: Option<usize>,
+}
+
+impl Dechunker {
+    /// prepares the decoding of the chunked response whose headers were parsed in
+    /// the buffer: its `Transfer-Encoding` header is replaced with `Connection: close`.
+    /// Returns None if the response uses other transfer codings
+    pub fn for_response(buf: &mut BufferQueue, body_start: usize) -> Option<Dechunker> {
+        let headers = parse_response_header_positions(buf.buffer.data())?;
+        let (offset, length) = chunked_header(&headers)?;
+
+        if !buf.replace_output_range(offset, length, b"Connection: close\r\n".to_vec()) {
+            return None;
+        }
+
+        Some(Dechunker::new(body_start, None))
+    }
+
+    /// prepares the decoding of the chunked request whose headers were parsed in the
+    /// buffer: its `Transfer-Encoding` header is removed, `finish` adds the length
+    /// once the body went through. Returns None if the request uses other transfer codings
+    pub fn for_request(buf: &mut BufferQueue, body_start: usize) -> Option<Dechunker> {
+        let (_, headers) = parse_request_header_positions(buf.buffer.data())?;
+        let (offset, length) = chunked_header(&headers)?;
+
+        if !buf.replace_output_range(offset, length, Vec::new()) {
+            return None;
+        }
+
+        Some(Dechunker::new(body_start, Some(offset + length)))
+    }
+
+    fn new(body_start: usize, length_header: Option<usize>) -> Dechunker {
+        Dechunker {
+            position: body_start,
+            remaining: 0,
+            chunk_end: false,
+            last_chunk: false,
+            length: 0,
+            length_header,
+        }
+    }
+
+    /// size of the body data parsed so far, and of the whole body once it ended
+    pub fn body_length(&self) -> usize {
+        self.length
+    }
+
+    /// adds the `Content-Length` header of a request whose body ended, returns
+    /// false if it cannot be added
+    pub fn finish(&mut self, buf: &mut BufferQueue) -> bool {
+        match self.length_header.take() {
+            Some(offset) => buf.replace_output_range(
+                offset,
+                0,
+                format!("Content-Length: {}\r\n", self.length).into_bytes(),
+            ),
+            None => true,
+        }
+    }
+
+    /// replaces the output of the chunks parsed since the last call with their data.
+    /// Returns false if the body is not made of well formed chunks
+    pub fn filter(&mut self, buf: &mut BufferQueue) -> bool {
+        let end = buf.start_parsing_position;
+        if end <= self.position {
+            return true;
+        }
+
+        // the parser sliced the chunks it went through at the end of the output
+        if !unslice_output(buf, end - self.position) {
+            return false;
+        }
+
+        while self.position < end {
+            if self.remaining > 0 {
+                // the data of the chunk may not be in the buffer yet
+                let size = min(self.remaining, end - self.position);
+                buf.slice_output(size);
+                self.remaining -= size;
+                self.position += size;
+                self.length += size;
+                continue;
+            }
+
+            let start = self.position - buf.buffer_position;
+            let data = match buf
+                .buffer
+                .data()
+                .get(start..min(end - buf.buffer_position, buf.buffer.available_data()))
+            {
+                Some(data) if !data.is_empty() => data,
+                _ => return false,
+            };
+
+            let framing = if self.chunk_end || self.last_chunk {
+                if !data.starts_with(b"\r\n") {
+                    return false;
+                }
+                self.chunk_end = false;
+                2
+            } else {
+                match chunk_header(data) {
+                    Ok((rest, 0)) => {
+                        self.last_chunk = true;
+                        data.offset(rest)
+                    }
+                    Ok((rest, size)) => {
+                        self.remaining = size;
+                        self.chunk_end = true;
+                        data.offset(rest)
+                    }
+                    Err(_) => return false,
+                }
+            };
+
+            buf.delete_output(framing);
+            self.position += framing;
+        }
+
+        // the framing at the end of the body may follow data already written
+        buf.consume_deleted_output();
+        true
+    }
+}
+
+/// adds the chunk framing to a response body that ends when the backend
+/// closes the connection.
+///
+/// Positions are in the stream of the back buffer, like its `buffer_position`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunker {
+    /// position of the next body byte to go through
+    position: usize,
+    /// the last chunk was added
+    ended: bool,
+}
+
+impl Chunker {
+    /// prepares the encoding of the response whose headers were parsed in the buffer:
+    /// a `Transfer-Encoding: chunked` header is added, and the `Connection: close`
+    /// header is removed, the client connection outliving the backend one
+    pub fn for_response(buf: &mut BufferQueue, body_start: usize) -> Option<Chunker> {
+        // before the empty line ending the headers
+        let offset = body_start.checked_sub(buf.buffer_position + 2)?;
+        if !buf.replace_output_range(offset, 0, b"Transfer-Encoding: chunked\r\n".to_vec()) {
+            return None;
+        }
+        buf.output_queue.retain(|element| {
+            !matches!(element, OutputElement::Insert(data) if data == b"Connection: close\r\n")
+        });
+
+        Some(Chunker {
+            position: body_start,
+            ended: false,
+        })
+    }
+
+    /// makes a chunk of the body data parsed since the last call, and adds the last
+    /// chunk once the backend closed the connection. Returns false if the body was
+    /// not sliced at the end of the output
+    pub fn filter(&mut self, buf: &mut BufferQueue, back_closed: bool) -> bool {
+        let end = buf.start_parsing_position;
+        if end > self.position {
+            let size = end - self.position;
+            if !unslice_output(buf, size) {
+                return false;
+            }
+            buf.insert_output(format!("{:x}\r\n", size).into_bytes());
+            buf.slice_output(size);
+            buf.insert_output(b"\r\n".to_vec());
+            self.position = end;
+        }
+
+        if back_closed && !self.ended {
+            buf.insert_output(b"0\r\n\r\n".to_vec());
+            self.ended = true;
+        }
+        true
+    }
+
+    /// the last chunk was added, the response ends once it is written
+    pub fn is_ended(&self) -> bool {
+        self.ended
+    }
+}
+
+/// position and length of the `Transfer-Encoding: chunked` header
+fn chunked_header(headers: &[LocatedHeader]) -> Option<(usize, usize)> {
+    headers
+        .iter()
+        .find(|(_, _, header)| {
+            compare_no_case(&header.name, b"Transfer-Encoding")
+                && compare_no_case(&header.value, b"chunked")
+        })
+        .map(|(offset, length, _)| (*offset, *length))
+}
+
+/// removes the last `length` bytes of slices from the output, returns false if
+/// the output does not end with that much sliced data
+fn unslice_output(buf: &mut BufferQueue, mut length: usize) -> bool {
+    while length > 0 {
+        match buf.output_queue.pop() {
+            Some(OutputElement::Slice(size)) if size <= length => length -= size,
+            Some(OutputElement::Slice(size)) => {
+                buf.slice_output(size - length);
+                length = 0;
+            }
+            _ => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::buffer_queue::buf_with_capacity;
+
+    #[test]
+    fn dechunk_body() {
+        let head = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let body = "5\r\nhello\r\n7\r\n world!\r\n0\r\n\r\n";
+        let (first, rest) = body.split_at(13);
+
+        // like the reads from the backend socket
+        let read = |buf: &mut BufferQueue, data: &[u8]| {
+            buf.buffer.space()[..data.len()].copy_from_slice(data);
+            buf.buffer.fill(data.len());
+            buf.sliced_input(data.len());
+        };
+
+        let (_pool, mut buf) = buf_with_capacity(16384);
+        read(&mut buf, head.as_bytes());
+        read(&mut buf, first.as_bytes());
+
+        // what the response parser outputs: one slice per header, then the chunks,
+        // the second one being sliced before its data is in the buffer
+        buf.slice_output(17);
+        buf.slice_output(28);
+        buf.slice_output(2);
+        buf.slice_output(8);
+        buf.slice_output(12);
+        buf.consume_parsed_data(head.len() + 20);
+
+        let mut dechunker = Dechunker::for_response(&mut buf, head.len()).unwrap();
+        assert!(dechunker.filter(&mut buf));
+
+        let mut output = Vec::new();
+        let mut write_output = |buf: &mut BufferQueue| {
+            while buf.output_data_size() > 0 && !buf.next_output_data().is_empty() {
+                let data = buf.next_output_data().to_vec();
+                output.extend_from_slice(&data);
+                buf.consume_output_data(data.len());
+            }
+        };
+        write_output(&mut buf);
+
+        read(&mut buf, rest.as_bytes());
+        buf.slice_output(5);
+        buf.slice_output(2);
+        buf.consume_parsed_data(7);
+        assert!(dechunker.filter(&mut buf));
+        write_output(&mut buf);
+        assert!(!buf.can_restart_parsing());
+        buf.consume_deleted_output();
+        assert!(buf.can_restart_parsing());
+
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nhello world!"
+        );
+    }
+
+    #[test]
+    fn malformed_chunks() {
+        let head = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let (_pool, mut buf) = buf_with_capacity(16384);
+        let data = format!("{}zz\r\n", head);
+        buf.buffer.space()[..data.len()].copy_from_slice(data.as_bytes());
+        buf.buffer.fill(data.len());
+        buf.sliced_input(data.len());
+
+        buf.slice_output(17);
+        buf.slice_output(28);
+        buf.slice_output(2);
+        buf.slice_output(4);
+        buf.consume_parsed_data(data.len());
+
+        let mut dechunker = Dechunker::for_response(&mut buf, head.len()).unwrap();
+        assert!(!dechunker.filter(&mut buf));
+
+        let (_pool, mut buf) = buf_with_capacity(16384);
+        let head = "HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\r\n";
+        buf.buffer.space()[..head.len()].copy_from_slice(head.as_bytes());
+        buf.buffer.fill(head.len());
+        buf.sliced_input(head.len());
+        buf.slice_output(head.len());
+        assert_eq!(Dechunker::for_response(&mut buf, head.len()), None);
+    }
+
+    #[test]
+    fn dechunk_request_body() {
+        let head = "POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let body = "5\r\nhello\r\n7\r\n world!\r\n0\r\n\r\n";
+        let data = format!("{}{}", head, body);
+
+        let (_pool, mut buf) = buf_with_capacity(16384);
+        buf.buffer.space()[..data.len()].copy_from_slice(data.as_bytes());
+        buf.buffer.fill(data.len());
+        buf.sliced_input(data.len());
+
+        // what the request parser outputs, the whole body being there
+        for size in [17, 17, 28, 2, 3, 5, 2, 3, 7, 2, 3, 2] {
+            buf.slice_output(size);
+        }
+        buf.consume_parsed_data(data.len());
+
+        let mut dechunker = Dechunker::for_request(&mut buf, head.len()).unwrap();
+        assert!(dechunker.filter(&mut buf));
+        assert_eq!(dechunker.body_length(), 12);
+        assert!(dechunker.finish(&mut buf));
+
+        let mut output = Vec::new();
+        while buf.output_data_size() > 0 && !buf.next_output_data().is_empty() {
+            let data = buf.next_output_data().to_vec();
+            output.extend_from_slice(&data);
+            buf.consume_output_data(data.len());
+        }
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 12\r\n\r\nhello world!"
+        );
+    }
+
+    #[test]
+    fn chunk_close_delimited_body() {
+        let head = "HTTP/1.1 200 OK\r\nServer: test\r\n\r\n";
+
+        let read = |buf: &mut BufferQueue, data: &[u8]| {
+            buf.buffer.space()[..data.len()].copy_from_slice(data);
+            buf.buffer.fill(data.len());
+            buf.sliced_input(data.len());
+        };
+
+        let (_pool, mut buf) = buf_with_capacity(16384);
+        read(&mut buf, format!("{}hello", head).as_bytes());
+
+        // what the response parser outputs, with the Connection header it adds back
+        buf.slice_output(17);
+        buf.slice_output(14);
+        buf.insert_output(b"Connection: close\r\n".to_vec());
+        buf.slice_output(2);
+        buf.slice_output(5);
+        buf.consume_parsed_data(head.len() + 5);
+
+        let mut chunker = Chunker::for_response(&mut buf, head.len()).unwrap();
+        assert!(chunker.filter(&mut buf, false));
+
+        let mut output = Vec::new();
+        let mut write_output = |buf: &mut BufferQueue| {
+            while buf.output_data_size() > 0 && !buf.next_output_data().is_empty() {
+                let data = buf.next_output_data().to_vec();
+                output.extend_from_slice(&data);
+                buf.consume_output_data(data.len());
+            }
+        };
+        write_output(&mut buf);
+
+        // the back buffer gets the next reads, then the backend closes
+        read(&mut buf, b" world");
+        buf.slice_output(6);
+        buf.consume_parsed_data(6);
+        assert!(chunker.filter(&mut buf, false));
+        assert!(!chunker.is_ended());
+        assert!(chunker.filter(&mut buf, true));
+        assert!(chunker.is_ended());
+        write_output(&mut buf);
+
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "HTTP/1.1 200 OK\r\nServer: test\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n"
+        );
+    }
+}
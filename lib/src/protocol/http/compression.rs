@@ -0,0 +1,424 @@
+//! on-the-fly compression of the response bodies
+//!
+//! Only responses with a `Content-Length` are compressed: their length header is
+//! replaced by `Transfer-Encoding: chunked`, and the body is compressed as it
+//! goes through the back buffer, each read of the backend becoming a chunk.
+use std::{
+    cmp::min,
+    io::{self, Write},
+    mem,
+};
+
+use brotli::CompressorWriter;
+use flate2::write::GzEncoder;
+
+use crate::{
+    buffer_queue::{BufferQueue, OutputElement},
+    sozu_command::proxy::Compression,
+};
+
+use super::parser::{compare_no_case, parse_response_header_positions, Header};
+
+const BROTLI_BUFFER_SIZE: usize = 4096;
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 19;
+
+/// media types compressed along with `text/*`, `*+json` and `*+xml`
+const COMPRESSIBLE_TYPES: &[&[u8]] = &[
+    b"application/javascript",
+    b"application/json",
+    b"application/x-javascript",
+    b"application/xml",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    /// the value of the `Content-Encoding` header
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+}
+
+/// chooses the encoding of the response from the `Accept-Encoding` headers of the request.
+/// Brotli is preferred to gzip when the client and the settings accept both
+pub fn negotiate_encoding(settings: &Compression, headers: &[Header]) -> Option<Encoding> {
+    let mut gzip = None;
+    let mut brotli = None;
+    let mut any = None;
+
+    for header in headers
+        .iter()
+        .filter(|header| compare_no_case(&header.name, b"Accept-Encoding"))
+    {
+        for coding in header.value.split(|c| *c == b',') {
+            let mut parameters = coding.split(|c| *c == b';');
+            let name = trim(parameters.next().unwrap_or_default());
+            let accepted = !parameters.any(is_zero_quality);
+
+            if compare_no_case(name, b"gzip") {
+                gzip = Some(accepted);
+            } else if compare_no_case(name, b"br") {
+                brotli = Some(accepted);
+            } else if name == b"*" {
+                any = Some(accepted);
+            }
+        }
+    }
+
+    let any = any.unwrap_or(false);
+    if settings.brotli == Some(true) && brotli.unwrap_or(any) {
+        Some(Encoding::Brotli)
+    } else if settings.gzip == Some(true) && gzip.unwrap_or(any) {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+/// whether the responses of this `Content-Type` gain from compression
+pub fn is_compressible(content_type: &[u8]) -> bool {
+    let media_type = trim(
+        content_type
+            .split(|c| *c == b';')
+            .next()
+            .unwrap_or_default(),
+    )
+    .to_ascii_lowercase();
+
+    media_type.starts_with(b"text/")
+        || media_type.ends_with(b"+json")
+        || media_type.ends_with(b"+xml")
+        || COMPRESSIBLE_TYPES.contains(&&media_type[..])
+}
+
+fn trim(value: &[u8]) -> &[u8] {
+    let start = value
+        .iter()
+        .position(|c| !c.is_ascii_whitespace())
+        .unwrap_or(value.len());
+    let end = value
+        .iter()
+        .rposition(|c| !c.is_ascii_whitespace())
+        .map(|position| position + 1)
+        .unwrap_or(start);
+    &value[start..end]
+}
+
+fn is_zero_quality(parameter: &[u8]) -> bool {
+    let parameter = trim(parameter);
+    parameter.len() > 2
+        && compare_no_case(&parameter[..2], b"q=")
+        && std::str::from_utf8(&parameter[2..])
+            .ok()
+            .and_then(|quality| quality.trim().parse::<f32>().ok())
+            .map(|quality| quality == 0.0)
+            .unwrap_or(false)
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Brotli(Box<CompressorWriter<Vec<u8>>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Encoder {
+        match encoding {
+            Encoding::Gzip => {
+                Encoder::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::default()))
+            }
+            Encoding::Brotli => Encoder::Brotli(Box::new(CompressorWriter::new(
+                Vec::new(),
+                BROTLI_BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+        }
+    }
+
+    /// compresses and flushes the data, returning what the encoder produced
+    fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                Ok(mem::take(encoder.get_mut()))
+            }
+            Encoder::Brotli(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                Ok(mem::take(encoder.get_mut()))
+            }
+        }
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Brotli(encoder) => Ok(encoder.into_inner()),
+        }
+    }
+}
+
+/// compresses a response body of known length as it is read from the backend.
+///
+/// Positions are in the stream of the back buffer, like its `buffer_position`
+pub struct Compressor {
+    encoder: Option<Encoder>,
+    /// position of the next body byte to compress
+    position: usize,
+    /// position of the end of the body
+    end: usize,
+}
+
+impl Compressor {
+    /// prepares the compression of the response whose headers were parsed in the buffer:
+    /// its `Content-Length` header is replaced with the encoding headers, and its body
+    /// removed from the output, to be added back by `filter`.
+    /// Returns None if the response cannot be compressed
+    pub fn for_response(
+        buf: &mut BufferQueue,
+        encoding: Encoding,
+        body_start: usize,
+        body_length: usize,
+    ) -> Option<Compressor> {
+        let headers = parse_response_header_positions(buf.buffer.data())?;
+
+        let compressible = headers.iter().any(|(_, _, header)| {
+            compare_no_case(&header.name, b"Content-Type") && is_compressible(&header.value)
+        });
+        let encoded = headers
+            .iter()
+            .any(|(_, _, header)| compare_no_case(&header.name, b"Content-Encoding"));
+        if !compressible || encoded {
+            return None;
+        }
+
+        let (offset, length, _) = headers
+            .iter()
+            .find(|(_, _, header)| compare_no_case(&header.name, b"Content-Length"))?;
+
+        // the body is sliced at the end of the output, right after the headers
+        match buf.output_queue.last() {
+            Some(OutputElement::Slice(size)) if *size >= body_length => {}
+            _ => return None,
+        }
+
+        let encoding_headers = format!(
+            "Content-Encoding: {}\r\nTransfer-Encoding: chunked\r\nVary: Accept-Encoding\r\n",
+            encoding.as_str()
+        );
+        if !buf.replace_output_range(*offset, *length, encoding_headers.into_bytes()) {
+            return None;
+        }
+
+        if let Some(OutputElement::Slice(size)) = buf.output_queue.last_mut() {
+            *size -= body_length;
+        }
+
+        Some(Compressor {
+            encoder: Some(Encoder::new(encoding)),
+            position: body_start,
+            end: body_start + body_length,
+        })
+    }
+
+    /// replaces the body data received since the last call with a compressed chunk.
+    /// The encoder is flushed every time, so that the body data does not stay in the
+    /// back buffer while the encoder waits for more input
+    pub fn filter(&mut self, buf: &mut BufferQueue) -> io::Result<()> {
+        let mut output = Vec::new();
+
+        let available_end = min(self.end, buf.buffer_position + buf.buffer.available_data());
+        if available_end > self.position {
+            let start = self.position - buf.buffer_position;
+            let length = available_end - self.position;
+
+            if let Some(encoder) = self.encoder.as_mut() {
+                push_chunk(
+                    &mut output,
+                    &encoder.compress(&buf.buffer.data()[start..start + length])?,
+                );
+            }
+            buf.delete_output(length);
+            self.position = available_end;
+        }
+
+        if self.position == self.end {
+            if let Some(encoder) = self.encoder.take() {
+                push_chunk(&mut output, &encoder.finish()?);
+                output.extend_from_slice(b"0\r\n\r\n");
+            }
+        }
+
+        if !output.is_empty() {
+            buf.insert_output(output);
+        }
+        Ok(())
+    }
+}
+
+fn push_chunk(output: &mut Vec<u8>, data: &[u8]) {
+    if !data.is_empty() {
+        output.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+        output.extend_from_slice(data);
+        output.extend_from_slice(b"\r\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    use crate::buffer_queue::buf_with_capacity;
+
+    fn header(name: &str, value: &str) -> Header {
+        Header {
+            name: name.as_bytes().to_vec(),
+            value: value.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn negotiation() {
+        let both = Compression {
+            gzip: Some(true),
+            brotli: Some(true),
+            min_size: None,
+        };
+        let gzip = Compression {
+            gzip: Some(true),
+            ..Default::default()
+        };
+
+        let accept = |value: &str| vec![header("Accept-Encoding", value)];
+
+        assert_eq!(
+            negotiate_encoding(&both, &accept("gzip, deflate, br")),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(
+            negotiate_encoding(&gzip, &accept("gzip, deflate, br")),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            negotiate_encoding(&both, &accept("br;q=0, GZIP;q=0.5")),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            negotiate_encoding(&both, &accept("*")),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(
+            negotiate_encoding(&both, &accept("*, br;q=0, gzip;q=0")),
+            None
+        );
+        assert_eq!(negotiate_encoding(&both, &accept("deflate")), None);
+        assert_eq!(negotiate_encoding(&both, &[]), None);
+        assert_eq!(
+            negotiate_encoding(&Compression::default(), &accept("gzip, br")),
+            None
+        );
+    }
+
+    #[test]
+    fn compressible_types() {
+        assert!(is_compressible(b"text/html; charset=utf-8"));
+        assert!(is_compressible(b"Application/JSON"));
+        assert!(is_compressible(b"image/svg+xml"));
+        assert!(!is_compressible(b"image/png"));
+        assert!(!is_compressible(b"application/octet-stream"));
+    }
+
+    #[test]
+    fn compress_body() {
+        let body = "hello world! ".repeat(100);
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        );
+        let (half, rest) = body.split_at(body.len() / 2);
+
+        // like the reads from the backend socket
+        let read = |buf: &mut BufferQueue, data: &[u8]| {
+            buf.buffer.space()[..data.len()].copy_from_slice(data);
+            buf.buffer.fill(data.len());
+            buf.sliced_input(data.len());
+        };
+
+        let (_pool, mut buf) = buf_with_capacity(16384);
+        read(&mut buf, response.as_bytes());
+        read(&mut buf, half.as_bytes());
+
+        // what the response parser outputs: one slice per header, then the body
+        let header_lines = ["HTTP/1.1 200 OK\r\n", "Content-Type: text/plain\r\n"];
+        for line in header_lines {
+            buf.slice_output(line.len());
+        }
+        let content_length = format!("Content-Length: {}\r\n", body.len());
+        buf.slice_output(content_length.len());
+        buf.slice_output(2 + body.len());
+        buf.consume_parsed_data(response.len() + body.len());
+
+        let mut compressor =
+            Compressor::for_response(&mut buf, Encoding::Gzip, response.len(), body.len()).unwrap();
+        compressor.filter(&mut buf).unwrap();
+
+        let mut output = Vec::new();
+        let mut write_output = |buf: &mut BufferQueue| {
+            while buf.output_data_size() > 0 {
+                let data = buf.next_output_data().to_vec();
+                output.extend_from_slice(&data);
+                buf.consume_output_data(data.len());
+            }
+        };
+        write_output(&mut buf);
+
+        read(&mut buf, rest.as_bytes());
+        compressor.filter(&mut buf).unwrap();
+        write_output(&mut buf);
+        assert!(buf.can_restart_parsing());
+
+        let headers_end = output
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap()
+            + 4;
+        let headers = std::str::from_utf8(&output[..headers_end]).unwrap();
+        assert_eq!(
+            headers,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Encoding: gzip\r\n\
+             Transfer-Encoding: chunked\r\nVary: Accept-Encoding\r\n\r\n"
+        );
+
+        // decode the chunks
+        let mut compressed = Vec::new();
+        let mut chunks = &output[headers_end..];
+        loop {
+            let line_end = chunks.windows(2).position(|w| w == b"\r\n").unwrap();
+            let size = usize::from_str_radix(std::str::from_utf8(&chunks[..line_end]).unwrap(), 16)
+                .unwrap();
+            if size == 0 {
+                assert_eq!(&chunks[line_end..], b"\r\n\r\n");
+                break;
+            }
+            compressed.extend_from_slice(&chunks[line_end + 2..line_end + 2 + size]);
+            chunks = &chunks[line_end + 2 + size + 2..];
+        }
+        assert!(compressed.len() < body.len());
+
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+    }
+}
@@ -7,6 +7,8 @@ use nom::{
     IResult,
 };
 
+use super::parser::{compare_no_case, Header};
+
 #[derive(Debug)]
 pub struct RequestCookie<'a> {
     pub name: &'a [u8],
@@ -56,6 +58,17 @@ pub fn parse_request_cookies(input: &[u8]) -> Option<Vec<RequestCookie>> {
     }
 }
 
+/// the value of the first cookie with that name in the `Cookie` headers of a request
+pub fn find_request_cookie(headers: &[Header], name: &str) -> Option<String> {
+    headers
+        .iter()
+        .filter(|header| compare_no_case(&header.name, b"Cookie"))
+        .filter_map(|header| parse_request_cookies(&header.value))
+        .flatten()
+        .find(|cookie| cookie.name == name.as_bytes())
+        .map(|cookie| String::from_utf8_lossy(cookie.value).into_owned())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -89,4 +102,24 @@ mod tests {
         assert_eq!(some_cookies[3].semicolon.is_some(), false);
         assert_eq!(some_cookies[3].spaces.len(), 0);
     }
+
+    #[test]
+    fn find_cookie_in_request_headers() {
+        let headers = vec![
+            Header {
+                name: b"Accept".to_vec(),
+                value: b"text/html".to_vec(),
+            },
+            Header {
+                name: b"cookie".to_vec(),
+                value: b"FOO=BAR; variant=checkout-b".to_vec(),
+            },
+        ];
+
+        assert_eq!(
+            find_request_cookie(&headers, "variant"),
+            Some("checkout-b".to_string())
+        );
+        assert_eq!(find_request_cookie(&headers, "BAR"), None);
+    }
 }
@@ -1,10 +1,15 @@
 pub mod answers;
+pub mod chunked;
+pub mod compression;
 pub mod cookies;
+pub mod normalization;
 pub mod parser;
+pub mod session;
 
 use std::{
     cell::RefCell,
     cmp::min,
+    io::IoSlice,
     net::{IpAddr, SocketAddr},
     rc::{Rc, Weak},
 };
@@ -14,21 +19,35 @@ use rusty_ulid::Ulid;
 use time::{Duration, Instant};
 
 use crate::{
-    buffer_queue::BufferQueue,
+    buffer_queue::{BufferQueue, OutputElement},
     pool::Pool,
     protocol::ProtocolResult,
-    socket::{SocketHandler, SocketResult, TransportProtocol},
-    sozu_command::ready::Ready,
+    server::tap_access_log,
+    socket::{BackendSocket, SocketHandler, SocketResult, TransportProtocol},
+    sozu_command::{
+        proxy::{
+            AccessLogRecord, Compression, HashKey, RequestLimits, RequestRetries, RetryCondition,
+            SecurityHeaders, StickyMode, DEFAULT_COMPRESSION_MIN_SIZE,
+        },
+        ready::Ready,
+    },
     timer::TimeoutContainer,
     util::UnwrapLog,
     Backend, ListenerHandler, LogDuration, {Protocol, Readiness, SessionMetrics, SessionResult},
 };
 
+use self::chunked::{Chunker, Dechunker};
+use self::compression::{negotiate_encoding, Compressor, Encoding};
+use self::cookies::find_request_cookie;
 use self::parser::{
-    compare_no_case, parse_request_until_stop, parse_response_until_stop, Chunk, Continue, Method,
-    RequestLine, RequestState, ResponseState, StatusLine,
+    compare_no_case, parse_request_header_positions, parse_request_headers,
+    parse_request_until_stop, parse_response_until_stop, Chunk, Continue, Header, HeaderEdits,
+    Method, RequestLine, RequestState, ResponseState, StatusLine, Version,
 };
 
+/// requests larger than this are not kept to be sent again to another backend
+pub const RETRY_BUFFER_MAX_SIZE: usize = 16384;
+
 #[derive(Clone)]
 pub struct StickySession {
     pub sticky_id: String,
@@ -52,11 +71,17 @@ pub enum SessionStatus {
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DefaultAnswerStatus {
     Answer301,
+    Answer302,
+    Answer307,
+    Answer308,
+    /// answer of a deny route, with its status code
+    AnswerDeny(u16),
     Answer400,
     Answer401,
     Answer404,
     Answer408,
     Answer413,
+    Answer431,
     Answer502,
     Answer503,
     Answer504,
@@ -67,11 +92,16 @@ impl Into<u16> for DefaultAnswerStatus {
     fn into(self) -> u16 {
         match self {
             Self::Answer301 => 301,
+            Self::Answer302 => 302,
+            Self::Answer307 => 307,
+            Self::Answer308 => 308,
+            Self::AnswerDeny(status) => status,
             Self::Answer400 => 400,
             Self::Answer401 => 401,
             Self::Answer404 => 404,
             Self::Answer408 => 408,
             Self::Answer413 => 413,
+            Self::Answer431 => 431,
             Self::Answer502 => 502,
             Self::Answer503 => 503,
             Self::Answer504 => 504,
@@ -92,7 +122,7 @@ pub enum TimeoutStatus {
 /// TODO: rename me (example: HttpState)
 pub struct Http<Front: SocketHandler, L: ListenerHandler> {
     pub frontend: Front,
-    pub backend: Option<TcpStream>,
+    pub backend: Option<BackendSocket>,
     frontend_token: Token,
     backend_token: Option<Token>,
     pub status: SessionStatus,
@@ -114,7 +144,39 @@ pub struct Http<Front: SocketHandler, L: ListenerHandler> {
     pub req_header_end: Option<usize>,
     pub res_header_end: Option<usize>,
     pub added_req_header: Option<AddedRequestHeader>,
-    pub added_res_header: String,
+    /// headers added to the response, by sozu and by the header actions
+    /// of the cluster, and headers removed from it
+    pub response_header_edits: HeaderEdits,
+    /// the Host header line added to the request for its backend, replaced
+    /// when the request is sent to another backend
+    pub rewritten_host: Option<Vec<u8>>,
+    /// when the request is mirrored, a copy of the data written to the backend
+    pub mirror_buffer: Option<Vec<u8>>,
+    /// when the request can be retried after a 502 or 503, a copy of the data written to the backend
+    retry_buffer: Option<Vec<u8>>,
+    /// limits of the listener, replaced by the ones of the cluster once the request is routed
+    pub request_limits: RequestLimits,
+    /// compression settings of the listener, merged with the ones of the cluster once the request is routed
+    pub compression: Compression,
+    /// encoding accepted by the client for the response, if the settings allow compressing it
+    response_encoding: Option<Encoding>,
+    /// compresses the body of the current response
+    compressor: Option<Compressor>,
+    /// removes the chunk framing of the current response, for HTTP/1.0 clients
+    dechunker: Option<Dechunker>,
+    /// removes the chunk framing of the current request, for the backends that need its length
+    request_dechunker: Option<Dechunker>,
+    /// chunks the current response if it ends with the backend connection, for HTTP/1.1 clients
+    chunker: Option<Chunker>,
+    /// the cluster streams its responses: they are not compressed, and the
+    /// back timeout does not end them once their body is forwarded
+    streaming: bool,
+    /// protocols of the cluster the connection can switch to, besides WebSocket
+    upgrade_protocols: Vec<String>,
+    /// position of the current request in the front buffer
+    request_start: usize,
+    /// number of lines of the request line and headers parsed so far
+    req_header_lines: usize,
     pub keepalive_count: usize,
     pub backend_stop: Option<Instant>,
     answers: Rc<RefCell<answers::HttpAnswers>>,
@@ -142,6 +204,8 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
         backend_timeout_duration: Duration,
         listener: Rc<RefCell<L>>,
     ) -> Http<Front, L> {
+        let request_limits = listener.borrow().get_request_limits();
+        let compression = listener.borrow().get_compression();
         // the variable name is misleading
         let mut session = Http {
             frontend: sock,
@@ -167,7 +231,21 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
             req_header_end: None,
             res_header_end: None,
             added_req_header: None,
-            added_res_header: String::from(""),
+            response_header_edits: HeaderEdits::default(),
+            rewritten_host: None,
+            mirror_buffer: None,
+            retry_buffer: None,
+            request_limits,
+            compression,
+            response_encoding: None,
+            compressor: None,
+            dechunker: None,
+            request_dechunker: None,
+            chunker: None,
+            streaming: false,
+            upgrade_protocols: Vec::new(),
+            request_start: 0,
+            req_header_lines: 0,
             keepalive_count: 0,
             backend_stop: None,
             closing: false,
@@ -180,7 +258,10 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
         };
 
         session.added_req_header = Some(session.added_request_header(session_address));
-        session.added_res_header = session.added_response_header();
+        session.response_header_edits = HeaderEdits {
+            removed: Vec::new(),
+            added: session.added_response_header().into_bytes(),
+        };
         session
     }
 
@@ -194,7 +275,22 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
         self.req_header_end = None;
         self.res_header_end = None;
         self.added_req_header = Some(self.added_request_header(self.session_address));
-        self.added_res_header = self.added_response_header();
+        self.response_header_edits = HeaderEdits {
+            removed: Vec::new(),
+            added: self.added_response_header().into_bytes(),
+        };
+        self.rewritten_host = None;
+        self.request_limits = self.listener.borrow().get_request_limits();
+        self.req_header_lines = 0;
+        self.compression = self.listener.borrow().get_compression();
+        self.response_encoding = None;
+        self.compressor = None;
+        self.dechunker = None;
+        self.request_dechunker = None;
+        self.chunker = None;
+        self.streaming = false;
+        self.upgrade_protocols.clear();
+        self.retry_buffer = None;
 
         // if HTTP requests are pipelined, we might still have some data in the front buffer
         if self
@@ -204,8 +300,10 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
             .unwrap_or(false)
         {
             self.front_readiness.event.insert(Ready::readable());
+            self.request_start = self.front_buf.as_ref().unwrap().start_parsing_position;
         } else {
             self.front_buf = None;
+            self.request_start = 0;
         }
 
         self.back_buf = None;
@@ -255,15 +353,26 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
         } else {
             match answer {
                 DefaultAnswerStatus::Answer301 => incr!("http.301.redirection"),
+                DefaultAnswerStatus::Answer302 => incr!("http.302.redirection"),
+                DefaultAnswerStatus::Answer307 => incr!("http.307.redirection"),
+                DefaultAnswerStatus::Answer308 => incr!("http.308.redirection"),
+                DefaultAnswerStatus::AnswerDeny(_) => incr!("http.deny.errors"),
                 DefaultAnswerStatus::Answer400 => incr!("http.400.errors"),
                 DefaultAnswerStatus::Answer401 => incr!("http.401.errors"),
                 DefaultAnswerStatus::Answer404 => incr!("http.404.errors"),
                 DefaultAnswerStatus::Answer408 => incr!("http.408.errors"),
                 DefaultAnswerStatus::Answer413 => incr!("http.413.errors"),
+                DefaultAnswerStatus::Answer431 => incr!("http.431.errors"),
                 DefaultAnswerStatus::Answer502 => incr!("http.502.errors"),
                 DefaultAnswerStatus::Answer503 => incr!("http.503.errors"),
                 DefaultAnswerStatus::Answer504 => incr!("http.504.errors"),
             };
+
+            // the server errors of sozu for a cluster, its backends could not answer
+            let status: u16 = answer.into();
+            if self.cluster_id.is_some() && status >= 500 {
+                incr!("http.status.5xx", self.cluster_id.as_deref(), None);
+            }
         }
 
         let buf = buf.unwrap_or_else(|| {
@@ -288,7 +397,7 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
 
     pub fn added_response_header(&self) -> String {
         if self.closing {
-            format!("Sozu-Id: {}\r\nConnection: close", self.request_id)
+            format!("Sozu-Id: {}\r\nConnection: close\r\n", self.request_id)
         } else {
             format!("Sozu-Id: {}\r\n", self.request_id)
         }
@@ -303,11 +412,11 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
     }
 
     pub fn back_socket(&self) -> Option<&TcpStream> {
-        self.backend.as_ref()
+        self.backend.as_ref().map(|backend| backend.socket_ref())
     }
 
     pub fn back_socket_mut(&mut self) -> Option<&mut TcpStream> {
-        self.backend.as_mut()
+        self.backend.as_mut().map(|backend| backend.socket_mut())
     }
 
     pub fn back_token(&self) -> Option<Token> {
@@ -318,7 +427,7 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
         match self.backend {
             Some(ref mut s) => {
                 let mut tmp = [0u8; 1];
-                let res = s.peek(&mut tmp[..]);
+                let res = s.socket_ref().peek(&mut tmp[..]);
 
                 match res {
                     // if the socket is half open, it will report 0 bytes read (EOF)
@@ -352,7 +461,11 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
 
     pub fn close(&mut self) {}
 
-    pub fn set_back_socket(&mut self, socket: TcpStream, backend: Option<Rc<RefCell<Backend>>>) {
+    pub fn set_back_socket(
+        &mut self,
+        socket: BackendSocket,
+        backend: Option<Rc<RefCell<Backend>>>,
+    ) {
         self.backend = Some(socket);
         self.backend_data = backend;
     }
@@ -448,7 +561,10 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
                 .map(|t| format!("{}", t.0))
                 .unwrap_or_else(|| "-".to_string())
         );
-        let addr: Option<SocketAddr> = self.backend.as_ref().and_then(|sock| sock.peer_addr().ok());
+        let addr: Option<SocketAddr> = self
+            .backend
+            .as_ref()
+            .and_then(|sock| sock.socket_ref().peer_addr().ok());
         self.cancel_backend_timeout();
         self.backend = None;
         self.backend_token = None;
@@ -541,6 +657,120 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
         self.request_state.as_ref().and_then(|r| r.get_host())
     }
 
+    /// the request headers are not kept by the parser, but as long as the
+    /// request was not sent to a backend, they are still in the front buffer
+    pub fn get_request_headers(&self) -> Vec<Header> {
+        self.front_buf
+            .as_ref()
+            .map(|buf| parse_request_headers(buf.buffer.data()))
+            .unwrap_or_default()
+    }
+
+    /// the value of a cookie of the request, read from the front buffer like the headers
+    pub fn get_request_cookie(&self, name: &str) -> Option<String> {
+        find_request_cookie(&self.get_request_headers(), name)
+    }
+
+    /// adds a `Set-Cookie` header to the response of the current request
+    pub fn add_response_cookie(&mut self, name: &str, value: &str) {
+        self.response_header_edits
+            .added
+            .extend(format!("Set-Cookie: {}={}; Path=/\r\n", name, value).as_bytes());
+    }
+
+    /// replaces the URI in the request line before the request is sent to the backend
+    pub fn rewrite_request_uri(&mut self, uri: &str) -> bool {
+        let (offset, current_uri) = match self.get_request_line() {
+            Some(request_line) => (
+                request_line.method.to_string().len() + 1,
+                request_line.uri.clone(),
+            ),
+            None => return false,
+        };
+
+        match self.front_buf.as_mut() {
+            Some(buf)
+                if buf.buffer.data().get(offset..offset + current_uri.len())
+                    == Some(current_uri.as_bytes()) =>
+            {
+                buf.replace_output_range(offset, current_uri.len(), uri.as_bytes().to_vec())
+            }
+            _ => false,
+        }
+    }
+
+    /// sends the request to the backend with this path. If the path cannot be
+    /// rewritten, the request is answered with a 502 instead of reaching the
+    /// backend with a path it is not meant to receive
+    pub fn set_request_path(&mut self, path: &str) -> bool {
+        if self.rewrite_request_uri(path) {
+            return true;
+        }
+
+        error!("could not rewrite the request path to {}", path);
+        self.set_answer(DefaultAnswerStatus::Answer502, None);
+        false
+    }
+
+    /// applies the header actions of the cluster to the request. Like the routing,
+    /// it only sees the headers received before the request was routed
+    pub fn edit_request_headers(&mut self, edits: &HeaderEdits) {
+        if edits.is_empty() {
+            return;
+        }
+
+        let buf = match self.front_buf.as_mut() {
+            Some(buf) => buf,
+            None => return,
+        };
+
+        let (headers_start, headers) = match parse_request_header_positions(buf.buffer.data()) {
+            Some(positions) => positions,
+            None => return,
+        };
+
+        for (offset, length, header) in headers {
+            // the header may already have been removed by the parser
+            if edits.should_remove(&header.name) {
+                buf.replace_output_range(offset, length, Vec::new());
+            }
+        }
+
+        if !edits.added.is_empty()
+            && !buf.replace_output_range(headers_start, 0, edits.added.clone())
+        {
+            error!("could not add the cluster's headers to the request");
+        }
+    }
+
+    /// replaces the Host header of the request. When the request is retried or
+    /// queued for another backend, the line added for the previous one is replaced,
+    /// so the edits do not pile up
+    pub fn rewrite_request_host(&mut self, host: &str) {
+        let edits = HeaderEdits::host(host);
+        let previous = match self.rewritten_host.take() {
+            None => {
+                self.edit_request_headers(&edits);
+                self.rewritten_host = Some(edits.added);
+                return;
+            }
+            Some(previous) => previous,
+        };
+
+        let replaced = previous == edits.added
+            || self
+                .front_buf
+                .as_mut()
+                .map(|buf| buf.replace_inserted(&previous, &edits.added))
+                .unwrap_or(false);
+        if replaced {
+            self.rewritten_host = Some(edits.added);
+        } else {
+            error!("could not replace the Host header of the request");
+            self.rewritten_host = Some(previous);
+        }
+    }
+
     pub fn get_request_line(&self) -> Option<&RequestLine> {
         self.request_state
             .as_ref()
@@ -552,6 +782,36 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
             .or_else(|| self.frontend.socket_ref().peer_addr().ok())
     }
 
+    /// the value hashed to choose the backend of a sticky cluster,
+    /// None if the request does not carry it or if the cluster sticks with a cookie
+    pub fn get_sticky_key(&self, sticky_mode: &StickyMode) -> Option<Vec<u8>> {
+        match sticky_mode {
+            StickyMode::Cookie => None,
+            StickyMode::SourceIp => self
+                .get_session_address()
+                .map(|address| address.ip().to_string().into_bytes()),
+            StickyMode::Header(name) => self
+                .get_request_headers()
+                .into_iter()
+                .find(|header| compare_no_case(&header.name, name.as_bytes()))
+                .map(|header| header.value),
+        }
+    }
+
+    /// the value hashed by the consistent hashing load balancing,
+    /// None if the request does not carry it
+    pub fn get_hash_key(&self, hash_key: &HashKey) -> Option<Vec<u8>> {
+        match hash_key {
+            HashKey::Url => self
+                .get_host()
+                .zip(self.get_request_line())
+                .map(|(host, line)| format!("{}{}", host, line.uri).into_bytes()),
+            HashKey::SourceIp => self.get_sticky_key(&StickyMode::SourceIp),
+            HashKey::Header(name) => self.get_sticky_key(&StickyMode::Header(name.to_owned())),
+            HashKey::Cookie(name) => self.get_request_cookie(name).map(String::into_bytes),
+        }
+    }
+
     pub fn get_backend_address(&self) -> Option<SocketAddr> {
         self.backend_data
             .as_ref()
@@ -559,7 +819,7 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
             .or_else(|| {
                 self.backend
                     .as_ref()
-                    .and_then(|backend| backend.peer_addr().ok())
+                    .and_then(|backend| backend.socket_ref().peer_addr().ok())
             })
     }
 
@@ -596,6 +856,45 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
         }
     }
 
+    /// counts the response in the passive health checks of the backend, the 5xx
+    /// statuses are failures
+    fn record_backend_response(&self) {
+        if let (Some(backend), Some(status_line)) =
+            (self.backend_data.as_ref(), self.get_response_status())
+        {
+            backend
+                .borrow_mut()
+                .record_outcome(self.cluster_id.as_deref(), status_line.status >= 500);
+        }
+    }
+
+    /// the request as sent to the clients tailing the access logs
+    fn access_log_record(
+        &self,
+        metrics: &SessionMetrics,
+        status: Option<u16>,
+        error: Option<&str>,
+    ) -> AccessLogRecord {
+        let request_line = self.get_request_line();
+        AccessLogRecord {
+            timestamp: 0,
+            cluster_id: self.cluster_id.clone(),
+            backend_id: metrics.backend_id.clone(),
+            session_address: self.get_session_address(),
+            backend_address: self.get_backend_address(),
+            protocol: self.protocol_string().to_string(),
+            host: self.get_host().map(String::from),
+            method: request_line.map(|line| line.method.to_string()),
+            path: request_line.map(|line| line.uri.clone()),
+            status,
+            response_time: metrics.response_time().whole_milliseconds() as u64,
+            service_time: metrics.service_time().whole_milliseconds() as u64,
+            bytes_in: metrics.bin,
+            bytes_out: metrics.bout,
+            error: error.map(String::from),
+        }
+    }
+
     pub fn log_request_success(&self, metrics: &SessionMetrics) {
         let session = SessionAddress(self.get_session_address());
         let backend = SessionAddress(self.get_backend_address());
@@ -635,6 +934,12 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
                     metrics.backend_bin,
                     metrics.backend_bout
                 );
+
+                if let Some(backend) = self.backend_data.as_ref() {
+                    backend
+                        .borrow_mut()
+                        .set_response_time(backend_response_time);
+                }
             }
         }
 
@@ -668,6 +973,10 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
             status_line,
             request_line
         );
+
+        tap_access_log(self.cluster_id.as_deref(), status_line.inner, || {
+            self.access_log_record(metrics, status_line.inner, None)
+        });
     }
 
     pub fn log_default_answer_success(&self, metrics: &SessionMetrics) {
@@ -736,6 +1045,10 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
             status_line,
             request_line
         );
+
+        tap_access_log(self.cluster_id.as_deref(), status_line.inner, || {
+            self.access_log_record(metrics, status_line.inner, None)
+        });
     }
 
     pub fn log_request_error(&mut self, metrics: &mut SessionMetrics, message: &str) {
@@ -813,6 +1126,10 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
             message,
             OptionalString::from(tags.as_ref())
         );
+
+        tap_access_log(self.cluster_id.as_deref(), status_line.inner, || {
+            self.access_log_record(metrics, status_line.inner, Some(message))
+        });
     }
 
     /// Read content from the session
@@ -854,9 +1171,17 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
             return SessionResult::Continue;
         }
 
-        let (size, socket_state) = self
-            .frontend
-            .socket_read(self.front_buf.as_mut().unwrap().buffer.space());
+        // the body is streamed to the backend, the client is not read too far ahead of it
+        let read_limit = self.body_read_limit();
+        if read_limit == Some(0) {
+            self.front_readiness.interest.remove(Ready::readable());
+            self.back_readiness.interest.insert(Ready::writable());
+            return SessionResult::Continue;
+        }
+
+        let space = self.front_buf.as_mut().unwrap().buffer.space();
+        let space_size = read_limit.map_or(space.len(), |limit| min(limit, space.len()));
+        let (size, socket_state) = self.frontend.socket_read(&mut space[..space_size]);
         debug!("{}\tFRONT: read {} bytes", self.log_context(), size);
 
         if size > 0 {
@@ -950,6 +1275,502 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
         self.readable_parse(metrics)
     }
 
+    /// how much of the request body can still be read before the backend gets the
+    /// data already buffered, if the limits cap it
+    fn body_read_limit(&self) -> Option<usize> {
+        let max_buffered_body = self.request_limits.max_buffered_body?;
+        self.backend_token?;
+        match self.request_state {
+            Some(RequestState::RequestWithBody(_, _, _, _))
+            | Some(RequestState::RequestWithBodyChunks(_, _, _, _)) => {}
+            _ => return None,
+        }
+
+        // the data parsed and waiting to be written, not a pipelined request
+        let buffered = self.front_buf.as_ref()?.parsed_data_size();
+        Some(max_buffered_body.max(1).saturating_sub(buffered))
+    }
+
+    /// parses the request in the front buffer, counting the lines of the headers
+    fn parse_request(&mut self) {
+        // avoid this unwrap
+        let front_buf = self.front_buf.as_mut().unwrap();
+        let parsing_start = front_buf.start_parsing_position;
+        let previous_header_end = self.req_header_end.take();
+
+        let (request_state, header_end) = parse_request_until_stop(
+            self.request_state.take().unwrap(),
+            previous_header_end,
+            front_buf,
+            self.added_req_header.as_ref(),
+            &self.sticky_name,
+        );
+
+        // the parsed headers are still in the buffer until they are written to the backend
+        if previous_header_end.is_none() {
+            let data = front_buf.buffer.data();
+            let parsed_end = header_end.unwrap_or(front_buf.start_parsing_position);
+            let start = min(
+                parsing_start.saturating_sub(front_buf.buffer_position),
+                data.len(),
+            );
+            let end = min(
+                parsed_end.saturating_sub(front_buf.buffer_position),
+                data.len(),
+            );
+            if start < end {
+                self.req_header_lines += data[start..end].iter().filter(|c| **c == b'\n').count();
+            }
+        }
+
+        self.request_state = Some(request_state);
+        self.req_header_end = header_end;
+    }
+
+    /// the answer to send if the request exceeds the limits
+    fn exceeded_request_limit(&self) -> Option<DefaultAnswerStatus> {
+        let limits = &self.request_limits;
+        let front_buf = self.front_buf.as_ref()?;
+
+        let (header_end, header_count) = match self.req_header_end {
+            // without the request line and the empty line ending the headers
+            Some(end) => (end, self.req_header_lines.saturating_sub(2)),
+            None => (
+                front_buf.start_parsing_position + front_buf.unparsed_data().len(),
+                self.req_header_lines.saturating_sub(1),
+            ),
+        };
+        let header_size = header_end.saturating_sub(self.request_start);
+        if matches!(limits.max_header_size, Some(max) if header_size > max)
+            || matches!(limits.max_header_count, Some(max) if header_count > max)
+        {
+            return Some(DefaultAnswerStatus::Answer431);
+        }
+
+        let body_size = match self.request_state {
+            Some(RequestState::RequestWithBody(_, _, _, length)) => length,
+            Some(RequestState::RequestWithBodyChunks(_, _, _, _)) => {
+                front_buf.start_parsing_position.saturating_sub(header_end)
+            }
+            _ => 0,
+        };
+        if matches!(limits.max_body_size, Some(max) if body_size > max) {
+            return Some(DefaultAnswerStatus::Answer413);
+        }
+
+        None
+    }
+
+    /// answers with a 431 or a 413 if the request exceeds the limits,
+    /// returns false in that case
+    pub fn check_request_limits(&mut self) -> bool {
+        match self.exceeded_request_limit() {
+            Some(answer) => {
+                self.set_answer(answer, None);
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// the limits of the cluster the request is routed to take precedence over
+    /// the ones of the listener, then the request is checked again
+    pub fn set_cluster_request_limits(&mut self, limits: &RequestLimits) -> bool {
+        self.request_limits = limits.or(&self.listener.borrow().get_request_limits());
+        self.check_request_limits()
+    }
+
+    /// merges the compression settings of the cluster with the listener's, and chooses
+    /// the encoding of the response while the request headers are still in the front buffer.
+    /// Compressed responses are chunked, so the request must be in HTTP/1.1
+    pub fn set_cluster_compression(&mut self, compression: &Compression) {
+        self.compression = compression.or(&self.listener.borrow().get_compression());
+
+        let is_head = self
+            .request_state
+            .as_ref()
+            .map(|request| request.is_head())
+            .unwrap_or(true);
+        self.response_encoding = match self.get_request_line() {
+            Some(request_line) if request_line.version == Version::V11 && !is_head => {
+                negotiate_encoding(&self.compression, &self.get_request_headers())
+            }
+            _ => None,
+        };
+    }
+
+    pub fn set_cluster_streaming(&mut self, streaming: bool) {
+        self.streaming = streaming;
+    }
+
+    /// keeps the protocols of the cluster, and removes the other ones from the
+    /// `Upgrade` header of the request, so that the backend cannot choose them
+    pub fn set_cluster_upgrades(&mut self, protocols: &[String]) {
+        self.upgrade_protocols = protocols.to_vec();
+
+        let offered = match self
+            .request_state
+            .as_ref()
+            .and_then(|request| request.get_keep_alive())
+            .and_then(|conn| conn.upgrade.clone())
+        {
+            Some(offered) => offered,
+            None => return,
+        };
+
+        let offered: Vec<&str> = offered
+            .split(',')
+            .map(str::trim)
+            .filter(|protocol| !protocol.is_empty())
+            .collect();
+        let allowed: Vec<&str> = offered
+            .iter()
+            .copied()
+            .filter(|protocol| self.upgrade_allowed(protocol))
+            .collect();
+        if allowed.len() == offered.len() {
+            return;
+        }
+
+        debug!(
+            "{}\tthe cluster does not allow upgrading to {:?}",
+            self.log_context(),
+            offered
+        );
+        incr!("http.upgrades.removed");
+        let added = if allowed.is_empty() {
+            Vec::new()
+        } else {
+            format!("Upgrade: {}\r\n", allowed.join(", ")).into_bytes()
+        };
+        self.edit_request_headers(&HeaderEdits {
+            removed: vec![b"Upgrade".to_vec()],
+            added,
+        });
+    }
+
+    /// WebSocket or a protocol of the cluster, which may leave out the version
+    fn upgrade_allowed(&self, protocol: &str) -> bool {
+        let name = protocol.split('/').next().unwrap_or(protocol);
+        compare_no_case(name.as_bytes(), b"websocket")
+            || self.upgrade_protocols.iter().any(|allowed| {
+                compare_no_case(allowed.as_bytes(), protocol.as_bytes())
+                    || compare_no_case(allowed.as_bytes(), name.as_bytes())
+            })
+    }
+
+    /// the backend accepted to switch the connection to WebSocket
+    pub fn is_websocket_upgrade(&self) -> bool {
+        matches!(
+            &self.response_state,
+            Some(ResponseState::ResponseUpgrade(_, _, protocol))
+                if compare_no_case(protocol.as_bytes(), b"websocket")
+        )
+    }
+
+    /// the backend answered with a 101: the connection becomes a pipe if the protocol
+    /// is allowed, otherwise the client gets a 502
+    fn switch_protocols(
+        &mut self,
+        metrics: &mut SessionMetrics,
+    ) -> (ProtocolResult, SessionResult) {
+        let protocol = match &self.response_state {
+            Some(ResponseState::ResponseUpgrade(_, _, protocol)) => protocol.clone(),
+            _ => return (ProtocolResult::Continue, SessionResult::Continue),
+        };
+        debug!("{}\tgot an upgrade to {:?}", self.log_context(), protocol);
+
+        if !self.upgrade_allowed(&protocol) {
+            error!(
+                "{}\tthe backend switched to {:?}, that the cluster does not allow",
+                self.log_context(),
+                protocol
+            );
+            incr!("http.upgrades.refused");
+            self.set_answer(DefaultAnswerStatus::Answer502, None);
+            return (ProtocolResult::Continue, self.writable(metrics));
+        }
+
+        if !self.is_websocket_upgrade() {
+            incr!("http.upgrades.other");
+        }
+        self.front_timeout.reset();
+        self.back_timeout.reset();
+        (ProtocolResult::Upgrade, SessionResult::Continue)
+    }
+
+    /// adds the security headers of the cluster, completed by the listener's, to the
+    /// response. Clients ignore `Strict-Transport-Security` over plain HTTP, so it is
+    /// only sent over HTTPS
+    pub fn set_cluster_security_headers(&mut self, security_headers: &SecurityHeaders) {
+        let mut security_headers =
+            security_headers.or(&self.listener.borrow().get_security_headers());
+        if self.protocol != Protocol::HTTPS {
+            security_headers.strict_transport_security = None;
+        }
+
+        self.response_header_edits
+            .extend(HeaderEdits::security_headers(&security_headers));
+    }
+
+    /// keeps a copy of the request to send it to another backend
+    /// if the retry policy of the cluster covers 502 or 503 responses
+    pub fn set_cluster_retries(&mut self, retries: &RequestRetries) {
+        self.retry_buffer = if retries.retries_on_status() {
+            Some(Vec::new())
+        } else {
+            None
+        };
+    }
+
+    /// the retry condition matched by the response, if the request can still be sent
+    /// to another backend: it was entirely written and kept, and nothing of the response
+    /// went to the client
+    pub fn retryable_response(&self) -> Option<RetryCondition> {
+        let condition = match self
+            .get_response_status()
+            .map(|status_line| status_line.status)
+        {
+            Some(502) => RetryCondition::Http502,
+            Some(503) => RetryCondition::Http503,
+            _ => return None,
+        };
+
+        let request_written = matches!(
+            self.request_state,
+            Some(RequestState::Request(_, _, _))
+                | Some(RequestState::RequestWithBody(_, _, _, _))
+                | Some(RequestState::RequestWithBodyChunks(_, _, _, Chunk::Ended))
+        ) && self
+            .front_buf
+            .as_ref()
+            .map(|buf| buf.output_data_size() == 0)
+            .unwrap_or(true);
+        let request_kept = self
+            .retry_buffer
+            .as_ref()
+            .map(|buf| !buf.is_empty())
+            .unwrap_or(false);
+        let response_unsent = self
+            .back_buf
+            .as_ref()
+            .map(|buf| buf.buffer_position == 0)
+            .unwrap_or(true);
+
+        if request_written && request_kept && response_unsent {
+            Some(condition)
+        } else {
+            None
+        }
+    }
+
+    /// drops the response of the backend and queues the request again,
+    /// to be written once connected to another backend
+    pub fn prepare_retry(&mut self) -> bool {
+        let request = match self.retry_buffer.take() {
+            Some(request) if !request.is_empty() => request,
+            _ => return false,
+        };
+        // the response is dropped, but the backend failed it
+        self.record_backend_response();
+
+        if self.front_buf.is_none() {
+            let buf = self
+                .pool
+                .upgrade()
+                .and_then(|pool| pool.borrow_mut().checkout());
+            match buf {
+                Some(buf) => self.front_buf = Some(BufferQueue::with_buffer(buf)),
+                None => {
+                    error!("cannot get front buffer from pool to retry the request");
+                    return false;
+                }
+            }
+        }
+
+        // pipelined requests may wait in the front buffer, the retried one goes first
+        if let Some(buf) = self.front_buf.as_mut() {
+            buf.output_queue.insert(0, OutputElement::Insert(request));
+        }
+
+        self.retry_buffer = Some(Vec::new());
+        self.back_buf = None;
+        self.response_state = Some(ResponseState::Initial);
+        self.res_header_end = None;
+        self.compressor = None;
+        self.dechunker = None;
+        self.chunker = None;
+        self.backend_stop = None;
+        self.front_readiness.interest.remove(Ready::writable());
+        true
+    }
+
+    /// once the response headers are parsed, replaces the body with its compressed version
+    /// if the response is large enough and of a compressible type
+    fn start_compression(&mut self) {
+        // a compressor holds data back until it has enough of it
+        if self.compressor.is_some() || self.streaming {
+            return;
+        }
+
+        let min_size = self
+            .compression
+            .min_size
+            .unwrap_or(DEFAULT_COMPRESSION_MIN_SIZE);
+        let (body_start, body_length) = match (&self.response_state, self.res_header_end) {
+            (Some(ResponseState::ResponseWithBody(status_line, _, length)), Some(header_end))
+                if status_line.status != 206 && *length >= min_size =>
+            {
+                (header_end, *length)
+            }
+            _ => return,
+        };
+
+        if let (Some(encoding), Some(buf)) = (self.response_encoding, self.back_buf.as_mut()) {
+            self.compressor = Compressor::for_response(buf, encoding, body_start, body_length);
+            if self.compressor.is_some() {
+                incr!("http.compressed_responses");
+            }
+        }
+    }
+
+    /// once the headers of a chunked response are parsed, removes the chunked
+    /// encoding if the client does not know it
+    fn start_dechunking(&mut self) {
+        if self.dechunker.is_some() {
+            return;
+        }
+
+        let body_start = match (&self.response_state, self.res_header_end) {
+            (Some(ResponseState::ResponseWithBodyChunks(_, _, _)), Some(header_end)) => header_end,
+            _ => return,
+        };
+        if !matches!(self.get_request_line(), Some(request_line) if request_line.version == Version::V10)
+        {
+            return;
+        }
+
+        if let Some(buf) = self.back_buf.as_mut() {
+            self.dechunker = Dechunker::for_response(buf, body_start);
+            if self.dechunker.is_some() {
+                incr!("http.dechunked_responses");
+            }
+        }
+    }
+
+    /// removes the chunk framing of the body parsed from the backend, returns false on error
+    fn dechunk_response_body(&mut self) -> bool {
+        match (self.dechunker.as_mut(), self.back_buf.as_mut()) {
+            (Some(dechunker), Some(buf)) => dechunker.filter(buf),
+            _ => true,
+        }
+    }
+
+    /// once the headers of a response ending with the backend connection are parsed,
+    /// chunks it if the HTTP/1.1 client would keep its connection
+    fn start_chunking(&mut self) {
+        if self.chunker.is_some() {
+            return;
+        }
+
+        let body_start = match (&self.response_state, self.res_header_end) {
+            (
+                Some(ResponseState::ResponseWithBodyCloseDelimited(status_line, _, false)),
+                Some(header_end),
+            ) if status_line.version == Version::V11 => header_end,
+            _ => return,
+        };
+        let front_keep_alive = matches!(self.get_request_line(), Some(request_line) if request_line.version == Version::V11)
+            && self
+                .request_state
+                .as_ref()
+                .map(|request| request.should_keep_alive())
+                .unwrap_or(false);
+        if !front_keep_alive {
+            return;
+        }
+
+        if let Some(buf) = self.back_buf.as_mut() {
+            self.chunker = Chunker::for_response(buf, body_start);
+            if self.chunker.is_some() {
+                incr!("http.chunked_responses");
+            }
+        }
+    }
+
+    /// adds the chunk framing to the body parsed from the backend, returns false on error
+    fn chunk_response_body(&mut self, back_closed: bool) -> bool {
+        match (self.chunker.as_mut(), self.back_buf.as_mut()) {
+            (Some(chunker), Some(buf)) => chunker.filter(buf, back_closed),
+            _ => true,
+        }
+    }
+
+    /// removes the chunk framing of the request body for the backends that need its
+    /// length. Returns true while the body is incomplete and must stay in the front
+    /// buffer, or if it was answered with a 400 for malformed chunks, or a 413 for
+    /// a body larger than the buffer
+    fn hold_request_body(&mut self) -> bool {
+        if self.request_limits.buffer_chunked_body != Some(true) {
+            return false;
+        }
+
+        let (body_start, ended) = match (&self.request_state, self.req_header_end) {
+            (Some(RequestState::RequestWithBodyChunks(_, _, _, chunk)), Some(header_end)) => {
+                (header_end, *chunk == Chunk::Ended)
+            }
+            _ => return false,
+        };
+        let buf = match self.front_buf.as_mut() {
+            Some(buf) => buf,
+            None => return false,
+        };
+
+        if self.request_dechunker.is_none() {
+            self.request_dechunker = Dechunker::for_request(buf, body_start);
+            if self.request_dechunker.is_some() {
+                incr!("http.dechunked_requests");
+            }
+        }
+        let dechunker = match self.request_dechunker.as_mut() {
+            Some(dechunker) => dechunker,
+            // other transfer codings are sent as is
+            None => return false,
+        };
+
+        if !dechunker.filter(buf) || (ended && !dechunker.finish(buf)) {
+            self.set_answer(DefaultAnswerStatus::Answer400, None);
+            return true;
+        }
+        if ended {
+            return false;
+        }
+
+        let max_buffered_body = self.request_limits.max_buffered_body.unwrap_or(usize::MAX);
+        if buf.buffer.available_space() == 0 || buf.parsed_data_size() >= max_buffered_body {
+            self.set_answer(DefaultAnswerStatus::Answer413, None);
+        }
+        true
+    }
+
+    /// compresses the body data read from the backend, returns false on error
+    fn compress_response_body(&mut self) -> bool {
+        match (self.compressor.as_mut(), self.back_buf.as_mut()) {
+            (Some(compressor), Some(buf)) => match compressor.filter(buf) {
+                Ok(()) => true,
+                Err(e) => {
+                    error!(
+                        "{}	could not compress the response: {}",
+                        self.log_context(),
+                        e
+                    );
+                    false
+                }
+            },
+            _ => true,
+        }
+    }
+
     pub fn readable_parse(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
         let is_initial = self.request_state == Some(RequestState::Initial);
         // if there's no host, continue parsing until we find it
@@ -959,20 +1780,7 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
             .map(|r| r.has_host())
             .unwrap_or(false);
         if !has_host {
-            let (request_state, header_end) = (
-                self.request_state.take().unwrap(),
-                self.req_header_end.take(),
-            );
-            let (request_state, header_end) = parse_request_until_stop(
-                request_state,
-                header_end,
-                self.front_buf.as_mut().unwrap(),
-                self.added_req_header.as_ref(),
-                &self.sticky_name,
-            );
-
-            self.request_state = Some(request_state);
-            self.req_header_end = header_end;
+            self.parse_request();
 
             if unwrap_msg!(self.request_state.as_ref()).is_front_error() {
                 incr!("http.front_parse_errors");
@@ -983,6 +1791,11 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
                 return SessionResult::Continue;
             }
 
+            if !self.check_request_limits() {
+                gauge_add!("http.active_requests", 1);
+                return SessionResult::Continue;
+            }
+
             let is_still_initial = self.request_state == Some(RequestState::Initial);
             if is_initial && !is_still_initial {
                 gauge_add!("http.active_requests", 1);
@@ -1035,20 +1848,7 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
                     .set_duration(self.frontend_timeout_duration);
 
                 if !self.front_buf.as_ref().unwrap().needs_input() {
-                    let (request_state, header_end) = (
-                        self.request_state.take().unwrap(),
-                        self.req_header_end.take(),
-                    );
-                    let (request_state, header_end) = parse_request_until_stop(
-                        request_state,
-                        header_end,
-                        self.front_buf.as_mut().unwrap(),
-                        self.added_req_header.as_ref(),
-                        &self.sticky_name,
-                    );
-
-                    self.request_state = Some(request_state);
-                    self.req_header_end = header_end;
+                    self.parse_request();
 
                     if unwrap_msg!(self.request_state.as_ref()).is_front_error() {
                         self.log_request_error(
@@ -1058,6 +1858,10 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
                         return SessionResult::CloseSession;
                     }
 
+                    if !self.check_request_limits() {
+                        return SessionResult::Continue;
+                    }
+
                     if let Some(RequestState::RequestWithBodyChunks(_, _, _, Chunk::Ended)) =
                         self.request_state
                     {
@@ -1069,27 +1873,17 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
             }
             // TODO: properly pattern match
             _ => {
-                let (request_state, header_end) = (
-                    // avoid this unwrap
-                    self.request_state.take().unwrap(),
-                    self.req_header_end.take(),
-                );
-                let (request_state, header_end) = parse_request_until_stop(
-                    request_state,
-                    header_end,
-                    self.front_buf.as_mut().unwrap(),
-                    self.added_req_header.as_ref(),
-                    &self.sticky_name,
-                );
-
-                self.request_state = Some(request_state);
-                self.req_header_end = header_end;
+                self.parse_request();
 
                 if unwrap_msg!(self.request_state.as_ref()).is_front_error() {
                     self.set_answer(DefaultAnswerStatus::Answer400, None);
                     return SessionResult::Continue;
                 }
 
+                if !self.check_request_limits() {
+                    return SessionResult::Continue;
+                }
+
                 if let Some(RequestState::Request(_, _, _)) = self.request_state {
                     self.front_readiness.interest.remove(Ready::readable());
                 }
@@ -1156,12 +1950,21 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
             return SessionResult::CloseSession;
         }
 
+        // a response without its chunks can end with framing, that was skipped
+        let dechunked_end = self.dechunker.is_some()
+            && matches!(
+                self.response_state,
+                Some(ResponseState::ResponseWithBodyChunks(_, _, Chunk::Ended))
+            )
+            && self.back_buf.as_ref().unwrap().output_queue.is_empty();
+
         let output_size = self.back_buf.as_ref().unwrap().output_data_size();
-        if self
-            .back_buf
-            .as_ref()
-            .map(|buf| buf.output_data_size() == 0 || buf.next_output_data().is_empty())
-            .unwrap()
+        if !dechunked_end
+            && self
+                .back_buf
+                .as_ref()
+                .map(|buf| buf.output_data_size() == 0 || buf.next_output_data().is_empty())
+                .unwrap()
         {
             self.back_readiness.interest.insert(Ready::readable());
             self.front_readiness.interest.remove(Ready::writable());
@@ -1209,6 +2012,11 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
         count!("bytes_out", sz as i64);
         metrics.bout += sz;
 
+        // the client may send nothing while a streamed response flows to it
+        if sz > 0 && self.is_streaming_body() {
+            self.front_timeout.reset();
+        }
+
         if let Some((front, back)) = self.tokens() {
             debug!(
                 "{}\tFRONT [{}<-{}]: wrote {} bytes of {}, buffer position {} restart position {}",
@@ -1234,11 +2042,21 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
             SocketResult::Continue => {}
         }
 
+        // the chunk framing removed at the end of a response follows its data
+        if self.dechunker.is_some() {
+            self.back_buf.as_mut().unwrap().consume_deleted_output();
+        }
+
         if !self.back_buf.as_ref().unwrap().can_restart_parsing() {
             self.back_readiness.interest.insert(Ready::readable());
             return SessionResult::Continue;
         }
 
+        // a compressed response ends with inserted data that may not be written yet
+        if self.back_buf.as_ref().unwrap().output_data_size() > 0 {
+            return SessionResult::Continue;
+        }
+
         //handle this case separately as its cumbersome to do from the pattern match
         if let Some(sz) = self.must_continue_response() {
             self.front_readiness.interest.insert(Ready::readable());
@@ -1274,21 +2092,38 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
 
         match self.response_state {
             // FIXME: should only restart parsing if we are using keepalive
+            // a response ending with the backend connection ends for the client
+            // with its last chunk
             Some(ResponseState::Response(_, _))
             | Some(ResponseState::ResponseWithBody(_, _, _))
-            | Some(ResponseState::ResponseWithBodyChunks(_, _, Chunk::Ended)) => {
-                let front_keep_alive = self
-                    .request_state
-                    .as_ref()
-                    .map(|r| r.should_keep_alive())
-                    .unwrap_or(false);
-                let back_keep_alive = self
-                    .response_state
-                    .as_ref()
-                    .map(|r| r.should_keep_alive())
-                    .unwrap_or(false);
+            | Some(ResponseState::ResponseWithBodyChunks(_, _, Chunk::Ended))
+            | Some(ResponseState::ResponseWithBodyCloseDelimited(_, _, true))
+                if !matches!(
+                    self.response_state,
+                    Some(ResponseState::ResponseWithBodyCloseDelimited(_, _, _))
+                ) || self.chunker.as_ref().is_some_and(Chunker::is_ended) =>
+            {
+                // without its chunks, the end of the response is the end of the connection
+                let front_keep_alive = self.dechunker.is_none()
+                    && self
+                        .request_state
+                        .as_ref()
+                        .map(|r| r.should_keep_alive())
+                        .unwrap_or(false);
+                let back_keep_alive = self.chunker.is_none()
+                    && self
+                        .response_state
+                        .as_ref()
+                        .map(|r| r.should_keep_alive())
+                        .unwrap_or(false);
+
+                save_http_status_metric(
+                    self.get_response_status(),
+                    self.cluster_id.as_deref(),
+                    self.backend_id.as_deref(),
+                );
 
-                save_http_status_metric(self.get_response_status());
+                self.record_backend_response();
 
                 self.log_request_success(metrics);
                 metrics.reset();
@@ -1333,7 +2168,12 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
             Some(ResponseState::ResponseWithBodyCloseDelimited(_, _, back_closed)) => {
                 self.back_readiness.interest.insert(Ready::readable());
                 if back_closed {
-                    save_http_status_metric(self.get_response_status());
+                    save_http_status_metric(
+                        self.get_response_status(),
+                        self.cluster_id.as_deref(),
+                        self.backend_id.as_deref(),
+                    );
+                    self.record_backend_response();
                     self.log_request_success(metrics);
 
                     SessionResult::CloseSession
@@ -1373,6 +2213,17 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
             return SessionResult::Continue;
         }
 
+        if self.hold_request_body() {
+            self.back_readiness.interest.remove(Ready::writable());
+            if let SessionStatus::DefaultAnswer(_, _, _) = self.status {
+                self.front_readiness.interest.remove(Ready::readable());
+                self.front_readiness.interest.insert(Ready::writable());
+            } else {
+                self.front_readiness.interest.insert(Ready::readable());
+            }
+            return SessionResult::Continue;
+        }
+
         if self
             .front_buf
             .as_ref()
@@ -1419,8 +2270,21 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
                 if bufs.is_empty() {
                     break;
                 }
-                let (current_sz, current_res) = sock.socket_write_vectored(&bufs);
+                let (current_sz, current_res) = if sock.has_vectored_writes() {
+                    sock.socket_write_vectored(&bufs)
+                } else {
+                    sock.socket_write(&bufs[0])
+                };
                 //println!("vectored io returned {:?}", (current_sz, current_res));
+                if let Some(mirror_buffer) = self.mirror_buffer.as_mut() {
+                    copy_written_data(mirror_buffer, &bufs, current_sz);
+                }
+                if let Some(retry_buffer) = self.retry_buffer.as_mut() {
+                    copy_written_data(retry_buffer, &bufs, current_sz);
+                    if retry_buffer.len() > RETRY_BUFFER_MAX_SIZE {
+                        self.retry_buffer = None;
+                    }
+                }
                 socket_result = current_res;
                 self.front_buf
                     .as_mut()
@@ -1465,7 +2329,9 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
         }
 
         // FIXME/ should read exactly as much data as needed
-        if self.front_buf.as_ref().unwrap().can_restart_parsing() {
+        let front_buf = self.front_buf.as_ref().unwrap();
+        // a retried request is inserted before the buffer data, wait until it is written
+        if front_buf.can_restart_parsing() && front_buf.output_data_size() == 0 {
             match self.request_state {
                 // the entire request was transmitted
                 Some(RequestState::Request(_, _, _))
@@ -1605,17 +2471,8 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
             return (ProtocolResult::Continue, SessionResult::CloseSession);
         }
 
-        // isolate that here because the "ref protocol" and the self.state = " make borrowing conflicts
-        if let Some(ResponseState::ResponseUpgrade(_, _, ref protocol)) = self.response_state {
-            debug!("got an upgrade state[{}]: {:?}", line!(), protocol);
-            if compare_no_case(protocol.as_bytes(), "websocket".as_bytes()) {
-                self.front_timeout.reset();
-                self.back_timeout.reset();
-                return (ProtocolResult::Upgrade, SessionResult::Continue);
-            } else {
-                //FIXME: should we upgrade to a pipe or send an error?
-                return (ProtocolResult::Continue, SessionResult::Continue);
-            }
+        if let Some(ResponseState::ResponseUpgrade(_, _, _)) = self.response_state {
+            return self.switch_protocols(metrics);
         }
 
         match self.response_state {
@@ -1627,6 +2484,10 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
                 (ProtocolResult::Continue, SessionResult::CloseSession)
             }
             Some(ResponseState::ResponseWithBody(_, _, _)) => {
+                if !self.compress_response_body() {
+                    self.log_request_error(metrics, "could not compress the response, closing");
+                    return (ProtocolResult::Continue, SessionResult::CloseSession);
+                }
                 self.front_readiness.interest.insert(Ready::writable());
                 if !self.back_buf.as_ref().unwrap().needs_input() {
                     metrics.backend_stop();
@@ -1683,7 +2544,7 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
                             header_end,
                             self.back_buf.as_mut().unwrap(),
                             is_head,
-                            &self.added_res_header,
+                            &self.response_header_edits,
                             &self.sticky_name,
                             sticky_session,
                             self.cluster_id.as_deref(),
@@ -1693,7 +2554,9 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
                         self.res_header_end = header_end;
                     }
 
-                    if unwrap_msg!(self.response_state.as_ref()).is_back_error() {
+                    if unwrap_msg!(self.response_state.as_ref()).is_back_error()
+                        || !self.dechunk_response_body()
+                    {
                         self.log_request_error(
                             metrics,
                             "back socket chunk parse error, closing connection",
@@ -1731,6 +2594,14 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
                         self.response_state = Some(ResponseState::ResponseWithBodyCloseDelimited(
                             rl, conn, true,
                         ));
+                        // the last chunk is left to write
+                        if !self.chunk_response_body(true) {
+                            self.log_request_error(
+                                metrics,
+                                "could not chunk the response, closing",
+                            );
+                            return (ProtocolResult::Continue, SessionResult::CloseSession);
+                        }
 
                         // if the back buffer is already empty, we can stop here
                         if self
@@ -1741,7 +2612,12 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
                             })
                             .unwrap()
                         {
-                            save_http_status_metric(self.get_response_status());
+                            save_http_status_metric(
+                                self.get_response_status(),
+                                self.cluster_id.as_deref(),
+                                self.backend_id.as_deref(),
+                            );
+                            self.record_backend_response();
                             self.log_request_success(metrics);
                             return (ProtocolResult::Continue, SessionResult::CloseSession);
                         }
@@ -1751,6 +2627,13 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
                             conn,
                             back_closed,
                         ));
+                        if !self.chunk_response_body(back_closed) {
+                            self.log_request_error(
+                                metrics,
+                                "could not chunk the response, closing",
+                            );
+                            return (ProtocolResult::Continue, SessionResult::CloseSession);
+                        }
                     }
                 }
 
@@ -1790,7 +2673,7 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
                         header_end,
                         self.back_buf.as_mut().unwrap(),
                         is_head,
-                        &self.added_res_header,
+                        &self.response_header_edits,
                         &self.sticky_name,
                         sticky_session,
                         self.cluster_id.as_deref(),
@@ -1815,18 +2698,29 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
                     self.back_readiness.interest.remove(Ready::readable());
                 }
 
-                if let Some(ResponseState::ResponseUpgrade(_, _, ref protocol)) =
-                    self.response_state
-                {
-                    debug!("got an upgrade state[{}]: {:?}", line!(), protocol);
-                    if compare_no_case(protocol.as_bytes(), "websocket".as_bytes()) {
-                        self.front_timeout.reset();
-                        self.back_timeout.reset();
-                        return (ProtocolResult::Upgrade, SessionResult::Continue);
-                    }
+                if let Some(ResponseState::ResponseUpgrade(_, _, _)) = self.response_state {
+                    return self.switch_protocols(metrics);
+                }
+
+                self.start_compression();
+                if !self.compress_response_body() {
+                    self.log_request_error(metrics, "could not compress the response, closing");
+                    return (ProtocolResult::Continue, SessionResult::CloseSession);
+                }
+
+                self.start_dechunking();
+                if !self.dechunk_response_body() {
+                    self.log_request_error(
+                        metrics,
+                        "back socket chunk parse error, closing connection",
+                    );
+                    return (ProtocolResult::Continue, SessionResult::CloseSession);
+                }
 
-                    //FIXME: should we upgrade to a pipe or send an error?
-                    return (ProtocolResult::Continue, SessionResult::Continue);
+                self.start_chunking();
+                if !self.chunk_response_body(false) {
+                    self.log_request_error(metrics, "could not chunk the response, closing");
+                    return (ProtocolResult::Continue, SessionResult::CloseSession);
                 }
 
                 self.front_readiness.interest.insert(Ready::writable());
@@ -1880,6 +2774,15 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
                     self.set_answer(DefaultAnswerStatus::Answer504, None);
                     self.writable(metrics)
                 }
+                TimeoutStatus::Response if self.is_streaming_body() => {
+                    // the next data from the backend arms the timeout again
+                    debug!(
+                        "{}\tno data from the backend, keeping the streamed response",
+                        self.log_context()
+                    );
+                    incr!("http.streaming.idle");
+                    SessionResult::Continue
+                }
                 TimeoutStatus::Response => {
                     error!(
                         "backend {:?} timeout while receiving response (cluster {:?})",
@@ -1895,6 +2798,17 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
         }
     }
 
+    /// the cluster streams its responses, and the body of the current one is being forwarded
+    fn is_streaming_body(&self) -> bool {
+        self.streaming
+            && matches!(
+                self.response_state,
+                Some(ResponseState::ResponseWithBody(_, _, _))
+                    | Some(ResponseState::ResponseWithBodyChunks(_, _, _))
+                    | Some(ResponseState::ResponseWithBodyCloseDelimited(_, _, _))
+            )
+    }
+
     pub fn cancel_timeouts(&mut self) {
         self.front_timeout.cancel();
         self.back_timeout.cancel();
@@ -1905,8 +2819,26 @@ impl<Front: SocketHandler, L: ListenerHandler> Http<Front, L> {
     }
 }
 
-/// Save the backend http response status code metric
-fn save_http_status_metric(rs_status_line: Option<&StatusLine>) {
+/// appends to `copy` the first `size` bytes of the slices written to a socket
+fn copy_written_data(copy: &mut Vec<u8>, bufs: &[IoSlice], size: usize) {
+    let mut remaining = size;
+    for buf in bufs.iter() {
+        if remaining == 0 {
+            break;
+        }
+        let size = remaining.min(buf.len());
+        copy.extend_from_slice(&buf[..size]);
+        remaining -= size;
+    }
+}
+
+/// Save the backend http response status code metric. The server errors are
+/// also counted by cluster and backend
+fn save_http_status_metric(
+    rs_status_line: Option<&StatusLine>,
+    cluster_id: Option<&str>,
+    backend_id: Option<&str>,
+) {
     if let Some(rs_status_line) = rs_status_line {
         match rs_status_line.status {
             100..=199 => {
@@ -1923,6 +2855,9 @@ fn save_http_status_metric(rs_status_line: Option<&StatusLine>) {
             }
             500..=599 => {
                 incr!("http.status.5xx");
+                if cluster_id.is_some() {
+                    incr!("http.status.5xx", cluster_id, backend_id);
+                }
             }
             _ => {
                 incr!("http.status.other");
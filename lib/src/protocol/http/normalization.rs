@@ -0,0 +1,202 @@
+use crate::sozu_command::proxy::{PathNormalization, TrailingSlash};
+
+/// the outcome of the normalization of a request URI
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NormalizedUri {
+    /// the URI is already normalized
+    Unchanged,
+    /// the request is routed and sent to the backend with this URI
+    Rewritten(String),
+    /// the client is redirected to this URI, to add or remove the trailing slash
+    Redirect(String),
+}
+
+/// normalizes the path of a request URI, the query is left untouched.
+/// URIs not starting with a slash, like `*` or the absolute form, are not modified
+pub fn normalize_uri(uri: &str, settings: &PathNormalization) -> NormalizedUri {
+    if !uri.starts_with('/') {
+        return NormalizedUri::Unchanged;
+    }
+
+    let (path, query) = match uri.find('?') {
+        Some(index) => uri.split_at(index),
+        None => (uri, ""),
+    };
+
+    let cleaned = clean_path(path, settings);
+    let normalized = match settings.trailing_slash {
+        TrailingSlash::Keep => cleaned.clone(),
+        TrailingSlash::Add if !cleaned.ends_with('/') => format!("{}/", cleaned),
+        TrailingSlash::Remove if cleaned.len() > 1 && cleaned.ends_with('/') => {
+            let trimmed = cleaned.trim_end_matches('/');
+            if trimmed.is_empty() {
+                String::from("/")
+            } else {
+                trimmed.to_string()
+            }
+        }
+        _ => cleaned.clone(),
+    };
+
+    if normalized != cleaned {
+        // browsers follow `//host/` or `/\host/` as a URL on another host,
+        // so the location starts with a single slash
+        let location = normalized.trim_start_matches(['/', '\\']);
+        NormalizedUri::Redirect(format!("/{}{}", location, query))
+    } else if normalized != path {
+        NormalizedUri::Rewritten(format!("{}{}", normalized, query))
+    } else {
+        NormalizedUri::Unchanged
+    }
+}
+
+/// merges the slashes and resolves the dot segments of a path starting with a slash,
+/// following the `remove_dot_segments` algorithm of RFC 3986
+fn clean_path(path: &str, settings: &PathNormalization) -> String {
+    if !settings.merge_slashes && !settings.resolve_dot_segments {
+        return path.to_string();
+    }
+
+    let segments: Vec<&str> = path[1..].split('/').collect();
+    let last_index = segments.len() - 1;
+    let mut output: Vec<&str> = Vec::with_capacity(segments.len());
+
+    for (index, segment) in segments.into_iter().enumerate() {
+        let is_last = index == last_index;
+        if settings.merge_slashes && segment.is_empty() && !is_last {
+            continue;
+        }
+
+        if settings.resolve_dot_segments && is_dot_segment(segment) {
+            if is_last {
+                output.push("");
+            }
+            continue;
+        }
+
+        if settings.resolve_dot_segments && is_double_dot_segment(segment) {
+            output.pop();
+            if is_last {
+                output.push("");
+            }
+            continue;
+        }
+
+        output.push(segment);
+    }
+
+    format!("/{}", output.join("/"))
+}
+
+fn is_dot_segment(segment: &str) -> bool {
+    segment == "." || segment.eq_ignore_ascii_case("%2e")
+}
+
+fn is_double_dot_segment(segment: &str) -> bool {
+    segment == ".."
+        || segment.eq_ignore_ascii_case(".%2e")
+        || segment.eq_ignore_ascii_case("%2e.")
+        || segment.eq_ignore_ascii_case("%2e%2e")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(trailing_slash: TrailingSlash) -> PathNormalization {
+        PathNormalization {
+            merge_slashes: true,
+            resolve_dot_segments: true,
+            trailing_slash,
+        }
+    }
+
+    #[test]
+    fn clean_paths() {
+        let clean = settings(TrailingSlash::Keep);
+        let rewritten = |uri: &str| NormalizedUri::Rewritten(uri.to_string());
+
+        assert_eq!(normalize_uri("/a/b", &clean), NormalizedUri::Unchanged);
+        assert_eq!(normalize_uri("/", &clean), NormalizedUri::Unchanged);
+        assert_eq!(normalize_uri("*", &clean), NormalizedUri::Unchanged);
+        assert_eq!(normalize_uri("//a///b/", &clean), rewritten("/a/b/"));
+        assert_eq!(normalize_uri("/a/./b/../c", &clean), rewritten("/a/c"));
+        assert_eq!(
+            normalize_uri("/public/../admin", &clean),
+            rewritten("/admin")
+        );
+        assert_eq!(
+            normalize_uri("/public/%2E%2e/admin", &clean),
+            rewritten("/admin")
+        );
+        assert_eq!(normalize_uri("/../../a", &clean), rewritten("/a"));
+        assert_eq!(normalize_uri("/a/b/..", &clean), rewritten("/a/"));
+        assert_eq!(
+            normalize_uri("/a//b?next=//c/../d", &clean),
+            rewritten("/a/b?next=//c/../d")
+        );
+
+        let only_slashes = PathNormalization {
+            merge_slashes: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            normalize_uri("//a/../b", &only_slashes),
+            rewritten("/a/../b")
+        );
+        assert_eq!(
+            normalize_uri("//a/../b", &PathNormalization::default()),
+            NormalizedUri::Unchanged
+        );
+    }
+
+    #[test]
+    fn trailing_slash_redirects() {
+        let add = settings(TrailingSlash::Add);
+        assert_eq!(
+            normalize_uri("/a//b?c=d", &add),
+            NormalizedUri::Redirect("/a/b/?c=d".to_string())
+        );
+        assert_eq!(normalize_uri("/a/b/", &add), NormalizedUri::Unchanged);
+
+        let remove = settings(TrailingSlash::Remove);
+        assert_eq!(
+            normalize_uri("/a/b/", &remove),
+            NormalizedUri::Redirect("/a/b".to_string())
+        );
+        assert_eq!(normalize_uri("/", &remove), NormalizedUri::Unchanged);
+        assert_eq!(
+            normalize_uri("//", &remove),
+            NormalizedUri::Rewritten("/".to_string())
+        );
+    }
+
+    #[test]
+    fn redirects_stay_on_the_host() {
+        let add = PathNormalization {
+            trailing_slash: TrailingSlash::Add,
+            ..Default::default()
+        };
+        assert_eq!(
+            normalize_uri("//evil.example", &add),
+            NormalizedUri::Redirect("/evil.example/".to_string())
+        );
+        assert_eq!(
+            normalize_uri("/\\evil.example?a=b", &add),
+            NormalizedUri::Redirect("/evil.example/?a=b".to_string())
+        );
+
+        let remove = PathNormalization {
+            trailing_slash: TrailingSlash::Remove,
+            ..Default::default()
+        };
+        assert_eq!(
+            normalize_uri("//evil.example/", &remove),
+            NormalizedUri::Redirect("/evil.example".to_string())
+        );
+        assert_eq!(
+            normalize_uri("///evil.example//", &remove),
+            NormalizedUri::Redirect("/evil.example".to_string())
+        );
+    }
+}
@@ -26,6 +26,7 @@ use nom::{
 };
 
 use super::cookies::{parse_request_cookies, RequestCookie};
+use crate::sozu_command::proxy::{HeaderAction, HeaderOperation, HeaderPosition, SecurityHeaders};
 
 pub use self::{request::*, response::*};
 
@@ -53,6 +54,11 @@ fn is_token_char(i: u8) -> bool {
     is_alphanumeric(i) || b"!#$%&'*+-.^_`|~".contains(&i)
 }
 
+/// checks a method or a header name
+pub fn is_token(s: &[u8]) -> bool {
+    !s.is_empty() && s.iter().all(|c| is_token_char(*c))
+}
+
 fn token(i: &[u8]) -> IResult<&[u8], &[u8]> {
     take_while(is_token_char)(i)
 }
@@ -268,12 +274,115 @@ fn status_line(i: &[u8]) -> IResult<&[u8], RawStatusLine> {
     ))
 }
 
+/// parses the status line and the headers of a response, up to the empty line
+pub fn response_head(i: &[u8]) -> IResult<&[u8], (RawStatusLine, Vec<Header>)> {
+    let (mut i, status) = status_line(i)?;
+    let mut headers = Vec::new();
+
+    loop {
+        if let Ok((i, _)) = crlf(i) {
+            return Ok((i, (status, headers)));
+        }
+
+        let (remaining, header) = message_header(i)?;
+        headers.push(header);
+        i = remaining;
+    }
+}
+
 #[derive(PartialEq, Eq, Debug)]
 pub struct Header {
     pub name: Vec<u8>,
     pub value: Vec<u8>,
 }
 
+/// the header actions of a cluster for one direction, prepared for the parser
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderEdits {
+    /// names of the headers to remove
+    pub removed: Vec<Vec<u8>>,
+    /// headers to add, already formatted as `Name: value\r\n`
+    pub added: Vec<u8>,
+}
+
+impl HeaderEdits {
+    pub fn new(actions: &[HeaderAction], position: HeaderPosition) -> Self {
+        let mut edits = HeaderEdits::default();
+
+        for action in actions.iter().filter(|action| action.position == position) {
+            if action.operation != HeaderOperation::Add {
+                edits.removed.push(action.name.as_bytes().to_vec());
+            }
+            if action.operation != HeaderOperation::Remove {
+                edits
+                    .added
+                    .extend(format!("{}: {}\r\n", action.name, action.value).as_bytes());
+            }
+        }
+
+        edits
+    }
+
+    /// replaces the Host header of a request
+    pub fn host(host: &str) -> Self {
+        HeaderEdits {
+            removed: vec![b"Host".to_vec()],
+            added: format!("Host: {}\r\n", host).into_bytes(),
+        }
+    }
+
+    /// sets the header carrying the subject of the client certificate. The header
+    /// sent by the client is removed even without certificate, so it cannot be forged
+    pub fn client_dn(name: &str, subject: Option<&str>) -> Self {
+        HeaderEdits {
+            removed: vec![name.as_bytes().to_vec()],
+            added: subject
+                .map(|subject| format!("{}: {}\r\n", name, subject).into_bytes())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// sets the security headers of the response, replacing those sent by the backend
+    pub fn security_headers(headers: &SecurityHeaders) -> Self {
+        let mut edits = HeaderEdits::default();
+
+        let headers = [
+            (
+                "Strict-Transport-Security",
+                &headers.strict_transport_security,
+            ),
+            ("X-Content-Type-Options", &headers.content_type_options),
+            ("X-Frame-Options", &headers.frame_options),
+        ];
+        for (name, value) in headers.iter() {
+            if let Some(value) = value {
+                edits.removed.push(name.as_bytes().to_vec());
+                edits
+                    .added
+                    .extend(format!("{}: {}\r\n", name, value).as_bytes());
+            }
+        }
+
+        edits
+    }
+
+    /// appends the edits of `other` to these ones
+    pub fn extend(&mut self, other: HeaderEdits) {
+        self.removed.extend(other.removed);
+        self.added.extend(other.added);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.added.is_empty()
+    }
+
+    pub fn should_remove(&self, name: &[u8]) -> bool {
+        self.removed
+            .iter()
+            .any(|removed| compare_no_case(removed, name))
+    }
+}
+
 #[cfg(feature = "tolerant-http1-parser")]
 fn message_header(i: &[u8]) -> IResult<&[u8], Header> {
     // ToDo handle folding?
@@ -490,6 +599,15 @@ pub fn hostname_and_port(i: &[u8]) -> IResult<&[u8], (&[u8], Option<&[u8]>)> {
     Ok((i, (host, port)))
 }
 
+/// the hostname of a Host header as the frontends and the ACLs match it:
+/// without its port, in lowercase. None if the header is not a valid host
+pub fn normalized_hostname(host: &str) -> Option<String> {
+    match hostname_and_port(host.as_bytes()) {
+        Ok((&[], (hostname, _))) => from_utf8(hostname).ok().map(str::to_ascii_lowercase),
+        _ => None,
+    }
+}
+
 pub fn is_hex_digit(chr: u8) -> bool {
     (0x30..=0x39).contains(&chr) || (0x41..=0x46).contains(&chr) || (0x61..=0x66).contains(&chr)
 }
@@ -507,7 +625,7 @@ pub fn chunk_size(input: &[u8]) -> IResult<&[u8], usize> {
     }
 }
 
-fn chunk_header(i: &[u8]) -> IResult<&[u8], usize> {
+pub fn chunk_header(i: &[u8]) -> IResult<&[u8], usize> {
     terminated(chunk_size, crlf)(i)
 }
 
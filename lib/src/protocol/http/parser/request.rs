@@ -347,6 +347,33 @@ pub fn parse_header<'a>(
     }
 }
 
+/// parses again the request line and headers at the start of a buffer,
+/// returning the headers found before the end of the header section
+/// or the end of the available data
+pub fn parse_request_headers(buf: &[u8]) -> Vec<Header> {
+    parse_request_header_positions(buf)
+        .map(|(_, headers)| headers.into_iter().map(|(_, _, header)| header).collect())
+        .unwrap_or_default()
+}
+
+/// a header along with its offset and length in the buffer
+pub type LocatedHeader = (usize, usize, Header);
+
+/// like `parse_request_headers`, also returning where the headers start in the buffer,
+/// and the position and length of each header
+pub fn parse_request_header_positions(buf: &[u8]) -> Option<(usize, Vec<LocatedHeader>)> {
+    let (mut i, _) = request_line(buf).ok()?;
+    let headers_start = buf.offset(i);
+
+    let mut headers = Vec::new();
+    while let Ok((rest, header)) = message_header(i) {
+        headers.push((buf.offset(i), i.offset(rest), header));
+        i = rest;
+    }
+
+    Some((headers_start, headers))
+}
+
 /// a request parsing step, depending on the request state
 pub fn parse_request(
     state: RequestState,
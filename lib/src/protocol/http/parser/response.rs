@@ -5,8 +5,8 @@ use nom::{Err, HexDisplay, IResult, Offset};
 use crate::{buffer_queue::BufferQueue, protocol::http::StickySession};
 
 use super::{
-    crlf, message_header, status_line, BufferMove, Chunk, Connection, Header, HeaderValue,
-    LengthInformation, StatusLine, TransferEncodingValue, Version,
+    crlf, message_header, status_line, BufferMove, Chunk, Connection, Header, HeaderEdits,
+    HeaderValue, LengthInformation, LocatedHeader, StatusLine, TransferEncodingValue, Version,
 };
 
 pub type UpgradeProtocol = String;
@@ -232,10 +232,18 @@ pub fn validate_response_header(
             trace!("parsed a protocol: {:?}", proto);
             trace!("state is {:?}", state);
             match state {
-                ResponseState::HasStatusLine(sl, mut conn) => {
+                ResponseState::HasStatusLine(sl, mut conn) if sl.status == 101 => {
                     conn.upgrade = Some(proto.clone());
                     ResponseState::HasUpgrade(sl, conn, proto)
                 }
+                // other responses only advertise the protocols the server knows
+                s if s
+                    .get_status_line()
+                    .map(|sl| sl.status != 101)
+                    .unwrap_or(false) =>
+                {
+                    s
+                }
                 s => s.into_error(),
             }
         }
@@ -262,6 +270,7 @@ pub fn parse_response(
     buf: &[u8],
     is_head: bool,
     sticky_name: &str,
+    header_edits: &HeaderEdits,
     cluster_id: Option<&str>,
 ) -> (BufferMove, ResponseState) {
     match state {
@@ -294,7 +303,9 @@ pub fn parse_response(
         ResponseState::HasStatusLine(sl, conn) => {
             match message_header(buf) {
                 Ok((i, header)) => {
-                    let buffer_move = if header.should_delete(&conn, sticky_name) {
+                    let buffer_move = if header.should_delete(&conn, sticky_name)
+                        || header_edits.should_remove(&header.name)
+                    {
                         BufferMove::Delete(buf.offset(i))
                     } else {
                         BufferMove::Advance(buf.offset(i))
@@ -343,7 +354,9 @@ pub fn parse_response(
         ResponseState::HasLength(sl, conn, length) => {
             match message_header(buf) {
                 Ok((i, header)) => {
-                    let buffer_move = if header.should_delete(&conn, sticky_name) {
+                    let buffer_move = if header.should_delete(&conn, sticky_name)
+                        || header_edits.should_remove(&header.name)
+                    {
                         BufferMove::Delete(buf.offset(i))
                     } else {
                         BufferMove::Advance(buf.offset(i))
@@ -383,7 +396,9 @@ pub fn parse_response(
         }
         ResponseState::HasUpgrade(sl, conn, protocol) => match message_header(buf) {
             Ok((i, header)) => {
-                let buffer_move = if header.should_delete(&conn, sticky_name) {
+                let buffer_move = if header.should_delete(&conn, sticky_name)
+                    || header_edits.should_remove(&header.name)
+                {
                     BufferMove::Delete(buf.offset(i))
                 } else {
                     BufferMove::Advance(buf.offset(i))
@@ -437,12 +452,25 @@ pub fn parse_response(
     }
 }
 
+/// the headers of the response at the start of the buffer, with their position and length
+pub fn parse_response_header_positions(buf: &[u8]) -> Option<Vec<LocatedHeader>> {
+    let (mut i, _) = status_line(buf).ok()?;
+
+    let mut headers = Vec::new();
+    while let Ok((rest, header)) = message_header(i) {
+        headers.push((buf.offset(i), i.offset(rest), header));
+        i = rest;
+    }
+
+    Some(headers)
+}
+
 pub fn parse_response_until_stop(
     mut current_state: ResponseState,
     mut header_end: Option<usize>,
     buf: &mut BufferQueue,
     is_head: bool,
-    added_res_header: &str,
+    header_edits: &HeaderEdits,
     sticky_name: &str,
     sticky_session: Option<&StickySession>,
     cluster_id: Option<&str>,
@@ -454,6 +482,7 @@ pub fn parse_response_until_stop(
             buf.unparsed_data(),
             is_head,
             sticky_name,
+            header_edits,
             cluster_id,
         );
         //trace!("PARSER\tinput:\n{}\nmv: {:?}, new state: {:?}\n", buf.unparsed_data().to_hex(16), mv, new_state);
@@ -470,7 +499,7 @@ pub fn parse_response_until_stop(
                         ResponseState::Response(_, _)
                         | ResponseState::ResponseUpgrade(_, _, _)
                         | ResponseState::ResponseWithBodyChunks(_, _, _) => {
-                            buf.insert_output(Vec::from(added_res_header.as_bytes()));
+                            buf.insert_output(header_edits.added.clone());
                             add_sticky_session_to_response(buf, sticky_name, sticky_session);
 
                             buf.consume_parsed_data(sz);
@@ -479,7 +508,7 @@ pub fn parse_response_until_stop(
                             buf.slice_output(sz);
                         }
                         ResponseState::ResponseWithBody(_, _, content_length) => {
-                            buf.insert_output(Vec::from(added_res_header.as_bytes()));
+                            buf.insert_output(header_edits.added.clone());
                             add_sticky_session_to_response(buf, sticky_name, sticky_session);
 
                             buf.consume_parsed_data(sz);
@@ -489,7 +518,7 @@ pub fn parse_response_until_stop(
                             buf.consume_parsed_data(content_length);
                         }
                         ResponseState::ResponseWithBodyCloseDelimited(_, ref conn, _) => {
-                            buf.insert_output(Vec::from(added_res_header.as_bytes()));
+                            buf.insert_output(header_edits.added.clone());
                             add_sticky_session_to_response(buf, sticky_name, sticky_session);
 
                             // special case: some servers send responses with no body,
@@ -528,14 +557,14 @@ pub fn parse_response_until_stop(
                         | ResponseState::ResponseWithBodyChunks(_, _, _) => {
                             //println!("FOUND HEADER END (delete):{}", buf.start_parsing_position);
                             header_end = Some(buf.start_parsing_position);
-                            buf.insert_output(Vec::from(added_res_header.as_bytes()));
+                            buf.insert_output(header_edits.added.clone());
                             add_sticky_session_to_response(buf, sticky_name, sticky_session);
 
                             buf.delete_output(length);
                         }
                         ResponseState::ResponseWithBody(_, _, content_length) => {
                             header_end = Some(buf.start_parsing_position);
-                            buf.insert_output(Vec::from(added_res_header.as_bytes()));
+                            buf.insert_output(header_edits.added.clone());
                             buf.delete_output(length);
 
                             add_sticky_session_to_response(buf, sticky_name, sticky_session);
@@ -963,7 +963,7 @@ fn parse_response_and_chunks_partial_test() {
         None,
         &mut buf,
         false,
-        "",
+        &HeaderEdits::default(),
         "SOZUBALANCEID",
         None,
         None,
@@ -995,7 +995,7 @@ fn parse_response_and_chunks_partial_test() {
         result.1,
         &mut buf,
         false,
-        "",
+        &HeaderEdits::default(),
         "SOZUBALANCEID",
         None,
         None,
@@ -1027,7 +1027,7 @@ fn parse_response_and_chunks_partial_test() {
         result.1,
         &mut buf,
         false,
-        "",
+        &HeaderEdits::default(),
         "SOZUBALANCEID",
         None,
         None,
@@ -1058,7 +1058,7 @@ fn parse_response_and_chunks_partial_test() {
         result.1,
         &mut buf,
         false,
-        "",
+        &HeaderEdits::default(),
         "SOZUBALANCEID",
         None,
         None,
@@ -1117,7 +1117,7 @@ fn parse_incomplete_chunk_header_test() {
         None,
         &mut buf,
         false,
-        "",
+        &HeaderEdits::default(),
         "SOZUBALANCEID",
         None,
         None,
@@ -1155,7 +1155,7 @@ fn parse_incomplete_chunk_header_test() {
         result.1,
         &mut buf,
         false,
-        "",
+        &HeaderEdits::default(),
         "SOZUBALANCEID",
         None,
         None,
@@ -1188,7 +1188,7 @@ fn parse_incomplete_chunk_header_test() {
         result.1,
         &mut buf,
         false,
-        "",
+        &HeaderEdits::default(),
         "SOZUBALANCEID",
         None,
         None,
@@ -1217,7 +1217,7 @@ fn parse_incomplete_chunk_header_test() {
         result.1,
         &mut buf,
         false,
-        "",
+        &HeaderEdits::default(),
         "SOZUBALANCEID",
         None,
         None,
@@ -1259,7 +1259,10 @@ fn parse_response_302() {
         None,
         &mut buf,
         false,
-        "Sozu-Id: 123456789\r\n",
+        &HeaderEdits {
+            removed: Vec::new(),
+            added: b"Sozu-Id: 123456789\r\n".to_vec(),
+        },
         "SOZUBALANCEID",
         None,
         None,
@@ -1299,6 +1302,154 @@ fn parse_response_302() {
     );
 }
 
+#[test]
+fn parse_response_upgrade() {
+    let parse = |input: &[u8]| {
+        let (_pool, mut buf) = buf_with_capacity(2048);
+        buf.write(input).unwrap();
+        parse_response_until_stop(
+            ResponseState::Initial,
+            None,
+            &mut buf,
+            false,
+            &HeaderEdits::default(),
+            "SOZUBALANCEID",
+            None,
+            None,
+        )
+        .0
+    };
+
+    // only a 101 switches the protocol
+    let state = parse(
+        b"HTTP/1.1 101 Switching Protocols\r\n\
+        Upgrade: h2c\r\n\
+        Connection: Upgrade\r\n\
+        \r\n",
+    );
+    assert!(
+        matches!(&state, ResponseState::ResponseUpgrade(sl, _, protocol) if sl.status == 101 && protocol == "h2c"),
+        "{:?}",
+        state
+    );
+
+    // the other responses may advertise the protocols of the server
+    let state = parse(
+        b"HTTP/1.1 200 OK\r\n\
+        Upgrade: h2c\r\n\
+        Connection: Upgrade\r\n\
+        Content-Length: 5\r\n\
+        \r\n\
+        hello",
+    );
+    assert!(
+        matches!(&state, ResponseState::ResponseWithBody(sl, _, 5) if sl.status == 200),
+        "{:?}",
+        state
+    );
+}
+
+#[test]
+fn parse_response_header_edits() {
+    let input = b"HTTP/1.1 302 Found\r\n\
+        Server: nginx\r\n\
+        Content-length: 0\r\n\
+        \r\n";
+    let initial = ResponseState::Initial;
+    let (_pool, mut buf) = buf_with_capacity(2048);
+    buf.write_all(&input[..]).unwrap();
+
+    let actions = vec![
+        HeaderAction {
+            position: HeaderPosition::Response,
+            operation: HeaderOperation::Remove,
+            name: String::from("server"),
+            value: String::new(),
+        },
+        HeaderAction {
+            position: HeaderPosition::Response,
+            operation: HeaderOperation::Add,
+            name: String::from("X-Served-By"),
+            value: String::from("sozu"),
+        },
+        HeaderAction {
+            position: HeaderPosition::Request,
+            operation: HeaderOperation::Remove,
+            name: String::from("Content-length"),
+            value: String::new(),
+        },
+    ];
+    let edits = HeaderEdits::new(&actions, HeaderPosition::Response);
+
+    let result = parse_response_until_stop(
+        initial,
+        None,
+        &mut buf,
+        false,
+        &edits,
+        "SOZUBALANCEID",
+        None,
+        None,
+    );
+    println!("result: {:?}", result);
+    println!("buffer output: {:?}", buf.output_queue);
+    assert_eq!(
+        buf.output_queue,
+        vec!(
+            OutputElement::Slice(20),
+            OutputElement::Delete(15),
+            OutputElement::Slice(19),
+            OutputElement::Insert(Vec::from(&b"X-Served-By: sozu\r\n"[..])),
+            OutputElement::Slice(2)
+        )
+    );
+    assert_eq!(result.1, Some(56));
+}
+
+#[test]
+fn parse_response_security_headers() {
+    let input = b"HTTP/1.1 302 Found\r\n\
+        X-Frame-Options: SAMEORIGIN\r\n\
+        Content-length: 0\r\n\
+        \r\n";
+    let initial = ResponseState::Initial;
+    let (_pool, mut buf) = buf_with_capacity(2048);
+    buf.write_all(&input[..]).unwrap();
+
+    let security_headers = SecurityHeaders {
+        strict_transport_security: None,
+        content_type_options: Some(String::from("nosniff")),
+        frame_options: Some(String::from("DENY")),
+    };
+    let edits = HeaderEdits::security_headers(&security_headers);
+
+    let result = parse_response_until_stop(
+        initial,
+        None,
+        &mut buf,
+        false,
+        &edits,
+        "SOZUBALANCEID",
+        None,
+        None,
+    );
+    println!("result: {:?}", result);
+    println!("buffer output: {:?}", buf.output_queue);
+    assert_eq!(
+        buf.output_queue,
+        vec!(
+            OutputElement::Slice(20),
+            OutputElement::Delete(29),
+            OutputElement::Slice(19),
+            OutputElement::Insert(Vec::from(
+                &b"X-Content-Type-Options: nosniff\r\nX-Frame-Options: DENY\r\n"[..]
+            )),
+            OutputElement::Slice(2)
+        )
+    );
+    assert_eq!(result.1, Some(70));
+}
+
 #[test]
 fn parse_response_303() {
     let input = b"HTTP/1.1 303 See Other\r\n\
@@ -1317,7 +1468,10 @@ fn parse_response_303() {
         None,
         &mut buf,
         false,
-        "Sozu-Id: 123456789\r\n",
+        &HeaderEdits {
+            removed: Vec::new(),
+            added: b"Sozu-Id: 123456789\r\n".to_vec(),
+        },
         "SOZUBALANCEID",
         None,
         None,
@@ -1374,7 +1528,7 @@ fn parse_response_304() {
         None,
         &mut buf,
         is_head,
-        "",
+        &HeaderEdits::default(),
         "SOZUBALANCEID",
         None,
         None,
@@ -1436,6 +1590,24 @@ fn hostname_parsing_test() {
     );
 }
 
+#[test]
+fn normalized_hostname_test() {
+    assert_eq!(
+        normalized_hostname("admin.example.com"),
+        Some(String::from("admin.example.com"))
+    );
+    assert_eq!(
+        normalized_hostname("admin.example.com:8080"),
+        Some(String::from("admin.example.com"))
+    );
+    assert_eq!(
+        normalized_hostname("ADMIN.Example.com:443"),
+        Some(String::from("admin.example.com"))
+    );
+    assert_eq!(normalized_hostname("admin.example.com:"), None);
+    assert_eq!(normalized_hostname("admin/example.com"), None);
+}
+
 #[test]
 #[cfg(not(feature = "tolerant-http1-parser"))]
 fn hostname_parsing_underscore_test() {
@@ -1474,7 +1646,7 @@ fn parse_state_head_with_content_length_test() {
         None,
         &mut buf,
         is_head,
-        "",
+        &HeaderEdits::default(),
         "SOZUBALANCEID",
         None,
         None,
@@ -0,0 +1,363 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use mio::{Registry, Token};
+use time::{Duration, Instant};
+
+use crate::{
+    backends::BackendMap,
+    protocol::{http::Http, mirror::Mirror},
+    server::SessionManager,
+    socket::SocketHandler,
+    sozu_command::proxy::{
+        Cluster, HostRewrite, RequestRetries, RetryCondition, Timeouts, WebSocketDrain,
+    },
+    Backend, BackendConnectAction, ClusterId, ConnectionError, ListenerHandler, ProxySession,
+    SessionMetrics, SessionResult,
+};
+
+/// the parts of the HTTP and HTTPS proxies read by their sessions
+pub trait HttpProxy {
+    fn clusters(&self) -> &HashMap<ClusterId, Cluster>;
+    fn backends(&self) -> &Rc<RefCell<BackendMap>>;
+    fn registry(&self) -> &Registry;
+    fn sessions(&self) -> &Rc<RefCell<SessionManager>>;
+}
+
+/// how the current request of a session goes through its cluster:
+/// the backends it tried, its place in the queue, the session limits
+/// counting the session, and the mirror receiving a copy of it
+#[derive(Default)]
+pub struct RequestRouting {
+    pub connection_attempt: u8,
+    /// backends the current request failed on
+    pub tried_backends: Vec<String>,
+    /// the current request waits in the queue of its cluster since
+    pub queued_since: Option<Instant>,
+    pub mirror: Option<Mirror>,
+    pub mirror_cluster_id: Option<ClusterId>,
+    /// listener counting the session in its session limit, none if the worker or
+    /// the listener held too many sessions already: the requests get a 503
+    pub counted_listener: Option<Token>,
+    /// cluster counting the session in its session limit
+    pub counted_cluster: Option<ClusterId>,
+}
+
+/// the handling of the clusters and backends shared by the HTTP and HTTPS sessions
+pub trait HttpSession {
+    type Front: SocketHandler;
+    type Listener: ListenerHandler;
+    type Proxy: HttpProxy;
+
+    fn proxy(&self) -> &Rc<RefCell<Self::Proxy>>;
+    fn http(&self) -> Option<&Http<Self::Front, Self::Listener>>;
+    fn http_mut(&mut self) -> Option<&mut Http<Self::Front, Self::Listener>>;
+    fn frontend_token(&self) -> Token;
+    fn cluster_id(&self) -> Option<&str>;
+    fn backend(&self) -> Option<&Rc<RefCell<Backend>>>;
+    fn metrics(&self) -> &SessionMetrics;
+    fn routing(&self) -> &RequestRouting;
+    fn routing_mut(&mut self) -> &mut RequestRouting;
+    /// front timeout of the listener
+    fn frontend_timeout_duration(&self) -> Duration;
+    /// back timeout of the listener
+    fn backend_timeout_duration(&self) -> Duration;
+    fn log_context(&self) -> String;
+    fn close_backend(&mut self);
+    fn connect_to_backend(
+        &mut self,
+        session_rc: Rc<RefCell<dyn ProxySession>>,
+    ) -> Result<BackendConnectAction, ConnectionError>;
+
+    /// the timeouts of the backend, then those of the cluster for the ones it does not set
+    fn timeouts(&self) -> Timeouts {
+        let backend_timeouts = self
+            .backend()
+            .map(|backend| backend.borrow().timeouts)
+            .unwrap_or_default();
+        let cluster_timeouts = self
+            .cluster_id()
+            .and_then(|cluster_id| {
+                self.proxy()
+                    .borrow()
+                    .clusters()
+                    .get(cluster_id)
+                    .map(|cluster| cluster.timeouts)
+            })
+            .unwrap_or_default();
+
+        backend_timeouts.or(&cluster_timeouts)
+    }
+
+    fn front_timeout_duration(&self) -> Duration {
+        self.timeouts()
+            .front_timeout
+            .map(|timeout| Duration::seconds(i64::from(timeout)))
+            .unwrap_or(self.frontend_timeout_duration())
+    }
+
+    fn back_timeout_duration(&self) -> Duration {
+        self.timeouts()
+            .back_timeout
+            .map(|timeout| Duration::seconds(i64::from(timeout)))
+            .unwrap_or(self.backend_timeout_duration())
+    }
+
+    /// the timeout of both sides of a WebSocket connection, if the cluster sets one
+    fn websocket_timeout_duration(&self) -> Option<Duration> {
+        self.timeouts()
+            .websocket_timeout
+            .map(|timeout| Duration::seconds(i64::from(timeout)))
+    }
+
+    /// waits for a free connection in the queue of the cluster if its backends are
+    /// saturated, returns false if the request must be answered with a 503
+    fn queue_request(&mut self, cluster_id: &str) -> bool {
+        let queued_since = self.proxy().borrow().backends().borrow_mut().queue_request(
+            cluster_id,
+            self.frontend_token(),
+            self.routing().queued_since,
+        );
+
+        match queued_since {
+            Some(since) => {
+                if self.routing().queued_since.is_none() {
+                    debug!(
+                        "{} the backends are saturated, the request waits in the queue",
+                        self.log_context()
+                    );
+                }
+                self.routing_mut().queued_since = Some(since);
+                true
+            }
+            None => {
+                if self.routing_mut().queued_since.take().is_some() {
+                    error!(
+                        "{} the request waited too long in the queue",
+                        self.log_context()
+                    );
+                    incr!("queue.timeouts", Some(cluster_id), None);
+                }
+                false
+            }
+        }
+    }
+
+    /// takes the request out of the queue of its cluster
+    fn leave_queue(&mut self) {
+        if self.routing_mut().queued_since.take().is_some() {
+            if let Some(cluster_id) = self.cluster_id() {
+                self.proxy()
+                    .borrow()
+                    .backends()
+                    .borrow_mut()
+                    .remove_queued_request(cluster_id, self.frontend_token());
+            }
+        }
+    }
+
+    /// counts the session in the session limit of its cluster, instead of the
+    /// previous one. Returns false if the cluster holds too many sessions
+    fn enter_cluster(&mut self, cluster_id: &str) -> bool {
+        if self.routing().counted_cluster.as_deref() == Some(cluster_id) {
+            return true;
+        }
+
+        let proxy = self.proxy().clone();
+        let proxy = proxy.borrow();
+        let max_sessions = proxy
+            .clusters()
+            .get(cluster_id)
+            .and_then(|cluster| cluster.max_sessions);
+        let mut sessions = proxy.sessions().borrow_mut();
+        if let Some(previous) = self.routing_mut().counted_cluster.take() {
+            sessions.leave_cluster(&previous);
+        }
+
+        if !sessions.enter_cluster(cluster_id, max_sessions) {
+            return false;
+        }
+        self.routing_mut().counted_cluster = Some(cluster_id.to_string());
+        true
+    }
+
+    /// releases the places of the session in the session limits
+    fn leave_session_limits(&mut self) {
+        let proxy = self.proxy().clone();
+        let proxy = proxy.borrow();
+        let mut sessions = proxy.sessions().borrow_mut();
+        if let Some(listener_token) = self.routing_mut().counted_listener.take() {
+            sessions.close_listener_session(listener_token);
+        }
+        if let Some(cluster_id) = self.routing_mut().counted_cluster.take() {
+            sessions.leave_cluster(&cluster_id);
+        }
+    }
+
+    /// counts a failed attempt of the current request, on the current backend
+    fn record_failed_attempt(&mut self) {
+        let backend_id = self
+            .backend()
+            .map(|backend| backend.borrow().backend_id.clone());
+        let routing = self.routing_mut();
+        routing.connection_attempt += 1;
+        routing.tried_backends.extend(backend_id);
+    }
+
+    fn request_retries(&self) -> RequestRetries {
+        self.cluster_id()
+            .and_then(|cluster_id| {
+                self.proxy()
+                    .borrow()
+                    .clusters()
+                    .get(cluster_id)
+                    .map(|cluster| cluster.request_retries.clone())
+            })
+            .unwrap_or_default()
+    }
+
+    fn websocket_drain(&self) -> WebSocketDrain {
+        self.cluster_id()
+            .and_then(|cluster_id| {
+                self.proxy()
+                    .borrow()
+                    .clusters()
+                    .get(cluster_id)
+                    .map(|cluster| cluster.websocket_drain)
+            })
+            .unwrap_or_default()
+    }
+
+    /// checks the retry policy of the cluster against the attempts made for the current request
+    fn can_retry(&self, condition: RetryCondition) -> bool {
+        let retries = self.request_retries();
+        retries.retries_on(condition) && self.routing().connection_attempt < retries.max_attempts()
+    }
+
+    /// sends the request to another backend if the response of this one allows it,
+    /// returns true if the session must wait for the new backend connection
+    fn retry_request(&mut self, session: Rc<RefCell<dyn ProxySession>>) -> bool {
+        let condition = match self.http().and_then(|http| http.retryable_response()) {
+            Some(condition) => condition,
+            None => return false,
+        };
+
+        self.record_failed_attempt();
+        if !self.can_retry(condition)
+            || !self
+                .http_mut()
+                .map(|http| http.prepare_retry())
+                .unwrap_or(false)
+        {
+            return false;
+        }
+
+        error!(
+            "{} backend answered with {:?}, trying another backend",
+            self.log_context(),
+            condition
+        );
+        incr!(
+            "retries",
+            self.cluster_id(),
+            self.metrics().backend_id.as_deref()
+        );
+
+        self.close_backend();
+        !matches!(
+            self.connect_to_backend(session),
+            Ok(BackendConnectAction::Reuse) | Err(_)
+        )
+    }
+
+    /// rewrites the Host header of the request as configured for the cluster
+    fn rewrite_host(&mut self, cluster_id: &str) {
+        let host = match self
+            .proxy()
+            .borrow()
+            .clusters()
+            .get(cluster_id)
+            .map(|cluster| &cluster.host_rewrite)
+        {
+            Some(HostRewrite::Fixed(host)) => host.clone(),
+            Some(HostRewrite::BackendAddress) => match self.backend() {
+                Some(backend) => backend.borrow().address.to_string(),
+                None => return,
+            },
+            _ => return,
+        };
+
+        if let Some(http) = self.http_mut() {
+            http.rewrite_request_host(&host);
+        }
+    }
+
+    /// opens the connection to the mirror cluster of the current request's frontend,
+    /// or closes the one used by the previous request if it does not apply anymore
+    fn connect_mirror(&mut self, session_rc: Rc<RefCell<dyn ProxySession>>) {
+        let mirror_cluster_id = self.routing_mut().mirror_cluster_id.take();
+
+        if self
+            .routing()
+            .mirror
+            .as_ref()
+            .map(|mirror| &mirror.cluster_id)
+            != mirror_cluster_id.as_ref()
+        {
+            self.close_mirror();
+
+            if let Some(cluster_id) = mirror_cluster_id {
+                let proxy = self.proxy().clone();
+                let proxy = proxy.borrow();
+                match Mirror::connect(
+                    &cluster_id,
+                    proxy.backends(),
+                    proxy.registry(),
+                    proxy.sessions(),
+                    session_rc,
+                ) {
+                    Ok(mirror) => self.routing_mut().mirror = Some(mirror),
+                    Err(e) => error!(
+                        "{} could not connect to the mirror cluster {}: {:?}",
+                        self.log_context(),
+                        cluster_id,
+                        e
+                    ),
+                }
+            }
+        }
+
+        let mirrored = self.routing().mirror.is_some();
+        if let Some(http) = self.http_mut() {
+            http.mirror_buffer = if mirrored { Some(Vec::new()) } else { None };
+        }
+    }
+
+    /// sends to the mirror the data written to the backend, and discards its answers
+    fn mirror_ready(&mut self) {
+        let data = self
+            .http_mut()
+            .and_then(|http| http.mirror_buffer.as_mut().map(std::mem::take));
+
+        let result = match self.routing_mut().mirror.as_mut() {
+            None => return,
+            Some(mirror) => match data {
+                Some(data) if !mirror.push(&data) => SessionResult::CloseBackend,
+                _ => mirror.ready(),
+            },
+        };
+
+        if result != SessionResult::Continue {
+            self.close_mirror();
+            if let Some(http) = self.http_mut() {
+                http.mirror_buffer = None;
+            }
+        }
+    }
+
+    fn close_mirror(&mut self) {
+        if let Some(mut mirror) = self.routing_mut().mirror.take() {
+            let proxy = self.proxy().borrow();
+            mirror.close(proxy.registry(), proxy.sessions());
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::{cell::RefCell, net::Shutdown, rc::Rc};
+
+use mio::{Interest, Registry, Token};
+
+use crate::{
+    backends::BackendMap,
+    server::SessionManager,
+    socket::{BackendSocket, SocketHandler, SocketResult},
+    sozu_command::{proxy::BackendProtocol, ready::Ready},
+    Backend, ClusterId, ConnectionError, ProxySession, Readiness, SessionResult,
+};
+
+/// how much request data can wait for the mirror backend before the mirror is dropped
+pub const MIRROR_MAX_BUFFER_SIZE: usize = 65536;
+
+/// copies the requests of a session to a backend of a shadow cluster,
+/// discarding whatever this backend answers.
+///
+/// The mirror never slows down the session: if its backend does not
+/// accept the data fast enough, the mirror is dropped
+pub struct Mirror {
+    pub token: Token,
+    pub cluster_id: ClusterId,
+    pub readiness: Readiness,
+    socket: BackendSocket,
+    backend: Rc<RefCell<Backend>>,
+    buffer: Vec<u8>,
+}
+
+impl Mirror {
+    /// connects to a backend of the mirror cluster, under a new token pointing to the session
+    pub fn connect(
+        cluster_id: &str,
+        backends: &RefCell<BackendMap>,
+        registry: &Registry,
+        sessions: &RefCell<SessionManager>,
+        session_rc: Rc<RefCell<dyn ProxySession>>,
+    ) -> Result<Mirror, ConnectionError> {
+        {
+            let sessions = sessions.borrow();
+            if sessions.slab.len() >= sessions.slab_capacity() {
+                return Err(ConnectionError::TooManyConnections);
+            }
+        }
+
+        let (backend, socket) = backends.borrow_mut().backend_from_cluster_id(cluster_id)?;
+        let mut socket = backends.borrow().backend_socket(
+            cluster_id,
+            &backend.borrow(),
+            socket,
+            BackendProtocol::Http1,
+        )?;
+        if let Err(e) = socket.socket_ref().set_nodelay(true) {
+            error!(
+                "error setting nodelay on mirror socket({:?}): {:?}",
+                socket.socket_ref(),
+                e
+            );
+        }
+
+        let token = {
+            let mut sessions = sessions.borrow_mut();
+            let entry = sessions.slab.vacant_entry();
+            let token = Token(entry.key());
+            entry.insert(session_rc);
+            token
+        };
+
+        if let Err(e) = registry.register(
+            socket.socket_mut(),
+            token,
+            Interest::READABLE | Interest::WRITABLE,
+        ) {
+            error!(
+                "error registering mirror socket({:?}): {:?}",
+                socket.socket_ref(),
+                e
+            );
+        }
+
+        let mut readiness = Readiness::new();
+        readiness.interest = Ready::readable() | Ready::hup() | Ready::error();
+        incr!("http.mirror.connections");
+
+        Ok(Mirror {
+            token,
+            cluster_id: cluster_id.to_string(),
+            readiness,
+            socket,
+            backend,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// queues a copy of data sent to the main backend, returns false if the mirror cannot keep up
+    pub fn push(&mut self, data: &[u8]) -> bool {
+        if self.buffer.len() + data.len() > MIRROR_MAX_BUFFER_SIZE {
+            incr!("http.mirror.overflow");
+            return false;
+        }
+
+        if !data.is_empty() {
+            self.buffer.extend_from_slice(data);
+            self.readiness.interest.insert(Ready::writable());
+        }
+        true
+    }
+
+    /// writes the queued data and reads the answers to discard them,
+    /// returns `SessionResult::CloseBackend` if the mirror should be closed
+    pub fn ready(&mut self) -> SessionResult {
+        let interest = self.readiness.filter_interest();
+
+        if interest.is_writable() {
+            let (size, result) = self.socket.socket_write(&self.buffer);
+            self.buffer.drain(..size);
+            match result {
+                SocketResult::Error | SocketResult::Closed => return SessionResult::CloseBackend,
+                SocketResult::WouldBlock => self.readiness.event.remove(Ready::writable()),
+                SocketResult::Continue => {}
+            }
+            if self.buffer.is_empty() {
+                self.readiness.interest.remove(Ready::writable());
+            }
+        }
+
+        if interest.is_readable() {
+            let mut discarded = [0u8; 4096];
+            loop {
+                let (_, result) = self.socket.socket_read(&mut discarded);
+                match result {
+                    SocketResult::Error | SocketResult::Closed => {
+                        return SessionResult::CloseBackend
+                    }
+                    SocketResult::WouldBlock => {
+                        self.readiness.event.remove(Ready::readable());
+                        break;
+                    }
+                    SocketResult::Continue => {}
+                }
+            }
+        }
+
+        if interest.is_hup() || interest.is_error() {
+            return SessionResult::CloseBackend;
+        }
+
+        SessionResult::Continue
+    }
+
+    pub fn close(&mut self, registry: &Registry, sessions: &RefCell<SessionManager>) {
+        if let Err(e) = registry.deregister(self.socket.socket_mut()) {
+            error!(
+                "error deregistering mirror socket({:?}): {:?}",
+                self.socket.socket_ref(),
+                e
+            );
+        }
+        sessions.borrow_mut().slab.try_remove(self.token.0);
+
+        if let Err(e) = self.socket.socket_ref().shutdown(Shutdown::Both) {
+            if e.kind() != std::io::ErrorKind::NotConnected {
+                error!(
+                    "error shutting down mirror socket({:?}): {:?}",
+                    self.socket.socket_ref(),
+                    e
+                );
+            }
+        }
+
+        self.backend.borrow_mut().dec_connections();
+    }
+}
@@ -50,13 +50,17 @@ macro_rules! empty (
   );
 );
 
+pub mod forward;
 pub mod h2;
 pub mod http;
+pub mod mirror;
 #[cfg(feature = "use-openssl")]
 pub mod openssl;
 pub mod pipe;
 pub mod proxy_protocol;
 pub mod rustls;
+pub mod sni;
+pub mod websocket;
 
 pub use self::http::{Http, StickySession};
 #[cfg(feature = "use-openssl")]
@@ -1,8 +1,11 @@
 use std::net::SocketAddr;
 
 use mio::net::*;
-use openssl::ssl::{HandshakeError, MidHandshakeSslStream, NameType, Ssl, SslStream, SslVersion};
+use openssl::ssl::{
+    Error, HandshakeError, MidHandshakeSslStream, NameType, Ssl, SslStream, SslVersion,
+};
 use rusty_ulid::Ulid;
+use time::Instant;
 
 use crate::{
     protocol::ProtocolResult, sozu_command::ready::Ready, LogDuration, Readiness, SessionMetrics,
@@ -25,6 +28,8 @@ pub struct TlsHandshake {
     mid: Option<MidHandshakeSslStream<TcpStream>>,
     state: TlsState,
     address: Option<SocketAddr>,
+    /// when the handshake started, to measure its duration
+    pub started: Instant,
 }
 
 impl TlsHandshake {
@@ -46,6 +51,7 @@ impl TlsHandshake {
             },
             request_id,
             address,
+            started: Instant::now(),
         }
     }
 
@@ -76,6 +82,7 @@ impl TlsHandshake {
                         (ProtocolResult::Continue, SessionResult::CloseSession)
                     }
                     Err(HandshakeError::Failure(e)) => {
+                        incr!(handshake_error_str(e.error()));
                         {
                             if let Some(error_stack) = e.error().ssl_error() {
                                 let errors = error_stack.errors();
@@ -153,6 +160,7 @@ impl TlsHandshake {
                         (ProtocolResult::Continue, SessionResult::CloseSession)
                     }
                     Err(HandshakeError::Failure(e)) => {
+                        incr!(handshake_error_str(e.error()));
                         debug!(
                             "mid handshake failed (client = {:?}): {:?}",
                             self.address, e
@@ -256,3 +264,27 @@ impl TlsHandshake {
         );
     }
 }
+
+/// classifies the reason of a failed handshake
+fn handshake_error_str(error: &Error) -> &'static str {
+    let errors = match error.ssl_error() {
+        Some(error_stack) => error_stack.errors(),
+        None => return "tls.handshake.failed.other",
+    };
+
+    match errors.first().map(|error| error.code()) {
+        // inappropriate fallback, wrong version number, unknown protocol, plain HTTP request
+        Some(0x140A1175) | Some(0x1408A10B) | Some(0x140760FC) | Some(0x1407609C) => {
+            "tls.handshake.failed.protocol_mismatch"
+        }
+        // no certificate for the server name
+        Some(0x1422E0EA) | Some(0x1412E0E2) => "tls.handshake.failed.no_certificate",
+        _ if errors
+            .iter()
+            .any(|error| matches!(error.reason(), Some(reason) if reason.contains("alert"))) =>
+        {
+            "tls.handshake.failed.client_alert"
+        }
+        _ => "tls.handshake.failed.other",
+    }
+}
@@ -6,9 +6,16 @@ use rusty_ulid::Ulid;
 
 use crate::{
     pool::Checkout,
-    protocol::http::OptionalString,
-    socket::{SocketHandler, SocketResult, TransportProtocol},
-    sozu_command::ready::Ready,
+    protocol::{
+        http::OptionalString,
+        websocket::{close_frame, FrameTracker, WebSocketFrames},
+    },
+    server::tap_access_log,
+    socket::{BackendSocket, SocketHandler, SocketResult, TransportProtocol},
+    sozu_command::{
+        proxy::{AccessLogRecord, WebSocketDrain},
+        ready::Ready,
+    },
     timer::TimeoutContainer,
     ListenerHandler, LogDuration, Protocol, {Readiness, SessionMetrics, SessionResult},
 };
@@ -30,7 +37,7 @@ enum ConnectionStatus {
 pub struct Pipe<Front: SocketHandler, L: ListenerHandler> {
     pub frontend: Front,
     frontend_token: Token,
-    backend: Option<TcpStream>,
+    backend: Option<BackendSocket>,
     backend_token: Option<Token>,
     pub front_buf: Checkout,
     back_buf: Checkout,
@@ -48,6 +55,11 @@ pub struct Pipe<Front: SocketHandler, L: ListenerHandler> {
     pub front_timeout: Option<TimeoutContainer>,
     pub back_timeout: Option<TimeoutContainer>,
     pub listener: Rc<RefCell<L>>,
+    /// frames of an upgraded WebSocket connection, none for TCP
+    websocket: Option<WebSocketFrames>,
+    websocket_drain: WebSocketDrain,
+    /// the close frames were queued, nothing is read anymore
+    closing: bool,
 }
 
 impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
@@ -58,7 +70,7 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
         cluster_id: Option<String>,
         backend_id: Option<String>,
         websocket_context: Option<String>,
-        backend: Option<TcpStream>,
+        backend: Option<BackendSocket>,
         front_buf: Checkout,
         back_buf: Checkout,
         session_address: Option<SocketAddr>,
@@ -105,12 +117,28 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
             front_timeout: None,
             back_timeout: None,
             listener,
+            websocket: None,
+            websocket_drain: WebSocketDrain::default(),
+            closing: false,
         };
 
         trace!("created pipe");
         session
     }
 
+    /// queues data of the proxy itself for the client, like the answer of
+    /// a forward proxy handshake once the destination is connected
+    pub fn queue_front_data(&mut self, data: &[u8]) -> bool {
+        if self.back_buf.available_space() < data.len() {
+            return false;
+        }
+
+        self.back_buf.space()[..data.len()].copy_from_slice(data);
+        self.back_buf.fill(data.len());
+        self.front_readiness.interest.insert(Ready::writable());
+        true
+    }
+
     fn tokens(&self) -> Option<(Token, Token)> {
         if let Some(back) = self.backend_token {
             return Some((self.frontend_token, back));
@@ -127,15 +155,15 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
     }
 
     pub fn back_socket(&self) -> Option<&TcpStream> {
-        self.backend.as_ref()
+        self.backend.as_ref().map(|backend| backend.socket_ref())
     }
 
     pub fn back_socket_mut(&mut self) -> Option<&mut TcpStream> {
-        self.backend.as_mut()
+        self.backend.as_mut().map(|backend| backend.socket_mut())
     }
 
     pub fn set_back_socket(&mut self, socket: TcpStream) {
-        self.backend = Some(socket);
+        self.backend = Some(BackendSocket::Tcp(socket));
         self.backend_status = ConnectionStatus::Normal;
     }
 
@@ -145,6 +173,9 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
 
     pub fn timeout(&mut self, token: Token, metrics: &mut SessionMetrics) -> SessionResult {
         //info!("got timeout for token: {:?}", token);
+        if self.websocket.is_some() {
+            incr!("websocket.idle_timeouts");
+        }
         if self.frontend_token == token {
             self.log_request_error(metrics, "front socket timeout");
             if let Some(timeout) = self.front_timeout.as_mut() {
@@ -207,6 +238,77 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
         );
     }
 
+    /// follows the frames of the connection once upgraded to WebSocket. The
+    /// buffers can start with the head of the request or response, that is
+    /// not part of the frames
+    pub fn track_websocket(&mut self, front_head: usize, back_head: usize, drain: WebSocketDrain) {
+        let mut frames = WebSocketFrames {
+            front: FrameTracker::new(front_head),
+            back: FrameTracker::new(back_head),
+        };
+        frames.front.feed(self.front_buf.data());
+        frames.back.feed(self.back_buf.data());
+        self.websocket = Some(frames);
+        self.websocket_drain = drain;
+        gauge_add!("websocket.connections", 1, self.cluster_id.as_deref(), None);
+    }
+
+    /// the worker stops: a WebSocket connection gets a close frame in both directions
+    /// once the frames in flight are forwarded, or is left to end by itself.
+    /// Other connections are closed
+    pub fn shutting_down(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
+        let frames = match self.websocket.as_mut() {
+            Some(frames) => frames,
+            None => return SessionResult::CloseSession,
+        };
+        if self.websocket_drain == WebSocketDrain::Wait {
+            return SessionResult::Continue;
+        }
+
+        if !self.closing {
+            // a close frame cannot be inserted in the middle of a frame of the backend
+            if !frames.back.at_boundary() || self.back_buf.available_space() < 4 {
+                return SessionResult::Continue;
+            }
+            self.closing = true;
+            self.front_readiness.interest.remove(Ready::readable());
+            self.back_readiness.interest.remove(Ready::readable());
+
+            if !frames.back.closed {
+                let frame = close_frame(false);
+                self.back_buf.space()[..frame.len()].copy_from_slice(&frame);
+                self.back_buf.fill(frame.len());
+            }
+            // the backend gets its close frame if the client is not sending one
+            if frames.front.at_boundary()
+                && !frames.front.closed
+                && self.front_buf.available_space() >= 8
+            {
+                let frame = close_frame(true);
+                self.front_buf.space()[..frame.len()].copy_from_slice(&frame);
+                self.front_buf.fill(frame.len());
+            }
+            incr!("websocket.drain.closed");
+        }
+
+        if self.back_buf.available_data() > 0
+            && self.writable(metrics) == SessionResult::CloseSession
+        {
+            return SessionResult::CloseSession;
+        }
+        if self.front_buf.available_data() > 0
+            && self.back_writable(metrics) == SessionResult::CloseSession
+        {
+            return SessionResult::CloseSession;
+        }
+        if self.front_buf.available_data() == 0 && self.back_buf.available_data() == 0 {
+            metrics.service_stop();
+            self.log_request_success(metrics);
+            return SessionResult::CloseSession;
+        }
+        SessionResult::Continue
+    }
+
     pub fn set_back_token(&mut self, token: Token) {
         self.backend_token = Some(token);
     }
@@ -227,7 +329,7 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
     pub fn get_backend_address(&self) -> Option<SocketAddr> {
         self.backend
             .as_ref()
-            .and_then(|backend| backend.peer_addr().ok())
+            .and_then(|backend| backend.socket_ref().peer_addr().ok())
     }
 
     fn protocol_string(&self) -> &'static str {
@@ -247,6 +349,27 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
         }
     }
 
+    /// the connection as sent to the clients tailing the access logs
+    fn access_log_record(&self, metrics: &SessionMetrics, error: Option<&str>) -> AccessLogRecord {
+        AccessLogRecord {
+            timestamp: 0,
+            cluster_id: self.cluster_id.clone(),
+            backend_id: metrics.backend_id.clone(),
+            session_address: self.get_session_address(),
+            backend_address: self.get_backend_address(),
+            protocol: self.protocol_string().to_string(),
+            host: None,
+            method: None,
+            path: None,
+            status: None,
+            response_time: metrics.response_time().whole_milliseconds() as u64,
+            service_time: metrics.service_time().whole_milliseconds() as u64,
+            bytes_in: metrics.bin,
+            bytes_out: metrics.bout,
+            error: error.map(String::from),
+        }
+    }
+
     pub fn log_request_success(&self, metrics: &SessionMetrics) {
         let session_addr = match self.get_session_address() {
             None => String::from("-"),
@@ -307,6 +430,10 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
             proto,
             self.websocket_context.as_deref().unwrap_or("-")
         );
+
+        tap_access_log(self.cluster_id.as_deref(), None, || {
+            self.access_log_record(metrics, None)
+        });
     }
 
     pub fn log_request_error(&self, metrics: &SessionMetrics, message: &str) {
@@ -360,6 +487,10 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
             self.websocket_context.as_deref().unwrap_or("-"),
             message
         );
+
+        tap_access_log(self.cluster_id.as_deref(), None, || {
+            self.access_log_record(metrics, Some(message))
+        });
     }
 
     pub fn check_connections(&self) -> bool {
@@ -443,6 +574,10 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
         self.reset_timeouts();
 
         trace!("pipe readable");
+        if self.closing {
+            self.front_readiness.interest.remove(Ready::readable());
+            return SessionResult::Continue;
+        }
         if self.front_buf.available_space() == 0 {
             self.front_readiness.interest.remove(Ready::readable());
             self.back_readiness.interest.insert(Ready::writable());
@@ -458,6 +593,10 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
         if sz > 0 {
             //FIXME: replace with copy()
             self.front_buf.fill(sz);
+            if let Some(frames) = self.websocket.as_mut() {
+                let data = self.front_buf.data();
+                frames.front.feed(&data[data.len() - sz..]);
+            }
 
             count!("bytes_in", sz as i64);
             metrics.bin += sz;
@@ -683,6 +822,10 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
         self.reset_timeouts();
 
         trace!("pipe back_readable");
+        if self.closing {
+            self.back_readiness.interest.remove(Ready::readable());
+            return SessionResult::Continue;
+        }
         if self.back_buf.available_space() == 0 {
             self.back_readiness.interest.remove(Ready::readable());
             return SessionResult::Continue;
@@ -693,6 +836,10 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
         if let Some(ref mut backend) = self.backend {
             let (size, remaining) = backend.socket_read(self.back_buf.space());
             self.back_buf.fill(size);
+            if let Some(frames) = self.websocket.as_mut() {
+                let data = self.back_buf.data();
+                frames.back.feed(&data[data.len() - size..]);
+            }
 
             if let Some((front, back)) = tokens {
                 debug!(
@@ -760,5 +907,13 @@ impl<Front: SocketHandler, L: ListenerHandler> std::ops::Drop for Pipe<Front, L>
             Protocol::TCP => {}
             _ => gauge_add!("websocket.active_requests", -1),
         }
+        if self.websocket.is_some() {
+            gauge_add!(
+                "websocket.connections",
+                -1,
+                self.cluster_id.as_deref(),
+                None
+            );
+        }
     }
 }
@@ -8,7 +8,7 @@ use crate::{
     pool::Checkout,
     protocol::pipe::Pipe,
     protocol::ProtocolResult,
-    socket::{SocketHandler, SocketResult},
+    socket::{BackendSocket, SocketHandler, SocketResult},
     sozu_command::ready::Ready,
     tcp::Listener,
     Protocol, Readiness, SessionMetrics, SessionResult,
@@ -171,7 +171,7 @@ impl<Front: SocketHandler + Read> ExpectProxyProtocol<Front> {
             None,
             None,
             None,
-            backend_socket,
+            backend_socket.map(BackendSocket::Tcp),
             front_buf,
             back_buf,
             addr,
@@ -12,7 +12,7 @@ use rusty_ulid::Ulid;
 use crate::{
     pool::Checkout,
     protocol::{pipe::Pipe, ProtocolResult},
-    socket::{SocketHandler, SocketResult},
+    socket::{BackendSocket, SocketHandler, SocketResult},
     sozu_command::ready::Ready,
     tcp::Listener,
     Protocol, Readiness, SessionMetrics, SessionResult,
@@ -201,7 +201,7 @@ impl<Front: SocketHandler + Read> RelayProxyProtocol<Front> {
             None,
             None,
             None,
-            Some(backend_socket),
+            Some(BackendSocket::Tcp(backend_socket)),
             self.front_buf,
             back_buf,
             addr,
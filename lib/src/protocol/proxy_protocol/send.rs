@@ -10,7 +10,7 @@ use rusty_ulid::Ulid;
 use crate::{
     pool::Checkout,
     protocol::{pipe::Pipe, ProtocolResult},
-    socket::SocketHandler,
+    socket::{BackendSocket, SocketHandler},
     sozu_command::ready::Ready,
     tcp::Listener,
     BackendConnectionStatus, Protocol, Readiness, SessionMetrics, SessionResult,
@@ -173,7 +173,7 @@ impl<Front: SocketHandler + Read> SendProxyProtocol<Front> {
             None,
             None,
             None,
-            Some(backend_socket),
+            Some(BackendSocket::Tcp(backend_socket)),
             front_buf,
             back_buf,
             addr,
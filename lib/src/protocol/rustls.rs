@@ -1,8 +1,9 @@
 use std::io::ErrorKind;
 
 use mio::net::*;
-use rustls::ServerConnection;
+use rustls::{Error, ServerConnection};
 use rusty_ulid::Ulid;
+use time::Instant;
 
 use crate::{protocol::ProtocolResult, Readiness, Ready, SessionResult};
 
@@ -18,6 +19,8 @@ pub struct TlsHandshake {
     pub session: ServerConnection,
     pub readiness: Readiness,
     pub request_id: Ulid,
+    /// when the handshake started, to measure its duration
+    pub started: Instant,
 }
 
 impl TlsHandshake {
@@ -30,6 +33,7 @@ impl TlsHandshake {
                 event: Ready::empty(),
             },
             request_id,
+            started: Instant::now(),
         }
     }
 
@@ -61,6 +65,7 @@ impl TlsHandshake {
                 }
 
                 if let Err(e) = self.session.process_new_packets() {
+                    incr!(handshake_error_str(&e));
                     error!("could not perform handshake: {:?}", e);
                     return (ProtocolResult::Continue, SessionResult::CloseSession);
                 }
@@ -118,6 +123,7 @@ impl TlsHandshake {
                 }
 
                 if let Err(e) = self.session.process_new_packets() {
+                    incr!(handshake_error_str(&e));
                     error!("could not perform handshake: {:?}", e);
                     return (ProtocolResult::Continue, SessionResult::CloseSession);
                 }
@@ -148,3 +154,53 @@ impl TlsHandshake {
         }
     }
 }
+
+/// classifies the reason of a failed handshake
+fn handshake_error_str(error: &Error) -> &'static str {
+    match error {
+        // the certificate resolver found no certificate for the server name
+        Error::General(_) => "tls.handshake.failed.no_certificate",
+        Error::PeerIncompatibleError(_)
+        | Error::InappropriateMessage { .. }
+        | Error::InappropriateHandshakeMessage { .. }
+        | Error::CorruptMessage
+        | Error::CorruptMessagePayload(_) => "tls.handshake.failed.protocol_mismatch",
+        Error::AlertReceived(_) => "tls.handshake.failed.client_alert",
+        _ => "tls.handshake.failed.other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::internal::msgs::enums::AlertDescription;
+
+    use super::*;
+
+    #[test]
+    fn handshake_failure_reasons() {
+        assert_eq!(
+            handshake_error_str(&Error::General(
+                "no server certificate chain resolved".into()
+            )),
+            "tls.handshake.failed.no_certificate"
+        );
+        assert_eq!(
+            handshake_error_str(&Error::PeerIncompatibleError(
+                "no ciphersuites in common".into()
+            )),
+            "tls.handshake.failed.protocol_mismatch"
+        );
+        assert_eq!(
+            handshake_error_str(&Error::CorruptMessage),
+            "tls.handshake.failed.protocol_mismatch"
+        );
+        assert_eq!(
+            handshake_error_str(&Error::AlertReceived(AlertDescription::UnknownCA)),
+            "tls.handshake.failed.client_alert"
+        );
+        assert_eq!(
+            handshake_error_str(&Error::DecryptError),
+            "tls.handshake.failed.other"
+        );
+    }
+}
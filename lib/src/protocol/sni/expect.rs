@@ -0,0 +1,156 @@
+use std::{cell::RefCell, rc::Rc};
+
+use mio::{net::TcpStream, *};
+use nom::{Err, HexDisplay};
+use rusty_ulid::Ulid;
+
+use crate::{
+    pool::Checkout,
+    protocol::pipe::Pipe,
+    protocol::ProtocolResult,
+    socket::{SocketHandler, SocketResult},
+    sozu_command::ready::Ready,
+    tcp::Listener,
+    Protocol, Readiness, SessionMetrics, SessionResult,
+};
+
+use super::parser::parse_client_hello;
+
+/// reads the TLS ClientHello of a TCP session to find the server name,
+/// the ClientHello stays in the buffer to be forwarded to the backend
+pub struct ExpectSni<Front: SocketHandler> {
+    pub frontend: Front,
+    pub frontend_token: Token,
+    pub request_id: Ulid,
+    pub front_buf: Checkout,
+    pub readiness: Readiness,
+    pub hostname: Option<String>,
+}
+
+impl<Front: SocketHandler> ExpectSni<Front> {
+    pub fn new(
+        frontend: Front,
+        frontend_token: Token,
+        request_id: Ulid,
+        front_buf: Checkout,
+    ) -> Self {
+        ExpectSni {
+            frontend,
+            frontend_token,
+            request_id,
+            front_buf,
+            readiness: Readiness {
+                interest: Ready::readable(),
+                event: Ready::empty(),
+            },
+            hostname: None,
+        }
+    }
+
+    pub fn readable(&mut self, metrics: &mut SessionMetrics) -> (ProtocolResult, SessionResult) {
+        let (sz, res) = self.frontend.socket_read(self.front_buf.space());
+        trace!(
+            "FRONT SNI [{:?}]: read {} bytes and res={:?}",
+            self.frontend_token,
+            sz,
+            res
+        );
+
+        if sz > 0 {
+            self.front_buf.fill(sz);
+
+            count!("bytes_in", sz as i64);
+            metrics.bin += sz;
+        } else {
+            self.readiness.event.remove(Ready::readable());
+        }
+
+        if res == SocketResult::Error {
+            error!(
+                "[{:?}] (expect SNI) front socket error, closing the connection(read {}, wrote {})",
+                self.frontend_token, metrics.bin, metrics.bout
+            );
+            metrics.service_stop();
+            incr!("sni.errors");
+            self.readiness.reset();
+            return (ProtocolResult::Continue, SessionResult::CloseSession);
+        }
+
+        if res == SocketResult::WouldBlock {
+            self.readiness.event.remove(Ready::readable());
+        }
+
+        match parse_client_hello(self.front_buf.data()) {
+            Ok((_, hostname)) => {
+                trace!("got ClientHello with server name: {:?}", hostname);
+                self.hostname = hostname;
+                (ProtocolResult::Upgrade, SessionResult::Continue)
+            }
+            Err(Err::Incomplete(_)) if self.front_buf.available_space() > 0 => {
+                if res == SocketResult::Closed {
+                    metrics.service_stop();
+                    self.readiness.reset();
+                    return (ProtocolResult::Continue, SessionResult::CloseSession);
+                }
+                (ProtocolResult::Continue, SessionResult::Continue)
+            }
+            Err(Err::Incomplete(_)) => {
+                error!(
+                    "[{:?}] the ClientHello does not fit in the buffer, closing the connection",
+                    self.frontend_token
+                );
+                metrics.service_stop();
+                incr!("sni.errors");
+                self.readiness.reset();
+                (ProtocolResult::Continue, SessionResult::CloseSession)
+            }
+            Err(Err::Error(e)) | Err(Err::Failure(e)) => {
+                error!(
+                    "[{:?}] expect SNI front socket parse error, closing the connection:\n{}",
+                    self.frontend_token,
+                    e.input.to_hex(16)
+                );
+                metrics.service_stop();
+                incr!("sni.errors");
+                self.readiness.reset();
+                (ProtocolResult::Continue, SessionResult::CloseSession)
+            }
+        }
+    }
+
+    pub fn front_socket(&self) -> &TcpStream {
+        self.frontend.socket_ref()
+    }
+
+    pub fn readiness(&mut self) -> &mut Readiness {
+        &mut self.readiness
+    }
+
+    pub fn into_pipe(
+        self,
+        back_buf: Checkout,
+        cluster_id: Option<String>,
+        listener: Rc<RefCell<Listener>>,
+    ) -> Pipe<Front, Listener> {
+        let addr = self.front_socket().peer_addr().ok();
+
+        let mut pipe = Pipe::new(
+            self.frontend,
+            self.frontend_token,
+            self.request_id,
+            cluster_id,
+            None,
+            None,
+            None,
+            self.front_buf,
+            back_buf,
+            addr,
+            Protocol::TCP,
+            listener,
+        );
+
+        pipe.front_readiness.event = self.readiness.event;
+
+        pipe
+    }
+}
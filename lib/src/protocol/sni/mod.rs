@@ -0,0 +1,2 @@
+pub mod expect;
+pub mod parser;
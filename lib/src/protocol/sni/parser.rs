@@ -0,0 +1,152 @@
+use nom::{
+    bytes::{complete, streaming},
+    combinator::{all_consuming, map_res},
+    error::{Error, ErrorKind, ParseError},
+    multi::{length_data, many0},
+    number::{
+        complete::{be_u16, be_u24, be_u8},
+        streaming as streaming_number,
+    },
+    Err, IResult,
+};
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const SERVER_NAME_TYPE_HOST_NAME: u8 = 0x00;
+/// maximum size of the plaintext fragment of a TLS record
+const MAX_RECORD_LENGTH: u16 = 16384;
+
+/// parses the TLS record holding the ClientHello, and returns the server
+/// name it indicates, if any. The ClientHello has to fit in the first
+/// record, as sent by the common clients
+pub fn parse_client_hello(i: &[u8]) -> IResult<&[u8], Option<String>> {
+    let (i, _) = streaming::tag(&[CONTENT_TYPE_HANDSHAKE])(i)?;
+    let (i, _major_version) = streaming::tag(&[0x03])(i)?;
+    let (i, _minor_version) = streaming_number::be_u8(i)?;
+    let (i, length) = streaming_number::be_u16(i)?;
+    if length > MAX_RECORD_LENGTH {
+        return Err(Err::Error(Error::from_error_kind(i, ErrorKind::TooLarge)));
+    }
+    let (i, record) = streaming::take(length)(i)?;
+
+    let (_, server_name) = parse_handshake(record)?;
+    Ok((i, server_name))
+}
+
+fn parse_handshake(i: &[u8]) -> IResult<&[u8], Option<String>> {
+    let (i, _) = complete::tag(&[HANDSHAKE_TYPE_CLIENT_HELLO])(i)?;
+    let (i, length) = be_u24(i)?;
+    let (i, client_hello) = complete::take(length)(i)?;
+
+    let (_, server_name) = parse_client_hello_body(client_hello)?;
+    Ok((i, server_name))
+}
+
+fn parse_client_hello_body(i: &[u8]) -> IResult<&[u8], Option<String>> {
+    let (i, _client_version) = be_u16(i)?;
+    let (i, _random) = complete::take(32u8)(i)?;
+    let (i, _session_id) = length_data(be_u8)(i)?;
+    let (i, _cipher_suites) = length_data(be_u16)(i)?;
+    let (i, _compression_methods) = length_data(be_u8)(i)?;
+
+    // the extensions are optional
+    if i.is_empty() {
+        return Ok((i, None));
+    }
+
+    let (i, extensions) = length_data(be_u16)(i)?;
+    let (_, extensions) = all_consuming(many0(parse_extension))(extensions)?;
+
+    let server_name = extensions
+        .into_iter()
+        .find(|(extension_type, _)| *extension_type == EXTENSION_SERVER_NAME)
+        .map(|(_, data)| parse_server_name_list(data))
+        .transpose()?
+        .flatten();
+
+    Ok((i, server_name))
+}
+
+fn parse_extension(i: &[u8]) -> IResult<&[u8], (u16, &[u8])> {
+    let (i, extension_type) = be_u16(i)?;
+    let (i, data) = length_data(be_u16)(i)?;
+    Ok((i, (extension_type, data)))
+}
+
+fn parse_server_name_list(i: &[u8]) -> Result<Option<String>, Err<Error<&[u8]>>> {
+    let (_, list) = length_data(be_u16)(i)?;
+    let (_, names) = all_consuming(many0(parse_server_name))(list)?;
+
+    Ok(names
+        .into_iter()
+        .find(|(name_type, _)| *name_type == SERVER_NAME_TYPE_HOST_NAME)
+        .map(|(_, name)| name))
+}
+
+fn parse_server_name(i: &[u8]) -> IResult<&[u8], (u8, String)> {
+    let (i, name_type) = be_u8(i)?;
+    let (i, name) = map_res(length_data(be_u16), |name: &[u8]| {
+        std::str::from_utf8(name).map(|name| name.to_ascii_lowercase())
+    })(i)?;
+    Ok((i, (name_type, name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerName};
+    use std::{convert::TryFrom, sync::Arc};
+
+    fn client_hello(server_name: ServerName) -> Vec<u8> {
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let mut connection = ClientConnection::new(Arc::new(config), server_name).unwrap();
+
+        let mut hello = Vec::new();
+        connection.write_tls(&mut hello).unwrap();
+        hello
+    }
+
+    #[test]
+    fn client_hello_server_name() {
+        let hello = client_hello(ServerName::try_from("Lolcatho.st").unwrap());
+
+        assert_eq!(
+            parse_client_hello(&hello),
+            Ok((&[][..], Some(String::from("lolcatho.st"))))
+        );
+    }
+
+    #[test]
+    fn client_hello_without_server_name() {
+        // the clients do not send the server name of an IP address
+        let hello = client_hello(ServerName::IpAddress("127.0.0.1".parse().unwrap()));
+
+        assert_eq!(parse_client_hello(&hello), Ok((&[][..], None)));
+    }
+
+    #[test]
+    fn incomplete_client_hello() {
+        let hello = client_hello(ServerName::try_from("lolcatho.st").unwrap());
+
+        assert!(matches!(
+            parse_client_hello(&hello[..3]),
+            Err(Err::Incomplete(_))
+        ));
+        assert!(matches!(
+            parse_client_hello(&hello[..hello.len() - 1]),
+            Err(Err::Incomplete(_))
+        ));
+    }
+
+    #[test]
+    fn not_a_client_hello() {
+        assert!(matches!(
+            parse_client_hello(b"GET / HTTP/1.1\r\n\r\n"),
+            Err(Err::Error(_))
+        ));
+    }
+}
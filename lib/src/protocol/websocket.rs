@@ -0,0 +1,167 @@
+//! frames of the connections upgraded to WebSocket. The messages are not
+//! looked at, the frame boundaries are followed in each direction so that a
+//! stopping worker can end the connections with close frames of its own
+use rand::random;
+
+/// status of the close frames sent when the worker stops
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+
+const OPCODE_CLOSE: u8 = 0x8;
+
+/// follows the frames sent in one direction of a connection
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FrameTracker {
+    /// data preceding the first frame, like the head of the HTTP response
+    skip: usize,
+    /// beginning of the header of the current frame
+    header: Vec<u8>,
+    /// payload of the current frame still to come
+    remaining: u64,
+    /// a close frame went through, nothing should be sent after it
+    pub closed: bool,
+}
+
+impl FrameTracker {
+    /// starts after `skip` bytes that are not part of the frames
+    pub fn new(skip: usize) -> Self {
+        FrameTracker {
+            skip,
+            ..Default::default()
+        }
+    }
+
+    /// follows the data sent after the data already seen
+    pub fn feed(&mut self, mut data: &[u8]) {
+        let skipped = self.skip.min(data.len());
+        self.skip -= skipped;
+        data = &data[skipped..];
+
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let size = self.remaining.min(data.len() as u64);
+                self.remaining -= size;
+                data = &data[size as usize..];
+                continue;
+            }
+
+            self.header.push(data[0]);
+            data = &data[1..];
+            if let Some(length) = payload_length(&self.header) {
+                if self.header[0] & 0x0f == OPCODE_CLOSE {
+                    self.closed = true;
+                }
+                self.remaining = length;
+                self.header.clear();
+            }
+        }
+    }
+
+    /// the data seen so far ends with a complete frame
+    pub fn at_boundary(&self) -> bool {
+        self.skip == 0 && self.header.is_empty() && self.remaining == 0
+    }
+}
+
+/// the payload length of a frame, once its whole header is known
+fn payload_length(header: &[u8]) -> Option<u64> {
+    if header.len() < 2 {
+        return None;
+    }
+
+    let masked = header[1] & 0x80 != 0;
+    let (extended, length) = match header[1] & 0x7f {
+        126 => (2, None),
+        127 => (8, None),
+        length => (0, Some(u64::from(length))),
+    };
+    let header_size = 2 + extended + if masked { 4 } else { 0 };
+    if header.len() < header_size {
+        return None;
+    }
+
+    length.or_else(|| {
+        Some(
+            header[2..2 + extended]
+                .iter()
+                .fold(0u64, |length, byte| (length << 8) | u64::from(*byte)),
+        )
+    })
+}
+
+/// a close frame with the status `CLOSE_GOING_AWAY`. The frames sent to a
+/// server must be masked, those sent to a client must not
+pub fn close_frame(masked: bool) -> Vec<u8> {
+    let status = CLOSE_GOING_AWAY.to_be_bytes();
+    if !masked {
+        return vec![0x80 | OPCODE_CLOSE, 2, status[0], status[1]];
+    }
+
+    let mask: [u8; 4] = random();
+    let mut frame = vec![0x80 | OPCODE_CLOSE, 0x80 | 2];
+    frame.extend_from_slice(&mask);
+    frame.push(status[0] ^ mask[0]);
+    frame.push(status[1] ^ mask[1]);
+    frame
+}
+
+/// the frames of both directions of an upgraded connection
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct WebSocketFrames {
+    /// sent by the client to the backend
+    pub front: FrameTracker,
+    /// sent by the backend to the client
+    pub back: FrameTracker,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_boundaries() {
+        // the head of the response precedes the frames
+        let mut tracker = FrameTracker::new(4);
+        tracker.feed(b"HTTP");
+        assert!(tracker.at_boundary());
+
+        // a text frame of 5 bytes, split in the middle of its payload
+        tracker.feed(&[0x81, 0x05, b'h', b'e']);
+        assert!(!tracker.at_boundary());
+        tracker.feed(b"llo");
+        assert!(tracker.at_boundary());
+
+        // a masked binary frame with a 16 bits length, split in its header
+        let mut frame = vec![0x82, 0x80 | 126, 0x01];
+        tracker.feed(&frame);
+        assert!(!tracker.at_boundary());
+        frame = vec![0x00, 1, 2, 3, 4];
+        frame.extend(vec![0u8; 256]);
+        tracker.feed(&frame[..100]);
+        assert!(!tracker.at_boundary());
+        tracker.feed(&frame[100..]);
+        assert!(tracker.at_boundary());
+
+        // a frame with a 64 bits length, followed by a close frame
+        let mut frames = vec![0x82, 127, 0, 0, 0, 0, 0, 0, 0, 3, 1, 2, 3];
+        frames.extend(close_frame(false));
+        tracker.feed(&frames);
+        assert!(tracker.at_boundary());
+        assert!(tracker.closed);
+    }
+
+    #[test]
+    fn close_frames() {
+        assert_eq!(close_frame(false), vec![0x88, 0x02, 0x03, 0xe9]);
+
+        let frame = close_frame(true);
+        assert_eq!(&frame[..2], &[0x88, 0x82]);
+        assert_eq!(frame.len(), 8);
+        assert_eq!(frame[6] ^ frame[2], 0x03);
+        assert_eq!(frame[7] ^ frame[3], 0xe9);
+
+        let mut tracker = FrameTracker::new(0);
+        tracker.feed(&frame);
+        assert!(tracker.at_boundary());
+        assert!(tracker.closed);
+    }
+}
@@ -1,20 +1,68 @@
 pub mod pattern_trie;
 pub mod trie;
 
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    thread_rng,
+};
 use regex::bytes::Regex;
 use std::str::from_utf8;
 
 use crate::{
-    protocol::http::parser::Method,
-    sozu_command::proxy::{HttpFrontend, Route, RulePosition},
+    protocol::http::parser::{compare_no_case, Header, Method},
+    sozu_command::proxy::{
+        is_deny_status, HttpFrontend, PathRewrite, Route, RulePosition, WeightedCluster,
+        REDIRECT_CODES,
+    },
+    ClusterId,
 };
 
 use self::pattern_trie::TrieNode;
 
+/// a frontend rule of the pre and post lists
+pub type DomainRouteRule = (
+    DomainRule,
+    PathRule,
+    MethodRule,
+    HeaderRules,
+    FrontendActions,
+    Route,
+);
+
+/// a frontend rule stored under its hostname in the tree
+pub type PathRouteRule = (PathRule, MethodRule, HeaderRules, FrontendActions, Route);
+
 pub struct Router {
-    pre: Vec<(DomainRule, PathRule, MethodRule, Route)>,
-    pub tree: TrieNode<Vec<(PathRule, MethodRule, Route)>>,
-    post: Vec<(DomainRule, PathRule, MethodRule, Route)>,
+    pre: Vec<DomainRouteRule>,
+    pub tree: TrieNode<Vec<PathRouteRule>>,
+    post: Vec<DomainRouteRule>,
+    /// frontends matching on request headers: without them, the headers
+    /// need not be parsed to route a request
+    header_rules: usize,
+}
+
+/// the route of a request, with the path to send to the backend if the frontend rewrites it
+/// and the cluster receiving a copy of the request if the frontend mirrors it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteResult {
+    pub route: Route,
+    pub rewritten_path: Option<String>,
+    pub mirror_cluster_id: Option<ClusterId>,
+    /// when the request is denied because of its method, the methods
+    /// to list in the `Allow` header of the 405 answer
+    pub allowed_methods: Vec<String>,
+}
+
+impl RouteResult {
+    /// sends the request to a cluster without modifying it, like the fallback cluster of a listener
+    pub fn for_cluster(cluster_id: &str) -> RouteResult {
+        RouteResult {
+            route: Route::ClusterId(cluster_id.to_string()),
+            rewritten_path: None,
+            mirror_cluster_id: None,
+            allowed_methods: Vec::new(),
+        }
+    }
 }
 
 impl Default for Router {
@@ -29,46 +77,92 @@ impl Router {
             pre: Vec::new(),
             tree: TrieNode::root(),
             post: Vec::new(),
+            header_rules: 0,
         }
     }
 
-    pub fn lookup(&self, hostname: &[u8], path: &[u8], method: &Method) -> Option<Route> {
-        for (domain_rule, path_rule, method_rule, cluster_id) in &self.pre {
+    pub fn has_header_rules(&self) -> bool {
+        self.header_rules > 0
+    }
+
+    pub fn lookup(
+        &self,
+        hostname: &[u8],
+        path: &[u8],
+        method: &Method,
+        headers: &[Header],
+    ) -> Option<Route> {
+        self.lookup_frontend(hostname, path, method, headers)
+            .map(|result| result.route)
+    }
+
+    pub fn lookup_frontend(
+        &self,
+        hostname: &[u8],
+        path: &[u8],
+        method: &Method,
+        headers: &[Header],
+    ) -> Option<RouteResult> {
+        let mut disallowed = DisallowedMethod::default();
+
+        for (domain_rule, path_rule, method_rule, header_rules, actions, cluster_id) in &self.pre {
+            let path_result = path_rule.matches(path);
             if domain_rule.matches(hostname)
-                && path_rule.matches(path) != PathRuleResult::None
-                && method_rule.matches(method) != MethodRuleResult::None
+                && path_result != PathRuleResult::None
+                && header_rules.matches(headers)
             {
-                return Some(cluster_id.clone());
+                if method_rule.matches(method) != MethodRuleResult::None {
+                    return Some(actions.route_result(cluster_id, path, &path_result));
+                }
+                disallowed.add(method_rule, actions);
             }
         }
 
         if let Some((_, path_rules)) = self.tree.lookup(hostname, true) {
             let mut prefix_length = 0;
+            // among rules with the same prefix, the one checking the most headers wins
+            let mut header_rules_count = 0;
             let mut res = None;
 
-            for (rule, method_rule, cluster_id) in path_rules {
-                match rule.matches(path) {
+            for (rule, method_rule, header_rules, actions, cluster_id) in path_rules {
+                if !header_rules.matches(headers) {
+                    continue;
+                }
+
+                let path_result = rule.matches(path);
+                match path_result {
                     PathRuleResult::Regex | PathRuleResult::Equals => {
                         match method_rule.matches(method) {
-                            MethodRuleResult::Equals => return Some(cluster_id.clone()),
+                            MethodRuleResult::Equals => {
+                                return Some(actions.route_result(cluster_id, path, &path_result))
+                            }
                             MethodRuleResult::All => {
                                 prefix_length = path.len();
-                                res = Some(cluster_id);
+                                header_rules_count = header_rules.len();
+                                res = Some((actions, path_result, cluster_id));
                             }
-                            MethodRuleResult::None => {}
+                            MethodRuleResult::None => disallowed.add(method_rule, actions),
                         }
                     }
                     PathRuleResult::Prefix(size) => {
-                        if size >= prefix_length {
+                        if method_rule.matches(method) == MethodRuleResult::None {
+                            disallowed.add(method_rule, actions);
+                        }
+
+                        if size > prefix_length
+                            || (size == prefix_length && header_rules.len() >= header_rules_count)
+                        {
                             match method_rule.matches(method) {
                                 // FIXME: the rule order will be important here
                                 MethodRuleResult::Equals => {
                                     prefix_length = size;
-                                    res = Some(cluster_id);
+                                    header_rules_count = header_rules.len();
+                                    res = Some((actions, path_result, cluster_id));
                                 }
                                 MethodRuleResult::All => {
                                     prefix_length = size;
-                                    res = Some(cluster_id);
+                                    header_rules_count = header_rules.len();
+                                    res = Some((actions, path_result, cluster_id));
                                 }
                                 MethodRuleResult::None => {}
                             }
@@ -78,31 +172,72 @@ impl Router {
                 }
             }
 
-            if let Some(cluster_id) = res {
-                return Some(cluster_id.clone());
+            if let Some((actions, path_result, cluster_id)) = res {
+                return Some(actions.route_result(cluster_id, path, &path_result));
             }
         }
 
-        for (domain_rule, path_rule, method_rule, cluster_id) in self.post.iter() {
+        for (domain_rule, path_rule, method_rule, header_rules, actions, cluster_id) in
+            self.post.iter()
+        {
+            let path_result = path_rule.matches(path);
             if domain_rule.matches(hostname)
-                && path_rule.matches(path) != PathRuleResult::None
-                && method_rule.matches(method) != MethodRuleResult::None
+                && path_result != PathRuleResult::None
+                && header_rules.matches(headers)
             {
-                return Some(cluster_id.clone());
+                if method_rule.matches(method) != MethodRuleResult::None {
+                    return Some(actions.route_result(cluster_id, path, &path_result));
+                }
+                disallowed.add(method_rule, actions);
             }
         }
 
-        None
+        disallowed.route_result()
     }
 
     pub fn add_http_front(&mut self, front: HttpFrontend) -> bool {
+        match &front.route {
+            Route::Weighted(clusters) if clusters.iter().all(|cluster| cluster.weight == 0) => {
+                return false
+            }
+            Route::Redirect { code, .. } if !REDIRECT_CODES.contains(code) => return false,
+            Route::AbTest { cookie, variants }
+                if cookie.is_empty() || variants.iter().all(|cluster| cluster.weight == 0) =>
+            {
+                return false
+            }
+            Route::Deny { status, .. } if !is_deny_status(*status) => return false,
+            _ => {}
+        }
+
+        let method = MethodRule::from_methods(&front.allowed_methods());
+        let headers = match HeaderRules::from_config(front.headers) {
+            Some(headers) => headers,
+            None => return false,
+        };
+
+        if front.rewrite_path == Some(PathRewrite::StripPrefix)
+            && !matches!(front.path, sozu_command::proxy::PathRule::Prefix(_))
+        {
+            return false;
+        }
+
+        let actions = match PathRewriteRule::from_config(front.rewrite_path) {
+            Some(rewrite) => FrontendActions {
+                rewrite,
+                mirror_cluster_id: front.mirror_cluster_id,
+                reject_other_methods: front.reject_other_methods,
+            },
+            None => return false,
+        };
+
         match front.position {
             RulePosition::Pre => match (
                 front.hostname.parse::<DomainRule>(),
                 PathRule::from_config(front.path),
             ) {
                 (Ok(domain), Some(path)) => {
-                    self.add_pre_rule(domain, path, MethodRule::new(front.method), front.route)
+                    self.add_pre_rule(domain, path, method, headers, actions, front.route)
                 }
                 _ => false,
             },
@@ -111,7 +246,7 @@ impl Router {
                 PathRule::from_config(front.path),
             ) {
                 (Ok(domain), Some(path)) => {
-                    self.add_post_rule(domain, path, MethodRule::new(front.method), front.route)
+                    self.add_post_rule(domain, path, method, headers, actions, front.route)
                 }
                 _ => false,
             },
@@ -119,7 +254,9 @@ impl Router {
                 Some(path) => self.add_tree_rule(
                     front.hostname.as_bytes(),
                     path,
-                    MethodRule::new(front.method),
+                    method,
+                    headers,
+                    actions,
                     front.route,
                 ),
                 _ => false,
@@ -128,30 +265,33 @@ impl Router {
     }
 
     pub fn remove_http_front(&mut self, front: HttpFrontend) -> bool {
+        let method = MethodRule::from_methods(&front.allowed_methods());
+        let headers = match HeaderRules::from_config(front.headers) {
+            Some(headers) => headers,
+            None => return false,
+        };
+
         match front.position {
             RulePosition::Pre => match (
                 front.hostname.parse::<DomainRule>(),
                 PathRule::from_config(front.path),
             ) {
-                (Ok(domain), Some(path)) => {
-                    self.remove_pre_rule(domain, path, MethodRule::new(front.method))
-                }
+                (Ok(domain), Some(path)) => self.remove_pre_rule(domain, path, method, headers),
                 _ => false,
             },
             RulePosition::Post => match (
                 front.hostname.parse::<DomainRule>(),
                 PathRule::from_config(front.path),
             ) {
-                (Ok(domain), Some(path)) => {
-                    self.remove_post_rule(domain, path, MethodRule::new(front.method))
-                }
+                (Ok(domain), Some(path)) => self.remove_post_rule(domain, path, method, headers),
                 _ => false,
             },
             RulePosition::Tree => match PathRule::from_config(front.path) {
                 Some(path) => self.remove_tree_rule(
                     front.hostname.as_bytes(),
                     path,
-                    MethodRule::new(front.method),
+                    method,
+                    headers,
                     front.route,
                 ),
                 _ => false,
@@ -164,6 +304,8 @@ impl Router {
         hostname: &[u8],
         path: PathRule,
         method: MethodRule,
+        headers: HeaderRules,
+        actions: FrontendActions,
         cluster_id: Route,
     ) -> bool {
         let hostname = match from_utf8(hostname) {
@@ -171,6 +313,10 @@ impl Router {
             Ok(h) => h,
         };
 
+        if !valid_wildcard_hostname(hostname) {
+            return false;
+        }
+
         match ::idna::domain_to_ascii(hostname) {
             Ok(hostname) => {
                 //FIXME: necessary ti build on stable rust (1.35), can be removed once 1.36 is there
@@ -179,15 +325,22 @@ impl Router {
                     self.tree.domain_lookup_mut(hostname.as_bytes(), false)
                 {
                     empty = false;
-                    if !paths.iter().any(|(p, m, _)| *p == path && *m == method) {
-                        paths.push((path, method, cluster_id));
+                    if !paths
+                        .iter()
+                        .any(|(p, m, h, _, _)| *p == path && *m == method && *h == headers)
+                    {
+                        self.header_rules += usize::from(!headers.is_empty());
+                        paths.push((path, method, headers, actions, cluster_id));
                         return true;
                     }
                 }
 
                 if empty {
-                    self.tree
-                        .domain_insert(hostname.into_bytes(), vec![(path, method, cluster_id)]);
+                    self.header_rules += usize::from(!headers.is_empty());
+                    self.tree.domain_insert(
+                        hostname.into_bytes(),
+                        vec![(path, method, headers, actions, cluster_id)],
+                    );
                     return true;
                 }
 
@@ -202,6 +355,7 @@ impl Router {
         hostname: &[u8],
         path: PathRule,
         method: MethodRule,
+        headers: HeaderRules,
         _cluster_id: Route,
     ) -> bool {
         let hostname = match from_utf8(hostname) {
@@ -215,7 +369,11 @@ impl Router {
                     let paths_opt = self.tree.domain_lookup_mut(hostname.as_bytes(), false);
 
                     if let Some((_, paths)) = paths_opt {
-                        paths.retain(|(p, m, _)| *p != path || *m != method);
+                        let len = paths.len();
+                        paths.retain(|(p, m, h, _, _)| *p != path || *m != method || *h != headers);
+                        if paths.len() != len && !headers.is_empty() {
+                            self.header_rules -= 1;
+                        }
                     }
 
                     paths_opt
@@ -239,14 +397,18 @@ impl Router {
         domain: DomainRule,
         path: PathRule,
         method: MethodRule,
+        headers: HeaderRules,
+        actions: FrontendActions,
         cluster_id: Route,
     ) -> bool {
         if !self
             .pre
             .iter()
-            .any(|(d, p, m, _)| *d == domain && *p == path && *m == method)
+            .any(|(d, p, m, h, _, _)| *d == domain && *p == path && *m == method && *h == headers)
         {
-            self.pre.push((domain, path, method, cluster_id));
+            self.header_rules += usize::from(!headers.is_empty());
+            self.pre
+                .push((domain, path, method, headers, actions, cluster_id));
             true
         } else {
             false
@@ -258,14 +420,18 @@ impl Router {
         domain: DomainRule,
         path: PathRule,
         method: MethodRule,
+        headers: HeaderRules,
+        actions: FrontendActions,
         cluster_id: Route,
     ) -> bool {
         if !self
             .post
             .iter()
-            .any(|(d, p, m, _)| *d == domain && *p == path && *m == method)
+            .any(|(d, p, m, h, _, _)| *d == domain && *p == path && *m == method && *h == headers)
         {
-            self.post.push((domain, path, method, cluster_id));
+            self.header_rules += usize::from(!headers.is_empty());
+            self.post
+                .push((domain, path, method, headers, actions, cluster_id));
             true
         } else {
             false
@@ -277,14 +443,14 @@ impl Router {
         domain: DomainRule,
         path: PathRule,
         method: MethodRule,
+        headers: HeaderRules,
     ) -> bool {
-        match self
-            .pre
-            .iter()
-            .position(|(d, p, m, _)| *d == domain && *p == path && *m == method)
-        {
+        match self.pre.iter().position(|(d, p, m, h, _, _)| {
+            *d == domain && *p == path && *m == method && *h == headers
+        }) {
             None => false,
             Some(index) => {
+                self.header_rules -= usize::from(!headers.is_empty());
                 self.pre.remove(index);
                 true
             }
@@ -296,14 +462,14 @@ impl Router {
         domain: DomainRule,
         path: PathRule,
         method: MethodRule,
+        headers: HeaderRules,
     ) -> bool {
-        match self
-            .post
-            .iter()
-            .position(|(d, p, m, _)| *d == domain && *p == path && *m == method)
-        {
+        match self.post.iter().position(|(d, p, m, h, _, _)| {
+            *d == domain && *p == path && *m == method && *h == headers
+        }) {
             None => false,
             Some(index) => {
+                self.header_rules -= usize::from(!headers.is_empty());
                 self.post.remove(index);
                 true
             }
@@ -311,6 +477,32 @@ impl Router {
     }
 }
 
+/// picks one of the clusters of a weighted route, proportionally to their weight
+pub fn pick_weighted_cluster(clusters: &[WeightedCluster]) -> Option<&str> {
+    let dist = WeightedIndex::new(clusters.iter().map(|cluster| cluster.weight)).ok()?;
+    clusters
+        .get(dist.sample(&mut thread_rng()))
+        .map(|cluster| cluster.cluster_id.as_str())
+}
+
+/// picks the cluster of an A/B test route: the one named by the cookie of the user
+/// if it is still a variant of the route, otherwise a new one chosen like with a
+/// weighted route. The boolean is true if the cluster is newly assigned to the user
+pub fn pick_ab_test_variant<'a>(
+    variants: &'a [WeightedCluster],
+    assigned: Option<&str>,
+) -> Option<(&'a str, bool)> {
+    if let Some(cluster_id) = variants
+        .iter()
+        .map(|cluster| cluster.cluster_id.as_str())
+        .find(|cluster_id| Some(*cluster_id) == assigned)
+    {
+        return Some((cluster_id, false));
+    }
+
+    pick_weighted_cluster(variants).map(|cluster_id| (cluster_id, true))
+}
+
 #[derive(Clone, Debug)]
 pub enum DomainRule {
     Any,
@@ -319,6 +511,18 @@ pub enum DomainRule {
     Regex(Regex),
 }
 
+/// wildcards are only accepted as the whole first label of a hostname:
+/// `*.example.com` matches one label, `**.example.com` one or more,
+/// and a lone `*` matches any hostname
+fn valid_wildcard_hostname(hostname: &str) -> bool {
+    if hostname.contains('/') {
+        return true;
+    }
+
+    let (first, rest) = hostname.split_once('.').unwrap_or((hostname, ""));
+    !rest.contains('*') && (first == "*" || first == "**" || !first.contains('*'))
+}
+
 fn convert_regex_domain_rule(hostname: &str) -> Option<String> {
     let mut result = String::new();
 
@@ -347,7 +551,7 @@ fn convert_regex_domain_rule(hostname: &str) -> Option<String> {
                 index = i;
                 if i < s.len() && s[i] == b'.' {
                     match std::str::from_utf8(&s[start..i]) {
-                        Ok(r) => result.push_str(r),
+                        Ok(r) => result.push_str(&r.to_ascii_lowercase()),
                         Err(_) => return None,
                     }
                     break;
@@ -355,7 +559,7 @@ fn convert_regex_domain_rule(hostname: &str) -> Option<String> {
             }
             if index == s.len() {
                 match std::str::from_utf8(&s[start..]) {
-                    Ok(r) => result.push_str(r),
+                    Ok(r) => result.push_str(&r.to_ascii_lowercase()),
                     Err(_) => return None,
                 }
             }
@@ -377,9 +581,16 @@ impl DomainRule {
         match self {
             DomainRule::Any => true,
             DomainRule::Wildcard(s) => {
-                let len_without_suffix = hostname.len() - s.len() + 1;
-                hostname.ends_with(s[1..].as_bytes())
-                    && !&hostname[..len_without_suffix].contains(&b'.')
+                let (labels, suffix) = match s.strip_prefix("**") {
+                    Some(suffix) => (None, suffix),
+                    None => (Some(1), &s[1..]),
+                };
+                if hostname.len() <= suffix.len() || !hostname.ends_with(suffix.as_bytes()) {
+                    return false;
+                }
+                // `*` matches one label, `**` any number of them
+                let prefix = &hostname[..hostname.len() - suffix.len()];
+                labels.is_none() || !prefix.contains(&b'.')
             }
             DomainRule::Exact(s) => s.as_bytes() == hostname,
             DomainRule::Regex(r) => r.is_match(hostname),
@@ -407,14 +618,18 @@ impl std::str::FromStr for DomainRule {
             DomainRule::Any
         } else if s.contains('/') {
             match convert_regex_domain_rule(s) {
-                Some(s) => match regex::bytes::Regex::new(&s) {
+                // request hostnames are lowercased before routing
+                Some(s) => match regex::bytes::RegexBuilder::new(&s)
+                    .case_insensitive(true)
+                    .build()
+                {
                     Ok(r) => DomainRule::Regex(r),
                     Err(_) => return Err(()),
                 },
                 None => return Err(()),
             }
         } else if s.contains('*') {
-            if s.starts_with('*') {
+            if valid_wildcard_hostname(s) {
                 match ::idna::domain_to_ascii(s) {
                     Ok(r) => DomainRule::Wildcard(r),
                     Err(_) => return Err(()),
@@ -509,9 +724,10 @@ impl std::str::FromStr for PathRule {
 }
 */
 
+/// the methods accepted by a frontend, it accepts any method if the list is empty
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct MethodRule {
-    pub inner: Option<Method>,
+    pub inner: Vec<Method>,
 }
 
 #[derive(PartialEq, Eq)]
@@ -524,21 +740,216 @@ pub enum MethodRuleResult {
 impl MethodRule {
     pub fn new(method: Option<String>) -> Self {
         MethodRule {
-            inner: method.map(|s| Method::new(s.as_bytes())),
+            inner: method
+                .map(|s| Method::new(s.as_bytes()))
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    pub fn from_methods(methods: &[&str]) -> Self {
+        MethodRule {
+            inner: methods.iter().map(|s| Method::new(s.as_bytes())).collect(),
         }
     }
 
     pub fn matches(&self, method: &Method) -> MethodRuleResult {
-        match self.inner {
-            None => MethodRuleResult::All,
-            Some(ref m) => {
-                if method == m {
-                    MethodRuleResult::Equals
+        if self.inner.is_empty() {
+            MethodRuleResult::All
+        } else if self.inner.contains(method) {
+            MethodRuleResult::Equals
+        } else {
+            MethodRuleResult::None
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum HeaderValueRule {
+    Prefix(String),
+    Regex(Regex),
+    Equals(String),
+}
+
+impl HeaderValueRule {
+    pub fn matches(&self, value: &[u8]) -> bool {
+        match self {
+            HeaderValueRule::Prefix(s) => value.starts_with(s.as_bytes()),
+            HeaderValueRule::Regex(r) => r.is_match(value),
+            HeaderValueRule::Equals(s) => value == s.as_bytes(),
+        }
+    }
+}
+
+impl std::cmp::PartialEq for HeaderValueRule {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (HeaderValueRule::Prefix(s1), HeaderValueRule::Prefix(s2)) => s1 == s2,
+            (HeaderValueRule::Regex(r1), HeaderValueRule::Regex(r2)) => r1.as_str() == r2.as_str(),
+            (HeaderValueRule::Equals(s1), HeaderValueRule::Equals(s2)) => s1 == s2,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeaderRule {
+    pub name: String,
+    pub value: HeaderValueRule,
+}
+
+impl HeaderRule {
+    /// a header rule matches if any header with that name has a matching value
+    pub fn matches(&self, headers: &[Header]) -> bool {
+        headers.iter().any(|header| {
+            compare_no_case(&header.name, self.name.as_bytes()) && self.value.matches(&header.value)
+        })
+    }
+
+    pub fn from_config(rule: sozu_command::proxy::HeaderRule) -> Option<Self> {
+        let value = match rule.value {
+            sozu_command::proxy::HeaderValueRule::Prefix(s) => HeaderValueRule::Prefix(s),
+            sozu_command::proxy::HeaderValueRule::Regex(s) => {
+                HeaderValueRule::Regex(Regex::new(&s).ok()?)
+            }
+            sozu_command::proxy::HeaderValueRule::Equals(s) => HeaderValueRule::Equals(s),
+        };
+
+        Some(HeaderRule {
+            name: rule.name,
+            value,
+        })
+    }
+}
+
+/// all the header rules of a frontend, they must all match
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HeaderRules(pub Vec<HeaderRule>);
+
+impl HeaderRules {
+    pub fn matches(&self, headers: &[Header]) -> bool {
+        self.0.iter().all(|rule| rule.matches(headers))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn from_config(rules: Vec<sozu_command::proxy::HeaderRule>) -> Option<Self> {
+        rules
+            .into_iter()
+            .map(HeaderRule::from_config)
+            .collect::<Option<Vec<_>>>()
+            .map(HeaderRules)
+    }
+}
+
+/// what a frontend does to a request besides routing it
+#[derive(Clone, Debug, Default)]
+pub struct FrontendActions {
+    pub rewrite: PathRewriteRule,
+    pub mirror_cluster_id: Option<ClusterId>,
+    /// answer 405 to the requests matching the frontend except for their method
+    pub reject_other_methods: bool,
+}
+
+impl FrontendActions {
+    fn route_result(
+        &self,
+        route: &Route,
+        path: &[u8],
+        path_result: &PathRuleResult,
+    ) -> RouteResult {
+        RouteResult {
+            route: route.clone(),
+            rewritten_path: self.rewrite.rewrite(path, path_result),
+            mirror_cluster_id: self.mirror_cluster_id.clone(),
+            allowed_methods: Vec::new(),
+        }
+    }
+}
+
+/// the frontends matching a request except for its method,
+/// the request is denied with a 405 if one of them rejects other methods
+#[derive(Default)]
+struct DisallowedMethod {
+    reject: bool,
+    allowed: Vec<Method>,
+}
+
+impl DisallowedMethod {
+    fn add(&mut self, method_rule: &MethodRule, actions: &FrontendActions) {
+        self.reject |= actions.reject_other_methods;
+        for method in &method_rule.inner {
+            if !self.allowed.contains(method) {
+                self.allowed.push(method.clone());
+            }
+        }
+    }
+
+    fn route_result(self) -> Option<RouteResult> {
+        if !self.reject {
+            return None;
+        }
+
+        Some(RouteResult {
+            route: Route::Deny {
+                status: 405,
+                body_path: None,
+            },
+            rewritten_path: None,
+            mirror_cluster_id: None,
+            allowed_methods: self.allowed.iter().map(|m| m.to_string()).collect(),
+        })
+    }
+}
+
+/// how a frontend modifies the request path before it is sent to the backend
+#[derive(Clone, Debug, Default)]
+pub enum PathRewriteRule {
+    #[default]
+    None,
+    StripPrefix,
+    Regex(Regex, String),
+}
+
+impl PathRewriteRule {
+    pub fn from_config(rewrite: Option<PathRewrite>) -> Option<Self> {
+        match rewrite {
+            None => Some(PathRewriteRule::None),
+            Some(PathRewrite::StripPrefix) => Some(PathRewriteRule::StripPrefix),
+            Some(PathRewrite::Regex {
+                pattern,
+                replacement,
+            }) => Regex::new(&pattern)
+                .ok()
+                .map(|regex| PathRewriteRule::Regex(regex, replacement)),
+        }
+    }
+
+    /// returns the new path, or None if the path is not modified
+    pub fn rewrite(&self, path: &[u8], path_result: &PathRuleResult) -> Option<String> {
+        let rewritten = match (self, path_result) {
+            (PathRewriteRule::None, _) => return None,
+            (PathRewriteRule::StripPrefix, PathRuleResult::Prefix(size)) => {
+                let remaining = path.get(*size..).unwrap_or_default();
+                if remaining.starts_with(b"/") {
+                    remaining.to_vec()
                 } else {
-                    MethodRuleResult::None
+                    [&b"/"[..], remaining].concat()
                 }
             }
-        }
+            (PathRewriteRule::StripPrefix, _) => return None,
+            (PathRewriteRule::Regex(regex, replacement), _) => {
+                regex.replace_all(path, replacement.as_bytes()).into_owned()
+            }
+        };
+
+        String::from_utf8(rewritten).ok()
     }
 }
 
@@ -593,7 +1004,12 @@ mod tests {
             "*.example.com".parse::<DomainRule>().unwrap(),
             DomainRule::Wildcard("*.example.com".to_string())
         );
+        assert_eq!(
+            "**.example.com".parse::<DomainRule>().unwrap(),
+            DomainRule::Wildcard("**.example.com".to_string())
+        );
         assert_eq!("test.*.example.com".parse::<DomainRule>(), Err(()));
+        assert_eq!("*test.example.com".parse::<DomainRule>(), Err(()));
         assert_eq!(
             "/cdn[0-9]+/.example.com".parse::<DomainRule>().unwrap(),
             DomainRule::Regex(Regex::new("cdn[0-9]+\\.example\\.com").unwrap())
@@ -611,6 +1027,14 @@ mod tests {
         );
         assert!(!DomainRule::Wildcard("*.example.com".to_string())
             .matches("test.www.example.com".as_bytes()));
+        assert!(
+            !DomainRule::Wildcard("*.example.com".to_string()).matches("example.com".as_bytes())
+        );
+        assert!(DomainRule::Wildcard("**.example.com".to_string())
+            .matches("test.www.example.com".as_bytes()));
+        assert!(
+            !DomainRule::Wildcard("**.example.com".to_string()).matches("example.com".as_bytes())
+        );
         assert!("/cdn[0-9]+/.example.com"
             .parse::<DomainRule>()
             .unwrap()
@@ -625,6 +1049,58 @@ mod tests {
             .matches("cdn10.exampleAcom".as_bytes()));
     }
 
+    #[test]
+    fn lowercase_domain_rules() {
+        assert_eq!(
+            "WWW.Example.com".parse::<DomainRule>().unwrap(),
+            DomainRule::Exact("www.example.com".to_string())
+        );
+        assert_eq!(
+            "*.Example.COM".parse::<DomainRule>().unwrap(),
+            DomainRule::Wildcard("*.example.com".to_string())
+        );
+        assert_eq!(
+            "/CDN[0-9]+/.Example.com".parse::<DomainRule>().unwrap(),
+            DomainRule::Regex(Regex::new("CDN[0-9]+\\.example\\.com").unwrap())
+        );
+
+        let mut router = Router::new();
+        let front = |hostname: &str, position, cluster_id: &str| HttpFrontend {
+            route: Route::ClusterId(cluster_id.to_string()),
+            address: "0.0.0.0:80".parse().unwrap(),
+            hostname: hostname.to_string(),
+            path: sozu_command::proxy::PathRule::Prefix("/".to_string()),
+            method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
+            position,
+            tags: None,
+        };
+
+        assert!(router.add_http_front(front("API.Example.com", RulePosition::Pre, "api")));
+        assert!(router.add_http_front(front("/CDN[0-9]+/.Example.com", RulePosition::Post, "cdn")));
+
+        fn lookup(router: &Router, hostname: &[u8]) -> Option<Route> {
+            router
+                .lookup_frontend(hostname, b"/", &Method::Get, &[])
+                .map(|result| result.route)
+        }
+        assert_eq!(
+            lookup(&router, b"api.example.com"),
+            Some(Route::ClusterId("api".to_string()))
+        );
+        assert_eq!(
+            lookup(&router, b"cdn1.example.com"),
+            Some(Route::ClusterId("cdn".to_string()))
+        );
+
+        assert!(router.remove_http_front(front("api.example.com", RulePosition::Pre, "api")));
+        assert_eq!(lookup(&router, b"api.example.com"), None);
+    }
+
     #[test]
     fn match_path_rule() {
         assert!(PathRule::Prefix("".to_string()).matches("/".as_bytes()) != PathRuleResult::None);
@@ -644,6 +1120,86 @@ mod tests {
         );
     }
 
+    #[test]
+    fn hostname_precedence() {
+        let mut router = Router::new();
+
+        for (hostname, cluster_id) in [
+            ("www.example.com", "exact"),
+            ("*.example.com", "wildcard"),
+            ("**.example.com", "multi"),
+            ("*", "catch_all"),
+        ] {
+            assert!(router.add_tree_rule(
+                hostname.as_bytes(),
+                PathRule::Prefix("/".to_string()),
+                MethodRule::new(None),
+                HeaderRules::default(),
+                FrontendActions::default(),
+                Route::ClusterId(cluster_id.to_string())
+            ));
+        }
+        // a second path on a wildcard hostname goes in the same rule list
+        assert!(router.add_tree_rule(
+            "*.example.com".as_bytes(),
+            PathRule::Prefix("/api".to_string()),
+            MethodRule::new(None),
+            HeaderRules::default(),
+            FrontendActions::default(),
+            Route::ClusterId("wildcard_api".to_string())
+        ));
+        assert!(!router.add_tree_rule(
+            "www.*.example.com".as_bytes(),
+            PathRule::Prefix("/".to_string()),
+            MethodRule::new(None),
+            HeaderRules::default(),
+            FrontendActions::default(),
+            Route::ClusterId("invalid".to_string())
+        ));
+
+        fn lookup(router: &Router, hostname: &str, path: &str) -> Option<Route> {
+            router.lookup(
+                hostname.as_bytes(),
+                path.as_bytes(),
+                &Method::new(&b"GET"[..]),
+                &[],
+            )
+        }
+        assert_eq!(
+            lookup(&router, "www.example.com", "/"),
+            Some(Route::ClusterId("exact".to_string()))
+        );
+        assert_eq!(
+            lookup(&router, "api.example.com", "/"),
+            Some(Route::ClusterId("wildcard".to_string()))
+        );
+        assert_eq!(
+            lookup(&router, "api.example.com", "/api/test"),
+            Some(Route::ClusterId("wildcard_api".to_string()))
+        );
+        assert_eq!(
+            lookup(&router, "a.www.example.com", "/"),
+            Some(Route::ClusterId("multi".to_string()))
+        );
+        assert_eq!(
+            lookup(&router, "example.com", "/"),
+            Some(Route::ClusterId("catch_all".to_string()))
+        );
+        assert_eq!(
+            lookup(&router, "localhost", "/"),
+            Some(Route::ClusterId("catch_all".to_string()))
+        );
+
+        assert!(router.remove_tree_rule(
+            "*".as_bytes(),
+            PathRule::Prefix("/".to_string()),
+            MethodRule::new(None),
+            HeaderRules::default(),
+            Route::ClusterId("catch_all".to_string())
+        ));
+        assert_eq!(lookup(&router, "localhost", "/"), None);
+    }
+
     #[test]
     fn match_router() {
         let mut router = Router::new();
@@ -652,24 +1208,32 @@ mod tests {
             "*".parse::<DomainRule>().unwrap(),
             PathRule::Prefix("/.well-known/acme-challenge".to_string()),
             MethodRule::new(Some("GET".to_string())),
+            HeaderRules::default(),
+            FrontendActions::default(),
             Route::ClusterId("acme".to_string())
         ));
         assert!(router.add_tree_rule(
             "www.example.com".as_bytes(),
             PathRule::Prefix("/".to_string()),
             MethodRule::new(Some("GET".to_string())),
+            HeaderRules::default(),
+            FrontendActions::default(),
             Route::ClusterId("example".to_string())
         ));
         assert!(router.add_tree_rule(
             "*.test.example.com".as_bytes(),
             PathRule::Regex(Regex::new("/hello[A-Z]+/").unwrap()),
             MethodRule::new(Some("GET".to_string())),
+            HeaderRules::default(),
+            FrontendActions::default(),
             Route::ClusterId("examplewildcard".to_string())
         ));
         assert!(router.add_tree_rule(
             "/test[0-9]/.example.com".as_bytes(),
             PathRule::Prefix("/".to_string()),
             MethodRule::new(Some("GET".to_string())),
+            HeaderRules::default(),
+            FrontendActions::default(),
             Route::ClusterId("exampleregex".to_string())
         ));
 
@@ -677,7 +1241,8 @@ mod tests {
             router.lookup(
                 "www.example.com".as_bytes(),
                 "/helloA".as_bytes(),
-                &Method::new(&b"GET"[..])
+                &Method::new(&b"GET"[..]),
+                &[]
             ),
             Some(Route::ClusterId("example".to_string()))
         );
@@ -685,7 +1250,8 @@ mod tests {
             router.lookup(
                 "www.example.com".as_bytes(),
                 "/.well-known/acme-challenge".as_bytes(),
-                &Method::new(&b"GET"[..])
+                &Method::new(&b"GET"[..]),
+                &[]
             ),
             Some(Route::ClusterId("acme".to_string()))
         );
@@ -693,7 +1259,8 @@ mod tests {
             router.lookup(
                 "www.test.example.com".as_bytes(),
                 "/".as_bytes(),
-                &Method::new(&b"GET"[..])
+                &Method::new(&b"GET"[..]),
+                &[]
             ),
             None
         );
@@ -701,7 +1268,8 @@ mod tests {
             router.lookup(
                 "www.test.example.com".as_bytes(),
                 "/helloAB/".as_bytes(),
-                &Method::new(&b"GET"[..])
+                &Method::new(&b"GET"[..]),
+                &[]
             ),
             Some(Route::ClusterId("examplewildcard".to_string()))
         );
@@ -709,9 +1277,327 @@ mod tests {
             router.lookup(
                 "test1.example.com".as_bytes(),
                 "/helloAB/".as_bytes(),
-                &Method::new(&b"GET"[..])
+                &Method::new(&b"GET"[..]),
+                &[]
             ),
             Some(Route::ClusterId("exampleregex".to_string()))
         );
     }
+
+    #[test]
+    fn match_header_rules() {
+        let mut router = Router::new();
+
+        assert!(router.add_tree_rule(
+            "www.example.com".as_bytes(),
+            PathRule::Prefix("/".to_string()),
+            MethodRule::new(None),
+            HeaderRules::default(),
+            FrontendActions::default(),
+            Route::ClusterId("default".to_string())
+        ));
+        assert!(router.add_tree_rule(
+            "www.example.com".as_bytes(),
+            PathRule::Prefix("/".to_string()),
+            MethodRule::new(None),
+            HeaderRules(vec![HeaderRule {
+                name: "X-Tenant".to_string(),
+                value: HeaderValueRule::Equals("foo".to_string()),
+            }]),
+            FrontendActions::default(),
+            Route::ClusterId("tenant_foo".to_string())
+        ));
+        assert!(router.add_pre_rule(
+            "*".parse::<DomainRule>().unwrap(),
+            PathRule::Prefix("/".to_string()),
+            MethodRule::new(None),
+            HeaderRules(vec![HeaderRule {
+                name: "X-Canary".to_string(),
+                value: HeaderValueRule::Regex(Regex::new("^(yes|true)$").unwrap()),
+            }]),
+            FrontendActions::default(),
+            Route::ClusterId("canary".to_string())
+        ));
+
+        let tenant_header = Header {
+            name: b"x-tenant".to_vec(),
+            value: b"foo".to_vec(),
+        };
+        let other_tenant_header = Header {
+            name: b"X-Tenant".to_vec(),
+            value: b"foobar".to_vec(),
+        };
+        let canary_header = Header {
+            name: b"X-Canary".to_vec(),
+            value: b"true".to_vec(),
+        };
+
+        assert_eq!(
+            router.lookup(
+                "www.example.com".as_bytes(),
+                "/api".as_bytes(),
+                &Method::Get,
+                &[]
+            ),
+            Some(Route::ClusterId("default".to_string()))
+        );
+        assert_eq!(
+            router.lookup(
+                "www.example.com".as_bytes(),
+                "/api".as_bytes(),
+                &Method::Get,
+                &[tenant_header]
+            ),
+            Some(Route::ClusterId("tenant_foo".to_string()))
+        );
+        assert_eq!(
+            router.lookup(
+                "www.example.com".as_bytes(),
+                "/api".as_bytes(),
+                &Method::Get,
+                &[other_tenant_header]
+            ),
+            Some(Route::ClusterId("default".to_string()))
+        );
+        assert_eq!(
+            router.lookup(
+                "www.example.com".as_bytes(),
+                "/api".as_bytes(),
+                &Method::Get,
+                &[canary_header]
+            ),
+            Some(Route::ClusterId("canary".to_string()))
+        );
+
+        assert!(router.remove_tree_rule(
+            "www.example.com".as_bytes(),
+            PathRule::Prefix("/".to_string()),
+            MethodRule::new(None),
+            HeaderRules::default(),
+            Route::ClusterId("default".to_string())
+        ));
+        assert_eq!(
+            router.lookup(
+                "www.example.com".as_bytes(),
+                "/api".as_bytes(),
+                &Method::Get,
+                &[]
+            ),
+            None
+        );
+
+        assert!(router.has_header_rules());
+        assert!(router.remove_tree_rule(
+            "www.example.com".as_bytes(),
+            PathRule::Prefix("/".to_string()),
+            MethodRule::new(None),
+            HeaderRules(vec![HeaderRule {
+                name: "X-Tenant".to_string(),
+                value: HeaderValueRule::Equals("foo".to_string()),
+            }]),
+            Route::ClusterId("tenant_foo".to_string())
+        ));
+        assert!(router.has_header_rules());
+        assert!(router.remove_pre_rule(
+            "*".parse::<DomainRule>().unwrap(),
+            PathRule::Prefix("/".to_string()),
+            MethodRule::new(None),
+            HeaderRules(vec![HeaderRule {
+                name: "X-Canary".to_string(),
+                value: HeaderValueRule::Regex(Regex::new("^(yes|true)$").unwrap()),
+            }]),
+        ));
+        assert!(!router.has_header_rules());
+    }
+
+    #[test]
+    fn method_lists() {
+        let mut router = Router::new();
+
+        let front = |path: &str, methods: &[&str], reject_other_methods| HttpFrontend {
+            route: Route::ClusterId("api".to_string()),
+            address: "0.0.0.0:80".parse().unwrap(),
+            hostname: "example.com".to_string(),
+            path: sozu_command::proxy::PathRule::Prefix(path.to_string()),
+            method: None,
+            methods: methods.iter().map(|m| m.to_string()).collect(),
+            reject_other_methods,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: None,
+            position: RulePosition::Tree,
+            tags: None,
+        };
+
+        assert!(router.add_http_front(front("/", &["GET", "HEAD"], false)));
+        assert!(router.add_http_front(front("/api", &["GET", "POST"], true)));
+        assert!(router.add_http_front(front("/api", &["DELETE"], false)));
+
+        let lookup = |path: &[u8], method: Method| {
+            router
+                .lookup_frontend(b"example.com", path, &method, &[])
+                .map(|result| (result.route, result.allowed_methods))
+        };
+        let api = Route::ClusterId("api".to_string());
+
+        assert_eq!(lookup(b"/", Method::Head), Some((api.clone(), Vec::new())));
+        assert_eq!(lookup(b"/", Method::Post), None);
+        assert_eq!(
+            lookup(b"/api", Method::Post),
+            Some((api.clone(), Vec::new()))
+        );
+        assert_eq!(lookup(b"/api", Method::Delete), Some((api, Vec::new())));
+        assert_eq!(
+            lookup(b"/api/users", Method::Put),
+            Some((
+                Route::Deny {
+                    status: 405,
+                    body_path: None
+                },
+                vec![
+                    "GET".to_string(),
+                    "HEAD".to_string(),
+                    "POST".to_string(),
+                    "DELETE".to_string()
+                ]
+            ))
+        );
+    }
+
+    #[test]
+    fn rewrite_path() {
+        let mut router = Router::new();
+
+        let front = |path: sozu_command::proxy::PathRule, rewrite_path| HttpFrontend {
+            route: Route::ClusterId("api".to_string()),
+            address: "0.0.0.0:80".parse().unwrap(),
+            hostname: "example.com".to_string(),
+            path,
+            method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path,
+            mirror_cluster_id: None,
+            position: RulePosition::Tree,
+            tags: None,
+        };
+
+        assert!(router.add_http_front(front(
+            sozu_command::proxy::PathRule::Prefix("/api".to_string()),
+            Some(PathRewrite::StripPrefix)
+        )));
+        assert!(router.add_http_front(front(
+            sozu_command::proxy::PathRule::Prefix("/v1/".to_string()),
+            Some(PathRewrite::Regex {
+                pattern: "^/v1/(.*)$".to_string(),
+                replacement: "/v2/$1".to_string(),
+            })
+        )));
+        // stripping the prefix needs a prefix path rule
+        assert!(!router.add_http_front(front(
+            sozu_command::proxy::PathRule::Regex("/admin/.*".to_string()),
+            Some(PathRewrite::StripPrefix)
+        )));
+
+        let lookup = |path: &[u8]| {
+            router
+                .lookup_frontend(b"example.com", path, &Method::Get, &[])
+                .and_then(|result| result.rewritten_path)
+        };
+        assert_eq!(lookup(b"/api/users?id=1"), Some("/users?id=1".to_string()));
+        assert_eq!(lookup(b"/api"), Some("/".to_string()));
+        assert_eq!(lookup(b"/v1/users"), Some("/v2/users".to_string()));
+    }
+
+    #[test]
+    fn mirror_cluster() {
+        let mut router = Router::new();
+
+        let front = |path: &str, mirror_cluster_id: Option<&str>| HttpFrontend {
+            route: Route::ClusterId("api".to_string()),
+            address: "0.0.0.0:80".parse().unwrap(),
+            hostname: "example.com".to_string(),
+            path: sozu_command::proxy::PathRule::Prefix(path.to_string()),
+            method: None,
+            methods: Vec::new(),
+            reject_other_methods: false,
+            headers: Vec::new(),
+            rewrite_path: None,
+            mirror_cluster_id: mirror_cluster_id.map(String::from),
+            position: RulePosition::Tree,
+            tags: None,
+        };
+
+        assert!(router.add_http_front(front("/", None)));
+        assert!(router.add_http_front(front("/v2", Some("api-v2"))));
+
+        let lookup = |path: &[u8]| {
+            router
+                .lookup_frontend(b"example.com", path, &Method::Get, &[])
+                .unwrap()
+        };
+        assert_eq!(
+            lookup(b"/v2/users"),
+            RouteResult {
+                route: Route::ClusterId("api".to_string()),
+                rewritten_path: None,
+                mirror_cluster_id: Some("api-v2".to_string()),
+                allowed_methods: Vec::new(),
+            }
+        );
+        assert_eq!(lookup(b"/users").mirror_cluster_id, None);
+    }
+
+    #[test]
+    fn pick_weighted() {
+        let clusters = vec![
+            WeightedCluster {
+                cluster_id: "stable".to_string(),
+                weight: 0,
+            },
+            WeightedCluster {
+                cluster_id: "canary".to_string(),
+                weight: 10,
+            },
+        ];
+        for _ in 0..100 {
+            assert_eq!(pick_weighted_cluster(&clusters), Some("canary"));
+        }
+
+        let disabled = vec![WeightedCluster {
+            cluster_id: "stable".to_string(),
+            weight: 0,
+        }];
+        assert_eq!(pick_weighted_cluster(&disabled), None);
+        assert_eq!(pick_weighted_cluster(&[]), None);
+    }
+
+    #[test]
+    fn pick_ab_test() {
+        let variants = vec![
+            WeightedCluster {
+                cluster_id: "checkout-a".to_string(),
+                weight: 0,
+            },
+            WeightedCluster {
+                cluster_id: "checkout-b".to_string(),
+                weight: 10,
+            },
+        ];
+
+        // users already assigned to a variant keep it, even if it gets no new users
+        assert_eq!(
+            pick_ab_test_variant(&variants, Some("checkout-a")),
+            Some(("checkout-a", false))
+        );
+        assert_eq!(
+            pick_ab_test_variant(&variants, None),
+            Some(("checkout-b", true))
+        );
+        assert_eq!(
+            pick_ab_test_variant(&variants, Some("removed")),
+            Some(("checkout-b", true))
+        );
+    }
 }
@@ -28,10 +28,22 @@ fn find_last_slash(input: &[u8]) -> Option<usize> {
     (0..input.len()).rev().find(|&i| input[i] == b'/')
 }
 
+/// a lone `*` is the catch-all hostname: it is stored at the root
+/// like a `**` wildcard, matching any number of labels
+fn catch_all(key: &[u8]) -> &[u8] {
+    if key == &b"*"[..] {
+        &b"**"[..]
+    } else {
+        key
+    }
+}
+
 #[derive(Debug)]
 pub struct TrieNode<V> {
     key_value: Option<KeyValue<Key, V>>,
     wildcard: Option<KeyValue<Key, V>>,
+    /// the `**` wildcard is the child of label `**`, which no hostname can
+    /// have, to keep the nodes small
     children: HashMap<Key, TrieNode<V>>,
     regexps: Vec<(Regex, TrieNode<V>)>,
 }
@@ -93,7 +105,7 @@ impl<V: Debug + Clone> TrieNode<V> {
             return InsertResult::Failed;
         }
 
-        let res = self.insert_recursive(&key, &key, value);
+        let res = self.insert_recursive(catch_all(&key), &key, value);
         assert_ne!(res, InsertResult::Failed);
         res
     }
@@ -177,7 +189,7 @@ impl<V: Debug + Clone> TrieNode<V> {
     }
 
     pub fn remove(&mut self, key: &Key) -> RemoveResult {
-        self.remove_recursive(key)
+        self.remove_recursive(catch_all(key))
     }
 
     pub fn remove_recursive(&mut self, partial_key: &[u8]) -> RemoveResult {
@@ -243,6 +255,10 @@ impl<V: Debug + Clone> TrieNode<V> {
         RemoveResult::Ok
     }
 
+    /// looks up the value for a hostname. With `accept_wildcard`, the most
+    /// specific rule wins: exact labels, then `*` (one label), then regexps,
+    /// then `**` (one or more labels), then the `*` catch-all at the root.
+    /// Without it, wildcard keys are only matched literally
     pub fn lookup(&self, partial_key: &[u8], accept_wildcard: bool) -> Option<&KeyValue<Key, V>> {
         //println!("lookup: key == {}", std::str::from_utf8(partial_key).unwrap());
 
@@ -257,36 +273,44 @@ impl<V: Debug + Clone> TrieNode<V> {
         };
         //println!("lookup: prefix|suffix: {} | {}", std::str::from_utf8(prefix).unwrap(), std::str::from_utf8(suffix).unwrap());
 
-        match self.children.get(suffix) {
-            Some(child) => child.lookup(prefix, accept_wildcard),
-            None => {
-                //println!("no child found, testing wildcard and regexps");
-                //self.print();
-
-                if prefix.is_empty() && self.wildcard.is_some() && accept_wildcard {
-                    //println!("no dot, wildcard applies");
-                    self.wildcard.as_ref()
-                } else {
-                    //println!("there's still a subdomain, wildcard does not apply");
-
-                    for (ref r, ref child) in self.regexps.iter() {
-                        let s = if suffix[0] == b'.' {
-                            &suffix[1..]
-                        } else {
-                            suffix
-                        };
-                        //println!("testing regexp: {} on suffix {}", r.as_str(), str::from_utf8(s).unwrap());
+        if let Some(key_value) = self
+            .children
+            .get(suffix)
+            .and_then(|child| child.lookup(prefix, accept_wildcard))
+        {
+            return Some(key_value);
+        }
 
-                        if r.is_match(s) {
-                            //println!("matched");
-                            return child.lookup(prefix, accept_wildcard);
-                        }
-                    }
+        if !accept_wildcard {
+            if partial_key == &b"*"[..] {
+                return self.wildcard.as_ref();
+            }
+        } else if prefix.is_empty() && self.wildcard.is_some() {
+            //println!("no dot, wildcard applies");
+            return self.wildcard.as_ref();
+        }
 
-                    None
+        let s = if suffix[0] == b'.' {
+            &suffix[1..]
+        } else {
+            suffix
+        };
+        for (r, child) in self.regexps.iter() {
+            //println!("testing regexp: {} on suffix {}", r.as_str(), str::from_utf8(s).unwrap());
+            if r.is_match(s) {
+                if let Some(key_value) = child.lookup(prefix, accept_wildcard) {
+                    return Some(key_value);
                 }
             }
         }
+
+        if accept_wildcard {
+            self.children
+                .get(&b"**"[..])
+                .and_then(|child| child.key_value.as_ref())
+        } else {
+            None
+        }
     }
 
     pub fn lookup_mut(
@@ -305,36 +329,47 @@ impl<V: Debug + Clone> TrieNode<V> {
             None => (&b""[..], partial_key),
             Some(pos) => (&partial_key[..pos], &partial_key[pos..]),
         };
-        //println!("lookup: prefix|suffix: {} | {}", std::str::from_utf8(prefix).unwrap(), std::str::from_utf8(suffix).unwrap());
-
-        match self.children.get_mut(suffix) {
-            Some(child) => child.lookup_mut(prefix, accept_wildcard),
-            None => {
-                //println!("no child found, testing wildcard and regexps");
 
-                if prefix.is_empty() && self.wildcard.is_some() && accept_wildcard {
-                    //println!("no dot, wildcard applies");
-                    self.wildcard.as_mut()
-                } else {
-                    //println!("there's still a subdomain, wildcard does not apply");
+        // the immutable lookup finds the branch holding the match, so we do not
+        // have to backtrack while holding a mutable borrow
+        if self
+            .children
+            .get(suffix)
+            .and_then(|child| child.lookup(prefix, accept_wildcard))
+            .is_some()
+        {
+            return self
+                .children
+                .get_mut(suffix)
+                .and_then(|child| child.lookup_mut(prefix, accept_wildcard));
+        }
 
-                    for (ref r, ref mut child) in self.regexps.iter_mut() {
-                        let s = if suffix[0] == b'.' {
-                            &suffix[1..]
-                        } else {
-                            suffix
-                        };
-                        //println!("testing regexp: {} on suffix {}", r.as_str(), str::from_utf8(s).unwrap());
+        if !accept_wildcard {
+            if partial_key == &b"*"[..] {
+                return self.wildcard.as_mut();
+            }
+        } else if prefix.is_empty() && self.wildcard.is_some() {
+            return self.wildcard.as_mut();
+        }
 
-                        if r.is_match(s) {
-                            //println!("matched");
-                            return child.lookup_mut(prefix, accept_wildcard);
-                        }
-                    }
+        let s = if suffix[0] == b'.' {
+            &suffix[1..]
+        } else {
+            suffix
+        };
+        let regexp_index = self.regexps.iter().position(|(r, child)| {
+            r.is_match(s) && child.lookup(prefix, accept_wildcard).is_some()
+        });
+        if let Some(index) = regexp_index {
+            return self.regexps[index].1.lookup_mut(prefix, accept_wildcard);
+        }
 
-                    None
-                }
-            }
+        if accept_wildcard {
+            self.children
+                .get_mut(&b"**"[..])
+                .and_then(|child| child.key_value.as_mut())
+        } else {
+            None
         }
     }
 
@@ -378,7 +413,7 @@ impl<V: Debug + Clone> TrieNode<V> {
     }
 
     pub fn domain_lookup(&self, key: &[u8], accept_wildcard: bool) -> Option<&KeyValue<Key, V>> {
-        self.lookup(key, accept_wildcard)
+        self.lookup(catch_all(key), accept_wildcard)
     }
 
     pub fn domain_lookup_mut(
@@ -386,7 +421,7 @@ impl<V: Debug + Clone> TrieNode<V> {
         key: &[u8],
         accept_wildcard: bool,
     ) -> Option<&mut KeyValue<Key, V>> {
-        self.lookup_mut(key, accept_wildcard)
+        self.lookup_mut(catch_all(key), accept_wildcard)
     }
 
     pub fn size(&self) -> usize {
@@ -678,6 +713,69 @@ mod tests {
         );
     }
 
+    #[test]
+    fn wildcard_precedence() {
+        let mut root: TrieNode<u8> = TrieNode::root();
+        root.domain_insert(b"www.example.com".to_vec(), 0u8);
+        root.domain_insert(b"*.example.com".to_vec(), 1u8);
+        root.domain_insert(b"**.example.com".to_vec(), 2u8);
+        root.domain_insert(b"*".to_vec(), 3u8);
+        root.print();
+
+        assert_eq!(
+            root.domain_lookup(b"www.example.com", true),
+            Some(&(b"www.example.com".to_vec(), 0u8))
+        );
+        assert_eq!(
+            root.domain_lookup(b"test.example.com", true),
+            Some(&(b"*.example.com".to_vec(), 1u8))
+        );
+        // the exact `www` label does not match deeper, so we fall back to the wildcards
+        assert_eq!(
+            root.domain_lookup(b"test.www.example.com", true),
+            Some(&(b"**.example.com".to_vec(), 2u8))
+        );
+        assert_eq!(
+            root.domain_lookup(b"a.b.c.example.com", true),
+            Some(&(b"**.example.com".to_vec(), 2u8))
+        );
+        assert_eq!(
+            root.domain_lookup(b"example.com", true),
+            Some(&(b"*".to_vec(), 3u8))
+        );
+        assert_eq!(
+            root.domain_lookup(b"localhost", true),
+            Some(&(b"*".to_vec(), 3u8))
+        );
+
+        // without accept_wildcard, wildcard keys are only found literally
+        assert_eq!(
+            root.domain_lookup(b"*.example.com", false),
+            Some(&(b"*.example.com".to_vec(), 1u8))
+        );
+        assert_eq!(
+            root.domain_lookup(b"**.example.com", false),
+            Some(&(b"**.example.com".to_vec(), 2u8))
+        );
+        assert_eq!(root.domain_lookup(b"test.example.com", false), None);
+        assert_eq!(root.domain_lookup(b"*", false), Some(&(b"*".to_vec(), 3u8)));
+        assert_eq!(
+            root.domain_insert(b"**.example.com".to_vec(), 4u8),
+            InsertResult::Existing
+        );
+
+        assert_eq!(
+            root.domain_remove(&b"**.example.com".to_vec()),
+            RemoveResult::Ok
+        );
+        assert_eq!(
+            root.domain_lookup(b"test.www.example.com", true),
+            Some(&(b"*".to_vec(), 3u8))
+        );
+        assert_eq!(root.domain_remove(&b"*".to_vec()), RemoveResult::Ok);
+        assert_eq!(root.domain_lookup(b"test.www.example.com", true), None);
+    }
+
     fn hm_insert(h: std::collections::HashMap<String, u32>) -> bool {
         let mut root: TrieNode<u32> = TrieNode::root();
 
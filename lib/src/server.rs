@@ -1,10 +1,10 @@
 //! event loop management
 use std::{
-    cell::RefCell,
-    collections::{HashSet, VecDeque},
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet, VecDeque},
     convert::TryFrom,
     net::SocketAddr,
-    os::unix::io::{AsRawFd, FromRawFd},
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
     rc::Rc,
 };
 
@@ -17,6 +17,7 @@ use time::{Duration, Instant};
 use crate::{
     backends::BackendMap,
     features::FEATURES,
+    health_check::HealthChecker,
     http,
     metrics::METRICS,
     pool::Pool,
@@ -24,9 +25,11 @@ use crate::{
         channel::Channel,
         config::Config,
         proxy::{
-            HttpsListener, ListenerType, MessageId, ProxyEvent, ProxyRequest, ProxyRequestOrder,
-            ProxyResponse, ProxyResponseContent, ProxyResponseStatus, Query, QueryAnswer,
-            QueryAnswerCertificate, QueryCertificateType, QueryClusterType, TlsProvider, Topic,
+            AccessLogFilter, AccessLogRateLimit, AccessLogRecord, ActivateListener, Affinity,
+            CrashReport, HttpsListener, ListenerType, MessageId, NameResolution, ProxyEvent,
+            ProxyRequest, ProxyRequestOrder, ProxyResponse, ProxyResponseContent,
+            ProxyResponseStatus, Query, QueryAnswer, QueryAnswerCertificate, QueryAnswerCluster,
+            QueryCertificateType, QueryClusterType, SoftStopReport, TlsProvider, Topic,
         },
         ready::Ready,
         scm_socket::{Listeners, ScmSocket},
@@ -34,6 +37,7 @@ use crate::{
     },
     tcp,
     timer::Timer,
+    tls::{BackendTlsConfig, DelegatedCertificateResolvers},
     AcceptError, Backend, Protocol, ProxyConfiguration, ProxySession,
 };
 
@@ -66,6 +70,105 @@ pub fn push_event(event: ProxyEvent) {
     });
 }
 
+/// sends a sticky key bound to a backend to the main process, to share it with
+/// the other workers
+pub fn push_affinity(affinity: Affinity) {
+    QUEUE.with(|queue| {
+        (*queue.borrow_mut()).push_back(ProxyResponse {
+            id: "AFFINITY".to_string(),
+            status: ProxyResponseStatus::Processing,
+            content: Some(ProxyResponseContent::Affinity(affinity)),
+        });
+    });
+}
+
+/// asks the main process for the addresses of a name, they come back to the
+/// session as a `NameResolved` order
+pub fn push_name_resolution(session: Token, name: String, port: u16) {
+    push_queue(ProxyResponse {
+        id: format!("RESOLVE-{}", session.0),
+        status: ProxyResponseStatus::Processing,
+        content: Some(ProxyResponseContent::ResolveName(NameResolution {
+            session: session.0,
+            name,
+            port,
+            addresses: Vec::new(),
+        })),
+    });
+}
+
+thread_local! {
+  /// the last order received by the worker, for its crash report
+  static LAST_ORDER: RefCell<Option<String>> = const { RefCell::new(None) };
+  /// the client sessions of the worker, updated on each loop iteration
+  static SESSIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn record_order(message: &ProxyRequest) {
+    // the name of the variant, without its content
+    let order = format!("{:?}", message.order);
+    let order_type = order
+        .split(|c: char| !c.is_alphanumeric())
+        .next()
+        .unwrap_or_default();
+    LAST_ORDER.with(|last_order| {
+        *last_order.borrow_mut() = Some(format!("{} ({})", message.id, order_type));
+    });
+}
+
+/// the report of a panic of the worker, with the last order it received and
+/// its client sessions. Called from a panic hook, so it never panics itself
+pub fn crash_report(message: String, location: Option<String>, backtrace: String) -> CrashReport {
+    CrashReport {
+        message,
+        location,
+        backtrace,
+        sessions: SESSIONS.try_with(Cell::get).unwrap_or(0),
+        last_order: LAST_ORDER
+            .try_with(|order| order.try_borrow().ok().and_then(|order| order.clone()))
+            .ok()
+            .flatten(),
+    }
+}
+
+thread_local! {
+  /// the filters of the clients tailing the access logs, with the records
+  /// each of them let through in the current second
+  static ACCESS_LOG_FILTERS: RefCell<Vec<(AccessLogFilter, AccessLogRateLimit)>> =
+    RefCell::new(Vec::new());
+}
+
+/// sends the record of a request to the main process, if it matches the filter
+/// of a client tailing the access logs and the rate of this filter allows it.
+/// The record is only built then, and stamped with the current time
+pub fn tap_access_log<F>(cluster_id: Option<&str>, status: Option<u16>, record: F)
+where
+    F: FnOnce() -> AccessLogRecord,
+{
+    let tapped = ACCESS_LOG_FILTERS.with(|filters| {
+        let mut filters = filters.borrow_mut();
+        let now = std::time::Instant::now();
+        let mut tapped = false;
+        for (filter, limit) in filters.iter_mut() {
+            if filter.matches(cluster_id, status) && limit.allow(filter.max_per_second, now) {
+                tapped = true;
+            }
+        }
+        tapped
+    });
+
+    if tapped {
+        let mut record = record();
+        record.timestamp =
+            (time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) as i64;
+        push_queue(ProxyResponse {
+            id: "ACCESS_LOG".to_string(),
+            status: ProxyResponseStatus::Processing,
+            content: Some(ProxyResponseContent::AccessLog(record)),
+        });
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct ListenToken(pub usize);
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -97,6 +200,7 @@ impl From<SessionToken> for usize {
 
 pub struct ServerConfig {
     pub max_connections: usize,
+    pub max_sessions: Option<usize>,
     pub front_timeout: u32,
     pub back_timeout: u32,
     pub connect_timeout: u32,
@@ -108,6 +212,7 @@ impl ServerConfig {
     pub fn from_config(config: &Config) -> ServerConfig {
         ServerConfig {
             max_connections: config.max_connections,
+            max_sessions: config.max_sessions,
             front_timeout: config.front_timeout,
             back_timeout: config.back_timeout,
             connect_timeout: config.connect_timeout,
@@ -125,6 +230,7 @@ impl Default for ServerConfig {
     fn default() -> ServerConfig {
         ServerConfig {
             max_connections: 10000,
+            max_sessions: None,
             front_timeout: 60,
             back_timeout: 30,
             connect_timeout: 3,
@@ -139,6 +245,14 @@ pub struct SessionManager {
     pub nb_connections: usize,
     pub can_accept: bool,
     pub slab: Slab<Rc<RefCell<dyn ProxySession>>>,
+    /// client sessions of the worker at most, the next ones are refused
+    pub max_sessions: Option<usize>,
+    /// client sessions counted in the session limits
+    pub open_sessions: usize,
+    /// sessions open on the listeners, by listener token
+    pub listener_sessions: HashMap<Token, usize>,
+    /// sessions routed to the clusters, by cluster id
+    pub cluster_sessions: HashMap<String, usize>,
 }
 
 impl SessionManager {
@@ -151,15 +265,75 @@ impl SessionManager {
             nb_connections: 0,
             can_accept: true,
             slab,
+            max_sessions: None,
+            open_sessions: 0,
+            listener_sessions: HashMap::new(),
+            cluster_sessions: HashMap::new(),
         }))
     }
 
+    /// counts a new session of the listener. Returns false if the worker or the
+    /// listener already holds as many sessions as allowed: the session is not
+    /// counted, it has to be refused
+    pub fn open_listener_session(&mut self, listener: Token, max_sessions: Option<usize>) -> bool {
+        let listener_sessions = self.listener_sessions.get(&listener).copied().unwrap_or(0);
+
+        if self
+            .max_sessions
+            .map(|max| self.open_sessions >= max)
+            .unwrap_or(false)
+            || max_sessions
+                .map(|max| listener_sessions >= max)
+                .unwrap_or(false)
+        {
+            incr!("sessions.refused");
+            return false;
+        }
+
+        self.open_sessions += 1;
+        self.listener_sessions
+            .insert(listener, listener_sessions + 1);
+        gauge!("sessions.open", self.open_sessions);
+        true
+    }
+
+    pub fn close_listener_session(&mut self, listener: Token) {
+        self.open_sessions = self.open_sessions.saturating_sub(1);
+        if let Some(sessions) = self.listener_sessions.get_mut(&listener) {
+            *sessions = sessions.saturating_sub(1);
+        }
+        gauge!("sessions.open", self.open_sessions);
+    }
+
+    /// counts a session routed to the cluster. Returns false if the cluster
+    /// already holds as many sessions as allowed
+    pub fn enter_cluster(&mut self, cluster_id: &str, max_sessions: Option<usize>) -> bool {
+        let sessions = self
+            .cluster_sessions
+            .entry(cluster_id.to_string())
+            .or_insert(0);
+
+        if max_sessions.map(|max| *sessions >= max).unwrap_or(false) {
+            incr!("sessions.refused", Some(cluster_id), None);
+            return false;
+        }
+
+        *sessions += 1;
+        true
+    }
+
+    pub fn leave_cluster(&mut self, cluster_id: &str) {
+        if let Some(sessions) = self.cluster_sessions.get_mut(cluster_id) {
+            *sessions = sessions.saturating_sub(1);
+        }
+    }
+
     pub fn slab_capacity(&self) -> usize {
         10 + 2 * self.max_connections
     }
 
     pub fn check_limits(&mut self) -> bool {
-        // this should be self.nb_connections >= self.max_connections 
+        // this should be self.nb_connections >= self.max_connections
         if self.nb_connections == self.max_connections {
             error!("max number of session connection reached, flushing the accept queue");
             gauge!("accept_queue.backpressure", 1);
@@ -268,6 +442,7 @@ pub struct Server {
     sessions: Rc<RefCell<SessionManager>>,
     pool: Rc<RefCell<Pool>>,
     backends: Rc<RefCell<BackendMap>>,
+    health_checks: HealthChecker,
     scm_listeners: Option<Listeners>,
     zombie_check_interval: Duration,
     accept_queue: VecDeque<(TcpStream, ListenToken, Protocol, Instant)>,
@@ -276,12 +451,15 @@ pub struct Server {
 }
 
 impl Server {
+    /// the rustls HTTPS listeners whose address is in `delegated_resolvers` present
+    /// the certificates of their resolver
     pub fn try_new_from_config(
         worker_to_main_channel: ProxyChannel,
         worker_to_main_scm: ScmSocket,
         config: Config,
         config_state: ConfigState,
         expects_initial_status: bool,
+        delegated_resolvers: DelegatedCertificateResolvers,
     ) -> anyhow::Result<Self> {
         let event_loop = Poll::new().with_context(|| "could not create event loop")?;
         let pool = Rc::new(RefCell::new(Pool::with_capacity(
@@ -298,6 +476,7 @@ impl Server {
             Slab::with_capacity(server_config.slab_capacity()),
             server_config.max_connections,
         );
+        sessions.borrow_mut().max_sessions = server_config.max_sessions;
         {
             let mut s = sessions.borrow_mut();
             let entry = s.slab.vacant_entry();
@@ -323,19 +502,14 @@ impl Server {
             })));
         }
 
-        let use_openssl = config.tls_provider == TlsProvider::Openssl;
         let registry = event_loop
             .registry()
             .try_clone()
             .with_context(|| "could not clone the mio Registry")?;
 
-        let https = HttpsProvider::new(
-            use_openssl,
-            registry,
-            sessions.clone(),
-            pool.clone(),
-            backends.clone(),
-        );
+        let mut https =
+            HttpsProvider::new(registry, sessions.clone(), pool.clone(), backends.clone());
+        https.set_delegated_resolvers(delegated_resolvers);
 
         Server::new(
             event_loop,
@@ -407,13 +581,7 @@ impl Server {
                     .registry()
                     .try_clone()
                     .with_context(|| "could not clone the mio Registry")?;
-                HttpsProvider::new(
-                    false,
-                    registry,
-                    sessions.clone(),
-                    pool.clone(),
-                    backends.clone(),
-                )
+                HttpsProvider::new(registry, sessions.clone(), pool.clone(), backends.clone())
             }
         };
 
@@ -442,6 +610,7 @@ impl Server {
             scm_listeners: None,
             pool,
             backends,
+            health_checks: HealthChecker::new(),
             zombie_check_interval: Duration::seconds(i64::from(
                 server_config.zombie_check_interval,
             )),
@@ -450,6 +619,15 @@ impl Server {
             base_sessions_count,
         };
 
+        // the listeners sent by the main process, before the initial state
+        // activates them
+        info!("will try to receive listeners");
+        server.scm.set_blocking(true);
+        let listeners = server.scm.receive_listeners().ok();
+        server.scm.set_blocking(false);
+        info!("received listeners: {:?}", listeners);
+        server.scm_listeners = listeners;
+
         // initialize the worker with the state we got from a file
         if let Some(state) = config_state {
             for (counter, order) in state.generate_orders().iter().enumerate() {
@@ -484,13 +662,6 @@ impl Server {
             server.channel.nonblocking();
         }
 
-        info!("will try to receive listeners");
-        server.scm.set_blocking(true);
-        let listeners = server.scm.receive_listeners().ok();
-        server.scm.set_blocking(false);
-        info!("received listeners: {:?}", listeners);
-        server.scm_listeners = listeners;
-
         Ok(server)
     }
 }
@@ -506,6 +677,10 @@ impl Server {
         let mut last_sessions_len = self.sessions.borrow().slab.len();
         let mut should_poll_at: Option<Instant> = None;
         let mut last_shutting_down_message = None;
+        // the drain deadline of a soft stop, and the client sessions it started with
+        let mut shutting_down_deadline: Option<Instant> = None;
+        let mut shutting_down_sessions = 0;
+        let mut killed_sessions = 0;
 
         let mut loop_start = Instant::now();
         loop {
@@ -617,6 +792,7 @@ impl Server {
                                     // do we really want to crash the server here?
                                     let msg = msg.expect("the message should be valid");
 
+                                    record_order(&msg);
                                     match msg.order {
                                         ProxyRequestOrder::HardStop => {
                                             let id_msg = msg.id.clone();
@@ -625,8 +801,13 @@ impl Server {
                                             self.channel.run();
                                             return;
                                         }
-                                        ProxyRequestOrder::SoftStop => {
+                                        ProxyRequestOrder::SoftStop(deadline) => {
                                             self.shutting_down = Some(msg.id.clone());
+                                            shutting_down_deadline = deadline.map(|seconds| {
+                                                Instant::now() + Duration::seconds(seconds as i64)
+                                            });
+                                            shutting_down_sessions =
+                                                self.sessions.borrow().nb_connections;
                                             last_sessions_len = self.sessions.borrow().slab.len();
                                             self.notify(msg);
                                         }
@@ -677,6 +858,24 @@ impl Server {
 
             should_poll_at = TIMER.with(|timer| timer.borrow().next_poll_date());
 
+            let health_check_date = self.health_checks.run(&mut self.backends.borrow_mut());
+            should_poll_at = match (should_poll_at, health_check_date) {
+                (Some(timer_date), Some(health_check_date)) => {
+                    Some(timer_date.min(health_check_date))
+                }
+                (timer_date, health_check_date) => timer_date.or(health_check_date),
+            };
+
+            let (queued_sessions, queue_date) =
+                self.backends.borrow_mut().dequeue_requests(Instant::now());
+            for token in queued_sessions {
+                self.wake_queued_session(token);
+            }
+            should_poll_at = match (should_poll_at, queue_date) {
+                (Some(date), Some(queue_date)) => Some(date.min(queue_date)),
+                (date, queue_date) => date.or(queue_date),
+            };
+
             let now = Instant::now();
             if now - last_zombie_check > self.zombie_check_interval {
                 info!("zombie check");
@@ -763,11 +962,21 @@ impl Server {
 
             gauge!("client.connections", self.sessions.borrow().nb_connections);
             gauge!("slab.count", self.sessions.borrow().slab.len());
+            SESSIONS.with(|sessions| sessions.set(self.sessions.borrow().nb_connections));
             METRICS.with(|metrics| {
                 (*metrics.borrow_mut()).send_data();
             });
 
             if self.shutting_down.is_some() {
+                if matches!(shutting_down_deadline, Some(deadline) if Instant::now() >= deadline) {
+                    shutting_down_deadline = None;
+                    killed_sessions = self.close_client_sessions();
+                    info!(
+                        "drain deadline reached, closed {} remaining sessions",
+                        killed_sessions
+                    );
+                }
+
                 let sessions_count = self.sessions.borrow().slab.len();
                 let slab = { self.sessions.borrow_mut().slab.clone() };
                 for session in slab {
@@ -801,7 +1010,10 @@ impl Server {
                             .take()
                             .expect("should have shut down correctly"), // panicking here makes sense actually
                         status: ProxyResponseStatus::Ok,
-                        content: None,
+                        content: Some(ProxyResponseContent::SoftStop(SoftStopReport {
+                            drained: shutting_down_sessions.saturating_sub(killed_sessions),
+                            killed: killed_sessions,
+                        })),
                     });
                     return;
                 } else if new_sessions_count < last_sessions_len {
@@ -816,6 +1028,62 @@ impl Server {
         }
     }
 
+    /// closes the client sessions left when the drain deadline of a soft stop
+    /// is reached, and returns how many were closed
+    /// sends the report of a panic of the event loop to the main process. The
+    /// channel is flushed first, it may be in the middle of a message
+    pub fn send_crash_report(&mut self, report: CrashReport) {
+        let response = ProxyResponse {
+            id: String::from("CRASH"),
+            status: ProxyResponseStatus::Error(report.message.clone()),
+            content: Some(ProxyResponseContent::Crash(report)),
+        };
+        self.channel.blocking();
+        self.channel.write_message(&response);
+    }
+
+    fn close_client_sessions(&mut self) -> usize {
+        let mut tokens = HashSet::new();
+        let mut frontend_tokens = HashSet::new();
+
+        for (_index, session) in self.sessions.borrow().slab.iter() {
+            let session = session.borrow();
+            if !matches!(
+                session.protocol(),
+                Protocol::HTTP | Protocol::HTTPS | Protocol::TCP
+            ) {
+                continue;
+            }
+            let t = session.tokens();
+            if frontend_tokens.insert(t[0]) {
+                tokens.extend(t);
+            }
+        }
+
+        for tk in frontend_tokens.iter() {
+            let cl = self.to_session(*tk);
+            if self.sessions.borrow().slab.contains(cl.0) {
+                let session = { self.sessions.borrow_mut().slab.remove(cl.0) };
+                session.borrow_mut().close();
+
+                let mut sessions = self.sessions.borrow_mut();
+                sessions.nb_connections = sessions.nb_connections.saturating_sub(1);
+                gauge!("client.connections", sessions.nb_connections);
+            }
+        }
+
+        for tk in tokens.into_iter() {
+            let cl = self.to_session(tk);
+            let mut sessions = self.sessions.borrow_mut();
+            if sessions.slab.contains(cl.0) {
+                sessions.slab.remove(cl.0);
+            }
+        }
+
+        count!("shutdown.killed_sessions", frontend_tokens.len() as i64);
+        frontend_tokens.len()
+    }
+
     fn send_queue(&mut self) {
         if self.channel.readiness.is_writable() {
             QUEUE.with(|q| {
@@ -856,6 +1124,36 @@ impl Server {
             return;
         }
 
+        if let ProxyRequestOrder::SetAccessLogFilters(filters) = &message.order {
+            ACCESS_LOG_FILTERS.with(|tapped| {
+                *tapped.borrow_mut() = filters
+                    .iter()
+                    .map(|filter| (filter.clone(), AccessLogRateLimit::default()))
+                    .collect();
+            });
+            push_queue(ProxyResponse::ok(message.id));
+            return;
+        }
+
+        if let ProxyRequestOrder::Batch(orders) = message.order {
+            self.notify_batch(message.id, orders);
+            return;
+        }
+
+        // the session may have closed while the main process resolved the name
+        if let ProxyRequestOrder::NameResolved(resolution) = message.order {
+            let session = self.sessions.borrow().slab.get(resolution.session).cloned();
+            match session {
+                Some(session) => session
+                    .borrow_mut()
+                    .name_resolved(resolution, session.clone()),
+                None => {
+                    debug!("no session waits for the resolution of {}", resolution.name);
+                }
+            }
+            return;
+        }
+
         if let ProxyRequestOrder::Query(ref query) = message.order {
             match query {
                 Query::ClustersHashes => {
@@ -871,7 +1169,7 @@ impl Server {
                 Query::Clusters(query_type) => {
                     let query_answer = match query_type {
                         QueryClusterType::ClusterId(cluster_id) => {
-                            QueryAnswer::Clusters(vec![self.config_state.cluster_state(cluster_id)])
+                            QueryAnswer::Clusters(vec![self.cluster_state(cluster_id)])
                         }
                         QueryClusterType::Domain(domain) => {
                             let cluster_ids = get_cluster_ids_by_domain(
@@ -881,7 +1179,7 @@ impl Server {
                             );
                             let answer = cluster_ids
                                 .iter()
-                                .map(|cluster_id| self.config_state.cluster_state(cluster_id))
+                                .map(|cluster_id| self.cluster_state(cluster_id))
                                 .collect();
 
                             QueryAnswer::Clusters(answer)
@@ -900,6 +1198,8 @@ impl Server {
                         QueryCertificateType::Domain(_) => {}
                         // forward the query to the TLS implementation
                         QueryCertificateType::All => {}
+                        // forward the query to the TLS implementation
+                        QueryCertificateType::Resolve(_) => {}
                         QueryCertificateType::Fingerprint(f) => {
                             push_queue(ProxyResponse {
                                 id: message.id.clone(),
@@ -914,6 +1214,31 @@ impl Server {
                         }
                     }
                 }
+                Query::BackendHealth(cluster_id) => {
+                    let health = self
+                        .health_checks
+                        .backend_health(cluster_id.as_deref(), &self.backends.borrow());
+                    push_queue(ProxyResponse {
+                        id: message.id.clone(),
+                        status: ProxyResponseStatus::Ok,
+                        content: Some(ProxyResponseContent::Query(QueryAnswer::BackendHealth(
+                            health,
+                        ))),
+                    });
+                    return;
+                }
+                Query::Backends(cluster_id) => {
+                    let backends = self
+                        .backends
+                        .borrow()
+                        .query_backends(cluster_id.as_deref(), Instant::now());
+                    push_queue(ProxyResponse {
+                        id: message.id.clone(),
+                        status: ProxyResponseStatus::Ok,
+                        content: Some(ProxyResponseContent::Query(QueryAnswer::Backends(backends))),
+                    });
+                    return;
+                }
                 Query::Metrics(query_metrics_options) => {
                     METRICS.with(|metrics| {
                         let data = (*metrics.borrow_mut()).query(query_metrics_options);
@@ -932,6 +1257,88 @@ impl Server {
         self.notify_proxys(message);
     }
 
+    /// applies the orders one after the other. If one of them fails, the orders of
+    /// the diff with the state before the batch undo the ones already applied
+    fn notify_batch(&mut self, id: MessageId, orders: Vec<ProxyRequestOrder>) {
+        let state_before = self.config_state.clone();
+
+        let mut failure = None;
+        for (index, order) in orders.into_iter().enumerate() {
+            if !order.is_reversible() {
+                failure = Some(format!(
+                    "order {} of the batch cannot be rolled back",
+                    index
+                ));
+                break;
+            }
+
+            // the failed order is not in the proxies, it is not rolled back
+            let state_before_order = self.config_state.clone();
+            let errors = self.notify_proxys_for_errors(format!("{}-{}", id, index), order);
+            if !errors.is_empty() {
+                self.config_state = state_before_order;
+                failure = Some(format!(
+                    "order {} of the batch failed: {}",
+                    index,
+                    errors.join(", ")
+                ));
+                break;
+            }
+        }
+
+        let message = match failure {
+            None => {
+                push_queue(ProxyResponse::ok(id));
+                return;
+            }
+            Some(message) => message,
+        };
+
+        error!("{}, rolling back the batch {}", message, id);
+        let rollback = self.config_state.diff(&state_before);
+        for (index, order) in rollback.into_iter().enumerate() {
+            let errors = self.notify_proxys_for_errors(format!("{}-ROLLBACK-{}", id, index), order);
+            if !errors.is_empty() {
+                error!(
+                    "could not roll back the batch {}: {}",
+                    id,
+                    errors.join(", ")
+                );
+            }
+        }
+
+        push_queue(ProxyResponse::error(id, message));
+    }
+
+    /// notifies the proxies, and takes their answers out of the queue
+    fn notify_proxys_for_errors(&mut self, id: MessageId, order: ProxyRequestOrder) -> Vec<String> {
+        self.notify_proxys(ProxyRequest {
+            id: id.clone(),
+            order,
+        });
+
+        QUEUE.with(|queue| {
+            let mut errors = Vec::new();
+            queue.borrow_mut().retain(|response| {
+                if response.id != id {
+                    return true;
+                }
+                if let ProxyResponseStatus::Error(message) = &response.status {
+                    errors.push(message.clone());
+                }
+                false
+            });
+            errors
+        })
+    }
+
+    /// the configuration of a cluster, with the backends failing their health checks
+    fn cluster_state(&self, cluster_id: &str) -> QueryAnswerCluster {
+        let mut cluster_state = self.config_state.cluster_state(cluster_id);
+        cluster_state.unhealthy_backends = self.backends.borrow().unhealthy_backends(cluster_id);
+        cluster_state
+    }
+
     pub fn notify_proxys(&mut self, message: ProxyRequest) {
         self.config_state.handle_order(&message.order);
 
@@ -947,19 +1354,57 @@ impl Server {
                         cluster.load_balancing,
                         cluster.load_metric,
                     );
+                self.backends
+                    .borrow_mut()
+                    .set_tls_for_cluster(&cluster.cluster_id, cluster.backend_tls.as_deref());
+                self.backends
+                    .borrow_mut()
+                    .set_outlier_detection_for_cluster(
+                        &cluster.cluster_id,
+                        cluster.outlier_detection,
+                    );
+                self.backends
+                    .borrow_mut()
+                    .set_request_queue_for_cluster(&cluster.cluster_id, cluster.request_queue);
+                self.backends
+                    .borrow_mut()
+                    .set_affinity_table_for_cluster(&cluster.cluster_id, cluster.affinity_table);
+                self.health_checks.set_cluster(
+                    &cluster.cluster_id,
+                    cluster.health_check.clone(),
+                    &mut self.backends.borrow_mut(),
+                );
                 //not returning because the message must still be handled by each proxy
             }
             ProxyRequest {
                 ref id,
                 order: ProxyRequestOrder::AddBackend(ref backend),
             } => {
-                let new_backend = Backend::new(
+                let mut new_backend = Backend::new(
                     &backend.backend_id,
                     backend.address,
                     backend.sticky_id.clone(),
                     backend.load_balancing_parameters.clone(),
                     backend.backup,
                 );
+                new_backend.max_connections = backend.max_connections;
+                new_backend.timeouts = backend.timeouts;
+                if let Some(tls) = &backend.tls {
+                    match BackendTlsConfig::new(tls) {
+                        Ok(tls) => new_backend.tls = Some(Rc::new(tls)),
+                        Err(e) => {
+                            error!(
+                                "invalid TLS configuration for backend {}: {}",
+                                backend.backend_id, e
+                            );
+                            push_queue(ProxyResponse::error(
+                                id,
+                                format!("invalid TLS configuration: {}", e),
+                            ));
+                            return;
+                        }
+                    }
+                }
                 self.backends
                     .borrow_mut()
                     .add_backend(&backend.cluster_id, new_backend);
@@ -967,6 +1412,14 @@ impl Server {
                 push_queue(ProxyResponse::ok(id));
                 return;
             }
+            ProxyRequest {
+                order: ProxyRequestOrder::RemoveCluster { ref cluster_id },
+                ..
+            } => {
+                self.health_checks
+                    .set_cluster(cluster_id, None, &mut self.backends.borrow_mut());
+                //not returning because the message must still be handled by each proxy
+            }
             ProxyRequest {
                 ref id,
                 order: ProxyRequestOrder::RemoveBackend(ref backend),
@@ -978,6 +1431,55 @@ impl Server {
                 push_queue(ProxyResponse::ok(id));
                 return;
             }
+            ProxyRequest {
+                ref id,
+                order: ProxyRequestOrder::DrainBackend(ref backend),
+            } => {
+                let status = if self.backends.borrow_mut().drain_backend(
+                    &backend.cluster_id,
+                    &backend.address,
+                    backend.remove,
+                ) {
+                    ProxyResponseStatus::Ok
+                } else {
+                    ProxyResponseStatus::Error(format!(
+                        "cluster {} has no backend at {}",
+                        backend.cluster_id, backend.address
+                    ))
+                };
+
+                push_queue(ProxyResponse::status(id, status));
+                return;
+            }
+            ProxyRequest {
+                ref id,
+                order: ProxyRequestOrder::SetAffinity(ref affinity),
+            } => {
+                self.backends.borrow_mut().set_affinity(affinity);
+
+                push_queue(ProxyResponse::ok(id));
+                return;
+            }
+            ProxyRequest {
+                ref id,
+                order: ProxyRequestOrder::UpdateBackendWeight(ref update),
+            } => {
+                let status = if self.backends.borrow_mut().set_backend_weight(
+                    &update.cluster_id,
+                    &update.address,
+                    update.weight,
+                ) {
+                    ProxyResponseStatus::Ok
+                } else {
+                    ProxyResponseStatus::Error(format!(
+                        "cluster {} has no backend at {}",
+                        update.cluster_id, update.address
+                    ))
+                };
+
+                push_queue(ProxyResponse::status(id, status));
+                return;
+            }
             _ => {}
         };
 
@@ -1041,6 +1543,7 @@ impl Server {
                 } => {
                     if activate.proxy == ListenerType::HTTP {
                         debug!("{} activate http listener {:?}", id, activate);
+                        self.receive_scm_listeners(activate);
                         let listener = self
                             .scm_listeners
                             .as_mut()
@@ -1177,6 +1680,7 @@ impl Server {
                 } => {
                     if activate.proxy == ListenerType::HTTPS {
                         debug!("{} activate https listener {:?}", id, activate);
+                        self.receive_scm_listeners(activate);
                         let listener = self
                             .scm_listeners
                             .as_mut()
@@ -1310,6 +1814,7 @@ impl Server {
                 } => {
                     if activate.proxy == ListenerType::TCP {
                         debug!("{} activate tcp listener {:?}", id, activate);
+                        self.receive_scm_listeners(activate);
                         let listener = self
                             .scm_listeners
                             .as_mut()
@@ -1391,6 +1896,37 @@ impl Server {
         }
     }
 
+    /// the main process sends the sockets on the scm socket before the orders
+    /// activating them with `from_scm`, like when it gives them back to this
+    /// worker after a failed upgrade
+    fn receive_scm_listeners(&mut self, activate: &ActivateListener) {
+        if !activate.from_scm {
+            return;
+        }
+
+        // the main process sends one message on the scm socket for each
+        // order activating a listener from it
+        self.scm.set_blocking(true);
+        match self.scm.receive_listeners() {
+            Ok(received) => {
+                info!("received listeners: {:?}", received);
+                match self.scm_listeners.as_mut() {
+                    Some(listeners) => {
+                        merge_listeners(&mut listeners.http, received.http);
+                        merge_listeners(&mut listeners.tls, received.tls);
+                        merge_listeners(&mut listeners.tcp, received.tcp);
+                    }
+                    None => self.scm_listeners = Some(received),
+                }
+            }
+            Err(e) => error!(
+                "could not receive the listeners from the main process: {}",
+                e
+            ),
+        }
+        self.scm.set_blocking(false);
+    }
+
     pub fn return_listen_sockets(&mut self) {
         self.scm.set_blocking(false);
 
@@ -1555,23 +2091,7 @@ impl Server {
                     }
                 }
                 Protocol::HTTPSListen => {
-                    let res = match self.https {
-                        #[cfg(feature = "use-openssl")]
-                        HttpsProvider::Openssl(ref mut openssl) => {
-                            let o = openssl.clone();
-                            openssl
-                                .borrow_mut()
-                                .create_session(sock, token, wait_time, o)
-                        }
-                        HttpsProvider::Rustls(ref mut rustls) => {
-                            let r = rustls.clone();
-                            rustls
-                                .borrow_mut()
-                                .create_session(sock, token, wait_time, r)
-                        }
-                    };
-
-                    if res.is_err() {
+                    if self.https.create_session(sock, token, wait_time).is_err() {
                         break;
                     }
                 }
@@ -1640,6 +2160,15 @@ impl Server {
         }
     }
 
+    /// lets a session that waited in the queue of a cluster try to connect again
+    fn wake_queued_session(&mut self, token: Token) {
+        let session = match self.sessions.borrow().slab.get(token.0) {
+            Some(session) => session.clone(),
+            None => return,
+        };
+        session.borrow_mut().ready(session.clone());
+    }
+
     pub fn handle_remaining_readiness(&mut self) {
         // try to accept again after handling all session events,
         // since we might have released a few session slots
@@ -1700,152 +2229,241 @@ use crate::https_openssl;
 
 use crate::https_rustls;
 
-#[cfg(feature = "use-openssl")]
-pub enum HttpsProvider {
-    Openssl(Rc<RefCell<https_openssl::Proxy>>),
-    Rustls(Rc<RefCell<https_rustls::configuration::Proxy>>),
-}
+/// the operations of an HTTPS proxy that the server uses, implemented by the rustls
+/// and the OpenSSL proxies so that their listeners can run side by side
+pub trait HttpsProxy {
+    fn notify(&mut self, message: ProxyRequest) -> ProxyResponse;
 
-#[cfg(not(feature = "use-openssl"))]
-pub enum HttpsProvider {
-    Rustls(Rc<RefCell<https_rustls::configuration::Proxy>>),
+    fn add_listener(&mut self, config: HttpsListener, token: Token) -> Option<Token>;
+
+    fn activate_listener(
+        &mut self,
+        addr: &SocketAddr,
+        tcp_listener: Option<TcpListener>,
+    ) -> Option<Token>;
+
+    fn give_back_listeners(&mut self) -> Vec<(SocketAddr, TcpListener)>;
+
+    fn give_back_listener(&mut self, address: SocketAddr) -> Option<(Token, TcpListener)>;
+
+    fn accept(&mut self, token: ListenToken) -> Result<TcpStream, AcceptError>;
+
+    fn create_session(
+        &mut self,
+        frontend_sock: TcpStream,
+        token: ListenToken,
+        wait_time: Duration,
+    ) -> Result<(), AcceptError>;
 }
 
-#[cfg(feature = "use-openssl")]
-impl HttpsProvider {
-    pub fn new(
-        use_openssl: bool,
-        registry: Registry,
-        sessions: Rc<RefCell<SessionManager>>,
-        pool: Rc<RefCell<Pool>>,
-        backends: Rc<RefCell<BackendMap>>,
-    ) -> HttpsProvider {
-        if use_openssl {
-            HttpsProvider::Openssl(Rc::new(RefCell::new(https_openssl::Proxy::new(
-                registry, sessions, pool, backends,
-            ))))
-        } else {
-            HttpsProvider::Rustls(Rc::new(RefCell::new(
-                https_rustls::configuration::Proxy::new(registry, sessions, pool, backends),
-            )))
-        }
+impl HttpsProxy for Rc<RefCell<https_rustls::configuration::Proxy>> {
+    fn notify(&mut self, message: ProxyRequest) -> ProxyResponse {
+        self.borrow_mut().notify(message)
     }
 
-    pub fn notify(&mut self, message: ProxyRequest) -> ProxyResponse {
-        match self {
-            &mut HttpsProvider::Rustls(ref mut rustls) => rustls.borrow_mut().notify(message),
-            &mut HttpsProvider::Openssl(ref mut openssl) => openssl.borrow_mut().notify(message),
-        }
+    fn add_listener(&mut self, config: HttpsListener, token: Token) -> Option<Token> {
+        self.borrow_mut().add_listener(config, token).ok().flatten()
     }
 
-    pub fn add_listener(&mut self, config: HttpsListener, token: Token) -> Option<Token> {
-        match self {
-            &mut HttpsProvider::Rustls(ref mut rustls) => rustls
-                .borrow_mut()
-                .add_listener(config, token)
-                .ok()
-                .flatten(),
-            &mut HttpsProvider::Openssl(ref mut openssl) => {
-                openssl.borrow_mut().add_listener(config, token)
-            }
-        }
+    fn activate_listener(
+        &mut self,
+        addr: &SocketAddr,
+        tcp_listener: Option<TcpListener>,
+    ) -> Option<Token> {
+        self.borrow_mut().activate_listener(addr, tcp_listener)
     }
 
-    pub fn activate_listener(
+    fn give_back_listeners(&mut self) -> Vec<(SocketAddr, TcpListener)> {
+        self.borrow_mut().give_back_listeners()
+    }
+
+    fn give_back_listener(&mut self, address: SocketAddr) -> Option<(Token, TcpListener)> {
+        self.borrow_mut().give_back_listener(address)
+    }
+
+    fn accept(&mut self, token: ListenToken) -> Result<TcpStream, AcceptError> {
+        self.borrow_mut().accept(token)
+    }
+
+    fn create_session(
+        &mut self,
+        frontend_sock: TcpStream,
+        token: ListenToken,
+        wait_time: Duration,
+    ) -> Result<(), AcceptError> {
+        let r = self.clone();
+        self.borrow_mut()
+            .create_session(frontend_sock, token, wait_time, r)
+    }
+}
+
+#[cfg(feature = "use-openssl")]
+impl HttpsProxy for Rc<RefCell<https_openssl::Proxy>> {
+    fn notify(&mut self, message: ProxyRequest) -> ProxyResponse {
+        self.borrow_mut().notify(message)
+    }
+
+    fn add_listener(&mut self, config: HttpsListener, token: Token) -> Option<Token> {
+        self.borrow_mut().add_listener(config, token)
+    }
+
+    fn activate_listener(
         &mut self,
         addr: &SocketAddr,
         tcp_listener: Option<TcpListener>,
     ) -> Option<Token> {
-        match self {
-            &mut HttpsProvider::Rustls(ref mut rustls) => {
-                rustls.borrow_mut().activate_listener(addr, tcp_listener)
-            }
-            &mut HttpsProvider::Openssl(ref mut openssl) => {
-                openssl.borrow_mut().activate_listener(addr, tcp_listener)
-            }
-        }
+        self.borrow_mut().activate_listener(addr, tcp_listener)
     }
 
-    pub fn give_back_listeners(&mut self) -> Vec<(SocketAddr, TcpListener)> {
-        match self {
-            &mut HttpsProvider::Rustls(ref mut rustls) => rustls.borrow_mut().give_back_listeners(),
-            &mut HttpsProvider::Openssl(ref mut openssl) => {
-                openssl.borrow_mut().give_back_listeners()
-            }
-        }
+    fn give_back_listeners(&mut self) -> Vec<(SocketAddr, TcpListener)> {
+        self.borrow_mut().give_back_listeners()
     }
 
-    pub fn give_back_listener(&mut self, address: SocketAddr) -> Option<(Token, TcpListener)> {
-        match self {
-            &mut HttpsProvider::Rustls(ref mut rustls) => {
-                rustls.borrow_mut().give_back_listener(address)
-            }
-            &mut HttpsProvider::Openssl(ref mut openssl) => {
-                openssl.borrow_mut().give_back_listener(address)
-            }
-        }
+    fn give_back_listener(&mut self, address: SocketAddr) -> Option<(Token, TcpListener)> {
+        self.borrow_mut().give_back_listener(address)
     }
 
-    pub fn accept(&mut self, token: ListenToken) -> Result<TcpStream, AcceptError> {
-        match self {
-            &mut HttpsProvider::Rustls(ref mut rustls) => rustls.borrow_mut().accept(token),
-            &mut HttpsProvider::Openssl(ref mut openssl) => openssl.borrow_mut().accept(token),
-        }
+    fn accept(&mut self, token: ListenToken) -> Result<TcpStream, AcceptError> {
+        self.borrow_mut().accept(token)
     }
 
-    pub fn create_session(
+    fn create_session(
         &mut self,
         frontend_sock: TcpStream,
         token: ListenToken,
         wait_time: Duration,
     ) -> Result<(), AcceptError> {
-        match self {
-            &mut HttpsProvider::Rustls(ref mut rustls) => {
-                let r = rustls.clone();
-                rustls
-                    .borrow_mut()
-                    .create_session(frontend_sock, token, wait_time, r)
-            }
-            &mut HttpsProvider::Openssl(ref mut openssl) => {
-                let o = openssl.clone();
-                openssl
-                    .borrow_mut()
-                    .create_session(frontend_sock, token, wait_time, o)
-            }
-        }
+        let o = self.clone();
+        self.borrow_mut()
+            .create_session(frontend_sock, token, wait_time, o)
     }
 }
 
-#[cfg(not(feature = "use-openssl"))]
+/// dispatches the HTTPS listeners to the rustls or the OpenSSL proxy, following
+/// the `tls_provider` of each listener. The orders concerning a listener are sent
+/// to its proxy, the other ones to both proxies
+pub struct HttpsProvider {
+    rustls: Rc<RefCell<https_rustls::configuration::Proxy>>,
+    #[cfg(feature = "use-openssl")]
+    openssl: Rc<RefCell<https_openssl::Proxy>>,
+    /// token and implementation of each listener, by address
+    listeners: HashMap<SocketAddr, (Token, TlsProvider)>,
+}
+
 impl HttpsProvider {
     pub fn new(
-        use_openssl: bool,
         registry: Registry,
         sessions: Rc<RefCell<SessionManager>>,
         pool: Rc<RefCell<Pool>>,
         backends: Rc<RefCell<BackendMap>>,
     ) -> HttpsProvider {
-        if use_openssl {
-            error!("the openssl provider is not compiled, continuing with the rustls provider");
+        #[cfg(feature = "use-openssl")]
+        let openssl = Rc::new(RefCell::new(https_openssl::Proxy::new(
+            registry
+                .try_clone()
+                .expect("could not clone the mio Registry"),
+            sessions.clone(),
+            pool.clone(),
+            backends.clone(),
+        )));
+
+        HttpsProvider {
+            rustls: Rc::new(RefCell::new(https_rustls::configuration::Proxy::new(
+                registry, sessions, pool, backends,
+            ))),
+            #[cfg(feature = "use-openssl")]
+            openssl,
+            listeners: HashMap::new(),
+        }
+    }
+
+    /// selects the certificate resolvers of the rustls listeners, before they are added
+    pub fn set_delegated_resolvers(&mut self, resolvers: DelegatedCertificateResolvers) {
+        let mut rustls = self.rustls.borrow_mut();
+        for (address, resolver) in resolvers {
+            rustls.set_delegated_resolver(address, resolver);
         }
+    }
+
+    fn proxy(&mut self, provider: TlsProvider) -> &mut dyn HttpsProxy {
+        match provider {
+            #[cfg(feature = "use-openssl")]
+            TlsProvider::Openssl => &mut self.openssl,
+            _ => &mut self.rustls,
+        }
+    }
+
+    fn proxies(&mut self) -> Vec<&mut dyn HttpsProxy> {
+        vec![
+            &mut self.rustls,
+            #[cfg(feature = "use-openssl")]
+            &mut self.openssl,
+        ]
+    }
 
-        let configuration =
-            https_rustls::configuration::Proxy::new(registry, sessions, pool, backends);
-        HttpsProvider::Rustls(Rc::new(RefCell::new(configuration)))
+    fn provider_by_token(&self, token: Token) -> TlsProvider {
+        self.listeners
+            .values()
+            .find(|(listener_token, _)| *listener_token == token)
+            .map(|(_, provider)| *provider)
+            .unwrap_or_default()
     }
 
     pub fn notify(&mut self, message: ProxyRequest) -> ProxyResponse {
-        let &mut HttpsProvider::Rustls(ref mut rustls) = self;
-        rustls.borrow_mut().notify(message)
+        if let Some(address) = listener_address(&message.order) {
+            let provider = self
+                .listeners
+                .get(&address)
+                .map(|(_, provider)| *provider)
+                .unwrap_or_default();
+            if let ProxyRequestOrder::RemoveListener(_) = message.order {
+                self.listeners.remove(&address);
+            }
+
+            return self.proxy(provider).notify(message);
+        }
+
+        self.proxies()
+            .into_iter()
+            .map(|proxy| proxy.notify(message.clone()))
+            .reduce(merge_responses)
+            .unwrap_or_else(|| ProxyResponse::error(message.id, "no HTTPS proxy"))
     }
 
     pub fn add_listener(&mut self, config: HttpsListener, token: Token) -> Option<Token> {
-        let &mut HttpsProvider::Rustls(ref mut rustls) = self;
-        rustls
-            .borrow_mut()
-            .add_listener(config, token)
-            .ok()
-            .flatten()
+        let address = config.address;
+        let provider = config.tls_provider;
+        if cfg!(not(feature = "use-openssl")) && provider == TlsProvider::Openssl {
+            error!(
+                "the openssl provider is not compiled, the listener {} uses the rustls provider",
+                address
+            );
+        }
+
+        if provider == TlsProvider::Openssl
+            && self
+                .rustls
+                .borrow()
+                .delegated_resolvers
+                .contains_key(&address)
+        {
+            error!(
+                "delegated certificate resolvers need the rustls provider, the listener {} will not use it",
+                address
+            );
+        }
+
+        if cfg!(feature = "use-openssl") && provider == TlsProvider::Openssl && config.http2.enabled
+        {
+            error!(
+                "HTTP/2 needs the rustls provider, the listener {} will only serve HTTP/1.1",
+                address
+            );
+        }
+
+        let token = self.proxy(provider).add_listener(config, token)?;
+        self.listeners.insert(address, (token, provider));
+        Some(token)
     }
 
     pub fn activate_listener(
@@ -1853,24 +2471,33 @@ impl HttpsProvider {
         addr: &SocketAddr,
         tcp_listener: Option<TcpListener>,
     ) -> Option<Token> {
-        let &mut HttpsProvider::Rustls(ref mut rustls) = self;
-
-        rustls.borrow_mut().activate_listener(addr, tcp_listener)
+        let provider = self
+            .listeners
+            .get(addr)
+            .map(|(_, provider)| *provider)
+            .unwrap_or_default();
+        self.proxy(provider).activate_listener(addr, tcp_listener)
     }
 
     pub fn give_back_listeners(&mut self) -> Vec<(SocketAddr, TcpListener)> {
-        let &mut HttpsProvider::Rustls(ref mut rustls) = self;
-        rustls.borrow_mut().give_back_listeners()
+        self.proxies()
+            .into_iter()
+            .flat_map(|proxy| proxy.give_back_listeners())
+            .collect()
     }
 
     pub fn give_back_listener(&mut self, address: SocketAddr) -> Option<(Token, TcpListener)> {
-        let &mut HttpsProvider::Rustls(ref mut rustls) = self;
-        rustls.borrow_mut().give_back_listener(address)
+        let provider = self
+            .listeners
+            .get(&address)
+            .map(|(_, provider)| *provider)
+            .unwrap_or_default();
+        self.proxy(provider).give_back_listener(address)
     }
 
     pub fn accept(&mut self, token: ListenToken) -> Result<TcpStream, AcceptError> {
-        let &mut HttpsProvider::Rustls(ref mut rustls) = self;
-        rustls.borrow_mut().accept(token)
+        let provider = self.provider_by_token(Token(token.0));
+        self.proxy(provider).accept(token)
     }
 
     pub fn create_session(
@@ -1879,11 +2506,87 @@ impl HttpsProvider {
         token: ListenToken,
         wait_time: Duration,
     ) -> Result<(), AcceptError> {
-        let &mut HttpsProvider::Rustls(ref mut rustls) = self;
-        let r = rustls.clone();
-        rustls
-            .borrow_mut()
-            .create_session(frontend_sock, token, wait_time, r)
+        let provider = self.provider_by_token(Token(token.0));
+        self.proxy(provider)
+            .create_session(frontend_sock, token, wait_time)
+    }
+}
+
+/// adds the sockets received from the main process to the ones not activated
+/// yet. A new socket replaces the one of the same address, that is closed
+fn merge_listeners(listeners: &mut Vec<(SocketAddr, RawFd)>, received: Vec<(SocketAddr, RawFd)>) {
+    for (address, fd) in received {
+        if let Some(pos) = listeners.iter().position(|(old, _)| *old == address) {
+            let (_, old_fd) = listeners.remove(pos);
+            let _ = unsafe { TcpListener::from_raw_fd(old_fd) };
+        }
+        listeners.push((address, fd));
+    }
+}
+
+/// the address of the listener an order applies to, when it concerns a single listener
+fn listener_address(order: &ProxyRequestOrder) -> Option<SocketAddr> {
+    match order {
+        ProxyRequestOrder::AddHttpsFrontend(front)
+        | ProxyRequestOrder::RemoveHttpsFrontend(front) => Some(front.address),
+        ProxyRequestOrder::AddCertificate(add) => Some(add.address),
+        ProxyRequestOrder::RemoveCertificate(remove) => Some(remove.address),
+        ProxyRequestOrder::ReplaceCertificate(replace) => Some(replace.address),
+        ProxyRequestOrder::SetOcspResponse(set) => Some(set.address),
+        ProxyRequestOrder::SetDefaultCertificate(set) => Some(set.address),
+        ProxyRequestOrder::AddAcl(acl) => Some(acl.address),
+        ProxyRequestOrder::RemoveAcl(remove) => Some(remove.address),
+        ProxyRequestOrder::RemoveListener(remove) => Some(remove.address),
+        ProxyRequestOrder::Query(Query::Certificates(QueryCertificateType::Resolve(resolve))) => {
+            Some(resolve.address)
+        }
+        _ => None,
+    }
+}
+
+/// combines the responses of the HTTPS proxies to the same order: an error or a
+/// processing status takes precedence, and the certificates of the queries are merged
+fn merge_responses(first: ProxyResponse, second: ProxyResponse) -> ProxyResponse {
+    match (&first.status, &second.status) {
+        (ProxyResponseStatus::Error(_), _) | (ProxyResponseStatus::Processing, _) => first,
+        (_, ProxyResponseStatus::Error(_)) | (_, ProxyResponseStatus::Processing) => second,
+        (ProxyResponseStatus::Ok, ProxyResponseStatus::Ok) => {
+            let content = match (first.content, second.content) {
+                (
+                    Some(ProxyResponseContent::Query(QueryAnswer::Certificates(
+                        QueryAnswerCertificate::All(mut first),
+                    ))),
+                    Some(ProxyResponseContent::Query(QueryAnswer::Certificates(
+                        QueryAnswerCertificate::All(second),
+                    ))),
+                ) => {
+                    first.extend(second);
+                    Some(ProxyResponseContent::Query(QueryAnswer::Certificates(
+                        QueryAnswerCertificate::All(first),
+                    )))
+                }
+                (
+                    Some(ProxyResponseContent::Query(QueryAnswer::Certificates(
+                        QueryAnswerCertificate::Domain(mut first),
+                    ))),
+                    Some(ProxyResponseContent::Query(QueryAnswer::Certificates(
+                        QueryAnswerCertificate::Domain(second),
+                    ))),
+                ) => {
+                    first.extend(second);
+                    Some(ProxyResponseContent::Query(QueryAnswer::Certificates(
+                        QueryAnswerCertificate::Domain(first),
+                    )))
+                }
+                (content, _) => content,
+            };
+
+            ProxyResponse {
+                id: first.id,
+                status: ProxyResponseStatus::Ok,
+                content,
+            }
+        }
     }
 }
 
@@ -1894,3 +2597,53 @@ fn clear_ssl_error() {
 
 #[cfg(not(feature = "use-openssl"))]
 fn clear_ssl_error() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_limits() {
+        let sessions = SessionManager::new(Slab::with_capacity(10), 10);
+        let mut sessions = sessions.borrow_mut();
+        sessions.max_sessions = Some(3);
+
+        let (http, tcp) = (Token(0), Token(1));
+        assert!(sessions.open_listener_session(http, Some(2)));
+        assert!(sessions.open_listener_session(http, Some(2)));
+        assert!(!sessions.open_listener_session(http, Some(2)));
+        assert!(sessions.open_listener_session(tcp, None));
+        // the worker holds 3 sessions
+        assert!(!sessions.open_listener_session(tcp, None));
+
+        sessions.close_listener_session(http);
+        assert!(sessions.open_listener_session(http, Some(2)));
+
+        assert!(sessions.enter_cluster("cluster", Some(1)));
+        assert!(!sessions.enter_cluster("cluster", Some(1)));
+        assert!(sessions.enter_cluster("other", Some(1)));
+        sessions.leave_cluster("cluster");
+        assert!(sessions.enter_cluster("cluster", Some(1)));
+    }
+
+    #[test]
+    fn received_listeners_replace_the_unused_ones() {
+        use std::os::unix::io::IntoRawFd;
+
+        let bind = || {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            (listener.local_addr().unwrap(), listener.into_raw_fd())
+        };
+        let (first, old_fd) = bind();
+        let (second, second_fd) = bind();
+        let (_, new_fd) = bind();
+
+        let mut listeners = vec![(first, old_fd), (second, second_fd)];
+        merge_listeners(&mut listeners, vec![(first, new_fd)]);
+        assert_eq!(listeners, vec![(second, second_fd), (first, new_fd)]);
+
+        for (_, fd) in listeners {
+            let _ = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        }
+    }
+}
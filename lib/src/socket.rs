@@ -6,7 +6,7 @@ use std::{
 use mio::net::{TcpListener, TcpStream};
 #[cfg(feature = "use-openssl")]
 use openssl::ssl::{ErrorCode, SslStream, SslVersion};
-use rustls::{ProtocolVersion, ServerConnection};
+use rustls::{ClientConnection, CommonState, ConnectionCommon, ProtocolVersion, ServerConnection};
 use socket2::{Domain, Protocol, Socket, Type};
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -253,196 +253,347 @@ pub struct FrontRustls {
 
 impl SocketHandler for FrontRustls {
     fn socket_read(&mut self, buf: &mut [u8]) -> (usize, SocketResult) {
-        let mut size = 0usize;
-        let mut can_read = true;
-        let mut is_error = false;
-        let mut is_closed = false;
+        rustls_read(&mut self.stream, &mut self.session, buf)
+    }
 
-        loop {
-            if size == buf.len() {
-                break;
-            }
+    fn socket_write(&mut self, buf: &[u8]) -> (usize, SocketResult) {
+        rustls_write(&mut self.stream, &mut self.session, buf)
+    }
 
-            if !can_read | is_error | is_closed {
-                break;
+    fn socket_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    fn socket_mut(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+
+    fn protocol(&self) -> TransportProtocol {
+        rustls_protocol(&self.session)
+    }
+
+    fn read_error(&self) {
+        incr!("rustls.read.error");
+    }
+
+    fn write_error(&self) {
+        incr!("rustls.write.error");
+    }
+}
+
+/// TLS connection to a backend
+pub struct BackRustls {
+    pub stream: TcpStream,
+    pub session: ClientConnection,
+}
+
+impl SocketHandler for BackRustls {
+    fn socket_read(&mut self, buf: &mut [u8]) -> (usize, SocketResult) {
+        let (size, result) = rustls_read(&mut self.stream, &mut self.session, buf);
+
+        // the handshake messages and the data buffered during the handshake
+        // are only sent when reading the answers of the backend
+        while self.session.wants_write() {
+            match self.session.write_tls(&mut self.stream) {
+                Ok(0) => break,
+                Ok(_sz) => {}
+                Err(e) => match e.kind() {
+                    ErrorKind::WouldBlock => break,
+                    _ => {
+                        error!("could not write TLS stream to socket: {:?}", e);
+                        return (size, SocketResult::Error);
+                    }
+                },
             }
+        }
 
-            match self.session.read_tls(&mut self.stream) {
-                Ok(0) => {
+        (size, result)
+    }
+
+    fn socket_write(&mut self, buf: &[u8]) -> (usize, SocketResult) {
+        rustls_write(&mut self.stream, &mut self.session, buf)
+    }
+
+    fn socket_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    fn socket_mut(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+
+    fn protocol(&self) -> TransportProtocol {
+        rustls_protocol(&self.session)
+    }
+
+    fn read_error(&self) {
+        incr!("rustls.read.error");
+    }
+
+    fn write_error(&self) {
+        incr!("rustls.write.error");
+    }
+}
+
+/// connection to a backend, in clear text or over TLS
+pub enum BackendSocket {
+    Tcp(TcpStream),
+    Rustls(Box<BackRustls>),
+}
+
+impl From<TcpStream> for BackendSocket {
+    fn from(stream: TcpStream) -> Self {
+        BackendSocket::Tcp(stream)
+    }
+}
+
+impl SocketHandler for BackendSocket {
+    fn socket_read(&mut self, buf: &mut [u8]) -> (usize, SocketResult) {
+        match self {
+            BackendSocket::Tcp(stream) => stream.socket_read(buf),
+            BackendSocket::Rustls(stream) => stream.socket_read(buf),
+        }
+    }
+
+    fn socket_write(&mut self, buf: &[u8]) -> (usize, SocketResult) {
+        match self {
+            BackendSocket::Tcp(stream) => stream.socket_write(buf),
+            BackendSocket::Rustls(stream) => stream.socket_write(buf),
+        }
+    }
+
+    fn socket_write_vectored(&mut self, bufs: &[std::io::IoSlice]) -> (usize, SocketResult) {
+        match self {
+            BackendSocket::Tcp(stream) => stream.socket_write_vectored(bufs),
+            BackendSocket::Rustls(stream) => stream.socket_write_vectored(bufs),
+        }
+    }
+
+    fn has_vectored_writes(&self) -> bool {
+        match self {
+            BackendSocket::Tcp(stream) => stream.has_vectored_writes(),
+            BackendSocket::Rustls(stream) => stream.has_vectored_writes(),
+        }
+    }
+
+    fn socket_ref(&self) -> &TcpStream {
+        match self {
+            BackendSocket::Tcp(stream) => stream,
+            BackendSocket::Rustls(stream) => stream.socket_ref(),
+        }
+    }
+
+    fn socket_mut(&mut self) -> &mut TcpStream {
+        match self {
+            BackendSocket::Tcp(stream) => stream,
+            BackendSocket::Rustls(stream) => stream.socket_mut(),
+        }
+    }
+
+    fn protocol(&self) -> TransportProtocol {
+        match self {
+            BackendSocket::Tcp(stream) => stream.protocol(),
+            BackendSocket::Rustls(stream) => stream.protocol(),
+        }
+    }
+
+    fn read_error(&self) {
+        match self {
+            BackendSocket::Tcp(stream) => stream.read_error(),
+            BackendSocket::Rustls(stream) => stream.read_error(),
+        }
+    }
+
+    fn write_error(&self) {
+        match self {
+            BackendSocket::Tcp(stream) => stream.write_error(),
+            BackendSocket::Rustls(stream) => stream.write_error(),
+        }
+    }
+}
+
+fn rustls_read<Data>(
+    stream: &mut TcpStream,
+    session: &mut ConnectionCommon<Data>,
+    buf: &mut [u8],
+) -> (usize, SocketResult) {
+    let mut size = 0usize;
+    let mut can_read = true;
+    let mut is_error = false;
+    let mut is_closed = false;
+
+    loop {
+        if size == buf.len() {
+            break;
+        }
+
+        if !can_read | is_error | is_closed {
+            break;
+        }
+
+        match session.read_tls(stream) {
+            Ok(0) => {
+                can_read = false;
+                is_closed = true;
+            }
+            Ok(_sz) => {}
+            Err(e) => match e.kind() {
+                ErrorKind::WouldBlock => {
                     can_read = false;
+                }
+                ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::BrokenPipe => {
                     is_closed = true;
                 }
-                Ok(_sz) => {}
+                _ => {
+                    error!("could not read TLS stream from socket: {:?}", e);
+                    is_error = true;
+                    break;
+                }
+            },
+        }
+
+        if let Err(e) = session.process_new_packets() {
+            error!("could not process read TLS packets: {:?}", e);
+            is_error = true;
+            break;
+        }
+
+        while !session.wants_read() {
+            match session.reader().read(&mut buf[size..]) {
+                Ok(0) => break,
+                Ok(sz) => size += sz,
                 Err(e) => match e.kind() {
                     ErrorKind::WouldBlock => {
-                        can_read = false;
+                        break;
                     }
                     ErrorKind::ConnectionReset
                     | ErrorKind::ConnectionAborted
                     | ErrorKind::BrokenPipe => {
                         is_closed = true;
+                        break;
                     }
                     _ => {
-                        error!("could not read TLS stream from socket: {:?}", e);
+                        error!("could not read data from TLS stream: {:?}", e);
                         is_error = true;
                         break;
                     }
                 },
             }
+        }
+    }
 
-            if let Err(e) = self.session.process_new_packets() {
-                error!("could not process read TLS packets: {:?}", e);
-                is_error = true;
-                break;
-            }
+    if is_error {
+        (size, SocketResult::Error)
+    } else if is_closed {
+        (size, SocketResult::Closed)
+    } else if !can_read {
+        (size, SocketResult::WouldBlock)
+    } else {
+        (size, SocketResult::Continue)
+    }
+}
 
-            while !self.session.wants_read() {
-                match self.session.reader().read(&mut buf[size..]) {
-                    Ok(0) => break,
-                    Ok(sz) => size += sz,
-                    Err(e) => match e.kind() {
-                        ErrorKind::WouldBlock => {
-                            break;
-                        }
-                        ErrorKind::ConnectionReset
-                        | ErrorKind::ConnectionAborted
-                        | ErrorKind::BrokenPipe => {
-                            is_closed = true;
-                            break;
-                        }
-                        _ => {
-                            error!("could not read data from TLS stream: {:?}", e);
-                            is_error = true;
-                            break;
-                        }
-                    },
-                }
-            }
+fn rustls_write<Data>(
+    stream: &mut TcpStream,
+    session: &mut ConnectionCommon<Data>,
+    buf: &[u8],
+) -> (usize, SocketResult) {
+    let mut buffered_size = 0usize;
+    let mut can_write = true;
+    let mut is_error = false;
+    let mut is_closed = false;
+
+    loop {
+        if buffered_size == buf.len() {
+            break;
         }
 
-        if is_error {
-            (size, SocketResult::Error)
-        } else if is_closed {
-            (size, SocketResult::Closed)
-        } else if !can_read {
-            (size, SocketResult::WouldBlock)
-        } else {
-            (size, SocketResult::Continue)
+        if !can_write | is_error | is_closed {
+            break;
         }
-    }
 
-    fn socket_write(&mut self, buf: &[u8]) -> (usize, SocketResult) {
-        let mut buffered_size = 0usize;
-        let mut can_write = true;
-        let mut is_error = false;
-        let mut is_closed = false;
-
-        loop {
-            if buffered_size == buf.len() {
+        match session.writer().write(&buf[buffered_size..]) {
+            Ok(0) => {
                 break;
             }
-
-            if !can_write | is_error | is_closed {
-                break;
+            Ok(sz) => {
+                buffered_size += sz;
             }
+            Err(e) => match e.kind() {
+                ErrorKind::WouldBlock => {
+                    // we don't need to do anything, the session will return false in wants_write?
+                    //error!("rustls socket_write wouldblock");
+                }
+                ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::BrokenPipe => {
+                    //FIXME: this should probably not happen here
+                    incr!("rustls.write.error");
+                    is_closed = true;
+                    break;
+                }
+                _ => {
+                    error!("could not write data to TLS stream: {:?}", e);
+                    incr!("rustls.write.error");
+                    is_error = true;
+                    break;
+                }
+            },
+        }
 
-            match self.session.writer().write(&buf[buffered_size..]) {
+        loop {
+            match session.write_tls(stream) {
                 Ok(0) => {
+                    //can_write = false;
                     break;
                 }
-                Ok(sz) => {
-                    buffered_size += sz;
-                }
+                Ok(_sz) => {}
                 Err(e) => match e.kind() {
-                    ErrorKind::WouldBlock => {
-                        // we don't need to do anything, the session will return false in wants_write?
-                        //error!("rustls socket_write wouldblock");
-                    }
+                    ErrorKind::WouldBlock => can_write = false,
                     ErrorKind::ConnectionReset
                     | ErrorKind::ConnectionAborted
                     | ErrorKind::BrokenPipe => {
-                        //FIXME: this should probably not happen here
                         incr!("rustls.write.error");
                         is_closed = true;
                         break;
                     }
                     _ => {
-                        error!("could not write data to TLS stream: {:?}", e);
+                        error!("could not write TLS stream to socket: {:?}", e);
                         incr!("rustls.write.error");
                         is_error = true;
                         break;
                     }
                 },
             }
-
-            loop {
-                match self.session.write_tls(&mut self.stream) {
-                    Ok(0) => {
-                        //can_write = false;
-                        break;
-                    }
-                    Ok(_sz) => {}
-                    Err(e) => match e.kind() {
-                        ErrorKind::WouldBlock => can_write = false,
-                        ErrorKind::ConnectionReset
-                        | ErrorKind::ConnectionAborted
-                        | ErrorKind::BrokenPipe => {
-                            incr!("rustls.write.error");
-                            is_closed = true;
-                            break;
-                        }
-                        _ => {
-                            error!("could not write TLS stream to socket: {:?}", e);
-                            incr!("rustls.write.error");
-                            is_error = true;
-                            break;
-                        }
-                    },
-                }
-            }
         }
-
-        if is_error {
-            (buffered_size, SocketResult::Error)
-        } else if is_closed {
-            (buffered_size, SocketResult::Closed)
-        } else if !can_write {
-            (buffered_size, SocketResult::WouldBlock)
-        } else {
-            (buffered_size, SocketResult::Continue)
-        }
-    }
-
-    fn socket_ref(&self) -> &TcpStream {
-        &self.stream
     }
 
-    fn socket_mut(&mut self) -> &mut TcpStream {
-        &mut self.stream
-    }
-
-    fn protocol(&self) -> TransportProtocol {
-        self.session
-            .protocol_version()
-            .map(|version| match version {
-                ProtocolVersion::SSLv2 => TransportProtocol::Ssl2,
-                ProtocolVersion::SSLv3 => TransportProtocol::Ssl3,
-                ProtocolVersion::TLSv1_0 => TransportProtocol::Tls1_0,
-                ProtocolVersion::TLSv1_1 => TransportProtocol::Tls1_1,
-                ProtocolVersion::TLSv1_2 => TransportProtocol::Tls1_2,
-                ProtocolVersion::TLSv1_3 => TransportProtocol::Tls1_3,
-                _ => TransportProtocol::Tls1_3,
-            })
-            .unwrap_or(TransportProtocol::Tcp)
-    }
-
-    fn read_error(&self) {
-        incr!("rustls.read.error");
+    if is_error {
+        (buffered_size, SocketResult::Error)
+    } else if is_closed {
+        (buffered_size, SocketResult::Closed)
+    } else if !can_write {
+        (buffered_size, SocketResult::WouldBlock)
+    } else {
+        (buffered_size, SocketResult::Continue)
     }
+}
 
-    fn write_error(&self) {
-        incr!("rustls.write.error");
-    }
+fn rustls_protocol(session: &CommonState) -> TransportProtocol {
+    session
+        .protocol_version()
+        .map(|version| match version {
+            ProtocolVersion::SSLv2 => TransportProtocol::Ssl2,
+            ProtocolVersion::SSLv3 => TransportProtocol::Ssl3,
+            ProtocolVersion::TLSv1_0 => TransportProtocol::Tls1_0,
+            ProtocolVersion::TLSv1_1 => TransportProtocol::Tls1_1,
+            ProtocolVersion::TLSv1_2 => TransportProtocol::Tls1_2,
+            ProtocolVersion::TLSv1_3 => TransportProtocol::Tls1_3,
+            _ => TransportProtocol::Tls1_3,
+        })
+        .unwrap_or(TransportProtocol::Tcp)
 }
 
 pub fn server_bind(addr: SocketAddr) -> io::Result<TcpListener> {
@@ -14,25 +14,29 @@ use slab::Slab;
 use time::{Duration, Instant};
 
 use crate::{
+    acl::AccessLists,
     backends::BackendMap,
     pool::{Checkout, Pool},
     protocol::{
+        forward::{self, expect::ExpectForward, Host, Refusal, Target},
         proxy_protocol::{
             expect::ExpectProxyProtocol, relay::RelayProxyProtocol, send::SendProxyProtocol,
         },
+        sni::expect::ExpectSni,
         {Pipe, ProtocolResult},
     },
     retry::RetryPolicy,
     server::{
-        push_event, ListenSession, ListenToken, ProxyChannel, Server, SessionManager, CONN_RETRIES,
-        TIMER,
+        push_event, push_name_resolution, tap_access_log, ListenSession, ListenToken, ProxyChannel,
+        Server, SessionManager, CONN_RETRIES, TIMER,
     },
     socket::server_bind,
     sozu_command::{
         config::ProxyProtocolConfig,
         logging,
         proxy::{
-            ProxyEvent, ProxyRequest, ProxyRequestOrder, ProxyResponse, TcpFrontend,
+            AccessLogRecord, DestinationHost, NameResolution, ProxyEvent, ProxyRequest,
+            ProxyRequestOrder, ProxyResponse, SniFrontend, TcpFrontend,
             TcpListener as TcpListenerConfig,
         },
         ready::Ready,
@@ -56,6 +60,8 @@ pub enum State {
     SendProxyProtocol(SendProxyProtocol<TcpStream>),
     RelayProxyProtocol(RelayProxyProtocol<TcpStream>),
     ExpectProxyProtocol(ExpectProxyProtocol<TcpStream>),
+    ExpectSni(ExpectSni<TcpStream>),
+    ExpectForward(ExpectForward<TcpStream>),
 }
 
 pub struct Session {
@@ -67,6 +73,16 @@ pub struct Session {
     request_id: Ulid,
     cluster_id: Option<String>,
     backend_id: Option<String>,
+    /// server name of the TLS ClientHello, for the listeners routing by SNI
+    sni_hostname: Option<String>,
+    /// destination requested to a forward proxy listener
+    forward_address: Option<SocketAddr>,
+    /// the main process resolves the name of the forward destination
+    resolving: bool,
+    /// addresses of the name of the forward destination, from the main process
+    resolved_addresses: Option<Vec<SocketAddr>>,
+    /// answer of the forward proxy handshake, sent once the destination is connected
+    forward_answer: Option<Vec<u8>>,
     metrics: SessionMetrics,
     protocol: Option<State>,
     front_buf: Option<Checkout>,
@@ -78,6 +94,10 @@ pub struct Session {
     back_timeout: TimeoutContainer,
     proxy: Rc<RefCell<Proxy>>,
     listener: Rc<RefCell<Listener>>,
+    /// listener counting the session in its session limit
+    counted_listener: Option<Token>,
+    /// cluster counting the session in its session limit
+    counted_cluster: Option<ClusterId>,
 }
 
 impl Session {
@@ -91,6 +111,7 @@ impl Session {
         cluster_id: Option<String>,
         backend_id: Option<String>,
         proxy_protocol: Option<ProxyProtocolConfig>,
+        expect_sni: bool,
         wait_time: Duration,
         front_timeout_duration: Duration,
         backend_timeout_duration: Duration,
@@ -104,7 +125,30 @@ impl Session {
         let front_timeout = TimeoutContainer::new(front_timeout_duration, frontend_token);
         let back_timeout = TimeoutContainer::new_empty(backend_timeout_duration);
 
+        let forward_proxy = listener.borrow().config.forward_proxy.clone();
+
         let protocol = match proxy_protocol {
+            _ if forward_proxy.is_some() => {
+                backend_buffer = Some(back_buf);
+                gauge_add!("protocol.forward", 1);
+                Some(State::ExpectForward(ExpectForward::new(
+                    sock,
+                    frontend_token,
+                    request_id,
+                    front_buf,
+                    forward_proxy.map(|f| f.socks5).unwrap_or(false),
+                )))
+            }
+            _ if expect_sni => {
+                backend_buffer = Some(back_buf);
+                gauge_add!("protocol.sni", 1);
+                Some(State::ExpectSni(ExpectSni::new(
+                    sock,
+                    frontend_token,
+                    request_id,
+                    front_buf,
+                )))
+            }
             Some(ProxyProtocolConfig::RelayHeader) => {
                 backend_buffer = Some(back_buf);
                 gauge_add!("protocol.proxy.relay", 1);
@@ -170,6 +214,11 @@ impl Session {
             request_id,
             cluster_id,
             backend_id,
+            sni_hostname: None,
+            forward_address: None,
+            resolving: false,
+            resolved_addresses: None,
+            forward_answer: None,
             metrics,
             protocol,
             front_buf: frontend_buffer,
@@ -181,6 +230,8 @@ impl Session {
             back_timeout,
             proxy,
             listener,
+            counted_listener: None,
+            counted_cluster: None,
         }
     }
 
@@ -231,6 +282,24 @@ impl Session {
             self.metrics.bin,
             self.metrics.bout
         );
+
+        tap_access_log(self.cluster_id.as_deref(), None, || AccessLogRecord {
+            timestamp: 0,
+            cluster_id: self.cluster_id.clone(),
+            backend_id: self.metrics.backend_id.clone(),
+            session_address: self.frontend_address,
+            backend_address,
+            protocol: String::from("TCP"),
+            host: None,
+            method: None,
+            path: None,
+            status: None,
+            response_time: response_time as u64,
+            service_time: service_time as u64,
+            bytes_in: self.metrics.bin,
+            bytes_out: self.metrics.bout,
+            error: None,
+        });
     }
 
     fn front_hup(&mut self) -> SessionResult {
@@ -253,6 +322,38 @@ impl Session {
         }
     }
 
+    /// counts the session in the session limit of its cluster. Returns false if
+    /// the cluster holds too many sessions
+    fn enter_cluster(&mut self, cluster_id: &str) -> bool {
+        let proxy = self.proxy.borrow();
+        let max_sessions = proxy
+            .configs
+            .get(cluster_id)
+            .and_then(|config| config.max_sessions);
+
+        if !proxy
+            .sessions
+            .borrow_mut()
+            .enter_cluster(cluster_id, max_sessions)
+        {
+            return false;
+        }
+        self.counted_cluster = Some(cluster_id.to_string());
+        true
+    }
+
+    /// releases the places of the session in the session limits
+    fn leave_session_limits(&mut self) {
+        let proxy = self.proxy.borrow();
+        let mut sessions = proxy.sessions.borrow_mut();
+        if let Some(listener_token) = self.counted_listener.take() {
+            sessions.close_listener_session(listener_token);
+        }
+        if let Some(cluster_id) = self.counted_cluster.take() {
+            sessions.leave_cluster(&cluster_id);
+        }
+    }
+
     fn log_context(&self) -> String {
         format!(
             "{} {} {}\t",
@@ -277,6 +378,16 @@ impl Session {
                 should_upgrade_protocol = res.0;
                 res.1
             }
+            Some(State::ExpectSni(ref mut sni)) => {
+                let res = sni.readable(&mut self.metrics);
+                should_upgrade_protocol = res.0;
+                res.1
+            }
+            Some(State::ExpectForward(ref mut forward)) => {
+                let res = forward.readable(&mut self.metrics);
+                should_upgrade_protocol = res.0;
+                res.1
+            }
             _ => SessionResult::Continue,
         };
 
@@ -294,6 +405,7 @@ impl Session {
     fn writable(&mut self) -> SessionResult {
         match self.protocol {
             Some(State::Pipe(ref mut pipe)) => pipe.writable(&mut self.metrics),
+            Some(State::ExpectForward(ref mut forward)) => forward.writable(&mut self.metrics),
             _ => SessionResult::Continue,
         }
     }
@@ -336,6 +448,8 @@ impl Session {
             Some(State::SendProxyProtocol(ref pp)) => pp.front_socket(),
             Some(State::RelayProxyProtocol(ref pp)) => pp.front_socket(),
             Some(State::ExpectProxyProtocol(ref pp)) => pp.front_socket(),
+            Some(State::ExpectSni(ref sni)) => sni.front_socket(),
+            Some(State::ExpectForward(ref forward)) => forward.front_socket(),
             _ => unreachable!(),
         }
     }
@@ -345,7 +459,9 @@ impl Session {
             Some(State::Pipe(ref mut pipe)) => pipe.back_socket_mut(),
             Some(State::SendProxyProtocol(ref mut pp)) => pp.back_socket_mut(),
             Some(State::RelayProxyProtocol(ref mut pp)) => pp.back_socket_mut(),
-            Some(State::ExpectProxyProtocol(_)) => None,
+            Some(State::ExpectProxyProtocol(_))
+            | Some(State::ExpectSni(_))
+            | Some(State::ExpectForward(_)) => None,
             _ => unreachable!(),
         }
     }
@@ -399,17 +515,159 @@ impl Session {
                 error!("Missing the backend buffer queue, we can't switch to a pipe");
                 UpgradeResult::Close
             }
+        } else if let Some(State::ExpectSni(sni)) = protocol {
+            self.sni_hostname = sni.hostname.clone();
+            let cluster_id = self
+                .listener
+                .borrow()
+                .cluster_id_for(self.sni_hostname.as_deref());
+
+            if cluster_id.is_none() {
+                error!(
+                    "{} no TCP cluster corresponds to the server name {:?}",
+                    self.log_context(),
+                    self.sni_hostname
+                );
+                incr!("sni.not_found");
+                // the session is closed with its current state
+                self.protocol = Some(State::ExpectSni(sni));
+                return UpgradeResult::Close;
+            }
+
+            if !cluster_id
+                .as_deref()
+                .map(|cluster_id| self.enter_cluster(cluster_id))
+                .unwrap_or(true)
+            {
+                self.protocol = Some(State::ExpectSni(sni));
+                return UpgradeResult::Close;
+            }
+
+            if let Some(back_buf) = self.back_buf.take() {
+                self.cluster_id = cluster_id;
+                let pipe = sni.into_pipe(back_buf, self.cluster_id.clone(), self.listener.clone());
+                self.protocol = Some(State::Pipe(pipe));
+                gauge_add!("protocol.sni", -1);
+                gauge_add!("protocol.tcp", 1);
+                UpgradeResult::ConnectBackend
+            } else {
+                error!("Missing the backend buffer queue, we can't switch to a pipe");
+                self.protocol = Some(State::ExpectSni(sni));
+                UpgradeResult::Close
+            }
+        } else if let Some(State::ExpectForward(mut forward)) = protocol {
+            let target = match forward.target.clone() {
+                Some(target) => target,
+                None => return UpgradeResult::Close,
+            };
+
+            let address = match self.forward_address_for(&target) {
+                Ok(Some(address)) => address,
+                // the client is not read until the main process answers
+                Ok(None) => {
+                    forward.readiness.interest.remove(Ready::readable());
+                    self.protocol = Some(State::ExpectForward(forward));
+                    return UpgradeResult::Continue;
+                }
+                Err(refusal) => {
+                    info!(
+                        "{} forward request to {} refused: {:?}",
+                        self.log_context(),
+                        target,
+                        refusal
+                    );
+                    let res = forward.refuse(refusal, &mut self.metrics);
+                    self.protocol = Some(State::ExpectForward(forward));
+                    return match res {
+                        SessionResult::CloseSession => UpgradeResult::Close,
+                        _ => UpgradeResult::Continue,
+                    };
+                }
+            };
+
+            if let Some(back_buf) = self.back_buf.take() {
+                self.forward_address = Some(address);
+                self.forward_answer = forward.handshake.map(forward::success_answer);
+                self.backend_id = Some(target.to_string());
+                let pipe = forward.into_pipe(back_buf, self.listener.clone());
+                self.protocol = Some(State::Pipe(pipe));
+                gauge_add!("protocol.forward", -1);
+                gauge_add!("protocol.tcp", 1);
+                UpgradeResult::ConnectBackend
+            } else {
+                error!("Missing the backend buffer queue, we can't switch to a pipe");
+                self.protocol = Some(State::ExpectForward(forward));
+                UpgradeResult::Close
+            }
         } else {
             UpgradeResult::Close
         }
     }
 
+    /// the address of a destination requested to a forward proxy listener, if
+    /// the destination is allowed. The names are resolved by the main process:
+    /// without its addresses yet, the name is sent to it and there is no address
+    fn forward_address_for(&mut self, target: &Target) -> Result<Option<SocketAddr>, Refusal> {
+        let listener = self.listener.clone();
+        let listener = listener.borrow();
+        let forward_proxy = listener
+            .config
+            .forward_proxy
+            .as_ref()
+            .ok_or(Refusal::Forbidden)?;
+
+        let name = match &target.host {
+            Host::Address(ip) => {
+                let address = SocketAddr::new(*ip, target.port);
+                return if forward_proxy.allows_address(address) {
+                    Ok(Some(address))
+                } else {
+                    Err(Refusal::Forbidden)
+                };
+            }
+            Host::Name(name) => name,
+        };
+
+        // a name that is not allowed is resolved only to check its addresses
+        let allowed_name = forward_proxy.allows_name(name, target.port);
+        if !allowed_name
+            && !forward_proxy
+                .allowed_destinations
+                .iter()
+                .any(|d| matches!(d.host, DestinationHost::Range(_)))
+        {
+            return Err(Refusal::Forbidden);
+        }
+
+        let addresses = match self.resolved_addresses.take() {
+            Some(addresses) => addresses,
+            None => {
+                self.resolving = true;
+                push_name_resolution(self.frontend_token, name.to_owned(), target.port);
+                return Ok(None);
+            }
+        };
+
+        addresses
+            .iter()
+            .find(|address| allowed_name || forward_proxy.allows_address(**address))
+            .copied()
+            .map(Some)
+            .ok_or(if addresses.is_empty() {
+                Refusal::Unreachable
+            } else {
+                Refusal::Forbidden
+            })
+    }
+
     fn front_readiness(&mut self) -> &mut Readiness {
         match self.protocol {
             Some(State::Pipe(ref mut pipe)) => pipe.front_readiness(),
             Some(State::SendProxyProtocol(ref mut pp)) => pp.front_readiness(),
             Some(State::RelayProxyProtocol(ref mut pp)) => pp.front_readiness(),
             Some(State::ExpectProxyProtocol(ref mut pp)) => pp.readiness(),
+            Some(State::ExpectSni(ref mut sni)) => sni.readiness(),
+            Some(State::ExpectForward(ref mut forward)) => forward.readiness(),
             _ => unreachable!(),
         }
     }
@@ -428,7 +686,9 @@ impl Session {
             Some(State::Pipe(ref pipe)) => pipe.back_token(),
             Some(State::SendProxyProtocol(ref pp)) => pp.back_token(),
             Some(State::RelayProxyProtocol(ref pp)) => pp.back_token(),
-            Some(State::ExpectProxyProtocol(_)) => None,
+            Some(State::ExpectProxyProtocol(_))
+            | Some(State::ExpectSni(_))
+            | Some(State::ExpectForward(_)) => None,
             _ => unreachable!(),
         }
     }
@@ -441,6 +701,12 @@ impl Session {
             Some(State::ExpectProxyProtocol(_)) => {
                 panic!("we should not set the back socket for the expect proxy protocol")
             }
+            Some(State::ExpectSni(_)) => {
+                panic!("we should not set the back socket before reading the server name")
+            }
+            Some(State::ExpectForward(_)) => {
+                panic!("we should not set the back socket before reading the forward request")
+            }
             _ => unreachable!(),
         }
     }
@@ -452,7 +718,9 @@ impl Session {
             Some(State::Pipe(ref mut pipe)) => pipe.set_back_token(token),
             Some(State::SendProxyProtocol(ref mut pp)) => pp.set_back_token(token),
             Some(State::RelayProxyProtocol(ref mut pp)) => pp.set_back_token(token),
-            Some(State::ExpectProxyProtocol(_)) => self.backend_token = Some(token),
+            Some(State::ExpectProxyProtocol(_))
+            | Some(State::ExpectSni(_))
+            | Some(State::ExpectForward(_)) => self.backend_token = Some(token),
             _ => unreachable!(),
         }
     }
@@ -484,6 +752,12 @@ impl Session {
                 pp.set_back_connected(BackendConnectionStatus::Connected);
             }
 
+            if let (Some(answer), Some(State::Pipe(pipe))) =
+                (self.forward_answer.take(), self.protocol.as_mut())
+            {
+                pipe.queue_front_data(&answer);
+            }
+
             if let Some(backend) = self.backend.as_ref() {
                 let mut backend = backend.borrow_mut();
 
@@ -510,6 +784,7 @@ impl Session {
                 //successful connection, rest failure counter
                 backend.failures = 0;
                 backend.retry_policy.succeed();
+                backend.record_outcome(self.cluster_id.as_deref(), false);
             }
         }
     }
@@ -533,6 +808,7 @@ impl Session {
 
             let already_unavailable = backend.retry_policy.is_down();
             backend.retry_policy.fail();
+            backend.record_outcome(self.cluster_id.as_deref(), true);
             incr!(
                 "connections.error",
                 self.cluster_id.as_deref(),
@@ -611,7 +887,14 @@ impl Session {
 
                 self.set_back_connected(BackendConnectionStatus::Connected);
             }
-        } else if back_connected == BackendConnectionStatus::NotConnected {
+        } else if back_connected == BackendConnectionStatus::NotConnected
+            && !matches!(
+                self.protocol,
+                Some(State::ExpectSni(_)) | Some(State::ExpectForward(_))
+            )
+        {
+            // the sessions routed by SNI connect once the ClientHello is read,
+            // the forward proxy sessions once the destination is known
             match self.connect_to_backend(session.clone()) {
                 // reuse connection or error we can continue
                 Ok(BackendConnectAction::Reuse) | Err(_) => {}
@@ -823,13 +1106,20 @@ impl Session {
         &mut self,
         session_rc: Rc<RefCell<dyn ProxySession>>,
     ) -> Result<BackendConnectAction, ConnectionError> {
+        if let Some(address) = self.forward_address {
+            return self.connect_to_destination(address, session_rc);
+        }
+
         let cluster_id = if let Some(cluster_id) = self
             .proxy
             .borrow()
             .listeners
             .get(&self.accept_token)
-            .and_then(|listener| listener.borrow().cluster_id.clone())
-        {
+            .and_then(|listener| {
+                listener
+                    .borrow()
+                    .cluster_id_for(self.sni_hostname.as_deref())
+            }) {
             cluster_id
         } else {
             error!("no TCP cluster corresponds to that front address");
@@ -850,50 +1140,28 @@ impl Session {
             return Err(ConnectionError::TooManyConnections);
         }
 
+        // hashed if the cluster uses a consistent hashing, bound to the backend
+        // if the cluster has an affinity table
+        let key = self
+            .frontend_address
+            .map(|address| address.ip().to_string().into_bytes());
         let conn = self
             .proxy
             .borrow()
             .backends
             .borrow_mut()
-            .backend_from_cluster_id(&cluster_id);
+            .backend_with_affinity(&cluster_id, key.as_deref(), |backends| {
+                backends.backend_for_key(&cluster_id, key.as_deref())
+            });
         match conn {
-            Ok((backend, mut stream)) => {
+            Ok((backend, stream)) => {
                 if let Err(e) = stream.set_nodelay(true) {
                     error!(
                         "error setting nodelay on back socket({:?}): {:?}",
                         stream, e
                     );
                 }
-                self.back_connected = BackendConnectionStatus::Connecting(Instant::now());
-
-                let back_token = {
-                    let proxy = self.proxy.borrow();
-                    let mut s = proxy.sessions.borrow_mut();
-                    let entry = s.slab.vacant_entry();
-                    let back_token = Token(entry.key());
-                    let _entry = entry.insert(session_rc.clone());
-                    back_token
-                };
-
-                if let Err(e) = self.proxy.borrow().registry.register(
-                    &mut stream,
-                    back_token,
-                    Interest::READABLE | Interest::WRITABLE,
-                ) {
-                    error!("error registering back socket({:?}): {:?}", stream, e);
-                }
-
-                let connect_timeout_duration = Duration::seconds(
-                    self.proxy.borrow().listeners[&self.accept_token]
-                        .borrow()
-                        .config
-                        .connect_timeout as i64,
-                );
-                self.back_timeout.set_duration(connect_timeout_duration);
-                self.back_timeout.set(back_token);
-
-                self.set_back_token(back_token);
-                self.set_back_socket(stream);
+                self.register_back_socket(stream, session_rc);
 
                 self.metrics.backend_id = Some(backend.borrow().backend_id.clone());
                 self.metrics.backend_start();
@@ -907,6 +1175,85 @@ impl Session {
             }
         }
     }
+
+    /// connects to the destination of a forward proxy session
+    fn connect_to_destination(
+        &mut self,
+        address: SocketAddr,
+        session_rc: Rc<RefCell<dyn ProxySession>>,
+    ) -> Result<BackendConnectAction, ConnectionError> {
+        if self.connection_attempt == CONN_RETRIES {
+            error!("{} max connection attempt reached", self.log_context());
+            return Err(ConnectionError::NoBackendAvailable);
+        }
+
+        if self.proxy.borrow().sessions.borrow().slab.len()
+            >= self.proxy.borrow().sessions.borrow().slab_capacity()
+        {
+            error!("not enough memory, cannot connect to destination");
+            return Err(ConnectionError::TooManyConnections);
+        }
+
+        let stream = TcpStream::connect(address).map_err(|e| {
+            error!(
+                "{} could not connect to {}: {:?}",
+                self.log_context(),
+                address,
+                e
+            );
+            incr!("forward.connections.error");
+            ConnectionError::NoBackendAvailable
+        })?;
+        if let Err(e) = stream.set_nodelay(true) {
+            error!(
+                "error setting nodelay on back socket({:?}): {:?}",
+                stream, e
+            );
+        }
+
+        self.register_back_socket(stream, session_rc);
+        self.metrics.backend_start();
+        incr!("forward.connections");
+
+        Ok(BackendConnectAction::New)
+    }
+
+    fn register_back_socket(
+        &mut self,
+        mut stream: TcpStream,
+        session_rc: Rc<RefCell<dyn ProxySession>>,
+    ) {
+        self.back_connected = BackendConnectionStatus::Connecting(Instant::now());
+
+        let back_token = {
+            let proxy = self.proxy.borrow();
+            let mut s = proxy.sessions.borrow_mut();
+            let entry = s.slab.vacant_entry();
+            let back_token = Token(entry.key());
+            let _entry = entry.insert(session_rc);
+            back_token
+        };
+
+        if let Err(e) = self.proxy.borrow().registry.register(
+            &mut stream,
+            back_token,
+            Interest::READABLE | Interest::WRITABLE,
+        ) {
+            error!("error registering back socket({:?}): {:?}", stream, e);
+        }
+
+        let connect_timeout_duration = Duration::seconds(
+            self.proxy.borrow().listeners[&self.accept_token]
+                .borrow()
+                .config
+                .connect_timeout as i64,
+        );
+        self.back_timeout.set_duration(connect_timeout_duration);
+        self.back_timeout.set(back_token);
+
+        self.set_back_token(back_token);
+        self.set_back_socket(stream);
+    }
 }
 
 impl ProxySession for Session {
@@ -924,12 +1271,15 @@ impl ProxySession for Session {
         }
 
         self.close_backend();
+        self.leave_session_limits();
 
         match self.protocol {
             Some(State::Pipe(_)) => gauge_add!("protocol.tcp", -1),
             Some(State::SendProxyProtocol(_)) => gauge_add!("protocol.proxy.send", -1),
             Some(State::RelayProxyProtocol(_)) => gauge_add!("protocol.proxy.relay", -1),
             Some(State::ExpectProxyProtocol(_)) => gauge_add!("protocol.proxy.expect", -1),
+            Some(State::ExpectSni(_)) => gauge_add!("protocol.sni", -1),
+            Some(State::ExpectForward(_)) => gauge_add!("protocol.forward", -1),
             None => {}
         }
 
@@ -1005,9 +1355,43 @@ impl ProxySession for Session {
         self.last_event
     }
 
+    fn name_resolved(
+        &mut self,
+        resolution: NameResolution,
+        session: Rc<RefCell<dyn ProxySession>>,
+    ) {
+        // the token may belong to a newer session
+        let expected = match &self.protocol {
+            Some(State::ExpectForward(forward)) => forward.target.as_ref().is_some_and(|target| {
+                target.port == resolution.port
+                    && matches!(&target.host, Host::Name(name) if *name == resolution.name)
+            }),
+            _ => false,
+        };
+        if !self.resolving || !expected {
+            debug!(
+                "{} unexpected resolution of {}",
+                self.log_context(),
+                resolution.name
+            );
+            return;
+        }
+
+        self.resolving = false;
+        self.resolved_addresses = Some(resolution.addresses);
+        self.last_event = Instant::now();
+        match self.upgrade() {
+            UpgradeResult::Close => self.close(),
+            // the backend is connected by the next call to ready
+            UpgradeResult::ConnectBackend | UpgradeResult::Continue => self.ready(session),
+        }
+    }
+
     fn print_state(&self) {
         let p: String = match &self.protocol {
             Some(State::ExpectProxyProtocol(_)) => String::from("Expect"),
+            Some(State::ExpectSni(_)) => String::from("ExpectSni"),
+            Some(State::ExpectForward(_)) => String::from("ExpectForward"),
             Some(State::SendProxyProtocol(_)) => String::from("Send"),
             Some(State::RelayProxyProtocol(_)) => String::from("Relay"),
             Some(State::Pipe(_)) => String::from("TCP"),
@@ -1016,6 +1400,8 @@ impl ProxySession for Session {
 
         let rf = match *unwrap_msg!(self.protocol.as_ref()) {
             State::ExpectProxyProtocol(ref expect) => &expect.readiness,
+            State::ExpectSni(ref sni) => &sni.readiness,
+            State::ExpectForward(ref forward) => &forward.readiness,
             State::SendProxyProtocol(ref send) => &send.front_readiness,
             State::RelayProxyProtocol(ref relay) => &relay.front_readiness,
             State::Pipe(ref pipe) => &pipe.front_readiness,
@@ -1044,6 +1430,8 @@ impl ProxySession for Session {
 
 pub struct Listener {
     cluster_id: Option<String>,
+    /// server name -> cluster id, the TLS connections are routed by SNI if not empty
+    sni_fronts: HashMap<String, String>,
     listener: Option<TcpListener>,
     token: Token,
     address: SocketAddr,
@@ -1051,6 +1439,7 @@ pub struct Listener {
     config: TcpListenerConfig,
     active: bool,
     tags: BTreeMap<String, BTreeMap<String, String>>,
+    acls: AccessLists,
 }
 
 impl ListenerHandler for Listener {
@@ -1074,6 +1463,7 @@ impl Listener {
     fn new(config: TcpListenerConfig, pool: Rc<RefCell<Pool>>, token: Token) -> Listener {
         Listener {
             cluster_id: None,
+            sni_fronts: HashMap::new(),
             listener: None,
             token,
             address: config.address,
@@ -1081,6 +1471,7 @@ impl Listener {
             config,
             active: false,
             tags: BTreeMap::new(),
+            acls: AccessLists::default(),
         }
     }
 
@@ -1116,11 +1507,27 @@ impl Listener {
         self.active = true;
         Some(self.token)
     }
+
+    /// cluster of the server name, by exact match then wildcard, or the cluster
+    /// of the TCP frontend
+    fn cluster_id_for(&self, hostname: Option<&str>) -> Option<String> {
+        hostname
+            .and_then(|hostname| {
+                self.sni_fronts.get(hostname).or_else(|| {
+                    hostname
+                        .split_once('.')
+                        .and_then(|(_, domain)| self.sni_fronts.get(&format!("*.{}", domain)))
+                })
+            })
+            .or(self.cluster_id.as_ref())
+            .cloned()
+    }
 }
 
 #[derive(Debug)]
 pub struct ClusterConfiguration {
     proxy_protocol: Option<ProxyProtocolConfig>,
+    max_sessions: Option<usize>,
     // Uncomment this when implementing new load balancing algorythms
     // load_balancing: LoadBalancingAlgorithms,
 }
@@ -1189,6 +1596,7 @@ impl Proxy {
             .filter_map(|listener| {
                 let mut owned = listener.borrow_mut();
                 if let Some(listener) = owned.listener.take() {
+                    owned.active = false;
                     return Some((owned.address, listener));
                 }
 
@@ -1204,10 +1612,10 @@ impl Proxy {
             .and_then(|listener| {
                 let mut owned = listener.borrow_mut();
 
-                owned
-                    .listener
-                    .take()
-                    .map(|listener| (owned.token, listener))
+                let listener = owned.listener.take()?;
+                // the listener can be activated again, with another socket
+                owned.active = false;
+                Some((owned.token, listener))
             })
     }
 
@@ -1256,6 +1664,57 @@ impl Proxy {
 
         Ok(())
     }
+
+    pub fn add_sni_front(&mut self, front: SniFrontend) -> Result<(), io::Error> {
+        let mut listener = match self
+            .listeners
+            .values()
+            .find(|l| l.borrow().address == front.address)
+        {
+            Some(l) => l.borrow_mut(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no such listener for '{}'", front.address),
+                ));
+            }
+        };
+
+        listener
+            .sni_fronts
+            .insert(front.hostname.to_ascii_lowercase(), front.cluster_id);
+        Ok(())
+    }
+
+    pub fn remove_sni_front(&mut self, front: &SniFrontend) -> Result<(), io::Error> {
+        let mut listener = match self
+            .listeners
+            .values()
+            .find(|l| l.borrow().address == front.address)
+        {
+            Some(l) => l.borrow_mut(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no such listener for '{}'", front.address),
+                ));
+            }
+        };
+
+        let hostname = front.hostname.to_ascii_lowercase();
+        if listener.sni_fronts.get(&hostname) != Some(&front.cluster_id) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "no SNI frontend for '{}' to the cluster {} on '{}'",
+                    front.hostname, front.cluster_id, front.address
+                ),
+            ));
+        }
+
+        listener.sni_fronts.remove(&hostname);
+        Ok(())
+    }
 }
 
 impl ProxyConfiguration<Session> for Proxy {
@@ -1275,7 +1734,21 @@ impl ProxyConfiguration<Session> for Proxy {
 
                 ProxyResponse::ok(message.id)
             }
-            ProxyRequestOrder::SoftStop => {
+            ProxyRequestOrder::AddSniFrontend(front) => {
+                if let Err(err) = self.add_sni_front(front) {
+                    return ProxyResponse::error(message.id, err);
+                }
+
+                ProxyResponse::ok(message.id)
+            }
+            ProxyRequestOrder::RemoveSniFrontend(front) => {
+                if let Err(err) = self.remove_sni_front(&front) {
+                    return ProxyResponse::error(message.id, err);
+                }
+
+                ProxyResponse::ok(message.id)
+            }
+            ProxyRequestOrder::SoftStop(_) => {
                 info!("{} processing soft shutdown", message.id);
                 let listeners: HashMap<_, _> = self.listeners.drain().collect();
                 for (_, l) in listeners.iter() {
@@ -1298,7 +1771,7 @@ impl ProxyConfiguration<Session> for Proxy {
                 ProxyResponse::ok(message.id)
             }
             ProxyRequestOrder::Status => {
-                info!("{} status", message.id);
+                debug!("{} status", message.id);
                 ProxyResponse::ok(message.id)
             }
             ProxyRequestOrder::Logging(logging_filter) => {
@@ -1315,6 +1788,7 @@ impl ProxyConfiguration<Session> for Proxy {
             ProxyRequestOrder::AddCluster(cluster) => {
                 let config = ClusterConfiguration {
                     proxy_protocol: cluster.proxy_protocol,
+                    max_sessions: cluster.max_sessions,
                     //load_balancing: cluster.load_balancing,
                 };
                 self.configs.insert(cluster.cluster_id, config);
@@ -1324,6 +1798,50 @@ impl ProxyConfiguration<Session> for Proxy {
                 self.configs.remove(&cluster_id);
                 ProxyResponse::ok(message.id)
             }
+            ProxyRequestOrder::AddAcl(acl) => {
+                debug!("{} add ACL {:?}", message.id, acl);
+                if acl.hostname.is_some() {
+                    return ProxyResponse::error(
+                        message.id,
+                        "TCP listeners do not support ACLs by hostname",
+                    );
+                }
+                match self
+                    .listeners
+                    .values()
+                    .find(|l| l.borrow().address == acl.address)
+                {
+                    Some(listener) => {
+                        listener.borrow_mut().acls.add(acl);
+                        ProxyResponse::ok(message.id)
+                    }
+                    None => ProxyResponse::error(
+                        message.id,
+                        format!("no TCP listener found for ACL: {:?}", acl),
+                    ),
+                }
+            }
+            ProxyRequestOrder::RemoveAcl(remove) => {
+                debug!("{} remove ACL {:?}", message.id, remove);
+                match self
+                    .listeners
+                    .values()
+                    .find(|l| l.borrow().address == remove.address)
+                {
+                    Some(listener)
+                        if listener
+                            .borrow_mut()
+                            .acls
+                            .remove(remove.hostname.as_deref()) =>
+                    {
+                        ProxyResponse::ok(message.id)
+                    }
+                    _ => ProxyResponse::error(
+                        message.id,
+                        format!("no ACL to remove on TCP listener: {:?}", remove),
+                    ),
+                }
+            }
             ProxyRequestOrder::RemoveListener(remove) => {
                 if !self.remove_listener(remove.address) {
                     ProxyResponse::error(
@@ -1347,17 +1865,9 @@ impl ProxyConfiguration<Session> for Proxy {
     fn accept(&mut self, token: ListenToken) -> Result<TcpStream, AcceptError> {
         let internal_token = Token(token.0);
         if let Some(listener) = self.listeners.get(&internal_token) {
-            if let Some(tcp_listener) = &listener.borrow().listener {
-                tcp_listener
-                    .accept()
-                    .map(|(frontend_sock, _)| frontend_sock)
-                    .map_err(|e| match e.kind() {
-                        ErrorKind::WouldBlock => AcceptError::WouldBlock,
-                        _ => {
-                            error!("accept() IO error: {:?}", e);
-                            AcceptError::IoError
-                        }
-                    })
+            let listener = listener.borrow();
+            if let Some(tcp_listener) = &listener.listener {
+                listener.acls.accept(tcp_listener)
             } else {
                 Err(AcceptError::IoError)
             }
@@ -1395,7 +1905,14 @@ impl ProxyConfiguration<Session> for Proxy {
             }
         };
 
-        if owned.cluster_id.is_none() {
+        // the cluster of the sessions routed by SNI is known after the ClientHello,
+        // they do not use the proxy protocol
+        let expect_sni = !owned.sni_fronts.is_empty();
+
+        // the forward proxy sessions are sent to the destinations of their clients
+        let forward_proxy = owned.config.forward_proxy.is_some();
+
+        if owned.cluster_id.is_none() && !expect_sni && !forward_proxy {
             error!(
                 "listener at address {:?} has no linked cluster",
                 owned.address
@@ -1403,10 +1920,12 @@ impl ProxyConfiguration<Session> for Proxy {
             return Err(AcceptError::IoError);
         }
 
-        let proxy_protocol = self
-            .configs
-            .get(owned.cluster_id.as_ref().unwrap())
-            .and_then(|c| c.proxy_protocol.clone());
+        let proxy_protocol = owned
+            .cluster_id
+            .as_ref()
+            .and_then(|cluster_id| self.configs.get(cluster_id))
+            .and_then(|c| c.proxy_protocol.clone())
+            .filter(|_| !expect_sni && !forward_proxy);
 
         if let Err(e) = frontend_sock.set_nodelay(true) {
             error!(
@@ -1416,6 +1935,26 @@ impl ProxyConfiguration<Session> for Proxy {
         }
 
         let mut session_manager = self.sessions.borrow_mut();
+
+        // the connections over the session limits are closed right away
+        if !session_manager.open_listener_session(internal_token, owned.config.max_sessions) {
+            return Ok(());
+        }
+        let counted_cluster = match owned.cluster_id.as_ref() {
+            Some(cluster_id) if !expect_sni && !forward_proxy => {
+                let max_sessions = self
+                    .configs
+                    .get(cluster_id)
+                    .and_then(|config| config.max_sessions);
+                if !session_manager.enter_cluster(cluster_id, max_sessions) {
+                    session_manager.close_listener_session(internal_token);
+                    return Ok(());
+                }
+                Some(cluster_id.clone())
+            }
+            _ => None,
+        };
+
         let entry = session_manager.slab.vacant_entry();
         let session_token = Token(entry.key());
 
@@ -1428,10 +1967,14 @@ impl ProxyConfiguration<Session> for Proxy {
                 "error registering front socket({:?}): {:?}",
                 frontend_sock, register_error
             );
+            session_manager.close_listener_session(internal_token);
+            if let Some(cluster_id) = counted_cluster {
+                session_manager.leave_cluster(&cluster_id);
+            }
             return Err(AcceptError::RegisterError);
         }
 
-        let session = Session::new(
+        let mut session = Session::new(
             frontend_sock,
             session_token,
             internal_token,
@@ -1441,12 +1984,15 @@ impl ProxyConfiguration<Session> for Proxy {
             owned.cluster_id.clone(),
             None,
             proxy_protocol,
+            expect_sni,
             wait_time,
             Duration::seconds(owned.config.front_timeout as i64),
             Duration::seconds(owned.config.back_timeout as i64),
             listener.clone(),
         );
         incr!("tcp.requests");
+        session.counted_listener = Some(internal_token);
+        session.counted_cluster = counted_cluster;
 
         let session = Rc::new(RefCell::new(session));
         entry.insert(session);
@@ -1549,7 +2095,9 @@ pub fn start(
 mod tests {
     use super::*;
     use crate::sozu_command::channel::Channel;
-    use crate::sozu_command::proxy::{self, LoadBalancingParams, TcpFrontend};
+    use crate::sozu_command::proxy::{
+        self, ForwardProxy, LoadBalancingParams, ProxyResponseContent, TcpFrontend,
+    };
     use crate::sozu_command::scm_socket::Listeners;
     use std::io::{Read, Write};
     use std::net::{Shutdown, TcpListener, TcpStream};
@@ -1673,8 +2221,160 @@ mod tests {
         });
     }
 
+    #[test]
+    fn forward_names_resolved_by_main_process() {
+        setup_test_logger!();
+        let backend = TcpListener::bind("127.0.0.1:0").expect("could not bind the backend");
+        let backend_address = backend.local_addr().unwrap();
+        let mut command = spawn_proxy(TcpListenerConfig {
+            address: "127.0.0.1:1240".parse().unwrap(),
+            public_address: None,
+            expect_proxy: false,
+            front_timeout: 60,
+            back_timeout: 30,
+            connect_timeout: 3,
+            forward_proxy: Some(ForwardProxy {
+                allowed_destinations: vec!["backend.test:*".parse().unwrap()],
+                socks5: false,
+            }),
+            max_sessions: None,
+        })
+        .expect("Could not start proxy");
+
+        // the listener is bound once the event loop runs
+        let mut client = (0..50)
+            .find_map(|_| {
+                TcpStream::connect("127.0.0.1:1240")
+                    .map_err(|_| thread::sleep(std::time::Duration::from_millis(10)))
+                    .ok()
+            })
+            .expect("could not connect");
+        client
+            .write_all(b"CONNECT backend.test:8080 HTTP/1.1\r\nHost: backend.test:8080\r\n\r\nping")
+            .unwrap();
+
+        // the worker asks for the addresses instead of resolving the name itself
+        let resolution = loop {
+            let response = command.read_message().expect("could not read message");
+            if let Some(ProxyResponseContent::ResolveName(resolution)) = response.content {
+                break resolution;
+            }
+        };
+        assert_eq!(resolution.name, "backend.test");
+        assert_eq!(resolution.port, 8080);
+        assert!(resolution.addresses.is_empty());
+
+        command.write_message(&ProxyRequest {
+            id: String::from("RESOLVE"),
+            order: ProxyRequestOrder::NameResolved(NameResolution {
+                addresses: vec![backend_address],
+                ..resolution
+            }),
+        });
+
+        let (mut stream, _) = backend.accept().expect("the destination was not connected");
+        let mut data = [0; 4];
+        stream.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"ping");
+
+        let answer = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+        let mut received = vec![0; answer.len()];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(&received[..], &answer[..]);
+    }
+
     /// used in tests only
     pub fn start_proxy() -> anyhow::Result<Channel<ProxyRequest, ProxyResponse>> {
+        let mut command = spawn_proxy(TcpListenerConfig {
+            address: "127.0.0.1:1234".parse().unwrap(),
+            public_address: None,
+            expect_proxy: false,
+            front_timeout: 60,
+            back_timeout: 30,
+            connect_timeout: 3,
+            forward_proxy: None,
+            max_sessions: None,
+        })?;
+
+        {
+            let front = TcpFrontend {
+                cluster_id: String::from("yolo"),
+                address: "127.0.0.1:1234"
+                    .parse()
+                    .with_context(|| "Could not parse address")?,
+                tags: None,
+            };
+            let backend = proxy::Backend {
+                cluster_id: String::from("yolo"),
+                backend_id: String::from("yolo-0"),
+                address: "127.0.0.1:5678"
+                    .parse()
+                    .with_context(|| "Could not parse address")?,
+                load_balancing_parameters: Some(LoadBalancingParams::default()),
+                sticky_id: None,
+                backup: None,
+                max_connections: None,
+                timeouts: proxy::Timeouts::default(),
+                tls: None,
+            };
+
+            command.write_message(&ProxyRequest {
+                id: String::from("ID_YOLO1"),
+                order: ProxyRequestOrder::AddTcpFrontend(front),
+            });
+            command.write_message(&ProxyRequest {
+                id: String::from("ID_YOLO2"),
+                order: ProxyRequestOrder::AddBackend(backend),
+            });
+        }
+        {
+            let front = TcpFrontend {
+                cluster_id: String::from("yolo"),
+                address: "127.0.0.1:1235"
+                    .parse()
+                    .with_context(|| "Could not parse address")?,
+                tags: None,
+            };
+            let backend = proxy::Backend {
+                cluster_id: String::from("yolo"),
+                backend_id: String::from("yolo-0"),
+                address: "127.0.0.1:5678"
+                    .parse()
+                    .with_context(|| "Could not parse address")?,
+                load_balancing_parameters: Some(LoadBalancingParams::default()),
+                sticky_id: None,
+                backup: None,
+                max_connections: None,
+                timeouts: proxy::Timeouts::default(),
+                tls: None,
+            };
+            command.write_message(&ProxyRequest {
+                id: String::from("ID_YOLO3"),
+                order: ProxyRequestOrder::AddTcpFrontend(front),
+            });
+            command.write_message(&ProxyRequest {
+                id: String::from("ID_YOLO4"),
+                order: ProxyRequestOrder::AddBackend(backend),
+            });
+        }
+
+        // not sure why four times
+        for _ in 0..4 {
+            println!(
+                "read_message: {:?}",
+                command
+                    .read_message()
+                    .with_context(|| "could not read message")?
+            );
+        }
+
+        Ok(command)
+    }
+
+    /// runs a worker with this listener in a thread, used in tests only
+    fn spawn_proxy(
+        listener_config: TcpListenerConfig,
+    ) -> anyhow::Result<Channel<ProxyRequest, ProxyResponse>> {
         use crate::server;
 
         info!("listen for connections");
@@ -1723,14 +2423,6 @@ mod tests {
             let sessions = SessionManager::new(sessions, max_connections);
             let registry = poll.registry().try_clone().unwrap();
             let mut configuration = Proxy::new(registry, sessions.clone(), backends.clone());
-            let listener_config = TcpListenerConfig {
-                address: "127.0.0.1:1234".parse().unwrap(),
-                public_address: None,
-                expect_proxy: false,
-                front_timeout: 60,
-                back_timeout: 30,
-                connect_timeout: 3,
-            };
 
             {
                 let address = listener_config.address;
@@ -1778,72 +2470,6 @@ mod tests {
         });
 
         command.blocking();
-        {
-            let front = TcpFrontend {
-                cluster_id: String::from("yolo"),
-                address: "127.0.0.1:1234"
-                    .parse()
-                    .with_context(|| "Could not parse address")?,
-                tags: None,
-            };
-            let backend = proxy::Backend {
-                cluster_id: String::from("yolo"),
-                backend_id: String::from("yolo-0"),
-                address: "127.0.0.1:5678"
-                    .parse()
-                    .with_context(|| "Could not parse address")?,
-                load_balancing_parameters: Some(LoadBalancingParams::default()),
-                sticky_id: None,
-                backup: None,
-            };
-
-            command.write_message(&ProxyRequest {
-                id: String::from("ID_YOLO1"),
-                order: ProxyRequestOrder::AddTcpFrontend(front),
-            });
-            command.write_message(&ProxyRequest {
-                id: String::from("ID_YOLO2"),
-                order: ProxyRequestOrder::AddBackend(backend),
-            });
-        }
-        {
-            let front = TcpFrontend {
-                cluster_id: String::from("yolo"),
-                address: "127.0.0.1:1235"
-                    .parse()
-                    .with_context(|| "Could not parse address")?,
-                tags: None,
-            };
-            let backend = proxy::Backend {
-                cluster_id: String::from("yolo"),
-                backend_id: String::from("yolo-0"),
-                address: "127.0.0.1:5678"
-                    .parse()
-                    .with_context(|| "Could not parse address")?,
-                load_balancing_parameters: Some(LoadBalancingParams::default()),
-                sticky_id: None,
-                backup: None,
-            };
-            command.write_message(&ProxyRequest {
-                id: String::from("ID_YOLO3"),
-                order: ProxyRequestOrder::AddTcpFrontend(front),
-            });
-            command.write_message(&ProxyRequest {
-                id: String::from("ID_YOLO4"),
-                order: ProxyRequestOrder::AddBackend(backend),
-            });
-        }
-
-        // not sure why four times
-        for _ in 0..4 {
-            println!(
-                "read_message: {:?}",
-                command
-                    .read_message()
-                    .with_context(|| "could not read message")?
-            );
-        }
-
         Ok(command)
     }
 }
@@ -7,18 +7,34 @@ use std::{
     collections::{HashMap, HashSet},
     convert::From,
     io::BufReader,
+    net::SocketAddr,
     sync::{Arc, Mutex},
+    time::SystemTime,
 };
 
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
 use rustls::{
-    server::{ClientHello, ResolvesServerCert},
-    sign::{CertifiedKey, RsaSigningKey},
-    Certificate, PrivateKey,
+    client::{ServerCertVerified, ServerCertVerifier},
+    server::{
+        AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientCertVerified,
+        ClientCertVerifier, ClientHello, ProducesTickets, ResolvesServerCert,
+    },
+    sign::{CertifiedKey, RsaSigningKey, SigningKey},
+    Certificate, ClientConfig, ClientConnection, DistinguishedNames, OwnedTrustAnchor, PrivateKey,
+    RootCertStore, ServerName, SignatureScheme,
 };
+use rustls_pemfile::Item;
 use sha2::{Digest, Sha256};
-use sozu_command::proxy::TlsVersion;
+use sozu_command::{
+    command::CertificateIssue,
+    proxy::{BackendProtocol, BackendTls, ClientAuth, TlsVersion},
+};
 use x509_parser::{
     oid_registry::{OID_X509_COMMON_NAME, OID_X509_EXT_SUBJECT_ALT_NAME},
+    parse_x509_certificate, parse_x509_crl,
     pem::{parse_x509_pem, Pem},
 };
 
@@ -26,7 +42,8 @@ use crate::{
     router::trie::*,
     sozu_command::proxy::{
         AddCertificate, CertificateAndKey, CertificateFingerprint, RemoveCertificate,
-        ReplaceCertificate,
+        ReplaceCertificate, ResolvedCertificate, SetDefaultCertificate, SetOcspResponse,
+        SetTicketKeys, TICKET_KEY_LENGTH,
     },
 };
 
@@ -74,6 +91,14 @@ pub trait CertificateResolver {
 
         Ok(fingerprint)
     }
+
+    // `set_ocsp_response` replaces the OCSP response stapled with a certificate, or stops
+    // stapling it if there is no response
+    fn set_ocsp_response(&mut self, opts: &SetOcspResponse) -> Result<(), Self::Error>;
+
+    // `set_default_certificate` selects the certificate presented when the client sends no
+    // server name or a name matching no certificate, or unsets it
+    fn set_default_certificate(&mut self, opts: &SetDefaultCertificate) -> Result<(), Self::Error>;
 }
 
 // -----------------------------------------------------------------------------
@@ -135,6 +160,9 @@ pub struct ParsedCertificateAndKey {
     pub chain: Vec<Pem>,
     pub key: String,
     pub versions: Vec<TlsVersion>,
+    /// DER encoded OCSP response
+    pub ocsp_response: Option<Vec<u8>>,
+    pub priority: i32,
 }
 
 impl Clone for ParsedCertificateAndKey {
@@ -156,6 +184,8 @@ impl Clone for ParsedCertificateAndKey {
                 .collect(),
             key: self.key.to_owned(),
             versions: self.versions.to_owned(),
+            ocsp_response: self.ocsp_response.to_owned(),
+            priority: self.priority,
         }
     }
 }
@@ -171,10 +201,14 @@ pub enum GenericCertificateResolverError {
     PemParseError(String),
     #[error("failed to parse der certificate, {0}")]
     DerParseError(String),
+    #[error("failed to decode ocsp response, {0}")]
+    OcspResponseDecodeError(String),
     #[error("certificate, chain or private key is invalid")]
     InvalidPrivateKeyError,
     #[error("certificate is still in use")]
     IsStillInUseError,
+    #[error("unknown certificate {0}")]
+    UnknownCertificateError(CertificateFingerprint),
 }
 
 // -----------------------------------------------------------------------------
@@ -186,6 +220,10 @@ pub struct GenericCertificateResolver {
     certificates: HashMap<CertificateFingerprint, ParsedCertificateAndKey>,
     name_fingerprint_idx: HashMap<String, HashSet<CertificateFingerprint>>,
     overrides: HashMap<CertificateFingerprint, CertificateOverride>,
+    /// certificate presented when there is no server name, or when it matches no certificate
+    pub default_certificate: Option<CertificateFingerprint>,
+    /// no certificate is presented without a server name matching one
+    pub strict_sni: bool,
 }
 
 impl CertificateResolver for GenericCertificateResolver {
@@ -213,8 +251,21 @@ impl CertificateResolver for GenericCertificateResolver {
             self.overrides.remove(&fingerprint);
         }
 
-        // We do not need to update the entry, if the certificate is already registered
-        if self.get_certificate(&fingerprint).is_some() {
+        // We do not need to update the entry, if the certificate is already registered, only
+        // its priority may have changed
+        if let Some(certificate_and_key) = self.certificates.get_mut(&fingerprint) {
+            if certificate_and_key.priority != parsed_certificate_and_key.priority {
+                certificate_and_key.priority = parsed_certificate_and_key.priority;
+
+                let names = match self.get_names_override(&fingerprint) {
+                    Some(names) => names,
+                    None => self.certificate_names(&parsed_certificate_and_key.certificate)?,
+                };
+                for name in &names {
+                    self.elect_certificate(name);
+                }
+            }
+
             return Ok(fingerprint);
         }
 
@@ -232,27 +283,30 @@ impl CertificateResolver for GenericCertificateResolver {
 
         self.certificates
             .insert(fingerprint.to_owned(), parsed_certificate_and_key);
-        for name in new_names {
-            self.domains
-                .insert(name.to_owned().into_bytes(), fingerprint.to_owned());
-
+        for name in &new_names {
             self.name_fingerprint_idx
-                .entry(name)
+                .entry(name.to_owned())
                 .or_insert_with(HashSet::new)
                 .insert(fingerprint.to_owned());
         }
 
-        for (fingerprint, names) in &certificates_to_remove {
-            for name in names {
+        let mut names_to_elect = new_names;
+        for (fingerprint, names) in certificates_to_remove {
+            for name in &names {
                 if let Some(fingerprints) = self.name_fingerprint_idx.get_mut(name) {
-                    fingerprints.remove(fingerprint);
+                    fingerprints.remove(&fingerprint);
                 }
             }
 
-            self.certificates.remove(fingerprint);
+            self.certificates.remove(&fingerprint);
+            names_to_elect.extend(names);
+        }
+
+        for name in &names_to_elect {
+            self.elect_certificate(name);
         }
 
-        Ok(fingerprint.to_owned())
+        Ok(fingerprint)
     }
 
     fn remove_certificate(&mut self, opts: &RemoveCertificate) -> Result<(), Self::Error> {
@@ -269,18 +323,45 @@ impl CertificateResolver for GenericCertificateResolver {
             for name in &names {
                 if let Some(fingerprints) = self.name_fingerprint_idx.get_mut(name) {
                     fingerprints.remove(&opts.fingerprint);
-
-                    if fingerprints.is_empty() {
-                        self.domains.domain_remove(&name.to_owned().into_bytes());
-                    }
                 }
             }
 
             self.certificates.remove(&opts.fingerprint);
+            for name in &names {
+                self.elect_certificate(name);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_ocsp_response(&mut self, opts: &SetOcspResponse) -> Result<(), Self::Error> {
+        let ocsp_response = match &opts.ocsp_response {
+            Some(ocsp_response) => Some(hex::decode(ocsp_response).map_err(|err| {
+                GenericCertificateResolverError::OcspResponseDecodeError(err.to_string())
+            })?),
+            None => None,
+        };
+
+        if let Some(certificate_and_key) = self.certificates.get_mut(&opts.fingerprint) {
+            certificate_and_key.ocsp_response = ocsp_response;
         }
 
         Ok(())
     }
+
+    fn set_default_certificate(&mut self, opts: &SetDefaultCertificate) -> Result<(), Self::Error> {
+        if let Some(fingerprint) = &opts.fingerprint {
+            if !self.certificates.contains_key(fingerprint) {
+                return Err(GenericCertificateResolverError::UnknownCertificateError(
+                    fingerprint.to_owned(),
+                ));
+            }
+        }
+
+        self.default_certificate = opts.fingerprint.to_owned();
+        Ok(())
+    }
 }
 
 impl CertificateResolverHelper for GenericCertificateResolver {
@@ -358,6 +439,13 @@ impl CertificateResolverHelper for GenericCertificateResolver {
             chains.push(chain);
         }
 
+        let ocsp_response = match &certificate_and_key.ocsp_response {
+            Some(ocsp_response) => Some(hex::decode(ocsp_response).map_err(|err| {
+                GenericCertificateResolverError::OcspResponseDecodeError(err.to_string())
+            })?),
+            None => None,
+        };
+
         // try to parse key as rsa private key
         let mut key_reader = BufReader::new(certificate_and_key.key.as_bytes());
         let parsed_keys = rustls_pemfile::rsa_private_keys(&mut key_reader);
@@ -370,6 +458,8 @@ impl CertificateResolverHelper for GenericCertificateResolver {
                         chain: chains,
                         key: certificate_and_key.key.to_owned(),
                         versions: certificate_and_key.versions.to_owned(),
+                        ocsp_response,
+                        priority: certificate_and_key.priority,
                     });
                 }
             }
@@ -388,6 +478,8 @@ impl CertificateResolverHelper for GenericCertificateResolver {
                         chain: chains,
                         key: certificate_and_key.key.to_owned(),
                         versions: certificate_and_key.versions.to_owned(),
+                        ocsp_response,
+                        priority: certificate_and_key.priority,
                     });
                 }
 
@@ -398,6 +490,8 @@ impl CertificateResolverHelper for GenericCertificateResolver {
                         chain: chains,
                         key: certificate_and_key.key.to_owned(),
                         versions: certificate_and_key.versions.to_owned(),
+                        ocsp_response,
+                        priority: certificate_and_key.priority,
                     });
                 }
             }
@@ -414,6 +508,8 @@ impl Default for GenericCertificateResolver {
             certificates: Default::default(),
             name_fingerprint_idx: Default::default(),
             overrides: Default::default(),
+            default_certificate: None,
+            strict_sni: false,
         }
     }
 }
@@ -461,6 +557,7 @@ impl GenericCertificateResolver {
         let expiration = self
             .get_expiration_override(fingerprint)
             .unwrap_or_else(|| x509.validity().not_after.timestamp());
+        let priority = parsed_certificate_and_key.priority;
 
         let fingerprints = self.find_certificates_by_names(&new_names)?;
         let mut certificates = HashMap::new();
@@ -481,7 +578,7 @@ impl GenericCertificateResolver {
 
             let certificate_names = match self.get_names_override(fingerprint) {
                 Some(names) => names,
-                None => self.certificate_names(&parsed_certificate_and_key.certificate)?,
+                None => self.certificate_names(&certificate_and_key.certificate)?,
             };
 
             let certificate_expiration = self
@@ -492,13 +589,22 @@ impl GenericCertificateResolver {
                 .difference(&new_names)
                 .collect::<HashSet<_>>();
 
-            // if the certificate has at least the same name or less and the expiration date
-            // is closer than the new one. We could remove it and allow the new insertion.
-            if extra_names.is_empty() && certificate_expiration < expiration {
+            // if the certificate has at least the same name or less, and a lower priority or
+            // the same priority and an expiration date closer than the new one. We could remove
+            // it and allow the new insertion.
+            if extra_names.is_empty()
+                && (certificate_and_key.priority, certificate_expiration) < (priority, expiration)
+            {
                 certificates_to_remove.insert(fingerprint.to_owned(), certificate_names.to_owned());
                 should_insert = true;
             }
 
+            // the new certificate is preferred for the names it shares with a certificate
+            // with a lower priority
+            if certificate_and_key.priority < priority {
+                should_insert = true;
+            }
+
             // We keep a track of all name of certificates that match our query to
             // check, if the new certificate provide an extra domain which is not
             // already exposed
@@ -520,6 +626,48 @@ impl GenericCertificateResolver {
         Ok((true, certificates_to_remove))
     }
 
+    /// points a name to the certificate providing it with the highest priority, then
+    /// the latest expiration, or removes the name if no certificate provides it anymore
+    fn elect_certificate(&mut self, name: &str) {
+        let elected = self
+            .name_fingerprint_idx
+            .get(name)
+            .and_then(|fingerprints| {
+                fingerprints
+                    .iter()
+                    .filter_map(|fingerprint| {
+                        let certificate_and_key = self.certificates.get(fingerprint)?;
+                        let expiration = match self.get_expiration_override(fingerprint) {
+                            Some(expiration) => expiration,
+                            None => certificate_and_key
+                                .certificate
+                                .parse_x509()
+                                .ok()?
+                                .validity()
+                                .not_after
+                                .timestamp(),
+                        };
+
+                        Some((certificate_and_key.priority, expiration, fingerprint))
+                    })
+                    .max()
+                    .map(|(_, _, fingerprint)| fingerprint.to_owned())
+            });
+
+        let key = name.as_bytes().to_vec();
+        match elected {
+            Some(fingerprint) => match self.domains.domain_lookup_mut(&key, true) {
+                Some((existing, value)) if *existing == key => *value = fingerprint,
+                _ => {
+                    self.domains.domain_insert(key, fingerprint);
+                }
+            },
+            None => {
+                self.domains.domain_remove(&key);
+            }
+        }
+    }
+
     fn get_expiration_override(&self, fingerprint: &CertificateFingerprint) -> Option<i64> {
         self.overrides.get(fingerprint).and_then(|co| co.expiration)
     }
@@ -537,6 +685,53 @@ impl GenericCertificateResolver {
     ) -> Option<&KeyValue<Key, CertificateFingerprint>> {
         self.domains.domain_lookup(domain, accept_wildcard)
     }
+
+    /// returns the fingerprint of the certificate to present for a server name, or the
+    /// default certificate if there is no server name or it matches no certificate,
+    /// unless the SNI is strict
+    pub fn resolve(&self, server_name: Option<&[u8]>) -> Option<&CertificateFingerprint> {
+        let matched = server_name
+            .and_then(|name| self.domain_lookup(name, true))
+            .map(|(_, fingerprint)| fingerprint);
+
+        if self.strict_sni {
+            return matched;
+        }
+        matched.or(self.default_certificate.as_ref())
+    }
+
+    /// describes the certificate that `resolve` picks for a server name
+    pub fn resolved_certificate(&self, server_name: Option<&[u8]>) -> Option<ResolvedCertificate> {
+        let matched = server_name.and_then(|name| self.domain_lookup(name, true));
+        let fingerprint = self.resolve(server_name)?;
+        let certificate_and_key = self.certificates.get(fingerprint)?;
+
+        let mut names: Vec<String> = self
+            .certificate_names(&certificate_and_key.certificate)
+            .ok()?
+            .into_iter()
+            .collect();
+        names.sort();
+
+        let expiration = match self.get_expiration_override(fingerprint) {
+            Some(expiration) => expiration,
+            None => certificate_and_key
+                .certificate
+                .parse_x509()
+                .ok()?
+                .validity()
+                .not_after
+                .timestamp(),
+        };
+
+        Some(ResolvedCertificate {
+            fingerprint: fingerprint.to_owned(),
+            names,
+            expiration,
+            versions: certificate_and_key.versions.to_owned(),
+            default: matched.is_none(),
+        })
+    }
 }
 
 // -----------------------------------------------------------------------------
@@ -547,26 +742,42 @@ pub struct MutexWrappedCertificateResolver(pub Mutex<GenericCertificateResolver>
 
 impl ResolvesServerCert for MutexWrappedCertificateResolver {
     fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
-        let server_name = client_hello.server_name();
-        let sigschemes = client_hello.signature_schemes();
+        self.resolve_server_name(client_hello.server_name(), client_hello.signature_schemes())
+    }
+}
 
-        if server_name.is_none() {
-            error!("cannot look up certificate: no SNI from session");
-            return None;
-        }
+impl Default for MutexWrappedCertificateResolver {
+    fn default() -> Self {
+        Self(Mutex::new(GenericCertificateResolver::default()))
+    }
+}
 
-        let name: &str = server_name.unwrap();
+impl MutexWrappedCertificateResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the certificate and key of the store to present for a server name
+    pub fn resolve_server_name(
+        &self,
+        server_name: Option<&str>,
+        sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
         trace!(
             "trying to resolve name: {:?} for signature scheme: {:?}",
-            name,
+            server_name,
             sigschemes
         );
         if let Ok(ref mut resolver) = self.0.try_lock() {
             //resolver.domains.print();
-            if let Some((_, fingerprint)) = resolver.domains.domain_lookup(name.as_bytes(), true) {
+            match server_name.and_then(|name| resolver.domain_lookup(name.as_bytes(), true)) {
+                Some(_) => incr!("tls.sni.hit"),
+                None => incr!("tls.sni.miss"),
+            }
+            if let Some(fingerprint) = resolver.resolve(server_name.map(str::as_bytes)) {
                 trace!(
                     "looking for certificate for {:?} with fingerprint {:?}",
-                    name,
+                    server_name,
                     fingerprint
                 );
                 return resolver
@@ -575,23 +786,20 @@ impl ResolvesServerCert for MutexWrappedCertificateResolver {
                     .and_then(Self::generate_certified_key)
                     .map(Arc::new);
             }
+
+            if resolver.strict_sni {
+                incr!("tls.strict_sni.rejected");
+                debug!("strict SNI, refusing the server name {:?}", server_name);
+                return None;
+            }
         }
 
-        error!("could not look up a certificate for server name '{}'", name);
+        match server_name {
+            Some(name) => error!("could not look up a certificate for server name '{}'", name),
+            None => error!("cannot look up certificate: no SNI from session"),
+        }
         None
     }
-}
-
-impl Default for MutexWrappedCertificateResolver {
-    fn default() -> Self {
-        Self(Mutex::new(GenericCertificateResolver::default()))
-    }
-}
-
-impl MutexWrappedCertificateResolver {
-    pub fn new() -> Self {
-        Self::default()
-    }
 
     fn generate_certified_key(
         certificate_and_key: &ParsedCertificateAndKey,
@@ -611,7 +819,8 @@ impl MutexWrappedCertificateResolver {
                 let key = PrivateKey(keys.swap_remove(0));
 
                 if let Ok(signing_key) = RsaSigningKey::new(&key) {
-                    let certified = CertifiedKey::new(chains, Arc::new(signing_key));
+                    let mut certified = CertifiedKey::new(chains, Arc::new(signing_key));
+                    certified.ocsp = certificate_and_key.ocsp_response.to_owned();
                     return Some(certified);
                 }
             } else {
@@ -621,10 +830,12 @@ impl MutexWrappedCertificateResolver {
                     if !keys.is_empty() {
                         let key = PrivateKey(keys.swap_remove(0));
                         if let Ok(signing_key) = RsaSigningKey::new(&key) {
-                            let certified = CertifiedKey::new(chains, Arc::new(signing_key));
+                            let mut certified = CertifiedKey::new(chains, Arc::new(signing_key));
+                            certified.ocsp = certificate_and_key.ocsp_response.to_owned();
                             return Some(certified);
                         } else if let Ok(k) = rustls::sign::any_ecdsa_type(&key) {
-                            let certified = CertifiedKey::new(chains, k);
+                            let mut certified = CertifiedKey::new(chains, k);
+                            certified.ocsp = certificate_and_key.ocsp_response.to_owned();
                             return Some(certified);
                         } else {
                             error!("could not decode signing key (tried RSA and ECDSA)");
@@ -640,6 +851,683 @@ impl MutexWrappedCertificateResolver {
     }
 }
 
+// -----------------------------------------------------------------------------
+// DelegatedCertificateResolver trait
+
+/// certificate resolver supplied by an embedder of the library, to present
+/// certificates and keys that are not stored in the worker (a vault, an HSM
+/// reached through PKCS#11...). It is set per rustls HTTPS listener, and the
+/// in-memory certificate store of the listener is only used for the server
+/// names it does not resolve.
+pub trait DelegatedCertificateResolver: Send + Sync {
+    /// returns the certificate chain and signing key to present for this
+    /// server name, the signing key must support one of the signature schemes
+    fn resolve(
+        &self,
+        server_name: Option<&str>,
+        signature_schemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>>;
+}
+
+/// delegated certificate resolvers, by address of the HTTPS listener
+pub type DelegatedCertificateResolvers = HashMap<SocketAddr, Arc<dyn DelegatedCertificateResolver>>;
+
+// -----------------------------------------------------------------------------
+// DelegatingCertificateResolver struct
+
+/// rustls resolver of a listener with a delegated certificate resolver, falling
+/// back to the certificates of the listener
+pub struct DelegatingCertificateResolver {
+    delegated: Arc<dyn DelegatedCertificateResolver>,
+    store: Arc<MutexWrappedCertificateResolver>,
+}
+
+impl DelegatingCertificateResolver {
+    pub fn new(
+        delegated: Arc<dyn DelegatedCertificateResolver>,
+        store: Arc<MutexWrappedCertificateResolver>,
+    ) -> Self {
+        Self { delegated, store }
+    }
+
+    /// the certificate and key of the delegated resolver for a server name, or
+    /// the ones of the store if it does not resolve it
+    pub fn resolve_server_name(
+        &self,
+        server_name: Option<&str>,
+        signature_schemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        if let Some(certified_key) = self.delegated.resolve(server_name, signature_schemes) {
+            incr!("tls.delegated_resolver.hit");
+            return Some(certified_key);
+        }
+
+        incr!("tls.delegated_resolver.miss");
+        self.store
+            .resolve_server_name(server_name, signature_schemes)
+    }
+}
+
+impl ResolvesServerCert for DelegatingCertificateResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.resolve_server_name(client_hello.server_name(), client_hello.signature_schemes())
+    }
+}
+
+// -----------------------------------------------------------------------------
+// ClientAuthError enum
+
+#[derive(thiserror::Error, Clone, Debug)]
+pub enum ClientAuthError {
+    #[error("failed to parse pem, {0}")]
+    PemParseError(String),
+    #[error("failed to parse certificate revocation list, {0}")]
+    CrlParseError(String),
+    #[error("no certificate authority found to verify the client certificates")]
+    NoCertificateAuthority,
+}
+
+// -----------------------------------------------------------------------------
+// RevocationList struct
+
+/// serial numbers of the revoked certificates, indexed by the raw DN of their issuer
+#[derive(Clone, Debug, Default)]
+pub struct RevocationList {
+    revoked: HashMap<Vec<u8>, HashSet<Vec<u8>>>,
+}
+
+impl RevocationList {
+    /// parses the `X509 CRL` blocks of a PEM bundle, the other blocks are ignored
+    pub fn from_pem(pem: &str) -> Result<Self, ClientAuthError> {
+        let mut revoked: HashMap<Vec<u8>, HashSet<Vec<u8>>> = HashMap::new();
+
+        for block in Pem::iter_from_buffer(pem.as_bytes()) {
+            let block = block.map_err(|err| ClientAuthError::PemParseError(err.to_string()))?;
+            // the parser stops the label at the first space, `X509 CRL` is read as `X509`
+            if !block.label.starts_with("X509") {
+                continue;
+            }
+
+            let (_, crl) = parse_x509_crl(&block.contents)
+                .map_err(|err| ClientAuthError::CrlParseError(err.to_string()))?;
+
+            revoked
+                .entry(crl.issuer().as_raw().to_vec())
+                .or_default()
+                .extend(
+                    crl.iter_revoked_certificates()
+                        .map(|certificate| certificate.serial().to_bytes_be()),
+                );
+        }
+
+        Ok(Self { revoked })
+    }
+
+    /// checks a DER encoded certificate. A certificate that cannot be parsed
+    /// is considered revoked, unless the list is empty
+    pub fn is_revoked(&self, certificate: &[u8]) -> bool {
+        if self.revoked.is_empty() {
+            return false;
+        }
+
+        match parse_x509_certificate(certificate) {
+            Ok((_, certificate)) => self
+                .revoked
+                .get(certificate.issuer().as_raw())
+                .map(|serials| serials.contains(&certificate.serial.to_bytes_be()))
+                .unwrap_or(false),
+            Err(_) => true,
+        }
+    }
+}
+
+/// returns the subject DN of a DER encoded certificate, without the control
+/// characters so that it can be sent in a header
+pub fn certificate_subject(certificate: &[u8]) -> Option<String> {
+    let (_, certificate) = parse_x509_certificate(certificate).ok()?;
+
+    Some(
+        certificate
+            .subject()
+            .to_string()
+            .chars()
+            .filter(|c| !c.is_control())
+            .collect(),
+    )
+}
+
+// -----------------------------------------------------------------------------
+// ClientCertificateVerifier struct
+
+/// verifies the client certificates for rustls, against the certificate authorities
+/// of the listener, then checks that no certificate of the chain is revoked
+pub struct ClientCertificateVerifier {
+    inner: Arc<dyn ClientCertVerifier>,
+    revocation_list: RevocationList,
+}
+
+impl ClientCertificateVerifier {
+    pub fn new(client_auth: &ClientAuth) -> Result<Self, ClientAuthError> {
+        let mut reader = BufReader::new(client_auth.ca_certificates.as_bytes());
+        let certificates = rustls_pemfile::certs(&mut reader)
+            .map_err(|err| ClientAuthError::PemParseError(err.to_string()))?;
+
+        let mut roots = RootCertStore::empty();
+        let (added, ignored) = roots.add_parsable_certificates(&certificates);
+        if ignored > 0 {
+            warn!("ignored {} invalid client certificate authorities", ignored);
+        }
+        if added == 0 {
+            return Err(ClientAuthError::NoCertificateAuthority);
+        }
+
+        let inner = if client_auth.required {
+            AllowAnyAuthenticatedClient::new(roots)
+        } else {
+            AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+        };
+
+        let revocation_list = match &client_auth.revocation_lists {
+            Some(pem) => RevocationList::from_pem(pem)?,
+            None => RevocationList::default(),
+        };
+
+        Ok(Self {
+            inner,
+            revocation_list,
+        })
+    }
+}
+
+impl ClientCertVerifier for ClientCertificateVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.inner.offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> Option<bool> {
+        self.inner.client_auth_mandatory()
+    }
+
+    fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+        self.inner.client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        let verified = self
+            .inner
+            .verify_client_cert(end_entity, intermediates, now)?;
+
+        if std::iter::once(end_entity)
+            .chain(intermediates)
+            .any(|certificate| self.revocation_list.is_revoked(&certificate.0))
+        {
+            incr!("tls.client_certificate.revoked");
+            return Err(rustls::Error::InvalidCertificateData(String::from(
+                "the client certificate is revoked",
+            )));
+        }
+
+        Ok(verified)
+    }
+}
+
+// -----------------------------------------------------------------------------
+// BackendTlsError enum
+
+#[derive(thiserror::Error, Clone, Debug)]
+pub enum BackendTlsError {
+    #[error("failed to parse pem, {0}")]
+    PemParseError(String),
+    #[error("no certificate authority found to verify the backends")]
+    NoCertificateAuthority,
+    #[error("no private key found for the client certificate")]
+    NoClientKey,
+    #[error("invalid server name {0}")]
+    InvalidServerName(String),
+    #[error("invalid client certificate, {0}")]
+    InvalidClientCertificate(String),
+}
+
+// -----------------------------------------------------------------------------
+// BackendTlsConfig struct
+
+/// rustls configuration of the connections to the backends of a cluster,
+/// or to a single backend
+#[derive(Debug)]
+pub struct BackendTlsConfig {
+    settings: BackendTls,
+    config: Arc<ClientConfig>,
+    /// the same configuration, offering only the "h2" ALPN protocol
+    h2_config: Arc<ClientConfig>,
+    server_name: Option<ServerName>,
+}
+
+impl PartialEq for BackendTlsConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.settings == other.settings
+    }
+}
+
+impl BackendTlsConfig {
+    pub fn new(tls: &BackendTls) -> Result<Self, BackendTlsError> {
+        let server_name = match &tls.sni {
+            Some(sni) => Some(
+                ServerName::try_from(sni.as_str())
+                    .map_err(|_| BackendTlsError::InvalidServerName(sni.to_owned()))?,
+            ),
+            None => None,
+        };
+
+        let mut roots = RootCertStore::empty();
+        match &tls.ca_certificates {
+            Some(pem) => {
+                let mut reader = BufReader::new(pem.as_bytes());
+                let certificates = rustls_pemfile::certs(&mut reader)
+                    .map_err(|err| BackendTlsError::PemParseError(err.to_string()))?;
+
+                let (added, ignored) = roots.add_parsable_certificates(&certificates);
+                if ignored > 0 {
+                    warn!(
+                        "ignored {} invalid backend certificate authorities",
+                        ignored
+                    );
+                }
+                if added == 0 {
+                    return Err(BackendTlsError::NoCertificateAuthority);
+                }
+            }
+            None => roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(
+                |anchor| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(
+                        anchor.subject,
+                        anchor.spki,
+                        anchor.name_constraints,
+                    )
+                },
+            )),
+        }
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+
+        let mut config = match (&tls.client_certificate, &tls.client_key) {
+            (Some(certificate), Some(key)) => {
+                let mut reader = BufReader::new(certificate.as_bytes());
+                let certificates = rustls_pemfile::certs(&mut reader)
+                    .map_err(|err| BackendTlsError::PemParseError(err.to_string()))?
+                    .into_iter()
+                    .map(Certificate)
+                    .collect();
+
+                builder
+                    .with_single_cert(certificates, parse_private_key(key)?)
+                    .map_err(|err| BackendTlsError::InvalidClientCertificate(err.to_string()))?
+            }
+            _ => builder.with_no_client_auth(),
+        };
+
+        if tls.skip_verification {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(SkipServerVerification));
+        }
+
+        let mut h2_config = config.clone();
+        h2_config.alpn_protocols = vec![b"h2".to_vec()];
+        config.alpn_protocols = tls
+            .alpn_protocols
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect();
+
+        Ok(Self {
+            settings: tls.to_owned(),
+            config: Arc::new(config),
+            h2_config: Arc::new(h2_config),
+            server_name,
+        })
+    }
+
+    /// starts a TLS session with a backend, identified by its IP address
+    /// if no server name is configured
+    pub fn connect(
+        &self,
+        address: SocketAddr,
+        protocol: BackendProtocol,
+    ) -> Result<ClientConnection, rustls::Error> {
+        let server_name = self
+            .server_name
+            .clone()
+            .unwrap_or(ServerName::IpAddress(address.ip()));
+
+        let config = match protocol {
+            BackendProtocol::Http1 => self.config.clone(),
+            BackendProtocol::Http2 | BackendProtocol::Grpc => self.h2_config.clone(),
+        };
+        ClientConnection::new(config, server_name)
+    }
+}
+
+/// returns the first private key of a PEM bundle, in the PKCS#1, PKCS#8 or SEC1 format
+fn parse_private_key(pem: &str) -> Result<PrivateKey, BackendTlsError> {
+    let mut reader = BufReader::new(pem.as_bytes());
+    let items = rustls_pemfile::read_all(&mut reader)
+        .map_err(|err| BackendTlsError::PemParseError(err.to_string()))?;
+
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or(BackendTlsError::NoClientKey)
+}
+
+/// accepts any certificate from the backends, when they are reached over a trusted network
+struct SkipServerVerification;
+
+impl ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Certificate validation
+
+/// signature algorithms accepted when verifying the chain of a certificate
+static SUPPORTED_SIGNATURE_ALGORITHMS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// checks a certificate before it is sent to the workers: its private key must match it,
+/// it must be valid now, and its chain must be ordered and lead to a known root
+/// certificate, or to a self-signed one. Returns the problems found
+pub fn validate_certificate(certificate_and_key: &CertificateAndKey) -> Vec<CertificateIssue> {
+    let mut certificates = Vec::new();
+    for certificate in std::iter::once(&certificate_and_key.certificate)
+        .chain(certificate_and_key.certificate_chain.iter())
+    {
+        match parse_x509_pem(certificate.as_bytes()) {
+            Ok((_, pem)) => certificates.push(pem.contents),
+            Err(err) => return vec![CertificateIssue::InvalidCertificate(err.to_string())],
+        }
+    }
+
+    let mut parsed_certificates = Vec::new();
+    for certificate in &certificates {
+        match parse_x509_certificate(certificate) {
+            Ok((_, parsed_certificate)) => parsed_certificates.push(parsed_certificate),
+            Err(err) => return vec![CertificateIssue::InvalidCertificate(err.to_string())],
+        }
+    }
+
+    let mut issues = Vec::new();
+
+    match parse_private_key(&certificate_and_key.key)
+        .ok()
+        .and_then(|key| rustls::sign::any_supported_type(&key).ok())
+    {
+        Some(key) if !key_matches_certificate(key.as_ref(), &certificates[0]) => {
+            issues.push(CertificateIssue::KeyMismatch)
+        }
+        Some(_) => {}
+        None => issues.push(CertificateIssue::InvalidKey),
+    }
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0);
+    let validity = parsed_certificates[0].validity();
+    let is_expired = validity.not_after.timestamp() < now as i64;
+    let is_not_yet_valid = validity.not_before.timestamp() > now as i64;
+    if is_expired {
+        issues.push(CertificateIssue::Expired);
+    }
+    if is_not_yet_valid {
+        issues.push(CertificateIssue::NotYetValid);
+    }
+
+    for (position, certificates) in parsed_certificates.windows(2).enumerate() {
+        if certificates[0].issuer().as_raw() != certificates[1].subject().as_raw() {
+            issues.push(CertificateIssue::UnorderedChain(position));
+        }
+    }
+
+    // a chain ending with a self-signed certificate is complete, even if it is not trusted
+    let last = &parsed_certificates[parsed_certificates.len() - 1];
+    if last.issuer().as_raw() == last.subject().as_raw() {
+        return issues;
+    }
+
+    let intermediates: Vec<&[u8]> = certificates[1..].iter().map(Vec::as_slice).collect();
+    let verification =
+        webpki::EndEntityCert::try_from(certificates[0].as_slice()).and_then(|certificate| {
+            certificate.verify_is_valid_tls_server_cert(
+                SUPPORTED_SIGNATURE_ALGORITHMS,
+                &webpki_roots::TLS_SERVER_ROOTS,
+                &intermediates,
+                webpki::Time::from_seconds_since_unix_epoch(now),
+            )
+        });
+    match verification {
+        Ok(()) => {}
+        Err(webpki::Error::UnknownIssuer) => issues.push(CertificateIssue::IncompleteChain),
+        // already reported for the certificate itself
+        Err(webpki::Error::CertExpired) if is_expired => {}
+        Err(webpki::Error::CertNotValidYet) if is_not_yet_valid => {}
+        Err(err) => issues.push(CertificateIssue::UntrustedChain(format!("{:?}", err))),
+    }
+
+    issues
+}
+
+/// signs a message with the private key, and verifies the signature with the public
+/// key of the DER encoded certificate
+fn key_matches_certificate(key: &dyn SigningKey, certificate: &[u8]) -> bool {
+    let signer = match key.choose_scheme(&[
+        SignatureScheme::ED25519,
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        SignatureScheme::RSA_PKCS1_SHA256,
+    ]) {
+        Some(signer) => signer,
+        None => return false,
+    };
+
+    let algorithm = match signer.scheme() {
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::RSA_PKCS1_SHA256 => &webpki::RSA_PKCS1_2048_8192_SHA256,
+        _ => return false,
+    };
+
+    let message = b"sozu certificate validation";
+    match (
+        signer.sign(message),
+        webpki::EndEntityCert::try_from(certificate),
+    ) {
+        (Ok(signature), Ok(certificate)) => certificate
+            .verify_signature(algorithm, message, &signature)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+// -----------------------------------------------------------------------------
+// TicketKeyError enum
+
+#[derive(thiserror::Error, Clone, Debug)]
+pub enum TicketKeyError {
+    #[error("failed to decode ticket key, {0}")]
+    InvalidKey(String),
+    #[error("failed to lock the ticket keys, {0}")]
+    LockError(String),
+}
+
+// -----------------------------------------------------------------------------
+// SharedTicketer struct
+
+const TICKET_KEY_NAME_LENGTH: usize = 16;
+
+struct TicketKey {
+    name: [u8; TICKET_KEY_NAME_LENGTH],
+    key: LessSafeKey,
+}
+
+#[derive(Default)]
+struct TicketKeys {
+    /// the first key encrypts the new tickets
+    keys: Vec<TicketKey>,
+    lifetime: u32,
+}
+
+/// encrypts the session tickets of the rustls listeners with the keys sent by the
+/// main process, so that a ticket issued by a worker is accepted by all of them.
+/// A ticket is the key name, a random nonce, then the encrypted session
+pub struct SharedTicketer {
+    keys: Mutex<TicketKeys>,
+    random: SystemRandom,
+}
+
+impl Default for SharedTicketer {
+    fn default() -> Self {
+        Self {
+            keys: Mutex::new(TicketKeys::default()),
+            random: SystemRandom::new(),
+        }
+    }
+}
+
+impl SharedTicketer {
+    /// replaces the keys, the tickets encrypted with a key that is not sent anymore
+    /// cannot be decrypted
+    pub fn set_keys(&self, set_ticket_keys: &SetTicketKeys) -> Result<(), TicketKeyError> {
+        let mut keys = Vec::new();
+        for key in &set_ticket_keys.keys {
+            let key =
+                hex::decode(key).map_err(|err| TicketKeyError::InvalidKey(err.to_string()))?;
+            if key.len() != TICKET_KEY_LENGTH {
+                return Err(TicketKeyError::InvalidKey(format!(
+                    "expected {} bytes, got {}",
+                    TICKET_KEY_LENGTH,
+                    key.len()
+                )));
+            }
+
+            let mut name = [0u8; TICKET_KEY_NAME_LENGTH];
+            name.copy_from_slice(&key[..TICKET_KEY_NAME_LENGTH]);
+            // the last 32 bytes are the encryption secret
+            let secret = &key[TICKET_KEY_LENGTH - CHACHA20_POLY1305.key_len()..];
+            let key = UnboundKey::new(&CHACHA20_POLY1305, secret)
+                .map_err(|err| TicketKeyError::InvalidKey(err.to_string()))?;
+
+            keys.push(TicketKey {
+                name,
+                key: LessSafeKey::new(key),
+            });
+        }
+
+        *self
+            .keys
+            .lock()
+            .map_err(|err| TicketKeyError::LockError(err.to_string()))? = TicketKeys {
+            keys,
+            lifetime: set_ticket_keys.lifetime,
+        };
+        Ok(())
+    }
+}
+
+impl ProducesTickets for SharedTicketer {
+    // without keys, rustls falls back to the session cache of the worker
+    fn enabled(&self) -> bool {
+        self.keys
+            .lock()
+            .map(|keys| !keys.keys.is_empty())
+            .unwrap_or(false)
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.keys.lock().map(|keys| keys.lifetime).unwrap_or(0)
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let keys = self.keys.lock().ok()?;
+        let current = keys.keys.first()?;
+
+        let mut nonce = [0u8; NONCE_LEN];
+        self.random.fill(&mut nonce).ok()?;
+
+        let mut encrypted = plain.to_vec();
+        current
+            .key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(current.name),
+                &mut encrypted,
+            )
+            .ok()?;
+
+        let mut ticket = Vec::with_capacity(TICKET_KEY_NAME_LENGTH + NONCE_LEN + encrypted.len());
+        ticket.extend_from_slice(&current.name);
+        ticket.extend_from_slice(&nonce);
+        ticket.extend_from_slice(&encrypted);
+        Some(ticket)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        if cipher.len() < TICKET_KEY_NAME_LENGTH + NONCE_LEN {
+            return None;
+        }
+        let (name, cipher) = cipher.split_at(TICKET_KEY_NAME_LENGTH);
+        let (nonce, cipher) = cipher.split_at(NONCE_LEN);
+
+        let keys = self.keys.lock().ok()?;
+        let ticket_key = keys.keys.iter().find(|key| key.name == name)?;
+
+        let mut decrypted = cipher.to_vec();
+        let plain_length = ticket_key
+            .key
+            .open_in_place(
+                Nonce::try_assume_unique_for_key(nonce).ok()?,
+                Aad::from(ticket_key.name),
+                &mut decrypted,
+            )
+            .ok()?
+            .len();
+        decrypted.truncate(plain_length);
+        Some(decrypted)
+    }
+}
+
 // -----------------------------------------------------------------------------
 // Unit tests
 
@@ -648,17 +1536,33 @@ mod tests {
     use std::{
         collections::HashSet,
         error::Error,
+        sync::Arc,
         time::{Duration, SystemTime},
     };
 
     use super::{
-        CertificateResolver, CertificateResolverHelper, GenericCertificateResolver,
-        GenericCertificateResolverError,
+        certificate_subject, validate_certificate, BackendTlsConfig, BackendTlsError,
+        CertificateResolver, CertificateResolverHelper, ClientCertificateVerifier,
+        DelegatedCertificateResolver, DelegatingCertificateResolver, GenericCertificateResolver,
+        GenericCertificateResolverError, MutexWrappedCertificateResolver, RevocationList,
+        SharedTicketer,
     };
 
-    use crate::sozu_command::proxy::{AddCertificate, CertificateAndKey, RemoveCertificate};
+    use crate::sozu_command::{
+        command::CertificateIssue,
+        proxy::{
+            AddCertificate, BackendProtocol, BackendTls, CertificateAndKey, CertificateFingerprint,
+            ClientAuth, RemoveCertificate, ResolvedCertificate, SetDefaultCertificate,
+            SetTicketKeys, TlsVersion, DEFAULT_CLIENT_DN_HEADER, TICKET_KEY_LENGTH,
+        },
+    };
 
     use rand::{seq::SliceRandom, thread_rng};
+    use rustls::{
+        server::{ClientCertVerifier, ProducesTickets},
+        sign::CertifiedKey,
+        Certificate, SignatureScheme,
+    };
     use x509_parser::pem::parse_x509_pem;
 
     #[test]
@@ -670,6 +1574,8 @@ mod tests {
             key: String::from(include_str!("../assets/key.pem")),
             certificate_chain: vec![],
             versions: vec![],
+            ocsp_response: None,
+            priority: 0,
         };
 
         let (_, pem) = parse_x509_pem(certificate_and_key.certificate.as_bytes())
@@ -720,6 +1626,8 @@ mod tests {
             key: String::from(include_str!("../assets/key.pem")),
             certificate_chain: vec![],
             versions: vec![],
+            ocsp_response: None,
+            priority: 0,
         };
 
         let (_, pem) = parse_x509_pem(certificate_and_key.certificate.as_bytes())
@@ -784,6 +1692,8 @@ mod tests {
             key: String::from(include_str!("../assets/tests/key-1y.pem")),
             certificate_chain: vec![],
             versions: vec![],
+            ocsp_response: None,
+            priority: 0,
         };
 
         let (_, pem) = parse_x509_pem(certificate_and_key_1y.certificate.as_bytes())
@@ -808,6 +1718,8 @@ mod tests {
             key: String::from(include_str!("../assets/tests/key-2y.pem")),
             certificate_chain: vec![],
             versions: vec![],
+            ocsp_response: None,
+            priority: 0,
         };
 
         let fingerprint_2y = resolver.add_certificate(&AddCertificate {
@@ -855,6 +1767,8 @@ mod tests {
             key: String::from(include_str!("../assets/tests/key-1y.pem")),
             certificate_chain: vec![],
             versions: vec![],
+            ocsp_response: None,
+            priority: 0,
         };
 
         let (_, pem) = parse_x509_pem(certificate_and_key_1y.certificate.as_bytes())
@@ -883,6 +1797,8 @@ mod tests {
             key: String::from(include_str!("../assets/tests/key-2y.pem")),
             certificate_chain: vec![],
             versions: vec![],
+            ocsp_response: None,
+            priority: 0,
         };
 
         let fingerprint_2y = resolver.add_certificate(&AddCertificate {
@@ -918,6 +1834,278 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn priority() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let address = "127.0.0.1:8080".parse()?;
+        let mut resolver = GenericCertificateResolver::new();
+
+        // ---------------------------------------------------------------------
+        // load a certificate expiring later, with the default priority
+        let fingerprint_2y = resolver.add_certificate(&AddCertificate {
+            address,
+            certificate: CertificateAndKey {
+                certificate: String::from(include_str!("../assets/tests/certificate-2y.pem")),
+                key: String::from(include_str!("../assets/tests/key-2y.pem")),
+                certificate_chain: vec![],
+                versions: vec![],
+                ocsp_response: None,
+                priority: 0,
+            },
+            names: vec!["localhost".into(), "lolcatho.st".into()],
+            expired_at: None,
+        })?;
+
+        // ---------------------------------------------------------------------
+        // load a certificate with a higher priority, sharing one of the names
+        let fingerprint_1y = resolver.add_certificate(&AddCertificate {
+            address,
+            certificate: CertificateAndKey {
+                certificate: String::from(include_str!("../assets/tests/certificate-1y.pem")),
+                key: String::from(include_str!("../assets/tests/key-1y.pem")),
+                certificate_chain: vec![],
+                versions: vec![],
+                ocsp_response: None,
+                priority: 1,
+            },
+            names: vec!["localhost".into()],
+            expired_at: None,
+        })?;
+
+        if resolver.get_certificate(&fingerprint_1y).is_none()
+            || resolver.get_certificate(&fingerprint_2y).is_none()
+        {
+            return Err("both certificates must be loaded".into());
+        }
+
+        if resolver.resolve(Some(b"localhost")) != Some(&fingerprint_1y) {
+            return Err("the certificate with the highest priority must be presented".into());
+        }
+
+        if resolver.resolve(Some(b"lolcatho.st")) != Some(&fingerprint_2y) {
+            return Err("the only certificate providing the name must be presented".into());
+        }
+
+        // ---------------------------------------------------------------------
+        // the name falls back on the remaining certificate
+        resolver.remove_certificate(&RemoveCertificate {
+            address,
+            fingerprint: fingerprint_1y.to_owned(),
+        })?;
+
+        if resolver.resolve(Some(b"localhost")) != Some(&fingerprint_2y) {
+            return Err("the remaining certificate must be presented".into());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn default_certificate() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let address = "127.0.0.1:8080".parse()?;
+        let mut resolver = GenericCertificateResolver::new();
+
+        let fingerprint = resolver.add_certificate(&AddCertificate {
+            address,
+            certificate: CertificateAndKey {
+                certificate: String::from(include_str!("../assets/certificate.pem")),
+                key: String::from(include_str!("../assets/key.pem")),
+                certificate_chain: vec![],
+                versions: vec![],
+                ocsp_response: None,
+                priority: 0,
+            },
+            names: vec!["lolcatho.st".into()],
+            expired_at: None,
+        })?;
+
+        if resolver.resolve(None).is_some() || resolver.resolve(Some(b"example.com")).is_some() {
+            return Err("there must be no certificate without a default one".into());
+        }
+
+        if resolver
+            .set_default_certificate(&SetDefaultCertificate {
+                address,
+                fingerprint: Some(CertificateFingerprint(vec![0; 32])),
+            })
+            .is_ok()
+        {
+            return Err("an unknown certificate must not be the default one".into());
+        }
+
+        resolver.set_default_certificate(&SetDefaultCertificate {
+            address,
+            fingerprint: Some(fingerprint.to_owned()),
+        })?;
+
+        if resolver.resolve(None) != Some(&fingerprint)
+            || resolver.resolve(Some(b"example.com")) != Some(&fingerprint)
+        {
+            return Err("the default certificate must be presented".into());
+        }
+
+        resolver.set_default_certificate(&SetDefaultCertificate {
+            address,
+            fingerprint: None,
+        })?;
+
+        if resolver.resolve(None).is_some() {
+            return Err("the default certificate must be unset".into());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn strict_sni() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let address = "127.0.0.1:8080".parse()?;
+        let mut resolver = GenericCertificateResolver::new();
+
+        let fingerprint = resolver.add_certificate(&AddCertificate {
+            address,
+            certificate: CertificateAndKey {
+                certificate: String::from(include_str!("../assets/certificate.pem")),
+                key: String::from(include_str!("../assets/key.pem")),
+                certificate_chain: vec![],
+                versions: vec![],
+                ocsp_response: None,
+                priority: 0,
+            },
+            names: vec!["lolcatho.st".into()],
+            expired_at: None,
+        })?;
+
+        resolver.set_default_certificate(&SetDefaultCertificate {
+            address,
+            fingerprint: Some(fingerprint.to_owned()),
+        })?;
+        resolver.strict_sni = true;
+
+        assert_eq!(resolver.resolve(None), None);
+        assert_eq!(resolver.resolve(Some(b"example.com")), None);
+        assert_eq!(resolver.resolve(Some(b"lolcatho.st")), Some(&fingerprint));
+
+        resolver.strict_sni = false;
+
+        assert_eq!(resolver.resolve(None), Some(&fingerprint));
+        assert_eq!(resolver.resolve(Some(b"example.com")), Some(&fingerprint));
+
+        Ok(())
+    }
+
+    /// resolves `vault.example` and its subdomains
+    struct VaultResolver(Arc<CertifiedKey>);
+
+    impl DelegatedCertificateResolver for VaultResolver {
+        fn resolve(
+            &self,
+            server_name: Option<&str>,
+            _: &[SignatureScheme],
+        ) -> Option<Arc<CertifiedKey>> {
+            server_name
+                .filter(|name| *name == "vault.example" || name.ends_with(".vault.example"))
+                .map(|_| self.0.clone())
+        }
+    }
+
+    fn store(name: &str) -> Result<MutexWrappedCertificateResolver, Box<dyn Error + Send + Sync>> {
+        let store = MutexWrappedCertificateResolver::new();
+        store.0.lock().unwrap().add_certificate(&AddCertificate {
+            address: "127.0.0.1:8443".parse()?,
+            certificate: CertificateAndKey {
+                certificate: String::from(include_str!("../assets/certificate.pem")),
+                key: String::from(include_str!("../assets/key.pem")),
+                certificate_chain: vec![],
+                versions: vec![],
+                ocsp_response: None,
+                priority: 0,
+            },
+            names: vec![name.into()],
+            expired_at: None,
+        })?;
+        Ok(store)
+    }
+
+    #[test]
+    fn delegated_resolver() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let vault_key = store("vault.example")?
+            .resolve_server_name(Some("vault.example"), &[])
+            .ok_or("the vault certificate must be resolved")?;
+        let store = Arc::new(store("lolcatho.st")?);
+        let resolver =
+            DelegatingCertificateResolver::new(Arc::new(VaultResolver(vault_key.clone())), store);
+
+        let delegated = |name: &str| {
+            resolver
+                .resolve_server_name(Some(name), &[])
+                .map(|key| Arc::ptr_eq(&key, &vault_key))
+        };
+
+        // an exact name and a wildcard one of the delegated resolver
+        assert_eq!(delegated("vault.example"), Some(true));
+        assert_eq!(delegated("api.vault.example"), Some(true));
+        // a name of the store only
+        assert_eq!(delegated("lolcatho.st"), Some(false));
+        // an unknown name, with no default certificate
+        assert_eq!(delegated("unknown.example"), None);
+        assert!(resolver.resolve_server_name(None, &[]).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolved_certificate() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let address = "127.0.0.1:8080".parse()?;
+        let mut resolver = GenericCertificateResolver::new();
+
+        let fingerprint = resolver.add_certificate(&AddCertificate {
+            address,
+            certificate: CertificateAndKey {
+                certificate: String::from(include_str!("../assets/certificate.pem")),
+                key: String::from(include_str!("../assets/key.pem")),
+                certificate_chain: vec![],
+                versions: vec![TlsVersion::TLSv1_3],
+                ocsp_response: None,
+                priority: 0,
+            },
+            names: vec!["lolcatho.st".into()],
+            expired_at: Some(1_000_000),
+        })?;
+
+        let expected = ResolvedCertificate {
+            fingerprint: fingerprint.to_owned(),
+            names: vec!["lolcatho.st".into()],
+            expiration: 1_000_000,
+            versions: vec![TlsVersion::TLSv1_3],
+            default: false,
+        };
+        if resolver.resolved_certificate(Some(b"lolcatho.st")) != Some(expected.to_owned()) {
+            return Err("the certificate of the name must be described".into());
+        }
+
+        if resolver
+            .resolved_certificate(Some(b"example.com"))
+            .is_some()
+        {
+            return Err("there must be no certificate without a default one".into());
+        }
+
+        resolver.set_default_certificate(&SetDefaultCertificate {
+            address,
+            fingerprint: Some(fingerprint),
+        })?;
+
+        if resolver.resolved_certificate(Some(b"example.com"))
+            != Some(ResolvedCertificate {
+                default: true,
+                ..expected
+            })
+        {
+            return Err("the default certificate must be described".into());
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn random() -> Result<(), Box<dyn Error + Send + Sync>> {
         // ---------------------------------------------------------------------
@@ -928,36 +2116,48 @@ mod tests {
                 key: include_str!("../assets/tests/key.pem").to_string(),
                 certificate_chain: vec![],
                 versions: vec![],
+                ocsp_response: None,
+                priority: 0,
             },
             CertificateAndKey {
                 certificate: include_str!("../assets/tests/certificate-2.pem").to_string(),
                 key: include_str!("../assets/tests/key.pem").to_string(),
                 certificate_chain: vec![],
                 versions: vec![],
+                ocsp_response: None,
+                priority: 0,
             },
             CertificateAndKey {
                 certificate: include_str!("../assets/tests/certificate-3.pem").to_string(),
                 key: include_str!("../assets/tests/key.pem").to_string(),
                 certificate_chain: vec![],
                 versions: vec![],
+                ocsp_response: None,
+                priority: 0,
             },
             CertificateAndKey {
                 certificate: include_str!("../assets/tests/certificate-4.pem").to_string(),
                 key: include_str!("../assets/tests/key.pem").to_string(),
                 certificate_chain: vec![],
                 versions: vec![],
+                ocsp_response: None,
+                priority: 0,
             },
             CertificateAndKey {
                 certificate: include_str!("../assets/tests/certificate-5.pem").to_string(),
                 key: include_str!("../assets/tests/key.pem").to_string(),
                 certificate_chain: vec![],
                 versions: vec![],
+                ocsp_response: None,
+                priority: 0,
             },
             CertificateAndKey {
                 certificate: include_str!("../assets/tests/certificate-6.pem").to_string(),
                 key: include_str!("../assets/tests/key.pem").to_string(),
                 certificate_chain: vec![],
                 versions: vec![],
+                ocsp_response: None,
+                priority: 0,
             },
         ];
 
@@ -1020,4 +2220,231 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn client_certificate_revocation() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (_, client) = parse_x509_pem(include_bytes!("../assets/tests/client-certificate.pem"))?;
+        let (_, other) = parse_x509_pem(include_bytes!("../assets/certificate.pem"))?;
+
+        assert_eq!(
+            certificate_subject(&client.contents).as_deref(),
+            Some("O=Sozu, CN=client-1")
+        );
+
+        let revocation_list =
+            RevocationList::from_pem(include_str!("../assets/tests/client-ca.crl.pem"))?;
+        assert!(revocation_list.is_revoked(&client.contents));
+        assert!(!revocation_list.is_revoked(&other.contents));
+        assert!(!RevocationList::default().is_revoked(&client.contents));
+
+        // the certificates of a bundle are not revocation lists
+        let revocation_list =
+            RevocationList::from_pem(include_str!("../assets/tests/client-ca.pem"))?;
+        assert!(!revocation_list.is_revoked(&client.contents));
+
+        let mut client_auth = ClientAuth {
+            required: true,
+            ca_certificates: String::from(include_str!("../assets/tests/client-ca.pem")),
+            revocation_lists: None,
+            dn_header: String::from(DEFAULT_CLIENT_DN_HEADER),
+        };
+        let client = Certificate(client.contents);
+        let verifier = ClientCertificateVerifier::new(&client_auth)?;
+        assert!(verifier
+            .verify_client_cert(&client, &[], SystemTime::now())
+            .is_ok());
+
+        client_auth.revocation_lists = Some(String::from(include_str!(
+            "../assets/tests/client-ca.crl.pem"
+        )));
+        let verifier = ClientCertificateVerifier::new(&client_auth)?;
+        assert!(verifier
+            .verify_client_cert(&client, &[], SystemTime::now())
+            .is_err());
+
+        Ok(())
+    }
+    #[test]
+    fn backend_tls_configuration() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let address = "127.0.0.1:8443".parse()?;
+
+        // the web authorities are used by default, and the backend is verified by IP address
+        let config = BackendTlsConfig::new(&BackendTls::default())?;
+        assert!(config
+            .connect(address, BackendProtocol::Http1)?
+            .wants_write());
+
+        let mut tls = BackendTls {
+            sni: Some(String::from("backend.example.com")),
+            skip_verification: false,
+            ca_certificates: Some(String::from(include_str!("../assets/tests/client-ca.pem"))),
+            client_certificate: Some(String::from(include_str!("../assets/certificate.pem"))),
+            client_key: Some(String::from(include_str!("../assets/key.pem"))),
+            alpn_protocols: vec![String::from("http/1.1")],
+        };
+        let config = BackendTlsConfig::new(&tls)?;
+        assert_eq!(config.config.alpn_protocols, vec![b"http/1.1".to_vec()]);
+        assert_eq!(config.h2_config.alpn_protocols, vec![b"h2".to_vec()]);
+        assert!(config
+            .connect(address, BackendProtocol::Http2)?
+            .wants_write());
+
+        tls.sni = Some(String::from("not a server name"));
+        assert!(matches!(
+            BackendTlsConfig::new(&tls),
+            Err(BackendTlsError::InvalidServerName(_))
+        ));
+
+        tls.sni = None;
+        tls.ca_certificates = Some(String::from(include_str!("../assets/key.pem")));
+        assert!(matches!(
+            BackendTlsConfig::new(&tls),
+            Err(BackendTlsError::NoCertificateAuthority)
+        ));
+
+        tls.ca_certificates = None;
+        tls.client_key = Some(String::from(include_str!("../assets/certificate.pem")));
+        assert!(matches!(
+            BackendTlsConfig::new(&tls),
+            Err(BackendTlsError::NoClientKey)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ticket_keys_rotation() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let first_key = hex::encode([1u8; TICKET_KEY_LENGTH]);
+        let second_key = hex::encode([2u8; TICKET_KEY_LENGTH]);
+
+        let ticketer = SharedTicketer::default();
+        assert!(!ticketer.enabled());
+        assert!(ticketer.encrypt(b"session").is_none());
+
+        ticketer.set_keys(&SetTicketKeys {
+            keys: vec![first_key.clone()],
+            lifetime: 3600,
+        })?;
+        assert!(ticketer.enabled());
+        assert_eq!(ticketer.lifetime(), 3600);
+
+        let ticket = ticketer
+            .encrypt(b"session")
+            .expect("should encrypt the ticket");
+        assert_eq!(ticketer.decrypt(&ticket), Some(b"session".to_vec()));
+
+        // after a rotation, the tickets of the previous key are still accepted
+        ticketer.set_keys(&SetTicketKeys {
+            keys: vec![second_key.clone(), first_key],
+            lifetime: 3600,
+        })?;
+        assert_eq!(ticketer.decrypt(&ticket), Some(b"session".to_vec()));
+        let new_ticket = ticketer
+            .encrypt(b"session")
+            .expect("should encrypt the ticket");
+        assert_ne!(new_ticket[..16], ticket[..16]);
+
+        // then refused once the key is dropped
+        ticketer.set_keys(&SetTicketKeys {
+            keys: vec![second_key],
+            lifetime: 3600,
+        })?;
+        assert_eq!(ticketer.decrypt(&ticket), None);
+        assert_eq!(ticketer.decrypt(&new_ticket), Some(b"session".to_vec()));
+
+        assert!(ticketer
+            .set_keys(&SetTicketKeys {
+                keys: vec![String::from("abcd")],
+                lifetime: 3600,
+            })
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn certificate_validation() {
+        let certificate_and_key =
+            |certificate: &str, chain: Vec<&str>, key: &str| CertificateAndKey {
+                certificate: certificate.to_string(),
+                certificate_chain: chain.into_iter().map(String::from).collect(),
+                key: key.to_string(),
+                versions: vec![],
+                ocsp_response: None,
+                priority: 0,
+            };
+
+        // self-signed, so the chain is complete
+        assert_eq!(
+            validate_certificate(&certificate_and_key(
+                include_str!("../assets/certificate.pem"),
+                vec![],
+                include_str!("../assets/key.pem"),
+            )),
+            vec![]
+        );
+
+        assert_eq!(
+            validate_certificate(&certificate_and_key(
+                include_str!("../assets/certificate.pem"),
+                vec![],
+                include_str!("../assets/tests/key.pem"),
+            )),
+            vec![CertificateIssue::KeyMismatch]
+        );
+
+        assert_eq!(
+            validate_certificate(&certificate_and_key(
+                include_str!("../assets/tests/certificate-1.pem"),
+                vec![],
+                include_str!("../assets/tests/key.pem"),
+            )),
+            vec![CertificateIssue::Expired]
+        );
+
+        // the key of the client certificate is not available
+        assert_eq!(
+            validate_certificate(&certificate_and_key(
+                include_str!("../assets/tests/client-certificate.pem"),
+                vec![],
+                include_str!("../assets/key.pem"),
+            )),
+            vec![
+                CertificateIssue::KeyMismatch,
+                CertificateIssue::IncompleteChain
+            ]
+        );
+
+        assert_eq!(
+            validate_certificate(&certificate_and_key(
+                include_str!("../assets/tests/client-certificate.pem"),
+                vec![include_str!("../assets/tests/client-ca.pem")],
+                include_str!("../assets/key.pem"),
+            )),
+            vec![CertificateIssue::KeyMismatch]
+        );
+
+        assert_eq!(
+            validate_certificate(&certificate_and_key(
+                include_str!("../assets/tests/client-certificate.pem"),
+                vec![
+                    include_str!("../assets/certificate.pem"),
+                    include_str!("../assets/tests/client-ca.pem")
+                ],
+                include_str!("../assets/key.pem"),
+            )),
+            vec![
+                CertificateIssue::KeyMismatch,
+                CertificateIssue::UnorderedChain(0),
+                CertificateIssue::UnorderedChain(1)
+            ]
+        );
+
+        assert_eq!(
+            validate_certificate(&certificate_and_key("certificate", vec![], "key")),
+            vec![CertificateIssue::InvalidCertificate(String::from(
+                "Parsing Error: MissingHeader"
+            ))]
+        );
+    }
 }
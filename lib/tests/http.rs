@@ -83,6 +83,11 @@ fn test() {
         hostname: String::from("example.com"),
         path: PathRule::Prefix(String::from("/")),
         method: None,
+        methods: Vec::new(),
+        reject_other_methods: false,
+        headers: Vec::new(),
+        rewrite_path: None,
+        mirror_cluster_id: None,
         position: RulePosition::Tree,
         tags: None,
     };
@@ -108,6 +113,9 @@ fn test() {
         load_balancing_parameters: Some(LoadBalancingParams::default()),
         sticky_id: None,
         backup: None,
+        max_connections: None,
+        timeouts: proxy::Timeouts::default(),
+        tls: None,
     };
 
     command.write_message(&proxy::ProxyRequest {
@@ -182,6 +190,9 @@ fn test() {
         load_balancing_parameters: Some(LoadBalancingParams::default()),
         sticky_id: None,
         backup: None,
+        max_connections: None,
+        timeouts: proxy::Timeouts::default(),
+        tls: None,
     };
 
     command.write_message(&proxy::ProxyRequest {
@@ -259,6 +270,9 @@ fn test() {
         load_balancing_parameters: Some(LoadBalancingParams::default()),
         sticky_id: None,
         backup: None,
+        max_connections: None,
+        timeouts: proxy::Timeouts::default(),
+        tls: None,
     };
 
     command.write_message(&proxy::ProxyRequest {